@@ -2,6 +2,7 @@
 //! the types.
 
 use std::sync::Arc;
+use std::sync::RwLock;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt;
@@ -12,6 +13,7 @@ use serde_tagged::de::SeedFactory;
 use serde_tagged::util::TagString;
 use serde_tagged as sdt;
 use serde_tagged::util::erased::SerializeErased;
+use core::qm;
 
 /// Uniquely identify the type of an object
 pub trait TypeId {
@@ -20,15 +22,22 @@ pub trait TypeId {
 
 /// A registry of methods to deserialize objects given a tag to identify
 /// the type of object, using TypeId.
-pub struct Registry<V> {
-    registry: HashMap<&'static str, V>
+///
+/// The types built into this crate are inserted when the registry is
+/// created. Downstream crates can add their own types afterwards by calling
+/// register, for example on models::get_registry() with the from_serial
+/// method of a MonteCarloModelFactory of their own, after which their types
+/// can be deserialized by tag just like the built-in ones.
+pub struct Registry<V: 'static> {
+    registry: HashMap<&'static str, V>,
+    registered: RwLock<HashMap<&'static str, &'static V>>
 }
 
-impl<V> Registry<V> {
+impl<V: 'static> Registry<V> {
 
     /// Creates an empty registry
     pub fn new() -> Registry<V> {
-        Registry { registry: HashMap::new() }
+        Registry { registry: HashMap::new(), registered: RwLock::new(HashMap::new()) }
     }
 
     /// Adds a creation method to the registry
@@ -36,6 +45,29 @@ impl<V> Registry<V> {
         self.registry.insert(key, value);
     }
 
+    /// Adds a creation method to a registry that is already in use, such as
+    /// one of the static registries of this crate. Registrations last for
+    /// the lifetime of the program. It is an error to register a key that
+    /// is already known.
+    pub fn register(&self, key: &'static str, value: V) -> Result<(), qm::Error> {
+        if self.registry.contains_key(key) {
+            return Err(qm::Error::new(&format!("'{}' is already registered", key)))
+        }
+        let mut registered = self.registered.write().map_err(|_| qm::Error::new(
+            "Registry is poisoned"))?;
+        if registered.contains_key(key) {
+            return Err(qm::Error::new(&format!("'{}' is already registered", key)))
+        }
+        registered.insert(key, Box::leak(Box::new(value)));
+        Ok(())
+    }
+
+    /// Looks up the creation method for a key, whether it was inserted or
+    /// registered
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.registry.get(key).or_else(|| self.registered.read().ok()
+            .and_then(|registered| registered.get(key).map(|v| *v)))
+    }
 }
 
 // Allow use of the registration as a seed factor, for deserialization
@@ -50,7 +82,7 @@ where
     where
         E: sd::de::Error,
     {
-        self.registry.get(tag.as_ref())
+        self.get(tag.as_ref())
             .ok_or_else(|| sd::de::Error::custom(&format!("Unknown tag: {}", tag.as_ref())))
     }
 }
@@ -416,6 +448,44 @@ pub mod tests {
         assert_debug_eq(&rc_c, &de_c);
     }
 
+    /// A type that is not known to the registry when it is created, as if
+    /// it were defined in a downstream crate
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+    pub struct D {
+        bar: f64,
+    }
+
+    impl TypeId for D {
+        fn type_id(&self) -> &'static str {
+            "D"
+        }
+    }
+
+    impl InstanceId for D {
+        fn id(&self) -> &str { "d" }
+    }
+
+    fn deserialize_d<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcStored, esd::Error> {
+        Ok(RcStored::new(Arc::new(D::deserialize(de)?)))
+    }
+
+    #[test]
+    fn serde_tagged_registered_roundtrip() {
+        let d : Arc<Stored> = Arc::new(D { bar: 1.5 });
+        let rc_d = RcStored::new(d.clone());
+        let ser_d = serde_json::to_string_pretty(&rc_d).unwrap();
+
+        // the registry cannot deserialize D until it is registered
+        assert!(serde_json::from_str::<RcStored>(&ser_d).is_err());
+        get_registry().register("D", BoxFnSeed::new(deserialize_d)).unwrap();
+        let de_d: RcStored = serde_json::from_str(&ser_d).unwrap();
+        assert_debug_eq(&rc_d, &de_d);
+
+        // keys can only be registered once, whether built in or not
+        assert!(get_registry().register("D", BoxFnSeed::new(deserialize_d)).is_err());
+        assert!(get_registry().register("A", BoxFnSeed::new(deserialize_d)).is_err());
+    }
+
     // test deduplicated factories
     pub type DrcStored = Drc<Stored, RcStored>;
 
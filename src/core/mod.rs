@@ -1,3 +1,4 @@
 pub mod qm;
 pub mod factories;
-pub mod dedup;
\ No newline at end of file
+pub mod dedup;
+pub mod parallel;
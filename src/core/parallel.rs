@@ -0,0 +1,87 @@
+//! Support for spreading work, such as Monte-Carlo paths, over threads
+
+use std::thread;
+use core::qm;
+
+/// The number of paths in each block of work. Pseudo-random numbers are
+/// drawn from a separate stream for each block, so the number of paths in a
+/// block must not change with the number of threads, or the results would.
+pub const PATHS_PER_BLOCK: usize = 1024;
+
+/// Runs the given function on each chunk of work, spreading the chunks over
+/// up to the given number of threads, each taking a contiguous run of them.
+/// The function is given the index of the chunk, so that what it does can
+/// depend on the chunk but never on the thread. With one thread, or only one
+/// chunk, everything runs on the calling thread. If any chunk fails, the
+/// error of the first failing chunk is returned.
+pub fn for_each_chunk<T, F>(threads: usize, chunks: Vec<T>, f: F)
+    -> Result<(), qm::Error>
+    where T: Send, F: Fn(usize, T) -> Result<(), qm::Error> + Sync {
+
+    let n_chunks = chunks.len();
+    let threads = threads.max(1).min(n_chunks);
+    if threads <= 1 {
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            f(i, chunk)?;
+        }
+        return Ok(())
+    }
+
+    let mut runs: Vec<Vec<(usize, T)>> = (0..threads).map(|_| Vec::new()).collect();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        runs[i * threads / n_chunks].push((i, chunk));
+    }
+
+    let f = &f;
+    let results: Vec<Result<(), qm::Error>> = thread::scope(|scope| {
+        let handles: Vec<_> = runs.into_iter().map(|run| scope.spawn(move || {
+            for (i, chunk) in run {
+                f(i, chunk)?;
+            }
+            Ok(())
+        })).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap_or_else(|_|
+            Err(qm::Error::new("Worker thread panicked")))).collect()
+    });
+
+    // the runs are in chunk order, so the first error is from the first chunk
+    for result in results {
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn every_chunk_is_run_once() {
+        for threads in [1, 2, 3, 7].iter() {
+            let mut values = vec![0_usize; 10];
+            let count = AtomicUsize::new(0);
+            {
+                let chunks: Vec<&mut [usize]> = values.chunks_mut(3).collect();
+                for_each_chunk(*threads, chunks, |i, chunk| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    for value in chunk.iter_mut() {
+                        *value = i;
+                    }
+                    Ok(())
+                }).unwrap();
+            }
+            assert_eq!(count.load(Ordering::SeqCst), 4);
+            assert_eq!(values, vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn first_error_is_returned() {
+        let chunks: Vec<usize> = (0..8).collect();
+        let result = for_each_chunk(4, chunks, |i, _| if i % 3 == 2 {
+            Err(qm::Error::new(&format!("chunk {}", i))) } else { Ok(()) });
+        assert_eq!(result.unwrap_err().to_string(), "rfin error: chunk 2");
+    }
+}
@@ -3,14 +3,20 @@ use data::bumpdivs::BumpDivs;
 use data::bumpvol::BumpVol;
 use data::bumpyield::BumpYield;
 use data::bumpspotdate::BumpSpotDate;
+use data::bumpcorrelation::BumpCorrelation;
 
 /// Enumeration spanning all bumps of market data
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Bump {
     Spot ( String, BumpSpot ),
+    FxSpot ( String, BumpSpot ),
     Divs ( String, BumpDivs ),
     Borrow ( String, BumpYield ),
     Vol ( String, BumpVol ),
+    VolCube ( String, BumpVol ),
     Yield ( String, BumpYield ),
+    Hazard ( String, BumpYield ),
+    Correlation ( String, String, BumpCorrelation ),
     SpotDate ( BumpSpotDate )
 }
 
@@ -19,6 +25,10 @@ impl Bump {
         Bump::Spot ( id.to_string(), bump )
     }
 
+    pub fn new_fx_spot(fx_id: &str, bump: BumpSpot) -> Bump {
+        Bump::FxSpot ( fx_id.to_string(), bump )
+    }
+
     pub fn new_divs(id: &str, bump: BumpDivs) -> Bump {
         Bump::Divs ( id.to_string(), bump )
     }
@@ -31,10 +41,22 @@ impl Bump {
         Bump::Vol ( id.to_string(), bump )
     }
 
+    pub fn new_vol_cube(id: &str, bump: BumpVol) -> Bump {
+        Bump::VolCube ( id.to_string(), bump )
+    }
+
     pub fn new_yield(credit_id: &str, bump: BumpYield) -> Bump {
         Bump::Yield ( credit_id.to_string(), bump )
     }
 
+    pub fn new_hazard(credit_id: &str, bump: BumpYield) -> Bump {
+        Bump::Hazard ( credit_id.to_string(), bump )
+    }
+
+    pub fn new_correlation(first: &str, second: &str, bump: BumpCorrelation) -> Bump {
+        Bump::Correlation ( first.to_string(), second.to_string(), bump )
+    }
+
     pub fn new_spot_date(bump: BumpSpotDate) -> Bump {
         Bump::SpotDate ( bump )
     }
@@ -0,0 +1,44 @@
+use data::bump::Bumper;
+
+/// Bump that defines all the supported bumps to a correlation between two
+/// factors. Bumped correlations are clamped so they remain between minus
+/// one and one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BumpCorrelation {
+    Additive { bump: f64 },
+    Replace { correlation: f64 }
+}
+
+impl BumpCorrelation {
+    pub fn new_additive(bump: f64) -> BumpCorrelation {
+        BumpCorrelation::Additive { bump: bump }
+    }
+
+    pub fn new_replace(correlation: f64) -> BumpCorrelation {
+        BumpCorrelation::Replace { correlation: correlation }
+    }
+}
+
+impl Bumper<f64> for BumpCorrelation {
+
+    fn apply(&self, old_correlation: f64) -> f64 {
+        let bumped = match self {
+            &BumpCorrelation::Additive { bump } => old_correlation + bump,
+            &BumpCorrelation::Replace { correlation } => correlation
+        };
+        bumped.max(-1.0).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlation_bumps_are_clamped() {
+        assert_eq!(BumpCorrelation::new_additive(0.1).apply(0.5), 0.6);
+        assert_eq!(BumpCorrelation::new_additive(0.1).apply(0.95), 1.0);
+        assert_eq!(BumpCorrelation::new_additive(-0.5).apply(-0.8), -1.0);
+        assert_eq!(BumpCorrelation::new_replace(0.3).apply(0.9), 0.3);
+    }
+}
@@ -1,18 +1,33 @@
 use data::divstream::RcDividendStream;
 use data::divstream::DividendStream;
 use data::bump::Bumper;
+use data::bumpyield::RateBucket;
 use std::sync::Arc;
 
 /// Bump that defines all the supported bumps and risk transformations of a
-/// vol surface.
+/// dividend stream. As well as bumping all the dividends, the cash amounts
+/// can be bumped separately from the yield-like parts, which are the
+/// relative amounts and the dividend yield. These bumps can optionally be
+/// restricted to a bucket of ex dates.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum BumpDivs {
     BumpAllRelative { size: f64 },
+    BumpCashRelative { size: f64, bucket: Option<RateBucket> },
+    BumpYieldRelative { size: f64, bucket: Option<RateBucket> }
 }
 
 impl BumpDivs {
     pub fn new_all_relative(size: f64) -> BumpDivs {
         BumpDivs::BumpAllRelative { size: size }
     }
+
+    pub fn new_cash_relative(size: f64, bucket: Option<RateBucket>) -> BumpDivs {
+        BumpDivs::BumpCashRelative { size: size, bucket: bucket }
+    }
+
+    pub fn new_yield_relative(size: f64, bucket: Option<RateBucket>) -> BumpDivs {
+        BumpDivs::BumpYieldRelative { size: size, bucket: bucket }
+    }
 }
 
 impl Bumper<RcDividendStream> for BumpDivs {
@@ -20,7 +35,13 @@ impl Bumper<RcDividendStream> for BumpDivs {
     fn apply(&self, divs: RcDividendStream) -> RcDividendStream {
         match self {
             &BumpDivs::BumpAllRelative { size }
-                => RcDividendStream::new(Arc::new(DividendStream::new_bump_all(&*divs, size)))
+                => RcDividendStream::new(Arc::new(DividendStream::new_bump_all(&*divs, size))),
+            &BumpDivs::BumpCashRelative { size, ref bucket }
+                => RcDividendStream::new(Arc::new(DividendStream::new_bump_cash(
+                    &*divs, size, bucket.as_ref()))),
+            &BumpDivs::BumpYieldRelative { size, ref bucket }
+                => RcDividendStream::new(Arc::new(DividendStream::new_bump_yield(
+                    &*divs, size, bucket.as_ref())))
         }
     }
 }
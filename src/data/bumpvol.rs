@@ -4,7 +4,15 @@ use data::volsurface::RcVolSurface;
 use data::volsurface::FlatVolSurface;
 use data::voldecorators::TimeScaledBumpVol;
 use data::voldecorators::ParallelBumpVol;
+use data::volcube::RcVolCube;
+use data::volcube::FlatVolCube;
+use data::volcube::ParallelBumpVolCube;
+use data::volcube::TimeScaledBumpVolCube;
+use data::volcube::BucketBumpVolCube;
+use data::voldecorators::BucketBumpVol;
 use data::bump::Bumper;
+use dates::Date;
+use core::qm;
 
 /// Bump that defines all the supported bumps and risk transformations of a
 /// vol surface.
@@ -12,7 +20,8 @@ use data::bump::Bumper;
 pub enum BumpVol {
     FlatAdditive { size: f64 },
     TimeScaled { size: f64, floor: f64 },
-    Replace { vol: f64 }
+    Replace { vol: f64 },
+    Bucketed { size: f64, bucket: VolBucket }
 }
 
 impl BumpVol {
@@ -28,11 +37,16 @@ impl BumpVol {
         BumpVol::Replace { vol }
     }
 
+    pub fn new_bucketed(size: f64, bucket: VolBucket) -> BumpVol {
+        BumpVol::Bucketed { size: size, bucket: bucket }
+    }
+
     pub fn bumpsize(&self) -> f64 {
         match self {
             &BumpVol::FlatAdditive { size } => size,
             &BumpVol::TimeScaled { size, floor: _ } => size,
-            &BumpVol::Replace { vol: _ } => NAN
+            &BumpVol::Replace { vol: _ } => NAN,
+            &BumpVol::Bucketed { size, bucket: _ } => size
         }
     }
 
@@ -49,7 +63,9 @@ impl BumpVol {
             &BumpVol::TimeScaled { size: _, floor } 
                 => BumpVol::TimeScaled { size : down_bump, floor: floor },
             &BumpVol::Replace { vol: _ } 
-                => BumpVol::Replace { vol: NAN }
+                => BumpVol::Replace { vol: NAN },
+            &BumpVol::Bucketed { size: _, ref bucket }
+                => BumpVol::Bucketed { size: down_bump, bucket: bucket.clone() }
         }
     }
 }
@@ -66,9 +82,159 @@ impl Bumper<RcVolSurface> for BumpVol {
 
             &BumpVol::Replace { vol }
                 => RcVolSurface::new(Arc::new(FlatVolSurface::new(vol, 
-                    surface.calendar().clone(), surface.base_date())))
+                    surface.calendar().clone(), surface.base_date()))),
+
+            &BumpVol::Bucketed { size, ref bucket }
+                => RcVolSurface::new(Arc::new(BucketBumpVol::new(surface.clone(),
+                    size, bucket.clone())))
         }
     }
 }
 
                 
+
+impl Bumper<RcVolCube> for BumpVol {
+
+    fn apply(&self, cube: RcVolCube) -> RcVolCube {
+        match self {
+            &BumpVol::FlatAdditive { size }
+                => RcVolCube::new(Arc::new(ParallelBumpVolCube::new(cube.clone(), size))),
+
+            &BumpVol::TimeScaled { size, floor }
+                => RcVolCube::new(Arc::new(TimeScaledBumpVolCube::new(cube.clone(), size, floor))),
+
+            &BumpVol::Replace { vol }
+                => RcVolCube::new(Arc::new(FlatVolCube::new(vol, cube.base_date()))),
+
+            &BumpVol::Bucketed { size, ref bucket }
+                => RcVolCube::new(Arc::new(BucketBumpVolCube::new(cube.clone(),
+                    size, bucket.clone())))
+        }
+    }
+}
+
+/// One bucket of a grid of expiries and strikes, used to bump the vols near
+/// one pillar of a vol surface rather than the whole surface. The bump is
+/// weighted by a tent in each direction, which is one at the pillar of the
+/// bucket, falling linearly to zero at the neighbouring pillars, and flat
+/// beyond the first and last pillars. Expiries are interpolated in days and
+/// strikes are absolute. As the weights of all the buckets sum to one, the
+/// bucketed bumps add up to a parallel bump.
+///
+/// If there are no strikes, every strike is bumped, giving buckets by
+/// expiry only, and similarly if there are no expiries.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VolBucket {
+    expiries: Vec<Date>,
+    strikes: Vec<f64>,
+    expiry: usize,
+    strike: usize
+}
+
+impl VolBucket {
+    /// Creates the bucket at the given indices into the expiries and
+    /// strikes, which must be strictly increasing. The index is ignored
+    /// if the corresponding list is empty.
+    pub fn new(expiries: Vec<Date>, strikes: Vec<f64>, expiry: usize,
+        strike: usize) -> Result<VolBucket, qm::Error> {
+
+        if !expiries.is_empty() && expiry >= expiries.len()
+            || !strikes.is_empty() && strike >= strikes.len() {
+            return Err(qm::Error::new("Vol bucket is outside the grid"))
+        }
+        if expiries.windows(2).any(|w| w[1] <= w[0])
+            || strikes.windows(2).any(|w| !(w[1] > w[0])) {
+            return Err(qm::Error::new(
+                "Vol bucket expiries and strikes must be strictly increasing"))
+        }
+
+        Ok(VolBucket { expiries: expiries, strikes: strikes, expiry: expiry,
+            strike: strike })
+    }
+
+    pub fn expiries(&self) -> &[Date] { &self.expiries }
+    pub fn strikes(&self) -> &[f64] { &self.strikes }
+    pub fn expiry(&self) -> usize { self.expiry }
+    pub fn strike(&self) -> usize { self.strike }
+
+    /// The weight of the bump for an expiry, which is zero outside the
+    /// bucket
+    pub fn expiry_weight(&self, date: Date) -> f64 {
+        if self.expiries.is_empty() {
+            return 1.0
+        }
+        let base = self.expiries[0];
+        let days: Vec<f64> = self.expiries.iter().map(|d| (*d - base) as f64).collect();
+        tent(&days, self.expiry, (date - base) as f64)
+    }
+
+    /// The weight of the bump for a strike, which is zero outside the
+    /// bucket
+    pub fn strike_weight(&self, strike: f64) -> f64 {
+        if self.strikes.is_empty() {
+            return 1.0
+        }
+        tent(&self.strikes, self.strike, strike)
+    }
+}
+
+/// The weight of a bump at x for the bucket at pillar i, which is one at
+/// the pillar, falling linearly to zero at its neighbours, and flat beyond
+/// the first and last pillars
+pub fn tent(pillars: &[f64], i: usize, x: f64) -> f64 {
+    let at = pillars[i];
+    if x < at {
+        if i == 0 {
+            1.0
+        } else {
+            let below = pillars[i - 1];
+            ((x - below) / (at - below)).max(0.0)
+        }
+    } else if i + 1 == pillars.len() {
+        1.0
+    } else {
+        let above = pillars[i + 1];
+        ((above - x) / (above - at)).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn vol_buckets_sum_to_one() {
+        let expiries = vec![Date::from_ymd(2017, 06, 01), Date::from_ymd(2018, 01, 01),
+            Date::from_ymd(2019, 01, 01)];
+        let strikes = vec![80.0, 100.0, 120.0];
+        let buckets: Vec<VolBucket> = (0..3).flat_map(|i| (0..3).map(move |j| (i, j)))
+            .map(|(i, j)| VolBucket::new(expiries.clone(), strikes.clone(), i, j).unwrap())
+            .collect();
+
+        for &date in [Date::from_ymd(2017, 01, 02), Date::from_ymd(2017, 06, 01),
+            Date::from_ymd(2017, 09, 15), Date::from_ymd(2020, 01, 01)].iter() {
+            for &strike in [50.0, 80.0, 95.0, 119.0, 200.0].iter() {
+                let total: f64 = buckets.iter()
+                    .map(|b| b.expiry_weight(date) * b.strike_weight(strike)).sum();
+                assert!(approx_eq(total, 1.0, 1e-12), "date={} strike={} total={}",
+                    date, strike, total);
+            }
+        }
+
+        // the middle bucket only covers its neighbourhood
+        let middle = &buckets[4];
+        assert_eq!(middle.strike_weight(100.0), 1.0);
+        assert!(approx_eq(middle.strike_weight(95.0), 0.75, 1e-12));
+        assert_eq!(middle.strike_weight(80.0), 0.0);
+        assert_eq!(middle.strike_weight(130.0), 0.0);
+        assert_eq!(middle.expiry_weight(Date::from_ymd(2017, 01, 02)), 0.0);
+
+        // buckets by expiry only bump every strike
+        let by_expiry = VolBucket::new(expiries.clone(), Vec::new(), 0, 0).unwrap();
+        assert_eq!(by_expiry.strike_weight(1000.0), 1.0);
+
+        assert!(VolBucket::new(expiries.clone(), strikes.clone(), 3, 0).is_err());
+        assert!(VolBucket::new(expiries, vec![100.0, 90.0], 0, 0).is_err());
+    }
+}
@@ -1,14 +1,20 @@
 use std::sync::Arc;
 use data::curves::AnnualisedFlatBump;
 use data::curves::ContinuouslyCompoundedFlatBump;
+use data::curves::KeyRateBump;
 use data::bump::Bumper;
+use data::bumpvol::tent;
 use data::curves::RcRateCurve;
+use dates::Date;
+use core::qm;
 
 /// Bump that defines all the supported bumps and risk transformations of a
 /// rate curve such as a borrow curve or a yield curve.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum BumpYield {
     FlatAnnualised { size: f64 },
-    FlatContinuouslyCompounded { size: f64 }
+    FlatContinuouslyCompounded { size: f64 },
+    KeyRate { size: f64, bucket: RateBucket }
 }
 
 impl BumpYield {
@@ -19,6 +25,12 @@ impl BumpYield {
     pub fn new_flat_continuously_compounded(size: f64) -> BumpYield {
         BumpYield::FlatContinuouslyCompounded { size: size }
     }
+
+    /// Creates an annualised bump of the part of the curve within one
+    /// bucket, such as for a key rate DV01
+    pub fn new_key_rate(size: f64, bucket: RateBucket) -> BumpYield {
+        BumpYield::KeyRate { size: size, bucket: bucket }
+    }
 }
 
 impl Bumper<RcRateCurve> for BumpYield {
@@ -34,8 +46,76 @@ impl Bumper<RcRateCurve> for BumpYield {
             // to be a bottleneck.
             &BumpYield::FlatContinuouslyCompounded { size }
                 => RcRateCurve::new(Arc::new(ContinuouslyCompoundedFlatBump::new(
-                    surface.clone(), size)))
+                    surface.clone(), size))),
+
+            &BumpYield::KeyRate { size, ref bucket }
+                => RcRateCurve::new(Arc::new(KeyRateBump::new(
+                    surface.clone(), size, bucket.clone())))
+        }
+    }
+}
+
+/// One bucket of a rate curve, or of anything else indexed by date such as
+/// a dividend stream, defined by a list of pillar dates. The bump has full
+/// weight at the pillar of the bucket, falling linearly in days to zero at
+/// the neighbouring pillars, and flat beyond the first and last pillars. As the weights of all the buckets sum to one, the bucketed
+/// bumps add up to a parallel bump.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateBucket {
+    pillars: Vec<Date>,
+    pillar: usize
+}
+
+impl RateBucket {
+    /// Creates the bucket at the given index into the pillars, which must
+    /// be strictly increasing.
+    pub fn new(pillars: Vec<Date>, pillar: usize) -> Result<RateBucket, qm::Error> {
+        if pillar >= pillars.len() {
+            return Err(qm::Error::new("Rate bucket is outside the pillars"))
+        }
+        if pillars.windows(2).any(|w| w[1] <= w[0]) {
+            return Err(qm::Error::new("Rate bucket pillars must be strictly increasing"))
         }
+        Ok(RateBucket { pillars: pillars, pillar: pillar })
+    }
+
+    pub fn pillars(&self) -> &[Date] { &self.pillars }
+    pub fn pillar(&self) -> usize { self.pillar }
+
+    /// The weight of the bump for a date, which is zero outside the bucket
+    pub fn weight(&self, date: Date) -> f64 {
+        let base = self.pillars[0];
+        let days: Vec<f64> = self.pillars.iter().map(|d| (*d - base) as f64).collect();
+        tent(&days, self.pillar, (date - base) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn rate_buckets_sum_to_one() {
+        let pillars = vec![Date::from_ymd(2017, 06, 01), Date::from_ymd(2018, 01, 01),
+            Date::from_ymd(2019, 01, 01)];
+        let buckets: Vec<RateBucket> = (0..3)
+            .map(|i| RateBucket::new(pillars.clone(), i).unwrap()).collect();
+
+        for date in [Date::from_ymd(2017, 01, 01), Date::from_ymd(2017, 06, 01),
+            Date::from_ymd(2017, 09, 15), Date::from_ymd(2018, 07, 01),
+            Date::from_ymd(2020, 01, 01)].iter() {
+            let total: f64 = buckets.iter().map(|b| b.weight(*date)).sum();
+            assert!(approx_eq(total, 1.0, 1e-12), "date={} total={}", date, total);
+        }
+
+        // between two pillars, the weight is split linearly in days
+        let date = Date::from_ymd(2018, 07, 01);
+        assert!(approx_eq(buckets[2].weight(date), 181.0 / 365.0, 1e-12));
+        assert_eq!(buckets[0].weight(date), 0.0);
+
+        assert!(RateBucket::new(pillars.clone(), 3).is_err());
+        assert!(RateBucket::new(vec![pillars[1], pillars[0]], 0).is_err());
     }
 }
 
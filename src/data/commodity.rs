@@ -0,0 +1,251 @@
+use dates::Date;
+use data::forward::Forward;
+use math::interpolation::Interpolate;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Registry;
+use core::factories::Qrc;
+use std::sync::Arc;
+use std::fmt::Debug;
+use erased_serde as esd;
+use serde as sd;
+use serde_tagged as sdt;
+use serde_tagged::de::BoxFnSeed;
+use serde::Deserialize;
+
+/// A commodity curve gives the forward price for delivery of a commodity on
+/// any given day. Commodities such as power and gas are traded for delivery
+/// over periods such as months or quarters, and their prices are strongly
+/// seasonal, so unlike an equity forward, the curve is not built from a spot
+/// and a carry.
+pub trait CommodityCurve : esd::Serialize + TypeId + Send + Sync + Debug {
+
+    /// Returns the forward price for delivery on the given day
+    fn forward(&self, date: Date) -> Result<f64, qm::Error>;
+
+    /// The date the curve was marked
+    fn base_date(&self) -> Date;
+}
+
+// Get serialization to work recursively for commodity curves by using the
+// technology defined in core/factories. RcCommodityCurve is a container
+// class holding a CommodityCurve
+pub type RcCommodityCurve = Qrc<CommodityCurve>;
+pub type TypeRegistry = Registry<BoxFnSeed<RcCommodityCurve>>;
+
+/// Implement deserialization for subclasses of the type
+impl<'de> sd::Deserialize<'de> for RcCommodityCurve {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: sd::Deserializer<'de>
+    {
+        sdt::de::external::deserialize(deserializer, get_registry())
+    }
+}
+
+/// Return the type registry required for deserialization.
+pub fn get_registry() -> &'static TypeRegistry {
+    lazy_static! {
+        static ref REG: TypeRegistry = {
+            let mut reg = TypeRegistry::new();
+            reg.insert("SeasonalCommodityCurve", BoxFnSeed::new(SeasonalCommodityCurve::from_serial));
+            reg
+        };
+    }
+    &REG
+}
+
+/// A commodity curve defined by the prices of monthly delivery contracts,
+/// and a seasonality factor for each calendar month. The price for delivery
+/// on any day in a quoted month is the price of that month's contract.
+///
+/// For months with no quote, such as those between quarterly quotes or
+/// beyond the last quote, the quoted prices are deseasonalised by dividing
+/// by their factors, interpolated linearly by month (and extrapolated
+/// flat), then multiplied by the factor of the month required. Only the
+/// ratios between the factors matter.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SeasonalCommodityCurve {
+    base_date: Date,
+    contracts: Vec<(Date, f64)>,
+    seasonality: Vec<f64>
+}
+
+impl TypeId for SeasonalCommodityCurve {
+    fn type_id(&self) -> &'static str { "SeasonalCommodityCurve" }
+}
+
+impl SeasonalCommodityCurve {
+    /// Creates a commodity curve. The contracts are given by the first day
+    /// of their delivery month and their price, in increasing order. There
+    /// must be twelve seasonality factors, starting with January.
+    pub fn new(base_date: Date, contracts: &[(Date, f64)], seasonality: &[f64])
+        -> Result<SeasonalCommodityCurve, qm::Error> {
+
+        if contracts.is_empty() {
+            return Err(qm::Error::new("Commodity curve must have at least one contract"))
+        }
+        for &(month, price) in contracts.iter() {
+            if month.ymd().2 != 1 {
+                return Err(qm::Error::new("Commodity contract months must be \
+                    identified by their first day"))
+            }
+            if !(price > 0.0) {
+                return Err(qm::Error::new("Commodity prices must be positive"))
+            }
+        }
+        for pair in contracts.windows(2) {
+            if pair[0].0 >= pair[1].0 {
+                return Err(qm::Error::new("Commodity contracts must be in \
+                    strictly increasing order"))
+            }
+        }
+        if seasonality.len() != 12 || seasonality.iter().any(|s| !(*s > 0.0)) {
+            return Err(qm::Error::new("Commodity seasonality must have twelve \
+                positive factors"))
+        }
+
+        Ok(SeasonalCommodityCurve { base_date: base_date,
+            contracts: contracts.to_vec(), seasonality: seasonality.to_vec() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcCommodityCurve, esd::Error> {
+        Ok(Qrc::new(Arc::new(SeasonalCommodityCurve::deserialize(de)?)))
+    }
+
+    fn factor(&self, month: i32) -> f64 {
+        self.seasonality[(month % 12) as usize]
+    }
+
+    fn deseasonalised(&self, index: usize) -> (i32, f64) {
+        let (month_date, price) = self.contracts[index];
+        let month = month_count(month_date);
+        (month, price / self.factor(month))
+    }
+}
+
+/// Counts months since the start of year zero, so that consecutive months
+/// have consecutive counts and the count modulo twelve is zero for January
+fn month_count(date: Date) -> i32 {
+    let (year, month, _) = date.ymd();
+    year * 12 + month - 1
+}
+
+impl CommodityCurve for SeasonalCommodityCurve {
+    fn forward(&self, date: Date) -> Result<f64, qm::Error> {
+        let month = month_count(date);
+        let n = self.contracts.len();
+
+        // find the first contract at or after the month required
+        let after = self.contracts.iter().position(|&(m, _)| month_count(m) >= month);
+        let deseasonalised = match after {
+            None => self.deseasonalised(n - 1).1,
+            Some(i) => {
+                let (m1, p1) = self.deseasonalised(i);
+                if m1 == month {
+                    return Ok(self.contracts[i].1)
+                }
+                if i == 0 {
+                    p1
+                } else {
+                    let (m0, p0) = self.deseasonalised(i - 1);
+                    p0 + (p1 - p0) * (month - m0) as f64 / (m1 - m0) as f64
+                }
+            }
+        };
+
+        Ok(deseasonalised * self.factor(month))
+    }
+
+    fn base_date(&self) -> Date { self.base_date }
+}
+
+/// Adapts a commodity curve so it can be used wherever a forward is needed,
+/// for example by vol surfaces and Monte-Carlo models.
+pub struct CommodityCurveForward {
+    curve: RcCommodityCurve
+}
+
+impl CommodityCurveForward {
+    pub fn new(curve: RcCommodityCurve) -> CommodityCurveForward {
+        CommodityCurveForward { curve: curve }
+    }
+}
+
+impl Forward for CommodityCurveForward {
+    fn as_interp(&self) -> &Interpolate<Date> { self }
+
+    fn forward(&self, date: Date) -> Result<f64, qm::Error> {
+        self.curve.forward(date)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use serde_json;
+
+    /// A gas curve with winter months dearer than summer ones, quoted
+    /// monthly for the first quarter of 2017 and quarterly thereafter
+    pub fn sample_commodity_curve() -> SeasonalCommodityCurve {
+        let seasonality = [1.3, 1.2, 1.1, 1.0, 0.9, 0.8, 0.8, 0.8, 0.9, 1.0, 1.1, 1.2];
+        SeasonalCommodityCurve::new(Date::from_ymd(2017, 01, 02), &[
+            (Date::from_ymd(2017, 01, 01), 52.0),
+            (Date::from_ymd(2017, 02, 01), 48.0),
+            (Date::from_ymd(2017, 03, 01), 44.0),
+            (Date::from_ymd(2017, 07, 01), 32.0),
+            (Date::from_ymd(2017, 10, 01), 40.0),
+            (Date::from_ymd(2018, 01, 01), 54.0)], &seasonality).unwrap()
+    }
+
+    #[test]
+    fn commodity_curve_quoted_months() {
+        let curve = sample_commodity_curve();
+        assert_approx(curve.forward(Date::from_ymd(2017, 01, 02)).unwrap(), 52.0);
+        assert_approx(curve.forward(Date::from_ymd(2017, 02, 28)).unwrap(), 48.0);
+        assert_approx(curve.forward(Date::from_ymd(2017, 10, 15)).unwrap(), 40.0);
+    }
+
+    #[test]
+    fn commodity_curve_seasonal_interpolation() {
+        let curve = sample_commodity_curve();
+
+        // March and July both deseasonalise to 40, so May is 40 times 0.9
+        assert_approx(curve.forward(Date::from_ymd(2017, 05, 10)).unwrap(), 36.0);
+
+        // beyond the last quote, the deseasonalised price of 41.54 is flat
+        let feb = curve.forward(Date::from_ymd(2018, 02, 01)).unwrap();
+        assert_approx(feb, 54.0 / 1.3 * 1.2);
+        let aug = curve.forward(Date::from_ymd(2018, 08, 01)).unwrap();
+        assert_approx(aug, 54.0 / 1.3 * 0.8);
+
+        // before the first quote, extrapolate flat in the same way
+        let dec = curve.forward(Date::from_ymd(2016, 12, 01)).unwrap();
+        assert_approx(dec, 52.0 / 1.3 * 1.2);
+    }
+
+    #[test]
+    fn commodity_curve_rejects_bad_input() {
+        let base = Date::from_ymd(2017, 01, 02);
+        let flat = [1.0; 12];
+        assert!(SeasonalCommodityCurve::new(base, &[], &flat).is_err());
+        assert!(SeasonalCommodityCurve::new(base,
+            &[(Date::from_ymd(2017, 01, 15), 50.0)], &flat).is_err());
+        assert!(SeasonalCommodityCurve::new(base,
+            &[(Date::from_ymd(2017, 01, 01), 50.0)], &flat[..11]).is_err());
+    }
+
+    #[test]
+    fn serde_commodity_curve_roundtrip() {
+        let curve = RcCommodityCurve::new(Arc::new(sample_commodity_curve()));
+        let serialized = serde_json::to_string_pretty(&curve).unwrap();
+        let deserialized: RcCommodityCurve = serde_json::from_str(&serialized).unwrap();
+        let date = Date::from_ymd(2017, 12, 01);
+        assert_approx(deserialized.forward(date).unwrap(), curve.forward(date).unwrap());
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-12),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use ndarray::Array2;
+use core::qm;
+use dates::Date;
+use data::bump::Bumper;
+use data::bumpcorrelation::BumpCorrelation;
+
+/// A symmetric collection of instantaneous correlations between pairs of
+/// market factors, keyed by id. The ids are normally those of instruments
+/// such as equities, but may also be the ids of factors that are not
+/// instruments, such as FX rates. The correlation of any factor with itself
+/// is one.
+///
+/// Each pair is stored once, under the lexically smaller of the two ids, so
+/// the correlations cannot become asymmetric.
+///
+/// Any pair may also have a term structure of correlations, which takes
+/// precedence over its constant correlation when fetching the average
+/// correlation over a period.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Correlations {
+    pairs: HashMap<String, HashMap<String, f64>>,
+    #[serde(default)]
+    term_structures: HashMap<String, HashMap<String, CorrelationTermStructure>>
+}
+
+impl Correlations {
+    /// Creates an empty set of correlations
+    pub fn new() -> Correlations {
+        Correlations { pairs: HashMap::new(), term_structures: HashMap::new() }
+    }
+
+    /// Sets the correlation between two different factors. The correlation
+    /// must be between minus one and one.
+    pub fn set(&mut self, first: &str, second: &str, correlation: f64)
+        -> Result<(), qm::Error> {
+
+        if first == second {
+            return Err(qm::Error::new(&format!(
+                "Cannot set the correlation of '{}' with itself", first)))
+        }
+        if correlation < -1.0 || correlation > 1.0 {
+            return Err(qm::Error::new(&format!(
+                "Correlation between '{}' and '{}' must be between -1 and 1",
+                first, second)))
+        }
+
+        let (low, high) = ordered(first, second);
+        self.pairs.entry(low.to_string()).or_insert_with(HashMap::new)
+            .insert(high.to_string(), correlation);
+        Ok(())
+    }
+
+    /// Sets the correlations between all pairs of the given factors from a
+    /// full correlation matrix, whose rows and columns are in the order of
+    /// the ids. The matrix must be symmetric with a unit diagonal, but need
+    /// not be positive definite: models that need a valid matrix repair it.
+    pub fn set_matrix(&mut self, ids: &[&str], matrix: &Array2<f64>)
+        -> Result<(), qm::Error> {
+
+        let n = ids.len();
+        if matrix.shape() != [n, n] {
+            return Err(qm::Error::new(
+                "Correlation matrix does not match the number of ids"))
+        }
+        for i in 0..n {
+            if matrix[(i, i)] != 1.0 {
+                return Err(qm::Error::new(&format!(
+                    "Correlation of '{}' with itself must be one", ids[i])))
+            }
+            for j in 0..i {
+                if matrix[(i, j)] != matrix[(j, i)] {
+                    return Err(qm::Error::new(&format!(
+                        "Correlation matrix is not symmetric for '{}' and '{}'",
+                        ids[i], ids[j])))
+                }
+            }
+        }
+
+        for i in 0..n {
+            for j in 0..i {
+                self.set(ids[i], ids[j], matrix[(i, j)])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the correlation between two factors, or an error if it has not
+    /// been supplied
+    pub fn get(&self, first: &str, second: &str) -> Result<f64, qm::Error> {
+        if first == second {
+            return Ok(1.0)
+        }
+
+        let (low, high) = ordered(first, second);
+        self.pairs.get(low).and_then(|inner| inner.get(high)).cloned()
+            .ok_or_else(|| qm::Error::new(&format!(
+                "Correlation between '{}' and '{}' not found", first, second)))
+    }
+
+    /// Sets the term structure of correlations between two different
+    /// factors
+    pub fn set_term_structure(&mut self, first: &str, second: &str,
+        term_structure: CorrelationTermStructure) -> Result<(), qm::Error> {
+
+        if first == second {
+            return Err(qm::Error::new(&format!(
+                "Cannot set the correlation of '{}' with itself", first)))
+        }
+
+        let (low, high) = ordered(first, second);
+        self.term_structures.entry(low.to_string()).or_insert_with(HashMap::new)
+            .insert(high.to_string(), term_structure);
+        Ok(())
+    }
+
+    /// Gets the average instantaneous correlation between two factors over
+    /// the period from one date to another. This comes from the term
+    /// structure for the pair if there is one, otherwise it is the constant
+    /// correlation.
+    pub fn get_average(&self, first: &str, second: &str, from: Date, to: Date)
+        -> Result<f64, qm::Error> {
+
+        if first == second {
+            return Ok(1.0)
+        }
+
+        let (low, high) = ordered(first, second);
+        match self.term_structures.get(low).and_then(|inner| inner.get(high)) {
+            Some(term_structure) => Ok(term_structure.average(from, to)),
+            None => self.get(first, second)
+        }
+    }
+
+    /// Bumps the correlation between two different factors, returning
+    /// false if it has not been supplied. Both the constant correlation and
+    /// any term structure are bumped.
+    pub fn bump(&mut self, first: &str, second: &str, bump: &BumpCorrelation)
+        -> Result<bool, qm::Error> {
+
+        if first == second {
+            return Ok(false)
+        }
+        let mut bumped = false;
+        if let Ok(correlation) = self.get(first, second) {
+            self.set(first, second, bump.apply(correlation))?;
+            bumped = true;
+        }
+        let (low, high) = ordered(first, second);
+        if let Some(term_structure) = self.term_structures.get_mut(low)
+            .and_then(|inner| inner.get_mut(high)) {
+            term_structure.bump(bump);
+            bumped = true;
+        }
+        Ok(bumped)
+    }
+}
+
+/// A term structure of instantaneous correlations between two factors,
+/// piecewise constant between pillar dates. Each correlation applies up to
+/// and including its pillar date, and the last one applies indefinitely
+/// after that. Short- and long-dated correlations often differ materially,
+/// for example between the constituents of a basket.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CorrelationTermStructure {
+    pillars: Vec<(Date, f64)>
+}
+
+impl CorrelationTermStructure {
+    /// Creates a term structure from pillar dates, which must be strictly
+    /// increasing, and the correlations up to each of them.
+    pub fn new(pillars: &[(Date, f64)]) -> Result<CorrelationTermStructure, qm::Error> {
+        if pillars.is_empty() {
+            return Err(qm::Error::new("Correlation term structure has no pillars"))
+        }
+        for (i, &(date, correlation)) in pillars.iter().enumerate() {
+            if i > 0 && date <= pillars[i - 1].0 {
+                return Err(qm::Error::new(
+                    "Correlation term structure dates must be increasing"))
+            }
+            if correlation < -1.0 || correlation > 1.0 {
+                return Err(qm::Error::new(&format!(
+                    "Correlation at {} must be between -1 and 1", date)))
+            }
+        }
+        Ok(CorrelationTermStructure { pillars: pillars.to_vec() })
+    }
+
+    /// The instantaneous correlation on the given date
+    pub fn correlation(&self, date: Date) -> f64 {
+        self.pillars.iter().find(|&&(pillar, _)| date <= pillar)
+            .unwrap_or_else(|| self.pillars.last().unwrap()).1
+    }
+
+    /// The average instantaneous correlation over the days after one date
+    /// up to and including another, weighted by calendar days. If the
+    /// period is empty, this is the correlation on the end date.
+    pub fn average(&self, from: Date, to: Date) -> f64 {
+        if to <= from {
+            return self.correlation(to)
+        }
+
+        let mut sum = 0.0;
+        let mut start = from;
+        for &(pillar, correlation) in self.pillars.iter() {
+            if pillar > start {
+                let end = if pillar < to { pillar } else { to };
+                sum += correlation * ((end - start) as f64);
+                start = end;
+            }
+            if start >= to {
+                break
+            }
+        }
+        if start < to {
+            sum += self.pillars.last().unwrap().1 * ((to - start) as f64);
+        }
+        sum / ((to - from) as f64)
+    }
+
+    fn bump(&mut self, bump: &BumpCorrelation) {
+        for pillar in self.pillars.iter_mut() {
+            pillar.1 = bump.apply(pillar.1);
+        }
+    }
+}
+
+fn ordered<'a>(first: &'a str, second: &'a str) -> (&'a str, &'a str) {
+    if first < second { (first, second) } else { (second, first) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use serde_json;
+
+    #[test]
+    fn correlations_are_symmetric() {
+        let mut correlations = Correlations::new();
+        correlations.set("GSK.L", "BP.L", 0.6).unwrap();
+        assert_eq!(correlations.get("BP.L", "GSK.L").unwrap(), 0.6);
+        assert_eq!(correlations.get("GSK.L", "BP.L").unwrap(), 0.6);
+
+        // setting the other way round replaces the value
+        correlations.set("BP.L", "GSK.L", -0.2).unwrap();
+        assert_eq!(correlations.get("GSK.L", "BP.L").unwrap(), -0.2);
+    }
+
+    #[test]
+    fn self_correlation_is_one() {
+        let correlations = Correlations::new();
+        assert_eq!(correlations.get("BP.L", "BP.L").unwrap(), 1.0);
+        assert!(correlations.get("BP.L", "GSK.L").is_err());
+    }
+
+    #[test]
+    fn invalid_correlations_rejected() {
+        let mut correlations = Correlations::new();
+        assert!(correlations.set("BP.L", "GSK.L", 1.1).is_err());
+        assert!(correlations.set("BP.L", "BP.L", 0.5).is_err());
+    }
+
+    #[test]
+    fn correlations_from_matrix() {
+        let mut correlations = Correlations::new();
+        let matrix = Array2::from_shape_vec((3, 3), vec![
+            1.0, 0.5, -0.2,
+            0.5, 1.0, 0.3,
+            -0.2, 0.3, 1.0]).unwrap();
+        correlations.set_matrix(&["BP.L", "GSK.L", "VOD.L"], &matrix).unwrap();
+        assert_eq!(correlations.get("GSK.L", "BP.L").unwrap(), 0.5);
+        assert_eq!(correlations.get("BP.L", "VOD.L").unwrap(), -0.2);
+        assert_eq!(correlations.get("VOD.L", "GSK.L").unwrap(), 0.3);
+
+        let mut asymmetric = matrix.clone();
+        asymmetric[(0, 1)] = 0.4;
+        assert!(correlations.set_matrix(&["BP.L", "GSK.L", "VOD.L"], &asymmetric).is_err());
+        assert!(correlations.set_matrix(&["BP.L", "GSK.L"], &matrix).is_err());
+    }
+
+    #[test]
+    fn bump_correlations() {
+        let mut correlations = Correlations::new();
+        correlations.set("BP.L", "GSK.L", 0.5).unwrap();
+        let bump = BumpCorrelation::new_additive(0.1);
+        assert!(correlations.bump("GSK.L", "BP.L", &bump).unwrap());
+        assert_eq!(correlations.get("BP.L", "GSK.L").unwrap(), 0.6);
+        assert!(!correlations.bump("BP.L", "VOD.L", &bump).unwrap());
+        assert!(!correlations.bump("BP.L", "BP.L", &bump).unwrap());
+    }
+
+    #[test]
+    fn term_structure_averages() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let term = CorrelationTermStructure::new(&[(d + 100, 0.8), (d + 300, 0.4)])
+            .unwrap();
+        assert_eq!(term.correlation(d + 50), 0.8);
+        assert_eq!(term.correlation(d + 100), 0.8);
+        assert_eq!(term.correlation(d + 101), 0.4);
+        assert_eq!(term.correlation(d + 1000), 0.4);
+        assert_eq!(term.average(d, d + 100), 0.8);
+        assert!(approx_eq(term.average(d, d + 200), 0.6, 1e-12));
+        assert!(approx_eq(term.average(d + 200, d + 500), 0.4, 1e-12));
+        assert_eq!(term.average(d + 150, d + 150), 0.4);
+
+        assert!(CorrelationTermStructure::new(&[(d + 100, 0.8), (d + 100, 0.4)]).is_err());
+        assert!(CorrelationTermStructure::new(&[(d + 100, 1.2)]).is_err());
+        assert!(CorrelationTermStructure::new(&[]).is_err());
+    }
+
+    #[test]
+    fn term_structure_overrides_constant() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let mut correlations = Correlations::new();
+        correlations.set("BP.L", "GSK.L", 0.5).unwrap();
+        correlations.set("BP.L", "VOD.L", 0.3).unwrap();
+        correlations.set_term_structure("GSK.L", "BP.L",
+            CorrelationTermStructure::new(&[(d + 100, 0.8), (d + 300, 0.4)]).unwrap())
+            .unwrap();
+        assert_eq!(correlations.get_average("BP.L", "GSK.L", d, d + 100).unwrap(), 0.8);
+        assert_eq!(correlations.get_average("BP.L", "VOD.L", d, d + 100).unwrap(), 0.3);
+        assert_eq!(correlations.get_average("BP.L", "BP.L", d, d + 100).unwrap(), 1.0);
+        assert_eq!(correlations.get("BP.L", "GSK.L").unwrap(), 0.5);
+
+        // bumps apply to the term structure too, and round-trip through serde
+        let bump = BumpCorrelation::new_additive(0.1);
+        assert!(correlations.bump("BP.L", "GSK.L", &bump).unwrap());
+        let serialized = serde_json::to_string(&correlations).unwrap();
+        let deserialized: Correlations = serde_json::from_str(&serialized).unwrap();
+        assert!(approx_eq(deserialized.get_average("GSK.L", "BP.L", d + 100, d + 300)
+            .unwrap(), 0.5, 1e-12));
+    }
+
+    #[test]
+    fn serde_correlations() {
+        let mut correlations = Correlations::new();
+        correlations.set("BP.L", "GBPUSD", -0.3).unwrap();
+        let serialized = serde_json::to_string(&correlations).unwrap();
+        let deserialized: Correlations = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.get("GBPUSD", "BP.L").unwrap(), -0.3);
+    }
+}
@@ -2,6 +2,7 @@ use dates::Date;
 use math::interpolation::Interpolate;
 use math::interpolation::Linear;
 use math::interpolation::Extrap;
+use data::bumpyield::RateBucket;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::Registry;
@@ -81,6 +82,8 @@ pub fn get_registry() -> &'static TypeRegistry {
             reg.insert("AnnualisedFlatBump", BoxFnSeed::new(AnnualisedFlatBump::from_serial));
             reg.insert("ContinuouslyCompoundedFlatBump", BoxFnSeed::new(ContinuouslyCompoundedFlatBump::from_serial));
             reg.insert("RelativeBump", BoxFnSeed::new(RelativeBump::from_serial));
+            reg.insert("KeyRateBump", BoxFnSeed::new(KeyRateBump::from_serial));
+            reg.insert("BucketRelativeBump", BoxFnSeed::new(BucketRelativeBump::from_serial));
             reg
         };
     }
@@ -284,6 +287,89 @@ impl RelativeBump {
     }
 }
 
+/// Decorator that applies a bump in annualised yield to the part of a rate
+/// curve within one bucket, weighted as defined by the bucket. Bumps of
+/// all the buckets of a set of pillars add up to an annualised flat bump.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyRateBump {
+    curve: RcRateCurve,
+    bump: f64,
+    bucket: RateBucket
+}
+
+impl TypeId for KeyRateBump {
+    fn type_id(&self) -> &'static str { "KeyRateBump" }
+}
+
+impl RateCurve for KeyRateBump {
+
+    fn r_and_t(&self, date: Date) -> Result<(f64, f64), qm::Error> {
+        // as for AnnualisedFlatBump, but with the bump weighted by date
+        let (r, t) = self.curve.r_and_t(date)?;
+        let weight = self.bucket.weight(date);
+        if weight == 0.0 {
+            return Ok((r, t))
+        }
+
+        let r_bumped = (r.exp() + self.bump * weight).ln();
+        Ok((r_bumped, t))
+    }
+
+    fn base_date(&self) -> Date {
+        self.curve.base_date()
+    }
+}
+
+impl KeyRateBump {
+    pub fn new(curve: RcRateCurve, bump: f64, bucket: RateBucket) -> KeyRateBump {
+        KeyRateBump { curve: curve, bump: bump, bucket: bucket }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcRateCurve, esd::Error> {
+        Ok(Qrc::new(Arc::new(KeyRateBump::deserialize(de)?)))
+    }
+}
+
+/// Decorator that applies a relative bump to the part of a rate curve
+/// within one bucket, weighted as defined by the bucket. As with
+/// RelativeBump, this is in continuously compounded yield.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BucketRelativeBump {
+    curve: RcRateCurve,
+    bump: f64,
+    bucket: RateBucket
+}
+
+impl TypeId for BucketRelativeBump {
+    fn type_id(&self) -> &'static str { "BucketRelativeBump" }
+}
+
+impl RateCurve for BucketRelativeBump {
+
+    fn r_and_t(&self, date: Date) -> Result<(f64, f64), qm::Error> {
+        let (r, t) = self.curve.r_and_t(date)?;
+        Ok((r * (1.0 + self.bump * self.bucket.weight(date)), t))
+    }
+
+    fn base_date(&self) -> Date {
+        self.curve.base_date()
+    }
+
+    fn is_zero(&self) -> bool {
+        self.curve.is_zero()
+    }
+}
+
+impl BucketRelativeBump {
+    pub fn new(curve: RcRateCurve, bump: f64, bucket: RateBucket) -> BucketRelativeBump {
+        BucketRelativeBump { curve: curve, bump: bump, bucket: bucket }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcRateCurve, esd::Error> {
+        Ok(Qrc::new(Arc::new(BucketRelativeBump::deserialize(de)?)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2,6 +2,8 @@ use dates::Date;
 use data::curves::RcRateCurve;
 use data::curves::RateCurve;
 use data::curves::RelativeBump;
+use data::curves::BucketRelativeBump;
+use data::bumpyield::RateBucket;
 use data::forward::log_discount_with_borrow;
 use data::forward::discount_with_borrow;
 use core::qm;
@@ -62,6 +64,13 @@ impl Dividend {
     }
 }
 
+/// Once a dividend has gone ex, its amount is known, and is recorded as a
+/// fixing on its ex date. Dividend fixings are kept separate from fixings of
+/// the price of the equity, under an id derived from that of the equity.
+pub fn dividend_fixing_id(id: &str) -> String {
+    format!("{}:div", id)
+}
+
 /// A dividend stream represents all of the dividends and dividend-like
 /// corporate actions that affect an equity underlier. Most corporate
 /// actions are treated by option exchanges to minimise the impact. However,
@@ -113,7 +122,7 @@ impl DividendStream {
            div.bump_all_relative(one_plus_bump);
         }
 
-        let bumped_yield = RcRateCurve::new(Arc::new(RelativeBump::new(divs.div_yield(), bump))); 
+        let bumped_yield = bump_div_yield(&divs.div_yield, bump, None);
 
         DividendStream {
             dividends: bumped_divs,
@@ -121,11 +130,63 @@ impl DividendStream {
             last_cash_ex_date: divs.last_cash_ex_date }
     }
 
+    /// Constructor used when bumping. Applies a relative bump to the cash
+    /// amounts of the dividends, leaving the relative amounts and the
+    /// dividend yield unchanged. If a bucket is given, the bump is weighted
+    /// by the ex date of each dividend.
+    pub fn new_bump_cash(divs: &DividendStream, bump: f64, bucket: Option<&RateBucket>)
+        -> DividendStream {
+
+        let mut bumped_divs = divs.dividends.to_vec();
+        for div in bumped_divs.iter_mut() {
+            div.cash *= 1.0 + bump * bucket.map_or(1.0, |b| b.weight(div.ex_date));
+        }
+
+        DividendStream {
+            dividends: bumped_divs,
+            div_yield: divs.div_yield(),
+            last_cash_ex_date: divs.last_cash_ex_date }
+    }
+
+    /// Constructor used when bumping. Applies a relative bump to the parts
+    /// of the dividends that are proportional to the forward, which are the
+    /// relative amounts and the dividend yield, leaving the cash amounts
+    /// unchanged. If a bucket is given, the bump is weighted by the ex date
+    /// of each dividend, and by date for the yield.
+    pub fn new_bump_yield(divs: &DividendStream, bump: f64, bucket: Option<&RateBucket>)
+        -> DividendStream {
+
+        let mut bumped_divs = divs.dividends.to_vec();
+        for div in bumped_divs.iter_mut() {
+            div.relative *= 1.0 + bump * bucket.map_or(1.0, |b| b.weight(div.ex_date));
+        }
+
+        DividendStream {
+            dividends: bumped_divs,
+            div_yield: bump_div_yield(&divs.div_yield, bump, bucket),
+            last_cash_ex_date: divs.last_cash_ex_date }
+    }
+
     pub fn dividends(&self) -> &[Dividend] { &self.dividends }
     pub fn div_yield(&self) -> RcRateCurve { self.div_yield.clone() }
     pub fn last_cash_ex_date(&self) -> Date { self.last_cash_ex_date }
 }
 
+/// Applies a relative bump to a dividend yield. A zero yield is left as it
+/// is, both because bumping it has no effect and because the dividend
+/// bootstrap only allows a yield to overlap the dividends if it is zero.
+fn bump_div_yield(div_yield: &RcRateCurve, bump: f64, bucket: Option<&RateBucket>)
+    -> RcRateCurve {
+    if div_yield.is_zero() {
+        return div_yield.clone()
+    }
+    match bucket {
+        None => RcRateCurve::new(Arc::new(RelativeBump::new(div_yield.clone(), 1.0 + bump))),
+        Some(bucket) => RcRateCurve::new(Arc::new(BucketRelativeBump::new(
+            div_yield.clone(), bump, bucket.clone())))
+    }
+}
+
 /// Create a type RcDividendStream to allow us to implement serialization
 /// and deserialization
 #[derive(Clone, Debug)]
@@ -288,7 +349,7 @@ impl DividendBootstrap {
                 discounted_cash;
             for acc in accumulation.iter_mut().rev() {
                 acc.discounted_cash_remaining = 
-                    final_discounted_cash - acc.discounted_cash_remaining;
+                    final_discounted_cash - acc.discounted_cash;
             }
         }
 
@@ -403,12 +464,15 @@ impl DividendBootstrap {
             // if we found it, return the remaining cash at this div
             Ok(i) => Ok(self.accumulation[i].discounted_cash_remaining),
 
-            // If we missed and it was after the last date, return zero.
-            // This also catches the case where there are no dividends
-            Err(i) => if i == self.accumulation.len() { Ok(0.0) } else {
+            // If we missed and it was before the first date, all the cash
+            // is remaining. This also catches the case where there are no
+            // dividends
+            Err(i) => if i == 0 {
+                Ok(self.accumulation.last().map_or(0.0, |acc| acc.discounted_cash))
+            } else {
 
-                // otherwise, return the remaining cash at the next div
-                Ok(self.accumulation[i].discounted_cash_remaining)
+                // otherwise, return the remaining cash at the prev div
+                Ok(self.accumulation[i-1].discounted_cash_remaining)
             }
         }
     }
@@ -470,6 +534,26 @@ mod tests {
         assert_cash(b.discounted_sum(d + 28, d + 210), 0.9562351685379344);
     }
 
+    #[test]
+    fn check_discounted_cash_divs_after() {
+
+        let d = Date::from_ymd(2017, 01, 02);
+        let divs = create_sample_divstream();
+        let b = create_sample_bootstrap(&divs, d + 1000);
+
+        // dividends on the date are not included, so the first div is the
+        // difference between the first two levels
+        assert_cash(b.discounted_cash_divs_after(d), 2.142252560842762);
+        assert_cash(b.discounted_cash_divs_after(d + 27), 2.142252560842762);
+        assert_cash(b.discounted_cash_divs_after(d + 28),
+            2.142252560842762 - 1.192633077939713);
+        assert_cash(b.discounted_cash_divs_after(d + 209), 0.9496194829030491);
+        assert_cash(b.discounted_cash_divs_after(d + 210), 0.18492498764797194);
+        assert_cash(b.discounted_cash_divs_after(d + 391), 0.18492498764797194);
+        assert_cash(b.discounted_cash_divs_after(d + 392), 0.0);
+        assert_cash(b.discounted_cash_divs_after(d + 800), 0.0);
+    }
+
     fn create_sample_divstream() -> DividendStream {
 
         // Early divs are purely cash. Later ones are mixed cash/relative
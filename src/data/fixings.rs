@@ -1,7 +1,12 @@
 use dates::Date;
 use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use dates::calendar::RcCalendar;
+use dates::daycount::DayCount;
+use data::curves::RateCurve;
 use core::qm;
 use std::collections::HashMap;
+use std::collections::hash_map::Iter;
 use std::sync::Arc;
 use std::ops::Deref;
 use serde as sd;
@@ -104,6 +109,103 @@ pub fn missing_fixing(id: &str, date_time: DateTime) -> qm::Error {
     qm::Error::new(&format!("Missing fixing for \"{}\" at {}", id, date_time))
 }
 
+/// A fixing of a floating rate index such as Libor, which is not itself an
+/// instrument. As well as when it fixes, this says how the rate can be
+/// projected from a forecast curve, so that fixings can be generated when
+/// the spot date moves past the fixing date.
+#[derive(Clone, Debug)]
+pub struct RateFixing {
+    date: DateTime,
+    forecast_id: String,
+    start: Date,
+    end: Date,
+    day_count: DayCount
+}
+
+impl RateFixing {
+    /// Creates a rate fixing, which fixes at the given date and time, and
+    /// represents simple interest between the start and end dates under the
+    /// given day count. The forecast id identifies the yield curve used to
+    /// project it.
+    pub fn new(date: DateTime, forecast_id: &str, start: Date, end: Date,
+        day_count: DayCount) -> RateFixing {
+        RateFixing { date: date, forecast_id: forecast_id.to_string(),
+            start: start, end: end, day_count: day_count }
+    }
+
+    pub fn date(&self) -> DateTime { self.date }
+    pub fn forecast_id(&self) -> &str { &self.forecast_id }
+    pub fn start(&self) -> Date { self.start }
+    pub fn end(&self) -> Date { self.end }
+
+    /// Projects the rate from the given forecast curve
+    pub fn forward(&self, forecast: &RateCurve) -> Result<f64, qm::Error> {
+        let year_fraction = self.day_count.year_fraction(self.start, self.end);
+        if year_fraction <= 0.0 {
+            return Err(qm::Error::new("Rate fixing must accrue over a positive period"))
+        }
+        Ok((forecast.df(self.start, self.end)? - 1.0) / year_fraction)
+    }
+}
+
+/// A run of daily fixings of an underlier, on every business day of a
+/// calendar from the first to the last date inclusive, all at the same time
+/// of day. Instruments that observe daily, such as range accruals, register
+/// their observations as ranges rather than one fixing per day, so that
+/// the dependencies stay small however long the observation period.
+#[derive(Clone, Debug)]
+pub struct FixingRange {
+    first: Date,
+    last: Date,
+    time_of_day: TimeOfDay,
+    calendar: RcCalendar
+}
+
+impl FixingRange {
+    pub fn new(first: Date, last: Date, time_of_day: TimeOfDay,
+        calendar: RcCalendar) -> FixingRange {
+        FixingRange { first: first, last: last, time_of_day: time_of_day,
+            calendar: calendar }
+    }
+
+    pub fn first(&self) -> Date { self.first }
+    pub fn last(&self) -> Date { self.last }
+    pub fn time_of_day(&self) -> TimeOfDay { self.time_of_day }
+    pub fn calendar(&self) -> &RcCalendar { &self.calendar }
+
+    /// The fixings in this range that fall on or after the from date and
+    /// before the to date, in increasing order
+    pub fn dates_between(&self, from: Date, to: Date) -> Vec<DateTime> {
+        let mut dates = Vec::new();
+        let mut date = if from > self.first { from } else { self.first };
+        while date <= self.last && date < to {
+            if !self.calendar.is_holiday(date) {
+                dates.push(DateTime::new(date, self.time_of_day));
+            }
+            date += 1;
+        }
+        dates
+    }
+
+    /// Extends this range to cover the other one, if they fix at the same
+    /// time of day on the same calendar, and they overlap or abut. Returns
+    /// false, leaving this range unchanged, if they cannot be merged.
+    pub fn merge(&mut self, other: &FixingRange) -> bool {
+        if self.time_of_day != other.time_of_day
+            || self.calendar.name() != other.calendar.name()
+            || other.first > self.last + 1 || self.first > other.last + 1 {
+            return false
+        }
+        if other.first < self.first {
+            self.first = other.first;
+        }
+        if other.last > self.last {
+            self.last = other.last;
+        }
+        true
+    }
+}
+
 fn duplicate_fixing_curve(id: &str) -> qm::Error {
     qm::Error::new(&format!("Duplicate fixing curve supplied for {}", id))
 }
@@ -235,6 +337,11 @@ impl Fixings {
             None
         }
     }
+
+    /// Iterates over all the fixings, in no particular order
+    pub fn iter(&self) -> Iter<DateTime, f64> {
+        self.fixing_by_date.iter()
+    }
 }
 
 fn duplicate_fixing(id: &str, v1: f64, v2: f64, date_time: DateTime)
@@ -247,6 +354,9 @@ fn duplicate_fixing(id: &str, v1: f64, v2: f64, date_time: DateTime)
 mod tests {
     use super::*;
     use dates::datetime::TimeOfDay;
+    use data::curves::RateCurveAct365;
+    use math::interpolation::Extrap;
+    use math::numerics::approx_eq;
     use serde_json;
 
     fn sample_fixings() -> FixingTable {
@@ -361,4 +471,39 @@ mod tests {
             assert!(false, "missing fixing");
         }
     }
+
+    #[test]
+    fn rate_fixing_projected_from_curve() {
+        let base = Date::from_ymd(2017, 01, 02);
+        let points = [(base, 0.05), (base + 365, 0.05)];
+        let curve = RateCurveAct365::new(base, &points, Extrap::Flat, Extrap::Flat).unwrap();
+        let fixing = RateFixing::new(DateTime::new(base, TimeOfDay::Close), "LSE",
+            base + 2, base + 184, DayCount::Act365);
+
+        // simple interest equivalent to five percent continuously compounded
+        let expected = ((0.05_f64 * 182.0 / 365.0).exp() - 1.0) * 365.0 / 182.0;
+        assert!(approx_eq(fixing.forward(&curve).unwrap(), expected, 1e-12));
+
+        let empty = RateFixing::new(DateTime::new(base, TimeOfDay::Close), "LSE",
+            base + 2, base + 2, DayCount::Act365);
+        assert!(empty.forward(&curve).is_err());
+    }
+
+    #[test]
+    fn fixing_range_skips_holidays() {
+        use dates::calendar::WeekdayCalendar;
+
+        // Monday 1 January to Friday 12 January 2018
+        let first = Date::from_ymd(2018, 01, 01);
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let range = FixingRange::new(first, first + 11, TimeOfDay::Close, calendar);
+        assert_eq!(range.dates_between(first - 10, first + 20).len(), 10);
+
+        // from Thursday to the following Tuesday, exclusive
+        let dates = range.dates_between(first + 3, first + 8);
+        assert_eq!(dates, vec![DateTime::new(first + 3, TimeOfDay::Close),
+            DateTime::new(first + 4, TimeOfDay::Close),
+            DateTime::new(first + 7, TimeOfDay::Close)]);
+        assert!(range.dates_between(first + 12, first + 20).is_empty());
+    }
 }
@@ -5,7 +5,10 @@ use data::divstream::DividendBootstrap;
 use data::divstream::DividendStream;
 use data::curves::RcRateCurve;
 use data::curves::RateCurve;
+use data::volsurface::RcVolSurface;
+use dates::datetime::DateDayFraction;
 use core::qm;
+use std::sync::Arc;
 
 /// Forward curve. This represents the expectation value of some asset over
 /// time. It is implemented in different ways for futures (generally driftless)
@@ -26,6 +29,14 @@ pub trait Forward : Interpolate<Date> + Sync + Send {
     fn fixed_divs_after(&self, _date: Date) -> Result<f64, qm::Error> {
         Ok(0.0)
     }
+
+    /// Returns the undiscounted sum of the cash and relative dividends that
+    /// go ex after the from date, up to and including the to date. Dividends
+    /// before the base date of the forward are not included. Defaults to
+    /// returning zero, because many sorts of forwards have no dividends.
+    fn undiscounted_divs(&self, _from: Date, _to: Date) -> Result<f64, qm::Error> {
+        Ok(0.0)
+    }
 }
 
 /// Allow any forward to be treated as an interpolator by date
@@ -113,6 +124,10 @@ impl Forward for EquityForward {
     fn fixed_divs_after(&self, date: Date) -> Result<f64, qm::Error> {
         self.bootstrap.discounted_cash_divs_after(date)
     }
+
+    fn undiscounted_divs(&self, from: Date, to: Date) -> Result<f64, qm::Error> {
+        self.bootstrap.undiscounted_sum(from, to)
+    }
 }
 
 impl EquityForward {
@@ -154,6 +169,116 @@ impl EquityForward {
     }
 }
 
+/// Forward of an FX rate, implied by covered interest parity from the
+/// discount curves of the two currencies. The rate is quoted as units of
+/// the domestic currency per unit of the foreign currency, so the forward
+/// grows at the domestic rate less the foreign rate. Both the spot and the
+/// forwards are for delivery after the settlement period of the rate.
+pub struct InterestParityForward {
+    settlement: RcDateRule,
+    domestic: RcRateCurve,
+    foreign: RcRateCurve,
+    spot: f64,
+    base_log_growth: f64
+}
+
+impl Forward for InterestParityForward {
+    fn as_interp(&self) -> &Interpolate<Date> { self }
+
+    fn forward(&self, date: Date) -> Result<f64, qm::Error> {
+        let pay_date = self.settlement.apply(date);
+        let log_growth = self.domestic.rt(pay_date)? - self.foreign.rt(pay_date)?;
+        Ok(self.spot * (log_growth - self.base_log_growth).exp())
+    }
+}
+
+impl InterestParityForward {
+    pub fn new(
+        base_date: Date,
+        spot: f64,
+        settlement: RcDateRule,
+        domestic: RcRateCurve,
+        foreign: RcRateCurve) -> Result<InterestParityForward, qm::Error> {
+
+        // The spot is for delivery on the spot date, so growth is measured
+        // from then rather than from the base date
+        let spot_date = settlement.apply(base_date);
+        let base_log_growth = domestic.rt(spot_date)? - foreign.rt(spot_date)?;
+
+        Ok(InterestParityForward {
+            settlement: settlement,
+            domestic: domestic,
+            foreign: foreign,
+            spot: spot,
+            base_log_growth: base_log_growth })
+    }
+}
+
+/// Forward of an asset, as seen by an instrument that pays in a different
+/// currency at a fixed rate of exchange (a quanto). The drift of the asset
+/// is corrected by minus the covariance of the asset with the FX rate,
+/// where the FX rate is quoted as units of the payment currency per unit of
+/// the asset currency.
+///
+/// The correction is only applied to the lognormal part of the asset,
+/// excluding any displacement from fixed cash dividends.
+pub struct QuantoForward {
+    base: Arc<Forward>,
+    asset_vol: RcVolSurface,
+    fx_vol: RcVolSurface,
+    fx_spot: f64,
+    correlation: f64
+}
+
+impl Forward for QuantoForward {
+    fn as_interp(&self) -> &Interpolate<Date> { self }
+
+    fn forward(&self, date: Date) -> Result<f64, qm::Error> {
+        let forward = self.base.forward(date)?;
+        let displacement = self.asset_vol.displacement(date)?;
+        let adjustment = quanto_adjustment(&self.asset_vol, &self.fx_vol,
+            self.correlation, DateDayFraction::new(date, 0.0),
+            forward, self.fx_spot)?;
+        Ok(displacement + (forward - displacement) * adjustment)
+    }
+
+    fn fixed_divs_after(&self, date: Date) -> Result<f64, qm::Error> {
+        self.base.fixed_divs_after(date)
+    }
+
+    fn undiscounted_divs(&self, from: Date, to: Date) -> Result<f64, qm::Error> {
+        self.base.undiscounted_divs(from, to)
+    }
+}
+
+impl QuantoForward {
+    pub fn new(base: Arc<Forward>, asset_vol: RcVolSurface,
+        fx_vol: RcVolSurface, fx_spot: f64, correlation: f64)
+        -> QuantoForward {
+        QuantoForward { base, asset_vol, fx_vol, fx_spot, correlation }
+    }
+}
+
+/// Returns the factor by which the lognormal part of a forward must be
+/// multiplied to give the forward of a quanto, which is exp(-rho sqrt(V_S V_X)),
+/// where V_S and V_X are the variances of the asset and the FX rate to the
+/// given date. The variances are read at the given asset forward and FX spot.
+/// This is exact where both vol surfaces are flat with the same calendar, and
+/// a common approximation otherwise.
+pub fn quanto_adjustment(asset_vol: &RcVolSurface, fx_vol: &RcVolSurface,
+    correlation: f64, date_time: DateDayFraction, asset_forward: f64,
+    fx_spot: f64) -> Result<f64, qm::Error> {
+
+    if correlation == 0.0 {
+        return Ok(1.0)
+    }
+
+    // variances before the base date of the surfaces are zero
+    let asset_var = asset_vol.variance(date_time, asset_forward)?.max(0.0);
+    let fx_var = fx_vol.variance(date_time, fx_spot)?.max(0.0);
+    Ok((-correlation * (asset_var * fx_var).sqrt()).exp())
+}
+
 /// Within a forward, all discounting and growth is done using
 /// exp(-rt + qt), i.e. using both the discount curve and the borrow
 /// curve. This is because the forward model is funded by repoing out the
@@ -194,6 +319,7 @@ mod tests {
     use dates::calendar::WeekdayCalendar;
     use dates::rules::BusinessDays;
     use dates::calendar::RcCalendar;
+    use data::volsurface::FlatVolSurface;
 
     #[test]
     fn driftless_forward() {
@@ -253,6 +379,79 @@ mod tests {
         assert_match(fwd.forward(d+1500), 125.93011849243018);
     }
 
+    #[test]
+    fn equity_forward_fixed_divs() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let divs = create_sample_divstream();
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar{}));
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 2)));
+        let fwd = EquityForward::new(d, 97.0, settlement, create_sample_rate(),
+            create_sample_borrow(), &divs, d + 1500).unwrap();
+
+        // the cash divs still to come drop on each ex date, and stay the
+        // same in between
+        let all = fwd.fixed_divs_after(d).unwrap();
+        assert!(all > 2.0 && all < 2.2, "all={}", all);
+        assert_match(fwd.fixed_divs_after(d + 27), all);
+        let after_first = fwd.fixed_divs_after(d + 28).unwrap();
+        assert!(after_first < all - 1.1, "after_first={}", after_first);
+        assert_match(fwd.fixed_divs_after(d + 100), after_first);
+        assert_match(fwd.fixed_divs_after(d + 392), 0.0);
+        assert_match(fwd.fixed_divs_after(d + 1000), 0.0);
+    }
+
+    #[test]
+    fn quanto_forward() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar{}));
+        let base = DateDayFraction::new(d, 0.2);
+        let asset_vol = RcVolSurface::new(Arc::new(
+            FlatVolSurface::new(0.3, calendar.clone(), base)));
+        let fx_vol = RcVolSurface::new(Arc::new(
+            FlatVolSurface::new(0.1, calendar.clone(), base)));
+        let base_fwd: Arc<Forward> = Arc::new(DriftlessForward::new(100.0));
+
+        // negative correlation with the FX rate makes the quanto forward
+        // higher than the forward in the asset's own currency
+        let fwd = QuantoForward::new(base_fwd.clone(), asset_vol.clone(),
+            fx_vol.clone(), 1.3, -0.5);
+        let expiry = d + 365;
+        let t = asset_vol.vol_time(DateDayFraction::new(expiry, 0.0)).unwrap();
+        assert_match(fwd.forward(expiry), 100.0 * (0.5 * 0.3 * 0.1 * t).exp());
+        assert!(fwd.forward(expiry).unwrap() > 100.0);
+
+        // before the base date of the surfaces there is no adjustment
+        assert_match(fwd.forward(d - 10), 100.0);
+
+        // with no correlation there is no adjustment
+        let uncorrelated = QuantoForward::new(base_fwd, asset_vol, fx_vol,
+            1.3, 0.0);
+        assert_match(uncorrelated.forward(expiry), 100.0);
+    }
+
+    #[test]
+    fn interest_parity_forward() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar{}));
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 2)));
+        let domestic = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, 0.03)], Extrap::Flat, Extrap::Flat).unwrap()));
+        let foreign = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, 0.01)], Extrap::Flat, Extrap::Flat).unwrap()));
+
+        let fwd = InterestParityForward::new(d, 1.25, settlement.clone(),
+            domestic, foreign).unwrap();
+
+        // spot is delivered on the spot date, so the forward there is spot
+        assert_match(fwd.forward(d), 1.25);
+
+        // thereafter it grows at the difference in rates, between the
+        // settlement dates
+        let expiry = d + 365;
+        let days = settlement.apply(expiry) - settlement.apply(d);
+        assert_match(fwd.forward(expiry), 1.25 * (0.02 * days as f64 / 365.0).exp());
+    }
+
     fn create_sample_divstream() -> DividendStream {
 
         // Early divs are purely cash. Later ones are mixed cash/relative
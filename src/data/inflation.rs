@@ -0,0 +1,144 @@
+use dates::Date;
+use data::curves::RcRateCurve;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Registry;
+use core::factories::Qrc;
+use std::sync::Arc;
+use std::fmt::Debug;
+use erased_serde as esd;
+use serde as sd;
+use serde_tagged as sdt;
+use serde_tagged::de::BoxFnSeed;
+use serde::Deserialize;
+
+/// An inflation curve gives the expected future values of a price index,
+/// such as a consumer price index. Index values are published monthly,
+/// some time after the end of the month they refer to. Reference months
+/// are identified by their first day.
+pub trait InflationCurve : esd::Serialize + TypeId + Send + Sync + Debug {
+
+    /// Returns the expected value of the index for the reference month
+    /// starting on the given date.
+    fn forward_index(&self, month: Date) -> Result<f64, qm::Error>;
+
+    /// The reference month of the last known value of the index
+    fn base_month(&self) -> Date;
+}
+
+// Get serialization to work recursively for inflation curves by using the
+// technology defined in core/factories. RcInflationCurve is a container
+// class holding an InflationCurve
+pub type RcInflationCurve = Qrc<InflationCurve>;
+pub type TypeRegistry = Registry<BoxFnSeed<RcInflationCurve>>;
+
+/// Implement deserialization for subclasses of the type
+impl<'de> sd::Deserialize<'de> for RcInflationCurve {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: sd::Deserializer<'de>
+    {
+        sdt::de::external::deserialize(deserializer, get_registry())
+    }
+}
+
+/// Return the type registry required for deserialization.
+pub fn get_registry() -> &'static TypeRegistry {
+    lazy_static! {
+        static ref REG: TypeRegistry = {
+            let mut reg = TypeRegistry::new();
+            reg.insert("BreakevenInflationCurve", BoxFnSeed::new(BreakevenInflationCurve::from_serial));
+            reg
+        };
+    }
+    &REG
+}
+
+/// An inflation curve defined by the last known value of the index and a
+/// curve of breakeven inflation rates from its reference month. Breakevens
+/// are held as a rate curve, so the index grows as one over its discount
+/// factors.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BreakevenInflationCurve {
+    base_month: Date,
+    base_index: f64,
+    breakevens: RcRateCurve
+}
+
+impl TypeId for BreakevenInflationCurve {
+    fn type_id(&self) -> &'static str { "BreakevenInflationCurve" }
+}
+
+impl BreakevenInflationCurve {
+    /// Creates an inflation curve, given the first day of the reference month
+    /// of the last known index value, the value itself and the breakevens.
+    pub fn new(base_month: Date, base_index: f64, breakevens: RcRateCurve)
+        -> Result<BreakevenInflationCurve, qm::Error> {
+        if base_month.ymd().2 != 1 {
+            return Err(qm::Error::new("Base month must be the first day of a month"))
+        }
+        if !(base_index > 0.0) {
+            return Err(qm::Error::new("Inflation index must be positive"))
+        }
+        Ok(BreakevenInflationCurve { base_month: base_month,
+            base_index: base_index, breakevens: breakevens })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcInflationCurve, esd::Error> {
+        Ok(Qrc::new(Arc::new(BreakevenInflationCurve::deserialize(de)?)))
+    }
+}
+
+impl InflationCurve for BreakevenInflationCurve {
+    fn forward_index(&self, month: Date) -> Result<f64, qm::Error> {
+        Ok(self.base_index / self.breakevens.df(month, self.base_month)?)
+    }
+
+    fn base_month(&self) -> Date { self.base_month }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::interpolation::Extrap;
+    use data::curves::RateCurveAct365;
+    use serde_json;
+
+    fn sample_inflation_curve() -> BreakevenInflationCurve {
+        let base = Date::from_ymd(2016, 10, 01);
+        let breakevens = RcRateCurve::new(Arc::new(RateCurveAct365::new(base,
+            &[(base, 0.03), (base + 3650, 0.03)], Extrap::Flat, Extrap::Flat).unwrap()));
+        BreakevenInflationCurve::new(base, 260.0, breakevens).unwrap()
+    }
+
+    #[test]
+    fn breakeven_curve_grows_the_index() {
+        let curve = sample_inflation_curve();
+        assert_approx(curve.forward_index(Date::from_ymd(2016, 10, 01)).unwrap(), 260.0);
+        let later = curve.forward_index(Date::from_ymd(2017, 10, 01)).unwrap();
+        assert!(later > 267.0 && later < 268.5, "later={}", later);
+    }
+
+    #[test]
+    fn breakeven_curve_rejects_mid_month_base() {
+        let base = Date::from_ymd(2016, 10, 15);
+        let breakevens = RcRateCurve::new(Arc::new(RateCurveAct365::new(base,
+            &[(base, 0.03)], Extrap::Flat, Extrap::Flat).unwrap()));
+        assert!(BreakevenInflationCurve::new(base, 260.0, breakevens).is_err());
+    }
+
+    #[test]
+    fn serde_inflation_curve_roundtrip() {
+        let curve = RcInflationCurve::new(Arc::new(sample_inflation_curve()));
+        let serialized = serde_json::to_string_pretty(&curve).unwrap();
+        let deserialized: RcInflationCurve = serde_json::from_str(&serialized).unwrap();
+        let month = Date::from_ymd(2018, 03, 01);
+        assert_approx(deserialized.forward_index(month).unwrap(),
+            curve.forward_index(month).unwrap());
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-12),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -1,13 +1,18 @@
 pub mod bump;
+pub mod bumpcorrelation;
 pub mod bumpdivs;
 pub mod bumpspot;
 pub mod bumpspotdate;
 pub mod bumpvol;
 pub mod bumpyield;
+pub mod commodity;
+pub mod correlations;
 pub mod curves;
 pub mod divstream;
 pub mod fixings;
 pub mod forward;
+pub mod inflation;
+pub mod volcube;
 pub mod voldecorators;
 pub mod volsmile;
 pub mod volsurface;
@@ -0,0 +1,459 @@
+use dates::Date;
+use data::bumpvol::VolBucket;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Registry;
+use core::factories::Qrc;
+use std::sync::Arc;
+use std::fmt::Debug;
+use erased_serde as esd;
+use serde as sd;
+use serde_tagged as sdt;
+use serde_tagged::de::BoxFnSeed;
+use serde::Deserialize;
+
+/// A vol cube supplies the volatilities used for pricing swaptions, caps
+/// and other options on interest rates. Unlike an equity vol surface, the
+/// vol depends on the tenor of the underlying rate as well as the expiry of
+/// the option and its strike.
+///
+/// Vols are lognormal Black vols of the forward rate. Vol time is
+/// measured as Act/365 from the spot date, so the expiry dates of the
+/// vols stay fixed as time moves on.
+pub trait VolCube : esd::Serialize + TypeId + Send + Sync + Debug {
+
+    /// Returns the volatility for an option expiring on the given date, on
+    /// a swap with the given tenor in years. The forward swap rate is
+    /// supplied, so that smiles can be expressed relative to it.
+    fn volatility(&self, expiry: Date, tenor: f64, forward: f64, strike: f64)
+        -> Result<f64, qm::Error>;
+
+    /// The date the vols were marked
+    fn base_date(&self) -> Date;
+}
+
+// Get serialization to work recursively for vol cubes by using the
+// technology defined in core/factories. RcVolCube is a container
+// class holding a VolCube
+pub type RcVolCube = Qrc<VolCube>;
+pub type TypeRegistry = Registry<BoxFnSeed<RcVolCube>>;
+
+/// Implement deserialization for subclasses of the type
+impl<'de> sd::Deserialize<'de> for RcVolCube {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: sd::Deserializer<'de>
+    {
+        sdt::de::external::deserialize(deserializer, get_registry())
+    }
+}
+
+/// Return the type registry required for deserialization.
+pub fn get_registry() -> &'static TypeRegistry {
+    lazy_static! {
+        static ref REG: TypeRegistry = {
+            let mut reg = TypeRegistry::new();
+            reg.insert("FlatVolCube", BoxFnSeed::new(FlatVolCube::from_serial));
+            reg.insert("InterpolatedVolCube", BoxFnSeed::new(InterpolatedVolCube::from_serial));
+            reg.insert("CapletVols", BoxFnSeed::new(CapletVols::from_serial));
+            reg.insert("ParallelBumpVolCube", BoxFnSeed::new(ParallelBumpVolCube::from_serial));
+            reg.insert("TimeScaledBumpVolCube", BoxFnSeed::new(TimeScaledBumpVolCube::from_serial));
+            reg.insert("BucketBumpVolCube", BoxFnSeed::new(BucketBumpVolCube::from_serial));
+            reg
+        };
+    }
+    &REG
+}
+
+/// A vol cube with the same vol for all expiries, tenors and strikes
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FlatVolCube {
+    vol: f64,
+    base_date: Date
+}
+
+impl TypeId for FlatVolCube {
+    fn type_id(&self) -> &'static str { "FlatVolCube" }
+}
+
+impl FlatVolCube {
+    pub fn new(vol: f64, base_date: Date) -> FlatVolCube {
+        FlatVolCube { vol: vol, base_date: base_date }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolCube, esd::Error> {
+        Ok(Qrc::new(Arc::new(FlatVolCube::deserialize(de)?)))
+    }
+}
+
+impl VolCube for FlatVolCube {
+    fn volatility(&self, _expiry: Date, _tenor: f64, _forward: f64, _strike: f64)
+        -> Result<f64, qm::Error> {
+        Ok(self.vol)
+    }
+
+    fn base_date(&self) -> Date { self.base_date }
+}
+
+/// A vol cube defined by a grid of vols, by expiry date, swap tenor in
+/// years and strike offset from the forward swap rate. Vols are linearly
+/// interpolated in each of the three directions, and extrapolated flat.
+/// The vols are stored with the strike offset varying fastest, then tenor,
+/// then expiry.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InterpolatedVolCube {
+    base_date: Date,
+    expiries: Vec<Date>,
+    tenors: Vec<f64>,
+    strike_offsets: Vec<f64>,
+    vols: Vec<f64>
+}
+
+impl TypeId for InterpolatedVolCube {
+    fn type_id(&self) -> &'static str { "InterpolatedVolCube" }
+}
+
+impl InterpolatedVolCube {
+    /// Creates a vol cube. Each axis must be non-empty and strictly
+    /// increasing, and the number of vols must be the product of the sizes
+    /// of the axes. Supply a single strike offset of zero for a cube that
+    /// has ATM vols only.
+    pub fn new(base_date: Date, expiries: &[Date], tenors: &[f64],
+        strike_offsets: &[f64], vols: &[f64]) -> Result<InterpolatedVolCube, qm::Error> {
+
+        let expiry_axis: Vec<f64> = expiries.iter()
+            .map(|d| (*d - base_date) as f64).collect();
+        validate_axis(&expiry_axis, "expiries")?;
+        validate_axis(tenors, "tenors")?;
+        validate_axis(strike_offsets, "strike offsets")?;
+        if vols.len() != expiries.len() * tenors.len() * strike_offsets.len() {
+            return Err(qm::Error::new("Vol cube size does not match its axes"))
+        }
+
+        Ok(InterpolatedVolCube { base_date: base_date, expiries: expiries.to_vec(),
+            tenors: tenors.to_vec(), strike_offsets: strike_offsets.to_vec(),
+            vols: vols.to_vec() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolCube, esd::Error> {
+        Ok(Qrc::new(Arc::new(InterpolatedVolCube::deserialize(de)?)))
+    }
+
+    fn vol_at(&self, expiry: usize, tenor: usize, strike: usize) -> f64 {
+        let n_strikes = self.strike_offsets.len();
+        self.vols[(expiry * self.tenors.len() + tenor) * n_strikes + strike]
+    }
+}
+
+impl VolCube for InterpolatedVolCube {
+    fn volatility(&self, expiry: Date, tenor: f64, forward: f64, strike: f64)
+        -> Result<f64, qm::Error> {
+
+        let expiry_axis: Vec<f64> = self.expiries.iter()
+            .map(|d| (*d - self.base_date) as f64).collect();
+        let e = bracket(&expiry_axis, (expiry - self.base_date) as f64);
+        let t = bracket(&self.tenors, tenor);
+        let s = bracket(&self.strike_offsets, strike - forward);
+
+        let mut vol = 0.0;
+        for &(ie, we) in [(e.0, 1.0 - e.2), (e.1, e.2)].iter() {
+            for &(it, wt) in [(t.0, 1.0 - t.2), (t.1, t.2)].iter() {
+                for &(is, ws) in [(s.0, 1.0 - s.2), (s.1, s.2)].iter() {
+                    vol += we * wt * ws * self.vol_at(ie, it, is);
+                }
+            }
+        }
+        Ok(vol)
+    }
+
+    fn base_date(&self) -> Date { self.base_date }
+}
+
+/// Caplet vols, as used for pricing caps and floors. These are quoted
+/// by the fixing date of the caplet and its absolute strike, for a single
+/// floating rate index, so the tenor is ignored. Vols are linearly
+/// interpolated in both directions, and extrapolated flat. The vols are
+/// stored with the strike varying fastest.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CapletVols {
+    base_date: Date,
+    expiries: Vec<Date>,
+    strikes: Vec<f64>,
+    vols: Vec<f64>
+}
+
+impl TypeId for CapletVols {
+    fn type_id(&self) -> &'static str { "CapletVols" }
+}
+
+impl CapletVols {
+    /// Creates caplet vols. Both axes must be non-empty and strictly
+    /// increasing, and the number of vols must be the product of the sizes
+    /// of the axes.
+    pub fn new(base_date: Date, expiries: &[Date], strikes: &[f64], vols: &[f64])
+        -> Result<CapletVols, qm::Error> {
+
+        let expiry_axis: Vec<f64> = expiries.iter()
+            .map(|d| (*d - base_date) as f64).collect();
+        validate_axis(&expiry_axis, "expiries")?;
+        validate_axis(strikes, "strikes")?;
+        if vols.len() != expiries.len() * strikes.len() {
+            return Err(qm::Error::new("Caplet vols size does not match its axes"))
+        }
+
+        Ok(CapletVols { base_date: base_date, expiries: expiries.to_vec(),
+            strikes: strikes.to_vec(), vols: vols.to_vec() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolCube, esd::Error> {
+        Ok(Qrc::new(Arc::new(CapletVols::deserialize(de)?)))
+    }
+}
+
+impl VolCube for CapletVols {
+    fn volatility(&self, expiry: Date, _tenor: f64, _forward: f64, strike: f64)
+        -> Result<f64, qm::Error> {
+
+        let expiry_axis: Vec<f64> = self.expiries.iter()
+            .map(|d| (*d - self.base_date) as f64).collect();
+        let e = bracket(&expiry_axis, (expiry - self.base_date) as f64);
+        let s = bracket(&self.strikes, strike);
+        let n = self.strikes.len();
+
+        let mut vol = 0.0;
+        for &(ie, we) in [(e.0, 1.0 - e.2), (e.1, e.2)].iter() {
+            for &(is, ws) in [(s.0, 1.0 - s.2), (s.1, s.2)].iter() {
+                vol += we * ws * self.vols[ie * n + is];
+            }
+        }
+        Ok(vol)
+    }
+
+    fn base_date(&self) -> Date { self.base_date }
+}
+
+fn validate_axis(axis: &[f64], name: &str) -> Result<(), qm::Error> {
+    if axis.is_empty() {
+        return Err(qm::Error::new(&format!("Vol cube has no {}", name)))
+    }
+    if axis.windows(2).any(|pair| pair[1] <= pair[0]) {
+        return Err(qm::Error::new(&format!(
+            "Vol cube {} must be strictly increasing", name)))
+    }
+    Ok(())
+}
+
+/// Finds the pair of indices either side of x and the weight of the upper
+/// one, extrapolating flat at each end.
+fn bracket(axis: &[f64], x: f64) -> (usize, usize, f64) {
+    let last = axis.len() - 1;
+    if x <= axis[0] {
+        return (0, 0, 0.0)
+    }
+    if x >= axis[last] {
+        return (last, last, 0.0)
+    }
+    let upper = axis.iter().position(|&a| a > x).unwrap();
+    let lower = upper - 1;
+    (lower, upper, (x - axis[lower]) / (axis[upper] - axis[lower]))
+}
+
+/// Apply a flat additive bump to all the vols of a vol cube
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ParallelBumpVolCube {
+    base_cube: RcVolCube,
+    bump: f64
+}
+
+impl TypeId for ParallelBumpVolCube {
+    fn type_id(&self) -> &'static str { "ParallelBumpVolCube" }
+}
+
+impl ParallelBumpVolCube {
+    pub fn new(base_cube: RcVolCube, bump: f64) -> ParallelBumpVolCube {
+        ParallelBumpVolCube { base_cube: base_cube, bump: bump }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolCube, esd::Error> {
+        Ok(Qrc::new(Arc::new(ParallelBumpVolCube::deserialize(de)?)))
+    }
+}
+
+impl VolCube for ParallelBumpVolCube {
+    fn volatility(&self, expiry: Date, tenor: f64, forward: f64, strike: f64)
+        -> Result<f64, qm::Error> {
+        let vol = self.base_cube.volatility(expiry, tenor, forward, strike)?;
+        Ok((vol + self.bump).max(0.0))
+    }
+
+    fn base_date(&self) -> Date { self.base_cube.base_date() }
+}
+
+/// Apply a vol bump that is scaled with sqrt T, measured Act/365 from the
+/// base date of the cube. This matches TimeScaledBumpVol for vol surfaces,
+/// including the cutoff at a minimum vol time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TimeScaledBumpVolCube {
+    base_cube: RcVolCube,
+    bump: f64,
+    vol_time_floor: f64
+}
+
+impl TypeId for TimeScaledBumpVolCube {
+    fn type_id(&self) -> &'static str { "TimeScaledBumpVolCube" }
+}
+
+impl TimeScaledBumpVolCube {
+    pub fn new(base_cube: RcVolCube, bump: f64, vol_time_floor: f64)
+        -> TimeScaledBumpVolCube {
+        TimeScaledBumpVolCube { base_cube: base_cube, bump: bump,
+            vol_time_floor: vol_time_floor }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolCube, esd::Error> {
+        Ok(Qrc::new(Arc::new(TimeScaledBumpVolCube::deserialize(de)?)))
+    }
+}
+
+impl VolCube for TimeScaledBumpVolCube {
+    fn volatility(&self, expiry: Date, tenor: f64, forward: f64, strike: f64)
+        -> Result<f64, qm::Error> {
+        let vol = self.base_cube.volatility(expiry, tenor, forward, strike)?;
+        let vol_time = (expiry - self.base_date()) as f64 / 365.0;
+        let scaled_bump = self.bump / vol_time.max(self.vol_time_floor).sqrt();
+        Ok((vol + scaled_bump).max(0.0))
+    }
+
+    fn base_date(&self) -> Date { self.base_cube.base_date() }
+}
+
+/// Apply an additive bump to the vols of a vol cube within one bucket of
+/// expiries and strikes, for bucketed vega. The tenor is not bucketed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BucketBumpVolCube {
+    base_cube: RcVolCube,
+    bump: f64,
+    bucket: VolBucket
+}
+
+impl TypeId for BucketBumpVolCube {
+    fn type_id(&self) -> &'static str { "BucketBumpVolCube" }
+}
+
+impl BucketBumpVolCube {
+    pub fn new(base_cube: RcVolCube, bump: f64, bucket: VolBucket)
+        -> BucketBumpVolCube {
+        BucketBumpVolCube { base_cube: base_cube, bump: bump, bucket: bucket }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolCube, esd::Error> {
+        Ok(Qrc::new(Arc::new(BucketBumpVolCube::deserialize(de)?)))
+    }
+}
+
+impl VolCube for BucketBumpVolCube {
+    fn volatility(&self, expiry: Date, tenor: f64, forward: f64, strike: f64)
+        -> Result<f64, qm::Error> {
+        let vol = self.base_cube.volatility(expiry, tenor, forward, strike)?;
+        let weight = self.bucket.expiry_weight(expiry) * self.bucket.strike_weight(strike);
+        Ok((vol + self.bump * weight).max(0.0))
+    }
+
+    fn base_date(&self) -> Date { self.base_cube.base_date() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use serde_json;
+
+    fn sample_vol_cube() -> InterpolatedVolCube {
+        let base = Date::from_ymd(2017, 01, 02);
+        let expiries = [base + 365, base + 730];
+        let tenors = [1.0, 5.0];
+        let offsets = [-0.01, 0.0, 0.01];
+        let vols = [
+            0.32, 0.30, 0.29,     // 1y into 1y
+            0.27, 0.25, 0.24,     // 1y into 5y
+            0.28, 0.26, 0.25,     // 2y into 1y
+            0.23, 0.21, 0.20];    // 2y into 5y
+        InterpolatedVolCube::new(base, &expiries, &tenors, &offsets, &vols).unwrap()
+    }
+
+    #[test]
+    fn vol_cube_on_grid() {
+        let cube = sample_vol_cube();
+        let base = cube.base_date();
+        assert_approx(cube.volatility(base + 365, 1.0, 0.02, 0.02).unwrap(), 0.30);
+        assert_approx(cube.volatility(base + 730, 5.0, 0.02, 0.03).unwrap(), 0.20);
+        assert_approx(cube.volatility(base + 365, 5.0, 0.03, 0.02).unwrap(), 0.27);
+    }
+
+    #[test]
+    fn vol_cube_interpolation_and_extrapolation() {
+        let cube = sample_vol_cube();
+        let base = cube.base_date();
+
+        // half way in expiry and tenor, at the money
+        let vol = cube.volatility(base + 547, 3.0, 0.02, 0.02).unwrap();
+        let expected = 0.25 * (0.30 + 0.25 + 0.26 + 0.21)
+            + (182.0 / 365.0 - 0.5) * 0.5 * (0.26 + 0.21 - 0.30 - 0.25);
+        assert_approx(vol, expected);
+
+        // flat outside the grid
+        assert_approx(cube.volatility(base + 30, 0.25, 0.02, 0.10).unwrap(), 0.29);
+        assert_approx(cube.volatility(base + 3650, 30.0, 0.02, 0.0).unwrap(), 0.23);
+    }
+
+    #[test]
+    fn caplet_vols_by_absolute_strike() {
+        let base = Date::from_ymd(2017, 01, 02);
+        let vols = CapletVols::new(base, &[base + 182, base + 365],
+            &[0.07, 0.09], &[0.30, 0.26, 0.24, 0.22]).unwrap();
+
+        // the tenor and forward make no difference
+        assert_approx(vols.volatility(base + 182, 0.5, 0.08, 0.09).unwrap(), 0.26);
+        assert_approx(vols.volatility(base + 182, 5.0, 0.02, 0.09).unwrap(), 0.26);
+        assert_approx(vols.volatility(base + 365, 0.5, 0.08, 0.08).unwrap(), 0.23);
+        assert_approx(vols.volatility(base + 1000, 0.5, 0.08, 0.05).unwrap(), 0.24);
+        assert!(CapletVols::new(base, &[base + 182], &[0.07, 0.09], &[0.3]).is_err());
+    }
+
+    #[test]
+    fn invalid_vol_cubes() {
+        let base = Date::from_ymd(2017, 01, 02);
+        assert!(InterpolatedVolCube::new(base, &[base + 365], &[1.0],
+            &[0.0], &[0.2, 0.3]).is_err());
+        assert!(InterpolatedVolCube::new(base, &[base + 365, base + 365], &[1.0],
+            &[0.0], &[0.2, 0.3]).is_err());
+        assert!(InterpolatedVolCube::new(base, &[], &[], &[], &[]).is_err());
+    }
+
+    #[test]
+    fn bumped_vol_cubes() {
+        let cube = RcVolCube::new(Arc::new(sample_vol_cube()));
+        let base = cube.base_date();
+        let flat = ParallelBumpVolCube::new(cube.clone(), 0.01);
+        assert_approx(flat.volatility(base + 730, 5.0, 0.02, 0.02).unwrap(), 0.22);
+
+        // at two years, the scaled bump is divided by sqrt 2
+        let scaled = TimeScaledBumpVolCube::new(cube.clone(), 0.01, 1.0 / 12.0);
+        assert_approx(scaled.volatility(base + 730, 5.0, 0.02, 0.02).unwrap(),
+            0.21 + 0.01 / 2.0_f64.sqrt());
+    }
+
+    #[test]
+    fn serde_vol_cube() {
+        let cube = RcVolCube::new(Arc::new(ParallelBumpVolCube::new(
+            RcVolCube::new(Arc::new(sample_vol_cube())), 0.01)));
+        let serialized = serde_json::to_string(&cube).unwrap();
+        let deserialized: RcVolCube = serde_json::from_str(&serialized).unwrap();
+        let base = cube.base_date();
+        assert_approx(deserialized.volatility(base + 365, 1.0, 0.02, 0.01).unwrap(), 0.33);
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-12),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -3,6 +3,7 @@ use data::volsurface::VolSurface;
 use data::volsurface::RcVolSurface;
 use data::forward::Forward;
 use data::volsurface::DivAssumptions;
+use data::bumpvol::VolBucket;
 use dates::datetime::DateDayFraction;
 use dates::calendar::RcCalendar;
 use dates::Date;
@@ -290,6 +291,69 @@ impl VolSurface for TimeScaledBumpVol {
     }
 }
 
+/// Apply an additive vol bump within one bucket of expiries and strikes,
+/// weighted as described in VolBucket, for bucketed vega. As with the
+/// parallel bump, vols are floored at zero.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BucketBumpVol {
+    base_vol: RcVolSurface,
+    bump: f64,
+    bucket: VolBucket
+}
+
+impl TypeId for BucketBumpVol {
+    fn type_id(&self) -> &'static str { "BucketBumpVol" }
+}
+
+impl BucketBumpVol {
+    pub fn new(base_vol: RcVolSurface, bump: f64, bucket: VolBucket) -> BucketBumpVol {
+        BucketBumpVol { base_vol: base_vol, bump: bump, bucket: bucket }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolSurface, esd::Error> {
+        Ok(Qrc::new(Arc::new(BucketBumpVol::deserialize(de)?)))
+    }
+}
+
+impl VolSurface for BucketBumpVol {
+
+    fn volatilities(&self,
+        date_time: DateDayFraction,
+        strikes: &[f64],
+        out: &mut[f64]) -> Result<(f64), qm::Error> {
+
+        let vol_time = self.base_vol.volatilities(date_time, strikes, out)?;
+        let bump = self.bump * self.bucket.expiry_weight(date_time.date());
+
+        for i in 0..out.len() {
+            let vol = out[i] + bump * self.bucket.strike_weight(strikes[i]);
+            out[i] = vol.max(0.0);
+        }
+
+        Ok(vol_time)
+    }
+
+    fn calendar(&self) -> &RcCalendar {
+        self.base_vol.calendar()
+    }
+
+    fn forward(&self) -> Option<&Interpolate<Date>> {
+        self.base_vol.forward()
+    }
+
+    fn base_date(&self) -> DateDayFraction {
+        self.base_vol.base_date()
+    }
+
+    fn div_assumptions(&self) -> DivAssumptions {
+        self.base_vol.div_assumptions()
+    }
+
+    fn displacement(&self, date: Date) -> Result<f64, qm::Error> {
+        self.base_vol.displacement(date)
+    }
+}
+
 /// Apply a shift in the strike direction between two forwards to a vol
 /// surface. This may be done for sticky delta risk calculation or evolution,
 /// or it may be done for benchmarking one vol surface from another.
@@ -1,6 +1,7 @@
 use math::interpolation::CubicSpline;
 use math::interpolation::Interpolate;
 use math::interpolation::Extrap;
+use math::sabr::SabrParameters;
 use core::qm;
 use std::f64::NAN;
 use std::fmt::Debug;
@@ -96,6 +97,61 @@ impl CubicSplineSmile {
     }
 }
 
+/// A smile given by the SABR model, using Hagan's expansion for the implied
+/// vols. The smile holds the forward and the time to expiry that the
+/// parameters were calibrated to, so it is only valid for its own date.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SabrSmile {
+    forward: f64,
+    time: f64,
+    parameters: SabrParameters
+}
+
+impl VolSmile for SabrSmile {
+
+    fn volatilities(
+        &self,
+        strikes: &[f64],
+        volatilities: &mut[f64]) -> Result<(), qm::Error> {
+
+        let n = strikes.len();
+        assert!(n == volatilities.len());
+
+        for i in 0..n {
+            volatilities[i] = self.parameters.implied_vol(self.forward,
+                strikes[i], self.time)?;
+        }
+        Ok(())
+    }
+}
+
+impl SabrSmile {
+
+    /// Creates a SABR smile from its parameters, and the forward and time
+    /// to expiry they apply to. The time should be in the same units as the
+    /// vol surface that contains the smile uses to turn vols into variances.
+    pub fn new(forward: f64, time: f64, parameters: SabrParameters)
+        -> Result<SabrSmile, qm::Error> {
+        if forward <= 0.0 || time < 0.0 {
+            return Err(qm::Error::new("SABR smile needs a positive forward \
+                and a non-negative time"))
+        }
+        Ok(SabrSmile { forward: forward, time: time, parameters: parameters })
+    }
+
+    /// Creates a SABR smile by calibrating to a market smile of (strike,
+    /// volatility) pairs, with the given beta.
+    pub fn calibrate(forward: f64, time: f64, beta: f64, pillars: &[(f64, f64)])
+        -> Result<SabrSmile, qm::Error> {
+        let parameters = SabrParameters::calibrate(forward, time, beta, pillars)?;
+        SabrSmile::new(forward, time, parameters)
+    }
+
+    pub fn parameters(&self) -> &SabrParameters {
+        &self.parameters
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,4 +190,21 @@ mod tests {
                 "vol={} expected={}", vols[i], expected[i]);
         }
     }
+
+    #[test]
+    fn test_sabr_smile() {
+        let points = [(70.0, 0.4), (80.0, 0.33), (90.0, 0.28), (100.0, 0.25),
+            (110.0, 0.24), (120.0, 0.245)];
+        let smile = SabrSmile::calibrate(95.0, 0.5, 1.0, &points).unwrap();
+
+        // three parameters cannot fit six points exactly, but should be close
+        let strikes: Vec<f64> = points.iter().map(|p| p.0).collect();
+        let mut vols = vec![0.0; strikes.len()];
+        smile.volatilities(&strikes, &mut vols).unwrap();
+        for (vol, point) in vols.iter().zip(points.iter()) {
+            assert!(approx_eq(*vol, point.1, 0.01),
+                "strike={} vol={} expected={}", point.0, vol, point.1);
+        }
+        assert!(smile.parameters().rho() < 0.0);
+    }
 }
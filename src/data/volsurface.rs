@@ -4,11 +4,13 @@ use dates::Date;
 use data::volsmile::VolSmile;
 use data::volsmile::FlatSmile;
 use data::volsmile::CubicSplineSmile;
+use data::volsmile::SabrSmile;
 use data::forward::Forward;
 use data::voldecorators::ConstantExpiryTimeEvolution;
 use data::voldecorators::RollingExpiryTimeEvolution;
 use data::voldecorators::ParallelBumpVol;
 use data::voldecorators::TimeScaledBumpVol;
+use data::voldecorators::BucketBumpVol;
 use data::voldecorators::StickyDeltaBumpVol;
 use math::interpolation::lerp;
 use math::interpolation::Interpolable;
@@ -251,10 +253,12 @@ pub fn get_registry() -> &'static TypeRegistry {
             let mut reg = TypeRegistry::new();
             reg.insert("FlatVolSurface", BoxFnSeed::new(FlatVolSurface::from_serial));
             reg.insert("VolByProbabilityCubicSplineSmile", BoxFnSeed::new(VolByProbabilityCubicSplineSmile::from_serial));
+            reg.insert("VolByProbabilitySabrSmile", BoxFnSeed::new(VolByProbabilitySabrSmile::from_serial));
             reg.insert("ConstantExpiryTimeEvolution", BoxFnSeed::new(ConstantExpiryTimeEvolution::from_serial));
             reg.insert("RollingExpiryTimeEvolution", BoxFnSeed::new(RollingExpiryTimeEvolution::from_serial));
             reg.insert("ParallelBumpVol", BoxFnSeed::new(ParallelBumpVol::from_serial));
             reg.insert("TimeScaledBumpVol", BoxFnSeed::new(TimeScaledBumpVol::from_serial));
+            reg.insert("BucketBumpVol", BoxFnSeed::new(BucketBumpVol::from_serial));
             reg
         };
     }
@@ -874,6 +878,55 @@ impl VolSurface for VolByProbabilityCubicSplineSmile {
     }
 }
 
+/// Create a new type for a VolByProbability<SabrSmile> so it can have its own
+/// type id and deserializer.
+#[derive(Debug, Serialize)]
+pub struct VolByProbabilitySabrSmile(VolByProbability<SabrSmile>);
+
+impl TypeId for VolByProbabilitySabrSmile {
+    fn type_id(&self) -> &'static str {
+        "VolByProbabilitySabrSmile"
+    }
+}
+
+impl VolByProbabilitySabrSmile {
+
+    pub fn new(smiles: &[(DateDayFraction, SabrSmile)],
+        calendar: RcCalendar,
+        base_date: DateDayFraction,
+        forward: Linear<Date>,
+        fixed_divs_after: Linear<Date>,
+        div_assumptions: DivAssumptions) -> Result<VolByProbabilitySabrSmile, qm::Error> {
+        let input = VolByProbabilityInput::new(smiles, calendar, base_date, forward,
+            fixed_divs_after, div_assumptions);
+        let surface = VolByProbability::new(input)?;
+        Ok(VolByProbabilitySabrSmile(surface))
+    }
+
+    // See the comments on VolByProbabilityCubicSplineSmile
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolSurface, esd::Error> {
+        let input = VolByProbabilityInput::<SabrSmile>::deserialize(de)?;
+        match VolByProbability::new(input) {
+            Ok(surface) => Ok(Qrc::new(Arc::new(surface))),
+            Err(e) => Err(esd::Error::custom(e.description()))
+        }
+    }
+}
+
+impl VolSurface for VolByProbabilitySabrSmile {
+    fn volatilities(&self, date_time: DateDayFraction, strikes: &[f64],
+        volatilities: &mut[f64]) -> Result<(f64), qm::Error> {
+        self.0.volatilities(date_time, strikes, volatilities)    
+    }
+    fn calendar(&self) -> &RcCalendar { self.0.calendar() }
+    fn base_date(&self) -> DateDayFraction { self.0.base_date() }
+    fn forward(&self) -> Option<&Interpolate<Date>> { self.0.forward() }
+    fn div_assumptions(&self) -> DivAssumptions { self.0.div_assumptions() }
+    fn displacement(&self, date: Date) -> Result<f64, qm::Error> {
+        self.0.displacement(date)
+    }
+}
+
 /// Normalised strike is defined as ln(K/F) / vol. It is a measure of
 /// the probability of a strike, in a date and forward independent way.
 pub fn to_normalised(strikes: &[f64], forward: f64, sqrt_variance: f64)
@@ -904,6 +957,7 @@ pub mod tests {
     use dates::calendar::WeekdayCalendar;
     use data::volsmile::CubicSplineSmile;
     use math::interpolation::Extrap;
+    use math::sabr::SabrParameters;
     use serde_json;
 
     #[test]
@@ -1034,6 +1088,46 @@ pub mod tests {
         assert_vars(&variances, &serde_variances);
     }
 
+    #[test]
+    fn sabr_vol_surface() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base_date = Date::from_ymd(2012, 05, 25);
+        let base = DateDayFraction::new(base_date, 0.2);
+        let d = base_date;
+        let fwd = Linear::new(&[(d, 90.0), (d + 960, 89.8)],
+            Extrap::Natural, Extrap::Natural).unwrap();
+        let divs = Linear::new(&[(d, 0.0)], Extrap::Flat, Extrap::Flat).unwrap();
+
+        let mut smiles = Vec::new();
+        for &(days, alpha) in [(28, 0.3), (112, 0.28), (364, 0.26)].iter() {
+            let expiry = DateDayFraction::new(d + days, 0.7);
+            let time = calendar.year_fraction(base, expiry);
+            let parameters = SabrParameters::new(alpha, 1.0, -0.5, 0.6).unwrap();
+            smiles.push((expiry, SabrSmile::new(90.0, time, parameters).unwrap()));
+        }
+        let surface = RcVolSurface::new(Arc::new(VolByProbabilitySabrSmile::new(
+            &smiles, calendar, base, fwd, divs, DivAssumptions::NoCashDivs).unwrap()));
+
+        // on a pillar, the vols are those of the smile
+        let strikes = vec![60.0, 80.0, 90.0, 100.0, 130.0];
+        let mut vols = vec![0.0; strikes.len()];
+        let mut expected = vec![0.0; strikes.len()];
+        surface.volatilities(smiles[1].0, &strikes, &mut vols).unwrap();
+        smiles[1].1.volatilities(&strikes, &mut expected).unwrap();
+        assert_vars(&vols, &expected);
+        assert!(vols[0] > vols[2] && vols[2] > vols[3]);
+
+        // and the surface round-trips through serialization
+        let serialized = serde_json::to_string(&surface).unwrap();
+        let deserialized: RcVolSurface = serde_json::from_str(&serialized).unwrap();
+        let expiry = DateDayFraction::new(d + 200, 0.7);
+        let mut variances = vec![0.0; strikes.len()];
+        let mut serde_variances = vec![0.0; strikes.len()];
+        surface.variances(expiry, &strikes, &mut variances).unwrap();
+        deserialized.variances(expiry, &strikes, &mut serde_variances).unwrap();
+        assert_vars(&variances, &serde_variances);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={} tolerance={}", value, expected, tolerance);
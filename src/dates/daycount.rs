@@ -0,0 +1,79 @@
+use dates::Date;
+
+/// Day count conventions define how the interest accrued between two dates
+/// is turned into a fraction of a year. They are used for the coupons of
+/// swaps and bonds, and for quoting money-market rates. Note that yield
+/// curves always work in Act/365, regardless of the conventions of the
+/// instruments they discount.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DayCount {
+    /// Actual days divided by 360. Standard for money-market rates and the
+    /// floating legs of most swaps.
+    Act360,
+    /// Actual days divided by 365, regardless of leap years. Standard for
+    /// GBP money-market rates.
+    Act365,
+    /// Each month is treated as having 30 days, and each year 360 days. This
+    /// is the ISDA bond basis, often used for the fixed legs of swaps.
+    Thirty360
+}
+
+impl DayCount {
+    /// Returns the fraction of a year between the two dates. This is
+    /// negative if the end date is before the start date.
+    pub fn year_fraction(&self, start: Date, end: Date) -> f64 {
+        match *self {
+            DayCount::Act360 => (end - start) as f64 / 360.0,
+            DayCount::Act365 => (end - start) as f64 / 365.0,
+            DayCount::Thirty360 => {
+                let (y1, m1, d1) = start.ymd();
+                let (y2, m2, d2) = end.ymd();
+                let d1 = if d1 == 31 { 30 } else { d1 };
+                let d2 = if d2 == 31 && d1 == 30 { 30 } else { d2 };
+                (360 * (y2 - y1) + 30 * (m2 - m1) + (d2 - d1)) as f64 / 360.0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use serde_json;
+
+    #[test]
+    fn actual_day_counts() {
+        let start = Date::from_ymd(2017, 01, 31);
+        let end = Date::from_ymd(2017, 07, 31);
+        assert_approx(DayCount::Act360.year_fraction(start, end), 181.0 / 360.0);
+        assert_approx(DayCount::Act365.year_fraction(start, end), 181.0 / 365.0);
+        assert_approx(DayCount::Act365.year_fraction(end, start), -181.0 / 365.0);
+    }
+
+    #[test]
+    fn thirty_360_day_count() {
+        // the 31st is treated as the 30th, so these are exact half years
+        let start = Date::from_ymd(2017, 01, 31);
+        let end = Date::from_ymd(2017, 07, 31);
+        assert_approx(DayCount::Thirty360.year_fraction(start, end), 0.5);
+
+        // February is not adjusted at the start
+        let start = Date::from_ymd(2017, 02, 28);
+        let end = Date::from_ymd(2017, 08, 31);
+        assert_approx(DayCount::Thirty360.year_fraction(start, end), 183.0 / 360.0);
+    }
+
+    #[test]
+    fn serde_day_count() {
+        let serialized = serde_json::to_string(&DayCount::Thirty360).unwrap();
+        assert_eq!(serialized, "\"Thirty360\"");
+        let deserialized: DayCount = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, DayCount::Thirty360);
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-12),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -1,6 +1,8 @@
 pub mod calendar;
 pub mod rules;
 pub mod datetime;
+pub mod daycount;
+pub mod schedule;
 
 use serde::Serializer;
 use serde::Serialize;
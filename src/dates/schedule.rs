@@ -0,0 +1,123 @@
+use dates::Date;
+use dates::rules::DateRule;
+use core::qm;
+use std::cmp::min;
+
+/// Adds a number of calendar months to a date. If the resulting month is
+/// too short for the day of the month, the result is the last day of the
+/// month. For example, one month after 31st January is 28th or 29th
+/// February. The number of months may be negative.
+pub fn add_months(date: Date, months: i32) -> Date {
+    let (year, month, day) = date.ymd();
+    let total = year * 12 + (month - 1) + months;
+    let (year, month) = (total / 12, total % 12 + 1);
+    let day = min(day, days_in_month(year, month));
+    Date::from_ymd(year, month, day)
+}
+
+/// Returns the number of days in the given month of the given year
+pub fn days_in_month(year: i32, month: i32) -> i32 {
+    let first = Date::from_ymd(year, month, 1);
+    let next = if month == 12 {
+        Date::from_ymd(year + 1, 1, 1)
+    } else {
+        Date::from_ymd(year, month + 1, 1)
+    };
+    next - first
+}
+
+/// Rolls out a schedule of dates from start to end, with a period of the
+/// given number of months. The schedule is rolled back from the end date,
+/// so if the period does not divide the interval exactly, there is a short
+/// stub at the front. Each unadjusted date is then adjusted by the given
+/// rule, normally a business day convention such as modified following.
+///
+/// The result contains both the adjusted start and end dates, so a schedule
+/// with n periods has n + 1 dates.
+pub fn roll_schedule(start: Date, end: Date, months: u32,
+    adjustment: &DateRule) -> Result<Vec<Date>, qm::Error> {
+
+    if end <= start {
+        return Err(qm::Error::new("Schedule must end after it starts"))
+    }
+    if months == 0 {
+        return Err(qm::Error::new("Schedule period must be at least a month"))
+    }
+
+    // roll back from the end, not from the previous date, so that the
+    // day of the month is not lost after a short month
+    let mut unadjusted = vec![end];
+    let mut step = 1;
+    loop {
+        let date = add_months(end, -(step * months as i32));
+        if date <= start {
+            break;
+        }
+        unadjusted.push(date);
+        step += 1;
+    }
+    unadjusted.push(start);
+    unadjusted.reverse();
+
+    let mut dates: Vec<Date> = Vec::with_capacity(unadjusted.len());
+    for date in unadjusted {
+        let adjusted = adjustment.apply(date);
+        if let Some(&prev) = dates.last() {
+            if adjusted <= prev {
+                return Err(qm::Error::new(&format!(
+                    "Adjusted schedule date {} is not after {}", adjusted, prev)))
+            }
+        }
+        dates.push(adjusted);
+    }
+    Ok(dates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::ModifiedFollowing;
+    use dates::rules::NullRule;
+
+    #[test]
+    fn add_months_clamps_to_month_end() {
+        let date = Date::from_ymd(2016, 01, 31);
+        assert_eq!(add_months(date, 1), Date::from_ymd(2016, 02, 29));
+        assert_eq!(add_months(date, 13), Date::from_ymd(2017, 02, 28));
+        assert_eq!(add_months(date, -2), Date::from_ymd(2015, 11, 30));
+        assert_eq!(add_months(date, 12), Date::from_ymd(2017, 01, 31));
+    }
+
+    #[test]
+    fn schedule_with_front_stub() {
+        let start = Date::from_ymd(2017, 01, 15);
+        let end = Date::from_ymd(2018, 03, 31);
+        let dates = roll_schedule(start, end, 6, &NullRule::new()).unwrap();
+        assert_eq!(dates, vec![start, Date::from_ymd(2017, 03, 31),
+            Date::from_ymd(2017, 09, 30), end]);
+    }
+
+    #[test]
+    fn schedule_adjusted_modified_following() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let rule = ModifiedFollowing::new(calendar);
+        let start = Date::from_ymd(2017, 03, 31);
+        let end = Date::from_ymd(2018, 03, 31);
+        let dates = roll_schedule(start, end, 3, &rule).unwrap();
+
+        // 2017-09-30 and 2018-03-31 are Saturdays, 2017-12-31 is a Sunday
+        assert_eq!(dates, vec![start, Date::from_ymd(2017, 06, 30),
+            Date::from_ymd(2017, 09, 29), Date::from_ymd(2017, 12, 29),
+            Date::from_ymd(2018, 03, 30)]);
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        let start = Date::from_ymd(2017, 01, 15);
+        assert!(roll_schedule(start, start, 6, &NullRule::new()).is_err());
+        assert!(roll_schedule(start, start + 365, 0, &NullRule::new()).is_err());
+    }
+}
@@ -148,6 +148,61 @@ pub fn calculate(pricer_factory: RcPricerFactory, instrument: RcInstrument,
     Ok(reports)
 }
 
+/// A PricingRequest holds everything needed to perform a calculation: the
+/// instrument, its fixings and the market data, the pricer factory with all
+/// its numerical settings, and the reports to generate. As it serializes as
+/// a whole, a stored request describes fully how its results were computed,
+/// and can be replayed to reproduce them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PricingRequest {
+    pricer_factory: RcPricerFactory,
+    instrument: RcInstrument,
+    fixing_table: RcFixingTable,
+    market_data: RcMarketData,
+    #[serde(default)]
+    report_generators: Vec<RcReportGenerator>
+}
+
+impl PricingRequest {
+    pub fn new(pricer_factory: RcPricerFactory, instrument: RcInstrument,
+        fixing_table: RcFixingTable, market_data: RcMarketData,
+        report_generators: Vec<RcReportGenerator>) -> PricingRequest {
+
+        PricingRequest { pricer_factory: pricer_factory, instrument: instrument,
+            fixing_table: fixing_table, market_data: market_data,
+            report_generators: report_generators }
+    }
+
+    pub fn pricer_factory(&self) -> &RcPricerFactory { &self.pricer_factory }
+    pub fn instrument(&self) -> &RcInstrument { &self.instrument }
+    pub fn fixing_table(&self) -> &RcFixingTable { &self.fixing_table }
+    pub fn market_data(&self) -> &RcMarketData { &self.market_data }
+    pub fn report_generators(&self) -> &[RcReportGenerator] { &self.report_generators }
+
+    /// Performs the calculation, as for the calculate function
+    pub fn calculate(&self) -> Result<Vec<BoxReport>, qm::Error> {
+        calculate(self.pricer_factory.clone(), self.instrument.clone(),
+            self.fixing_table.clone(), self.market_data.clone(),
+            &self.report_generators)
+    }
+}
+
+/// Reads a whole pricing request, with its components expanded inline.
+pub fn pricing_request_from_json(source: &mut Read)
+    -> Result<PricingRequest, qm::Error> {
+
+    let mut deserializer = sdj::Deserializer::from_reader(source);
+    let request = PricingRequest::deserialize(&mut deserializer)?;
+    Ok(request)
+}
+
+/// Writes a pricing request to the given stream, for storing alongside its
+/// results.
+pub fn write_pricing_request(request: &PricingRequest, pretty: bool, out: &mut Write)
+    -> Result<(), qm::Error> {
+    serialize_output(request, pretty, out)
+}
+
 /// Unpacks a set of calculation results to the given stream. For example, they may be
 /// written to a string buffer or to a file.
 pub fn write_results(reports: &[BoxReport], pretty: bool, out: &mut Write) 
@@ -236,6 +291,28 @@ pub mod tests {
         assert_approx_eq_reports(&results, &baseline, 1e-12, 1e-12, 1e-12).unwrap();
     }
 
+    #[test]
+    fn facade_pricing_request_round_trip() {
+        // assemble a request from the sample components
+        let part = |bytes: &'static [u8]| from_utf8(bytes).unwrap().to_string();
+        let json = format!(r#"{{"pricer_factory": {}, "instrument": {}, "fixing_table": {},
+            "market_data": {}, "report_generators": [{}]}}"#,
+            part(sample_pricer_factory_json()), part(sample_forward_european_json()),
+            part(sample_fixing_table_json()), part(sample_market_data_json()),
+            part(sample_report_generator_json()));
+        let request = pricing_request_from_json(&mut Cursor::new(json.as_bytes())).unwrap();
+        assert_eq!(request.pricer_factory().type_id(), "SelfPricerFactory");
+        assert_eq!(request.report_generators().len(), 1);
+
+        // the stored request reproduces the baseline results
+        let mut buffer = Vec::new();
+        write_pricing_request(&request, false, &mut Cursor::new(&mut buffer)).unwrap();
+        let replayed = pricing_request_from_json(&mut Cursor::new(&buffer)).unwrap();
+        let reports = replayed.calculate().unwrap();
+        let baseline: Vec<BoxReport> = sdj::from_slice(sample_results_json()).unwrap();
+        assert_approx_eq_reports(&reports, &baseline, 1e-12, 1e-12, 1e-12).unwrap();
+    }
+
     #[test]
     fn facade_read_currency() {
         let _ = currency_from_json(
@@ -0,0 +1,523 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use instruments::options::SpotStartingEuropean;
+use instruments::options::OptionSettlement;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An averaging schedule is a set of dates on which an underlying is
+/// observed, where the observations are combined into an arithmetic
+/// average with equal weights. As the dates are fixed, they are removed
+/// from the schedule and accumulated into the fixed sum, so a partially
+/// fixed average carries its accrued state with it.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AveragingSchedule {
+    dates: Vec<DateTime>,
+    fixed_sum: f64,
+    fixed_count: usize
+}
+
+impl AveragingSchedule {
+    /// Creates an averaging schedule where none of the dates are yet fixed.
+    /// The dates must be in strictly increasing order.
+    pub fn new(dates: &[DateTime]) -> Result<AveragingSchedule, qm::Error> {
+        AveragingSchedule::new_accrued(dates, 0.0, 0)
+    }
+
+    /// Creates an averaging schedule where some of the dates are already
+    /// fixed. The fixed dates are represented only by the sum and count of
+    /// fixings.
+    pub fn new_accrued(dates: &[DateTime], fixed_sum: f64, fixed_count: usize)
+        -> Result<AveragingSchedule, qm::Error> {
+
+        if dates.is_empty() && fixed_count == 0 {
+            return Err(qm::Error::new("Averaging schedule must not be empty"))
+        }
+        for pair in dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Averaging dates must be in \
+                    strictly increasing order"))
+            }
+        }
+        Ok(AveragingSchedule { dates: dates.to_vec(), fixed_sum: fixed_sum,
+            fixed_count: fixed_count })
+    }
+
+    /// The dates that have not yet been fixed
+    pub fn dates(&self) -> &[DateTime] {
+        &self.dates
+    }
+
+    /// True if all the dates in the schedule are fixed
+    pub fn is_fixed(&self) -> bool {
+        self.dates.is_empty()
+    }
+
+    /// The average of the fixings so far. Returns None if there are none.
+    pub fn accrued_average(&self) -> Option<f64> {
+        if self.fixed_count == 0 {
+            None
+        } else {
+            Some(self.fixed_sum / self.fixed_count as f64)
+        }
+    }
+
+    /// Calculates the average, given values for the unfixed dates
+    pub fn average<'a, I>(&self, unfixed: I) -> f64
+    where I: IntoIterator<Item = &'a f64> {
+        let sum = unfixed.into_iter().fold(self.fixed_sum, |acc, x| acc + x);
+        sum / (self.fixed_count + self.dates.len()) as f64
+    }
+
+    /// Applies any fixings for the given underlying, returning the
+    /// modified schedule. Returns None if no fixings apply.
+    pub fn fix(&self, id: &str, fixing_table: &FixingTable)
+        -> Result<Option<AveragingSchedule>, qm::Error> {
+
+        let mut fixed_sum = self.fixed_sum;
+        let mut fixed = 0;
+        for date in self.dates.iter() {
+            if let Some(fixing) = fixing_table.get(id, *date)? {
+                fixed_sum += fixing;
+                fixed += 1;
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(AveragingSchedule { dates: self.dates[fixed..].to_vec(),
+                fixed_sum: fixed_sum, fixed_count: self.fixed_count + fixed }))
+        }
+    }
+}
+
+/// An Asian option is an option on the arithmetic average of the underlying
+/// over a schedule of dates, rather than its value at expiry. Averaging
+/// makes the option cheaper than the equivalent European, and makes it hard
+/// to manipulate the payoff by moving the underlying at expiry.
+///
+/// The average-out schedule defines the final level of the underlying. The
+/// optional average-in schedule defines the initial level, in which case the
+/// strike is a fraction of the initial level, otherwise it is an absolute
+/// strike. Asian options are always settled in cash, at the settlement date
+/// of the last averaging date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AsianOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    strike: f64,
+    put_or_call: PutOrCall,
+    average_in: Option<AveragingSchedule>,
+    average_out: AveragingSchedule,
+    expiry: DateTime,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date,
+}
+
+impl TypeId for AsianOption {
+    fn type_id(&self) -> &'static str { "AsianOption" }
+}
+
+impl AsianOption {
+    /// Creates an Asian option. The expiry is the last of the average-out
+    /// dates. If the average-out schedule is already fully fixed, the expiry
+    /// must be passed in explicitly, as it is used to define the payment date.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        strike: f64,
+        put_or_call: PutOrCall,
+        average_in: Option<AveragingSchedule>,
+        average_out: AveragingSchedule,
+        expiry: DateTime)
+        -> Result<AsianOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+        if let Some(last) = average_out.dates().last() {
+            if *last != expiry {
+                return Err(qm::Error::new("The expiry of an Asian option \
+                    must be its last averaging date"))
+            }
+        }
+        if let Some(ref schedule) = average_in {
+            if let (Some(last_in), Some(first_out))
+                = (schedule.dates().last(), average_out.dates().first()) {
+                if *last_in >= *first_out {
+                    return Err(qm::Error::new("Average-in dates must all be \
+                        before the average-out dates"))
+                }
+            }
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        Ok(AsianOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            strike: strike,
+            put_or_call: put_or_call,
+            average_in: average_in,
+            average_out: average_out,
+            expiry: expiry,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(AsianOption::deserialize(de)?)))
+    }
+
+    pub fn average_in(&self) -> Option<&AveragingSchedule> {
+        self.average_in.as_ref()
+    }
+
+    pub fn average_out(&self) -> &AveragingSchedule {
+        &self.average_out
+    }
+
+    /// The payoff, given the initial and final levels
+    fn payoff(&self, initial: Option<f64>, fin: f64) -> f64 {
+        let strike = match initial {
+            Some(level) => self.strike * level,
+            None => self.strike
+        };
+        match self.put_or_call {
+            PutOrCall::Call => (fin - strike).max(0.0),
+            PutOrCall::Put => (strike - fin).max(0.0)
+        }
+    }
+
+    /// All the unfixed averaging dates, average-in first
+    fn unfixed_dates(&self) -> Vec<DateTime> {
+        let mut dates = Vec::new();
+        if let Some(ref schedule) = self.average_in {
+            dates.extend_from_slice(schedule.dates());
+        }
+        dates.extend_from_slice(self.average_out.dates());
+        dates
+    }
+}
+
+impl InstanceId for AsianOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for AsianOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // every unfixed averaging date is a fixing
+        let id = self.underlying.id();
+        for date in self.unfixed_dates().iter() {
+            context.fixing(id, *date);
+        }
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        let expiry_date = self.expiry.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let average_in = match self.average_in {
+            Some(ref schedule) => schedule.fix(id, fixing_table)?,
+            None => None
+        };
+        let average_out = self.average_out.fix(id, fixing_table)?;
+        if average_in.is_none() && average_out.is_none() {
+            return Ok(None)
+        }
+
+        let average_in = average_in.or_else(|| self.average_in.clone());
+        let average_out = average_out.unwrap_or_else(|| self.average_out.clone());
+
+        // Once the final average is known, the option turns into a payment
+        if average_out.is_fixed() {
+            let initial = match average_in {
+                Some(ref schedule) => Some(schedule.average(&[])),
+                None => None
+            };
+            let payment = self.payoff(initial, average_out.average(&[]));
+            let mut decomp = Vec::new();
+            if payment > 0.0 {
+                decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:payment", self.id), &self.credit_id,
+                    RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                    self.expiry, self.pay_date, self.settlement.clone()))))));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let asian = AsianOption::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.strike,
+            self.put_or_call, average_in, average_out, self.expiry)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(asian))))]))
+    }
+}
+
+impl MonteCarloPriceable for AsianOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // only the unfixed dates are observations. The fixed part of the
+        // averages is carried in the schedules.
+        for date in self.unfixed_dates().iter() {
+            let time = self.underlying.time_to_day_fraction(*date)?;
+            output.observation(&self.underlying, time);
+        }
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        match self.average_in {
+            Some(ref schedule) => match schedule.dates().first() {
+                Some(date) => self.underlying.time_to_day_fraction(*date).ok(),
+                None => None
+            },
+            None => None
+        }
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        let n_in = match self.average_in {
+            Some(ref schedule) => schedule.dates().len(),
+            None => 0
+        };
+        assert_eq!(shape[1], n_in + self.average_out.dates().len());
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let initial = match self.average_in {
+                    Some(ref schedule) => Some(schedule.average(path.iter().take(n_in))),
+                    None => None
+                };
+                let fin = self.average_out.average(path.iter().skip(n_in));
+                *flow = self.payoff(initial, fin);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+
+    /// A European with the same strike and expiry, whose payoff is highly
+    /// correlated with the average. There is no such proxy if the strike is
+    /// set by an average-in.
+    fn control_variate(&self) -> Result<Option<RcInstrument>, qm::Error> {
+        if self.average_in.is_some() {
+            return Ok(None)
+        }
+        let european = SpotStartingEuropean::new(&format!("{}:ControlVariate", self.id),
+            &self.credit_id, self.underlying.clone(), self.settlement.clone(),
+            self.expiry, self.strike, self.put_or_call, OptionSettlement::Cash)?;
+        Ok(Some(RcInstrument::new(Qrc::new(Arc::new(european)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::dependencies::DependencyCollector;
+    use risk::Pricer;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::Threading;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+
+    fn monthly_dates(from: Date, count: i32) -> Vec<DateTime> {
+        (0..count).map(|i| DateTime::new(from + 30 * i, TimeOfDay::Close)).collect()
+    }
+
+    fn sample_asian(strike: f64, average_in: Option<AveragingSchedule>,
+        average_out: AveragingSchedule) -> AsianOption {
+        let expiry = sample_expiry();
+        let equity = sample_underlying();
+        AsianOption::new("SampleAsian", "OPT", equity, sample_settlement(2),
+            strike, PutOrCall::Call, average_in, average_out, expiry).unwrap()
+    }
+
+    fn average_out_dates() -> Vec<DateTime> {
+        let mut dates = monthly_dates(Date::from_ymd(2017, 12, 04), 6);
+        dates.push(sample_expiry());
+        dates
+    }
+
+    fn mc_price(asian: AsianOption) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(asian))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+
+        // seed the paths, so comparisons of nearby prices are not at the
+        // mercy of the noise
+        let pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn schedule_accrues_fixings() {
+        let dates = monthly_dates(Date::from_ymd(2017, 01, 03), 4);
+        let schedule = AveragingSchedule::new(&dates).unwrap();
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 03, 01),
+            &[("BP.L", &[(dates[0], 100.0), (dates[1], 110.0)])]).unwrap();
+        let fixed = schedule.fix("BP.L", &fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.dates(), &dates[2..]);
+        assert_approx(fixed.accrued_average().unwrap(), 105.0, 1e-12);
+        assert_approx(fixed.average(&[120.0, 130.0]), 115.0, 1e-12);
+    }
+
+    #[test]
+    fn schedule_errors_on_missing_fixing() {
+        let dates = monthly_dates(Date::from_ymd(2017, 01, 03), 4);
+        let schedule = AveragingSchedule::new(&dates).unwrap();
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 03, 01),
+            &[("BP.L", &[(dates[1], 110.0)])]).unwrap();
+        assert!(schedule.fix("BP.L", &fixing_table).is_err());
+    }
+
+    #[test]
+    fn asian_dependencies_are_unfixed_dates() {
+        let average_in = AveragingSchedule::new(
+            &monthly_dates(Date::from_ymd(2017, 01, 03), 3)).unwrap();
+        let average_out = AveragingSchedule::new(&average_out_dates()).unwrap();
+        let asian = sample_asian(1.0, Some(average_in), average_out);
+        let rc = RcInstrument::new(Qrc::new(Arc::new(asian.clone())));
+        let mut context = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        context.spot(&rc);
+        assert_eq!(context.fixings("BP.L").len(), 10);
+        assert_eq!(context.fixings("BP.L")[0], asian.average_in().unwrap().dates()[0]);
+    }
+
+    #[test]
+    fn fully_fixed_asian_becomes_payment() {
+        let dates = average_out_dates();
+        let average_out = AveragingSchedule::new(&dates).unwrap();
+        let asian = sample_asian(100.0, None, average_out);
+        let fixings : Vec<(DateTime, f64)> = dates.iter().enumerate()
+            .map(|(i, d)| (*d, 100.0 + i as f64)).collect();
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &fixings)]).unwrap();
+        let fixed = asian.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 3.0, 1e-12);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+    }
+
+    #[test]
+    fn single_date_asian_matches_european() {
+
+        // with a single averaging date at expiry, the Asian is a European,
+        // so should match the self-pricer European price
+        let expiry = sample_expiry();
+        let average_out = AveragingSchedule::new(&[expiry]).unwrap();
+        let price = mc_price(sample_asian(100.0, None, average_out));
+        assert_approx(price, 16.710717400832973, 0.5);
+    }
+
+    #[test]
+    fn averaging_cheapens_the_option() {
+        let average_out = AveragingSchedule::new(&average_out_dates()).unwrap();
+        let price = mc_price(sample_asian(100.0, None, average_out));
+        assert!(price > 10.0 && price < 16.0, "price={}", price);
+    }
+
+    #[test]
+    fn seasoned_average_in_uses_accrued_level() {
+
+        // The average-in has fully fixed at a very low level, so a call with
+        // a strike of 50% is deep in the money
+        let average_in = AveragingSchedule::new_accrued(&[], 120.0, 3).unwrap();
+        let average_out = AveragingSchedule::new(&average_out_dates()).unwrap();
+        let asian = sample_asian(0.5, Some(average_in), average_out.clone());
+        assert!(asian.start_date().is_none());
+        let deep = mc_price(asian);
+
+        let atm = mc_price(sample_asian(20.0, None, average_out));
+        assert_approx(deep, atm, 0.6);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -8,6 +8,7 @@ use std::hash::Hasher;
 use std::ops::Deref;
 use std::cell::RefCell;
 use instruments::Instrument;
+use instruments::ExchangeRate;
 use instruments::Priceable;
 use instruments::PricingContext;
 use instruments::DependencyContext;
@@ -303,6 +304,234 @@ impl Priceable for Equity {
     }
 }
 
+/// Represents a currency pair, such as GBPUSD. The value of the pair is the
+/// FX rate, quoted as units of the domestic (quote) currency per unit of
+/// the foreign (base) currency, so for GBPUSD the domestic currency is USD.
+///
+/// The id of the pair is also the id of the FX rate, and its spot is
+/// supplied with the other spots. Forwards are implied by covered interest
+/// parity from the yield curves of the two currencies, identified by their
+/// credit ids.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CurrencyPair {
+    id: String,
+    foreign: RcCurrency,
+    foreign_credit_id: String,
+    domestic: RcCurrency,
+    domestic_credit_id: String,
+    settlement: RcDateRule
+}
+
+impl TypeId for CurrencyPair {
+    fn type_id(&self) -> &'static str { "CurrencyPair" }
+}
+
+impl InstanceId for CurrencyPair {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl CurrencyPair {
+    /// Creates a currency pair. The settlement rule is the spot lag of the
+    /// pair, normally two business days.
+    pub fn new(id: &str, foreign: RcCurrency, foreign_credit_id: &str,
+        domestic: RcCurrency, domestic_credit_id: &str,
+        settlement: RcDateRule) -> CurrencyPair {
+
+        CurrencyPair { id: id.to_string(), foreign: foreign,
+            foreign_credit_id: foreign_credit_id.to_string(),
+            domestic: domestic,
+            domestic_credit_id: domestic_credit_id.to_string(),
+            settlement: settlement }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CurrencyPair::deserialize(de)?)))
+    }
+
+    pub fn foreign(&self) -> &RcCurrency { &self.foreign }
+    pub fn domestic(&self) -> &RcCurrency { &self.domestic }
+}
+
+impl Instrument for CurrencyPair {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.domestic
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.domestic_credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        dependence_on_spot_discount(self, context);
+        let spot_date = context.spot_date();
+        let pay_date = self.settlement.apply(spot_date);
+        context.yield_curve(&self.foreign_credit_id, pay_date);
+        context.fx_rate(&self.id, spot_date);
+        SpotRequirement::Required
+    }
+
+    fn time_to_day_fraction(&self, date_time: DateTime)
+        -> Result<DateDayFraction, qm::Error> {
+
+        // as for equities, we hard-code the conversion for now
+        let day_fraction = match date_time.time_of_day() {
+            TimeOfDay::Open => 0.0,
+            TimeOfDay::EDSP => 0.0,
+            TimeOfDay::Close => 0.8 };
+        Ok(DateDayFraction::new(date_time.date(), day_fraction))
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_exchange_rate(&self) -> Option<&ExchangeRate> {
+        Some(self)
+    }
+}
+
+impl ExchangeRate for CurrencyPair {
+    fn foreign_credit_id(&self) -> &str { &self.foreign_credit_id }
+    fn domestic_credit_id(&self) -> &str { &self.domestic_credit_id }
+    fn as_instrument(&self) -> &Instrument { self }
+}
+
+impl Display for CurrencyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.id.fmt(f)
+    }
+}
+
+impl Priceable for CurrencyPair {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The price of a currency pair is the FX spot on the spot date, and
+    /// otherwise the FX forward.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        let n_dates = dates.len();
+        assert_eq!(n_dates, out.len());
+
+        if n_dates == 0 {
+            Ok(())
+        } else if n_dates == 1 && dates[0].date() == context.spot_date() {
+            out[0] = context.fx_spot(&self.id)?;
+            Ok(())
+        } else {
+            let fc = context.fx_forward_curve(self, dates.last().unwrap().date())?;
+            for (date, output) in dates.iter().zip(out.iter_mut()) {
+                *output = fc.forward(date.date())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Represents a physical commodity, such as power or gas delivered at a
+/// particular hub. Commodities have no meaningful spot. Their prices are
+/// given by a commodity curve, keyed by the id of the commodity, of forward
+/// prices for delivery on each day, which is used wherever the forward of
+/// the commodity is needed.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Commodity {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    settlement: RcDateRule
+}
+
+impl TypeId for Commodity {
+    fn type_id(&self) -> &'static str { "Commodity" }
+}
+
+impl InstanceId for Commodity {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl Commodity {
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule) -> Commodity {
+
+        Commodity { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, settlement: settlement }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Commodity::deserialize(de)?)))
+    }
+}
+
+impl Instrument for Commodity {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        dependence_on_spot_discount(self, context);
+        SpotRequirement::NotRequired
+    }
+
+    fn time_to_day_fraction(&self, date_time: DateTime)
+        -> Result<DateDayFraction, qm::Error> {
+
+        // as for equities, we hard-code the conversion for now
+        let day_fraction = match date_time.time_of_day() {
+            TimeOfDay::Open => 0.0,
+            TimeOfDay::EDSP => 0.0,
+            TimeOfDay::Close => 0.8 };
+        Ok(DateDayFraction::new(date_time.date(), day_fraction))
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn is_forward_quoted(&self) -> bool {
+        true
+    }
+}
+
+impl Display for Commodity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.id.fmt(f)
+    }
+}
+
+impl Priceable for Commodity {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The price of a commodity on any date is the forward price for
+    /// delivery on that date, even on the spot date.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        if let Some(last) = dates.last() {
+            let fc = context.forward_curve(self, last.date())?;
+            for (date, output) in dates.iter().zip(out.iter_mut()) {
+                *output = fc.forward(date.date())?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Represents a credit entity
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct CreditEntity {
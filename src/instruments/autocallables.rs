@@ -0,0 +1,579 @@
+use std::sync::Arc;
+use std::slice;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::PathDependent;
+use instruments::PathStatus;
+use instruments::mc_price_path_dependent;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::digitals::DigitalOption;
+use instruments::digitals::DigitalPayout;
+use instruments::options::SpotStartingEuropean;
+use instruments::options::PutOrCall;
+use instruments::options::OptionSettlement;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::LifecycleEventType;
+use instruments::lifecycle::in_range;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// One observation of an autocallable. All barriers are expressed as
+/// fractions of the initial level of the underlying, and the coupon as a
+/// fraction of the notional. If there is no autocall barrier, the note cannot
+/// be called on this date.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AutocallObservation {
+    date: DateTime,
+    autocall_barrier: Option<f64>,
+    coupon_barrier: f64,
+    coupon: f64
+}
+
+impl AutocallObservation {
+    pub fn new(date: DateTime, autocall_barrier: Option<f64>,
+        coupon_barrier: f64, coupon: f64) -> AutocallObservation {
+        AutocallObservation { date: date, autocall_barrier: autocall_barrier,
+            coupon_barrier: coupon_barrier, coupon: coupon }
+    }
+
+    pub fn date(&self) -> DateTime {
+        self.date
+    }
+}
+
+/// An autocallable (or phoenix) note. On each observation date, if the
+/// underlying is at or above the coupon barrier, the note pays the coupon
+/// for that date. If the note has a memory feature, it also pays any
+/// coupons missed on earlier dates. If the underlying is at or above the
+/// autocall barrier, the note then redeems at par and terminates.
+///
+/// If the note survives to the final observation, it redeems at par unless
+/// the underlying is below the put barrier. In that case, the investor is
+/// short a put, and receives the notional times one less the amount by which
+/// the performance is below the put strike.
+///
+/// Coupons missed so far (for memory notes) are carried with the note, so a
+/// seasoned note prices correctly. Each observation pays at its own
+/// settlement date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Autocallable {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    notional: f64,
+    initial_level: f64,
+    observations: Vec<AutocallObservation>,
+    memory: bool,
+    put_barrier: f64,
+    put_strike: f64,
+    missed_coupons: f64,
+
+    // fields precomputed for performance and simplicity
+    pay_dates: Vec<Date>,
+}
+
+impl TypeId for Autocallable {
+    fn type_id(&self) -> &'static str { "Autocallable" }
+}
+
+impl Autocallable {
+    /// Creates an autocallable note. The observations are the remaining
+    /// unfixed observations, in strictly increasing date order. The missed
+    /// coupons are the total amount (in currency, not as a fraction of
+    /// notional) of coupons missed on already fixed dates, which is only
+    /// relevant if the note has memory.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        notional: f64,
+        initial_level: f64,
+        observations: &[AutocallObservation],
+        memory: bool,
+        put_barrier: f64,
+        put_strike: f64,
+        missed_coupons: f64)
+        -> Result<Autocallable, qm::Error> {
+
+        if observations.is_empty() {
+            return Err(qm::Error::new("Autocallable must have at least one \
+                unfixed observation"))
+        }
+        for pair in observations.windows(2) {
+            if pair[0].date >= pair[1].date {
+                return Err(qm::Error::new("Autocallable observations must be \
+                    in strictly increasing order"))
+            }
+        }
+        if initial_level <= 0.0 {
+            return Err(qm::Error::new("Autocallable initial level must be positive"))
+        }
+        if put_barrier < 0.0 || put_strike <= 0.0 {
+            return Err(qm::Error::new("Autocallable put barrier must be \
+                non-negative and put strike positive"))
+        }
+
+        let pay_dates = observations.iter()
+            .map(|o| settlement.apply(o.date.date())).collect();
+        Ok(Autocallable {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            notional: notional,
+            initial_level: initial_level,
+            observations: observations.to_vec(),
+            memory: memory,
+            put_barrier: put_barrier,
+            put_strike: put_strike,
+            missed_coupons: missed_coupons,
+            pay_dates: pay_dates })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Autocallable::deserialize(de)?)))
+    }
+
+    /// The observations that have not yet been fixed
+    pub fn observations(&self) -> &[AutocallObservation] {
+        &self.observations
+    }
+
+    /// The total of coupons missed so far, to be paid if the memory feature
+    /// is triggered
+    pub fn missed_coupons(&self) -> f64 {
+        self.missed_coupons
+    }
+
+    /// Works out what happens at the given observation, given the level of
+    /// the underlying and the coupons missed so far, which are updated.
+    /// Returns the amount paid and whether the note terminates.
+    fn observe(&self, index: usize, spot: f64, missed: &mut f64) -> (f64, bool) {
+        let observation = &self.observations[index];
+        let performance = spot / self.initial_level;
+        let coupon = self.notional * observation.coupon;
+
+        let mut amount = 0.0;
+        if performance >= observation.coupon_barrier {
+            amount += coupon + *missed;
+            *missed = 0.0;
+        } else if self.memory {
+            *missed += coupon;
+        }
+
+        if index + 1 == self.observations.len() {
+            let shortfall = if performance < self.put_barrier {
+                (self.put_strike - performance).max(0.0)
+            } else {
+                0.0
+            };
+            (amount + self.notional * (1.0 - shortfall), true)
+        } else if observation.autocall_barrier.map_or(false, |b| performance >= b) {
+            (amount + self.notional, true)
+        } else {
+            (amount, false)
+        }
+    }
+
+    fn payment(&self, index: usize, amount: f64) -> (f64, RcInstrument) {
+        let date = self.observations[index].date;
+        (amount, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment:{}", self.id, date.date()), &self.credit_id,
+            RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+            date, self.pay_dates[index], self.settlement.clone())))))
+    }
+}
+
+impl InstanceId for Autocallable {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for Autocallable {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let id = self.underlying.id();
+        for observation in self.observations.iter() {
+            context.fixing(id, observation.date);
+        }
+
+        context.yield_curve(&self.credit_id, *self.pay_dates.last().unwrap());
+        let expiry_date = self.observations.last().unwrap().date.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Each fixed observation may generate a payment. If the note is called,
+    /// or reaches maturity, only the payments remain. Otherwise, the note
+    /// is replaced by one with fewer observations, carrying any missed
+    /// coupons.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut decomp = Vec::new();
+        let mut missed = self.missed_coupons;
+        let mut fixed = 0;
+        for (index, observation) in self.observations.iter().enumerate() {
+            if let Some(fixing) = fixing_table.get(id, observation.date)? {
+                fixed += 1;
+                let (amount, terminated) = self.observe(index, fixing, &mut missed);
+                if amount != 0.0 {
+                    decomp.push(self.payment(index, amount));
+                }
+                if terminated {
+                    return Ok(Some(decomp))
+                }
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        let remaining = Autocallable::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.notional,
+            self.initial_level, &self.observations[fixed..], self.memory,
+            self.put_barrier, self.put_strike, missed)?;
+        decomp.push((1.0, RcInstrument::new(Qrc::new(Arc::new(remaining)))));
+        Ok(Some(decomp))
+    }
+
+    /// Reports each coupon or redemption paid, and the autocall if the note
+    /// is called before its final observation.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut events = Vec::new();
+        let mut missed = self.missed_coupons;
+        for (index, observation) in self.observations.iter().enumerate() {
+            let fixing = match fixing_table.get(id, observation.date)? {
+                Some(fixing) => fixing,
+                None => break
+            };
+
+            let (amount, terminated) = self.observe(index, fixing, &mut missed);
+            let reported = in_range(observation.date, from, to);
+            if reported && amount != 0.0 {
+                events.push(LifecycleEvent::payment(self, observation.date,
+                    amount, self.pay_dates[index]));
+            }
+            if terminated {
+                if reported && index + 1 < self.observations.len() {
+                    events.push(LifecycleEvent::new(&self.id, observation.date,
+                        LifecycleEventType::Autocalled { level: fixing }));
+                }
+                break;
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl MonteCarloPriceable for Autocallable {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// One observation per date, and one flow per date, which holds both
+    /// any coupon and any redemption paid on that date.
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        for (index, observation) in self.observations.iter().enumerate() {
+            let time = self.underlying.time_to_day_fraction(observation.date)?;
+            output.observation(&self.underlying, time);
+
+            let flow : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+                ZeroCoupon::new(&format!("{}:Obs{}", self.id, index),
+                &self.credit_id, currency.clone(), observation.date,
+                self.pay_dates[index], self.settlement.clone()))));
+            output.flow(&flow);
+        }
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+        mc_price_path_dependent(self, context)
+    }
+
+    /// The payoff of a note that is never called: a digital for each coupon
+    /// and a short put at maturity. Below the put barrier, the shortfall is
+    /// a put struck at the barrier, plus a digital for the rest of the way
+    /// to the put strike. Memory coupons depend on the path, so they are
+    /// not replicated, and neither are coupons with no barrier, or the
+    /// redemption at par, which carry no risk to the underlying.
+    fn static_replication(&self) -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+        let mut parts = Vec::new();
+        if !self.memory {
+            for observation in self.observations.iter() {
+                if observation.coupon == 0.0 || observation.coupon_barrier <= 0.0 {
+                    continue;
+                }
+                let digital = DigitalOption::new(
+                    &format!("{}:coupon:{}", self.id, observation.date.date()),
+                    &self.credit_id, self.underlying.clone(), self.settlement.clone(),
+                    observation.date, observation.coupon_barrier * self.initial_level,
+                    PutOrCall::Call, DigitalPayout::CashOrNothing(1.0), 0.0)?;
+                parts.push((self.notional * observation.coupon,
+                    RcInstrument::new(Qrc::new(Arc::new(digital)))));
+            }
+        }
+
+        if self.put_barrier > 0.0 {
+            let expiry = self.observations.last().unwrap().date;
+            let strike = self.put_barrier.min(self.put_strike);
+            let put = SpotStartingEuropean::new(&format!("{}:put", self.id),
+                &self.credit_id, self.underlying.clone(), self.settlement.clone(),
+                expiry, strike * self.initial_level, PutOrCall::Put,
+                OptionSettlement::Cash)?;
+            parts.push((-self.notional / self.initial_level,
+                RcInstrument::new(Qrc::new(Arc::new(put)))));
+            if self.put_strike > self.put_barrier {
+                let digital = DigitalOption::new(&format!("{}:put:digital", self.id),
+                    &self.credit_id, self.underlying.clone(), self.settlement.clone(),
+                    expiry, self.put_barrier * self.initial_level, PutOrCall::Put,
+                    DigitalPayout::CashOrNothing(1.0), 0.0)?;
+                parts.push((-self.notional * (self.put_strike - self.put_barrier),
+                    RcInstrument::new(Qrc::new(Arc::new(digital)))));
+            }
+        }
+
+        Ok(if parts.is_empty() { None } else { Some(parts) })
+    }
+}
+
+impl PathDependent for Autocallable {
+    fn path_underlyings(&self) -> &[RcInstrument] {
+        slice::from_ref(&self.underlying)
+    }
+
+    fn path_flows(&self) -> usize {
+        self.observations.len()
+    }
+
+    /// The only state is the missed coupons
+    fn path_state_size(&self) -> usize { 1 }
+
+    fn path_initial_state(&self, state: &mut [f64]) {
+        state[0] = self.missed_coupons;
+    }
+
+    fn path_step(&self, step: usize, spots: &[f64], state: &mut [f64],
+        flows: &mut [f64]) -> Result<PathStatus, qm::Error> {
+
+        let (amount, terminated) = self.observe(step, spots[0], &mut state[0]);
+        flows[step] += amount;
+        Ok(if terminated { PathStatus::Terminated } else { PathStatus::Alive })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::Pricer;
+    use instruments::Priceable;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+
+    fn sample_dates() -> Vec<DateTime> {
+        // quarterly over two years
+        [(2017, 04, 03), (2017, 07, 03), (2017, 10, 02), (2018, 01, 02),
+            (2018, 04, 03), (2018, 07, 02), (2018, 10, 01), (2019, 01, 02)]
+            .iter().map(|&(y, m, d)| DateTime::new(Date::from_ymd(y, m, d),
+            TimeOfDay::Close)).collect()
+    }
+
+    fn sample_observations(autocall: Option<f64>, coupon_barrier: f64)
+        -> Vec<AutocallObservation> {
+        sample_dates().iter().map(|d| AutocallObservation::new(*d, autocall,
+            coupon_barrier, 0.02)).collect()
+    }
+
+    fn sample_autocallable(observations: &[AutocallObservation],
+        put_barrier: f64, missed_coupons: f64) -> Autocallable {
+        let equity = sample_underlying();
+        Autocallable::new("SampleAutocall", "OPT", equity, sample_settlement(2),
+            1000.0, 100.0, observations, true, put_barrier, 1.0,
+            missed_coupons).unwrap()
+    }
+
+    fn mc_price(autocallable: Autocallable) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(autocallable))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    fn bond_price(ex_date: DateTime) -> f64 {
+        let market_data = sample_market_data();
+        let settlement = sample_settlement(2);
+        let pay_date = settlement.apply(ex_date.date());
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), ex_date, pay_date, settlement);
+        let val_date = sample_val_date();
+        bond.price(&market_data, val_date).unwrap()
+    }
+
+    fn fixings(levels: &[f64]) -> FixingTable {
+        let dates = sample_dates();
+        let fixings : Vec<(DateTime, f64)> = dates.iter().cloned()
+            .zip(levels.iter().cloned()).collect();
+        let today = dates[levels.len() - 1].date() + 1;
+        FixingTable::from_fixings(today, &[("BP.L", &fixings[..])]).unwrap()
+    }
+
+    #[test]
+    fn coupons_and_autocall_are_lifecycle_events() {
+        let autocallable = sample_autocallable(
+            &sample_observations(Some(1.0), 0.8), 0.6, 0.0);
+        let dates = sample_dates();
+        let from = Date::from_ymd(2017, 01, 02);
+        let to = Date::from_ymd(2017, 12, 31);
+
+        // the memory coupon missed on the first date is paid on the second,
+        // and the note is called on the third
+        let events = autocallable.lifecycle_events(
+            &fixings(&[75.0, 90.0, 105.0]), from, to).unwrap();
+        let pay_date = |date: DateTime| sample_settlement(2).apply(date.date());
+        assert_eq!(events, vec![
+            LifecycleEvent::payment(&autocallable, dates[1], 40.0, pay_date(dates[1])),
+            LifecycleEvent::payment(&autocallable, dates[2], 1020.0, pay_date(dates[2])),
+            LifecycleEvent::new("SampleAutocall", dates[2],
+                LifecycleEventType::Autocalled { level: 105.0 })]);
+
+        // only events within the range are reported
+        let events = autocallable.lifecycle_events(
+            &fixings(&[75.0, 90.0, 105.0]), dates[2].date(), to).unwrap();
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn called_at_first_observation() {
+        let autocallable = sample_autocallable(
+            &sample_observations(Some(1.0), 0.8), 0.6, 0.0);
+        let fixed = autocallable.fix(&fixings(&[101.0])).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+        assert_approx(fixed[0].0, 1020.0, 1e-12);
+    }
+
+    #[test]
+    fn memory_coupons_are_paid_when_barrier_is_regained() {
+        let autocallable = sample_autocallable(
+            &sample_observations(Some(1.0), 0.8), 0.6, 0.0);
+
+        // first below the coupon barrier, so the coupon is remembered
+        let fixed = autocallable.fix(&fixings(&[75.0])).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "Autocallable");
+
+        // then above the coupon but below the autocall barrier, paying both
+        let fixed = autocallable.fix(&fixings(&[75.0, 90.0])).unwrap().unwrap();
+        assert_eq!(fixed.len(), 2);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+        assert_approx(fixed[0].0, 40.0, 1e-12);
+        assert_eq!(fixed[1].1.type_id(), "Autocallable");
+    }
+
+    #[test]
+    fn knocked_in_at_maturity() {
+        let autocallable = sample_autocallable(
+            &sample_observations(Some(1.0), 0.8), 0.6, 0.0);
+        let levels = [90.0, 85.0, 70.0, 60.0, 55.0, 58.0, 52.0, 50.0];
+        let fixed = autocallable.fix(&fixings(&levels)).unwrap().unwrap();
+
+        // two coupons, then the redemption at half the notional
+        assert_eq!(fixed.len(), 3);
+        assert_approx(fixed[0].0, 20.0, 1e-12);
+        assert_approx(fixed[1].0, 20.0, 1e-12);
+        assert_approx(fixed[2].0, 500.0, 1e-12);
+    }
+
+    #[test]
+    fn certain_autocall_prices_as_bond() {
+
+        // with zero barriers, every path calls at the first date
+        let autocallable = sample_autocallable(
+            &sample_observations(Some(0.0), 0.0), 0.6, 15.0);
+        let price = mc_price(autocallable);
+        let expected = 1035.0 * bond_price(sample_dates()[0]);
+        assert_approx(price, expected, 1e-8);
+    }
+
+    #[test]
+    fn autocallable_between_bounds() {
+
+        // The note can never be worth more than par plus all the coupons.
+        // Without the put, it is worth at least the discounted par.
+        let observations = sample_observations(Some(1.0), 0.8);
+        let maturity = bond_price(*sample_dates().last().unwrap());
+        let price = mc_price(sample_autocallable(&observations, 0.6, 0.0));
+        let no_put_price = mc_price(sample_autocallable(&observations, 0.0, 0.0));
+        assert!(price < 1160.0, "price={}", price);
+        assert!(no_put_price > 1000.0 * maturity, "price={}", no_put_price);
+        assert!(price < no_put_price, "price={} no put={}", price, no_put_price);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
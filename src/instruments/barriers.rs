@@ -0,0 +1,828 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::Barrier;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::AnalyticPriceable;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use instruments::options::OptionSettlement;
+use instruments::options::SpotStartingEuropean;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::LifecycleEventType;
+use instruments::lifecycle::in_range;
+use math::optionpricing::Black76;
+use pricers::analytic::AnalyticModel;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::calendar::RcCalendar;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The four flavours of barrier. An up barrier is hit if the underlying
+/// fixes at or above the barrier level, and a down barrier if it fixes at or
+/// below it. A knock-out option expires worthless if the barrier is hit, and
+/// a knock-in option is worthless unless the barrier is hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BarrierType { UpAndOut, DownAndOut, UpAndIn, DownAndIn }
+
+impl BarrierType {
+    /// Returns true if the given value of the underlying hits the barrier
+    pub fn is_hit(&self, barrier: f64, spot: f64) -> bool {
+        match *self {
+            BarrierType::UpAndOut | BarrierType::UpAndIn => spot >= barrier,
+            BarrierType::DownAndOut | BarrierType::DownAndIn => spot <= barrier
+        }
+    }
+
+    /// Returns true for barriers above the underlying
+    pub fn is_up(&self) -> bool {
+        match *self {
+            BarrierType::UpAndOut | BarrierType::UpAndIn => true,
+            BarrierType::DownAndOut | BarrierType::DownAndIn => false
+        }
+    }
+
+    /// Returns true for knock-in barriers
+    pub fn is_knock_in(&self) -> bool {
+        match *self {
+            BarrierType::UpAndIn | BarrierType::DownAndIn => true,
+            BarrierType::UpAndOut | BarrierType::DownAndOut => false
+        }
+    }
+}
+
+/// Defines when the barrier is monitored. A discretely monitored barrier is
+/// only observed on the given dates. A continuously monitored barrier is
+/// observed from the start date up to expiry. In practice, we represent
+/// continuous monitoring by the closing fixing on each business day of the
+/// given calendar, which is what the fixing table can supply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BarrierMonitoring {
+    Continuous { start: DateTime, calendar: RcCalendar },
+    Discrete(Vec<DateTime>)
+}
+
+impl BarrierMonitoring {
+    /// Returns the observation dates of the barrier, in order, up to and
+    /// including the given expiry.
+    pub fn observations(&self, expiry: DateTime) -> Vec<DateTime> {
+        match *self {
+            BarrierMonitoring::Discrete(ref dates) => dates.clone(),
+            BarrierMonitoring::Continuous { start, ref calendar } => {
+                let mut dates = Vec::new();
+                let mut date = start.date();
+                while date <= expiry.date() {
+                    if !calendar.is_holiday(date) {
+                        let time_of_day = if date == expiry.date() {
+                            expiry.time_of_day()
+                        } else if date == start.date() {
+                            start.time_of_day()
+                        } else {
+                            TimeOfDay::Close
+                        };
+                        dates.push(DateTime::new(date, time_of_day));
+                    }
+                    date = date + 1;
+                }
+                dates
+            }
+        }
+    }
+
+    /// Returns the monitoring that remains after the given observation
+    /// date-time has passed.
+    fn remaining_after(&self, observations: &[DateTime], fixed: usize)
+        -> BarrierMonitoring {
+        match *self {
+            BarrierMonitoring::Discrete(_)
+                => BarrierMonitoring::Discrete(observations[fixed..].to_vec()),
+            BarrierMonitoring::Continuous { start: _, ref calendar }
+                => BarrierMonitoring::Continuous {
+                    start: observations[fixed], calendar: calendar.clone() }
+        }
+    }
+}
+
+/// The shift of a barrier away from the underlying that allows for it only
+/// being monitored at discrete times, in standard deviations of the log of
+/// the underlying over the interval between observations. This is zeta(1/2)
+/// over the square root of two pi, from Broadie, Glasserman and Kou (1997).
+const MONITORING_SHIFT: f64 = 0.5826;
+
+/// A barrier option is a European option that is knocked in or knocked out
+/// if the underlying hits a barrier level during the monitoring period.
+/// Barrier options are cheaper than the equivalent European, which makes
+/// them popular for investors with a view on the range of the underlying.
+///
+/// The barrier observations are fixings of the underlying. Once the barrier
+/// is hit, the option fixes into either a European option (knock-in) or
+/// nothing (knock-out). Observations that have passed without the barrier
+/// being hit are removed from the option, so it ages correctly as the spot
+/// date moves forward.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BarrierOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+    cash_or_physical: OptionSettlement,
+    barrier: f64,
+    barrier_type: BarrierType,
+    monitoring: BarrierMonitoring,
+
+    // fields precomputed for performance and simplicity
+    observations: Vec<DateTime>,
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for BarrierOption {
+    fn type_id(&self) -> &'static str { "BarrierOption" }
+}
+
+impl BarrierOption {
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall,
+        cash_or_physical: OptionSettlement,
+        barrier: f64,
+        barrier_type: BarrierType,
+        monitoring: BarrierMonitoring)
+        -> Result<BarrierOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+        if barrier <= 0.0 {
+            return Err(qm::Error::new("Barrier must be greater than zero"))
+        }
+
+        let observations = monitoring.observations(expiry);
+        if observations.is_empty() {
+            return Err(qm::Error::new("Barrier must have at least one observation"))
+        }
+        for pair in observations.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Barrier observations must be in \
+                    strictly increasing order"))
+            }
+        }
+        if *observations.last().unwrap() > expiry {
+            return Err(qm::Error::new("Barrier observations must not be after expiry"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = underlying.time_to_day_fraction(expiry)?;
+        Ok(BarrierOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            cash_or_physical: cash_or_physical,
+            barrier: barrier,
+            barrier_type: barrier_type,
+            monitoring: monitoring,
+            observations: observations,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BarrierOption::deserialize(de)?)))
+    }
+
+    /// The barrier observation dates that have not yet been fixed
+    pub fn observations(&self) -> &[DateTime] {
+        &self.observations
+    }
+
+    /// The European option that remains if the barrier no longer matters,
+    /// either because it has knocked in, or because a knock-out barrier can
+    /// no longer be hit.
+    fn european(&self) -> Result<SpotStartingEuropean, qm::Error> {
+        SpotStartingEuropean::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.expiry,
+            self.strike, self.put_or_call, self.cash_or_physical)
+    }
+}
+
+impl InstanceId for BarrierOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for BarrierOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // every barrier observation is a fixing, as well as the expiry
+        let id = self.underlying.id();
+        for observation in self.observations.iter() {
+            context.fixing(id, *observation);
+        }
+        if *self.observations.last().unwrap() != self.expiry {
+            context.fixing(id, self.expiry);
+        }
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        let expiry_date = self.expiry.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        // as for a European option, we do not need a spot
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    fn as_analytic(&self) -> Option<&AnalyticPriceable> {
+        Some(self)
+    }
+
+    fn as_barrier(&self) -> Option<&Barrier> {
+        Some(self)
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Walk through the observations until we find one that is not yet
+        // fixed, or we hit the barrier
+        let id = self.underlying.id();
+        let mut fixed = 0;
+        let mut hit = false;
+        for observation in self.observations.iter() {
+            if let Some(fixing) = fixing_table.get(id, *observation)? {
+                fixed += 1;
+                if self.barrier_type.is_hit(self.barrier, fixing) {
+                    hit = true;
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        // Once the barrier is hit, or there are no more observations, the
+        // option either becomes a European or it is worthless.
+        let finished = hit || fixed == self.observations.len();
+        if finished {
+            let knocked_in = self.barrier_type.is_knock_in();
+            if hit == knocked_in {
+                let european = self.european()?;
+                if let Some(further) = european.fix(fixing_table)? {
+                    Ok(Some(further))
+                } else {
+                    Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(european))))]))
+                }
+            } else {
+                Ok(Some(Vec::new()))
+            }
+        } else {
+            let monitoring = self.monitoring.remaining_after(&self.observations, fixed);
+            let remaining = BarrierOption::new(&self.id, &self.credit_id,
+                self.underlying.clone(), self.settlement.clone(), self.expiry,
+                self.strike, self.put_or_call, self.cash_or_physical,
+                self.barrier, self.barrier_type, monitoring)?;
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(remaining))))]))
+        }
+    }
+
+    /// Reports the barrier being hit, then the events of the European the
+    /// option becomes if it is alive at expiry. A knock-in that is never
+    /// hit expires at its last observation.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        let id = self.underlying.id();
+        let knock_in = self.barrier_type.is_knock_in();
+        let mut events = Vec::new();
+        let mut last = None;
+        for observation in self.observations.iter() {
+            let fixing = match fixing_table.get(id, *observation)? {
+                Some(fixing) => fixing,
+                None => return Ok(events)
+            };
+            last = Some((*observation, fixing));
+
+            if self.barrier_type.is_hit(self.barrier, fixing) {
+                if in_range(*observation, from, to) {
+                    let event = if knock_in {
+                        LifecycleEventType::KnockedIn { level: fixing }
+                    } else {
+                        LifecycleEventType::KnockedOut { level: fixing }
+                    };
+                    events.push(LifecycleEvent::new(&self.id, *observation, event));
+                }
+                if knock_in {
+                    events.extend(self.european()?.lifecycle_events(
+                        fixing_table, from, to)?);
+                }
+                return Ok(events)
+            }
+        }
+
+        if knock_in {
+            if let Some((observation, fixing)) = last {
+                if in_range(observation, from, to) {
+                    events.push(LifecycleEvent::new(&self.id, observation,
+                        LifecycleEventType::Expired { level: fixing }));
+                }
+            }
+        } else {
+            events.extend(self.european()?.lifecycle_events(fixing_table, from, to)?);
+        }
+        Ok(events)
+    }
+}
+
+impl Barrier for BarrierOption {
+    fn underlying(&self) -> &RcInstrument { &self.underlying }
+    fn expiry(&self) -> DateTime { self.expiry }
+    fn barrier(&self) -> f64 { self.barrier }
+    fn barrier_type(&self) -> BarrierType { self.barrier_type }
+    fn observations(&self) -> &[DateTime] { &self.observations }
+
+    fn payoff(&self, underlying: f64) -> f64 {
+        let sign = match self.put_or_call {
+            PutOrCall::Call => 1.0,
+            PutOrCall::Put => -1.0 };
+        (sign * (underlying - self.strike)).max(0.0)
+    }
+
+    fn as_instrument(&self) -> &Instrument { self }
+}
+
+impl AnalyticPriceable for BarrierOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Under Black, values the option by the closed form of Reiner and
+    /// Rubinstein, with the barrier shifted to allow for monitoring only at
+    /// the observations. The observations are treated as evenly spread in
+    /// variance between the val date and expiry, so there is no closed form
+    /// unless the last of them is on the expiry date.
+    fn analytic_price(&self, context: &PricingContext, model: &AnalyticModel,
+        val_date: DateTime) -> Result<Option<f64>, qm::Error> {
+
+        if *model != AnalyticModel::Black {
+            return Ok(None)
+        }
+
+        // We assume the option goes ex just after its expiry date/time
+        if val_date > self.expiry {
+            return Ok(Some(0.0))
+        }
+
+        // if there are no observations left, the barrier can no longer be
+        // hit, so the option is either a European or nothing
+        let remaining = self.observations.iter().filter(|o| **o >= val_date).count();
+        if remaining == 0 {
+            return if self.barrier_type.is_knock_in() {
+                Ok(Some(0.0))
+            } else {
+                Ok(Some(self.european()?.price(context, val_date)?))
+            }
+        }
+        if self.observations.last().unwrap().date() != self.expiry.date() {
+            return Ok(None)
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let forward_curve = context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(forward_curve.clone()))?;
+
+        // work in the displaced space of the vol surface, treating the
+        // forward to the val date as the level of the underlying now
+        let displacement = vol.displacement(expiry_date)?;
+        let forward = forward_curve.forward(expiry_date)? - displacement;
+        let level = forward_curve.forward(val_date.date())? - displacement;
+        let strike = self.strike - displacement;
+        let barrier = self.barrier - displacement;
+        if forward <= 0.0 || level <= 0.0 || strike <= 0.0 || barrier <= 0.0 {
+            return Err(qm::Error::new("Barrier option forward, strike and \
+                barrier must be above the displacement"))
+        }
+
+        let settlement_date = self.settlement.apply(val_date.date());
+        let df = (yc.rt(settlement_date)? - yc.rt(self.pay_date)?).exp();
+        let val_time = self.underlying.time_to_day_fraction(val_date)?;
+        let variance = vol.forward_variance(val_time, self.expiry_time, self.strike)?;
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"))
+        }
+
+        let up = self.barrier_type.is_up();
+        let shift = MONITORING_SHIFT * (variance / remaining as f64).sqrt();
+        let barrier = barrier * if up { shift.exp() } else { (-shift).exp() };
+
+        let black76 = Black76::new()?;
+        Ok(Some(black76.barrier_price(df, level, forward, strike, barrier,
+            variance.sqrt(), self.put_or_call == PutOrCall::Call, up,
+            self.barrier_type.is_knock_in())))
+    }
+}
+
+impl MonteCarloPriceable for BarrierOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation per barrier date, plus the expiry if the barrier
+        // is not observed then
+        for observation in self.observations.iter() {
+            let time = self.underlying.time_to_day_fraction(*observation)?;
+            output.observation(&self.underlying, time);
+        }
+        if *self.observations.last().unwrap() != self.expiry {
+            output.observation(&self.underlying, self.expiry_time);
+        }
+
+        // A knock-in is worth nothing unless the barrier is hit, so
+        // importance sampling should steer the paths towards the barrier by
+        // the end of the monitoring. Otherwise, what matters is ending in
+        // the money.
+        if self.barrier_type.is_knock_in() {
+            let last = self.underlying.time_to_day_fraction(
+                *self.observations.last().unwrap())?;
+            output.importance_level(&self.underlying, last, self.barrier);
+        } else {
+            output.importance_level(&self.underlying, self.expiry_time, self.strike);
+        }
+
+        // As for vanillas, we treat all barriers as if they paid cash at the
+        // pay date.
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        let n_obs = shape[1];
+        let n_barrier = self.observations.len();
+        assert!(n_obs == n_barrier || n_obs == n_barrier + 1);
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        let knock_in = self.barrier_type.is_knock_in();
+        let sign = match self.put_or_call {
+            PutOrCall::Call => 1.0,
+            PutOrCall::Put => -1.0 };
+
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let hit = path.iter().take(n_barrier).any(|spot|
+                    self.barrier_type.is_hit(self.barrier, *spot));
+                *flow = if hit == knock_in {
+                    (sign * (path[n_obs - 1] - self.strike)).max(0.0)
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        // sum and discount the flows
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use risk::Pricer;
+    use risk::BumpablePricingContext;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::calendar::WeekdayCalendar;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::PathGeneration;
+    use models::Threading;
+    use std::collections::HashMap;
+
+    fn sample_barrier(barrier: f64, barrier_type: BarrierType,
+        monitoring: BarrierMonitoring) -> BarrierOption {
+        let expiry = sample_expiry();
+        let equity = sample_underlying();
+        BarrierOption::new("SampleBarrier", "OPT", equity, sample_settlement(2),
+            expiry, 100.0, PutOrCall::Call, OptionSettlement::Cash,
+            barrier, barrier_type, monitoring).unwrap()
+    }
+
+    fn monthly_monitoring() -> BarrierMonitoring {
+        let mut dates = Vec::new();
+        let start = Date::from_ymd(2017, 02, 01);
+        for i in 0..17 {
+            dates.push(DateTime::new(start + 30 * i, TimeOfDay::Close));
+        }
+        dates.push(sample_expiry());
+        BarrierMonitoring::Discrete(dates)
+    }
+
+    fn sample_fixing_table(fixings: &[(DateTime, f64)]) -> FixingTable {
+        FixingTable::from_fixings(Date::from_ymd(2017, 03, 01),
+            &[("BP.L", fixings)]).unwrap()
+    }
+
+    #[test]
+    fn continuous_monitoring_skips_holidays() {
+        let start = DateTime::new(Date::from_ymd(2017, 01, 05), TimeOfDay::Open);
+        let expiry = DateTime::new(Date::from_ymd(2017, 01, 10), TimeOfDay::Close);
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let monitoring = BarrierMonitoring::Continuous { start, calendar };
+        let observations = monitoring.observations(expiry);
+        assert_eq!(observations, vec![start,
+            DateTime::new(Date::from_ymd(2017, 01, 06), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 01, 09), TimeOfDay::Close),
+            expiry]);
+    }
+
+    #[test]
+    fn barrier_observations_are_fixings() {
+        let barrier = sample_barrier(80.0, BarrierType::DownAndOut, monthly_monitoring());
+        let rc = RcInstrument::new(Qrc::new(Arc::new(barrier.clone())));
+        let mut context = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        context.spot(&rc);
+        assert_eq!(context.fixings("BP.L"), barrier.observations());
+    }
+
+    #[test]
+    fn knock_out_fixes_to_nothing() {
+        let barrier = sample_barrier(80.0, BarrierType::DownAndOut, monthly_monitoring());
+        let fixing_table = sample_fixing_table(&[
+            (DateTime::new(Date::from_ymd(2017, 02, 01), TimeOfDay::Close), 79.0)]);
+        let fixed = barrier.fix(&fixing_table).unwrap().unwrap();
+        assert!(fixed.is_empty());
+    }
+
+    #[test]
+    fn knock_in_fixes_to_european() {
+        let barrier = sample_barrier(80.0, BarrierType::DownAndIn, monthly_monitoring());
+        let fixing_table = sample_fixing_table(&[
+            (DateTime::new(Date::from_ymd(2017, 02, 01), TimeOfDay::Close), 79.0)]);
+        let fixed = barrier.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "SpotStartingEuropean");
+    }
+
+    #[test]
+    fn barrier_hits_are_lifecycle_events() {
+        let date = DateTime::new(Date::from_ymd(2017, 02, 01), TimeOfDay::Close);
+        let fixing_table = sample_fixing_table(&[(date, 79.0)]);
+        let from = Date::from_ymd(2017, 01, 02);
+        let to = Date::from_ymd(2017, 03, 01);
+
+        let knock_out = sample_barrier(80.0, BarrierType::DownAndOut, monthly_monitoring());
+        let events = knock_out.lifecycle_events(&fixing_table, from, to).unwrap();
+        assert_eq!(events, vec![LifecycleEvent::new("SampleBarrier", date,
+            LifecycleEventType::KnockedOut { level: 79.0 })]);
+
+        let knock_in = sample_barrier(80.0, BarrierType::DownAndIn, monthly_monitoring());
+        let events = knock_in.lifecycle_events(&fixing_table, from, to).unwrap();
+        assert_eq!(events, vec![LifecycleEvent::new("SampleBarrier", date,
+            LifecycleEventType::KnockedIn { level: 79.0 })]);
+
+        // unhit barriers and fixings outside the range give no events
+        let unhit = sample_barrier(70.0, BarrierType::DownAndIn, monthly_monitoring());
+        assert!(unhit.lifecycle_events(&fixing_table, from, to).unwrap().is_empty());
+        assert!(knock_out.lifecycle_events(&fixing_table, to, to + 30).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unhit_observations_are_removed() {
+        let barrier = sample_barrier(80.0, BarrierType::DownAndIn, monthly_monitoring());
+        let fixing_table = sample_fixing_table(&[
+            (DateTime::new(Date::from_ymd(2017, 02, 01), TimeOfDay::Close), 95.0)]);
+        let fixed = barrier.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        let mut context = DependencyCollector::new(Date::from_ymd(2017, 03, 01));
+        context.spot(&fixed[0].1);
+        assert_eq!(context.fixings("BP.L"), &barrier.observations()[1..]);
+    }
+
+    #[test]
+    fn knock_in_plus_knock_out_is_european() {
+
+        // In-out parity means the two barriers should add up to the analytic
+        // European price. (See the self-pricer tests for the European.) The
+        // tolerance allows for the two independent Monte-Carlo runs.
+        let market_data = sample_market_data();
+        let mut total = 0.0;
+        for barrier_type in [BarrierType::DownAndIn, BarrierType::DownAndOut].iter() {
+            let barrier = sample_barrier(80.0, *barrier_type, monthly_monitoring());
+            let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(barrier))))];
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 20000)));
+            let pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+                MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                    ..MonteCarloSettings::default() }, &market_data).unwrap();
+            let price = pricer.price().unwrap();
+            assert!(price > 0.0);
+            total += price;
+        }
+        assert_approx(total, 16.710717400832973, 0.8);
+    }
+
+    #[test]
+    fn importance_sampling_prices_far_knock_in() {
+
+        // Fewer than one path in a hundred reaches this barrier. Steering
+        // the paths towards it gives about the accuracy of a plain run with
+        // ten times as many paths. The tolerance allows for the noise in the
+        // plain run.
+        let market_data = sample_market_data();
+        let barrier = sample_barrier(250.0, BarrierType::UpAndIn, monthly_monitoring());
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(barrier))))];
+        let mut prices = Vec::new();
+        for &(n_paths, path_generation, importance_sampling) in [
+            (200000, PathGeneration::PseudoRandom, false),
+            (16383, PathGeneration::Sobol, true)].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, n_paths)));
+            let pricer = MonteCarloPricer::with_settings(instruments.clone(),
+                model_factory, MonteCarloSettings { path_generation: path_generation,
+                    importance_sampling: importance_sampling,
+                    ..MonteCarloSettings::default() }, &market_data).unwrap();
+            prices.push(pricer.price().unwrap());
+        }
+        assert!(prices[1] > 1.0);
+        assert_approx(prices[1], prices[0], 0.1);
+    }
+
+    #[test]
+    fn analytic_matches_monte_carlo() {
+
+        // The closed form, shifted for the monthly monitoring, against a
+        // Monte-Carlo run that sees only the observations. The tolerance
+        // allows for the discrete dividends, which the closed form spreads
+        // evenly over the life of the option.
+        let market_data = sample_market_data();
+        let context = market_data.as_pricing_context();
+        let val_date = sample_val_date();
+        for &(level, barrier_type) in [(80.0, BarrierType::DownAndOut),
+            (90.0, BarrierType::DownAndIn), (130.0, BarrierType::UpAndOut),
+            (150.0, BarrierType::UpAndIn)].iter() {
+            let barrier = sample_barrier(level, barrier_type, monthly_monitoring());
+            let analytic = barrier.analytic_price(context, &AnalyticModel::Black,
+                val_date).unwrap().unwrap();
+
+            let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(barrier))))];
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 65535)));
+            let pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+                MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                    ..MonteCarloSettings::default() }, &market_data).unwrap();
+            let mc = pricer.price().unwrap();
+            assert_approx(analytic, mc, 0.25);
+        }
+    }
+
+    #[test]
+    fn analytic_knock_in_plus_knock_out_is_european() {
+        let market_data = sample_market_data();
+        let context = market_data.as_pricing_context();
+        let val_date = sample_val_date();
+        for &(level, knock_in, knock_out) in [
+            (80.0, BarrierType::DownAndIn, BarrierType::DownAndOut),
+            (120.0, BarrierType::UpAndIn, BarrierType::UpAndOut)].iter() {
+            let mut total = 0.0;
+            for barrier_type in [knock_in, knock_out].iter() {
+                let barrier = sample_barrier(level, *barrier_type, monthly_monitoring());
+                let price = barrier.analytic_price(context, &AnalyticModel::Black,
+                    val_date).unwrap().unwrap();
+                assert!(price > 0.0);
+                total += price;
+            }
+            assert_approx(total, 16.710717400832973, 1e-10);
+        }
+    }
+
+    #[test]
+    fn analytic_needs_observation_at_expiry() {
+        let market_data = sample_market_data();
+        let context = market_data.as_pricing_context();
+        let val_date = sample_val_date();
+        let dates = (0..12).map(|i| DateTime::new(Date::from_ymd(2017, 02, 01) + 30 * i,
+            TimeOfDay::Close)).collect();
+        let barrier = sample_barrier(80.0, BarrierType::DownAndOut,
+            BarrierMonitoring::Discrete(dates));
+        assert!(barrier.analytic_price(context, &AnalyticModel::Black,
+            val_date).unwrap().is_none());
+
+        // nor is there one under Bachelier
+        let barrier = sample_barrier(80.0, BarrierType::DownAndOut, monthly_monitoring());
+        let model = AnalyticModel::Bachelier(HashMap::new());
+        assert!(barrier.analytic_price(context, &model, val_date).unwrap().is_none());
+    }
+
+    #[test]
+    fn down_and_out_knocked_out_by_time_bump() {
+
+        // A barrier just below spot. Rolling the spot date over the first
+        // observation with sticky forward dynamics fixes below the barrier,
+        // because the first dividend goes ex before the first observation.
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let barrier = sample_barrier(99.5, BarrierType::DownAndOut, monthly_monitoring());
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(barrier)));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&instrument);
+
+        // rolling to just before the first observation changes nothing
+        let mut instruments = vec![(1.0, instrument)];
+        let time_bump = BumpTime::new(Date::from_ymd(2017, 02, 01), spot_date,
+            SpotDynamics::StickyForward);
+        let changed = time_bump.update_instruments(&mut instruments,
+            &market_data, &dependencies).unwrap();
+        assert!(!changed);
+        assert_eq!(instruments.len(), 1);
+
+        // rolling past it knocks the barrier out
+        let time_bump = BumpTime::new(Date::from_ymd(2017, 02, 02), spot_date,
+            SpotDynamics::StickyForward);
+        let changed = time_bump.update_instruments(&mut instruments,
+            &market_data, &dependencies).unwrap();
+        assert!(changed);
+        assert!(instruments.is_empty());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -14,6 +14,9 @@ use instruments::DependencyContext;
 use instruments::SpotRequirement;
 use instruments::assets::Currency;
 use instruments::assets::RcCurrency;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::lifecycle_events_all;
+use dates::Date;
 use dates::rules::RcDateRule;
 use dates::datetime::TimeOfDay;
 use dates::datetime::DateTime;
@@ -106,6 +109,12 @@ impl Instrument for Basket {
             None => Ok(None)
         }
     }
+
+    /// The events of the components, scaled by their weights
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+        lifecycle_events_all(&self.basket, fixing_table, from, to)
+    }
 }
 
 impl Display for Basket {
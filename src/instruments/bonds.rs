@@ -4,14 +4,22 @@ use std::hash::Hash;
 use std::hash::Hasher;
 use std::sync::Arc;
 use instruments::Instrument;
+use instruments::FixedPayment;
+use instruments::RcInstrument;
 use instruments::Priceable;
 use instruments::PricingContext;
 use instruments::DependencyContext;
 use instruments::SpotRequirement;
 use instruments::assets::Currency;
 use instruments::assets::RcCurrency;
+use instruments::swaps::FixedLeg;
+use instruments::options::PutOrCall;
+use instruments::exercise::ExerciseSchedule;
+use math::lattice::ShortRateLattice;
 use dates::Date;
 use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use dates::datetime::DateDayFraction;
 use dates::rules::RcDateRule;
 use core::qm;
 use core::factories::TypeId;
@@ -91,9 +99,31 @@ impl Instrument for ZeroCoupon {
         true
     }
 
+    /// Zero coupons can be the underlying of options under a short rate
+    /// model. As for equities, we hard-code the conversion for now.
+    fn time_to_day_fraction(&self, date_time: DateTime)
+        -> Result<DateDayFraction, qm::Error> {
+
+        let day_fraction = match date_time.time_of_day() {
+            TimeOfDay::Open => 0.0,
+            TimeOfDay::EDSP => 0.0,
+            TimeOfDay::Close => 0.8 };
+        Ok(DateDayFraction::new(date_time.date(), day_fraction))
+    }
+
     fn as_priceable(&self) -> Option<&Priceable> {
         Some(self)
     }
+
+    fn as_fixed_payment(&self) -> Option<&FixedPayment> {
+        Some(self)
+    }
+}
+
+impl FixedPayment for ZeroCoupon {
+    fn ex_date(&self) -> DateTime { self.ex_date }
+    fn payment_date(&self) -> Date { self.payment_date }
+    fn as_instrument(&self) -> &Instrument { self }
 }
 
 impl Display for ZeroCoupon {
@@ -141,6 +171,432 @@ impl Priceable for ZeroCoupon {
     }
 }
 
+/// A bond paying fixed coupons on a schedule, and returning its notional
+/// on the final payment date. The coupon schedule and day count are those
+/// of a swap fixed leg. It is discounted according to the yield curve
+/// matching its credit_id, so it can represent a risky bond.
+///
+/// Trades in the bond settle according to its settlement rule, which is
+/// normally different from that of the currency. A buyer receives all
+/// coupons paid after the settlement date, and pays the seller the interest
+/// accrued since the last coupon on top of the quoted clean price.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FixedCouponBond {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    coupons: FixedLeg,
+    settlement: RcDateRule
+}
+
+impl TypeId for FixedCouponBond {
+    fn type_id(&self) -> &'static str { "FixedCouponBond" }
+}
+
+impl InstanceId for FixedCouponBond {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl FixedCouponBond {
+    /// Creates a fixed coupon bond. The notional is paid together with the
+    /// final coupon.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        coupons: FixedLeg, settlement: RcDateRule)
+        -> Result<FixedCouponBond, qm::Error> {
+
+        if coupons.periods().is_empty() {
+            return Err(qm::Error::new("Bond must have at least one coupon"))
+        }
+
+        Ok(FixedCouponBond { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional, coupons: coupons,
+            settlement: settlement })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(FixedCouponBond::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn coupons(&self) -> &FixedLeg { &self.coupons }
+
+    pub fn maturity(&self) -> Date {
+        self.coupons.periods().last().map_or(Date::from_nil(), |p| p.payment())
+    }
+
+    /// The coupon interest accrued from the start of the current period
+    /// up to the given settlement date. This is zero on a coupon date, and
+    /// before the bond starts accruing.
+    pub fn accrued_interest(&self, settlement_date: Date) -> f64 {
+        let day_count = self.coupons.day_count();
+        for period in self.coupons.periods().iter() {
+            if period.start() <= settlement_date && settlement_date < period.payment() {
+                let end = if settlement_date < period.end() {
+                    settlement_date
+                } else {
+                    period.end()
+                };
+                return self.notional * self.coupons.rate()
+                    * day_count.year_fraction(period.start(), end)
+            }
+        }
+        0.0
+    }
+
+    /// The quoted price of the bond, which is the dirty price, as returned
+    /// by price, less the accrued interest at the settlement date.
+    pub fn clean_price(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<f64, qm::Error> {
+        let dirty = self.price(context, val_date)?;
+        let settlement_date = self.settlement.apply(val_date.date());
+        Ok(dirty - self.accrued_interest(settlement_date))
+    }
+}
+
+impl Instrument for FixedCouponBond {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.maturity());
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for FixedCouponBond {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The dirty price of the bond, which includes accrued interest. Coupons
+    /// and the notional are included if they are paid after the settlement
+    /// date, and are discounted to it.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.maturity())?;
+        let day_count = self.coupons.day_count();
+        let maturity = self.maturity();
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let settlement_date = self.settlement.apply(date.date());
+
+            let mut total = 0.0;
+            for period in self.coupons.periods().iter()
+                .filter(|p| p.payment() > settlement_date) {
+                let coupon = self.coupons.rate()
+                    * day_count.year_fraction(period.start(), period.end());
+                total += coupon * yc.df(period.payment(), settlement_date)?;
+            }
+            if maturity > settlement_date {
+                total += yc.df(maturity, settlement_date)?;
+            }
+
+            *output = self.notional * total;
+        }
+
+        Ok(())
+    }
+}
+
+/// The right of the issuer to redeem a bond early, as a call, or of the
+/// holder, as a put, on a given date at a given price per unit notional.
+/// On exercise, the holder receives the exercise price times the notional,
+/// plus the interest accrued to the exercise date, on the exercise date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct EmbeddedExercise {
+    date: Date,
+    price: f64,
+    put_or_call: PutOrCall
+}
+
+impl EmbeddedExercise {
+    pub fn new(date: Date, price: f64, put_or_call: PutOrCall) -> EmbeddedExercise {
+        EmbeddedExercise { date: date, price: price, put_or_call: put_or_call }
+    }
+
+    pub fn date(&self) -> Date { self.date }
+    pub fn price(&self) -> f64 { self.price }
+    pub fn put_or_call(&self) -> PutOrCall { self.put_or_call }
+}
+
+/// The maximum number of steps in the lattice used for pricing. Steps are
+/// a whole number of days, so short bonds are priced with daily steps.
+const CALLABLE_LATTICE_MAX_STEPS: i32 = 1000;
+
+/// A fixed coupon bond that the issuer may redeem early on any of a schedule
+/// of call dates, or that the holder may redeem early on any of a schedule
+/// of put dates, or both.
+///
+/// The bond is priced on a Ho-Lee short rate lattice calibrated to the
+/// yield curve matching its credit id. The normal volatility of the short
+/// rate is taken from a vol cube, at the money, with the expiry of the last
+/// exercise and the tenor from there to maturity.
+///
+/// The exercise dates are registered as dependencies, so that when time
+/// moves past one of them, the decision is made on the forward value of the
+/// remaining cashflows. A called or put bond becomes a payment of the
+/// exercise value, and a bond with no remaining exercises becomes a plain
+/// fixed coupon bond.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CallableBond {
+    bond: FixedCouponBond,
+    exercises: Vec<EmbeddedExercise>,
+    vol_cube_id: String
+}
+
+impl TypeId for CallableBond {
+    fn type_id(&self) -> &'static str { "CallableBond" }
+}
+
+impl InstanceId for CallableBond {
+    fn id(&self) -> &str { &self.bond.id }
+}
+
+impl CallableBond {
+    /// Creates a callable or puttable bond. There must be at least one
+    /// exercise, and all of them must be on or before maturity.
+    pub fn new(bond: FixedCouponBond, exercises: &[EmbeddedExercise],
+        vol_cube_id: &str) -> Result<CallableBond, qm::Error> {
+
+        if exercises.is_empty() {
+            return Err(qm::Error::new("Callable bond must have at least one exercise"))
+        }
+        let maturity = bond.maturity();
+        if exercises.iter().any(|e| e.date > maturity) {
+            return Err(qm::Error::new("Callable bond exercises must not be after maturity"))
+        }
+
+        let mut exercises = exercises.to_vec();
+        exercises.sort_by_key(|e| e.date);
+        Ok(CallableBond { bond: bond, exercises: exercises,
+            vol_cube_id: vol_cube_id.to_string() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CallableBond::deserialize(de)?)))
+    }
+
+    pub fn bond(&self) -> &FixedCouponBond { &self.bond }
+    pub fn exercises(&self) -> &[EmbeddedExercise] { &self.exercises }
+
+    /// The dates of the embedded exercises, which are decided at the close
+    pub fn exercise_schedule(&self) -> Result<ExerciseSchedule, qm::Error> {
+        let dates: Vec<DateTime> = self.exercises.iter()
+            .map(|e| DateTime::new(e.date, TimeOfDay::Close)).collect();
+        ExerciseSchedule::new(&dates)
+    }
+
+    /// The cashflows of the underlying bond paid after the given date,
+    /// including the notional at maturity
+    fn cashflows(&self, after: Date) -> Vec<(Date, f64)> {
+        let coupons = &self.bond.coupons;
+        let day_count = coupons.day_count();
+        let mut flows: Vec<(Date, f64)> = coupons.periods().iter()
+            .filter(|p| p.payment() > after)
+            .map(|p| (p.payment(), self.bond.notional * coupons.rate()
+                * day_count.year_fraction(p.start(), p.end()))).collect();
+        let maturity = self.bond.maturity();
+        if maturity > after {
+            flows.push((maturity, self.bond.notional));
+        }
+        flows
+    }
+
+    /// The amount received on exercise
+    fn exercise_value(&self, exercise: &EmbeddedExercise) -> f64 {
+        self.bond.notional * exercise.price + self.bond.accrued_interest(exercise.date)
+    }
+
+    /// Prices the bond on a lattice whose first step is the given settlement
+    /// date, discounting to that date.
+    fn lattice_price(&self, context: &PricingContext, settlement_date: Date)
+        -> Result<f64, qm::Error> {
+
+        let maturity = self.bond.maturity();
+        if maturity <= settlement_date {
+            return Ok(0.0)
+        }
+
+        // lay out the steps in whole days, with the last step at maturity
+        let days = maturity - settlement_date;
+        let step_days = (days + CALLABLE_LATTICE_MAX_STEPS - 1) / CALLABLE_LATTICE_MAX_STEPS;
+        let steps = ((days + step_days - 1) / step_days) as usize;
+        let step_of = |date: Date| {
+            let offset = (date - settlement_date) as f64 / step_days as f64;
+            (offset.round() as usize).min(steps)
+        };
+
+        let yc = context.yield_curve(&self.bond.credit_id, maturity)?;
+        let mut dfs = Vec::with_capacity(steps + 1);
+        for i in 0..(steps + 1) {
+            let date = (settlement_date + i as i32 * step_days).min(maturity);
+            dfs.push(yc.df(date, settlement_date)?);
+        }
+
+        // exercises on or before the settlement date can no longer be made
+        let exercises: Vec<&EmbeddedExercise> = self.exercises.iter()
+            .filter(|e| e.date > settlement_date).collect();
+        let vol = match exercises.last() {
+            None => 0.0,
+            Some(last) => {
+                let cube = context.vol_cube(&self.vol_cube_id, maturity)?;
+                let tenor = (maturity - last.date) as f64 / 365.0;
+                cube.volatility(last.date, tenor, 0.0, 0.0)?
+            }
+        };
+        let lattice = ShortRateLattice::new(&dfs, step_days as f64 / 365.0, vol)?;
+
+        let mut flows = vec![0.0; steps + 1];
+        for (date, amount) in self.cashflows(settlement_date) {
+            flows[step_of(date)] += amount;
+        }
+
+        // Roll back through the lattice. At an exercise, the issuer calls if
+        // the bond is worth more than the exercise value, and the holder puts
+        // if it is worth less. Cashflows on the exercise date are paid either
+        // way, so they are added afterwards.
+        let mut values = vec![flows[steps]; steps + 1];
+        let mut next_exercise = exercises.len();
+        for step in (0..steps).rev() {
+            values = lattice.roll_back(step, &values);
+            while next_exercise > 0 && step_of(exercises[next_exercise - 1].date) >= step {
+                next_exercise -= 1;
+                let exercise = exercises[next_exercise];
+                if step_of(exercise.date) != step {
+                    continue;
+                }
+                let strike = self.exercise_value(exercise);
+                for value in values.iter_mut() {
+                    *value = match exercise.put_or_call {
+                        PutOrCall::Call => value.min(strike),
+                        PutOrCall::Put => value.max(strike)
+                    };
+                }
+            }
+            for value in values.iter_mut() {
+                *value += flows[step];
+            }
+        }
+
+        Ok(values[0])
+    }
+}
+
+impl Instrument for CallableBond {
+
+    fn payoff_currency(&self) -> &Currency {
+        self.bond.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.bond.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.bond.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        let maturity = self.bond.maturity();
+        context.yield_curve(&self.bond.credit_id, maturity);
+        context.vol_cube(&self.vol_cube_id, maturity);
+        if let Ok(schedule) = self.exercise_schedule() {
+            schedule.dependencies(&self.bond.id, context);
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    /// The decision is made on the forward value of the remaining cashflows
+    /// as of the exercise date, as implied by the current yield curve.
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let exercise_date = date.date();
+        let exercise = match self.exercises.iter().find(|e| e.date == exercise_date) {
+            None => return Ok(None),
+            Some(exercise) => exercise
+        };
+
+        let yc = context.yield_curve(&self.bond.credit_id, self.bond.maturity())?;
+        let mut forward_value = 0.0;
+        for (payment, amount) in self.cashflows(exercise_date) {
+            forward_value += amount * yc.df(payment, exercise_date)?;
+        }
+
+        let strike = self.exercise_value(exercise);
+        let exercised = match exercise.put_or_call {
+            PutOrCall::Call => forward_value > strike,
+            PutOrCall::Put => forward_value < strike
+        };
+
+        if exercised {
+            let id = format!("{}.EXERCISED", self.bond.id);
+            let payment = ZeroCoupon::new(&id, &self.bond.credit_id,
+                self.bond.currency.clone(), date, exercise_date,
+                self.bond.settlement.clone());
+            return Ok(Some(vec![(strike, RcInstrument::new(Qrc::new(Arc::new(payment))))]))
+        }
+
+        let remaining: Vec<EmbeddedExercise> = self.exercises.iter()
+            .filter(|e| e.date > exercise_date).cloned().collect();
+        let unexercised = if remaining.is_empty() {
+            RcInstrument::new(Qrc::new(Arc::new(self.bond.clone())))
+        } else {
+            RcInstrument::new(Qrc::new(Arc::new(CallableBond {
+                bond: self.bond.clone(), exercises: remaining,
+                vol_cube_id: self.vol_cube_id.clone() })))
+        };
+        Ok(Some(vec![(1.0, unexercised)]))
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for CallableBond {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The dirty price of the bond, including the value of the embedded
+    /// exercises, discounted to the settlement date.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let settlement_date = self.bond.settlement.apply(date.date());
+            *output = self.lattice_price(context, settlement_date)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -153,9 +609,31 @@ mod tests {
     use dates::calendar::WeekdayCalendar;
     use dates::calendar::RcCalendar;
     use dates::rules::BusinessDays;
+    use dates::rules::ModifiedFollowing;
+    use dates::daycount::DayCount;
     use dates::Date;
     use dates::datetime::TimeOfDay;
     use std::sync::Arc;
+    use instruments::RcInstrument;
+    use data::bump::Bump;
+    use data::bumpyield::BumpYield;
+    use risk::Pricer;
+    use risk::ReportGenerator;
+    use risk::dv01::Dv01ReportGenerator;
+    use risk::dv01::Dv01Report;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use risk::vegavolga::VegaVolgaReportGenerator;
+    use risk::vegavolga::VegaVolgaReport;
+    use pricers::selfpricer::SelfPricer;
+    use data::volcube::FlatVolCube;
+    use data::volcube::RcVolCube;
+    use data::bumpvol::BumpVol;
+    use data::bumpspotdate::SpotDynamics;
+    use serde_json;
 
     fn sample_currency(step: u32) -> Currency {
         let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
@@ -226,6 +704,254 @@ mod tests {
         assert_approx(price, 0.9930885737840461);
     }
 
+    fn sample_fixed_coupon_bond(currency: RcCurrency, step: u32) -> FixedCouponBond {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, step)));
+        let coupons = FixedLeg::new(Date::from_ymd(2017, 07, 05),
+            Date::from_ymd(2019, 07, 05), 6, &adjustment, DayCount::Thirty360,
+            0.06).unwrap();
+        FixedCouponBond::new("GBP.6%.2019", "OPT", currency, 100.0, coupons,
+            settlement).unwrap()
+    }
+
+    #[test]
+    fn fixed_coupon_bond_is_a_strip_of_zero_coupons() {
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 05), TimeOfDay::Open);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = sample_fixed_coupon_bond(currency.clone(), 1);
+        let context = sample_pricing_context();
+        let price = bond.price(&context, val_date).unwrap();
+
+        // the coupon paid in January 2018 has gone, leaving three coupons.
+        // 2019-01-05 is a Saturday, so the coupon periods either side of it
+        // are adjusted, and accrue on the adjusted dates.
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 1)));
+        let dates = [Date::from_ymd(2018, 01, 05), Date::from_ymd(2018, 07, 05),
+            Date::from_ymd(2019, 01, 07), Date::from_ymd(2019, 07, 05)];
+        let mut expected = 0.0;
+        for pair in dates.windows(2) {
+            let payment = pair[1];
+            let mut amount = 6.0 * DayCount::Thirty360.year_fraction(pair[0], payment);
+            if payment == Date::from_ymd(2019, 07, 05) {
+                amount += 100.0;
+            }
+            let zero = ZeroCoupon::new("zero", "OPT", currency.clone(),
+                DateTime::new(payment, TimeOfDay::Open), payment, settlement.clone());
+            expected += amount * zero.price(&context, val_date).unwrap();
+        }
+        assert_approx(price, expected);
+    }
+
+    #[test]
+    fn fixed_coupon_bond_accrued_interest() {
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 05), TimeOfDay::Open);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = sample_fixed_coupon_bond(currency, 1);
+
+        // settles on 2018-06-06, five months and a day into the period
+        // starting on 2018-01-05
+        let accrued = bond.accrued_interest(Date::from_ymd(2018, 06, 06));
+        assert_approx(accrued, 100.0 * 0.06 * 151.0 / 360.0);
+        assert_eq!(bond.accrued_interest(Date::from_ymd(2017, 07, 05)), 0.0);
+        assert_eq!(bond.accrued_interest(Date::from_ymd(2017, 01, 05)), 0.0);
+        assert_eq!(bond.accrued_interest(Date::from_ymd(2019, 07, 05)), 0.0);
+
+        let context = sample_pricing_context();
+        let dirty = bond.price(&context, val_date).unwrap();
+        let clean = bond.clean_price(&context, val_date).unwrap();
+        assert_approx(dirty - clean, accrued);
+    }
+
+    #[test]
+    fn fixed_coupon_bond_dv01() {
+        let market_data = sample_market_data();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = sample_fixed_coupon_bond(currency, 1);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(bond)));
+        let mut pricer = SelfPricer::new(vec![(1.0, instrument)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = Dv01ReportGenerator::new(0.0001);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<Dv01Report>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        let dv01 = *results.get("OPT").unwrap();
+
+        // a bond of a notional of 100 and a bit over two years to run loses
+        // roughly two cents for each basis point
+        assert!(dv01 < -0.015 && dv01 > -0.025, "dv01={}", dv01);
+
+        let bump = Bump::new_yield("OPT", BumpYield::new_flat_annualised(0.0001));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        let bumped = pricer.price().unwrap();
+        assert!(approx_eq(bumped - unbumped, dv01, 1e-5),
+            "bumped={} unbumped={} dv01={}", bumped, unbumped, dv01);
+    }
+
+    #[test]
+    fn fixed_coupon_bond_tagged_serde() {
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 05), TimeOfDay::Open);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = sample_fixed_coupon_bond(currency, 1);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(bond.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let context = sample_pricing_context();
+        let price = deserialized.as_priceable().unwrap().price(&context, val_date).unwrap();
+        assert_approx(price, bond.price(&context, val_date).unwrap());
+    }
+
+    fn sample_callable(coupon: f64, price: f64, put_or_call: PutOrCall) -> CallableBond {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 1)));
+        let coupons = FixedLeg::new(Date::from_ymd(2016, 07, 05),
+            Date::from_ymd(2019, 07, 05), 6, &adjustment, DayCount::Thirty360,
+            coupon).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = FixedCouponBond::new("GBP.CALLABLE.2019", "OPT", currency, 100.0,
+            coupons, settlement).unwrap();
+
+        // exercisable on the coupon dates, starting this week
+        let exercises: Vec<EmbeddedExercise> = [Date::from_ymd(2017, 01, 05),
+            Date::from_ymd(2017, 07, 05), Date::from_ymd(2018, 01, 05),
+            Date::from_ymd(2018, 07, 05), Date::from_ymd(2019, 01, 07)].iter()
+            .map(|&date| EmbeddedExercise::new(date, price, put_or_call)).collect();
+        CallableBond::new(bond, &exercises, "GBP.SHORTRATE").unwrap()
+    }
+
+    fn sample_callable_market_data(vol: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        let cube = FlatVolCube::new(vol, market_data.spot_date());
+        market_data.add_vol_cube("GBP.SHORTRATE", RcVolCube::new(Arc::new(cube)));
+        market_data
+    }
+
+    fn sample_market_val_date() -> DateTime {
+        sample_val_date()
+    }
+
+    #[test]
+    fn unreachable_exercise_gives_straight_bond() {
+        let market_data = sample_callable_market_data(0.01);
+        let val_date = sample_market_val_date();
+        let callable = sample_callable(0.06, 10.0, PutOrCall::Call);
+        let straight = callable.bond().price(&market_data, val_date).unwrap();
+        let price = callable.price(&market_data, val_date).unwrap();
+        assert!(approx_eq(price, straight, 1e-9), "price={} straight={}", price, straight);
+
+        let puttable = sample_callable(0.06, 0.0, PutOrCall::Put);
+        let price = puttable.price(&market_data, val_date).unwrap();
+        assert!(approx_eq(price, straight, 1e-9), "price={} straight={}", price, straight);
+    }
+
+    #[test]
+    fn embedded_exercise_bounds() {
+        let market_data = sample_callable_market_data(0.01);
+        let val_date = sample_market_val_date();
+        let callable = sample_callable(0.08, 1.0, PutOrCall::Call);
+        let puttable = sample_callable(0.08, 1.0, PutOrCall::Put);
+        let straight = callable.bond().price(&market_data, val_date).unwrap();
+        let called = callable.price(&market_data, val_date).unwrap();
+        let put = puttable.price(&market_data, val_date).unwrap();
+        assert!(called < straight, "called={} straight={}", called, straight);
+        assert!(put > straight, "put={} straight={}", put, straight);
+
+        // the issuer can redeem at par plus accrued this week, which is
+        // what the holder would receive on 2017-01-05
+        assert!(called <= 100.0 + 4.0, "called={}", called);
+
+        // the option to call is worth more to the issuer when rates are
+        // more volatile, so the holder of a callable loses value
+        let volatile = sample_callable_market_data(0.02);
+        let more_called = callable.price(&volatile, val_date).unwrap();
+        assert!(more_called < called, "more_called={} called={}", more_called, called);
+        let more_put = puttable.price(&volatile, val_date).unwrap();
+        assert!(more_put > put, "more_put={} put={}", more_put, put);
+    }
+
+    #[test]
+    fn callable_vega_from_short_rate_vol() {
+        let market_data = sample_callable_market_data(0.01);
+        let callable = sample_callable(0.08, 1.0, PutOrCall::Call);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(callable)));
+        let mut pricer = SelfPricer::new(vec![(1.0, instrument)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = VegaVolgaReportGenerator::new(BumpVol::new_flat_additive(0.001));
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaVolgaReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        let vega = results.get("GBP.SHORTRATE").unwrap().vega();
+        assert!(vega < 0.0, "vega={}", vega);
+    }
+
+    #[test]
+    fn exercise_dates_age_under_bump_time() {
+        let market_data = sample_callable_market_data(0.01);
+        let spot_date = market_data.spot_date();
+
+        // a high coupon bond that is worth more than par on the forward
+        // is called on the first exercise date
+        let callable = RcInstrument::new(Qrc::new(Arc::new(
+            sample_callable(0.12, 1.0, PutOrCall::Call))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&callable);
+        assert_eq!(dependencies.exercises("GBP.CALLABLE.2019").len(), 5);
+
+        let mut instruments = vec![(1.0, callable.clone())];
+        let bump = BumpTime::new(spot_date + 2, spot_date, SpotDynamics::StickyForward);
+        assert!(!bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+
+        let bump = BumpTime::new(spot_date + 4, spot_date, SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].0, 100.0);
+        assert_eq!(instruments[0].1.type_id(), "ZeroCoupon");
+
+        // the same bond callable at a high price is not called, and keeps
+        // its remaining exercises
+        let callable = RcInstrument::new(Qrc::new(Arc::new(
+            sample_callable(0.12, 1.2, PutOrCall::Call))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&callable);
+        let mut instruments = vec![(1.0, callable.clone())];
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].0, 1.0);
+        let aged = instruments[0].1.clone();
+        assert_eq!(aged.type_id(), "CallableBond");
+        let mut aged_dependencies = DependencyCollector::new(spot_date + 4);
+        aged_dependencies.spot(&aged);
+        assert_eq!(aged_dependencies.exercises("GBP.CALLABLE.2019").len(), 4);
+
+        // once the last exercise has passed, it is a plain bond
+        let last = DateTime::new(Date::from_ymd(2019, 01, 07), TimeOfDay::Close);
+        let plain = aged.exercise(&market_data, last).unwrap().unwrap();
+        assert_eq!(plain[0].1.type_id(), "FixedCouponBond");
+    }
+
+    #[test]
+    fn callable_bond_tagged_serde() {
+        let market_data = sample_callable_market_data(0.01);
+        let val_date = sample_market_val_date();
+        let callable = sample_callable(0.08, 1.0, PutOrCall::Call);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(callable.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, callable.price(&market_data, val_date).unwrap());
+    }
+
     fn assert_approx(value: f64, expected: f64) {
         assert!(approx_eq(value, expected, 1e-12),
             "value={} expected={}", value, expected);
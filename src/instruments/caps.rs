@@ -0,0 +1,375 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::swaps::FloatingLeg;
+use instruments::swaps::FloatingPeriod;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::daycount::DayCount;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// Whether each period pays out when the floating rate is above the strike,
+/// as for a cap, or below it, as for a floor.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum CapOrFloor {
+    Cap,
+    Floor
+}
+
+/// An interest rate cap or floor is a strip of caplets or floorlets, one
+/// for each period of a floating leg. Each pays the notional times the
+/// accrual fraction times the amount by which the floating rate, including
+/// any spread on the leg, exceeds the strike for a cap, or falls below it
+/// for a floor, at the end of the period.
+///
+/// Each caplet is priced with Black-76 on its forward rate, using vols
+/// from a vol cube, normally caplet vols. Every unfixed period registers its
+/// own fixing, so that as time moves on, caplets whose rate has fixed become
+/// known payments.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CapFloor {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    cap_or_floor: CapOrFloor,
+    strike: f64,
+    leg: FloatingLeg,
+    vol_cube_id: String
+}
+
+impl TypeId for CapFloor {
+    fn type_id(&self) -> &'static str { "CapFloor" }
+}
+
+impl CapFloor {
+    /// Creates a cap or floor on the periods of the given floating leg. The
+    /// payments are discounted on the yield curve of the credit id, and
+    /// the vols are taken from the vol cube with the given id.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        cap_or_floor: CapOrFloor, strike: f64, leg: FloatingLeg, vol_cube_id: &str)
+        -> Result<CapFloor, qm::Error> {
+
+        if strike <= 0.0 {
+            return Err(qm::Error::new("Cap and floor strikes must be positive"))
+        }
+        if leg.periods().is_empty() {
+            return Err(qm::Error::new("Cap or floor must have at least one period"))
+        }
+
+        Ok(CapFloor { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional, cap_or_floor: cap_or_floor,
+            strike: strike, leg: leg, vol_cube_id: vol_cube_id.to_string() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CapFloor::deserialize(de)?)))
+    }
+
+    pub fn cap_or_floor(&self) -> CapOrFloor { self.cap_or_floor }
+    pub fn strike(&self) -> f64 { self.strike }
+    pub fn floating_leg(&self) -> &FloatingLeg { &self.leg }
+    pub fn vol_cube_id(&self) -> &str { &self.vol_cube_id }
+
+    fn intrinsic(&self, rate: f64) -> f64 {
+        match self.cap_or_floor {
+            CapOrFloor::Cap => (rate - self.strike).max(0.0),
+            CapOrFloor::Floor => (self.strike - rate).max(0.0)
+        }
+    }
+
+    fn last_period(&self) -> &FloatingPeriod {
+        &self.leg.periods()[self.leg.periods().len() - 1]
+    }
+}
+
+impl InstanceId for CapFloor {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for CapFloor {
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.currency.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        let last = self.last_period();
+        context.yield_curve(&self.credit_id, last.accrual().payment());
+        context.yield_curve(self.leg.forecast_id(), last.accrual().end());
+        context.vol_cube(&self.vol_cube_id, last.fixing_date().date());
+        self.leg.dependencies(context);
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        match self.leg.fix(fixing_table)? {
+            None => Ok(None),
+            Some(leg) => {
+                let mut cap = self.clone();
+                cap.leg = leg;
+                Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(cap))))]))
+            }
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for CapFloor {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Caplets paid on or before the valuation date are excluded. Caplets
+    /// whose rate is known, or whose fixing date has passed, are worth their
+    /// intrinsic value.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let black76 = Black76::new()?;
+        let last = self.last_period();
+        let yc = context.yield_curve(&self.credit_id, last.accrual().payment())?;
+        let forecast = context.yield_curve(self.leg.forecast_id(), last.accrual().end())?;
+        let cube = context.vol_cube(&self.vol_cube_id, last.fixing_date().date())?;
+        let day_count = self.leg.day_count();
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let discount_date = self.settlement().apply(date.date());
+
+            let mut total = 0.0;
+            for period in self.leg.periods().iter()
+                .filter(|p| p.accrual().payment() > date.date()) {
+
+                let accrual = period.accrual();
+                let year_fraction = day_count.year_fraction(accrual.start(), accrual.end());
+                let df = yc.df(accrual.payment(), discount_date)? * year_fraction;
+                let rate = self.leg.rate(period, &*forecast)? + self.leg.spread();
+                let vol_time = vol_time(date.date(), period.fixing_date().date());
+
+                total += if period.fixing().is_some() || vol_time <= 0.0 {
+                    df * self.intrinsic(rate)
+                } else {
+                    let tenor = DayCount::Act365.year_fraction(accrual.start(), accrual.end());
+                    let vol = cube.volatility(period.fixing_date().date(), tenor,
+                        rate, self.strike)?;
+                    let sqrt_variance = vol * vol_time.sqrt();
+                    match self.cap_or_floor {
+                        CapOrFloor::Cap => black76.call_price(df, rate, self.strike, sqrt_variance),
+                        CapOrFloor::Floor => black76.put_price(df, rate, self.strike, sqrt_variance)
+                    }
+                };
+            }
+
+            *output = self.notional * total;
+        }
+
+        Ok(())
+    }
+}
+
+/// Vol cubes measure vol time as Act/365
+fn vol_time(from: Date, to: Date) -> f64 {
+    DayCount::Act365.year_fraction(from, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::swaps::FixedLeg;
+    use instruments::swaps::InterestRateSwap;
+    use instruments::swaps::PayOrReceive;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use risk::vegavolga::VegaVolgaReportGenerator;
+    use risk::vegavolga::VegaVolgaReport;
+    use risk::ReportGenerator;
+    use risk::Pricer;
+    use pricers::selfpricer::SelfPricer;
+    use data::volcube::RcVolCube;
+    use data::volcube::CapletVols;
+    use data::bumpvol::BumpVol;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::BusinessDays;
+    use dates::rules::ModifiedFollowing;
+    use serde_json;
+
+    fn sample_dates() -> (Date, Date) {
+        (Date::from_ymd(2017, 01, 04), Date::from_ymd(2019, 01, 04))
+    }
+
+    fn sample_leg() -> FloatingLeg {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let fixing_rule = BusinessDays::new_back(calendar, 2);
+        let (start, end) = sample_dates();
+        FloatingLeg::new(start, end, 6, &adjustment, DayCount::Act360,
+            "GBPLIBOR6M", "LSE", &fixing_rule, 0.0).unwrap()
+    }
+
+    fn sample_cap(cap_or_floor: CapOrFloor, strike: f64) -> CapFloor {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        CapFloor::new("SampleCap", "OPT", currency, 1000000.0, cap_or_floor,
+            strike, sample_leg(), "GBPLIBOR6M.CAPLET").unwrap()
+    }
+
+    fn sample_cap_market_data() -> MarketData {
+        let mut market_data = sample_market_data();
+        let base = market_data.spot_date();
+        let vols = CapletVols::new(base, &[base + 182, base + 730],
+            &[0.07, 0.1], &[0.25, 0.22, 0.21, 0.19]).unwrap();
+        market_data.add_vol_cube("GBPLIBOR6M.CAPLET", RcVolCube::new(Arc::new(vols)));
+        market_data
+    }
+
+    #[test]
+    fn caplets_priced_with_black() {
+        let market_data = sample_cap_market_data();
+        let val_date = sample_val_date();
+        let cap = sample_cap(CapOrFloor::Cap, 0.085);
+        let price = cap.price(&market_data, val_date).unwrap();
+
+        // rebuild the price caplet by caplet
+        let yc = market_data.yield_curve("OPT", Date::from_ymd(2019, 01, 04)).unwrap();
+        let forecast = market_data.yield_curve("LSE", Date::from_ymd(2019, 01, 04)).unwrap();
+        let cube = market_data.vol_cube("GBPLIBOR6M.CAPLET", val_date.date()).unwrap();
+        let black76 = Black76::new().unwrap();
+        let mut expected = 0.0;
+        for period in cap.floating_leg().periods().iter() {
+            let accrual = period.accrual();
+            let year_fraction = DayCount::Act360.year_fraction(accrual.start(), accrual.end());
+            let forward = cap.floating_leg().rate(period, &*forecast).unwrap();
+            let fixing = period.fixing_date().date();
+            let vol = cube.volatility(fixing, 0.5, forward, 0.085).unwrap();
+            let t = (fixing - val_date.date()) as f64 / 365.0;
+            let df = yc.df(accrual.payment(), Date::from_ymd(2017, 01, 04)).unwrap();
+            if t > 0.0 {
+                expected += black76.call_price(df * year_fraction, forward, 0.085, vol * t.sqrt());
+            } else {
+                expected += df * year_fraction * (forward - 0.085).max(0.0);
+            }
+        }
+        assert_approx(price, 1000000.0 * expected, 1e-6);
+        assert!(price > 0.0);
+    }
+
+    #[test]
+    fn cap_floor_parity() {
+        // a cap minus a floor is a payer swap with the same schedule
+        let market_data = sample_cap_market_data();
+        let val_date = sample_val_date();
+        let strike = 0.085;
+        let cap = sample_cap(CapOrFloor::Cap, strike).price(&market_data, val_date).unwrap();
+        let floor = sample_cap(CapOrFloor::Floor, strike).price(&market_data, val_date).unwrap();
+
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar);
+        let (start, end) = sample_dates();
+        let fixed = FixedLeg::new(start, end, 6, &adjustment, DayCount::Act360, strike).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let swap = InterestRateSwap::new("Swap", "OPT", currency, 1000000.0,
+            PayOrReceive::Pay, fixed, sample_leg()).unwrap();
+        let swap_price = swap.price(&market_data, val_date).unwrap();
+        assert_approx(cap - floor, swap_price, 1e-6);
+    }
+
+    #[test]
+    fn seasoned_cap_ages_under_bump_time() {
+        let market_data = sample_cap_market_data();
+        let spot_date = market_data.spot_date();
+        let cap = RcInstrument::new(Qrc::new(Arc::new(sample_cap(CapOrFloor::Cap, 0.085))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&cap);
+        assert_eq!(dependencies.fixings("GBPLIBOR6M").len(), 4);
+        assert_eq!(dependencies.rate_fixings().get("GBPLIBOR6M").unwrap().len(), 4);
+
+        // the first caplet fixes at the close today, so bumping to tomorrow
+        // fixes it at its forward rate
+        let mut instruments = vec![(1.0, cap.clone())];
+        let bump = BumpTime::new(spot_date + 1, spot_date, SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        let aged = instruments[0].1.clone();
+        assert_eq!(aged.type_id(), "CapFloor");
+
+        let mut aged_dependencies = DependencyCollector::new(spot_date + 1);
+        aged_dependencies.spot(&aged);
+        assert_eq!(aged_dependencies.fixings("GBPLIBOR6M").len(), 3);
+
+        // the first caplet fixes today, so it already had no time value, and
+        // fixing it at its forward does not change the price
+        let val_date = sample_val_date();
+        let price = cap.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        let aged_price = aged.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(aged_price, price, 1e-6);
+    }
+
+    #[test]
+    fn cap_vega_from_caplet_vols() {
+        let market_data = sample_cap_market_data();
+        let cap = RcInstrument::new(Qrc::new(Arc::new(sample_cap(CapOrFloor::Floor, 0.08))));
+        let mut pricer = SelfPricer::new(vec![(1.0, cap)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        let generator = VegaVolgaReportGenerator::new(BumpVol::new_flat_additive(0.01));
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaVolgaReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        assert!(results.get("GBPLIBOR6M.CAPLET").unwrap().vega() > 0.0);
+    }
+
+    #[test]
+    fn cap_tagged_serde() {
+        let market_data = sample_cap_market_data();
+        let cap = sample_cap(CapOrFloor::Cap, 0.09);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(cap.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = cap.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,304 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::Exercisable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::options::SpotStartingEuropean;
+use instruments::options::PutOrCall;
+use instruments::options::OptionSettlement;
+use instruments::compound::expectation_at_expiry;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A chooser option lets the holder elect, on the choice date, whether it
+/// is a call or a put. After the choice date, it is whichever European the
+/// holder chose. In a simple chooser, the call and put have the same strike
+/// and expiry. In a complex chooser they may differ, though they must be on
+/// the same underlying.
+///
+/// The holder chooses the more valuable option, so the chooser is valued by
+/// integrating the greater of the call and put values over the level of
+/// the underlying at the choice date. The choice date is registered as a
+/// decision date, so that as time moves past it, the election is made and
+/// the chooser ages into the chosen vanilla.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChooserOption {
+    id: String,
+    call: SpotStartingEuropean,
+    put: SpotStartingEuropean,
+    choice_date: DateTime,
+
+    // fields precomputed for performance and simplicity
+    choice_time: DateDayFraction
+}
+
+impl TypeId for ChooserOption {
+    fn type_id(&self) -> &'static str { "ChooserOption" }
+}
+
+impl ChooserOption {
+    /// Creates a simple chooser, where the call and put share a strike,
+    /// expiry and settlement
+    pub fn new_simple(id: &str, credit_id: &str, underlying: RcInstrument,
+        settlement: RcDateRule, choice_date: DateTime, expiry: DateTime,
+        strike: f64, cash_or_physical: OptionSettlement)
+        -> Result<ChooserOption, qm::Error> {
+
+        let call = SpotStartingEuropean::new(&format!("{}:call", id), credit_id,
+            underlying.clone(), settlement.clone(), expiry, strike,
+            PutOrCall::Call, cash_or_physical)?;
+        let put = SpotStartingEuropean::new(&format!("{}:put", id), credit_id,
+            underlying, settlement, expiry, strike, PutOrCall::Put,
+            cash_or_physical)?;
+        ChooserOption::new_complex(id, call, put, choice_date)
+    }
+
+    /// Creates a complex chooser from a call and a put on the same
+    /// underlying, both of which must expire after the choice date
+    pub fn new_complex(id: &str, call: SpotStartingEuropean,
+        put: SpotStartingEuropean, choice_date: DateTime)
+        -> Result<ChooserOption, qm::Error> {
+
+        if call.underlying().id() != put.underlying().id() {
+            return Err(qm::Error::new("The call and put of a chooser must have \
+                the same underlying"))
+        }
+        if choice_date >= Exercisable::expiry(&call)
+            || choice_date >= Exercisable::expiry(&put) {
+            return Err(qm::Error::new("The choice date of a chooser must be \
+                before the expiry of both options"))
+        }
+        if call.put_or_call() != PutOrCall::Call || put.put_or_call() != PutOrCall::Put {
+            return Err(qm::Error::new("A chooser must choose between a call and a put"))
+        }
+
+        let choice_time = call.underlying().time_to_day_fraction(choice_date)?;
+        Ok(ChooserOption {
+            id: id.to_string(),
+            call: call,
+            put: put,
+            choice_date: choice_date,
+            choice_time: choice_time })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ChooserOption::deserialize(de)?)))
+    }
+
+    pub fn call(&self) -> &SpotStartingEuropean { &self.call }
+    pub fn put(&self) -> &SpotStartingEuropean { &self.put }
+    pub fn choice_date(&self) -> DateTime { self.choice_date }
+
+    /// The later of the expiries of the call and put
+    fn last_expiry(&self) -> DateTime {
+        Exercisable::expiry(&self.call).max(Exercisable::expiry(&self.put))
+    }
+}
+
+impl InstanceId for ChooserOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for ChooserOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.call.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.call.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.call.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        if self.choice_date.date() >= context.spot_date() {
+            context.exercise(&self.id, self.choice_date);
+        }
+        self.put.dependencies(context);
+        self.call.dependencies(context)
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// On the choice date, the holder elects whichever of the call and put
+    /// is worth more
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if date != self.choice_date {
+            return Ok(None)
+        }
+
+        let call = self.call.price(context, date)?;
+        let put = self.put.price(context, date)?;
+        let chosen = if call >= put { &self.call } else { &self.put };
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(chosen.clone()))))]))
+    }
+}
+
+impl Priceable for ChooserOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        // The call and put price themselves discounted to the settlement date
+        // of the choice date, so we discount from there on the call's curve
+        let underlying = self.call.underlying();
+        let choice_settlement = self.settlement().apply(self.choice_date.date());
+        let yc = context.yield_curve(underlying.credit_id(), choice_settlement)?;
+        let df_from_base = (-yc.rt(choice_settlement)?).exp();
+        let high_water_mark = self.last_expiry().date();
+
+        // use the vol at the strike of whichever option is nearer the money
+        let forward = context.forward_curve(&**underlying, high_water_mark)?
+            .forward(self.choice_date.date())?;
+        let strike = if (self.call.strike() - forward).abs()
+            < (self.put.strike() - forward).abs() {
+            self.call.strike() } else { self.put.strike() };
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.choice_date {
+                let expected = expectation_at_expiry(context, underlying, *date,
+                    self.choice_date, self.choice_time, high_water_mark, strike,
+                    &|conditional| {
+                        let call = self.call.price(conditional, self.choice_date)?;
+                        let put = self.put.price(conditional, self.choice_date)?;
+                        Ok(call.max(put))
+                    })?;
+                let settlement_date = self.settlement().apply(date.date());
+                df_from_base * yc.rt(settlement_date)?.exp() * expected
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::Date;
+    use dates::datetime::TimeOfDay;
+
+    fn sample_european(strike: f64, put_or_call: PutOrCall) -> SpotStartingEuropean {
+        SpotStartingEuropean::new("SampleEuropean", "OPT", sample_underlying(),
+            sample_settlement(2), sample_expiry(), strike, put_or_call,
+            OptionSettlement::Cash).unwrap()
+    }
+
+    fn sample_chooser(choice_date: Date) -> ChooserOption {
+        ChooserOption::new_simple("SampleChooser", "OPT", sample_underlying(),
+            sample_settlement(2), DateTime::new(choice_date, TimeOfDay::Close),
+            sample_expiry(), 100.0, OptionSettlement::Cash).unwrap()
+    }
+
+    #[test]
+    fn simple_chooser_is_between_vanillas_and_straddle() {
+        let market_data = sample_market_data();
+        let call = sample_european(100.0, PutOrCall::Call)
+            .price(&market_data, sample_val_date()).unwrap();
+        let put = sample_european(100.0, PutOrCall::Put)
+            .price(&market_data, sample_val_date()).unwrap();
+        let chooser = sample_chooser(Date::from_ymd(2017, 06, 01))
+            .price(&market_data, sample_val_date()).unwrap();
+        assert!(chooser > call.max(put) && chooser < call + put,
+            "chooser={} call={} put={}", chooser, call, put);
+    }
+
+    #[test]
+    fn simple_chooser_limits() {
+        let market_data = sample_market_data();
+        let call = sample_european(100.0, PutOrCall::Call)
+            .price(&market_data, sample_val_date()).unwrap();
+        let put = sample_european(100.0, PutOrCall::Put)
+            .price(&market_data, sample_val_date()).unwrap();
+
+        // choosing at the close today gives almost the better of the two
+        let today = sample_chooser(Date::from_ymd(2017, 01, 02))
+            .price(&market_data, sample_val_date()).unwrap();
+        assert_approx(today, call.max(put), 1e-4);
+
+        // choosing just before expiry is almost a straddle
+        let late = sample_chooser(Date::from_ymd(2018, 05, 31))
+            .price(&market_data, sample_val_date()).unwrap();
+        assert_approx(late, call + put, 0.05);
+    }
+
+    #[test]
+    fn complex_chooser_validation() {
+        let choice_date = DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close);
+        let call = sample_european(110.0, PutOrCall::Call);
+        let put = sample_european(90.0, PutOrCall::Put);
+        assert!(ChooserOption::new_complex("SampleChooser", call.clone(),
+            put.clone(), choice_date).is_ok());
+        assert!(ChooserOption::new_complex("SampleChooser", call.clone(),
+            call.clone(), choice_date).is_err());
+        assert!(ChooserOption::new_complex("SampleChooser", call, put,
+            sample_expiry()).is_err());
+    }
+
+    #[test]
+    fn chooser_ages_into_chosen_vanilla() {
+        let market_data = sample_market_data();
+
+        // the forward is above 90, so the holder chooses the call
+        let call = sample_european(90.0, PutOrCall::Call);
+        let put = sample_european(90.0, PutOrCall::Put);
+        let choice_date = DateTime::new(Date::from_ymd(2017, 01, 03), TimeOfDay::Close);
+        let chooser = RcInstrument::new(Qrc::new(Arc::new(ChooserOption::new_complex(
+            "SampleChooser", call, put, choice_date).unwrap())));
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        dependencies.spot(&chooser);
+
+        let mut instruments = vec![(1.0, chooser)];
+        let new_date = Date::from_ymd(2017, 01, 05);
+        let bump = BumpTime::new(new_date, new_date, SpotDynamics::StickySpot);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].1.type_id(), "SpotStartingEuropean");
+        let chosen = instruments[0].1.as_exercisable().unwrap();
+        assert_eq!(chosen.exercise_value(100.0), 10.0);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,469 @@
+use std::sync::Arc;
+use std::slice;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::PathDependent;
+use instruments::PathStatus;
+use instruments::mc_price_path_dependent;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A cliquet (or ratchet) is a series of forward-starting performance
+/// periods, defined by a schedule of reset dates. The return over each
+/// period, S(i) / S(i-1) - 1, is clamped between a local floor and an
+/// optional local cap. The clamped returns are summed, and the sum is then
+/// floored by the global floor and optionally capped by a global cap. The
+/// cliquet pays the notional times the result, in cash, at the settlement
+/// date of the last reset.
+///
+/// As reset dates are fixed, they are removed from the schedule and the
+/// clamped returns accumulated, along with the last fixing, which is the
+/// start level of the next period.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Cliquet {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    notional: f64,
+    resets: Vec<DateTime>,
+    local_floor: f64,
+    local_cap: Option<f64>,
+    global_floor: f64,
+    global_cap: Option<f64>,
+    last_fixing: Option<f64>,
+    accrued_return: f64,
+    expiry: DateTime,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date,
+}
+
+impl TypeId for Cliquet {
+    fn type_id(&self) -> &'static str { "Cliquet" }
+}
+
+impl Cliquet {
+    /// Creates a cliquet. The resets are the unfixed reset dates, in strictly
+    /// increasing order, the last of which is the expiry. For a new cliquet,
+    /// the first reset is the start of the first period, and last_fixing is
+    /// None. For a seasoned cliquet, pass in the last fixing and the sum of
+    /// clamped returns so far.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        notional: f64,
+        resets: &[DateTime],
+        local_floor: f64,
+        local_cap: Option<f64>,
+        global_floor: f64,
+        global_cap: Option<f64>,
+        last_fixing: Option<f64>,
+        accrued_return: f64)
+        -> Result<Cliquet, qm::Error> {
+
+        let min_resets = if last_fixing.is_some() { 1 } else { 2 };
+        if resets.len() < min_resets {
+            return Err(qm::Error::new("Cliquet must have at least one \
+                unfixed performance period"))
+        }
+        for pair in resets.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Cliquet reset dates must be in \
+                    strictly increasing order"))
+            }
+        }
+        if let Some(cap) = local_cap {
+            if cap < local_floor {
+                return Err(qm::Error::new("Cliquet local cap must not be \
+                    below the local floor"))
+            }
+        }
+        if let Some(cap) = global_cap {
+            if cap < global_floor {
+                return Err(qm::Error::new("Cliquet global cap must not be \
+                    below the global floor"))
+            }
+        }
+        if let Some(fixing) = last_fixing {
+            if fixing <= 0.0 {
+                return Err(qm::Error::new("Cliquet fixings must be positive"))
+            }
+        }
+
+        let expiry = *resets.last().unwrap();
+        let pay_date = settlement.apply(expiry.date());
+        Ok(Cliquet {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            notional: notional,
+            resets: resets.to_vec(),
+            local_floor: local_floor,
+            local_cap: local_cap,
+            global_floor: global_floor,
+            global_cap: global_cap,
+            last_fixing: last_fixing,
+            accrued_return: accrued_return,
+            expiry: expiry,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Cliquet::deserialize(de)?)))
+    }
+
+    /// The reset dates that have not yet been fixed
+    pub fn resets(&self) -> &[DateTime] {
+        &self.resets
+    }
+
+    /// The sum of the clamped returns of the periods fixed so far
+    pub fn accrued_return(&self) -> f64 {
+        self.accrued_return
+    }
+
+    /// The return over one period, clamped by the local floor and cap
+    fn period_return(&self, start: f64, end: f64) -> f64 {
+        let performance = (end / start - 1.0).max(self.local_floor);
+        match self.local_cap {
+            Some(cap) => performance.min(cap),
+            None => performance
+        }
+    }
+
+    /// The payoff given the sum of all the clamped returns
+    fn payoff(&self, total_return: f64) -> f64 {
+        let floored = total_return.max(self.global_floor);
+        let capped = match self.global_cap {
+            Some(cap) => floored.min(cap),
+            None => floored
+        };
+        self.notional * capped
+    }
+}
+
+impl InstanceId for Cliquet {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for Cliquet {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    /// Every reset is a fixing, so that time bumps across period
+    /// boundaries fix the cliquet correctly.
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let id = self.underlying.id();
+        for reset in self.resets.iter() {
+            context.fixing(id, *reset);
+        }
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        let expiry_date = self.expiry.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut last_fixing = self.last_fixing;
+        let mut accrued = self.accrued_return;
+        let mut fixed = 0;
+        for reset in self.resets.iter() {
+            if let Some(fixing) = fixing_table.get(id, *reset)? {
+                if fixing <= 0.0 {
+                    return Err(qm::Error::new("Cliquet fixings must be positive"))
+                }
+                if let Some(start) = last_fixing {
+                    accrued += self.period_return(start, fixing);
+                }
+                last_fixing = Some(fixing);
+                fixed += 1;
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        // if everything is fixed, the cliquet becomes a payment
+        if fixed == self.resets.len() {
+            let payment = self.payoff(accrued);
+            let mut decomp = Vec::new();
+            if payment != 0.0 {
+                decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:payment", self.id), &self.credit_id,
+                    RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                    self.expiry, self.pay_date, self.settlement.clone()))))));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let cliquet = Cliquet::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.notional,
+            &self.resets[fixed..], self.local_floor, self.local_cap,
+            self.global_floor, self.global_cap, last_fixing, accrued)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(cliquet))))]))
+    }
+}
+
+impl MonteCarloPriceable for Cliquet {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        for reset in self.resets.iter() {
+            let time = self.underlying.time_to_day_fraction(*reset)?;
+            output.observation(&self.underlying, time);
+        }
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+        mc_price_path_dependent(self, context)
+    }
+}
+
+impl PathDependent for Cliquet {
+    fn path_underlyings(&self) -> &[RcInstrument] {
+        slice::from_ref(&self.underlying)
+    }
+
+    fn path_flows(&self) -> usize { 1 }
+
+    /// The state is the start level of the current period (zero if there
+    /// is none yet) and the sum of clamped returns so far.
+    fn path_state_size(&self) -> usize { 2 }
+
+    fn path_initial_state(&self, state: &mut [f64]) {
+        state[0] = self.last_fixing.unwrap_or(0.0);
+        state[1] = self.accrued_return;
+    }
+
+    fn path_step(&self, step: usize, spots: &[f64], state: &mut [f64],
+        flows: &mut [f64]) -> Result<PathStatus, qm::Error> {
+
+        let spot = spots[0];
+        if state[0] > 0.0 {
+            state[1] += self.period_return(state[0], spot);
+        }
+        state[0] = spot;
+
+        if step + 1 == self.resets.len() {
+            flows[0] = self.payoff(state[1]);
+        }
+        Ok(PathStatus::Alive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use risk::Pricer;
+    use instruments::Priceable;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::Threading;
+
+    fn sample_resets() -> Vec<DateTime> {
+        // quarterly resets over a year from spot
+        [(2017, 01, 03), (2017, 04, 03), (2017, 07, 03), (2017, 10, 02), (2018, 01, 02)]
+            .iter().map(|&(y, m, d)| DateTime::new(Date::from_ymd(y, m, d),
+            TimeOfDay::Close)).collect()
+    }
+
+    fn sample_cliquet(resets: &[DateTime], local_floor: f64, local_cap: Option<f64>,
+        global_floor: f64, last_fixing: Option<f64>, accrued: f64) -> Cliquet {
+        let equity = sample_underlying();
+        Cliquet::new("SampleCliquet", "OPT", equity, sample_settlement(2), 1000.0,
+            resets, local_floor, local_cap, global_floor, None, last_fixing,
+            accrued).unwrap()
+    }
+
+    fn mc_price(cliquet: Cliquet) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(cliquet))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    fn discount_factor(ex_date: DateTime) -> f64 {
+        let market_data = sample_market_data();
+        let settlement = sample_settlement(2);
+        let pay_date = settlement.apply(ex_date.date());
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), ex_date, pay_date, settlement);
+        let val_date = sample_val_date();
+        bond.price(&market_data, val_date).unwrap()
+    }
+
+    #[test]
+    fn threaded_cliquet_matches_single_threaded() {
+
+        // the paths are stepped through in blocks on the threads, but each
+        // path is valued exactly as it would be on one thread
+        let market_data = sample_market_data();
+        let cliquet = sample_cliquet(&sample_resets(), -0.05, Some(0.08), 0.0, None, 0.0);
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(cliquet))))];
+        let mut prices = Vec::new();
+        for threads in [1, 3].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 5000)));
+            let pricer = MonteCarloPricer::with_settings(instruments.clone(),
+                model_factory, MonteCarloSettings {
+                    threading: Threading::new(*threads, Some(7)),
+                    ..MonteCarloSettings::default() }, &market_data).unwrap();
+            prices.push(pricer.price().unwrap());
+        }
+        assert_eq!(prices[0], prices[1]);
+    }
+
+    #[test]
+    fn fully_fixed_cliquet_pays_clamped_sum() {
+        let resets = sample_resets();
+        let cliquet = sample_cliquet(&resets, -0.05, Some(0.08), 0.0, None, 0.0);
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 01, 03),
+            &[("BP.L", &[(resets[0], 100.0), (resets[1], 110.0), (resets[2], 99.0),
+            (resets[3], 102.96), (resets[4], 100.0)])]).unwrap();
+        let fixed = cliquet.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+
+        // periods of +10%, -10%, +4% and about -2.9%, clamped to 8% and -5%
+        let expected = 1000.0 * (0.08 - 0.05 + 0.04 + (100.0 / 102.96 - 1.0));
+        assert_approx(fixed[0].0, expected, 1e-10);
+    }
+
+    #[test]
+    fn global_floor_applies_to_sum() {
+        let resets = sample_resets();
+        let cliquet = sample_cliquet(&resets[3..], -0.05, Some(0.08), 0.02,
+            Some(100.0), -0.1);
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 01, 03),
+            &[("BP.L", &[(resets[3], 104.0), (resets[4], 110.0)])]).unwrap();
+        let fixed = cliquet.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 20.0, 1e-10);
+    }
+
+    #[test]
+    fn flat_local_limits_price_as_bond() {
+
+        // with a zero local cap and floor, every period returns zero, so the
+        // cliquet pays the global floor on every path
+        let cliquet = sample_cliquet(&sample_resets(), 0.0, Some(0.0), 0.03, None, 0.0);
+        let price = mc_price(cliquet);
+        let expected = 30.0 * discount_factor(*sample_resets().last().unwrap());
+        assert_approx(price, expected, 1e-8);
+    }
+
+    #[test]
+    fn global_floor_adds_value() {
+        let floored = mc_price(sample_cliquet(&sample_resets(), -0.05, Some(0.05), 0.0, None, 0.0));
+        let unfloored = mc_price(sample_cliquet(&sample_resets(), -0.05, Some(0.05), -1.0, None, 0.0));
+        assert!(floored > 0.0, "floored={}", floored);
+        assert!(floored > unfloored, "floored={} unfloored={}", floored, unfloored);
+    }
+
+    #[test]
+    fn time_bump_across_period_boundary() {
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let cliquet = sample_cliquet(&sample_resets(), -0.05, Some(0.08), 0.0, None, 0.0);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(cliquet)));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&instrument);
+        assert_eq!(dependencies.fixings("BP.L").len(), 5);
+
+        let mut instruments = vec![(1.0, instrument)];
+        let time_bump = BumpTime::new(Date::from_ymd(2017, 05, 01), spot_date,
+            SpotDynamics::StickyForward);
+        let changed = time_bump.update_instruments(&mut instruments,
+            &market_data, &dependencies).unwrap();
+        assert!(changed);
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].1.type_id(), "Cliquet");
+
+        let mut rolled = DependencyCollector::new(Date::from_ymd(2017, 05, 01));
+        rolled.spot(&instruments[0].1);
+        assert_eq!(rolled.fixings("BP.L").len(), 3);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,669 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use data::fixings::FixingRange;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::calendar::RcCalendar;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The period over which a commodity is delivered, from the first to the
+/// last day inclusive. Power and gas are normally traded for delivery over
+/// calendar months or quarters, and longer periods can be represented as
+/// strips of months.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct DeliveryPeriod {
+    first: Date,
+    last: Date
+}
+
+impl DeliveryPeriod {
+    pub fn new(first: Date, last: Date) -> Result<DeliveryPeriod, qm::Error> {
+        if last < first {
+            return Err(qm::Error::new("Delivery period must not end before it starts"))
+        }
+        Ok(DeliveryPeriod { first: first, last: last })
+    }
+
+    /// Delivery over a calendar month, numbered from one
+    pub fn month(year: i32, month: i32) -> Result<DeliveryPeriod, qm::Error> {
+        if month < 1 || month > 12 {
+            return Err(qm::Error::new("Delivery month must be from 1 to 12"))
+        }
+        DeliveryPeriod::new(Date::from_ymd(year, month, 1),
+            first_of_next_month(year, month) - 1)
+    }
+
+    /// Delivery over a calendar quarter, numbered from one
+    pub fn quarter(year: i32, quarter: i32) -> Result<DeliveryPeriod, qm::Error> {
+        if quarter < 1 || quarter > 4 {
+            return Err(qm::Error::new("Delivery quarter must be from 1 to 4"))
+        }
+        let first_month = quarter * 3 - 2;
+        DeliveryPeriod::new(Date::from_ymd(year, first_month, 1),
+            first_of_next_month(year, first_month + 2) - 1)
+    }
+
+    pub fn first(&self) -> Date { self.first }
+    pub fn last(&self) -> Date { self.last }
+
+    /// Splits the period into the strip of calendar months it covers. The
+    /// first and last of these are truncated if the period starts or ends
+    /// part way through a month.
+    pub fn months(&self) -> Vec<DeliveryPeriod> {
+        let mut months = Vec::new();
+        let mut first = self.first;
+        while first <= self.last {
+            let (year, month, _) = first.ymd();
+            let next = first_of_next_month(year, month);
+            let last = if next - 1 < self.last { next - 1 } else { self.last };
+            months.push(DeliveryPeriod { first: first, last: last });
+            first = next;
+        }
+        months
+    }
+
+    /// The delivery days, which are the business days of the given calendar
+    fn delivery_days(&self, calendar: &RcCalendar) -> FixingRange {
+        FixingRange::new(self.first, self.last, TimeOfDay::Close, calendar.clone())
+    }
+}
+
+fn first_of_next_month(year: i32, month: i32) -> Date {
+    if month == 12 {
+        Date::from_ymd(year + 1, 1, 1)
+    } else {
+        Date::from_ymd(year, month + 1, 1)
+    }
+}
+
+/// A forward on a commodity for delivery over a period. The holder receives
+/// a fixed quantity on each delivery day, which is each business day of the
+/// period, and pays the strike for it. The forward is settled financially:
+/// the price of the commodity fixes on each delivery day, and the holder
+/// receives the quantity times the sum of the differences between the
+/// fixings and the strike, paid after the end of the period.
+///
+/// Before delivery, the forward is valued from the commodity curve, so it
+/// reflects the seasonal shape of prices within the period. Once delivery
+/// starts, the fixings so far are carried with the forward.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CommodityForward {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    calendar: RcCalendar,
+    period: DeliveryPeriod,
+    strike: f64,
+    quantity: f64,
+    first_unfixed: Date,
+    fixed_sum: f64,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date,
+}
+
+impl TypeId for CommodityForward {
+    fn type_id(&self) -> &'static str { "CommodityForward" }
+}
+
+impl CommodityForward {
+    /// Creates a forward with no fixed delivery days. The underlying must be
+    /// a commodity, and the quantity is per delivery day.
+    pub fn new(id: &str, credit_id: &str, underlying: RcInstrument,
+        settlement: RcDateRule, calendar: RcCalendar, period: DeliveryPeriod,
+        strike: f64, quantity: f64) -> Result<CommodityForward, qm::Error> {
+        CommodityForward::new_accrued(id, credit_id, underlying, settlement,
+            calendar, period, strike, quantity, period.first(), 0.0)
+    }
+
+    /// Creates a forward where the delivery days before the first unfixed
+    /// date have fixed, and their fixings add up to the fixed sum.
+    pub fn new_accrued(id: &str, credit_id: &str, underlying: RcInstrument,
+        settlement: RcDateRule, calendar: RcCalendar, period: DeliveryPeriod,
+        strike: f64, quantity: f64, first_unfixed: Date, fixed_sum: f64)
+        -> Result<CommodityForward, qm::Error> {
+
+        if !underlying.is_forward_quoted() {
+            return Err(qm::Error::new("The underlying of a commodity forward \
+                must be a commodity"))
+        }
+        if first_unfixed < period.first() || first_unfixed > period.last() + 1 {
+            return Err(qm::Error::new("Commodity forward first unfixed date \
+                must be within its delivery period"))
+        }
+        if period.delivery_days(&calendar).dates_between(period.first(),
+            period.last() + 1).is_empty() {
+            return Err(qm::Error::new("Commodity delivery period must contain \
+                at least one business day"))
+        }
+
+        let pay_date = settlement.apply(period.last());
+        Ok(CommodityForward {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            calendar: calendar,
+            period: period,
+            strike: strike,
+            quantity: quantity,
+            first_unfixed: first_unfixed,
+            fixed_sum: fixed_sum,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CommodityForward::deserialize(de)?)))
+    }
+
+    pub fn period(&self) -> DeliveryPeriod { self.period }
+    pub fn strike(&self) -> f64 { self.strike }
+    pub fn quantity(&self) -> f64 { self.quantity }
+
+    /// Splits the forward into a strip of forwards on each of the months of
+    /// its delivery period, with the same strike and daily quantity, and
+    /// with ids formed from this id and the start of each month. Fixings
+    /// so far are not carried over, so this may only be done before
+    /// delivery starts.
+    pub fn strip(&self) -> Result<Vec<CommodityForward>, qm::Error> {
+        if self.first_unfixed != self.period.first() {
+            return Err(qm::Error::new("Cannot split a commodity forward into \
+                months once delivery has started"))
+        }
+        self.period.months().iter().map(|month| CommodityForward::new(
+            &format!("{}:{}", self.id, month.first()), &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.calendar.clone(),
+            *month, self.strike, self.quantity)).collect()
+    }
+
+    /// The number of delivery days in the whole period
+    fn days(&self) -> usize {
+        self.period.delivery_days(&self.calendar)
+            .dates_between(self.period.first(), self.period.last() + 1).len()
+    }
+
+    /// The delivery days that have not yet fixed
+    fn unfixed(&self) -> Vec<DateTime> {
+        self.period.delivery_days(&self.calendar)
+            .dates_between(self.first_unfixed, self.period.last() + 1)
+    }
+
+    /// The expected average price over the delivery days, including any
+    /// that have already fixed
+    pub fn average_price(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        let forward = context.forward_curve(&*self.underlying, self.period.last())?;
+        let mut sum = self.fixed_sum;
+        for date in self.unfixed().iter() {
+            sum += forward.forward(date.date())?;
+        }
+        Ok(sum / self.days() as f64)
+    }
+
+    /// The undiscounted amount paid, given the average price
+    fn amount(&self, average_price: f64) -> f64 {
+        self.quantity * self.days() as f64 * (average_price - self.strike)
+    }
+}
+
+impl InstanceId for CommodityForward {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for CommodityForward {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        if self.first_unfixed <= self.period.last() {
+            context.fixing_range(self.underlying.id(), FixingRange::new(
+                self.first_unfixed, self.period.last(), TimeOfDay::Close,
+                self.calendar.clone()));
+        }
+        context.yield_curve(&self.credit_id, self.pay_date);
+        context.forward_curve(&self.underlying, self.period.last());
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// Adds any newly fixed delivery days to the fixed sum. Once the whole
+    /// period has fixed, the forward turns into a payment.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut fixed_sum = self.fixed_sum;
+        let mut first_unfixed = self.period.last() + 1;
+        let mut changed = false;
+        for date in self.unfixed().iter() {
+            if let Some(fixing) = fixing_table.get(id, *date)? {
+                fixed_sum += fixing;
+                changed = true;
+            } else {
+                first_unfixed = date.date();
+                break;
+            }
+        }
+
+        if !changed {
+            return Ok(None)
+        }
+
+        if first_unfixed > self.period.last() {
+            let average = fixed_sum / self.days() as f64;
+            let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+            let payment = ZeroCoupon::new(&format!("{}:payment", self.id),
+                &self.credit_id, currency,
+                DateTime::new(self.period.last(), TimeOfDay::Close),
+                self.pay_date, self.settlement.clone());
+            return Ok(Some(vec![(self.amount(average),
+                RcInstrument::new(Qrc::new(Arc::new(payment))))]))
+        }
+
+        let accrued = CommodityForward::new_accrued(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.calendar.clone(),
+            self.period, self.strike, self.quantity, first_unfixed, fixed_sum)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(accrued))))]))
+    }
+}
+
+impl Priceable for CommodityForward {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The forward is worth the discounted difference between the expected
+    /// average price over the delivery days and the strike.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let amount = self.amount(self.average_price(context)?);
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let settlement_date = self.settlement.apply(date.date());
+            *output = amount * df_from_base * yc.rt(settlement_date)?.exp();
+        }
+
+        Ok(())
+    }
+}
+
+/// A European option on a commodity forward. At expiry, which must be
+/// before delivery starts, the holder of a call may choose to enter into
+/// the forward at the strike, and the holder of a put may choose to enter
+/// into it as the seller.
+///
+/// The option is valued with the Black76 formula on the expected average
+/// price over the delivery days, using the vol of the commodity at the
+/// expiry of the option and the strike.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CommodityOption {
+    id: String,
+    forward: CommodityForward,
+    expiry: DateTime,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+}
+
+impl TypeId for CommodityOption {
+    fn type_id(&self) -> &'static str { "CommodityOption" }
+}
+
+impl CommodityOption {
+    /// Creates an option to enter into the given forward, whose strike is
+    /// the strike of the option.
+    pub fn new(id: &str, forward: CommodityForward, expiry: DateTime,
+        put_or_call: PutOrCall) -> Result<CommodityOption, qm::Error> {
+
+        if expiry.date() >= forward.period().first() {
+            return Err(qm::Error::new("Commodity option must expire before \
+                delivery starts"))
+        }
+        let expiry_time = forward.underlying.time_to_day_fraction(expiry)?;
+        Ok(CommodityOption { id: id.to_string(), forward: forward,
+            expiry: expiry, put_or_call: put_or_call, expiry_time: expiry_time })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CommodityOption::deserialize(de)?)))
+    }
+
+    pub fn forward(&self) -> &CommodityForward { &self.forward }
+    pub fn expiry(&self) -> DateTime { self.expiry }
+}
+
+impl InstanceId for CommodityOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for CommodityOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.forward.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.forward.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.forward.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        self.forward.dependencies(context);
+        context.vol_surface(&self.forward.underlying, self.expiry.date());
+        if self.expiry.date() >= context.spot_date() {
+            context.exercise(&self.id, self.expiry);
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// At expiry, the option is exercised into the forward if it is in the
+    /// money, and otherwise lapses
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if date != self.expiry {
+            return Ok(None)
+        }
+
+        let value = self.forward.price(context, date)?;
+        let forward = RcInstrument::new(Qrc::new(Arc::new(self.forward.clone())));
+        Ok(Some(match self.put_or_call {
+            PutOrCall::Call if value > 0.0 => vec![(1.0, forward)],
+            PutOrCall::Put if value < 0.0 => vec![(-1.0, forward)],
+            _ => Vec::new()
+        }))
+    }
+}
+
+impl Priceable for CommodityOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let forward = &self.forward;
+        let underlying = &*forward.underlying;
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&forward.credit_id, forward.pay_date)?;
+        let vol = context.vol_surface(underlying, expiry_date,
+            &|| context.forward_curve(underlying, forward.period.last()))?;
+        let average = forward.average_price(context)?;
+        let df_from_base = (-yc.rt(forward.pay_date)?).exp();
+        let scale = forward.quantity * forward.days() as f64;
+
+        let displacement = vol.displacement(expiry_date)?;
+        let k = forward.strike - displacement;
+        let f = average - displacement;
+        if f < 0.0 {
+            return Err(qm::Error::new("Negative forward"));
+        }
+
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = forward.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                let val_date = underlying.time_to_day_fraction(*date)?;
+                let variance = vol.forward_variance(val_date, self.expiry_time,
+                    forward.strike)?;
+                if variance < 0.0 {
+                    return Err(qm::Error::new("Negative variance"));
+                }
+                scale * match self.put_or_call {
+                    PutOrCall::Put => black76.put_price(df, f, k, variance.sqrt()),
+                    PutOrCall::Call => black76.call_price(df, f, k, variance.sqrt())
+                }
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use dates::calendar::WeekdayCalendar;
+    use data::commodity::RcCommodityCurve;
+    use data::commodity::tests::sample_commodity_curve;
+    use data::bumpspotdate::SpotDynamics;
+    use instruments::assets::Commodity;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::create_sample_flat_vol;
+    use risk::marketdata::tests::create_sample_displaced_vol;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+
+    fn sample_commodity_market_data() -> MarketData {
+        let mut market_data = sample_market_data();
+        market_data.add_commodity_curve("NBP",
+            RcCommodityCurve::new(Arc::new(sample_commodity_curve())));
+        market_data.add_vol_surface("NBP", create_sample_flat_vol());
+        market_data
+    }
+
+    fn sample_commodity() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(Commodity::new("NBP", "OPT",
+            currency, sample_settlement(2)))))
+    }
+
+    fn sample_forward(period: DeliveryPeriod, strike: f64) -> CommodityForward {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        CommodityForward::new("SampleForward", "OPT", sample_commodity(),
+            sample_settlement(2), calendar, period, strike, 10.0).unwrap()
+    }
+
+    fn sample_option(strike: f64, put_or_call: PutOrCall) -> CommodityOption {
+        let forward = sample_forward(DeliveryPeriod::quarter(2017, 4).unwrap(), strike);
+        let expiry = DateTime::new(Date::from_ymd(2017, 09, 26), TimeOfDay::Close);
+        CommodityOption::new("SampleOption", forward, expiry, put_or_call).unwrap()
+    }
+
+    #[test]
+    fn delivery_periods() {
+        let q1 = DeliveryPeriod::quarter(2017, 1).unwrap();
+        assert_eq!(q1.first(), Date::from_ymd(2017, 01, 01));
+        assert_eq!(q1.last(), Date::from_ymd(2017, 03, 31));
+        let months = q1.months();
+        assert_eq!(months.len(), 3);
+        assert_eq!(months[1], DeliveryPeriod::month(2017, 2).unwrap());
+        assert_eq!(DeliveryPeriod::month(2017, 12).unwrap().last(),
+            Date::from_ymd(2017, 12, 31));
+        assert!(DeliveryPeriod::quarter(2017, 5).is_err());
+    }
+
+    #[test]
+    fn commodity_forward_at_average_price_is_worthless() {
+        let market_data = sample_commodity_market_data();
+        let forward = sample_forward(DeliveryPeriod::quarter(2017, 4).unwrap(), 0.0);
+
+        // Q4 is quoted as 40 in October, and November and December are
+        // interpolated seasonally towards January, so the average is higher
+        let average = forward.average_price(&market_data).unwrap();
+        assert!(average > 40.0 && average < 54.0, "average={}", average);
+
+        let at_market = sample_forward(forward.period(), average);
+        assert_approx(at_market.price(&market_data, sample_val_date()).unwrap(),
+            0.0, 1e-9);
+    }
+
+    #[test]
+    fn commodity_quarterly_forward_is_strip_of_months() {
+        let market_data = sample_commodity_market_data();
+        let forward = sample_forward(DeliveryPeriod::quarter(2017, 2).unwrap(), 38.0);
+        let price = forward.price(&market_data, sample_val_date()).unwrap();
+        let strip = forward.strip().unwrap();
+        assert_eq!(strip.len(), 3);
+
+        // the months are priced separately, but pay at their own month end
+        // so we compare undiscounted prices
+        let undiscounted = |f: &CommodityForward| f.amount(
+            f.average_price(&market_data).unwrap());
+        let total: f64 = strip.iter().map(|f| undiscounted(f)).sum();
+        assert_approx(total, undiscounted(&forward), 1e-9);
+        assert!(price < 0.0, "price={}", price);
+    }
+
+    #[test]
+    fn commodity_forward_fixes_daily() {
+        let forward = sample_forward(DeliveryPeriod::month(2017, 2).unwrap(), 45.0);
+
+        // February 2017 has 20 weekdays. Fix the first ten at 50.
+        let mut fixings = Vec::new();
+        let mut date = Date::from_ymd(2017, 02, 01);
+        while fixings.len() < 10 {
+            if date.day_of_week() < 5 {
+                fixings.push((DateTime::new(date, TimeOfDay::Close), 50.0));
+            }
+            date += 1;
+        }
+        let fixing_table = FixingTable::from_fixings(date,
+            &[("NBP", &fixings[..])]).unwrap();
+        let fixed = forward.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "CommodityForward");
+
+        // then the rest at 40, so the average is exactly the strike
+        let mut date = Date::from_ymd(2017, 02, 01);
+        fixings.clear();
+        while date <= Date::from_ymd(2017, 02, 28) {
+            if date.day_of_week() < 5 {
+                let level = if fixings.len() < 10 { 50.0 } else { 40.0 };
+                fixings.push((DateTime::new(date, TimeOfDay::Close), level));
+            }
+            date += 1;
+        }
+        let fixing_table = FixingTable::from_fixings(date,
+            &[("NBP", &fixings[..])]).unwrap();
+        let fixed = forward.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+        assert_approx(fixed[0].0, 0.0, 1e-9);
+    }
+
+    #[test]
+    fn commodity_option_put_call_parity() {
+        let market_data = sample_commodity_market_data();
+        let call = sample_option(45.0, PutOrCall::Call);
+        let put = sample_option(45.0, PutOrCall::Put);
+        let call_price = call.price(&market_data, sample_val_date()).unwrap();
+        let put_price = put.price(&market_data, sample_val_date()).unwrap();
+        let forward_price = call
+            .forward().price(&market_data, sample_val_date()).unwrap();
+        assert!(call_price > 0.0 && put_price > 0.0);
+        assert_approx(call_price - put_price, forward_price, 1e-8);
+    }
+
+    #[test]
+    fn commodity_option_put_call_parity_with_displaced_vol() {
+        let mut market_data = sample_commodity_market_data();
+        market_data.add_vol_surface("NBP", create_sample_displaced_vol(10.0));
+        let call = sample_option(45.0, PutOrCall::Call);
+        let put = sample_option(45.0, PutOrCall::Put);
+        let call_price = call.price(&market_data, sample_val_date()).unwrap();
+        let put_price = put.price(&market_data, sample_val_date()).unwrap();
+        let forward_price = call
+            .forward().price(&market_data, sample_val_date()).unwrap();
+        assert_approx(call_price - put_price, forward_price, 1e-8);
+    }
+
+    #[test]
+    fn commodity_option_exercises_into_forward() {
+        let market_data = sample_commodity_market_data();
+        for &(strike, put_or_call, expected) in [
+            (30.0, PutOrCall::Call, Some(1.0)),
+            (60.0, PutOrCall::Call, None),
+            (60.0, PutOrCall::Put, Some(-1.0))].iter() {
+
+            let option = RcInstrument::new(Qrc::new(Arc::new(
+                sample_option(strike, put_or_call))));
+            let mut dependencies = DependencyCollector::new(market_data.spot_date());
+            dependencies.spot(&option);
+
+            let mut instruments = vec![(1.0, option)];
+            let new_date = Date::from_ymd(2017, 09, 28);
+            let bump = BumpTime::new(new_date, new_date, SpotDynamics::StickySpot);
+            let changed = bump.update_instruments(&mut instruments,
+                &market_data, &dependencies).unwrap();
+            assert!(changed);
+            match expected {
+                Some(weight) => {
+                    assert_eq!(instruments.len(), 1);
+                    assert_eq!(instruments[0].0, weight);
+                    assert_eq!(instruments[0].1.type_id(), "CommodityForward");
+                },
+                None => assert!(instruments.is_empty())
+            }
+        }
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,388 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A composite (compo) European option. The payoff is on a foreign
+/// underlying converted into the payment currency at the FX rate prevailing
+/// at expiry, and the strike is in the payment currency. For example, a
+/// compo call on a UK equity paying in dollars pays max(S * X - K, 0)
+/// dollars, where X is the GBPUSD rate at expiry.
+///
+/// Unlike a quanto, the holder takes the FX risk. The option is valued with
+/// Black76 on the converted underlying, whose forward is the product of the
+/// asset and FX forwards, and whose variance combines the asset vol, the FX
+/// vol and their correlation. The FX forward is implied by covered interest
+/// parity from the discount curve of the payment currency (the credit id of
+/// the option) and that of the underlying (its own credit id).
+///
+/// The FX rate is identified by an id such as "GBPUSD", quoted as units of
+/// the payment currency per unit of the underlying's currency, and is fixed
+/// at expiry alongside the underlying. Any displacement of the asset vol
+/// surface from cash dividends is ignored.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CompoOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    currency: RcCurrency,
+    fx_id: String,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for CompoOption {
+    fn type_id(&self) -> &'static str { "CompoOption" }
+}
+
+impl CompoOption {
+    /// Creates a compo option. The strike is in the payment currency, given
+    /// by the currency argument, and the id of the FX rate converts from the
+    /// underlying's currency into the payment currency.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        currency: RcCurrency,
+        fx_id: &str,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall) -> Result<CompoOption, qm::Error> {
+
+        if strike <= 0.0 {
+            return Err(qm::Error::new("Compo strike must be positive"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = underlying.time_to_day_fraction(expiry)?;
+        Ok(CompoOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            currency: currency,
+            fx_id: fx_id.to_string(),
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CompoOption::deserialize(de)?)))
+    }
+
+    pub fn fx_id(&self) -> &str {
+        &self.fx_id
+    }
+
+    fn payoff(&self, spot: f64, fx: f64) -> f64 {
+        let converted = spot * fx;
+        match self.put_or_call {
+            PutOrCall::Call => (converted - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - converted).max(0.0)
+        }
+    }
+}
+
+impl InstanceId for CompoOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for CompoOption {
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // both the underlying and the FX rate fix at expiry
+        context.fixing(self.underlying.id(), self.expiry);
+        context.fixing(&self.fx_id, self.expiry);
+
+        // the discount curves of both currencies define the FX forward
+        let expiry_date = self.expiry.date();
+        context.yield_curve(&self.credit_id, self.pay_date);
+        context.yield_curve(self.underlying.credit_id(), expiry_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+        context.fx_rate(&self.fx_id, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// Once both the underlying and the FX rate have fixed at expiry, the
+    /// option turns into a cash payment in the payment currency.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let spot = fixing_table.get(self.underlying.id(), self.expiry)?;
+        let fx = fixing_table.get(&self.fx_id, self.expiry)?;
+        if let (Some(spot), Some(fx)) = (spot, fx) {
+            let mut decomp = Vec::new();
+            let payment = self.payoff(spot, fx);
+            if payment > 0.0 {
+                decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:payment", self.id), &self.credit_id,
+                    self.currency.clone(), self.expiry, self.pay_date,
+                    self.settlement.clone()))))));
+            }
+            Ok(Some(decomp))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Priceable for CompoOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the compo using Black76 on the converted underlying.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let foreign_yc = context.yield_curve(self.underlying.credit_id(),
+            expiry_date)?;
+        let forward_curve = context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(forward_curve.clone()))?;
+        let fx_vol = context.fx_vol_surface(&self.fx_id, expiry_date)?;
+        let fx_spot = context.fx_spot(&self.fx_id)?;
+        let correlation = context.correlation_by_id(self.underlying.id(),
+            &self.fx_id)?;
+
+        // the forward of the converted underlying, using covered interest
+        // parity for the FX forward
+        let forward = forward_curve.forward(expiry_date)?;
+        let fx_forward = fx_spot
+            * (yc.rt(expiry_date)? - foreign_yc.rt(expiry_date)?).exp();
+        let converted_forward = forward * fx_forward;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+
+        // read the asset vol at the strike in the asset's currency, and the
+        // FX vol at the money
+        let asset_strike = self.strike / fx_forward;
+
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                let val_date = self.underlying.time_to_day_fraction(*date)?;
+                let asset_var = vol.forward_variance(val_date,
+                    self.expiry_time, asset_strike)?;
+                let fx_var = fx_vol.forward_variance(val_date,
+                    self.expiry_time, fx_forward)?;
+                let variance = combined_variance(asset_var, fx_var, correlation);
+                let sqrt_var = variance.sqrt();
+                match self.put_or_call {
+                    PutOrCall::Call => black76.call_price(df,
+                        converted_forward, self.strike, sqrt_var),
+                    PutOrCall::Put => black76.put_price(df,
+                        converted_forward, self.strike, sqrt_var)
+                }
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// The variance of the product of an asset and an FX rate, given the
+/// variance of each and their correlation
+pub fn combined_variance(asset_variance: f64, fx_variance: f64,
+    correlation: f64) -> f64 {
+    let asset_var = asset_variance.max(0.0);
+    let fx_var = fx_variance.max(0.0);
+    asset_var + fx_var + 2.0 * correlation * (asset_var * fx_var).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::dependencies::DependencyCollector;
+    use data::volsurface::FlatVolSurface;
+    use data::volsurface::RcVolSurface;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use serde_json;
+
+    fn sample_compo(strike: f64, put_or_call: PutOrCall) -> CompoOption {
+        let equity = sample_underlying();
+        let usd = RcCurrency::new(Arc::new(Currency::new("USD",
+            sample_settlement(2))));
+        CompoOption::new("SampleCompo", "OPT", equity, sample_settlement(2),
+            usd, "GBPUSD", sample_expiry(), strike, put_or_call).unwrap()
+    }
+
+    fn sample_compo_market_data(fx_vol: f64, correlation: f64) -> MarketData {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(Date::from_ymd(2016, 12, 30), 0.2);
+        let mut market_data = sample_market_data();
+        market_data.add_fx_vol_surface("GBPUSD", RcVolSurface::new(Arc::new(
+            FlatVolSurface::new(fx_vol, calendar, base))));
+        market_data.set_spot("GBPUSD", 1.25);
+        market_data.set_correlation("BP.L", "GBPUSD", correlation).unwrap();
+        market_data
+    }
+
+    #[test]
+    fn compo_with_no_fx_vol_is_scaled_european() {
+        // The sample market data discounts both currencies on the same
+        // curve, so the FX forward is flat. With no FX vol, the compo is
+        // just a european on fx_spot shares, with a scaled strike.
+        let market_data = sample_compo_market_data(0.0, 0.0);
+        let compo = sample_compo(125.0, PutOrCall::Call);
+        let price = compo.price(&market_data, sample_val_date()).unwrap();
+        assert_approx(price, 1.25 * 16.710717400832973, 1e-12);
+    }
+
+    #[test]
+    fn compo_put_call_parity() {
+        let market_data = sample_compo_market_data(0.1, -0.3);
+        let val_date = sample_val_date();
+        let call = sample_compo(125.0, PutOrCall::Call).price(
+            &market_data, val_date).unwrap();
+        let put = sample_compo(125.0, PutOrCall::Put).price(
+            &market_data, val_date).unwrap();
+        let no_fx_vol = sample_compo_market_data(0.0, 0.0);
+        let call_no_vol = sample_compo(125.0, PutOrCall::Call).price(
+            &no_fx_vol, val_date).unwrap();
+        let put_no_vol = sample_compo(125.0, PutOrCall::Put).price(
+            &no_fx_vol, val_date).unwrap();
+
+        // the forward does not depend on vols, so neither does call - put
+        assert_approx(call - put, call_no_vol - put_no_vol, 1e-10);
+    }
+
+    #[test]
+    fn compo_vol_combines_asset_and_fx() {
+        assert_approx(combined_variance(0.09, 0.01, 0.0), 0.1, 1e-14);
+        assert_approx(combined_variance(0.09, 0.01, 1.0), 0.16, 1e-14);
+        assert_approx(combined_variance(0.09, 0.01, -1.0), 0.04, 1e-14);
+
+        // positive correlation increases the combined vol, so the price
+        let val_date = sample_val_date();
+        let compo = sample_compo(125.0, PutOrCall::Call);
+        let negative = compo.price(&sample_compo_market_data(0.1, -0.5),
+            val_date).unwrap();
+        let positive = compo.price(&sample_compo_market_data(0.1, 0.5),
+            val_date).unwrap();
+        assert!(positive > negative + 1.0, "positive={} negative={}",
+            positive, negative);
+    }
+
+    #[test]
+    fn compo_dependencies_include_fx() {
+        let compo: RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            sample_compo(125.0, PutOrCall::Call))));
+        let mut collector = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        collector.spot(&compo);
+
+        let expiry = sample_expiry();
+        assert_eq!(collector.fixings("BP.L"), &[expiry]);
+        assert_eq!(collector.fixings("GBPUSD"), &[expiry]);
+        assert_eq!(collector.fx_rate_hwm("GBPUSD"), Some(expiry.date()));
+        let equity = collector.instrument_by_id("BP.L").unwrap().clone();
+        assert_eq!(collector.vol_surface_hwm(&equity), Some(expiry.date()));
+    }
+
+    #[test]
+    fn compo_fixes_with_both_fixings() {
+        let compo = sample_compo(125.0, PutOrCall::Call);
+        let expiry = sample_expiry();
+        let after = Date::from_ymd(2018, 06, 02);
+
+        // with only the equity fixing, a missing historical FX fixing is an error
+        let fixings = FixingTable::from_fixings(after,
+            &[("BP.L", &[(expiry, 110.0)])]).unwrap();
+        assert!(compo.fix(&fixings).is_err());
+
+        let fixings = FixingTable::from_fixings(after,
+            &[("BP.L", &[(expiry, 110.0)]), ("GBPUSD", &[(expiry, 1.2)])]).unwrap();
+        let decomp = compo.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 110.0 * 1.2 - 125.0, 1e-12);
+        assert_eq!(decomp[0].1.payoff_currency().id(), "USD");
+    }
+
+    #[test]
+    fn compo_tagged_serde() {
+        let compo = sample_compo(125.0, PutOrCall::Call);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(compo.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let market_data = sample_compo_market_data(0.1, -0.3);
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = compo.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
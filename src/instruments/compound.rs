@@ -0,0 +1,489 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::Exercisable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::ExchangeRate;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::SpotStartingEuropean;
+use instruments::options::PutOrCall;
+use math::interpolation::Interpolate;
+use data::curves::RcRateCurve;
+use data::forward::Forward;
+use data::volsurface::RcVolSurface;
+use data::volcube::RcVolCube;
+use data::inflation::RcInflationCurve;
+use data::commodity::RcCommodityCurve;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+/// Number of standard deviations either side of the forward over which the
+/// underlying at an expiry is integrated
+const COMPOUND_STDEVS: f64 = 8.0;
+
+/// Number of intervals in the integral over the underlying at an expiry
+const COMPOUND_STEPS: usize = 400;
+
+/// A compound option is an option on an option. At the outer expiry, the
+/// holder of a call may choose to buy the inner option for the outer strike,
+/// and the holder of a put may choose to sell it. The inner option is a
+/// spot-starting European, which then runs to its own expiry and strike, so
+/// there are four flavours such as call-on-call and put-on-call.
+///
+/// The option is valued by integrating over the value of the underlying at
+/// the outer expiry, which is taken to be log-normal with the vol to the
+/// outer expiry, allowing for displacement. At each level, the inner option
+/// prices itself in a context where the forward of the underlying starts
+/// from that level, so any European the repo can price can be used.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CompoundOption {
+    id: String,
+    credit_id: String,
+    inner: SpotStartingEuropean,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date
+}
+
+impl TypeId for CompoundOption {
+    fn type_id(&self) -> &'static str { "CompoundOption" }
+}
+
+impl CompoundOption {
+    /// Creates an option to buy or sell the inner option at the given
+    /// strike on the given expiry, which must be before that of the inner
+    /// option. The strike is paid on the settlement date of the expiry.
+    pub fn new(id: &str, credit_id: &str, inner: SpotStartingEuropean,
+        settlement: RcDateRule, expiry: DateTime, strike: f64,
+        put_or_call: PutOrCall) -> Result<CompoundOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+        if expiry >= Exercisable::expiry(&inner) {
+            return Err(qm::Error::new("Compound option must expire before its \
+                inner option"))
+        }
+
+        let expiry_time = inner.underlying().time_to_day_fraction(expiry)?;
+        let pay_date = settlement.apply(expiry.date());
+        Ok(CompoundOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            inner: inner,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CompoundOption::deserialize(de)?)))
+    }
+
+    pub fn inner(&self) -> &SpotStartingEuropean { &self.inner }
+    pub fn expiry(&self) -> DateTime { self.expiry }
+    pub fn strike(&self) -> f64 { self.strike }
+
+    fn intrinsic(&self, inner_value: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (inner_value - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - inner_value).max(0.0)
+        }
+    }
+}
+
+impl InstanceId for CompoundOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for CompoundOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.inner.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        if self.expiry.date() >= context.spot_date() {
+            context.exercise(&self.id, self.expiry);
+        }
+        self.inner.dependencies(context)
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// At the outer expiry, the holder exercises if the inner option is
+    /// worth more than the strike (for a call) or less (for a put). On
+    /// exercise, the holder receives or delivers the inner option, and pays
+    /// or receives the strike.
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if date != self.expiry {
+            return Ok(None)
+        }
+
+        let value = self.inner.price(context, date)?;
+        if self.intrinsic(value) <= 0.0 {
+            return Ok(Some(Vec::new()))
+        }
+
+        let sign = match self.put_or_call {
+            PutOrCall::Call => 1.0,
+            PutOrCall::Put => -1.0 };
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment = ZeroCoupon::new(&format!("{}:payment", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone());
+        Ok(Some(vec![
+            (sign, RcInstrument::new(Qrc::new(Arc::new(self.inner.clone())))),
+            (-sign * self.strike, RcInstrument::new(Qrc::new(Arc::new(payment))))]))
+    }
+}
+
+impl Priceable for CompoundOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+        let inner_expiry = Exercisable::expiry(&self.inner).date();
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let expected = expectation_at_expiry(context,
+                    self.inner.underlying(), *date, self.expiry, self.expiry_time,
+                    inner_expiry, self.inner.strike(),
+                    &|conditional| Ok(self.intrinsic(
+                        self.inner.price(conditional, self.expiry)?)))?;
+                let settlement_date = self.settlement.apply(date.date());
+                df_from_base * yc.rt(settlement_date)?.exp() * expected
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Integrates a payoff over the level of the underlying at an expiry, as
+/// seen from the val date. The underlying is taken to be log-normal, with
+/// the vol at the given strike and allowing for displacement. The payoff is
+/// evaluated at each level in a context where the forward of the underlying
+/// is conditional on that level, so it may be the price of any instrument
+/// on the expiry date. The high water mark is the last date on which the
+/// payoff needs the forward.
+///
+/// This is used by instruments such as compound and chooser options, which
+/// turn into other options at their expiry.
+pub fn expectation_at_expiry(context: &PricingContext, underlying: &RcInstrument,
+    val_date: DateTime, expiry: DateTime, expiry_time: DateDayFraction,
+    high_water_mark: Date, strike: f64,
+    payoff: &Fn(&PricingContext) -> Result<f64, qm::Error>)
+    -> Result<f64, qm::Error> {
+
+    let expiry_date = expiry.date();
+    let forward = context.forward_curve(&**underlying, high_water_mark)?;
+    let vol = context.vol_surface(&**underlying, high_water_mark,
+        &|| Ok(forward.clone()))?;
+
+    let displacement = vol.displacement(expiry_date)?;
+    let f = forward.forward(expiry_date)? - displacement;
+    if f < 0.0 {
+        return Err(qm::Error::new("Negative forward"));
+    }
+
+    let from = underlying.time_to_day_fraction(val_date)?;
+    let variance = vol.forward_variance(from, expiry_time, strike)?;
+    if variance < 0.0 {
+        return Err(qm::Error::new("Negative variance"));
+    }
+    let sqrt_var = variance.sqrt();
+
+    // Integrate over the standard normal driving the log of the displaced
+    // underlying, using the trapezium rule
+    let dz = 2.0 * COMPOUND_STDEVS / COMPOUND_STEPS as f64;
+    let mut sum = 0.0;
+    for i in 0..(COMPOUND_STEPS + 1) {
+        let z = -COMPOUND_STDEVS + i as f64 * dz;
+        let level = displacement + f * (sqrt_var * z - 0.5 * variance).exp();
+        let density = (-0.5 * z * z).exp() / (2.0 * PI).sqrt();
+        let weight = if i == 0 || i == COMPOUND_STEPS { 0.5 } else { 1.0 };
+        let conditional = ConditionalContext {
+            context: context,
+            underlying: underlying.id(),
+            forward: Arc::new(ConditionalForward::new(forward.clone(),
+                vol.clone(), expiry_date, level)?) };
+        sum += weight * density * payoff(&conditional)?;
+    }
+
+    Ok(sum * dz)
+}
+
+/// The forward of the underlying, conditional on it having the given level
+/// on the given date. The displaced part of the forward is scaled, so that
+/// it is consistent with the vol surface's view of cash dividends.
+struct ConditionalForward {
+    base: Arc<Forward>,
+    vol: RcVolSurface,
+    scale: f64
+}
+
+impl ConditionalForward {
+    fn new(base: Arc<Forward>, vol: RcVolSurface, date: Date, level: f64)
+        -> Result<ConditionalForward, qm::Error> {
+        let displacement = vol.displacement(date)?;
+        let scale = (level - displacement) / (base.forward(date)? - displacement);
+        Ok(ConditionalForward { base: base, vol: vol, scale: scale })
+    }
+}
+
+impl Forward for ConditionalForward {
+    fn as_interp(&self) -> &Interpolate<Date> { self }
+
+    fn forward(&self, date: Date) -> Result<f64, qm::Error> {
+        let displacement = self.vol.displacement(date)?;
+        Ok(displacement + (self.base.forward(date)? - displacement) * self.scale)
+    }
+}
+
+/// Decorates a pricing context, so that the forward of the underlying of a
+/// compound option is conditional on its level at the outer expiry.
+/// Everything else is passed straight through.
+struct ConditionalContext<'a> {
+    context: &'a PricingContext,
+    underlying: &'a str,
+    forward: Arc<Forward>
+}
+
+impl<'a> PricingContext for ConditionalContext<'a> {
+    fn spot_date(&self) -> Date {
+        self.context.spot_date()
+    }
+
+    fn yield_curve(&self, credit_id: &str, high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        self.context.yield_curve(credit_id, high_water_mark)
+    }
+
+    fn spot(&self, id: &str) -> Result<f64, qm::Error> {
+        self.context.spot(id)
+    }
+
+    fn forward_curve(&self, instrument: &Instrument, high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+        if instrument.id() == self.underlying {
+            Ok(self.forward.clone())
+        } else {
+            self.context.forward_curve(instrument, high_water_mark)
+        }
+    }
+
+    fn vol_surface(&self, instrument: &Instrument, high_water_mark: Date,
+        forward_fn: &Fn() -> Result<Arc<Forward>, qm::Error>)
+        -> Result<RcVolSurface, qm::Error> {
+        self.context.vol_surface(instrument, high_water_mark, forward_fn)
+    }
+
+    fn correlation(&self, first: &Instrument, second: &Instrument)
+        -> Result<f64, qm::Error> {
+        self.context.correlation(first, second)
+    }
+
+    fn average_correlation(&self, first: &Instrument, second: &Instrument,
+        from: Date, to: Date) -> Result<f64, qm::Error> {
+        self.context.average_correlation(first, second, from, to)
+    }
+
+    fn fx_spot(&self, fx_id: &str) -> Result<f64, qm::Error> {
+        self.context.fx_spot(fx_id)
+    }
+
+    fn fx_forward_curve(&self, pair: &ExchangeRate, high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+        self.context.fx_forward_curve(pair, high_water_mark)
+    }
+
+    fn fx_vol_surface(&self, fx_id: &str, high_water_mark: Date)
+        -> Result<RcVolSurface, qm::Error> {
+        self.context.fx_vol_surface(fx_id, high_water_mark)
+    }
+
+    fn correlation_by_id(&self, first: &str, second: &str)
+        -> Result<f64, qm::Error> {
+        self.context.correlation_by_id(first, second)
+    }
+
+    fn vol_cube(&self, id: &str, high_water_mark: Date)
+        -> Result<RcVolCube, qm::Error> {
+        self.context.vol_cube(id, high_water_mark)
+    }
+
+    fn hazard_curve(&self, credit_id: &str, high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        self.context.hazard_curve(credit_id, high_water_mark)
+    }
+
+    fn inflation_curve(&self, index_id: &str, high_water_mark: Date)
+        -> Result<RcInflationCurve, qm::Error> {
+        self.context.inflation_curve(index_id, high_water_mark)
+    }
+
+    fn commodity_curve(&self, id: &str, high_water_mark: Date)
+        -> Result<RcCommodityCurve, qm::Error> {
+        self.context.commodity_curve(id, high_water_mark)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::datetime::TimeOfDay;
+
+    fn sample_inner(put_or_call: PutOrCall) -> SpotStartingEuropean {
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        SpotStartingEuropean::new("SampleInner", "OPT", equity,
+            sample_settlement(2), expiry, 100.0, put_or_call,
+            OptionSettlement::Cash).unwrap()
+    }
+
+    fn sample_compound(inner: PutOrCall, outer: PutOrCall, strike: f64)
+        -> CompoundOption {
+        let expiry = DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close);
+        CompoundOption::new("SampleCompound", "OPT", sample_inner(inner),
+            sample_settlement(2), expiry, strike, outer).unwrap()
+    }
+
+    #[test]
+    fn compound_put_call_parity() {
+        let market_data = sample_market_data();
+        let inner = sample_inner(PutOrCall::Call)
+            .price(&market_data, sample_val_date()).unwrap();
+        let call = sample_compound(PutOrCall::Call, PutOrCall::Call, 8.0)
+            .price(&market_data, sample_val_date()).unwrap();
+        let put = sample_compound(PutOrCall::Call, PutOrCall::Put, 8.0)
+            .price(&market_data, sample_val_date()).unwrap();
+
+        // the strike is paid at the settlement date of the outer expiry
+        let yc = market_data.yield_curve("OPT", Date::from_ymd(2017, 06, 05)).unwrap();
+        let df = (yc.rt(Date::from_ymd(2017, 01, 04)).unwrap()
+            - yc.rt(Date::from_ymd(2017, 06, 05)).unwrap()).exp();
+        assert!(call > 0.0 && put > 0.0, "call={} put={}", call, put);
+        assert_approx(call - put, inner - 8.0 * df, 1e-8);
+    }
+
+    #[test]
+    fn compound_with_zero_strike_is_inner_option() {
+        let market_data = sample_market_data();
+        for &put_or_call in [PutOrCall::Call, PutOrCall::Put].iter() {
+            let inner = sample_inner(put_or_call)
+                .price(&market_data, sample_val_date()).unwrap();
+            let compound = sample_compound(put_or_call, PutOrCall::Call, 0.0)
+                .price(&market_data, sample_val_date()).unwrap();
+            assert_approx(compound, inner, 1e-8);
+        }
+    }
+
+    #[test]
+    fn compound_is_cheaper_than_inner_option() {
+        let market_data = sample_market_data();
+        let inner = sample_inner(PutOrCall::Put)
+            .price(&market_data, sample_val_date()).unwrap();
+        let call_on_put = sample_compound(PutOrCall::Put, PutOrCall::Call, 5.0)
+            .price(&market_data, sample_val_date()).unwrap();
+        assert!(call_on_put < inner && call_on_put > inner - 5.0,
+            "call_on_put={} inner={}", call_on_put, inner);
+    }
+
+    #[test]
+    fn compound_exercises_into_inner_option() {
+        let market_data = sample_market_data();
+        for &(strike, expected) in [(1.0, 2), (50.0, 0)].iter() {
+            let compound = RcInstrument::new(Qrc::new(Arc::new(
+                sample_compound(PutOrCall::Call, PutOrCall::Call, strike))));
+            let mut dependencies = DependencyCollector::new(market_data.spot_date());
+            dependencies.spot(&compound);
+
+            let mut instruments = vec![(1.0, compound)];
+            let new_date = Date::from_ymd(2017, 06, 05);
+            let bump = BumpTime::new(new_date, new_date, SpotDynamics::StickySpot);
+            let changed = bump.update_instruments(&mut instruments,
+                &market_data, &dependencies).unwrap();
+            assert!(changed);
+            assert_eq!(instruments.len(), expected);
+            if expected > 0 {
+                assert_eq!(instruments[0].1.type_id(), "SpotStartingEuropean");
+                assert_eq!(instruments[0].0, 1.0);
+                assert_eq!(instruments[1].1.type_id(), "ZeroCoupon");
+                assert_eq!(instruments[1].0, -strike);
+            }
+        }
+    }
+
+    #[test]
+    fn compound_must_expire_before_inner() {
+        let expiry = sample_expiry();
+        assert!(CompoundOption::new("SampleCompound", "OPT",
+            sample_inner(PutOrCall::Call), sample_settlement(2), expiry, 5.0,
+            PutOrCall::Call).is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,471 @@
+use std::sync::Arc;
+use std::f64::NAN;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::bonds::FixedCouponBond;
+use math::tridiagonal::solve_tridiagonal;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The right of the issuer to call a convertible bond at a given price per
+/// unit notional, at any time within a window, but only while the spot of
+/// the underlying is at or above a trigger level. When called, the holder
+/// may choose to convert instead.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SoftCall {
+    start: Date,
+    end: Date,
+    price: f64,
+    trigger: f64
+}
+
+impl SoftCall {
+    pub fn new(start: Date, end: Date, price: f64, trigger: f64)
+        -> Result<SoftCall, qm::Error> {
+        if end < start {
+            return Err(qm::Error::new("Soft call window must not end before it starts"))
+        }
+        Ok(SoftCall { start: start, end: end, price: price, trigger: trigger })
+    }
+
+    pub fn start(&self) -> Date { self.start }
+    pub fn end(&self) -> Date { self.end }
+    pub fn price(&self) -> f64 { self.price }
+    pub fn trigger(&self) -> f64 { self.trigger }
+}
+
+/// The maximum number of time steps in the finite difference grid. Steps
+/// are a whole number of days, so short bonds are priced with daily steps.
+const CONVERTIBLE_MAX_TIME_STEPS: i32 = 1000;
+
+/// The number of spot steps between zero and today's spot. The grid extends
+/// to several times the largest of the spot, the conversion price and the
+/// soft call triggers, though never beyond a limit relative to the spot.
+const CONVERTIBLE_SPOT_STEPS_TO_SPOT: usize = 100;
+const CONVERTIBLE_GRID_MULTIPLE: f64 = 4.0;
+const CONVERTIBLE_MAX_GRID_MULTIPLE: f64 = 10.0;
+
+/// A convertible bond is a fixed coupon bond that the holder may convert at
+/// any time into a fixed number of shares of an underlying equity. The
+/// issuer may also have soft calls, which let it redeem the bond while the
+/// share price is high, forcing the holder to convert or be redeemed.
+///
+/// The issuer may default, with a hazard rate given by the hazard curve for
+/// its issuer id. On default, the share price jumps to zero and the holder
+/// recovers a fraction of the notional. The yield curve of the bond should
+/// be the risk-free curve, as the credit risk comes from the hazard curve.
+///
+/// The bond is priced by solving the pricing PDE in the share price on a
+/// fully implicit finite difference grid. The share price drifts at the
+/// rate of growth of its forward plus the hazard rate, which compensates
+/// for the jump to zero on default, and values are discounted at the risk-
+/// free rate plus the hazard rate. The total variance of the share price to
+/// maturity, at the conversion price, is spread evenly over the steps.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ConvertibleBond {
+    bond: FixedCouponBond,
+    underlying: RcInstrument,
+    conversion_ratio: f64,
+    issuer_id: String,
+    recovery: f64,
+    soft_calls: Vec<SoftCall>
+}
+
+impl TypeId for ConvertibleBond {
+    fn type_id(&self) -> &'static str { "ConvertibleBond" }
+}
+
+impl InstanceId for ConvertibleBond {
+    fn id(&self) -> &str { self.bond.id() }
+}
+
+impl ConvertibleBond {
+    /// Creates a convertible bond, which converts into the given number of
+    /// shares of the underlying for each bond of the notional given by the
+    /// underlying fixed coupon bond. The recovery is a fraction of the
+    /// notional.
+    pub fn new(bond: FixedCouponBond, underlying: RcInstrument,
+        conversion_ratio: f64, issuer_id: &str, recovery: f64,
+        soft_calls: &[SoftCall]) -> Result<ConvertibleBond, qm::Error> {
+
+        if !(conversion_ratio > 0.0) {
+            return Err(qm::Error::new("Conversion ratio must be positive"))
+        }
+        if recovery < 0.0 || recovery > 1.0 {
+            return Err(qm::Error::new("Recovery must be between zero and one"))
+        }
+
+        Ok(ConvertibleBond { bond: bond, underlying: underlying,
+            conversion_ratio: conversion_ratio, issuer_id: issuer_id.to_string(),
+            recovery: recovery, soft_calls: soft_calls.to_vec() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ConvertibleBond::deserialize(de)?)))
+    }
+
+    pub fn bond(&self) -> &FixedCouponBond { &self.bond }
+    pub fn underlying(&self) -> &RcInstrument { &self.underlying }
+    pub fn conversion_ratio(&self) -> f64 { self.conversion_ratio }
+    pub fn issuer_id(&self) -> &str { &self.issuer_id }
+    pub fn recovery(&self) -> f64 { self.recovery }
+    pub fn soft_calls(&self) -> &[SoftCall] { &self.soft_calls }
+
+    /// The share price at which the value of the shares received on
+    /// conversion equals the notional
+    pub fn conversion_price(&self) -> f64 {
+        self.bond.notional() / self.conversion_ratio
+    }
+
+    /// Prices the bond on a grid whose first step is the given settlement
+    /// date, discounting to that date.
+    fn grid_price(&self, context: &PricingContext, val_date: DateTime,
+        settlement_date: Date) -> Result<f64, qm::Error> {
+
+        let maturity = self.bond.maturity();
+        if maturity <= settlement_date {
+            return Ok(0.0)
+        }
+
+        // lay out the time steps in whole days, with the last at maturity
+        let days = maturity - settlement_date;
+        let step_days = (days + CONVERTIBLE_MAX_TIME_STEPS - 1) / CONVERTIBLE_MAX_TIME_STEPS;
+        let steps = ((days + step_days - 1) / step_days) as usize;
+        let step_dates: Vec<Date> = (0..(steps + 1)).map(|i|
+            (settlement_date + i as i32 * step_days).min(maturity)).collect();
+        let step_of = |date: Date| {
+            let offset = (date - settlement_date) as f64 / step_days as f64;
+            (offset.round() as usize).min(steps)
+        };
+
+        // discount factors, survival probabilities and forwards at each step
+        let yc = context.yield_curve(self.bond.credit_id(), maturity)?;
+        let hazard = context.hazard_curve(&self.issuer_id, maturity)?;
+        let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlying of a convertible must itself be priceable"))?;
+        let forward_dates: Vec<DateTime> = step_dates.iter().map(|date|
+            DateTime::new(*date, TimeOfDay::Close)).collect();
+        let mut forwards = vec![NAN; steps + 1];
+        underlying.prices(context, &forward_dates, &mut forwards)?;
+        let mut dfs = Vec::with_capacity(steps + 1);
+        let mut survivals = Vec::with_capacity(steps + 1);
+        for date in step_dates.iter() {
+            dfs.push(yc.df(*date, settlement_date)?);
+            survivals.push(hazard.df(*date, settlement_date)?);
+        }
+
+        let maturity_time = DateTime::new(maturity, TimeOfDay::Close);
+        let vol = context.vol_surface(&*self.underlying, maturity,
+            &|| context.forward_curve(&*self.underlying, maturity))?;
+        let variance = vol.forward_variance(
+            self.underlying.time_to_day_fraction(val_date)?,
+            self.underlying.time_to_day_fraction(maturity_time)?,
+            self.conversion_price())?;
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"))
+        }
+        let step_variance = variance / steps as f64;
+
+        // the spot grid, with today's forward exactly on a node
+        let spot = forwards[0];
+        if !(spot > 0.0) {
+            return Err(qm::Error::new("Underlying of a convertible must have a positive forward"))
+        }
+        let ds = spot / CONVERTIBLE_SPOT_STEPS_TO_SPOT as f64;
+        let highest = self.soft_calls.iter().fold(
+            spot.max(self.conversion_price()), |h, call| h.max(call.trigger))
+            .min(CONVERTIBLE_MAX_GRID_MULTIPLE * spot);
+        let nodes = (CONVERTIBLE_GRID_MULTIPLE * highest / ds).ceil() as usize + 1;
+        let spots: Vec<f64> = (0..nodes).map(|k| k as f64 * ds).collect();
+        let parity: Vec<f64> = spots.iter().map(|s| self.conversion_ratio * s).collect();
+
+        // coupons and the notional, by step
+        let notional = self.bond.notional();
+        let coupons = self.bond.coupons();
+        let day_count = coupons.day_count();
+        let mut flows = vec![0.0; steps + 1];
+        for period in coupons.periods().iter().filter(|p| p.payment() > settlement_date) {
+            flows[step_of(period.payment())] += notional * coupons.rate()
+                * day_count.year_fraction(period.start(), period.end());
+        }
+        flows[steps] += notional;
+
+        // at maturity, the holder either takes the redemption or converts
+        let mut values: Vec<f64> = parity.iter().map(|p| p.max(flows[steps])).collect();
+
+        let mut lower = vec![0.0; nodes];
+        let mut diag = vec![0.0; nodes];
+        let mut upper = vec![0.0; nodes];
+        for step in (0..steps).rev() {
+            let growth = (forwards[step + 1] / forwards[step]).ln();
+            let default_probability = 1.0 - survivals[step + 1] / survivals[step];
+            let hazard_dt = -(survivals[step + 1] / survivals[step]).ln();
+            let discount = dfs[step] / dfs[step + 1] * survivals[step] / survivals[step + 1] - 1.0;
+            let drift = growth + hazard_dt;
+
+            // the recovery is received at the end of the step if the issuer
+            // defaults within it
+            let recovery = default_probability * self.recovery * notional
+                / (1.0 - default_probability);
+
+            // At spot zero the shares are worthless, and at the top of the
+            // grid the value is assumed to be linear in spot
+            for k in 0..nodes {
+                let kf = k as f64;
+                if k == 0 {
+                    lower[k] = 0.0;
+                    diag[k] = 1.0 + discount;
+                    upper[k] = 0.0;
+                } else if k == nodes - 1 {
+                    lower[k] = drift * kf;
+                    diag[k] = 1.0 + discount - drift * kf;
+                    upper[k] = 0.0;
+                } else {
+                    let diffusion = 0.5 * step_variance * kf * kf;
+                    let convection = 0.5 * drift * kf;
+                    lower[k] = -(diffusion - convection);
+                    diag[k] = 1.0 + 2.0 * diffusion + discount;
+                    upper[k] = -(diffusion + convection);
+                }
+            }
+            let rhs: Vec<f64> = values.iter().map(|v| v + recovery).collect();
+            values = solve_tridiagonal(&lower, &diag, &upper, &rhs)?;
+
+            // coupons are paid to the holder, who may then convert. If the
+            // issuer can call, the holder gets the better of the call price
+            // and conversion.
+            let date = step_dates[step];
+            let call_price = self.soft_calls.iter()
+                .filter(|call| call.start <= date && date <= call.end)
+                .map(|call| (call.price * notional
+                    + self.bond.accrued_interest(date), call.trigger))
+                .collect::<Vec<(f64, f64)>>();
+            for k in 0..nodes {
+                let mut value = values[k] + flows[step];
+                for &(price, trigger) in call_price.iter() {
+                    if spots[k] >= trigger {
+                        value = value.min(price.max(parity[k]));
+                    }
+                }
+                values[k] = value.max(parity[k]);
+            }
+        }
+
+        Ok(values[CONVERTIBLE_SPOT_STEPS_TO_SPOT])
+    }
+}
+
+impl Instrument for ConvertibleBond {
+
+    fn payoff_currency(&self) -> &Currency {
+        self.bond.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.bond.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.bond.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        let maturity = self.bond.maturity();
+        context.yield_curve(self.bond.credit_id(), maturity);
+        context.hazard_curve(&self.issuer_id, maturity);
+        context.forward_curve(&self.underlying, maturity);
+        context.vol_surface(&self.underlying, maturity);
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for ConvertibleBond {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The dirty price of the bond, including the value of conversion and
+    /// any soft calls, discounted to the settlement date.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let settlement_date = self.bond.settlement().apply(date.date());
+            *output = self.grid_price(context, *date, settlement_date)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::interpolation::Extrap;
+    use data::curves::RateCurveAct365;
+    use data::curves::RcRateCurve;
+    use dates::calendar::WeekdayCalendar;
+    use dates::calendar::RcCalendar;
+    use dates::rules::ModifiedFollowing;
+    use dates::daycount::DayCount;
+    use instruments::assets::RcCurrency;
+    use instruments::swaps::FixedLeg;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::dependencies::DependencyCollector;
+    use serde_json;
+
+    fn sample_bond() -> FixedCouponBond {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar);
+        let coupons = FixedLeg::new(Date::from_ymd(2017, 01, 05),
+            Date::from_ymd(2019, 01, 05), 6, &adjustment, DayCount::Thirty360,
+            0.04).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        FixedCouponBond::new("BP.CONV.2019", "OPT", currency, 100.0, coupons,
+            sample_settlement(1)).unwrap()
+    }
+
+    fn sample_convertible(ratio: f64, soft_calls: &[SoftCall]) -> ConvertibleBond {
+        let equity = sample_underlying();
+        ConvertibleBond::new(sample_bond(), equity, ratio, "BP.ISSUER", 0.4,
+            soft_calls).unwrap()
+    }
+
+    fn sample_convertible_market_data(hazard_rate: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        let d = Date::from_ymd(2016, 12, 30);
+        let hazard = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, hazard_rate), (d + 728, hazard_rate)],
+            Extrap::Flat, Extrap::Flat).unwrap()));
+        market_data.add_hazard_curve("BP.ISSUER", hazard);
+        market_data
+    }
+
+    #[test]
+    fn convertible_without_conversion_or_default_is_straight_bond() {
+        let market_data = sample_convertible_market_data(0.0);
+        let val_date = sample_val_date();
+        let convertible = sample_convertible(1e-8, &[]);
+        let price = convertible.price(&market_data, val_date).unwrap();
+        let straight = sample_bond().price(&market_data, val_date).unwrap();
+        assert_approx(price, straight, 1e-9);
+    }
+
+    #[test]
+    fn convertible_is_worth_more_than_bond_or_shares() {
+        let market_data = sample_convertible_market_data(0.02);
+        let val_date = sample_val_date();
+        let convertible = sample_convertible(1.0, &[]);
+        let price = convertible.price(&market_data, val_date).unwrap();
+        let straight = sample_convertible(1e-8, &[]).price(&market_data, val_date).unwrap();
+        let parity = 100.0;
+        assert!(price > straight && price > parity,
+            "price={} straight={} parity={}", price, straight, parity);
+
+        // a large conversion ratio makes the bond trade like the shares
+        let convertible = sample_convertible(10.0, &[]);
+        let price = convertible.price(&market_data, val_date).unwrap();
+        let parity = 1000.0;
+        assert!(price >= parity && price < parity * 1.01,
+            "price={} parity={}", price, parity);
+    }
+
+    #[test]
+    fn convertible_is_worth_less_with_more_credit_risk() {
+        let val_date = sample_val_date();
+        let convertible = sample_convertible(1.0, &[]);
+        let safe = convertible.price(
+            &sample_convertible_market_data(0.0), val_date).unwrap();
+        let risky = convertible.price(
+            &sample_convertible_market_data(0.05), val_date).unwrap();
+        assert!(risky < safe, "risky={} safe={}", risky, safe);
+    }
+
+    #[test]
+    fn soft_call_reduces_convertible_value() {
+        let market_data = sample_convertible_market_data(0.02);
+        let val_date = sample_val_date();
+        let price = sample_convertible(1.0, &[])
+            .price(&market_data, val_date).unwrap();
+        let call = SoftCall::new(Date::from_ymd(2017, 07, 05),
+            Date::from_ymd(2019, 01, 05), 1.0, 130.0).unwrap();
+        let callable = sample_convertible(1.0, &[call])
+            .price(&market_data, val_date).unwrap();
+        assert!(callable < price, "callable={} price={}", callable, price);
+
+        // a trigger that is never reached has almost no effect
+        let call = SoftCall::new(Date::from_ymd(2017, 07, 05),
+            Date::from_ymd(2019, 01, 05), 1.0, 10000.0).unwrap();
+        let unreachable = sample_convertible(1.0, &[call])
+            .price(&market_data, val_date).unwrap();
+        assert_approx(unreachable, price, 1e-12);
+    }
+
+    #[test]
+    fn convertible_dependencies_include_hazard_curve() {
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let convertible = RcInstrument::new(Qrc::new(Arc::new(
+            sample_convertible(1.0, &[]))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&convertible);
+
+        let maturity = Date::from_ymd(2019, 01, 07);
+        assert_eq!(dependencies.yield_curve_hwm("OPT"), Some(maturity));
+        assert_eq!(dependencies.hazard_curve_hwm("BP.ISSUER"), Some(maturity));
+        let equity = dependencies.instrument_by_id("BP.L").unwrap().clone();
+        assert_eq!(dependencies.forward_curve_hwm(&equity), Some(maturity));
+        assert_eq!(dependencies.vol_surface_hwm(&equity), Some(maturity));
+    }
+
+    #[test]
+    fn convertible_rejects_bad_inputs() {
+        let equity = sample_underlying();
+        assert!(ConvertibleBond::new(sample_bond(), equity.clone(), 0.0,
+            "BP.ISSUER", 0.4, &[]).is_err());
+        assert!(ConvertibleBond::new(sample_bond(), equity, 1.0,
+            "BP.ISSUER", 1.5, &[]).is_err());
+        assert!(SoftCall::new(Date::from_ymd(2018, 01, 05),
+            Date::from_ymd(2017, 01, 05), 1.0, 130.0).is_err());
+    }
+
+    #[test]
+    fn convertible_tagged_serde() {
+        let market_data = sample_convertible_market_data(0.02);
+        let val_date = sample_val_date();
+        let convertible = sample_convertible(1.0, &[]);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(convertible.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, convertible.price(&market_data, val_date).unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
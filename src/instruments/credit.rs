@@ -0,0 +1,333 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::swaps::FixedLeg;
+use instruments::swaps::PayOrReceive;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A single-name credit default swap. The buyer of protection pays a
+/// running spread on the notional, as a premium leg with the schedule and
+/// day count of a fixed leg. If the reference entity defaults before the
+/// end of the last premium period, the seller pays the notional times one
+/// minus the recovery, and the buyer pays the premium accrued since the
+/// start of the period.
+///
+/// Default is driven by the hazard curve of the reference entity, whose
+/// discount factors are survival probabilities. Following the usual
+/// approximation, default within a period is assumed to happen half way
+/// through the part of the period that remains, which is where the default
+/// payments are discounted from. Premiums are discounted according to the
+/// yield curve matching the credit_id of the swap.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CreditDefaultSwap {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    pay_or_receive: PayOrReceive,
+    premium: FixedLeg,
+    reference_id: String,
+    recovery: f64
+}
+
+impl TypeId for CreditDefaultSwap {
+    fn type_id(&self) -> &'static str { "CreditDefaultSwap" }
+}
+
+impl InstanceId for CreditDefaultSwap {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl CreditDefaultSwap {
+    /// Creates a credit default swap. If pay_or_receive is Pay, the holder
+    /// pays the premium and buys protection on the reference entity, so the
+    /// swap gains value as the entity becomes more likely to default. The
+    /// spread is the rate of the premium leg. The recovery is the fraction
+    /// of the notional assumed to be recovered on default.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        pay_or_receive: PayOrReceive, premium: FixedLeg, reference_id: &str,
+        recovery: f64) -> Result<CreditDefaultSwap, qm::Error> {
+
+        if premium.periods().is_empty() {
+            return Err(qm::Error::new("Credit default swap must have at least one premium period"))
+        }
+        if recovery < 0.0 || recovery > 1.0 {
+            return Err(qm::Error::new("Recovery must be between zero and one"))
+        }
+
+        Ok(CreditDefaultSwap { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional, pay_or_receive: pay_or_receive,
+            premium: premium, reference_id: reference_id.to_string(),
+            recovery: recovery })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CreditDefaultSwap::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn pay_or_receive(&self) -> PayOrReceive { self.pay_or_receive }
+    pub fn premium_leg(&self) -> &FixedLeg { &self.premium }
+    pub fn reference_id(&self) -> &str { &self.reference_id }
+    pub fn recovery(&self) -> f64 { self.recovery }
+
+    /// The end of protection, which is the end of the last premium period
+    pub fn protection_end(&self) -> Date {
+        self.premium.periods().last().map_or(Date::from_nil(), |p| p.end())
+    }
+
+    fn last_payment(&self) -> Date {
+        self.premium.periods().last().map_or(Date::from_nil(), |p| p.payment())
+    }
+
+    /// The spread that would make the swap worth nothing
+    pub fn par_spread(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<f64, qm::Error> {
+        let (protection, annuity) = self.legs(context, val_date.date())?;
+        if annuity == 0.0 {
+            return Err(qm::Error::new("Credit default swap has no remaining premium"))
+        }
+        Ok(protection / annuity)
+    }
+
+    /// The value of the premium leg per unit notional, in units of the
+    /// spread, including premium accrued at default. This is often known
+    /// as the risky annuity or risky PV01.
+    pub fn risky_annuity(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<f64, qm::Error> {
+        let (_, annuity) = self.legs(context, val_date.date())?;
+        Ok(annuity)
+    }
+
+    /// Values the protection leg and the risky annuity per unit notional,
+    /// conditional on the reference entity having survived to the given
+    /// date, discounted to the settlement date.
+    fn legs(&self, context: &PricingContext, date: Date)
+        -> Result<(f64, f64), qm::Error> {
+
+        let yc = context.yield_curve(&self.credit_id, self.last_payment())?;
+        let hazard = context.hazard_curve(&self.reference_id, self.protection_end())?;
+        let discount_date = self.settlement().apply(date);
+        let day_count = self.premium.day_count();
+
+        let mut protection = 0.0;
+        let mut annuity = 0.0;
+        for period in self.premium.periods().iter().filter(|p| p.end() > date) {
+            let start = if period.start() > date { period.start() } else { date };
+            let default_date = start + (period.end() - start) / 2;
+            let default_probability = hazard.df(start, date)? - hazard.df(period.end(), date)?;
+            let default_df = yc.df(default_date, discount_date)?;
+
+            protection += (1.0 - self.recovery) * default_probability * default_df;
+            annuity += day_count.year_fraction(period.start(), default_date)
+                * default_probability * default_df;
+            if period.payment() > discount_date {
+                annuity += day_count.year_fraction(period.start(), period.end())
+                    * hazard.df(period.end(), date)?
+                    * yc.df(period.payment(), discount_date)?;
+            }
+        }
+
+        Ok((protection, annuity))
+    }
+}
+
+impl Instrument for CreditDefaultSwap {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.currency.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.last_payment());
+        context.hazard_curve(&self.reference_id, self.protection_end());
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for CreditDefaultSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The value of the protection less the value of the premium, or the
+    /// other way round if the holder receives the premium. Once protection
+    /// has ended, the swap is worth nothing.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let (protection, annuity) = self.legs(context, date.date())?;
+            let value = self.notional * (protection - self.premium.rate() * annuity);
+            *output = match self.pay_or_receive {
+                PayOrReceive::Pay => value,
+                PayOrReceive::Receive => -value
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::interpolation::Extrap;
+    use data::curves::RateCurveAct365;
+    use data::curves::RcRateCurve;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::ModifiedFollowing;
+    use dates::daycount::DayCount;
+    use dates::datetime::TimeOfDay;
+    use instruments::RcInstrument;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use serde_json;
+
+    pub fn sample_cds(spread: f64, pay_or_receive: PayOrReceive) -> CreditDefaultSwap {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar);
+        let premium = FixedLeg::new(Date::from_ymd(2016, 12, 20),
+            Date::from_ymd(2021, 12, 20), 3, &adjustment, DayCount::Act360,
+            spread).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        CreditDefaultSwap::new("BP.CDS.5Y", "OPT", currency, 1e6, pay_or_receive,
+            premium, "BP.ISSUER", 0.4).unwrap()
+    }
+
+    pub fn sample_cds_market_data(hazard_rate: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        let d = Date::from_ymd(2016, 12, 30);
+        let hazard = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, hazard_rate), (d + 1820, hazard_rate)],
+            Extrap::Flat, Extrap::Flat).unwrap()));
+        market_data.add_hazard_curve("BP.ISSUER", hazard);
+        market_data
+    }
+
+    #[test]
+    fn cds_par_spread_matches_credit_triangle() {
+        // the par spread is roughly the hazard rate times the loss given
+        // default, which is 0.02 * 0.6
+        let market_data = sample_cds_market_data(0.02);
+        let val_date = sample_val_date();
+        let cds = sample_cds(0.01, PayOrReceive::Pay);
+        let spread = cds.par_spread(&market_data, val_date).unwrap();
+        assert_approx(spread, 0.012, 0.0003);
+
+        // the swap at its par spread is worth nothing
+        let par = sample_cds(spread, PayOrReceive::Pay);
+        assert_approx(par.price(&market_data, val_date).unwrap(), 0.0, 1e-6);
+    }
+
+    #[test]
+    fn cds_protection_buyer_gains_as_credit_worsens() {
+        let val_date = sample_val_date();
+        let buyer = sample_cds(0.01, PayOrReceive::Pay);
+        let seller = sample_cds(0.01, PayOrReceive::Receive);
+        let safe = sample_cds_market_data(0.01);
+        let risky = sample_cds_market_data(0.03);
+
+        let safe_price = buyer.price(&safe, val_date).unwrap();
+        let risky_price = buyer.price(&risky, val_date).unwrap();
+        assert!(safe_price < 0.0 && risky_price > 0.0,
+            "safe={} risky={}", safe_price, risky_price);
+        assert_approx(seller.price(&risky, val_date).unwrap(), -risky_price, 1e-9);
+    }
+
+    #[test]
+    fn cds_without_default_risk_is_an_annuity() {
+        let market_data = sample_cds_market_data(0.0);
+        let val_date = sample_val_date();
+        let cds = sample_cds(0.01, PayOrReceive::Receive);
+        let annuity = cds.risky_annuity(&market_data, val_date).unwrap();
+        let price = cds.price(&market_data, val_date).unwrap();
+        assert_approx(price, 1e6 * 0.01 * annuity, 1e-9);
+
+        // the annuity is about five years of premium, discounted
+        assert!(annuity > 4.0 && annuity < 5.1, "annuity={}", annuity);
+    }
+
+    #[test]
+    fn cds_is_worthless_after_protection_ends() {
+        let market_data = sample_cds_market_data(0.02);
+        let cds = sample_cds(0.01, PayOrReceive::Pay);
+        let after = DateTime::new(Date::from_ymd(2022, 01, 04), TimeOfDay::Open);
+        assert_eq!(cds.price(&market_data, after).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn cds_dependencies() {
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let cds = RcInstrument::new(Qrc::new(Arc::new(
+            sample_cds(0.01, PayOrReceive::Pay))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&cds);
+
+        assert_eq!(dependencies.yield_curve_hwm("OPT"), Some(Date::from_ymd(2021, 12, 20)));
+        assert_eq!(dependencies.hazard_curve_hwm("BP.ISSUER"), Some(Date::from_ymd(2021, 12, 20)));
+    }
+
+    #[test]
+    fn cds_rejects_bad_recovery() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar);
+        let premium = FixedLeg::new(Date::from_ymd(2016, 12, 20),
+            Date::from_ymd(2021, 12, 20), 3, &adjustment, DayCount::Act360,
+            0.01).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        assert!(CreditDefaultSwap::new("BP.CDS.5Y", "OPT", currency, 1e6,
+            PayOrReceive::Pay, premium, "BP.ISSUER", -0.1).is_err());
+    }
+
+    #[test]
+    fn cds_tagged_serde() {
+        let market_data = sample_cds_market_data(0.02);
+        let val_date = sample_val_date();
+        let cds = sample_cds(0.01, PayOrReceive::Pay);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(cds.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, cds.price(&market_data, val_date).unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,710 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::AnalyticPriceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::AdjointContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use math::optionpricing::Black76;
+use math::adjoint::Var;
+use pricers::analytic::AnalyticModel;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// What a digital option pays if it expires in the money. A cash-or-nothing
+/// digital pays a fixed amount of cash. An asset-or-nothing digital pays the
+/// level of the underlying at expiry (in cash).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DigitalPayout { CashOrNothing(f64), AssetOrNothing }
+
+/// A European digital (binary) option. A call pays out if the underlying
+/// ends above the strike, and a put if it ends below.
+///
+/// The discontinuity at the strike makes bump-and-revalue deltas and gammas
+/// unstable near expiry, so the option can be valued as a call spread
+/// (for a call) or put spread (for a put) centred on the strike, with the
+/// given spread width. This is the usual conservative risk treatment. A width
+/// of zero values the digital exactly. The width only affects valuation:
+/// once the expiry fixing is known the option pays its true digital payoff.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DigitalOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+    payout: DigitalPayout,
+    spread_width: f64,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for DigitalOption {
+    fn type_id(&self) -> &'static str { "DigitalOption" }
+}
+
+impl DigitalOption {
+    /// Creates a digital option. The spread width is in the same units as
+    /// the strike, and must be less than twice the strike, so the lower
+    /// strike of the replicating spread is positive.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall,
+        payout: DigitalPayout,
+        spread_width: f64)
+        -> Result<DigitalOption, qm::Error> {
+
+        if strike <= 0.0 {
+            return Err(qm::Error::new("Digital strike must be greater than zero"))
+        }
+        if spread_width < 0.0 || spread_width >= 2.0 * strike {
+            return Err(qm::Error::new("Digital spread width must be non-negative \
+                and less than twice the strike"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = underlying.time_to_day_fraction(expiry)?;
+        Ok(DigitalOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            payout: payout,
+            spread_width: spread_width,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DigitalOption::deserialize(de)?)))
+    }
+
+    /// The width of the call or put spread used for valuation, or zero if
+    /// the digital is valued exactly
+    pub fn spread_width(&self) -> f64 {
+        self.spread_width
+    }
+
+    /// Returns a copy of this digital with a different spread width, for
+    /// example to compare risk with and without smoothing.
+    pub fn with_spread_width(&self, spread_width: f64)
+        -> Result<DigitalOption, qm::Error> {
+        DigitalOption::new(&self.id, &self.credit_id, self.underlying.clone(),
+            self.settlement.clone(), self.expiry, self.strike, self.put_or_call,
+            self.payout, spread_width)
+    }
+
+    /// The fraction of the cash digital that pays out given the level of the
+    /// underlying at expiry. This ramps linearly across the spread if there
+    /// is one, and otherwise is zero or one.
+    fn in_the_money(&self, spot: f64, spread_width: f64) -> f64 {
+        if spread_width > 0.0 {
+            let half = 0.5 * spread_width;
+            let fraction = match self.put_or_call {
+                PutOrCall::Call => (spot - (self.strike - half)) / spread_width,
+                PutOrCall::Put => ((self.strike + half) - spot) / spread_width
+            };
+            fraction.max(0.0).min(1.0)
+        } else {
+            let hit = match self.put_or_call {
+                PutOrCall::Call => spot > self.strike,
+                PutOrCall::Put => spot < self.strike
+            };
+            if hit { 1.0 } else { 0.0 }
+        }
+    }
+
+    /// The payoff given the level of the underlying at expiry. With a
+    /// spread, an asset-or-nothing digital is replicated as a vanilla plus
+    /// the strike times a cash digital, as this is exact when there is no
+    /// spread.
+    fn payoff(&self, spot: f64, spread_width: f64) -> f64 {
+        let digital = self.in_the_money(spot, spread_width);
+        match self.payout {
+            DigitalPayout::CashOrNothing(amount) => amount * digital,
+            DigitalPayout::AssetOrNothing => if spread_width > 0.0 {
+                match self.put_or_call {
+                    PutOrCall::Call => (spot - self.strike).max(0.0)
+                        + self.strike * digital,
+                    PutOrCall::Put => self.strike * digital
+                        - (self.strike - spot).max(0.0)
+                }
+            } else {
+                spot * digital
+            }
+        }
+    }
+
+    /// The payoff with a spread, as above, on an adjoint tape. Without a
+    /// spread the payoff has no useful derivative.
+    fn adjoint_payoff<'t>(&self, spot: Var<'t>) -> Var<'t> {
+        let half = 0.5 * self.spread_width;
+        let fraction = match self.put_or_call {
+            PutOrCall::Call => (spot - (self.strike - half)) / self.spread_width,
+            PutOrCall::Put => ((self.strike + half) - spot) / self.spread_width
+        };
+        let digital = fraction.max(0.0).min(1.0);
+        match self.payout {
+            DigitalPayout::CashOrNothing(amount) => digital * amount,
+            DigitalPayout::AssetOrNothing => match self.put_or_call {
+                PutOrCall::Call => (spot - self.strike).max(0.0)
+                    + digital * self.strike,
+                PutOrCall::Put => digital * self.strike
+                    - (self.strike - spot).max(0.0)
+            }
+        }
+    }
+
+    /// Values the digital given the discount factor, the displaced forward
+    /// and a closure giving the variance to expiry at any strike.
+    fn value(&self, black76: &Black76, df: f64, forward: f64, displacement: f64,
+        variance: &Fn(f64) -> Result<f64, qm::Error>) -> Result<f64, qm::Error> {
+
+        let sqrt_var = |strike: f64| -> Result<f64, qm::Error> {
+            let var = variance(strike)?;
+            if var < 0.0 {
+                return Err(qm::Error::new("Negative variance"));
+            }
+            Ok(var.sqrt())
+        };
+
+        let strike = self.strike;
+        if self.spread_width == 0.0 {
+            let k = strike - displacement;
+            let sv = sqrt_var(strike)?;
+            let (cash, asset) = match self.put_or_call {
+                PutOrCall::Call => (
+                    black76.cash_digital_call_price(df, forward, k, sv),
+                    black76.asset_digital_call_price(df, forward, k, sv)),
+                PutOrCall::Put => (
+                    black76.cash_digital_put_price(df, forward, k, sv),
+                    black76.asset_digital_put_price(df, forward, k, sv))
+            };
+            return Ok(match self.payout {
+                DigitalPayout::CashOrNothing(amount) => amount * cash,
+                DigitalPayout::AssetOrNothing => asset + displacement * cash
+            })
+        }
+
+        // Replicate using a spread of vanillas either side of the strike.
+        // Each vanilla uses the vol at its own strike, so any skew is
+        // reflected in the price.
+        let vanilla = |k: f64| -> Result<f64, qm::Error> {
+            let sv = sqrt_var(k)?;
+            Ok(match self.put_or_call {
+                PutOrCall::Call => black76.call_price(df, forward, k - displacement, sv),
+                PutOrCall::Put => black76.put_price(df, forward, k - displacement, sv)
+            })
+        };
+        let half = 0.5 * self.spread_width;
+        let low = vanilla(strike - half)?;
+        let high = vanilla(strike + half)?;
+        let digital = match self.put_or_call {
+            PutOrCall::Call => (low - high) / self.spread_width,
+            PutOrCall::Put => (high - low) / self.spread_width
+        };
+
+        Ok(match self.payout {
+            DigitalPayout::CashOrNothing(amount) => amount * digital,
+            DigitalPayout::AssetOrNothing => match self.put_or_call {
+                PutOrCall::Call => vanilla(strike)? + strike * digital,
+                PutOrCall::Put => strike * digital - vanilla(strike)?
+            }
+        })
+    }
+}
+
+impl InstanceId for DigitalOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for DigitalOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // just one fixing, at expiry
+        context.fixing(self.underlying.id(), self.expiry);
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        let expiry_date = self.expiry.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    fn as_analytic(&self) -> Option<&AnalyticPriceable> {
+        Some(self)
+    }
+
+    /// If there is an expiry fixing, the digital turns into a cash payment,
+    /// which is the true digital payoff regardless of the spread width.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if let Some(spot) = fixing_table.get(self.underlying.id(), self.expiry)? {
+            let mut decomp = Vec::new();
+            let payment = self.payoff(spot, 0.0);
+            if payment > 0.0 {
+                decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:payment", self.id), &self.credit_id,
+                    RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                    self.expiry, self.pay_date, self.settlement.clone()))))));
+            }
+            Ok(Some(decomp))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Priceable for DigitalOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the digital using Black76, either exactly or as a spread of
+    /// vanillas, depending on the spread width.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| context.forward_curve(&*self.underlying, expiry_date))?;
+
+        let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlying of a digital must itself be priceable"))?;
+        let forward = underlying.price(context, self.expiry)?;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+
+        // displace the forward and strike in the same way as for a vanilla
+        let displacement = vol.displacement(expiry_date)?;
+        let f = forward - displacement;
+        if f < 0.0 {
+            return Err(qm::Error::new("Negative forward"));
+        }
+
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                let val_date = self.underlying.time_to_day_fraction(*date)?;
+                self.value(&black76, df, f, displacement, &|strike|
+                    vol.forward_variance(val_date, self.expiry_time, strike))?
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl AnalyticPriceable for DigitalOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The self price is Black76, so there is no closed form otherwise
+    fn analytic_price(&self, context: &PricingContext, model: &AnalyticModel,
+        val_date: DateTime) -> Result<Option<f64>, qm::Error> {
+        match *model {
+            AnalyticModel::Black => Ok(Some(self.price(context, val_date)?)),
+            AnalyticModel::Bachelier(_) => Ok(None)
+        }
+    }
+}
+
+impl MonteCarloPriceable for DigitalOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation, at expiry
+        output.observation(&self.underlying, self.expiry_time);
+
+        // far from the money, only the paths that end beyond the strike
+        // matter, so importance sampling should steer the paths there
+        output.importance_level(&self.underlying, self.expiry_time, self.strike);
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    /// Uses the same spread replication as the analytic valuation, so the
+    /// two are consistent and Monte-Carlo risks are similarly smoothed.
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        assert_eq!(shape[1], 1);
+        let ref path_column = paths.subview(Axis(1), 0);
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (spot, flow) in path_column.iter().zip(flow_column.iter_mut()) {
+                *flow = self.payoff(*spot, self.spread_width);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+
+    /// Only digitals valued as spreads have pathwise greeks. An exact
+    /// digital needs likelihood ratio greeks instead.
+    fn mc_adjoint_quantities<'t>(&self, context: &AdjointContext<'t>)
+        -> Result<Option<Vec<Var<'t>>>, qm::Error> {
+
+        if self.spread_width == 0.0 {
+            return Ok(None)
+        }
+        let path = context.path(&self.underlying)?;
+        assert_eq!(path.len(), 1);
+        Ok(Some(vec![self.adjoint_payoff(path[0])]))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_displaced_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::Pricer;
+    use risk::Bumpable;
+    use risk::marketdata::SavedData;
+    use data::bump::Bump;
+    use data::bumpspot::BumpSpot;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::PathGeneration;
+    use models::Threading;
+    use serde_json;
+
+    pub fn sample_digital(strike: f64, expiry: DateTime, put_or_call: PutOrCall,
+        payout: DigitalPayout, spread_width: f64) -> DigitalOption {
+        let equity = sample_underlying();
+        DigitalOption::new("SampleDigital", "OPT", equity, sample_settlement(2),
+            expiry, strike, put_or_call, payout, spread_width).unwrap()
+    }
+
+    #[test]
+    fn cash_digital_call_and_put_sum_to_bond() {
+        let market_data = sample_market_data();
+        let payout = DigitalPayout::CashOrNothing(10.0);
+        let call = sample_digital(100.0, sample_expiry(), PutOrCall::Call, payout, 0.0);
+        let put = sample_digital(100.0, sample_expiry(), PutOrCall::Put, payout, 0.0);
+        let call_price = call.price(&market_data, sample_val_date()).unwrap();
+        let put_price = put.price(&market_data, sample_val_date()).unwrap();
+
+        // the pair is worth the discounted amount, whatever the strike
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), sample_expiry(),
+            call.pay_date, sample_settlement(2));
+        let bond_price = bond.price(&market_data, sample_val_date()).unwrap();
+        assert_approx(call_price + put_price, 10.0 * bond_price, 1e-12);
+        assert!(call_price > 0.0 && put_price > 0.0);
+    }
+
+    #[test]
+    fn asset_digital_less_cash_digital_is_european() {
+        let market_data = sample_market_data();
+        let asset = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::AssetOrNothing, 0.0);
+        let cash = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::CashOrNothing(100.0), 0.0);
+        let asset_price = asset.price(&market_data, sample_val_date()).unwrap();
+        let cash_price = cash.price(&market_data, sample_val_date()).unwrap();
+
+        // matches the European in risk::marketdata::tests
+        assert_approx(asset_price - cash_price, 16.710717400832973, 1e-10);
+    }
+
+    #[test]
+    fn asset_digital_less_cash_digital_is_european_with_displaced_vol() {
+        let market_data = sample_displaced_market_data(10.0);
+        let european = sample_european().price(&market_data, sample_val_date()).unwrap();
+
+        // with the spread, the asset less the cash digital is the vanilla
+        // at the strike, so it should be very close to the exact digital
+        for &spread_width in [0.0, 0.01].iter() {
+            let asset = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+                DigitalPayout::AssetOrNothing, spread_width);
+            let cash = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+                DigitalPayout::CashOrNothing(100.0), spread_width);
+            let asset_price = asset.price(&market_data, sample_val_date()).unwrap();
+            let cash_price = cash.price(&market_data, sample_val_date()).unwrap();
+            assert_approx(asset_price - cash_price, european, 1e-6);
+        }
+    }
+
+    #[test]
+    fn narrow_spread_converges_to_exact_digital() {
+        let market_data = sample_market_data();
+        for payout in [DigitalPayout::CashOrNothing(1.0), DigitalPayout::AssetOrNothing].iter() {
+            for put_or_call in [PutOrCall::Call, PutOrCall::Put].iter() {
+                let exact = sample_digital(100.0, sample_expiry(), *put_or_call, *payout, 0.0);
+                let spread = exact.with_spread_width(0.01).unwrap();
+                let exact_price = exact.price(&market_data, sample_val_date()).unwrap();
+                let spread_price = spread.price(&market_data, sample_val_date()).unwrap();
+                assert_approx(spread_price, exact_price, 1e-4 * exact_price.max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn call_spread_stabilises_delta_near_expiry() {
+
+        // a digital close to expiry and close to the strike has a huge
+        // delta, which the call spread smooths out
+        let market_data = sample_market_data();
+        let expiry = DateTime::new(Date::from_ymd(2017, 01, 04), TimeOfDay::Close);
+        let exact = sample_digital(99.0, expiry, PutOrCall::Call,
+            DigitalPayout::CashOrNothing(1.0), 0.0);
+        let spread = exact.with_spread_width(5.0).unwrap();
+
+        let delta = |digital: &DigitalOption| {
+            let mut mut_data = market_data.clone();
+            let mut save = SavedData::new();
+            let unbumped = digital.price(&mut_data, sample_val_date()).unwrap();
+            let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+            assert!(mut_data.bump(&bump, Some(&mut save)).unwrap());
+            let bumped = digital.price(&mut_data, sample_val_date()).unwrap();
+            bumped - unbumped
+        };
+
+        // the change in the spread price for a one point move in spot can
+        // be no more than one point over the spread width
+        let spread_delta = delta(&spread);
+        let exact_delta = delta(&exact);
+        assert!(spread_delta > 0.0 && spread_delta < 0.2, "spread delta={}", spread_delta);
+        assert!(exact_delta > spread_delta, "exact delta={} spread delta={}",
+            exact_delta, spread_delta);
+    }
+
+    #[test]
+    fn fixing_pays_true_digital_payoff() {
+        let expiry = sample_expiry();
+        let digital = sample_digital(100.0, expiry, PutOrCall::Call,
+            DigitalPayout::CashOrNothing(10.0), 20.0);
+
+        // inside the spread, but the fixed payment is the full amount
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 101.0)])]).unwrap();
+        let fixed = digital.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 10.0, 1e-12);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+
+        // below the strike it pays nothing
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 99.0)])]).unwrap();
+        let fixed = digital.fix(&fixing_table).unwrap().unwrap();
+        assert!(fixed.is_empty());
+
+        // an asset digital pays the fixing
+        let asset = sample_digital(100.0, expiry, PutOrCall::Put,
+            DigitalPayout::AssetOrNothing, 0.0);
+        let fixed = asset.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 99.0, 1e-12);
+    }
+
+    #[test]
+    fn monte_carlo_matches_analytic() {
+        let market_data = sample_market_data();
+        let payout = 100.0;
+        let digital = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::CashOrNothing(payout), 5.0);
+        let analytic = digital.price(&market_data, sample_val_date()).unwrap();
+
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(digital))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let result = pricer.price_with_statistics(1).unwrap().unwrap();
+
+        // the paths are seeded, and the standard error is about 0.3% of the
+        // payout, so this allows for about three standard errors
+        assert!(result.standard_error() < 0.0035 * payout);
+        assert_approx(result.price(), analytic, 0.01 * payout);
+    }
+
+    #[test]
+    fn spread_has_pathwise_delta() {
+        let market_data = sample_market_data();
+        let spread = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::AssetOrNothing, 5.0);
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(spread))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 16383)));
+        let mut pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let greeks = pricer.adjoint_greeks().unwrap();
+        assert_approx(greeks.price(), pricer.price().unwrap(), 1e-10);
+
+        // central differences on the same paths, with a bump small enough
+        // that few paths cross the steep edges of the spread
+        let mut save = pricer.new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(1e-4));
+        pricer.bump(&bump, Some(&mut *save)).unwrap();
+        let up = pricer.price().unwrap();
+        pricer.restore(&*save).unwrap();
+        save.clear();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(-1e-4));
+        pricer.bump(&bump, Some(&mut *save)).unwrap();
+        let down = pricer.price().unwrap();
+        pricer.restore(&*save).unwrap();
+        assert_approx(greeks.deltas()["BP.L"], (up - down) / 0.02, 5e-3);
+
+        // an exact digital has no pathwise delta
+        let exact = sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::CashOrNothing(10.0), 0.0);
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(exact))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 1000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        assert!(pricer.adjoint_greeks().is_err());
+    }
+
+    #[test]
+    fn importance_sampling_prices_far_out_of_the_money() {
+        // Only about one path in three hundred ends above this strike, so
+        // plain Monte-Carlo with a few thousand paths is mostly noise. The
+        // fine substepping keeps the Euler steps of the model from thinning
+        // the tail it is sampling.
+        let mut market_data = sample_market_data();
+        let digital = sample_digital(250.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::CashOrNothing(100.0), 10.0);
+        let analytic = digital.price(&market_data, sample_val_date()).unwrap();
+
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(digital.clone()))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.001, 32767)));
+        let mut pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                importance_sampling: true, ..MonteCarloSettings::default() },
+            &market_data).unwrap();
+        let price = pricer.price().unwrap();
+        assert_approx(price, analytic, 0.01);
+
+        // the bumped paths keep their weights, so the delta of the spread
+        // is as smooth as the analytic one
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let mut save = SavedData::new();
+        assert!(market_data.bump(&bump, Some(&mut save)).unwrap());
+        let analytic_delta = digital.price(&market_data, sample_val_date()).unwrap()
+            - analytic;
+        let mut saved = pricer.new_saveable();
+        assert!(pricer.bump(&bump, Some(&mut *saved)).unwrap());
+        let delta = pricer.price().unwrap() - price;
+        assert_approx(delta, analytic_delta, 0.002);
+    }
+
+    #[test]
+    fn digital_tagged_serde() {
+        let market_data = sample_market_data();
+        let digital = sample_digital(110.0, sample_expiry(), PutOrCall::Put,
+            DigitalPayout::AssetOrNothing, 2.0);
+        let price = digital.price(&market_data, sample_val_date()).unwrap();
+
+        let instrument: Qrc<Instrument> = Qrc::new(Arc::new(digital));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: Qrc<Instrument> = serde_json::from_str(&serialized).unwrap();
+        let serde_price = deserialized.as_priceable().unwrap()
+            .price(&market_data, sample_val_date()).unwrap();
+        assert_approx(serde_price, price, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,646 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::swaps::PayOrReceive;
+use data::fixings::FixingTable;
+use data::divstream::dividend_fixing_id;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use ndarray::Array2;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The period over which the dividends of an underlier are counted by a
+/// dividend future or swap. Dividends count if they go ex after the start
+/// date, up to and including the end date. As time moves past ex dates, the
+/// amounts are fixed and added to the realized total.
+///
+/// Only the discrete dividends in the dividend stream are counted. Any
+/// continuous dividend yield is not.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DividendPeriod {
+    underlying: RcInstrument,
+    start: Date,
+    end: Date,
+    realized: f64,
+    fixed_until: Date
+}
+
+impl DividendPeriod {
+    /// Creates a period with no realized dividends. The end must be after
+    /// the start.
+    pub fn new(underlying: RcInstrument, start: Date, end: Date)
+        -> Result<DividendPeriod, qm::Error> {
+        if end <= start {
+            return Err(qm::Error::new("Dividend period must end after it starts"))
+        }
+        Ok(DividendPeriod { underlying: underlying, start: start, end: end,
+            realized: 0.0, fixed_until: start })
+    }
+
+    pub fn underlying(&self) -> &RcInstrument { &self.underlying }
+    pub fn start(&self) -> Date { self.start }
+    pub fn end(&self) -> Date { self.end }
+
+    /// The sum of the dividends that have already gone ex in the period
+    pub fn realized(&self) -> f64 { self.realized }
+
+    /// True once all the dividends in the period are known
+    pub fn is_complete(&self) -> bool { self.fixed_until >= self.end }
+
+    pub fn dependencies(&self, context: &mut DependencyContext) {
+        if !self.is_complete() {
+            context.forward_curve(&self.underlying, self.end);
+            context.dividends(&self.underlying, self.end);
+        }
+    }
+
+    /// The realized dividends, plus the dividends expected to go ex in the
+    /// rest of the period
+    pub fn expected(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        if self.is_complete() {
+            return Ok(self.realized)
+        }
+        let forward = context.forward_curve(&*self.underlying, self.end)?;
+        let from = self.start.max(self.fixed_until);
+        Ok(self.realized + forward.undiscounted_divs(from, self.end)?)
+    }
+
+    /// Returns a copy of the period with any newly known dividends added
+    /// to the realized total, or None if nothing has changed. Dividends are
+    /// known on the dates before the fixing table is known until.
+    pub fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<DividendPeriod>, qm::Error> {
+
+        let fixings = match fixing_table.get_fixings(
+            &dividend_fixing_id(self.underlying.id())) {
+            Some(fixings) => fixings,
+            None => return Ok(None)
+        };
+
+        let known = fixing_table.fixings_known_until() - 1;
+        let fixed_to = if known < self.end { known } else { self.end };
+        if fixed_to <= self.fixed_until {
+            return Ok(None)
+        }
+
+        let mut period = self.clone();
+        for (date, amount) in fixings.iter() {
+            let ex_date = date.date();
+            if ex_date > self.fixed_until && ex_date <= fixed_to {
+                period.realized += *amount;
+            }
+        }
+        period.fixed_until = fixed_to;
+        Ok(Some(period))
+    }
+}
+
+/// A listed future on the dividends of an equity or equity index over a
+/// period, usually a calendar year. Like an equity future, it has daily
+/// variation margin. Its value is the margin that would be due if it were
+/// settled now, which is the multiplier times the difference between the
+/// expected dividends and the last settlement price. The margin is not
+/// discounted, so the future has a mu of one per unit multiplier to the
+/// dividends in the period.
+///
+/// Once the last dividend in the period is known, the future is replaced by
+/// its final margin payment, paid on the settlement date of the underlying
+/// after the end of the period.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DividendFuture {
+    id: String,
+    period: DividendPeriod,
+    multiplier: f64,
+    settlement_price: f64
+}
+
+impl TypeId for DividendFuture {
+    fn type_id(&self) -> &'static str { "DividendFuture" }
+}
+
+impl InstanceId for DividendFuture {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl DividendFuture {
+    /// Creates a future. The multiplier is the value of the contract per
+    /// unit of dividends, and the settlement price is the price at which the
+    /// future was last margined, or the traded price if it has not yet been
+    /// margined.
+    pub fn new(id: &str, period: DividendPeriod, multiplier: f64,
+        settlement_price: f64) -> DividendFuture {
+        DividendFuture { id: id.to_string(), period: period,
+            multiplier: multiplier, settlement_price: settlement_price }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DividendFuture::deserialize(de)?)))
+    }
+
+    pub fn period(&self) -> &DividendPeriod { &self.period }
+    pub fn multiplier(&self) -> f64 { self.multiplier }
+    pub fn settlement_price(&self) -> f64 { self.settlement_price }
+
+    /// Returns a copy of this future, margined at the given settlement
+    /// price. If this is the current futures price, the copy is worth
+    /// nothing.
+    pub fn margined(&self, settlement_price: f64) -> DividendFuture {
+        let mut future = self.clone();
+        future.settlement_price = settlement_price;
+        future
+    }
+
+    /// The futures price, which is the expected dividends in the period
+    pub fn futures_price(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        self.period.expected(context)
+    }
+}
+
+impl Instrument for DividendFuture {
+
+    fn payoff_currency(&self) -> &Currency {
+        self.period.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.period.underlying.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.period.underlying.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        self.period.dependencies(context);
+        SpotRequirement::NotRequired
+    }
+
+    /// Newly known dividends are added to the period. Once the period is
+    /// complete, the future is replaced by its final margin payment.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let period = match self.period.fix(fixing_table)? {
+            Some(period) => period,
+            None => return Ok(None)
+        };
+
+        if period.is_complete() {
+            let margin = self.multiplier * (period.realized - self.settlement_price);
+            let end = period.end;
+            let pay_date = self.settlement().apply(end);
+            let payment = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                &format!("{}:margin", self.id), self.credit_id(),
+                RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                DateTime::new(end, TimeOfDay::Close), pay_date,
+                self.settlement().clone()))));
+            Ok(Some(vec![(margin, payment)]))
+        } else {
+            let mut future = self.clone();
+            future.period = period;
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(future))))]))
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for DividendFuture {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The variation margin that would be due if the future were settled at
+    /// the given date, which is not discounted.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let margin = self.multiplier * (self.futures_price(context)? - self.settlement_price);
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if date.date() <= self.period.end { margin } else { 0.0 };
+        }
+
+        Ok(())
+    }
+}
+
+/// An over-the-counter swap that exchanges the dividends of an underlier
+/// over a period for a fixed strike. The difference between the realized
+/// dividends and the strike, times the notional, is paid on the settlement
+/// date of the currency after the end of the period, and is discounted on
+/// the yield curve of the credit id. If pay_or_receive is Receive, the
+/// holder receives the dividends and pays the strike.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DividendSwap {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    pay_or_receive: PayOrReceive,
+    period: DividendPeriod,
+    strike: f64
+}
+
+impl TypeId for DividendSwap {
+    fn type_id(&self) -> &'static str { "DividendSwap" }
+}
+
+impl InstanceId for DividendSwap {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl DividendSwap {
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        pay_or_receive: PayOrReceive, period: DividendPeriod, strike: f64)
+        -> DividendSwap {
+        DividendSwap { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional,
+            pay_or_receive: pay_or_receive, period: period, strike: strike }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DividendSwap::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn pay_or_receive(&self) -> PayOrReceive { self.pay_or_receive }
+    pub fn period(&self) -> &DividendPeriod { &self.period }
+    pub fn strike(&self) -> f64 { self.strike }
+
+    /// The date the difference between the dividends and the strike is paid
+    pub fn pay_date(&self) -> Date {
+        self.settlement().apply(self.period.end)
+    }
+
+    fn signed_notional(&self) -> f64 {
+        match self.pay_or_receive {
+            PayOrReceive::Receive => self.notional,
+            PayOrReceive::Pay => -self.notional
+        }
+    }
+
+    fn payment(&self) -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment", self.id), &self.credit_id, self.currency.clone(),
+            DateTime::new(self.period.end, TimeOfDay::Close), self.pay_date(),
+            self.settlement().clone()))))
+    }
+}
+
+impl Instrument for DividendSwap {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.currency.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.pay_date());
+        self.period.dependencies(context);
+
+        // Monte-Carlo models of the dividends also evolve the underlying
+        if !self.period.is_complete() {
+            context.vol_surface(&self.period.underlying, self.period.end);
+        }
+        SpotRequirement::NotRequired
+    }
+
+    /// Newly known dividends are added to the period. Once the period is
+    /// complete, the swap is replaced by its payment.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let period = match self.period.fix(fixing_table)? {
+            Some(period) => period,
+            None => return Ok(None)
+        };
+
+        if period.is_complete() {
+            let amount = self.signed_notional() * (period.realized - self.strike);
+            Ok(Some(vec![(amount, self.payment())]))
+        } else {
+            let mut swap = self.clone();
+            swap.period = period;
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))]))
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+}
+
+impl MonteCarloPriceable for DividendSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The underlying is observed at the end of the period, so that models
+    /// with stochastic dividends simulate all the dividends in it. There is
+    /// a single flow, on the pay date.
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        if !self.period.is_complete() {
+            let underlying = &self.period.underlying;
+            let end = DateTime::new(self.period.end, TimeOfDay::Close);
+            output.observation(underlying, underlying.time_to_day_fraction(end)?);
+        }
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    /// If the model has stochastic dividends, the payment on each path is
+    /// from the dividends on that path. Otherwise, it is the payment
+    /// expected by the forward curve, as for Priceable.
+    fn mc_price(&self, context: &MonteCarloContext) -> Result<f64, qm::Error> {
+
+        // a complete period has a known payment, so we do not need paths
+        let pricing_context = context.pricing_context();
+        if self.period.is_complete() {
+            let val_date = DateTime::new(pricing_context.spot_date(), TimeOfDay::Open);
+            return self.price(pricing_context, val_date)
+        }
+
+        let underlying = &self.period.underlying;
+        let n_paths = context.paths(underlying)?.shape()[0];
+        let mut quantities = Array2::zeros((n_paths, 1));
+
+        if let Some((ex_dates, amounts)) = context.dividends(underlying)? {
+            assert_eq!(amounts.shape()[0], n_paths);
+            let from = self.period.start.max(self.period.fixed_until);
+            for (path, mut quantity) in amounts.outer_iter()
+                .zip(quantities.outer_iter_mut()) {
+                let mut total = self.period.realized;
+                for (ex_date, amount) in ex_dates.iter().zip(path.iter()) {
+                    if *ex_date > from && *ex_date <= self.period.end {
+                        total += *amount;
+                    }
+                }
+                quantity[0] = self.signed_notional() * (total - self.strike);
+            }
+        } else {
+            let expected = self.period.expected(pricing_context)?;
+            quantities.fill(self.signed_notional() * (expected - self.strike));
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+impl Priceable for DividendSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The expected payment, discounted to the settlement date of the
+    /// currency. The swap is worth nothing once the payment has been made.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let pay_date = self.pay_date();
+        let yc = context.yield_curve(&self.credit_id, pay_date)?;
+        let amount = self.signed_notional() * (self.period.expected(context)? - self.strike);
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let discount_date = self.settlement().apply(date.date());
+            *output = if discount_date <= pay_date {
+                amount * yc.df(pay_date, discount_date)?
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use risk::Bumpable;
+    use data::bump::Bump;
+    use data::bumpdivs::BumpDivs;
+    use data::bumpspotdate::SpotDynamics;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use risk::Pricer;
+    use serde_json;
+
+    /// A period covering the 2017 dividends of BP.L in the sample market
+    /// data, which are a cash dividend of 1.2 and a mixed one of 0.8 plus
+    /// a fifth of a percent of the forward.
+    pub fn sample_dividend_period() -> DividendPeriod {
+        let equity = sample_underlying();
+        DividendPeriod::new(equity, Date::from_ymd(2016, 12, 31),
+            Date::from_ymd(2017, 12, 31)).unwrap()
+    }
+
+    pub fn sample_dividend_future(settlement_price: f64) -> DividendFuture {
+        DividendFuture::new("BP.L.DIV.DEC17", sample_dividend_period(), 10.0,
+            settlement_price)
+    }
+
+    pub fn sample_dividend_swap(strike: f64) -> DividendSwap {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        DividendSwap::new("BP.L.DIVSWAP", "OPT", currency, 1000.0,
+            PayOrReceive::Receive, sample_dividend_period(), strike)
+    }
+
+    #[test]
+    fn dividend_future_price_is_expected_dividends() {
+        let market_data = sample_market_data();
+        let future = sample_dividend_future(0.0);
+        let expected = future.futures_price(&market_data).unwrap();
+        assert!(expected > 2.1 && expected < 2.3, "expected={}", expected);
+
+        let price = future.price(&market_data, sample_val_date()).unwrap();
+        assert_approx(price, 10.0 * expected, 1e-12);
+
+        let margined = future.margined(expected);
+        assert_approx(margined.price(&market_data, sample_val_date()).unwrap(),
+            0.0, 1e-12);
+    }
+
+    #[test]
+    fn dividend_future_moves_with_dividends() {
+        let mut market_data = sample_market_data();
+        let future = sample_dividend_future(2.0);
+        let expected = future.futures_price(&market_data).unwrap();
+        let price = future.price(&market_data, sample_val_date()).unwrap();
+
+        let bump = Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.01));
+        assert!(market_data.bump(&bump, None).unwrap());
+        let bumped = future.price(&market_data, sample_val_date()).unwrap();
+
+        // relative dividends move slightly less than the bump, because the
+        // forward falls as the cash dividends rise
+        let change = bumped - price;
+        assert!(change > 0.09 * expected && change <= 0.1 * expected,
+            "change={} expected={}", change, expected);
+    }
+
+    #[test]
+    fn dividend_period_fixes_realized_dividends() {
+        let period = sample_dividend_period();
+        let ex_date = DateTime::new(Date::from_ymd(2017, 01, 30), TimeOfDay::Open);
+        let fixing_id = dividend_fixing_id("BP.L");
+
+        // no dividend fixings, nothing changes
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 02, 01),
+            &[("BP.L", &[])]).unwrap();
+        assert!(period.fix(&fixing_table).unwrap().is_none());
+
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 02, 01),
+            &[(&fixing_id, &[(ex_date, 1.25)])]).unwrap();
+        let fixed = period.fix(&fixing_table).unwrap().unwrap();
+        assert_approx(fixed.realized(), 1.25, 1e-12);
+        assert!(!fixed.is_complete());
+
+        // fixing again with the same table changes nothing
+        assert!(fixed.fix(&fixing_table).unwrap().is_none());
+
+        // once the period is over, the future becomes its final margin
+        let future = DividendFuture::new("F", fixed, 10.0, 2.0);
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 01, 02),
+            &[(&fixing_id, &[(ex_date, 1.25),
+                (DateTime::new(Date::from_ymd(2017, 07, 31), TimeOfDay::Open), 1.0)])]).unwrap();
+        let settled = future.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(settled.len(), 1);
+        assert_approx(settled[0].0, 10.0 * (2.25 - 2.0), 1e-12);
+        assert_eq!(settled[0].1.type_id(), "ZeroCoupon");
+    }
+
+    #[test]
+    fn dividend_future_theta_realizes_dividends() {
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let future = RcInstrument::new(Qrc::new(Arc::new(sample_dividend_future(2.0))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&future);
+
+        // move past the first dividend, which goes ex on 30 January
+        let mut instruments = vec![(1.0, future.clone())];
+        let bump = BumpTime::new(spot_date + 35, spot_date, SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        let aged = instruments[0].1.clone();
+
+        // the dividend is now realized rather than expected, so with the
+        // market unchanged the price does not move
+        let val_date = sample_val_date();
+        let price = future.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        let aged_price = aged.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(aged_price, price, 1e-9);
+
+        // but the realized cash dividend of 1.2 no longer moves with the
+        // dividend stream
+        let mut bumped_market_data = market_data.clone();
+        let bump = Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.01));
+        assert!(bumped_market_data.bump(&bump, None).unwrap());
+        let change = future.as_priceable().unwrap().price(&bumped_market_data, val_date).unwrap() - price;
+        let aged_change = aged.as_priceable().unwrap().price(&bumped_market_data, val_date).unwrap() - aged_price;
+        assert_approx(change - aged_change, 10.0 * 0.012, 1e-9);
+    }
+
+    #[test]
+    fn dividend_swap_discounts_payment() {
+        let market_data = sample_market_data();
+        let swap = sample_dividend_swap(2.0);
+        let expected = swap.period().expected(&market_data).unwrap();
+        let price = swap.price(&market_data, sample_val_date()).unwrap();
+        let undiscounted = 1000.0 * (expected - 2.0);
+        assert!(price > 0.9 * undiscounted && price < undiscounted,
+            "price={} undiscounted={}", price, undiscounted);
+
+        let pay = DividendSwap::new("P", "OPT", RcCurrency::new(Arc::new(sample_currency(2))),
+            1000.0, PayOrReceive::Pay, sample_dividend_period(), 2.0);
+        assert_approx(pay.price(&market_data, sample_val_date()).unwrap(), -price, 1e-12);
+    }
+
+    #[test]
+    fn dividend_swap_dependencies() {
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let swap = RcInstrument::new(Qrc::new(Arc::new(sample_dividend_swap(2.0))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&swap);
+
+        let equity = dependencies.instrument_by_id("BP.L").unwrap().clone();
+        assert_eq!(dependencies.forward_curve_hwm(&equity), Some(Date::from_ymd(2017, 12, 31)));
+        assert_eq!(dependencies.dividends().get("BP.L"), Some(&Date::from_ymd(2017, 12, 31)));
+        assert!(dependencies.yield_curve_hwm("OPT").is_some());
+        assert_eq!(dependencies.vol_surface_hwm(&equity), Some(Date::from_ymd(2017, 12, 31)));
+    }
+
+    #[test]
+    fn dividend_swap_monte_carlo_uses_expected_dividends() {
+        // a model with deterministic dividends pays the expected dividends
+        // on every path, so there is no Monte-Carlo noise
+        let market_data = sample_market_data();
+        let swap = sample_dividend_swap(2.0);
+        let expected = swap.price(&market_data, sample_val_date()).unwrap();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 1000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        assert_approx(pricer.price().unwrap(), expected, 1e-9);
+    }
+
+    #[test]
+    fn dividend_swap_tagged_serde() {
+        let market_data = sample_market_data();
+        let swap = sample_dividend_swap(2.0);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(swap.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap()
+            .price(&market_data, sample_val_date()).unwrap();
+        assert_approx(price, swap.price(&market_data, sample_val_date()).unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,456 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An exchange option gives the holder the right, at expiry, to give up one
+/// basket of assets in return for another. It pays max(R - D, 0), where R is
+/// the weighted sum of the assets received and D of those delivered. Each
+/// basket is a list of weights and assets, which must all pay in the same
+/// currency.
+///
+/// Where each side is a single asset, the option is valued analytically by
+/// Margrabe's formula, which is exact under Black assumptions and does not
+/// depend on interest rates other than for discounting. Otherwise, or if
+/// preferred, it is valued by Monte-Carlo. Either way, the value depends on
+/// the correlations between the assets, so bumping them gives the
+/// correlation sensitivity.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ExchangeOption {
+    id: String,
+    credit_id: String,
+    receive: Vec<(f64, RcInstrument)>,
+    deliver: Vec<(f64, RcInstrument)>,
+    settlement: RcDateRule,
+    expiry: DateTime,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for ExchangeOption {
+    fn type_id(&self) -> &'static str { "ExchangeOption" }
+}
+
+impl ExchangeOption {
+    /// Creates an option to receive one basket in exchange for delivering
+    /// another. Both baskets must be non-empty with positive weights.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        receive: Vec<(f64, RcInstrument)>,
+        deliver: Vec<(f64, RcInstrument)>,
+        settlement: RcDateRule,
+        expiry: DateTime) -> Result<ExchangeOption, qm::Error> {
+
+        if receive.is_empty() || deliver.is_empty() {
+            return Err(qm::Error::new(
+                "Exchange option must receive and deliver at least one asset"))
+        }
+        let currency = receive[0].1.payoff_currency().id().to_string();
+        for &(weight, ref asset) in receive.iter().chain(deliver.iter()) {
+            if !(weight > 0.0) {
+                return Err(qm::Error::new(
+                    "Exchange option weights must be positive"))
+            }
+            if asset.payoff_currency().id() != currency {
+                return Err(qm::Error::new(
+                    "Exchange option assets must pay in the same currency"))
+            }
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = receive[0].1.time_to_day_fraction(expiry)?;
+        Ok(ExchangeOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            receive: receive,
+            deliver: deliver,
+            settlement: settlement,
+            expiry: expiry,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ExchangeOption::deserialize(de)?)))
+    }
+
+    pub fn receive(&self) -> &[(f64, RcInstrument)] { &self.receive }
+    pub fn deliver(&self) -> &[(f64, RcInstrument)] { &self.deliver }
+
+    /// True if each side is a single asset, so the option can be valued by
+    /// Margrabe's formula
+    pub fn is_analytic(&self) -> bool {
+        self.receive.len() == 1 && self.deliver.len() == 1
+    }
+
+    /// The distinct assets on either side
+    fn underlyings(&self) -> Vec<RcInstrument> {
+        let mut underlyings: Vec<RcInstrument> = Vec::new();
+        for &(_, ref asset) in self.receive.iter().chain(self.deliver.iter()) {
+            if underlyings.iter().all(|u| u.id() != asset.id()) {
+                underlyings.push(asset.clone());
+            }
+        }
+        underlyings
+    }
+
+    /// The payoff given a function that returns the level of each asset
+    fn payoff(&self, level: &Fn(&RcInstrument) -> Result<f64, qm::Error>)
+        -> Result<f64, qm::Error> {
+        let mut value = 0.0;
+        for &(weight, ref asset) in self.receive.iter() {
+            value += weight * level(asset)?;
+        }
+        for &(weight, ref asset) in self.deliver.iter() {
+            value -= weight * level(asset)?;
+        }
+        Ok(value.max(0.0))
+    }
+}
+
+impl InstanceId for ExchangeOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for ExchangeOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.receive[0].1.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let expiry_date = self.expiry.date();
+        for underlying in self.underlyings().iter() {
+            context.fixing(underlying.id(), self.expiry);
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(&self.credit_id, self.pay_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        if self.is_analytic() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Once all the assets have fixed at expiry, the option turns into a
+    /// cash payment.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        for underlying in self.underlyings().iter() {
+            if fixing_table.get(underlying.id(), self.expiry)?.is_none() {
+                return Ok(None)
+            }
+        }
+
+        let payment = self.payoff(&|asset| fixing_table.get(asset.id(), self.expiry)?
+            .ok_or_else(|| qm::Error::new("Missing exchange option fixing")))?;
+        let mut decomp = Vec::new();
+        if payment > 0.0 {
+            decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                &format!("{}:payment", self.id), &self.credit_id,
+                RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                self.expiry, self.pay_date, self.settlement.clone()))))));
+        }
+        Ok(Some(decomp))
+    }
+}
+
+impl Priceable for ExchangeOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the exchange of single assets using Margrabe's formula. This
+    /// is a Black76 call on the forward of what is received, struck at the
+    /// forward of what is delivered, with the vol of their ratio.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+        if !self.is_analytic() {
+            return Err(qm::Error::new("Exchange options on baskets must be \
+                valued by Monte-Carlo"))
+        }
+
+        let (q1, ref receive) = self.receive[0];
+        let (q2, ref deliver) = self.deliver[0];
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let receive_fwd = context.forward_curve(&**receive, expiry_date)?;
+        let deliver_fwd = context.forward_curve(&**deliver, expiry_date)?;
+        let receive_vol = context.vol_surface(&**receive, expiry_date,
+            &|| Ok(receive_fwd.clone()))?;
+        let deliver_vol = context.vol_surface(&**deliver, expiry_date,
+            &|| Ok(deliver_fwd.clone()))?;
+        let correlation = context.correlation(&**receive, &**deliver)?;
+
+        let f1 = receive_fwd.forward(expiry_date)?;
+        let f2 = deliver_fwd.forward(expiry_date)?;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                let val_date = receive.time_to_day_fraction(*date)?;
+                let v1 = receive_vol.forward_variance(val_date, self.expiry_time, f1)?;
+                let v2 = deliver_vol.forward_variance(val_date, self.expiry_time, f2)?;
+                let variance = v1 + v2 - 2.0 * correlation * (v1 * v2).sqrt();
+                black76.call_price(df, q1 * f1, q2 * f2, variance.max(0.0).sqrt())
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl MonteCarloPriceable for ExchangeOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation of each asset, at expiry
+        for underlying in self.underlyings().iter() {
+            output.observation(underlying, self.expiry_time);
+        }
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let underlyings = self.underlyings();
+        let mut paths = Vec::with_capacity(underlyings.len());
+        for underlying in underlyings.iter() {
+            let path = context.paths(underlying)?;
+            assert_eq!(path.shape()[1], 1);
+            paths.push(path);
+        }
+        let n_paths = paths[0].shape()[0];
+        let index = |asset: &RcInstrument| underlyings.iter()
+            .position(|u| u.id() == asset.id()).unwrap();
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        for (path, flow) in quantities.subview_mut(Axis(1), 0).iter_mut().enumerate() {
+            *flow = self.payoff(&|asset| Ok(paths[index(asset)][(path, 0)]))?;
+        }
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::assets::Equity;
+    use instruments::spreads::SpreadOption;
+    use instruments::options::PutOrCall;
+    use risk::Pricer;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_margrabe;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use data::bump::Bump;
+    use data::bumpcorrelation::BumpCorrelation;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use pricers::selfpricer::SelfPricer;
+    use serde_json;
+
+    fn sample_assets() -> (RcInstrument, RcInstrument) {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = |id| RcInstrument::new(Qrc::new(Arc::new(
+            Equity::new(id, "LSE", currency.clone(), sample_settlement(2)))));
+        (equity("BP.L"), equity("GSK.L"))
+    }
+
+    fn sample_exchange(receive: Vec<(f64, RcInstrument)>,
+        deliver: Vec<(f64, RcInstrument)>) -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(ExchangeOption::new("SampleExchange",
+            "OPT", receive, deliver, sample_settlement(2), sample_expiry()).unwrap())))
+    }
+
+    fn mc_pricer(instrument: RcInstrument, market_data: &MarketData) -> MonteCarloPricer {
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        MonteCarloPricer::with_settings(vec![(1.0, instrument)], factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, market_data).unwrap()
+    }
+
+    #[test]
+    fn margrabe_matches_zero_strike_spread() {
+        let market_data = sample_correlated_market_data(0.5);
+        let (bp, gsk) = sample_assets();
+        let exchange = sample_exchange(vec![(1.0, gsk.clone())], vec![(1.0, bp.clone())]);
+        let spread = SpreadOption::new("SampleSpread", "OPT", gsk, bp,
+            sample_settlement(2), sample_expiry(), 0.0, PutOrCall::Call, false).unwrap();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let exchange_price = exchange.as_priceable().unwrap()
+            .price(&market_data, val_date).unwrap();
+        let spread_price = spread.price(&market_data, val_date).unwrap();
+        assert!(exchange_price > 0.0);
+        assert_approx(exchange_price, spread_price, 1e-12);
+    }
+
+    #[test]
+    fn margrabe_matches_monte_carlo() {
+        let market_data = sample_correlated_market_data(0.5);
+        let analytic = SelfPricer::new(vec![(1.0, sample_margrabe())], &market_data)
+            .unwrap().price().unwrap();
+        let mc = mc_pricer(sample_margrabe(), &market_data).price().unwrap();
+        assert_approx(mc, analytic, 0.03 * analytic);
+    }
+
+    #[test]
+    fn exchange_of_baskets_by_monte_carlo() {
+        let market_data = sample_correlated_market_data(0.5);
+        let (bp, gsk) = sample_assets();
+
+        // receiving BP.L and GSK.L for GSK.L is the same as receiving BP.L
+        let exchange = sample_exchange(vec![(1.0, bp.clone()), (1.0, gsk.clone())],
+            vec![(1.0, gsk)]);
+        assert!(exchange.as_priceable().is_none());
+        let mc = mc_pricer(exchange, &market_data).price().unwrap();
+
+        let forward = market_data.forward_curve(&*bp, sample_expiry().date()).unwrap()
+            .forward(sample_expiry().date()).unwrap();
+        let yc = market_data.yield_curve("OPT", Date::from_ymd(2018, 06, 05)).unwrap();
+        let df = (yc.rt(Date::from_ymd(2017, 01, 04)).unwrap()
+            - yc.rt(Date::from_ymd(2018, 06, 05)).unwrap()).exp();
+        assert_approx(mc, forward * df, 0.02 * forward);
+    }
+
+    #[test]
+    fn exchange_correlation_sensitivity() {
+        let market_data = sample_correlated_market_data(0.5);
+        let bump = Bump::new_correlation("GSK.L", "BP.L", BumpCorrelation::new_additive(0.1));
+        let expected = SelfPricer::new(vec![(1.0, sample_margrabe())],
+            &sample_correlated_market_data(0.6)).unwrap().price().unwrap();
+
+        // analytic: the bumped price matches a correlation of 0.6, and
+        // restoring gets back the original
+        let mut pricer = SelfPricer::new(vec![(1.0, sample_margrabe())], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+        assert_approx(bumped, expected, 1e-12);
+        assert!(bumped < unbumped, "bumped={} unbumped={}", bumped, unbumped);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // Monte-Carlo: the same random numbers are used after the bump, so
+        // the change in price matches closely
+        let mut pricer = mc_pricer(sample_margrabe(), &market_data);
+        let mc_unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let mc_bumped = pricer.price().unwrap();
+        assert_approx(mc_bumped - mc_unbumped, bumped - unbumped,
+            0.1 * (bumped - unbumped).abs());
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_approx(pricer.price().unwrap(), mc_unbumped, 1e-12);
+
+        // bumping a correlation that is not supplied does nothing
+        let missing = Bump::new_correlation("BP.L", "VOD.L", BumpCorrelation::new_additive(0.1));
+        assert!(!pricer.as_mut_bumpable().bump(&missing, None).unwrap());
+    }
+
+    #[test]
+    fn exchange_fixes_to_payment() {
+        let exchange = sample_margrabe();
+        let expiry = sample_expiry();
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 130.0)]), ("GSK.L", &[(expiry, 220.0)])]).unwrap();
+        let decomp = exchange.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 40.0, 1e-12);
+    }
+
+    #[test]
+    fn exchange_tagged_serde() {
+        let serialized = serde_json::to_string_pretty(&sample_margrabe()).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.id(), "SampleExchange");
+        assert!(deserialized.as_priceable().is_some());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
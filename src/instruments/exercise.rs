@@ -0,0 +1,139 @@
+use instruments::DependencyContext;
+use dates::Date;
+use dates::datetime::DateTime;
+use core::qm;
+
+/// The dates and times on which the holder of a Bermudan right may
+/// exercise it, in increasing order. The same schedule is used by equity
+/// Bermudan options, Bermudan swaptions and callable bonds.
+///
+/// Exercise dates are registered as dependencies, so that when time moves
+/// past one of them, the instrument is asked to make its decision. Pricers
+/// that roll back through a lattice or tree use the schedule to decide at
+/// which steps exercise is allowed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExerciseSchedule {
+    dates: Vec<DateTime>
+}
+
+impl ExerciseSchedule {
+    /// Creates a schedule from the given exercise dates, which may be in any
+    /// order. Duplicates are removed. There must be at least one date.
+    pub fn new(dates: &[DateTime]) -> Result<ExerciseSchedule, qm::Error> {
+        if dates.is_empty() {
+            return Err(qm::Error::new("Exercise schedule must have at least one date"))
+        }
+        let mut dates = dates.to_vec();
+        dates.sort();
+        dates.dedup();
+        Ok(ExerciseSchedule { dates: dates })
+    }
+
+    pub fn dates(&self) -> &[DateTime] { &self.dates }
+
+    /// The first exercise opportunity
+    pub fn first(&self) -> DateTime { self.dates[0] }
+
+    /// The last exercise opportunity, which is the expiry of the right
+    pub fn last(&self) -> DateTime { self.dates[self.dates.len() - 1] }
+
+    /// Returns true if the holder may exercise at exactly the given time
+    pub fn contains(&self, date: DateTime) -> bool {
+        self.dates.binary_search(&date).is_ok()
+    }
+
+    /// The exercise dates strictly after the given date and time
+    pub fn after(&self, date: DateTime) -> &[DateTime] {
+        let first = match self.dates.binary_search(&date) {
+            Ok(i) => i + 1,
+            Err(i) => i
+        };
+        &self.dates[first..]
+    }
+
+    /// The schedule that remains once time has moved past the given date
+    /// and time, or None if there are no exercise opportunities left.
+    pub fn remaining_after(&self, date: DateTime) -> Option<ExerciseSchedule> {
+        let remaining = self.after(date);
+        if remaining.is_empty() {
+            None
+        } else {
+            Some(ExerciseSchedule { dates: remaining.to_vec() })
+        }
+    }
+
+    /// Registers the exercise dates on or after the spot date as decisions
+    /// to be made by the instrument with the given id.
+    pub fn dependencies(&self, id: &str, context: &mut DependencyContext) {
+        let spot_date = context.spot_date();
+        for date in self.dates.iter().filter(|d| d.date() >= spot_date) {
+            context.exercise(id, *date);
+        }
+    }
+
+    /// Maps the exercise dates strictly after the given start onto the steps
+    /// of a tree or lattice, given a function that finds the nearest step to
+    /// a date. Returns a flag for each of the given number of steps plus one.
+    pub fn steps(&self, after: DateTime, steps: usize, step_of: &Fn(Date) -> usize)
+        -> Vec<bool> {
+        let mut exercisable = vec![false; steps + 1];
+        for date in self.after(after).iter() {
+            exercisable[step_of(date.date()).min(steps)] = true;
+        }
+        exercisable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dates::datetime::TimeOfDay;
+    use risk::dependencies::DependencyCollector;
+
+    fn close(year: i32, month: i32, day: i32) -> DateTime {
+        DateTime::new(Date::from_ymd(year, month, day), TimeOfDay::Close)
+    }
+
+    #[test]
+    fn schedule_is_sorted_and_unique() {
+        let schedule = ExerciseSchedule::new(&[close(2018, 06, 01),
+            close(2017, 06, 01), close(2018, 06, 01)]).unwrap();
+        assert_eq!(schedule.dates(), &[close(2017, 06, 01), close(2018, 06, 01)]);
+        assert_eq!(schedule.first(), close(2017, 06, 01));
+        assert_eq!(schedule.last(), close(2018, 06, 01));
+        assert!(schedule.contains(close(2018, 06, 01)));
+        assert!(!schedule.contains(close(2018, 06, 02)));
+        assert!(ExerciseSchedule::new(&[]).is_err());
+    }
+
+    #[test]
+    fn schedule_remaining_after() {
+        let schedule = ExerciseSchedule::new(&[close(2017, 06, 01),
+            close(2017, 12, 01), close(2018, 06, 01)]).unwrap();
+        assert_eq!(schedule.after(close(2017, 06, 01)).len(), 2);
+        assert_eq!(schedule.after(close(2017, 01, 01)).len(), 3);
+        assert_eq!(schedule.remaining_after(close(2017, 12, 01)).unwrap().dates(),
+            &[close(2018, 06, 01)]);
+        assert!(schedule.remaining_after(close(2018, 06, 01)).is_none());
+    }
+
+    #[test]
+    fn schedule_dependencies_skip_past_dates() {
+        let schedule = ExerciseSchedule::new(&[close(2016, 06, 01),
+            close(2017, 06, 01), close(2018, 06, 01)]).unwrap();
+        let mut dependencies = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        schedule.dependencies("BERMUDAN", &mut dependencies);
+        assert_eq!(dependencies.exercises("BERMUDAN"),
+            &[close(2017, 06, 01), close(2018, 06, 01)]);
+    }
+
+    #[test]
+    fn schedule_steps() {
+        let start = DateTime::new(Date::from_ymd(2017, 01, 01), TimeOfDay::Open);
+        let schedule = ExerciseSchedule::new(&[close(2017, 01, 11),
+            close(2017, 01, 21)]).unwrap();
+        let step_of = |date: Date| ((date - start.date()) / 10) as usize;
+        let steps = schedule.steps(start, 2, &step_of);
+        assert_eq!(steps, vec![false, true, true]);
+    }
+}
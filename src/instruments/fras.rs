@@ -0,0 +1,391 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::swaps::AccrualPeriod;
+use instruments::swaps::PayOrReceive;
+use data::fixings::FixingTable;
+use data::fixings::RateFixing;
+use dates::Date;
+use dates::rules::DateRule;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use dates::daycount::DayCount;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A forward rate agreement fixes a floating index, such as a Libor rate,
+/// for a single future accrual period, and exchanges it for a fixed rate on
+/// a notional. Following market convention, the rate fixes some business
+/// days before the start of the period, and the difference is settled at
+/// the start of the period rather than the end, discounted from the end at
+/// the floating rate:
+///
+/// notional * tau * (L - K) / (1 + tau * L)
+///
+/// The holder of a payer FRA pays the fixed rate and receives the floating
+/// index, so it gains when rates rise. The payment is discounted on the
+/// yield curve of the credit id, and the index is projected from its own
+/// forecast curve until it fixes.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ForwardRateAgreement {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    pay_or_receive: PayOrReceive,
+    rate: f64,
+    accrual: AccrualPeriod,
+    day_count: DayCount,
+    fixing_date: DateTime,
+    index_id: String,
+    forecast_id: String,
+    fixing: Option<f64>
+}
+
+impl TypeId for ForwardRateAgreement {
+    fn type_id(&self) -> &'static str { "ForwardRateAgreement" }
+}
+
+impl ForwardRateAgreement {
+    /// Creates an FRA on the period from start to end, paying or receiving
+    /// the given fixed rate. The index fixes at the close on the date given
+    /// by applying the fixing rule to the start date, for example two
+    /// business days before, and the settlement amount is paid on the start
+    /// date.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        pay_or_receive: PayOrReceive, rate: f64, start: Date, end: Date,
+        day_count: DayCount, index_id: &str, forecast_id: &str,
+        fixing_rule: &DateRule) -> Result<ForwardRateAgreement, qm::Error> {
+
+        if end <= start {
+            return Err(qm::Error::new("FRA must end after it starts"))
+        }
+
+        let fixing_date = DateTime::new(fixing_rule.apply(start), TimeOfDay::Close);
+        if fixing_date.date() > start {
+            return Err(qm::Error::new("FRA must not fix after it starts"))
+        }
+
+        Ok(ForwardRateAgreement { id: id.to_string(),
+            credit_id: credit_id.to_string(), currency: currency,
+            notional: notional, pay_or_receive: pay_or_receive, rate: rate,
+            accrual: AccrualPeriod::new(start, end, start),
+            day_count: day_count, fixing_date: fixing_date,
+            index_id: index_id.to_string(), forecast_id: forecast_id.to_string(),
+            fixing: None })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ForwardRateAgreement::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn pay_or_receive(&self) -> PayOrReceive { self.pay_or_receive }
+    pub fn rate(&self) -> f64 { self.rate }
+    pub fn accrual(&self) -> &AccrualPeriod { &self.accrual }
+    pub fn day_count(&self) -> DayCount { self.day_count }
+    pub fn fixing_date(&self) -> DateTime { self.fixing_date }
+    pub fn index_id(&self) -> &str { &self.index_id }
+    pub fn forecast_id(&self) -> &str { &self.forecast_id }
+    pub fn fixing(&self) -> Option<f64> { self.fixing }
+
+    /// Describes the fixing of the index, including how to project it
+    pub fn rate_fixing(&self) -> RateFixing {
+        RateFixing::new(self.fixing_date, &self.forecast_id,
+            self.accrual.start(), self.accrual.end(), self.day_count)
+    }
+
+    /// The fixed rate which would give this FRA zero value. This is the
+    /// fixing if known, otherwise the forward rate projected from the
+    /// forecast curve.
+    pub fn par_rate(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        match self.fixing {
+            Some(fixing) => Ok(fixing),
+            None => {
+                let forecast = context.yield_curve(&self.forecast_id,
+                    self.accrual.end())?;
+                self.rate_fixing().forward(&*forecast)
+            }
+        }
+    }
+}
+
+impl InstanceId for ForwardRateAgreement {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for ForwardRateAgreement {
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.currency.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.accrual.payment());
+        if self.fixing.is_none() {
+            context.yield_curve(&self.forecast_id, self.accrual.end());
+            context.rate_fixing(&self.index_id, self.rate_fixing());
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if self.fixing.is_some() {
+            return Ok(None)
+        }
+
+        match fixing_table.get(&self.index_id, self.fixing_date)? {
+            None => Ok(None),
+            Some(fixing) => {
+                let mut fra = self.clone();
+                fra.fixing = Some(fixing);
+                Ok(Some(vec![(1.0, RcInstrument::new(
+                    Qrc::new(Arc::new(fra))))]))
+            }
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for ForwardRateAgreement {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The settlement amount is discounted from the start of the period to
+    /// the settlement date of the currency. Once the settlement amount has
+    /// been paid, the FRA is worth nothing.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.accrual.payment())?;
+        let floating = self.par_rate(context)?;
+        let year_fraction = self.day_count.year_fraction(
+            self.accrual.start(), self.accrual.end());
+        let settlement_amount = year_fraction * (floating - self.rate)
+            / (1.0 + year_fraction * floating);
+        let amount = self.notional * match self.pay_or_receive {
+            PayOrReceive::Pay => settlement_amount,
+            PayOrReceive::Receive => -settlement_amount
+        };
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if self.accrual.payment() > date.date() {
+                let discount_date = self.settlement().apply(date.date());
+                amount * yc.df(self.accrual.payment(), discount_date)?
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use risk::Pricer;
+    use data::bump::Bump;
+    use pricers::selfpricer::SelfPricer;
+    use data::bumpyield::BumpYield;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::BusinessDays;
+    use serde_json;
+
+    fn sample_fra(pay_or_receive: PayOrReceive, rate: f64, start: Date)
+        -> ForwardRateAgreement {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let fixing_rule = BusinessDays::new_back(calendar, 2);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        ForwardRateAgreement::new("SampleFRA", "OPT", currency, 1000000.0,
+            pay_or_receive, rate, start, start + 182, DayCount::Act360,
+            "GBPLIBOR6M", "LSE", &fixing_rule).unwrap()
+    }
+
+    fn sample_start() -> Date {
+        // a Wednesday, so the fixing is on the Monday
+        Date::from_ymd(2017, 07, 05)
+    }
+
+    #[test]
+    fn fra_fixes_two_business_days_before_start() {
+        let fra = sample_fra(PayOrReceive::Pay, 0.08, sample_start());
+        assert_eq!(fra.fixing_date(),
+            DateTime::new(Date::from_ymd(2017, 07, 03), TimeOfDay::Close));
+        assert_eq!(fra.accrual().payment(), sample_start());
+    }
+
+    #[test]
+    fn fra_priced_with_discounted_settlement() {
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let fra = sample_fra(PayOrReceive::Pay, 0.08, sample_start());
+        let price = fra.price(&market_data, val_date).unwrap();
+
+        let start = sample_start();
+        let end = start + 182;
+        let forecast = market_data.yield_curve("LSE", end).unwrap();
+        let yc = market_data.yield_curve("OPT", end).unwrap();
+        let year_fraction = 182.0 / 360.0;
+        let forward = (forecast.df(start, end).unwrap() - 1.0) / year_fraction;
+        let df = yc.df(start, Date::from_ymd(2017, 01, 04)).unwrap();
+        let expected = 1000000.0 * year_fraction * (forward - 0.08)
+            / (1.0 + year_fraction * forward) * df;
+        assert_approx(price, expected, 1e-6);
+
+        // the receiver is the exact opposite
+        let receiver = sample_fra(PayOrReceive::Receive, 0.08, start);
+        assert_approx(receiver.price(&market_data, val_date).unwrap(), -price, 1e-6);
+    }
+
+    #[test]
+    fn par_fra_has_zero_value() {
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let fra = sample_fra(PayOrReceive::Pay, 0.08, sample_start());
+        let par_rate = fra.par_rate(&market_data).unwrap();
+        assert!(par_rate > 0.05 && par_rate < 0.12, "par_rate={}", par_rate);
+
+        let par_fra = sample_fra(PayOrReceive::Pay, par_rate, sample_start());
+        assert_approx(par_fra.price(&market_data, val_date).unwrap(), 0.0, 1e-9);
+    }
+
+    #[test]
+    fn fra_reacts_to_yield_bumps() {
+        let market_data = sample_market_data();
+        let fra = RcInstrument::new(Qrc::new(Arc::new(
+            sample_fra(PayOrReceive::Pay, 0.08, sample_start()))));
+        let mut pricer = SelfPricer::new(vec![(1.0, fra)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        // bumping the forecast curve raises the forward rate, which is good
+        // for the payer
+        let bump = Bump::new_yield("LSE", BumpYield::new_flat_annualised(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+        assert!(bumped > unbumped + 4000.0, "bumped={} unbumped={}", bumped, unbumped);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-9);
+
+        // bumping the discount curve only changes the discounting of the
+        // settlement amount
+        let bump = Bump::new_yield("OPT", BumpYield::new_flat_annualised(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+        assert!((bumped - unbumped).abs() < 0.01 * unbumped.abs(),
+            "bumped={} unbumped={}", bumped, unbumped);
+        assert!((bumped - unbumped).abs() > 0.0);
+    }
+
+    #[test]
+    fn fra_fixes_and_ages_under_bump_time() {
+        // start the FRA so that it fixes at tomorrow's close
+        let market_data = sample_market_data();
+        let spot_date = market_data.spot_date();
+        let fra = sample_fra(PayOrReceive::Pay, 0.08, Date::from_ymd(2017, 01, 05));
+        assert_eq!(fra.fixing_date().date(), spot_date + 1);
+        let val_date = sample_val_date();
+        let price = fra.price(&market_data, val_date).unwrap();
+        let projected = fra.par_rate(&market_data).unwrap();
+
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(fra.clone())));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&instrument);
+        assert_eq!(dependencies.fixings("GBPLIBOR6M").len(), 1);
+        assert_eq!(dependencies.yield_curve_hwm("LSE"), Some(Date::from_ymd(2017, 07, 06)));
+
+        // bumping only to tomorrow leaves it unfixed
+        let mut instruments = vec![(1.0, instrument.clone())];
+        let bump = BumpTime::new(spot_date + 1, spot_date, SpotDynamics::StickyForward);
+        assert!(!bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+
+        // bumping past the fixing fixes it at the projected rate, which
+        // leaves the price unchanged
+        let bump = BumpTime::new(spot_date + 2, spot_date, SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        let aged = instruments[0].1.clone();
+        assert_eq!(aged.type_id(), "ForwardRateAgreement");
+        let aged_price = aged.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(aged_price, price, 1e-6);
+
+        let mut aged_dependencies = DependencyCollector::new(spot_date + 2);
+        aged_dependencies.spot(&aged);
+        assert_eq!(aged_dependencies.fixings("GBPLIBOR6M").len(), 0);
+        assert_eq!(aged_dependencies.yield_curve_hwm("LSE"), None);
+
+        // an explicit fixing replaces the projection
+        let fixing_table = FixingTable::from_fixings(spot_date + 2,
+            &[("GBPLIBOR6M", &[(fra.fixing_date(), projected + 0.01)])]).unwrap();
+        let fixed = fra.fix(&fixing_table).unwrap().unwrap();
+        let fixed_price = fixed[0].1.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert!(fixed_price > price + 4000.0, "fixed={} price={}", fixed_price, price);
+        assert!(fixed[0].1.fix(&fixing_table).unwrap().is_none());
+
+        // once the settlement amount is paid, the FRA is worth nothing
+        let after = DateTime::new(Date::from_ymd(2017, 01, 05), TimeOfDay::Close);
+        assert_eq!(fra.price(&market_data, after).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn fra_tagged_serde() {
+        let market_data: MarketData = sample_market_data();
+        let fra = sample_fra(PayOrReceive::Receive, 0.085, sample_start());
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(fra.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = fra.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A listed future on an equity or equity index, with daily variation
+/// margin. Each day, the exchange marks the future to a settlement price,
+/// and the change in value since the previous settlement price is paid as
+/// margin. Straight after a margin payment, the future is worth nothing.
+///
+/// The future records the settlement price it was last margined at. Its
+/// value is the margin that would be due if it were settled now, which is
+/// the multiplier times the difference between the futures price and the
+/// last settlement price. As rates are not stochastic, the futures price is
+/// the forward of the underlying to the expiry, and the margin is not
+/// discounted. The future therefore has a delta of one per unit multiplier
+/// to the forward.
+///
+/// At expiry, the future settles against a fixing of the underlying, and
+/// the final margin is paid on the settlement date of the underlying.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct EquityFuture {
+    id: String,
+    underlying: RcInstrument,
+    expiry: DateTime,
+    multiplier: f64,
+    settlement_price: f64
+}
+
+impl TypeId for EquityFuture {
+    fn type_id(&self) -> &'static str { "EquityFuture" }
+}
+
+impl InstanceId for EquityFuture {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl EquityFuture {
+    /// Creates a future. The multiplier is the value of the contract per
+    /// unit of the underlying, and the settlement price is the price at which
+    /// the future was last margined, or the traded price if it has not yet
+    /// been margined.
+    pub fn new(id: &str, underlying: RcInstrument, expiry: DateTime,
+        multiplier: f64, settlement_price: f64) -> EquityFuture {
+
+        EquityFuture { id: id.to_string(), underlying: underlying,
+            expiry: expiry, multiplier: multiplier,
+            settlement_price: settlement_price }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(EquityFuture::deserialize(de)?)))
+    }
+
+    pub fn underlying(&self) -> &RcInstrument { &self.underlying }
+    pub fn expiry(&self) -> DateTime { self.expiry }
+    pub fn multiplier(&self) -> f64 { self.multiplier }
+    pub fn settlement_price(&self) -> f64 { self.settlement_price }
+
+    /// Returns a copy of this future, margined at the given settlement
+    /// price. If this is the current futures price, the copy is worth
+    /// nothing.
+    pub fn margined(&self, settlement_price: f64) -> EquityFuture {
+        let mut future = self.clone();
+        future.settlement_price = settlement_price;
+        future
+    }
+
+    /// The futures price, which is the forward of the underlying to expiry
+    pub fn futures_price(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        let expiry_date = self.expiry.date();
+        let forward = context.forward_curve(&*self.underlying, expiry_date)?;
+        forward.forward(expiry_date)
+    }
+}
+
+impl Instrument for EquityFuture {
+
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.underlying.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.underlying.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        context.fixing(self.underlying.id(), self.expiry);
+        context.forward_curve(&self.underlying, self.expiry.date());
+        SpotRequirement::NotRequired
+    }
+
+    /// At expiry, the future is replaced by its final margin payment
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        match fixing_table.get(self.underlying.id(), self.expiry)? {
+            None => Ok(None),
+            Some(fixing) => {
+                let margin = self.multiplier * (fixing - self.settlement_price);
+                let pay_date = self.settlement().apply(self.expiry.date());
+                let payment = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:margin", self.id), self.credit_id(),
+                    RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                    self.expiry, pay_date, self.settlement().clone()))));
+                Ok(Some(vec![(margin, payment)]))
+            }
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for EquityFuture {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The variation margin that would be due if the future were settled at
+    /// the given date, which is not discounted.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let margin = self.multiplier * (self.futures_price(context)? - self.settlement_price);
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry { margin } else { 0.0 };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use dates::Date;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::dependencies::DependencyCollector;
+    use serde_json;
+
+    fn sample_future(settlement_price: f64) -> EquityFuture {
+        let equity = sample_underlying();
+        EquityFuture::new("BP.L.FUT.JUN18", equity,
+            DateTime::new(Date::from_ymd(2018, 06, 15), TimeOfDay::Close),
+            10.0, settlement_price)
+    }
+
+    #[test]
+    fn future_is_worth_nothing_once_margined() {
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let future = sample_future(95.0);
+        let futures_price = future.futures_price(&market_data).unwrap();
+        assert_approx(future.price(&market_data, val_date).unwrap(),
+            10.0 * (futures_price - 95.0), 1e-12);
+
+        let margined = future.margined(futures_price);
+        assert_approx(margined.price(&market_data, val_date).unwrap(), 0.0, 1e-12);
+    }
+
+    #[test]
+    fn future_has_unit_delta_to_the_forward() {
+        let mut market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let future = sample_future(100.0);
+        let price = future.price(&market_data, val_date).unwrap();
+        let forward = future.futures_price(&market_data).unwrap();
+
+        market_data.set_spot("BP.L", 101.0);
+        let bumped = future.price(&market_data, val_date).unwrap();
+        let bumped_forward = future.futures_price(&market_data).unwrap();
+        assert_approx((bumped - price) / (bumped_forward - forward), 10.0, 1e-9);
+    }
+
+    #[test]
+    fn future_fixes_into_final_margin() {
+        let market_data = sample_market_data();
+        let future = sample_future(100.0);
+        let expiry = future.expiry();
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 06, 16),
+            &[("BP.L", &[(expiry, 104.0)])]).unwrap();
+        let fixed = future.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 40.0, 1e-12);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+
+        // the margin is paid on the settlement date after expiry, which is
+        // the date it is discounted to at expiry
+        let payment = fixed[0].1.as_priceable().unwrap()
+            .price(&market_data, expiry).unwrap();
+        assert_approx(payment, 1.0, 1e-12);
+    }
+
+    #[test]
+    fn future_dependencies() {
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let future = RcInstrument::new(Qrc::new(Arc::new(sample_future(100.0))));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&future);
+
+        let equity = dependencies.instrument_by_id("BP.L").unwrap().clone();
+        assert_eq!(dependencies.forward_curve_hwm(&equity), Some(Date::from_ymd(2018, 06, 15)));
+        assert_eq!(dependencies.fixings("BP.L").len(), 1);
+        assert!(dependencies.has_spot(&equity));
+    }
+
+    #[test]
+    fn future_tagged_serde() {
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let future = sample_future(95.0);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(future.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, future.price(&market_data, val_date).unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,536 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::ExchangeRate;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An FX forward, or outright. The holder buys a notional amount of the
+/// foreign currency of a currency pair at expiry, paying the strike rate in
+/// the domestic currency, with both payments made on the settlement date of
+/// the pair after expiry. A negative notional sells the foreign currency.
+///
+/// The forward is cash settled in the domestic currency against a fixing of
+/// the pair at expiry, which is economically the same as exchanging the two
+/// amounts. It is valued from the FX forward, discounted on the domestic
+/// curve.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FxForward {
+    id: String,
+    pair: RcInstrument,
+    expiry: DateTime,
+    strike: f64,
+    notional: f64,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date
+}
+
+impl TypeId for FxForward {
+    fn type_id(&self) -> &'static str { "FxForward" }
+}
+
+impl InstanceId for FxForward {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl FxForward {
+    /// Creates an FX forward on the given currency pair. The strike is the
+    /// agreed rate, in units of the domestic currency per unit of the
+    /// foreign, and the notional is the amount of the foreign currency.
+    pub fn new(id: &str, pair: RcInstrument, expiry: DateTime, strike: f64,
+        notional: f64) -> Result<FxForward, qm::Error> {
+
+        exchange_rate(&pair)?;
+        if !(strike > 0.0) {
+            return Err(qm::Error::new("FX forward strike must be positive"))
+        }
+
+        let pay_date = pair.settlement().apply(expiry.date());
+        Ok(FxForward { id: id.to_string(), pair: pair, expiry: expiry,
+            strike: strike, notional: notional, pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(FxForward::deserialize(de)?)))
+    }
+
+    pub fn pair(&self) -> &RcInstrument { &self.pair }
+    pub fn expiry(&self) -> DateTime { self.expiry }
+    pub fn strike(&self) -> f64 { self.strike }
+    pub fn notional(&self) -> f64 { self.notional }
+
+    /// The forward rate of the pair to expiry
+    pub fn forward_rate(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        let expiry_date = self.expiry.date();
+        let forward = context.fx_forward_curve(exchange_rate(&self.pair)?,
+            expiry_date)?;
+        forward.forward(expiry_date)
+    }
+}
+
+impl Instrument for FxForward {
+    fn payoff_currency(&self) -> &Currency {
+        self.pair.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.pair.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.pair.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        fx_dependencies(&self.pair, self.expiry, self.pay_date, context);
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// At expiry, the forward turns into a payment of the difference between
+    /// the fixing and the strike, which may be negative.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        match fixing_table.get(self.pair.id(), self.expiry)? {
+            None => Ok(None),
+            Some(fixing) => {
+                let amount = self.notional * (fixing - self.strike);
+                Ok(Some(vec![(amount, fx_payment(&self.id, "payment",
+                    &self.pair, self.expiry, self.pay_date))]))
+            }
+        }
+    }
+}
+
+impl Priceable for FxForward {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())
+        }
+
+        let yc = context.yield_curve(self.credit_id(), self.pay_date)?;
+        let undiscounted = self.notional * (self.forward_rate(context)? - self.strike);
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement().apply(date.date());
+                undiscounted * yc.df(settlement_date, self.pay_date)?
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// A European option on an FX rate, valued with Garman-Kohlhagen. This is
+/// Black76 on the FX forward, which is implied by the yield curves of the
+/// two currencies, discounted on the domestic curve. The payoff is in the
+/// domestic currency, per unit of the foreign currency for a notional of
+/// one, and is cash settled against a fixing of the pair at expiry.
+///
+/// The vol surface is that of the FX rate, identified by the id of the pair.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FxOption {
+    id: String,
+    pair: RcInstrument,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+    notional: f64,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date
+}
+
+impl TypeId for FxOption {
+    fn type_id(&self) -> &'static str { "FxOption" }
+}
+
+impl InstanceId for FxOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl FxOption {
+    /// Creates an FX option on the given currency pair. The strike is in
+    /// units of the domestic currency per unit of the foreign, and the
+    /// notional is the amount of the foreign currency.
+    pub fn new(id: &str, pair: RcInstrument, expiry: DateTime, strike: f64,
+        put_or_call: PutOrCall, notional: f64) -> Result<FxOption, qm::Error> {
+
+        exchange_rate(&pair)?;
+        if !(strike > 0.0) {
+            return Err(qm::Error::new("FX option strike must be positive"))
+        }
+
+        let pay_date = pair.settlement().apply(expiry.date());
+        let expiry_time = pair.time_to_day_fraction(expiry)?;
+        Ok(FxOption { id: id.to_string(), pair: pair, expiry: expiry,
+            strike: strike, put_or_call: put_or_call, notional: notional,
+            expiry_time: expiry_time, pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(FxOption::deserialize(de)?)))
+    }
+
+    pub fn pair(&self) -> &RcInstrument { &self.pair }
+    pub fn expiry(&self) -> DateTime { self.expiry }
+    pub fn strike(&self) -> f64 { self.strike }
+    pub fn put_or_call(&self) -> PutOrCall { self.put_or_call }
+    pub fn notional(&self) -> f64 { self.notional }
+
+    fn payoff(&self, fx: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (fx - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - fx).max(0.0)
+        }
+    }
+}
+
+impl Instrument for FxOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.pair.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.pair.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.pair.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        fx_dependencies(&self.pair, self.expiry, self.pay_date, context);
+        context.fx_rate(self.pair.id(), self.expiry.date());
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// At expiry, the option turns into a cash payment in the domestic
+    /// currency, or into nothing if it expires out of the money.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        match fixing_table.get(self.pair.id(), self.expiry)? {
+            None => Ok(None),
+            Some(fixing) => {
+                let mut decomp = Vec::new();
+                let payment = self.notional * self.payoff(fixing);
+                if payment != 0.0 {
+                    decomp.push((payment, fx_payment(&self.id, "payment",
+                        &self.pair, self.expiry, self.pay_date)));
+                }
+                Ok(Some(decomp))
+            }
+        }
+    }
+}
+
+impl Priceable for FxOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(self.credit_id(), self.pay_date)?;
+        let forward_curve = context.fx_forward_curve(exchange_rate(&self.pair)?,
+            expiry_date)?;
+        let forward = forward_curve.forward(expiry_date)?;
+        let vol = context.fx_vol_surface(self.pair.id(), expiry_date)?;
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement().apply(date.date());
+                let df = yc.df(settlement_date, self.pay_date)?;
+                let val_date = self.pair.time_to_day_fraction(*date)?;
+                let variance = vol.forward_variance(val_date, self.expiry_time,
+                    self.strike)?;
+                let sqrt_var = variance.max(0.0).sqrt();
+                self.notional * match self.put_or_call {
+                    PutOrCall::Call => black76.call_price(df, forward,
+                        self.strike, sqrt_var),
+                    PutOrCall::Put => black76.put_price(df, forward,
+                        self.strike, sqrt_var)
+                }
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Casts an instrument to an exchange rate, or fails with an error
+fn exchange_rate(pair: &RcInstrument) -> Result<&ExchangeRate, qm::Error> {
+    pair.as_exchange_rate().ok_or_else(|| qm::Error::new(&format!(
+        "FX instruments must be written on a currency pair: '{}'", pair.id())))
+}
+
+/// The dependencies shared by FX forwards and options. Both fix at expiry,
+/// and are valued from the forward of the pair, discounted on the domestic
+/// curve to the pay date.
+fn fx_dependencies(pair: &RcInstrument, expiry: DateTime, pay_date: Date,
+    context: &mut DependencyContext) {
+    context.fixing(pair.id(), expiry);
+    context.forward_curve(pair, expiry.date());
+    context.yield_curve(pair.credit_id(), pay_date);
+}
+
+/// A unit payment in the domestic currency of a pair
+fn fx_payment(id: &str, suffix: &str, pair: &RcInstrument, expiry: DateTime,
+    pay_date: Date) -> RcInstrument {
+    RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+        &format!("{}:{}", id, suffix), pair.credit_id(),
+        RcCurrency::new(Arc::new(pair.payoff_currency().clone())),
+        expiry, pay_date, pair.settlement().clone()))))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use instruments::assets::CurrencyPair;
+    use math::numerics::approx_eq;
+    use math::interpolation::Extrap;
+    use data::curves::RateCurveAct365;
+    use data::curves::RcRateCurve;
+    use data::volsurface::FlatVolSurface;
+    use data::volsurface::RcVolSurface;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::deltagamma::DeltaGammaReport;
+    use risk::ReportGenerator;
+    use risk::Pricer;
+    use pricers::selfpricer::SelfPricer;
+    use serde_json;
+
+    pub fn sample_currency_pair() -> RcInstrument {
+        let gbp = RcCurrency::new(Arc::new(sample_currency(2)));
+        let usd = RcCurrency::new(Arc::new(Currency::new("USD",
+            sample_settlement(2))));
+        RcInstrument::new(Qrc::new(Arc::new(CurrencyPair::new("GBPUSD",
+            gbp, "GBP", usd, "USD", sample_settlement(2)))))
+    }
+
+    /// Market data for GBPUSD, with flat rates of 3% in dollars and 1% in
+    /// sterling, and a flat FX vol
+    pub fn sample_fx_market_data(fx_vol: f64) -> MarketData {
+        let base = Date::from_ymd(2016, 12, 30);
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("USD".to_string(), flat_curve(base, 0.03));
+        yield_curves.insert("GBP".to_string(), flat_curve(base, 0.01));
+
+        let mut market_data = MarketData::new(Date::from_ymd(2017, 01, 02),
+            HashMap::new(), yield_curves, HashMap::new(), HashMap::new(),
+            HashMap::new());
+        market_data.set_spot("GBPUSD", 1.25);
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        market_data.add_fx_vol_surface("GBPUSD", RcVolSurface::new(Arc::new(
+            FlatVolSurface::new(fx_vol, calendar,
+            DateDayFraction::new(base, 0.2)))));
+        market_data
+    }
+
+    fn flat_curve(base: Date, rate: f64) -> RcRateCurve {
+        RcRateCurve::new(Arc::new(RateCurveAct365::new(base, &[(base, rate)],
+            Extrap::Flat, Extrap::Flat).unwrap()))
+    }
+
+    fn sample_fx_option(strike: f64, put_or_call: PutOrCall) -> FxOption {
+        FxOption::new("GBPUSD.OPT", sample_currency_pair(), sample_expiry(),
+            strike, put_or_call, 1000000.0).unwrap()
+    }
+
+    #[test]
+    fn currency_pair_forward_follows_interest_parity() {
+        let market_data = sample_fx_market_data(0.1);
+        let pair = sample_currency_pair();
+        let priceable = pair.as_priceable().unwrap();
+        assert_approx(priceable.price(&market_data, sample_val_date()).unwrap(),
+            1.25, 1e-12);
+
+        // dollar rates are higher, so sterling is at a forward premium
+        let expiry = sample_expiry();
+        let settlement = sample_settlement(2);
+        let days = settlement.apply(expiry.date())
+            - settlement.apply(sample_val_date().date());
+        let expected = 1.25 * (0.02 * days as f64 / 365.0).exp();
+        let mut out = [0.0, 0.0];
+        priceable.prices(&market_data, &[sample_val_date(), expiry], &mut out).unwrap();
+        assert_approx(out[1], expected, 1e-12);
+    }
+
+    #[test]
+    fn fx_forward_at_the_forward_rate_is_worth_nothing() {
+        let market_data = sample_fx_market_data(0.1);
+        let val_date = sample_val_date();
+        let forward = FxForward::new("GBPUSD.FWD", sample_currency_pair(),
+            sample_expiry(), 1.2, 1000000.0).unwrap();
+        let rate = forward.forward_rate(&market_data).unwrap();
+        let at_market = FxForward::new("GBPUSD.FWD", sample_currency_pair(),
+            sample_expiry(), rate, 1000000.0).unwrap();
+        assert_approx(at_market.price(&market_data, val_date).unwrap(), 0.0, 1e-6);
+
+        // off market, the value is the discounted difference in rates
+        let price = forward.price(&market_data, val_date).unwrap();
+        let yc = market_data.yield_curve("USD", val_date.date()).unwrap();
+        let df = yc.df(sample_settlement(2).apply(val_date.date()),
+            forward.pay_date).unwrap();
+        assert_approx(price, 1000000.0 * (rate - 1.2) * df, 1e-6);
+    }
+
+    #[test]
+    fn fx_option_put_call_parity() {
+        let market_data = sample_fx_market_data(0.1);
+        let val_date = sample_val_date();
+        let call = sample_fx_option(1.3, PutOrCall::Call).price(&market_data, val_date).unwrap();
+        let put = sample_fx_option(1.3, PutOrCall::Put).price(&market_data, val_date).unwrap();
+        let forward = FxForward::new("GBPUSD.FWD", sample_currency_pair(),
+            sample_expiry(), 1.3, 1000000.0).unwrap();
+        let fwd_price = forward.price(&market_data, val_date).unwrap();
+        assert_approx(call - put, fwd_price, 1e-6);
+        assert!(call > 0.0 && put > 0.0);
+    }
+
+    #[test]
+    fn fx_option_delta_matches_garman_kohlhagen() {
+        let market_data = sample_fx_market_data(0.1);
+        let option = sample_fx_option(1.3, PutOrCall::Call);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(option.clone())));
+        let mut pricer = SelfPricer::new(vec![(1.0, instrument)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = DeltaGammaReportGenerator::new(0.001);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<DeltaGammaReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        let delta = results.get("GBPUSD").unwrap().delta();
+
+        // analytic delta is df * N(d1) * dF/dS, where F is proportional to S
+        let val_date = sample_val_date();
+        let forward = FxForward::new("GBPUSD.FWD", sample_currency_pair(),
+            sample_expiry(), 1.3, 1.0).unwrap();
+        let fwd = forward.forward_rate(&market_data).unwrap();
+        let df = forward.price(&market_data, val_date).unwrap() / (fwd - 1.3);
+        let pair = sample_currency_pair();
+        let variance = market_data.fx_vol_surface("GBPUSD", val_date.date()).unwrap()
+            .forward_variance(pair.time_to_day_fraction(val_date).unwrap(),
+            option.expiry_time, 1.3).unwrap();
+        let d1 = ((fwd / 1.3).ln() + 0.5 * variance) / variance.sqrt();
+        let black76 = Black76::new().unwrap();
+        let expected = 1000000.0 * df * black76.cdf(d1) * fwd / 1.25;
+        assert_approx(delta, expected, 1e-3 * expected);
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-9);
+    }
+
+    #[test]
+    fn fx_option_dependencies() {
+        let option = RcInstrument::new(Qrc::new(Arc::new(
+            sample_fx_option(1.3, PutOrCall::Call))));
+        let mut collector = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        collector.spot(&option);
+
+        let expiry = sample_expiry();
+        let pair = collector.instrument_by_id("GBPUSD").unwrap().clone();
+        assert!(collector.has_spot(&pair));
+        assert_eq!(collector.forward_curve_hwm(&pair), Some(expiry.date()));
+        assert_eq!(collector.fixings("GBPUSD"), &[expiry]);
+        assert_eq!(collector.fx_rate_hwm("GBPUSD"), Some(expiry.date()));
+        assert!(collector.yield_curve_hwm("GBP").is_some());
+        assert_eq!(collector.forward_id_by_credit_id("GBP"), &["GBPUSD".to_string()]);
+    }
+
+    #[test]
+    fn fx_option_fixes_into_payment() {
+        let option = sample_fx_option(1.3, PutOrCall::Call);
+        let expiry = sample_expiry();
+        let after = Date::from_ymd(2018, 06, 02);
+        let fixings = FixingTable::from_fixings(after,
+            &[("GBPUSD", &[(expiry, 1.35)])]).unwrap();
+        let decomp = option.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 1000000.0 * 0.05, 1e-6);
+        assert_eq!(decomp[0].1.payoff_currency().id(), "USD");
+
+        let fixings = FixingTable::from_fixings(after,
+            &[("GBPUSD", &[(expiry, 1.25)])]).unwrap();
+        assert!(option.fix(&fixings).unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fx_instruments_reject_non_pairs() {
+        let currency = RcInstrument::new(Qrc::new(Arc::new(sample_currency(2))));
+        assert!(FxForward::new("BAD", currency.clone(), sample_expiry(), 1.3, 1.0).is_err());
+        assert!(FxOption::new("BAD", currency, sample_expiry(), 1.3,
+            PutOrCall::Call, 1.0).is_err());
+    }
+
+    #[test]
+    fn fx_option_tagged_serde() {
+        let market_data = sample_fx_market_data(0.1);
+        let val_date = sample_val_date();
+        let option = sample_fx_option(1.3, PutOrCall::Put);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(option.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, option.price(&market_data, val_date).unwrap(), 1e-9);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,414 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::swaps::FixedLeg;
+use data::fixings::FixingTable;
+use data::inflation::InflationCurve;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use dates::schedule::add_months;
+use dates::schedule::days_in_month;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An inflation-linked bond pays coupons at a fixed real rate, with the
+/// coupons and the notional scaled by the ratio of a price index at the
+/// payment date to its value when the bond was issued. There is no floor
+/// on the redemption amount if the index falls.
+///
+/// The value of the index at a date is taken from the reference months
+/// some number of months earlier, given by the indexation lag. It is
+/// interpolated between the index values for the first lagged month and
+/// the one after, according to the day of the month, as for most modern
+/// index-linked bonds. Index values for months that have been published
+/// are recorded in the bond as fixings. Other months are projected from
+/// the inflation curve for the index.
+///
+/// The coupon schedule, discounting and settlement are as for a fixed coupon
+/// bond, so the price is the dirty price discounted to the settlement date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct InflationLinkedBond {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    coupons: FixedLeg,
+    settlement: RcDateRule,
+    index_id: String,
+    lag_months: u32,
+    base_index: f64,
+    fixings: Vec<(Date, f64)>
+}
+
+impl TypeId for InflationLinkedBond {
+    fn type_id(&self) -> &'static str { "InflationLinkedBond" }
+}
+
+impl InstanceId for InflationLinkedBond {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl InflationLinkedBond {
+    /// Creates an inflation-linked bond. The coupon rate of the leg is the
+    /// real rate. The base index is the reference index value at issue,
+    /// which all payments are scaled relative to. The fixings are any known
+    /// values of the index, keyed by the first day of their reference month.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        coupons: FixedLeg, settlement: RcDateRule, index_id: &str,
+        lag_months: u32, base_index: f64, fixings: &[(Date, f64)])
+        -> Result<InflationLinkedBond, qm::Error> {
+
+        if coupons.periods().is_empty() {
+            return Err(qm::Error::new("Bond must have at least one coupon"))
+        }
+        if !(base_index > 0.0) {
+            return Err(qm::Error::new("Base index must be positive"))
+        }
+        if fixings.iter().any(|&(month, _)| month.ymd().2 != 1) {
+            return Err(qm::Error::new("Index fixings must be keyed by the first day of the month"))
+        }
+
+        let mut fixings = fixings.to_vec();
+        fixings.sort_by_key(|&(month, _)| month);
+        Ok(InflationLinkedBond { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional, coupons: coupons,
+            settlement: settlement, index_id: index_id.to_string(),
+            lag_months: lag_months, base_index: base_index, fixings: fixings })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(InflationLinkedBond::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn coupons(&self) -> &FixedLeg { &self.coupons }
+    pub fn index_id(&self) -> &str { &self.index_id }
+    pub fn lag_months(&self) -> u32 { self.lag_months }
+    pub fn base_index(&self) -> f64 { self.base_index }
+    pub fn fixings(&self) -> &[(Date, f64)] { &self.fixings }
+
+    pub fn maturity(&self) -> Date {
+        self.coupons.periods().last().map_or(Date::from_nil(), |p| p.payment())
+    }
+
+    /// The value of the index referenced by the given date, interpolated
+    /// between the lagged reference months
+    pub fn reference_index(&self, context: &PricingContext, date: Date)
+        -> Result<f64, qm::Error> {
+        let curve = context.inflation_curve(&self.index_id, self.last_month())?;
+        let (first, second, weight) = self.reference_months(date);
+        let first_index = self.index(&*curve, first)?;
+        if weight == 0.0 {
+            return Ok(first_index)
+        }
+        let second_index = self.index(&*curve, second)?;
+        Ok(first_index + weight * (second_index - first_index))
+    }
+
+    /// The ratio of the reference index at the given date to the base index,
+    /// which scales payments made on the date
+    pub fn index_ratio(&self, context: &PricingContext, date: Date)
+        -> Result<f64, qm::Error> {
+        Ok(self.reference_index(context, date)? / self.base_index)
+    }
+
+    /// Returns the first day of the two reference months for the given date,
+    /// and the weight of the second
+    fn reference_months(&self, date: Date) -> (Date, Date, f64) {
+        let (year, month, day) = date.ymd();
+        let first = add_months(Date::from_ymd(year, month, 1), -(self.lag_months as i32));
+        let weight = (day - 1) as f64 / days_in_month(year, month) as f64;
+        (first, add_months(first, 1), weight)
+    }
+
+    fn last_month(&self) -> Date {
+        self.reference_months(self.maturity()).1
+    }
+
+    fn fixing(&self, month: Date) -> Option<f64> {
+        self.fixings.binary_search_by_key(&month, |&(m, _)| m).ok()
+            .map(|i| self.fixings[i].1)
+    }
+
+    fn index(&self, curve: &InflationCurve, month: Date) -> Result<f64, qm::Error> {
+        match self.fixing(month) {
+            Some(fixing) => Ok(fixing),
+            None => curve.forward_index(month)
+        }
+    }
+
+    /// The reference months needed for payments after the given date, that
+    /// do not yet have fixings
+    fn unfixed_months(&self, after: Date) -> Vec<Date> {
+        let mut months = Vec::new();
+        for period in self.coupons.periods().iter().filter(|p| p.payment() > after) {
+            let (first, second, _) = self.reference_months(period.payment());
+            for month in [first, second].iter() {
+                if self.fixing(*month).is_none() && !months.contains(month) {
+                    months.push(*month);
+                }
+            }
+        }
+        months
+    }
+}
+
+impl Instrument for InflationLinkedBond {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.maturity());
+        context.inflation_curve(&self.index_id, self.last_month());
+        let spot_date = context.spot_date();
+        for month in self.unfixed_months(spot_date).iter() {
+            context.inflation_fixing(&self.index_id, *month);
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    /// Records any index values that have been published. Index values are
+    /// published some time after the end of their reference month, so the
+    /// value for a reference month that has started may not yet be known,
+    /// and a fixing table without it is not an error.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let mut bond = self.clone();
+        let mut any_fixed = false;
+        for month in self.unfixed_months(Date::from_nil()).iter() {
+            let date = DateTime::new(*month, TimeOfDay::Close);
+            if let Some(fixing) = fixing_table.get_optional(&self.index_id, date) {
+                bond.fixings.push((*month, fixing));
+                any_fixed = true;
+            }
+        }
+
+        if any_fixed {
+            bond.fixings.sort_by_key(|&(month, _)| month);
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(bond))))]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for InflationLinkedBond {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The dirty price of the bond. Coupons and the notional are scaled by
+    /// the index ratio at their payment dates, and included if they are paid
+    /// after the settlement date.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.maturity())?;
+        let day_count = self.coupons.day_count();
+        let maturity = self.maturity();
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let settlement_date = self.settlement.apply(date.date());
+
+            let mut total = 0.0;
+            for period in self.coupons.periods().iter()
+                .filter(|p| p.payment() > settlement_date) {
+                let coupon = self.coupons.rate()
+                    * day_count.year_fraction(period.start(), period.end());
+                total += coupon * self.index_ratio(context, period.payment())?
+                    * yc.df(period.payment(), settlement_date)?;
+            }
+            if maturity > settlement_date {
+                total += self.index_ratio(context, maturity)?
+                    * yc.df(maturity, settlement_date)?;
+            }
+
+            *output = self.notional * total;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::interpolation::Extrap;
+    use data::curves::RateCurveAct365;
+    use data::curves::RcRateCurve;
+    use data::inflation::BreakevenInflationCurve;
+    use data::inflation::RcInflationCurve;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::ModifiedFollowing;
+    use dates::daycount::DayCount;
+    use instruments::bonds::FixedCouponBond;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use serde_json;
+
+    fn sample_coupons() -> FixedLeg {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar);
+        FixedLeg::new(Date::from_ymd(2016, 07, 05), Date::from_ymd(2019, 07, 05),
+            6, &adjustment, DayCount::Thirty360, 0.01).unwrap()
+    }
+
+    fn sample_linker(base_index: f64) -> InflationLinkedBond {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let fixings = [(Date::from_ymd(2016, 10, 01), 259.0),
+            (Date::from_ymd(2016, 11, 01), 260.0)];
+        InflationLinkedBond::new("GBP.IL.2019", "OPT", currency, 100.0,
+            sample_coupons(), sample_settlement(1), "UKRPI", 3, base_index,
+            &fixings).unwrap()
+    }
+
+    fn sample_inflation_market_data(breakeven: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        let base = Date::from_ymd(2016, 11, 01);
+        let breakevens = RcRateCurve::new(Arc::new(RateCurveAct365::new(base,
+            &[(base, breakeven), (base + 1825, breakeven)],
+            Extrap::Flat, Extrap::Flat).unwrap()));
+        let curve = BreakevenInflationCurve::new(base, 260.0, breakevens).unwrap();
+        market_data.add_inflation_curve("UKRPI", RcInflationCurve::new(Arc::new(curve)));
+        market_data
+    }
+
+    #[test]
+    fn reference_index_is_lagged_and_interpolated() {
+        let market_data = sample_inflation_market_data(0.0);
+        let linker = sample_linker(250.0);
+
+        // the first of January references October exactly
+        let index = linker.reference_index(&market_data, Date::from_ymd(2017, 01, 01)).unwrap();
+        assert_approx(index, 259.0, 1e-12);
+
+        // mid month interpolates towards November
+        let index = linker.reference_index(&market_data, Date::from_ymd(2017, 01, 16)).unwrap();
+        assert_approx(index, 259.0 + 15.0 / 31.0, 1e-12);
+        assert_approx(linker.index_ratio(&market_data, Date::from_ymd(2017, 01, 16)).unwrap(),
+            index / 250.0, 1e-12);
+    }
+
+    #[test]
+    fn linker_without_inflation_is_a_nominal_bond() {
+        // with no inflation from the base index, payments are not scaled
+        let market_data = sample_inflation_market_data(0.0);
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 09), TimeOfDay::Open);
+        let linker = sample_linker(260.0);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let nominal = FixedCouponBond::new("GBP.1%.2019", "OPT", currency, 100.0,
+            sample_coupons(), sample_settlement(1)).unwrap();
+        assert_approx(linker.price(&market_data, val_date).unwrap(),
+            nominal.price(&market_data, val_date).unwrap(), 1e-12);
+    }
+
+    #[test]
+    fn linker_gains_from_higher_inflation() {
+        let val_date = sample_val_date();
+        let linker = sample_linker(250.0);
+        let low = linker.price(&sample_inflation_market_data(0.01), val_date).unwrap();
+        let high = linker.price(&sample_inflation_market_data(0.04), val_date).unwrap();
+        assert!(high > low, "high={} low={}", high, low);
+
+        // three years of extra inflation
+        assert!(high / low > 1.07 && high / low < 1.11, "ratio={}", high / low);
+    }
+
+    #[test]
+    fn index_fixings_age_under_bump_time() {
+        let market_data = sample_inflation_market_data(0.03);
+        let spot_date = market_data.spot_date();
+        let val_date = sample_val_date();
+        let linker = RcInstrument::new(Qrc::new(Arc::new(sample_linker(250.0))));
+
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&linker);
+        assert_eq!(dependencies.inflation_curve_hwm("UKRPI"), Some(Date::from_ymd(2019, 05, 01)));
+        let months = dependencies.inflation_fixings().get("UKRPI").unwrap();
+        assert!(months.contains(&Date::from_ymd(2017, 04, 01)));
+        assert!(!months.contains(&Date::from_ymd(2016, 11, 01)));
+
+        // rolling past the start of April fixes the April index from the curve
+        let mut instruments = vec![(1.0, linker.clone())];
+        let bump = BumpTime::new(Date::from_ymd(2017, 04, 03), spot_date,
+            SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        let aged = instruments[0].1.clone();
+        let mut aged_dependencies = DependencyCollector::new(Date::from_ymd(2017, 04, 03));
+        aged_dependencies.spot(&aged);
+        let months = aged_dependencies.inflation_fixings().get("UKRPI").unwrap();
+        assert!(!months.contains(&Date::from_ymd(2017, 04, 01)));
+
+        // the projected fixing does not change the price
+        let price = linker.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        let aged_price = aged.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(aged_price, price, 1e-12);
+    }
+
+    #[test]
+    fn linker_rejects_bad_fixings() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        assert!(InflationLinkedBond::new("GBP.IL.2019", "OPT", currency, 100.0,
+            sample_coupons(), sample_settlement(1), "UKRPI", 3, 250.0,
+            &[(Date::from_ymd(2016, 10, 15), 259.0)]).is_err());
+    }
+
+    #[test]
+    fn linker_tagged_serde() {
+        let market_data = sample_inflation_market_data(0.03);
+        let val_date = sample_val_date();
+        let linker = sample_linker(250.0);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(linker.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, linker.price(&market_data, val_date).unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
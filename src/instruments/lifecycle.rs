@@ -0,0 +1,191 @@
+//! Lifecycle events are the things that happen to an instrument as time
+//! passes and its fixings become known, such as a coupon being paid or a
+//! barrier being hit. Pricing handles these by fixing the instrument into
+//! whatever it becomes, which changes its value without saying why. Back
+//! office systems need to see the events themselves, so that they can book
+//! payments and reconcile their positions against the library.
+
+use instruments::Instrument;
+use instruments::RcInstrument;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::datetime::DateTime;
+use core::qm;
+use core::dedup::InstanceId;
+
+/// What happened in a lifecycle event. Levels are the fixings of the
+/// underlying that caused the event. Amounts and quantities are per unit of
+/// the instrument.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum LifecycleEventType {
+    /// A cash amount became payable, such as a coupon or redemption, or the
+    /// payoff of a cash-settled option
+    Payment { amount: f64, currency: String, pay_date: Date },
+
+    /// A quantity of the underlying became deliverable, for example on the
+    /// exercise of a physically settled option. A negative quantity is
+    /// delivered by the holder.
+    Delivery { underlying: String, quantity: f64, pay_date: Date },
+
+    /// An option expired in the money and was exercised
+    Exercised { level: f64 },
+
+    /// An option expired worthless, either out of the money or because a
+    /// knock-in barrier was never hit
+    Expired { level: f64 },
+
+    /// A knock-in barrier was hit, so the option became a vanilla
+    KnockedIn { level: f64 },
+
+    /// A knock-out barrier was hit, so the option expired worthless
+    KnockedOut { level: f64 },
+
+    /// An autocallable note was called, redeeming early
+    Autocalled { level: f64 },
+
+    /// The instrument terminated early for some other reason, such as a
+    /// target redemption note reaching its target
+    Terminated
+}
+
+/// An event in the life of an instrument, on the date and time of the
+/// fixing that caused it
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct LifecycleEvent {
+    pub instrument_id: String,
+    pub date: DateTime,
+    pub event: LifecycleEventType
+}
+
+impl LifecycleEvent {
+    pub fn new(instrument_id: &str, date: DateTime, event: LifecycleEventType)
+        -> LifecycleEvent {
+        LifecycleEvent { instrument_id: instrument_id.to_string(), date: date,
+            event: event }
+    }
+
+    /// Creates a payment event in the payoff currency of the instrument
+    pub fn payment(instrument: &Instrument, date: DateTime, amount: f64,
+        pay_date: Date) -> LifecycleEvent {
+        LifecycleEvent::new(instrument.id(), date, LifecycleEventType::Payment {
+            amount: amount, currency: instrument.payoff_currency().id().to_string(),
+            pay_date: pay_date })
+    }
+
+    /// The same event for a holding of the given number of units, which
+    /// scales any amount or quantity
+    pub fn scaled(&self, weight: f64) -> LifecycleEvent {
+        let event = match self.event {
+            LifecycleEventType::Payment { amount, ref currency, pay_date } =>
+                LifecycleEventType::Payment { amount: amount * weight,
+                    currency: currency.clone(), pay_date: pay_date },
+            LifecycleEventType::Delivery { ref underlying, quantity, pay_date } =>
+                LifecycleEventType::Delivery { underlying: underlying.clone(),
+                    quantity: quantity * weight, pay_date: pay_date },
+            ref other => other.clone()
+        };
+        LifecycleEvent { instrument_id: self.instrument_id.clone(),
+            date: self.date, event: event }
+    }
+}
+
+/// Returns true if an event at the given date and time falls within the
+/// range of dates. As with time bumping, the start date is included and
+/// the end date is not.
+pub fn in_range(date: DateTime, from: Date, to: Date) -> bool {
+    date.date() >= from && date.date() < to
+}
+
+/// Collects the lifecycle events of all instruments in a weighted vector,
+/// scaling them by the weights
+pub fn lifecycle_events_all(instruments: &[(f64, RcInstrument)],
+    fixing_table: &FixingTable, from: Date, to: Date)
+    -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+    let mut events = Vec::new();
+    for &(weight, ref instrument) in instruments.iter() {
+        for event in instrument.lifecycle_events(fixing_table, from, to)? {
+            events.push(event.scaled(weight));
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use core::factories::Qrc;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use serde_json;
+
+    fn sample_option(strike: f64, put_or_call: PutOrCall,
+        settlement: OptionSettlement) -> RcInstrument {
+        let equity = sample_underlying();
+        RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleOption", "OPT", equity, sample_settlement(2), expiry(), strike,
+            put_or_call, settlement).unwrap())))
+    }
+
+    fn expiry() -> DateTime {
+        sample_expiry()
+    }
+
+    fn fixings() -> FixingTable {
+        FixingTable::from_fixings(Date::from_ymd(2018, 06, 04),
+            &[("BP.L", &[(expiry(), 105.0)])]).unwrap()
+    }
+
+    #[test]
+    fn european_exercise_events() {
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let from = Date::from_ymd(2018, 06, 01);
+        let to = Date::from_ymd(2018, 06, 04);
+        let call = sample_option(100.0, PutOrCall::Call, OptionSettlement::Cash);
+        let events = call.lifecycle_events(&fixings(), from, to).unwrap();
+        assert_eq!(events, vec![
+            LifecycleEvent::new("SampleOption", expiry(),
+                LifecycleEventType::Exercised { level: 105.0 }),
+            LifecycleEvent::new("SampleOption", expiry(), LifecycleEventType::Payment {
+                amount: 5.0, currency: "GBP".to_string(), pay_date: pay_date })]);
+
+        let physical = sample_option(100.0, PutOrCall::Put, OptionSettlement::Physical);
+        let events = physical.lifecycle_events(&fixings(), from, to).unwrap();
+        assert_eq!(events, vec![LifecycleEvent::new("SampleOption", expiry(),
+            LifecycleEventType::Expired { level: 105.0 })]);
+
+        let physical = sample_option(110.0, PutOrCall::Put, OptionSettlement::Physical);
+        let events = physical.lifecycle_events(&fixings(), from, to).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].event, LifecycleEventType::Payment {
+            amount: 110.0, currency: "GBP".to_string(), pay_date: pay_date });
+        assert_eq!(events[2].event, LifecycleEventType::Delivery {
+            underlying: "BP.L".to_string(), quantity: -1.0, pay_date: pay_date });
+
+        // events outside the range are not reported
+        assert!(call.lifecycle_events(&fixings(), to, to + 10).unwrap().is_empty());
+        assert!(call.lifecycle_events(&fixings(), from - 10, from).unwrap().is_empty());
+    }
+
+    #[test]
+    fn weighted_events_are_scaled() {
+        let call = sample_option(100.0, PutOrCall::Call, OptionSettlement::Cash);
+        let events = lifecycle_events_all(&[(-3.0, call)], &fixings(),
+            Date::from_ymd(2018, 06, 01), Date::from_ymd(2018, 06, 04)).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, LifecycleEventType::Exercised { level: 105.0 });
+        match events[1].event {
+            LifecycleEventType::Payment { amount, .. } => assert_eq!(amount, -15.0),
+            ref other => panic!("unexpected event {:?}", other)
+        }
+
+        let serialized = serde_json::to_string(&events).unwrap();
+        let deserialized: Vec<LifecycleEvent> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, events);
+    }
+}
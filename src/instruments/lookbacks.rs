@@ -0,0 +1,388 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The strike of a lookback option. A fixed-strike lookback pays off the
+/// best level of the underlying against a fixed strike. A floating-strike
+/// lookback pays off the final level of the underlying against the best
+/// level, which acts as the strike.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LookbackStrike { Fixed(f64), Floating }
+
+/// A lookback option pays off the most favourable level of the underlying
+/// seen over a schedule of observation dates. For a fixed-strike call, this is
+/// (max(S) - K).max(0) and for a put (K - min(S)).max(0). For a floating-strike
+/// call it is S - min(S) and for a put max(S) - S, where S is the level at
+/// expiry, which is the last observation.
+///
+/// As observations are fixed, they are removed and the running extreme
+/// (the maximum or minimum, depending on the payoff) is updated. This means a
+/// seasoned lookback carries its history with it, and ages correctly when
+/// the fixings are applied.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LookbackOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    strike: LookbackStrike,
+    put_or_call: PutOrCall,
+    observations: Vec<DateTime>,
+    running_extreme: Option<f64>,
+    expiry: DateTime,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date,
+}
+
+impl TypeId for LookbackOption {
+    fn type_id(&self) -> &'static str { "LookbackOption" }
+}
+
+impl LookbackOption {
+    /// Creates a lookback option. The observations must be in increasing
+    /// order, and the last of them must be the expiry. If some observations
+    /// are already fixed, pass in the running extreme (max or min depending
+    /// on the payoff) of those fixings, and only the remaining observations.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        strike: LookbackStrike,
+        put_or_call: PutOrCall,
+        observations: &[DateTime],
+        running_extreme: Option<f64>,
+        expiry: DateTime)
+        -> Result<LookbackOption, qm::Error> {
+
+        if let LookbackStrike::Fixed(k) = strike {
+            if k < 0.0 {
+                return Err(qm::Error::new("Strike must be greater or equal to zero"))
+            }
+        }
+        if observations.is_empty() {
+            return Err(qm::Error::new("Lookback must have at least one \
+                unfixed observation"))
+        }
+        for pair in observations.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Lookback observations must be in \
+                    strictly increasing order"))
+            }
+        }
+        if *observations.last().unwrap() != expiry {
+            return Err(qm::Error::new("The last lookback observation must be \
+                the expiry"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        Ok(LookbackOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            strike: strike,
+            put_or_call: put_or_call,
+            observations: observations.to_vec(),
+            running_extreme: running_extreme,
+            expiry: expiry,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(LookbackOption::deserialize(de)?)))
+    }
+
+    /// The observations that have not yet been fixed
+    pub fn observations(&self) -> &[DateTime] {
+        &self.observations
+    }
+
+    /// The running maximum or minimum of the fixings so far
+    pub fn running_extreme(&self) -> Option<f64> {
+        self.running_extreme
+    }
+
+    /// Returns true if this lookback tracks the running maximum, or false for
+    /// the running minimum.
+    fn tracks_max(&self) -> bool {
+        match (self.strike, self.put_or_call) {
+            (LookbackStrike::Fixed(_), PutOrCall::Call) => true,
+            (LookbackStrike::Fixed(_), PutOrCall::Put) => false,
+            (LookbackStrike::Floating, PutOrCall::Call) => false,
+            (LookbackStrike::Floating, PutOrCall::Put) => true
+        }
+    }
+
+    /// Combines a new observation into the running extreme
+    fn update(&self, extreme: Option<f64>, spot: f64) -> f64 {
+        match extreme {
+            None => spot,
+            Some(e) => if self.tracks_max() { e.max(spot) } else { e.min(spot) }
+        }
+    }
+
+    /// The payoff, given the extreme over all observations and the final level
+    fn payoff(&self, extreme: f64, fin: f64) -> f64 {
+        match (self.strike, self.put_or_call) {
+            (LookbackStrike::Fixed(k), PutOrCall::Call) => (extreme - k).max(0.0),
+            (LookbackStrike::Fixed(k), PutOrCall::Put) => (k - extreme).max(0.0),
+            (LookbackStrike::Floating, PutOrCall::Call) => (fin - extreme).max(0.0),
+            (LookbackStrike::Floating, PutOrCall::Put) => (extreme - fin).max(0.0)
+        }
+    }
+}
+
+impl InstanceId for LookbackOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for LookbackOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let id = self.underlying.id();
+        for observation in self.observations.iter() {
+            context.fixing(id, *observation);
+        }
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        let expiry_date = self.expiry.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut extreme = self.running_extreme;
+        let mut last = None;
+        let mut fixed = 0;
+        for observation in self.observations.iter() {
+            if let Some(fixing) = fixing_table.get(id, *observation)? {
+                extreme = Some(self.update(extreme, fixing));
+                last = Some(fixing);
+                fixed += 1;
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        // If everything is fixed, the lookback becomes a payment
+        if fixed == self.observations.len() {
+            let payment = self.payoff(extreme.unwrap(), last.unwrap());
+            let mut decomp = Vec::new();
+            if payment > 0.0 {
+                decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:payment", self.id), &self.credit_id,
+                    RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                    self.expiry, self.pay_date, self.settlement.clone()))))));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let lookback = LookbackOption::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.strike,
+            self.put_or_call, &self.observations[fixed..], extreme, self.expiry)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(lookback))))]))
+    }
+}
+
+impl MonteCarloPriceable for LookbackOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        for observation in self.observations.iter() {
+            let time = self.underlying.time_to_day_fraction(*observation)?;
+            output.observation(&self.underlying, time);
+        }
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        let n_obs = shape[1];
+        assert_eq!(n_obs, self.observations.len());
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let extreme = path.iter().fold(self.running_extreme,
+                    |e, spot| Some(self.update(e, *spot))).unwrap();
+                *flow = self.payoff(extreme, path[n_obs - 1]);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::Pricer;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+
+    fn sample_observations() -> Vec<DateTime> {
+        let start = Date::from_ymd(2017, 01, 03);
+        let mut dates : Vec<DateTime> = (0..17)
+            .map(|i| DateTime::new(start + 30 * i, TimeOfDay::Close)).collect();
+        dates.push(sample_expiry());
+        dates
+    }
+
+    fn sample_lookback(strike: LookbackStrike, put_or_call: PutOrCall,
+        observations: &[DateTime], running_extreme: Option<f64>) -> LookbackOption {
+        let expiry = sample_expiry();
+        let equity = sample_underlying();
+        LookbackOption::new("SampleLookback", "OPT", equity, sample_settlement(2),
+            strike, put_or_call, observations, running_extreme, expiry).unwrap()
+    }
+
+    fn mc_price(lookback: LookbackOption) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(lookback))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn fixings_update_running_max() {
+        let observations = sample_observations();
+        let lookback = sample_lookback(LookbackStrike::Fixed(100.0),
+            PutOrCall::Call, &observations, None);
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 03, 01),
+            &[("BP.L", &[(observations[0], 104.0), (observations[1], 98.0)])]).unwrap();
+        let fixed = lookback.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 1.0, 1e-12);
+
+        // refix the result with a later fixing table to see the state
+        let later = FixingTable::from_fixings(Date::from_ymd(2017, 03, 03),
+            &[("BP.L", &[(observations[0], 104.0), (observations[1], 98.0),
+            (observations[2], 101.0)])]).unwrap();
+        let fixed_again = fixed[0].1.fix(&later).unwrap().unwrap();
+        assert_eq!(fixed_again.len(), 1);
+        assert_eq!(fixed_again[0].1.type_id(), "LookbackOption");
+    }
+
+    #[test]
+    fn fully_fixed_floating_put_pays_max_less_final() {
+        let observations = sample_observations();
+        let lookback = sample_lookback(LookbackStrike::Floating,
+            PutOrCall::Put, &observations[15..], Some(130.0));
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(observations[15], 120.0), (observations[16], 125.0),
+            (observations[17], 110.0)])]).unwrap();
+        let fixed = lookback.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 20.0, 1e-12);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+    }
+
+    #[test]
+    fn fixed_strike_lookback_worth_more_than_european() {
+
+        // compared with the self-pricer European price
+        let lookback = sample_lookback(LookbackStrike::Fixed(100.0),
+            PutOrCall::Call, &sample_observations(), None);
+        let price = mc_price(lookback);
+        assert!(price > 16.710717400832973 + 1.0, "price={}", price);
+    }
+
+    #[test]
+    fn seasoned_running_max_is_used() {
+
+        // a running max far above any likely future level makes the
+        // lookback worth roughly the discounted intrinsic value
+        let observations = sample_observations();
+        let lookback = sample_lookback(LookbackStrike::Fixed(100.0),
+            PutOrCall::Call, &observations[10..], Some(1000.0));
+        let price = mc_price(lookback);
+        assert!(price > 780.0 && price < 820.0, "price={}", price);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -2,14 +2,97 @@ pub mod assets;
 pub mod bonds;
 pub mod options;
 pub mod basket;
+pub mod barriers;
+pub mod asians;
+pub mod lookbacks;
+pub mod digitals;
+pub mod varswaps;
+pub mod autocallables;
+pub mod cliquets;
+pub mod quanto;
+pub mod compo;
+pub mod rainbow;
+pub mod spreads;
+pub mod swaps;
+pub mod swaptions;
+pub mod caps;
+pub mod fras;
+pub mod convertibles;
+pub mod credit;
+pub mod inflation;
+pub mod totalreturn;
+pub mod futures;
+pub mod fx;
+pub mod dividends;
+pub mod exercise;
+pub mod tarns;
+pub mod rangeaccruals;
+pub mod commodities;
+pub mod warrants;
+pub mod compound;
+pub mod choosers;
+pub mod exchange;
+pub mod script;
+pub mod scripted;
+pub mod portfolio;
+pub mod rebalancing;
+pub mod lifecycle;
+pub mod touch;
 
 use instruments::assets::Currency;
 use instruments::assets::CreditEntity;
 use instruments::assets::Equity;
+use instruments::assets::CurrencyPair;
+use instruments::assets::Commodity;
+use instruments::exercise::ExerciseSchedule;
 use instruments::bonds::ZeroCoupon;
+use instruments::bonds::FixedCouponBond;
+use instruments::bonds::CallableBond;
 use instruments::basket::Basket;
+use instruments::barriers::BarrierOption;
+use instruments::barriers::BarrierType;
+use instruments::asians::AsianOption;
 use instruments::options::SpotStartingEuropean;
 use instruments::options::ForwardStartingEuropean;
+use instruments::lookbacks::LookbackOption;
+use instruments::digitals::DigitalOption;
+use instruments::varswaps::VarianceSwap;
+use instruments::varswaps::VolatilitySwap;
+use instruments::autocallables::Autocallable;
+use instruments::cliquets::Cliquet;
+use instruments::quanto::Quanto;
+use instruments::compo::CompoOption;
+use instruments::rainbow::RainbowOption;
+use instruments::spreads::SpreadOption;
+use instruments::swaps::InterestRateSwap;
+use instruments::swaptions::Swaption;
+use instruments::caps::CapFloor;
+use instruments::fras::ForwardRateAgreement;
+use instruments::convertibles::ConvertibleBond;
+use instruments::credit::CreditDefaultSwap;
+use instruments::inflation::InflationLinkedBond;
+use instruments::totalreturn::TotalReturnSwap;
+use instruments::futures::EquityFuture;
+use instruments::fx::FxForward;
+use instruments::fx::FxOption;
+use instruments::dividends::DividendFuture;
+use instruments::dividends::DividendSwap;
+use instruments::options::SpotStartingBermudan;
+use instruments::touch::TouchOption;
+use instruments::swaptions::BermudanSwaption;
+use instruments::tarns::Tarn;
+use instruments::rebalancing::RebalancingBasket;
+use instruments::rangeaccruals::RangeAccrual;
+use instruments::portfolio::Portfolio;
+use instruments::portfolio::PortfolioTags;
+use instruments::commodities::CommodityForward;
+use instruments::commodities::CommodityOption;
+use instruments::scripted::ScriptedInstrument;
+use instruments::warrants::Warrant;
+use instruments::exchange::ExchangeOption;
+use instruments::compound::CompoundOption;
+use instruments::choosers::ChooserOption;
+use instruments::lifecycle::LifecycleEvent;
 use dates::Date;
 use dates::datetime::TimeOfDay;
 use dates::rules::RcDateRule;
@@ -18,15 +101,23 @@ use dates::datetime::DateDayFraction;
 use data::curves::RcRateCurve;
 use data::forward::Forward;
 use data::volsurface::RcVolSurface;
+use data::volcube::RcVolCube;
+use data::inflation::RcInflationCurve;
+use data::commodity::RcCommodityCurve;
 use data::volsurface::VolTimeDynamics;
 use data::volsurface::VolForwardDynamics;
 use data::fixings::FixingTable;
+use data::fixings::RateFixing;
+use data::fixings::FixingRange;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::Registry;
 use core::factories::Qrc;
 use core::dedup::{Dedup, DedupControl, Drc, FromId, InstanceId};
 use math::interpolation::Interpolate;
+use math::adjoint::Tape;
+use math::adjoint::Var;
+use pricers::analytic::AnalyticModel;
 use std::sync::Arc;
 use std::hash::Hash;
 use std::collections::HashMap;
@@ -37,6 +128,11 @@ use std::f64::NAN;
 use std::fmt::Debug;
 use std::fmt;
 use ndarray::ArrayView2;
+use ndarray::Array2;
+use ndarray::Array1;
+use ndarray::Axis;
+use core::parallel::for_each_chunk;
+use core::parallel::PATHS_PER_BLOCK;
 use erased_serde as esd;
 use serde as sd;
 use serde_tagged as sdt;
@@ -87,6 +183,13 @@ pub trait Instrument : esd::Serialize + TypeId + InstanceId + Sync + Send + Debu
         false
     }
 
+    /// Return true for underlyings that have no spot, such as commodities,
+    /// whose forwards are quoted directly by a curve of their own rather
+    /// than implied from a spot, dividends and borrow. Defaults to false.
+    fn is_forward_quoted(&self) -> bool {
+        false
+    }
+
     /// For underlyings that support volatility, converts a date plus time
     /// of day to a date plus time fraction. Other underlyings give an error.
     fn time_to_day_fraction(&self, _date_time: DateTime)
@@ -121,6 +224,30 @@ pub trait Instrument : esd::Serialize + TypeId + InstanceId + Sync + Send + Debu
         Ok(None) 
     }
 
+    /// Transforms the instrument, given an exercise decision on the given
+    /// date. Instruments with embedded exercise, such as callable bonds,
+    /// register their exercise dates as dependencies. When time moves past
+    /// one of them, the instrument makes the decision given the market data
+    /// in the context, and returns what it turns into, or None if it is not
+    /// exercised. Most instruments have no embedded exercise, so this is the
+    /// default implementation.
+    fn exercise(&self, _context: &PricingContext, _date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+        Ok(None)
+    }
+
+    /// Reports the lifecycle events, such as coupons paid or barriers hit,
+    /// caused by fixings on dates from `from` inclusive to `to` exclusive.
+    /// Where `fix` silently transforms the instrument, this says what
+    /// happened so that it can be booked. Exercise decisions made by the
+    /// holder are not visible in the fixings, so are not reported, except
+    /// for exercise at expiry. Most instruments have no events driven by
+    /// fixings, so this is the default implementation.
+    fn lifecycle_events(&self, _fixing_table: &FixingTable, _from: Date,
+        _to: Date) -> Result<Vec<LifecycleEvent>, qm::Error> {
+        Ok(Vec::new())
+    }
+
     /// Cast from instrument to a priceable. Returns None if not possible.
     fn as_priceable(&self) -> Option<&Priceable> {
         None
@@ -130,6 +257,174 @@ pub trait Instrument : esd::Serialize + TypeId + InstanceId + Sync + Send + Debu
     fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
         None
     }
+
+    /// Cast from instrument to an exercisable. Returns None if not possible.
+    fn as_exercisable(&self) -> Option<&Exercisable> {
+        None
+    }
+
+    /// Cast from instrument to an analytic priceable. Returns None if not
+    /// possible.
+    fn as_analytic(&self) -> Option<&AnalyticPriceable> {
+        None
+    }
+
+    /// Cast from instrument to an exchange rate. Returns None if not
+    /// possible.
+    fn as_exchange_rate(&self) -> Option<&ExchangeRate> {
+        None
+    }
+
+
+    /// Cast from instrument to a composite. Returns None if not possible.
+    fn as_composite(&self) -> Option<&Composite> {
+        None
+    }
+
+    /// Cast from instrument to a fixed payment. Returns None if not
+    /// possible.
+    fn as_fixed_payment(&self) -> Option<&FixedPayment> {
+        None
+    }
+
+    /// Cast from instrument to a barrier. Returns None if not possible.
+    fn as_barrier(&self) -> Option<&Barrier> {
+        None
+    }
+}
+
+/// Options give the holder the right to exercise into some payoff. Some of
+/// them, such as American options, allow the holder to exercise before
+/// expiry. This interface exposes what a lattice or PDE pricer needs in
+/// order to roll back through the life of the option, choosing at each node
+/// between the continuation value and the exercise value.
+pub trait Exercisable : Instrument {
+
+    /// The underlying whose value decides whether it is optimal to exercise.
+    fn underlying(&self) -> &RcInstrument;
+
+    /// The last date and time when the holder may exercise.
+    fn expiry(&self) -> DateTime;
+
+    /// Returns true if the holder may exercise before expiry. If this is
+    /// false, the only exercise opportunity is at expiry, and the option
+    /// behaves like a European.
+    fn early_exercise(&self) -> bool;
+
+    /// For a Bermudan, the dates on which the holder may exercise, which
+    /// end at expiry. Returns None if the holder may exercise at any time up
+    /// to expiry, or only at expiry, as given by early_exercise.
+    fn exercise_schedule(&self) -> Option<&ExerciseSchedule> { None }
+
+    /// The value received on exercise, given the value of the underlying.
+    /// This is paid at the settlement date of the exercise date, using the
+    /// settlement rule of this instrument.
+    fn exercise_value(&self, underlying: f64) -> f64;
+
+    /// The levels of the underlying at which the exercise value has a kink
+    /// or a jump, such as the strike of a vanilla option. Lattice pricers
+    /// use these to integrate the payoff accurately when smoothing it.
+    fn kinks(&self) -> Vec<f64> { Vec::new() }
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
+}
+
+/// Composite instruments are weighted collections of other instruments,
+/// some of which may themselves be composite, forming a tree such as desk,
+/// book and trade. This interface lets risk reports see through the tree to
+/// the trades, and report results against each of its nodes.
+pub trait Composite : Instrument {
+
+    /// The weighted members of this node.
+    fn members(&self) -> &[(f64, RcInstrument)];
+
+    /// The book, counterparty and netting set of this node. Any that are
+    /// not set are inherited from the node above.
+    fn tags(&self) -> &PortfolioTags;
+
+    /// The trades in this node and all the nodes below it, with their
+    /// weights multiplied through the tree
+    fn positions(&self) -> Vec<(f64, RcInstrument)> {
+        let mut positions = Vec::new();
+        for &(weight, ref member) in self.members().iter() {
+            match member.as_composite() {
+                Some(composite) => for (inner, trade) in composite.positions() {
+                    positions.push((weight * inner, trade));
+                },
+                None => positions.push((weight, member.clone()))
+            }
+        }
+        positions
+    }
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
+}
+
+/// Exchange rates are the price of a foreign currency in units of the
+/// domestic one. They have no dividends or borrow, and their forwards are
+/// implied by covered interest parity from the yield curves of the two
+/// currencies, identified by these credit ids.
+pub trait ExchangeRate : Instrument {
+
+    /// The credit id of the yield curve of the foreign currency.
+    fn foreign_credit_id(&self) -> &str;
+
+    /// The credit id of the yield curve of the domestic currency, which is
+    /// also the payoff currency.
+    fn domestic_credit_id(&self) -> &str;
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
+}
+
+/// Fixed payments pay one unit of their currency on a known date, such as
+/// zero coupon bonds. Short rate models simulate these directly, as the
+/// underlyings and flows of rates options, by discounting along the paths.
+pub trait FixedPayment : Instrument {
+
+    /// The date and time after which the holder is no longer entitled to
+    /// the payment.
+    fn ex_date(&self) -> DateTime;
+
+    /// The date on which the payment is made.
+    fn payment_date(&self) -> Date;
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
+}
+
+/// Barrier options pay out at expiry unless they are knocked out, or only if
+/// they are knocked in, by a fixing of the underlying that touches the
+/// barrier. This interface exposes what a PDE pricer needs in order to apply
+/// the barrier at each observation as it rolls back through the life of the
+/// option.
+pub trait Barrier : Instrument {
+
+    /// The underlying whose fixings are compared with the barrier.
+    fn underlying(&self) -> &RcInstrument;
+
+    /// The date and time when the payoff is fixed.
+    fn expiry(&self) -> DateTime;
+
+    /// The level of the barrier.
+    fn barrier(&self) -> f64;
+
+    /// Whether the barrier is up or down, and whether it knocks in or out.
+    fn barrier_type(&self) -> BarrierType;
+
+    /// The barrier observation dates that have not yet been fixed, in
+    /// strictly increasing order and not after expiry.
+    fn observations(&self) -> &[DateTime];
+
+    /// The value at expiry given the value of the underlying, if the barrier
+    /// has knocked in or has not knocked out. This is paid at the settlement
+    /// date of the expiry, using the settlement rule of this instrument.
+    fn payoff(&self, underlying: f64) -> f64;
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
 }
 
 /// Utility method to fix all instruments in a vector, returning them as a weighted vector.
@@ -197,10 +492,53 @@ pub fn get_registry() -> &'static TypeRegistry {
             reg.insert("CreditEntity", BoxFnSeed::new(CreditEntity::from_serial));
             reg.insert("Equity", BoxFnSeed::new(Equity::from_serial));
             reg.insert("Equity", BoxFnSeed::new(Equity::from_serial));
+            reg.insert("CurrencyPair", BoxFnSeed::new(CurrencyPair::from_serial));
+            reg.insert("Commodity", BoxFnSeed::new(Commodity::from_serial));
             reg.insert("ZeroCoupon", BoxFnSeed::new(ZeroCoupon::from_serial));
+            reg.insert("FixedCouponBond", BoxFnSeed::new(FixedCouponBond::from_serial));
+            reg.insert("CallableBond", BoxFnSeed::new(CallableBond::from_serial));
             reg.insert("Basket", BoxFnSeed::new(Basket::from_serial));
             reg.insert("SpotStartingEuropean", BoxFnSeed::new(SpotStartingEuropean::from_serial));
             reg.insert("ForwardStartingEuropean", BoxFnSeed::new(ForwardStartingEuropean::from_serial));
+            reg.insert("BarrierOption", BoxFnSeed::new(BarrierOption::from_serial));
+            reg.insert("AsianOption", BoxFnSeed::new(AsianOption::from_serial));
+            reg.insert("LookbackOption", BoxFnSeed::new(LookbackOption::from_serial));
+            reg.insert("DigitalOption", BoxFnSeed::new(DigitalOption::from_serial));
+            reg.insert("VarianceSwap", BoxFnSeed::new(VarianceSwap::from_serial));
+            reg.insert("VolatilitySwap", BoxFnSeed::new(VolatilitySwap::from_serial));
+            reg.insert("Autocallable", BoxFnSeed::new(Autocallable::from_serial));
+            reg.insert("Cliquet", BoxFnSeed::new(Cliquet::from_serial));
+            reg.insert("Quanto", BoxFnSeed::new(Quanto::from_serial));
+            reg.insert("CompoOption", BoxFnSeed::new(CompoOption::from_serial));
+            reg.insert("RainbowOption", BoxFnSeed::new(RainbowOption::from_serial));
+            reg.insert("SpreadOption", BoxFnSeed::new(SpreadOption::from_serial));
+            reg.insert("InterestRateSwap", BoxFnSeed::new(InterestRateSwap::from_serial));
+            reg.insert("Swaption", BoxFnSeed::new(Swaption::from_serial));
+            reg.insert("CapFloor", BoxFnSeed::new(CapFloor::from_serial));
+            reg.insert("ForwardRateAgreement", BoxFnSeed::new(ForwardRateAgreement::from_serial));
+            reg.insert("ConvertibleBond", BoxFnSeed::new(ConvertibleBond::from_serial));
+            reg.insert("CreditDefaultSwap", BoxFnSeed::new(CreditDefaultSwap::from_serial));
+            reg.insert("InflationLinkedBond", BoxFnSeed::new(InflationLinkedBond::from_serial));
+            reg.insert("TotalReturnSwap", BoxFnSeed::new(TotalReturnSwap::from_serial));
+            reg.insert("EquityFuture", BoxFnSeed::new(EquityFuture::from_serial));
+            reg.insert("FxForward", BoxFnSeed::new(FxForward::from_serial));
+            reg.insert("FxOption", BoxFnSeed::new(FxOption::from_serial));
+            reg.insert("DividendFuture", BoxFnSeed::new(DividendFuture::from_serial));
+            reg.insert("DividendSwap", BoxFnSeed::new(DividendSwap::from_serial));
+            reg.insert("SpotStartingBermudan", BoxFnSeed::new(SpotStartingBermudan::from_serial));
+            reg.insert("BermudanSwaption", BoxFnSeed::new(BermudanSwaption::from_serial));
+            reg.insert("Tarn", BoxFnSeed::new(Tarn::from_serial));
+            reg.insert("RangeAccrual", BoxFnSeed::new(RangeAccrual::from_serial));
+            reg.insert("CommodityForward", BoxFnSeed::new(CommodityForward::from_serial));
+            reg.insert("CommodityOption", BoxFnSeed::new(CommodityOption::from_serial));
+            reg.insert("Warrant", BoxFnSeed::new(Warrant::from_serial));
+            reg.insert("CompoundOption", BoxFnSeed::new(CompoundOption::from_serial));
+            reg.insert("ChooserOption", BoxFnSeed::new(ChooserOption::from_serial));
+            reg.insert("ExchangeOption", BoxFnSeed::new(ExchangeOption::from_serial));
+            reg.insert("ScriptedInstrument", BoxFnSeed::new(ScriptedInstrument::from_serial));
+            reg.insert("Portfolio", BoxFnSeed::new(Portfolio::from_serial));
+            reg.insert("RebalancingBasket", BoxFnSeed::new(RebalancingBasket::from_serial));
+            reg.insert("TouchOption", BoxFnSeed::new(TouchOption::from_serial));
             reg
         };
     }
@@ -348,6 +686,54 @@ pub trait DependencyContext {
     /// Specify a dependency on a specific fixing, by underlier id and
     /// date-time
     fn fixing(&mut self, id: &str, date: DateTime);
+
+    /// Specify a dependency on a run of daily fixings of the instrument with
+    /// the given id. This is equivalent to a fixing on each business day of
+    /// the range, but is held compactly, and overlapping ranges are merged.
+    fn fixing_range(&mut self, id: &str, range: FixingRange);
+
+    /// Specify a dependency on a fixing of a floating rate index, such as
+    /// Libor, given the id of the index. Rate indices are not instruments,
+    /// so the fixing carries the information needed to project it.
+    fn rate_fixing(&mut self, index_id: &str, fixing: RateFixing);
+
+    /// Specify a dependency on an FX rate, given its id such as "GBPUSD".
+    /// This covers both the spot and the vol surface of the rate. FX rates
+    /// are not instruments, so they are identified only by id. Also specify
+    /// a high water mark, beyond which we never directly ask for vols.
+    fn fx_rate(&mut self, fx_id: &str, high_water_mark: Date);
+
+    /// Specify a dependency on a vol cube for options on swap rates, given
+    /// its id. Also specify a high water mark, beyond which we never directly
+    /// ask for vols.
+    fn vol_cube(&mut self, id: &str, high_water_mark: Date);
+
+    /// Specify a date and time when an exercise decision is made, given the
+    /// id of the instrument that makes it. When time moves past this date,
+    /// the instrument is asked to make the decision.
+    fn exercise(&mut self, id: &str, date: DateTime);
+
+    /// Specify a dependency on a hazard rate curve, given the credit id of
+    /// the entity that may default. Also specify a date beyond which we never
+    /// ask for survival probabilities.
+    fn hazard_curve(&mut self, credit_id: &str, high_water_mark: Date);
+
+    /// Specify a dependency on an inflation curve, given the id of the price
+    /// index. Also specify the last reference month for which we ever ask for
+    /// index values.
+    fn inflation_curve(&mut self, index_id: &str, high_water_mark: Date);
+
+    /// Specify a dependency on the value of a price index for the reference
+    /// month starting on the given date. The index id is not an instrument,
+    /// so when time moves past the month, the value is projected from the
+    /// inflation curve.
+    fn inflation_fixing(&mut self, index_id: &str, month: Date);
+
+    /// Specify a dependency on the dividends paid by an instrument, up to
+    /// and including those going ex on the high water mark. When time moves
+    /// past an ex date, the amount is recorded as a fixing under the id
+    /// given by `dividend_fixing_id`.
+    fn dividends(&mut self, instrument: &RcInstrument, high_water_mark: Date);
 }
 
 /// The external dependencies of an instrument. For example, valuation may
@@ -440,6 +826,22 @@ pub trait Priceable : Instrument {
     fn as_instrument(&self) -> &Instrument;
 }
 
+/// Instruments that have a closed-form price under one of the simple models
+/// of the analytic pricer implement this. Unlike Priceable, the model is
+/// chosen by the caller, so the same instrument may have a closed form under
+/// a log-normal model but not under a normal one.
+pub trait AnalyticPriceable : Instrument {
+    /// Values the instrument in closed form under the given model, with the
+    /// same conventions as Priceable::price. Returns None if there is no
+    /// closed form under this model, or in the current state of the
+    /// instrument.
+    fn analytic_price(&self, context: &PricingContext, model: &AnalyticModel,
+        val_date: DateTime) -> Result<Option<f64>, qm::Error>;
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
+}
+
 /// Sometimes it is useful to treat a priceable as if it were a forward curve.
 /// The only issue is that a priceable takes a DateTime and a forward takes a date.
 /// We require the user to pass in a time of day, so we can convert.
@@ -494,9 +896,9 @@ pub trait PricingContext : Sync + Send {
         forward_fn: &Fn() -> Result<Arc<Forward>, qm::Error>)
          -> Result<RcVolSurface, qm::Error>;
 
-    /// Gets an instantaneous correlation between two instruments. At present,
-    /// we consider this to be constant. (A datetime parameter could be added
-    /// in future.) However, this does not mean that the local correlation
+    /// Gets an instantaneous correlation between two instruments. This is
+    /// constant: see average_correlation for correlations that vary with
+    /// time. However, this does not mean that the local correlation
     /// can be considered to be the same thing as a terminal correlation,
     /// unless both vol surfaces are flat, with the same calendars.
     ///
@@ -506,6 +908,82 @@ pub trait PricingContext : Sync + Send {
     /// structure of at the money vols for each asset.
     fn correlation(&self, first: &Instrument, second: &Instrument)
         -> Result<f64, qm::Error>;
+
+    /// Gets the average instantaneous correlation between two instruments
+    /// over the period after one date up to another, for models whose
+    /// paths step through time. Contexts without a term structure of
+    /// correlations return the constant correlation.
+    fn average_correlation(&self, first: &Instrument, second: &Instrument,
+        _from: Date, _to: Date) -> Result<f64, qm::Error> {
+        self.correlation(first, second)
+    }
+
+    /// Gets a Vol Surface for an FX rate, identified by an id such as
+    /// "GBPUSD". FX rates are not instruments, so they need their own entry
+    /// point. Contexts that do not support FX vols need not implement this.
+    fn fx_vol_surface(&self, fx_id: &str, _high_water_mark: Date)
+        -> Result<RcVolSurface, qm::Error> {
+        Err(qm::Error::new(&format!("FX vol surface not available: '{}'", fx_id)))
+    }
+
+    /// Gets the spot of an FX rate, identified by an id such as "GBPUSD". FX
+    /// spots are normally supplied with the other spots, so the default
+    /// fetches them from there.
+    fn fx_spot(&self, fx_id: &str) -> Result<f64, qm::Error> {
+        self.spot(fx_id)
+    }
+
+    /// Gets the forward of a currency pair, implied by the yield curves of
+    /// its two currencies. Also specify a high water mark, beyond which we
+    /// never directly ask for forwards. Contexts that do not support FX need
+    /// not implement this.
+    fn fx_forward_curve(&self, pair: &ExchangeRate, _high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+        Err(qm::Error::new(&format!("FX forward not available: '{}'", pair.id())))
+    }
+
+    /// Gets an instantaneous correlation between two factors identified by
+    /// id. Unlike the correlation method, the factors need not be
+    /// instruments, so this can correlate an equity with an FX rate, for
+    /// example.
+    fn correlation_by_id(&self, first: &str, second: &str)
+        -> Result<f64, qm::Error> {
+        Err(qm::Error::new(&format!(
+            "Correlation between '{}' and '{}' not available", first, second)))
+    }
+
+    /// Gets a vol cube for options on swap rates, such as swaptions. Cubes
+    /// are identified by id, normally that of the floating rate index of the
+    /// underlying swaps. Contexts that do not support rates vols need not
+    /// implement this.
+    fn vol_cube(&self, id: &str, _high_water_mark: Date)
+        -> Result<RcVolCube, qm::Error> {
+        Err(qm::Error::new(&format!("Vol cube not available: '{}'", id)))
+    }
+
+    /// Gets a hazard rate curve, given the credit id of the entity that may
+    /// default. The curve is a rate curve whose rates are hazard rates, so
+    /// its discount factors are survival probabilities. Contexts that do not
+    /// support credit need not implement this.
+    fn hazard_curve(&self, credit_id: &str, _high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        Err(qm::Error::new(&format!("Hazard curve not available: '{}'", credit_id)))
+    }
+
+    /// Gets an inflation curve, given the id of the price index. Contexts
+    /// that do not support inflation need not implement this.
+    fn inflation_curve(&self, index_id: &str, _high_water_mark: Date)
+        -> Result<RcInflationCurve, qm::Error> {
+        Err(qm::Error::new(&format!("Inflation curve not available: '{}'", index_id)))
+    }
+
+    /// Gets the curve of forward prices for delivery of a commodity, given
+    /// its id. Contexts that do not support commodities need not implement
+    /// this.
+    fn commodity_curve(&self, id: &str, _high_water_mark: Date)
+        -> Result<RcCommodityCurve, qm::Error> {
+        Err(qm::Error::new(&format!("Commodity curve not available: '{}'", id)))
+    }
 }
 
 /// Allow an instrument to be priced using Monte-Carlo. The way this works is
@@ -555,6 +1033,39 @@ pub trait MonteCarloPriceable : Instrument {
     /// immutable, so this must be done using RefCell.)
     fn mc_price(&self, context: &MonteCarloContext) -> Result<f64, qm::Error>;
 
+    /// An instrument whose payoff is closely correlated with this one, but
+    /// which has an analytic price, such as a European for an Asian. If
+    /// control variates are enabled, the pricer values both on the same
+    /// paths, and corrects this price by the error in the proxy. The proxy
+    /// must be both Priceable and MonteCarloPriceable, and must not observe
+    /// its underlyings before this instrument's last observation. Most
+    /// instruments have no proxy, which is the default.
+    fn control_variate(&self) -> Result<Option<RcInstrument>, qm::Error> {
+        Ok(None)
+    }
+
+    /// A static replication of part of this instrument, as weighted
+    /// instruments that are both Priceable and MonteCarloPriceable, such as
+    /// the digitals that pay the coupons of an autocallable. The
+    /// decomposition pricer values the replication analytically, and only
+    /// the residual, which is this instrument less the replication, on the
+    /// paths. Most instruments have no replication, which is the default.
+    fn static_replication(&self) -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+        Ok(None)
+    }
+
+    /// The quantities of the flows on a single path, in the order they were
+    /// passed to the flow method in MonteCarloDependencies, as functions of
+    /// the values of the underlyings recorded on an adjoint tape. This lets
+    /// the pricer differentiate the price by every input of the model in a
+    /// single pass over the paths. Payoffs that jump, such as digitals, have
+    /// no useful derivatives path by path. Instruments return None if they
+    /// do not support this, which is the default.
+    fn mc_adjoint_quantities<'t>(&self, _context: &AdjointContext<'t>)
+        -> Result<Option<Vec<Var<'t>>>, qm::Error> {
+        Ok(None)
+    }
+
     /// Return this object as an instrument
     fn as_instrument(&self) -> &Instrument;
 }
@@ -579,6 +1090,23 @@ pub trait MonteCarloDependencies {
     /// instruments that reflect the dates of transfer, so Bond rather than
     /// Currency, for example.
     fn flow(&mut self, instrument: &RcInstrument);
+
+    /// Specifies that the paths of an underlying must be generated in the
+    /// measure of a payment currency other than its own, because they are
+    /// used by a quanto. The FX rate is identified by id, quoted as units
+    /// of the payment currency per unit of the underlying's currency. Models
+    /// that cannot handle quantos may ignore this, so the default does
+    /// nothing.
+    fn quanto(&mut self, _instrument: &RcInstrument, _fx_id: &str) {}
+
+    /// Specifies the level of an underlying at an observation that matters
+    /// most to the payoff, such as the strike of a digital or the barrier
+    /// of a knock-in. Models that support importance sampling may shift the
+    /// drift of the underlying so that more paths get there, and weight the
+    /// paths to compensate. Models that do not may ignore this, so the
+    /// default does nothing.
+    fn importance_level(&mut self, _instrument: &RcInstrument,
+        _date_time: DateDayFraction, _level: f64) {}
 }
 
 /// Context for Monte-Carlo pricing. The most important thing this gives is
@@ -603,7 +1131,199 @@ pub trait MonteCarloContext {
     fn evaluate_flows(&self, quantities: ArrayView2<f64>) 
         -> Result<f64, qm::Error>;
 
+    /// Value the flows path by path, returning the discounted value of each
+    /// path, so that their average is the result of evaluate_flows. The
+    /// default implementation assumes that a unit of each flow is worth the
+    /// same on every path, as it is with deterministic rates, so models
+    /// with stochastic discounting must override it.
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+
+        let (n_paths, n_flows) = quantities.dim();
+        let mut values = Array1::<f64>::zeros(n_paths);
+        let mut unit = Array2::<f64>::zeros((n_paths, n_flows));
+        for (flow, quantity) in quantities.axis_iter(Axis(1)).enumerate() {
+            unit.subview_mut(Axis(1), flow).fill(1.0);
+            let value = self.evaluate_flows(unit.view())?;
+            unit.subview_mut(Axis(1), flow).fill(0.0);
+            values.scaled_add(value, &quantity);
+        }
+        Ok(values)
+    }
+
     /// Access to the underlying pricing context. Note that this is unaffected by
     /// the filtration within any path.
     fn pricing_context(&self) -> &PricingContext;
+
+    /// The number of threads the model may use, which path-by-path payoffs
+    /// may also use to value their paths. Models that have not been told
+    /// otherwise run on one thread.
+    fn threads(&self) -> usize {
+        1
+    }
+
+    /// For an underlier that may default, returns for each path the index
+    /// of the first observation at or after its default, or the number of
+    /// observations if it survives. The paths already include the jump on
+    /// default. Models without default return None.
+    fn default_steps(&self, _instrument: &RcInstrument)
+        -> Result<Option<&[usize]>, qm::Error> {
+        Ok(None)
+    }
+
+    /// For an underlier with stochastic dividends, returns the ex dates of
+    /// its discrete dividends up to its last observation, and the amounts
+    /// on each path, indexed by path then ex date. Models with
+    /// deterministic dividends return None, in which case the dividends
+    /// are those expected by the forward curve.
+    fn dividends(&self, _instrument: &RcInstrument)
+        -> Result<Option<(&[Date], ArrayView2<f64>)>, qm::Error> {
+        Ok(None)
+    }
+}
+
+/// Context for valuing a single Monte-Carlo path on an adjoint tape. See
+/// MonteCarloPriceable::mc_adjoint_quantities.
+pub trait AdjointContext<'t> {
+    /// The values of an underlying on this path, at the observations the
+    /// instrument specified in its mc_dependencies, in the same order.
+    fn path(&self, instrument: &RcInstrument) -> Result<&[Var<'t>], qm::Error>;
+
+    /// The tape the path is recorded on, for recording any constants
+    fn tape(&self) -> &'t Tape;
+}
+
+/// Whether a path-dependent payoff is still alive after an observation. Once
+/// a path is terminated, for example by an autocall, no further observations
+/// are presented for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStatus { Alive, Terminated }
+
+/// Some Monte-Carlo priceable instruments are most naturally described by
+/// stepping along each path one observation at a time, carrying some state,
+/// emitting cashflows along the way, and possibly terminating early. Such
+/// instruments implement this interface, and can then implement mc_price by
+/// invoking mc_price_path_dependent.
+///
+/// The underlyings must all have been registered in mc_dependencies with the
+/// same number of observations, and the flows registered must match the
+/// number returned by path_flows.
+pub trait PathDependent : MonteCarloPriceable {
+
+    /// The underlyings observed by the payoff, in the order their values are
+    /// presented to path_step.
+    fn path_underlyings(&self) -> &[RcInstrument];
+
+    /// The number of flows registered in mc_dependencies
+    fn path_flows(&self) -> usize;
+
+    /// The number of state variables carried along each path
+    fn path_state_size(&self) -> usize { 0 }
+
+    /// Sets the state at the start of each path. The state is zeroed before
+    /// this call, so the default implementation does nothing. Seasoned
+    /// instruments may override this to start from their accrued state.
+    fn path_initial_state(&self, _state: &mut [f64]) {}
+
+    /// Processes one observation on one path. The spots are the values of
+    /// the underlyings at this observation, and any flows generated should be
+    /// added to the flows, which are in the order registered.
+    fn path_step(&self, step: usize, spots: &[f64], state: &mut [f64],
+        flows: &mut [f64]) -> Result<PathStatus, qm::Error>;
+
+    /// Processes the default of one of the underlyings on one path, given
+    /// its index in path_underlyings. This is called just before path_step
+    /// for the first observation at or after the default, and the spots
+    /// have already jumped. Instruments with default terms may override
+    /// this to settle, for example adding a recovery flow and terminating.
+    /// By default, the path carries on with the defaulted spots.
+    fn path_default(&self, _step: usize, _underlying: usize, _spots: &[f64],
+        _state: &mut [f64], _flows: &mut [f64]) -> Result<PathStatus, qm::Error> {
+        Ok(PathStatus::Alive)
+    }
+}
+
+/// Prices a path-dependent instrument by stepping along each path in turn,
+/// stopping early if the instrument terminates, and then evaluating the
+/// resulting flows.
+pub fn mc_price_path_dependent(instrument: &PathDependent,
+    context: &MonteCarloContext) -> Result<f64, qm::Error> {
+
+    let underlyings = instrument.path_underlyings();
+    if underlyings.is_empty() {
+        return Err(qm::Error::new("Path-dependent instrument has no underlyings"))
+    }
+    let mut paths = Vec::with_capacity(underlyings.len());
+    for underlying in underlyings.iter() {
+        paths.push(context.paths(underlying)?);
+    }
+
+    let shape = paths[0].shape().to_vec();
+    assert_eq!(shape.len(), 2);
+    for path in paths.iter() {
+        if path.shape() != &shape[..] {
+            return Err(qm::Error::new("All underlyings of a path-dependent \
+                instrument must have the same observations"))
+        }
+    }
+    let n_paths = shape[0];
+    let n_steps = shape[1];
+    let n_flows = instrument.path_flows();
+    let mut defaults = Vec::with_capacity(underlyings.len());
+    for underlying in underlyings.iter() {
+        defaults.push(context.default_steps(underlying)?);
+    }
+
+    // Each block of paths is valued on whichever thread picks it up. The
+    // paths are independent, so the quantities are the same however many
+    // threads there are.
+    let mut quantities = Array2::zeros((n_paths, n_flows));
+    {
+        let blocks: Vec<_> = quantities.axis_chunks_iter_mut(Axis(0),
+            PATHS_PER_BLOCK).collect();
+        for_each_chunk(context.threads(), blocks, |block, mut block_quantities| {
+            let first = block * PATHS_PER_BLOCK;
+            let mut spots = vec![0.0; underlyings.len()];
+            let mut state = vec![0.0; instrument.path_state_size()];
+            let mut flows = vec![0.0; n_flows];
+            for (offset, mut row) in block_quantities.outer_iter_mut().enumerate() {
+                let path = first + offset;
+                for s in state.iter_mut() { *s = 0.0; }
+                for f in flows.iter_mut() { *f = 0.0; }
+                instrument.path_initial_state(&mut state);
+
+                for step in 0..n_steps {
+                    for (spot, underlying_paths) in spots.iter_mut().zip(paths.iter()) {
+                        *spot = underlying_paths[[path, step]];
+                    }
+                    let mut status = PathStatus::Alive;
+                    for (underlying, default) in defaults.iter().enumerate() {
+                        if let Some(steps) = *default {
+                            if steps[path] == step {
+                                status = instrument.path_default(step, underlying,
+                                    &spots, &mut state, &mut flows)?;
+                                if status == PathStatus::Terminated {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    if status == PathStatus::Terminated {
+                        break;
+                    }
+                    let status = instrument.path_step(step, &spots, &mut state, &mut flows)?;
+                    if status == PathStatus::Terminated {
+                        break;
+                    }
+                }
+
+                for (quantity, flow) in row.iter_mut().zip(flows.iter()) {
+                    *quantity = *flow;
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    context.evaluate_flows(quantities.view())
 }
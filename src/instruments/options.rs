@@ -3,6 +3,8 @@ use std::f64::NAN;
 use instruments::Instrument;
 use instruments::RcInstrument;
 use instruments::Priceable;
+use instruments::AnalyticPriceable;
+use instruments::Exercisable;
 use instruments::PricingContext;
 use instruments::DependencyContext;
 use instruments::assets::Currency;
@@ -12,7 +14,15 @@ use instruments::SpotRequirement;
 use instruments::MonteCarloPriceable;
 use instruments::MonteCarloDependencies;
 use instruments::MonteCarloContext;
+use instruments::AdjointContext;
+use instruments::exercise::ExerciseSchedule;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::LifecycleEventType;
+use instruments::lifecycle::in_range;
 use math::optionpricing::Black76;
+use math::optionpricing::Bachelier;
+use math::adjoint::Var;
+use pricers::analytic::AnalyticModel;
 use data::fixings::FixingTable;
 use dates::Date;
 use dates::rules::RcDateRule;
@@ -114,7 +124,7 @@ impl VanillaOption {
         // For some div assumptions, we must displace the forward and strike.
         // (This errors for JumpDivs, which we do not currently handle.)
         let displacement = vol.displacement(self.expiry.date())?;
-        let k = strike + displacement;
+        let k = strike - displacement;
         let f = forward - displacement;
         if f < 0.0 {
             return Err(qm::Error::new("Negative forward"));
@@ -162,6 +172,256 @@ impl VanillaOption {
 
         Ok(())
     }
+
+    /// Prices this option at a single val date under the Bachelier model,
+    /// with the given normal vol, discounting in the same way as prices
+    fn bachelier_price(&self, context: &PricingContext, val_date: DateTime,
+        strike: f64, normal_vol: f64) -> Result<f64, qm::Error> {
+
+        // We assume the option goes ex just after its expiry date/time
+        if val_date > self.expiry {
+            return Ok(0.0)
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(self.underlying.credit_id(), self.pay_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| context.forward_curve(&*self.underlying, expiry_date))?;
+        let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlying of an option must itself be priceable"))?;
+        let forward = underlying.price(context, self.expiry)?;
+
+        let settlement_date = self.settlement().apply(val_date.date());
+        let df = (yc.rt(settlement_date)? - yc.rt(self.pay_date)?).exp();
+        let start = vol.vol_time(self.underlying.time_to_day_fraction(val_date)?)?;
+        let end = vol.vol_time(self.expiry_time)?;
+        let sqrt_var = normal_vol * (end - start).max(0.0).sqrt();
+
+        let bachelier = Bachelier::new()?;
+        Ok(match self.put_or_call {
+            PutOrCall::Put => bachelier.put_price(df, forward, strike, sqrt_var),
+            PutOrCall::Call => bachelier.call_price(df, forward, strike, sqrt_var)
+        })
+    }
+
+    /// If there is an expiry fixing (error if missing and in the past),
+    /// the option turns into either a cash flow, or an equity flow and
+    /// a cash flow. Shared by all vanillas with a known strike.
+    fn fix_with_strike(&self, fixing_table: &FixingTable, strike: f64)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let fixing = fixing_table.get(self.underlying.id(),
+            self.expiry)?;
+        if let Some(spot_fixing) = fixing {
+            Ok(Some(self.exercised(strike, spot_fixing, self.expiry, self.pay_date)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The flows the option turns into when exercised at the given date and
+    /// time, with the given value of the underlying, paying on the given
+    /// date. This is either a cash flow, or an equity flow and a cash flow,
+    /// or nothing if the option is out of the money.
+    fn exercised(&self, strike: f64, spot: f64, date: DateTime, pay_date: Date)
+        -> Vec<(f64, RcInstrument)> {
+
+        let mut decomp : Vec<(f64, RcInstrument)> = Vec::new();
+        let sign = match self.put_or_call {
+                    PutOrCall::Call => 1.0,
+                    PutOrCall::Put => -1.0 };
+        let payment_id = format!("{}:payment", self.id);
+        match self.cash_or_physical {
+
+            // cash settlement -- pay a zero coupon if payment > 0
+            OptionSettlement::Cash => {
+                let payment = sign * (spot - strike);
+                if payment > 0.0 {
+                    decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                        &payment_id, &self.credit_id, 
+                        RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                        date, 
+                        pay_date,
+                        self.settlement.clone()))))));
+                }
+            },
+
+            OptionSettlement::Physical => {
+                if sign * (spot - strike) > 0.0 {
+                    decomp.push((-strike * sign, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                        &payment_id, &self.credit_id, 
+                        RcCurrency::new(Arc::new(self.payoff_currency().clone())), 
+                        date,
+                        pay_date,
+                        self.settlement.clone()))))));
+                    decomp.push((sign, self.underlying.clone()));
+                }
+            }
+        }
+
+        decomp
+    }
+
+    /// The lifecycle events if the expiry fixing falls within the range:
+    /// either exercise and what it pays or delivers, or expiry. Shared by
+    /// all vanillas with a known strike.
+    fn lifecycle_events_with_strike(&self, fixing_table: &FixingTable,
+        strike: f64, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        let mut events = Vec::new();
+        if !in_range(self.expiry, from, to) {
+            return Ok(events)
+        }
+
+        let spot = match fixing_table.get(self.underlying.id(), self.expiry)? {
+            Some(spot) => spot,
+            None => return Ok(events)
+        };
+
+        let sign = match self.put_or_call {
+                    PutOrCall::Call => 1.0,
+                    PutOrCall::Put => -1.0 };
+        if sign * (spot - strike) <= 0.0 {
+            events.push(LifecycleEvent::new(&self.id, self.expiry,
+                LifecycleEventType::Expired { level: spot }));
+            return Ok(events)
+        }
+
+        events.push(LifecycleEvent::new(&self.id, self.expiry,
+            LifecycleEventType::Exercised { level: spot }));
+        match self.cash_or_physical {
+            OptionSettlement::Cash => events.push(LifecycleEvent::payment(
+                self, self.expiry, sign * (spot - strike), self.pay_date)),
+            OptionSettlement::Physical => {
+                events.push(LifecycleEvent::payment(
+                    self, self.expiry, -strike * sign, self.pay_date));
+                events.push(LifecycleEvent::new(&self.id, self.expiry,
+                    LifecycleEventType::Delivery {
+                        underlying: self.underlying.id().to_string(),
+                        quantity: sign, pay_date: self.pay_date }));
+            }
+        }
+        Ok(events)
+    }
+
+    /// The value received if the option is exercised with the given strike
+    /// and value of the underlying.
+    fn intrinsic(&self, strike: f64, spot: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (spot - strike).max(0.0),
+            PutOrCall::Put => (strike - spot).max(0.0)
+        }
+    }
+
+    /// Prices this option with a range of val dates on a recombining binomial
+    /// tree. The holder may exercise at expiry, and before expiry as allowed
+    /// by the tree exercise. Bermudan exercise dates are rounded to the
+    /// nearest step of the tree.
+    ///
+    /// The tree has equal up and down probabilities, with the nodes at each
+    /// step scaled to match the forward of the underlying at that step. Steps
+    /// are evenly spaced in calendar time, and the total variance from the val
+    /// date to expiry is evenly spread across them. Displacement is handled in
+    /// the same way as for the Black76 valuation of a European.
+    fn tree_prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64],
+        strike: f64, exercise: TreeExercise, steps: usize) -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        assert!(steps > 0);
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(self.underlying.credit_id(), self.pay_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| context.forward_curve(&*self.underlying, expiry_date))?;
+        let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlying of an option must itself be priceable"))?;
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            if *date > self.expiry {
+                *output = 0.0;
+                continue;
+            }
+
+            // lay out the steps of the tree between the val date and expiry
+            let days = (expiry_date - date.date()) as f64;
+            let mut step_dates = Vec::with_capacity(steps + 1);
+            step_dates.push(*date);
+            for i in 1..steps {
+                let offset = (days * i as f64 / steps as f64).round() as i32;
+                step_dates.push(DateTime::new(date.date() + offset,
+                    self.expiry.time_of_day()).max(*date));
+            }
+            step_dates.push(self.expiry);
+
+            // forwards of the log-normal part of the underlying, plus the
+            // discount factor from the base date of the yield curve to the
+            // settlement date of exercise at each step
+            let mut forwards = vec![NAN; steps + 1];
+            underlying.prices(context, &step_dates, &mut forwards)?;
+            let mut displacements = Vec::with_capacity(steps + 1);
+            let mut dfs = Vec::with_capacity(steps + 1);
+            for (step_date, forward) in step_dates.iter().zip(forwards.iter_mut()) {
+                let displacement = vol.displacement(step_date.date())?;
+                *forward -= displacement;
+                if *forward < 0.0 {
+                    return Err(qm::Error::new("Negative forward"));
+                }
+                displacements.push(displacement);
+                let settlement_date = self.settlement.apply(step_date.date());
+                dfs.push((-yc.rt(settlement_date)?).exp());
+            }
+
+            let val_date = self.underlying.time_to_day_fraction(*date)?;
+            let variance = vol.forward_variance(val_date, self.expiry_time, strike)?;
+            if variance < 0.0 {
+                return Err(qm::Error::new("Negative variance"));
+            }
+            let up = (variance / steps as f64).sqrt().exp();
+            let exercisable = match exercise {
+                TreeExercise::Any => vec![true; steps + 1],
+                TreeExercise::Schedule(schedule) => {
+                    let step_of = |exercise_date: Date| if days > 0.0 {
+                        ((exercise_date - date.date()) as f64 * steps as f64 / days)
+                            .round() as usize
+                    } else {
+                        0
+                    };
+                    schedule.steps(*date, steps, &step_of)
+                }
+            };
+            let scale = 0.5 * (up + 1.0 / up);
+
+            // The underlying at node j of step i, where j counts the up moves
+            let node = |i: usize, j: usize| forwards[i]
+                * up.powi(2 * j as i32 - i as i32) / scale.powi(i as i32);
+
+            // roll back through the tree, working in values discounted to
+            // the base date of the yield curve
+            let mut values : Vec<f64> = (0..(steps + 1)).map(|j| dfs[steps]
+                * self.intrinsic(strike - displacements[steps], node(steps, j)))
+                .collect();
+            for i in (0..steps).rev() {
+                for j in 0..(i + 1) {
+                    let mut value = 0.5 * (values[j] + values[j + 1]);
+                    if exercisable[i] {
+                        let exercise = dfs[i]
+                            * self.intrinsic(strike - displacements[i], node(i, j));
+                        value = value.max(exercise);
+                    }
+                    values[j] = value;
+                }
+            }
+
+            // discount to the settlement date of the val date
+            *output = values[0] / dfs[0];
+        }
+
+        Ok(())
+    }
 }
 
 /// A European option gives the buyer the option but not the obligation to
@@ -176,11 +436,23 @@ impl VanillaOption {
 /// volatile, time value means there is a possibility of the underlying price
 /// going up or down. The optionality means that the downside is limited, but
 /// not the upside, so time value generally means the option is worth more.
+///
+/// With the early exercise flag set, this is an American option, which the
+/// holder may exercise at any time up to and including expiry. If exercised
+/// early, the payoff is paid at the settlement date of the exercise date.
+/// Early exercise is never optimal for a call on an underlier that pays no
+/// dividends, but it may be for a put, or for a call just before a
+/// dividend. There is no closed form, so an American prices itself on a
+/// binomial tree, and is not priceable by plain Monte-Carlo. Lattice, PDE
+/// and least squares pricers make the exercise decision themselves, via
+/// the Exercisable interface.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SpotStartingEuropean {
     #[serde(flatten)]
     vanilla: VanillaOption,
     strike: f64,
+    #[serde(default)]
+    early_exercise: bool,
 }
 
 impl TypeId for SpotStartingEuropean {
@@ -217,6 +489,39 @@ impl TypeId for ForwardStartingEuropean {
     fn type_id(&self) -> &'static str { "ForwardStartingEuropean" }
 }
 
+/// Number of steps used when an American or Bermudan option prices itself
+/// on a tree
+const AMERICAN_TREE_STEPS: usize = 200;
+
+/// When the holder of an option priced on a tree may exercise it, other
+/// than at expiry
+#[derive(Clone, Copy)]
+enum TreeExercise<'a> {
+    Any,
+    Schedule(&'a ExerciseSchedule)
+}
+
+/// A Bermudan option is between a European and an American. The holder may
+/// exercise on any of a schedule of dates, the last of which is the expiry.
+/// If exercised early, the payoff is paid at the settlement date of the
+/// exercise date.
+///
+/// Like an American, the option prices itself on a binomial tree, and is
+/// Exercisable. The exercise dates are also registered as dependencies, so
+/// that when time moves past one of them, the holder exercises if the
+/// payoff on the forward is worth more than the remaining option.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpotStartingBermudan {
+    #[serde(flatten)]
+    vanilla: VanillaOption,
+    strike: f64,
+    schedule: ExerciseSchedule
+}
+
+impl TypeId for SpotStartingBermudan {
+    fn type_id(&self) -> &'static str { "SpotStartingBermudan" }
+}
+
 impl SpotStartingEuropean {
     pub fn new(
         id: &str,
@@ -234,18 +539,29 @@ impl SpotStartingEuropean {
         } else {
             let vanilla = VanillaOption::new(id, credit_id, underlying,
                 settlement, expiry, put_or_call, cash_or_physical)?;
-            Ok(SpotStartingEuropean { vanilla: vanilla, strike: strike })
+            Ok(SpotStartingEuropean { vanilla: vanilla, strike: strike,
+                early_exercise: false })
         }
     }
 
+    /// Returns a copy of this option, which may be exercised early if the
+    /// flag is set, making it an American
+    pub fn with_early_exercise(&self, early_exercise: bool) -> SpotStartingEuropean {
+        SpotStartingEuropean { vanilla: self.vanilla.clone(), strike: self.strike,
+            early_exercise: early_exercise }
+    }
+
     fn from_vanilla(vanilla: VanillaOption, strike: f64)
         -> SpotStartingEuropean {
-        SpotStartingEuropean { vanilla: vanilla, strike: strike }
+        SpotStartingEuropean { vanilla: vanilla, strike: strike, early_exercise: false }
     }
 
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
         Ok(Qrc::new(Arc::new(SpotStartingEuropean::deserialize(de)?)))
     }
+
+    pub fn strike(&self) -> f64 { self.strike }
+    pub fn put_or_call(&self) -> PutOrCall { self.vanilla.put_or_call }
 }
 
 impl ForwardStartingEuropean {
@@ -278,6 +594,37 @@ impl ForwardStartingEuropean {
     }
 }
 
+impl SpotStartingBermudan {
+    /// Creates a Bermudan option, which expires on the last date of the
+    /// exercise schedule.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        schedule: ExerciseSchedule,
+        strike: f64,
+        put_or_call: PutOrCall,
+        cash_or_physical: OptionSettlement)
+        -> Result<SpotStartingBermudan, qm::Error> {
+
+        if strike < 0.0 {
+            Err(qm::Error::new("Strike must be greater or equal to zero"))
+        } else {
+            let vanilla = VanillaOption::new(id, credit_id, underlying,
+                settlement, schedule.last(), put_or_call, cash_or_physical)?;
+            Ok(SpotStartingBermudan { vanilla: vanilla, strike: strike,
+                schedule: schedule })
+        }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SpotStartingBermudan::deserialize(de)?)))
+    }
+
+    pub fn schedule(&self) -> &ExerciseSchedule { &self.schedule }
+}
+
 impl InstanceId for VanillaOption {
     fn id(&self) -> &str {
         &self.id
@@ -333,63 +680,116 @@ impl Instrument for SpotStartingEuropean {
     fn payoff_currency(&self) -> &Currency { self.vanilla.payoff_currency() }
     fn credit_id(&self) -> &str { self.vanilla.credit_id() }
     fn settlement(&self) -> &RcDateRule { self.vanilla.settlement() }
+    fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
+    fn as_exercisable(&self) -> Option<&Exercisable> { Some(self) }
+
+    // There is no closed form if the holder may exercise early, and the
+    // Monte-Carlo payoff would ignore the exercise
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        if self.early_exercise { None } else { Some(self) }
+    }
+    fn as_analytic(&self) -> Option<&AnalyticPriceable> {
+        if self.early_exercise { None } else { Some(self) }
+    }
+
+    // The fixings are the same with or without early exercise. Any earlier
+    // exercise is a decision by the holder, which is not visible from them.
     fn dependencies(&self, context: &mut DependencyContext)
         -> SpotRequirement { self.vanilla.dependencies(context) }
-    fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
-    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
 
     // We cannot delegate fix to the contained vanilla, because it needs
     // to know the strike
     fn fix(&self, fixing_table: &FixingTable)
         -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+        self.vanilla.fix_with_strike(fixing_table, self.strike)
+    }
 
-        // If there is an expiry fixing (error if missing and in the past),
-        // the product turns into either a cash flow, or an equity flow and
-        // a cash flow.
-        let fixing = fixing_table.get(self.vanilla.underlying.id(),
-            self.vanilla.expiry)?;
-        if let Some(spot_fixing) = fixing {
-            let mut decomp : Vec<(f64, RcInstrument)> = Vec::new();
-            let strike = self.strike;
-            let sign = match self.vanilla.put_or_call {
-                        PutOrCall::Call => 1.0,
-                        PutOrCall::Put => -1.0 };
-            let payment_id = format!("{}:payment", self.id());
-            match self.vanilla.cash_or_physical {
-
-                // cash settlement -- pay a zero coupon if payment > 0
-                OptionSettlement::Cash => {
-                    let payment = sign * (spot_fixing - strike);
-                    if payment > 0.0 {
-                        decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
-                            &payment_id, self.credit_id(), 
-                            RcCurrency::new(Arc::new(self.payoff_currency().clone())),
-                            self.vanilla.expiry, 
-                            self.vanilla.pay_date,
-                            self.vanilla.settlement.clone()))))));
-                    }
-                },
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+        self.vanilla.lifecycle_events_with_strike(fixing_table, self.strike, from, to)
+    }
+}
 
-                OptionSettlement::Physical => {
-                    if sign * (spot_fixing - strike) > 0.0 {
-                        decomp.push((-strike * sign, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
-                            &payment_id, self.credit_id(), 
-                            RcCurrency::new(Arc::new(self.payoff_currency().clone())), 
-                            self.vanilla.expiry,
-                            self.vanilla.pay_date,
-                            self.vanilla.settlement.clone()))))));
-                        decomp.push((sign, self.vanilla.underlying.clone()));
-                    }
-                }
-            }
+impl Exercisable for SpotStartingEuropean {
+    fn underlying(&self) -> &RcInstrument { &self.vanilla.underlying }
+    fn expiry(&self) -> DateTime { self.vanilla.expiry }
+    fn early_exercise(&self) -> bool { self.early_exercise }
+    fn exercise_value(&self, underlying: f64) -> f64 {
+        self.vanilla.intrinsic(self.strike, underlying)
+    }
+    fn kinks(&self) -> Vec<f64> { vec![self.strike] }
+    fn as_instrument(&self) -> &Instrument { self }
+}
+
+impl InstanceId for SpotStartingBermudan {
+    fn id(&self) -> &str { self.vanilla.id() }
+}
+
+impl Instrument for SpotStartingBermudan {
+    fn payoff_currency(&self) -> &Currency { self.vanilla.payoff_currency() }
+    fn credit_id(&self) -> &str { self.vanilla.credit_id() }
+    fn settlement(&self) -> &RcDateRule { self.vanilla.settlement() }
+    fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
+    fn as_exercisable(&self) -> Option<&Exercisable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        self.schedule.dependencies(self.id(), context);
+        self.vanilla.dependencies(context)
+    }
 
-            Ok(Some(decomp))
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+        self.vanilla.fix_with_strike(fixing_table, self.strike)
+    }
+
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+        self.vanilla.lifecycle_events_with_strike(fixing_table, self.strike, from, to)
+    }
+
+    /// The holder exercises if the payoff on the forward to the exercise
+    /// date is worth more than the option with the remaining exercise dates.
+    /// Exercise at expiry is handled by the expiry fixing.
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if !self.schedule.contains(date) || date == self.vanilla.expiry {
+            return Ok(None)
+        }
+
+        let remaining = match self.schedule.remaining_after(date) {
+            Some(schedule) => schedule,
+            None => return Ok(None)
+        };
+        let unexercised = SpotStartingBermudan { vanilla: self.vanilla.clone(),
+            strike: self.strike, schedule: remaining };
+        let continuation = unexercised.price(context, date)?;
+
+        let underlying = &self.vanilla.underlying;
+        let curve = context.forward_curve(&**underlying, self.vanilla.expiry.date())?;
+        let forward = curve.forward(date.date())?;
+        if self.vanilla.intrinsic(self.strike, forward) > continuation {
+            let pay_date = self.settlement().apply(date.date());
+            Ok(Some(self.vanilla.exercised(self.strike, forward, date, pay_date)))
         } else {
-            Ok(None)
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(unexercised))))]))
         }
     }
 }
 
+impl Exercisable for SpotStartingBermudan {
+    fn underlying(&self) -> &RcInstrument { &self.vanilla.underlying }
+    fn expiry(&self) -> DateTime { self.vanilla.expiry }
+    fn early_exercise(&self) -> bool { self.schedule.dates().len() > 1 }
+    fn exercise_schedule(&self) -> Option<&ExerciseSchedule> { Some(&self.schedule) }
+    fn exercise_value(&self, underlying: f64) -> f64 {
+        self.vanilla.intrinsic(self.strike, underlying)
+    }
+    fn kinks(&self) -> Vec<f64> { vec![self.strike] }
+    fn as_instrument(&self) -> &Instrument { self }
+}
+
 impl InstanceId for ForwardStartingEuropean {
     fn id(&self) -> &str { self.vanilla.id() }
 }
@@ -400,6 +800,7 @@ impl Instrument for ForwardStartingEuropean {
     fn settlement(&self) -> &RcDateRule { self.vanilla.settlement() }
     fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
     fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+    fn as_analytic(&self) -> Option<&AnalyticPriceable> { Some(self) }
 
     fn dependencies(&self, context: &mut DependencyContext)
         -> SpotRequirement {
@@ -437,15 +838,33 @@ impl Instrument for ForwardStartingEuropean {
             Ok(None)
         }
     }
+
+    /// Once the strike is fixed, the events are those of the spot starting
+    /// European it becomes. Striking is not itself reported as an event.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        match fixing_table.get(self.vanilla.underlying.id(), self.strike_date)? {
+            Some(f) => SpotStartingEuropean::from_vanilla(self.vanilla.clone(),
+                f * self.strike_fraction).lifecycle_events(fixing_table, from, to),
+            None => Ok(Vec::new())
+        }
+    }
 }
 
 impl Priceable for SpotStartingEuropean {
     fn as_instrument(&self) -> &Instrument { self }
 
-    // Values the European Option using the analytic formula Black 76
+    // Values the European Option using the analytic formula Black 76, or
+    // on a binomial tree if it may be exercised early
     fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
         -> Result<(), qm::Error> {
 
+        if self.early_exercise {
+            return self.vanilla.tree_prices(context, dates, out, self.strike,
+                TreeExercise::Any, AMERICAN_TREE_STEPS)
+        }
+
         let before_time = DateDayFraction::new(Date::from_nil(), 0.0);
         self.vanilla.prices(context, dates, out, before_time, 
             &|underlying| Ok((self.strike, underlying.price(context, self.vanilla.expiry)?)))
@@ -476,6 +895,47 @@ impl Priceable for ForwardStartingEuropean {
     }
 }
 
+impl Priceable for SpotStartingBermudan {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the Bermudan option on a binomial tree
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        self.vanilla.tree_prices(context, dates, out, self.strike,
+            TreeExercise::Schedule(&self.schedule), AMERICAN_TREE_STEPS)
+    }
+}
+
+impl AnalyticPriceable for SpotStartingEuropean {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Under Black this is the self price, and under Bachelier it uses the
+    /// normal vol of the underlying
+    fn analytic_price(&self, context: &PricingContext, model: &AnalyticModel,
+        val_date: DateTime) -> Result<Option<f64>, qm::Error> {
+        match *model {
+            AnalyticModel::Black => Ok(Some(Priceable::price(self, context, val_date)?)),
+            AnalyticModel::Bachelier(_) => {
+                let vol = model.normal_vol(self.vanilla.underlying.id())?;
+                Ok(Some(self.vanilla.bachelier_price(context, val_date, self.strike, vol)?))
+            }
+        }
+    }
+}
+
+impl AnalyticPriceable for ForwardStartingEuropean {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The self price is Black76 with the forward vol from the strike date
+    fn analytic_price(&self, context: &PricingContext, model: &AnalyticModel,
+        val_date: DateTime) -> Result<Option<f64>, qm::Error> {
+        match *model {
+            AnalyticModel::Black => Ok(Some(Priceable::price(self, context, val_date)?)),
+            AnalyticModel::Bachelier(_) => Ok(None)
+        }
+    }
+}
+
 impl MonteCarloPriceable for SpotStartingEuropean {
     fn as_instrument(&self) -> &Instrument { self }
 
@@ -539,6 +999,17 @@ impl MonteCarloPriceable for SpotStartingEuropean {
         // sum and discount the flows
         context.evaluate_flows(quantities.view())
     }
+
+    fn mc_adjoint_quantities<'t>(&self, context: &AdjointContext<'t>)
+        -> Result<Option<Vec<Var<'t>>>, qm::Error> {
+
+        let path = context.path(&self.vanilla.underlying)?;
+        assert_eq!(path.len(), 1);
+        let sign = match self.vanilla.put_or_call {
+            PutOrCall::Call => 1.0,
+            PutOrCall::Put => -1.0 };
+        Ok(Some(vec![((path[0] - self.strike) * sign).max(0.0)]))
+    }
 }
 
 impl MonteCarloPriceable for ForwardStartingEuropean {
@@ -606,6 +1077,18 @@ impl MonteCarloPriceable for ForwardStartingEuropean {
         // sum and discount the flows
         context.evaluate_flows(quantities.view())
     }
+
+    fn mc_adjoint_quantities<'t>(&self, context: &AdjointContext<'t>)
+        -> Result<Option<Vec<Var<'t>>>, qm::Error> {
+
+        let path = context.path(&self.vanilla.underlying)?;
+        assert_eq!(path.len(), 2);
+        let sign = match self.vanilla.put_or_call {
+            PutOrCall::Call => 1.0,
+            PutOrCall::Put => -1.0 };
+        let strike = path[0] * self.strike_fraction;
+        Ok(Some(vec![((path[1] - strike) * sign).max(0.0)]))
+    }
 }
 
 #[cfg(test)]
@@ -622,6 +1105,9 @@ mod tests {
     use data::curves::RcRateCurve;
     use data::forward::InterpolatedForward;
     use data::volsurface::FlatVolSurface;
+    use risk::marketdata::tests::sample_displaced_market_data;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
     use dates::calendar::WeekdayCalendar;
     use dates::calendar::RcCalendar;
     use dates::Date;
@@ -635,7 +1121,8 @@ mod tests {
     use serde::Serialize;
 
     struct SamplePricingContext { 
-        spot: f64
+        spot: f64,
+        dividends: bool
     }
 
     impl PricingContext for SamplePricingContext {
@@ -666,9 +1153,22 @@ mod tests {
 
             let d = Date::from_ymd(2018, 06, 01);
 
-            let points = [(d, self.spot), (d+30, 1.03 * self.spot),
-                (d+60, 0.97 * self.spot), (d+90, 0.99 * self.spot),
-                (d+120, 1.05 * self.spot)];
+            // without dividends, the forward grows at the rate of the yield
+            // curve, so it is never worth exercising a call early
+            let points = if self.dividends {
+                vec![(d, self.spot), (d+30, 1.03 * self.spot),
+                    (d+60, 0.97 * self.spot), (d+90, 0.99 * self.spot),
+                    (d+120, 1.05 * self.spot)]
+            } else {
+                let yc = self.yield_curve("OPT", d)?;
+                let rt = yc.rt(d)?;
+                let mut points = Vec::new();
+                for i in 0..10 {
+                    let date = d + 30 * i;
+                    points.push((date, self.spot * (yc.rt(date)? - rt).exp()));
+                }
+                points
+            };
             let cs = Box::new(CubicSpline::new(&points,
                 Extrap::Natural, Extrap::Natural).unwrap());
             let fwd = InterpolatedForward::new(cs);
@@ -693,7 +1193,11 @@ mod tests {
     }
 
     fn sample_pricing_context(spot: f64) -> SamplePricingContext {
-        SamplePricingContext { spot: spot }
+        SamplePricingContext { spot: spot, dividends: true }
+    }
+
+    fn sample_pricing_context_without_dividends(spot: f64) -> SamplePricingContext {
+        SamplePricingContext { spot: spot, dividends: false }
     }
 
     fn sample_fixings() -> FixingTable {
@@ -872,6 +1376,249 @@ mod tests {
             PutOrCall::Put, 10.121695405560876);
     }
 
+    #[test]
+    fn american_put_far_in_the_money_at_expiry() {
+
+        let spot = 123.4;
+        let strike = 150.0;
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+
+        check_american_value(spot, strike, expiry, PutOrCall::Put, strike - spot);
+    }
+
+    #[test]
+    fn american_call_before_expiry() {
+
+        let spot = 100.0;
+        let strike = 115.170375;
+        let expiry = DateTime::new(
+            Date::from_ymd(2018, 12, 01), TimeOfDay::Close);
+
+        check_american_value(spot, strike, expiry, PutOrCall::Call,
+            9.516605002242637);
+    }
+
+    #[test]
+    fn american_put_before_expiry() {
+
+        let spot = 100.0;
+        let strike = 115.170375;
+        let expiry = DateTime::new(
+            Date::from_ymd(2018, 12, 01), TimeOfDay::Close);
+
+        check_american_value(spot, strike, expiry, PutOrCall::Put,
+            19.200738407040856);
+    }
+
+    #[test]
+    fn american_call_without_dividends_matches_european() {
+
+        let spot = 100.0;
+        let strike = 115.170375;
+        let expiry = DateTime::new(
+            Date::from_ymd(2018, 12, 01), TimeOfDay::Close);
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+        let context = sample_pricing_context_without_dividends(spot);
+
+        // the american is priced on the tree, and the european by Black-Scholes
+        let american = sample_american(strike, expiry, PutOrCall::Call);
+        let european = sample_european(strike, expiry, PutOrCall::Call);
+        assert_approx(american.price(&context, val_date).unwrap(),
+            european.price(&context, val_date).unwrap(), 0.02);
+    }
+
+    #[test]
+    fn european_parity_with_displaced_vol() {
+
+        // the displacement changes the option prices, but not the
+        // difference between a call and a put, which is a forward
+        let strike = 90.0;
+        let expiry = sample_expiry();
+        let val_date = sample_val_date();
+        let call = sample_european(strike, expiry, PutOrCall::Call);
+        let put = sample_european(strike, expiry, PutOrCall::Put);
+
+        let undisplaced = sample_displaced_market_data(0.0);
+        let displaced = sample_displaced_market_data(10.0);
+        let forward = call.price(&undisplaced, val_date).unwrap()
+            - put.price(&undisplaced, val_date).unwrap();
+        let call_price = call.price(&displaced, val_date).unwrap();
+        let put_price = put.price(&displaced, val_date).unwrap();
+        assert_approx(call_price - put_price, forward, 1e-10);
+        assert!(call_price < call.price(&undisplaced, val_date).unwrap());
+    }
+
+    #[test]
+    fn tree_with_displaced_vol_matches_european() {
+
+        let strike = 90.0;
+        let expiry = sample_expiry();
+        let val_date = sample_val_date();
+        let market_data = sample_displaced_market_data(10.0);
+
+        // exercisable only at expiry, the bermudan is priced on the tree
+        for &put_or_call in [PutOrCall::Call, PutOrCall::Put].iter() {
+            let bermudan = sample_bermudan_on(&[expiry], strike, put_or_call);
+            let european = sample_european(strike, expiry, put_or_call);
+            assert_approx(bermudan.price(&market_data, val_date).unwrap(),
+                european.price(&market_data, val_date).unwrap(), 0.02);
+        }
+    }
+
+    #[test]
+    fn american_tagged_serde() {
+
+        let expiry = DateTime::new(
+            Date::from_ymd(2018, 12, 01), TimeOfDay::Close);
+        let american = sample_american(115.170375, expiry, PutOrCall::Put);
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+        let context = sample_pricing_context(100.0);
+        let price = american.price(&context, val_date).unwrap();
+
+        // round-trip via the polymorphic instrument interface
+        let instrument: Qrc<Instrument> = Qrc::new(Arc::new(american));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: Qrc<Instrument> = serde_json::from_str(&serialized).unwrap();
+        assert!(deserialized.as_exercisable().unwrap().early_exercise());
+        assert!(deserialized.as_mc_priceable().is_none());
+
+        let serde_price = deserialized.as_priceable().unwrap().price(&context, val_date).unwrap();
+        assert_approx(serde_price, price, 1e-12);
+    }
+
+    #[test]
+    fn bermudan_between_european_and_american() {
+
+        let spot = 100.0;
+        let strike = 115.170375;
+        let expiry = DateTime::new(
+            Date::from_ymd(2018, 12, 01), TimeOfDay::Close);
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+        let context = sample_pricing_context(spot);
+
+        let bermudan = sample_bermudan(strike, PutOrCall::Put);
+        let price = bermudan.price(&context, val_date).unwrap();
+        let american = sample_american(strike, expiry, PutOrCall::Put)
+            .price(&context, val_date).unwrap();
+        let european = sample_european(strike, expiry, PutOrCall::Put)
+            .price(&context, val_date).unwrap();
+        assert!(price >= european - 0.02 && price <= american + 1e-9,
+            "bermudan={} european={} american={}", price, european, american);
+        assert!(price > european + 0.1, "bermudan={} european={}", price, european);
+    }
+
+    #[test]
+    fn bermudan_exercisable_only_at_expiry_matches_european() {
+
+        let strike = 115.170375;
+        let expiry = DateTime::new(
+            Date::from_ymd(2018, 12, 01), TimeOfDay::Close);
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+        let context = sample_pricing_context(100.0);
+
+        for &put_or_call in [PutOrCall::Call, PutOrCall::Put].iter() {
+            let bermudan = sample_bermudan_on(&[expiry], strike, put_or_call);
+            let european = sample_european(strike, expiry, put_or_call);
+            assert!(!bermudan.early_exercise());
+            assert_approx(bermudan.price(&context, val_date).unwrap(),
+                european.price(&context, val_date).unwrap(), 0.02);
+        }
+    }
+
+    #[test]
+    fn bermudan_exercise_decision() {
+
+        let context = sample_pricing_context(100.0);
+        let bermudan = sample_bermudan(200.0, PutOrCall::Put);
+        let first = bermudan.schedule().first();
+
+        // deep in the money, with the forward at its lowest, the put is
+        // exercised into a cash payment
+        let second = bermudan.schedule().dates()[1];
+        let exercised = bermudan.exercise(&context, second).unwrap().unwrap();
+        assert_eq!(exercised.len(), 1);
+        assert_eq!(exercised[0].1.type_id(), "ZeroCoupon");
+        assert!(exercised[0].0 > 90.0, "payment={}", exercised[0].0);
+
+        // out of the money, the call lives on with the remaining dates
+        let bermudan = sample_bermudan(200.0, PutOrCall::Call);
+        let unexercised = bermudan.exercise(&context, first).unwrap().unwrap();
+        assert_eq!(unexercised.len(), 1);
+        assert_eq!(unexercised[0].1.type_id(), "SpotStartingBermudan");
+        let remaining = unexercised[0].1.as_exercisable().unwrap()
+            .exercise_schedule().unwrap().dates().len();
+        assert_eq!(remaining, bermudan.schedule().dates().len() - 1);
+
+        // there is no decision on other dates
+        let other = DateTime::new(first.date() + 1, TimeOfDay::Close);
+        assert!(bermudan.exercise(&context, other).unwrap().is_none());
+    }
+
+    #[test]
+    fn bermudan_tagged_serde() {
+
+        let bermudan = sample_bermudan(115.170375, PutOrCall::Put);
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+        let context = sample_pricing_context(100.0);
+        let price = bermudan.price(&context, val_date).unwrap();
+
+        let instrument: Qrc<Instrument> = Qrc::new(Arc::new(bermudan.clone()));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: Qrc<Instrument> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.as_exercisable().unwrap().exercise_schedule(),
+            Some(bermudan.schedule()));
+
+        let serde_price = deserialized.as_priceable().unwrap().price(&context, val_date).unwrap();
+        assert_approx(serde_price, price, 1e-12);
+    }
+
+    /// A Bermudan exercisable at the start of each month until December
+    fn sample_bermudan(strike: f64, put_or_call: PutOrCall) -> SpotStartingBermudan {
+        let dates: Vec<DateTime> = (7..13).map(|month| DateTime::new(
+            Date::from_ymd(2018, month, 01), TimeOfDay::Close)).collect();
+        sample_bermudan_on(&dates, strike, put_or_call)
+    }
+
+    fn sample_bermudan_on(dates: &[DateTime], strike: f64, put_or_call: PutOrCall)
+        -> SpotStartingBermudan {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, "BP.L", 2))));
+        let settlement = equity.settlement().clone();
+        let schedule = ExerciseSchedule::new(dates).unwrap();
+        SpotStartingBermudan::new("SampleBermudan", "OPT", equity.clone(),
+            settlement, schedule, strike, put_or_call, OptionSettlement::Cash).unwrap()
+    }
+
+    fn sample_european(strike: f64, expiry: DateTime, put_or_call: PutOrCall)
+        -> SpotStartingEuropean {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, "BP.L", 2))));
+        let settlement = equity.settlement().clone();
+        SpotStartingEuropean::new("SampleEuropean", "OPT", equity.clone(),
+            settlement, expiry, strike, put_or_call, OptionSettlement::Cash).unwrap()
+    }
+
+    fn sample_american(strike: f64, expiry: DateTime, put_or_call: PutOrCall)
+        -> SpotStartingEuropean {
+        sample_european(strike, expiry, put_or_call).with_early_exercise(true)
+    }
+
+    fn check_american_value(spot: f64, strike: f64, expiry: DateTime,
+        put_or_call: PutOrCall, expected: f64) {
+
+        let american = sample_american(strike, expiry, put_or_call);
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Open);
+        let context = sample_pricing_context(spot);
+        let price = american.price(&context, val_date).unwrap();
+        assert_approx(price, expected, 1e-8);
+
+        // an American is always worth at least as much as a European
+        let european = sample_european(strike, expiry, put_or_call);
+        let european_price = european.price(&context, val_date).unwrap();
+        assert!(price >= european_price - 0.02, "american={} european={}",
+            price, european_price);
+    }
+
     fn check_european_value(spot: f64, strike: f64, expiry: DateTime,
         put_or_call: PutOrCall, expected: f64) {
 
@@ -0,0 +1,600 @@
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use instruments::fix_all;
+use instruments::Instrument;
+use instruments::Composite;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::lifecycle_events_all;
+use data::fixings::FixingTable;
+use data::fixings::FixingRange;
+use data::fixings::RateFixing;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The tags that place a portfolio within a bank's books. Any tag that is
+/// not set is inherited from the enclosing portfolio.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct PortfolioTags {
+    pub book: Option<String>,
+    pub counterparty: Option<String>,
+    pub netting_set: Option<String>
+}
+
+impl PortfolioTags {
+    pub fn new(book: Option<&str>, counterparty: Option<&str>,
+        netting_set: Option<&str>) -> PortfolioTags {
+        PortfolioTags {
+            book: book.map(|s| s.to_string()),
+            counterparty: counterparty.map(|s| s.to_string()),
+            netting_set: netting_set.map(|s| s.to_string()) }
+    }
+
+    /// Tags with any unset values taken from the parent
+    pub fn inherit(&self, parent: &PortfolioTags) -> PortfolioTags {
+        PortfolioTags {
+            book: self.book.clone().or_else(|| parent.book.clone()),
+            counterparty: self.counterparty.clone()
+                .or_else(|| parent.counterparty.clone()),
+            netting_set: self.netting_set.clone()
+                .or_else(|| parent.netting_set.clone()) }
+    }
+
+    pub fn get(&self, tag: PortfolioTag) -> Option<&str> {
+        match tag {
+            PortfolioTag::Book => self.book.as_ref(),
+            PortfolioTag::Counterparty => self.counterparty.as_ref(),
+            PortfolioTag::NettingSet => self.netting_set.as_ref()
+        }.map(|s| s.as_str())
+    }
+}
+
+/// Identifies one of the portfolio tags, for aggregation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortfolioTag { Book, Counterparty, NettingSet }
+
+/// A portfolio is a weighted collection of instruments, some of which may
+/// themselves be portfolios, forming a tree such as desk, book and trade.
+/// Each node may be tagged with its book, counterparty and netting set.
+///
+/// A portfolio is itself an instrument, so a whole book can be handed to a
+/// pricer and risked in one pass. Its price is the weighted sum of the
+/// prices of its members, which must all be priceable and pay in the
+/// currency of the portfolio. To see how the value breaks down, use
+/// valuation, which prices each trade once and aggregates the results up
+/// the tree.
+///
+/// Portfolios are not priceable by Monte-Carlo, as each Monte-Carlo priced
+/// instrument is currently given a context of its own. Pass the positions
+/// to the Monte-Carlo pricer instead.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Portfolio {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    settlement: RcDateRule,
+    tags: PortfolioTags,
+    members: Vec<(f64, RcInstrument)>
+}
+
+impl TypeId for Portfolio {
+    fn type_id(&self) -> &'static str { "Portfolio" }
+}
+
+impl InstanceId for Portfolio {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl Portfolio {
+    /// Creates a portfolio. The credit id and settlement are only used if
+    /// the portfolio itself is treated as a tradeable, for example for the
+    /// settlement of its premium.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule, tags: PortfolioTags,
+        members: Vec<(f64, RcInstrument)>) -> Result<Portfolio, qm::Error> {
+
+        for &(_, ref member) in members.iter() {
+            if member.payoff_currency().id() != currency.id() {
+                return Err(qm::Error::new(&format!("Portfolio {} is in {} \
+                    but {} pays in {}", id, currency.id(), member.id(),
+                    member.payoff_currency().id())))
+            }
+        }
+
+        Ok(Portfolio { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, settlement: settlement, tags: tags,
+            members: members })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Portfolio::deserialize(de)?)))
+    }
+
+    /// A copy of this portfolio with different members
+    fn with_members(&self, members: Vec<(f64, RcInstrument)>)
+        -> Result<Portfolio, qm::Error> {
+        Portfolio::new(&self.id, &self.credit_id, self.currency.clone(),
+            self.settlement.clone(), self.tags.clone(), members)
+    }
+}
+
+impl Composite for Portfolio {
+    fn members(&self) -> &[(f64, RcInstrument)] { &self.members }
+    fn tags(&self) -> &PortfolioTags { &self.tags }
+    fn as_instrument(&self) -> &Instrument { self }
+}
+
+/// Prices every trade in a portfolio or other composite, and returns the
+/// values of each node of the tree
+pub fn valuation(node: &Composite, context: &PricingContext, val_date: DateTime)
+    -> Result<PortfolioValuation, qm::Error> {
+    valuation_within(node, context, val_date, &PortfolioTags::default())
+}
+
+fn valuation_within(node: &Composite, context: &PricingContext,
+    val_date: DateTime, parent: &PortfolioTags)
+    -> Result<PortfolioValuation, qm::Error> {
+
+    let tags = node.tags().inherit(parent);
+    let mut value = 0.0;
+    let mut trades = Vec::new();
+    let mut children = Vec::new();
+    for &(weight, ref member) in node.members().iter() {
+        if let Some(composite) = member.as_composite() {
+            let mut child = valuation_within(composite, context, val_date, &tags)?;
+            child.scale(weight);
+            value += child.value;
+            children.push(child);
+        } else {
+            let price = priceable(member)?.price(context, val_date)?;
+            value += weight * price;
+            trades.push(TradeValuation { id: member.id().to_string(),
+                weight: weight, price: price });
+        }
+    }
+
+    Ok(PortfolioValuation { id: node.id().to_string(), tags: tags,
+        value: value, trades: trades, children: children })
+}
+
+fn priceable(member: &RcInstrument) -> Result<&Priceable, qm::Error> {
+    member.as_priceable().ok_or_else(|| qm::Error::new(&format!(
+        "Portfolio member {} is not priceable", member.id())))
+}
+
+impl Instrument for Portfolio {
+    fn payoff_currency(&self) -> &Currency { &*self.currency }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+
+    /// The members report their dependencies as usual, except that any
+    /// exercise decisions are also registered against the portfolio, so
+    /// that it is asked to make them on behalf of its members.
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        let mut context = PortfolioDependencies { id: &self.id, inner: context };
+        for &(_, ref member) in self.members.iter() {
+            match member.dependencies(&mut context) {
+                SpotRequirement::NotRequired => {}, // nothing to do
+                _ => context.spot(member)
+            };
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_composite(&self) -> Option<&Composite> {
+        Some(self)
+    }
+
+    /// Fixing a portfolio fixes its members, keeping the same id and tags,
+    /// so that results are still reported against the same node.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+        match fix_all(&self.members, fixing_table)? {
+            Some(members) => Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(
+                Arc::new(self.with_members(members)?))))])),
+            None => Ok(None)
+        }
+    }
+
+    /// The events of the members, scaled by their weights. Each event keeps
+    /// the id of the trade it happened to.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+        lifecycle_events_all(&self.members, fixing_table, from, to)
+    }
+
+    /// Passes the exercise decision to every member, replacing any that
+    /// exercise with what they turn into
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let mut any_exercised = false;
+        let mut members = Vec::with_capacity(self.members.len());
+        for &(weight, ref member) in self.members.iter() {
+            match member.exercise(context, date)? {
+                Some(decomposition) => {
+                    any_exercised = true;
+                    for (inner, instrument) in decomposition {
+                        members.push((weight * inner, instrument));
+                    }
+                },
+                None => members.push((weight, member.clone()))
+            }
+        }
+
+        if any_exercised {
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(
+                self.with_members(members)?))))]))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Priceable for Portfolio {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The price of a portfolio is the weighted sum of its members
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for o in out.iter_mut() {
+            *o = 0.0;
+        }
+
+        let mut temp = vec!(0.0; dates.len());
+        for &(weight, ref member) in self.members.iter() {
+            priceable(member)?.prices(context, dates, &mut temp)?;
+            for (i, o) in temp.iter().zip(out.iter_mut()) {
+                *o += i * weight;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Forwards dependencies to the real context, also registering exercise
+/// decisions against the portfolio
+struct PortfolioDependencies<'a> {
+    id: &'a str,
+    inner: &'a mut DependencyContext
+}
+
+impl<'a> DependencyContext for PortfolioDependencies<'a> {
+    fn spot_date(&self) -> Date {
+        self.inner.spot_date()
+    }
+    fn yield_curve(&mut self, credit_id: &str, high_water_mark: Date) {
+        self.inner.yield_curve(credit_id, high_water_mark)
+    }
+    fn spot(&mut self, instrument: &RcInstrument) {
+        self.inner.spot(instrument)
+    }
+    fn forward_curve(&mut self, instrument: &RcInstrument, high_water_mark: Date) {
+        self.inner.forward_curve(instrument, high_water_mark)
+    }
+    fn vol_surface(&mut self, instrument: &RcInstrument, high_water_mark: Date) {
+        self.inner.vol_surface(instrument, high_water_mark)
+    }
+    fn fixing(&mut self, id: &str, date: DateTime) {
+        self.inner.fixing(id, date)
+    }
+    fn fixing_range(&mut self, id: &str, range: FixingRange) {
+        self.inner.fixing_range(id, range)
+    }
+    fn rate_fixing(&mut self, index_id: &str, fixing: RateFixing) {
+        self.inner.rate_fixing(index_id, fixing)
+    }
+    fn fx_rate(&mut self, fx_id: &str, high_water_mark: Date) {
+        self.inner.fx_rate(fx_id, high_water_mark)
+    }
+    fn vol_cube(&mut self, id: &str, high_water_mark: Date) {
+        self.inner.vol_cube(id, high_water_mark)
+    }
+    fn exercise(&mut self, id: &str, date: DateTime) {
+        self.inner.exercise(id, date);
+        self.inner.exercise(self.id, date)
+    }
+    fn hazard_curve(&mut self, credit_id: &str, high_water_mark: Date) {
+        self.inner.hazard_curve(credit_id, high_water_mark)
+    }
+    fn inflation_curve(&mut self, index_id: &str, high_water_mark: Date) {
+        self.inner.inflation_curve(index_id, high_water_mark)
+    }
+    fn inflation_fixing(&mut self, index_id: &str, month: Date) {
+        self.inner.inflation_fixing(index_id, month)
+    }
+    fn dividends(&mut self, instrument: &RcInstrument, high_water_mark: Date) {
+        self.inner.dividends(instrument, high_water_mark)
+    }
+}
+
+/// The value of one trade within a portfolio. The price is per unit, and
+/// the weight is the number of units held, multiplied through any enclosing
+/// portfolios.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TradeValuation {
+    pub id: String,
+    pub weight: f64,
+    pub price: f64
+}
+
+impl TradeValuation {
+    pub fn value(&self) -> f64 { self.weight * self.price }
+}
+
+/// The value of one node of a portfolio, with the trades directly within
+/// it and the valuations of its sub-portfolios. The tags include any
+/// inherited from enclosing portfolios, and values are weighted by the
+/// holding of this node within its parents.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct PortfolioValuation {
+    pub id: String,
+    pub tags: PortfolioTags,
+    pub value: f64,
+    pub trades: Vec<TradeValuation>,
+    pub children: Vec<PortfolioValuation>
+}
+
+impl PortfolioValuation {
+    fn scale(&mut self, weight: f64) {
+        self.value *= weight;
+        for trade in self.trades.iter_mut() {
+            trade.weight *= weight;
+        }
+        for child in self.children.iter_mut() {
+            child.scale(weight);
+        }
+    }
+
+    /// Finds the valuation of the node with the given id, searching this
+    /// node and then its descendants, depth first
+    pub fn find(&self, id: &str) -> Option<&PortfolioValuation> {
+        if self.id == id {
+            return Some(self)
+        }
+        self.children.iter().filter_map(|child| child.find(id)).next()
+    }
+
+    /// Totals the values of all trades, grouped by the given tag. Trades in
+    /// nodes that have no such tag are grouped under the empty string.
+    pub fn totals_by(&self, tag: PortfolioTag) -> BTreeMap<String, f64> {
+        let mut totals = BTreeMap::new();
+        self.add_totals(tag, &mut totals);
+        totals
+    }
+
+    fn add_totals(&self, tag: PortfolioTag, totals: &mut BTreeMap<String, f64>) {
+        let direct: f64 = self.trades.iter().map(|t| t.value()).sum();
+        if !self.trades.is_empty() {
+            let key = self.tags.get(tag).unwrap_or("").to_string();
+            *totals.entry(key).or_insert(0.0) += direct;
+        }
+        for child in self.children.iter() {
+            child.add_totals(tag, totals);
+        }
+    }
+
+    /// The exposure to counterparty default, allowing for netting. Within a
+    /// netting set, trades are netted so only a positive total is exposed.
+    /// Trades outside any netting set are not netted at all.
+    pub fn netted_exposure(&self) -> f64 {
+        let mut exposure = 0.0;
+        self.add_unnetted_exposure(&mut exposure);
+        for (netting_set, value) in self.totals_by(PortfolioTag::NettingSet) {
+            if !netting_set.is_empty() {
+                exposure += value.max(0.0);
+            }
+        }
+        exposure
+    }
+
+    fn add_unnetted_exposure(&self, exposure: &mut f64) {
+        if self.tags.netting_set.is_none() {
+            *exposure += self.trades.iter().map(|t| t.value().max(0.0)).sum::<f64>();
+        }
+        for child in self.children.iter() {
+            child.add_unnetted_exposure(exposure);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::choosers::ChooserOption;
+    use risk::Pricer;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use data::bump::Bump;
+    use data::bumpspot::BumpSpot;
+    use data::bumpspotdate::SpotDynamics;
+    use pricers::selfpricer::SelfPricer;
+    use dates::datetime::TimeOfDay;
+    use serde_json;
+
+    fn currency(id: &str) -> RcCurrency {
+        let gbp = sample_currency(2);
+        RcCurrency::new(Arc::new(Currency::new(id, gbp.settlement().clone())))
+    }
+
+    pub fn sample_option(id: &str, strike: f64, put_or_call: PutOrCall) -> RcInstrument {
+        let underlying = sample_underlying();
+        RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(id, "OPT",
+            underlying, sample_settlement(2),
+            sample_expiry(),
+            strike, put_or_call, OptionSettlement::Cash).unwrap())))
+    }
+
+    fn portfolio(id: &str, tags: PortfolioTags, members: Vec<(f64, RcInstrument)>)
+        -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(Portfolio::new(id, "OPT",
+            currency("GBP"), sample_settlement(2), tags, members).unwrap())))
+    }
+
+    /// A desk with two books. The first book has trades with two
+    /// counterparties, one of which has a netting agreement. The second
+    /// book is held twice.
+    fn sample_desk() -> RcInstrument {
+        let netted = portfolio("Netted", PortfolioTags::new(None, Some("ACME"),
+            Some("ACME-ISDA")), vec![
+                (10.0, sample_option("Call90", 90.0, PutOrCall::Call)),
+                (-5.0, sample_option("Call110", 110.0, PutOrCall::Call))]);
+        let book1 = portfolio("Book1", PortfolioTags::new(Some("Book1"), None, None), vec![
+            (1.0, netted),
+            (-3.0, sample_option("Put100", 100.0, PutOrCall::Put))]);
+        let book2 = portfolio("Book2", PortfolioTags::new(Some("Book2"),
+            Some("ACME"), None), vec![(1.0, sample_option("Call100", 100.0, PutOrCall::Call))]);
+        portfolio("Desk", PortfolioTags::default(), vec![(1.0, book1), (2.0, book2)])
+    }
+
+    fn price(instrument: &RcInstrument) -> f64 {
+        let val_date = sample_val_date();
+        instrument.as_priceable().unwrap().price(&sample_market_data(), val_date).unwrap()
+    }
+
+    #[test]
+    fn portfolio_valuation_aggregates_by_node() {
+        let desk = sample_desk();
+        let call90 = price(&sample_option("Call90", 90.0, PutOrCall::Call));
+        let call100 = price(&sample_option("Call100", 100.0, PutOrCall::Call));
+        let call110 = price(&sample_option("Call110", 110.0, PutOrCall::Call));
+        let put100 = price(&sample_option("Put100", 100.0, PutOrCall::Put));
+        let netted = 10.0 * call90 - 5.0 * call110;
+        let book1 = netted - 3.0 * put100;
+        let book2 = 2.0 * call100;
+        assert_approx(price(&desk), book1 + book2);
+
+        let val_date = sample_val_date();
+        let valuation = valuation(desk.as_composite().unwrap(),
+            &sample_market_data(), val_date).unwrap();
+        assert_approx(valuation.value, book1 + book2);
+        assert_approx(valuation.find("Book1").unwrap().value, book1);
+        assert_approx(valuation.find("Book2").unwrap().value, book2);
+        let node = valuation.find("Netted").unwrap();
+        assert_approx(node.value, netted);
+        assert_eq!(node.tags, PortfolioTags::new(Some("Book1"), Some("ACME"),
+            Some("ACME-ISDA")));
+        assert!(valuation.find("Missing").is_none());
+
+        // the second book is held twice, so its trade weight is doubled
+        let trade = &valuation.find("Book2").unwrap().trades[0];
+        assert_eq!(trade.id, "Call100");
+        assert_approx(trade.weight, 2.0);
+        assert_approx(trade.price, call100);
+
+        let by_book = valuation.totals_by(PortfolioTag::Book);
+        assert_eq!(by_book.len(), 2);
+        assert_approx(by_book["Book1"], book1);
+        assert_approx(by_book["Book2"], book2);
+        let by_counterparty = valuation.totals_by(PortfolioTag::Counterparty);
+        assert_approx(by_counterparty["ACME"], netted + book2);
+        assert_approx(by_counterparty[""], -3.0 * put100);
+
+        // the short call is netted against the long one, but short trades
+        // outside the netting set cannot offset anything
+        assert_approx(valuation.netted_exposure(), netted + book2);
+
+        let positions = desk.as_composite().unwrap().positions();
+        assert_eq!(positions.len(), 4);
+        assert_approx(positions[3].0, 2.0);
+        assert_eq!(positions[3].1.id(), "Call100");
+    }
+
+    #[test]
+    fn portfolio_risk_in_one_pass() {
+        let market_data = sample_market_data();
+        let desk = sample_desk();
+        let mut pricer = SelfPricer::new(vec![(1.0, desk.clone())], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+
+        // the same as bumping each trade separately
+        let positions = desk.as_composite().unwrap().positions();
+        let mut trades = SelfPricer::new(positions, &market_data).unwrap();
+        let trades_unbumped = trades.price().unwrap();
+        assert!(trades.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert_approx(unbumped, trades_unbumped);
+        assert_approx(bumped, trades.price().unwrap());
+    }
+
+    #[test]
+    fn portfolio_currency_mismatch() {
+        let option = sample_option("Call100", 100.0, PutOrCall::Call);
+        assert!(Portfolio::new("Book", "OPT", currency("USD"), sample_settlement(2),
+            PortfolioTags::default(), vec![(1.0, option)]).is_err());
+    }
+
+    #[test]
+    fn portfolio_exercises_members() {
+        let market_data = sample_market_data();
+        let underlying = sample_underlying();
+        let chooser = RcInstrument::new(Qrc::new(Arc::new(ChooserOption::new_simple(
+            "Chooser", "OPT", underlying, sample_settlement(2),
+            DateTime::new(Date::from_ymd(2017, 01, 03), TimeOfDay::Close),
+            sample_expiry(),
+            90.0, OptionSettlement::Cash).unwrap())));
+        let book = portfolio("Book", PortfolioTags::default(), vec![
+            (2.0, chooser), (1.0, sample_option("Put100", 100.0, PutOrCall::Put))]);
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        dependencies.spot(&book);
+
+        let mut instruments = vec![(1.0, book)];
+        let new_date = Date::from_ymd(2017, 01, 05);
+        let bump = BumpTime::new(new_date, new_date, SpotDynamics::StickySpot);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].1.id(), "Book");
+        let members = instruments[0].1.as_composite().unwrap().members();
+        assert_eq!(members.len(), 2);
+        assert_approx(members[0].0, 2.0);
+        assert_eq!(members[0].1.id(), "Chooser:call");
+    }
+
+    #[test]
+    fn portfolio_serde() {
+        let serialized = serde_json::to_string_pretty(&sample_desk()).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.id(), "Desk");
+        assert_approx(price(&deserialized), price(&sample_desk()));
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-10),
+            "value={} expected={}", value, expected);
+    }
+}
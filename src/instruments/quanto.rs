@@ -0,0 +1,488 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::AnalyticPriceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::ExchangeRate;
+use data::curves::RcRateCurve;
+use data::forward::Forward;
+use data::forward::QuantoForward;
+use data::volsurface::RcVolSurface;
+use data::volcube::RcVolCube;
+use data::inflation::RcInflationCurve;
+use data::fixings::FixingTable;
+use pricers::analytic::AnalyticModel;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A quanto wraps an instrument whose payoff is measured in the currency of
+/// a foreign underlying, and pays it in a domestic currency at a fixed rate
+/// of exchange. For example, a quanto call on a UK equity paying in dollars
+/// pays fx_rate * max(S - K, 0) dollars, whatever the GBPUSD rate at expiry.
+///
+/// The holder of a quanto is exposed to the correlation between the
+/// underlying and the FX rate, which shows up as a correction to the drift
+/// of the underlying. The FX rate is identified by an id such as "GBPUSD",
+/// and must be quoted as units of the payment currency per unit of the
+/// underlying's currency. Its spot and vol surface must be supplied in the
+/// market data, along with its correlation with the underlying.
+///
+/// The wrapped instrument should be set up to discount on the curve of the
+/// payment currency, as that is the currency in which it actually pays.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Quanto {
+    id: String,
+    inner: RcInstrument,
+    underlying: RcInstrument,
+    currency: RcCurrency,
+    fx_id: String,
+    fx_rate: f64
+}
+
+impl TypeId for Quanto {
+    fn type_id(&self) -> &'static str { "Quanto" }
+}
+
+impl Quanto {
+    /// Creates a quanto version of the inner instrument, paying in the given
+    /// currency. The underlying is the asset whose drift must be corrected,
+    /// and fx_rate is the fixed rate at which the payoff is converted.
+    pub fn new(id: &str, inner: RcInstrument, underlying: RcInstrument,
+        currency: RcCurrency, fx_id: &str, fx_rate: f64)
+        -> Result<Quanto, qm::Error> {
+
+        if fx_rate <= 0.0 {
+            return Err(qm::Error::new("Quanto FX rate must be positive"))
+        }
+
+        Ok(Quanto {
+            id: id.to_string(),
+            inner: inner,
+            underlying: underlying,
+            currency: currency,
+            fx_id: fx_id.to_string(),
+            fx_rate: fx_rate })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Quanto::deserialize(de)?)))
+    }
+
+    pub fn inner(&self) -> &RcInstrument {
+        &self.inner
+    }
+
+    pub fn fx_id(&self) -> &str {
+        &self.fx_id
+    }
+
+    pub fn fx_rate(&self) -> f64 {
+        self.fx_rate
+    }
+}
+
+impl InstanceId for Quanto {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for Quanto {
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        self.inner.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.inner.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // The wrapper does not know how far out the inner instrument looks,
+        // so the FX rate is registered as of spot. The FX vol is fetched
+        // directly from the market data, so this does not restrict it.
+        context.fx_rate(&self.fx_id, context.spot_date());
+        self.inner.dependencies(context)
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        if self.inner.as_mc_priceable().is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn as_analytic(&self) -> Option<&AnalyticPriceable> {
+        if self.inner.as_analytic().is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Fixing the inner instrument may decompose it into other instruments,
+    /// such as payments. Each of these is still paid at the fixed rate, so
+    /// each is wrapped as a quanto in its turn.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if let Some(decomp) = self.inner.fix(fixing_table)? {
+            let mut quantos = Vec::with_capacity(decomp.len());
+            for (weight, component) in decomp.iter() {
+                let quanto = Quanto::new(
+                    &format!("{}:{}", self.id, component.id()),
+                    component.clone(), self.underlying.clone(),
+                    self.currency.clone(), &self.fx_id, self.fx_rate)?;
+                quantos.push((*weight, RcInstrument::new(Qrc::new(Arc::new(quanto)))));
+            }
+            Ok(Some(quantos))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Priceable for Quanto {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the inner instrument in a context where the forward of the
+    /// underlying carries the quanto drift correction, then converts at the
+    /// fixed rate.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        let inner = self.inner.as_priceable().ok_or_else(|| qm::Error::new(
+            "The inner instrument of a quanto must be priceable"))?;
+
+        let quanto_context = QuantoContext {
+            context: context,
+            underlying: self.underlying.id(),
+            fx_id: &self.fx_id };
+        inner.prices(&quanto_context, dates, out)?;
+
+        for output in out.iter_mut() {
+            *output *= self.fx_rate;
+        }
+        Ok(())
+    }
+}
+
+impl AnalyticPriceable for Quanto {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Under Black, values the closed form of the inner instrument in a
+    /// context where the forward carries the quanto drift correction, then
+    /// converts at the fixed rate. The correction needs log-normal vols, so
+    /// there is no closed form under other models.
+    fn analytic_price(&self, context: &PricingContext, model: &AnalyticModel,
+        val_date: DateTime) -> Result<Option<f64>, qm::Error> {
+
+        if *model != AnalyticModel::Black {
+            return Ok(None)
+        }
+        let inner = self.inner.as_analytic().ok_or_else(|| qm::Error::new(
+            "The inner instrument of the quanto has no closed form"))?;
+
+        let quanto_context = QuantoContext {
+            context: context,
+            underlying: self.underlying.id(),
+            fx_id: &self.fx_id };
+        Ok(inner.analytic_price(&quanto_context, model, val_date)?
+            .map(|price| price * self.fx_rate))
+    }
+}
+
+impl MonteCarloPriceable for Quanto {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // ask the model to evolve the underlying in the payment measure
+        output.quanto(&self.underlying, &self.fx_id);
+        self.mc_inner()?.mc_dependencies(dates, output)
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        self.inner.as_mc_priceable().and_then(|inner| inner.start_date())
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+        Ok(self.fx_rate * self.mc_inner()?.mc_price(context)?)
+    }
+}
+
+impl Quanto {
+    fn mc_inner(&self) -> Result<&MonteCarloPriceable, qm::Error> {
+        self.inner.as_mc_priceable().ok_or_else(|| qm::Error::new(
+            "The inner instrument of a quanto must be Monte-Carlo priceable"))
+    }
+}
+
+/// Decorates a pricing context, so that the forward of the underlying of a
+/// quanto has the quanto drift correction applied. Everything else is passed
+/// straight through.
+struct QuantoContext<'a> {
+    context: &'a PricingContext,
+    underlying: &'a str,
+    fx_id: &'a str
+}
+
+impl<'a> PricingContext for QuantoContext<'a> {
+    fn spot_date(&self) -> Date {
+        self.context.spot_date()
+    }
+
+    fn yield_curve(&self, credit_id: &str, high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        self.context.yield_curve(credit_id, high_water_mark)
+    }
+
+    fn spot(&self, id: &str) -> Result<f64, qm::Error> {
+        self.context.spot(id)
+    }
+
+    fn forward_curve(&self, instrument: &Instrument, high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+
+        let forward = self.context.forward_curve(instrument, high_water_mark)?;
+        if instrument.id() != self.underlying {
+            return Ok(forward)
+        }
+
+        let asset_vol = self.context.vol_surface(instrument, high_water_mark,
+            &|| Ok(forward.clone()))?;
+        let fx_vol = self.context.fx_vol_surface(self.fx_id, high_water_mark)?;
+        let fx_spot = self.context.fx_spot(self.fx_id)?;
+        let correlation = self.context.correlation_by_id(
+            self.underlying, self.fx_id)?;
+        Ok(Arc::new(QuantoForward::new(forward, asset_vol, fx_vol, fx_spot,
+            correlation)))
+    }
+
+    fn vol_surface(&self, instrument: &Instrument, high_water_mark: Date,
+        forward_fn: &Fn() -> Result<Arc<Forward>, qm::Error>)
+        -> Result<RcVolSurface, qm::Error> {
+        self.context.vol_surface(instrument, high_water_mark, forward_fn)
+    }
+
+    fn correlation(&self, first: &Instrument, second: &Instrument)
+        -> Result<f64, qm::Error> {
+        self.context.correlation(first, second)
+    }
+
+    fn average_correlation(&self, first: &Instrument, second: &Instrument,
+        from: Date, to: Date) -> Result<f64, qm::Error> {
+        self.context.average_correlation(first, second, from, to)
+    }
+
+    fn fx_spot(&self, fx_id: &str) -> Result<f64, qm::Error> {
+        self.context.fx_spot(fx_id)
+    }
+
+    fn fx_forward_curve(&self, pair: &ExchangeRate, high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+        self.context.fx_forward_curve(pair, high_water_mark)
+    }
+
+    fn fx_vol_surface(&self, fx_id: &str, high_water_mark: Date)
+        -> Result<RcVolSurface, qm::Error> {
+        self.context.fx_vol_surface(fx_id, high_water_mark)
+    }
+
+    fn correlation_by_id(&self, first: &str, second: &str)
+        -> Result<f64, qm::Error> {
+        self.context.correlation_by_id(first, second)
+    }
+
+    fn vol_cube(&self, id: &str, high_water_mark: Date)
+        -> Result<RcVolCube, qm::Error> {
+        self.context.vol_cube(id, high_water_mark)
+    }
+
+    fn hazard_curve(&self, credit_id: &str, high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        self.context.hazard_curve(credit_id, high_water_mark)
+    }
+
+    fn inflation_curve(&self, index_id: &str, high_water_mark: Date)
+        -> Result<RcInflationCurve, qm::Error> {
+        self.context.inflation_curve(index_id, high_water_mark)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::Pricer;
+    use data::volsurface::FlatVolSurface;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use serde_json;
+    use std::collections::HashMap;
+
+    fn sample_quanto(correlation: f64) -> (Quanto, MarketData) {
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+            equity.clone(), sample_settlement(2), expiry, 100.0,
+            PutOrCall::Call, OptionSettlement::Cash).unwrap();
+
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let settlement = sample_settlement(2);
+        let usd = RcCurrency::new(Arc::new(Currency::new("USD", settlement)));
+        let quanto = Quanto::new("SampleQuanto",
+            RcInstrument::new(Qrc::new(Arc::new(european))), equity, usd,
+            "GBPUSD", 1.3).unwrap();
+
+        let mut market_data = sample_market_data();
+        let base = DateDayFraction::new(Date::from_ymd(2016, 12, 30), 0.2);
+        market_data.add_fx_vol_surface("GBPUSD", RcVolSurface::new(Arc::new(
+            FlatVolSurface::new(0.1, calendar, base))));
+        market_data.set_spot("GBPUSD", 1.25);
+        market_data.set_correlation("BP.L", "GBPUSD", correlation).unwrap();
+
+        (quanto, market_data)
+    }
+
+    #[test]
+    fn uncorrelated_quanto_is_converted_european() {
+        let (quanto, market_data) = sample_quanto(0.0);
+        let price = quanto.price(&market_data, sample_val_date()).unwrap();
+        assert_approx(price, 1.3 * 16.710717400832973, 1e-12);
+    }
+
+    #[test]
+    fn correlation_moves_quanto_call() {
+        // If the underlying tends to fall when the foreign currency
+        // strengthens, the quanto forward is higher, so calls are worth more
+        let (uncorrelated, market_data) = sample_quanto(0.0);
+        let base = uncorrelated.price(&market_data, sample_val_date()).unwrap();
+
+        let (quanto, market_data) = sample_quanto(-0.5);
+        let negative = quanto.price(&market_data, sample_val_date()).unwrap();
+        let (quanto, market_data) = sample_quanto(0.5);
+        let positive = quanto.price(&market_data, sample_val_date()).unwrap();
+        assert!(negative > base + 0.1, "negative={} base={}", negative, base);
+        assert!(positive < base - 0.1, "positive={} base={}", positive, base);
+    }
+
+    #[test]
+    fn quanto_fails_without_fx_data() {
+        let (quanto, _) = sample_quanto(0.0);
+        let market_data = sample_market_data();
+        assert!(quanto.price(&market_data, sample_val_date()).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_matches_analytic() {
+        let (quanto, market_data) = sample_quanto(-0.5);
+        let analytic = quanto.price(&market_data, sample_val_date()).unwrap();
+
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(quanto)));
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::with_settings(vec![(1.0, instrument)], factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let result = pricer.price_with_statistics(1).unwrap().unwrap();
+
+        // the paths are seeded, and with 20000 of them the standard error
+        // is about 0.2
+        assert_approx(result.price(), analytic, 3.0 * result.standard_error());
+    }
+
+    #[test]
+    fn closed_form_matches_price() {
+        let (quanto, market_data) = sample_quanto(-0.5);
+        let expected = quanto.price(&market_data, sample_val_date()).unwrap();
+        let analytic = quanto.as_analytic().unwrap();
+        let price = analytic.analytic_price(&market_data, &AnalyticModel::Black,
+            sample_val_date()).unwrap().unwrap();
+        assert_approx(price, expected, 1e-12);
+
+        let model = AnalyticModel::Bachelier(HashMap::new());
+        assert!(analytic.analytic_price(&market_data, &model,
+            sample_val_date()).unwrap().is_none());
+    }
+
+    #[test]
+    fn quanto_fixes_to_quanto_payment() {
+        let (quanto, market_data) = sample_quanto(-0.5);
+        let expiry = sample_expiry();
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 110.0)])]).unwrap();
+        let decomp = quanto.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+
+        // the payment is still converted at the fixed rate and pays dollars
+        let (weight, ref payment) = decomp[0];
+        assert_approx(weight, 10.0, 1e-12);
+        assert_eq!(payment.payoff_currency().id(), "USD");
+        let val_date = sample_val_date();
+        let price = payment.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        assert!(price > 1.0 && price < 1.3, "price={}", price);
+    }
+
+    #[test]
+    fn quanto_tagged_serde() {
+        let (quanto, market_data) = sample_quanto(-0.5);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(quanto.clone())));
+
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = quanto.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,415 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// Which of the performances in the basket a rainbow option pays off on.
+/// NthBest(1) is the same as BestOf, and NthBest(n) where n is the number of
+/// assets is the same as WorstOf.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RainbowRank { BestOf, WorstOf, NthBest(usize) }
+
+/// A rainbow option pays off on one of the performances of a basket of
+/// underlyings, chosen by rank, for example the best or the worst performer.
+/// The performance of each underlying is its level at expiry divided by its
+/// initial level, and the strike is also expressed as a performance. The
+/// payoff of a call is notional * max(P - K, 0) and of a put
+/// notional * max(K - P, 0), where P is the chosen performance.
+///
+/// The payoff depends on the joint distribution of the underlyings, so the
+/// option is valued by Monte-Carlo using the correlations in the market data.
+/// Each underlying is registered as a dependency, so delta and vega are
+/// reported per underlying by the standard risk reports.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RainbowOption {
+    id: String,
+    credit_id: String,
+    underlyings: Vec<RcInstrument>,
+    initial_levels: Vec<f64>,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+    rank: RainbowRank,
+    notional: f64,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for RainbowOption {
+    fn type_id(&self) -> &'static str { "RainbowOption" }
+}
+
+impl RainbowOption {
+    /// Creates a rainbow option. There must be at least one underlying, all
+    /// paying in the same currency and all observed on the same calendar,
+    /// with an initial level for each.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlyings: &[RcInstrument],
+        initial_levels: &[f64],
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall,
+        rank: RainbowRank,
+        notional: f64) -> Result<RainbowOption, qm::Error> {
+
+        if underlyings.is_empty() {
+            return Err(qm::Error::new("Rainbow option has no underlyings"))
+        }
+        if underlyings.len() != initial_levels.len() {
+            return Err(qm::Error::new(
+                "Rainbow option needs one initial level per underlying"))
+        }
+        if initial_levels.iter().any(|level| *level <= 0.0) {
+            return Err(qm::Error::new(
+                "Rainbow initial levels must be positive"))
+        }
+        let currency = underlyings[0].payoff_currency().id();
+        if underlyings.iter().any(|u| u.payoff_currency().id() != currency) {
+            return Err(qm::Error::new(
+                "Rainbow underlyings must all pay in the same currency"))
+        }
+        if strike < 0.0 {
+            return Err(qm::Error::new("Rainbow strike must not be negative"))
+        }
+        if let RainbowRank::NthBest(n) = rank {
+            if n == 0 || n > underlyings.len() {
+                return Err(qm::Error::new(&format!(
+                    "Rainbow rank {} is out of range 1..{}", n, underlyings.len())))
+            }
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = underlyings[0].time_to_day_fraction(expiry)?;
+        Ok(RainbowOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlyings: underlyings.to_vec(),
+            initial_levels: initial_levels.to_vec(),
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            rank: rank,
+            notional: notional,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RainbowOption::deserialize(de)?)))
+    }
+
+    pub fn underlyings(&self) -> &[RcInstrument] {
+        &self.underlyings
+    }
+
+    /// Index of the chosen performance, once the performances are sorted
+    /// best first
+    fn rank_index(&self) -> usize {
+        match self.rank {
+            RainbowRank::BestOf => 0,
+            RainbowRank::WorstOf => self.underlyings.len() - 1,
+            RainbowRank::NthBest(n) => n - 1
+        }
+    }
+
+    /// Calculates the payoff given the levels of the underlyings at expiry.
+    /// The performances buffer is used as workspace, to avoid allocation.
+    fn payoff(&self, levels: &[f64], performances: &mut [f64]) -> f64 {
+        for ((level, initial), perf) in levels.iter().zip(
+            self.initial_levels.iter()).zip(performances.iter_mut()) {
+            *perf = level / initial;
+        }
+
+        // sort best first. Performances are never NaN unless the paths are
+        performances.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        let perf = performances[self.rank_index()];
+
+        self.notional * match self.put_or_call {
+            PutOrCall::Call => (perf - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - perf).max(0.0)
+        }
+    }
+}
+
+impl InstanceId for RainbowOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for RainbowOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlyings[0].payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let expiry_date = self.expiry.date();
+        for underlying in self.underlyings.iter() {
+            context.fixing(underlying.id(), self.expiry);
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(&self.credit_id, self.pay_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Once all the underlyings have fixed at expiry, the option becomes a
+    /// cash payment.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let mut levels = Vec::with_capacity(self.underlyings.len());
+        for underlying in self.underlyings.iter() {
+            match fixing_table.get(underlying.id(), self.expiry)? {
+                Some(level) => levels.push(level),
+                None => return Ok(None)
+            }
+        }
+
+        let mut performances = vec![0.0; levels.len()];
+        let payment = self.payoff(&levels, &mut performances);
+        let mut decomp = Vec::new();
+        if payment != 0.0 {
+            decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                &format!("{}:payment", self.id), &self.credit_id,
+                RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                self.expiry, self.pay_date, self.settlement.clone()))))));
+        }
+        Ok(Some(decomp))
+    }
+}
+
+impl MonteCarloPriceable for RainbowOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation of each underlying, at expiry
+        for underlying in self.underlyings.iter() {
+            output.observation(underlying, self.expiry_time);
+        }
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        // fetch the expiry column of the paths for each underlying
+        let n_assets = self.underlyings.len();
+        let mut columns = Vec::with_capacity(n_assets);
+        for underlying in self.underlyings.iter() {
+            let paths = context.paths(underlying)?;
+            assert_eq!(paths.shape()[1], 1);
+            columns.push(paths.subview(Axis(1), 0).to_owned());
+        }
+        let n_paths = columns[0].len();
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        let mut levels = vec![0.0; n_assets];
+        let mut performances = vec![0.0; n_assets];
+        for (path, flow) in quantities.subview_mut(Axis(1), 0).iter_mut().enumerate() {
+            for (level, column) in levels.iter_mut().zip(columns.iter()) {
+                *level = column[path];
+            }
+            *flow = self.payoff(&levels, &mut performances);
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::Pricer;
+    use risk::ReportGenerator;
+    use risk::deltagamma::DeltaGammaReport;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::vegavolga::VegaVolgaReport;
+    use risk::vegavolga::VegaVolgaReportGenerator;
+    use data::bumpvol::BumpVol;
+    use instruments::assets::Equity;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use serde_json;
+
+    fn sample_underlyings() -> Vec<RcInstrument> {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        ["BP.L", "GSK.L"].iter().map(|id| RcInstrument::new(Qrc::new(Arc::new(
+            Equity::new(id, "LSE", currency.clone(), sample_settlement(2))))))
+            .collect()
+    }
+
+    fn sample_rainbow(rank: RainbowRank, put_or_call: PutOrCall)
+        -> RainbowOption {
+        let expiry = sample_expiry();
+        RainbowOption::new("SampleRainbow", "OPT", &sample_underlyings(),
+            &[100.0, 200.0], sample_settlement(2), expiry, 1.0, put_or_call,
+            rank, 100.0).unwrap()
+    }
+
+    fn sample_pricer(rainbow: RainbowOption, market_data: &MarketData)
+        -> MonteCarloPricer {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(rainbow)));
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        MonteCarloPricer::new(vec![(1.0, instrument)], factory,
+            market_data).unwrap()
+    }
+
+    fn mc_price(rainbow: RainbowOption, correlation: f64) -> f64 {
+        let market_data = sample_correlated_market_data(correlation);
+        sample_pricer(rainbow, &market_data).price().unwrap()
+    }
+
+    #[test]
+    fn rainbow_payoff_by_rank() {
+        let mut perfs = [0.0; 2];
+        let levels = [120.0, 180.0];
+        let best = sample_rainbow(RainbowRank::BestOf, PutOrCall::Call);
+        assert_approx(best.payoff(&levels, &mut perfs), 20.0, 1e-12);
+        let worst = sample_rainbow(RainbowRank::WorstOf, PutOrCall::Put);
+        assert_approx(worst.payoff(&levels, &mut perfs), 10.0, 1e-12);
+        let second = sample_rainbow(RainbowRank::NthBest(2), PutOrCall::Call);
+        assert_approx(second.payoff(&levels, &mut perfs), 0.0, 1e-12);
+
+        assert!(RainbowOption::new("Bad", "OPT", &sample_underlyings(),
+            &[100.0, 200.0], sample_settlement(2), best.expiry, 1.0,
+            PutOrCall::Call, RainbowRank::NthBest(3), 100.0).is_err());
+    }
+
+    #[test]
+    fn best_of_call_bounds_worst_of_call() {
+        // Whatever the correlation, best-of >= worst-of. With perfect
+        // correlation and identical assets (the sample data gives both the
+        // same vol, divs and rates) they converge to the vanilla on either.
+        let best = mc_price(sample_rainbow(RainbowRank::BestOf, PutOrCall::Call), 0.5);
+        let worst = mc_price(sample_rainbow(RainbowRank::WorstOf, PutOrCall::Call), 0.5);
+        assert!(best > worst + 5.0, "best={} worst={}", best, worst);
+
+        let best_high = mc_price(sample_rainbow(RainbowRank::BestOf, PutOrCall::Call), 0.9);
+        let worst_high = mc_price(sample_rainbow(RainbowRank::WorstOf, PutOrCall::Call), 0.9);
+        assert!(best_high < best, "best_high={} best={}", best_high, best);
+        assert!(worst_high > worst, "worst_high={} worst={}", worst_high, worst);
+    }
+
+    #[test]
+    fn rainbow_reports_delta_and_vega_per_asset() {
+        let market_data = sample_correlated_market_data(0.5);
+        let mut pricer = sample_pricer(
+            sample_rainbow(RainbowRank::BestOf, PutOrCall::Call), &market_data);
+        let unbumped = pricer.price().unwrap();
+
+        let mut save = pricer.as_bumpable().new_saveable();
+        let generator = DeltaGammaReportGenerator::new(0.01);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<DeltaGammaReport>()
+            .unwrap().results();
+        assert_eq!(results.len(), 2);
+        let bp_delta = results.get("BP.L").unwrap().delta();
+        let gsk_delta = results.get("GSK.L").unwrap().delta();
+        assert!(bp_delta > 0.0 && gsk_delta > 0.0,
+            "bp={} gsk={}", bp_delta, gsk_delta);
+
+        // GSK has twice the initial level, so half the delta per unit of spot
+        assert_approx(bp_delta, 2.0 * gsk_delta, 0.1);
+
+        let generator = VegaVolgaReportGenerator::new(
+            BumpVol::new_flat_additive(0.01));
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaVolgaReport>()
+            .unwrap().results();
+        assert_eq!(results.len(), 2);
+        assert!(results.get("BP.L").unwrap().vega() > 0.0);
+        assert!(results.get("GSK.L").unwrap().vega() > 0.0);
+    }
+
+    #[test]
+    fn rainbow_fixes_to_payment() {
+        let rainbow = sample_rainbow(RainbowRank::WorstOf, PutOrCall::Call);
+        let expiry = rainbow.expiry;
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 130.0)]), ("GSK.L", &[(expiry, 220.0)])]).unwrap();
+        let decomp = rainbow.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 10.0, 1e-12);
+    }
+
+    #[test]
+    fn rainbow_tagged_serde() {
+        let rainbow = sample_rainbow(RainbowRank::NthBest(2), PutOrCall::Put);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(rainbow)));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.id(), "SampleRainbow");
+        assert!(deserialized.as_mc_priceable().is_some());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,539 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use data::fixings::FixingRange;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::calendar::RcCalendar;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// One coupon period of a range accrual. The underlying is observed at the
+/// close on each business day from the start date up to but excluding the
+/// end date. The coupon, as a fraction of notional, is the full coupon for
+/// the period, which is scaled by the fraction of the observations that
+/// fall inside the range.
+///
+/// Observations already fixed are represented by the first unfixed date
+/// and the number of fixed observations that fell inside the range.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RangeAccrualPeriod {
+    start: Date,
+    end: Date,
+    coupon: f64,
+    first_unfixed: Date,
+    days_in_range: usize
+}
+
+impl RangeAccrualPeriod {
+    /// Creates a period with no fixed observations
+    pub fn new(start: Date, end: Date, coupon: f64) -> RangeAccrualPeriod {
+        RangeAccrualPeriod::new_accrued(start, end, coupon, start, 0)
+    }
+
+    /// Creates a period where the observations before the first unfixed
+    /// date have fixed, and the given number of them were inside the range
+    pub fn new_accrued(start: Date, end: Date, coupon: f64,
+        first_unfixed: Date, days_in_range: usize) -> RangeAccrualPeriod {
+        RangeAccrualPeriod { start: start, end: end, coupon: coupon,
+            first_unfixed: first_unfixed, days_in_range: days_in_range }
+    }
+
+    pub fn start(&self) -> Date { self.start }
+    pub fn end(&self) -> Date { self.end }
+    pub fn first_unfixed(&self) -> Date { self.first_unfixed }
+    pub fn days_in_range(&self) -> usize { self.days_in_range }
+
+    /// The daily observations of the whole period
+    fn observations(&self, calendar: &RcCalendar) -> FixingRange {
+        FixingRange::new(self.start, self.end - 1, TimeOfDay::Close, calendar.clone())
+    }
+
+    /// The number of observations in the whole period
+    fn days(&self, calendar: &RcCalendar) -> usize {
+        self.observations(calendar).dates_between(self.start, self.end).len()
+    }
+
+    /// The observations that have not yet fixed
+    fn unfixed(&self, calendar: &RcCalendar) -> Vec<DateTime> {
+        self.observations(calendar).dates_between(self.first_unfixed, self.end)
+    }
+}
+
+/// A range accrual note. Each period pays a coupon at its end, scaled by
+/// the fraction of business days in the period when the underlying fixed
+/// inside the range, inclusive of the lower and upper levels. The note
+/// redeems at par with the final coupon.
+///
+/// The observations are daily, so rather than registering a fixing for
+/// each one, the note registers a fixing range per period, which the
+/// dependency collector merges into a single run.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RangeAccrual {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    calendar: RcCalendar,
+    notional: f64,
+    lower: f64,
+    upper: f64,
+    periods: Vec<RangeAccrualPeriod>,
+
+    // fields precomputed for performance and simplicity
+    pay_dates: Vec<Date>,
+}
+
+impl TypeId for RangeAccrual {
+    fn type_id(&self) -> &'static str { "RangeAccrual" }
+}
+
+impl RangeAccrual {
+    /// Creates a range accrual note. The periods are the remaining periods
+    /// that have not completely fixed, in increasing date order, and must
+    /// not overlap. The observations are on business days of the calendar.
+    /// The lower and upper levels of the range are absolute levels of the
+    /// underlying.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        calendar: RcCalendar,
+        notional: f64,
+        lower: f64,
+        upper: f64,
+        periods: &[RangeAccrualPeriod])
+        -> Result<RangeAccrual, qm::Error> {
+
+        if periods.is_empty() {
+            return Err(qm::Error::new("Range accrual must have at least one period"))
+        }
+        for pair in periods.windows(2) {
+            if pair[0].end > pair[1].start {
+                return Err(qm::Error::new("Range accrual periods must be \
+                    in increasing order and must not overlap"))
+            }
+        }
+        for period in periods.iter() {
+            if period.first_unfixed < period.start || period.first_unfixed > period.end {
+                return Err(qm::Error::new("Range accrual first unfixed date \
+                    must be within its period"))
+            }
+            if period.days(&calendar) == 0 {
+                return Err(qm::Error::new("Range accrual period must contain \
+                    at least one business day"))
+            }
+        }
+        if lower > upper {
+            return Err(qm::Error::new("Range accrual lower level must not \
+                be above the upper level"))
+        }
+
+        let pay_dates = periods.iter().map(|p| settlement.apply(p.end)).collect();
+        Ok(RangeAccrual {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            calendar: calendar,
+            notional: notional,
+            lower: lower,
+            upper: upper,
+            periods: periods.to_vec(),
+            pay_dates: pay_dates })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RangeAccrual::deserialize(de)?)))
+    }
+
+    /// The periods that have not yet completely fixed
+    pub fn periods(&self) -> &[RangeAccrualPeriod] {
+        &self.periods
+    }
+
+    fn in_range(&self, level: f64) -> bool {
+        level >= self.lower && level <= self.upper
+    }
+
+    /// The amount paid for the given period, given the number of its
+    /// observations (fixed or expected) inside the range
+    fn amount(&self, index: usize, days_in_range: f64) -> f64 {
+        let period = &self.periods[index];
+        let mut amount = self.notional * period.coupon * days_in_range
+            / period.days(&self.calendar) as f64;
+        if index + 1 == self.periods.len() {
+            amount += self.notional;
+        }
+        amount
+    }
+
+    fn payment(&self, index: usize, amount: f64) -> (f64, RcInstrument) {
+        let end = self.periods[index].end;
+        (amount, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment:{}", self.id, end), &self.credit_id,
+            RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+            DateTime::new(end, TimeOfDay::Close), self.pay_dates[index],
+            self.settlement.clone())))))
+    }
+}
+
+impl InstanceId for RangeAccrual {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for RangeAccrual {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let id = self.underlying.id();
+        for period in self.periods.iter() {
+            if period.first_unfixed < period.end {
+                context.fixing_range(id, FixingRange::new(period.first_unfixed,
+                    period.end - 1, TimeOfDay::Close, self.calendar.clone()));
+            }
+        }
+
+        context.yield_curve(&self.credit_id, *self.pay_dates.last().unwrap());
+        let last_date = self.periods.last().unwrap().end;
+        context.forward_curve(&self.underlying, last_date);
+        context.vol_surface(&self.underlying, last_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// Counts any newly fixed observations. Each period that has completely
+    /// fixed turns into a payment. If any periods remain, the note is
+    /// replaced by one with its first period accrued to date.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut decomp = Vec::new();
+        let mut remaining = Vec::new();
+        let mut changed = false;
+        for (index, period) in self.periods.iter().enumerate() {
+            if !remaining.is_empty() {
+                remaining.push(period.clone());
+                continue;
+            }
+
+            let mut days_in_range = period.days_in_range;
+            let mut first_unfixed = period.end;
+            for date in period.unfixed(&self.calendar).iter() {
+                if let Some(fixing) = fixing_table.get(id, *date)? {
+                    changed = true;
+                    if self.in_range(fixing) {
+                        days_in_range += 1;
+                    }
+                } else {
+                    first_unfixed = date.date();
+                    break;
+                }
+            }
+
+            if first_unfixed == period.end {
+                decomp.push(self.payment(index, self.amount(index, days_in_range as f64)));
+            } else {
+                remaining.push(RangeAccrualPeriod::new_accrued(period.start,
+                    period.end, period.coupon, first_unfixed, days_in_range));
+            }
+        }
+
+        if !changed {
+            return Ok(None)
+        }
+
+        if !remaining.is_empty() {
+            let note = RangeAccrual::new(&self.id, &self.credit_id,
+                self.underlying.clone(), self.settlement.clone(),
+                self.calendar.clone(), self.notional, self.lower, self.upper,
+                &remaining)?;
+            decomp.push((1.0, RcInstrument::new(Qrc::new(Arc::new(note)))));
+        }
+        Ok(Some(decomp))
+    }
+}
+
+impl Priceable for RangeAccrual {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Each unfixed observation contributes the probability of fixing inside
+    /// the range, which is the difference of two cash digitals, each priced
+    /// using Black76 with the vol at its own level.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let last_date = self.periods.last().unwrap().end;
+        let yc = context.yield_curve(&self.credit_id, *self.pay_dates.last().unwrap())?;
+        let forward = context.forward_curve(&*self.underlying, last_date)?;
+        let vol = context.vol_surface(&*self.underlying, last_date,
+            &|| context.forward_curve(&*self.underlying, last_date))?;
+        let black76 = Black76::new()?;
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let val_date = self.underlying.time_to_day_fraction(*date)?;
+            let settlement_date = self.settlement.apply(date.date());
+            let df_to_settlement = yc.rt(settlement_date)?.exp();
+
+            let mut total = 0.0;
+            for (index, period) in self.periods.iter().enumerate() {
+                let mut expected = period.days_in_range as f64;
+                for observation in period.unfixed(&self.calendar).iter() {
+                    let obs_date = observation.date();
+                    let displacement = vol.displacement(obs_date)?;
+                    let f = forward.forward(obs_date)? - displacement;
+                    if f < 0.0 {
+                        return Err(qm::Error::new("Negative forward"));
+                    }
+                    let obs_time = self.underlying.time_to_day_fraction(*observation)?;
+
+                    // probability of fixing at or above a level, which is
+                    // known if the observation is not after the val date.
+                    // The level is displaced in the same way as the forward.
+                    let above = |level: f64| -> Result<f64, qm::Error> {
+                        let k = level - displacement;
+                        if obs_time <= val_date {
+                            return Ok(if f >= k { 1.0 } else { 0.0 })
+                        }
+                        let var = vol.forward_variance(val_date, obs_time, level)?;
+                        if var < 0.0 {
+                            return Err(qm::Error::new("Negative variance"));
+                        }
+                        Ok(black76.cash_digital_call_price(1.0, f, k, var.sqrt()))
+                    };
+                    let below_upper = if self.upper.is_infinite() { 0.0 } else { above(self.upper)? };
+                    let above_lower = if self.lower <= 0.0 { 1.0 } else { above(self.lower)? };
+                    expected += (above_lower - below_upper).max(0.0);
+                }
+
+                let df = (-yc.rt(self.pay_dates[index])?).exp() * df_to_settlement;
+                total += df * self.amount(index, expected);
+            }
+
+            *output = total;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use dates::calendar::WeekdayCalendar;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_displaced_market_data;
+    use instruments::digitals::tests::sample_digital;
+    use instruments::digitals::DigitalPayout;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use data::bumpspotdate::SpotDynamics;
+
+    fn sample_periods() -> Vec<RangeAccrualPeriod> {
+        // quarterly over a year, paying 2% a quarter
+        let dates = [(2017, 01, 03), (2017, 04, 03), (2017, 07, 03),
+            (2017, 10, 02), (2018, 01, 02)];
+        dates.windows(2).map(|pair| {
+            let (y0, m0, d0) = pair[0];
+            let (y1, m1, d1) = pair[1];
+            RangeAccrualPeriod::new(Date::from_ymd(y0, m0, d0),
+                Date::from_ymd(y1, m1, d1), 0.02) }).collect()
+    }
+
+    fn sample_range_accrual(lower: f64, upper: f64, periods: &[RangeAccrualPeriod])
+        -> RangeAccrual {
+        let equity = sample_underlying();
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        RangeAccrual::new("SampleRangeAccrual", "OPT", equity, sample_settlement(2),
+            calendar, 1000.0, lower, upper, periods).unwrap()
+    }
+
+    fn bond_price(end: Date) -> f64 {
+        let market_data = sample_market_data();
+        let settlement = sample_settlement(2);
+        let pay_date = settlement.apply(end);
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))),
+            DateTime::new(end, TimeOfDay::Close), pay_date, settlement);
+        bond.price(&market_data, sample_val_date()).unwrap()
+    }
+
+    #[test]
+    fn range_accrual_observations_are_one_fixing_range() {
+        let note = sample_range_accrual(90.0, 110.0, &sample_periods());
+        let mut dependencies = DependencyCollector::new(sample_val_date().date());
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(note)));
+        dependencies.spot(&instrument);
+
+        // the four periods abut, so are merged into a year of observations
+        assert!(dependencies.fixings("BP.L").is_empty());
+        let ranges = dependencies.fixing_ranges("BP.L");
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].first(), Date::from_ymd(2017, 01, 03));
+        assert_eq!(ranges[0].last(), Date::from_ymd(2018, 01, 01));
+    }
+
+    #[test]
+    fn range_accrual_wide_range_prices_as_bonds() {
+        let periods = sample_periods();
+        let note = sample_range_accrual(0.0, 1e6, &periods);
+        let market_data = sample_market_data();
+        let price = note.price(&market_data, sample_val_date()).unwrap();
+        let expected = periods.iter().map(|p| 20.0 * bond_price(p.end())).sum::<f64>()
+            + 1000.0 * bond_price(periods.last().unwrap().end());
+        assert_approx(price, expected, 1e-8);
+
+        // a range the underlying can never reach pays only the redemption
+        let note = sample_range_accrual(1e5, 1e6, &periods);
+        let price = note.price(&market_data, sample_val_date()).unwrap();
+        assert_approx(price, 1000.0 * bond_price(periods.last().unwrap().end()), 1e-6);
+    }
+
+    #[test]
+    fn range_accrual_narrower_range_is_cheaper() {
+        let periods = sample_periods();
+        let market_data = sample_market_data();
+        let wide = sample_range_accrual(80.0, 120.0, &periods)
+            .price(&market_data, sample_val_date()).unwrap();
+        let narrow = sample_range_accrual(95.0, 105.0, &periods)
+            .price(&market_data, sample_val_date()).unwrap();
+        let redemption = 1000.0 * bond_price(periods.last().unwrap().end());
+        assert!(narrow > redemption, "narrow={} redemption={}", narrow, redemption);
+        assert!(wide > narrow, "wide={} narrow={}", wide, narrow);
+        assert!(wide < redemption + 80.0, "wide={}", wide);
+    }
+
+    #[test]
+    fn range_accrual_fixes_daily_observations() {
+        // the first period has 64 observations. Fix all of them, half
+        // inside the range, and the first week of the second period.
+        let periods = sample_periods();
+        let mut fixings = Vec::new();
+        let mut date = Date::from_ymd(2017, 01, 03);
+        let mut count = 0;
+        while date < Date::from_ymd(2017, 04, 08) {
+            if date.day_of_week() < 5 {
+                let level = if count % 2 == 0 { 100.0 } else { 120.0 };
+                fixings.push((DateTime::new(date, TimeOfDay::Close), level));
+                count += 1;
+            }
+            date += 1;
+        }
+        let today = Date::from_ymd(2017, 04, 08);
+        let fixing_table = FixingTable::from_fixings(today,
+            &[("BP.L", &fixings[..])]).unwrap();
+
+        let note = sample_range_accrual(90.0, 110.0, &periods);
+        let fixed = note.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 2);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+        assert_approx(fixed[0].0, 10.0, 1e-12);
+        assert_eq!(fixed[1].1.type_id(), "RangeAccrual");
+
+        // fixing a note with no new observations does nothing
+        let seasoned = sample_range_accrual(90.0, 110.0, &[
+            RangeAccrualPeriod::new_accrued(periods[1].start(), periods[1].end(),
+                0.02, today, 3)]);
+        assert!(seasoned.fix(&fixing_table).unwrap().is_none());
+    }
+
+    #[test]
+    fn range_accrual_time_bump_fixes_each_day() {
+        let periods = sample_periods();
+        let market_data = sample_market_data();
+        let note = RcInstrument::new(Qrc::new(Arc::new(
+            sample_range_accrual(90.0, 110.0, &periods))));
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        dependencies.spot(&note);
+
+        // moving forward a week fixes five observations, all at the forward,
+        // which is inside the range, so a seasoned note remains
+        let mut instruments = vec![(1.0, note)];
+        let new_date = Date::from_ymd(2017, 01, 10);
+        let bump = BumpTime::new(new_date, new_date, SpotDynamics::StickyForward);
+        let changed = bump.update_instruments(&mut instruments,
+            &market_data, &dependencies).unwrap();
+        assert!(changed);
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].1.type_id(), "RangeAccrual");
+    }
+
+    #[test]
+    fn range_accrual_with_displaced_vol_matches_digital() {
+        // a single observation above a level pays the coupon as a digital
+        let market_data = sample_displaced_market_data(10.0);
+        let expiry = sample_expiry();
+        let periods = [RangeAccrualPeriod::new(expiry.date(), expiry.date() + 1, 0.02)];
+        let note = sample_range_accrual(95.0, 1e6, &periods)
+            .price(&market_data, sample_val_date()).unwrap();
+        let digital = sample_digital(95.0, expiry, PutOrCall::Call,
+            DigitalPayout::CashOrNothing(20.0), 0.0)
+            .price(&market_data, sample_val_date()).unwrap();
+        let end = expiry.date() + 1;
+        let expected = (digital / bond_price(expiry.date()) + 1000.0) * bond_price(end);
+        assert_approx(note, expected, 1e-8);
+
+        // an observation at the val date is known. The spot of 100 is in
+        // the range, even though its displaced value is not.
+        let today = Date::from_ymd(2017, 01, 02);
+        let close = DateTime::new(today, TimeOfDay::Close);
+        let periods = [RangeAccrualPeriod::new(today, today + 1, 0.02)];
+        let note = sample_range_accrual(95.0, 1e6, &periods)
+            .price(&market_data, close).unwrap();
+        let wide = sample_range_accrual(0.0, 1e6, &periods)
+            .price(&market_data, close).unwrap();
+        assert_approx(note, wide, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,464 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use data::fixings::FixingTable;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The rule used to choose the weights of a rebalancing basket on each
+/// rebalance date. The weights are fractions of the value of the basket.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum RebalanceRule {
+    /// Every component is given the same weight
+    EqualWeight,
+
+    /// The components that performed best since the previous rebalance
+    /// share the basket equally, and the others are dropped. On the first
+    /// rebalance, where there is no past performance, all components are
+    /// weighted equally.
+    Momentum { top: usize }
+}
+
+impl RebalanceRule {
+    /// Works out the weights, given the current levels of the components
+    /// and the levels at the previous rebalance, if any
+    pub fn weights(&self, levels: &[f64], previous: Option<&[f64]>) -> Vec<f64> {
+
+        let n = levels.len();
+        match (*self, previous) {
+            (RebalanceRule::EqualWeight, _) | (RebalanceRule::Momentum { .. }, None) =>
+                vec![1.0 / n as f64; n],
+            (RebalanceRule::Momentum { top }, Some(previous)) => {
+                assert_eq!(previous.len(), n);
+                let mut ranked: Vec<(usize, f64)> = levels.iter().zip(previous.iter())
+                    .map(|(level, prev)| level / prev).enumerate().collect();
+
+                // best first, breaking ties in order of the components
+                ranked.sort_by(|a, b| b.1.partial_cmp(&a.1)
+                    .unwrap_or(::std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+                let mut weights = vec![0.0; n];
+                for &(index, _) in ranked.iter().take(top) {
+                    weights[index] = 1.0 / top as f64;
+                }
+                weights
+            }
+        }
+    }
+
+    /// True if the weights chosen depend on the levels of the components,
+    /// which means that the value beyond the first rebalance depends on the
+    /// paths of the components rather than just their forwards
+    pub fn is_path_dependent(&self) -> bool {
+        match *self {
+            RebalanceRule::EqualWeight => false,
+            RebalanceRule::Momentum { .. } => true
+        }
+    }
+}
+
+/// The units of each component held by a rebalancing basket since its
+/// last rebalance, along with the levels of the components at that
+/// rebalance.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Holdings {
+    units: Vec<f64>,
+    levels: Vec<f64>
+}
+
+impl Holdings {
+    pub fn new(units: &[f64], levels: &[f64]) -> Holdings {
+        Holdings { units: units.to_vec(), levels: levels.to_vec() }
+    }
+
+    pub fn units(&self) -> &[f64] { &self.units }
+    pub fn levels(&self) -> &[f64] { &self.levels }
+
+    /// The value of the holdings, given the levels of the components
+    pub fn value(&self, levels: &[f64]) -> f64 {
+        self.units.iter().zip(levels.iter()).map(|(u, l)| u * l).sum()
+    }
+}
+
+/// A basket whose holdings are rebalanced on scheduled dates. On the first
+/// date the basket starts at its initial level, and on each date the value
+/// is reallocated across the components according to the rebalance rule.
+/// Between rebalances, the basket holds a fixed number of units of each
+/// component, so its value moves with them.
+///
+/// The rebalance dates are fixings of the components, so as time moves past
+/// them the holdings are worked out and carried with the basket, and only
+/// the rebalances still to come remain in its schedule.
+///
+/// The price of the basket at any date up to its next rebalance is the
+/// value of its holdings. Beyond that, it is the forward of the strategy,
+/// which is only known if the weights do not depend on the path taken.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RebalancingBasket {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    settlement: RcDateRule,
+    components: Vec<RcInstrument>,
+    rule: RebalanceRule,
+    schedule: Vec<DateTime>,
+    initial_level: f64,
+    holdings: Option<Holdings>
+}
+
+impl TypeId for RebalancingBasket {
+    fn type_id(&self) -> &'static str { "RebalancingBasket" }
+}
+
+impl InstanceId for RebalancingBasket {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl RebalancingBasket {
+    /// Creates a basket that has not yet started. The first date of the
+    /// schedule is when it starts at the initial level, and the schedule
+    /// must be in strictly increasing order.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule, components: Vec<RcInstrument>,
+        rule: RebalanceRule, schedule: &[DateTime], initial_level: f64)
+        -> Result<RebalancingBasket, qm::Error> {
+        RebalancingBasket::new_seasoned(id, credit_id, currency, settlement,
+            components, rule, schedule, initial_level, None)
+    }
+
+    /// Creates a basket that has already started, with the given holdings.
+    /// The schedule contains only the rebalances still to come, and may be
+    /// empty.
+    pub fn new_seasoned(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule, components: Vec<RcInstrument>,
+        rule: RebalanceRule, schedule: &[DateTime], initial_level: f64,
+        holdings: Option<Holdings>) -> Result<RebalancingBasket, qm::Error> {
+
+        if components.is_empty() {
+            return Err(qm::Error::new("Rebalancing basket must have components"))
+        }
+        if holdings.is_none() && schedule.is_empty() {
+            return Err(qm::Error::new("Rebalancing basket must have a start date"))
+        }
+        for component in components.iter() {
+            if component.payoff_currency().id() != currency.id() {
+                return Err(qm::Error::new(&format!("Rebalancing basket {} \
+                    component {} is not in {}", id, component.id(), currency.id())))
+            }
+        }
+        if let RebalanceRule::Momentum { top } = rule {
+            if top == 0 || top > components.len() {
+                return Err(qm::Error::new("Momentum basket must select between \
+                    one and all of its components"))
+            }
+        }
+        for pair in schedule.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Rebalancing basket schedule must be \
+                    in strictly increasing order"))
+            }
+        }
+        if let Some(ref holdings) = holdings {
+            if holdings.units.len() != components.len()
+                || holdings.levels.len() != components.len() {
+                return Err(qm::Error::new("Rebalancing basket holdings must \
+                    match its components"))
+            }
+        }
+
+        Ok(RebalancingBasket { id: id.to_string(),
+            credit_id: credit_id.to_string(), currency: currency,
+            settlement: settlement, components: components, rule: rule,
+            schedule: schedule.to_vec(), initial_level: initial_level,
+            holdings: holdings })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RebalancingBasket::deserialize(de)?)))
+    }
+
+    pub fn components(&self) -> &[RcInstrument] { &self.components }
+    pub fn rule(&self) -> RebalanceRule { self.rule }
+
+    /// The rebalances still to come, including the start if the basket has
+    /// not yet started
+    pub fn schedule(&self) -> &[DateTime] { &self.schedule }
+
+    /// The holdings since the last rebalance, or None if not yet started
+    pub fn holdings(&self) -> Option<&Holdings> { self.holdings.as_ref() }
+
+    /// Rebalances given the levels of the components on a rebalance date
+    fn rebalance(&self, holdings: Option<&Holdings>, levels: &[f64]) -> Holdings {
+
+        let (value, previous) = match holdings {
+            Some(holdings) => (holdings.value(levels), Some(&holdings.levels[..])),
+            None => (self.initial_level, None)
+        };
+        let weights = self.rule.weights(levels, previous);
+        let units = weights.iter().zip(levels.iter())
+            .map(|(weight, level)| value * weight / level).collect();
+        Holdings { units: units, levels: levels.to_vec() }
+    }
+
+    fn component_levels(&self, context: &PricingContext, date: DateTime)
+        -> Result<Vec<f64>, qm::Error> {
+        let mut levels = Vec::with_capacity(self.components.len());
+        for component in self.components.iter() {
+            let priceable = component.as_priceable().ok_or_else(|| qm::Error::new(
+                "The components of a rebalancing basket must be priceable"))?;
+            levels.push(priceable.price(context, date)?);
+        }
+        Ok(levels)
+    }
+}
+
+impl Instrument for RebalancingBasket {
+    fn payoff_currency(&self) -> &Currency { &*self.currency }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        for component in self.components.iter() {
+            match component.dependencies(context) {
+                SpotRequirement::NotRequired => {}, // nothing to do
+                _ => context.spot(component)
+            };
+            for date in self.schedule.iter() {
+                context.fixing(component.id(), *date);
+            }
+            if let Some(last) = self.schedule.last() {
+                context.forward_curve(component, last.date());
+            }
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    /// Rebalances on each date in turn for which all the components have
+    /// fixed, carrying the resulting holdings.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let mut holdings = self.holdings.clone();
+        let mut fixed = 0;
+        'schedule: for date in self.schedule.iter() {
+            let mut levels = Vec::with_capacity(self.components.len());
+            for component in self.components.iter() {
+                match fixing_table.get(component.id(), *date)? {
+                    Some(fixing) => levels.push(fixing),
+                    None => break 'schedule
+                }
+            }
+            holdings = Some(self.rebalance(holdings.as_ref(), &levels));
+            fixed += 1;
+        }
+
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        let remaining = RebalancingBasket::new_seasoned(&self.id,
+            &self.credit_id, self.currency.clone(), self.settlement.clone(),
+            self.components.clone(), self.rule, &self.schedule[fixed..],
+            self.initial_level, holdings)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(remaining))))]))
+    }
+}
+
+impl Priceable for RebalancingBasket {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Before the start, the value is the initial level. Otherwise, it is
+    /// the value of the holdings, rolled through any rebalances before the
+    /// date using the forwards of the components.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let mut holdings = self.holdings.clone();
+            for rebalance in self.schedule.iter() {
+                if rebalance > date {
+                    break;
+                }
+                if holdings.is_some() && self.rule.is_path_dependent() {
+                    return Err(qm::Error::new(&format!("Rebalancing basket {} \
+                        cannot be valued from forwards beyond its rebalance \
+                        on {}", self.id, rebalance)))
+                }
+                let levels = self.component_levels(context, *rebalance)?;
+                holdings = Some(self.rebalance(holdings.as_ref(), &levels));
+            }
+
+            *output = match holdings {
+                Some(holdings) => holdings.value(&self.component_levels(context, *date)?),
+                None => self.initial_level
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::assets::Equity;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use dates::Date;
+    use dates::datetime::TimeOfDay;
+    use serde_json;
+
+    fn currency() -> RcCurrency {
+        RcCurrency::new(Arc::new(sample_currency(2)))
+    }
+
+    fn sample_components() -> Vec<RcInstrument> {
+        let equity = |id| RcInstrument::new(Qrc::new(Arc::new(
+            Equity::new(id, "LSE", currency(), sample_settlement(2)))));
+        vec![equity("BP.L"), equity("GSK.L")]
+    }
+
+    fn sample_schedule() -> Vec<DateTime> {
+        [(2017, 01, 03), (2017, 04, 03), (2017, 07, 03), (2017, 10, 02)]
+            .iter().map(|&(y, m, d)| DateTime::new(Date::from_ymd(y, m, d),
+            TimeOfDay::Close)).collect()
+    }
+
+    fn sample_basket(rule: RebalanceRule) -> RebalancingBasket {
+        RebalancingBasket::new("SampleRebalancing", "LSE", currency(),
+            sample_settlement(2), sample_components(), rule, &sample_schedule(),
+            1000.0).unwrap()
+    }
+
+    fn fixings(levels: &[(f64, f64)]) -> FixingTable {
+        let schedule = sample_schedule();
+        let bp: Vec<(DateTime, f64)> = schedule.iter().cloned()
+            .zip(levels.iter().map(|l| l.0)).collect();
+        let gsk: Vec<(DateTime, f64)> = schedule.iter().cloned()
+            .zip(levels.iter().map(|l| l.1)).collect();
+        FixingTable::from_fixings(schedule[levels.len() - 1].date() + 1,
+            &[("BP.L", &bp[..]), ("GSK.L", &gsk[..])]).unwrap()
+    }
+
+    fn fixed(basket: &RebalancingBasket, levels: &[(f64, f64)]) -> Holdings {
+        let decomp = basket.fix(&fixings(levels)).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        let tagged = serde_json::to_value(&decomp[0].1).unwrap();
+        let remaining: RebalancingBasket = serde_json::from_value(
+            tagged["RebalancingBasket"].clone()).unwrap();
+        assert_eq!(remaining.schedule().len(), 4 - levels.len());
+        remaining.holdings().unwrap().clone()
+    }
+
+    #[test]
+    fn equal_weight_rebalances_value() {
+        let basket = sample_basket(RebalanceRule::EqualWeight);
+
+        // start with half the initial level in each
+        let holdings = fixed(&basket, &[(100.0, 200.0)]);
+        assert_approx(holdings.units()[0], 5.0);
+        assert_approx(holdings.units()[1], 2.5);
+
+        // BP.L rises 20% and GSK.L falls 10%, so the basket is up 5%, and
+        // is then split equally again
+        let holdings = fixed(&basket, &[(100.0, 200.0), (120.0, 180.0)]);
+        assert_approx(holdings.value(&[120.0, 180.0]), 1050.0);
+        assert_approx(holdings.units()[0], 525.0 / 120.0);
+        assert_approx(holdings.units()[1], 525.0 / 180.0);
+    }
+
+    #[test]
+    fn momentum_picks_best_performer() {
+        let basket = sample_basket(RebalanceRule::Momentum { top: 1 });
+        let holdings = fixed(&basket, &[(100.0, 200.0)]);
+        assert_approx(holdings.units()[0], 5.0);
+        assert_approx(holdings.units()[1], 2.5);
+
+        // GSK.L did better, so the whole basket moves into it
+        let holdings = fixed(&basket, &[(100.0, 200.0), (90.0, 210.0)]);
+        assert_approx(holdings.units()[0], 0.0);
+        assert_approx(holdings.units()[1], 975.0 / 210.0);
+
+        // then BP.L does better
+        let holdings = fixed(&basket, &[(100.0, 200.0), (90.0, 210.0), (95.0, 189.0)]);
+        assert_approx(holdings.value(&[95.0, 189.0]), 877.5);
+        assert_approx(holdings.units()[0], 877.5 / 95.0);
+        assert_approx(holdings.units()[1], 0.0);
+    }
+
+    #[test]
+    fn rebalancing_basket_prices() {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let components = sample_components();
+        let forward = |component: &RcInstrument, date: DateTime| component
+            .as_priceable().unwrap().price(&market_data, date).unwrap();
+
+        // before it starts, the basket is worth its initial level
+        let basket = sample_basket(RebalanceRule::EqualWeight);
+        assert_approx(basket.price(&market_data, val_date).unwrap(), 1000.0);
+
+        // after starting, it is worth the value of its holdings
+        let holdings = fixed(&basket, &[(100.0, 200.0)]);
+        let seasoned = RebalancingBasket::new_seasoned("SampleRebalancing", "LSE",
+            currency(), sample_settlement(2), components.clone(),
+            RebalanceRule::EqualWeight, &sample_schedule()[1..], 1000.0,
+            Some(holdings.clone())).unwrap();
+        let spot = holdings.value(&[100.0, 200.0]);
+        assert_approx(seasoned.price(&market_data, val_date).unwrap(), spot);
+
+        // beyond a rebalance, the forward rolls through the rebalance
+        let schedule = sample_schedule();
+        let after = DateTime::new(Date::from_ymd(2017, 05, 02), TimeOfDay::Close);
+        let at_rebalance = holdings.value(&[forward(&components[0], schedule[1]),
+            forward(&components[1], schedule[1])]);
+        let expected = at_rebalance * 0.5 * (
+            forward(&components[0], after) / forward(&components[0], schedule[1])
+            + forward(&components[1], after) / forward(&components[1], schedule[1]));
+        assert_approx(seasoned.price(&market_data, after).unwrap(), expected);
+
+        // a started momentum basket cannot be priced beyond its next rebalance
+        let momentum = RebalancingBasket::new_seasoned("SampleRebalancing", "LSE",
+            currency(), sample_settlement(2), components, RebalanceRule::Momentum { top: 1 },
+            &sample_schedule()[1..], 1000.0, Some(holdings)).unwrap();
+        assert_approx(momentum.price(&market_data, val_date).unwrap(), spot);
+        assert!(momentum.price(&market_data, after).is_err());
+    }
+
+    #[test]
+    fn rebalancing_basket_validation() {
+        let make = |rule, schedule: &[DateTime]| RebalancingBasket::new(
+            "SampleRebalancing", "LSE", currency(), sample_settlement(2),
+            sample_components(), rule, schedule, 1000.0);
+        let schedule = sample_schedule();
+        assert!(make(RebalanceRule::EqualWeight, &schedule).is_ok());
+        assert!(make(RebalanceRule::EqualWeight, &[]).is_err());
+        assert!(make(RebalanceRule::EqualWeight, &[schedule[1], schedule[0]]).is_err());
+        assert!(make(RebalanceRule::Momentum { top: 0 }, &schedule).is_err());
+        assert!(make(RebalanceRule::Momentum { top: 3 }, &schedule).is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-10),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,767 @@
+//! A small language for describing payoffs, so that structured products
+//! that are not in the library can be priced without writing a new
+//! instrument. A script is run once at each observation date of a scripted
+//! instrument, in date order, on each Monte-Carlo path or on the fixings.
+//!
+//! A script is a sequence of statements:
+//!
+//! * `name = expr;` assigns to a variable. Variables keep their values from
+//!   one observation to the next, so they can be used as accumulators, and
+//!   start at zero unless the instrument supplies an initial value. Variables
+//!   that are never assigned are inputs, such as strikes or notionals, which
+//!   the instrument must supply.
+//! * `if expr { ... } else { ... }` runs one of its blocks, depending on
+//!   whether the condition is non-zero. The else block is optional, and may
+//!   be another if statement.
+//! * `pay(expr);` pays the given amount at the settlement date of the
+//!   current observation. Payments on the same observation are summed.
+//! * `stop;` terminates the instrument after this observation, as for an
+//!   autocall or a target redemption.
+//!
+//! Expressions are built from numbers, variables, the arithmetic operators
+//! `+ - * /`, the comparisons `< <= > >= == !=` and the logical operators
+//! `and or not`, where comparisons give one or zero and any non-zero value
+//! is true. The functions `max` and `min` take any number of arguments, and
+//! `abs`, `exp`, `log` and `sqrt` take one. The level of an underlying at the
+//! current observation is `spot("id")`. The built-in `step` is the index of
+//! the current observation, counting from zero, and `last` is one on the
+//! final observation and zero otherwise. Comments start with `//`.
+//!
+//! For example, a two-year note that autocalls at a 5% coupon per
+//! observation if BP.L is above its initial level, and otherwise pays back
+//! its notional less any fall in the level:
+//!
+//! ```text
+//! performance = spot("BP.L") / initial;
+//! if performance >= 1 {
+//!     pay(notional * (1 + 0.05 * (step + 1)));
+//!     stop;
+//! } else if last {
+//!     pay(notional * min(performance, 1));
+//! }
+//! ```
+
+use std::fmt;
+use std::str::Chars;
+use std::iter::Peekable;
+use core::qm;
+use serde::Serialize;
+use serde::Serializer;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::de::Error;
+
+/// A parsed payoff script. The script is serialized as its source, and
+/// parsed again on deserialization.
+#[derive(Clone, Debug)]
+pub struct Script {
+    source: String,
+    statements: Vec<Statement>,
+    variables: Vec<String>,
+    assigned: Vec<bool>,
+    underlyings: Vec<String>
+}
+
+impl Script {
+    /// Parses a script, returning an error that gives the line of the
+    /// problem if the script is not well-formed.
+    pub fn parse(source: &str) -> Result<Script, qm::Error> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: tokens, position: 0,
+            variables: Vec::new(), assigned: Vec::new(),
+            underlyings: Vec::new() };
+        let mut statements = Vec::new();
+        while !parser.at_end() {
+            statements.push(parser.statement()?);
+        }
+        if parser.underlyings.is_empty() {
+            return Err(qm::Error::new("Script must observe at least one \
+                underlying using spot(\"id\")"))
+        }
+
+        Ok(Script {
+            source: source.to_string(),
+            statements: statements,
+            variables: parser.variables,
+            assigned: parser.assigned,
+            underlyings: parser.underlyings })
+    }
+
+    pub fn source(&self) -> &str { &self.source }
+
+    /// The names of all the variables in the script. The state passed to
+    /// execute has one value per variable, in this order.
+    pub fn variables(&self) -> &[String] { &self.variables }
+
+    /// True if the variable at this index is never assigned by the script,
+    /// so it must be supplied as an input.
+    pub fn is_input(&self, index: usize) -> bool { !self.assigned[index] }
+
+    /// The ids of the underlyings observed by the script. The spots passed
+    /// to execute have one value per underlying, in this order.
+    pub fn underlyings(&self) -> &[String] { &self.underlyings }
+
+    /// Runs the script for one observation. The step is the index of the
+    /// observation and last is true if it is the final one. The state is
+    /// updated with any assignments. Returns the total amount paid and
+    /// whether the script stopped.
+    pub fn execute(&self, step: usize, last: bool, spots: &[f64],
+        state: &mut [f64]) -> (f64, bool) {
+
+        assert_eq!(spots.len(), self.underlyings.len());
+        assert_eq!(state.len(), self.variables.len());
+
+        let mut context = Context { step: step as f64,
+            last: if last { 1.0 } else { 0.0 }, spots: spots, state: state,
+            paid: 0.0 };
+        let flow = context.block(&self.statements);
+        (context.paid, flow == Flow::Stop)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de> {
+        let source = String::deserialize(deserializer)?;
+        Script::parse(&source).map_err(|e| D::Error::custom(e.to_string()))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Statement {
+    Assign(usize, Expr),
+    If(Expr, Vec<Statement>, Vec<Statement>),
+    Pay(Expr),
+    Stop
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(f64),
+    Variable(usize),
+    Spot(usize),
+    Step,
+    Last,
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Function(Function, Vec<Expr>)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp { Add, Subtract, Multiply, Divide, Less, LessEqual, Greater,
+    GreaterEqual, Equal, NotEqual, And, Or }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function { Max, Min, Abs, Exp, Log, Sqrt }
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "max" => Some(Function::Max),
+            "min" => Some(Function::Min),
+            "abs" => Some(Function::Abs),
+            "exp" => Some(Function::Exp),
+            "log" => Some(Function::Log),
+            "sqrt" => Some(Function::Sqrt),
+            _ => None
+        }
+    }
+
+    /// True if this function takes exactly one argument, rather than one
+    /// or more
+    fn is_unary(&self) -> bool {
+        match *self {
+            Function::Max | Function::Min => false,
+            _ => true
+        }
+    }
+}
+
+/// Names that cannot be used as variables
+const RESERVED: [&str; 16] = ["if", "else", "pay", "stop", "and", "or", "not",
+    "spot", "step", "last", "max", "min", "abs", "exp", "log", "sqrt"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Text(String),
+    Symbol(&'static str)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Identifier(ref s) => write!(f, "{}", s),
+            Token::Text(ref s) => write!(f, "\"{}\"", s),
+            Token::Symbol(s) => write!(f, "{}", s)
+        }
+    }
+}
+
+/// Splits the source into tokens, each with the line it was found on
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, qm::Error> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == '\n' {
+            line += 1;
+            chars.next();
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            tokens.push((Token::Number(number(&mut chars, line)?), line));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') { break; }
+                name.push(c);
+                chars.next();
+            }
+            tokens.push((Token::Identifier(name), line));
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\n') | None => return Err(script_error(
+                        "unterminated string", line)),
+                    Some(c) => text.push(c)
+                }
+            }
+            tokens.push((Token::Text(text), line));
+        } else {
+            chars.next();
+            let next = chars.peek().cloned();
+            let symbol = match (c, next) {
+                ('/', Some('/')) => {
+                    while let Some(&c) = chars.peek() {
+                        if c == '\n' { break; }
+                        chars.next();
+                    }
+                    continue;
+                },
+                ('<', Some('=')) => "<=",
+                ('>', Some('=')) => ">=",
+                ('=', Some('=')) => "==",
+                ('!', Some('=')) => "!=",
+                ('<', _) => "<",
+                ('>', _) => ">",
+                ('=', _) => "=",
+                ('+', _) => "+",
+                ('-', _) => "-",
+                ('*', _) => "*",
+                ('/', _) => "/",
+                ('(', _) => "(",
+                (')', _) => ")",
+                ('{', _) => "{",
+                ('}', _) => "}",
+                (',', _) => ",",
+                (';', _) => ";",
+                _ => return Err(script_error(
+                    &format!("unexpected character '{}'", c), line))
+            };
+            if symbol.len() == 2 {
+                chars.next();
+            }
+            tokens.push((Token::Symbol(symbol), line));
+        }
+    }
+    Ok(tokens)
+}
+
+fn number(chars: &mut Peekable<Chars>, line: usize) -> Result<f64, qm::Error> {
+    let mut text = String::new();
+    while let Some(&c) = chars.peek() {
+        let exponent_sign = (c == '-' || c == '+')
+            && text.ends_with(|e| e == 'e' || e == 'E');
+        if !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign) {
+            break;
+        }
+        text.push(c);
+        chars.next();
+    }
+    text.parse::<f64>().map_err(|_| script_error(
+        &format!("badly formed number '{}'", text), line))
+}
+
+fn script_error(message: &str, line: usize) -> qm::Error {
+    qm::Error::new(&format!("Script error at line {}: {}", line, message))
+}
+
+/// A recursive descent parser, which resolves variables and underlyings to
+/// indices as it goes.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    variables: Vec<String>,
+    assigned: Vec<bool>,
+    underlyings: Vec<String>
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|&(ref token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.position) {
+            Some(&(_, line)) => line,
+            None => self.tokens.last().map_or(1, |&(_, line)| line)
+        }
+    }
+
+    fn error(&self, message: &str) -> qm::Error {
+        script_error(message, self.line())
+    }
+
+    fn next(&mut self) -> Result<Token, qm::Error> {
+        match self.tokens.get(self.position).cloned() {
+            Some((token, _)) => {
+                self.position += 1;
+                Ok(token)
+            },
+            None => Err(self.error("unexpected end of script"))
+        }
+    }
+
+    /// Consumes the next token if it is the given symbol or keyword
+    fn accept(&mut self, expected: &str) -> bool {
+        let found = match self.peek() {
+            Some(&Token::Symbol(s)) => s == expected,
+            Some(&Token::Identifier(ref s)) => s == expected,
+            _ => false
+        };
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), qm::Error> {
+        if self.accept(expected) {
+            Ok(())
+        } else {
+            let found = match self.peek() {
+                Some(token) => format!("'{}'", token),
+                None => "end of script".to_string()
+            };
+            Err(self.error(&format!("expected '{}' but found {}", expected, found)))
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> Result<usize, qm::Error> {
+        if RESERVED.contains(&name) {
+            return Err(self.error(&format!("'{}' cannot be used as a variable", name)))
+        }
+        match self.variables.iter().position(|v| v == name) {
+            Some(index) => Ok(index),
+            None => {
+                self.variables.push(name.to_string());
+                self.assigned.push(false);
+                Ok(self.variables.len() - 1)
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, qm::Error> {
+        if self.accept("if") {
+            return self.if_statement()
+        }
+        if self.accept("pay") {
+            self.expect("(")?;
+            let amount = self.expression()?;
+            self.expect(")")?;
+            self.expect(";")?;
+            return Ok(Statement::Pay(amount))
+        }
+        if self.accept("stop") {
+            self.expect(";")?;
+            return Ok(Statement::Stop)
+        }
+
+        match self.next()? {
+            Token::Identifier(name) => {
+                let index = self.variable(&name)?;
+                self.assigned[index] = true;
+                self.expect("=")?;
+                let value = self.expression()?;
+                self.expect(";")?;
+                Ok(Statement::Assign(index, value))
+            },
+            token => {
+                self.position -= 1;
+                Err(self.error(&format!("expected a statement but found '{}'", token)))
+            }
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<Statement, qm::Error> {
+        let condition = self.expression()?;
+        let then = self.block()?;
+        let otherwise = if self.accept("else") {
+            if self.accept("if") {
+                vec![self.if_statement()?]
+            } else {
+                self.block()?
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(Statement::If(condition, then, otherwise))
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, qm::Error> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.accept("}") {
+            if self.at_end() {
+                return Err(self.error("missing '}'"))
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> Result<Expr, qm::Error> {
+        let mut left = self.conjunction()?;
+        while self.accept("or") {
+            let right = self.conjunction()?;
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn conjunction(&mut self) -> Result<Expr, qm::Error> {
+        let mut left = self.comparison()?;
+        while self.accept("and") {
+            let right = self.comparison()?;
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, qm::Error> {
+        let left = self.sum()?;
+        let ops = [("<=", BinaryOp::LessEqual), (">=", BinaryOp::GreaterEqual),
+            ("==", BinaryOp::Equal), ("!=", BinaryOp::NotEqual),
+            ("<", BinaryOp::Less), (">", BinaryOp::Greater)];
+        for &(symbol, op) in ops.iter() {
+            if self.accept(symbol) {
+                let right = self.sum()?;
+                return Ok(Expr::Binary(op, Box::new(left), Box::new(right)))
+            }
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Expr, qm::Error> {
+        let mut left = self.product()?;
+        loop {
+            let op = if self.accept("+") {
+                BinaryOp::Add
+            } else if self.accept("-") {
+                BinaryOp::Subtract
+            } else {
+                return Ok(left)
+            };
+            let right = self.product()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, qm::Error> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.accept("*") {
+                BinaryOp::Multiply
+            } else if self.accept("/") {
+                BinaryOp::Divide
+            } else {
+                return Ok(left)
+            };
+            let right = self.unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, qm::Error> {
+        if self.accept("-") {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else if self.accept("not") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, qm::Error> {
+        match self.next()? {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Symbol("(") => {
+                let inner = self.expression()?;
+                self.expect(")")?;
+                Ok(inner)
+            },
+            Token::Identifier(name) => {
+                if name == "step" {
+                    Ok(Expr::Step)
+                } else if name == "last" {
+                    Ok(Expr::Last)
+                } else if name == "spot" {
+                    self.spot()
+                } else if let Some(function) = Function::from_name(&name) {
+                    self.function(function)
+                } else {
+                    Ok(Expr::Variable(self.variable(&name)?))
+                }
+            },
+            token => {
+                self.position -= 1;
+                Err(self.error(&format!("expected an expression but found '{}'", token)))
+            }
+        }
+    }
+
+    fn spot(&mut self) -> Result<Expr, qm::Error> {
+        self.expect("(")?;
+        let id = match self.next()? {
+            Token::Text(id) => id,
+            _ => return Err(self.error("spot takes the id of an underlying \
+                in double quotes"))
+        };
+        self.expect(")")?;
+        let index = match self.underlyings.iter().position(|u| *u == id) {
+            Some(index) => index,
+            None => {
+                self.underlyings.push(id);
+                self.underlyings.len() - 1
+            }
+        };
+        Ok(Expr::Spot(index))
+    }
+
+    fn function(&mut self, function: Function) -> Result<Expr, qm::Error> {
+        self.expect("(")?;
+        let mut args = vec![self.expression()?];
+        while self.accept(",") {
+            args.push(self.expression()?);
+        }
+        self.expect(")")?;
+        if function.is_unary() && args.len() != 1 {
+            return Err(self.error(&format!("{:?} takes exactly one argument",
+                function).to_lowercase()))
+        }
+        Ok(Expr::Function(function, args))
+    }
+}
+
+/// Whether execution should carry on after a statement
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flow { Continue, Stop }
+
+/// The state while executing one observation of a script
+struct Context<'a> {
+    step: f64,
+    last: f64,
+    spots: &'a [f64],
+    state: &'a mut [f64],
+    paid: f64
+}
+
+impl<'a> Context<'a> {
+    fn block(&mut self, statements: &[Statement]) -> Flow {
+        for statement in statements.iter() {
+            if self.statement(statement) == Flow::Stop {
+                return Flow::Stop
+            }
+        }
+        Flow::Continue
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Flow {
+        match *statement {
+            Statement::Assign(index, ref value) => {
+                self.state[index] = self.evaluate(value);
+                Flow::Continue
+            },
+            Statement::If(ref condition, ref then, ref otherwise) => {
+                if self.evaluate(condition) != 0.0 {
+                    self.block(then)
+                } else {
+                    self.block(otherwise)
+                }
+            },
+            Statement::Pay(ref amount) => {
+                self.paid += self.evaluate(amount);
+                Flow::Continue
+            },
+            Statement::Stop => Flow::Stop
+        }
+    }
+
+    fn evaluate(&self, expr: &Expr) -> f64 {
+        match *expr {
+            Expr::Number(n) => n,
+            Expr::Variable(index) => self.state[index],
+            Expr::Spot(index) => self.spots[index],
+            Expr::Step => self.step,
+            Expr::Last => self.last,
+            Expr::Negate(ref inner) => -self.evaluate(inner),
+            Expr::Not(ref inner) => truth(self.evaluate(inner) == 0.0),
+            Expr::Binary(BinaryOp::And, ref left, ref right) =>
+                truth(self.evaluate(left) != 0.0 && self.evaluate(right) != 0.0),
+            Expr::Binary(BinaryOp::Or, ref left, ref right) =>
+                truth(self.evaluate(left) != 0.0 || self.evaluate(right) != 0.0),
+            Expr::Binary(op, ref left, ref right) => {
+                let l = self.evaluate(left);
+                let r = self.evaluate(right);
+                match op {
+                    BinaryOp::Add => l + r,
+                    BinaryOp::Subtract => l - r,
+                    BinaryOp::Multiply => l * r,
+                    BinaryOp::Divide => l / r,
+                    BinaryOp::Less => truth(l < r),
+                    BinaryOp::LessEqual => truth(l <= r),
+                    BinaryOp::Greater => truth(l > r),
+                    BinaryOp::GreaterEqual => truth(l >= r),
+                    BinaryOp::Equal => truth(l == r),
+                    BinaryOp::NotEqual => truth(l != r),
+                    BinaryOp::And | BinaryOp::Or => unreachable!()
+                }
+            },
+            Expr::Function(function, ref args) => {
+                let first = self.evaluate(&args[0]);
+                match function {
+                    Function::Max => args[1..].iter()
+                        .fold(first, |m, arg| m.max(self.evaluate(arg))),
+                    Function::Min => args[1..].iter()
+                        .fold(first, |m, arg| m.min(self.evaluate(arg))),
+                    Function::Abs => first.abs(),
+                    Function::Exp => first.exp(),
+                    Function::Log => first.ln(),
+                    Function::Sqrt => first.sqrt()
+                }
+            }
+        }
+    }
+}
+
+fn truth(condition: bool) -> f64 {
+    if condition { 1.0 } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn run(script: &Script, step: usize, last: bool, spots: &[f64],
+        state: &mut [f64]) -> (f64, bool) {
+        script.execute(step, last, spots, state)
+    }
+
+    #[test]
+    fn parse_and_execute_accumulator() {
+        let script = Script::parse("
+            // accumulate the positive moves above the strike
+            total = total + max(spot(\"BP.L\") - strike, 0);
+            if last { pay(notional * total / 100); }").unwrap();
+        assert_eq!(script.underlyings(), &["BP.L".to_string()]);
+        assert_eq!(script.variables(), &["total".to_string(),
+            "strike".to_string(), "notional".to_string()]);
+        assert!(!script.is_input(0));
+        assert!(script.is_input(1));
+        assert!(script.is_input(2));
+
+        let mut state = [0.0, 100.0, 1000.0];
+        assert_eq!(run(&script, 0, false, &[103.0], &mut state), (0.0, false));
+        assert_eq!(run(&script, 1, false, &[97.0], &mut state), (0.0, false));
+        assert_eq!(run(&script, 2, true, &[105.0], &mut state), (80.0, false));
+        assert_eq!(state[0], 8.0);
+    }
+
+    #[test]
+    fn conditionals_and_stop() {
+        let script = Script::parse("
+            if spot(\"BP.L\") > 110 and spot(\"GSK.L\") > 210 {
+                pay(10 * (step + 1));
+                stop;
+                pay(1000);
+            } else if not (spot(\"BP.L\") >= 90) or last {
+                pay(-5);
+            } else {
+                pay(1);
+            }").unwrap();
+        assert_eq!(script.underlyings().len(), 2);
+        let mut state = [];
+        assert_eq!(run(&script, 2, false, &[111.0, 211.0], &mut state), (30.0, true));
+        assert_eq!(run(&script, 2, false, &[111.0, 200.0], &mut state), (1.0, false));
+        assert_eq!(run(&script, 2, false, &[89.0, 211.0], &mut state), (-5.0, false));
+        assert_eq!(run(&script, 2, true, &[100.0, 200.0], &mut state), (-5.0, false));
+    }
+
+    #[test]
+    fn arithmetic_precedence_and_functions() {
+        let script = Script::parse("
+            pay(1 + 2 * 3 - 8 / 4 / 2 + -spot(\"X\") * 2);
+            pay(min(4, 2.5e1, 3) + abs(-1) + sqrt(4) + log(exp(1.5)));
+            pay((1 < 2) + (2 <= 2) + (3 > 4) + (1 == 1) + (1 != 1));").unwrap();
+        let (paid, stopped) = run(&script, 0, false, &[1.0], &mut []);
+        assert_eq!(paid, 4.0 + 7.5 + 3.0);
+        assert!(!stopped);
+    }
+
+    #[test]
+    fn syntax_errors_give_line() {
+        let errors = [
+            ("pay(spot(\"X\"));\nx = ;", "line 2"),
+            ("pay(spot(\"X\"))", "expected ';'"),
+            ("step = spot(\"X\");", "cannot be used as a variable"),
+            ("pay(sqrt(spot(\"X\"), 1));", "takes exactly one argument"),
+            ("pay(spot(X));", "double quotes"),
+            ("if spot(\"X\") > 1 { pay(1);", "missing '}'"),
+            ("pay(spot(\"X\") # 2);", "unexpected character"),
+            ("x = 1;", "at least one underlying")];
+        for &(source, message) in errors.iter() {
+            match Script::parse(source) {
+                Ok(_) => panic!("expected an error from {}", source),
+                Err(e) => assert!(e.to_string().contains(message),
+                    "{} does not contain {}", e, message)
+            }
+        }
+    }
+
+    #[test]
+    fn script_serializes_as_source() {
+        let source = "pay(max(spot(\"BP.L\") - strike, 0));";
+        let script = Script::parse(source).unwrap();
+        let serialized = serde_json::to_string(&script).unwrap();
+        assert_eq!(serialized, serde_json::to_string(source).unwrap());
+        let deserialized: Script = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.source(), source);
+        assert_eq!(deserialized.variables(), script.variables());
+        assert!(serde_json::from_str::<Script>("\"pay(;\"").is_err());
+    }
+}
@@ -0,0 +1,599 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::PathDependent;
+use instruments::PathStatus;
+use instruments::mc_price_path_dependent;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::LifecycleEventType;
+use instruments::lifecycle::in_range;
+use instruments::script::Script;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An instrument whose payoff is given by a script, so that payoffs not in
+/// the library can be priced without a new instrument type. See the script
+/// module for the language. The script is run at each observation date in
+/// turn, seeing the levels of its underlyings on that date, and any amounts
+/// it pays are paid in cash at the settlement date of the observation.
+///
+/// The state of the script, meaning the values of all its variables, is
+/// carried with the instrument. As observations fix, the instrument is
+/// replaced by one with the updated state, so accumulators and other
+/// path-dependent features price correctly once seasoned. Scripted
+/// instruments are priced by Monte-Carlo.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ScriptedInstrument {
+    id: String,
+    credit_id: String,
+    underlyings: Vec<RcInstrument>,
+    settlement: RcDateRule,
+    observations: Vec<DateTime>,
+    script: Script,
+    state: Vec<f64>,
+    next: usize,
+
+    // fields precomputed for performance and simplicity
+    pay_dates: Vec<Date>,
+}
+
+impl TypeId for ScriptedInstrument {
+    fn type_id(&self) -> &'static str { "ScriptedInstrument" }
+}
+
+impl ScriptedInstrument {
+    /// Creates a scripted instrument. The underlyings must be exactly those
+    /// observed by the script, in any order, and must all pay in the same
+    /// currency, which is also the currency of any payments. The
+    /// observations must be in strictly increasing order. The initial values
+    /// must include every input of the script, meaning every variable it
+    /// never assigns, and may also give starting values for other
+    /// variables, which otherwise start at zero.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlyings: &[RcInstrument],
+        settlement: RcDateRule,
+        observations: &[DateTime],
+        script: Script,
+        initial: &[(&str, f64)])
+        -> Result<ScriptedInstrument, qm::Error> {
+
+        // order the underlyings as the script expects them
+        let mut ordered = Vec::with_capacity(underlyings.len());
+        for id in script.underlyings().iter() {
+            match underlyings.iter().find(|u| u.id() == id) {
+                Some(underlying) => ordered.push(underlying.clone()),
+                None => return Err(qm::Error::new(&format!(
+                    "Script observes {} which is not an underlying", id)))
+            }
+        }
+        if ordered.len() != underlyings.len() {
+            return Err(qm::Error::new("Scripted instrument has underlyings \
+                that are not observed by the script"))
+        }
+
+        let mut state = vec![0.0; script.variables().len()];
+        for &(name, value) in initial.iter() {
+            match script.variables().iter().position(|v| v == name) {
+                Some(index) => state[index] = value,
+                None => return Err(qm::Error::new(&format!(
+                    "Initial value for {}, which is not a script variable", name)))
+            }
+        }
+        for (index, variable) in script.variables().iter().enumerate() {
+            if script.is_input(index)
+                && initial.iter().all(|&(name, _)| name != variable) {
+                return Err(qm::Error::new(&format!(
+                    "Script input {} must be given a value", variable)))
+            }
+        }
+
+        ScriptedInstrument::new_seasoned(id, credit_id, ordered, settlement,
+            observations, script, state, 0)
+    }
+
+    /// Creates an instrument where the first few observations have already
+    /// fixed. The state is in the order of the script's variables, and the
+    /// underlyings in the order of the script's underlyings.
+    fn new_seasoned(
+        id: &str,
+        credit_id: &str,
+        underlyings: Vec<RcInstrument>,
+        settlement: RcDateRule,
+        observations: &[DateTime],
+        script: Script,
+        state: Vec<f64>,
+        next: usize)
+        -> Result<ScriptedInstrument, qm::Error> {
+
+        if next >= observations.len() {
+            return Err(qm::Error::new("Scripted instrument must have at least \
+                one unfixed observation"))
+        }
+        for pair in observations.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Scripted instrument observations \
+                    must be in strictly increasing order"))
+            }
+        }
+        let currency = underlyings[0].payoff_currency().id().to_string();
+        if underlyings.iter().any(|u| u.payoff_currency().id() != currency) {
+            return Err(qm::Error::new("Scripted instrument underlyings must \
+                pay in the same currency"))
+        }
+
+        let pay_dates = observations.iter()
+            .map(|o| settlement.apply(o.date())).collect();
+        Ok(ScriptedInstrument {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlyings: underlyings,
+            settlement: settlement,
+            observations: observations.to_vec(),
+            script: script,
+            state: state,
+            next: next,
+            pay_dates: pay_dates })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ScriptedInstrument::deserialize(de)?)))
+    }
+
+    pub fn script(&self) -> &Script {
+        &self.script
+    }
+
+    /// All the observations, including any that have already fixed
+    pub fn observations(&self) -> &[DateTime] {
+        &self.observations
+    }
+
+    /// The observations that have not yet been fixed
+    pub fn unfixed_observations(&self) -> &[DateTime] {
+        &self.observations[self.next..]
+    }
+
+    /// The current value of a script variable, or None if there is no
+    /// variable of this name
+    pub fn value(&self, name: &str) -> Option<f64> {
+        self.script.variables().iter().position(|v| v == name)
+            .map(|index| self.state[index])
+    }
+
+    /// Runs the script for the given observation, updating the state.
+    /// Returns the amount paid and whether the instrument terminates.
+    fn observe(&self, index: usize, spots: &[f64], state: &mut [f64])
+        -> Result<(f64, bool), qm::Error> {
+
+        let last = index + 1 == self.observations.len();
+        let (paid, stopped) = self.script.execute(index, last, spots, state);
+        if !paid.is_finite() {
+            return Err(qm::Error::new(&format!("Script for {} paid {} on {}",
+                self.id, paid, self.observations[index])))
+        }
+        Ok((paid, stopped || last))
+    }
+
+    fn payment(&self, index: usize, amount: f64) -> (f64, RcInstrument) {
+        let date = self.observations[index];
+        (amount, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment:{}", self.id, date.date()), &self.credit_id,
+            RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+            date, self.pay_dates[index], self.settlement.clone())))))
+    }
+}
+
+impl InstanceId for ScriptedInstrument {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for ScriptedInstrument {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlyings[0].payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let expiry_date = self.observations.last().unwrap().date();
+        for underlying in self.underlyings.iter() {
+            for observation in self.unfixed_observations().iter() {
+                context.fixing(underlying.id(), *observation);
+            }
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(&self.credit_id, *self.pay_dates.last().unwrap());
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Runs the script on each observation for which all the underlyings
+    /// have fixed, generating payments. If the script stops, or the last
+    /// observation fixes, only the payments remain. Otherwise, the
+    /// instrument is replaced by one carrying the updated state.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let mut decomp = Vec::new();
+        let mut state = self.state.clone();
+        let mut spots = vec![0.0; self.underlyings.len()];
+        let mut next = self.next;
+        'observations: while next < self.observations.len() {
+            let date = self.observations[next];
+            for (spot, underlying) in spots.iter_mut().zip(self.underlyings.iter()) {
+                match fixing_table.get(underlying.id(), date)? {
+                    Some(fixing) => *spot = fixing,
+                    None => break 'observations
+                }
+            }
+
+            let (amount, terminated) = self.observe(next, &spots, &mut state)?;
+            if amount != 0.0 {
+                decomp.push(self.payment(next, amount));
+            }
+            if terminated {
+                return Ok(Some(decomp))
+            }
+            next += 1;
+        }
+
+        if next == self.next {
+            return Ok(None)
+        }
+
+        let remaining = ScriptedInstrument::new_seasoned(&self.id,
+            &self.credit_id, self.underlyings.clone(), self.settlement.clone(),
+            &self.observations, self.script.clone(), state, next)?;
+        decomp.push((1.0, RcInstrument::new(Qrc::new(Arc::new(remaining)))));
+        Ok(Some(decomp))
+    }
+
+    /// Reports each payment made by the script, and the termination if the
+    /// script stops before the last observation.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        let mut events = Vec::new();
+        let mut state = self.state.clone();
+        let mut spots = vec![0.0; self.underlyings.len()];
+        'observations: for index in self.next..self.observations.len() {
+            let date = self.observations[index];
+            for (spot, underlying) in spots.iter_mut().zip(self.underlyings.iter()) {
+                match fixing_table.get(underlying.id(), date)? {
+                    Some(fixing) => *spot = fixing,
+                    None => break 'observations
+                }
+            }
+
+            let (amount, terminated) = self.observe(index, &spots, &mut state)?;
+            let reported = in_range(date, from, to);
+            if reported && amount != 0.0 {
+                events.push(LifecycleEvent::payment(self, date, amount,
+                    self.pay_dates[index]));
+            }
+            if terminated {
+                if reported && index + 1 < self.observations.len() {
+                    events.push(LifecycleEvent::new(&self.id, date,
+                        LifecycleEventType::Terminated));
+                }
+                break;
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl MonteCarloPriceable for ScriptedInstrument {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Each unfixed observation observes every underlying, and has one
+    /// flow for whatever the script pays on that date.
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        for index in self.next..self.observations.len() {
+            let observation = self.observations[index];
+            for underlying in self.underlyings.iter() {
+                let time = underlying.time_to_day_fraction(observation)?;
+                output.observation(underlying, time);
+            }
+
+            let flow : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+                ZeroCoupon::new(&format!("{}:Obs{}", self.id, index),
+                &self.credit_id, currency.clone(), observation,
+                self.pay_dates[index], self.settlement.clone()))));
+            output.flow(&flow);
+        }
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+        mc_price_path_dependent(self, context)
+    }
+}
+
+impl PathDependent for ScriptedInstrument {
+    fn path_underlyings(&self) -> &[RcInstrument] {
+        &self.underlyings
+    }
+
+    fn path_flows(&self) -> usize {
+        self.observations.len() - self.next
+    }
+
+    /// The state is the value of each of the script's variables
+    fn path_state_size(&self) -> usize {
+        self.state.len()
+    }
+
+    fn path_initial_state(&self, state: &mut [f64]) {
+        state.copy_from_slice(&self.state);
+    }
+
+    fn path_step(&self, step: usize, spots: &[f64], state: &mut [f64],
+        flows: &mut [f64]) -> Result<PathStatus, qm::Error> {
+
+        let (amount, terminated) = self.observe(self.next + step, spots, state)?;
+        flows[step] += amount;
+        Ok(if terminated { PathStatus::Terminated } else { PathStatus::Alive })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::Pricer;
+    use instruments::Priceable;
+    use instruments::assets::Equity;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::exchange::ExchangeOption;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use serde_json;
+
+    fn sample_dates() -> Vec<DateTime> {
+        // quarterly over two years
+        [(2017, 04, 03), (2017, 07, 03), (2017, 10, 02), (2018, 01, 02),
+            (2018, 04, 03), (2018, 07, 02), (2018, 10, 01), (2019, 01, 02)]
+            .iter().map(|&(y, m, d)| DateTime::new(Date::from_ymd(y, m, d),
+            TimeOfDay::Close)).collect()
+    }
+
+    fn sample_underlyings() -> Vec<RcInstrument> {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = |id| RcInstrument::new(Qrc::new(Arc::new(
+            Equity::new(id, "LSE", currency.clone(), sample_settlement(2)))));
+        vec![equity("BP.L"), equity("GSK.L")]
+    }
+
+    fn sample_scripted(source: &str, dates: &[DateTime], underlyings: &[RcInstrument],
+        initial: &[(&str, f64)]) -> ScriptedInstrument {
+        ScriptedInstrument::new("SampleScripted", "OPT", underlyings,
+            sample_settlement(2), dates, Script::parse(source).unwrap(),
+            initial).unwrap()
+    }
+
+    /// A target redemption note, paying a fixed coupon plus a participation
+    /// in any rise of BP.L each quarter, which redeems at par once the
+    /// coupons reach the target, with the coupon that hits the target capped
+    fn sample_tarn_script() -> &'static str {
+        "
+        performance = spot(\"BP.L\") / initial;
+        coupon = fixed + participation * notional * max(performance - 1, 0);
+        coupon = min(coupon, target - paid);
+        paid = paid + coupon;
+        if paid >= target or last {
+            pay(notional + coupon);
+            stop;
+        }
+        pay(coupon);
+        "
+    }
+
+    fn sample_tarn(participation: f64) -> ScriptedInstrument {
+        sample_scripted(sample_tarn_script(), &sample_dates(),
+            &sample_underlyings()[..1], &[("initial", 100.0), ("fixed", 30.0),
+            ("participation", participation), ("notional", 1000.0),
+            ("target", 100.0)])
+    }
+
+    fn mc_price(instrument: RcInstrument) -> f64 {
+        let market_data = sample_correlated_market_data(0.5);
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::with_settings(vec![(1.0, instrument)],
+            model_factory, MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    fn rc(instrument: ScriptedInstrument) -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(instrument)))
+    }
+
+    fn bond_price(ex_date: DateTime) -> f64 {
+        let market_data = sample_market_data();
+        let settlement = sample_settlement(2);
+        let pay_date = settlement.apply(ex_date.date());
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), ex_date, pay_date, settlement);
+        let val_date = sample_val_date();
+        bond.price(&market_data, val_date).unwrap()
+    }
+
+    #[test]
+    fn scripted_call_matches_european() {
+        let expiry = sample_expiry();
+        let underlyings = sample_underlyings();
+        let scripted = sample_scripted("pay(max(spot(\"BP.L\") - strike, 0));",
+            &[expiry], &underlyings[..1], &[("strike", 100.0)]);
+        let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+            underlyings[0].clone(), sample_settlement(2), expiry, 100.0,
+            PutOrCall::Call, OptionSettlement::Cash).unwrap();
+        let val_date = sample_val_date();
+        let expected = european.price(&sample_market_data(), val_date).unwrap();
+        let price = mc_price(rc(scripted));
+        assert_approx(price, expected, 0.02 * expected);
+    }
+
+    #[test]
+    fn scripted_tarn_prices_as_bonds() {
+        // with no participation, every path pays three coupons and then
+        // redeems with a capped coupon of 10
+        let dates = sample_dates();
+        let price = mc_price(rc(sample_tarn(0.0)));
+        let expected = 30.0 * (bond_price(dates[0]) + bond_price(dates[1])
+            + bond_price(dates[2])) + 1010.0 * bond_price(dates[3]);
+        assert_approx(price, expected, 1e-8);
+    }
+
+    #[test]
+    fn worst_of_basket_script() {
+        // pays the worst performance at maturity, which must be less than
+        // either underlying alone
+        let dates = sample_dates();
+        let underlyings = sample_underlyings();
+        let worst = sample_scripted(
+            "if last { pay(100 * min(spot(\"BP.L\") / 100, spot(\"GSK.L\") / 200)); }",
+            &dates, &underlyings, &[]);
+        let bp = sample_scripted("if last { pay(spot(\"BP.L\")); }",
+            &dates, &underlyings[..1], &[]);
+        let worst_price = mc_price(rc(worst));
+        let bp_price = mc_price(rc(bp));
+        assert!(worst_price < bp_price && worst_price > 0.8 * bp_price,
+            "worst={} bp={}", worst_price, bp_price);
+    }
+
+    #[test]
+    fn scripted_exchange_matches_margrabe() {
+        // the right to give up half a GSK.L for a BP.L at maturity
+        let dates = sample_dates();
+        let underlyings = sample_underlyings();
+        let scripted = sample_scripted(
+            "if last { pay(max(spot(\"BP.L\") - spot(\"GSK.L\") / 2, 0)); }",
+            &dates, &underlyings, &[]);
+        let exchange = ExchangeOption::new("SampleExchange", "OPT",
+            vec![(1.0, underlyings[0].clone())], vec![(0.5, underlyings[1].clone())],
+            sample_settlement(2), *dates.last().unwrap()).unwrap();
+        let market_data = sample_correlated_market_data(0.5);
+        let val_date = sample_val_date();
+        let expected = exchange.price(&market_data, val_date).unwrap();
+        let price = mc_price(rc(scripted));
+        assert_approx(price, expected, 0.03 * expected);
+    }
+
+    #[test]
+    fn scripted_fixing_carries_state() {
+        let dates = sample_dates();
+        let scripted = sample_tarn(0.5);
+        let fixings = |levels: &[f64]| {
+            let fixings : Vec<(DateTime, f64)> = dates.iter().cloned()
+                .zip(levels.iter().cloned()).collect();
+            FixingTable::from_fixings(dates[levels.len() - 1].date() + 1,
+                &[("BP.L", &fixings[..])]).unwrap()
+        };
+
+        // after two fixings, two coupons have been paid and the rest of
+        // the note remains, carrying the coupons paid so far
+        let decomp = scripted.fix(&fixings(&[104.0, 98.0])).unwrap().unwrap();
+        assert_eq!(decomp.len(), 3);
+        assert_approx(decomp[0].0, 50.0, 1e-12);
+        assert_approx(decomp[1].0, 30.0, 1e-12);
+        assert_eq!(decomp[2].1.type_id(), "ScriptedInstrument");
+
+        // the remaining note has paid 80, so it always redeems on the next
+        // date with a capped coupon of 20
+        let serialized = serde_json::to_string(&decomp[2].1).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.id(), "SampleScripted");
+        let price = mc_price(deserialized);
+        assert_approx(price, 1020.0 * bond_price(dates[2]), 1e-8);
+
+        // after the third fixing, the target has been hit
+        let decomp = scripted.fix(&fixings(&[104.0, 98.0, 100.0])).unwrap().unwrap();
+        assert_eq!(decomp.len(), 3);
+        assert_approx(decomp[2].0, 1020.0, 1e-12);
+        assert_eq!(decomp[2].1.type_id(), "ZeroCoupon");
+    }
+
+    #[test]
+    fn scripted_validation() {
+        let dates = sample_dates();
+        let underlyings = sample_underlyings();
+        let make = |source: &str, dates: &[DateTime], underlyings: &[RcInstrument],
+            initial: &[(&str, f64)]| ScriptedInstrument::new("SampleScripted",
+            "OPT", underlyings, sample_settlement(2), dates,
+            Script::parse(source).unwrap(), initial);
+        let source = "pay(max(spot(\"BP.L\") - strike, 0));";
+        let scripted = make(source, &dates, &underlyings[..1], &[("strike", 100.0)]).unwrap();
+        assert_eq!(scripted.value("strike"), Some(100.0));
+        assert_eq!(scripted.value("unknown"), None);
+        assert!(make(source, &dates, &underlyings[..1], &[]).is_err());
+        assert!(make(source, &dates, &underlyings[..1],
+            &[("strike", 100.0), ("unknown", 1.0)]).is_err());
+        assert!(make(source, &dates, &underlyings, &[("strike", 100.0)]).is_err());
+        assert!(make(source, &dates, &underlyings[1..], &[("strike", 100.0)]).is_err());
+        assert!(make(source, &[], &underlyings[..1], &[("strike", 100.0)]).is_err());
+        assert!(make(source, &[dates[1], dates[0]], &underlyings[..1],
+            &[("strike", 100.0)]).is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
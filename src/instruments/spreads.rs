@@ -0,0 +1,455 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use math::optionpricing::Black76;
+use math::optionpricing::kirk_spread_variance;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A European option on the spread between two underlyings. A call pays
+/// max(S1 - S2 - K, 0) and a put max(K - S1 + S2, 0), where S1 is the long
+/// underlying and S2 the short one, both at expiry.
+///
+/// Analytic valuation uses Kirk's approximation, which is exact (Margrabe's
+/// formula) when the strike is zero. Under Monte-Carlo, the option can
+/// optionally use the zero-strike exchange option as a control variate,
+/// since its Margrabe value is exact for a Black diffusion. This greatly
+/// reduces the noise where the strike is small relative to the underlyings.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpreadOption {
+    id: String,
+    credit_id: String,
+    long: RcInstrument,
+    short: RcInstrument,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+    control_variate: bool,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for SpreadOption {
+    fn type_id(&self) -> &'static str { "SpreadOption" }
+}
+
+impl SpreadOption {
+    /// Creates a spread option. The underlyings must pay in the same
+    /// currency. If control_variate is set, Monte-Carlo valuation uses the
+    /// exchange option as a control.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        long: RcInstrument,
+        short: RcInstrument,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall,
+        control_variate: bool) -> Result<SpreadOption, qm::Error> {
+
+        if long.id() == short.id() {
+            return Err(qm::Error::new(
+                "Spread option must be on two different underlyings"))
+        }
+        if long.payoff_currency().id() != short.payoff_currency().id() {
+            return Err(qm::Error::new(
+                "Spread option underlyings must pay in the same currency"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = long.time_to_day_fraction(expiry)?;
+        Ok(SpreadOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            long: long,
+            short: short,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            control_variate: control_variate,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SpreadOption::deserialize(de)?)))
+    }
+
+    pub fn control_variate(&self) -> bool {
+        self.control_variate
+    }
+
+    fn payoff(&self, long: f64, short: f64, strike: f64) -> f64 {
+        let spread = long - short;
+        match self.put_or_call {
+            PutOrCall::Call => (spread - strike).max(0.0),
+            PutOrCall::Put => (strike - spread).max(0.0)
+        }
+    }
+
+    /// Values the option by Kirk's approximation, with the given strike.
+    /// A zero strike gives the exact Margrabe value of the exchange option.
+    fn values(&self, context: &PricingContext, strike: f64, dates: &[DateTime],
+        out: &mut [f64]) -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let long_fwd = context.forward_curve(&*self.long, expiry_date)?;
+        let short_fwd = context.forward_curve(&*self.short, expiry_date)?;
+        let long_vol = context.vol_surface(&*self.long, expiry_date,
+            &|| Ok(long_fwd.clone()))?;
+        let short_vol = context.vol_surface(&*self.short, expiry_date,
+            &|| Ok(short_fwd.clone()))?;
+        let correlation = context.correlation(&*self.long, &*self.short)?;
+
+        let f1 = long_fwd.forward(expiry_date)?;
+        let f2 = short_fwd.forward(expiry_date)?;
+        let effective_strike = f2 + strike;
+        if effective_strike <= 0.0 {
+            return Err(qm::Error::new(
+                "Kirk's approximation needs the short forward plus strike to be positive"))
+        }
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                let val_date = self.long.time_to_day_fraction(*date)?;
+                let v1 = long_vol.forward_variance(val_date, self.expiry_time, f1)?;
+                let v2 = short_vol.forward_variance(val_date, self.expiry_time, f2)?;
+                let covariance = correlation * (v1 * v2).sqrt();
+                let variance = kirk_spread_variance(v1, v2, covariance, f2, strike);
+                let sqrt_var = variance.max(0.0).sqrt();
+                match self.put_or_call {
+                    PutOrCall::Call => black76.call_price(df, f1,
+                        effective_strike, sqrt_var),
+                    PutOrCall::Put => black76.put_price(df, f1,
+                        effective_strike, sqrt_var)
+                }
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl InstanceId for SpreadOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for SpreadOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.long.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let expiry_date = self.expiry.date();
+        for underlying in [&self.long, &self.short].iter() {
+            context.fixing(underlying.id(), self.expiry);
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(&self.credit_id, self.pay_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Once both underlyings have fixed at expiry, the option turns into a
+    /// cash payment.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let long = fixing_table.get(self.long.id(), self.expiry)?;
+        let short = fixing_table.get(self.short.id(), self.expiry)?;
+        if let (Some(long), Some(short)) = (long, short) {
+            let mut decomp = Vec::new();
+            let payment = self.payoff(long, short, self.strike);
+            if payment > 0.0 {
+                decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                    &format!("{}:payment", self.id), &self.credit_id,
+                    RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+                    self.expiry, self.pay_date, self.settlement.clone()))))));
+            }
+            Ok(Some(decomp))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Priceable for SpreadOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the spread option using Kirk's approximation
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        self.values(context, self.strike, dates, out)
+    }
+}
+
+impl MonteCarloPriceable for SpreadOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation of each underlying, at expiry
+        output.observation(&self.long, self.expiry_time);
+        output.observation(&self.short, self.expiry_time);
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))));
+        output.flow(&payment);
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    /// If the control variate is enabled, the price is corrected by beta
+    /// times the error in the Monte-Carlo value of the exchange option,
+    /// where beta is the regression coefficient of the payoff on the
+    /// exchange option payoff across the paths.
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let long_paths = context.paths(&self.long)?;
+        let short_paths = context.paths(&self.short)?;
+        assert_eq!(long_paths.shape()[1], 1);
+        assert_eq!(short_paths.shape()[1], 1);
+        let long = long_paths.subview(Axis(1), 0);
+        let short = short_paths.subview(Axis(1), 0);
+        let n_paths = long.len();
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        for ((flow, l), s) in quantities.subview_mut(Axis(1), 0).iter_mut()
+            .zip(long.iter()).zip(short.iter()) {
+            *flow = self.payoff(*l, *s, self.strike);
+        }
+        let price = context.evaluate_flows(quantities.view())?;
+
+        if !self.control_variate {
+            return Ok(price)
+        }
+
+        let mut controls = Array2::zeros((n_paths, 1));
+        for ((flow, l), s) in controls.subview_mut(Axis(1), 0).iter_mut()
+            .zip(long.iter()).zip(short.iter()) {
+            *flow = self.payoff(*l, *s, 0.0);
+        }
+        let control_price = context.evaluate_flows(controls.view())?;
+
+        // the exact value of the control, as of the same date as the flows
+        let pricing_context = context.pricing_context();
+        let val_date = DateTime::new(pricing_context.spot_date(), TimeOfDay::Open);
+        let mut exact = [0.0];
+        self.values(pricing_context, 0.0, &[val_date], &mut exact)?;
+
+        let beta = regression_coefficient(
+            quantities.subview(Axis(1), 0).as_slice().unwrap(),
+            controls.subview(Axis(1), 0).as_slice().unwrap());
+        Ok(price - beta * (control_price - exact[0]))
+    }
+}
+
+/// Regression coefficient of y on x, cov(x, y) / var(x). Returns zero if
+/// x has no variance, in which case it is useless as a control.
+fn regression_coefficient(y: &[f64], x: &[f64]) -> f64 {
+    let n = x.len() as f64;
+    let x_mean = x.iter().sum::<f64>() / n;
+    let y_mean = y.iter().sum::<f64>() / n;
+    let mut covariance = 0.0;
+    let mut variance = 0.0;
+    for (xi, yi) in x.iter().zip(y.iter()) {
+        covariance += (xi - x_mean) * (yi - y_mean);
+        variance += (xi - x_mean) * (xi - x_mean);
+    }
+    if variance > 0.0 { covariance / variance } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::Pricer;
+    use instruments::assets::Equity;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use serde_json;
+
+    fn sample_equity(id: &str) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(
+            Equity::new(id, "LSE", currency, sample_settlement(2)))))
+    }
+
+    fn sample_spread(strike: f64, put_or_call: PutOrCall,
+        control_variate: bool) -> SpreadOption {
+        SpreadOption::new("SampleSpread", "OPT", sample_equity("GSK.L"),
+            sample_equity("BP.L"), sample_settlement(2), sample_expiry(),
+            strike, put_or_call, control_variate).unwrap()
+    }
+
+    fn mc_price(spread: SpreadOption) -> f64 {
+        let market_data = sample_correlated_market_data(0.6);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(spread)));
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::new(vec![(1.0, instrument)], factory,
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn kirk_put_call_parity() {
+        // Kirk prices a call and a put as Black76 on the same forward and
+        // effective strike, so call - put is linear in the strike
+        let market_data = sample_correlated_market_data(0.6);
+        let val_date = sample_val_date();
+        let call_less_put = |strike: f64| {
+            let call = sample_spread(strike, PutOrCall::Call, false)
+                .price(&market_data, val_date).unwrap();
+            let put = sample_spread(strike, PutOrCall::Put, false)
+                .price(&market_data, val_date).unwrap();
+            call - put
+        };
+
+        let at_zero = call_less_put(0.0);
+        let at_45 = call_less_put(45.0);
+        let at_90 = call_less_put(90.0);
+        assert_approx(at_90 - at_zero, 2.0 * (at_45 - at_zero), 1e-10);
+        assert!(at_90 < at_zero);
+    }
+
+    #[test]
+    fn monte_carlo_matches_margrabe() {
+        let market_data = sample_correlated_market_data(0.6);
+        let spread = sample_spread(0.0, PutOrCall::Call, false);
+        let analytic = spread.price(&market_data, sample_val_date()).unwrap();
+        // the standard error with 20000 paths is about 0.5
+        let mc = mc_price(spread);
+        assert_approx(mc, analytic, 1.5);
+    }
+
+    #[test]
+    fn control_variate_matches_kirk() {
+        let market_data = sample_correlated_market_data(0.6);
+        let spread = sample_spread(10.0, PutOrCall::Call, true);
+        let kirk = spread.price(&market_data, sample_val_date()).unwrap();
+        let mc = mc_price(spread);
+        assert_approx(mc, kirk, 0.1);
+
+        // ignoring the control gives the same answer, but much noisier
+        let plain = mc_price(sample_spread(10.0, PutOrCall::Call, false));
+        assert_approx(plain, kirk, 1.5);
+    }
+
+    #[test]
+    fn regression_coefficient_of_proportional_series() {
+        let x = [1.0, 2.0, 4.0, 8.0];
+        let y = [3.0, 5.0, 9.0, 17.0];
+        assert_approx(regression_coefficient(&y, &x), 2.0, 1e-14);
+        assert_approx(regression_coefficient(&y, &[1.0; 4]), 0.0, 1e-14);
+    }
+
+    #[test]
+    fn spread_fixes_to_payment() {
+        let spread = sample_spread(90.0, PutOrCall::Call, true);
+        let expiry = sample_expiry();
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 101.0)]), ("GSK.L", &[(expiry, 205.0)])]).unwrap();
+        let decomp = spread.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 14.0, 1e-12);
+    }
+
+    #[test]
+    fn spread_tagged_serde() {
+        let spread = sample_spread(10.0, PutOrCall::Put, true);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(spread.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let market_data = sample_correlated_market_data(0.6);
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = spread.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
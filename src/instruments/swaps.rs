@@ -0,0 +1,544 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use data::curves::RateCurve;
+use data::fixings::FixingTable;
+use data::fixings::RateFixing;
+use dates::Date;
+use dates::rules::DateRule;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use dates::daycount::DayCount;
+use dates::schedule::roll_schedule;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// Whether the holder of a swap pays or receives the fixed rate. A payer
+/// swap gains value when rates rise.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum PayOrReceive {
+    Pay,
+    Receive
+}
+
+/// A single accrual period of a swap leg. Interest accrues from the start
+/// to the end date, and is paid on the payment date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AccrualPeriod {
+    start: Date,
+    end: Date,
+    payment: Date
+}
+
+impl AccrualPeriod {
+    pub fn new(start: Date, end: Date, payment: Date) -> AccrualPeriod {
+        AccrualPeriod { start: start, end: end, payment: payment }
+    }
+
+    pub fn start(&self) -> Date { self.start }
+    pub fn end(&self) -> Date { self.end }
+    pub fn payment(&self) -> Date { self.payment }
+
+    fn periods(start: Date, end: Date, months: u32, adjustment: &DateRule)
+        -> Result<Vec<AccrualPeriod>, qm::Error> {
+        let dates = roll_schedule(start, end, months, adjustment)?;
+        Ok(dates.windows(2).map(|pair|
+            AccrualPeriod::new(pair[0], pair[1], pair[1])).collect())
+    }
+}
+
+/// The fixed leg of a swap, paying a constant rate on the notional.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FixedLeg {
+    periods: Vec<AccrualPeriod>,
+    day_count: DayCount,
+    rate: f64
+}
+
+impl FixedLeg {
+    /// Creates a fixed leg, rolling a schedule back from the end date with
+    /// the given period in months. The dates are adjusted by the given
+    /// rule, and interest is paid at the end of each period.
+    pub fn new(start: Date, end: Date, months: u32, adjustment: &DateRule,
+        day_count: DayCount, rate: f64) -> Result<FixedLeg, qm::Error> {
+        let periods = AccrualPeriod::periods(start, end, months, adjustment)?;
+        Ok(FixedLeg { periods: periods, day_count: day_count, rate: rate })
+    }
+
+    pub fn periods(&self) -> &[AccrualPeriod] { &self.periods }
+    pub fn day_count(&self) -> DayCount { self.day_count }
+    pub fn rate(&self) -> f64 { self.rate }
+
+    /// A copy of this leg with only the periods starting on or after the
+    /// given date
+    pub fn starting_from(&self, date: Date) -> FixedLeg {
+        let mut leg = self.clone();
+        leg.periods.retain(|p| p.start >= date);
+        leg
+    }
+
+    /// The value of a basis point per unit notional, in units of the
+    /// fixed rate, for all periods paid after the given date. Discount
+    /// factors are to the discount date.
+    fn annuity(&self, yc: &RateCurve, after: Date, discount_date: Date)
+        -> Result<f64, qm::Error> {
+        let mut annuity = 0.0;
+        for period in self.periods.iter().filter(|p| p.payment > after) {
+            let accrual = self.day_count.year_fraction(period.start, period.end);
+            annuity += accrual * yc.df(period.payment, discount_date)?;
+        }
+        Ok(annuity)
+    }
+}
+
+/// A period of a floating leg. The rate is fixed on the fixing date, and
+/// once this has passed, the fixing is recorded here.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FloatingPeriod {
+    accrual: AccrualPeriod,
+    fixing_date: DateTime,
+    fixing: Option<f64>
+}
+
+impl FloatingPeriod {
+    pub fn accrual(&self) -> &AccrualPeriod { &self.accrual }
+    pub fn fixing_date(&self) -> DateTime { self.fixing_date }
+    pub fn fixing(&self) -> Option<f64> { self.fixing }
+}
+
+/// The floating leg of a swap, paying a floating index plus a spread. The
+/// index, such as a Libor rate, is identified by an id which is used to
+/// look up its historical fixings. Future fixings are projected from the
+/// forecast curve, identified by a credit id like any other yield curve.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FloatingLeg {
+    periods: Vec<FloatingPeriod>,
+    day_count: DayCount,
+    index_id: String,
+    forecast_id: String,
+    spread: f64
+}
+
+impl FloatingLeg {
+    /// Creates a floating leg, rolling a schedule back from the end date with
+    /// the given period in months. The dates are adjusted by the given
+    /// rule, and the rate for each period is fixed at the close on the date
+    /// given by applying the fixing rule to the start of the period, for
+    /// example two business days before.
+    pub fn new(start: Date, end: Date, months: u32, adjustment: &DateRule,
+        day_count: DayCount, index_id: &str, forecast_id: &str,
+        fixing_rule: &DateRule, spread: f64) -> Result<FloatingLeg, qm::Error> {
+
+        let periods = AccrualPeriod::periods(start, end, months, adjustment)?
+            .into_iter().map(|accrual| {
+                let fixing_date = DateTime::new(
+                    fixing_rule.apply(accrual.start), TimeOfDay::Close);
+                FloatingPeriod { accrual: accrual, fixing_date: fixing_date,
+                    fixing: None }
+            }).collect();
+
+        Ok(FloatingLeg { periods: periods, day_count: day_count,
+            index_id: index_id.to_string(), forecast_id: forecast_id.to_string(),
+            spread: spread })
+    }
+
+    pub fn periods(&self) -> &[FloatingPeriod] { &self.periods }
+    pub fn day_count(&self) -> DayCount { self.day_count }
+    pub fn index_id(&self) -> &str { &self.index_id }
+
+    /// A copy of this leg with only the periods starting on or after the
+    /// given date
+    pub fn starting_from(&self, date: Date) -> FloatingLeg {
+        let mut leg = self.clone();
+        leg.periods.retain(|p| p.accrual.start >= date);
+        leg
+    }
+    pub fn forecast_id(&self) -> &str { &self.forecast_id }
+    pub fn spread(&self) -> f64 { self.spread }
+
+    /// Registers the fixings of all periods that are not yet fixed
+    pub fn dependencies(&self, context: &mut DependencyContext) {
+        for period in self.periods.iter().filter(|p| p.fixing.is_none()) {
+            context.rate_fixing(&self.index_id, self.rate_fixing(period));
+        }
+    }
+
+    /// Describes the fixing of the given period, including how to project it
+    pub fn rate_fixing(&self, period: &FloatingPeriod) -> RateFixing {
+        RateFixing::new(period.fixing_date, &self.forecast_id,
+            period.accrual.start, period.accrual.end, self.day_count)
+    }
+
+    /// The rate paid in the given period, excluding the spread. This is
+    /// the fixing if known, otherwise it is projected from the forecast
+    /// curve, even if the fixing date has passed. This happens when the spot
+    /// date is bumped forward.
+    pub fn rate(&self, period: &FloatingPeriod, forecast: &RateCurve)
+        -> Result<f64, qm::Error> {
+        match period.fixing {
+            Some(fixing) => Ok(fixing),
+            None => self.rate_fixing(period).forward(forecast)
+        }
+    }
+
+    /// The value per unit notional of all periods paid after the given date,
+    /// discounted to the discount date.
+    pub fn value(&self, yc: &RateCurve, forecast: &RateCurve, after: Date,
+        discount_date: Date) -> Result<f64, qm::Error> {
+        let mut value = 0.0;
+        for period in self.periods.iter().filter(|p| p.accrual.payment > after) {
+            let accrual = &period.accrual;
+            let year_fraction = self.day_count.year_fraction(accrual.start, accrual.end);
+            let rate = self.rate(period, forecast)?;
+            value += (rate + self.spread) * year_fraction
+                * yc.df(accrual.payment, discount_date)?;
+        }
+        Ok(value)
+    }
+
+    /// Returns a copy of this leg with any fixings from the table filled in,
+    /// or None if there are no new fixings.
+    pub fn fix(&self, fixing_table: &FixingTable) -> Result<Option<FloatingLeg>, qm::Error> {
+        let mut fixed = None;
+        for (i, period) in self.periods.iter().enumerate() {
+            if period.fixing.is_some() {
+                continue;
+            }
+            if let Some(fixing) = fixing_table.get(&self.index_id, period.fixing_date)? {
+                fixed.get_or_insert_with(|| self.clone()).periods[i].fixing = Some(fixing);
+            }
+        }
+        Ok(fixed)
+    }
+}
+
+/// A vanilla interest rate swap, exchanging a fixed rate for a floating
+/// index on the same notional. There is no exchange of notional. Both legs
+/// are discounted on the yield curve of the credit id, while the floating
+/// leg is projected from its own forecast curve, which can be the same.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct InterestRateSwap {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    pay_or_receive: PayOrReceive,
+    fixed: FixedLeg,
+    floating: FloatingLeg
+}
+
+impl TypeId for InterestRateSwap {
+    fn type_id(&self) -> &'static str { "InterestRateSwap" }
+}
+
+impl InterestRateSwap {
+    /// Creates a swap, which pays or receives the fixed leg and does the
+    /// opposite on the floating leg. Prices are discounted to the settlement
+    /// date of the currency.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        pay_or_receive: PayOrReceive, fixed: FixedLeg, floating: FloatingLeg)
+        -> Result<InterestRateSwap, qm::Error> {
+
+        if fixed.periods.is_empty() || floating.periods.is_empty() {
+            return Err(qm::Error::new("Swap legs must have at least one period"))
+        }
+
+        Ok(InterestRateSwap { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional, pay_or_receive: pay_or_receive,
+            fixed: fixed, floating: floating })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(InterestRateSwap::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn pay_or_receive(&self) -> PayOrReceive { self.pay_or_receive }
+    pub fn fixed_leg(&self) -> &FixedLeg { &self.fixed }
+    pub fn floating_leg(&self) -> &FloatingLeg { &self.floating }
+
+    /// The swap made up of the periods of both legs starting on or after the
+    /// given date, with the given id. This is the swap entered into by
+    /// exercising a Bermudan swaption on that date.
+    pub fn starting_from(&self, id: &str, date: Date)
+        -> Result<InterestRateSwap, qm::Error> {
+        InterestRateSwap::new(id, &self.credit_id, self.currency.clone(),
+            self.notional, self.pay_or_receive, self.fixed.starting_from(date),
+            self.floating.starting_from(date))
+    }
+
+    /// The value of one unit of fixed rate paid on the notional, for all
+    /// periods paid after the given date. This is the PV01 of the swap
+    /// multiplied by 10,000.
+    pub fn annuity(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<f64, qm::Error> {
+        let yc = context.yield_curve(&self.credit_id, self.last_payment())?;
+        let discount_date = self.settlement().apply(val_date.date());
+        Ok(self.notional * self.fixed.annuity(&*yc, val_date.date(), discount_date)?)
+    }
+
+    /// The fixed rate which would give this swap zero value
+    pub fn par_rate(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<f64, qm::Error> {
+        let (annuity, floating) = self.leg_values(context, val_date)?;
+        if annuity == 0.0 {
+            return Err(qm::Error::new("Swap has no remaining fixed periods"))
+        }
+        Ok(floating / annuity)
+    }
+
+    /// Returns the annuity and floating leg values per unit notional
+    fn leg_values(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<(f64, f64), qm::Error> {
+        let yc = context.yield_curve(&self.credit_id, self.last_payment())?;
+        let forecast = context.yield_curve(&self.floating.forecast_id,
+            self.last_accrual_end())?;
+        let after = val_date.date();
+        let discount_date = self.settlement().apply(after);
+        let annuity = self.fixed.annuity(&*yc, after, discount_date)?;
+        let floating = self.floating.value(&*yc, &*forecast, after, discount_date)?;
+        Ok((annuity, floating))
+    }
+
+    pub fn last_payment(&self) -> Date {
+        let fixed = self.fixed.periods.last().map_or(Date::from_nil(), |p| p.payment);
+        let floating = self.floating.periods.last().map_or(Date::from_nil(), |p| p.accrual.payment);
+        fixed.max(floating)
+    }
+
+    fn last_accrual_end(&self) -> Date {
+        self.floating.periods.last().map_or(Date::from_nil(), |p| p.accrual.end)
+    }
+}
+
+impl InstanceId for InterestRateSwap {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for InterestRateSwap {
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.currency.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.last_payment());
+        context.yield_curve(&self.floating.forecast_id, self.last_accrual_end());
+        self.floating.dependencies(context);
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        match self.floating.fix(fixing_table)? {
+            None => Ok(None),
+            Some(floating) => {
+                let mut swap = self.clone();
+                swap.floating = floating;
+                Ok(Some(vec![(1.0, RcInstrument::new(
+                    Qrc::new(Arc::new(swap))))]))
+            }
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for InterestRateSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The value of the swap is the difference between the two legs, both
+    /// discounted to the settlement date of the currency. Periods paid on
+    /// or before the valuation date are excluded.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let (annuity, floating) = self.leg_values(context, *date)?;
+            let receive_fixed = self.fixed.rate * annuity - floating;
+            *output = self.notional * match self.pay_or_receive {
+                PayOrReceive::Receive => receive_fixed,
+                PayOrReceive::Pay => -receive_fixed
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::BusinessDays;
+    use dates::rules::ModifiedFollowing;
+    use serde_json;
+
+    fn sample_swap(pay_or_receive: PayOrReceive, fixed_rate: f64,
+        forecast_id: &str) -> InterestRateSwap {
+
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let fixing_rule = BusinessDays::new_back(calendar, 2);
+        let start = Date::from_ymd(2017, 01, 04);
+        let end = Date::from_ymd(2019, 01, 04);
+        let fixed = FixedLeg::new(start, end, 12, &adjustment,
+            DayCount::Thirty360, fixed_rate).unwrap();
+        let floating = FloatingLeg::new(start, end, 6, &adjustment,
+            DayCount::Act360, "GBPLIBOR6M", forecast_id, &fixing_rule, 0.0).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        InterestRateSwap::new("SampleSwap", "OPT", currency, 1000000.0,
+            pay_or_receive, fixed, floating).unwrap()
+    }
+
+    #[test]
+    fn swap_schedules() {
+        let swap = sample_swap(PayOrReceive::Pay, 0.08, "LSE");
+        assert_eq!(swap.fixed_leg().periods().len(), 2);
+        let floating = swap.floating_leg().periods();
+        assert_eq!(floating.len(), 4);
+
+        // 2018-01-04 is a Thursday, and the fixing is two business days
+        // before the start of the period
+        assert_eq!(floating[2].accrual().start(), Date::from_ymd(2018, 01, 04));
+        assert_eq!(floating[2].fixing_date(),
+            DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close));
+    }
+
+    #[test]
+    fn par_swap_has_zero_value() {
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let swap = sample_swap(PayOrReceive::Pay, 0.08, "LSE");
+        let par_rate = swap.par_rate(&market_data, val_date).unwrap();
+        assert!(par_rate > 0.08 && par_rate < 0.095, "par_rate={}", par_rate);
+
+        let par_swap = sample_swap(PayOrReceive::Pay, par_rate, "LSE");
+        let price = par_swap.price(&market_data, val_date).unwrap();
+        assert_approx(price, 0.0, 1e-6);
+
+        // the payer swap loses a basis point of annuity per basis point
+        // of fixed rate, and the receiver gains it
+        let annuity = swap.annuity(&market_data, val_date).unwrap();
+        let payer = sample_swap(PayOrReceive::Pay, par_rate + 0.0001, "LSE");
+        let receiver = sample_swap(PayOrReceive::Receive, par_rate + 0.0001, "LSE");
+        assert_approx(payer.price(&market_data, val_date).unwrap(),
+            -0.0001 * annuity, 1e-6);
+        assert_approx(receiver.price(&market_data, val_date).unwrap(),
+            0.0001 * annuity, 1e-6);
+    }
+
+    #[test]
+    fn floating_leg_on_discount_curve_is_worth_par() {
+        // When the floating leg is projected off the discount curve and
+        // starts on the settlement date, its value telescopes to one minus
+        // the discount factor to the end, regardless of day count.
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let swap = sample_swap(PayOrReceive::Receive, 0.0, "OPT");
+        let price = swap.price(&market_data, val_date).unwrap();
+
+        let yc = market_data.yield_curve("OPT", Date::from_ymd(2019, 01, 04)).unwrap();
+        let df = yc.df(Date::from_ymd(2019, 01, 04), Date::from_ymd(2017, 01, 04)).unwrap();
+        assert_approx(price, -1000000.0 * (1.0 - df), 1e-6);
+    }
+
+    #[test]
+    fn fixing_replaces_projected_rate() {
+        let market_data = sample_market_data();
+        let val_date = sample_val_date();
+        let swap = sample_swap(PayOrReceive::Pay, 0.08, "LSE");
+        let unfixed = swap.price(&market_data, val_date).unwrap();
+
+        let fixing_date = swap.floating_leg().periods()[0].fixing_date();
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 01, 03),
+            &[("GBPLIBOR6M", &[(fixing_date, 0.07)])]).unwrap();
+        let fixed = swap.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        let (weight, ref fixed_swap) = fixed[0];
+        assert_eq!(weight, 1.0);
+        let price = fixed_swap.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+
+        // the price moves by the difference between the fixing and the
+        // projected rate in the first period
+        let period = swap.floating_leg().periods()[0].accrual().clone();
+        let forecast = market_data.yield_curve("LSE", period.end()).unwrap();
+        let yc = market_data.yield_curve("OPT", period.end()).unwrap();
+        let year_fraction = DayCount::Act360.year_fraction(period.start(), period.end());
+        let projected = (forecast.df(period.start(), period.end()).unwrap() - 1.0) / year_fraction;
+        let df = yc.df(period.payment(), Date::from_ymd(2017, 01, 04)).unwrap();
+        let expected = unfixed + 1000000.0 * (0.07 - projected) * year_fraction * df;
+        assert_approx(price, expected, 1e-6);
+
+        // fixing again with the same table makes no difference
+        let refixed = fixed_swap.fix(&fixing_table).unwrap();
+        assert!(refixed.is_none());
+    }
+
+    #[test]
+    fn swap_dependencies() {
+        let swap = RcInstrument::new(Qrc::new(Arc::new(
+            sample_swap(PayOrReceive::Pay, 0.08, "LSE"))));
+        let mut dependencies = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        dependencies.spot(&swap);
+
+        assert_eq!(dependencies.fixings("GBPLIBOR6M").len(), 4);
+        assert_eq!(dependencies.yield_curve_hwm("OPT"), Some(Date::from_ymd(2019, 01, 04)));
+        assert_eq!(dependencies.yield_curve_hwm("LSE"), Some(Date::from_ymd(2019, 01, 04)));
+    }
+
+    #[test]
+    fn swap_tagged_serde() {
+        let market_data: MarketData = sample_market_data();
+        let swap = sample_swap(PayOrReceive::Receive, 0.085, "LSE");
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(swap.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = swap.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
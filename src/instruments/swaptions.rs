@@ -0,0 +1,722 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::swaps::InterestRateSwap;
+use instruments::swaps::PayOrReceive;
+use instruments::exercise::ExerciseSchedule;
+use math::optionpricing::Black76;
+use math::lattice::ShortRateLattice;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::daycount::DayCount;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// How a swaption is settled on exercise. Physical settlement delivers the
+/// underlying swap. Cash settlement pays the value of the swap on the
+/// expiry date, calculated conventionally by discounting the fixed leg at
+/// the forward swap rate, as is standard for EUR and GBP swaptions.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum SwaptionSettlement {
+    Physical,
+    Cash
+}
+
+/// A European swaption gives the holder the right, at expiry, to enter
+/// into the underlying swap at its fixed rate, which acts as the strike.
+/// If the swap pays fixed, this is a payer swaption, which behaves like a
+/// call on the swap rate. If it receives fixed, it is a receiver swaption.
+///
+/// Swaptions are priced using Black-76 on the forward swap rate, with the
+/// vol taken from a vol cube identified by id. Exercise at expiry is not
+/// yet handled by fixings, so swaptions are worth zero after expiry.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Swaption {
+    id: String,
+    swap: InterestRateSwap,
+    expiry: DateTime,
+    vol_cube_id: String,
+    settlement: SwaptionSettlement
+}
+
+impl TypeId for Swaption {
+    fn type_id(&self) -> &'static str { "Swaption" }
+}
+
+impl Swaption {
+    /// Creates a European swaption on the given swap. The first fixed
+    /// period of the swap must not start before the expiry.
+    pub fn new(id: &str, swap: InterestRateSwap, expiry: DateTime,
+        vol_cube_id: &str, settlement: SwaptionSettlement)
+        -> Result<Swaption, qm::Error> {
+
+        if swap.fixed_leg().periods()[0].start() < expiry.date() {
+            return Err(qm::Error::new("Swaption underlying must start on or after expiry"))
+        }
+
+        Ok(Swaption { id: id.to_string(), swap: swap, expiry: expiry,
+            vol_cube_id: vol_cube_id.to_string(), settlement: settlement })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Swaption::deserialize(de)?)))
+    }
+
+    pub fn swap(&self) -> &InterestRateSwap { &self.swap }
+    pub fn expiry(&self) -> DateTime { self.expiry }
+    pub fn vol_cube_id(&self) -> &str { &self.vol_cube_id }
+
+    /// The tenor of the underlying swap in years, used to look up the
+    /// vol cube
+    pub fn tenor(&self) -> f64 {
+        let periods = self.swap.fixed_leg().periods();
+        DayCount::Act365.year_fraction(periods[0].start(),
+            periods[periods.len() - 1].end())
+    }
+
+    /// The annuity by which the Black-76 price on the swap rate is
+    /// multiplied, including the notional. For physical settlement, this is
+    /// the value of the fixed leg per unit rate. For cash settlement, it is
+    /// the conventional cash annuity, discounting each fixed period at the
+    /// forward swap rate, paid at the start of the swap.
+    fn annuity(&self, context: &PricingContext, val_date: DateTime, forward: f64)
+        -> Result<f64, qm::Error> {
+        match self.settlement {
+            SwaptionSettlement::Physical => self.swap.annuity(context, val_date),
+
+            SwaptionSettlement::Cash => {
+                let fixed = self.swap.fixed_leg();
+                let day_count = fixed.day_count();
+                let mut compounded = 1.0;
+                let mut cash_annuity = 0.0;
+                for period in fixed.periods().iter() {
+                    let year_fraction = day_count.year_fraction(period.start(), period.end());
+                    compounded *= 1.0 + year_fraction * forward;
+                    cash_annuity += year_fraction / compounded;
+                }
+
+                let start = fixed.periods()[0].start();
+                let yc = context.yield_curve(self.credit_id(), start)?;
+                let discount_date = self.settlement().apply(val_date.date());
+                let df = yc.df(start, discount_date)?;
+                Ok(self.swap.notional() * cash_annuity * df)
+            }
+        }
+    }
+}
+
+impl InstanceId for Swaption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for Swaption {
+    fn payoff_currency(&self) -> &Currency {
+        self.swap.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.swap.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.swap.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        self.swap.dependencies(context);
+        context.vol_cube(&self.vol_cube_id, self.expiry.date());
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for Swaption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let black76 = Black76::new()?;
+        let cube = context.vol_cube(&self.vol_cube_id, self.expiry.date())?;
+        let strike = self.swap.fixed_leg().rate();
+        let tenor = self.tenor();
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            if *date > self.expiry {
+                *output = 0.0;
+                continue;
+            }
+
+            let forward = self.swap.par_rate(context, *date)?;
+            let annuity = self.annuity(context, *date, forward)?;
+            let vol = cube.volatility(self.expiry.date(), tenor, forward, strike)?;
+            let vol_time = vol_time(date.date(), self.expiry.date());
+            let sqrt_variance = vol * vol_time.sqrt();
+
+            *output = match self.swap.pay_or_receive() {
+                PayOrReceive::Pay => black76.call_price(annuity, forward, strike, sqrt_variance),
+                PayOrReceive::Receive => black76.put_price(annuity, forward, strike, sqrt_variance)
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// The maximum number of steps in the lattice used for pricing Bermudan
+/// swaptions. Steps are a whole number of days.
+const BERMUDAN_LATTICE_MAX_STEPS: i32 = 1000;
+
+/// A Bermudan swaption gives the holder the right, on any of a schedule of
+/// exercise dates, to enter into the rest of the underlying swap, made up of
+/// the periods starting on or after the exercise date. Each exercise date
+/// must be the start of a period of both legs of the swap. As for a
+/// European swaption, paying fixed makes this a payer swaption.
+///
+/// Bermudan swaptions are priced on a Ho-Lee short rate lattice calibrated
+/// to the discount curve, in the same way as callable bonds. The normal
+/// volatility of the short rate is taken from the vol cube, at the money,
+/// with the expiry of the first remaining exercise and the tenor from there
+/// to the end of the swap. The floating leg is valued on the lattice as if
+/// projected from the discount curve. Any difference from its value using
+/// the forecast curve and spread is added as a fixed adjustment on each
+/// exercise date.
+///
+/// The exercise dates are registered as dependencies. When time moves past
+/// one of them, the holder exercises if the swap is worth more than the
+/// swaption with the remaining exercise dates.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BermudanSwaption {
+    id: String,
+    swap: InterestRateSwap,
+    schedule: ExerciseSchedule,
+    vol_cube_id: String
+}
+
+impl TypeId for BermudanSwaption {
+    fn type_id(&self) -> &'static str { "BermudanSwaption" }
+}
+
+impl InstanceId for BermudanSwaption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl BermudanSwaption {
+    /// Creates a Bermudan swaption on the given swap. Every exercise date
+    /// must be the start of a fixed period and of a floating period.
+    pub fn new(id: &str, swap: InterestRateSwap, schedule: ExerciseSchedule,
+        vol_cube_id: &str) -> Result<BermudanSwaption, qm::Error> {
+
+        for exercise in schedule.dates().iter() {
+            let date = exercise.date();
+            if !swap.fixed_leg().periods().iter().any(|p| p.start() == date)
+                || !swap.floating_leg().periods().iter().any(|p| p.accrual().start() == date) {
+                return Err(qm::Error::new(&format!(
+                    "Bermudan swaption exercise on {} is not the start of a swap period", date)))
+            }
+        }
+
+        Ok(BermudanSwaption { id: id.to_string(), swap: swap, schedule: schedule,
+            vol_cube_id: vol_cube_id.to_string() })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BermudanSwaption::deserialize(de)?)))
+    }
+
+    pub fn swap(&self) -> &InterestRateSwap { &self.swap }
+    pub fn schedule(&self) -> &ExerciseSchedule { &self.schedule }
+    pub fn vol_cube_id(&self) -> &str { &self.vol_cube_id }
+
+    /// The swap entered into by exercising on the given date
+    pub fn exercised_swap(&self, date: Date) -> Result<InterestRateSwap, qm::Error> {
+        self.swap.starting_from(&format!("{}.EXERCISED", self.id), date)
+    }
+
+    fn last_accrual_end(&self) -> Date {
+        self.swap.floating_leg().periods().last()
+            .map_or(Date::from_nil(), |p| p.accrual().end())
+    }
+
+    /// Prices the swaption on a lattice whose first step is the settlement
+    /// date of the given val date, discounting to that date. Only exercises
+    /// after the val date are considered.
+    fn lattice_price(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<f64, qm::Error> {
+
+        let settlement_date = self.settlement().apply(val_date.date());
+        let end = self.swap.last_payment();
+        let exercises = self.schedule.after(val_date);
+        if exercises.is_empty() || end <= settlement_date {
+            return Ok(0.0)
+        }
+
+        // lay out the steps in whole days, with the last step at the end
+        let days = end - settlement_date;
+        let step_days = (days + BERMUDAN_LATTICE_MAX_STEPS - 1) / BERMUDAN_LATTICE_MAX_STEPS;
+        let steps = ((days + step_days - 1) / step_days) as usize;
+        let step_of = |date: Date| {
+            let offset = (date - settlement_date) as f64 / step_days as f64;
+            (offset.round().max(0.0) as usize).min(steps)
+        };
+
+        let yc = context.yield_curve(self.credit_id(), end)?;
+        let forecast = context.yield_curve(self.swap.floating_leg().forecast_id(),
+            self.last_accrual_end())?;
+        let mut dfs = Vec::with_capacity(steps + 1);
+        for i in 0..(steps + 1) {
+            let date = (settlement_date + i as i32 * step_days).min(end);
+            dfs.push(yc.df(date, settlement_date)?);
+        }
+
+        let first = exercises[0].date();
+        let cube = context.vol_cube(&self.vol_cube_id, self.schedule.last().date())?;
+        let tenor = (end - first) as f64 / 365.0;
+        let vol = cube.volatility(first, tenor, 0.0, 0.0)?;
+        let lattice = ShortRateLattice::new(&dfs, step_days as f64 / 365.0, vol)?;
+
+        // Dates are rounded to the nearest step, so amounts are scaled by
+        // the discount factor from the date to its step. This makes the
+        // lattice exact when the vol is zero.
+        let to_step = |date: Date| -> Result<(usize, f64), qm::Error> {
+            let step = step_of(date);
+            Ok((step, yc.df(date, settlement_date)? / dfs[step]))
+        };
+
+        // The fixed leg plus the notional at the end of the floating leg,
+        // rolled back to an exercise date, less the notional, is the value of
+        // receiving fixed in the rest of the swap if the floating leg were
+        // projected from the discount curve.
+        let notional = self.swap.notional();
+        let fixed = self.swap.fixed_leg();
+        let mut flows = vec![0.0; steps + 1];
+        for period in fixed.periods().iter().filter(|p| p.start() >= first) {
+            let year_fraction = fixed.day_count().year_fraction(period.start(), period.end());
+            let (step, df) = to_step(period.payment())?;
+            flows[step] += notional * fixed.rate() * year_fraction * df;
+        }
+        let float_end = self.swap.floating_leg().periods().last()
+            .map_or(end, |p| p.accrual().payment());
+        let (step, df) = to_step(float_end)?;
+        flows[step] += notional * df;
+
+        // The notional plus any adjustment for the floating leg is paid on
+        // exercise. Also find the sign of the exercise value.
+        let mut strikes = Vec::with_capacity(exercises.len());
+        for exercise in exercises.iter() {
+            let date = exercise.date();
+            let floating = self.swap.floating_leg().starting_from(date)
+                .value(&*yc, &*forecast, date, date)?;
+            let projected = 1.0 - yc.df(float_end, date)?;
+            let (step, df) = to_step(date)?;
+            strikes.push((step, notional * (1.0 + floating - projected) * df));
+        }
+        let sign = match self.swap.pay_or_receive() {
+            PayOrReceive::Receive => 1.0,
+            PayOrReceive::Pay => -1.0
+        };
+
+        // Roll back the fixed flows and the swaption together. At an
+        // exercise, the swaption is worth at least the swap. Cashflows on the
+        // exercise date are not part of the swap, so they are added after.
+        let mut bond = vec![flows[steps]; steps + 1];
+        let mut values = vec![0.0; steps + 1];
+        let mut next_exercise = strikes.len();
+        for step in (0..steps).rev() {
+            bond = lattice.roll_back(step, &bond);
+            values = lattice.roll_back(step, &values);
+            while next_exercise > 0 && strikes[next_exercise - 1].0 >= step {
+                next_exercise -= 1;
+                let (exercise_step, strike) = strikes[next_exercise];
+                if exercise_step != step {
+                    continue;
+                }
+                for (value, bond_value) in values.iter_mut().zip(bond.iter()) {
+                    *value = value.max(sign * (bond_value - strike));
+                }
+            }
+            for bond_value in bond.iter_mut() {
+                *bond_value += flows[step];
+            }
+        }
+
+        Ok(values[0])
+    }
+}
+
+impl Instrument for BermudanSwaption {
+    fn payoff_currency(&self) -> &Currency {
+        self.swap.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        self.swap.credit_id()
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.swap.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        self.swap.dependencies(context);
+        context.vol_cube(&self.vol_cube_id, self.schedule.last().date());
+        self.schedule.dependencies(&self.id, context);
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    /// The holder exercises if the swap is worth more than the swaption with
+    /// the remaining exercise dates, both valued as of the exercise date. An
+    /// unexercised swaption with no remaining exercises lapses.
+    fn exercise(&self, context: &PricingContext, date: DateTime)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        if !self.schedule.contains(date) {
+            return Ok(None)
+        }
+
+        let swap = self.exercised_swap(date.date())?;
+        let exercise_value = swap.price(context, date)?;
+        let remaining = match self.schedule.remaining_after(date) {
+            None => None,
+            Some(schedule) => Some(BermudanSwaption { id: self.id.clone(),
+                swap: self.swap.clone(), schedule: schedule,
+                vol_cube_id: self.vol_cube_id.clone() })
+        };
+        let continuation = match remaining {
+            None => 0.0,
+            Some(ref swaption) => swaption.price(context, date)?
+        };
+
+        if exercise_value > continuation {
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))]))
+        } else {
+            Ok(Some(remaining.into_iter().map(|swaption|
+                (1.0, RcInstrument::new(Qrc::new(Arc::new(swaption))))).collect()))
+        }
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for BermudanSwaption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The value of the swaption on the lattice, discounted to the
+    /// settlement date of the currency. The swaption is worth zero once
+    /// the last exercise date has passed.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = self.lattice_price(context, *date)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Vol cubes measure vol time as Act/365
+fn vol_time(from: Date, to: Date) -> f64 {
+    DayCount::Act365.year_fraction(from, to).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::RcInstrument;
+    use instruments::assets::RcCurrency;
+    use instruments::swaps::FixedLeg;
+    use instruments::swaps::FloatingLeg;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::vegavolga::VegaVolgaReportGenerator;
+    use risk::vegavolga::VegaVolgaReport;
+    use risk::ReportGenerator;
+    use risk::Pricer;
+    use pricers::selfpricer::SelfPricer;
+    use risk::dependencies::DependencyCollector;
+    use data::volcube::RcVolCube;
+    use data::volcube::FlatVolCube;
+    use data::volcube::ParallelBumpVolCube;
+    use data::bumpvol::BumpVol;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::BusinessDays;
+    use dates::rules::ModifiedFollowing;
+    use dates::datetime::TimeOfDay;
+    use serde_json;
+
+    fn sample_swap(pay_or_receive: PayOrReceive, fixed_rate: f64) -> InterestRateSwap {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let fixing_rule = BusinessDays::new_back(calendar, 2);
+        let start = Date::from_ymd(2018, 01, 04);
+        let end = Date::from_ymd(2021, 01, 04);
+        let fixed = FixedLeg::new(start, end, 12, &adjustment,
+            DayCount::Thirty360, fixed_rate).unwrap();
+        let floating = FloatingLeg::new(start, end, 6, &adjustment,
+            DayCount::Act360, "GBPLIBOR6M", "LSE", &fixing_rule, 0.0).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        InterestRateSwap::new("SampleSwap", "OPT", currency, 1000000.0,
+            pay_or_receive, fixed, floating).unwrap()
+    }
+
+    fn sample_swaption(pay_or_receive: PayOrReceive, strike: f64,
+        settlement: SwaptionSettlement) -> Swaption {
+        let expiry = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        Swaption::new("SampleSwaption", sample_swap(pay_or_receive, strike),
+            expiry, "GBPLIBOR6M", settlement).unwrap()
+    }
+
+    fn sample_swaption_market_data(vol: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        let cube = FlatVolCube::new(vol, market_data.spot_date());
+        market_data.add_vol_cube("GBPLIBOR6M", RcVolCube::new(Arc::new(cube)));
+        market_data
+    }
+
+    #[test]
+    fn swaption_black_price() {
+        let market_data = sample_swaption_market_data(0.2);
+        let val_date = sample_val_date();
+        let swaption = sample_swaption(PayOrReceive::Pay, 0.08, SwaptionSettlement::Physical);
+        let price = swaption.price(&market_data, val_date).unwrap();
+
+        let swap = swaption.swap();
+        let forward = swap.par_rate(&market_data, val_date).unwrap();
+        let annuity = swap.annuity(&market_data, val_date).unwrap();
+        let sqrt_variance = 0.2 * (365.0_f64 / 365.0).sqrt();
+        let expected = Black76::new().unwrap().call_price(annuity, forward, 0.08, sqrt_variance);
+        assert_approx(price, expected, 1e-6);
+        assert!(price > 0.0);
+    }
+
+    #[test]
+    fn payer_receiver_parity() {
+        // a payer minus a receiver at the same strike is the forward
+        // starting payer swap
+        let market_data = sample_swaption_market_data(0.2);
+        let val_date = sample_val_date();
+        for &strike in [0.07, 0.085, 0.1].iter() {
+            let payer = sample_swaption(PayOrReceive::Pay, strike,
+                SwaptionSettlement::Physical).price(&market_data, val_date).unwrap();
+            let receiver = sample_swaption(PayOrReceive::Receive, strike,
+                SwaptionSettlement::Physical).price(&market_data, val_date).unwrap();
+            let swap = sample_swap(PayOrReceive::Pay, strike).price(&market_data, val_date).unwrap();
+            assert_approx(payer - receiver, swap, 1e-6);
+        }
+    }
+
+    #[test]
+    fn cash_settled_close_to_physical() {
+        // at the money, the cash annuity is close to the physical one
+        let market_data = sample_swaption_market_data(0.2);
+        let val_date = sample_val_date();
+        let forward = sample_swap(PayOrReceive::Pay, 0.08)
+            .par_rate(&market_data, val_date).unwrap();
+        let physical = sample_swaption(PayOrReceive::Pay, forward,
+            SwaptionSettlement::Physical).price(&market_data, val_date).unwrap();
+        let cash = sample_swaption(PayOrReceive::Pay, forward,
+            SwaptionSettlement::Cash).price(&market_data, val_date).unwrap();
+        assert!((cash / physical - 1.0).abs() < 0.01, "cash={} physical={}", cash, physical);
+    }
+
+    #[test]
+    fn swaption_expired() {
+        let market_data = sample_swaption_market_data(0.2);
+        let swaption = sample_swaption(PayOrReceive::Pay, 0.08, SwaptionSettlement::Physical);
+        let after_expiry = DateTime::new(Date::from_ymd(2018, 01, 03), TimeOfDay::Open);
+        assert_eq!(swaption.price(&market_data, after_expiry).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn swaption_vega_from_vol_cube() {
+        let market_data = sample_swaption_market_data(0.2);
+        let swaption = sample_swaption(PayOrReceive::Receive, 0.08, SwaptionSettlement::Cash);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(swaption.clone())));
+        let mut pricer = SelfPricer::new(vec![(1.0, instrument)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        let generator = VegaVolgaReportGenerator::new(BumpVol::new_flat_additive(0.01));
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaVolgaReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        let vega = results.get("GBPLIBOR6M").unwrap().vega();
+
+        // compare with bumping the cube directly, applying the down bump on
+        // top of the up bump as the report does
+        let val_date = sample_val_date();
+        let down = BumpVol::new_flat_additive(0.01).opposite().bumpsize();
+        let bumped_price = |bump: f64| {
+            let mut bumped = sample_market_data();
+            let cube = RcVolCube::new(Arc::new(FlatVolCube::new(0.2, bumped.spot_date())));
+            bumped.add_vol_cube("GBPLIBOR6M", RcVolCube::new(Arc::new(
+                ParallelBumpVolCube::new(cube, bump))));
+            swaption.price(&bumped, val_date).unwrap()
+        };
+        let expected = (bumped_price(0.01) - bumped_price(0.01 + down)) / 0.02;
+        assert!(vega > 0.0);
+        assert_approx(vega, expected, 1e-6);
+
+        // the pricer is restored after the report
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn swaption_tagged_serde() {
+        let market_data = sample_swaption_market_data(0.2);
+        let swaption = sample_swaption(PayOrReceive::Pay, 0.085, SwaptionSettlement::Cash);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(swaption.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = swaption.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    /// A Bermudan swaption exercisable at the start of any of the given
+    /// fixed periods of the sample swap
+    fn sample_bermudan(pay_or_receive: PayOrReceive, strike: f64,
+        periods: &[usize]) -> BermudanSwaption {
+        let swap = sample_swap(pay_or_receive, strike);
+        let dates: Vec<DateTime> = periods.iter().map(|&i| DateTime::new(
+            swap.fixed_leg().periods()[i].start(), TimeOfDay::Close)).collect();
+        let schedule = ExerciseSchedule::new(&dates).unwrap();
+        BermudanSwaption::new("SampleBermudan", swap, schedule, "GBPLIBOR6M").unwrap()
+    }
+
+    #[test]
+    fn bermudan_with_low_vol_is_worth_the_swap() {
+        let market_data = sample_swaption_market_data(1e-8);
+        let val_date = sample_val_date();
+        let forward = sample_swap(PayOrReceive::Pay, 0.0).par_rate(&market_data,
+            val_date).unwrap();
+
+        for &pay_or_receive in [PayOrReceive::Pay, PayOrReceive::Receive].iter() {
+            for &strike in [forward - 0.01, forward + 0.01].iter() {
+                let bermudan = sample_bermudan(pay_or_receive, strike, &[0]);
+                let price = bermudan.price(&market_data, val_date).unwrap();
+                let first = bermudan.schedule().first().date();
+                let swap = bermudan.exercised_swap(first).unwrap()
+                    .price(&market_data, val_date).unwrap();
+                assert!(approx_eq(price, swap.max(0.0), 1e-3),
+                    "price={} swap={}", price, swap);
+            }
+        }
+    }
+
+    #[test]
+    fn bermudan_worth_more_than_any_single_exercise() {
+        let market_data = sample_swaption_market_data(0.01);
+        let val_date = sample_val_date();
+        let forward = sample_swap(PayOrReceive::Pay, 0.0).par_rate(&market_data,
+            val_date).unwrap();
+
+        for &pay_or_receive in [PayOrReceive::Pay, PayOrReceive::Receive].iter() {
+            let bermudan = sample_bermudan(pay_or_receive, forward, &[0, 1, 2])
+                .price(&market_data, val_date).unwrap();
+            let mut best = 0.0_f64;
+            for i in 0..3 {
+                let single = sample_bermudan(pay_or_receive, forward, &[i])
+                    .price(&market_data, val_date).unwrap();
+                assert!(single > 0.0);
+                best = best.max(single);
+            }
+            assert!(bermudan > best, "bermudan={} best={}", bermudan, best);
+        }
+    }
+
+    #[test]
+    fn bermudan_exercise_decision() {
+        let market_data = sample_swaption_market_data(0.01);
+
+        // paying no fixed rate, exercise into the swap at once
+        let payer = sample_bermudan(PayOrReceive::Pay, 0.0, &[0, 1, 2]);
+        let first = payer.schedule().first();
+        let exercised = payer.exercise(&market_data, first).unwrap().unwrap();
+        assert_eq!(exercised.len(), 1);
+        assert_eq!(exercised[0].1.type_id(), "InterestRateSwap");
+
+        // paying a huge fixed rate, never exercise
+        let payer = sample_bermudan(PayOrReceive::Pay, 0.5, &[0, 1, 2]);
+        let unexercised = payer.exercise(&market_data, first).unwrap().unwrap();
+        assert_eq!(unexercised.len(), 1);
+        assert_eq!(unexercised[0].1.type_id(), "BermudanSwaption");
+
+        // on the last exercise date, the unexercised swaption lapses
+        let payer = sample_bermudan(PayOrReceive::Pay, 0.5, &[2]);
+        let last = payer.schedule().last();
+        assert!(payer.exercise(&market_data, last).unwrap().unwrap().is_empty());
+        assert!(payer.exercise(&market_data, first).unwrap().is_none());
+    }
+
+    #[test]
+    fn bermudan_dependencies() {
+        let bermudan = RcInstrument::new(Qrc::new(Arc::new(
+            sample_bermudan(PayOrReceive::Pay, 0.08, &[0, 1, 2]))));
+        let mut dependencies = DependencyCollector::new(Date::from_ymd(2017, 01, 02));
+        dependencies.spot(&bermudan);
+        assert_eq!(dependencies.exercises("SampleBermudan").len(), 3);
+        assert!(dependencies.vol_cube_hwm("GBPLIBOR6M").is_some());
+    }
+
+    #[test]
+    fn bermudan_rejects_exercise_between_periods() {
+        let swap = sample_swap(PayOrReceive::Pay, 0.08);
+        let schedule = ExerciseSchedule::new(&[DateTime::new(
+            Date::from_ymd(2018, 03, 01), TimeOfDay::Close)]).unwrap();
+        assert!(BermudanSwaption::new("B", swap, schedule, "GBPLIBOR6M").is_err());
+    }
+
+    #[test]
+    fn bermudan_tagged_serde() {
+        let market_data = sample_swaption_market_data(0.01);
+        let bermudan = sample_bermudan(PayOrReceive::Receive, 0.08, &[0, 1, 2]);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(bermudan.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let val_date = sample_val_date();
+        let price = deserialized.as_priceable().unwrap().price(&market_data,
+            val_date).unwrap();
+        let expected = bermudan.price(&market_data, val_date).unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
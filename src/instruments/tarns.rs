@@ -0,0 +1,520 @@
+use std::sync::Arc;
+use std::slice;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::PathDependent;
+use instruments::PathStatus;
+use instruments::mc_price_path_dependent;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::LifecycleEventType;
+use instruments::lifecycle::in_range;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// One coupon observation of a target redemption note. The coupon, as a
+/// fraction of the notional, is the fixed coupon plus the participation
+/// times any rise in the performance of the underlying above the strike.
+/// The strike is expressed as a fraction of the initial level.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TarnObservation {
+    date: DateTime,
+    fixed_coupon: f64,
+    participation: f64,
+    strike: f64
+}
+
+impl TarnObservation {
+    pub fn new(date: DateTime, fixed_coupon: f64, participation: f64,
+        strike: f64) -> TarnObservation {
+        TarnObservation { date: date, fixed_coupon: fixed_coupon,
+            participation: participation, strike: strike }
+    }
+
+    pub fn date(&self) -> DateTime {
+        self.date
+    }
+
+    /// The coupon as a fraction of notional, given the performance of the
+    /// underlying relative to its initial level
+    pub fn coupon(&self, performance: f64) -> f64 {
+        self.fixed_coupon + self.participation * (performance - self.strike).max(0.0)
+    }
+}
+
+/// A target redemption note (TARN). On each observation date the note pays
+/// a coupon, and the coupons paid so far are accumulated. As soon as the
+/// accumulated coupons reach the target, the note redeems at par along with
+/// that coupon, and terminates. If the target is never reached, the note
+/// redeems at par on the final observation date.
+///
+/// If the note is capped, the coupon that hits the target is reduced so
+/// that the total paid is exactly the target. If the note is make-whole,
+/// any shortfall below the target is paid at maturity, so the investor
+/// always receives the target. The target is a fraction of the notional.
+///
+/// The coupons accumulated so far are carried with the note, so a seasoned
+/// note prices correctly. Each observation pays at its own settlement date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Tarn {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    notional: f64,
+    initial_level: f64,
+    observations: Vec<TarnObservation>,
+    target: f64,
+    capped: bool,
+    make_whole: bool,
+    accumulated: f64,
+
+    // fields precomputed for performance and simplicity
+    pay_dates: Vec<Date>,
+}
+
+impl TypeId for Tarn {
+    fn type_id(&self) -> &'static str { "Tarn" }
+}
+
+impl Tarn {
+    /// Creates a target redemption note. The observations are the remaining
+    /// unfixed observations, in strictly increasing date order. The
+    /// accumulated coupons are the total amount (in currency, not as a
+    /// fraction of notional) of coupons paid on already fixed dates.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        notional: f64,
+        initial_level: f64,
+        observations: &[TarnObservation],
+        target: f64,
+        capped: bool,
+        make_whole: bool,
+        accumulated: f64)
+        -> Result<Tarn, qm::Error> {
+
+        if observations.is_empty() {
+            return Err(qm::Error::new("Tarn must have at least one \
+                unfixed observation"))
+        }
+        for pair in observations.windows(2) {
+            if pair[0].date >= pair[1].date {
+                return Err(qm::Error::new("Tarn observations must be \
+                    in strictly increasing order"))
+            }
+        }
+        if initial_level <= 0.0 {
+            return Err(qm::Error::new("Tarn initial level must be positive"))
+        }
+        if target < 0.0 {
+            return Err(qm::Error::new("Tarn target must be non-negative"))
+        }
+
+        let pay_dates = observations.iter()
+            .map(|o| settlement.apply(o.date.date())).collect();
+        Ok(Tarn {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            notional: notional,
+            initial_level: initial_level,
+            observations: observations.to_vec(),
+            target: target,
+            capped: capped,
+            make_whole: make_whole,
+            accumulated: accumulated,
+            pay_dates: pay_dates })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Tarn::deserialize(de)?)))
+    }
+
+    /// The observations that have not yet been fixed
+    pub fn observations(&self) -> &[TarnObservation] {
+        &self.observations
+    }
+
+    /// The total of coupons paid so far, in currency
+    pub fn accumulated(&self) -> f64 {
+        self.accumulated
+    }
+
+    /// Works out what happens at the given observation, given the level of
+    /// the underlying and the coupons accumulated so far, which are updated.
+    /// Returns the amount paid and whether the note terminates.
+    fn observe(&self, index: usize, spot: f64, accumulated: &mut f64) -> (f64, bool) {
+        let performance = spot / self.initial_level;
+        let target = self.notional * self.target;
+        let mut coupon = self.notional * self.observations[index].coupon(performance);
+
+        let hit = *accumulated + coupon >= target;
+        if hit && self.capped {
+            coupon = (target - *accumulated).max(0.0);
+        }
+        *accumulated += coupon;
+
+        if hit {
+            (coupon + self.notional, true)
+        } else if index + 1 == self.observations.len() {
+            let shortfall = if self.make_whole {
+                (target - *accumulated).max(0.0)
+            } else {
+                0.0
+            };
+            (coupon + shortfall + self.notional, true)
+        } else {
+            (coupon, false)
+        }
+    }
+
+    fn payment(&self, index: usize, amount: f64) -> (f64, RcInstrument) {
+        let date = self.observations[index].date;
+        (amount, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment:{}", self.id, date.date()), &self.credit_id,
+            RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+            date, self.pay_dates[index], self.settlement.clone())))))
+    }
+}
+
+impl InstanceId for Tarn {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for Tarn {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let id = self.underlying.id();
+        for observation in self.observations.iter() {
+            context.fixing(id, observation.date);
+        }
+
+        context.yield_curve(&self.credit_id, *self.pay_dates.last().unwrap());
+        let expiry_date = self.observations.last().unwrap().date.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Each fixed observation generates a payment. If the target is hit, or
+    /// the note reaches maturity, only the payments remain. Otherwise, the
+    /// note is replaced by one with fewer observations, carrying the
+    /// accumulated coupons.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut decomp = Vec::new();
+        let mut accumulated = self.accumulated;
+        let mut fixed = 0;
+        for (index, observation) in self.observations.iter().enumerate() {
+            if let Some(fixing) = fixing_table.get(id, observation.date)? {
+                fixed += 1;
+                let (amount, terminated) = self.observe(index, fixing, &mut accumulated);
+                if amount != 0.0 {
+                    decomp.push(self.payment(index, amount));
+                }
+                if terminated {
+                    return Ok(Some(decomp))
+                }
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        let remaining = Tarn::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), self.notional,
+            self.initial_level, &self.observations[fixed..], self.target,
+            self.capped, self.make_whole, accumulated)?;
+        decomp.push((1.0, RcInstrument::new(Qrc::new(Arc::new(remaining)))));
+        Ok(Some(decomp))
+    }
+
+    /// Reports each coupon or redemption paid, and the early termination if
+    /// the target is reached before the final observation.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        let id = self.underlying.id();
+        let mut events = Vec::new();
+        let mut accumulated = self.accumulated;
+        for (index, observation) in self.observations.iter().enumerate() {
+            let fixing = match fixing_table.get(id, observation.date)? {
+                Some(fixing) => fixing,
+                None => break
+            };
+
+            let (amount, terminated) = self.observe(index, fixing, &mut accumulated);
+            let reported = in_range(observation.date, from, to);
+            if reported && amount != 0.0 {
+                events.push(LifecycleEvent::payment(self, observation.date,
+                    amount, self.pay_dates[index]));
+            }
+            if terminated {
+                if reported && index + 1 < self.observations.len() {
+                    events.push(LifecycleEvent::new(&self.id, observation.date,
+                        LifecycleEventType::Terminated));
+                }
+                break;
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl MonteCarloPriceable for Tarn {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// One observation per date, and one flow per date, which holds both
+    /// the coupon and any redemption paid on that date.
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        for (index, observation) in self.observations.iter().enumerate() {
+            let time = self.underlying.time_to_day_fraction(observation.date)?;
+            output.observation(&self.underlying, time);
+
+            let flow : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+                ZeroCoupon::new(&format!("{}:Obs{}", self.id, index),
+                &self.credit_id, currency.clone(), observation.date,
+                self.pay_dates[index], self.settlement.clone()))));
+            output.flow(&flow);
+        }
+
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+        mc_price_path_dependent(self, context)
+    }
+}
+
+impl PathDependent for Tarn {
+    fn path_underlyings(&self) -> &[RcInstrument] {
+        slice::from_ref(&self.underlying)
+    }
+
+    fn path_flows(&self) -> usize {
+        self.observations.len()
+    }
+
+    /// The only state is the coupons accumulated so far on this path
+    fn path_state_size(&self) -> usize { 1 }
+
+    fn path_initial_state(&self, state: &mut [f64]) {
+        state[0] = self.accumulated;
+    }
+
+    fn path_step(&self, step: usize, spots: &[f64], state: &mut [f64],
+        flows: &mut [f64]) -> Result<PathStatus, qm::Error> {
+
+        let (amount, terminated) = self.observe(step, spots[0], &mut state[0]);
+        flows[step] += amount;
+        Ok(if terminated { PathStatus::Terminated } else { PathStatus::Alive })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::Pricer;
+    use instruments::Priceable;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+
+    fn sample_dates() -> Vec<DateTime> {
+        // quarterly over two years
+        [(2017, 04, 03), (2017, 07, 03), (2017, 10, 02), (2018, 01, 02),
+            (2018, 04, 03), (2018, 07, 02), (2018, 10, 01), (2019, 01, 02)]
+            .iter().map(|&(y, m, d)| DateTime::new(Date::from_ymd(y, m, d),
+            TimeOfDay::Close)).collect()
+    }
+
+    fn sample_observations(fixed_coupon: f64, participation: f64)
+        -> Vec<TarnObservation> {
+        sample_dates().iter().map(|d| TarnObservation::new(*d, fixed_coupon,
+            participation, 1.0)).collect()
+    }
+
+    fn sample_tarn(observations: &[TarnObservation], target: f64,
+        capped: bool, make_whole: bool, accumulated: f64) -> Tarn {
+        let equity = sample_underlying();
+        Tarn::new("SampleTarn", "OPT", equity, sample_settlement(2),
+            1000.0, 100.0, observations, target, capped, make_whole,
+            accumulated).unwrap()
+    }
+
+    fn mc_price(tarn: Tarn) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(tarn))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    fn bond_price(ex_date: DateTime) -> f64 {
+        let market_data = sample_market_data();
+        let settlement = sample_settlement(2);
+        let pay_date = settlement.apply(ex_date.date());
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), ex_date, pay_date, settlement);
+        let val_date = sample_val_date();
+        bond.price(&market_data, val_date).unwrap()
+    }
+
+    fn fixings(levels: &[f64]) -> FixingTable {
+        let dates = sample_dates();
+        let fixings : Vec<(DateTime, f64)> = dates.iter().cloned()
+            .zip(levels.iter().cloned()).collect();
+        let today = dates[levels.len() - 1].date() + 1;
+        FixingTable::from_fixings(today, &[("BP.L", &fixings[..])]).unwrap()
+    }
+
+    #[test]
+    fn coupons_accumulate_until_target() {
+        let tarn = sample_tarn(&sample_observations(0.01, 0.5), 0.1,
+            true, false, 0.0);
+
+        // the first coupon is 1% + half of the 4% rise, so 3%
+        let fixed = tarn.fix(&fixings(&[104.0])).unwrap().unwrap();
+        assert_eq!(fixed.len(), 2);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+        assert_approx(fixed[0].0, 30.0, 1e-12);
+        assert_eq!(fixed[1].1.type_id(), "Tarn");
+
+        // the third coupon would take the total to 11%, so it is capped
+        let fixed = tarn.fix(&fixings(&[104.0, 98.0, 112.0])).unwrap().unwrap();
+        assert_eq!(fixed.len(), 3);
+        assert_approx(fixed[0].0, 30.0, 1e-12);
+        assert_approx(fixed[1].0, 10.0, 1e-12);
+        assert_approx(fixed[2].0, 1060.0, 1e-12);
+    }
+
+    #[test]
+    fn uncapped_coupon_is_paid_in_full() {
+        let tarn = sample_tarn(&sample_observations(0.01, 0.5), 0.1,
+            false, false, 40.0);
+        let fixed = tarn.fix(&fixings(&[112.0])).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 1070.0, 1e-12);
+    }
+
+    #[test]
+    fn make_whole_pays_shortfall_at_maturity() {
+        let levels = [90.0, 85.0, 70.0, 60.0, 55.0, 58.0, 52.0, 50.0];
+        let tarn = sample_tarn(&sample_observations(0.01, 0.5), 0.1,
+            true, true, 0.0);
+        let fixed = tarn.fix(&fixings(&levels)).unwrap().unwrap();
+        assert_eq!(fixed.len(), 8);
+        assert_approx(fixed[7].0, 1030.0, 1e-12);
+
+        let tarn = sample_tarn(&sample_observations(0.01, 0.5), 0.1,
+            true, false, 0.0);
+        let fixed = tarn.fix(&fixings(&levels)).unwrap().unwrap();
+        assert_approx(fixed[7].0, 1010.0, 1e-12);
+    }
+
+    #[test]
+    fn fixed_coupon_tarn_prices_as_bonds() {
+
+        // With fixed coupons of 3% and a target of 10%, every path pays
+        // three coupons and then redeems with a capped coupon of 1%.
+        let tarn = sample_tarn(&sample_observations(0.03, 0.0), 0.1,
+            true, false, 0.0);
+        let price = mc_price(tarn);
+        let dates = sample_dates();
+        let expected = 30.0 * (bond_price(dates[0]) + bond_price(dates[1])
+            + bond_price(dates[2])) + 1010.0 * bond_price(dates[3]);
+        assert_approx(price, expected, 1e-8);
+    }
+
+    #[test]
+    fn seasoned_tarn_terminates_sooner() {
+        let tarn = sample_tarn(&sample_observations(0.03, 0.0), 0.1,
+            true, false, 80.0);
+        let price = mc_price(tarn);
+        let expected = 1020.0 * bond_price(sample_dates()[0]);
+        assert_approx(price, expected, 1e-8);
+    }
+
+    #[test]
+    fn tarn_between_bounds() {
+
+        // An uncapped note is worth more than a capped one. A capped note is
+        // worth no more than par plus the target paid at the first date, and
+        // no less than par at maturity.
+        let observations = sample_observations(0.01, 0.5);
+        let first = bond_price(sample_dates()[0]);
+        let maturity = bond_price(*sample_dates().last().unwrap());
+        let capped = mc_price(sample_tarn(&observations, 0.1, true, false, 0.0));
+        let uncapped = mc_price(sample_tarn(&observations, 0.1, false, false, 0.0));
+        assert!(capped < 1100.0 * first, "capped={}", capped);
+        assert!(capped > 1000.0 * maturity, "capped={}", capped);
+        assert!(uncapped > capped, "uncapped={} capped={}", uncapped, capped);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,423 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::swaps::FloatingLeg;
+use instruments::swaps::PayOrReceive;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A reset date of the performance leg of a total return swap. Once the
+/// reset date has passed, the level of the underlying is recorded here.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Reset {
+    date: DateTime,
+    level: Option<f64>
+}
+
+impl Reset {
+    pub fn date(&self) -> DateTime { self.date }
+    pub fn level(&self) -> Option<f64> { self.level }
+}
+
+/// An equity total return swap exchanges the performance of an underlier,
+/// or a weighted basket of underliers, for a floating rate plus a spread.
+///
+/// The performance leg is divided into periods by reset dates. For each
+/// period, it pays the notional times the return of the underlying level,
+/// which is the weighted sum of the underliers, from one reset to the next.
+/// The payment is made on the settlement date of the currency after the end
+/// of the period. The levels are fixings of each underlier on the reset
+/// dates, so the swap fixes as time moves on. The performance leg pays the
+/// price return only. Dividends are not passed through.
+///
+/// The funding leg is a floating leg on the same notional, which does not
+/// reset with the performance. Both legs are discounted on the yield curve of
+/// the credit id.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TotalReturnSwap {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    notional: f64,
+    pay_or_receive: PayOrReceive,
+    underlyings: Vec<(f64, RcInstrument)>,
+    resets: Vec<Reset>,
+    funding: FloatingLeg
+}
+
+impl TypeId for TotalReturnSwap {
+    fn type_id(&self) -> &'static str { "TotalReturnSwap" }
+}
+
+impl InstanceId for TotalReturnSwap {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl TotalReturnSwap {
+    /// Creates a total return swap. If pay_or_receive is Receive, the holder
+    /// receives the performance and pays the funding leg. The underlying
+    /// level is the weighted sum of the underlyings, so pass a single
+    /// underlying with a weight of one for a swap on one equity. The reset
+    /// dates must be in increasing order, and there must be at least two of
+    /// them, the first being the start of the first performance period.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency, notional: f64,
+        pay_or_receive: PayOrReceive, underlyings: Vec<(f64, RcInstrument)>,
+        reset_dates: &[DateTime], funding: FloatingLeg)
+        -> Result<TotalReturnSwap, qm::Error> {
+
+        if underlyings.is_empty() {
+            return Err(qm::Error::new("Total return swap must have an underlying"))
+        }
+        if reset_dates.len() < 2 {
+            return Err(qm::Error::new("Total return swap must have at least two resets"))
+        }
+        if reset_dates.windows(2).any(|pair| pair[1] <= pair[0]) {
+            return Err(qm::Error::new("Reset dates must be in increasing order"))
+        }
+
+        let resets = reset_dates.iter().map(|&date|
+            Reset { date: date, level: None }).collect();
+        Ok(TotalReturnSwap { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, notional: notional, pay_or_receive: pay_or_receive,
+            underlyings: underlyings, resets: resets, funding: funding })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(TotalReturnSwap::deserialize(de)?)))
+    }
+
+    pub fn notional(&self) -> f64 { self.notional }
+    pub fn pay_or_receive(&self) -> PayOrReceive { self.pay_or_receive }
+    pub fn underlyings(&self) -> &[(f64, RcInstrument)] { &self.underlyings }
+    pub fn resets(&self) -> &[Reset] { &self.resets }
+    pub fn funding_leg(&self) -> &FloatingLeg { &self.funding }
+
+    fn last_reset(&self) -> Date {
+        self.resets.last().map_or(Date::from_nil(), |r| r.date.date())
+    }
+
+    fn last_payment(&self) -> Date {
+        let performance = self.settlement().apply(self.last_reset());
+        let funding = self.funding.periods().last()
+            .map_or(Date::from_nil(), |p| p.accrual().payment());
+        performance.max(funding)
+    }
+
+    fn last_accrual_end(&self) -> Date {
+        self.funding.periods().last().map_or(Date::from_nil(), |p| p.accrual().end())
+    }
+
+    /// The level of the underlying at a reset, which is the fixing if known,
+    /// otherwise projected from the forwards of the underlyings.
+    fn level(&self, context: &PricingContext, reset: &Reset) -> Result<f64, qm::Error> {
+        if let Some(level) = reset.level {
+            return Ok(level)
+        }
+        let mut level = 0.0;
+        for &(weight, ref underlying) in self.underlyings.iter() {
+            let curve = context.forward_curve(&**underlying, self.last_reset())?;
+            level += weight * curve.forward(reset.date.date())?;
+        }
+        Ok(level)
+    }
+
+    /// The value of the performance leg per unit notional, for periods paid
+    /// after the given date, discounted to the discount date.
+    fn performance(&self, context: &PricingContext, after: Date,
+        discount_date: Date) -> Result<f64, qm::Error> {
+        let yc = context.yield_curve(&self.credit_id, self.last_payment())?;
+        let mut value = 0.0;
+        for pair in self.resets.windows(2) {
+            let payment = self.settlement().apply(pair[1].date.date());
+            if payment <= after {
+                continue;
+            }
+            let start = self.level(context, &pair[0])?;
+            if !(start > 0.0) {
+                return Err(qm::Error::new("Underlying level must be positive"))
+            }
+            let end = self.level(context, &pair[1])?;
+            value += (end / start - 1.0) * yc.df(payment, discount_date)?;
+        }
+        Ok(value)
+    }
+}
+
+impl Instrument for TotalReturnSwap {
+
+    fn payoff_currency(&self) -> &Currency {
+        &*self.currency
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        self.currency.settlement()
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext) -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.last_payment());
+        context.yield_curve(self.funding.forecast_id(), self.last_accrual_end());
+        self.funding.dependencies(context);
+
+        let unfixed: Vec<DateTime> = self.resets.iter()
+            .filter(|r| r.level.is_none()).map(|r| r.date).collect();
+        if !unfixed.is_empty() {
+            for &(_, ref underlying) in self.underlyings.iter() {
+                context.forward_curve(underlying, self.last_reset());
+                for date in unfixed.iter() {
+                    context.fixing(underlying.id(), *date);
+                }
+            }
+        }
+        SpotRequirement::NotRequired
+    }
+
+    /// A reset fixes once all of its underlyings have fixings. Funding rates
+    /// fix in the same way as a swap.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let mut fixed = None;
+        if let Some(funding) = self.funding.fix(fixing_table)? {
+            fixed.get_or_insert_with(|| self.clone()).funding = funding;
+        }
+
+        for (i, reset) in self.resets.iter().enumerate() {
+            if reset.level.is_some() {
+                continue;
+            }
+            let mut level = 0.0;
+            let mut found = 0;
+            for &(weight, ref underlying) in self.underlyings.iter() {
+                if let Some(fixing) = fixing_table.get(underlying.id(), reset.date)? {
+                    level += weight * fixing;
+                    found += 1;
+                }
+            }
+            if found == self.underlyings.len() {
+                fixed.get_or_insert_with(|| self.clone()).resets[i].level = Some(level);
+            } else if found > 0 {
+                return Err(qm::Error::new(&format!(
+                    "Incomplete fixings for reset of '{}' at {}", self.id, reset.date)))
+            }
+        }
+
+        Ok(fixed.map(|swap| vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))]))
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for TotalReturnSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The value of the performance leg less the funding leg, both discounted
+    /// to the settlement date of the currency. Periods paid on or before the
+    /// valuation date are excluded.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.last_payment())?;
+        let forecast = context.yield_curve(self.funding.forecast_id(),
+            self.last_accrual_end())?;
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let discount_date = self.settlement().apply(date.date());
+            let performance = self.performance(context, date.date(), discount_date)?;
+            let funding = self.funding.value(&*yc, &*forecast, date.date(),
+                discount_date)?;
+            let receive_performance = performance - funding;
+            *output = self.notional * match self.pay_or_receive {
+                PayOrReceive::Receive => receive_performance,
+                PayOrReceive::Pay => -receive_performance
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::assets::Equity;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::rules::BusinessDays;
+    use dates::rules::ModifiedFollowing;
+    use dates::daycount::DayCount;
+    use dates::datetime::TimeOfDay;
+    use serde_json;
+
+    fn sample_underlyings() -> Vec<(f64, RcInstrument)> {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bp = Equity::new("BP.L", "LSE", currency.clone(), sample_settlement(2));
+        let gsk = Equity::new("GSK.L", "LSE", currency, sample_settlement(2));
+        vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(bp)))),
+            (0.5, RcInstrument::new(Qrc::new(Arc::new(gsk))))]
+    }
+
+    fn sample_reset_dates() -> Vec<DateTime> {
+        [Date::from_ymd(2017, 01, 03), Date::from_ymd(2017, 04, 03),
+            Date::from_ymd(2017, 07, 03), Date::from_ymd(2017, 10, 03),
+            Date::from_ymd(2018, 01, 03)].iter()
+            .map(|&date| DateTime::new(date, TimeOfDay::Close)).collect()
+    }
+
+    fn sample_trs(spread: f64, underlyings: Vec<(f64, RcInstrument)>) -> TotalReturnSwap {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let fixing_rule = BusinessDays::new_back(calendar, 2);
+        let funding = FloatingLeg::new(Date::from_ymd(2017, 01, 05),
+            Date::from_ymd(2018, 01, 05), 3, &adjustment, DayCount::Act365,
+            "GBPLIBOR3M", "OPT", &fixing_rule, spread).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        TotalReturnSwap::new("SampleTRS", "OPT", currency, 1e6,
+            PayOrReceive::Receive, underlyings, &sample_reset_dates(), funding).unwrap()
+    }
+
+    fn sample_trs_market_data() -> MarketData {
+        sample_market_data()
+    }
+
+    #[test]
+    fn trs_gains_from_higher_spot() {
+        let mut market_data = sample_trs_market_data();
+        let val_date = sample_val_date();
+        let trs = sample_trs(0.0, sample_underlyings());
+        let price = trs.price(&market_data, val_date).unwrap();
+
+        // the first reset is still to come, so a change in spot rescales all
+        // the levels and hardly changes the price. It changes a little, as
+        // the dividends of the sample equities are partly fixed cash amounts.
+        market_data.set_spot("BP.L", 110.0);
+        let bumped = trs.price(&market_data, val_date).unwrap();
+        assert!((bumped - price).abs() < 1000.0, "bumped={} price={}", bumped, price);
+
+        // once the first reset is fixed, the swap is long the underlying
+        let mut fixed = trs.clone();
+        fixed.resets[0].level = Some(200.0);
+        let market_data = sample_trs_market_data();
+        let fixed_price = fixed.price(&market_data, val_date).unwrap();
+        let mut market_data = sample_trs_market_data();
+        market_data.set_spot("BP.L", 110.0);
+        let fixed_bumped = fixed.price(&market_data, val_date).unwrap();
+        assert!(fixed_bumped - fixed_price > 40000.0,
+            "fixed_bumped={} fixed_price={}", fixed_bumped, fixed_price);
+    }
+
+    #[test]
+    fn trs_spread_reduces_value_to_receiver() {
+        let market_data = sample_trs_market_data();
+        let val_date = sample_val_date();
+        let flat = sample_trs(0.0, sample_underlyings())
+            .price(&market_data, val_date).unwrap();
+        let wide = sample_trs(0.01, sample_underlyings())
+            .price(&market_data, val_date).unwrap();
+
+        // a year of one percent on a million, discounted
+        let difference = flat - wide;
+        assert!(difference > 9000.0 && difference < 10000.0, "difference={}", difference);
+    }
+
+    #[test]
+    fn trs_resets_age_under_bump_time() {
+        let market_data = sample_trs_market_data();
+        let spot_date = market_data.spot_date();
+        let val_date = sample_val_date();
+        let trs = RcInstrument::new(Qrc::new(Arc::new(
+            sample_trs(0.0, sample_underlyings()))));
+
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&trs);
+        assert_eq!(dependencies.fixings("BP.L").len(), 5);
+        assert_eq!(dependencies.fixings("GSK.L").len(), 5);
+
+        // rolling past the first reset fixes it for both underlyings
+        let mut instruments = vec![(1.0, trs.clone())];
+        let bump = BumpTime::new(spot_date + 2, spot_date, SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        let aged = instruments[0].1.clone();
+        let mut aged_dependencies = DependencyCollector::new(spot_date + 2);
+        aged_dependencies.spot(&aged);
+        assert_eq!(aged_dependencies.fixings("BP.L").len(), 4);
+
+        // with sticky forward dynamics, the price hardly changes
+        let price = trs.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        let aged_price = aged.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(aged_price, price, 1e-6);
+    }
+
+    #[test]
+    fn trs_rejects_incomplete_basket_fixings() {
+        let trs = sample_trs(0.0, sample_underlyings());
+        let date = sample_reset_dates()[0];
+        let fixing_table = FixingTable::from_fixings(date.date(),
+            &[("BP.L", &[(date, 100.0)])]).unwrap();
+        assert!(trs.fix(&fixing_table).is_err());
+    }
+
+    #[test]
+    fn trs_rejects_bad_resets() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let adjustment = ModifiedFollowing::new(calendar.clone());
+        let fixing_rule = BusinessDays::new_back(calendar, 2);
+        let funding = FloatingLeg::new(Date::from_ymd(2017, 01, 05),
+            Date::from_ymd(2018, 01, 05), 3, &adjustment, DayCount::Act365,
+            "GBPLIBOR3M", "OPT", &fixing_rule, 0.0).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let mut resets = sample_reset_dates();
+        resets.reverse();
+        assert!(TotalReturnSwap::new("SampleTRS", "OPT", currency, 1e6,
+            PayOrReceive::Receive, sample_underlyings(), &resets, funding).is_err());
+    }
+
+    #[test]
+    fn trs_tagged_serde() {
+        let market_data = sample_trs_market_data();
+        let val_date = sample_val_date();
+        let trs = sample_trs(0.001, sample_underlyings());
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(trs.clone())));
+        let serialized = serde_json::to_string_pretty(&instrument).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+
+        let price = deserialized.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        assert_approx(price, trs.price(&market_data, val_date).unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
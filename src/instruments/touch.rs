@@ -0,0 +1,632 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::barriers::BarrierMonitoring;
+use instruments::lifecycle::LifecycleEvent;
+use instruments::lifecycle::LifecycleEventType;
+use instruments::lifecycle::in_range;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::calendar::RcCalendar;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The four flavours of touch option. An up barrier is touched if the
+/// underlying reaches or exceeds it, and a down barrier if it reaches or
+/// falls below it. A one-touch pays if the barrier is touched, and a
+/// no-touch pays if it is not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TouchType { UpOneTouch, DownOneTouch, UpNoTouch, DownNoTouch }
+
+impl TouchType {
+    /// Returns true if the given value of the underlying touches the barrier
+    pub fn is_hit(&self, barrier: f64, spot: f64) -> bool {
+        match *self {
+            TouchType::UpOneTouch | TouchType::UpNoTouch => spot >= barrier,
+            TouchType::DownOneTouch | TouchType::DownNoTouch => spot <= barrier
+        }
+    }
+
+    /// Returns true for one-touch options, which pay if the barrier is hit
+    pub fn is_one_touch(&self) -> bool {
+        match *self {
+            TouchType::UpOneTouch | TouchType::DownOneTouch => true,
+            TouchType::UpNoTouch | TouchType::DownNoTouch => false
+        }
+    }
+
+    /// Returns true for up barriers
+    pub fn is_up(&self) -> bool {
+        match *self {
+            TouchType::UpOneTouch | TouchType::UpNoTouch => true,
+            TouchType::DownOneTouch | TouchType::DownNoTouch => false
+        }
+    }
+}
+
+/// A one-touch or no-touch option, also known as an American digital. The
+/// barrier is monitored continuously from the start date to expiry, and the
+/// option pays a fixed amount of cash at the settlement date after expiry,
+/// either if the barrier is touched (one-touch) or if it is not (no-touch).
+///
+/// The analytic valuation uses the first-passage probability of a Brownian
+/// motion with constant drift and variance, taken from the forward and vol
+/// surface to expiry. For fixings, continuous monitoring is represented by
+/// the closing fixing on each business day of the given calendar.
+///
+/// Monte-Carlo valuation observes the underlying on a coarser grid of at
+/// most `mc_steps` intervals. Simply checking the barrier at these dates
+/// would miss paths that touch it between them, so between each pair of
+/// observations the probability of touching is given by the Brownian bridge
+/// joining them. This removes most of the discretization bias, and also
+/// makes the payoff smooth in the path, which stabilises the risks.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TouchOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    start: DateTime,
+    expiry: DateTime,
+    calendar: RcCalendar,
+    barrier: f64,
+    touch_type: TouchType,
+    payout: f64,
+    mc_steps: usize,
+
+    // fields precomputed for performance and simplicity
+    observations: Vec<DateTime>,
+    expiry_time: DateDayFraction,
+    pay_date: Date,
+}
+
+impl TypeId for TouchOption {
+    fn type_id(&self) -> &'static str { "TouchOption" }
+}
+
+impl TouchOption {
+    /// Creates a touch option, monitored from the start to the expiry on
+    /// business days of the given calendar. The payout is the amount of
+    /// cash paid, and mc_steps is the maximum number of intervals between
+    /// Monte-Carlo observations.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        start: DateTime,
+        expiry: DateTime,
+        calendar: RcCalendar,
+        barrier: f64,
+        touch_type: TouchType,
+        payout: f64,
+        mc_steps: usize)
+        -> Result<TouchOption, qm::Error> {
+
+        if barrier <= 0.0 {
+            return Err(qm::Error::new("Touch barrier must be greater than zero"))
+        }
+        if mc_steps == 0 {
+            return Err(qm::Error::new("Touch option must have at least one \
+                Monte-Carlo step"))
+        }
+        if start > expiry {
+            return Err(qm::Error::new("Touch monitoring must not start after expiry"))
+        }
+
+        let monitoring = BarrierMonitoring::Continuous {
+            start: start, calendar: calendar.clone() };
+        let observations = monitoring.observations(expiry);
+        if observations.is_empty() {
+            return Err(qm::Error::new("Touch option must have at least one \
+                business day of monitoring"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = underlying.time_to_day_fraction(expiry)?;
+        Ok(TouchOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            start: start,
+            expiry: expiry,
+            calendar: calendar,
+            barrier: barrier,
+            touch_type: touch_type,
+            payout: payout,
+            mc_steps: mc_steps,
+            observations: observations,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(TouchOption::deserialize(de)?)))
+    }
+
+    /// The monitoring dates that have not yet been fixed
+    pub fn observations(&self) -> &[DateTime] {
+        &self.observations
+    }
+
+    /// The dates on which the Monte-Carlo paths are observed: the first and
+    /// last monitoring dates, and evenly spaced dates between them.
+    pub fn mc_observations(&self) -> Vec<DateTime> {
+        let n = self.observations.len();
+        let steps = self.mc_steps.min(n - 1);
+        if steps == 0 {
+            return vec![self.observations[0]]
+        }
+        (0..steps + 1).map(|i| self.observations[i * (n - 1) / steps]).collect()
+    }
+
+    /// The amount paid, given whether the barrier was hit
+    fn payment(&self, hit: bool) -> f64 {
+        if hit == self.touch_type.is_one_touch() { self.payout } else { 0.0 }
+    }
+
+    fn zero_coupon(&self) -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment", self.id), &self.credit_id,
+            RcCurrency::new(Arc::new(self.payoff_currency().clone())),
+            self.expiry, self.pay_date, self.settlement.clone()))))
+    }
+
+    /// Walks the fixings until the barrier is hit or one is not yet known.
+    /// Returns the number fixed and whether the barrier was hit, plus the
+    /// date and level of the last fixing.
+    fn walk_fixings(&self, fixing_table: &FixingTable)
+        -> Result<(usize, bool, Option<(DateTime, f64)>), qm::Error> {
+
+        let id = self.underlying.id();
+        let mut fixed = 0;
+        let mut last = None;
+        for observation in self.observations.iter() {
+            match fixing_table.get(id, *observation)? {
+                Some(fixing) => {
+                    fixed += 1;
+                    last = Some((*observation, fixing));
+                    if self.touch_type.is_hit(self.barrier, fixing) {
+                        return Ok((fixed, true, last))
+                    }
+                },
+                None => break
+            }
+        }
+        Ok((fixed, false, last))
+    }
+}
+
+/// The probability that a Brownian motion starting at zero, with the given
+/// total drift and variance, reaches the given level at some point over the
+/// period. This is the hit probability of a continuously monitored barrier
+/// in log space.
+pub fn hit_probability(level: f64, drift: f64, variance: f64, up: bool,
+    black76: &Black76) -> f64 {
+
+    if (up && level <= 0.0) || (!up && level >= 0.0) {
+        return 1.0
+    }
+    if variance <= 0.0 {
+        return if (up && drift >= level) || (!up && drift <= level) { 1.0 } else { 0.0 }
+    }
+
+    let sd = variance.sqrt();
+    let reflection = (2.0 * drift * level / variance).exp();
+    let probability = if up {
+        black76.cdf((drift - level) / sd)
+            + reflection * black76.cdf((-level - drift) / sd)
+    } else {
+        black76.cdf((level - drift) / sd)
+            + reflection * black76.cdf((level + drift) / sd)
+    };
+    probability.max(0.0).min(1.0)
+}
+
+/// The probability that a Brownian bridge between two points on the same
+/// side of a level, with the given variance between them, touches the level.
+/// All arguments are in log space.
+pub fn bridge_hit_probability(from: f64, to: f64, level: f64, variance: f64) -> f64 {
+    if variance <= 0.0 {
+        return 0.0
+    }
+    (-2.0 * (from - level) * (to - level) / variance).exp()
+}
+
+impl InstanceId for TouchOption {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for TouchOption {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        let id = self.underlying.id();
+        for observation in self.observations.iter() {
+            context.fixing(id, *observation);
+        }
+
+        context.yield_curve(&self.credit_id, self.pay_date);
+        let expiry_date = self.expiry.date();
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        // the level today is taken from the forward curve
+        SpotRequirement::NotRequired
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Once the barrier is touched, or the last monitoring date has fixed,
+    /// the option becomes either a payment at expiry or nothing. Otherwise
+    /// the fixed monitoring dates are removed.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let (fixed, hit, _) = self.walk_fixings(fixing_table)?;
+        if fixed == 0 {
+            return Ok(None)
+        }
+
+        if hit || fixed == self.observations.len() {
+            let amount = self.payment(hit);
+            if amount != 0.0 {
+                Ok(Some(vec![(amount, self.zero_coupon())]))
+            } else {
+                Ok(Some(Vec::new()))
+            }
+        } else {
+            let remaining = TouchOption::new(&self.id, &self.credit_id,
+                self.underlying.clone(), self.settlement.clone(),
+                self.observations[fixed], self.expiry, self.calendar.clone(),
+                self.barrier, self.touch_type, self.payout, self.mc_steps)?;
+            Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(remaining))))]))
+        }
+    }
+
+    /// Reports the barrier being touched, and the payment it causes or
+    /// cancels. A one-touch that is never touched expires at the end of
+    /// its monitoring.
+    fn lifecycle_events(&self, fixing_table: &FixingTable, from: Date, to: Date)
+        -> Result<Vec<LifecycleEvent>, qm::Error> {
+
+        let mut events = Vec::new();
+        let (fixed, hit, last) = self.walk_fixings(fixing_table)?;
+        let (date, level) = match last {
+            Some(last) => last,
+            None => return Ok(events)
+        };
+        if !(hit || fixed == self.observations.len()) || !in_range(date, from, to) {
+            return Ok(events)
+        }
+
+        let amount = self.payment(hit);
+        if hit {
+            let event = if amount != 0.0 {
+                LifecycleEventType::KnockedIn { level: level }
+            } else {
+                LifecycleEventType::KnockedOut { level: level }
+            };
+            events.push(LifecycleEvent::new(&self.id, date, event));
+        }
+        if amount != 0.0 {
+            events.push(LifecycleEvent::payment(self, date, amount, self.pay_date));
+        } else if !hit {
+            events.push(LifecycleEvent::new(&self.id, date,
+                LifecycleEventType::Expired { level: level }));
+        }
+        Ok(events)
+    }
+}
+
+impl Priceable for TouchOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the option from the probability of touching the barrier
+    /// between each date and expiry, treating the forward as the level of
+    /// the underlying on that date.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let forward_curve = context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(forward_curve.clone()))?;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+
+        // work in the displaced space of the vol surface
+        let displacement = vol.displacement(expiry_date)?;
+        let forward = forward_curve.forward(expiry_date)? - displacement;
+        let barrier = self.barrier - displacement;
+        if forward <= 0.0 || barrier <= 0.0 {
+            return Err(qm::Error::new("Touch option forward and barrier must be \
+                above the displacement"))
+        }
+
+        let black76 = Black76::new()?;
+        let start = self.observations[0];
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                let from = if *date > start { *date } else { start };
+                let level = forward_curve.forward(from.date())? - displacement;
+                if level <= 0.0 {
+                    return Err(qm::Error::new("Negative forward"))
+                }
+                let from_time = self.underlying.time_to_day_fraction(from)?;
+                let variance = vol.forward_variance(from_time, self.expiry_time,
+                    self.barrier)?;
+                if variance < 0.0 {
+                    return Err(qm::Error::new("Negative variance"))
+                }
+                let drift = (forward / level).ln() - 0.5 * variance;
+                let probability = hit_probability((barrier / level).ln(), drift,
+                    variance, self.touch_type.is_up(), &black76);
+                let paid = if self.touch_type.is_one_touch() {
+                    probability
+                } else {
+                    1.0 - probability
+                };
+                df * self.payout * paid
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl MonteCarloPriceable for TouchOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        for observation in self.mc_observations() {
+            let time = self.underlying.time_to_day_fraction(observation)?;
+            output.observation(&self.underlying, time);
+        }
+        output.flow(&self.zero_coupon());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    /// Accumulates the probability of not touching the barrier along each
+    /// path. Between observations, this uses the Brownian bridge with the
+    /// at-the-money forward variance, which is the variance used by the
+    /// Black diffusion that generates the paths.
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let observations = self.mc_observations();
+        let pricing_context = context.pricing_context();
+        let expiry_date = self.expiry.date();
+        let forward_curve = pricing_context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = pricing_context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(forward_curve.clone()))?;
+        let displacement = vol.displacement(expiry_date)?;
+
+        let mut variances = Vec::with_capacity(observations.len());
+        for observation in observations.iter() {
+            let time = self.underlying.time_to_day_fraction(*observation)?;
+            let atm = forward_curve.forward(observation.date())?;
+            variances.push(vol.variance(time, atm)?);
+        }
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        assert_eq!(shape[1], observations.len());
+
+        let barrier = (self.barrier - displacement).ln();
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let mut survival = 1.0;
+                let mut previous = 0.0;
+                for (i, spot) in path.iter().enumerate() {
+                    if self.touch_type.is_hit(self.barrier, *spot) {
+                        survival = 0.0;
+                        break;
+                    }
+                    let level = (*spot - displacement).ln();
+                    if i > 0 {
+                        let variance = variances[i] - variances[i - 1];
+                        survival *= 1.0 - bridge_hit_probability(
+                            previous, level, barrier, variance);
+                    }
+                    previous = level;
+                }
+
+                *flow = if self.touch_type.is_one_touch() {
+                    (1.0 - survival) * self.payout
+                } else {
+                    survival * self.payout
+                };
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::Pricer;
+    use dates::calendar::WeekdayCalendar;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+
+    fn sample_touch(barrier: f64, touch_type: TouchType, mc_steps: usize)
+        -> TouchOption {
+        let equity = sample_underlying();
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        TouchOption::new("SampleTouch", "OPT", equity, sample_settlement(2),
+            DateTime::new(Date::from_ymd(2017, 01, 03), TimeOfDay::Close),
+            sample_expiry(),
+            calendar, barrier, touch_type, 100.0, mc_steps).unwrap()
+    }
+
+    fn analytic_price(touch: TouchOption) -> f64 {
+        touch.price(&sample_market_data(), sample_val_date()).unwrap()
+    }
+
+    fn mc_price(touch: TouchOption) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(touch))))];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        let pricer = MonteCarloPricer::new(instruments, model_factory,
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn driftless_hit_probability_is_reflected() {
+        let black76 = Black76::new().unwrap();
+        let expected = 2.0 * black76.cdf(-0.5);
+        assert_approx(hit_probability(-0.1, 0.0, 0.04, false, &black76), expected, 1e-12);
+        assert_approx(hit_probability(0.1, 0.0, 0.04, true, &black76), expected, 1e-12);
+        assert_eq!(hit_probability(0.1, 0.0, 0.04, false, &black76), 1.0);
+        assert_approx(bridge_hit_probability(0.1, 0.1, 0.0, 0.04), (-0.5f64).exp(), 1e-12);
+    }
+
+    #[test]
+    fn one_touch_plus_no_touch_is_bond() {
+        let one_touch = analytic_price(sample_touch(80.0, TouchType::DownOneTouch, 17));
+        let no_touch = analytic_price(sample_touch(80.0, TouchType::DownNoTouch, 17));
+        assert!(one_touch > 20.0 && one_touch < 80.0, "one_touch={}", one_touch);
+
+        let up = analytic_price(sample_touch(120.0, TouchType::UpOneTouch, 17));
+        assert!(up > 20.0 && up < 80.0, "up={}", up);
+
+        // the pair is worth the discounted payout, whatever the barrier
+        let touch = sample_touch(80.0, TouchType::DownOneTouch, 17);
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), touch.expiry,
+            touch.pay_date, sample_settlement(2));
+        let bond_price = bond.price(&sample_market_data(), sample_val_date()).unwrap();
+        assert_approx(one_touch + no_touch, 100.0 * bond_price, 1e-10);
+    }
+
+    #[test]
+    fn bridged_monte_carlo_matches_analytic() {
+
+        // Monthly observations without a bridge would miss many touches. The
+        // tolerance allows for Monte-Carlo noise and for the dividends, which
+        // the analytic price treats as a continuous yield.
+        let touch = sample_touch(80.0, TouchType::DownOneTouch, 17);
+        assert_eq!(touch.mc_observations().len(), 18);
+        let analytic = analytic_price(touch.clone());
+        let mc = mc_price(touch);
+        assert_approx(mc, analytic, 2.0);
+
+        let touch = sample_touch(120.0, TouchType::UpNoTouch, 17);
+        let analytic = analytic_price(touch.clone());
+        let mc = mc_price(touch);
+        assert_approx(mc, analytic, 2.0);
+    }
+
+    #[test]
+    fn touch_fixes_to_payment_or_nothing() {
+        let touch = sample_touch(80.0, TouchType::DownOneTouch, 17);
+        let first = DateTime::new(Date::from_ymd(2017, 01, 03), TimeOfDay::Close);
+        let second = DateTime::new(Date::from_ymd(2017, 01, 04), TimeOfDay::Close);
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 01, 05),
+            &[("BP.L", &[(first, 90.0), (second, 79.0)])]).unwrap();
+        let fixed = touch.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+        assert_eq!(fixed[0].0, 100.0);
+
+        let events = touch.lifecycle_events(&fixing_table, first.date(),
+            Date::from_ymd(2017, 01, 05)).unwrap();
+        assert_eq!(events, vec![
+            LifecycleEvent::new("SampleTouch", second,
+                LifecycleEventType::KnockedIn { level: 79.0 }),
+            LifecycleEvent::payment(&touch, second, 100.0, Date::from_ymd(2018, 06, 05))]);
+
+        let no_touch = sample_touch(80.0, TouchType::DownNoTouch, 17);
+        assert!(no_touch.fix(&fixing_table).unwrap().unwrap().is_empty());
+
+        // an untouched barrier just loses its fixed monitoring dates
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 01, 04),
+            &[("BP.L", &[(first, 90.0)])]).unwrap();
+        let fixed = no_touch.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "TouchOption");
+        assert!(no_touch.lifecycle_events(&fixing_table, first.date(),
+            Date::from_ymd(2017, 01, 04)).unwrap().is_empty());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
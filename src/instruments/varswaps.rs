@@ -0,0 +1,817 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A returns schedule is a set of dates on which an underlying is observed
+/// so that the log returns between consecutive observations can be combined
+/// into a realized variance. As dates are fixed, they are removed from the
+/// schedule and their squared log returns accumulated, along with the last
+/// fixing, which is needed for the next return. This means a seasoned trade
+/// carries its accrued realized variance with it.
+///
+/// Realized variance is calculated in the usual market way, as the
+/// annualised mean of the squared log returns, without subtracting the mean
+/// return.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReturnsSchedule {
+    dates: Vec<DateTime>,
+    last_fixing: Option<f64>,
+    sum_squared_returns: f64,
+    returns_fixed: usize
+}
+
+impl ReturnsSchedule {
+    /// Creates a returns schedule where none of the dates are yet fixed.
+    /// There must be at least two dates, in strictly increasing order.
+    pub fn new(dates: &[DateTime]) -> Result<ReturnsSchedule, qm::Error> {
+        if dates.len() < 2 {
+            return Err(qm::Error::new("Returns schedule must have at least \
+                two dates"))
+        }
+        ReturnsSchedule::new_accrued(dates, None, 0.0, 0)
+    }
+
+    /// Creates a returns schedule where some of the dates are already fixed.
+    /// The fixed dates are represented by the last fixing, plus the sum and
+    /// count of the squared log returns up to that fixing.
+    pub fn new_accrued(dates: &[DateTime], last_fixing: Option<f64>,
+        sum_squared_returns: f64, returns_fixed: usize)
+        -> Result<ReturnsSchedule, qm::Error> {
+
+        if last_fixing.is_none() && returns_fixed > 0 {
+            return Err(qm::Error::new("A returns schedule with fixed returns \
+                must have a last fixing"))
+        }
+        if let Some(fixing) = last_fixing {
+            if fixing <= 0.0 {
+                return Err(qm::Error::new("Fixings in a returns schedule \
+                    must be positive"))
+            }
+        }
+        for pair in dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new("Returns dates must be in \
+                    strictly increasing order"))
+            }
+        }
+        let schedule = ReturnsSchedule { dates: dates.to_vec(),
+            last_fixing: last_fixing, sum_squared_returns: sum_squared_returns,
+            returns_fixed: returns_fixed };
+        if schedule.total_returns() == 0 {
+            return Err(qm::Error::new("Returns schedule must contain at \
+                least one return"))
+        }
+        Ok(schedule)
+    }
+
+    /// The dates that have not yet been fixed
+    pub fn dates(&self) -> &[DateTime] {
+        &self.dates
+    }
+
+    /// The most recent fixing, if any
+    pub fn last_fixing(&self) -> Option<f64> {
+        self.last_fixing
+    }
+
+    /// True if all the dates in the schedule are fixed
+    pub fn is_fixed(&self) -> bool {
+        self.dates.is_empty()
+    }
+
+    /// The total number of returns over the whole schedule, fixed or not
+    pub fn total_returns(&self) -> usize {
+        let unfixed = self.dates.len();
+        if self.last_fixing.is_some() {
+            self.returns_fixed + unfixed
+        } else {
+            self.returns_fixed + unfixed.max(1) - 1
+        }
+    }
+
+    /// The realized variance of the returns fixed so far, annualised with
+    /// the given number of observations per year. Returns None if there are
+    /// no fixed returns.
+    pub fn accrued_variance(&self, observations_per_year: f64) -> Option<f64> {
+        if self.returns_fixed == 0 {
+            None
+        } else {
+            Some(observations_per_year * self.sum_squared_returns
+                / self.returns_fixed as f64)
+        }
+    }
+
+    /// Calculates the realized variance over the whole schedule, given
+    /// values for the unfixed dates. Note that the divisor is the total
+    /// number of returns, so the result is the final realized variance.
+    pub fn realized_variance<'a, I>(&self, unfixed: I, observations_per_year: f64) -> f64
+    where I: IntoIterator<Item = &'a f64> {
+        let (sum, _) = unfixed.into_iter().fold(
+            (self.sum_squared_returns, self.last_fixing),
+            |(sum, previous), x| match previous {
+                Some(p) => { let r = (x / p).ln(); (sum + r * r, Some(*x)) },
+                None => (sum, Some(*x))
+            });
+        observations_per_year * sum / self.total_returns() as f64
+    }
+
+    /// Applies any fixings for the given underlying, returning the
+    /// modified schedule. Returns None if no fixings apply.
+    pub fn fix(&self, id: &str, fixing_table: &FixingTable)
+        -> Result<Option<ReturnsSchedule>, qm::Error> {
+
+        let mut last_fixing = self.last_fixing;
+        let mut sum = self.sum_squared_returns;
+        let mut returns = self.returns_fixed;
+        let mut fixed = 0;
+        for date in self.dates.iter() {
+            if let Some(fixing) = fixing_table.get(id, *date)? {
+                if fixing <= 0.0 {
+                    return Err(qm::Error::new("Fixings in a returns schedule \
+                        must be positive"))
+                }
+                if let Some(previous) = last_fixing {
+                    let r = (fixing / previous).ln();
+                    sum += r * r;
+                    returns += 1;
+                }
+                last_fixing = Some(fixing);
+                fixed += 1;
+            } else {
+                break;
+            }
+        }
+
+        if fixed == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(ReturnsSchedule { dates: self.dates[fixed..].to_vec(),
+                last_fixing: last_fixing, sum_squared_returns: sum,
+                returns_fixed: returns }))
+        }
+    }
+}
+
+/// The strike of a variance or volatility swap may be quoted either as a
+/// volatility or as a variance. For example, a strike of Vol(0.2) is the same
+/// as Variance(0.04).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VarianceStrike { Vol(f64), Variance(f64) }
+
+impl VarianceStrike {
+    /// The strike in variance units
+    pub fn variance(&self) -> f64 {
+        match *self {
+            VarianceStrike::Vol(vol) => vol * vol,
+            VarianceStrike::Variance(variance) => variance
+        }
+    }
+
+    /// The strike in volatility units
+    pub fn vol(&self) -> f64 {
+        match *self {
+            VarianceStrike::Vol(vol) => vol,
+            VarianceStrike::Variance(variance) => variance.sqrt()
+        }
+    }
+
+    fn validate(&self) -> Result<(), qm::Error> {
+        let value = match *self {
+            VarianceStrike::Vol(vol) => vol,
+            VarianceStrike::Variance(variance) => variance
+        };
+        if value < 0.0 {
+            return Err(qm::Error::new("Variance or vol strike must not be negative"))
+        }
+        Ok(())
+    }
+}
+
+/// A variance swap pays the variance notional times the difference between
+/// the realized variance of the underlying over the observation schedule and
+/// the strike variance. The payment may be negative. Realized variance is in
+/// decimal units (e.g. 0.04 for a 20% vol), annualised using the given number
+/// of observations per year, typically 252 for daily observations.
+///
+/// The swap is settled in cash at the settlement date of its last
+/// observation, which is the expiry.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct VarianceSwap {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    schedule: ReturnsSchedule,
+    strike: VarianceStrike,
+    variance_notional: f64,
+    observations_per_year: f64,
+    expiry: DateTime,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date,
+}
+
+impl TypeId for VarianceSwap {
+    fn type_id(&self) -> &'static str { "VarianceSwap" }
+}
+
+impl VarianceSwap {
+    /// Creates a variance swap. If the schedule is not yet fully fixed,
+    /// the expiry must be its last date.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        schedule: ReturnsSchedule,
+        strike: VarianceStrike,
+        variance_notional: f64,
+        observations_per_year: f64,
+        expiry: DateTime)
+        -> Result<VarianceSwap, qm::Error> {
+
+        strike.validate()?;
+        validate_schedule(&schedule, observations_per_year, expiry)?;
+
+        let pay_date = settlement.apply(expiry.date());
+        Ok(VarianceSwap {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            schedule: schedule,
+            strike: strike,
+            variance_notional: variance_notional,
+            observations_per_year: observations_per_year,
+            expiry: expiry,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(VarianceSwap::deserialize(de)?)))
+    }
+
+    pub fn schedule(&self) -> &ReturnsSchedule {
+        &self.schedule
+    }
+
+    pub fn strike(&self) -> VarianceStrike {
+        self.strike
+    }
+
+    /// The payoff given the final realized variance
+    fn payoff(&self, realized_variance: f64) -> f64 {
+        self.variance_notional * (realized_variance - self.strike.variance())
+    }
+}
+
+/// Checks the schedule and annualisation of a variance or vol swap
+fn validate_schedule(schedule: &ReturnsSchedule, observations_per_year: f64,
+    expiry: DateTime) -> Result<(), qm::Error> {
+
+    if observations_per_year <= 0.0 {
+        return Err(qm::Error::new("Observations per year must be positive"))
+    }
+    if let Some(last) = schedule.dates().last() {
+        if *last != expiry {
+            return Err(qm::Error::new("The expiry must be the last date \
+                of the returns schedule"))
+        }
+    }
+    Ok(())
+}
+
+/// Registers the remaining observations, so that time bumps roll the
+/// accrued returns forward, plus the market data needed for valuation.
+fn schedule_dependencies(underlying: &RcInstrument, schedule: &ReturnsSchedule,
+    credit_id: &str, pay_date: Date, expiry: DateTime,
+    context: &mut DependencyContext) -> SpotRequirement {
+
+    let id = underlying.id();
+    for date in schedule.dates().iter() {
+        context.fixing(id, *date);
+    }
+
+    context.yield_curve(credit_id, pay_date);
+    let expiry_date = expiry.date();
+    context.forward_curve(underlying, expiry_date);
+    context.vol_surface(underlying, expiry_date);
+
+    SpotRequirement::NotRequired
+}
+
+/// Registers the Monte-Carlo observations and the single payment flow
+fn schedule_mc_dependencies(instrument: &Instrument, underlying: &RcInstrument,
+    schedule: &ReturnsSchedule, expiry: DateTime, pay_date: Date,
+    output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+    for date in schedule.dates().iter() {
+        let time = underlying.time_to_day_fraction(*date)?;
+        output.observation(underlying, time);
+    }
+
+    let currency = RcCurrency::new(Arc::new(instrument.payoff_currency().clone()));
+    let payment : RcInstrument = RcInstrument::new(Qrc::new(Arc::new(
+        ZeroCoupon::new(&format!("{}:Expiry", instrument.id()),
+        instrument.credit_id(), currency, expiry, pay_date,
+        instrument.settlement().clone()))));
+    output.flow(&payment);
+
+    Ok(())
+}
+
+/// Converts a fully fixed swap into its payment
+fn payment(instrument: &Instrument, amount: f64, expiry: DateTime, pay_date: Date)
+    -> Vec<(f64, RcInstrument)> {
+
+    let mut decomp = Vec::new();
+    if amount != 0.0 {
+        decomp.push((amount, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:payment", instrument.id()), instrument.credit_id(),
+            RcCurrency::new(Arc::new(instrument.payoff_currency().clone())),
+            expiry, pay_date, instrument.settlement().clone()))))));
+    }
+    decomp
+}
+
+impl InstanceId for VarianceSwap {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for VarianceSwap {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        schedule_dependencies(&self.underlying, &self.schedule,
+            &self.credit_id, self.pay_date, self.expiry, context)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Accrues any fixings into the realized leg. Once all the dates are
+    /// fixed, the swap becomes a single payment, which may be negative.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let schedule = match self.schedule.fix(self.underlying.id(), fixing_table)? {
+            Some(schedule) => schedule,
+            None => return Ok(None)
+        };
+
+        if schedule.is_fixed() {
+            let empty: [f64; 0] = [];
+            let realized = schedule.realized_variance(empty.iter(),
+                self.observations_per_year);
+            return Ok(Some(payment(self, self.payoff(realized),
+                self.expiry, self.pay_date)))
+        }
+
+        let swap = VarianceSwap::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), schedule,
+            self.strike, self.variance_notional, self.observations_per_year,
+            self.expiry)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))]))
+    }
+}
+
+impl MonteCarloPriceable for VarianceSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+        schedule_mc_dependencies(self, &self.underlying, &self.schedule,
+            self.expiry, self.pay_date, output)
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        assert_eq!(shape[1], self.schedule.dates().len());
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let realized = self.schedule.realized_variance(path.iter(),
+                    self.observations_per_year);
+                *flow = self.payoff(realized);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+/// A volatility swap pays the vega notional times the difference between the
+/// realized volatility of the underlying over the observation schedule and
+/// the strike vol. Realized volatility is the square root of the realized
+/// variance, calculated exactly as for a variance swap. Because the square
+/// root is concave, a vol swap struck at the square root of the fair
+/// variance strike has negative value. This convexity makes vol swaps hard
+/// to replicate, so they are valued by Monte-Carlo.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct VolatilitySwap {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    schedule: ReturnsSchedule,
+    strike: VarianceStrike,
+    vega_notional: f64,
+    observations_per_year: f64,
+    expiry: DateTime,
+
+    // fields precomputed for performance and simplicity
+    pay_date: Date,
+}
+
+impl TypeId for VolatilitySwap {
+    fn type_id(&self) -> &'static str { "VolatilitySwap" }
+}
+
+impl VolatilitySwap {
+    /// Creates a volatility swap. If the schedule is not yet fully fixed,
+    /// the expiry must be its last date.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        schedule: ReturnsSchedule,
+        strike: VarianceStrike,
+        vega_notional: f64,
+        observations_per_year: f64,
+        expiry: DateTime)
+        -> Result<VolatilitySwap, qm::Error> {
+
+        strike.validate()?;
+        validate_schedule(&schedule, observations_per_year, expiry)?;
+
+        let pay_date = settlement.apply(expiry.date());
+        Ok(VolatilitySwap {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            schedule: schedule,
+            strike: strike,
+            vega_notional: vega_notional,
+            observations_per_year: observations_per_year,
+            expiry: expiry,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(VolatilitySwap::deserialize(de)?)))
+    }
+
+    pub fn schedule(&self) -> &ReturnsSchedule {
+        &self.schedule
+    }
+
+    pub fn strike(&self) -> VarianceStrike {
+        self.strike
+    }
+
+    /// The payoff given the final realized variance
+    fn payoff(&self, realized_variance: f64) -> f64 {
+        self.vega_notional * (realized_variance.sqrt() - self.strike.vol())
+    }
+}
+
+impl InstanceId for VolatilitySwap {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Instrument for VolatilitySwap {
+    fn payoff_currency(&self) -> &Currency {
+        self.underlying.payoff_currency()
+    }
+
+    fn credit_id(&self) -> &str {
+        &self.credit_id
+    }
+
+    fn settlement(&self) -> &RcDateRule {
+        &self.settlement
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        schedule_dependencies(&self.underlying, &self.schedule,
+            &self.credit_id, self.pay_date, self.expiry, context)
+    }
+
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
+        Some(self)
+    }
+
+    /// Accrues any fixings into the realized leg, in the same way as for a
+    /// variance swap.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        let schedule = match self.schedule.fix(self.underlying.id(), fixing_table)? {
+            Some(schedule) => schedule,
+            None => return Ok(None)
+        };
+
+        if schedule.is_fixed() {
+            let empty: [f64; 0] = [];
+            let realized = schedule.realized_variance(empty.iter(),
+                self.observations_per_year);
+            return Ok(Some(payment(self, self.payoff(realized),
+                self.expiry, self.pay_date)))
+        }
+
+        let swap = VolatilitySwap::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), schedule,
+            self.strike, self.vega_notional, self.observations_per_year,
+            self.expiry)?;
+        Ok(Some(vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))]))
+    }
+}
+
+impl MonteCarloPriceable for VolatilitySwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+        schedule_mc_dependencies(self, &self.underlying, &self.schedule,
+            self.expiry, self.pay_date, output)
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        assert_eq!(shape[1], self.schedule.dates().len());
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let realized = self.schedule.realized_variance(path.iter(),
+                    self.observations_per_year);
+                *flow = self.payoff(realized);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::Pricer;
+    use risk::dependencies::DependencyCollector;
+    use risk::bumptime::BumpTime;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::datetime::TimeOfDay;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+
+    fn sample_dates() -> Vec<DateTime> {
+        // weekly observations over about a year
+        let start = Date::from_ymd(2017, 01, 03);
+        (0..53).map(|i| DateTime::new(start + 7 * i, TimeOfDay::Close)).collect()
+    }
+
+    fn sample_swap(schedule: ReturnsSchedule, strike: VarianceStrike) -> VarianceSwap {
+        let expiry = *sample_dates().last().unwrap();
+        let equity = sample_underlying();
+        VarianceSwap::new("SampleVarSwap", "OPT", equity, sample_settlement(2),
+            schedule, strike, 100.0, 50.4, expiry).unwrap()
+    }
+
+    fn sample_vol_swap(schedule: ReturnsSchedule, strike: VarianceStrike) -> VolatilitySwap {
+        let expiry = *sample_dates().last().unwrap();
+        let equity = sample_underlying();
+        VolatilitySwap::new("SampleVolSwap", "OPT", equity, sample_settlement(2),
+            schedule, strike, 100.0, 50.4, expiry).unwrap()
+    }
+
+    fn mc_price(instrument: RcInstrument) -> f64 {
+        let market_data = sample_market_data();
+        let instruments = vec![(1.0, instrument)];
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+
+        // seed the paths, so comparisons of nearby prices are not at the
+        // mercy of the noise
+        let pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn strike_units() {
+        assert_approx(VarianceStrike::Vol(0.2).variance(), 0.04, 1e-15);
+        assert_approx(VarianceStrike::Variance(0.09).vol(), 0.3, 1e-15);
+    }
+
+    #[test]
+    fn schedule_accrues_fixings() {
+        let dates = sample_dates();
+        let schedule = ReturnsSchedule::new(&dates).unwrap();
+        assert_eq!(schedule.total_returns(), 52);
+
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 01, 20),
+            &[("BP.L", &[(dates[0], 100.0), (dates[1], 110.0), (dates[2], 99.0)])]).unwrap();
+        let fixed = schedule.fix("BP.L", &fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.dates().len(), 50);
+        assert_eq!(fixed.total_returns(), 52);
+        assert_eq!(fixed.last_fixing(), Some(99.0));
+
+        let r1 = (110.0_f64 / 100.0).ln();
+        let r2 = (99.0_f64 / 110.0).ln();
+        let accrued = fixed.accrued_variance(52.0).unwrap();
+        assert_approx(accrued, 52.0 * (r1 * r1 + r2 * r2) / 2.0, 1e-12);
+
+        // if the rest of the path is flat, the realized variance is diluted
+        let flat = vec![99.0; 50];
+        let realized = fixed.realized_variance(flat.iter(), 52.0);
+        assert_approx(realized, accrued * 2.0 / 52.0, 1e-12);
+
+        // fixing the whole schedule from scratch gives the same answer
+        let path : Vec<f64> = [100.0, 110.0].iter().chain(flat.iter()).cloned().collect();
+        assert_approx(schedule.realized_variance(path.iter(), 52.0), realized, 1e-12);
+    }
+
+    #[test]
+    fn fully_fixed_swap_pays_realized_less_strike() {
+        let dates = sample_dates();
+        let schedule = ReturnsSchedule::new_accrued(&dates[51..], Some(100.0), 0.05, 50).unwrap();
+        let swap = sample_swap(schedule, VarianceStrike::Vol(0.3));
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 01, 10),
+            &[("BP.L", &[(dates[51], 105.0), (dates[52], 100.0)])]).unwrap();
+        let fixed = swap.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+
+        let r1 = (105.0_f64 / 100.0).ln();
+        let r2 = (100.0_f64 / 105.0).ln();
+        let realized = 50.4 * (0.05 + r1 * r1 + r2 * r2) / 52.0;
+        assert_approx(fixed[0].0, 100.0 * (realized - 0.09), 1e-12);
+    }
+
+    #[test]
+    fn swap_at_flat_vol_strike_is_near_zero() {
+
+        // The sample market has a flat 30% vol, measured in business days.
+        // Weekly observations are annualised at 252 / 5 per year to match.
+        // Dividends add a little realized variance.
+        let schedule = ReturnsSchedule::new(&sample_dates()).unwrap();
+        let swap = sample_swap(schedule, VarianceStrike::Vol(0.3));
+        let price = mc_price(RcInstrument::new(Qrc::new(Arc::new(swap))));
+        assert_approx(price, 0.0, 0.1);
+    }
+
+    #[test]
+    fn time_bump_rolls_realized_variance() {
+
+        // rolling the spot date past the first three observations fixes
+        // them, leaving only the remaining dates as dependencies
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let schedule = ReturnsSchedule::new(&sample_dates()).unwrap();
+        let swap = sample_swap(schedule, VarianceStrike::Vol(0.3));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(swap)));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&instrument);
+        assert_eq!(dependencies.fixings("BP.L").len(), 53);
+
+        let mut instruments = vec![(1.0, instrument)];
+        let time_bump = BumpTime::new(Date::from_ymd(2017, 01, 20), spot_date,
+            SpotDynamics::StickyForward);
+        let changed = time_bump.update_instruments(&mut instruments,
+            &market_data, &dependencies).unwrap();
+        assert!(changed);
+        assert_eq!(instruments.len(), 1);
+        assert_eq!(instruments[0].1.type_id(), "VarianceSwap");
+
+        let mut rolled = DependencyCollector::new(Date::from_ymd(2017, 01, 20));
+        rolled.spot(&instruments[0].1);
+        assert_eq!(rolled.fixings("BP.L").len(), 50);
+    }
+
+    #[test]
+    fn fully_fixed_vol_swap_pays_realized_vol_less_strike() {
+        let dates = sample_dates();
+        let schedule = ReturnsSchedule::new_accrued(&dates[51..], Some(100.0), 0.05, 50).unwrap();
+        let swap = sample_vol_swap(schedule, VarianceStrike::Variance(0.09));
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 01, 10),
+            &[("BP.L", &[(dates[51], 105.0), (dates[52], 100.0)])]).unwrap();
+        let fixed = swap.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].1.type_id(), "ZeroCoupon");
+
+        let r1 = (105.0_f64 / 100.0).ln();
+        let r2 = (100.0_f64 / 105.0).ln();
+        let realized = 50.4 * (0.05 + r1 * r1 + r2 * r2) / 52.0;
+        assert_approx(fixed[0].0, 100.0 * (realized.sqrt() - 0.3), 1e-12);
+    }
+
+    #[test]
+    fn vol_swap_is_cheaper_than_variance_swap() {
+
+        // A variance swap with variance notional N / 2K has the same
+        // sensitivity to small moves in vol as a vol swap with vega notional
+        // N. The vol swap is worth less, because of the concavity of the
+        // square root.
+        let strike = VarianceStrike::Vol(0.3);
+        let vol_swap = sample_vol_swap(ReturnsSchedule::new(&sample_dates()).unwrap(), strike);
+        let var_swap = sample_swap(ReturnsSchedule::new(&sample_dates()).unwrap(), strike);
+        let vol_price = mc_price(RcInstrument::new(Qrc::new(Arc::new(vol_swap))));
+        let var_price = mc_price(RcInstrument::new(Qrc::new(Arc::new(var_swap))));
+        let var_equivalent = var_price / (2.0 * strike.vol());
+        assert!(vol_price < 0.0, "vol swap={}", vol_price);
+        assert!(vol_price < var_equivalent, "vol swap={} var swap equivalent={}",
+            vol_price, var_equivalent);
+    }
+
+    #[test]
+    fn seasoned_vol_swap_rolls_with_fixings() {
+        let dates = sample_dates();
+        let swap = sample_vol_swap(ReturnsSchedule::new(&dates).unwrap(),
+            VarianceStrike::Vol(0.3));
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2017, 01, 11),
+            &[("BP.L", &[(dates[0], 100.0), (dates[1], 104.0)])]).unwrap();
+        let fixed = swap.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 1.0, 1e-12);
+        assert_eq!(fixed[0].1.type_id(), "VolatilitySwap");
+
+        // the seasoned swap reports only its remaining dates
+        let mut dependencies = DependencyCollector::new(Date::from_ymd(2017, 01, 11));
+        dependencies.spot(&fixed[0].1);
+        assert_eq!(dependencies.fixings("BP.L").len(), 51);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
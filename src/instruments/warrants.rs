@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::options::SpotStartingEuropean;
+use instruments::options::PutOrCall;
+use instruments::options::OptionSettlement;
+use data::fixings::FixingTable;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A warrant is a call option issued by a company on its own shares. Unlike
+/// a listed call, exercise is satisfied by issuing new shares, and the
+/// strike is paid to the company, so exercise dilutes the existing
+/// shareholders.
+///
+/// With N shares outstanding and M warrants, each of which entitles the
+/// holder to g new shares at a strike of K per share, the equity after
+/// exercise is worth N S + M g K, shared between N + M g shares. The payoff
+/// per warrant is then N g / (N + M g) times (S - K).max(0), where S is the
+/// share price without dilution. The warrant is therefore valued as that
+/// fraction of a European call.
+///
+/// Strictly, S is the price the shares would have if the warrants had not
+/// been issued, and the vol is that of the equity of the company. As is
+/// conventional, we use the quoted share price and vol, which already
+/// reflect the market's view of the dilution.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Warrant {
+    option: SpotStartingEuropean,
+    shares_outstanding: f64,
+    warrants_outstanding: f64,
+    shares_per_warrant: f64,
+
+    // fields precomputed for performance and simplicity
+    dilution: f64
+}
+
+impl TypeId for Warrant {
+    fn type_id(&self) -> &'static str { "Warrant" }
+}
+
+impl Warrant {
+    /// Creates a warrant on the given equity. The strike is per share
+    /// received, and the numbers of shares and warrants outstanding are
+    /// those in issue at the time of pricing.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        cash_or_physical: OptionSettlement,
+        shares_outstanding: f64,
+        warrants_outstanding: f64,
+        shares_per_warrant: f64)
+        -> Result<Warrant, qm::Error> {
+
+        if !(shares_outstanding > 0.0) {
+            return Err(qm::Error::new("Warrant shares outstanding must be positive"))
+        }
+        if warrants_outstanding < 0.0 {
+            return Err(qm::Error::new("Warrants outstanding must not be negative"))
+        }
+        if !(shares_per_warrant > 0.0) {
+            return Err(qm::Error::new("Warrant shares per warrant must be positive"))
+        }
+
+        let option = SpotStartingEuropean::new(id, credit_id, underlying,
+            settlement, expiry, strike, PutOrCall::Call, cash_or_physical)?;
+        let new_shares = warrants_outstanding * shares_per_warrant;
+        let dilution = shares_outstanding * shares_per_warrant
+            / (shares_outstanding + new_shares);
+
+        Ok(Warrant {
+            option: option,
+            shares_outstanding: shares_outstanding,
+            warrants_outstanding: warrants_outstanding,
+            shares_per_warrant: shares_per_warrant,
+            dilution: dilution })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Warrant::deserialize(de)?)))
+    }
+
+    pub fn shares_outstanding(&self) -> f64 { self.shares_outstanding }
+    pub fn warrants_outstanding(&self) -> f64 { self.warrants_outstanding }
+    pub fn shares_per_warrant(&self) -> f64 { self.shares_per_warrant }
+
+    /// The number of undiluted calls each warrant is worth
+    pub fn dilution(&self) -> f64 { self.dilution }
+}
+
+impl InstanceId for Warrant {
+    fn id(&self) -> &str { self.option.id() }
+}
+
+impl Instrument for Warrant {
+    fn payoff_currency(&self) -> &Currency { self.option.payoff_currency() }
+    fn credit_id(&self) -> &str { self.option.credit_id() }
+    fn settlement(&self) -> &RcDateRule { self.option.settlement() }
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement { self.option.dependencies(context) }
+    fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    /// At expiry, the warrant fixes into the same flows as the call, scaled
+    /// by the dilution. For physical settlement, the holder actually pays
+    /// the full strike for new shares, but the scaled flows are worth the
+    /// same.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        Ok(self.option.fix(fixing_table)?.map(|decomp| decomp.into_iter()
+            .map(|(weight, component)| (weight * self.dilution, component))
+            .collect()))
+    }
+}
+
+impl Priceable for Warrant {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        self.option.prices(context, dates, out)?;
+        for output in out.iter_mut() {
+            *output *= self.dilution;
+        }
+        Ok(())
+    }
+}
+
+impl MonteCarloPriceable for Warrant {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+        self.option.mc_dependencies(dates, output)
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        self.option.start_date()
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+        Ok(self.dilution * self.option.mc_price(context)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use dates::Date;
+    use serde_json;
+
+    fn sample_warrant(warrants: f64, shares_per_warrant: f64) -> Warrant {
+        Warrant::new("SampleWarrant", "OPT", sample_underlying(),
+            sample_settlement(2), sample_expiry(), 100.0, OptionSettlement::Cash,
+            1000000.0, warrants, shares_per_warrant).unwrap()
+    }
+
+    fn sample_call() -> SpotStartingEuropean {
+        SpotStartingEuropean::new("SampleCall", "OPT", sample_underlying(),
+            sample_settlement(2), sample_expiry(), 100.0, PutOrCall::Call,
+            OptionSettlement::Cash).unwrap()
+    }
+
+    #[test]
+    fn warrant_is_diluted_call() {
+        let market_data = sample_market_data();
+        let call = sample_call().price(&market_data, sample_val_date()).unwrap();
+
+        // a quarter of a million warrants on a million shares dilutes by 20%
+        let warrant = sample_warrant(250000.0, 1.0);
+        assert_approx(warrant.dilution(), 0.8);
+        assert_approx(warrant.price(&market_data, sample_val_date()).unwrap(),
+            0.8 * call);
+
+        // with no warrants outstanding there is no dilution
+        let undiluted = sample_warrant(0.0, 1.0);
+        assert_approx(undiluted.price(&market_data, sample_val_date()).unwrap(), call);
+
+        // a warrant for two shares is worth just under two calls
+        let double = sample_warrant(250000.0, 2.0);
+        assert_approx(double.dilution(), 2.0 / 1.5);
+    }
+
+    #[test]
+    fn warrant_fixes_into_diluted_payment() {
+        let warrant = sample_warrant(250000.0, 1.0);
+        let fixing_table = FixingTable::from_fixings(Date::from_ymd(2018, 06, 05),
+            &[("BP.L", &[(sample_expiry(), 130.0)])]).unwrap();
+        let decomp = warrant.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 0.8 * 30.0);
+        assert_eq!(decomp[0].1.type_id(), "ZeroCoupon");
+    }
+
+    #[test]
+    fn warrant_rejects_bad_share_counts() {
+        let make = |shares: f64, warrants: f64, per_warrant: f64| Warrant::new(
+            "SampleWarrant", "OPT", sample_underlying(), sample_settlement(2),
+            sample_expiry(), 100.0, OptionSettlement::Cash, shares, warrants,
+            per_warrant);
+        assert!(make(0.0, 1.0, 1.0).is_err());
+        assert!(make(1.0, -1.0, 1.0).is_err());
+        assert!(make(1.0, 1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn serde_warrant_roundtrip() {
+        let warrant = RcInstrument::new(Qrc::new(Arc::new(
+            sample_warrant(250000.0, 1.0))));
+        let serialized = serde_json::to_string_pretty(&warrant).unwrap();
+        let deserialized: RcInstrument = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.type_id(), "Warrant");
+        assert_eq!(deserialized.id(), "SampleWarrant");
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-12),
+            "value={} expected={}", value, expected);
+    }
+}
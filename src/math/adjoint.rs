@@ -0,0 +1,281 @@
+use std::cell::RefCell;
+use std::ops::Add;
+use std::ops::Sub;
+use std::ops::Mul;
+use std::ops::Div;
+use std::ops::Neg;
+
+/// A tape for reverse-mode (adjoint) algorithmic differentiation. Every
+/// arithmetic operation on the variables of a tape records the partial
+/// derivatives of its result with respect to its operands, so that one
+/// backward sweep gives the derivatives of a result with respect to every
+/// input, whatever the number of inputs.
+///
+/// The tape may be rewound to an earlier length, so a calculation that is
+/// repeated many times, such as the valuation of each Monte-Carlo path,
+/// can share inputs recorded once at the start.
+pub struct Tape {
+    nodes: RefCell<Vec<Node>>
+}
+
+/// Each node has at most two operands, identified by their positions on
+/// the tape. Inputs and constants are their own operands, with zero partials.
+#[derive(Clone, Copy)]
+struct Node {
+    operands: [usize; 2],
+    partials: [f64; 2]
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape { nodes: RefCell::new(Vec::new()) }
+    }
+
+    /// Records an input, with respect to which results may be differentiated
+    pub fn input(&self, value: f64) -> Var {
+        let index = self.len();
+        self.push(value, [index, index], [0.0, 0.0])
+    }
+
+    /// Records a constant. This is the same as an input, but states intent.
+    pub fn constant(&self, value: f64) -> Var {
+        self.input(value)
+    }
+
+    /// The number of nodes recorded so far
+    pub fn len(&self) -> usize {
+        self.nodes.borrow().len()
+    }
+
+    /// Discards all the nodes recorded after the given length. Variables
+    /// recorded after that point must not be used again.
+    pub fn rewind(&self, len: usize) {
+        self.nodes.borrow_mut().truncate(len);
+    }
+
+    /// The derivatives of the output with respect to each of the inputs,
+    /// by a backward sweep over the tape from the output.
+    pub fn gradient(&self, output: Var, inputs: &[Var]) -> Vec<f64> {
+        let nodes = self.nodes.borrow();
+
+        // nothing before the first input can contribute to the gradient
+        let offset = inputs.iter().map(|v| v.index).min().unwrap_or(output.index)
+            .min(output.index);
+        let mut adjoints = vec![0.0; output.index + 1 - offset];
+        adjoints[output.index - offset] = 1.0;
+        for index in (offset..output.index + 1).rev() {
+            let adjoint = adjoints[index - offset];
+            if adjoint == 0.0 {
+                continue;
+            }
+            let node = &nodes[index];
+            for (&operand, &partial) in node.operands.iter().zip(node.partials.iter()) {
+                if partial != 0.0 && operand >= offset {
+                    adjoints[operand - offset] += adjoint * partial;
+                }
+            }
+        }
+        inputs.iter().map(|v| if v.index >= offset && v.index <= output.index {
+            adjoints[v.index - offset] } else { 0.0 }).collect()
+    }
+
+    fn push(&self, value: f64, operands: [usize; 2], partials: [f64; 2]) -> Var {
+        let mut nodes = self.nodes.borrow_mut();
+        let index = nodes.len();
+        nodes.push(Node { operands: operands, partials: partials });
+        Var { tape: self, index: index, value: value }
+    }
+}
+
+/// A variable recorded on a tape, which behaves like an f64 in arithmetic
+#[derive(Clone, Copy)]
+pub struct Var<'t> {
+    tape: &'t Tape,
+    index: usize,
+    value: f64
+}
+
+impl<'t> Var<'t> {
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// The tape this variable is recorded on, for recording constants
+    pub fn tape(&self) -> &'t Tape {
+        self.tape
+    }
+
+    pub fn exp(self) -> Var<'t> {
+        let value = self.value.exp();
+        self.unary(value, value)
+    }
+
+    pub fn ln(self) -> Var<'t> {
+        self.unary(self.value.ln(), 1.0 / self.value)
+    }
+
+    pub fn sqrt(self) -> Var<'t> {
+        let value = self.value.sqrt();
+        self.unary(value, 0.5 / value)
+    }
+
+    /// The larger of this and a constant. At a tie the derivative is taken
+    /// from the constant, which is the usual choice for option payoffs.
+    pub fn max(self, other: f64) -> Var<'t> {
+        if self.value > other {
+            self
+        } else {
+            self.tape.constant(other)
+        }
+    }
+
+    /// The smaller of this and a constant, taking the derivative from the
+    /// constant at a tie
+    pub fn min(self, other: f64) -> Var<'t> {
+        if self.value < other {
+            self
+        } else {
+            self.tape.constant(other)
+        }
+    }
+
+    fn unary(self, value: f64, partial: f64) -> Var<'t> {
+        self.tape.push(value, [self.index, self.index], [partial, 0.0])
+    }
+
+    fn binary(self, other: Var<'t>, value: f64, partial: f64, other_partial: f64)
+        -> Var<'t> {
+        self.tape.push(value, [self.index, other.index], [partial, other_partial])
+    }
+}
+
+impl<'t> Add for Var<'t> {
+    type Output = Var<'t>;
+    fn add(self, other: Var<'t>) -> Var<'t> {
+        self.binary(other, self.value + other.value, 1.0, 1.0)
+    }
+}
+
+impl<'t> Sub for Var<'t> {
+    type Output = Var<'t>;
+    fn sub(self, other: Var<'t>) -> Var<'t> {
+        self.binary(other, self.value - other.value, 1.0, -1.0)
+    }
+}
+
+impl<'t> Mul for Var<'t> {
+    type Output = Var<'t>;
+    fn mul(self, other: Var<'t>) -> Var<'t> {
+        self.binary(other, self.value * other.value, other.value, self.value)
+    }
+}
+
+impl<'t> Div for Var<'t> {
+    type Output = Var<'t>;
+    fn div(self, other: Var<'t>) -> Var<'t> {
+        let value = self.value / other.value;
+        self.binary(other, value, 1.0 / other.value, -value / other.value)
+    }
+}
+
+impl<'t> Neg for Var<'t> {
+    type Output = Var<'t>;
+    fn neg(self) -> Var<'t> {
+        self.unary(-self.value, -1.0)
+    }
+}
+
+impl<'t> Add<f64> for Var<'t> {
+    type Output = Var<'t>;
+    fn add(self, other: f64) -> Var<'t> {
+        self.unary(self.value + other, 1.0)
+    }
+}
+
+impl<'t> Sub<f64> for Var<'t> {
+    type Output = Var<'t>;
+    fn sub(self, other: f64) -> Var<'t> {
+        self.unary(self.value - other, 1.0)
+    }
+}
+
+impl<'t> Mul<f64> for Var<'t> {
+    type Output = Var<'t>;
+    fn mul(self, other: f64) -> Var<'t> {
+        self.unary(self.value * other, other)
+    }
+}
+
+impl<'t> Div<f64> for Var<'t> {
+    type Output = Var<'t>;
+    fn div(self, other: f64) -> Var<'t> {
+        self.unary(self.value / other, 1.0 / other)
+    }
+}
+
+impl<'t> Add<Var<'t>> for f64 {
+    type Output = Var<'t>;
+    fn add(self, other: Var<'t>) -> Var<'t> {
+        other + self
+    }
+}
+
+impl<'t> Sub<Var<'t>> for f64 {
+    type Output = Var<'t>;
+    fn sub(self, other: Var<'t>) -> Var<'t> {
+        other.unary(self - other.value, -1.0)
+    }
+}
+
+impl<'t> Mul<Var<'t>> for f64 {
+    type Output = Var<'t>;
+    fn mul(self, other: Var<'t>) -> Var<'t> {
+        other * self
+    }
+}
+
+impl<'t> Div<Var<'t>> for f64 {
+    type Output = Var<'t>;
+    fn div(self, other: Var<'t>) -> Var<'t> {
+        let value = self / other.value;
+        other.unary(value, -value / other.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn gradient_matches_hand_derivatives() {
+        let tape = Tape::new();
+        let x = tape.input(1.5);
+        let y = tape.input(0.3);
+
+        // f = x y exp(y) / sqrt(x) + ln(x) - 2 / y
+        let f = x * y * y.exp() / x.sqrt() + x.ln() - 2.0 / y;
+        let gradient = tape.gradient(f, &[x, y]);
+
+        let (xv, yv) = (1.5_f64, 0.3_f64);
+        let dfdx = 0.5 * yv * yv.exp() / xv.sqrt() + 1.0 / xv;
+        let dfdy = xv.sqrt() * (1.0 + yv) * yv.exp() + 2.0 / (yv * yv);
+        assert!(approx_eq(gradient[0], dfdx, 1e-14), "{} {}", gradient[0], dfdx);
+        assert!(approx_eq(gradient[1], dfdy, 1e-13), "{} {}", gradient[1], dfdy);
+    }
+
+    #[test]
+    fn rewound_tape_reuses_inputs() {
+        let tape = Tape::new();
+        let x = tape.input(2.0);
+        let start = tape.len();
+        let mut total = 0.0;
+        for i in 0..3 {
+            tape.rewind(start);
+            let payoff = (x * i as f64 - 1.0).max(0.0);
+            total += tape.gradient(payoff, &[x])[0];
+        }
+        assert_eq!(tape.len(), start + 2);
+        assert_eq!(total, 3.0);
+    }
+}
@@ -0,0 +1,121 @@
+/// Builds Brownian increments from independent gaussians by the Brownian
+/// bridge construction, on a grid of steps of unit variance. The first
+/// gaussian fixes the end of the path, the second its middle, and so on,
+/// halving the intervals. The result is again a set of independent unit
+/// gaussians, one per step, so it can replace the gaussians of any model.
+///
+/// With pseudo-random numbers this changes nothing, but with a
+/// low-discrepancy sequence it puts the best dimensions, which come first,
+/// onto the large-scale shape of the path, which matters most to most
+/// payoffs. The effective dimension of the simulation shrinks, and the
+/// convergence improves.
+pub struct BrownianBridge {
+    // for each gaussian after the first, the step it fixes and the steps
+    // either side that are already fixed, as indices into the path
+    // counting from one, with zero for the start of the path
+    index: Vec<usize>,
+    left: Vec<usize>,
+    right: Vec<usize>,
+    left_weight: Vec<f64>,
+    right_weight: Vec<f64>,
+    sd: Vec<f64>
+}
+
+impl BrownianBridge {
+    /// Creates a bridge over the given number of steps, which must be at
+    /// least one.
+    pub fn new(steps: usize) -> BrownianBridge {
+        assert!(steps > 0);
+        let mut bridge = BrownianBridge { index: vec![steps], left: vec![0],
+            right: vec![0], left_weight: vec![0.0], right_weight: vec![0.0],
+            sd: vec![(steps as f64).sqrt()] };
+
+        // work through the intervals breadth first, so that the earliest
+        // gaussians fix the biggest features of the path
+        let mut intervals = vec![(0, steps)];
+        let mut next = 0;
+        while next < intervals.len() {
+            let (l, r) = intervals[next];
+            next += 1;
+            if r - l < 2 {
+                continue;
+            }
+            let m = l + (r - l) / 2;
+            let span = (r - l) as f64;
+            bridge.index.push(m);
+            bridge.left.push(l);
+            bridge.right.push(r);
+            bridge.left_weight.push((r - m) as f64 / span);
+            bridge.right_weight.push((m - l) as f64 / span);
+            bridge.sd.push(((m - l) as f64 * (r - m) as f64 / span).sqrt());
+            intervals.push((l, m));
+            intervals.push((m, r));
+        }
+        assert_eq!(bridge.index.len(), steps);
+        bridge
+    }
+
+    /// The number of steps, which is also the number of gaussians
+    pub fn steps(&self) -> usize { self.index.len() }
+
+    /// Turns the given independent gaussians, in order of importance, into
+    /// independent unit increments of the path, in order of time.
+    pub fn increments(&self, gaussians: &[f64], out: &mut [f64]) {
+        let steps = self.steps();
+        assert_eq!(gaussians.len(), steps);
+        assert_eq!(out.len(), steps);
+
+        // the path, with its start at zero
+        let mut path = vec![0.0; steps + 1];
+        path[steps] = self.sd[0] * gaussians[0];
+        for i in 1..steps {
+            path[self.index[i]] = self.left_weight[i] * path[self.left[i]]
+                + self.right_weight[i] * path[self.right[i]]
+                + self.sd[i] * gaussians[i];
+        }
+        for (i, increment) in out.iter_mut().enumerate() {
+            *increment = path[i + 1] - path[i];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bridge_is_orthogonal() {
+        // the bridge is linear, and the increments are independent unit
+        // gaussians if and only if its matrix is orthogonal
+        for steps in [1, 2, 7, 8, 13].iter() {
+            let bridge = BrownianBridge::new(*steps);
+            let mut columns = Vec::new();
+            for j in 0..*steps {
+                let mut unit = vec![0.0; *steps];
+                unit[j] = 1.0;
+                let mut column = vec![0.0; *steps];
+                bridge.increments(&unit, &mut column);
+                columns.push(column);
+            }
+            for a in 0..*steps {
+                for b in 0..*steps {
+                    let dot: f64 = columns[a].iter().zip(columns[b].iter())
+                        .map(|(x, y)| x * y).sum();
+                    let expected = if a == b { 1.0 } else { 0.0 };
+                    assert!((dot - expected).abs() < 1e-12,
+                        "steps={} a={} b={} dot={}", steps, a, b, dot);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn first_gaussian_fixes_the_end() {
+        let bridge = BrownianBridge::new(4);
+        let mut increments = [0.0; 4];
+        bridge.increments(&[1.0, 0.0, 0.0, 0.0], &mut increments);
+        for increment in increments.iter() {
+            assert!((increment - 0.5).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::ops::Add;
+use std::ops::Sub;
+use std::ops::Mul;
+use std::ops::Div;
+use std::ops::Neg;
+
+/// A complex number, with just enough arithmetic for evaluating
+/// characteristic functions. Logarithms and square roots take the principal
+/// branch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re: re, im: im }
+    }
+
+    /// The imaginary unit
+    pub fn i() -> Complex {
+        Complex::new(0.0, 1.0)
+    }
+
+    pub fn from_real(re: f64) -> Complex {
+        Complex::new(re, 0.0)
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn arg(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn exp(&self) -> Complex {
+        let scale = self.re.exp();
+        Complex::new(scale * self.im.cos(), scale * self.im.sin())
+    }
+
+    pub fn ln(&self) -> Complex {
+        Complex::new(self.norm().ln(), self.arg())
+    }
+
+    pub fn sqrt(&self) -> Complex {
+        let root = self.norm().sqrt();
+        let half_arg = 0.5 * self.arg();
+        Complex::new(root * half_arg.cos(), root * half_arg.sin())
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Add<f64> for Complex {
+    type Output = Complex;
+    fn add(self, other: f64) -> Complex {
+        Complex::new(self.re + other, self.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Sub<f64> for Complex {
+    type Output = Complex;
+    fn sub(self, other: f64) -> Complex {
+        Complex::new(self.re - other, self.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re)
+    }
+}
+
+impl Mul<f64> for Complex {
+    type Output = Complex;
+    fn mul(self, other: f64) -> Complex {
+        Complex::new(self.re * other, self.im * other)
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, other: Complex) -> Complex {
+        let denominator = other.re * other.re + other.im * other.im;
+        Complex::new((self.re * other.re + self.im * other.im) / denominator,
+            (self.im * other.re - self.re * other.im) / denominator)
+    }
+}
+
+impl Div<f64> for Complex {
+    type Output = Complex;
+    fn div(self, other: f64) -> Complex {
+        Complex::new(self.re / other, self.im / other)
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use std::f64::consts::PI;
+
+    fn assert_complex(value: Complex, expected: Complex) {
+        assert!(approx_eq(value.re, expected.re, 1e-12)
+            && approx_eq(value.im, expected.im, 1e-12),
+            "value={:?} expected={:?}", value, expected);
+    }
+
+    #[test]
+    fn complex_arithmetic() {
+        let a = Complex::new(1.0, 2.0);
+        let b = Complex::new(3.0, -1.0);
+        assert_complex(a * b, Complex::new(5.0, 5.0));
+        assert_complex((a * b) / b, a);
+        assert_complex(a - b + b, a);
+        assert_complex(Complex::i() * Complex::i(), Complex::from_real(-1.0));
+        assert_complex(-a * 2.0 + 1.0, Complex::new(-1.0, -4.0));
+    }
+
+    #[test]
+    fn complex_functions() {
+        assert_complex((Complex::i() * PI).exp(), Complex::from_real(-1.0));
+        let a = Complex::new(-0.5, 1.5);
+        assert_complex(a.ln().exp(), a);
+        assert_complex(a.sqrt() * a.sqrt(), a);
+        assert_complex(Complex::from_real(-4.0).sqrt(), Complex::new(0.0, 2.0));
+    }
+}
@@ -0,0 +1,190 @@
+use nalgebra::base::DMatrix;
+use nalgebra::linalg::Cholesky;
+use core::qm;
+
+/// Largest number of alternating projections when repairing a correlation
+/// matrix. Convergence is linear, but normally takes a few tens of steps.
+const MAX_PROJECTIONS: usize = 200;
+
+/// The repair stops when successive projections move the matrix by less
+/// than this, in the Frobenius norm
+const PROJECTION_TOLERANCE: f64 = 1e-10;
+
+/// Smallest eigenvalue of a repaired correlation matrix. The nearest
+/// positive semi-definite matrix is singular, so its smallest eigenvalues
+/// are lifted to this, which lets the Cholesky decomposition succeed.
+const MIN_EIGENVALUE: f64 = 1e-8;
+
+/// Returns the lower-triangular Cholesky root of a correlation matrix. If
+/// the matrix is not positive definite, as happens when correlations are
+/// estimated pair by pair or bumped independently, it is first replaced by
+/// the nearest valid correlation matrix.
+pub fn correlation_root(correlation: DMatrix<f64>)
+    -> Result<DMatrix<f64>, qm::Error> {
+
+    if let Some(root) = Cholesky::new(correlation.clone()) {
+        return Ok(root.unpack())
+    }
+
+    let repaired = nearest_correlation(&correlation)?;
+    let root = Cholesky::new(repaired).ok_or_else(|| qm::Error::new(
+        "Repaired correlation matrix is not positive definite"))?;
+    Ok(root.unpack())
+}
+
+/// Finds the nearest correlation matrix to the given symmetric matrix, in
+/// the Frobenius norm, by the alternating projections method of Higham
+/// (2002) with Dykstra's correction. The projection onto positive
+/// semi-definite matrices clips the negative eigenvalues to zero, and the
+/// projection onto matrices with unit diagonal resets the diagonal.
+///
+/// The result is then made strictly positive definite, by lifting its
+/// smallest eigenvalues to a small positive floor and rescaling back to a
+/// unit diagonal. Matrices that are already valid correlation matrices are
+/// returned essentially unchanged.
+pub fn nearest_correlation(matrix: &DMatrix<f64>)
+    -> Result<DMatrix<f64>, qm::Error> {
+
+    let n = matrix.nrows();
+    if matrix.ncols() != n {
+        return Err(qm::Error::new("Correlation matrix must be square"))
+    }
+    for i in 0..n {
+        for j in 0..i {
+            let c = matrix[(i, j)];
+            if !c.is_finite() || c != matrix[(j, i)] {
+                return Err(qm::Error::new(
+                    "Correlation matrix must be finite and symmetric"))
+            }
+        }
+    }
+
+    let mut y = matrix.clone();
+    let mut correction = DMatrix::<f64>::zeros(n, n);
+    for _ in 0..MAX_PROJECTIONS {
+        let r = &y - &correction;
+        let x = clip_eigenvalues(&r, 0.0)?;
+        correction = &x - &r;
+        let mut next = x.clone();
+        set_unit_diagonal(&mut next);
+        let change = (&next - &y).norm();
+        y = next;
+        if change < PROJECTION_TOLERANCE {
+            break
+        }
+    }
+
+    // lift to strictly positive definite, then rescale to unit diagonal
+    let lifted = clip_eigenvalues(&y, MIN_EIGENVALUE)?;
+    let scales: Vec<f64> = (0..n).map(|i| 1.0 / lifted[(i, i)].sqrt()).collect();
+    let mut result = DMatrix::from_fn(n, n, |i, j|
+        lifted[(i, j)] * scales[i] * scales[j]);
+
+    // the arithmetic can leave tiny asymmetries, which Cholesky ignores but
+    // which would confuse anyone reading the matrix
+    for i in 0..n {
+        result[(i, i)] = 1.0;
+        for j in 0..i {
+            let c = 0.5 * (result[(i, j)] + result[(j, i)]);
+            result[(i, j)] = c;
+            result[(j, i)] = c;
+        }
+    }
+    Ok(result)
+}
+
+/// Projects a symmetric matrix onto those whose eigenvalues are at least
+/// the given floor
+fn clip_eigenvalues(matrix: &DMatrix<f64>, floor: f64)
+    -> Result<DMatrix<f64>, qm::Error> {
+
+    let eigen = matrix.clone().try_symmetric_eigen(1e-15, 10000)
+        .ok_or_else(|| qm::Error::new(
+            "Eigen decomposition of correlation matrix did not converge"))?;
+    let mut values = eigen.eigenvalues;
+    for value in values.iter_mut() {
+        *value = value.max(floor);
+    }
+    let vectors = eigen.eigenvectors;
+    Ok(&vectors * DMatrix::from_diagonal(&values) * vectors.transpose())
+}
+
+fn set_unit_diagonal(matrix: &mut DMatrix<f64>) {
+    for i in 0..matrix.nrows() {
+        matrix[(i, i)] = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    fn inconsistent() -> DMatrix<f64> {
+        // the first two are both strongly correlated with the third, but
+        // strongly anti-correlated with each other, which is impossible
+        DMatrix::from_row_slice(3, 3, &[
+            1.0, -0.9, 0.9,
+            -0.9, 1.0, 0.9,
+            0.9, 0.9, 1.0])
+    }
+
+    #[test]
+    fn valid_correlation_is_unchanged() {
+        let matrix = DMatrix::from_row_slice(3, 3, &[
+            1.0, 0.5, 0.2,
+            0.5, 1.0, -0.3,
+            0.2, -0.3, 1.0]);
+        let nearest = nearest_correlation(&matrix).unwrap();
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!(approx_eq(nearest[(i, j)], matrix[(i, j)], 1e-8),
+                    "i={} j={} nearest={}", i, j, nearest[(i, j)]);
+            }
+        }
+    }
+
+    #[test]
+    fn inconsistent_correlation_is_repaired() {
+        let matrix = inconsistent();
+        assert!(Cholesky::new(matrix.clone()).is_none());
+
+        let nearest = nearest_correlation(&matrix).unwrap();
+        let eigenvalues = nearest.clone().symmetric_eigenvalues();
+        for value in eigenvalues.iter() {
+            assert!(*value > 0.0, "eigenvalue={}", value);
+        }
+        for i in 0..3 {
+            assert_eq!(nearest[(i, i)], 1.0);
+            for j in 0..i {
+                assert_eq!(nearest[(i, j)], nearest[(j, i)]);
+            }
+        }
+
+        // All three correlations move by the same amount towards
+        // consistency, to plus or minus one half, which is on the edge of
+        // the valid correlation matrices.
+        assert!(approx_eq(nearest[(0, 1)], -0.5, 1e-6), "nearest={}", nearest);
+        assert!(approx_eq(nearest[(0, 2)], 0.5, 1e-6), "nearest={}", nearest);
+        assert!(approx_eq(nearest[(1, 2)], 0.5, 1e-6), "nearest={}", nearest);
+    }
+
+    #[test]
+    fn root_reproduces_correlation() {
+        let root = correlation_root(inconsistent()).unwrap();
+        let product = &root * root.transpose();
+        let nearest = nearest_correlation(&inconsistent()).unwrap();
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!(approx_eq(product[(i, j)], nearest[(i, j)], 1e-10),
+                    "i={} j={} product={}", i, j, product[(i, j)]);
+            }
+        }
+    }
+
+    #[test]
+    fn asymmetric_matrix_is_rejected() {
+        let matrix = DMatrix::from_row_slice(2, 2, &[1.0, 0.5, 0.4, 1.0]);
+        assert!(nearest_correlation(&matrix).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use std::f64::consts::PI;
+use math::complex::Complex;
+
+/// Replaces the values by their discrete Fourier transform, in other words
+/// x_j becomes the sum over m of x_m exp(-2 pi i j m / n), using the
+/// iterative radix-2 algorithm of Cooley and Tukey. The number of values
+/// must be a power of two.
+pub fn fft(values: &mut [Complex]) {
+    let n = values.len();
+    assert!(n.is_power_of_two());
+
+    // bit-reversal permutation, so the butterflies can work in place
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        let root = Complex::new(angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let mut twiddle = Complex::from_real(1.0);
+            for k in 0..len / 2 {
+                let even = values[start + k];
+                let odd = values[start + k + len / 2] * twiddle;
+                values[start + k] = even + odd;
+                values[start + k + len / 2] = even - odd;
+                twiddle = twiddle * root;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fft_matches_naive_transform() {
+        for &n in [1, 2, 8, 64].iter() {
+            let input: Vec<Complex> = (0..n)
+                .map(|i| Complex::new((i as f64 * 0.7).sin(), 1.0 / (1.0 + i as f64)))
+                .collect();
+            let mut output = input.clone();
+            fft(&mut output);
+
+            for j in 0..n {
+                let mut expected = Complex::from_real(0.0);
+                for (m, x) in input.iter().enumerate() {
+                    let angle = -2.0 * PI * (j * m) as f64 / n as f64;
+                    expected = expected + *x * Complex::new(angle.cos(), angle.sin());
+                }
+                assert!((output[j] - expected).norm() < 1e-12,
+                    "n={} j={} value={:?} expected={:?}", n, j, output[j], expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::f64::consts::PI;
+use math::complex::Complex;
+use math::fft::fft;
+use core::qm;
+
+/// A model whose terminal distribution is known through its characteristic
+/// function. Semi-analytic pricers only need this, so the same pricers work
+/// for any such model, such as Heston or models with jumps.
+pub trait CharacteristicFunction {
+    /// The characteristic function of the log of the underlying relative to
+    /// its forward at time t, in other words E[exp(i u ln(S_t / F_t))]. As
+    /// the underlying divided by its forward is a martingale, this must be
+    /// one when u is -i.
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex;
+}
+
+/// A model where the log of the underlying relative to its forward is a
+/// Levy process, in other words its increments are independent of the
+/// path so far, and of each other, and their distribution depends only on
+/// the length of time they span. The characteristic function of the
+/// increment over any period is then the characteristic function at the
+/// length of that period, which is what pricers need to roll back through
+/// intermediate dates. Stochastic vol models such as Heston are not Levy
+/// processes, as the distribution of later increments depends on the
+/// variance reached.
+pub trait LevyProcess : CharacteristicFunction {
+    /// Returns this model as a characteristic function
+    fn as_characteristic_function(&self) -> &CharacteristicFunction;
+}
+
+/// Upper limit of the Fourier integral. The integrand decays at least as
+/// fast as 1/u^2, and much faster for any reasonable amount of variance.
+const INTEGRATION_LIMIT: f64 = 250.0;
+
+/// Number of intervals for Simpson's rule, which must be even.
+const INTEGRATION_STEPS: usize = 5000;
+
+/// Prices a European call by the formula of Lewis (2001), which needs a
+/// single integral over the characteristic function. The result includes
+/// the given discount factor, and the time is in whatever units the model
+/// uses for its parameters.
+pub fn call_price(model: &CharacteristicFunction, df: f64, forward: f64,
+    strike: f64, t: f64) -> Result<f64, qm::Error> {
+
+    if forward <= 0.0 || strike <= 0.0 {
+        return Err(qm::Error::new("Fourier pricing needs a positive forward and strike"))
+    }
+    if t <= 0.0 {
+        return Ok(df * (forward - strike).max(0.0))
+    }
+
+    let k = (forward / strike).ln();
+    let integrand = |u: f64| -> f64 {
+        let phi = model.characteristic_function(Complex::new(u, -0.5), t);
+        let rotation = (Complex::i() * (u * k)).exp();
+        (rotation * phi).re / (u * u + 0.25)
+    };
+
+    // Simpson's rule
+    let h = INTEGRATION_LIMIT / INTEGRATION_STEPS as f64;
+    let mut sum = integrand(0.0) + integrand(INTEGRATION_LIMIT);
+    for i in 1..INTEGRATION_STEPS {
+        let weight = if i % 2 == 1 { 4.0 } else { 2.0 };
+        sum += weight * integrand(i as f64 * h);
+    }
+    let integral = sum * h / 3.0;
+
+    let undiscounted = forward - (forward * strike).sqrt() * integral / PI;
+    if !undiscounted.is_finite() {
+        return Err(qm::Error::new("Fourier pricing failed to converge"))
+    }
+
+    // clamp to the no-arbitrage bounds, which the quadrature can breach
+    // by a tiny amount far from the money
+    Ok(df * undiscounted.max((forward - strike).max(0.0)).min(forward))
+}
+
+/// Prices a European put by put-call parity from the Fourier call price
+pub fn put_price(model: &CharacteristicFunction, df: f64, forward: f64,
+    strike: f64, t: f64) -> Result<f64, qm::Error> {
+    let call = call_price(model, df, forward, strike, t)?;
+    Ok(call - df * (forward - strike))
+}
+
+/// Number of terms in the cosine expansion of the COS method
+const COS_TERMS: usize = 512;
+
+/// Width of the truncated range of the COS method, in standard deviations
+/// of the log of the underlying
+const COS_WIDTH: f64 = 12.0;
+
+/// Step for the finite differences that estimate the cumulants from the
+/// characteristic function
+const CUMULANT_STEP: f64 = 1e-3;
+
+/// The truncated range of the log of the underlying relative to its forward
+/// at time t, over which the COS method expands the density. It is centred
+/// on the mean, and extends COS_WIDTH standard deviations either side, with
+/// both estimated by finite differences of the characteristic function.
+pub fn cos_range(model: &CharacteristicFunction, t: f64)
+    -> Result<(f64, f64), qm::Error> {
+
+    // cumulants of the log of the underlying relative to its forward
+    let h = CUMULANT_STEP;
+    let up = model.characteristic_function(Complex::from_real(h), t).ln();
+    let down = model.characteristic_function(Complex::from_real(-h), t).ln();
+    let mean = (up.im - down.im) / (2.0 * h);
+    let variance = -(up.re + down.re) / (h * h);
+    if !mean.is_finite() || !(variance > 0.0) {
+        return Err(qm::Error::new("COS pricing needs a distribution with positive variance"))
+    }
+
+    Ok((mean - COS_WIDTH * variance.sqrt(), mean + COS_WIDTH * variance.sqrt()))
+}
+
+/// Prices a European put by the COS method of Fang and Oosterlee (2008),
+/// which expands the density of the log of the underlying as a cosine
+/// series over a truncated range. The range is centred on the mean, and
+/// its width set by the variance, both estimated from the characteristic
+/// function, so that only the characteristic function is needed. It is
+/// much faster than the Lewis integral for the same accuracy, and suits
+/// pricing many strikes. Puts are priced directly, as their payoff is
+/// bounded, which makes the expansion insensitive to the range.
+pub fn cos_put_price(model: &CharacteristicFunction, df: f64, forward: f64,
+    strike: f64, t: f64) -> Result<f64, qm::Error> {
+
+    if forward <= 0.0 || strike <= 0.0 {
+        return Err(qm::Error::new("Fourier pricing needs a positive forward and strike"))
+    }
+    if t <= 0.0 {
+        return Ok(df * (strike - forward).max(0.0))
+    }
+
+    let (a, b) = cos_range(model, t)?;
+    let width = b - a;
+    let d = (strike / forward).ln().max(a).min(b);
+
+    // the put payoff is strike - forward * exp(y), for y between a and d
+    let mut sum = 0.0;
+    for k in 0..COS_TERMS {
+        let w = k as f64 * PI / width;
+        let chi = ((w * (d - a)).cos() + w * (w * (d - a)).sin()) * d.exp()
+            - a.exp();
+        let chi = chi / (1.0 + w * w);
+        let psi = if k == 0 { d - a } else { (w * (d - a)).sin() / w };
+        let payoff = 2.0 / width * (strike * psi - forward * chi);
+
+        let phi = model.characteristic_function(Complex::from_real(w), t);
+        let shift = (Complex::i() * (-w * a)).exp();
+        let term = (phi * shift).re * payoff;
+        sum += if k == 0 { 0.5 * term } else { term };
+    }
+
+    if !sum.is_finite() {
+        return Err(qm::Error::new("COS pricing failed to converge"))
+    }
+
+    // clamp to the no-arbitrage bounds
+    Ok(df * sum.max((strike - forward).max(0.0)).min(strike))
+}
+
+/// Prices a European call by put-call parity from the COS put price
+pub fn cos_call_price(model: &CharacteristicFunction, df: f64, forward: f64,
+    strike: f64, t: f64) -> Result<f64, qm::Error> {
+    let put = cos_put_price(model, df, forward, strike, t)?;
+    Ok(put + df * (forward - strike))
+}
+
+/// Number of points of the Carr-Madan FFT, which must be a power of two
+const FFT_POINTS: usize = 4096;
+
+/// Spacing of the Carr-Madan FFT in the Fourier variable. The spacing in
+/// log strike is then 2 pi / (FFT_POINTS * FFT_SPACING), about 0.006.
+const FFT_SPACING: f64 = 0.25;
+
+/// Damping of the call prices in log strike, which makes them square
+/// integrable. The model must have finite moments of the underlying up to
+/// one plus this, which is true of all but the most extreme parameters.
+const FFT_DAMPING: f64 = 1.5;
+
+/// Prices a strip of European calls by the FFT method of Carr and Madan
+/// (1999). A single transform of the characteristic function gives the
+/// damped call prices on a regular grid of log strikes, centred on the
+/// forward, which are interpolated onto the requested strikes. This is far
+/// faster than pricing each strike separately when there are many of them,
+/// as when calibrating to a smile. The result includes the given discount
+/// factor, and accuracy is limited by the grid spacing for very short
+/// expiries, where the Lewis integral is better.
+pub fn fft_call_prices(model: &CharacteristicFunction, df: f64, forward: f64,
+    strikes: &[f64], t: f64) -> Result<Vec<f64>, qm::Error> {
+
+    if forward <= 0.0 || strikes.iter().any(|&k| !(k > 0.0)) {
+        return Err(qm::Error::new("Fourier pricing needs a positive forward and strike"))
+    }
+    if t <= 0.0 {
+        return Ok(strikes.iter().map(|&k| df * (forward - k).max(0.0)).collect())
+    }
+
+    // grid of log strikes relative to the forward, with the forward itself
+    // at the middle point
+    let n = FFT_POINTS;
+    let eta = FFT_SPACING;
+    let lambda = 2.0 * PI / (n as f64 * eta);
+    let lowest = -lambda * (n / 2) as f64;
+
+    // The integrand is even in its real part, so the trapezium rule with
+    // half weight at zero is the full-line rule, whose only error is
+    // aliasing of the damped prices from a whole grid width away.
+    let alpha = FFT_DAMPING;
+    let mut values = Vec::with_capacity(n);
+    for m in 0..n {
+        let u = m as f64 * eta;
+        let phi = model.characteristic_function(Complex::new(u, -(alpha + 1.0)), t);
+        let denominator = Complex::new(alpha * alpha + alpha - u * u, (2.0 * alpha + 1.0) * u);
+        let shift = (Complex::i() * (-lowest * u)).exp();
+        let weight = if m == 0 { 0.5 * eta } else { eta };
+        values.push(shift * phi / denominator * weight);
+    }
+    fft(&mut values);
+
+    // undamped call prices relative to the forward on the grid
+    let grid: Vec<f64> = values.iter().enumerate().map(|(j, value)| {
+        let k = lowest + j as f64 * lambda;
+        (-alpha * k).exp() / PI * value.re }).collect();
+    if grid.iter().any(|price| !price.is_finite()) {
+        return Err(qm::Error::new("Fourier pricing failed to converge"))
+    }
+
+    // cubic interpolation in log strike through the four nearest points
+    let mut prices = Vec::with_capacity(strikes.len());
+    for &strike in strikes.iter() {
+        let x = ((strike / forward).ln() - lowest) / lambda;
+        let i = x.floor() as isize - 1;
+        if i < 0 || i + 3 >= n as isize {
+            return Err(qm::Error::new("Strike is outside the range of the FFT grid"))
+        }
+        let i = i as usize;
+        let s = x - (i + 1) as f64;
+        let (p0, p1, p2, p3) = (grid[i], grid[i + 1], grid[i + 2], grid[i + 3]);
+        let price = -s * (s - 1.0) * (s - 2.0) / 6.0 * p0
+            + (s + 1.0) * (s - 1.0) * (s - 2.0) / 2.0 * p1
+            - (s + 1.0) * s * (s - 2.0) / 2.0 * p2
+            + (s + 1.0) * s * (s - 1.0) / 6.0 * p3;
+
+        // clamp to the no-arbitrage bounds
+        let undiscounted = (forward * price).max((forward - strike).max(0.0)).min(forward);
+        prices.push(df * undiscounted);
+    }
+    Ok(prices)
+}
+
+/// Prices a strip of European puts by put-call parity from the FFT call
+/// prices
+pub fn fft_put_prices(model: &CharacteristicFunction, df: f64, forward: f64,
+    strikes: &[f64], t: f64) -> Result<Vec<f64>, qm::Error> {
+    let calls = fft_call_prices(model, df, forward, strikes, t)?;
+    Ok(calls.iter().zip(strikes.iter())
+        .map(|(call, strike)| call - df * (forward - strike)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::optionpricing::Black76;
+
+    struct Lognormal { vol: f64 }
+
+    impl CharacteristicFunction for Lognormal {
+        fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+            // ln(S/F) is normal with mean -v/2 and variance v
+            let v = self.vol * self.vol * t;
+            let iu = Complex::i() * u;
+            (iu * (-0.5 * v) + iu * iu * (0.5 * v)).exp()
+        }
+    }
+
+    #[test]
+    fn fourier_matches_black() {
+        let model = Lognormal { vol: 0.3 };
+        let black76 = Black76::new().unwrap();
+        for &(strike, t) in [(100.0, 1.0_f64), (60.0, 2.0), (150.0, 0.5), (101.0, 0.02)].iter() {
+            let sqrt_var = 0.3 * t.sqrt();
+            let expected = black76.call_price(0.9, 100.0, strike, sqrt_var);
+            let call = call_price(&model, 0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(call, expected, 1e-6),
+                "strike={} t={} call={} expected={}", strike, t, call, expected);
+
+            let expected = black76.put_price(0.9, 100.0, strike, sqrt_var);
+            let put = put_price(&model, 0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(put, expected, 1e-6),
+                "strike={} t={} put={} expected={}", strike, t, put, expected);
+        }
+    }
+
+    #[test]
+    fn cos_matches_black() {
+        let model = Lognormal { vol: 0.3 };
+        let black76 = Black76::new().unwrap();
+        for &(strike, t) in [(100.0, 1.0_f64), (60.0, 2.0), (150.0, 0.5), (101.0, 0.02)].iter() {
+            let sqrt_var = 0.3 * t.sqrt();
+            let expected = black76.put_price(0.9, 100.0, strike, sqrt_var);
+            let put = cos_put_price(&model, 0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(put, expected, 1e-8),
+                "strike={} t={} put={} expected={}", strike, t, put, expected);
+
+            let expected = black76.call_price(0.9, 100.0, strike, sqrt_var);
+            let call = cos_call_price(&model, 0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(call, expected, 1e-8),
+                "strike={} t={} call={} expected={}", strike, t, call, expected);
+        }
+    }
+
+    #[test]
+    fn fft_matches_black() {
+        let model = Lognormal { vol: 0.3 };
+        let black76 = Black76::new().unwrap();
+        let strikes = [40.0, 60.0, 85.0, 99.5, 100.0, 101.0, 120.0, 150.0, 250.0];
+        for &t in [0.25, 1.0_f64, 5.0].iter() {
+            let sqrt_var = 0.3 * t.sqrt();
+            let calls = fft_call_prices(&model, 0.9, 100.0, &strikes, t).unwrap();
+            let puts = fft_put_prices(&model, 0.9, 100.0, &strikes, t).unwrap();
+            for (i, &strike) in strikes.iter().enumerate() {
+                let expected = black76.call_price(0.9, 100.0, strike, sqrt_var);
+                assert!(approx_eq(calls[i], expected, 1e-6),
+                    "strike={} t={} call={} expected={}", strike, t, calls[i], expected);
+                let expected = black76.put_price(0.9, 100.0, strike, sqrt_var);
+                assert!(approx_eq(puts[i], expected, 1e-6),
+                    "strike={} t={} put={} expected={}", strike, t, puts[i], expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use core::qm;
+
+/// A recombining binomial lattice for the Ho-Lee short rate model, where
+/// the short rate follows dr = theta(t) dt + sigma dW. The drift is chosen
+/// at each step so that the lattice exactly reprices the zero coupon bonds
+/// maturing at every step, and the volatility is a normal volatility of the
+/// short rate.
+///
+/// Steps are evenly spaced in time. Step zero has a single node, and step i
+/// has i + 1 nodes, where node j has had j up moves. Up and down moves have
+/// equal probability. Rates are continuously compounded over each step.
+pub struct ShortRateLattice {
+    dt: f64,
+    spacing: f64,
+    drifts: Vec<f64>
+}
+
+impl ShortRateLattice {
+    /// Creates a lattice, given the discount factors from step zero to each
+    /// step, starting with one at step zero, the time between steps in years
+    /// and the normal volatility of the short rate.
+    pub fn new(discount_factors: &[f64], dt: f64, vol: f64)
+        -> Result<ShortRateLattice, qm::Error> {
+
+        if discount_factors.len() < 2 {
+            return Err(qm::Error::new("Lattice must have at least one step"))
+        }
+        if !(dt > 0.0) {
+            return Err(qm::Error::new("Lattice step must be positive"))
+        }
+        if vol < 0.0 {
+            return Err(qm::Error::new("Lattice vol must not be negative"))
+        }
+
+        // Calibrate by forward induction of the Arrow-Debreu prices, which
+        // are the values today of receiving one at each node and nowhere
+        // else. The drift at each step is the one that makes the sum of the
+        // discounted Arrow-Debreu prices match the discount factor.
+        let steps = discount_factors.len() - 1;
+        let spacing = vol * dt.sqrt();
+        let mut drifts = Vec::with_capacity(steps);
+        let mut prices = vec![discount_factors[0]];
+        for i in 0..steps {
+            let target = discount_factors[i + 1];
+            if !(target > 0.0) {
+                return Err(qm::Error::new("Discount factors must be positive"))
+            }
+
+            let sum: f64 = prices.iter().enumerate().map(|(j, price)|
+                price * (-offset(spacing, i, j) * dt).exp()).sum();
+            let drift = (sum / target).ln() / dt;
+            drifts.push(drift);
+
+            let mut next = vec![0.0; i + 2];
+            for (j, price) in prices.iter().enumerate() {
+                let discounted = 0.5 * price
+                    * (-(drift + offset(spacing, i, j)) * dt).exp();
+                next[j] += discounted;
+                next[j + 1] += discounted;
+            }
+            prices = next;
+        }
+
+        Ok(ShortRateLattice { dt: dt, spacing: spacing, drifts: drifts })
+    }
+
+    /// The number of steps in the lattice. There are discount factors and
+    /// values for one more than this.
+    pub fn steps(&self) -> usize { self.drifts.len() }
+
+    /// The time between steps in years
+    pub fn dt(&self) -> f64 { self.dt }
+
+    /// The short rate applying from the given node until the next step
+    pub fn rate(&self, step: usize, node: usize) -> f64 {
+        assert!(node <= step);
+        self.drifts[step] + offset(self.spacing, step, node)
+    }
+
+    /// Given the values at each node of the step after the given one, returns
+    /// the expected discounted values at each node of the given step.
+    pub fn roll_back(&self, step: usize, values: &[f64]) -> Vec<f64> {
+        assert_eq!(values.len(), step + 2);
+        (0..(step + 1)).map(|j| 0.5 * (values[j] + values[j + 1])
+            * (-self.rate(step, j) * self.dt).exp()).collect()
+    }
+}
+
+/// The offset of the rate at node j of step i from the drift
+fn offset(spacing: f64, i: usize, j: usize) -> f64 {
+    spacing * (2.0 * j as f64 - i as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    fn sample_discount_factors(steps: usize, dt: f64) -> Vec<f64> {
+        // an upward sloping curve
+        (0..(steps + 1)).map(|i| {
+            let t = i as f64 * dt;
+            (-(0.03 + 0.01 * t) * t).exp()
+        }).collect()
+    }
+
+    #[test]
+    fn lattice_reprices_zero_coupons() {
+        let dt = 1.0 / 52.0;
+        let dfs = sample_discount_factors(104, dt);
+        let lattice = ShortRateLattice::new(&dfs, dt, 0.01).unwrap();
+        assert_eq!(lattice.steps(), 104);
+
+        for maturity in [1, 10, 52, 104].iter() {
+            let mut values = vec![1.0; maturity + 1];
+            for step in (0..*maturity).rev() {
+                values = lattice.roll_back(step, &values);
+            }
+            assert_eq!(values.len(), 1);
+            assert_approx(values[0], dfs[*maturity], 1e-12);
+        }
+    }
+
+    #[test]
+    fn zero_vol_lattice_follows_the_forwards() {
+        let dt = 1.0 / 12.0;
+        let dfs = sample_discount_factors(24, dt);
+        let lattice = ShortRateLattice::new(&dfs, dt, 0.0).unwrap();
+        for step in 0..24 {
+            let forward = (dfs[step] / dfs[step + 1]).ln() / dt;
+            for node in 0..(step + 1) {
+                assert_approx(lattice.rate(step, node), forward, 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn lattice_rates_spread_with_vol() {
+        let dt = 0.25;
+        let dfs = sample_discount_factors(8, dt);
+        let lattice = ShortRateLattice::new(&dfs, dt, 0.01).unwrap();
+        let spacing = 0.01 * 0.5;
+        assert_approx(lattice.rate(4, 3) - lattice.rate(4, 2), 2.0 * spacing, 1e-12);
+        assert!(lattice.rate(7, 7) > lattice.rate(7, 0));
+    }
+
+    #[test]
+    fn lattice_rejects_bad_inputs() {
+        assert!(ShortRateLattice::new(&[1.0], 0.1, 0.01).is_err());
+        assert!(ShortRateLattice::new(&[1.0, 0.99], 0.0, 0.01).is_err());
+        assert!(ShortRateLattice::new(&[1.0, 0.99], 0.1, -0.01).is_err());
+        assert!(ShortRateLattice::new(&[1.0, 0.0], 0.1, 0.01).is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -2,3 +2,14 @@ pub mod brent;
 pub mod interpolation;
 pub mod numerics;
 pub mod optionpricing;
+pub mod lattice;
+pub mod tridiagonal;
+pub mod complex;
+pub mod fourier;
+pub mod fft;
+pub mod adjoint;
+pub mod neldermead;
+pub mod sabr;
+pub mod correlation;
+pub mod sobol;
+pub mod brownianbridge;
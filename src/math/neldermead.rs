@@ -0,0 +1,125 @@
+use core::qm;
+
+/// Guards the convergence test against division by zero when the minimum
+/// of the function is zero, as it is for an exact fit
+const TINY: f64 = 1e-10;
+
+/// The downhill simplex method of minimization, due to Nelder and Mead, as
+/// described in Numerical Recipes in C by Press, Teukolsky, Vetterling and
+/// Flannery. It only needs function values, not derivatives, so it is
+/// robust for small problems such as calibrating a handful of model
+/// parameters, though slow for large ones.
+///
+/// The initial simplex is the start point plus a step along each axis.
+/// Iteration stops when the function values at the vertices of the simplex
+/// agree to within the relative tolerance. Returns the best point found and
+/// the function value there.
+pub fn nelder_mead<F>(start: &[f64], step: &[f64], tol: f64, max_iter: u32,
+    func: &mut F) -> Result<(Vec<f64>, f64), qm::Error>
+    where F: FnMut(&[f64]) -> Result<f64, qm::Error> {
+
+    let n = start.len();
+    if n == 0 || step.len() != n {
+        return Err(qm::Error::new("Nelder-Mead needs a start point and a step \
+            for each dimension"))
+    }
+
+    let mut simplex = Vec::with_capacity(n + 1);
+    simplex.push(start.to_vec());
+    for i in 0..n {
+        let mut vertex = start.to_vec();
+        vertex[i] += step[i];
+        simplex.push(vertex);
+    }
+    let mut values = Vec::with_capacity(n + 1);
+    for vertex in simplex.iter() {
+        values.push(func(vertex)?);
+    }
+
+    for _ in 0..max_iter {
+        // order the vertices, best first
+        let mut order: Vec<usize> = (0..(n + 1)).collect();
+        order.sort_by(|a, b| values[*a].partial_cmp(&values[*b])
+            .unwrap_or(::std::cmp::Ordering::Equal));
+        simplex = order.iter().map(|i| simplex[*i].clone()).collect();
+        values = order.iter().map(|i| values[*i]).collect();
+
+        let best = values[0];
+        let worst = values[n];
+        if 2.0 * (worst - best).abs() <= tol * (worst.abs() + best.abs() + TINY) {
+            return Ok((simplex[0].clone(), best))
+        }
+
+        // centroid of all but the worst vertex
+        let mut centroid = vec![0.0; n];
+        for vertex in simplex.iter().take(n) {
+            for (c, x) in centroid.iter_mut().zip(vertex.iter()) {
+                *c += x / n as f64;
+            }
+        }
+        let along = |scale: f64| -> Vec<f64> {
+            centroid.iter().zip(simplex[n].iter())
+                .map(|(c, w)| c + scale * (c - w)).collect()
+        };
+
+        // reflect the worst vertex through the centroid, then try
+        // expanding or contracting
+        let reflected = along(1.0);
+        let f_reflected = func(&reflected)?;
+        if f_reflected < best {
+            let expanded = along(2.0);
+            let f_expanded = func(&expanded)?;
+            if f_expanded < f_reflected {
+                simplex[n] = expanded;
+                values[n] = f_expanded;
+            } else {
+                simplex[n] = reflected;
+                values[n] = f_reflected;
+            }
+        } else if f_reflected < values[n - 1] {
+            simplex[n] = reflected;
+            values[n] = f_reflected;
+        } else {
+            let contracted = if f_reflected < worst { along(0.5) } else { along(-0.5) };
+            let f_contracted = func(&contracted)?;
+            if f_contracted < worst.min(f_reflected) {
+                simplex[n] = contracted;
+                values[n] = f_contracted;
+            } else {
+                // shrink everything towards the best vertex
+                for i in 1..(n + 1) {
+                    let shrunk: Vec<f64> = simplex[0].iter().zip(simplex[i].iter())
+                        .map(|(b, x)| b + 0.5 * (x - b)).collect();
+                    values[i] = func(&shrunk)?;
+                    simplex[i] = shrunk;
+                }
+            }
+        }
+    }
+
+    Err(qm::Error::new("Too many iterations in nelder_mead"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn minimizes_rosenbrock() {
+        let mut rosenbrock = |x: &[f64]| -> Result<f64, qm::Error> {
+            Ok((1.0 - x[0]).powi(2) + 100.0 * (x[1] - x[0] * x[0]).powi(2))
+        };
+        let (x, f) = nelder_mead(&[-1.2, 1.0], &[0.5, 0.5], 1e-14, 5000,
+            &mut rosenbrock).unwrap();
+        assert!(approx_eq(x[0], 1.0, 1e-4) && approx_eq(x[1], 1.0, 1e-4),
+            "x={:?}", x);
+        assert!(f < 1e-8, "f={}", f);
+    }
+
+    #[test]
+    fn reports_failure_to_converge() {
+        let mut unbounded = |x: &[f64]| -> Result<f64, qm::Error> { Ok(x[0]) };
+        assert!(nelder_mead(&[0.0], &[1.0], 1e-12, 50, &mut unbounded).is_err());
+    }
+}
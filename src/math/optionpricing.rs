@@ -1,5 +1,8 @@
 use statrs::distribution::Normal;
 use statrs::distribution::Univariate;
+use statrs::distribution::Continuous;
+use statrs::function::erf::erf;
+use statrs::function::erf::erf_inv;
 use core::qm;
 
 /// The 1976 reformulation of the Black-Scholes formula, where the price of
@@ -39,11 +42,199 @@ impl Black76 {
         df * (self.cdf(-d_minus) * strike - self.cdf(-d_plus) * forward)
     }
 
+    /// Calculates the PV of a European cash-or-nothing digital call, which
+    /// pays one unit of cash if the forward ends above the strike.
+    pub fn cash_digital_call_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        let log_moneyness = (forward / strike).ln();
+        let (_, d_minus) = d_plus_minus(log_moneyness, sqrt_variance);
+
+        df * self.cdf(d_minus)
+    }
+
+    /// Calculates the PV of a European cash-or-nothing digital put, which
+    /// pays one unit of cash if the forward ends below the strike.
+    pub fn cash_digital_put_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        let log_moneyness = (forward / strike).ln();
+        let (_, d_minus) = d_plus_minus(log_moneyness, sqrt_variance);
+
+        df * self.cdf(-d_minus)
+    }
+
+    /// Calculates the PV of a European asset-or-nothing digital call, which
+    /// pays the underlying if it ends above the strike.
+    pub fn asset_digital_call_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        let log_moneyness = (forward / strike).ln();
+        let (d_plus, _) = d_plus_minus(log_moneyness, sqrt_variance);
+
+        df * self.cdf(d_plus) * forward
+    }
+
+    /// Calculates the PV of a European asset-or-nothing digital put, which
+    /// pays the underlying if it ends below the strike.
+    pub fn asset_digital_put_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        let log_moneyness = (forward / strike).ln();
+        let (d_plus, _) = d_plus_minus(log_moneyness, sqrt_variance);
+
+        df * self.cdf(-d_plus) * forward
+    }
+
+    /// Calculates the PV of a continuously monitored barrier option with
+    /// no rebate, by the closed forms of Reiner and Rubinstein (1991). The
+    /// barrier is monitored from now, when the underlying is at the given
+    /// spot, to expiry, when it has the given forward. The carry from spot
+    /// to forward is taken to accrue in proportion to the variance, which
+    /// is exact for constant rates and vols. The flags say whether the
+    /// option is a call, whether the barrier is above the spot, and whether
+    /// it knocks in rather than out.
+    pub fn barrier_price(&self, df: f64, spot: f64, forward: f64, strike: f64,
+        barrier: f64, sqrt_variance: f64, call: bool, up: bool, knock_in: bool)
+        -> f64 {
+
+        let vanilla = if call {
+            self.call_price(df, forward, strike, sqrt_variance)
+        } else {
+            self.put_price(df, forward, strike, sqrt_variance)
+        };
+
+        // if the barrier has already been hit, the option is a vanilla or
+        // nothing, and if there is no variance left it cannot be hit
+        let hit = if up { spot >= barrier } else { spot <= barrier };
+        if hit || sqrt_variance <= 0.0 {
+            return if hit == knock_in { vanilla } else { 0.0 }
+        }
+
+        let s = sqrt_variance;
+        let v = s * s;
+        let mu = (forward / spot).ln() / v - 0.5;
+        let phi = if call { 1.0 } else { -1.0 };
+        let eta = if up { -1.0 } else { 1.0 };
+        let ratio = barrier / spot;
+        let asset_reflection = ratio.powf(2.0 * (mu + 1.0));
+        let cash_reflection = ratio.powf(2.0 * mu);
+
+        // the terms A to D of Reiner and Rubinstein, expressed in terms of
+        // the forward rather than the spot and the cost of carry
+        let term = |x: f64| phi * df * (forward * self.cdf(phi * x)
+            - strike * self.cdf(phi * (x - s)));
+        let reflected = |y: f64| phi * df * (forward * asset_reflection
+            * self.cdf(eta * y) - strike * cash_reflection * self.cdf(eta * (y - s)));
+        let a = term(((forward / strike).ln() + 0.5 * v) / s);
+        let b = term(((forward / barrier).ln() + 0.5 * v) / s);
+        let c = reflected(((forward * barrier * barrier / (spot * spot * strike)).ln()
+            + 0.5 * v) / s);
+        let d = reflected(((forward * barrier / (spot * spot)).ln() + 0.5 * v) / s);
+
+        let above = strike >= barrier;
+        let knocked_in = match (call, up, above) {
+            (true, false, true) => c,
+            (true, false, false) => a - b + d,
+            (true, true, true) => a,
+            (true, true, false) => b - c + d,
+            (false, false, true) => b - c + d,
+            (false, false, false) => a,
+            (false, true, true) => a - b + d,
+            (false, true, false) => c
+        };
+
+        // knock-in plus knock-out is the vanilla, and neither can be
+        // negative, whatever the rounding
+        let knocked_in = knocked_in.max(0.0).min(vanilla);
+        if knock_in { knocked_in } else { vanilla - knocked_in }
+    }
+
     pub fn cdf(&self, x: f64) -> f64 {
         self.normal.cdf(x)
     }
 }
 
+/// The Bachelier formula, where the forward is normally rather than
+/// log-normally distributed. The vol is a normal vol, in the same units as
+/// the forward, so the model makes sense for forwards that can be negative
+/// or close to zero, such as rates and spreads.
+pub struct Bachelier {
+    normal: Normal
+}
+
+impl Bachelier {
+    pub fn new() -> Result<Bachelier, qm::Error> {
+        match Normal::new(0.0, 1.0) {
+            Ok(normal) => Ok(Bachelier { normal: normal }),
+            Err(e) => Err(qm::Error::new(&format!("RSStat error: {}", e)))
+        }
+    }
+
+    /// Calculates the PV of a European call option, given the standard
+    /// deviation of the forward at expiry, which is the normal vol times
+    /// the square root of the time to expiry
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        let intrinsic = forward - strike;
+        if sqrt_variance <= 0.0 {
+            return df * intrinsic.max(0.0)
+        }
+        let d = intrinsic / sqrt_variance;
+        df * (intrinsic * self.normal.cdf(d) + sqrt_variance * self.normal.pdf(d))
+    }
+
+    /// Calculates the PV of a European put option
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        let intrinsic = strike - forward;
+        if sqrt_variance <= 0.0 {
+            return df * intrinsic.max(0.0)
+        }
+        let d = intrinsic / sqrt_variance;
+        df * (intrinsic * self.normal.cdf(d) + sqrt_variance * self.normal.pdf(d))
+    }
+}
+
+/// Converts the square root of the log-normal variance of a forward into
+/// that of a displaced diffusion, where the forward plus the shift is
+/// log-normal, such that both give the same price for an at the money
+/// option. The at the money Black price is F erf(s / 2 sqrt(2)), so this
+/// has a closed form. A positive shift gives a downward-sloping skew.
+pub fn displaced_sqrt_variance(forward: f64, shift: f64, sqrt_variance: f64)
+    -> Result<f64, qm::Error> {
+
+    if forward <= 0.0 || forward + shift <= 0.0 {
+        return Err(qm::Error::new("Displaced diffusion needs the forward \
+            and the shifted forward to be positive"))
+    }
+    let scale = 2.0 * ::std::f64::consts::SQRT_2;
+    let target = forward / (forward + shift) * erf(sqrt_variance / scale);
+    if target >= 1.0 {
+        return Err(qm::Error::new("At the money price cannot be matched \
+            with this shift"))
+    }
+    Ok(scale * erf_inv(target))
+}
+
+/// Kirk's approximation for a spread option paying max(S1 - S2 - K, 0). The
+/// option is treated as an option to exchange S1 for S2 + K, where S2 + K is
+/// taken to be lognormal with the vol of S2 scaled by F2 / (F2 + K). This
+/// returns the effective variance, to be used in Black76 with forward F1
+/// and strike F2 + K. With a zero strike it reduces to Margrabe's exact
+/// formula for an exchange option.
+///
+/// The variances are the total variances of each asset to expiry, and the
+/// covariance is correlation * sqrt(variance1 * variance2).
+pub fn kirk_spread_variance(variance1: f64, variance2: f64, covariance: f64,
+    forward2: f64, strike: f64) -> f64 {
+
+    let weight = forward2 / (forward2 + strike);
+    variance1 - 2.0 * covariance * weight + variance2 * weight * weight
+}
+
 /// Calculates the internal d_plus and d_minus values needed for many of the
 /// Black Scholes formulae.
 fn d_plus_minus(log_moneyness: f64, sqrt_variance: f64) -> (f64, f64) {
@@ -72,6 +263,22 @@ mod tests {
         assert_approx(black76.cdf(4.0), 0.99996833, 1e-8, "cdf"); 
     }
 
+    #[test]
+    fn kirk_reduces_to_margrabe() {
+        // with zero strike, the variance is that of the ratio S1 / S2
+        let variance = kirk_spread_variance(0.09, 0.04, 0.03, 95.0, 0.0);
+        assert_approx(variance, 0.09 + 0.04 - 2.0 * 0.03, 1e-14, "margrabe");
+
+        // a positive strike damps the contribution of the second asset
+        let variance = kirk_spread_variance(0.09, 0.04, 0.03, 95.0, 5.0);
+        assert_approx(variance, 0.09 - 2.0 * 0.03 * 0.95 + 0.04 * 0.95 * 0.95,
+            1e-14, "kirk");
+
+        // perfectly correlated assets with equal vols have no spread vol
+        let variance = kirk_spread_variance(0.09, 0.09, 0.09, 100.0, 0.0);
+        assert_approx(variance, 0.0, 1e-14, "degenerate");
+    }
+
     #[test]
     fn black76_price() {
 
@@ -94,6 +301,139 @@ mod tests {
         }
     }
 
+    #[test]
+    fn black76_digital_price() {
+
+        let forward = 100.0;
+        let df = 0.99;
+        let sqrt_var = 0.5;
+        let black76 = Black76::new().unwrap();
+
+        for strike in [50.0, 70.0, 90.0, 100.0, 110.0, 130.0, 160.0].iter() {
+            let cash_call = black76.cash_digital_call_price(df, forward, *strike, sqrt_var);
+            let cash_put = black76.cash_digital_put_price(df, forward, *strike, sqrt_var);
+            let asset_call = black76.asset_digital_call_price(df, forward, *strike, sqrt_var);
+            let asset_put = black76.asset_digital_put_price(df, forward, *strike, sqrt_var);
+            let call_price = black76.call_price(df, forward, *strike, sqrt_var);
+            let put_price = black76.put_price(df, forward, *strike, sqrt_var);
+
+            // a vanilla is an asset digital less strike times a cash digital
+            assert_approx(cash_call + cash_put, df, 1e-12, "cash digital parity");
+            assert_approx(asset_call + asset_put, df * forward, 1e-12, "asset digital parity");
+            assert_approx(asset_call - *strike * cash_call, call_price, 1e-12, "call decomposition");
+            assert_approx(*strike * cash_put - asset_put, put_price, 1e-12, "put decomposition");
+        }
+    }
+
+    #[test]
+    fn barrier_matches_put_call_symmetry() {
+        // with no carry, a down-and-in call struck above the barrier is a
+        // put struck at the reflected strike, scaled by strike over barrier,
+        // and likewise an up-and-in put struck below the barrier is a call
+        let black76 = Black76::new().unwrap();
+        let (df, spot, sqrt_var) = (0.95, 100.0, 0.3);
+        for &(strike, barrier) in [(100.0, 90.0), (90.0, 80.0), (120.0, 95.0)].iter() {
+            let price = black76.barrier_price(df, spot, spot, strike, barrier, sqrt_var,
+                true, false, true);
+            let expected = strike / barrier * black76.put_price(df, spot,
+                barrier * barrier / strike, sqrt_var);
+            assert_approx(price, expected, 1e-12, "down and in call");
+        }
+        for &(strike, barrier) in [(100.0, 110.0), (110.0, 120.0), (80.0, 105.0)].iter() {
+            let price = black76.barrier_price(df, spot, spot, strike, barrier, sqrt_var,
+                false, true, true);
+            let expected = strike / barrier * black76.call_price(df, spot,
+                barrier * barrier / strike, sqrt_var);
+            assert_approx(price, expected, 1e-12, "up and in put");
+        }
+    }
+
+    #[test]
+    fn barrier_in_plus_out_is_vanilla() {
+        let black76 = Black76::new().unwrap();
+        let (df, spot, forward, sqrt_var) = (0.95, 100.0, 103.0, 0.25);
+        for &(strike, barrier) in [(100.0, 80.0), (70.0, 80.0), (100.0, 120.0),
+            (130.0, 120.0)].iter() {
+            for &call in [true, false].iter() {
+                let up = barrier > spot;
+                let vanilla = if call {
+                    black76.call_price(df, forward, strike, sqrt_var)
+                } else {
+                    black76.put_price(df, forward, strike, sqrt_var)
+                };
+                let knock_in = black76.barrier_price(df, spot, forward, strike,
+                    barrier, sqrt_var, call, up, true);
+                let knock_out = black76.barrier_price(df, spot, forward, strike,
+                    barrier, sqrt_var, call, up, false);
+                assert!(knock_in > 0.0 && knock_out >= 0.0,
+                    "strike={} barrier={} call={} in={} out={}",
+                    strike, barrier, call, knock_in, knock_out);
+                assert_approx(knock_in + knock_out, vanilla, 1e-12, "parity");
+
+                // a barrier that is out of reach does nothing
+                let far = if up { 1e6 } else { 1e-6 };
+                let knock_out = black76.barrier_price(df, spot, forward, strike,
+                    far, sqrt_var, call, up, false);
+                assert_approx(knock_out, vanilla, 1e-12, "far barrier");
+            }
+        }
+    }
+
+    #[test]
+    fn bachelier_price() {
+
+        let df = 0.99;
+        let sqrt_var = 20.0;
+        let bachelier = Bachelier::new().unwrap();
+
+        // at the money, the price is the expected positive part of a normal
+        let atm = bachelier.call_price(df, 100.0, 100.0, sqrt_var);
+        let expected = df * sqrt_var / (2.0 * ::std::f64::consts::PI).sqrt();
+        assert_approx(atm, expected, 1e-12, "at the money");
+
+        // negative forwards and strikes are fine
+        for &(forward, strike) in [(100.0, 70.0), (100.0, 130.0), (-0.5, 0.25),
+            (0.1, -0.3)].iter() {
+            let call_price = bachelier.call_price(df, forward, strike, sqrt_var);
+            let put_price = bachelier.put_price(df, forward, strike, sqrt_var);
+            let parity = df * (forward - strike) + put_price - call_price;
+            assert!(call_price >= df * (forward - strike).max(0.0));
+            assert!(put_price >= df * (strike - forward).max(0.0));
+            assert_approx(parity, 0.0, 1e-12, "put/call parity");
+        }
+
+        // close to the money with small vol, Bachelier and Black agree when
+        // the normal vol is the log-normal vol times the geometric mean of
+        // the forward and strike
+        let black76 = Black76::new().unwrap();
+        let normal = bachelier.call_price(df, 100.0, 101.0, (100.0_f64 * 101.0).sqrt() * 0.02);
+        let lognormal = black76.call_price(df, 100.0, 101.0, 0.02);
+        assert_approx(normal, lognormal, 2e-3, "normal vs lognormal");
+    }
+
+    #[test]
+    fn displaced_variance_matches_at_the_money() {
+        let black76 = Black76::new().unwrap();
+        let forward = 100.0;
+        let sqrt_var = 0.3;
+        let expected = black76.call_price(0.99, forward, forward, sqrt_var);
+        for &shift in [-50.0, 0.0, 50.0, 400.0].iter() {
+            let shifted = displaced_sqrt_variance(forward, shift, sqrt_var).unwrap();
+            let price = black76.call_price(0.99, forward + shift, forward + shift, shifted);
+            assert_approx(price, expected, 1e-10, "at the money");
+        }
+
+        // positive shifts give a downward-sloping skew
+        let shift = 100.0;
+        let shifted = displaced_sqrt_variance(forward, shift, sqrt_var).unwrap();
+        let put = black76.put_price(0.99, forward + shift, 70.0 + shift, shifted);
+        assert!(put > black76.put_price(0.99, forward, 70.0, sqrt_var));
+        let call = black76.call_price(0.99, forward + shift, 130.0 + shift, shifted);
+        assert!(call < black76.call_price(0.99, forward, 130.0, sqrt_var));
+
+        assert!(displaced_sqrt_variance(forward, -100.0, sqrt_var).is_err());
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64, message: &str) {
         assert!(approx_eq(value, expected, tolerance),
             "{}: value={} expected={}", message, value, expected);
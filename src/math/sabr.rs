@@ -0,0 +1,211 @@
+use core::qm;
+use math::neldermead::nelder_mead;
+
+/// Relative tolerance of the calibration, in terms of the sum of squared
+/// errors in the vols
+const CALIBRATION_TOLERANCE: f64 = 1e-12;
+
+/// Maximum number of iterations of the calibration
+const CALIBRATION_MAX_ITER: u32 = 5000;
+
+/// The parameters of the SABR stochastic volatility model of Hagan et al
+/// (2002), for the forward F to some expiry:
+///
+///  dF = alpha F^beta dW1
+///  dalpha = nu alpha dW2
+///  dW1 dW2 = rho dt
+///
+/// where alpha is the initial volatility, beta the elasticity, which moves
+/// the backbone between normal (zero) and log-normal (one), rho the
+/// correlation between the forward and its vol, and nu the vol of vol. The
+/// units of alpha depend on beta, as it is a vol of F^beta.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SabrParameters {
+    alpha: f64,
+    beta: f64,
+    rho: f64,
+    nu: f64
+}
+
+impl SabrParameters {
+    pub fn new(alpha: f64, beta: f64, rho: f64, nu: f64)
+        -> Result<SabrParameters, qm::Error> {
+
+        if alpha <= 0.0 {
+            return Err(qm::Error::new("SABR alpha must be positive"))
+        }
+        if beta < 0.0 || beta > 1.0 {
+            return Err(qm::Error::new("SABR beta must be between 0 and 1"))
+        }
+        if rho <= -1.0 || rho >= 1.0 {
+            return Err(qm::Error::new("SABR rho must be strictly between -1 and 1"))
+        }
+        if nu < 0.0 {
+            return Err(qm::Error::new("SABR nu must not be negative"))
+        }
+
+        Ok(SabrParameters { alpha: alpha, beta: beta, rho: rho, nu: nu })
+    }
+
+    pub fn alpha(&self) -> f64 { self.alpha }
+    pub fn beta(&self) -> f64 { self.beta }
+    pub fn rho(&self) -> f64 { self.rho }
+    pub fn nu(&self) -> f64 { self.nu }
+
+    /// The Black implied volatility at the given strike, for the given
+    /// forward and time to expiry, by the asymptotic expansion of Hagan et
+    /// al. This is accurate for short expiries and strikes not far from the
+    /// forward, and very fast, which is why it is the usual way of using
+    /// SABR as a smile. Far from the money, and for long expiries, it can
+    /// imply a negative density.
+    pub fn implied_vol(&self, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+
+        if forward <= 0.0 || strike <= 0.0 {
+            return Err(qm::Error::new("SABR needs a positive forward and strike"))
+        }
+
+        let one_minus_beta = 1.0 - self.beta;
+        let log_moneyness = (forward / strike).ln();
+        let log2 = log_moneyness * log_moneyness;
+        let mid = (forward * strike).powf(0.5 * one_minus_beta);
+
+        // the ratio z / x(z), which tends to one at the money
+        let z = self.nu / self.alpha * mid * log_moneyness;
+        let z_over_x = if z.abs() < 1e-6 {
+            1.0 - 0.5 * self.rho * z + (2.0 - 3.0 * self.rho * self.rho) * z * z / 12.0
+        } else {
+            let x = (((1.0 - 2.0 * self.rho * z + z * z).sqrt() + z - self.rho)
+                / (1.0 - self.rho)).ln();
+            z / x
+        };
+
+        let omb2 = one_minus_beta * one_minus_beta;
+        let denominator = mid * (1.0 + omb2 * log2 / 24.0 + omb2 * omb2 * log2 * log2 / 1920.0);
+        let correction = 1.0 + t * (omb2 * self.alpha * self.alpha / (24.0 * mid * mid)
+            + 0.25 * self.rho * self.beta * self.nu * self.alpha / mid
+            + (2.0 - 3.0 * self.rho * self.rho) * self.nu * self.nu / 24.0);
+
+        let vol = self.alpha / denominator * z_over_x * correction;
+        if !vol.is_finite() || vol < 0.0 {
+            return Err(qm::Error::new("SABR implied vol is not valid"))
+        }
+        Ok(vol)
+    }
+
+    /// Calibrates alpha, rho and nu to a market smile of (strike, vol)
+    /// pairs, for the given forward and time to expiry, minimizing the sum
+    /// of squared errors in the vols. Beta is not calibrated, as it is
+    /// poorly determined by a single smile. It is normally chosen to
+    /// match the backbone, in other words how the at-the-money vol moves
+    /// with the forward.
+    pub fn calibrate(forward: f64, t: f64, beta: f64, smile: &[(f64, f64)])
+        -> Result<SabrParameters, qm::Error> {
+
+        if smile.len() < 3 {
+            return Err(qm::Error::new("SABR calibration needs at least three \
+                points on the smile"))
+        }
+        if t <= 0.0 {
+            return Err(qm::Error::new("SABR calibration needs a positive time"))
+        }
+
+        // start from a flat smile at the vol nearest the money
+        let mut atm_vol = smile[0].1;
+        let mut nearest = ::std::f64::INFINITY;
+        for &(strike, vol) in smile.iter() {
+            let distance = (strike / forward).ln().abs();
+            if distance < nearest {
+                nearest = distance;
+                atm_vol = vol;
+            }
+        }
+        let alpha = atm_vol * forward.powf(1.0 - beta);
+
+        // Search in terms of unconstrained variables: the logs of alpha and
+        // nu, and a tanh transform of rho. Parameters for which the
+        // expansion fails are heavily penalized.
+        let parameters = |x: &[f64]| SabrParameters::new(x[0].exp(), beta,
+            x[1].tanh(), x[2].exp());
+        let mut objective = |x: &[f64]| -> Result<f64, qm::Error> {
+            let p = parameters(x)?;
+            let mut sum = 0.0;
+            for &(strike, vol) in smile.iter() {
+                sum += match p.implied_vol(forward, strike, t) {
+                    Ok(model) => (model - vol) * (model - vol),
+                    Err(_) => 1.0
+                };
+            }
+            Ok(sum)
+        };
+
+        // restart from the best point, as the simplex can collapse early
+        let mut start = vec![alpha.ln(), 0.0, (0.5_f64).ln()];
+        for _ in 0..2 {
+            let (best, _) = nelder_mead(&start, &[0.2, 0.3, 0.5],
+                CALIBRATION_TOLERANCE, CALIBRATION_MAX_ITER, &mut objective)?;
+            start = best;
+        }
+        parameters(&start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn log_normal_without_vol_of_vol_is_flat() {
+        let p = SabrParameters::new(0.25, 1.0, -0.4, 0.0).unwrap();
+        for &strike in [50.0, 90.0, 100.0, 100.0000001, 150.0].iter() {
+            let vol = p.implied_vol(100.0, strike, 2.0).unwrap();
+            assert!(approx_eq(vol, 0.25, 1e-12), "strike={} vol={}", strike, vol);
+        }
+    }
+
+    #[test]
+    fn at_the_money_vol() {
+        // Hagan's formula reduces to a simple expression at the money
+        let p = SabrParameters::new(2.5, 0.5, -0.3, 0.4).unwrap();
+        let (f, t) = (100.0_f64, 1.5);
+        let f_beta = f.powf(0.5);
+        let expected = 2.5 / f_beta * (1.0 + t * (0.25 * 2.5 * 2.5 / (24.0 * f)
+            + 0.25 * -0.3 * 0.5 * 0.4 * 2.5 / f_beta + (2.0 - 3.0 * 0.09) * 0.16 / 24.0));
+        let vol = p.implied_vol(f, f, t).unwrap();
+        assert!(approx_eq(vol, expected, 1e-12), "vol={} expected={}", vol, expected);
+
+        // and is continuous either side of the money
+        let near = p.implied_vol(f, f * (1.0 + 1e-9), t).unwrap();
+        assert!(approx_eq(near, vol, 1e-9), "near={} vol={}", near, vol);
+    }
+
+    #[test]
+    fn negative_correlation_gives_skew() {
+        let p = SabrParameters::new(0.3, 1.0, -0.5, 0.5).unwrap();
+        let low = p.implied_vol(100.0, 80.0, 1.0).unwrap();
+        let atm = p.implied_vol(100.0, 100.0, 1.0).unwrap();
+        let high = p.implied_vol(100.0, 120.0, 1.0).unwrap();
+        assert!(low > atm && atm > high, "low={} atm={} high={}", low, atm, high);
+    }
+
+    #[test]
+    fn calibration_recovers_parameters() {
+        let (forward, t) = (100.0, 0.75);
+        let p = SabrParameters::new(2.0, 0.5, -0.35, 0.6).unwrap();
+        let smile: Vec<(f64, f64)> = [70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 135.0]
+            .iter().map(|&k| (k, p.implied_vol(forward, k, t).unwrap())).collect();
+
+        let fitted = SabrParameters::calibrate(forward, t, 0.5, &smile).unwrap();
+        assert!(approx_eq(fitted.alpha(), 2.0, 1e-4), "fitted={:?}", fitted);
+        assert!(approx_eq(fitted.rho(), -0.35, 1e-4), "fitted={:?}", fitted);
+        assert!(approx_eq(fitted.nu(), 0.6, 1e-4), "fitted={:?}", fitted);
+        for &(strike, vol) in smile.iter() {
+            let fitted_vol = fitted.implied_vol(forward, strike, t).unwrap();
+            assert!(approx_eq(fitted_vol, vol, 1e-6),
+                "strike={} vol={} fitted={}", strike, vol, fitted_vol);
+        }
+
+        assert!(SabrParameters::calibrate(forward, t, 0.5, &smile[0..2]).is_err());
+    }
+}
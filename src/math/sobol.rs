@@ -0,0 +1,437 @@
+use core::qm;
+
+/// The number of bits in each coordinate, which also limits the number of
+/// points to two to the power of this, less one
+const BITS: usize = 32;
+
+/// The largest number of dimensions supported. This is the size of the
+/// widely used tables of Joe and Kuo (2008), which is far more than any
+/// realistic path needs, and needs primitive polynomials up to degree 18.
+pub const MAX_SOBOL_DIMENSIONS: usize = 21201;
+
+/// Seed for the initial direction numbers. Any seed gives a valid Sobol
+/// sequence, but fixing it makes the sequence reproducible.
+const DIRECTION_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// The number of leading dimensions whose initial direction numbers are
+/// chosen by search, and the number of random candidates tried for each
+const SEARCHED_DIMENSIONS: usize = 128;
+const CANDIDATES: usize = 8;
+
+/// The search judges the two-dimensional projections of the first two to
+/// the power of this many points
+const SEARCH_BITS: usize = 10;
+
+lazy_static! {
+    static ref LEADING_DIRECTIONS: LeadingDirections = search_leading_directions();
+}
+
+/// A Sobol low-discrepancy sequence, generated in Gray code order by the
+/// method of Antonov and Saleev. The first dimension is the van der Corput
+/// sequence, and each further dimension uses the next primitive polynomial
+/// modulo two, in order of degree then value. The polynomials are found by
+/// search, so any number of dimensions up to the limit is supported
+/// without tables.
+///
+/// The initial direction numbers of each dimension are the "regularity
+/// breaking" initialisation of Jäckel (2002), which draws them uniformly
+/// from the odd numbers below their limits. This avoids the poor
+/// two-dimensional projections of the unit initialisation in high
+/// dimensions, but a few unlucky pairs of dimensions are still poor. In the
+/// leading dimensions, which matter most, we therefore draw several
+/// candidates and keep the one whose two-dimensional projections onto the
+/// earlier dimensions have the best t-values, which is the criterion of Joe
+/// and Kuo (2008).
+///
+/// The point at the origin is skipped, so every coordinate is strictly
+/// between zero and one, which allows them to be mapped to gaussians by the
+/// inverse cumulative normal.
+pub struct Sobol {
+    directions: Vec<[u32; BITS]>,
+    point: Vec<u32>,
+    index: u64
+}
+
+impl Sobol {
+    /// Creates a sequence in the given dimensions, skipping the given number
+    /// of leading dimensions, so that independent parts of a simulation can
+    /// each take their own block of dimensions.
+    pub fn new(first_dimension: usize, dimensions: usize)
+        -> Result<Sobol, qm::Error> {
+
+        let end = first_dimension + dimensions;
+        if dimensions == 0 {
+            return Err(qm::Error::new("Sobol sequence needs at least one dimension"))
+        }
+        if end > MAX_SOBOL_DIMENSIONS {
+            return Err(qm::Error::new(&format!("Sobol sequence is limited to {} \
+                dimensions but {} are needed", MAX_SOBOL_DIMENSIONS, end)))
+        }
+
+        // the dimensions after the searched ones are drawn in order, so we
+        // must draw any we skip, to keep the sequence reproducible
+        let leading = &*LEADING_DIRECTIONS;
+        let polynomials = primitive_polynomials(end - 1);
+        let mut state = leading.state;
+        let mut directions = Vec::with_capacity(dimensions);
+        for dimension in 0..end {
+            let v = if dimension < leading.directions.len() {
+                leading.directions[dimension]
+            } else {
+                random_directions(polynomials[dimension - 1], &mut state)
+            };
+            if dimension >= first_dimension {
+                directions.push(v);
+            }
+        }
+
+        Ok(Sobol { directions: directions, point: vec![0; dimensions], index: 0 })
+    }
+
+    /// The number of dimensions of each point
+    pub fn dimensions(&self) -> usize { self.point.len() }
+
+    /// Writes the next point of the sequence, with each coordinate strictly
+    /// between zero and one. Returns an error once the sequence is
+    /// exhausted.
+    pub fn next(&mut self, out: &mut [f64]) -> Result<(), qm::Error> {
+        assert_eq!(out.len(), self.point.len());
+
+        // the bit that changes in the Gray code of the index
+        let bit = (!self.index).trailing_zeros() as usize;
+        if bit >= BITS {
+            return Err(qm::Error::new("Sobol sequence is exhausted"))
+        }
+        self.index += 1;
+
+        let scale = 1.0 / (1_u64 << BITS) as f64;
+        for ((x, v), u) in self.point.iter_mut().zip(self.directions.iter())
+            .zip(out.iter_mut()) {
+            *x ^= v[bit];
+            *u = *x as f64 * scale;
+        }
+        Ok(())
+    }
+}
+
+/// The direction numbers of the leading dimensions, and the state of the
+/// random generator after choosing them
+struct LeadingDirections {
+    directions: Vec<[u32; BITS]>,
+    state: u64
+}
+
+fn search_leading_directions() -> LeadingDirections {
+    let polynomials = primitive_polynomials(SEARCHED_DIMENSIONS - 1);
+    let mut state = DIRECTION_SEED;
+
+    // the van der Corput sequence has all its initial numbers one
+    let mut directions = vec![[0_u32; BITS]];
+    for (k, value) in directions[0].iter_mut().enumerate() {
+        *value = 1 << (BITS - 1 - k);
+    }
+    let mut rows = vec![generator_rows(&directions[0])];
+
+    for polynomial in polynomials.iter() {
+        // the worst t-values come first, so the comparison of these vectors
+        // prefers the candidate with the best worst case
+        let mut best: Option<(Vec<usize>, [u32; BITS])> = None;
+        for _ in 0..CANDIDATES {
+            let candidate = random_directions(*polynomial, &mut state);
+            let candidate_rows = generator_rows(&candidate);
+            let mut t_values: Vec<usize> = rows.iter()
+                .map(|r| t_value(&candidate_rows, r)).collect();
+            t_values.sort_by(|a, b| b.cmp(a));
+            if best.as_ref().map_or(true, |b| t_values < b.0) {
+                best = Some((t_values, candidate));
+            }
+        }
+        let chosen = best.unwrap().1;
+        rows.push(generator_rows(&chosen));
+        directions.push(chosen);
+    }
+
+    LeadingDirections { directions: directions, state: state }
+}
+
+/// Draws the initial direction numbers for the given primitive polynomial,
+/// and extends them by the recurrence of Bratley and Fox (1988). Each
+/// direction number is returned shifted to the top of the word.
+fn random_directions(polynomial: u64, state: &mut u64) -> [u32; BITS] {
+    // the initial numbers are odd and less than two to the power of their
+    // index, counting from one
+    let mut m = [0_u64; BITS];
+    let degree = degree(polynomial);
+    for k in 0..degree.min(BITS) {
+        let limit = 1_u64 << (k + 1);
+        m[k] = (next_random(state) % (limit / 2)) * 2 + 1;
+    }
+
+    for k in degree..BITS {
+        let mut value = m[k - degree] ^ (m[k - degree] << degree);
+        for i in 1..degree {
+            if (polynomial >> (degree - i)) & 1 == 1 {
+                value ^= m[k - i] << i;
+            }
+        }
+        m[k] = value;
+    }
+
+    let mut v = [0_u32; BITS];
+    for (k, value) in v.iter_mut().enumerate() {
+        *value = (m[k] << (BITS - 1 - k)) as u32;
+    }
+    v
+}
+
+/// The leading rows of the generator matrix of a dimension, restricted to
+/// the columns of the first 2^SEARCH_BITS points. Row r holds bit r of the
+/// coordinate, counting from the top, for each direction number.
+fn generator_rows(directions: &[u32; BITS]) -> [u32; SEARCH_BITS] {
+    let mut rows = [0_u32; SEARCH_BITS];
+    for (r, row) in rows.iter_mut().enumerate() {
+        for (k, v) in directions.iter().take(SEARCH_BITS).enumerate() {
+            *row |= ((v >> (BITS - 1 - r)) & 1) << k;
+        }
+    }
+    rows
+}
+
+/// The t-value of the two-dimensional projection of the first
+/// 2^SEARCH_BITS points onto a pair of dimensions. The points are a
+/// (t, m, 2)-net if every box of area 2^(t - m) with sides that are powers
+/// of two holds the same number of points, which means that for any split
+/// of m - t rows between the two dimensions, the rows are linearly
+/// independent. Fewer rows are then independent too, so we work upwards.
+fn t_value(a: &[u32; SEARCH_BITS], b: &[u32; SEARCH_BITS]) -> usize {
+    let mut rows = Vec::with_capacity(SEARCH_BITS);
+    for k in 1..(SEARCH_BITS + 1) {
+        for k1 in 0..(k + 1) {
+            rows.clear();
+            rows.extend_from_slice(&a[..k1]);
+            rows.extend_from_slice(&b[..(k - k1)]);
+            if !independent(&mut rows) {
+                return SEARCH_BITS + 1 - k
+            }
+        }
+    }
+    0
+}
+
+/// Whether the given vectors over the field of two elements are linearly
+/// independent, by Gaussian elimination. The vectors are overwritten.
+fn independent(rows: &mut [u32]) -> bool {
+    for i in 0..rows.len() {
+        if rows[i] == 0 {
+            return false
+        }
+        let pivot = rows[i] & rows[i].wrapping_neg();
+        for j in (i + 1)..rows.len() {
+            if rows[j] & pivot != 0 {
+                rows[j] ^= rows[i];
+            }
+        }
+    }
+    true
+}
+
+/// Returns the first n primitive polynomials modulo two, in order of degree
+/// then value, excluding the polynomial x. Each is represented by its
+/// coefficients as bits, so x^3 + x + 1 is 0b1011.
+pub fn primitive_polynomials(n: usize) -> Vec<u64> {
+    let mut result = Vec::with_capacity(n);
+    let mut degree = 1;
+    while result.len() < n {
+        let order = (1_u64 << degree) - 1;
+        let factors = prime_factors(order);
+
+        // the constant term of a primitive polynomial is always one
+        let mut candidate = (1_u64 << degree) | 1;
+        while candidate < (2_u64 << degree) && result.len() < n {
+            if is_primitive(candidate, degree, order, &factors) {
+                result.push(candidate);
+            }
+            candidate += 2;
+        }
+        degree += 1;
+    }
+    result
+}
+
+/// A polynomial of the given degree is primitive if x has order 2^d - 1
+/// in the field of polynomials modulo it, which means x^(2^d - 1) is one,
+/// and x^((2^d - 1) / q) is not one for any prime factor q.
+fn is_primitive(polynomial: u64, degree: usize, order: u64, factors: &[u64]) -> bool {
+    if degree == 1 {
+        return true
+    }
+    if power_of_x(order, polynomial, degree) != 1 {
+        return false
+    }
+    factors.iter().all(|q| power_of_x(order / q, polynomial, degree) != 1)
+}
+
+/// x to the given power, modulo the polynomial
+fn power_of_x(exponent: u64, polynomial: u64, degree: usize) -> u64 {
+    let mut result = 1_u64;
+    let mut base = reduce(2, polynomial, degree);
+    let mut e = exponent;
+    while e > 0 {
+        if e & 1 == 1 {
+            result = multiply(result, base, polynomial, degree);
+        }
+        base = multiply(base, base, polynomial, degree);
+        e >>= 1;
+    }
+    result
+}
+
+/// The product of two polynomials modulo a third, all modulo two
+fn multiply(a: u64, b: u64, polynomial: u64, degree: usize) -> u64 {
+    let mut product = 0_u64;
+    let mut shifted = a;
+    let mut bits = b;
+    while bits > 0 {
+        if bits & 1 == 1 {
+            product ^= shifted;
+        }
+        shifted = reduce(shifted << 1, polynomial, degree);
+        bits >>= 1;
+    }
+    product
+}
+
+/// Reduces a polynomial of degree at most the given degree
+fn reduce(a: u64, polynomial: u64, degree: usize) -> u64 {
+    if (a >> degree) & 1 == 1 { a ^ polynomial } else { a }
+}
+
+fn degree(polynomial: u64) -> usize {
+    63 - polynomial.leading_zeros() as usize
+}
+
+fn prime_factors(n: u64) -> Vec<u64> {
+    let mut factors = Vec::new();
+    let mut remaining = n;
+    let mut divisor = 2;
+    while divisor * divisor <= remaining {
+        if remaining % divisor == 0 {
+            factors.push(divisor);
+            while remaining % divisor == 0 {
+                remaining /= divisor;
+            }
+        }
+        divisor += 1;
+    }
+    if remaining > 1 {
+        factors.push(remaining);
+    }
+    factors
+}
+
+/// The splitmix64 generator, which is more than good enough for choosing
+/// initial direction numbers, and needs no external state
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_primitive_polynomials() {
+        // the number of primitive polynomials of degree d is the Euler
+        // totient of 2^d - 1, divided by d
+        let polynomials = primitive_polynomials(1 + 1 + 2 + 2 + 6 + 6 + 18 + 16);
+        let mut counts = vec![0; 9];
+        for p in polynomials.iter() {
+            counts[degree(*p)] += 1;
+        }
+        assert_eq!(counts, vec![0, 1, 1, 2, 2, 6, 6, 18, 16]);
+        assert_eq!(&polynomials[..4], &[0b11, 0b111, 0b1011, 0b1101]);
+    }
+
+    #[test]
+    fn first_dimension_is_van_der_corput() {
+        let mut sobol = Sobol::new(0, 1).unwrap();
+        let mut point = [0.0];
+        let expected = [0.5, 0.75, 0.25, 0.375, 0.875, 0.625, 0.125];
+        for e in expected.iter() {
+            sobol.next(&mut point).unwrap();
+            assert_eq!(point[0], *e);
+        }
+    }
+
+    #[test]
+    fn every_dimension_is_stratified() {
+        // the first 2^k - 1 points, plus the origin, put exactly one point
+        // in each interval of width 2^-k, in every dimension
+        let k = 8;
+        let n = (1 << k) - 1;
+        for &first in [0, 1000, MAX_SOBOL_DIMENSIONS - 50].iter() {
+            let dimensions = 50;
+            let mut sobol = Sobol::new(first, dimensions).unwrap();
+            let mut point = vec![0.0; dimensions];
+            let mut seen = vec![vec![false; n + 1]; dimensions];
+            for d in 0..dimensions {
+                seen[d][0] = true;
+            }
+            for _ in 0..n {
+                sobol.next(&mut point).unwrap();
+                for (d, u) in point.iter().enumerate() {
+                    assert!(*u > 0.0 && *u < 1.0);
+                    let cell = (u * (n + 1) as f64) as usize;
+                    assert!(!seen[d][cell], "dimension {} cell {}", first + d, cell);
+                    seen[d][cell] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn integrates_products_accurately() {
+        // the integral of the product of (1 + (u - 1/2) / 2) over the unit
+        // cube is one in any number of dimensions
+        let dimensions = 20;
+        let n = (1 << 14) - 1;
+        let mut sobol = Sobol::new(0, dimensions).unwrap();
+        let mut point = vec![0.0; dimensions];
+        let mut total = 0.0;
+        for _ in 0..n {
+            sobol.next(&mut point).unwrap();
+            total += point.iter().map(|u| 1.0 + 0.5 * (u - 0.5)).product::<f64>();
+        }
+        let integral = total / n as f64;
+        assert!((integral - 1.0).abs() < 1e-3, "integral={}", integral);
+    }
+
+    #[test]
+    fn leading_dimensions_have_good_projections() {
+        // the first two dimensions are always a (0, m, 2)-net, and the
+        // search removes the pairs with t-values of seven or more, which
+        // the random initialisation alone gives among these dimensions
+        let sobol = Sobol::new(0, 40).unwrap();
+        let rows: Vec<[u32; SEARCH_BITS]> = sobol.directions.iter()
+            .map(generator_rows).collect();
+        assert_eq!(t_value(&rows[0], &rows[1]), 0);
+        let mut worst = 0;
+        for i in 0..rows.len() {
+            for j in 0..i {
+                worst = worst.max(t_value(&rows[i], &rows[j]));
+            }
+        }
+        assert!(worst <= 6, "worst t-value={}", worst);
+    }
+
+    #[test]
+    fn rejects_too_many_dimensions() {
+        assert!(Sobol::new(0, 0).is_err());
+        assert!(Sobol::new(MAX_SOBOL_DIMENSIONS - 1, 2).is_err());
+        assert!(Sobol::new(MAX_SOBOL_DIMENSIONS - 1, 1).is_ok());
+    }
+}
@@ -0,0 +1,68 @@
+use core::qm;
+
+/// Solves a tridiagonal system of linear equations by the Thomas algorithm.
+/// Row i of the matrix has lower[i] to the left of the diagonal, diag[i] on
+/// it and upper[i] to the right. The first element of lower and the last
+/// element of upper are ignored.
+///
+/// The algorithm is stable if the matrix is diagonally dominant, as it is
+/// for the implicit finite difference schemes used for pricing.
+pub fn solve_tridiagonal(lower: &[f64], diag: &[f64], upper: &[f64], rhs: &[f64])
+    -> Result<Vec<f64>, qm::Error> {
+
+    let n = diag.len();
+    if lower.len() != n || upper.len() != n || rhs.len() != n {
+        return Err(qm::Error::new("Tridiagonal system has mismatched sizes"))
+    }
+    if n == 0 {
+        return Ok(Vec::new())
+    }
+
+    // forward sweep, eliminating the lower diagonal
+    let mut modified_upper = vec![0.0; n];
+    let mut modified_rhs = vec![0.0; n];
+    let mut pivot = diag[0];
+    for i in 0..n {
+        if i > 0 {
+            pivot = diag[i] - lower[i] * modified_upper[i - 1];
+        }
+        if pivot == 0.0 {
+            return Err(qm::Error::new("Tridiagonal system is singular"))
+        }
+        modified_upper[i] = upper[i] / pivot;
+        let previous = if i > 0 { lower[i] * modified_rhs[i - 1] } else { 0.0 };
+        modified_rhs[i] = (rhs[i] - previous) / pivot;
+    }
+
+    // back substitution
+    let mut solution = modified_rhs;
+    for i in (0..(n - 1)).rev() {
+        solution[i] -= modified_upper[i] * solution[i + 1];
+    }
+    Ok(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn tridiagonal_solves_small_system() {
+        // 2x + y = 3, x + 3y + z = 7, y + 2z = 7 has solution (1, 1, 3)
+        let solution = solve_tridiagonal(&[0.0, 1.0, 1.0], &[2.0, 3.0, 2.0],
+            &[1.0, 1.0, 0.0], &[3.0, 7.0, 7.0]).unwrap();
+        let expected = [1.0, 1.0, 3.0];
+        for (value, expected) in solution.iter().zip(expected.iter()) {
+            assert!(approx_eq(*value, *expected, 1e-12),
+                "value={} expected={}", value, expected);
+        }
+    }
+
+    #[test]
+    fn tridiagonal_rejects_bad_systems() {
+        assert!(solve_tridiagonal(&[0.0], &[1.0, 2.0], &[0.0], &[1.0]).is_err());
+        assert!(solve_tridiagonal(&[0.0, 1.0], &[0.0, 1.0], &[1.0, 0.0], &[1.0, 1.0]).is_err());
+        assert!(solve_tridiagonal(&[], &[], &[], &[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings_with;
+use pricers::analytic::AnalyticModel;
+use models::vol_times;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The BachelierFactory creates a Bachelier (normal) model, given the
+/// timeline of the product(s) to value and the market data. The normal vol
+/// of each underlying is held by the factory, keyed by the id of the
+/// underlying, as the vol surfaces in the market data are log-normal. The
+/// factory also holds the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BachelierFactory {
+    normal_vols: HashMap<String, f64>,
+    number_of_paths: usize
+}
+
+impl BachelierFactory {
+    pub fn new(normal_vols: HashMap<String, f64>, number_of_paths: usize)
+        -> BachelierFactory {
+
+        BachelierFactory { normal_vols: normal_vols,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BachelierFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for BachelierFactory {
+    fn type_id(&self) -> &'static str { "BachelierFactory" }
+}
+
+impl MonteCarloModelFactory for BachelierFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Bachelier::new(timeline, context, &self.normal_vols,
+            self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+
+    fn analytic_model(&self) -> Option<AnalyticModel> {
+        Some(AnalyticModel::Bachelier(self.normal_vols.clone()))
+    }
+}
+
+/// A Bachelier model evolves each underlying as its forward plus a
+/// brownian motion scaled by a constant normal vol, so the underlying at
+/// each observation is normally distributed about its forward and may go
+/// negative. This is the usual model for rates and spreads, where a
+/// log-normal model cannot represent zero or negative levels. Each period
+/// between observations is simulated exactly, and Europeans match the
+/// Bachelier formula in math::optionpricing.
+///
+/// The underlyings are correlated with each other via the correlations in
+/// the market data. As for Heston, the drift comes from the forward curve
+/// and the vol surface only supplies the business-day vol time, so vol
+/// bumps have no effect on the price. Quanto underlyings are not supported.
+#[derive(Clone)]
+pub struct Bachelier {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    normal_vols: Vec<f64>,
+    gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl Bachelier {
+
+    /// Creates a new Bachelier model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the normal vols by underlying id and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        normal_vols: &HashMap<String, f64>,
+        n_paths: usize)
+        -> Result<Bachelier, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("Bachelier does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_vols) =
+            collect_underlyings_with(timeline, |id| {
+                let vol = *normal_vols.get(id).ok_or_else(|| qm::Error::new(
+                    &format!("No normal vol for '{}'", id)))?;
+                if vol < 0.0 {
+                    return Err(qm::Error::new(&format!(
+                        "Normal vol for '{}' must not be negative", id)))
+                }
+                Ok(vol)
+            })?;
+
+        // One step per observation. The gaussians are kept uncorrelated,
+        // so that paths can be refetched with the same random numbers after
+        // any bump, including a correlation bump.
+        let steps = vec![1; observations.len()];
+        let mut source = GaussianSource::from_timeline(timeline);
+        let gaussians = source.fetch(&steps, instruments.len(), n_paths)?;
+        let paths = fetch_paths(&observations, &gaussians,
+            context.as_pricing_context(), &instruments, &asset_vols)?;
+
+        Ok(Bachelier {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            normal_vols: asset_vols,
+            gaussians: gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.gaussians,
+            self.context.as_pricing_context(), &self.instruments,
+            &self.normal_vols)?;
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    normal_vols: &[f64]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    let correlated = correlate_gaussians(context, instruments, observations,
+        &vec![1; observations.len()], gaussians)?;
+
+    for (((instrument, vol), gaussians), path) in instruments.iter()
+        .zip(normal_vols.iter())
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), *vol, context, observations,
+            gaussians, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, normal_vol: f64,
+    context: &PricingContext, observations: &[DateDayFraction],
+    gaussians: ArrayView2<f64>, mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut std_devs = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for time in times.iter() {
+        std_devs.push(normal_vol * (time - previous).sqrt());
+        previous = *time;
+    }
+
+    for (z, mut one_path) in gaussians.outer_iter().zip(path.outer_iter_mut()) {
+        let mut w = 0.0;
+        for i in 0..observations.len() {
+            w += std_devs[i] * z[i];
+            one_path[i] = forwards[i] + w;
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for Bachelier {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The normal vols are parameters of the model, so bumps to vol levels
+    /// leave the paths unchanged, though bumps to forwards, rates or
+    /// correlations change them.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for Bachelier {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Bachelier does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for Bachelier {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for Bachelier {
+
+    /// Bumps the market data, then regenerates all the paths with the same
+    /// random numbers, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::optionpricing;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_expiry;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use risk::marketdata::tests::sample_market_data;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    #[test]
+    fn monte_carlo_matches_bachelier_formula() {
+        // a normal vol large enough that a few paths end negative
+        let normal_vol = 30.0;
+        let mut normal_vols = HashMap::new();
+        normal_vols.insert("BP.L".to_string(), normal_vol);
+        let factory = round_trip(&BachelierFactory::new(normal_vols, 20000));
+        let bachelier = optionpricing::Bachelier::new().unwrap();
+
+        // The paths are seeded. The normal model is simulated exactly, so
+        // the only error is noise, with standard errors up to 0.15.
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), sample_expiry(), &[(0.0, PutOrCall::Put),
+            (70.0, PutOrCall::Put), (100.0, PutOrCall::Call), (130.0, PutOrCall::Call)],
+            0.5, &|terms, strike, put_or_call| {
+                let sqrt_variance = normal_vol * terms.vol_time.sqrt();
+                match put_or_call {
+                    PutOrCall::Call => bachelier.call_price(terms.df, terms.forward,
+                        strike, sqrt_variance),
+                    PutOrCall::Put => bachelier.put_price(terms.df, terms.forward,
+                        strike, sqrt_variance)
+                }
+            });
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut normal_vols = HashMap::new();
+        normal_vols.insert("BP.L".to_string(), 30.0);
+        let mut model = Bachelier::new(&timeline, context, &normal_vols, 100).unwrap();
+
+        // the normal vol is a parameter, not read from the vol surface
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
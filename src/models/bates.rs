@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use core::qm;
+use risk::BumpablePricingContext;
+use math::complex::Complex;
+use math::fourier::CharacteristicFunction;
+use math::fourier;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::heston::Heston;
+use models::heston::HestonParameters;
+use models::merton::LogNormalJumps;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The parameters of the Bates (1996) model for one underlying, which is
+/// Heston with log-normal jumps in the spot:
+///
+///  dS/S = mu(t) dt + sqrt(v) dW1 + (exp(J) - 1) dN
+///  dv = kappa (theta - v) dt + xi sqrt(v) dW2
+///  dW1 dW2 = rho dt
+///
+/// The stochastic variance gives the skew at long expiries, and the jumps
+/// the steep skew at short expiries, which Heston alone cannot match. The
+/// jumps are independent of the diffusion, so the characteristic function
+/// is the product of those of Heston and of the jumps.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BatesParameters {
+    heston: HestonParameters,
+    jumps: LogNormalJumps
+}
+
+impl BatesParameters {
+    pub fn new(heston: HestonParameters, jumps: LogNormalJumps)
+        -> BatesParameters {
+        BatesParameters { heston: heston, jumps: jumps }
+    }
+
+    pub fn heston(&self) -> &HestonParameters { &self.heston }
+    pub fn jumps(&self) -> &LogNormalJumps { &self.jumps }
+
+    /// Semi-analytic price of a European call, by Fourier inversion of the
+    /// characteristic function
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::call_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic price of a European put
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::put_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic prices of a strip of European calls, from a single FFT
+    pub fn call_prices(&self, df: f64, forward: f64, strikes: &[f64], t: f64)
+        -> Result<Vec<f64>, qm::Error> {
+        fourier::fft_call_prices(self, df, forward, strikes, t)
+    }
+
+    /// Semi-analytic prices of a strip of European puts, from a single FFT
+    pub fn put_prices(&self, df: f64, forward: f64, strikes: &[f64], t: f64)
+        -> Result<Vec<f64>, qm::Error> {
+        fourier::fft_put_prices(self, df, forward, strikes, t)
+    }
+}
+
+impl CharacteristicFunction for BatesParameters {
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+        self.heston.characteristic_function(u, t)
+            * (self.jumps.characteristic_exponent(u) * t).exp()
+    }
+}
+
+/// The BatesFactory creates a Heston model with jumps, given the timeline
+/// of the product(s) to value and the market data. As for HestonFactory,
+/// the parameters for each underlying are held by the factory, keyed by
+/// the id of the underlying, together with the maximum time step and the
+/// number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BatesFactory {
+    parameters: HashMap<String, BatesParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl BatesFactory {
+    pub fn new(parameters: HashMap<String, BatesParameters>, time_step: f64,
+        number_of_paths: usize) -> BatesFactory {
+
+        BatesFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BatesFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for BatesFactory {
+    fn type_id(&self) -> &'static str { "BatesFactory" }
+}
+
+impl MonteCarloModelFactory for BatesFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let mut heston = HashMap::new();
+        let mut jumps = HashMap::new();
+        for (id, p) in self.parameters.iter() {
+            heston.insert(id.clone(), p.heston);
+            jumps.insert(id.clone(), p.jumps);
+        }
+
+        let model = Heston::with_jumps(timeline, context, &heston, &jumps,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_expiry;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use risk::marketdata::tests::sample_market_data;
+
+    fn sample_bates() -> BatesParameters {
+        let heston = HestonParameters::new(0.04, 1.5, 0.05, 0.4, -0.6).unwrap();
+        let jumps = LogNormalJumps::new(0.3, -0.1, 0.15).unwrap();
+        BatesParameters::new(heston, jumps)
+    }
+
+    #[test]
+    fn characteristic_function_is_martingale() {
+        let u = Complex::new(0.0, -1.0);
+        let phi = sample_bates().characteristic_function(u, 2.0);
+        assert!(approx_eq(phi.re, 1.0, 1e-12) && approx_eq(phi.im, 0.0, 1e-12),
+            "phi={:?}", phi);
+    }
+
+    #[test]
+    fn without_jumps_is_heston() {
+        let heston = *sample_bates().heston();
+        let p = BatesParameters::new(heston, LogNormalJumps::new(0.0, -0.1, 0.15).unwrap());
+        for &strike in [70.0, 100.0, 130.0].iter() {
+            let call = p.call_price(0.95, 100.0, strike, 1.0).unwrap();
+            let expected = heston.call_price(0.95, 100.0, strike, 1.0).unwrap();
+            assert!(approx_eq(call, expected, 1e-12),
+                "strike={} call={} expected={}", strike, call, expected);
+        }
+    }
+
+    #[test]
+    fn cos_matches_lewis() {
+        // the same characteristic function serves both pricers
+        let p = sample_bates();
+        for &(strike, t) in [(70.0, 0.25_f64), (100.0, 1.0), (130.0, 2.0)].iter() {
+            let put = fourier::cos_put_price(&p, 0.9, 100.0, strike, t).unwrap();
+            let expected = p.put_price(0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(put, expected, 1e-5),
+                "strike={} t={} put={} expected={}", strike, t, put, expected);
+            let call = fourier::cos_call_price(&p, 0.9, 100.0, strike, t).unwrap();
+            let expected = p.call_price(0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(call, expected, 1e-5),
+                "strike={} t={} call={} expected={}", strike, t, call, expected);
+        }
+    }
+
+    #[test]
+    fn fft_matches_lewis() {
+        // a whole strip from one transform, against pricing each strike
+        let p = sample_bates();
+        let strikes = [60.0, 80.0, 95.0, 100.0, 105.0, 120.0, 150.0];
+        for &t in [0.5, 1.0_f64, 2.0].iter() {
+            let calls = p.call_prices(0.9, 100.0, &strikes, t).unwrap();
+            let puts = p.put_prices(0.9, 100.0, &strikes, t).unwrap();
+            for (i, &strike) in strikes.iter().enumerate() {
+                let expected = p.call_price(0.9, 100.0, strike, t).unwrap();
+                assert!(approx_eq(calls[i], expected, 1e-5),
+                    "strike={} t={} call={} expected={}", strike, t, calls[i], expected);
+                let expected = p.put_price(0.9, 100.0, strike, t).unwrap();
+                assert!(approx_eq(puts[i], expected, 1e-5),
+                    "strike={} t={} put={} expected={}", strike, t, puts[i], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn monte_carlo_matches_semi_analytic() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), sample_bates());
+        let factory = round_trip(&BatesFactory::new(parameters, 1.0 / 52.0, 20000));
+
+        // The paths are seeded. The standard errors are up to 0.12, and
+        // the QE variance steps bias the low strike put a little, so allow
+        // a little over three standard errors.
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), sample_expiry(),
+            &[(80.0, PutOrCall::Put), (100.0, PutOrCall::Call), (120.0, PutOrCall::Call)],
+            0.4, &|terms, strike, put_or_call| match put_or_call {
+                PutOrCall::Call => sample_bates().call_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap(),
+                PutOrCall::Put => sample_bates().put_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap()
+            });
+    }
+}
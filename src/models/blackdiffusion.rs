@@ -4,7 +4,7 @@ use std::ops::Deref;
 use std::sync::Arc;
 use rand;
 use rand::StdRng;
-use nalgebra::linalg::Cholesky;
+use rand::SeedableRng;
 use nalgebra::base::DMatrix;
 use statrs::distribution::Distribution;
 use statrs::distribution::Normal;
@@ -15,6 +15,7 @@ use ndarray::Array3;
 use ndarray::ArrayView2;
 use ndarray::ArrayViewMut2;
 use ndarray::Axis;
+use ndarray::ShapeBuilder;
 use core::qm;
 use instruments::Instrument;
 use instruments::MonteCarloContext;
@@ -26,12 +27,33 @@ use risk::Saveable;
 use risk::marketdata::MarketData;
 use risk::dependencies::DependencyCollector;
 use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use data::forward::Forward;
+use data::forward::quanto_adjustment;
+use data::volsurface::VolSurface;
+use math::correlation::correlation_root;
+use math::optionpricing::displaced_sqrt_variance;
 use models::MonteCarloModel;
+use models::AdjointModel;
+use models::AdjointRecorder;
+use models::LikelihoodRatioModel;
+use math::adjoint::Tape;
+use math::adjoint::Var;
+use pricers::analytic::AnalyticModel;
+use models::PathGeneration;
+use models::Threading;
+use core::parallel::for_each_chunk;
+use core::parallel::PATHS_PER_BLOCK;
+use math::sobol::Sobol;
+use math::brownianbridge::BrownianBridge;
+use statrs::function::erf::erfc_inv;
+use statrs::function::erf::erfc;
+use std::f64::consts::SQRT_2;
 use models::MonteCarloTimeline;
 use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::evaluate_weighted_flows;
 use dates::datetime::DateDayFraction;
-use dates::datetime::DateTime;
-use dates::datetime::TimeOfDay;
 use core::factories::TypeId;
 use core::factories::Qrc;
 use serde::Deserialize;
@@ -78,6 +100,10 @@ impl MonteCarloModelFactory for BlackDiffusionFactory {
             self.correlation_substep, self.path_substep, self.number_of_paths)?;
         Ok(Box::new(model))
     }
+
+    fn analytic_model(&self) -> Option<AnalyticModel> {
+        Some(AnalyticModel::Black)
+    }
 }
 
 /// A Black Diffusion model represents the SDE:
@@ -102,12 +128,23 @@ impl MonteCarloModelFactory for BlackDiffusionFactory {
 /// the t parameter altogether, except in fetching the variance over a time
 /// step.
 ///
+/// Where observations are far apart, they are split into substeps. The vol
+/// is piecewise constant over the substeps, following the at the money term
+/// structure of the vol surface (see VolTermStructure), so paths between
+/// observations see the forward vols implied by the surface rather than a
+/// single average vol. The variances at the observations themselves are
+/// unaffected.
+///
 /// A note on the ordering of dimensions in the paths array (and the correlated
 /// gaussians array, which is kept the same for simplicity). The most natural
 /// ordering for constructing the paths is the one we currently use:
 /// path, observation, asset. This may also be the fastest when accessing the
 /// paths, depending on whether instruments vectorise by path in a SIMD sort
 /// of way. (If they do, a better ordering might be observation, asset, path.)
+/// Internally, the paths are built a block of paths at a time, with each
+/// block gathered into observation-major, path-minor buffers, so the
+/// arithmetic is vectorised over the paths of the block whatever the
+/// ordering of the arrays.
 /// The most natural ordering when bumping paths or passing them to the
 /// instrument would have asset on the outside. My proposal is to wait until
 /// there is a reasonable population of instruments, then decide it by
@@ -129,9 +166,14 @@ pub struct BlackDiffusion {
     context: Box<BumpablePricingContext>,
     key: HashMap<String, usize>,
     instruments: Vec<RcInstrument>,
+    quantos: Vec<Option<String>>,
+    shifts: Vec<f64>,
     substepping: Vec<usize>,
+    gaussians: Array3<f64>,
     correlated_gaussians: Array3<f64>,
-    paths: Array3<f64>
+    paths: Array3<f64>,
+    weights: Option<Array1<f64>>,
+    threads: usize
 }
 
 impl BlackDiffusion {
@@ -154,10 +196,28 @@ impl BlackDiffusion {
         n_paths: usize)
         -> Result<BlackDiffusion, qm::Error> {
 
+        BlackDiffusion::with_shifts(timeline, context, correlation_substep,
+            path_substep, n_paths, &HashMap::new())
+    }
+
+    /// Create a new BlackDiffusion model where the underlyings given by id
+    /// in the shifts map are displaced diffusions: the underlying plus its
+    /// shift is log-normal. Other underlyings are unshifted. See
+    /// models::displaceddiffusion for how the vols are calibrated.
+    pub fn with_shifts(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        _correlation_substep: usize,
+        path_substep: f64,
+        n_paths: usize,
+        shifts: &HashMap<String, f64>)
+        -> Result<BlackDiffusion, qm::Error> {
+
         // key to all observations and all instruments
         let mut observations = Vec::new();
         let mut key = HashMap::new();
         let mut instruments = Vec::new();
+        let mut quantos = Vec::new();
+        let mut asset_shifts = Vec::new();
         for (asset, obs) in timeline.observations().iter() {
 
             // at present, we just insist that all observations are the same
@@ -171,6 +231,8 @@ impl BlackDiffusion {
             // store the assets in the order we are told about them
             key.insert(asset.id().to_string(), instruments.len());
             instruments.push(asset.clone());
+            quantos.push(timeline.quantos().get(asset).cloned());
+            asset_shifts.push(shifts.get(asset.id()).cloned().unwrap_or(0.0));
         }
 
         // Calculate the substepping required, given the path_substep
@@ -180,14 +242,28 @@ impl BlackDiffusion {
 
         // Populate the correlated gaussians. (Really, this should be redone
         // whenever any forward or vol changes, but that would slow all 
-        // risks down, and it is only a second order effect.)
-        let correlated_gaussians = fetch_correlated_gaussians(
-            context.as_pricing_context(), &instruments,
-            correlation_substep, &substepping, n_paths)?;
+        // risks down, and it is only a second order effect.) The
+        // uncorrelated gaussians are kept, so that a correlation bump can
+        // reuse the same random numbers.
+        let threads = timeline.threading().threads();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let mut gaussians = source.fetch(&substepping, instruments.len(), n_paths)?;
+
+        // With importance sampling, the shifted gaussians are kept, so that
+        // bumps see the same paths and the weights stay valid.
+        let weights = match timeline.importance_levels() {
+            Some(levels) => shift_gaussians(context.as_pricing_context(),
+                &instruments, &observations, &substepping, levels,
+                &mut gaussians)?,
+            None => None
+        };
+        let correlated_gaussians = correlate_gaussians_threaded(
+            context.as_pricing_context(), &instruments, &observations,
+            &substepping, &gaussians, threads)?;
 
         let paths = fetch_paths(&observations, &correlated_gaussians,
-            context.as_pricing_context(), &instruments, 
-            &substepping, n_paths)?;
+            context.as_pricing_context(), &instruments, &quantos,
+            &asset_shifts, &substepping, n_paths, threads)?;
 
         // create the model with these paths and gaussians
         Ok(BlackDiffusion { 
@@ -196,9 +272,14 @@ impl BlackDiffusion {
             context: context,
             key: key,
             instruments: instruments,
+            quantos: quantos,
+            shifts: asset_shifts,
             substepping: substepping,
+            gaussians: gaussians,
             correlated_gaussians: correlated_gaussians,
-            paths: paths })
+            paths: paths,
+            weights: weights,
+            threads: threads })
     }
 
     /// Refetch a single asset
@@ -219,10 +300,12 @@ impl BlackDiffusion {
                 s.insert(*asset, path.to_owned());
             }
             fetch_path(self.instruments[*asset].deref(), 
+                self.quantos[*asset].as_ref().map(|s| s.as_str()),
+                self.shifts[*asset],
                 self.context.as_pricing_context(), &self.observations,
                 self.correlated_gaussians.subview(Axis(2), *asset),
                 &self.substepping,
-                path)?;
+                path, self.threads)?;
 
         } else {
             return Err(qm::Error::new("Failed to find asset"))
@@ -231,6 +314,61 @@ impl BlackDiffusion {
         Ok(true)
     }
 
+    /// Refetch the paths affected by a change to an FX spot. These are the
+    /// paths of the currency pair itself, if it is simulated, and of any
+    /// assets that are quantoed using the rate.
+    pub fn refetch_fx(&mut self, fx_id: &str, bumped: bool,
+        mut saved_paths: Option<&mut HashMap<usize, Array2<f64>>>) -> Result<bool, qm::Error> {
+
+        if !bumped {
+            return Ok(false)
+        }
+
+        let ids: Vec<String> = self.instruments.iter().zip(self.quantos.iter())
+            .filter(|&(instrument, quanto)| instrument.id() == fx_id
+                || quanto.as_ref().map_or(false, |q| q == fx_id))
+            .map(|(instrument, _)| instrument.id().to_string())
+            .collect();
+        for id in ids.iter() {
+            self.refetch(id, bumped, saved_paths.as_mut().map(|s| &mut **s))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Recorrelate the gaussians after a correlation bump, and refetch all
+    /// paths. The same uncorrelated random numbers are used, so that the
+    /// change in price is not swamped by noise.
+    pub fn recorrelate(&mut self, bumped: bool,
+        saved_gaussians: Option<&mut Option<Array3<f64>>>,
+        saved_paths: Option<&mut HashMap<usize, Array2<f64>>>) -> Result<bool, qm::Error> {
+
+        if !bumped {
+            return Ok(false)
+        }
+
+        let correlated_gaussians = correlate_gaussians_threaded(
+            self.context.as_pricing_context(), &self.instruments,
+            &self.observations, &self.substepping, &self.gaussians,
+            self.threads)?;
+        let old = ::std::mem::replace(&mut self.correlated_gaussians,
+            correlated_gaussians);
+        if let Some(s) = saved_gaussians {
+            if s.is_none() {
+                *s = Some(old);
+            }
+        }
+
+        if let Some(s) = saved_paths {
+            for asset in 0..self.instruments.len() {
+                s.entry(asset).or_insert_with(||
+                    self.paths.subview(Axis(2), asset).to_owned());
+            }
+        }
+        self.refetch_all()?;
+        Ok(true)
+    }
+
     /// Refetch all paths for all assets. Note that this does not refetch the
     /// correlated gaussians, so does not work for a correlation bump. It also
     /// assumes the form of the instrument(s) being priced is unchanged.
@@ -240,7 +378,8 @@ impl BlackDiffusion {
 
         self.paths = fetch_paths(&self.observations, &self.correlated_gaussians,
             self.context.as_pricing_context(), &self.instruments,
-            &self.substepping, n_paths)?;
+            &self.quantos, &self.shifts, &self.substepping, n_paths,
+            self.threads)?;
         Ok(())
     }
 }
@@ -290,61 +429,108 @@ pub fn calculate_substepping(
     Ok(substepping)
 }
 
-/// Fetch the correlated gaussians. In other words, a set of random
-/// numbers weighted by a gaussian distribution with correlations defined
-/// by the correlation matrix in the pricing context.
-pub fn fetch_correlated_gaussians(
-    context: &PricingContext,
-    instruments: &Vec<RcInstrument>,
-    _correlation_substep: usize,
-    substepping: &[usize],
-    n_paths: usize) -> Result<Array3<f64>, qm::Error> {
-
-    // calculate how many substeps we need altogether
-    let n_steps = substepping.iter().sum();
-    assert!(n_steps > 0);
-
-    // create a 3d tensor indexed by path, then observation, then asset
-    let n_assets = instruments.len();
-    assert!(n_assets > 0);
-    assert!(n_paths > 0);
-    let mut result = Array3::<f64>::zeros((n_paths, n_steps, n_assets));
+/// The at the money variance term structure of a vol surface, sampled at
+/// the end of each substep along a timeline. Within each observation
+/// interval, the substeps are evenly spaced in calendar days, and the vol is
+/// piecewise constant between them. The weights give the fraction of the
+/// variance of each observation interval that falls in each substep, so
+/// that models can follow the term structure of the surface while matching
+/// whatever variances they need at the observations.
+#[derive(Clone, Debug)]
+pub struct VolTermStructure {
+    points: Vec<DateDayFraction>,
+    variances: Vec<f64>,
+    weights: Vec<f64>
+}
 
-    // TODO we currently just use the raw correlations, but we ought to
-    // calculate correlations between the timeline points. If there is a
-    // term structure to vol, this is likely to be different, even with
-    // flat correlation structure.
+impl VolTermStructure {
+    /// Samples the vol surface at the forward given by the forward curve,
+    /// at the end of each substep. The substepping gives the number of
+    /// substeps up to each observation, and the first interval starts at
+    /// the base date of the vol surface.
+    pub fn new(surface: &VolSurface, forward: &Forward,
+        observations: &[DateDayFraction], substepping: &[usize])
+        -> Result<VolTermStructure, qm::Error> {
+
+        assert_eq!(observations.len(), substepping.len());
+        let n_steps = substepping.iter().sum();
+        let mut points = Vec::with_capacity(n_steps);
+        let mut variances = Vec::with_capacity(n_steps);
+        let mut weights = Vec::with_capacity(n_steps);
+
+        let mut start = surface.base_date();
+        let mut start_variance = 0.0;
+        for (obs, substeps) in observations.iter().zip(substepping.iter()) {
+            let end_variance = surface.variance(*obs, forward.forward(obs.date())?)?
+                .max(start_variance);
+
+            // intermediate points strictly between the dates, clamped so the
+            // variance is monotonic within the interval
+            let days = obs.date() - start.date();
+            let mut previous = start_variance;
+            for k in 1..*substeps {
+                let date = start.date() + days * (k as i32) / (*substeps as i32);
+                let variance = if date > start.date() && date < obs.date() {
+                    let point = DateDayFraction::new(date, obs.day_fraction());
+                    points.push(point);
+                    surface.variance(point, forward.forward(date)?)?
+                        .max(previous).min(end_variance)
+                } else {
+                    points.push(if date <= start.date() { start } else { *obs });
+                    previous
+                };
+                variances.push(variance);
+                previous = variance;
+            }
+            points.push(*obs);
+            variances.push(end_variance);
+
+            // the fraction of the interval variance in each substep, or
+            // equal weights if there is no variance in the interval
+            let first = variances.len() - substeps;
+            let total = end_variance - start_variance;
+            let mut previous = start_variance;
+            for variance in variances[first..].iter() {
+                weights.push(if total > 0.0 {
+                    (variance - previous) / total
+                } else {
+                    1.0 / (*substeps as f64)
+                });
+                previous = *variance;
+            }
 
-    // Create a correlation matrix. Starting with an identity matrix (eye)
-    // fills in the diagonals.
-    let mut correl = Array2::<f64>::eye(n_assets);
-    for i in 0..n_assets {
-        let first = instruments[i].deref();
-        for j in 0..i {
-            let second = instruments[j].deref();
-            let c = context.correlation(first, second)?;
-            correl[(i, j)] = c;
-            correl[(j, i)] = c;
+            start = *obs;
+            start_variance = end_variance;
         }
+
+        Ok(VolTermStructure { points: points, variances: variances,
+            weights: weights })
     }
 
-    // Use Cholesky decomposition to create a matrix to use for generating
-    // correlated gaussians. (There are alternative ways of producing
-    // copulae. This should be user-settable.)
+    /// The end of each substep
+    pub fn points(&self) -> &[DateDayFraction] { &self.points }
 
-    // This is what it would look like if we could use ndarray_linalg
-    // if let Some(cholesky) = correl.cholesky(UPLO::Lower) {
+    /// The at the money variance from the base date to the end of each
+    /// substep
+    pub fn variances(&self) -> &[f64] { &self.variances }
 
-    // convert to a DMatrix
-    let slice = correl.as_slice().ok_or_else(|| qm::Error::new(
-        "Correlation cannot be accessed as a slice"))?;
-    let correld = DMatrix::from_column_slice(n_assets, n_assets, slice);
-    let rootd = Cholesky::new(correld).ok_or_else(|| qm::Error::new(
-        "Correlation matrix is not positive semi-definite"))?;
+    /// The fraction of the variance of its observation interval that falls
+    /// in each substep. The weights within each interval add up to one.
+    pub fn weights(&self) -> &[f64] { &self.weights }
+}
 
-    // convert back to an Array2
-    let root_slice = rootd.unpack().as_slice().to_vec();
-    let root = Array::from_shape_vec((n_assets, n_assets), root_slice)?;
+/// Fetch uncorrelated gaussians. In other words, a set of random numbers
+/// weighted by a gaussian distribution, indexed by path, then substep, then
+/// asset.
+pub fn fetch_gaussians(substepping: &[usize], n_assets: usize, n_paths: usize)
+    -> Array3<f64> {
+
+    // calculate how many substeps we need altogether
+    let n_steps = substepping.iter().sum();
+    assert!(n_steps > 0);
+    assert!(n_assets > 0);
+    assert!(n_paths > 0);
+    let mut result = Array3::<f64>::zeros((n_paths, n_steps, n_assets));
 
     // Use the standard library random number generator for now. (Look
     // at better generators such as Mersenne Twister, or better still
@@ -357,32 +543,499 @@ pub fn fetch_correlated_gaussians(
     // sequences like Sobol.
     let normal = Normal::new(0.0, 1.0).unwrap();
 
-    let mut draws = Array1::zeros(n_assets);
+    for draw in result.iter_mut() {
+        *draw = normal.sample::<StdRng>(&mut rand);
+    }
+
+    result
+}
+
+/// Fetch uncorrelated gaussians in antithetic pairs, so that each odd path
+/// has the negated gaussians of the even path before it. Correlating the
+/// gaussians is linear, so the pairs survive correlation, and because bumps
+/// reuse the uncorrelated gaussians, they survive bumps too.
+pub fn fetch_antithetic_gaussians(substepping: &[usize], n_assets: usize,
+    n_paths: usize) -> Result<Array3<f64>, qm::Error> {
+
+    check_antithetic(n_paths)?;
+    Ok(antithetic_pairs(&fetch_gaussians(substepping, n_assets, n_paths / 2)))
+}
+
+fn check_antithetic(n_paths: usize) -> Result<(), qm::Error> {
+    if n_paths % 2 != 0 {
+        return Err(qm::Error::new(&format!("Antithetic sampling needs an even \
+            number of paths, not {}", n_paths)))
+    }
+    Ok(())
+}
+
+fn antithetic_pairs(half: &Array3<f64>) -> Array3<f64> {
+    let shape = half.dim();
+    let mut result = Array3::<f64>::zeros((2 * shape.0, shape.1, shape.2));
+    for (i, draws) in half.outer_iter().enumerate() {
+        result.subview_mut(Axis(0), 2 * i).assign(&draws);
+        result.subview_mut(Axis(0), 2 * i + 1).assign(&draws.mapv(|z| -z));
+    }
+    result
+}
 
-    for mut path in result.outer_iter_mut() {
-        for mut step in path.outer_iter_mut() {
+/// Fetch uncorrelated gaussians, as fetch_gaussians, but from the given
+/// seed. Each block of paths is drawn from its own generator, keyed by the
+/// seed, the stream of the pricer (see models::Threading), the substream,
+/// which distinguishes the fetches of one model, and the block. The blocks
+/// are drawn on up to the given number of threads, and the result does not
+/// depend on how many.
+pub fn fetch_seeded_gaussians(substepping: &[usize], n_assets: usize,
+    n_paths: usize, seed: u64, stream: u64, substream: usize, threads: usize)
+    -> Result<Array3<f64>, qm::Error> {
 
-            // create uncorrelated gaussians
+    let n_steps = substepping.iter().sum();
+    assert!(n_steps > 0);
+    assert!(n_assets > 0);
+    assert!(n_paths > 0);
+    let mut result = Array3::<f64>::zeros((n_paths, n_steps, n_assets));
+    {
+        let blocks: Vec<_> = result.axis_chunks_iter_mut(Axis(0), PATHS_PER_BLOCK)
+            .collect();
+        for_each_chunk(threads, blocks, |block, mut draws| {
+            let mut rand = seeded_generator(seed, stream, substream, block);
+            let normal = Normal::new(0.0, 1.0).unwrap();
             for draw in draws.iter_mut() {
                 *draw = normal.sample::<StdRng>(&mut rand);
             }
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+/// A generator keyed by a seed, stream, substream and block. The seed and
+/// stream are split into words, so the generators are the same on 32 and 64
+/// bit platforms.
+pub fn seeded_generator(seed: u64, stream: u64, substream: usize, block: usize)
+    -> StdRng {
+    let key = [(seed & 0xffff_ffff) as usize, (seed >> 32) as usize,
+        (stream & 0xffff_ffff) as usize, (stream >> 32) as usize,
+        substream, block];
+    StdRng::from_seed(&key[..])
+}
+
+/// A source of uncorrelated gaussians for a model, generated as set by the
+/// timeline. With a Sobol sequence, each fetch takes the next block of
+/// dimensions, so the gaussians of different factors of a model, such as
+/// spot and variance, are independent of each other. The dimensions of
+/// each fetch are ordered by substep, then asset, or with the Brownian
+/// bridge by order of importance, then asset, so the best dimensions go
+/// to the earliest steps or the largest features of every asset.
+///
+/// Pseudo-random gaussians are seeded as the threading says. With a seed,
+/// or more than one thread, each fetch takes the next substream of the
+/// stream (see fetch_seeded_gaussians), so the factors are again
+/// independent. Stratified gaussians take a second substream, for the
+/// random order of the strata.
+///
+/// Overlays that wrap a model, such as stochastic discounting, draw their
+/// own pseudo-random gaussians, from a source with the threading of the
+/// overlay (see models::Threading::for_overlay), as they cannot know which
+/// dimensions the model has used.
+pub struct GaussianSource {
+    path_generation: PathGeneration,
+    next_dimension: usize,
+    threads: usize,
+    seed: Option<u64>,
+    stream: u64,
+    next_substream: usize
+}
+
+impl GaussianSource {
+    pub fn new(path_generation: PathGeneration) -> GaussianSource {
+        GaussianSource::with_threading(path_generation, Threading::default())
+    }
+
+    /// Creates a source that generates gaussians as set by the timeline
+    pub fn from_timeline(timeline: &MonteCarloTimeline) -> GaussianSource {
+        GaussianSource::with_threading(timeline.path_generation(),
+            timeline.threading())
+    }
+
+    pub fn with_threading(path_generation: PathGeneration, threading: Threading)
+        -> GaussianSource {
+
+        let threads = threading.threads();
+        let seed = match threading.seed() {
+            Some(seed) => Some(seed),
+            None if threads > 1 => Some(rand::random()),
+            None => None
+        };
+        GaussianSource { path_generation: path_generation, next_dimension: 0,
+            threads: threads, seed: seed, stream: threading.stream(),
+            next_substream: 0 }
+    }
+
+    fn pseudo_random(&mut self, substepping: &[usize], n_assets: usize,
+        n_paths: usize) -> Result<Array3<f64>, qm::Error> {
+
+        match self.seed {
+            Some(seed) => {
+                let substream = self.next_substream;
+                self.next_substream += 1;
+                fetch_seeded_gaussians(substepping, n_assets, n_paths, seed,
+                    self.stream, substream, self.threads)
+            },
+            None => Ok(fetch_gaussians(substepping, n_assets, n_paths))
+        }
+    }
+
+    /// A generator for the random choices of a fetch, other than its
+    /// gaussians, seeded from the next substream if there is a seed
+    fn generator(&mut self) -> StdRng {
+        match self.seed {
+            Some(seed) => {
+                let substream = self.next_substream;
+                self.next_substream += 1;
+                seeded_generator(seed, self.stream, substream, 0)
+            },
+            None => StdRng::new().unwrap()
+        }
+    }
+
+    /// Pseudo-random gaussians, stratified so that each dimension has one
+    /// path in each of n_paths strata of equal probability, with the strata
+    /// in random order. Unless every dimension is stratified, as in a Latin
+    /// hypercube, only the first gaussian of each asset is, and it is used
+    /// with the Brownian bridge to fix the end of the path.
+    fn stratified(&mut self, substepping: &[usize], n_assets: usize,
+        n_paths: usize, latin_hypercube: bool) -> Result<Array3<f64>, qm::Error> {
+        use rand::Rng;
+
+        let mut result = self.pseudo_random(substepping, n_assets, n_paths)?;
+        let mut rand = self.generator();
+        let n_steps = result.shape()[1];
+        let mut strata: Vec<usize> = (0..n_paths).collect();
+        if latin_hypercube {
+            for asset in 0..n_assets {
+                for step in 0..n_steps {
+                    rand.shuffle(&mut strata);
+                    for (path, stratum) in strata.iter().enumerate() {
+                        let draw = &mut result[[path, step, asset]];
+                        *draw = stratify(*draw, *stratum, n_paths);
+                    }
+                }
+            }
+            return Ok(result)
+        }
+
+        let bridge = BrownianBridge::new(n_steps);
+        let mut normals = vec![0.0; n_steps];
+        let mut increments = vec![0.0; n_steps];
+        for asset in 0..n_assets {
+            rand.shuffle(&mut strata);
+            for (path, stratum) in strata.iter().enumerate() {
+                for step in 0..n_steps {
+                    normals[step] = result[[path, step, asset]];
+                }
+                normals[0] = stratify(normals[0], *stratum, n_paths);
+                bridge.increments(&normals, &mut increments);
+                for step in 0..n_steps {
+                    result[[path, step, asset]] = increments[step];
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Fetch uncorrelated gaussians, indexed by path, then substep, then
+    /// asset.
+    pub fn fetch(&mut self, substepping: &[usize], n_assets: usize, n_paths: usize)
+        -> Result<Array3<f64>, qm::Error> {
+
+        let bridge = match self.path_generation {
+            PathGeneration::PseudoRandom =>
+                return self.pseudo_random(substepping, n_assets, n_paths),
+            PathGeneration::Antithetic => {
+                check_antithetic(n_paths)?;
+                let half = self.pseudo_random(substepping, n_assets, n_paths / 2)?;
+                return Ok(antithetic_pairs(&half))
+            },
+            PathGeneration::Stratified =>
+                return self.stratified(substepping, n_assets, n_paths, false),
+            PathGeneration::LatinHypercube =>
+                return self.stratified(substepping, n_assets, n_paths, true),
+            PathGeneration::Sobol => false,
+            PathGeneration::SobolBrownianBridge => true
+        };
+
+        let n_steps = substepping.iter().sum();
+        assert!(n_steps > 0);
+        assert!(n_assets > 0);
+        assert!(n_paths > 0);
+        let dimensions = n_steps * n_assets;
+        let mut sobol = Sobol::new(self.next_dimension, dimensions)?;
+        self.next_dimension += dimensions;
+
+        let bridge = if bridge { Some(BrownianBridge::new(n_steps)) } else { None };
+        let mut result = Array3::<f64>::zeros((n_paths, n_steps, n_assets));
+        let mut point = vec![0.0; dimensions];
+        let mut normals = vec![0.0; n_steps];
+        let mut increments = vec![0.0; n_steps];
+        for mut path in result.outer_iter_mut() {
+            sobol.next(&mut point)?;
+            for u in point.iter_mut() {
+                *u = -SQRT_2 * erfc_inv(2.0 * *u);
+            }
+            for asset in 0..n_assets {
+                for step in 0..n_steps {
+                    normals[step] = point[step * n_assets + asset];
+                }
+                match bridge {
+                    Some(ref bridge) => bridge.increments(&normals, &mut increments),
+                    None => increments.copy_from_slice(&normals)
+                }
+                for step in 0..n_steps {
+                    path[[step, asset]] = increments[step];
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Moves a gaussian into the given one of n strata of equal probability,
+/// keeping its quantile within the stratum, so that a gaussian drawn at
+/// random lands uniformly within the stratum
+fn stratify(draw: f64, stratum: usize, n: usize) -> f64 {
+    let u = (stratum as f64 + 0.5 * erfc(-draw / SQRT_2)) / n as f64;
+    -SQRT_2 * erfc_inv(2.0 * u)
+}
+
+/// Shifts the uncorrelated gaussians of the underlyings that have importance
+/// levels, and returns the likelihood ratio of each path, or None if no
+/// underlying is shifted.
+///
+/// Each shift is chosen so that the median of the path reaches the level
+/// at its observation, and is spread over the steps before it in
+/// proportion to their vols, which is close to optimal for a payoff that
+/// depends on reaching the level. Any shift gives an unbiased price, as the
+/// paths are weighted by the ratio of the densities of their gaussians with
+/// and without the shift. The shift is applied before correlation, so an
+/// underlying correlated with earlier ones moves a little less than
+/// intended, and drags the later ones with it.
+pub fn shift_gaussians(
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    observations: &[DateDayFraction],
+    substepping: &[usize],
+    levels: &HashMap<RcInstrument, (DateDayFraction, f64)>,
+    gaussians: &mut Array3<f64>) -> Result<Option<Array1<f64>>, qm::Error> {
+
+    assert_eq!(observations.len(), substepping.len());
+    let n_paths = gaussians.shape()[0];
+    let mut log_weights = Array1::<f64>::zeros(n_paths);
+    let mut shifted = false;
+    for (asset, instrument) in instruments.iter().enumerate() {
+        let (date_time, level) = match levels.get(instrument) {
+            Some(&(date_time, level)) => (date_time, level),
+            None => continue
+        };
+
+        // the observations up to the one where the level matters
+        let n_obs = observations.iter().take_while(|obs| **obs <= date_time).count();
+        if n_obs == 0 {
+            continue;
+        }
+        let last = observations[n_obs - 1];
+        let forward_curve = context.forward_curve(instrument.deref(), last.date())?;
+        let vol_surface = context.vol_surface(instrument.deref(), last.date(),
+            &|| Ok(forward_curve.clone()))?;
+        let mut variances = Vec::with_capacity(n_obs);
+        for obs in observations[..n_obs].iter() {
+            let fwd = forward_curve.forward(obs.date())?;
+            variances.push(vol_surface.variance(*obs, fwd)?);
+        }
+
+        // the drift, per unit of variance, that takes the median to the level
+        let forward = forward_curve.forward(last.date())?;
+        let displacement = vol_surface.displacement(last.date())?;
+        let variance = variances[n_obs - 1];
+        if variance <= 0.0 || level <= displacement || forward <= displacement {
+            continue;
+        }
+        let drift = (((level - displacement) / (forward - displacement)).ln()
+            + 0.5 * variance) / variance;
+
+        let mut shifts = Vec::new();
+        let mut previous = 0.0;
+        for (var, substeps) in variances.iter().zip(substepping.iter()) {
+            let substep_variance = (var - previous).max(0.0) / *substeps as f64;
+            for _ in 0..*substeps {
+                shifts.push(drift * substep_variance.sqrt());
+            }
+            previous = *var;
+        }
+        let norm: f64 = shifts.iter().map(|shift| shift * shift).sum();
+
+        for (mut path, log_weight) in gaussians.outer_iter_mut()
+            .zip(log_weights.iter_mut()) {
+            for (step, shift) in shifts.iter().enumerate() {
+                let draw = path[[step, asset]];
+                *log_weight -= shift * draw;
+                path[[step, asset]] = draw + shift;
+            }
+            *log_weight -= 0.5 * norm;
+        }
+        shifted = true;
+    }
+
+    if shifted {
+        Ok(Some(log_weights.mapv(f64::exp)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Fetch the correlated gaussians. In other words, the given uncorrelated
+/// gaussians, transformed to have the correlations defined by the pricing
+/// context. The gaussians for each observation are divided into substeps
+/// as given, and each substep takes the average correlations over the
+/// period since the previous observation, so a term structure of
+/// correlations is respected.
+pub fn correlate_gaussians(
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    observations: &[DateDayFraction],
+    substepping: &[usize],
+    gaussians: &Array3<f64>) -> Result<Array3<f64>, qm::Error> {
+
+    correlate_gaussians_threaded(context, instruments, observations,
+        substepping, gaussians, 1)
+}
+
+/// Fetch the correlated gaussians, as correlate_gaussians, correlating the
+/// blocks of paths on up to the given number of threads.
+pub fn correlate_gaussians_threaded(
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    observations: &[DateDayFraction],
+    substepping: &[usize],
+    gaussians: &Array3<f64>,
+    threads: usize) -> Result<Array3<f64>, qm::Error> {
+
+    // create a 3d tensor indexed by path, then observation, then asset
+    let n_assets = instruments.len();
+    assert_eq!(gaussians.shape()[2], n_assets);
+    assert_eq!(observations.len(), substepping.len());
+    assert_eq!(gaussians.shape()[1], substepping.iter().sum::<usize>());
+    let mut result = Array3::<f64>::zeros(gaussians.dim());
+
+    // the root of the correlation matrix for each observation, and which
+    // of them applies to each substep
+    let mut roots = Vec::with_capacity(observations.len());
+    let mut substep_roots = Vec::with_capacity(gaussians.shape()[1]);
+    let mut from = context.spot_date();
+    let mut previous: Option<(Array2<f64>, Array2<f64>)> = None;
+    for (observation, substeps) in observations.iter().zip(substepping.iter()) {
+        let to = observation.date();
+
+        // Create a correlation matrix. Starting with an identity matrix
+        // (eye) fills in the diagonals.
+        let mut correl = Array2::<f64>::eye(n_assets);
+        for i in 0..n_assets {
+            let first = instruments[i].deref();
+            for j in 0..i {
+                let second = instruments[j].deref();
+                let c = context.average_correlation(first, second, from, to)?;
+                correl[(i, j)] = c;
+                correl[(j, i)] = c;
+            }
+        }
 
-            // turn them into correlated gaussians. TODO ensure that this
-            // multiplication does not result in an allocation.
-            step.assign(&root.dot(&draws));
+        // the correlations are normally the same for every observation, so
+        // only decompose them when they change
+        let root = match previous {
+            Some((ref last, ref root)) if *last == correl => root.clone(),
+            _ => correlation_matrix_root(&correl)?
+        };
+
+        for _ in 0..*substeps {
+            substep_roots.push(roots.len());
         }
+        roots.push(root.clone());
+        from = to;
+        previous = Some((correl, root));
+    }
+
+    // Correlate a block of paths at a time, one substep at a time. The
+    // draws for the substep are gathered into rows by asset, so that each
+    // correlated row is a sum of contiguous rows scaled by the root, which
+    // vectorises, rather than a small matrix product per path.
+    {
+        let blocks: Vec<_> = result.axis_chunks_iter_mut(Axis(0), PATHS_PER_BLOCK)
+            .zip(gaussians.axis_chunks_iter(Axis(0), PATHS_PER_BLOCK)).collect();
+        for_each_chunk(threads, blocks, |_, (mut block, draws)| {
+            let n_block = draws.shape()[0];
+            let mut inputs = Array2::<f64>::zeros((n_assets, n_block));
+            let mut outputs = Array2::<f64>::zeros((n_assets, n_block));
+            for (substep, root) in substep_roots.iter().enumerate() {
+                let root = &roots[*root];
+                for (mut input, column) in inputs.outer_iter_mut().zip(
+                    draws.subview(Axis(1), substep).axis_iter(Axis(1))) {
+                    input.assign(&column);
+                }
+                for (asset, mut output) in outputs.outer_iter_mut().enumerate() {
+                    output.fill(0.0);
+                    for (coefficient, input) in root.row(asset).iter()
+                        .zip(inputs.outer_iter()) {
+                        if *coefficient != 0.0 {
+                            output.scaled_add(*coefficient, &input);
+                        }
+                    }
+                }
+                for (output, mut column) in outputs.outer_iter().zip(
+                    block.subview_mut(Axis(1), substep).axis_iter_mut(Axis(1))) {
+                    column.assign(&output);
+                }
+            }
+            Ok(())
+        })?;
     }
 
     Ok(result)
 }
 
+/// Use Cholesky decomposition to create a matrix to use for generating
+/// correlated gaussians. (There are alternative ways of producing copulae.
+/// This should be user-settable.) Correlations supplied or bumped pair by
+/// pair may not form a valid matrix, in which case the nearest valid matrix
+/// is used.
+fn correlation_matrix_root(correl: &Array2<f64>) -> Result<Array2<f64>, qm::Error> {
+
+    // This is what it would look like if we could use ndarray_linalg
+    // if let Some(cholesky) = correl.cholesky(UPLO::Lower) {
+
+    // convert to a DMatrix
+    let n_assets = correl.shape()[0];
+    let slice = correl.as_slice().ok_or_else(|| qm::Error::new(
+        "Correlation cannot be accessed as a slice"))?;
+    let correld = DMatrix::from_column_slice(n_assets, n_assets, slice);
+    let rootd = correlation_root(correld)?;
+
+    // convert back to an Array2. DMatrix is stored in column-major order,
+    // so the shape must be column-major too, otherwise we would get the
+    // transpose of the lower-triangular root.
+    let root_slice = rootd.as_slice().to_vec();
+    Ok(Array::from_shape_vec((n_assets, n_assets).f(), root_slice)?)
+}
+
 pub fn fetch_paths(
     observations: &[DateDayFraction],
     correlated_gaussians: &Array3<f64>,
     context: &PricingContext,
     instruments: &Vec<RcInstrument>,
+    quantos: &[Option<String>],
+    shifts: &[f64],
     substepping: &[usize],
-    n_paths: usize) -> Result<Array3<f64>, qm::Error> {
+    n_paths: usize,
+    threads: usize) -> Result<Array3<f64>, qm::Error> {
 
     // create a 3d tensor indexed by path, then observation, then asset
     let n_assets = instruments.len();
@@ -392,23 +1045,30 @@ pub fn fetch_paths(
     assert!(n_paths > 0);
     let mut paths = Array3::<f64>::zeros((n_paths, n_obs, n_assets));
 
-    for ((asset, gaussians), path) in
-        instruments.iter().zip(
+    for ((((asset, quanto), shift), gaussians), path) in
+        instruments.iter().zip(quantos.iter()).zip(shifts.iter()).zip(
         correlated_gaussians.axis_iter(Axis(2))).zip(
         paths.axis_iter_mut(Axis(2))) {
 
         let instr: &Instrument = asset.deref();
-        fetch_path(instr, context, &observations, gaussians,
-            substepping, path)?;
+        fetch_path(instr, quanto.as_ref().map(|s| s.as_str()), *shift, context,
+            &observations, gaussians, substepping, path, threads)?;
     }
 
     Ok(paths)
 }
 
-pub fn fetch_path(instrument: &Instrument, context: &PricingContext,
+/// Fetches the paths for a single asset. If the asset is quanto, the fx_id
+/// identifies the FX rate, and the drift of the asset is adjusted by its
+/// covariance with the rate. If the shift is non-zero, the underlying plus
+/// the shift is log-normal, with variances calibrated to reprice the at the
+/// money options on the vol surface. The paths are walked in blocks on up
+/// to the given number of threads.
+pub fn fetch_path(instrument: &Instrument, quanto: Option<&str>, shift: f64,
+    context: &PricingContext,
     observations: &[DateDayFraction], correlated_gaussians: ArrayView2<f64>,
     substepping: &[usize],
-    mut path: ArrayViewMut2<f64>) -> Result<(), qm::Error> {
+    mut path: ArrayViewMut2<f64>, threads: usize) -> Result<(), qm::Error> {
 
     let n_obs = observations.len();
     assert!(n_obs > 0);  // otherwise we should not be evolving this asset
@@ -421,6 +1081,15 @@ pub fn fetch_path(instrument: &Instrument, context: &PricingContext,
     let hwm = observations.last().unwrap().date();
     let forward_curve = context.forward_curve(instrument, hwm)?;
     let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+
+    // For a quanto, we also need the FX vol and the correlation
+    let quanto_data = match quanto {
+        Some(fx_id) => Some((
+            context.fx_vol_surface(fx_id, hwm)?,
+            context.fx_spot(fx_id)?,
+            context.correlation_by_id(instrument.id(), fx_id)?)),
+        None => None
+    };
     
     // Fetch the forwards and variances on each observation date
     // We use the at the forward variances, using the live forward curve
@@ -430,45 +1099,82 @@ pub fn fetch_path(instrument: &Instrument, context: &PricingContext,
     let mut displacements = Vec::with_capacity(n_obs);
     for obs in observations.iter() {
         let fwd = forward_curve.forward(obs.date())?;
-        variances.push(vol_surface.variance(*obs, fwd)?);
+        let variance = vol_surface.variance(*obs, fwd)?;
         let displacement = vol_surface.displacement(obs.date())?;
-        displacements.push(displacement);
-        forwards.push(fwd - displacement);
+        let adjustment = match quanto_data {
+            Some((ref fx_vol, fx_spot, correlation)) => quanto_adjustment(
+                &vol_surface, fx_vol, correlation, *obs, fwd, fx_spot)?,
+            None => 1.0
+        };
+        let forward = (fwd - displacement) * adjustment;
+        if shift == 0.0 {
+            variances.push(variance);
+        } else {
+            let sqrt_variance = displaced_sqrt_variance(forward, shift,
+                variance.max(0.0).sqrt())?;
+            variances.push(sqrt_variance * sqrt_variance);
+        }
+        forwards.push(forward + shift);
+        displacements.push(displacement - shift);
     }
 
     // The sigma dW term should be treated as a finite step, since our
     // observations are widely spaced. To ensure we integrate to the correct
     // overall variances, we use the sqrt of the forward variance over each
     // step. (No need to use the forward_variance method here, as we are only
-    // looking along the forward, so smile is irrelevant.)
-    let mut sigmas = Vec::with_capacity(n_obs);
+    // looking along the forward, so smile is irrelevant.) The variance of
+    // each observation interval is shared between its substeps according to
+    // the term structure of the vol surface.
+    let term_structure = VolTermStructure::new(&*vol_surface, &*forward_curve,
+        observations, substepping)?;
+    let mut weights = term_structure.weights().iter();
+    let mut sigmas = Vec::with_capacity(term_structure.weights().len());
     let mut prev_var = 0.0;
     for (var, substep) in variances.iter().zip(substepping.iter()) {
-        let fwd_var = (var - prev_var) / (*substep as f64);
+        let fwd_var = var - prev_var;
         if fwd_var < 0.0 {
             return Err(qm::Error::new("Negative forward variance")) 
         }
-        sigmas.push(fwd_var.sqrt());
+        for _ in 0..*substep {
+            sigmas.push((fwd_var * weights.next().unwrap()).sqrt());
+        }
         prev_var = *var;
     }
 
-    // for each of the paths
-    for (ref gaussians, ref mut one_path) in 
-        correlated_gaussians.outer_iter().zip(path.outer_iter_mut()) {
-
-        // walk along each path
-        let mut point = 1.0;
+    // Walk a block of paths at a time, all of them together one substep at
+    // a time. The points and the draws of the block are held contiguously,
+    // so the loops over the paths vectorise, where walking each path in turn
+    // is a chain of dependent multiplications. Each path sees exactly the
+    // same arithmetic either way.
+    let blocks: Vec<_> = correlated_gaussians.axis_chunks_iter(Axis(0), PATHS_PER_BLOCK)
+        .zip(path.axis_chunks_iter_mut(Axis(0), PATHS_PER_BLOCK)).collect();
+    for_each_chunk(threads, blocks, |_, (block_gaussians, mut block_paths)| {
+        let n_block = block_gaussians.shape()[0];
+        let mut points = vec![1.0; n_block];
+        let mut draws = vec![0.0; n_block];
         let mut g = 0;	// index into the gaussians
         for i in 0..n_obs {
-            let sigma = sigmas[i];
             for _ in 0..substepping[i] {
-                point *= 1.0 + gaussians[g] * sigma;
+                for (draw, gaussian) in draws.iter_mut().zip(
+                    block_gaussians.subview(Axis(1), g).iter()) {
+                    *draw = *gaussian;
+                }
+                let sigma = sigmas[g];
+                for (point, draw) in points.iter_mut().zip(draws.iter()) {
+                    *point *= 1.0 + draw * sigma;
+                }
                 g += 1;
             }
-                
-            one_path[i] = point * forwards[i] + displacements[i];
+
+            let forward = forwards[i];
+            let displacement = displacements[i];
+            for (value, point) in block_paths.subview_mut(Axis(1), i).iter_mut()
+                .zip(points.iter()) {
+                *value = point * forward + displacement;
+            }
         }
-    }
+        Ok(())
+    })?;
 
     //println!("BlackDiffusion: sigma={:?}, forwards={:?}, displacements={:?}",
     //    sigma, forwards, displacements);
@@ -482,6 +1188,227 @@ impl MonteCarloModel for BlackDiffusion {
     fn as_bumpable(&self) -> &Bumpable { self }
     fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
     fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+    fn as_adjoint(&self) -> Option<&AdjointModel> { Some(self) }
+    fn as_likelihood_ratio(&self) -> Option<&LikelihoodRatioModel> { Some(self) }
+}
+
+impl AdjointModel for BlackDiffusion {
+    fn adjoint_recorder<'a>(&'a self) -> Result<Box<AdjointRecorder + 'a>, qm::Error> {
+        Ok(Box::new(BlackDiffusionRecorder::new(self)?))
+    }
+}
+
+/// Within each interval between observations, the model steps the path
+/// of an underlying, scaled by its forward, by a factor of one plus sigma
+/// times a gaussian, so each substep is normal given the last, and the
+/// scores follow exactly from the gaussians of each path. The spot moves
+/// the forward that the path is scaled by at the start of each interval,
+/// and a shift of vol moves the variance of every substep of the interval
+/// in proportion. As for the adjoint greeks, the weights of the substeps
+/// are held fixed. The density of several correlated underlyings is not
+/// supported, nor are observations with no variance since the last, such
+/// as the spot date, as their values are certain.
+impl LikelihoodRatioModel for BlackDiffusion {
+    fn likelihood_scores(&self)
+        -> Result<(Vec<RcInstrument>, Array2<f64>, Array2<f64>), qm::Error> {
+
+        if self.instruments.len() != 1 {
+            return Err(qm::Error::new("Likelihood ratio greeks are only \
+                supported for a single underlying"))
+        }
+        let assets = adjoint_assets(self)?;
+        let n_paths = self.paths.shape()[0];
+        let mut spot_scores = Array2::<f64>::zeros((n_paths, assets.len()));
+        let mut vol_scores = Array2::<f64>::zeros((n_paths, assets.len()));
+        for (a, asset) in assets.iter().enumerate() {
+
+            // the sensitivities of each interval, which are the same for
+            // every path
+            let mut intervals = Vec::with_capacity(self.substepping.len());
+            let mut prev_var = 0.0;
+            let mut prev_elasticity = 0.0;
+            let mut prev_vega = 0.0;
+            for i in 0..self.substepping.len() {
+                let fwd_var = asset.variances[i] - prev_var;
+                if !(fwd_var > 0.0) {
+                    return Err(qm::Error::new("Likelihood ratio greeks need \
+                        variance between every observation"))
+                }
+                let elasticity = asset.forward_deltas[i] / asset.forwards[i];
+                intervals.push((fwd_var, elasticity - prev_elasticity,
+                    0.5 * (asset.variance_vegas[i] - prev_vega) / fwd_var));
+                prev_var = asset.variances[i];
+                prev_elasticity = elasticity;
+                prev_vega = asset.variance_vegas[i];
+            }
+
+            for path in 0..n_paths {
+                let mut spot_score = 0.0;
+                let mut vol_score = 0.0;
+                let mut g = 0;
+                for (substeps, &(fwd_var, elasticity, log_vega)) in
+                    self.substepping.iter().zip(intervals.iter()) {
+
+                    // the first substep is normal about the last point
+                    // rescaled by the ratio of the forwards, with a score
+                    // by that ratio of (z / sigma + z^2 - 1)
+                    let draw = self.correlated_gaussians[[path, g, a]];
+                    let sigma = (fwd_var * asset.weights[g]).sqrt();
+                    spot_score += (draw / sigma + draw * draw - 1.0) * elasticity;
+
+                    // each substep has a score by its log variance of
+                    // (z^2 - 1) / 2
+                    let mut squares = 0.0;
+                    for _ in 0..*substeps {
+                        let draw = self.correlated_gaussians[[path, g, a]];
+                        squares += draw * draw - 1.0;
+                        g += 1;
+                    }
+                    vol_score += squares * log_vega;
+                }
+                spot_scores[[path, a]] = spot_score;
+                vol_scores[[path, a]] = vol_score;
+            }
+        }
+        Ok((self.instruments.clone(), spot_scores, vol_scores))
+    }
+}
+
+/// The relative spot bump used to find the sensitivity of the forwards to
+/// the spot. The forwards are affine in the spot, so any size will do.
+const FORWARD_DELTA_BUMP: f64 = 0.01;
+
+/// The market data that the path of one underlying depends on, at each
+/// observation, with its sensitivities to the spot and vol
+struct AdjointAsset {
+    forwards: Vec<f64>,
+    forward_deltas: Vec<f64>,
+    displacements: Vec<f64>,
+    variances: Vec<f64>,
+    variance_vegas: Vec<f64>,
+    weights: Vec<f64>
+}
+
+/// Records the paths of a BlackDiffusion on an adjoint tape, with the same
+/// random numbers and the same arithmetic as the model, so the recorded
+/// values equal its paths. The forwards are affine in the spot, so their
+/// sensitivities come exactly from a copy of the market data with the spot
+/// bumped. A shift of vol adds to the vol at every observation, moving its
+/// variance by twice the vol times the vol time. The variances are still
+/// taken at the unshifted forwards, and the weights of the substeps are
+/// held fixed, so smile and term structure do not contribute to the greeks.
+struct BlackDiffusionRecorder<'a> {
+    model: &'a BlackDiffusion,
+    assets: Vec<AdjointAsset>
+}
+
+impl<'a> BlackDiffusionRecorder<'a> {
+    fn new(model: &'a BlackDiffusion) -> Result<BlackDiffusionRecorder<'a>, qm::Error> {
+        Ok(BlackDiffusionRecorder { model: model, assets: adjoint_assets(model)? })
+    }
+}
+
+/// Fetches the market data behind the paths of each underlying, with its
+/// sensitivities, for greeks that differentiate the paths rather than
+/// bumping them. Neither importance sampling nor quanto or shifted
+/// underlyings are supported.
+fn adjoint_assets(model: &BlackDiffusion) -> Result<Vec<AdjointAsset>, qm::Error> {
+    if model.weights.is_some() {
+        return Err(qm::Error::new("Greeks of the paths cannot be combined \
+            with importance sampling"))
+    }
+
+    let context = model.context.as_pricing_context();
+    let hwm = model.observations.last().unwrap().date();
+    let mut assets = Vec::with_capacity(model.instruments.len());
+    for ((instrument, quanto), shift) in model.instruments.iter()
+        .zip(model.quantos.iter()).zip(model.shifts.iter()) {
+        if quanto.is_some() || *shift != 0.0 {
+            return Err(qm::Error::new(&format!("Greeks of the paths are not \
+                supported for quanto or shifted underlyings such as '{}'",
+                instrument.id())))
+        }
+
+        let instr: &Instrument = instrument.deref();
+        let forward_curve = context.forward_curve(instr, hwm)?;
+        let vol_surface = context.vol_surface(instr, hwm, &|| Ok(forward_curve.clone()))?;
+        let spot = context.spot(instrument.id())?;
+        let mut bumped = model.context.clone();
+        bumped.as_mut_bumpable().bump(&Bump::new_spot(instrument.id(),
+            BumpSpot::new_relative(FORWARD_DELTA_BUMP)), None)?;
+        let bumped_curve = bumped.as_pricing_context().forward_curve(instr, hwm)?;
+
+        let n_obs = model.observations.len();
+        let mut asset = AdjointAsset {
+            forwards: Vec::with_capacity(n_obs),
+            forward_deltas: Vec::with_capacity(n_obs),
+            displacements: Vec::with_capacity(n_obs),
+            variances: Vec::with_capacity(n_obs),
+            variance_vegas: Vec::with_capacity(n_obs),
+            weights: VolTermStructure::new(&*vol_surface, &*forward_curve,
+                &model.observations, &model.substepping)?.weights().to_vec() };
+        for obs in model.observations.iter() {
+            let fwd = forward_curve.forward(obs.date())?;
+            let bumped_fwd = bumped_curve.forward(obs.date())?;
+            let variance = vol_surface.variance(*obs, fwd)?;
+            let vol_time = vol_surface.vol_time(*obs)?;
+            let displacement = vol_surface.displacement(obs.date())?;
+            asset.forwards.push(fwd - displacement);
+            asset.forward_deltas.push((bumped_fwd - fwd) / (spot * FORWARD_DELTA_BUMP));
+            asset.displacements.push(displacement);
+            asset.variances.push(variance);
+            asset.variance_vegas.push(2.0 * (variance * vol_time).max(0.0).sqrt());
+        }
+        assets.push(asset);
+    }
+    Ok(assets)
+}
+
+impl<'a> AdjointRecorder for BlackDiffusionRecorder<'a> {
+    fn underlyings(&self) -> &[RcInstrument] {
+        &self.model.instruments
+    }
+
+    fn n_paths(&self) -> usize {
+        self.model.paths.shape()[0]
+    }
+
+    fn record<'t>(&self, tape: &'t Tape, path: usize, spot_shifts: &[Var<'t>],
+        vol_shifts: &[Var<'t>], values: &mut Vec<Vec<Var<'t>>>)
+        -> Result<(), qm::Error> {
+
+        values.resize(self.assets.len(), Vec::new());
+        for (a, (asset, asset_values)) in self.assets.iter()
+            .zip(values.iter_mut()).enumerate() {
+            asset_values.clear();
+            let mut point = tape.constant(1.0);
+            let mut prev_var = tape.constant(0.0);
+            let mut g = 0;
+            for (i, substeps) in self.model.substepping.iter().enumerate() {
+                let var = vol_shifts[a] * asset.variance_vegas[i] + asset.variances[i];
+                let fwd_var = var - prev_var;
+                if fwd_var.value() < 0.0 {
+                    return Err(qm::Error::new("Negative forward variance"))
+                }
+                for _ in 0..*substeps {
+                    let step_var = fwd_var * asset.weights[g];
+                    let sigma = if step_var.value() > 0.0 {
+                        step_var.sqrt()
+                    } else {
+                        tape.constant(0.0)
+                    };
+                    let draw = self.model.correlated_gaussians[[path, g, a]];
+                    point = point * (sigma * draw + 1.0);
+                    g += 1;
+                }
+                prev_var = var;
+
+                let forward = spot_shifts[a] * asset.forward_deltas[i] + asset.forwards[i];
+                asset_values.push(point * forward + asset.displacements[i]);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl MonteCarloContext for BlackDiffusion {
@@ -498,49 +1425,40 @@ impl MonteCarloContext for BlackDiffusion {
     fn evaluate_flows(&self, quantities: ArrayView2<f64>)
         -> Result<f64, qm::Error> {
 
-        let flows_shape = quantities.shape();
-        let paths_shape = self.paths.shape();
-        let n_paths = paths_shape[0];
-        let n_paths_f64: f64 = n_paths as f64;
-        assert_eq!(flows_shape[0], n_paths);
-        assert_eq!(flows_shape[1], self.flows.len());
-
-        // For now, always value as of the spot date at the open. (We may want to relax this
-        // restriction later, by passing a slice of date-times into the method.)
-        let val_date = DateTime::new(self.context.as_pricing_context().spot_date(), TimeOfDay::Open);
-
-        // weighted sum of all of the flows
-        let mut total = 0.0;
-        for (flow, quantity) in self.flows.iter().zip(
-            quantities.axis_iter(Axis(1))) {
-
-            // BlackDiffusion is a non-stochastic-rate model, so we can
-            // save time by evaluating the pure rate flows using Priceable
-            if flow.is_pure_rates() {
-
-                // value of the instrument times the average quantity
-                let average = quantity.scalar_sum() / n_paths_f64;
-                let pricer = flow.as_priceable().ok_or_else(|| qm::Error::new(
-                    "All pure-rates flows must be priceable"))?;
-                let value = pricer.price(self.context.as_pricing_context(), val_date)?;
-                total += average * value;
-
-                //println!("BlackDiffusion::evaluate_flows value={} average={} \
-                //    total={}", value, average, total);
-
-            } else {
+        match self.weights {
+            Some(ref weights) => {
+                let values = evaluate_weighted_flows(
+                    self.context.as_pricing_context(), &self.flows, quantities,
+                    weights.view())?;
+                Ok(values.scalar_sum() / values.len() as f64)
+            },
+            None => evaluate_flows(self.context.as_pricing_context(),
+                &self.flows, quantities)
+        }
+    }
 
-                // otherwise we must price by Monte-Carlo over each path
-                // TODO how do we pass in the weights?
-                return Err(qm::Error::new("not implemented"))
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+
+        match self.weights {
+            Some(ref weights) => evaluate_weighted_flows(
+                self.context.as_pricing_context(), &self.flows, quantities,
+                weights.view()),
+            None => {
+                let n_paths = quantities.shape()[0];
+                evaluate_weighted_flows(self.context.as_pricing_context(),
+                    &self.flows, quantities, Array1::<f64>::ones(n_paths).view())
             }
         }
-        Ok(total)
     }
 
     fn pricing_context(&self) -> &PricingContext {
         &*self.context.as_pricing_context()
     }
+
+    fn threads(&self) -> usize {
+        self.threads
+    }
 }
 
 impl Bumpable for BlackDiffusion {
@@ -551,12 +1469,14 @@ impl Bumpable for BlackDiffusion {
         // we have to unpack the option<saveable> into options on all its
         // components all at the same time, to avoid problems with borrowing.
         let saved = to_saved(any_saved)?;
-        let (saved_data, saved_paths) 
-            : (Option<&mut Saveable>, Option<&mut HashMap<usize, Array2<f64>>>)
+        let (saved_data, saved_paths, saved_gaussians)
+            : (Option<&mut Saveable>, Option<&mut HashMap<usize, Array2<f64>>>,
+                Option<&mut Option<Array3<f64>>>)
             = if let Some(s) = saved {
-            (Some(&mut *s.saved_data), Some(&mut s.paths))
+            (Some(&mut *s.saved_data), Some(&mut s.paths),
+                Some(&mut s.correlated_gaussians))
         } else {
-            (None, None)
+            (None, None, None)
         };
 
         // bump the underlying market data (and prefetched content if any)
@@ -565,9 +1485,17 @@ impl Bumpable for BlackDiffusion {
         // refetch any paths that may have changed
         match bump {
             &Bump::Spot(ref id, _) => self.refetch(&id, bumped, saved_paths),
+            &Bump::FxSpot(ref fx_id, _) => self.refetch_fx(&fx_id, bumped, saved_paths),
             &Bump::Divs(ref id, _) => self.refetch(&id, bumped, saved_paths),
             &Bump::Borrow(ref id, _) => self.refetch(&id, bumped, saved_paths),
             &Bump::Vol(ref id, _) => self.refetch(&id, bumped, saved_paths),
+            // vol cubes do not affect equity paths
+            &Bump::VolCube(_, _) => Ok(bumped),
+            // nor do hazard curves, which only affect credit instruments
+            &Bump::Hazard(_, _) => Ok(bumped),
+            // correlations affect all the paths, including quanto drifts
+            &Bump::Correlation(_, _, _) => self.recorrelate(bumped,
+                saved_gaussians, saved_paths),
             &Bump::Yield(ref credit_id, _) => {
                 // we have to copy these ids to avoid a tangle with borrowing
                 let v = self.dependencies()?
@@ -622,7 +1550,10 @@ impl Bumpable for BlackDiffusion {
             // first restore the underlying market data and cached curves
             self.context.as_mut_bumpable().restore(&*saved.saved_data)?;
 
-            // now restore any cached paths
+            // now restore any cached gaussians and paths
+            if let Some(ref gaussians) = saved.correlated_gaussians {
+                self.correlated_gaussians.assign(gaussians);
+            }
             for (asset, paths) in saved.paths.iter() {
                 let mut dest = self.paths.subview_mut(Axis(2), *asset);
                 dest.assign(paths);
@@ -652,7 +1583,8 @@ fn to_saved(opt_saveable: Option<&mut Saveable>)
 /// Save space for BlackDiffusion to use during bumping
 pub struct SavedBlackDiffusion {
     saved_data: Box<Saveable>,
-    paths: HashMap<usize, Array2<f64>>
+    paths: HashMap<usize, Array2<f64>>,
+    correlated_gaussians: Option<Array3<f64>>
 }
 
 impl SavedBlackDiffusion {
@@ -662,7 +1594,8 @@ impl SavedBlackDiffusion {
     pub fn new(saved_data: Box<Saveable>) -> SavedBlackDiffusion {
         SavedBlackDiffusion {
             saved_data: saved_data,
-            paths: HashMap::new() }
+            paths: HashMap::new(),
+            correlated_gaussians: None }
     }
 }
 
@@ -673,5 +1606,347 @@ impl Saveable for SavedBlackDiffusion {
     fn clear(&mut self) {
         self.saved_data.clear();
         self.paths.clear();
+        self.correlated_gaussians = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use models::StreamAssignment;
+    use math::correlation::nearest_correlation;
+    use data::correlations::CorrelationTermStructure;
+    use instruments::assets::Equity;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use data::forward::DriftlessForward;
+    use data::volsmile::FlatSmile;
+    use data::volsurface::VolByProbabilityFlatSmile;
+    use data::volsurface::DivAssumptions;
+    use dates::Date;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use math::interpolation::Linear;
+    use math::interpolation::Extrap;
+
+    #[test]
+    fn sobol_gaussians_are_independent_unit_gaussians() {
+        for &path_generation in [PathGeneration::Sobol,
+            PathGeneration::SobolBrownianBridge].iter() {
+            let n_paths = 4095;
+            let mut source = GaussianSource::new(path_generation);
+            let first = source.fetch(&[3, 5], 2, n_paths).unwrap();
+            let second = source.fetch(&[3, 5], 2, n_paths).unwrap();
+            assert_eq!(first.shape(), &[n_paths, 8, 2]);
+
+            // each column has zero mean and unit variance, and no
+            // correlation with any other, including those of the second
+            // fetch, which take the next dimensions of the sequence
+            let mut columns = Vec::new();
+            for gaussians in [&first, &second].iter() {
+                for step in 0..8 {
+                    for asset in 0..2 {
+                        columns.push(gaussians.subview(Axis(2), asset)
+                            .subview(Axis(1), step).to_vec());
+                    }
+                }
+            }
+            for (i, a) in columns.iter().enumerate() {
+                let mean = a.iter().sum::<f64>() / n_paths as f64;
+                assert!(mean.abs() < 0.01, "{:?} column {} mean={}",
+                    path_generation, i, mean);
+                for (j, b) in columns.iter().enumerate().take(i + 1) {
+                    let covariance = a.iter().zip(b.iter()).map(|(x, y)| x * y)
+                        .sum::<f64>() / n_paths as f64;
+                    let expected = if i == j { 1.0 } else { 0.0 };
+                    assert!(approx_eq(covariance, expected, 0.05),
+                        "{:?} columns {} and {} covariance={}",
+                        path_generation, i, j, covariance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_gaussians_fill_every_stratum() {
+        let n_paths = 500;
+        let substepping = [3, 5];
+        let quantile = |x: f64| 0.5 * erfc(-x / SQRT_2);
+        for &path_generation in [PathGeneration::Stratified,
+            PathGeneration::LatinHypercube].iter() {
+            let threading = Threading::new(1, Some(42));
+            let mut source = GaussianSource::with_threading(path_generation, threading);
+            let gaussians = source.fetch(&substepping, 2, n_paths).unwrap();
+            assert_eq!(gaussians.shape(), &[n_paths, 8, 2]);
+
+            // the same seed gives the same paths
+            let mut repeat = GaussianSource::with_threading(path_generation, threading);
+            assert_eq!(repeat.fetch(&substepping, 2, n_paths).unwrap(), gaussians);
+
+            // the end of the path of each asset, or for a Latin hypercube
+            // each gaussian, lands once in each stratum
+            let mut columns = Vec::new();
+            for asset in 0..2 {
+                let assets = gaussians.subview(Axis(2), asset);
+                if path_generation == PathGeneration::LatinHypercube {
+                    for step in 0..8 {
+                        columns.push(assets.subview(Axis(1), step).to_vec());
+                    }
+                } else {
+                    columns.push(assets.outer_iter().map(|path|
+                        path.scalar_sum() / 8.0_f64.sqrt()).collect::<Vec<f64>>());
+                }
+            }
+            for column in columns.iter() {
+                let mut strata: Vec<usize> = column.iter()
+                    .map(|x| (quantile(*x) * n_paths as f64) as usize).collect();
+                strata.sort();
+                assert_eq!(strata, (0..n_paths).collect::<Vec<usize>>(),
+                    "{:?}", path_generation);
+            }
+
+            // the strata of the assets are matched at random, so they are
+            // uncorrelated, and the steps are still unit gaussians
+            let covariance = columns[0].iter().zip(columns.last().unwrap().iter())
+                .map(|(x, y)| x * y).sum::<f64>() / n_paths as f64;
+            assert!(covariance.abs() < 0.15, "covariance={}", covariance);
+            let variance = gaussians.subview(Axis(2), 1).subview(Axis(1), 4)
+                .iter().map(|x| x * x).sum::<f64>() / n_paths as f64;
+            assert!(approx_eq(variance, 1.0, 0.15), "variance={}", variance);
+        }
+    }
+
+    #[test]
+    fn antithetic_gaussians_come_in_pairs() {
+        let mut source = GaussianSource::new(PathGeneration::Antithetic);
+        let gaussians = source.fetch(&[3, 2], 2, 1000).unwrap();
+        assert_eq!(gaussians.shape(), &[1000, 5, 2]);
+        for pair in 0..500 {
+            let even = gaussians.subview(Axis(0), 2 * pair);
+            let odd = gaussians.subview(Axis(0), 2 * pair + 1);
+            for (a, b) in even.iter().zip(odd.iter()) {
+                assert_eq!(*a, -*b);
+            }
+        }
+
+        // the mean of every column is exactly zero, and the pairs are not
+        // all the same
+        let mean = gaussians.subview(Axis(2), 1).subview(Axis(1), 3).scalar_sum() / 1000.0;
+        assert!(mean.abs() < 1e-12);
+        assert!(gaussians[[0, 0, 0]] != gaussians[[2, 0, 0]]);
+
+        assert!(source.fetch(&[3, 2], 2, 999).is_err());
+    }
+
+    #[test]
+    fn seeded_gaussians_do_not_depend_on_threads() {
+        let n_paths = 2 * PATHS_PER_BLOCK + 7;
+        let mut single = GaussianSource::with_threading(PathGeneration::PseudoRandom,
+            Threading::new(1, Some(1234)));
+        let mut threaded = GaussianSource::with_threading(PathGeneration::PseudoRandom,
+            Threading::new(3, Some(1234)));
+        let spot = single.fetch(&[3, 2], 2, n_paths).unwrap();
+        assert_eq!(spot, threaded.fetch(&[3, 2], 2, n_paths).unwrap());
+
+        // the next fetch, for another factor, has its own stream, and so do
+        // the blocks within a fetch
+        let variance = single.fetch(&[3, 2], 2, n_paths).unwrap();
+        assert_eq!(variance, threaded.fetch(&[3, 2], 2, n_paths).unwrap());
+        assert!(spot[[0, 0, 0]] != variance[[0, 0, 0]]);
+        assert!(spot[[0, 0, 0]] != spot[[PATHS_PER_BLOCK, 0, 0]]);
+
+        // another stream of the same seed has other gaussians
+        let mut other = GaussianSource::with_threading(PathGeneration::PseudoRandom,
+            Threading::with_streams(1, Some(1234), StreamAssignment::Stream(1)));
+        assert!(other.fetch(&[3, 2], 2, n_paths).unwrap()[[0, 0, 0]] != spot[[0, 0, 0]]);
+
+        let mean = spot.scalar_sum() / spot.len() as f64;
+        assert!(mean.abs() < 0.05, "mean={}", mean);
+    }
+
+    #[test]
+    fn substeps_follow_vol_term_structure() {
+        // a vol surface with high vols at the short end
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let d = Date::from_ymd(2017, 01, 02);
+        let base = DateDayFraction::new(d, 0.2);
+        let smiles = [(DateDayFraction::new(d + 91, 0.7), FlatSmile::new(0.35).unwrap()),
+            (DateDayFraction::new(d + 364, 0.7), FlatSmile::new(0.25).unwrap())];
+        let forward = Linear::new(&[(d, 100.0)], Extrap::Flat, Extrap::Flat).unwrap();
+        let divs = Linear::new(&[(d, 0.0)], Extrap::Flat, Extrap::Flat).unwrap();
+        let surface = VolByProbabilityFlatSmile::new(&smiles, calendar, base,
+            forward, divs, DivAssumptions::NoCashDivs).unwrap();
+
+        let observations = [DateDayFraction::new(d + 364, 0.7),
+            DateDayFraction::new(d + 364, 0.7)];
+        let term_structure = VolTermStructure::new(&surface,
+            &DriftlessForward::new(100.0), &observations, &[4, 1]).unwrap();
+
+        // the substeps end a quarter of the way through the first interval,
+        // and the weights are the fractions of the variance in each
+        let points = term_structure.points();
+        assert_eq!(points.len(), 5);
+        assert_eq!(points[0], DateDayFraction::new(d + 91, 0.7));
+        assert_eq!(points[3], observations[0]);
+        let variances = term_structure.variances();
+        let weights = term_structure.weights();
+        assert!(approx_eq(variances[3], surface.variance(observations[0], 100.0)
+            .unwrap(), 1e-12));
+        assert!(approx_eq(weights[0], variances[0] / variances[3], 1e-12));
+        assert!(approx_eq(weights[..4].iter().sum(), 1.0, 1e-12));
+
+        // the high short-dated vol puts more of the variance in the first
+        // substep than in the last one
+        assert!(weights[0] > 2.0 * weights[3], "weights={:?}", weights);
+
+        // an interval with no variance shares it equally
+        assert!(approx_eq(weights[4], 1.0, 1e-12));
+    }
+
+    #[test]
+    fn correlated_gaussians_have_the_given_correlation() {
+        let ids = ["BP.L", "GSK.L"];
+        let mut market_data = sample_market_data();
+        market_data.set_correlation(ids[0], ids[1], 0.6).unwrap();
+
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let instruments: Vec<RcInstrument> = ids.iter().map(|id|
+            RcInstrument::new(Qrc::new(Arc::new(Equity::new(id, "LSE",
+            currency.clone(), sample_settlement(2)))))).collect();
+        let n_paths = 20000;
+        let gaussians = fetch_seeded_gaussians(&[1], 2, n_paths, 42, 0, 0, 1)
+            .unwrap();
+        let observations = [DateDayFraction::new(market_data.spot_date() + 365, 0.0)];
+        let correlated = correlate_gaussians(&market_data, &instruments,
+            &observations, &[1], &gaussians).unwrap();
+
+        // the root of the correlation matrix must be lower triangular. Its
+        // transpose would give the first asset a variance of 1.36 and the
+        // pair a covariance of 0.48. The sample moments are within a few
+        // standard errors of about 1/sqrt(n_paths).
+        for &(i, j, expected) in [(0, 0, 1.0), (1, 1, 1.0), (1, 0, 0.6)].iter() {
+            let mut sum = 0.0;
+            for p in 0..n_paths {
+                sum += correlated[[p, 0, i]] * correlated[[p, 0, j]];
+            }
+            let sample = sum / n_paths as f64;
+            assert!(approx_eq(sample, expected, 0.03),
+                "i={} j={} sample={} expected={}", i, j, sample, expected);
+        }
+    }
+
+    #[test]
+    fn inconsistent_correlations_are_repaired() {
+        let ids = ["BP.L", "GSK.L", "VOD.L"];
+        let values = vec![
+            1.0, -0.9, 0.9,
+            -0.9, 1.0, 0.9,
+            0.9, 0.9, 1.0];
+        let matrix = Array2::from_shape_vec((3, 3), values.clone()).unwrap();
+        let mut market_data = sample_market_data();
+        market_data.set_correlation_matrix(&ids, &matrix).unwrap();
+
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let instruments: Vec<RcInstrument> = ids.iter().map(|id|
+            RcInstrument::new(Qrc::new(Arc::new(Equity::new(id, "LSE",
+            currency.clone(), sample_settlement(2)))))).collect();
+        let n_paths = 20000;
+        let gaussians = fetch_seeded_gaussians(&[1], 3, n_paths, 42, 0, 0, 1)
+            .unwrap();
+        let observations = [DateDayFraction::new(market_data.spot_date() + 365, 0.0)];
+        let correlated = correlate_gaussians(&market_data, &instruments,
+            &observations, &[1], &gaussians).unwrap();
+
+        // the sample correlations are those of the nearest valid matrix,
+        // within a few standard errors of about 1/sqrt(n_paths)
+        let nearest = nearest_correlation(&DMatrix::from_row_slice(3, 3, &values)).unwrap();
+        for i in 0..3 {
+            for j in 0..i {
+                let mut sum = 0.0;
+                for p in 0..n_paths {
+                    sum += correlated[[p, 0, i]] * correlated[[p, 0, j]];
+                }
+                let sample = sum / n_paths as f64;
+                assert!(approx_eq(sample, nearest[(i, j)], 0.03),
+                    "i={} j={} sample={} nearest={}", i, j, sample, nearest[(i, j)]);
+            }
+        }
+    }
+
+    #[test]
+    fn blocked_correlation_matches_per_path() {
+        let ids = ["BP.L", "GSK.L", "VOD.L"];
+        let values = vec![
+            1.0, 0.5, 0.3,
+            0.5, 1.0, -0.2,
+            0.3, -0.2, 1.0];
+        let matrix = Array2::from_shape_vec((3, 3), values).unwrap();
+        let mut market_data = sample_market_data();
+        market_data.set_correlation_matrix(&ids, &matrix).unwrap();
+
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let instruments: Vec<RcInstrument> = ids.iter().map(|id|
+            RcInstrument::new(Qrc::new(Arc::new(Equity::new(id, "LSE",
+            currency.clone(), sample_settlement(2)))))).collect();
+
+        // more paths than a block, so there is a partial block at the end
+        let n_paths = PATHS_PER_BLOCK + 100;
+        let substepping = [2, 1];
+        let gaussians = fetch_gaussians(&substepping, 3, n_paths);
+        let d = market_data.spot_date();
+        let observations = [DateDayFraction::new(d + 100, 0.0),
+            DateDayFraction::new(d + 200, 0.0)];
+        let correlated = correlate_gaussians_threaded(&market_data, &instruments,
+            &observations, &substepping, &gaussians, 2).unwrap();
+
+        let root = correlation_matrix_root(&matrix).unwrap();
+        for path in [0, 1, PATHS_PER_BLOCK - 1, PATHS_PER_BLOCK, n_paths - 1].iter() {
+            for step in 0..3 {
+                let expected = root.dot(&gaussians.subview(Axis(0), *path)
+                    .subview(Axis(0), step));
+                for asset in 0..3 {
+                    assert!(approx_eq(correlated[[*path, step, asset]],
+                        expected[asset], 1e-14));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn correlations_follow_term_structure() {
+        let mut market_data = sample_market_data();
+        let d = market_data.spot_date();
+        market_data.set_correlation_term_structure("BP.L", "GSK.L",
+            CorrelationTermStructure::new(&[(d + 100, 0.8), (d + 150, 0.6),
+                (d + 300, 0.2)]).unwrap())
+            .unwrap();
+
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let instruments: Vec<RcInstrument> = ["BP.L", "GSK.L"].iter().map(|id|
+            RcInstrument::new(Qrc::new(Arc::new(Equity::new(id, "LSE",
+            currency.clone(), sample_settlement(2)))))).collect();
+        let n_paths = 20000;
+        let substepping = [2, 1, 1];
+        let gaussians = fetch_seeded_gaussians(&substepping, 2, n_paths, 42, 0, 0, 1)
+            .unwrap();
+        let observations = [DateDayFraction::new(d + 100, 0.0),
+            DateDayFraction::new(d + 200, 0.0), DateDayFraction::new(d + 400, 0.0)];
+        let correlated = correlate_gaussians(&market_data, &instruments,
+            &observations, &substepping, &gaussians).unwrap();
+
+        // each substep has the average correlation since the previous
+        // observation, within a few standard errors
+        for &(step, expected) in [(0, 0.8), (1, 0.8), (2, 0.4), (3, 0.2)].iter() {
+            let mut sum = 0.0;
+            for p in 0..n_paths {
+                sum += correlated[[p, step, 0]] * correlated[[p, step, 1]];
+            }
+            let sample = sum / n_paths as f64;
+            assert!(approx_eq(sample, expected, 0.03),
+                "step={} sample={} expected={}", step, sample, expected);
+        }
     }
 }
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use core::qm;
+use risk::BumpablePricingContext;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::blackdiffusion::BlackDiffusion;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The DisplacedDiffusionFactory creates a displaced (shifted log-normal)
+/// diffusion, given the timeline of the product(s) to value and the market
+/// data. This is a BlackDiffusion where, for each underlying with a shift,
+/// the underlying plus the shift is log-normal:
+///
+///  dS/(S + d) = mu(t) dt + sigma_d(t) dW
+///
+/// The shifted vols are calibrated so that each observation reprices the
+/// at the money option on the vol surface. A positive shift then gives a
+/// downward-sloping skew, and a negative one an upward-sloping skew, which
+/// is a cheap way to capture the first-order effect of skew. Shifts also
+/// allow forwards that are low relative to their vol, such as rates.
+///
+/// Apart from the shifts, the parameters are those of BlackDiffusionFactory.
+/// Underlyings without a shift are simulated as for BlackDiffusion.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DisplacedDiffusionFactory {
+    shifts: HashMap<String, f64>,
+    correlation_substep: usize,
+    path_substep: f64,
+    number_of_paths: usize
+}
+
+impl DisplacedDiffusionFactory {
+    pub fn new(shifts: HashMap<String, f64>, correlation_substep: usize,
+        path_substep: f64, number_of_paths: usize) -> DisplacedDiffusionFactory {
+
+        DisplacedDiffusionFactory { shifts: shifts,
+            correlation_substep: correlation_substep,
+            path_substep: path_substep, number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DisplacedDiffusionFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for DisplacedDiffusionFactory {
+    fn type_id(&self) -> &'static str { "DisplacedDiffusionFactory" }
+}
+
+impl MonteCarloModelFactory for DisplacedDiffusionFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = BlackDiffusion::with_shifts(timeline, context,
+            self.correlation_substep, self.path_substep, self.number_of_paths,
+            &self.shifts)?;
+        Ok(Box::new(model))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::optionpricing::Black76;
+    use math::optionpricing::displaced_sqrt_variance;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::EuropeanTerms;
+    use models::tests::sample_european_terms;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+
+    #[test]
+    fn displaced_diffusion_matches_shifted_black() {
+        let shift = 100.0;
+        let mut shifts = HashMap::new();
+        shifts.insert("BP.L".to_string(), shift);
+        let factory = round_trip(&DisplacedDiffusionFactory::new(shifts, 20, 0.01,
+            20000));
+        let black76 = Black76::new().unwrap();
+        let shifted_price = |terms: &EuropeanTerms, strike: f64, put_or_call: PutOrCall| {
+            let shifted = displaced_sqrt_variance(terms.forward, shift,
+                terms.sqrt_variance).unwrap();
+            match put_or_call {
+                PutOrCall::Call => black76.call_price(terms.df, terms.forward + shift,
+                    strike + shift, shifted),
+                PutOrCall::Put => black76.put_price(terms.df, terms.forward + shift,
+                    strike + shift, shifted)
+            }
+        };
+
+        // With seeded paths this is deterministic. The shifted log-normal
+        // is simulated exactly; the standard errors reach 0.17 for the
+        // call, so allow three of those.
+        let market_data = sample_market_data();
+        let options = [(70.0, PutOrCall::Put), (100.0, PutOrCall::Call),
+            (130.0, PutOrCall::Call)];
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &market_data, sample_expiry(), &options, 0.5, &shifted_price);
+
+        // the shift gives a downward-sloping skew, so the low strike
+        // put is dearer and the high strike call cheaper than flat vol
+        let terms = sample_european_terms(&market_data, sample_expiry());
+        for &(strike, put_or_call) in options.iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(), strike,
+                put_or_call, OptionSettlement::Cash).unwrap();
+            let unshifted = european.price(&market_data, sample_val_date()).unwrap();
+            let expected = shifted_price(&terms, strike, put_or_call);
+            if strike < 100.0 {
+                assert!(expected > unshifted + 0.3,
+                    "expected={} unshifted={}", expected, unshifted);
+            } else if strike > 100.0 {
+                assert!(expected < unshifted - 0.3,
+                    "expected={} unshifted={}", expected, unshifted);
+            }
+        }
+    }
+}
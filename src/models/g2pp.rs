@@ -0,0 +1,572 @@
+use std::sync::Arc;
+use nalgebra::linalg::Cholesky;
+use nalgebra::base::DMatrix;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::Axis;
+use core::qm;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use instruments::options::PutOrCall;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::hullwhite::ShortRateTimeline;
+use models::hullwhite::gaussian_bond_option;
+use models::hullwhite::year_fraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The parameters of the G2++ two-factor gaussian short rate model:
+///
+///  r(t) = x(t) + y(t) + phi(t)
+///  dx = -a x dt + sigma dW1
+///  dy = -b y dt + eta dW2
+///  dW1 dW2 = rho dt
+///
+/// As for Hull-White, the deterministic shift phi(t) is never needed
+/// explicitly, as it is implied by fitting the initial yield curve. With
+/// two factors, rates of different tenors are imperfectly correlated, so
+/// the model can value products that depend on the shape of the curve.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct G2ppParameters {
+    a: f64,
+    sigma: f64,
+    b: f64,
+    eta: f64,
+    rho: f64
+}
+
+impl G2ppParameters {
+    pub fn new(a: f64, sigma: f64, b: f64, eta: f64, rho: f64)
+        -> Result<G2ppParameters, qm::Error> {
+
+        if a <= 0.0 || b <= 0.0 {
+            return Err(qm::Error::new("G2++ mean reversions must be positive"))
+        }
+        if sigma < 0.0 || eta < 0.0 {
+            return Err(qm::Error::new("G2++ vols must not be negative"))
+        }
+        if rho <= -1.0 || rho >= 1.0 {
+            return Err(qm::Error::new("G2++ correlation must be strictly between -1 and 1"))
+        }
+
+        Ok(G2ppParameters { a: a, sigma: sigma, b: b, eta: eta, rho: rho })
+    }
+
+    pub fn a(&self) -> f64 { self.a }
+    pub fn sigma(&self) -> f64 { self.sigma }
+    pub fn b(&self) -> f64 { self.b }
+    pub fn eta(&self) -> f64 { self.eta }
+    pub fn rho(&self) -> f64 { self.rho }
+
+    /// The mean reversion and vol of each factor
+    fn factors(&self) -> [(f64, f64); 2] {
+        [(self.a, self.sigma), (self.b, self.eta)]
+    }
+
+    fn correlation(&self, i: usize, j: usize) -> f64 {
+        if i == j { 1.0 } else { self.rho }
+    }
+
+    /// The variance of the integral of x + y from zero to t
+    pub fn integral_variance(&self, t: f64) -> f64 {
+        let factors = self.factors();
+        let mut total = 0.0;
+        for (i, &(ki, si)) in factors.iter().enumerate() {
+            for (j, &(kj, sj)) in factors.iter().enumerate() {
+                total += self.correlation(i, j) * si * sj / (ki * kj)
+                    * (t - decay_integral(ki, t) - decay_integral(kj, t)
+                        + decay_integral(ki + kj, t));
+            }
+        }
+        total
+    }
+
+    /// The price at time t of a zero coupon bond maturing at the given
+    /// time, given the states x and y at t and the forward discount factor
+    /// P(0, maturity) / P(0, t) from the initial curve
+    pub fn zero_bond(&self, forward_df: f64, t: f64, maturity: f64,
+        x: f64, y: f64) -> f64 {
+
+        let tenor = maturity - t;
+        let convexity = 0.5 * (self.integral_variance(tenor)
+            - self.integral_variance(maturity) + self.integral_variance(t));
+        forward_df * (convexity - decay_integral(self.a, tenor) * x
+            - decay_integral(self.b, tenor) * y).exp()
+    }
+
+    /// Closed-form price of a European call expiring at t on a zero coupon
+    /// bond maturing at the given time, given the discount factors to the
+    /// expiry and to the maturity from the initial curve.
+    pub fn zero_bond_call(&self, df_expiry: f64, df_maturity: f64,
+        strike: f64, t: f64, maturity: f64) -> Result<f64, qm::Error> {
+        let sigma_p = self.bond_option_vol(t, maturity)?;
+        gaussian_bond_option(df_expiry, df_maturity, strike, sigma_p, PutOrCall::Call)
+    }
+
+    /// Closed-form price of a European put on a zero coupon bond
+    pub fn zero_bond_put(&self, df_expiry: f64, df_maturity: f64,
+        strike: f64, t: f64, maturity: f64) -> Result<f64, qm::Error> {
+        let sigma_p = self.bond_option_vol(t, maturity)?;
+        gaussian_bond_option(df_expiry, df_maturity, strike, sigma_p, PutOrCall::Put)
+    }
+
+    /// The standard deviation of the log of the forward price at t of a
+    /// zero coupon bond maturing at the given time
+    pub fn bond_option_vol(&self, t: f64, maturity: f64) -> Result<f64, qm::Error> {
+        if t <= 0.0 || maturity <= t {
+            return Err(qm::Error::new("Bond options must expire before the bond matures"))
+        }
+
+        let factors = self.factors();
+        let tenor = maturity - t;
+        let mut variance = 0.0;
+        for (i, &(ki, si)) in factors.iter().enumerate() {
+            for (j, &(kj, sj)) in factors.iter().enumerate() {
+                variance += self.correlation(i, j) * si * sj
+                    * decay_integral(ki, tenor) * decay_integral(kj, tenor)
+                    * decay_integral(ki + kj, t);
+            }
+        }
+        Ok(variance.sqrt())
+    }
+
+    /// The covariance matrix of the changes over a step of length dt in
+    /// x, the integral of x, y and the integral of y, in that order, given
+    /// their values at the start of the step.
+    pub fn step_covariance(&self, dt: f64) -> [[f64; 4]; 4] {
+        let factors = self.factors();
+        let mut covariance = [[0.0; 4]; 4];
+        for (i, &(ki, si)) in factors.iter().enumerate() {
+            for (j, &(kj, sj)) in factors.iter().enumerate() {
+                let c = self.correlation(i, j) * si * sj;
+                let both = decay_integral(ki + kj, dt);
+                covariance[2 * i][2 * j] = c * both;
+                covariance[2 * i][2 * j + 1] = c / kj * (decay_integral(ki, dt) - both);
+                covariance[2 * i + 1][2 * j] = c / ki * (decay_integral(kj, dt) - both);
+                covariance[2 * i + 1][2 * j + 1] = c / (ki * kj)
+                    * (dt - decay_integral(ki, dt) - decay_integral(kj, dt) + both);
+            }
+        }
+        covariance
+    }
+}
+
+/// The integral of exp(-k s) from zero to t, that is (1 - exp(-k t)) / k
+fn decay_integral(k: f64, t: f64) -> f64 {
+    (1.0 - (-k * t).exp()) / k
+}
+
+/// The G2ppFactory creates a G2++ model, given the timeline of the
+/// product(s) to value and the market data. As for HullWhiteFactory, it
+/// holds the credit id of the stochastic yield curve, the parameters and
+/// the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct G2ppFactory {
+    credit_id: String,
+    parameters: G2ppParameters,
+    number_of_paths: usize
+}
+
+impl G2ppFactory {
+    pub fn new(credit_id: &str, parameters: G2ppParameters,
+        number_of_paths: usize) -> G2ppFactory {
+
+        G2ppFactory { credit_id: credit_id.to_string(),
+            parameters: parameters, number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(G2ppFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for G2ppFactory {
+    fn type_id(&self) -> &'static str { "G2ppFactory" }
+}
+
+impl MonteCarloModelFactory for G2ppFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = G2pp::new(timeline, context, &self.credit_id,
+            self.parameters, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A G2++ model makes the rates of one yield curve stochastic, driven by
+/// two correlated factors. It is used in the same way as HullWhite: the
+/// underlyings must be zero coupon bonds on the stochastic curve, which
+/// are reconstituted in closed form from the two states on each path, and
+/// flows on that curve are discounted along each path.
+///
+/// Both states and their integrals are simulated jointly and exactly on
+/// the grid of observation and payment dates.
+#[derive(Clone)]
+pub struct G2pp {
+    parameters: G2ppParameters,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    timeline: ShortRateTimeline,
+    x: Array2<f64>,
+    y: Array2<f64>,
+    deflators: Array2<f64>,
+    paths: Vec<Array2<f64>>
+}
+
+impl G2pp {
+
+    /// Creates a new G2++ model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the credit id of the stochastic curve, the parameters and the number
+    /// of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        credit_id: &str,
+        parameters: G2ppParameters,
+        n_paths: usize)
+        -> Result<G2pp, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("G2++ does not support quanto underlyings"))
+        }
+
+        let spot_date = context.as_pricing_context().spot_date();
+        let rates_timeline = ShortRateTimeline::new(timeline, credit_id, spot_date)?;
+
+        let times = rates_timeline.times();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let gaussians = source.fetch(&vec![1; times.len()], 4, n_paths)?;
+        let (x, y, integrals) = fetch_states(&parameters, &times, &gaussians)?;
+        let mut deflators = integrals;
+        for (mut column, t) in deflators.axis_iter_mut(Axis(1)).zip(times.iter()) {
+            let convexity = 0.5 * parameters.integral_variance(*t);
+            column.mapv_inplace(|integral| (-integral - convexity).exp());
+        }
+
+        let mut model = G2pp {
+            parameters: parameters,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            timeline: rates_timeline,
+            x: x,
+            y: y,
+            deflators: deflators,
+            paths: Vec::new() };
+        model.refetch_all()?;
+        Ok(model)
+    }
+
+    /// Refetch the bond prices on all paths, using the same states
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        let parameters = &self.parameters;
+        let x = &self.x;
+        let y = &self.y;
+        let context = self.context.as_pricing_context();
+        let spot_date = context.spot_date();
+        self.paths = self.timeline.fetch_bond_paths(context, x.shape()[0],
+            &|path, g, forward_df, date, maturity| parameters.zero_bond(
+                forward_df, year_fraction(spot_date, date),
+                year_fraction(spot_date, maturity), x[[path, g]], y[[path, g]]))?;
+        Ok(())
+    }
+}
+
+/// Simulates the two states and the integral of their sum on the given
+/// times, returning arrays indexed by path and time. The changes over each
+/// step are correlated gaussians, whose covariance depends only on the
+/// length of the step, so we decompose it once per step.
+fn fetch_states(parameters: &G2ppParameters, times: &[f64],
+    gaussians: &Array3<f64>)
+    -> Result<(Array2<f64>, Array2<f64>, Array2<f64>), qm::Error> {
+
+    let mut steps = Vec::with_capacity(times.len());
+    let mut previous = 0.0;
+    for t in times.iter() {
+        let dt = t - previous;
+        previous = *t;
+        if dt > 0.0 {
+            let covariance = parameters.step_covariance(dt);
+            let matrix = DMatrix::from_fn(4, 4, |i, j| covariance[i][j]);
+            let root = Cholesky::new(matrix).ok_or_else(|| qm::Error::new(
+                "G2++ step covariance is not positive definite"))?.unpack();
+            steps.push(Some((dt, root)));
+        } else {
+            steps.push(None);
+        }
+    }
+
+    let n_paths = gaussians.shape()[0];
+    let mut xs = Array2::<f64>::zeros((n_paths, times.len()));
+    let mut ys = Array2::<f64>::zeros((n_paths, times.len()));
+    let mut integrals = Array2::<f64>::zeros((n_paths, times.len()));
+    let (a, b) = (parameters.a, parameters.b);
+
+    for (((z, mut x_path), mut y_path), mut integral) in gaussians.outer_iter()
+        .zip(xs.outer_iter_mut()).zip(ys.outer_iter_mut())
+        .zip(integrals.outer_iter_mut()) {
+
+        let (mut x, mut y, mut total) = (0.0, 0.0, 0.0);
+        for (i, step) in steps.iter().enumerate() {
+            if let Some((dt, ref root)) = *step {
+                let mut eps = [0.0; 4];
+                for (r, e) in eps.iter_mut().enumerate() {
+                    for c in 0..r + 1 {
+                        *e += root[(r, c)] * z[[i, c]];
+                    }
+                }
+                total += x * decay_integral(a, dt) + y * decay_integral(b, dt)
+                    + eps[1] + eps[3];
+                x = x * (-a * dt).exp() + eps[0];
+                y = y * (-b * dt).exp() + eps[2];
+            }
+            x_path[i] = x;
+            y_path[i] = y;
+            integral[i] = total;
+        }
+    }
+
+    Ok((xs, ys, integrals))
+}
+
+impl MonteCarloModel for G2pp {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The two factors come from the parameters, so only bumps to the yield
+    /// curves or the spot date change the bond prices.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_rates_bump(bump))
+    }
+}
+
+impl MonteCarloContext for G2pp {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+        self.timeline.paths(&self.paths, instrument)
+    }
+
+    /// Flows on the stochastic curve are discounted along each path, by
+    /// P(0, T) exp(-I(T) - V(T) / 2), where I is the integral of x + y and
+    /// V its variance.
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        self.timeline.evaluate_flows(self.context.as_pricing_context(),
+            &self.flows, &self.deflators, quantities)
+    }
+
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+        self.timeline.evaluate_path_flows(self.context.as_pricing_context(),
+            &self.flows, &self.deflators, quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for G2pp {
+    type Paths = Vec<Array2<f64>>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Vec<Array2<f64>> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Vec<Array2<f64>>) {
+        self.paths = paths.clone();
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for G2pp {
+
+    /// Bumps the market data, then reconstitutes the bond prices from the
+    /// same states, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::DependencyContext;
+    use instruments::MonteCarloPriceable;
+    use instruments::bonds::ZeroCoupon;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::OptionSettlement;
+    use instruments::assets::RcCurrency;
+    use models::hullwhite::HullWhiteParameters;
+    use models::Threading;
+    use models::tests::round_trip;
+    use risk::cache::PricingContextPrefetch;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use instruments::options::PutOrCall;
+
+    fn sample_g2pp() -> G2ppParameters {
+        G2ppParameters::new(0.5, 0.01, 0.05, 0.008, -0.7).unwrap()
+    }
+
+    #[test]
+    fn one_factor_is_hull_white() {
+        let p = G2ppParameters::new(0.1, 0.01, 0.3, 0.0, 0.5).unwrap();
+        let hw = HullWhiteParameters::new(0.1, 0.01).unwrap();
+        let bond = p.zero_bond(0.9, 1.5, 4.0, 0.02, 0.0);
+        let expected = hw.zero_bond(0.9, 1.5, 4.0, 0.02);
+        assert!(approx_eq(bond, expected, 1e-14), "bond={} expected={}", bond, expected);
+        let vol = p.bond_option_vol(1.5, 4.0).unwrap();
+        let expected = hw.bond_option_vol(1.5, 4.0).unwrap();
+        assert!(approx_eq(vol, expected, 1e-14), "vol={} expected={}", vol, expected);
+    }
+
+    #[test]
+    fn step_covariance_is_consistent() {
+        // over one step from zero, the variance of the integral of x + y
+        // must match the unconditional one
+        let p = sample_g2pp();
+        let c = p.step_covariance(2.0);
+        let variance = c[1][1] + c[3][3] + c[1][3] + c[3][1];
+        let expected = p.integral_variance(2.0);
+        assert!(approx_eq(variance, expected, 1e-15),
+            "variance={} expected={}", variance, expected);
+    }
+
+    #[test]
+    fn monte_carlo_matches_closed_form() {
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let expiry = sample_expiry();
+        let maturity = Date::from_ymd(2020, 06, 01);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            "SampleBond", "OPT", currency,
+            DateTime::new(maturity, TimeOfDay::Open), maturity,
+            sample_settlement(0)))));
+
+        let factory = round_trip(&G2ppFactory::new("OPT", sample_g2pp(), 20000));
+
+        // the closed-form price pays at expiry and is valued at the spot
+        // date, but the option pays at its pay date and is valued at its
+        // settlement date, so adjust with the initial curve
+        let pay_date = sample_settlement(2).apply(expiry.date());
+        let yc = market_data.yield_curve("OPT", maturity).unwrap();
+        let df_expiry = yc.df(expiry.date(), spot_date).unwrap();
+        let df_maturity = yc.df(maturity, spot_date).unwrap();
+        let df_pay = yc.df(pay_date, expiry.date()).unwrap();
+        let settlement_date = sample_settlement(2).apply(spot_date);
+        let df_settlement = yc.df(settlement_date, spot_date).unwrap();
+        let t = year_fraction(spot_date, expiry.date());
+        let s = year_fraction(spot_date, maturity);
+        let forward = df_maturity / df_expiry;
+
+        for &(strike, put_or_call) in [(forward - 0.02, PutOrCall::Put),
+            (forward, PutOrCall::Call), (forward + 0.02, PutOrCall::Call)].iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                bond.clone(), sample_settlement(2), expiry, strike, put_or_call,
+                OptionSettlement::Cash).unwrap();
+
+            // as for Hull-White, build the model directly from the timeline
+            let mut timeline = MonteCarloTimeline::new(spot_date);
+            timeline.set_threading(Threading::new(1, Some(42)));
+            european.mc_dependencies(&[], &mut timeline).unwrap();
+            timeline.collate().unwrap();
+            let mut dependencies = DependencyCollector::new(spot_date);
+            dependencies.spot(&bond);
+            let context = PricingContextPrefetch::new(&market_data,
+                Arc::new(dependencies)).unwrap();
+            let model = factory.factory(&timeline, Box::new(context)).unwrap();
+            let price = european.mc_price(model.as_mc_context()).unwrap();
+
+            let expected = df_pay / df_settlement * match put_or_call {
+                PutOrCall::Call => sample_g2pp().zero_bond_call(
+                    df_expiry, df_maturity, strike, t, s).unwrap(),
+                PutOrCall::Put => sample_g2pp().zero_bond_put(
+                    df_expiry, df_maturity, strike, t, s).unwrap()
+            };
+
+            // seeded paths, so the result is repeatable; with two factors the
+            // spread across seeds is still under 3e-5 per option
+            assert!(approx_eq(price, expected, 1e-4),
+                "strike={} price={} expected={}", strike, price, expected);
+        }
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let maturity = Date::from_ymd(2020, 06, 01);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            "SampleBond", "OPT", currency,
+            DateTime::new(maturity, TimeOfDay::Open), maturity,
+            sample_settlement(0)))));
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", bond.clone(), sample_settlement(0), sample_expiry(),
+            0.9, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &bond);
+        let mut model = G2pp::new(&timeline, context, "OPT", sample_g2pp(), 100).unwrap();
+
+        // the bond prices only see the yield curve
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
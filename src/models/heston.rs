@@ -0,0 +1,995 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use statrs::distribution::Normal;
+use statrs::distribution::Univariate;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use math::complex::Complex;
+use math::fourier::CharacteristicFunction;
+use math::fourier;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::vol_times;
+use models::calculate_substepping;
+use models::BumpedPaths;
+use models::ForwardScaledPaths;
+use models::RegeneratedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use models::merton::LogNormalJumps;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The parameters of the Heston stochastic volatility model for one
+/// underlying:
+///
+///  dS/S = mu(t) dt + sqrt(v) dW1
+///  dv = kappa (theta - v) dt + xi sqrt(v) dW2
+///  dW1 dW2 = rho dt
+///
+/// where v0 is the initial variance, kappa the speed of mean reversion,
+/// theta the long-term variance and xi the volatility of variance. A
+/// negative rho gives the downward-sloping skew typical of equities, which
+/// a flat-vol diffusion cannot represent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct HestonParameters {
+    v0: f64,
+    kappa: f64,
+    theta: f64,
+    xi: f64,
+    rho: f64
+}
+
+impl HestonParameters {
+    pub fn new(v0: f64, kappa: f64, theta: f64, xi: f64, rho: f64)
+        -> Result<HestonParameters, qm::Error> {
+
+        if v0 < 0.0 || theta <= 0.0 {
+            return Err(qm::Error::new("Heston initial variance must be non-negative \
+                and long-term variance positive"))
+        }
+        if kappa <= 0.0 || xi <= 0.0 {
+            return Err(qm::Error::new("Heston mean reversion and vol of variance \
+                must be positive"))
+        }
+        if rho < -1.0 || rho > 1.0 {
+            return Err(qm::Error::new("Heston correlation must be between -1 and 1"))
+        }
+
+        Ok(HestonParameters { v0: v0, kappa: kappa, theta: theta, xi: xi, rho: rho })
+    }
+
+    pub fn v0(&self) -> f64 { self.v0 }
+    pub fn kappa(&self) -> f64 { self.kappa }
+    pub fn theta(&self) -> f64 { self.theta }
+    pub fn xi(&self) -> f64 { self.xi }
+    pub fn rho(&self) -> f64 { self.rho }
+
+    /// Semi-analytic price of a European call, by Fourier inversion of the
+    /// characteristic function
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::call_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic price of a European put
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::put_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic prices of a strip of European calls, from a single FFT
+    pub fn call_prices(&self, df: f64, forward: f64, strikes: &[f64], t: f64)
+        -> Result<Vec<f64>, qm::Error> {
+        fourier::fft_call_prices(self, df, forward, strikes, t)
+    }
+
+    /// Semi-analytic prices of a strip of European puts, from a single FFT
+    pub fn put_prices(&self, df: f64, forward: f64, strikes: &[f64], t: f64)
+        -> Result<Vec<f64>, qm::Error> {
+        fourier::fft_put_prices(self, df, forward, strikes, t)
+    }
+
+    /// Takes one step of the quadratic-exponential (QE) discretization of
+    /// Andersen (2008), with the martingale correction, so that the
+    /// underlying relative to its forward stays a martingale. Takes and
+    /// returns the log of the underlying relative to its forward, and the
+    /// variance. The two gaussians drive the variance and the part of the
+    /// underlying that is independent of it.
+    pub fn qe_step(&self, log_x: f64, v: f64, dt: f64, z_v: f64, z_x: f64,
+        normal: &Normal) -> (f64, f64) {
+
+        if dt <= 0.0 {
+            return (log_x, v)
+        }
+
+        // coefficients of the log step, with central weighting in time
+        let k1 = 0.5 * dt * (self.kappa * self.rho / self.xi - 0.5) - self.rho / self.xi;
+        let k2 = 0.5 * dt * (self.kappa * self.rho / self.xi - 0.5) + self.rho / self.xi;
+        let k3 = 0.5 * dt * (1.0 - self.rho * self.rho);
+        let a = k2 + 0.5 * k3;
+        let uncorrected = -self.rho * self.kappa * self.theta * dt / self.xi;
+
+        // Sample the variance, then find the drift that makes the step a
+        // martingale, which exists unless the time step is very large.
+        let qe = QeVariance::new(self.kappa, self.theta, self.xi, v, dt);
+        let v_next = qe.sample(z_v, normal);
+        let log_m = match qe {
+            QeVariance::Quadratic { b2, scale, .. } => if 2.0 * a * scale < 1.0 {
+                Some(a * b2 * scale / (1.0 - 2.0 * a * scale)
+                    - 0.5 * (1.0 - 2.0 * a * scale).ln())
+            } else {
+                None
+            },
+            QeVariance::Exponential { p, beta, .. } => if a < beta {
+                Some((p + beta * (1.0 - p) / (beta - a)).ln())
+            } else {
+                None
+            }
+        };
+
+        let k0 = match log_m {
+            Some(log_m) => -log_m - (k1 + 0.5 * k3) * v,
+            None => uncorrected
+        };
+        let log_x_next = log_x + k0 + k1 * v + k2 * v_next
+            + (k3 * (v + v_next)).sqrt() * z_x;
+        (log_x_next, v_next)
+    }
+}
+
+/// The QE approximation of Andersen (2008) to the distribution of a CIR
+/// process at the end of a step:
+///
+///  dv = kappa (theta - v) dt + xi sqrt(v) dW
+///
+/// given its value at the start. The variance is either a scaled
+/// non-central chi-squared with one degree of freedom (when it is large
+/// relative to its spread), or a mixture of a mass at zero and an
+/// exponential (when it is small). Either way, the first two moments match
+/// those of the exact distribution.
+#[derive(Clone, Copy, Debug)]
+pub enum QeVariance {
+    Quadratic { mean: f64, b2: f64, scale: f64 },
+    Exponential { mean: f64, p: f64, beta: f64 }
+}
+
+impl QeVariance {
+    pub fn new(kappa: f64, theta: f64, xi: f64, v: f64, dt: f64) -> QeVariance {
+
+        // moments of the variance at the end of the step
+        let decay = (-kappa * dt).exp();
+        let xi2 = xi * xi;
+        let m = theta + (v - theta) * decay;
+        let s2 = v * xi2 * decay * (1.0 - decay) / kappa
+            + theta * xi2 * (1.0 - decay) * (1.0 - decay) / (2.0 * kappa);
+        let psi = s2 / (m * m);
+
+        if psi <= 1.5 {
+            let b2 = 2.0 / psi - 1.0 + (2.0 / psi).sqrt() * (2.0 / psi - 1.0).sqrt();
+            QeVariance::Quadratic { mean: m, b2: b2, scale: m / (1.0 + b2) }
+        } else {
+            let p = (psi - 1.0) / (psi + 1.0);
+            QeVariance::Exponential { mean: m, p: p, beta: (1.0 - p) / m }
+        }
+    }
+
+    /// The expected variance at the end of the step
+    pub fn mean(&self) -> f64 {
+        match *self {
+            QeVariance::Quadratic { mean, .. } => mean,
+            QeVariance::Exponential { mean, .. } => mean
+        }
+    }
+
+    /// Samples the variance at the end of the step, given a gaussian
+    pub fn sample(&self, z: f64, normal: &Normal) -> f64 {
+        match *self {
+            QeVariance::Quadratic { b2, scale, .. } => {
+                let root = b2.sqrt() + z;
+                scale * root * root
+            },
+            QeVariance::Exponential { p, beta, .. } => {
+                let u = normal.cdf(z);
+                if u <= p { 0.0 } else { ((1.0 - p) / (1.0 - u)).ln() / beta }
+            }
+        }
+    }
+}
+
+impl CharacteristicFunction for HestonParameters {
+
+    /// Uses the formulation of Albrecher et al (2007), which avoids the
+    /// branch cut in the complex logarithm that affects the original.
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+        let zero = Complex::from_real(0.0);
+        let (c, dv) = self.riccati(u, t, zero, zero);
+        (c + dv * self.v0).exp()
+    }
+}
+
+impl HestonParameters {
+
+    /// Solves the Riccati equations of the characteristic function over
+    /// a time t for which the parameters are constant, given the values of
+    /// the coefficients at the end of the time. Returns the coefficients at
+    /// the start, so that the characteristic function is exp(c + dv * v).
+    fn riccati(&self, u: Complex, t: f64, c0: Complex, dv0: Complex)
+        -> (Complex, Complex) {
+
+        let xi2 = self.xi * self.xi;
+        let iu = Complex::i() * u;
+        let beta = (iu * (-self.rho * self.xi)) + self.kappa;
+        let d = (beta * beta + (iu + u * u) * xi2).sqrt();
+        let minus = beta - d;
+        let plus = beta + d;
+        let g = (minus - dv0 * xi2) / (plus - dv0 * xi2);
+        let decay = (-d * t).exp();
+        let one = Complex::from_real(1.0);
+        let log_term = ((one - g * decay) / (one - g)).ln();
+        let c = c0 + (minus * t - log_term * 2.0) * (self.kappa * self.theta / xi2);
+        let dv = (minus - plus * g * decay) / ((one - g * decay) * xi2);
+        (c, dv)
+    }
+}
+
+/// Heston parameters that are piecewise constant in time, so that the
+/// model can match the term structure of the vol surface as well as its
+/// skew. Each bucket has its own kappa, theta, xi and rho, and the initial
+/// variance is shared. The bucket boundaries are in vol time, as for the
+/// time steps, and the last bucket extends indefinitely.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PiecewiseHestonParameters {
+    v0: f64,
+    ends: Vec<f64>,
+    buckets: Vec<HestonParameters>
+}
+
+impl PiecewiseHestonParameters {
+
+    /// Creates piecewise parameters given the initial variance, the end
+    /// of each bucket but the last, and the parameters of each bucket.
+    pub fn new(v0: f64, ends: &[f64], kappa: &[f64], theta: &[f64],
+        xi: &[f64], rho: &[f64]) -> Result<PiecewiseHestonParameters, qm::Error> {
+
+        let n = kappa.len();
+        if n == 0 || ends.len() + 1 != n || theta.len() != n || xi.len() != n
+            || rho.len() != n {
+            return Err(qm::Error::new("Piecewise Heston needs parameters for \
+                at least one bucket, and one fewer bucket end"))
+        }
+        let mut previous = 0.0;
+        for &end in ends.iter() {
+            if end <= previous {
+                return Err(qm::Error::new("Piecewise Heston bucket ends must be \
+                    positive and increasing"))
+            }
+            previous = end;
+        }
+
+        let mut buckets = Vec::with_capacity(n);
+        for i in 0..n {
+            buckets.push(HestonParameters::new(v0, kappa[i], theta[i], xi[i], rho[i])?);
+        }
+
+        Ok(PiecewiseHestonParameters { v0: v0, ends: ends.to_vec(),
+            buckets: buckets })
+    }
+
+    pub fn v0(&self) -> f64 { self.v0 }
+    pub fn ends(&self) -> &[f64] { &self.ends }
+    pub fn buckets(&self) -> &[HestonParameters] { &self.buckets }
+
+    /// The parameters that apply at the given vol time
+    pub fn bucket(&self, t: f64) -> &HestonParameters {
+        let i = self.ends.iter().take_while(|&&end| end <= t).count();
+        &self.buckets[i]
+    }
+
+    /// Semi-analytic price of a European call
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::call_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic price of a European put
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::put_price(self, df, forward, strike, t)
+    }
+}
+
+impl From<HestonParameters> for PiecewiseHestonParameters {
+    fn from(parameters: HestonParameters) -> PiecewiseHestonParameters {
+        PiecewiseHestonParameters { v0: parameters.v0, ends: Vec::new(),
+            buckets: vec![parameters] }
+    }
+}
+
+impl CharacteristicFunction for PiecewiseHestonParameters {
+
+    /// Solves the Riccati equations backwards in time through the buckets,
+    /// as in Mikhailov and Nogel (2003), starting from the bucket that
+    /// contains t.
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+        let mut c = Complex::from_real(0.0);
+        let mut dv = Complex::from_real(0.0);
+        let mut end = t;
+        for (i, bucket) in self.buckets.iter().enumerate().rev() {
+            let start = if i == 0 { 0.0 } else { self.ends[i - 1] };
+            if start >= end {
+                continue
+            }
+            let (c_start, dv_start) = bucket.riccati(u, end - start, c, dv);
+            c = c_start;
+            dv = dv_start;
+            end = start;
+        }
+        (c + dv * self.v0).exp()
+    }
+}
+
+/// The HestonFactory creates a Heston model, given the timeline of the
+/// product(s) to value and the market data. The Heston parameters for each
+/// underlying are held by the factory, keyed by the id of the underlying,
+/// as the market data only contains implied vols. The factory also holds
+/// the maximum time step and the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HestonFactory {
+    parameters: HashMap<String, HestonParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl HestonFactory {
+    pub fn new(parameters: HashMap<String, HestonParameters>, time_step: f64,
+        number_of_paths: usize) -> HestonFactory {
+
+        HestonFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(HestonFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for HestonFactory {
+    fn type_id(&self) -> &'static str { "HestonFactory" }
+}
+
+impl MonteCarloModelFactory for HestonFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Heston::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// The PiecewiseHestonFactory creates a Heston model whose parameters are
+/// piecewise constant in time, given the timeline of the product(s) to
+/// value and the market data. Otherwise it is the same as HestonFactory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PiecewiseHestonFactory {
+    parameters: HashMap<String, PiecewiseHestonParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl PiecewiseHestonFactory {
+    pub fn new(parameters: HashMap<String, PiecewiseHestonParameters>,
+        time_step: f64, number_of_paths: usize) -> PiecewiseHestonFactory {
+
+        PiecewiseHestonFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(PiecewiseHestonFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for PiecewiseHestonFactory {
+    fn type_id(&self) -> &'static str { "PiecewiseHestonFactory" }
+}
+
+impl MonteCarloModelFactory for PiecewiseHestonFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Heston::piecewise(timeline, context, &self.parameters,
+            &HashMap::new(), self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A Heston model evolves each underlying with its own stochastic variance,
+/// using the QE scheme. The underlyings are correlated with each other via
+/// the correlations in the market data, and each with its own variance via
+/// rho. The variance processes are independent of each other.
+///
+/// The drift comes from the forward curve in the market data, as for
+/// BlackDiffusion, so the model reprices forwards, dividends and rates.
+/// The vol surface only supplies the measure of time, which is the
+/// business-day vol time, so the Heston parameters are in the same units as
+/// the implied vols. The levels of the implied vols are not used, so vol
+/// bumps have no effect on the price: calibrate new parameters instead.
+///
+/// The parameters may be piecewise constant in time, in which case each
+/// substep uses the parameters of the bucket containing its midpoint.
+///
+/// Any underlying may also have log-normal jumps in its spot, which makes
+/// it the Bates model. The jumps are independent of everything else.
+///
+/// Quanto underlyings and displaced vol surfaces are not supported.
+#[derive(Clone)]
+pub struct Heston {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<PiecewiseHestonParameters>,
+    jumps: Vec<Option<LogNormalJumps>>,
+    substepping: Vec<usize>,
+    spot_gaussians: Array3<f64>,
+    correlated_gaussians: Array3<f64>,
+    variance_gaussians: Array3<f64>,
+    jump_gaussians: Option<(Array3<f64>, Array3<f64>)>,
+    paths: ForwardScaledPaths
+}
+
+impl Heston {
+
+    /// Creates a new Heston model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the Heston parameters by underlying id, the maximum step in vol time
+    /// and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, HestonParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<Heston, qm::Error> {
+
+        Heston::with_jumps(timeline, context, parameters, &HashMap::new(),
+            time_step, n_paths)
+    }
+
+    /// Creates a new Heston model with jumps in the spot of the underlyings
+    /// given by id in the jumps map. Other underlyings have no jumps.
+    pub fn with_jumps(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, HestonParameters>,
+        jumps: &HashMap<String, LogNormalJumps>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<Heston, qm::Error> {
+
+        let piecewise = parameters.iter()
+            .map(|(id, p)| (id.clone(), PiecewiseHestonParameters::from(*p)))
+            .collect();
+        Heston::piecewise(timeline, context, &piecewise, jumps, time_step, n_paths)
+    }
+
+    /// Creates a new Heston model with parameters that are piecewise
+    /// constant in time, and jumps as for with_jumps.
+    pub fn piecewise(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, PiecewiseHestonParameters>,
+        jumps: &HashMap<String, LogNormalJumps>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<Heston, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("Heston time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("Heston does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "Heston")?;
+        let asset_jumps: Vec<_> = instruments.iter()
+            .map(|asset| jumps.get(asset.id()).cloned()).collect();
+
+        let substepping = calculate_substepping(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let variance_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let jump_gaussians = if asset_jumps.iter().any(|j| j.is_some()) {
+            Some((source.fetch(&substepping, n_assets, n_paths)?,
+                source.fetch(&substepping, n_assets, n_paths)?))
+        } else {
+            None
+        };
+        let correlated_gaussians = correlate_gaussians(context.as_pricing_context(),
+            &instruments, &observations, &substepping, &spot_gaussians)?;
+        let paths = fetch_paths(&observations, &correlated_gaussians,
+            &variance_gaussians, &jump_gaussians, context.as_pricing_context(),
+            &instruments, &asset_parameters, &asset_jumps, &substepping)?;
+
+        Ok(Heston {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            jumps: asset_jumps,
+            substepping: substepping,
+            spot_gaussians: spot_gaussians,
+            correlated_gaussians: correlated_gaussians,
+            variance_gaussians: variance_gaussians,
+            jump_gaussians: jump_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers,
+    /// correlating them again
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.correlated_gaussians = correlate_gaussians(
+            self.context.as_pricing_context(), &self.instruments,
+            &self.observations, &self.substepping, &self.spot_gaussians)?;
+        self.paths = fetch_paths(&self.observations, &self.correlated_gaussians,
+            &self.variance_gaussians, &self.jump_gaussians,
+            self.context.as_pricing_context(), &self.instruments,
+            &self.parameters, &self.jumps, &self.substepping)?;
+        Ok(())
+    }
+
+    /// Rescales the paths of the underlying with the given id to its
+    /// forwards, if it is one of ours
+    pub fn refetch_forwards(&mut self, id: &str) -> Result<(), qm::Error> {
+        if let Some(&asset) = self.key.get(id) {
+            let forwards = fetch_forwards(&*self.instruments[asset],
+                self.context.as_pricing_context(), &self.observations)?;
+            self.paths.rescale(asset, &forwards);
+        }
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    correlated_gaussians: &Array3<f64>,
+    variance_gaussians: &Array3<f64>,
+    jump_gaussians: &Option<(Array3<f64>, Array3<f64>)>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[PiecewiseHestonParameters],
+    jumps: &[Option<LogNormalJumps>],
+    substepping: &[usize]) -> Result<ForwardScaledPaths, qm::Error> {
+
+    let n_paths = correlated_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut drivers = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+    let mut forwards = Vec::with_capacity(n_assets);
+
+    // the underlyings are correlated with each other, but not their variances
+    for (asset, ((((instrument, p), spot), variance), driver)) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated_gaussians.axis_iter(Axis(2)))
+        .zip(variance_gaussians.axis_iter(Axis(2)))
+        .zip(drivers.axis_iter_mut(Axis(2))).enumerate() {
+
+        let asset_jumps = match (&jumps[asset], jump_gaussians) {
+            (&Some(ref j), &Some((ref counts, ref sizes))) => Some((j,
+                counts.subview(Axis(2), asset), sizes.subview(Axis(2), asset))),
+            _ => None
+        };
+        forwards.push(fetch_forwards(instrument.deref(), context, observations)?);
+        fetch_driver(instrument.deref(), p, asset_jumps, context, observations,
+            spot, variance, substepping, driver)?;
+    }
+    Ok(ForwardScaledPaths::new(drivers, &forwards))
+}
+
+/// Fetches the forwards of a single asset at the observations
+fn fetch_forwards(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction]) -> Result<Vec<f64>, qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("Heston does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+    Ok(forwards)
+}
+
+/// Fetches the drivers for a single asset, which are its paths divided by
+/// its forwards, so they do not depend on the forwards
+fn fetch_driver(instrument: &Instrument, parameters: &PiecewiseHestonParameters,
+    jumps: Option<(&LogNormalJumps, ArrayView2<f64>, ArrayView2<f64>)>,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, variance_gaussians: ArrayView2<f64>,
+    substepping: &[usize], mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for (time, substep) in times.iter().zip(substepping.iter()) {
+        steps.push((time - previous) / (*substep as f64));
+        previous = *time;
+    }
+
+    let normal = match Normal::new(0.0, 1.0) {
+        Ok(normal) => normal,
+        Err(e) => return Err(qm::Error::new(&format!("RSStat error: {}", e)))
+    };
+    for (p, ((z_x, z_v), mut one_path)) in spot_gaussians.outer_iter()
+        .zip(variance_gaussians.outer_iter()).zip(path.outer_iter_mut())
+        .enumerate() {
+
+        let mut log_x = 0.0;
+        let mut v = parameters.v0;
+        let mut g = 0;
+        let mut start = 0.0;
+        for i in 0..observations.len() {
+            for _ in 0..substepping[i] {
+                // each substep uses the bucket containing its midpoint
+                let bucket = parameters.bucket(start + 0.5 * steps[i]);
+                start += steps[i];
+                let (next_x, next_v) = bucket.qe_step(log_x, v, steps[i],
+                    z_v[g], z_x[g], &normal);
+                log_x = next_x;
+                v = next_v;
+                if let Some((j, ref counts, ref sizes)) = jumps {
+                    log_x += j.sample(steps[i], counts[[p, g]], sizes[[p, g]], &normal);
+                }
+                g += 1;
+            }
+            one_path[i] = log_x.exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for Heston {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for Heston {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Heston does not know about '{}'", id)))?;
+        Ok(self.paths.paths().subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for Heston {
+    type Paths = (ForwardScaledPaths, Array3<f64>);
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> (ForwardScaledPaths, Array3<f64>) {
+        (self.paths.clone(), self.correlated_gaussians.clone())
+    }
+
+    fn set_paths(&mut self, paths: &(ForwardScaledPaths, Array3<f64>)) {
+        let &(ref scaled, ref correlated) = paths;
+        self.paths = scaled.clone();
+        self.correlated_gaussians.assign(correlated);
+    }
+
+    fn regenerate_paths(&mut self, changes: &BumpedPaths) -> Result<(), qm::Error> {
+        match changes {
+            &BumpedPaths::Unchanged => Ok(()),
+            &BumpedPaths::Forwards(ref ids) => {
+                for id in ids.iter() {
+                    self.refetch_forwards(id)?;
+                }
+                Ok(())
+            },
+            &BumpedPaths::All => self.refetch_all()
+        }
+    }
+}
+
+impl Bumpable for Heston {
+
+    /// Bumps the market data, then updates the paths with the same random
+    /// numbers. Bumps to forwards or rates only rescale the paths of the
+    /// underlyings they move, and bumps to vol levels leave the paths
+    /// unchanged. Bumps to correlations or the spot date simulate all the
+    /// paths again.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::optionpricing::Black76;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::Pricer;
+    use models::RcMonteCarloModelFactory;
+    use models::Threading;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_expiry;
+    use data::bumpspot::BumpSpot;
+    use data::bumpdivs::BumpDivs;
+    use data::bumpyield::BumpYield;
+    use data::bumpvol::BumpVol;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+
+    fn skewed() -> HestonParameters {
+        HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap()
+    }
+
+    #[test]
+    fn characteristic_function_is_martingale() {
+        let p = skewed();
+        let phi = p.characteristic_function(Complex::new(0.0, -1.0), 1.5);
+        assert!(approx_eq(phi.re, 1.0, 1e-12) && approx_eq(phi.im, 0.0, 1e-12),
+            "phi={:?}", phi);
+        let phi = p.characteristic_function(Complex::from_real(0.0), 1.5);
+        assert!(approx_eq(phi.re, 1.0, 1e-12), "phi={:?}", phi);
+    }
+
+    #[test]
+    fn heston_without_vol_of_variance_is_black() {
+        let p = HestonParameters::new(0.09, 1.0, 0.09, 1e-3, 0.0).unwrap();
+        let black76 = Black76::new().unwrap();
+        for &strike in [70.0, 100.0, 130.0].iter() {
+            let expected = black76.call_price(0.95, 100.0, strike, 0.3 * 2.0_f64.sqrt());
+            let call = p.call_price(0.95, 100.0, strike, 2.0).unwrap();
+            assert!(approx_eq(call, expected, 1e-5),
+                "strike={} call={} expected={}", strike, call, expected);
+        }
+    }
+
+    #[test]
+    fn negative_correlation_gives_skew() {
+        // compared with the flat-vol price at the same overall variance,
+        // low strike puts are dearer and high strike calls cheaper
+        let p = HestonParameters::new(0.09, 1.0, 0.09, 0.5, -0.7).unwrap();
+        let black76 = Black76::new().unwrap();
+        let sqrt_var = 0.3;
+        let put = p.put_price(1.0, 100.0, 70.0, 1.0).unwrap();
+        assert!(put > black76.put_price(1.0, 100.0, 70.0, sqrt_var));
+        let call = p.call_price(1.0, 100.0, 130.0, 1.0).unwrap();
+        assert!(call < black76.call_price(1.0, 100.0, 130.0, sqrt_var));
+    }
+
+    #[test]
+    fn fft_matches_lewis() {
+        // a whole strip from one transform, against pricing each strike
+        let p = skewed();
+        let strikes = [60.0, 80.0, 95.0, 100.0, 105.0, 120.0, 150.0];
+        for &t in [0.5, 1.0_f64, 2.0].iter() {
+            let calls = p.call_prices(0.9, 100.0, &strikes, t).unwrap();
+            let puts = p.put_prices(0.9, 100.0, &strikes, t).unwrap();
+            for (i, &strike) in strikes.iter().enumerate() {
+                let expected = p.call_price(0.9, 100.0, strike, t).unwrap();
+                assert!(approx_eq(calls[i], expected, 1e-5),
+                    "strike={} t={} call={} expected={}", strike, t, calls[i], expected);
+                let expected = p.put_price(0.9, 100.0, strike, t).unwrap();
+                assert!(approx_eq(puts[i], expected, 1e-5),
+                    "strike={} t={} put={} expected={}", strike, t, puts[i], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn piecewise_buckets_chain_together() {
+        // splitting constant parameters into buckets changes nothing
+        let p = skewed();
+        let split = PiecewiseHestonParameters::new(0.09, &[0.5, 1.0],
+            &[2.0, 2.0, 2.0], &[0.08, 0.08, 0.08], &[0.6, 0.6, 0.6],
+            &[-0.7, -0.7, -0.7]).unwrap();
+        for &(re, t) in [(0.5, 0.3), (1.5, 0.75), (3.0, 2.0)].iter() {
+            let u = Complex::new(re, -0.5);
+            let expected = p.characteristic_function(u, t);
+            let phi = split.characteristic_function(u, t);
+            assert!(approx_eq(phi.re, expected.re, 1e-12)
+                && approx_eq(phi.im, expected.im, 1e-12),
+                "t={} phi={:?} expected={:?}", t, phi, expected);
+        }
+
+        // differing buckets still give a martingale
+        let term = term_structure();
+        let phi = term.characteristic_function(Complex::new(0.0, -1.0), 1.5);
+        assert!(approx_eq(phi.re, 1.0, 1e-12) && approx_eq(phi.im, 0.0, 1e-12),
+            "phi={:?}", phi);
+        assert_eq!(term.bucket(0.2).kappa(), 3.0);
+        assert_eq!(term.bucket(0.5).kappa(), 1.5);
+
+        assert!(PiecewiseHestonParameters::new(0.09, &[1.0, 0.5],
+            &[2.0, 2.0, 2.0], &[0.08, 0.08, 0.08], &[0.6, 0.6, 0.6],
+            &[-0.7, -0.7, -0.7]).is_err());
+        assert!(PiecewiseHestonParameters::new(0.09, &[1.0],
+            &[2.0], &[0.08], &[0.6], &[-0.7]).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_matches_semi_analytic() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), skewed());
+        let factory = round_trip(&HestonFactory::new(parameters, 1.0 / 52.0, 20000));
+        check_monte_carlo(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &PiecewiseHestonParameters::from(skewed()));
+    }
+
+    #[test]
+    fn piecewise_monte_carlo_matches_semi_analytic() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), term_structure());
+        let factory = round_trip(&PiecewiseHestonFactory::new(parameters,
+            1.0 / 52.0, 20000));
+        check_monte_carlo(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &term_structure());
+    }
+
+    fn term_structure() -> PiecewiseHestonParameters {
+        PiecewiseHestonParameters::new(0.09, &[0.5, 1.0], &[3.0, 1.5, 1.0],
+            &[0.06, 0.1, 0.08], &[0.8, 0.5, 0.4], &[-0.8, -0.6, -0.3]).unwrap()
+    }
+
+    fn check_monte_carlo(model_factory: RcMonteCarloModelFactory,
+        parameters: &PiecewiseHestonParameters) {
+
+        // The paths are seeded, so this is deterministic. The standard
+        // errors are up to 0.14, and the monthly QE steps add a little
+        // bias, so allow about three standard errors.
+        check_monte_carlo_europeans(model_factory, &sample_market_data(),
+            sample_expiry(),
+            &[(80.0, PutOrCall::Put), (100.0, PutOrCall::Call), (120.0, PutOrCall::Call)],
+            0.4, &|terms, strike, put_or_call| match put_or_call {
+                PutOrCall::Call => parameters.call_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap(),
+                PutOrCall::Put => parameters.put_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap()
+            });
+    }
+
+    #[test]
+    fn bumps_rescale_the_same_paths() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), skewed());
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            HestonFactory::new(parameters, 1.0 / 12.0, 1000)));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let settings = MonteCarloSettings { threading: Threading::new(1, Some(42)),
+            ..MonteCarloSettings::default() };
+        let price = |market_data: &MarketData| MonteCarloPricer::with_settings(
+            vec![(1.0, instrument.clone())], model_factory.clone(), settings.clone(),
+            market_data).unwrap().price().unwrap();
+        let market_data = sample_market_data();
+        let mut pricer = MonteCarloPricer::with_settings(
+            vec![(1.0, instrument.clone())], model_factory.clone(), settings.clone(),
+            &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.new_saveable();
+
+        // rescaling the paths after a bump to the forwards gives the same
+        // price as simulating them again on the bumped market data
+        for (i, bump) in [Bump::new_spot("BP.L", BumpSpot::new_relative(0.01)),
+            Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.1)),
+            Bump::new_yield("LSE", BumpYield::new_flat_annualised(0.01))].iter()
+            .enumerate() {
+            assert!(pricer.as_mut_bumpable().bump(bump, Some(&mut *save)).unwrap());
+            let bumped = pricer.price().unwrap();
+            let mut bumped_market_data = market_data.clone();
+            bumped_market_data.bump(bump, None).unwrap();
+            let expected = price(&bumped_market_data);
+            assert!(approx_eq(bumped, expected, 1e-10) && bumped != unbumped,
+                "bump={} bumped={} expected={}", i, bumped, expected);
+            pricer.as_mut_bumpable().restore(&*save).unwrap();
+            save.clear();
+            assert_eq!(pricer.price().unwrap(), unbumped);
+        }
+
+        // the paths do not see the vol surface
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            skewed());
+        let mut model = Heston::new(&timeline, context,
+            &parameters, 1.0 / 12.0, 100).unwrap();
+
+        // neither the drivers nor the forwards see the vol levels
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use nalgebra::base::DMatrix;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::Axis;
+use core::qm;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use math::correlation::correlation_root;
+use dates::datetime::DateDayFraction;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::collect_underlyings_with;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::hullwhite::HullWhiteParameters;
+use models::hullwhite::ShortRateTimeline;
+use models::hullwhite::year_fraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The EquityRatesHybridFactory creates a hybrid of Black equity diffusions
+/// and Hull-White rates, given the timeline of the product(s) to value and
+/// the market data. The factory holds the credit id of the yield curve
+/// whose rates are stochastic, the Hull-White parameters, the correlation
+/// of each underlying with the short rate, keyed by underlying id, and the
+/// number of paths. There is no time step, as each period between dates is
+/// simulated exactly.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EquityRatesHybridFactory {
+    credit_id: String,
+    parameters: HullWhiteParameters,
+    rates_correlations: HashMap<String, f64>,
+    number_of_paths: usize
+}
+
+impl EquityRatesHybridFactory {
+    pub fn new(credit_id: &str, parameters: HullWhiteParameters,
+        rates_correlations: HashMap<String, f64>, number_of_paths: usize)
+        -> EquityRatesHybridFactory {
+
+        EquityRatesHybridFactory { credit_id: credit_id.to_string(),
+            parameters: parameters, rates_correlations: rates_correlations,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(EquityRatesHybridFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for EquityRatesHybridFactory {
+    fn type_id(&self) -> &'static str { "EquityRatesHybridFactory" }
+}
+
+impl MonteCarloModelFactory for EquityRatesHybridFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = EquityRatesHybrid::new(timeline, context, &self.credit_id,
+            self.parameters, &self.rates_correlations, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// An equity-rates hybrid evolves each underlying as a log-normal diffusion
+/// whose drift is the stochastic short rate of a Hull-White curve, and
+/// whose Brownian motion is correlated with that of the short rate:
+///
+///  dS/S = (r(t) - q(t)) dt + sigma(t) dW
+///  dr = (theta(t) - a r) dt + sigma_r dW_r
+///  dW dW_r = rho dt
+///
+/// The deterministic part of the drift, including dividends and borrow,
+/// comes from the forward curve in the market data, and sigma(t) from the
+/// at the money variances on the vol surface, so the underlying's own vol
+/// is that of the surface, and Europeans pick up extra variance from the
+/// rates. Writing the Hull-White short rate as x + alpha, with integral I,
+/// the underlying is
+///
+///  S(t) = F(0, t) exp(I(t) + VI(t) / 2) exp(W(t) - V(t) / 2)
+///
+/// where VI is the variance of I and V that of W, so that the underlying
+/// deflated along each path is a martingale. Flows on the stochastic curve
+/// are discounted along each path, as for HullWhite, so long-dated payoffs
+/// that are correlated with rates, such as autocallables, are valued with
+/// stochastic discounting.
+///
+/// The states are simulated jointly and exactly on a grid of the
+/// observation and payment dates, with the vol assumed constant in
+/// calendar time between observations. All underlyings must share the same
+/// observations. Quanto underlyings and displaced vol surfaces are not
+/// supported.
+#[derive(Clone)]
+pub struct EquityRatesHybrid {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    rates_correlations: Vec<f64>,
+    timeline: ShortRateTimeline,
+    gaussians: Array3<f64>,
+    integrals: Array2<f64>,
+    deflators: Array2<f64>,
+    paths: Array3<f64>
+}
+
+impl EquityRatesHybrid {
+
+    /// Creates a new equity-rates hybrid, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the credit id of the stochastic curve, the Hull-White parameters,
+    /// the correlations of the underlyings with the short rate and the
+    /// number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        credit_id: &str,
+        parameters: HullWhiteParameters,
+        rates_correlations: &HashMap<String, f64>,
+        n_paths: usize)
+        -> Result<EquityRatesHybrid, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new(
+                "Equity-rates hybrid does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, correlations) =
+            collect_underlyings_with(timeline, |id| {
+                let rho = *rates_correlations.get(id).ok_or_else(|| qm::Error::new(
+                    &format!("No equity-rates correlation for '{}'", id)))?;
+                if rho < -1.0 || rho > 1.0 {
+                    return Err(qm::Error::new(
+                        "Equity-rates correlation must be between minus one and one"))
+                }
+                Ok(rho)
+            })?;
+        if instruments.is_empty() {
+            return Err(qm::Error::new("Equity-rates hybrid has no underlyings"))
+        }
+
+        let spot_date = context.as_pricing_context().spot_date();
+        let dates: Vec<_> = observations.iter().map(|o| o.date()).collect();
+        let rates_timeline = ShortRateTimeline::for_dates(timeline, credit_id,
+            spot_date, &dates)?;
+
+        // The rates use the first gaussian and the last two, and the
+        // underlyings those in between. The rates depend only on the
+        // Hull-White parameters, so they are simulated once, and the
+        // gaussians kept uncorrelated so the underlyings can be refetched
+        // with the same random numbers after any bump.
+        let times = rates_timeline.times();
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let gaussians = source.fetch(&vec![1; times.len()], n_assets + 3, n_paths)?;
+        let mut integrals = fetch_integrals(&parameters, &times, &gaussians);
+        let mut deflators = integrals.clone();
+        for (mut column, t) in deflators.axis_iter_mut(Axis(1)).zip(times.iter()) {
+            let convexity = 0.5 * parameters.integral_variance(*t);
+            column.mapv_inplace(|integral| (-integral - convexity).exp());
+        }
+
+        // the integrals are stored with their convexity, ready to grow
+        // the underlyings
+        for (mut column, t) in integrals.axis_iter_mut(Axis(1)).zip(times.iter()) {
+            let convexity = 0.5 * parameters.integral_variance(*t);
+            column.mapv_inplace(|integral| integral + convexity);
+        }
+
+        let mut model = EquityRatesHybrid {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            rates_correlations: correlations,
+            timeline: rates_timeline,
+            gaussians: gaussians,
+            integrals: integrals,
+            deflators: deflators,
+            paths: Array3::<f64>::zeros((0, 0, 0)) };
+        model.refetch_all()?;
+        Ok(model)
+    }
+
+    /// Refetch all paths for all underlyings, using the same random numbers
+    /// and rates
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(self.context.as_pricing_context(),
+            &self.instruments, &self.rates_correlations, &self.observations,
+            &self.timeline, &self.gaussians, &self.integrals)?;
+        Ok(())
+    }
+}
+
+/// Simulates the integral of the Hull-White state jointly with the short
+/// rate Brownian motion, returning an array indexed by path and time.
+/// Over each step, the normalised Brownian increment is the first
+/// gaussian, and the changes in the state and its integral are built from
+/// it and the last two gaussians, using the Cholesky root of their
+/// covariances.
+fn fetch_integrals(parameters: &HullWhiteParameters, times: &[f64],
+    gaussians: &Array3<f64>) -> Array2<f64> {
+
+    let n_paths = gaussians.shape()[0];
+    let n_factors = gaussians.shape()[2];
+    let mut integrals = Array2::<f64>::zeros((n_paths, times.len()));
+    let a = parameters.mean_reversion();
+    let sigma = parameters.vol();
+
+    // the covariances only depend on the length of each step
+    let mut roots = Vec::with_capacity(times.len());
+    let mut previous = 0.0;
+    for t in times.iter() {
+        let dt = t - previous;
+        previous = *t;
+        roots.push(if dt > 0.0 {
+            let b = parameters.b(dt);
+            let c_x = sigma * b / dt.sqrt();
+            let c_i = sigma * (dt - b) / (a * dt.sqrt());
+            let var_x = parameters.state_variance(dt);
+            let var_i = parameters.integral_variance(dt);
+            let covar = 0.5 * sigma * sigma * b * b;
+            let l_xx = (var_x - c_x * c_x).max(0.0).sqrt();
+            let l_ix = if l_xx > 0.0 { (covar - c_i * c_x) / l_xx } else { 0.0 };
+            let l_ii = (var_i - c_i * c_i - l_ix * l_ix).max(0.0).sqrt();
+            Some(((-a * dt).exp(), b, c_x, l_xx, c_i, l_ix, l_ii))
+        } else {
+            None
+        });
+    }
+
+    for (z, mut integral) in gaussians.outer_iter().zip(integrals.outer_iter_mut()) {
+        let mut x = 0.0;
+        let mut total = 0.0;
+        for (i, root) in roots.iter().enumerate() {
+            if let Some((decay, b, c_x, l_xx, c_i, l_ix, l_ii)) = *root {
+                let w = z[[i, 0]];
+                let z_x = z[[i, n_factors - 2]];
+                let z_i = z[[i, n_factors - 1]];
+                total += x * b + c_i * w + l_ix * z_x + l_ii * z_i;
+                x = x * decay + c_x * w + l_xx * z_x;
+            }
+            integral[i] = total;
+        }
+    }
+
+    integrals
+}
+
+/// Fetches the paths of all the underlyings, returning an array indexed by
+/// path, observation and underlying. The integrals are those of the state,
+/// including their convexity.
+fn fetch_paths(context: &PricingContext, instruments: &[RcInstrument],
+    rates_correlations: &[f64], observations: &[DateDayFraction],
+    timeline: &ShortRateTimeline, gaussians: &Array3<f64>,
+    integrals: &Array2<f64>) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let times = timeline.times();
+    let spot_date = timeline.spot_date();
+    let hwm = observations.last().unwrap().date();
+
+    // the forwards on each observation date, and the variances over each
+    // grid step, interpolated linearly in calendar time between the
+    // observations
+    let obs_times: Vec<f64> = observations.iter()
+        .map(|o| year_fraction(spot_date, o.date())).collect();
+    let mut forwards = Vec::with_capacity(n_assets);
+    let mut step_variances = Vec::with_capacity(n_assets);
+    for instrument in instruments.iter() {
+        let forward_curve = context.forward_curve(instrument.deref(), hwm)?;
+        let vol_surface = context.vol_surface(instrument.deref(), hwm,
+            &|| Ok(forward_curve.clone()))?;
+        let mut asset_forwards = Vec::with_capacity(observations.len());
+        let mut variances = Vec::with_capacity(observations.len());
+        for obs in observations.iter() {
+            if vol_surface.displacement(obs.date())? != 0.0 {
+                return Err(qm::Error::new(
+                    "Equity-rates hybrid does not support displaced vol surfaces"))
+            }
+            let forward = forward_curve.forward(obs.date())?;
+            variances.push(vol_surface.variance(*obs, forward)?);
+            asset_forwards.push(forward);
+        }
+        forwards.push(asset_forwards);
+
+        let mut steps = Vec::with_capacity(times.len());
+        let mut previous = 0.0;
+        for t in times.iter() {
+            let variance = interpolate_variance(&obs_times, &variances, *t);
+            if variance < previous {
+                return Err(qm::Error::new("Negative forward variance"))
+            }
+            steps.push((variance - previous).sqrt());
+            previous = variance;
+        }
+        step_variances.push(steps);
+    }
+
+    // correlate the rates Brownian motion, which comes first, with the
+    // underlyings, using the nearest valid matrix if need be
+    let n = n_assets + 1;
+    let mut correlation = DMatrix::<f64>::identity(n, n);
+    for i in 0..n_assets {
+        correlation[(0, i + 1)] = rates_correlations[i];
+        correlation[(i + 1, 0)] = rates_correlations[i];
+        for j in 0..i {
+            let c = context.correlation(instruments[i].deref(),
+                instruments[j].deref())?;
+            correlation[(i + 1, j + 1)] = c;
+            correlation[(j + 1, i + 1)] = c;
+        }
+    }
+    let root = correlation_root(correlation)?;
+
+    let grid_indices = observations.iter().map(|o| timeline.grid_index(o.date()))
+        .collect::<Result<Vec<usize>, qm::Error>>()?;
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+    for ((z, integral), mut path) in gaussians.outer_iter()
+        .zip(integrals.outer_iter()).zip(paths.outer_iter_mut()) {
+
+        let mut log_w = vec![0.0; n_assets];
+        let mut g = 0;
+        for (k, grid_index) in grid_indices.iter().enumerate() {
+            // walk the underlyings along to this observation
+            while g <= *grid_index {
+                for (i, w) in log_w.iter_mut().enumerate() {
+                    let mut correlated = 0.0;
+                    for j in 0..(i + 2) {
+                        correlated += root[(i + 1, j)] * z[[g, j]];
+                    }
+                    let sqrt_variance = step_variances[i][g];
+                    *w += sqrt_variance * correlated
+                        - 0.5 * sqrt_variance * sqrt_variance;
+                }
+                g += 1;
+            }
+
+            for (i, w) in log_w.iter().enumerate() {
+                path[[k, i]] = forwards[i][k] * (integral[*grid_index] + w).exp();
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Interpolates the variances on the observation times linearly in
+/// calendar time, starting from zero and flat beyond the last observation
+fn interpolate_variance(times: &[f64], variances: &[f64], t: f64) -> f64 {
+    let mut previous_time = 0.0;
+    let mut previous_variance = 0.0;
+    for (time, variance) in times.iter().zip(variances.iter()) {
+        if t <= *time {
+            if *time <= previous_time {
+                return *variance
+            }
+            return previous_variance + (variance - previous_variance)
+                * (t - previous_time) / (time - previous_time)
+        }
+        previous_time = *time;
+        previous_variance = *variance;
+    }
+    previous_variance
+}
+
+impl MonteCarloModel for EquityRatesHybrid {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The equity vols come from the vol surfaces, so bumps to the vols of
+    /// the underlyings change the paths, as do bumps to forwards, rates or
+    /// correlations.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump_with_vols(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for EquityRatesHybrid {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("EquityRatesHybrid does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    /// Flows on the stochastic curve are discounted along each path, as for
+    /// HullWhite. Other pure-rates flows are valued deterministically.
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        self.timeline.evaluate_flows(self.context.as_pricing_context(),
+            &self.flows, &self.deflators, quantities)
+    }
+
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+        self.timeline.evaluate_path_flows(self.context.as_pricing_context(),
+            &self.flows, &self.deflators, quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for EquityRatesHybrid {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for EquityRatesHybrid {
+
+    /// Bumps the market data, then regenerates the paths of the underlyings
+    /// with the same random numbers and rates, if the bump changes them.
+    /// Bumps to the stochastic yield curve change the initial curve used for
+    /// discounting, but the Hull-White states are unchanged.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::optionpricing::Black76;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_settlement;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::round_trip;
+    use models::tests::EuropeanTerms;
+    use models::tests::sample_european_terms;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    #[test]
+    fn hybrid_european_includes_rates_variance() {
+        let market_data = sample_market_data();
+        let expiry = sample_expiry();
+        let parameters = HullWhiteParameters::new(0.1, 0.02).unwrap();
+        let rho = 0.7;
+        let mut correlations = HashMap::new();
+        correlations.insert("BP.L".to_string(), rho);
+        let factory = round_trip(&EquityRatesHybridFactory::new("OPT", parameters,
+            correlations, 20000));
+        let black76 = Black76::new().unwrap();
+
+        // Under the forward measure, the forward is log-normal, with the
+        // variance of the underlying, plus that of the bond to expiry and
+        // their covariance
+        let t = year_fraction(sample_val_date().date(), expiry.date());
+        let total_sqrt_variance = |terms: &EuropeanTerms| {
+            let variance = terms.sqrt_variance * terms.sqrt_variance;
+            let a = parameters.mean_reversion();
+            let sigma_r = parameters.vol();
+            (variance + parameters.integral_variance(t) + 2.0 * rho
+                * (variance / t).sqrt() * sigma_r * (t - parameters.b(t)) / a).sqrt()
+        };
+        let hybrid_price = |terms: &EuropeanTerms, strike: f64, put_or_call: PutOrCall| {
+            let sqrt_variance = total_sqrt_variance(terms);
+            match put_or_call {
+                PutOrCall::Call => black76.call_price(terms.df, terms.forward, strike,
+                    sqrt_variance),
+                PutOrCall::Put => black76.put_price(terms.df, terms.forward, strike,
+                    sqrt_variance)
+            }
+        };
+
+        // The paths are seeded, so the comparison is repeatable.
+        // Stochastic rates widen the spread of discounted payoffs, giving
+        // standard errors up to 0.19.
+        let options = [(80.0, PutOrCall::Put), (100.0, PutOrCall::Call),
+            (120.0, PutOrCall::Call)];
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &market_data, expiry, &options, 0.6, &hybrid_price);
+
+        // positively correlated rates add to the variance
+        let terms = sample_european_terms(&market_data, expiry);
+        for &(strike, put_or_call) in options.iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), expiry, strike, put_or_call,
+                OptionSettlement::Cash).unwrap();
+            let deterministic = european.price(&market_data, sample_val_date()).unwrap();
+            let expected = hybrid_price(&terms, strike, put_or_call);
+            assert!(expected > deterministic + 0.2,
+                "expected={} deterministic={}", expected, deterministic);
+        }
+    }
+
+    #[test]
+    fn bump_to_other_vol_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut correlations = HashMap::new();
+        correlations.insert("BP.L".to_string(), -0.3);
+        let mut model = EquityRatesHybrid::new(&timeline, context, "OPT",
+            HullWhiteParameters::new(0.1, 0.02).unwrap(), &correlations, 100).unwrap();
+
+        // the paths of BP.L do not see the vols of GSK.L
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use statrs::distribution::Normal;
+use statrs::distribution::Univariate;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::ArrayView2;
+use ndarray::Axis;
+use core::qm;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use dates::datetime::DateDayFraction;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::RcMonteCarloModelFactory;
+use models::PathGeneration;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// What happens to an underlying on default. The issuer id identifies the
+/// hazard curve, whose discount factors are survival probabilities, and the
+/// recovery is the fraction of its value the underlying keeps on default.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DefaultTerms {
+    issuer_id: String,
+    recovery: f64
+}
+
+impl DefaultTerms {
+    pub fn new(issuer_id: &str, recovery: f64) -> Result<DefaultTerms, qm::Error> {
+        if recovery < 0.0 || recovery > 1.0 {
+            return Err(qm::Error::new("Default recovery must be between zero and one"))
+        }
+        Ok(DefaultTerms { issuer_id: issuer_id.to_string(), recovery: recovery })
+    }
+
+    pub fn issuer_id(&self) -> &str { &self.issuer_id }
+    pub fn recovery(&self) -> f64 { self.recovery }
+}
+
+/// The JumpToDefaultFactory overlays default on the underlyings of any
+/// other Monte-Carlo model. It holds the factory of the model to overlay,
+/// and the default terms of each underlying that may default, keyed by its
+/// id. Other underlyings are left as they are.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JumpToDefaultFactory {
+    model: RcMonteCarloModelFactory,
+    defaults: HashMap<String, DefaultTerms>
+}
+
+impl JumpToDefaultFactory {
+    pub fn new(model: RcMonteCarloModelFactory,
+        defaults: HashMap<String, DefaultTerms>) -> JumpToDefaultFactory {
+        JumpToDefaultFactory { model: model, defaults: defaults }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(JumpToDefaultFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for JumpToDefaultFactory {
+    fn type_id(&self) -> &'static str { "JumpToDefaultFactory" }
+}
+
+impl MonteCarloModelFactory for JumpToDefaultFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = self.model.factory(timeline, context)?;
+        let overlay = JumpToDefault::new(timeline, model, &self.defaults)?;
+        Ok(Box::new(overlay))
+    }
+}
+
+/// A jump-to-default overlay lets the underlyings of another model default,
+/// at a random time given by the hazard curve of the issuer. On default,
+/// the underlying drops to the recovery fraction of its value, and then
+/// carries on evolving as in the overlaid model. Before default, it grows
+/// by the loss given default times the hazard rate, which compensates for
+/// the expected loss, so the forwards are unchanged:
+///
+///  S(t) = S'(t) Q(t)^-(1 - R)         before default
+///  S(t) = S'(t) R Q(tau)^-(1 - R)     after default at tau
+///
+/// where S' is the underlying in the overlaid model, Q the survival
+/// probability and R the recovery. Defaults are independent of the overlaid
+/// model, and of each other. They are seen at the first observation on or
+/// after the default time, when path-dependent instruments may settle per
+/// their default terms.
+///
+/// Flows are valued by the overlaid model, so any credit risk of the
+/// payments themselves should be in their yield curve.
+#[derive(Clone)]
+pub struct JumpToDefault {
+    model: Box<MonteCarloModel>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    terms: Vec<DefaultTerms>,
+    observations: Vec<Vec<DateDayFraction>>,
+    survivals: Array2<f64>,
+    default_steps: Vec<Vec<usize>>,
+    paths: Vec<Array2<f64>>
+}
+
+impl JumpToDefault {
+
+    /// Creates a jump-to-default overlay, given the timeline of the
+    /// instrument(s) we want to price, the model to overlay and the default
+    /// terms by underlying id.
+    pub fn new(timeline: &MonteCarloTimeline, model: Box<MonteCarloModel>,
+        defaults: &HashMap<String, DefaultTerms>)
+        -> Result<JumpToDefault, qm::Error> {
+
+        let mut key = HashMap::new();
+        let mut instruments = Vec::new();
+        let mut terms = Vec::new();
+        let mut observations = Vec::new();
+        for (asset, obs) in timeline.observations().iter() {
+            if let Some(t) = defaults.get(asset.id()) {
+                key.insert(asset.id().to_string(), instruments.len());
+                instruments.push(asset.clone());
+                terms.push(t.clone());
+                observations.push(obs.to_vec());
+            }
+        }
+
+        // Each path and underlying has a uniform survival probability, at
+        // which it defaults. These are independent of the market data, so
+        // the same defaults are used after any bump.
+        let n_paths = model.paths(timeline.observations().keys().next()
+            .ok_or_else(|| qm::Error::new("Jump to default has no underlyings"))?)?
+            .shape()[0];
+        let normal = match Normal::new(0.0, 1.0) {
+            Ok(normal) => normal,
+            Err(e) => return Err(qm::Error::new(&format!("RSStat error: {}", e)))
+        };
+        let survivals = if instruments.is_empty() {
+            Array2::<f64>::zeros((n_paths, 0))
+        } else {
+            let mut source = GaussianSource::with_threading(
+                PathGeneration::PseudoRandom, timeline.threading().for_overlay("default"));
+            let gaussians = source.fetch(&[1], instruments.len(), n_paths)?;
+            gaussians.subview(Axis(1), 0).mapv(|z| normal.cdf(z))
+        };
+
+        let mut overlay = JumpToDefault {
+            model: model,
+            key: key,
+            instruments: instruments,
+            terms: terms,
+            observations: observations,
+            survivals: survivals,
+            default_steps: Vec::new(),
+            paths: Vec::new() };
+        overlay.refetch_all()?;
+        Ok(overlay)
+    }
+
+    /// Reapplies the defaults to the paths of the overlaid model
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        let context = self.model.pricing_context();
+        let spot_date = context.spot_date();
+        let mut paths = Vec::with_capacity(self.instruments.len());
+        let mut default_steps = Vec::with_capacity(self.instruments.len());
+        for (((instrument, terms), observations), survivals) in self.instruments.iter()
+            .zip(self.terms.iter()).zip(self.observations.iter())
+            .zip(self.survivals.axis_iter(Axis(1))) {
+
+            let hwm = observations.last().unwrap().date();
+            let hazard = context.hazard_curve(&terms.issuer_id, hwm)?;
+            let mut curve = Vec::with_capacity(observations.len());
+            for obs in observations.iter() {
+                curve.push(hazard.df(obs.date(), spot_date)?);
+            }
+            let loss = 1.0 - terms.recovery;
+            let growth: Vec<f64> = curve.iter().map(|q| q.powf(-loss)).collect();
+
+            let mut path = self.model.paths(instrument)?.to_owned();
+            let mut steps = Vec::with_capacity(path.shape()[0]);
+            for (mut one_path, u) in path.outer_iter_mut().zip(survivals.iter()) {
+                // default happens once the survival probability falls below
+                // the uniform, which is then the survival at default
+                let step = curve.iter().position(|q| q < u)
+                    .unwrap_or(observations.len());
+                let jump = terms.recovery * u.powf(-loss);
+                for (i, value) in one_path.iter_mut().enumerate() {
+                    *value *= if i < step { growth[i] } else { jump };
+                }
+                steps.push(step);
+            }
+            paths.push(path);
+            default_steps.push(steps);
+        }
+
+        self.paths = paths;
+        self.default_steps = default_steps;
+        Ok(())
+    }
+}
+
+impl MonteCarloModel for JumpToDefault {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.model.raw_market_data() }
+
+    /// Hazard bumps move the default times. Other bumps change the paths
+    /// only if they change the paths of the overlaid model.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        if let &Bump::Hazard(_, _) = bump {
+            return Ok(BumpedPaths::All)
+        }
+        Ok(match self.model.bumped_paths(bump)? {
+            BumpedPaths::Unchanged => BumpedPaths::Unchanged,
+            _ => BumpedPaths::All
+        })
+    }
+}
+
+impl MonteCarloContext for JumpToDefault {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        match self.key.get(instrument.id()) {
+            Some(asset) => Ok(self.paths[*asset].view()),
+            None => self.model.paths(instrument)
+        }
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        self.model.evaluate_flows(quantities)
+    }
+
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+        self.model.evaluate_path_flows(quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        self.model.pricing_context()
+    }
+
+    fn threads(&self) -> usize {
+        self.model.threads()
+    }
+
+    fn default_steps(&self, instrument: &RcInstrument)
+        -> Result<Option<&[usize]>, qm::Error> {
+
+        match self.key.get(instrument.id()) {
+            Some(asset) => Ok(Some(&self.default_steps[*asset])),
+            None => self.model.default_steps(instrument)
+        }
+    }
+}
+
+impl RegeneratedPaths for JumpToDefault {
+    type Paths = (Vec<Array2<f64>>, Vec<Vec<usize>>);
+
+    fn bumpable_data(&self) -> &Bumpable { self.model.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.model.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> (Vec<Array2<f64>>, Vec<Vec<usize>>) {
+        (self.paths.clone(), self.default_steps.clone())
+    }
+
+    fn set_paths(&mut self, paths: &(Vec<Array2<f64>>, Vec<Vec<usize>>)) {
+        let &(ref defaulted, ref default_steps) = paths;
+        self.paths = defaulted.clone();
+        self.default_steps = default_steps.clone();
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for JumpToDefault {
+
+    /// Bumps the overlaid model, then reapplies the defaults to its paths,
+    /// with the same uniforms, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.model.as_bumpable().dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.model.pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::optionpricing::Black76;
+    use instruments::DependencyContext;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use data::curves::RateCurveAct365;
+    use data::curves::RcRateCurve;
+    use math::interpolation::Extrap;
+    use risk::cache::PricingContextPrefetch;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::tests::round_trip;
+    use models::tests::check_monte_carlo_europeans;
+    use dates::Date;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+    use models::heston::HestonFactory;
+    use models::heston::HestonParameters;
+
+    fn sample_default_market_data(hazard_rate: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        let d = Date::from_ymd(2017, 01, 02);
+        let hazard = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, hazard_rate), (d + 1820, hazard_rate)],
+            Extrap::Flat, Extrap::Flat).unwrap()));
+        market_data.add_hazard_curve("BP.ISSUER", hazard);
+        market_data
+    }
+
+    fn sample_factory(recovery: f64) -> JumpToDefaultFactory {
+        let mut defaults = HashMap::new();
+        defaults.insert("BP.L".to_string(),
+            DefaultTerms::new("BP.ISSUER", recovery).unwrap());
+        let model = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000)));
+        JumpToDefaultFactory::new(model, defaults)
+    }
+
+    #[test]
+    fn european_with_zero_recovery() {
+        let market_data = sample_default_market_data(0.05);
+        let expiry = sample_expiry();
+        let factory = round_trip(&sample_factory(0.0));
+        let survival = market_data.hazard_curve("BP.ISSUER", expiry.date()).unwrap()
+            .df(expiry.date(), sample_val_date().date()).unwrap();
+        let black76 = Black76::new().unwrap();
+
+        // With zero recovery, the underlying is worth nothing after default,
+        // and log-normal with forward F / Q if it survives. The paths are
+        // seeded. The surviving paths are scaled up by 1 / Q, so the noise
+        // is larger than without default: the standard errors are up to
+        // 0.21, and this allows about three.
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &market_data, expiry,
+            &[(80.0, PutOrCall::Put), (100.0, PutOrCall::Call), (120.0, PutOrCall::Call)],
+            0.65, &|terms, strike, put_or_call| match put_or_call {
+                PutOrCall::Call => survival * black76.call_price(terms.df,
+                    terms.forward / survival, strike, terms.sqrt_variance),
+                PutOrCall::Put => survival * black76.put_price(terms.df,
+                    terms.forward / survival, strike, terms.sqrt_variance)
+                    + (1.0 - survival) * terms.df * strike
+            });
+    }
+
+    #[test]
+    fn defaults_follow_hazard_curve() {
+        let market_data = sample_default_market_data(0.1);
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", equity.clone(), sample_settlement(2), expiry,
+            100.0, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        european.as_mc_priceable().unwrap().mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&european);
+        let context = PricingContextPrefetch::new(&market_data,
+            Arc::new(dependencies)).unwrap();
+        let model = sample_factory(0.4).factory(&timeline, Box::new(context)).unwrap();
+
+        // the fraction of paths defaulting matches the hazard curve, and
+        // the forward is unchanged by the defaults
+        let survival = market_data.hazard_curve("BP.ISSUER", expiry.date()).unwrap()
+            .df(expiry.date(), spot_date).unwrap();
+        let steps = model.default_steps(&equity).unwrap().unwrap();
+        let defaulted = steps.iter().filter(|s| **s == 0).count() as f64
+            / steps.len() as f64;
+        assert!(approx_eq(defaulted, 1.0 - survival, 0.015),
+            "defaulted={} survival={}", defaulted, survival);
+
+        let paths = model.paths(&equity).unwrap();
+        let mean = paths.scalar_sum() / steps.len() as f64;
+        let forward = market_data.forward_curve(&*equity, expiry.date()).unwrap()
+            .forward(expiry.date()).unwrap();
+        assert!(approx_eq(mean, forward, 1.0), "mean={} forward={}", mean, forward);
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_default_market_data(0.05);
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap());
+        let heston = HestonFactory::new(parameters, 1.0 / 12.0, 100)
+            .factory(&timeline, context).unwrap();
+        let mut defaults = HashMap::new();
+        defaults.insert("BP.L".to_string(), DefaultTerms::new("BP.ISSUER", 0.4).unwrap());
+        let mut model = JumpToDefault::new(&timeline, heston, &defaults).unwrap();
+
+        // the Heston paths do not see the vol levels, so neither do the
+        // defaults applied to them
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
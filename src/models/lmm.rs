@@ -0,0 +1,663 @@
+use std::sync::Arc;
+use nalgebra::linalg::Cholesky;
+use nalgebra::base::DMatrix;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::Axis;
+use core::qm;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use dates::Date;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::hullwhite::ShortRateTimeline;
+use models::hullwhite::year_fraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Number of intervals for Simpson's rule, which must be even.
+const INTEGRATION_STEPS: usize = 16;
+
+/// The shape of the instantaneous vol of a forward rate, as a function of
+/// its time to fixing tau:
+///
+///  g(tau) = (a + b tau) exp(-c tau) + d
+///
+/// This is Rebonato's abcd form. With b positive, it has the hump seen in
+/// caplet vols, and it decays to d for forwards that fix far in the future.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AbcdVol {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64
+}
+
+impl AbcdVol {
+    pub fn new(a: f64, b: f64, c: f64, d: f64) -> Result<AbcdVol, qm::Error> {
+        if c < 0.0 || d < 0.0 {
+            return Err(qm::Error::new("abcd vol must have non-negative c and d"))
+        }
+        if a + d <= 0.0 {
+            return Err(qm::Error::new("abcd vol must be positive at fixing"))
+        }
+        Ok(AbcdVol { a: a, b: b, c: c, d: d })
+    }
+
+    pub fn vol(&self, tau: f64) -> f64 {
+        let tau = tau.max(0.0);
+        (self.a + self.b * tau) * (-self.c * tau).exp() + self.d
+    }
+
+    /// The integral from t0 to t1 of the product of the vols of forwards
+    /// fixing at ti and tj
+    pub fn covariance(&self, t0: f64, t1: f64, ti: f64, tj: f64) -> f64 {
+        let h = (t1 - t0) / INTEGRATION_STEPS as f64;
+        let integrand = |s: f64| self.vol(ti - s) * self.vol(tj - s);
+        let mut sum = integrand(t0) + integrand(t1);
+        for i in 1..INTEGRATION_STEPS {
+            let weight = if i % 2 == 1 { 4.0 } else { 2.0 };
+            sum += weight * integrand(t0 + i as f64 * h);
+        }
+        sum * h / 3.0
+    }
+}
+
+/// The parameters of a LIBOR market model, where each forward rate F_i
+/// evolves log-normally:
+///
+///  dF_i / F_i = mu_i dt + k_i g(T_i - t) dW_i
+///  dW_i dW_j = exp(-beta |T_i - T_j|) dt
+///
+/// The vol shape g is shared by all forwards, and the scale factors k_i
+/// are calibrated so that each forward reprices its caplet.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LmmParameters {
+    vol: AbcdVol,
+    correlation_decay: f64
+}
+
+impl LmmParameters {
+    pub fn new(vol: AbcdVol, correlation_decay: f64)
+        -> Result<LmmParameters, qm::Error> {
+
+        if correlation_decay < 0.0 {
+            return Err(qm::Error::new("LMM correlation decay must not be negative"))
+        }
+        Ok(LmmParameters { vol: vol, correlation_decay: correlation_decay })
+    }
+
+    pub fn vol(&self) -> &AbcdVol { &self.vol }
+    pub fn correlation_decay(&self) -> f64 { self.correlation_decay }
+
+    /// The correlation between forwards fixing at ti and tj
+    pub fn correlation(&self, ti: f64, tj: f64) -> f64 {
+        (-self.correlation_decay * (ti - tj).abs()).exp()
+    }
+
+    /// Calibrates the scale factors of forwards fixing at the given times,
+    /// so that their root mean square vols to fixing match the given
+    /// caplet vols.
+    pub fn calibrate(&self, expiries: &[f64], caplet_vols: &[f64])
+        -> Result<Vec<f64>, qm::Error> {
+
+        assert_eq!(expiries.len(), caplet_vols.len());
+        let mut scales = Vec::with_capacity(expiries.len());
+        for (&t, &caplet_vol) in expiries.iter().zip(caplet_vols.iter()) {
+            if t <= 0.0 {
+                return Err(qm::Error::new("LMM caplets must expire in the future"))
+            }
+            if caplet_vol < 0.0 {
+                return Err(qm::Error::new("LMM caplet vols must not be negative"))
+            }
+            let variance = self.vol.covariance(0.0, t, t, t);
+            scales.push(caplet_vol * (t / variance).sqrt());
+        }
+        Ok(scales)
+    }
+}
+
+/// The LmmFactory creates a LIBOR market model, given the timeline of the
+/// product(s) to value and the market data. The factory holds the credit
+/// id of the stochastic curve, the id of the vol cube of caplet vols to
+/// calibrate to, the parameters, the maximum time step and the number of
+/// paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LmmFactory {
+    credit_id: String,
+    vol_cube_id: String,
+    parameters: LmmParameters,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl LmmFactory {
+    pub fn new(credit_id: &str, vol_cube_id: &str, parameters: LmmParameters,
+        time_step: f64, number_of_paths: usize) -> LmmFactory {
+
+        LmmFactory { credit_id: credit_id.to_string(),
+            vol_cube_id: vol_cube_id.to_string(), parameters: parameters,
+            time_step: time_step, number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(LmmFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for LmmFactory {
+    fn type_id(&self) -> &'static str { "LmmFactory" }
+}
+
+impl MonteCarloModelFactory for LmmFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Lmm::new(timeline, context, &self.credit_id,
+            &self.vol_cube_id, self.parameters, self.time_step,
+            self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A LIBOR market model makes the rates of one yield curve stochastic,
+/// by simulating the forward rates between consecutive dates of a tenor
+/// structure. It is used in the same way as HullWhite: the underlyings
+/// must be zero coupon bonds on the stochastic curve, and flows on that
+/// curve are discounted along each path.
+///
+/// The tenor structure is the spot date, the observation and settlement
+/// dates of the bonds, their maturities and the payment dates of the flows,
+/// so the bond prices are products of simulated forwards. The paths are
+/// generated under the spot measure, whose numeraire rolls over at each
+/// tenor date, using a log-Euler scheme with a predictor-corrector drift.
+/// The forwards are calibrated to the ATM caplet vols in the vol cube, so
+/// vol cube bumps, as well as yield curve bumps, change the paths.
+#[derive(Clone)]
+pub struct Lmm {
+    vol_cube_id: String,
+    parameters: LmmParameters,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    timeline: ShortRateTimeline,
+    tenors: Vec<Date>,
+    substepping: Vec<usize>,
+    gaussians: Array3<f64>,
+    deflators: Array2<f64>,
+    paths: Vec<Array2<f64>>
+}
+
+impl Lmm {
+
+    /// Creates a new LIBOR market model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the credit id of the stochastic curve, the id of the caplet vol
+    /// cube, the parameters, the maximum time step in years and the number
+    /// of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        credit_id: &str,
+        vol_cube_id: &str,
+        parameters: LmmParameters,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<Lmm, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("LMM does not support quanto underlyings"))
+        }
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("LMM time step must be positive"))
+        }
+
+        let spot_date = context.as_pricing_context().spot_date();
+        let rates_timeline = ShortRateTimeline::new(timeline, credit_id, spot_date)?;
+
+        // every bond price we need must be a product of forwards
+        let mut tenors = rates_timeline.grid().to_vec();
+        tenors.push(spot_date);
+        for (instrument, observations) in rates_timeline.instruments().iter()
+            .zip(rates_timeline.observations().iter()) {
+            let bond = instrument.as_fixed_payment().unwrap();
+            tenors.push(bond.payment_date());
+            for obs in observations.iter() {
+                tenors.push(bond.settlement().apply(obs.date()));
+            }
+        }
+        tenors.sort();
+        tenors.dedup();
+        if tenors.len() < 2 {
+            return Err(qm::Error::new("LMM needs at least one forward period"))
+        }
+
+        let mut substepping = Vec::with_capacity(tenors.len() - 1);
+        for pair in tenors.windows(2) {
+            let dt = year_fraction(pair[0], pair[1]);
+            substepping.push(((dt / time_step).ceil() as usize).max(1));
+        }
+        let mut source = GaussianSource::from_timeline(timeline);
+        let gaussians = source.fetch(&substepping, tenors.len() - 1, n_paths)?;
+
+        let mut model = Lmm {
+            vol_cube_id: vol_cube_id.to_string(),
+            parameters: parameters,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            timeline: rates_timeline,
+            tenors: tenors,
+            substepping: substepping,
+            gaussians: gaussians,
+            deflators: Array2::zeros((0, 0)),
+            paths: Vec::new() };
+        model.refetch_all()?;
+        Ok(model)
+    }
+
+    /// Recalibrates to the caplet vols, then refetches all paths, using the
+    /// same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        let context = self.context.as_pricing_context();
+        let spot_date = context.spot_date();
+        let last = self.tenors[self.tenors.len() - 1];
+        let yc = context.yield_curve(self.timeline.credit_id(), last)?;
+        let cube = context.vol_cube(&self.vol_cube_id, last)?;
+
+        let times: Vec<f64> = self.tenors.iter()
+            .map(|d| year_fraction(spot_date, *d)).collect();
+        let n_forwards = self.tenors.len() - 1;
+        let mut deltas = Vec::with_capacity(n_forwards);
+        let mut initial = Vec::with_capacity(n_forwards);
+        let mut caplet_vols = Vec::with_capacity(n_forwards);
+        for (i, pair) in self.tenors.windows(2).enumerate() {
+            let delta = times[i + 1] - times[i];
+            let forward = (1.0 / yc.df(pair[1], pair[0])? - 1.0) / delta;
+            if forward <= 0.0 {
+                return Err(qm::Error::new("LMM needs positive forward rates"))
+            }
+
+            // the first forward fixes at the spot date, so needs no vol
+            if i > 0 {
+                caplet_vols.push(cube.volatility(pair[0], delta, forward, forward)?);
+            }
+            deltas.push(delta);
+            initial.push(forward);
+        }
+        let mut scales = vec![0.0];
+        scales.extend(self.parameters.calibrate(&times[1..n_forwards], &caplet_vols)?);
+
+        let forwards = fetch_forwards(&self.parameters, &scales, &times,
+            &initial, &self.substepping, &self.gaussians)?;
+
+        // The deflator at each tenor date is one over the product of the
+        // spot numeraire and the initial discount factor
+        let n_paths = self.gaussians.shape()[0];
+        let grid = self.timeline.grid();
+        let mut deflators = Array2::<f64>::zeros((n_paths, grid.len()));
+        for (g, date) in grid.iter().enumerate() {
+            let k = tenor_index(&self.tenors, *date);
+            let df = yc.df(*date, spot_date)?;
+            for (p, deflator) in deflators.subview_mut(Axis(1), g).iter_mut().enumerate() {
+                let mut numeraire = 1.0;
+                for j in 0..k {
+                    numeraire *= 1.0 + deltas[j] * forwards[[p, j, j]];
+                }
+                *deflator = 1.0 / (numeraire * df);
+            }
+        }
+
+        let tenors = &self.tenors;
+        let paths = self.timeline.fetch_bond_paths(context, n_paths,
+            &|p, _, _, date, maturity| {
+                let k = tenor_index(tenors, date);
+                let m = tenor_index(tenors, maturity);
+                let mut bond = 1.0;
+                for j in k..m {
+                    bond /= 1.0 + deltas[j] * forwards[[p, k, j]];
+                }
+                bond
+            })?;
+
+        self.deflators = deflators;
+        self.paths = paths;
+        Ok(())
+    }
+}
+
+/// The position of a date in the tenor structure. The constructor ensures
+/// that all the dates we look up are there.
+fn tenor_index(tenors: &[Date], date: Date) -> usize {
+    tenors.binary_search(&date).unwrap_or_else(|i| i)
+}
+
+/// Simulates the forwards, returning an array indexed by path, tenor date
+/// and forward. Forwards that have fixed keep their fixings. The covariance
+/// of the alive forwards over each substep depends only on the times, so
+/// we decompose it once per substep.
+fn fetch_forwards(parameters: &LmmParameters, scales: &[f64], times: &[f64],
+    initial: &[f64], substepping: &[usize], gaussians: &Array3<f64>)
+    -> Result<Array3<f64>, qm::Error> {
+
+    let n_forwards = initial.len();
+    let mut steps = Vec::new();
+    for k in 0..n_forwards {
+        let first = k + 1;
+        let n_alive = n_forwards - first;
+        let n_sub = substepping[k];
+        let dt = (times[k + 1] - times[k]) / n_sub as f64;
+        for m in 0..n_sub {
+            if n_alive == 0 {
+                steps.push(None);
+                continue;
+            }
+            let t0 = times[k] + m as f64 * dt;
+            let t1 = t0 + dt;
+            let covariance = DMatrix::from_fn(n_alive, n_alive, |i, j| {
+                let (ti, tj) = (times[first + i], times[first + j]);
+                scales[first + i] * scales[first + j] * parameters.correlation(ti, tj)
+                    * parameters.vol.covariance(t0, t1, ti, tj)
+            });
+            let root = Cholesky::new(covariance.clone()).ok_or_else(|| qm::Error::new(
+                "LMM forward covariance is not positive definite"))?.unpack();
+            steps.push(Some((first, covariance, root)));
+        }
+    }
+
+    let n_paths = gaussians.shape()[0];
+    let mut forwards = Array3::<f64>::zeros((n_paths, times.len(), n_forwards));
+    let deltas: Vec<f64> = times.windows(2).map(|t| t[1] - t[0]).collect();
+    // The spot measure drift of each alive forward over the substep, given
+    // the log forwards at the start of it
+    let drift = |log_f: &[f64], first: usize, covariance: &DMatrix<f64>, out: &mut [f64]| {
+        for j in first..log_f.len() {
+            let jj = j - first;
+            let mut sum = 0.0;
+            for i in first..j + 1 {
+                let fi = log_f[i].exp();
+                sum += deltas[i] * fi / (1.0 + deltas[i] * fi) * covariance[(i - first, jj)];
+            }
+            out[j] = sum - 0.5 * covariance[(jj, jj)];
+        }
+    };
+
+    for (z, mut path) in gaussians.outer_iter().zip(forwards.outer_iter_mut()) {
+        let mut log_f: Vec<f64> = initial.iter().map(|f| f.ln()).collect();
+        let mut predicted = log_f.clone();
+        let mut drift0 = vec![0.0; n_forwards];
+        let mut drift1 = vec![0.0; n_forwards];
+        let mut diffusion = vec![0.0; n_forwards];
+        for (j, f) in initial.iter().enumerate() {
+            path[[0, j]] = *f;
+        }
+
+        let mut s = 0;
+        for k in 0..n_forwards {
+            for _ in 0..substepping[k] {
+                if let Some((first, ref covariance, ref root)) = steps[s] {
+                    for j in first..n_forwards {
+                        let jj = j - first;
+                        let mut d = 0.0;
+                        for c in 0..jj + 1 {
+                            d += root[(jj, c)] * z[[s, c]];
+                        }
+                        diffusion[j] = d;
+                    }
+                    drift(&log_f, first, covariance, &mut drift0);
+                    for j in first..n_forwards {
+                        predicted[j] = log_f[j] + drift0[j] + diffusion[j];
+                    }
+                    drift(&predicted, first, covariance, &mut drift1);
+                    for j in first..n_forwards {
+                        log_f[j] += 0.5 * (drift0[j] + drift1[j]) + diffusion[j];
+                    }
+                }
+                s += 1;
+            }
+            for j in 0..n_forwards {
+                path[[k + 1, j]] = log_f[j].exp();
+            }
+        }
+    }
+
+    Ok(forwards)
+}
+
+impl MonteCarloModel for Lmm {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The forward rates are calibrated to the caplet vols of the vol cube,
+    /// so bumps to the vol cubes change the paths, as do bumps to the yield
+    /// curves or the spot date.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(match bump {
+            &Bump::VolCube(_, _) => BumpedPaths::All,
+            _ => BumpedPaths::of_rates_bump(bump)
+        })
+    }
+}
+
+impl MonteCarloContext for Lmm {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+        self.timeline.paths(&self.paths, instrument)
+    }
+
+    /// Flows on the stochastic curve are discounted along each path by the
+    /// spot numeraire.
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        self.timeline.evaluate_flows(self.context.as_pricing_context(),
+            &self.flows, &self.deflators, quantities)
+    }
+
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+        self.timeline.evaluate_path_flows(self.context.as_pricing_context(),
+            &self.flows, &self.deflators, quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for Lmm {
+    type Paths = (Vec<Array2<f64>>, Array2<f64>);
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> (Vec<Array2<f64>>, Array2<f64>) {
+        (self.paths.clone(), self.deflators.clone())
+    }
+
+    fn set_paths(&mut self, paths: &(Vec<Array2<f64>>, Array2<f64>)) {
+        let &(ref bonds, ref deflators) = paths;
+        self.paths = bonds.clone();
+        self.deflators.assign(deflators);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for Lmm {
+
+    /// Bumps the market data, then recalibrates and regenerates all the
+    /// paths with the same random numbers, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::optionpricing::Black76;
+    use instruments::DependencyContext;
+    use instruments::MonteCarloPriceable;
+    use instruments::bonds::ZeroCoupon;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::assets::RcCurrency;
+    use data::volcube::FlatVolCube;
+    use data::volcube::RcVolCube;
+    use risk::cache::PricingContextPrefetch;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_expiry;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use models::Threading;
+    use models::tests::round_trip;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use dates::Date;
+
+    fn sample_lmm() -> LmmParameters {
+        LmmParameters::new(AbcdVol::new(0.1, 0.5, 1.5, 0.15).unwrap(), 0.1).unwrap()
+    }
+
+    #[test]
+    fn calibration_reprices_caplets() {
+        let p = sample_lmm();
+        let expiries = [0.5, 1.0, 2.5];
+        let vols = [0.25, 0.22, 0.18];
+        let scales = p.calibrate(&expiries, &vols).unwrap();
+        for ((&t, &vol), &k) in expiries.iter().zip(vols.iter()).zip(scales.iter()) {
+            let rms = k * (p.vol().covariance(0.0, t, t, t) / t).sqrt();
+            assert!(approx_eq(rms, vol, 1e-14), "t={} rms={} vol={}", t, rms, vol);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_matches_black_caplets() {
+        let mut market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let caplet_vol = 0.2;
+        market_data.add_vol_cube("OPT.CAPLET",
+            RcVolCube::new(Arc::new(FlatVolCube::new(caplet_vol, spot_date))));
+
+        let expiry = sample_expiry();
+        let maturity = Date::from_ymd(2018, 12, 01);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            "SampleBond", "OPT", currency,
+            DateTime::new(maturity, TimeOfDay::Open), maturity,
+            sample_settlement(0)))));
+
+        let factory = round_trip(&LmmFactory::new("OPT", "OPT.CAPLET", sample_lmm(),
+            1.0 / 12.0, 20000));
+
+        let yc = market_data.yield_curve("OPT", maturity).unwrap();
+        let df_expiry = yc.df(expiry.date(), spot_date).unwrap();
+        let df_maturity = yc.df(maturity, spot_date).unwrap();
+        let t = year_fraction(spot_date, expiry.date());
+        let delta = year_fraction(expiry.date(), maturity);
+        let forward = (df_expiry / df_maturity - 1.0) / delta;
+        let black76 = Black76::new().unwrap();
+
+        // A put on the bond struck at 1 / (1 + delta K) is a caplet struck
+        // at K, divided by 1 + delta K. A deep in the money call on the
+        // bond checks that the forwards have the right drift.
+        for &(rate_strike, put_or_call) in [(forward, PutOrCall::Put),
+            (forward + 0.02, PutOrCall::Put), (1.0 / delta, PutOrCall::Call)].iter() {
+            let strike = 1.0 / (1.0 + delta * rate_strike);
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                bond.clone(), sample_settlement(0), expiry, strike, put_or_call,
+                OptionSettlement::Cash).unwrap();
+
+            // as for Hull-White, build the model directly from the timeline
+            let mut timeline = MonteCarloTimeline::new(spot_date);
+            timeline.set_threading(Threading::new(1, Some(42)));
+            european.mc_dependencies(&[], &mut timeline).unwrap();
+            timeline.collate().unwrap();
+            let mut dependencies = DependencyCollector::new(spot_date);
+            dependencies.spot(&bond);
+            let context = PricingContextPrefetch::new(&market_data,
+                Arc::new(dependencies)).unwrap();
+            let model = factory.factory(&timeline, Box::new(context)).unwrap();
+            let price = european.mc_price(model.as_mc_context()).unwrap();
+
+            let expected = match put_or_call {
+                PutOrCall::Put => black76.call_price(delta * df_maturity, forward,
+                    rate_strike, caplet_vol * t.sqrt()) * strike,
+                PutOrCall::Call => df_maturity - strike * df_expiry
+            };
+
+            // the paths are seeded. Caplet prices differ by up to 5e-5 from
+            // one seed to the next, so allow three times that
+            assert!(approx_eq(price, expected, 1.5e-4),
+                "strike={} price={} expected={}", strike, price, expected);
+        }
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let mut market_data = sample_market_data();
+        market_data.add_vol_cube("OPT.CAPLET", RcVolCube::new(Arc::new(
+            FlatVolCube::new(0.2, market_data.spot_date()))));
+        let maturity = Date::from_ymd(2020, 06, 01);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let bond = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            "SampleBond", "OPT", currency,
+            DateTime::new(maturity, TimeOfDay::Open), maturity,
+            sample_settlement(0)))));
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", bond.clone(), sample_settlement(0), sample_expiry(),
+            0.9, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &bond);
+        let mut model = Lmm::new(&timeline, context, "OPT", "OPT.CAPLET", sample_lmm(),
+            1.0 / 12.0, 100).unwrap();
+
+        // the calibration sees the vol cube, not the vol surfaces
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
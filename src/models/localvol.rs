@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use data::forward::Forward;
+use data::volsurface::VolSurface;
+use data::volsurface::DivAssumptions;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings_with;
+use models::calculate_substepping;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Number of points across the strike dimension of a local vol grid
+const GRID_STRIKES: usize = 101;
+
+/// Half-width of a local vol grid in log strike, as a number of standard
+/// deviations at the last date of the grid
+const GRID_WIDTH: f64 = 5.0;
+
+/// Smallest half-width of a local vol grid in log strike, so that grids
+/// with very little variance still cover a sensible range of strikes
+const MIN_GRID_WIDTH: f64 = 0.1;
+
+/// Smallest allowed denominator in Dupire's formula. The denominator is
+/// proportional to the density of the underlying, so it is only this small
+/// far in the wings or where the vol surface has butterfly arbitrage.
+const MIN_DUPIRE_DENOMINATOR: f64 = 0.01;
+
+/// A grid of Dupire local variances for one underlying, calculated from
+/// its implied vol surface.
+///
+/// Cash dividends need care. If the vol surface assumes fixed dividends,
+/// the underlying is a positive process X plus the value of the cash
+/// dividends still to come, which is the displacement of the vol surface.
+/// The implied vols describe X, so the local vols do too: they are a
+/// function of ln(X / E[X]), on a grid of dates measured in vol time. As the
+/// forward of X has no jumps on ex-dates, nor does the local vol. Surfaces
+/// with no cash dividends are the same with no displacement.
+pub struct LocalVolGrid {
+    times: Vec<f64>,
+    log_strikes: Vec<f64>,
+    variances: Array2<f64>
+}
+
+impl LocalVolGrid {
+
+    /// Calculates the local vol at each of the given dates, which must be in
+    /// increasing order after the base date of the vol surface. The local
+    /// vol at each date applies from the previous date (or the base date)
+    /// up to that date.
+    pub fn new(vol_surface: &VolSurface, forward_curve: &Forward,
+        dates: &[DateDayFraction]) -> Result<LocalVolGrid, qm::Error> {
+
+        if dates.is_empty() {
+            return Err(qm::Error::new("Local vol grid needs at least one date"))
+        }
+        match vol_surface.div_assumptions() {
+            DivAssumptions::IndependentLogNormals => return Err(qm::Error::new(
+                "Local vol cannot be calibrated to a vol surface that treats \
+                each expiry as an independent log-normal")),
+            DivAssumptions::JumpDivs => return Err(qm::Error::new(
+                "Local vol does not support vol surfaces with jump dividends")),
+            _ => {}
+        }
+
+        // forwards and displacements, both in the space of the underlying
+        let n_dates = dates.len();
+        let mut times = Vec::with_capacity(n_dates);
+        let mut forwards = Vec::with_capacity(n_dates);
+        let mut displacements = Vec::with_capacity(n_dates);
+        let mut previous = 0.0_f64;
+        for date in dates.iter() {
+            let time = vol_surface.vol_time(*date)?.max(previous);
+            times.push(time);
+            previous = time;
+
+            let displacement = vol_surface.displacement(date.date())?;
+            let forward = forward_curve.forward(date.date())? - displacement;
+            if forward <= 0.0 {
+                return Err(qm::Error::new("Negative forward"))
+            }
+            forwards.push(forward);
+            displacements.push(displacement);
+        }
+
+        // the grid is wide enough to cover the at-the-money variance at
+        // the last date, which is roughly the largest
+        let last = n_dates - 1;
+        let atm_variance = vol_surface.variance(dates[last],
+            forwards[last] + displacements[last])?;
+        let width = (GRID_WIDTH * atm_variance.max(0.0).sqrt()).max(MIN_GRID_WIDTH);
+        let dy = 2.0 * width / (GRID_STRIKES - 1) as f64;
+        let log_strikes: Vec<f64> = (0..GRID_STRIKES)
+            .map(|j| -width + j as f64 * dy).collect();
+
+        // total implied variances along lines of constant log moneyness
+        let mut total = Array2::<f64>::zeros((n_dates, GRID_STRIKES));
+        let mut strikes = vec![0.0; GRID_STRIKES];
+        for (i, mut row) in total.outer_iter_mut().enumerate() {
+            for (strike, y) in strikes.iter_mut().zip(log_strikes.iter()) {
+                *strike = displacements[i] + forwards[i] * y.exp();
+            }
+            let out = row.as_slice_mut().ok_or_else(|| qm::Error::new(
+                "Local vol grid cannot be accessed as a slice"))?;
+            vol_surface.variances(dates[i], &strikes, out)?;
+        }
+
+        // Dupire's formula in terms of the total implied variance w and the
+        // log moneyness y (see Gatheral, The Volatility Surface, 2006). The
+        // time derivative is a finite difference over each period. The
+        // strike derivatives are central differences at the end of the
+        // period, extended flat to the edges of the grid. Small calendar or
+        // butterfly arbitrages in the wings are floored, rather than
+        // stopping the pricing.
+        let mut variances = Array2::<f64>::zeros((n_dates, GRID_STRIKES));
+        let mut previous_time = 0.0;
+        for i in 0..n_dates {
+            let dt = times[i] - previous_time;
+            previous_time = times[i];
+            if dt <= 0.0 {
+                // no vol time passes, so the local vol is irrelevant
+                continue
+            }
+
+            for j in 1..(GRID_STRIKES - 1) {
+                let y = log_strikes[j];
+                let w = total[(i, j)];
+                let dw_dt = (w - if i == 0 { 0.0 } else { total[(i - 1, j)] }) / dt;
+                let w_y = (total[(i, j + 1)] - total[(i, j - 1)]) / (2.0 * dy);
+                let w_yy = (total[(i, j + 1)] - 2.0 * w + total[(i, j - 1)]) / (dy * dy);
+                let denominator = if w > 0.0 {
+                    1.0 - y * w_y / w
+                        + 0.25 * (-0.25 - 1.0 / w + y * y / (w * w)) * w_y * w_y
+                        + 0.5 * w_yy
+                } else {
+                    1.0
+                };
+                let local = dw_dt.max(0.0) / denominator.max(MIN_DUPIRE_DENOMINATOR);
+                if !local.is_finite() {
+                    return Err(qm::Error::new("Local vol is not finite"))
+                }
+                variances[(i, j)] = local;
+            }
+            variances[(i, 0)] = variances[(i, 1)];
+            variances[(i, GRID_STRIKES - 1)] = variances[(i, GRID_STRIKES - 2)];
+        }
+
+        Ok(LocalVolGrid { times: times, log_strikes: log_strikes,
+            variances: variances })
+    }
+
+    /// The vol times of the dates of the grid
+    pub fn times(&self) -> &[f64] {
+        &self.times
+    }
+
+    /// The local variance per unit vol time in the period ending at the
+    /// given date index, where the log of the underlying relative to its
+    /// forward is as given. This is in the displaced space if the vol
+    /// surface has fixed dividends. Interpolation is linear in log strike,
+    /// and flat beyond the grid.
+    pub fn local_variance(&self, date_index: usize, log_moneyness: f64) -> f64 {
+        let n = self.log_strikes.len();
+        let first = self.log_strikes[0];
+        let dy = self.log_strikes[1] - first;
+        let position = (log_moneyness - first) / dy;
+        if position <= 0.0 {
+            self.variances[(date_index, 0)]
+        } else if position >= (n - 1) as f64 {
+            self.variances[(date_index, n - 1)]
+        } else {
+            let j = position.floor() as usize;
+            let fraction = position - j as f64;
+            self.variances[(date_index, j)] * (1.0 - fraction)
+                + self.variances[(date_index, j + 1)] * fraction
+        }
+    }
+
+    /// The local volatility, which is the square root of the local variance
+    pub fn local_vol(&self, date_index: usize, log_moneyness: f64) -> f64 {
+        self.local_variance(date_index, log_moneyness).sqrt()
+    }
+}
+
+/// The LocalVolFactory creates a local vol model, given the timeline of the
+/// product(s) to value and the market data. It holds the maximum time step
+/// and the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LocalVolFactory {
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl LocalVolFactory {
+    pub fn new(time_step: f64, number_of_paths: usize) -> LocalVolFactory {
+        LocalVolFactory { time_step: time_step, number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(LocalVolFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for LocalVolFactory {
+    fn type_id(&self) -> &'static str { "LocalVolFactory" }
+}
+
+impl MonteCarloModelFactory for LocalVolFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = LocalVol::new(timeline, context, self.time_step,
+            self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A local vol model evolves each underlying with the Dupire local vol
+/// calibrated from its implied vol surface, so that it reprices all the
+/// Europeans on the surface, subject to discretization and Monte-Carlo
+/// error. The underlyings are correlated with each other via the
+/// correlations in the market data.
+///
+/// The drift comes from the forward curve in the market data, as for
+/// BlackDiffusion. If the vol surface assumes fixed cash dividends, the
+/// paths are of the displaced underlying, with the displacement added back
+/// on each observation, so each path drops by the cash dividends on their
+/// ex-dates without the local vols needing to jump. Vol surfaces that
+/// treat expiries as independent log-normals, or dividends as jumps in a
+/// log-normal process, are not supported, nor are quanto underlyings.
+///
+/// The paths are generated on a grid of whole days, no further apart in
+/// vol time than the time step, which includes all the observations. The
+/// local vols are recalibrated on any bump, so vol bumps change the paths.
+#[derive(Clone)]
+pub struct LocalVol {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    step_dates: Vec<DateDayFraction>,
+    substepping: Vec<usize>,
+    gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl LocalVol {
+
+    /// Creates a new local vol model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the maximum step in vol time and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<LocalVol, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("Local vol time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("Local vol does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, _) =
+            collect_underlyings_with(timeline, |_| Ok(()))?;
+
+        let (step_dates, substepping) = calculate_steps(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump.
+        let mut source = GaussianSource::from_timeline(timeline);
+        let gaussians = source.fetch(&substepping, instruments.len(), n_paths)?;
+        let paths = fetch_paths(&observations, &step_dates, &substepping,
+            &gaussians, context.as_pricing_context(), &instruments)?;
+
+        Ok(LocalVol {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            step_dates: step_dates,
+            substepping: substepping,
+            gaussians: gaussians,
+            paths: paths })
+    }
+
+    /// Recalibrates the local vols and refetches all paths for all assets,
+    /// using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.step_dates,
+            &self.substepping, &self.gaussians,
+            self.context.as_pricing_context(), &self.instruments)?;
+        Ok(())
+    }
+}
+
+/// Works out the dates of the steps along each path, which end on each
+/// observation. Steps are whole days apart, other than the last to each
+/// observation, and no step is longer than the given vol time for any
+/// underlying unless it is a single day. Returns the dates and how many
+/// steps end at or before each observation.
+pub fn calculate_steps(observations: &[DateDayFraction],
+    context: &PricingContext, instruments: &[RcInstrument], time_step: f64)
+    -> Result<(Vec<DateDayFraction>, Vec<usize>), qm::Error> {
+
+    let mut substepping = calculate_substepping(observations, context,
+        instruments, time_step)?;
+
+    let mut step_dates = Vec::new();
+    let mut start = context.spot_date();
+    for (obs, substep) in observations.iter().zip(substepping.iter_mut()) {
+        let days = (obs.date() - start).max(0) as usize;
+        *substep = (*substep).min(days).max(1);
+        for k in 1..*substep {
+            let date = start + ((k * days) / *substep) as i32;
+            step_dates.push(DateDayFraction::new(date, obs.day_fraction()));
+        }
+        step_dates.push(*obs);
+        start = obs.date();
+    }
+
+    Ok((step_dates, substepping))
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    step_dates: &[DateDayFraction],
+    substepping: &[usize],
+    gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, gaussians)?;
+
+    for ((instrument, gaussians), path) in instruments.iter()
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), context, observations, step_dates,
+            substepping, gaussians, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction], step_dates: &[DateDayFraction],
+    substepping: &[usize], gaussians: ArrayView2<f64>,
+    mut path: ArrayViewMut2<f64>) -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let grid = LocalVolGrid::new(&*vol_surface, &*forward_curve, step_dates)?;
+
+    // the forwards of the displaced underlying, and the displacements
+    let mut forwards = Vec::with_capacity(observations.len());
+    let mut displacements = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        let displacement = vol_surface.displacement(obs.date())?;
+        forwards.push(forward_curve.forward(obs.date())? - displacement);
+        displacements.push(displacement);
+    }
+
+    let mut steps = Vec::with_capacity(step_dates.len());
+    let mut previous = 0.0;
+    for time in grid.times().iter() {
+        steps.push(time - previous);
+        previous = *time;
+    }
+
+    // Each step is log-normal with the local vol at the start of the step,
+    // so the underlying relative to its forward is a martingale however
+    // large the steps.
+    for (z, mut one_path) in gaussians.outer_iter().zip(path.outer_iter_mut()) {
+        let mut log_x = 0.0;
+        let mut g = 0;
+        for i in 0..observations.len() {
+            for _ in 0..substepping[i] {
+                let variance = grid.local_variance(g, log_x) * steps[g];
+                log_x += variance.sqrt() * z[g] - 0.5 * variance;
+                g += 1;
+            }
+            one_path[i] = forwards[i] * log_x.exp() + displacements[i];
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for LocalVol {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The local vols are calibrated to the vol surfaces, so bumps to the
+    /// vols of the underlyings change the paths, as do bumps to forwards,
+    /// rates or correlations.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump_with_vols(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for LocalVol {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("LocalVol does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for LocalVol {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for LocalVol {
+
+    /// Bumps the market data, then recalibrates the local vols and
+    /// regenerates all the paths with the same random numbers, if the bump
+    /// changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::interpolation::Extrap;
+    use math::interpolation::Linear;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use data::forward::DriftlessForward;
+    use data::volsurface::FlatVolSurface;
+    use data::volsurface::RcVolSurface;
+    use data::volsurface::VolByProbabilityCubicSplineSmile;
+    use data::volsmile::CubicSplineSmile;
+    use dates::Date;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::marketdata::tests::create_sample_divstream;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::marketdata::tests::create_sample_borrow;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::round_trip;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    /// The sample market data, but with a skewed vol surface for BP.L. The
+    /// smile is defined in terms of the displaced forward, so that it has
+    /// the same shape whatever the div assumptions.
+    pub fn skewed_market_data(div_assumptions: DivAssumptions) -> MarketData {
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let equity = sample_underlying();
+        let sample = sample_market_data();
+        let forward_curve = sample.forward_curve(&*equity,
+            spot_date + 730).unwrap();
+
+        let mut forwards = vec![(spot_date, forward_curve.forward(spot_date).unwrap())];
+        let mut divs = vec![(spot_date, forward_curve.fixed_divs_after(spot_date).unwrap())];
+        let mut smiles = Vec::new();
+        for &days in [91, 182, 365, 730].iter() {
+            let date = spot_date + days;
+            let forward = forward_curve.forward(date).unwrap();
+            let displacement = match div_assumptions {
+                DivAssumptions::FixedDivs => forward_curve.fixed_divs_after(date).unwrap(),
+                _ => 0.0
+            };
+            forwards.push((date, forward));
+            divs.push((date, forward_curve.fixed_divs_after(date).unwrap()));
+            let points: Vec<(f64, f64)> = [(0.3, 0.45), (0.6, 0.38), (0.8, 0.33),
+                (1.0, 0.29), (1.2, 0.26), (1.5, 0.25), (2.5, 0.25)].iter().map(|&(moneyness, vol)|
+                (displacement + (forward - displacement) * moneyness, vol)).collect();
+            smiles.push((DateDayFraction::new(date, 0.7),
+                CubicSplineSmile::new(&points).unwrap()));
+        }
+
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(spot_date, 0.0);
+        let forward = Linear::new(&forwards, Extrap::Flat, Extrap::Flat).unwrap();
+        let divs = Linear::new(&divs, Extrap::Flat, Extrap::Flat).unwrap();
+        let surface = VolByProbabilityCubicSplineSmile::new(&smiles, calendar,
+            base, forward, divs, div_assumptions).unwrap();
+
+        let mut spots = HashMap::new();
+        spots.insert("BP.L".to_string(), 100.0);
+        let mut dividends = HashMap::new();
+        dividends.insert("BP.L".to_string(), create_sample_divstream());
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+        yield_curves.insert("LSE".to_string(), create_sample_rate());
+        let mut borrow_curves = HashMap::new();
+        borrow_curves.insert("BP.L".to_string(), create_sample_borrow());
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("BP.L".to_string(), RcVolSurface::new(Arc::new(surface)));
+        MarketData::new(spot_date, spots, yield_curves, borrow_curves,
+            dividends, vol_surfaces)
+    }
+
+    /// Checks that the model reprices Europeans on the skewed sample
+    /// market data, which it must do if it is calibrated to the surface.
+    pub fn assert_reprices_europeans(model_factory: RcMonteCarloModelFactory,
+        market_data: &MarketData) {
+
+        // The paths are seeded, so the price is repeatable. The standard
+        // errors are up to 0.13, and the time steps add some bias in the
+        // wings, so allow about three standard errors.
+        let expiry = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        check_monte_carlo_europeans(model_factory, market_data, expiry,
+            &[(70.0, PutOrCall::Put), (100.0, PutOrCall::Call), (130.0, PutOrCall::Call)],
+            0.4, &|_, strike, put_or_call| SpotStartingEuropean::new("SampleEuropean",
+                "OPT", sample_underlying(), sample_settlement(2), expiry, strike,
+                put_or_call, OptionSettlement::Cash).unwrap()
+                .price(market_data, sample_val_date()).unwrap());
+    }
+
+    #[test]
+    fn flat_vol_gives_flat_local_vol() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base_date = Date::from_ymd(2017, 01, 02);
+        let surface = FlatVolSurface::new(0.3, calendar,
+            DateDayFraction::new(base_date, 0.0));
+        let forward = DriftlessForward::new(100.0);
+        let dates: Vec<DateDayFraction> = (1..13).map(|month|
+            DateDayFraction::new(base_date + 30 * month, 0.7)).collect();
+
+        let grid = LocalVolGrid::new(&surface, &forward, &dates).unwrap();
+        for i in 0..dates.len() {
+            for &y in [-2.0, -0.5, 0.0, 0.3, 2.0].iter() {
+                let vol = grid.local_vol(i, y);
+                assert!(approx_eq(vol, 0.3, 1e-10), "date={} y={} vol={}", i, y, vol);
+            }
+        }
+    }
+
+    #[test]
+    fn skew_gives_local_vol_decreasing_in_strike() {
+        let market_data = skewed_market_data(DivAssumptions::NoCashDivs);
+        let equity = sample_underlying();
+        let hwm = Date::from_ymd(2018, 01, 02);
+        let forward = market_data.forward_curve(&*equity, hwm).unwrap();
+        let vol = market_data.vol_surface(&*equity, hwm, &|| Ok(forward.clone())).unwrap();
+        let dates = [DateDayFraction::new(hwm, 0.7)];
+        let grid = LocalVolGrid::new(&*vol, &*forward, &dates).unwrap();
+
+        // local vols are steeper than implied vols, but equal at the money
+        assert!(grid.local_vol(0, -0.2) > grid.local_vol(0, 0.0));
+        assert!(grid.local_vol(0, 0.0) > grid.local_vol(0, 0.2));
+        let atm = vol.variance(dates[0], forward.forward(hwm).unwrap()).unwrap();
+        let local = grid.local_variance(0, 0.0) * grid.times()[0];
+        assert!(approx_eq(local, atm, 0.1 * atm), "local={} atm={}", local, atm);
+    }
+
+    #[test]
+    fn local_vol_reprices_europeans() {
+        assert_reprices_europeans(local_vol_factory(),
+            &skewed_market_data(DivAssumptions::NoCashDivs));
+    }
+
+    #[test]
+    fn local_vol_reprices_europeans_with_fixed_dividends() {
+        assert_reprices_europeans(local_vol_factory(),
+            &skewed_market_data(DivAssumptions::FixedDivs));
+    }
+
+    fn local_vol_factory() -> RcMonteCarloModelFactory {
+        let factory = round_trip(&LocalVolFactory::new(1.0 / 52.0, 20000));
+        RcMonteCarloModelFactory::new(Arc::new(factory))
+    }
+
+    #[test]
+    fn independent_log_normals_are_rejected() {
+        let market_data = skewed_market_data(DivAssumptions::IndependentLogNormals);
+        let equity = sample_underlying();
+        let hwm = Date::from_ymd(2018, 01, 02);
+        let forward = market_data.forward_curve(&*equity, hwm).unwrap();
+        let vol = market_data.vol_surface(&*equity, hwm, &|| Ok(forward.clone())).unwrap();
+        let dates = [DateDayFraction::new(hwm, 0.7)];
+        assert!(LocalVolGrid::new(&*vol, &*forward, &dates).is_err());
+    }
+
+    #[test]
+    fn bump_to_other_vol_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut model = LocalVol::new(&timeline, context, 1.0 / 52.0, 100).unwrap();
+
+        // only BP.L is calibrated, so its paths do not see the vols of GSK.L
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
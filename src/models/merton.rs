@@ -0,0 +1,650 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use statrs::distribution::Normal;
+use statrs::distribution::Univariate;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use math::complex::Complex;
+use math::fourier::CharacteristicFunction;
+use math::fourier::LevyProcess;
+use math::optionpricing::Black76;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::vol_times;
+use models::BumpedPaths;
+use models::ForwardScaledPaths;
+use models::RegeneratedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Limit on the number of jumps in a single period, or terms in the series
+/// pricer. Either is only reached for absurd intensities.
+const MAX_JUMPS: usize = 1000;
+
+/// The series pricer stops once the Poisson probabilities of the terms it
+/// has summed are within this of one.
+const SERIES_TOLERANCE: f64 = 1e-14;
+
+/// Jumps in the log of an underlying, arriving as a Poisson process with
+/// the given intensity, with normally distributed sizes of the given mean
+/// and standard deviation. In other words, each jump multiplies the
+/// underlying by a log-normal factor. The jumps are compensated, so the
+/// underlying relative to its forward stays a martingale.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LogNormalJumps {
+    intensity: f64,
+    mean: f64,
+    std_dev: f64
+}
+
+impl LogNormalJumps {
+    pub fn new(intensity: f64, mean: f64, std_dev: f64)
+        -> Result<LogNormalJumps, qm::Error> {
+
+        if intensity < 0.0 {
+            return Err(qm::Error::new("Jump intensity must not be negative"))
+        }
+        if std_dev < 0.0 {
+            return Err(qm::Error::new("Jump size standard deviation must not be negative"))
+        }
+
+        Ok(LogNormalJumps { intensity: intensity, mean: mean, std_dev: std_dev })
+    }
+
+    pub fn intensity(&self) -> f64 { self.intensity }
+    pub fn mean(&self) -> f64 { self.mean }
+    pub fn std_dev(&self) -> f64 { self.std_dev }
+
+    /// The expected proportional change in the underlying from a single
+    /// jump, in other words E[exp(J)] - 1.
+    pub fn mean_jump(&self) -> f64 {
+        (self.mean + 0.5 * self.std_dev * self.std_dev).exp() - 1.0
+    }
+
+    /// The drift in the log of the underlying per unit time that
+    /// compensates for the jumps.
+    pub fn compensator(&self) -> f64 {
+        -self.intensity * self.mean_jump()
+    }
+
+    /// The log of the characteristic function of the compensated jumps per
+    /// unit time. Any model can add jumps by adding this, multiplied by the
+    /// time, to the log of its own characteristic function.
+    pub fn characteristic_exponent(&self, u: Complex) -> Complex {
+        let iu = Complex::i() * u;
+        let jump = (iu * self.mean - u * u * (0.5 * self.std_dev * self.std_dev)).exp();
+        (jump - 1.0) * self.intensity + iu * self.compensator()
+    }
+
+    /// Samples the change in the log of the underlying from the jumps over
+    /// a time dt, including the compensator. The number of jumps comes
+    /// from the normal cdf of the first gaussian, so the sample is a
+    /// deterministic function of the gaussians, and the sizes of all the
+    /// jumps together from the second gaussian.
+    pub fn sample(&self, dt: f64, z_count: f64, z_size: f64, normal: &Normal)
+        -> f64 {
+
+        if dt <= 0.0 {
+            return 0.0
+        }
+
+        let n = poisson_inverse(self.intensity * dt, normal.cdf(z_count));
+        let sizes = if n == 0 {
+            0.0
+        } else {
+            let jumps = n as f64;
+            jumps * self.mean + jumps.sqrt() * self.std_dev * z_size
+        };
+        sizes + self.compensator() * dt
+    }
+}
+
+/// Inverts the cumulative Poisson distribution with the given mean,
+/// returning the smallest count whose cumulative probability reaches u.
+fn poisson_inverse(mean: f64, u: f64) -> usize {
+    let mut probability = (-mean).exp();
+    let mut cumulative = probability;
+    let mut n = 0;
+    while u > cumulative && n < MAX_JUMPS {
+        n += 1;
+        probability *= mean / n as f64;
+        cumulative += probability;
+
+        // the remaining probabilities are too small to accumulate
+        if probability <= 0.0 && n as f64 > mean {
+            break;
+        }
+    }
+    n
+}
+
+/// The parameters of the Merton (1976) jump-diffusion model for one
+/// underlying:
+///
+///  dS/S = mu(t) dt + sigma dW + (exp(J) - 1) dN
+///
+/// where sigma is the diffusion vol, N a Poisson process and the log jump
+/// sizes J are normally distributed. The drift mu(t) comes from the forward
+/// curve, less the compensator for the jumps. Downward jumps give the
+/// short-dated skew typical of equities, which diffusions find hard to
+/// represent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MertonParameters {
+    sigma: f64,
+    jumps: LogNormalJumps
+}
+
+impl MertonParameters {
+    pub fn new(sigma: f64, jumps: LogNormalJumps)
+        -> Result<MertonParameters, qm::Error> {
+
+        if sigma < 0.0 {
+            return Err(qm::Error::new("Merton diffusion vol must not be negative"))
+        }
+
+        Ok(MertonParameters { sigma: sigma, jumps: jumps })
+    }
+
+    pub fn sigma(&self) -> f64 { self.sigma }
+    pub fn jumps(&self) -> &LogNormalJumps { &self.jumps }
+
+    /// Closed-form price of a European call, as Merton's series of Black
+    /// prices, each conditional on the number of jumps and weighted by its
+    /// Poisson probability.
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        self.series_price(df, forward, strike, t, &|black76, df, f, k, s|
+            black76.call_price(df, f, k, s))
+    }
+
+    /// Closed-form price of a European put
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        self.series_price(df, forward, strike, t, &|black76, df, f, k, s|
+            black76.put_price(df, f, k, s))
+    }
+
+    fn series_price(&self, df: f64, forward: f64, strike: f64, t: f64,
+        black: &Fn(&Black76, f64, f64, f64, f64) -> f64)
+        -> Result<f64, qm::Error> {
+
+        if forward <= 0.0 || strike <= 0.0 {
+            return Err(qm::Error::new("Merton needs a positive forward and strike"))
+        }
+        if t < 0.0 {
+            return Err(qm::Error::new("Merton needs a non-negative time"))
+        }
+
+        let black76 = Black76::new()?;
+        let mean = self.jumps.intensity * t;
+        let diffusion_var = self.sigma * self.sigma * t;
+        let jump_var = self.jumps.std_dev * self.jumps.std_dev;
+        let log_growth = (1.0 + self.jumps.mean_jump()).ln();
+
+        // Conditional on n jumps, the underlying is log-normal, with the
+        // forward shifted by the sizes of the jumps less the compensator
+        let mut weight = (-mean).exp();
+        let mut total_weight = 0.0;
+        let mut price = 0.0;
+        for n in 0..MAX_JUMPS {
+            if n > 0 {
+                weight *= mean / n as f64;
+            }
+            let jumps = n as f64;
+            let shifted = forward * (jumps * log_growth + self.jumps.compensator() * t).exp();
+            let sqrt_var = (diffusion_var + jumps * jump_var).sqrt();
+            price += weight * black(&black76, df, shifted, strike, sqrt_var);
+            total_weight += weight;
+            if 1.0 - total_weight < SERIES_TOLERANCE && jumps >= mean {
+                return Ok(price)
+            }
+        }
+
+        Err(qm::Error::new("Merton series failed to converge"))
+    }
+}
+
+impl CharacteristicFunction for MertonParameters {
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+        let iu = Complex::i() * u;
+        let diffusion = (iu + u * u) * (-0.5 * self.sigma * self.sigma);
+        ((diffusion + self.jumps.characteristic_exponent(u)) * t).exp()
+    }
+}
+
+impl LevyProcess for MertonParameters {
+    fn as_characteristic_function(&self) -> &CharacteristicFunction { self }
+}
+
+/// The MertonFactory creates a Merton jump-diffusion model, given the
+/// timeline of the product(s) to value and the market data. The Merton
+/// parameters for each underlying are held by the factory, keyed by the id
+/// of the underlying, as the market data only contains implied vols. The
+/// factory also holds the number of paths. There is no time step, as each
+/// period between observations is simulated exactly.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MertonFactory {
+    parameters: HashMap<String, MertonParameters>,
+    number_of_paths: usize
+}
+
+impl MertonFactory {
+    pub fn new(parameters: HashMap<String, MertonParameters>,
+        number_of_paths: usize) -> MertonFactory {
+
+        MertonFactory { parameters: parameters,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(MertonFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for MertonFactory {
+    fn type_id(&self) -> &'static str { "MertonFactory" }
+}
+
+impl MonteCarloModelFactory for MertonFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Merton::new(timeline, context, &self.parameters,
+            self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A Merton model evolves each underlying as a log-normal diffusion with
+/// log-normal jumps. The diffusions are correlated with each other via the
+/// correlations in the market data, but the jumps of each underlying are
+/// independent. As the vol and jump parameters are constant, each period
+/// between observations is simulated exactly, with no discretization
+/// error.
+///
+/// As for Heston, the drift comes from the forward curve and the vol
+/// surface only supplies the business-day vol time, which is also the
+/// time in which the jumps arrive. Vol bumps have no effect on the price.
+/// Quanto underlyings and displaced vol surfaces are not supported.
+#[derive(Clone)]
+pub struct Merton {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<MertonParameters>,
+    spot_gaussians: Array3<f64>,
+    correlated_gaussians: Array3<f64>,
+    count_gaussians: Array3<f64>,
+    size_gaussians: Array3<f64>,
+    paths: ForwardScaledPaths
+}
+
+impl Merton {
+
+    /// Creates a new Merton model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the Merton parameters by underlying id and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, MertonParameters>,
+        n_paths: usize)
+        -> Result<Merton, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("Merton does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "Merton")?;
+
+        // One step per observation. The gaussians are kept uncorrelated,
+        // so that paths can be refetched with the same random numbers after
+        // any bump, including a correlation bump.
+        let steps = vec![1; observations.len()];
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&steps, n_assets, n_paths)?;
+        let count_gaussians = source.fetch(&steps, n_assets, n_paths)?;
+        let size_gaussians = source.fetch(&steps, n_assets, n_paths)?;
+        let correlated_gaussians = correlate_gaussians(context.as_pricing_context(),
+            &instruments, &observations, &steps, &spot_gaussians)?;
+        let paths = fetch_paths(&observations, &correlated_gaussians,
+            &count_gaussians, &size_gaussians, context.as_pricing_context(),
+            &instruments, &asset_parameters)?;
+
+        Ok(Merton {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            spot_gaussians: spot_gaussians,
+            correlated_gaussians: correlated_gaussians,
+            count_gaussians: count_gaussians,
+            size_gaussians: size_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers,
+    /// correlating them again
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.correlated_gaussians = correlate_gaussians(
+            self.context.as_pricing_context(), &self.instruments,
+            &self.observations, &vec![1; self.observations.len()],
+            &self.spot_gaussians)?;
+        self.paths = fetch_paths(&self.observations, &self.correlated_gaussians,
+            &self.count_gaussians, &self.size_gaussians,
+            self.context.as_pricing_context(), &self.instruments,
+            &self.parameters)?;
+        Ok(())
+    }
+
+    /// Rescales the paths of the underlying with the given id to its
+    /// forwards, if it is one of ours
+    pub fn refetch_forwards(&mut self, id: &str) -> Result<(), qm::Error> {
+        if let Some(&asset) = self.key.get(id) {
+            let forwards = fetch_forwards(&*self.instruments[asset],
+                self.context.as_pricing_context(), &self.observations)?;
+            self.paths.rescale(asset, &forwards);
+        }
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    correlated_gaussians: &Array3<f64>,
+    count_gaussians: &Array3<f64>,
+    size_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[MertonParameters]) -> Result<ForwardScaledPaths, qm::Error> {
+
+    let n_paths = correlated_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut drivers = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+    let mut forwards = Vec::with_capacity(n_assets);
+
+    // the diffusions are correlated with each other, but not the jumps
+    for (((((instrument, p), spot), count), size), driver) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated_gaussians.axis_iter(Axis(2)))
+        .zip(count_gaussians.axis_iter(Axis(2)))
+        .zip(size_gaussians.axis_iter(Axis(2)))
+        .zip(drivers.axis_iter_mut(Axis(2))) {
+
+        forwards.push(fetch_forwards(instrument.deref(), context, observations)?);
+        fetch_driver(instrument.deref(), p, context, observations, spot,
+            count, size, driver)?;
+    }
+    Ok(ForwardScaledPaths::new(drivers, &forwards))
+}
+
+/// Fetches the forwards of a single asset at the observations
+fn fetch_forwards(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction]) -> Result<Vec<f64>, qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("Merton does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+    Ok(forwards)
+}
+
+/// Fetches the drivers for a single asset, which are its paths divided by
+/// its forwards
+fn fetch_driver(instrument: &Instrument, parameters: &MertonParameters,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, count_gaussians: ArrayView2<f64>,
+    size_gaussians: ArrayView2<f64>, mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for time in times.iter() {
+        steps.push(time - previous);
+        previous = *time;
+    }
+
+    let normal = match Normal::new(0.0, 1.0) {
+        Ok(normal) => normal,
+        Err(e) => return Err(qm::Error::new(&format!("RSStat error: {}", e)))
+    };
+    let sigma = parameters.sigma;
+    for (((z_x, z_n), z_j), mut one_path) in spot_gaussians.outer_iter()
+        .zip(count_gaussians.outer_iter()).zip(size_gaussians.outer_iter())
+        .zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0;
+        for (i, dt) in steps.iter().enumerate() {
+            let dt = dt.max(0.0);
+            log_x += sigma * dt.sqrt() * z_x[i] - 0.5 * sigma * sigma * dt
+                + parameters.jumps.sample(dt, z_n[i], z_j[i], &normal);
+            one_path[i] = log_x.exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for Merton {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for Merton {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Merton does not know about '{}'", id)))?;
+        Ok(self.paths.paths().subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for Merton {
+    type Paths = (ForwardScaledPaths, Array3<f64>);
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> (ForwardScaledPaths, Array3<f64>) {
+        (self.paths.clone(), self.correlated_gaussians.clone())
+    }
+
+    fn set_paths(&mut self, paths: &(ForwardScaledPaths, Array3<f64>)) {
+        let &(ref scaled, ref correlated) = paths;
+        self.paths = scaled.clone();
+        self.correlated_gaussians.assign(correlated);
+    }
+
+    fn regenerate_paths(&mut self, changes: &BumpedPaths) -> Result<(), qm::Error> {
+        match changes {
+            &BumpedPaths::Unchanged => Ok(()),
+            &BumpedPaths::Forwards(ref ids) => {
+                for id in ids.iter() {
+                    self.refetch_forwards(id)?;
+                }
+                Ok(())
+            },
+            &BumpedPaths::All => self.refetch_all()
+        }
+    }
+}
+
+impl Bumpable for Merton {
+
+    /// Bumps the market data, then updates the paths with the same random
+    /// numbers, as for Heston. Bumps to forwards or rates only rescale the
+    /// paths of the underlyings they move, and bumps to vol levels leave
+    /// the paths unchanged.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::fourier;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_expiry;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use risk::marketdata::tests::sample_market_data;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    fn crash_prone() -> MertonParameters {
+        let jumps = LogNormalJumps::new(0.5, -0.15, 0.1).unwrap();
+        MertonParameters::new(0.2, jumps).unwrap()
+    }
+
+    #[test]
+    fn characteristic_function_is_martingale() {
+        let u = Complex::new(0.0, -1.0);
+        let phi = crash_prone().characteristic_function(u, 1.5);
+        assert!(approx_eq(phi.re, 1.0, 1e-12) && approx_eq(phi.im, 0.0, 1e-12),
+            "phi={:?}", phi);
+    }
+
+    #[test]
+    fn without_jumps_is_black() {
+        let jumps = LogNormalJumps::new(0.0, -0.15, 0.1).unwrap();
+        let p = MertonParameters::new(0.3, jumps).unwrap();
+        let black76 = Black76::new().unwrap();
+        for &strike in [70.0, 100.0, 130.0].iter() {
+            let expected = black76.call_price(0.95, 100.0, strike, 0.3 * 2.0_f64.sqrt());
+            let call = p.call_price(0.95, 100.0, strike, 2.0).unwrap();
+            assert!(approx_eq(call, expected, 1e-12),
+                "strike={} call={} expected={}", strike, call, expected);
+        }
+    }
+
+    #[test]
+    fn series_matches_fourier() {
+        let p = crash_prone();
+        for &strike in [60.0, 80.0, 100.0, 120.0, 150.0].iter() {
+            let call = p.call_price(0.9, 100.0, strike, 1.0).unwrap();
+            let expected = fourier::call_price(&p, 0.9, 100.0, strike, 1.0).unwrap();
+            assert!(approx_eq(call, expected, 1e-6),
+                "strike={} call={} expected={}", strike, call, expected);
+            let put = p.put_price(0.9, 100.0, strike, 1.0).unwrap();
+            let expected = fourier::put_price(&p, 0.9, 100.0, strike, 1.0).unwrap();
+            assert!(approx_eq(put, expected, 1e-6),
+                "strike={} put={} expected={}", strike, put, expected);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_matches_series() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), crash_prone());
+        let factory = round_trip(&MertonFactory::new(parameters, 20000));
+
+        // The paths are seeded and the jumps are simulated exactly, so the
+        // only error is the noise, whose standard error is up to 0.14.
+        // This allows about three of them.
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), sample_expiry(),
+            &[(80.0, PutOrCall::Put), (100.0, PutOrCall::Call), (120.0, PutOrCall::Call)],
+            0.4, &|terms, strike, put_or_call| match put_or_call {
+                PutOrCall::Call => crash_prone().call_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap(),
+                PutOrCall::Put => crash_prone().put_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap()
+            });
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            crash_prone());
+        let mut model = Merton::new(&timeline, context, &parameters, 100).unwrap();
+
+        // the diffusion vol and jumps are parameters of the model
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
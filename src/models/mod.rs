@@ -1,16 +1,70 @@
 pub mod blackdiffusion;
+pub mod heston;
+pub mod localvol;
+pub mod sabr;
+pub mod merton;
+pub mod bates;
+pub mod variancegamma;
+pub mod roughbergomi;
+pub mod hullwhite;
+pub mod g2pp;
+pub mod lmm;
+pub mod slv;
+pub mod regimeswitching;
+pub mod bachelier;
+pub mod displaceddiffusion;
+pub mod hybrid;
+pub mod jumptodefault;
+pub mod stochasticdividends;
+pub mod threehalves;
+pub mod discounting;
+pub mod stochasticborrow;
 
 use models::blackdiffusion::BlackDiffusionFactory;
+use models::heston::HestonFactory;
+use models::heston::PiecewiseHestonFactory;
+use models::localvol::LocalVolFactory;
+use models::sabr::SabrFactory;
+use models::merton::MertonFactory;
+use models::bates::BatesFactory;
+use models::variancegamma::VarianceGammaFactory;
+use models::roughbergomi::RoughBergomiFactory;
+use models::hullwhite::HullWhiteFactory;
+use models::g2pp::G2ppFactory;
+use models::lmm::LmmFactory;
+use models::slv::SlvFactory;
+use models::regimeswitching::RegimeSwitchingFactory;
+use models::bachelier::BachelierFactory;
+use models::displaceddiffusion::DisplacedDiffusionFactory;
+use models::hybrid::EquityRatesHybridFactory;
+use models::jumptodefault::JumpToDefaultFactory;
+use models::stochasticdividends::StochasticDividendFactory;
+use models::threehalves::ThreeHalvesFactory;
+use models::stochasticborrow::StochasticBorrowFactory;
 use core::qm;
+use instruments::Instrument;
 use instruments::RcInstrument;
 use instruments::MonteCarloDependencies;
 use instruments::MonteCarloContext;
+use instruments::PricingContext;
 use risk::Bumpable;
+use risk::Saveable;
 use risk::BumpablePricingContext;
 use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
 use dates::Date;
 use dates::datetime::DateDayFraction;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView1;
+use ndarray::ArrayView2;
+use ndarray::Axis;
 use core::factories::{TypeId, Qrc, Registry};
+use std::any::Any;
 use std::collections::HashMap;
 use std::clone::Clone;
 use erased_serde as esd;
@@ -18,6 +72,11 @@ use serde as sd;
 use serde_tagged as sdt;
 use serde_tagged::de::BoxFnSeed;
 use std::fmt::Debug;
+use std::ops::Deref;
+use pricers::analytic::AnalyticModel;
+use math::adjoint::Tape;
+use math::adjoint::Var;
+use rand;
 
 /// Interface that must be implemented by a model factory in order to support
 /// Monte-Carlo pricing.
@@ -28,6 +87,11 @@ pub trait MonteCarloModelFactory : esd::Serialize + TypeId + Sync + Send + Debug
     fn factory(&self, timeline: &MonteCarloTimeline, 
         context: Box<BumpablePricingContext>)
         -> Result<Box<MonteCarloModel>, qm::Error>;
+
+    /// The model under which instruments have the closed-form prices that
+    /// this Monte-Carlo model converges to, if any. Pricers may prefer the
+    /// closed forms where the instruments supply them.
+    fn analytic_model(&self) -> Option<AnalyticModel> { None }
 }
 
 // Get serialization to work recursively for instruments by using the
@@ -50,6 +114,26 @@ pub fn get_registry() -> &'static TypeRegistry {
         static ref REG: TypeRegistry = {
             let mut reg = TypeRegistry::new();
             reg.insert("BlackDiffusionFactory", BoxFnSeed::new(BlackDiffusionFactory::from_serial));
+            reg.insert("HestonFactory", BoxFnSeed::new(HestonFactory::from_serial));
+            reg.insert("LocalVolFactory", BoxFnSeed::new(LocalVolFactory::from_serial));
+            reg.insert("SabrFactory", BoxFnSeed::new(SabrFactory::from_serial));
+            reg.insert("MertonFactory", BoxFnSeed::new(MertonFactory::from_serial));
+            reg.insert("BatesFactory", BoxFnSeed::new(BatesFactory::from_serial));
+            reg.insert("VarianceGammaFactory", BoxFnSeed::new(VarianceGammaFactory::from_serial));
+            reg.insert("RoughBergomiFactory", BoxFnSeed::new(RoughBergomiFactory::from_serial));
+            reg.insert("HullWhiteFactory", BoxFnSeed::new(HullWhiteFactory::from_serial));
+            reg.insert("G2ppFactory", BoxFnSeed::new(G2ppFactory::from_serial));
+            reg.insert("LmmFactory", BoxFnSeed::new(LmmFactory::from_serial));
+            reg.insert("SlvFactory", BoxFnSeed::new(SlvFactory::from_serial));
+            reg.insert("RegimeSwitchingFactory", BoxFnSeed::new(RegimeSwitchingFactory::from_serial));
+            reg.insert("BachelierFactory", BoxFnSeed::new(BachelierFactory::from_serial));
+            reg.insert("DisplacedDiffusionFactory", BoxFnSeed::new(DisplacedDiffusionFactory::from_serial));
+            reg.insert("EquityRatesHybridFactory", BoxFnSeed::new(EquityRatesHybridFactory::from_serial));
+            reg.insert("JumpToDefaultFactory", BoxFnSeed::new(JumpToDefaultFactory::from_serial));
+            reg.insert("StochasticDividendFactory", BoxFnSeed::new(StochasticDividendFactory::from_serial));
+            reg.insert("ThreeHalvesFactory", BoxFnSeed::new(ThreeHalvesFactory::from_serial));
+            reg.insert("PiecewiseHestonFactory", BoxFnSeed::new(PiecewiseHestonFactory::from_serial));
+            reg.insert("StochasticBorrowFactory", BoxFnSeed::new(StochasticBorrowFactory::from_serial));
             reg
         };
     }
@@ -70,6 +154,62 @@ pub trait MonteCarloModel : MonteCarloContext + Bumpable + MonteCarloModelClone
     fn as_mut_bumpable(&mut self) -> &mut Bumpable;
 
     fn raw_market_data(&self) -> &MarketData;
+
+    /// Converts this model to an AdjointModel, if it can record its paths
+    /// on an adjoint tape. Most models cannot, which is the default.
+    fn as_adjoint(&self) -> Option<&AdjointModel> { None }
+
+    /// Converts this model to a LikelihoodRatioModel, if it knows the
+    /// density of its paths well enough to give their scores. Most models
+    /// do not, which is the default.
+    fn as_likelihood_ratio(&self) -> Option<&LikelihoodRatioModel> { None }
+
+    /// Says which of the paths a bump to the market data changes. By
+    /// default, any bump changes all of them.
+    fn bumped_paths(&self, _bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::All)
+    }
+}
+
+/// Interface for Monte-Carlo models that can record their paths on an
+/// adjoint tape, so that a pricer can differentiate a price by the spot and
+/// the vol of every underlying in a single pass over the paths.
+pub trait AdjointModel {
+    /// Fetches the market data and its sensitivities needed to record the
+    /// paths. This is done once per pass, however many paths there are.
+    fn adjoint_recorder<'a>(&'a self) -> Result<Box<AdjointRecorder + 'a>, qm::Error>;
+}
+
+/// Records the paths of an adjoint model, one at a time.
+pub trait AdjointRecorder {
+    /// The underlyings the model evolves, in the order of the inputs to
+    /// record and of the values it returns
+    fn underlyings(&self) -> &[RcInstrument];
+
+    /// The number of paths
+    fn n_paths(&self) -> usize;
+
+    /// Records a path on the tape, given inputs that shift the spot and
+    /// the vol of each underlying, all of which are zero. The shifts of
+    /// vol are additive, and parallel across the vol surface. The values
+    /// of each underlying at each observation are returned in the values,
+    /// which are indexed by underlying then observation.
+    fn record<'t>(&self, tape: &'t Tape, path: usize, spot_shifts: &[Var<'t>],
+        vol_shifts: &[Var<'t>], values: &mut Vec<Vec<Var<'t>>>)
+        -> Result<(), qm::Error>;
+}
+
+/// Interface for Monte-Carlo models that can give the score of each path,
+/// which is the derivative of the log of the density of the path by some
+/// parameter of the model. The derivative of a price by that parameter is
+/// then the mean over the paths of the value of each path times its
+/// score, whether or not the payoff is continuous.
+pub trait LikelihoodRatioModel {
+    /// The underlyings the model evolves, and the scores of each path by
+    /// the spot and by a parallel additive shift of the vol of each
+    /// underlying, indexed by path then underlying.
+    fn likelihood_scores(&self)
+        -> Result<(Vec<RcInstrument>, Array2<f64>, Array2<f64>), qm::Error>;
 }
 
 pub trait MonteCarloModelClone {
@@ -90,12 +230,516 @@ impl Clone for Box<MonteCarloModel> {
     }
 }
 
+/// The vol times of the observations for one underlying, measured from the
+/// spot date, never decreasing
+pub fn vol_times(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction]) -> Result<Vec<f64>, qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut times = Vec::with_capacity(observations.len());
+    let mut previous = 0.0_f64;
+    for obs in observations.iter() {
+        let time = vol_surface.vol_time(*obs)?.max(previous);
+        times.push(time);
+        previous = time;
+    }
+    Ok(times)
+}
+
+/// Works out how many steps to take to each observation, so that no step
+/// is longer than the given vol time for any underlying.
+pub fn calculate_substepping(observations: &[DateDayFraction],
+    context: &PricingContext, instruments: &[RcInstrument], time_step: f64)
+    -> Result<Vec<usize>, qm::Error> {
+
+    if observations.is_empty() {
+        return Err(qm::Error::new("No observations"))
+    }
+
+    let mut substepping = vec![1_usize; observations.len()];
+    for instrument in instruments.iter() {
+        let times = vol_times(instrument.deref(), context, observations)?;
+        let mut previous = 0.0;
+        for (time, substep) in times.iter().zip(substepping.iter_mut()) {
+            let steps = ((time - previous) / time_step).ceil() as usize;
+            *substep = (*substep).max(steps);
+            previous = *time;
+        }
+    }
+    Ok(substepping)
+}
+
+/// The underlyings of a timeline for a model that evolves them all over the
+/// same observations, as for BlackDiffusion, with the observations, the
+/// index of each underlying by id, and the parameters of each underlying
+/// from the given map. It is an error for an underlying to have no
+/// parameters, and the error names the model.
+pub fn collect_underlyings<P: Clone>(timeline: &MonteCarloTimeline,
+    parameters: &HashMap<String, P>, model: &str)
+    -> Result<(Vec<DateDayFraction>, HashMap<String, usize>, Vec<RcInstrument>, Vec<P>),
+        qm::Error> {
+
+    collect_underlyings_with(timeline, |id| parameters.get(id).cloned()
+        .ok_or_else(|| qm::Error::new(&format!("No {} parameters for '{}'", model, id))))
+}
+
+/// As collect_underlyings, but fetching the parameters of each underlying
+/// by id from the given function, which may also validate them or supply
+/// defaults.
+pub fn collect_underlyings_with<P, F>(timeline: &MonteCarloTimeline, mut parameters: F)
+    -> Result<(Vec<DateDayFraction>, HashMap<String, usize>, Vec<RcInstrument>, Vec<P>),
+        qm::Error>
+    where F: FnMut(&str) -> Result<P, qm::Error> {
+
+    let mut observations = Vec::new();
+    let mut key = HashMap::new();
+    let mut instruments = Vec::new();
+    let mut asset_parameters = Vec::new();
+    for (asset, obs) in timeline.observations().iter() {
+        if observations.is_empty() {
+            observations = obs.to_vec();
+        }
+        let id = asset.id().to_string();
+        asset_parameters.push(parameters(&id)?);
+        key.insert(id, instruments.len());
+        instruments.push(asset.clone());
+    }
+    Ok((observations, key, instruments, asset_parameters))
+}
+
+/// Which paths a bump changes. Models regenerate their paths only after
+/// bumps that change them. Some models have paths that are the forwards
+/// times drivers that depend only on the random numbers, the correlations,
+/// the vol times and the parameters of the model, as in Heston. Such a
+/// model can keep its drivers between bumps, and rescale just the paths of
+/// the underlyings whose forwards move, rather than simulating again.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BumpedPaths {
+    /// The paths are unchanged, as by a bump to data the model does not use
+    Unchanged,
+    /// The forwards of the underlyings with these ids are changed
+    Forwards(Vec<String>),
+    /// Any of the paths may be changed, as by a bump to the correlations or
+    /// the spot date
+    All
+}
+
+impl BumpedPaths {
+    /// Which paths a bump changes, for models that look at the vol surfaces
+    /// only for their calendars, and whose paths are the forwards times
+    /// drivers as described above
+    pub fn of_bump(bump: &Bump, dependencies: &DependencyCollector) -> BumpedPaths {
+        match bump {
+            &Bump::Spot(ref id, _) => BumpedPaths::Forwards(vec![id.clone()]),
+            &Bump::Divs(ref id, _) => BumpedPaths::Forwards(vec![id.clone()]),
+            &Bump::Borrow(ref id, _) => BumpedPaths::Forwards(vec![id.clone()]),
+            &Bump::Yield(ref credit_id, _) => BumpedPaths::Forwards(
+                dependencies.forward_id_by_credit_id(credit_id).to_vec()),
+            // fx rates only matter to quanto underlyings, and these models
+            // do not support them
+            &Bump::FxSpot(_, _) => BumpedPaths::Unchanged,
+            &Bump::Vol(_, _) => BumpedPaths::Unchanged,
+            &Bump::VolCube(_, _) => BumpedPaths::Unchanged,
+            &Bump::Hazard(_, _) => BumpedPaths::Unchanged,
+            &Bump::Correlation(_, _, _) => BumpedPaths::All,
+            &Bump::SpotDate(_) => BumpedPaths::All
+        }
+    }
+
+    /// Which paths a bump changes, for models that also use the vol levels,
+    /// such as local vol. A bump to a vol surface the model fetched changes
+    /// all of the paths, as does any bump that moves a forward, because
+    /// these models cannot just rescale their paths.
+    pub fn of_bump_with_vols(bump: &Bump, dependencies: &DependencyCollector)
+        -> BumpedPaths {
+
+        if let &Bump::Vol(ref id, _) = bump {
+            return match dependencies.instrument_by_id(id) {
+                Some(instrument) if dependencies.vol_surface_hwm(instrument).is_some()
+                    => BumpedPaths::All,
+                _ => BumpedPaths::Unchanged
+            }
+        }
+
+        match BumpedPaths::of_bump(bump, dependencies) {
+            BumpedPaths::Unchanged => BumpedPaths::Unchanged,
+            _ => BumpedPaths::All
+        }
+    }
+
+    /// Which paths a bump changes, for short rate models whose states are
+    /// given by their parameters, so that only the yield curves and the
+    /// spot date change their bond prices
+    pub fn of_rates_bump(bump: &Bump) -> BumpedPaths {
+        match bump {
+            &Bump::Yield(_, _) => BumpedPaths::All,
+            &Bump::SpotDate(_) => BumpedPaths::All,
+            _ => BumpedPaths::Unchanged
+        }
+    }
+}
+
+/// The paths of a model whose paths are the forwards times drivers, as
+/// described for BumpedPaths. The drivers are kept, so that a bump to the
+/// forwards of an underlying only rescales its paths.
+#[derive(Clone, PartialEq)]
+pub struct ForwardScaledPaths {
+    drivers: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl ForwardScaledPaths {
+    /// Creates the paths from the drivers, indexed by path, observation
+    /// then asset, and the forwards of each asset at each observation
+    pub fn new(drivers: Array3<f64>, forwards: &[Vec<f64>]) -> ForwardScaledPaths {
+        let mut paths = ForwardScaledPaths { paths: drivers.clone(), drivers: drivers };
+        for (asset, asset_forwards) in forwards.iter().enumerate() {
+            paths.rescale(asset, asset_forwards);
+        }
+        paths
+    }
+
+    pub fn paths(&self) -> &Array3<f64> { &self.paths }
+    pub fn drivers(&self) -> &Array3<f64> { &self.drivers }
+
+    /// Rescales the paths of one asset to new forwards at each observation
+    pub fn rescale(&mut self, asset: usize, forwards: &[f64]) {
+        assert_eq!(forwards.len(), self.drivers.shape()[1]);
+        let drivers = self.drivers.subview(Axis(2), asset);
+        let mut paths = self.paths.subview_mut(Axis(2), asset);
+        for (mut path, driver) in paths.outer_iter_mut().zip(drivers.outer_iter()) {
+            for ((value, d), forward) in path.iter_mut().zip(driver.iter())
+                .zip(forwards.iter()) {
+                *value = forward * d;
+            }
+        }
+    }
+}
+
+/// Interface for Monte-Carlo models that generate their paths up front,
+/// and regenerate them from the same random numbers when their market data
+/// is bumped. Such a model implements Bumpable by delegating to bump_paths,
+/// new_saved_paths and restore_paths.
+pub trait RegeneratedPaths : MonteCarloModel {
+    /// Everything needed to restore the paths after a bump
+    type Paths: 'static;
+
+    /// The market data, or other model, that the paths are generated from
+    fn bumpable_data(&self) -> &Bumpable;
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable;
+
+    /// Copies the paths so they can be restored later
+    fn copy_paths(&self) -> Self::Paths;
+    fn set_paths(&mut self, paths: &Self::Paths);
+
+    /// Regenerates the paths after a bump that changes them, as given by
+    /// MonteCarloModel::bumped_paths
+    fn regenerate_paths(&mut self, changes: &BumpedPaths) -> Result<(), qm::Error>;
+}
+
+/// Bumps the market data of a model, then regenerates any paths that the
+/// bump changes. The paths are saved before the first bump that changes
+/// them, so bumps that leave the paths unchanged cost nothing more.
+pub fn bump_paths<M: RegeneratedPaths>(model: &mut M, bump: &Bump,
+    any_saved: Option<&mut Saveable>) -> Result<bool, qm::Error> {
+
+    let (saved_data, saved_paths) : (Option<&mut Saveable>, Option<&mut Option<M::Paths>>)
+        = if let Some(saveable) = any_saved {
+        if let Some(saved) = saveable.as_mut_any().downcast_mut::<SavedPaths<M::Paths>>() {
+            (Some(&mut *saved.saved_data), Some(&mut saved.paths))
+        } else {
+            return Err(qm::Error::new("Mismatching save space for bump"))
+        }
+    } else {
+        (None, None)
+    };
+
+    let bumped = model.bumpable_data_mut().bump(bump, saved_data)?;
+    if !bumped {
+        return Ok(false)
+    }
+    let changes = model.bumped_paths(bump)?;
+    if changes == BumpedPaths::Unchanged {
+        return Ok(true)
+    }
+    if let Some(s) = saved_paths {
+        if s.is_none() {
+            *s = Some(model.copy_paths());
+        }
+    }
+    model.regenerate_paths(&changes)?;
+    Ok(true)
+}
+
+/// Creates the save space for bump_paths
+pub fn new_saved_paths<M: RegeneratedPaths>(model: &M) -> Box<Saveable> {
+    Box::new(SavedPaths::<M::Paths> {
+        saved_data: model.bumpable_data().new_saveable(),
+        paths: None })
+}
+
+/// Restores the market data and any paths saved by bump_paths
+pub fn restore_paths<M: RegeneratedPaths>(model: &mut M, any_saved: &Saveable)
+    -> Result<(), qm::Error> {
+
+    if let Some(saved) = any_saved.as_any().downcast_ref::<SavedPaths<M::Paths>>() {
+        model.bumpable_data_mut().restore(&*saved.saved_data)?;
+        if let Some(ref paths) = saved.paths {
+            model.set_paths(paths);
+        }
+        Ok(())
+    } else {
+        Err(qm::Error::new("Mismatching save space for restore"))
+    }
+}
+
+/// Save space for a model with RegeneratedPaths to use during bumping
+pub struct SavedPaths<P> {
+    saved_data: Box<Saveable>,
+    paths: Option<P>
+}
+
+impl<P: 'static> Saveable for SavedPaths<P> {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    fn clear(&mut self) {
+        self.saved_data.clear();
+        self.paths = None;
+    }
+}
+
+/// Values the flows resulting from a Monte-Carlo valuation, for models with
+/// deterministic rates. The quantities are ordered by paths then flows, and
+/// each flow must be a pure-rates instrument. As rates are not stochastic,
+/// each flow is worth its Priceable value times its average quantity.
+pub fn evaluate_flows(context: &PricingContext, flows: &[RcInstrument],
+    quantities: ArrayView2<f64>) -> Result<f64, qm::Error> {
+
+    let flows_shape = quantities.shape();
+    let n_paths_f64: f64 = flows_shape[0] as f64;
+    assert_eq!(flows_shape[1], flows.len());
+
+    // For now, always value as of the spot date at the open. (We may want to relax this
+    // restriction later, by passing a slice of date-times into the method.)
+    let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+
+    // weighted sum of all of the flows
+    let mut total = 0.0;
+    for (flow, quantity) in flows.iter().zip(quantities.axis_iter(Axis(1))) {
+
+        // With non-stochastic rates, we can save time by evaluating the
+        // pure rate flows using Priceable
+        if flow.is_pure_rates() {
+
+            // value of the instrument times the average quantity
+            let average = quantity.scalar_sum() / n_paths_f64;
+            let pricer = flow.as_priceable().ok_or_else(|| qm::Error::new(
+                "All pure-rates flows must be priceable"))?;
+            let value = pricer.price(context, val_date)?;
+            total += average * value;
+
+        } else {
+
+            // otherwise we must price by Monte-Carlo over each path
+            // TODO how do we pass in the weights?
+            return Err(qm::Error::new("not implemented"))
+        }
+    }
+    Ok(total)
+}
+
+/// Values the flows path by path for models with deterministic rates, as
+/// evaluate_flows, then multiplies the value of each path by its weight.
+/// Models that use importance sampling weight the paths by their likelihood
+/// ratios, so that the average of the result is the price.
+pub fn evaluate_weighted_flows(context: &PricingContext, flows: &[RcInstrument],
+    quantities: ArrayView2<f64>, weights: ArrayView1<f64>)
+    -> Result<Array1<f64>, qm::Error> {
+
+    let (n_paths, n_flows) = quantities.dim();
+    assert_eq!(n_flows, flows.len());
+    assert_eq!(weights.len(), n_paths);
+
+    let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+    let mut values = Array1::<f64>::zeros(n_paths);
+    for (flow, quantity) in flows.iter().zip(quantities.axis_iter(Axis(1))) {
+        if !flow.is_pure_rates() {
+            return Err(qm::Error::new("not implemented"))
+        }
+        let pricer = flow.as_priceable().ok_or_else(|| qm::Error::new(
+            "All pure-rates flows must be priceable"))?;
+        let value = pricer.price(context, val_date)?;
+        values.scaled_add(value, &quantity);
+    }
+    values *= &weights;
+    Ok(values)
+}
+
+/// How the gaussians that drive the paths are generated
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PathGeneration {
+    /// Pseudo-random numbers, randomly seeded, so each pricer has its own
+    /// paths
+    PseudoRandom,
+    /// Pseudo-random numbers in antithetic pairs, where each odd path is
+    /// driven by the negated gaussians of the path before it. This cancels
+    /// the noise in the part of the payoff that is linear in the gaussians.
+    /// The number of paths must be even.
+    Antithetic,
+    /// A Sobol low-discrepancy sequence, with one dimension per step and
+    /// factor, which converges faster than pseudo-random numbers for
+    /// smooth payoffs. The paths are the same for every pricer.
+    Sobol,
+    /// A Sobol sequence, with the paths of each factor built by the
+    /// Brownian bridge, which puts the best dimensions onto the shape of
+    /// the path rather than its first steps
+    SobolBrownianBridge,
+    /// Pseudo-random numbers, with the end of the path of each factor
+    /// stratified, so that its gaussian falls once into each of as many
+    /// strata of equal probability as there are paths. The rest of the path
+    /// is filled in by the Brownian bridge. This removes most of the noise
+    /// of payoffs that depend on a single fixing, such as vanillas and
+    /// digitals. The strata of different factors are matched at random,
+    /// which makes a Latin hypercube of the ends of the paths.
+    Stratified,
+    /// Pseudo-random numbers, stratified in every step and factor, and
+    /// matched at random across them, which is a Latin hypercube. Each
+    /// dimension on its own is as evenly sampled as it can be, which helps
+    /// payoffs that are close to sums of functions of single dimensions.
+    LatinHypercube
+}
+
+impl Default for PathGeneration {
+    fn default() -> PathGeneration { PathGeneration::PseudoRandom }
+}
+
+/// How many threads a model may use to generate and value its paths, and
+/// the seed and streams for its pseudo-random numbers. For a given seed and
+/// stream, the prices do not depend on the number of threads, because the
+/// pseudo-random numbers are drawn in fixed blocks of paths, each from its
+/// own substream. Without a seed, the Monte-Carlo pricer picks one at
+/// random when it is built, so that each pricer still has its own paths.
+/// Sobol sequences are the same whatever the threading. Stratified and
+/// Latin hypercube paths are seeded like pseudo-random ones.
+///
+/// The pricer resolves the seed and stream once, and keeps them when it is
+/// rebuilt after a bump of time, as it is for theta. Other bumps reuse the
+/// paths of the model, so every bump of a pricer sees the same random
+/// numbers as the unbumped price.
+///
+/// Overlays such as stochastic discounting and jump to default, and
+/// non-gaussian draws such as variance gamma times, are seeded too, each
+/// from streams of its own, derived from those of the model by name. Only
+/// BlackDiffusion, and models built on it, also build their paths and value
+/// path-dependent payoffs on several threads. Other models just draw their
+/// gaussians in parallel.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub struct Threading {
+    #[serde(default)]
+    threads: usize,
+    #[serde(default)]
+    seed: Option<u64>,
+    #[serde(default)]
+    streams: StreamAssignment
+}
+
+/// How the pseudo-random streams of a pricer are chosen, within its seed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum StreamAssignment {
+    /// Every pricer with the same seed draws from the same streams, so
+    /// instruments priced separately see common random numbers
+    Shared,
+    /// Each pricer draws from streams keyed by the ids of the instruments
+    /// it values, so separately priced instruments have independent noise,
+    /// whatever order they are priced in
+    PerInstrument,
+    /// The pricer draws from the given stream
+    Stream(u64)
+}
+
+impl Default for StreamAssignment {
+    fn default() -> StreamAssignment { StreamAssignment::Shared }
+}
+
+impl Threading {
+    /// Creates a threading configuration. Zero or one threads means the
+    /// model runs on the calling thread.
+    pub fn new(threads: usize, seed: Option<u64>) -> Threading {
+        Threading::with_streams(threads, seed, StreamAssignment::Shared)
+    }
+
+    /// Creates a threading configuration, also setting how the streams of
+    /// pseudo-random numbers are chosen
+    pub fn with_streams(threads: usize, seed: Option<u64>,
+        streams: StreamAssignment) -> Threading {
+        Threading { threads: threads, seed: seed, streams: streams }
+    }
+
+    /// The number of threads to use, which is at least one
+    pub fn threads(&self) -> usize { self.threads.max(1) }
+    pub fn seed(&self) -> Option<u64> { self.seed }
+    pub fn streams(&self) -> StreamAssignment { self.streams }
+
+    /// The stream to draw from. This is zero for shared streams, and
+    /// streams per instrument must first be resolved.
+    pub fn stream(&self) -> u64 {
+        match self.streams {
+            StreamAssignment::Stream(stream) => stream,
+            _ => 0
+        }
+    }
+
+    /// Fixes the seed, picking one at random if there is none, and the
+    /// stream, keying streams per instrument by the given ids. Resolving
+    /// again changes nothing, so a pricer that is rebuilt with its
+    /// resolved threading sees the same random numbers.
+    pub fn resolve(&self, ids: &[&str]) -> Threading {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let stream = match self.streams {
+            StreamAssignment::PerInstrument => hash_words(0, ids),
+            _ => self.stream()
+        };
+        Threading::with_streams(self.threads, Some(seed),
+            StreamAssignment::Stream(stream))
+    }
+
+    /// The threading for the draws of an overlay or secondary factor, with
+    /// the same seed but a stream of its own, keyed by the given name
+    pub fn for_overlay(&self, name: &str) -> Threading {
+        let stream = hash_words(self.stream(), &[name]);
+        Threading::with_streams(self.threads, self.seed,
+            StreamAssignment::Stream(stream))
+    }
+}
+
+/// Hashes words into a stream, by 64-bit FNV-1a, which unlike the hashers
+/// of the standard library is fixed, so the streams are stable
+fn hash_words(stream: u64, words: &[&str]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ stream;
+    for word in words.iter() {
+        // terminate each word, so that the ids cannot run into each other
+        for byte in word.bytes().chain(Some(0xff)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
 /// Timeline, which collects the information about an instrument that a model
 /// needs to generate paths for valuing it.
 pub struct MonteCarloTimeline {
     _spot_date: Date,
     observations: HashMap<RcInstrument, Vec<DateDayFraction>>,
     flows: Vec<RcInstrument>,
+    quantos: HashMap<RcInstrument, String>,
+    importance_levels: HashMap<RcInstrument, (DateDayFraction, f64)>,
+    path_generation: PathGeneration,
+    importance_sampling: bool,
+    threading: Threading,
     collated: bool
 }
 
@@ -107,9 +751,67 @@ impl MonteCarloTimeline {
     pub fn new(spot_date: Date) -> MonteCarloTimeline {
         MonteCarloTimeline { _spot_date: spot_date, 
             observations: HashMap::new(), flows: Vec::new(),
+            quantos: HashMap::new(), importance_levels: HashMap::new(),
+            path_generation: PathGeneration::PseudoRandom,
+            importance_sampling: false, threading: Threading::default(),
             collated: false }
     }
 
+    /// Sets how the model should generate the gaussians for its paths.
+    /// Models that cannot use low-discrepancy sequences may ignore this.
+    pub fn set_path_generation(&mut self, path_generation: PathGeneration) {
+        self.path_generation = path_generation;
+    }
+
+    pub fn path_generation(&self) -> PathGeneration {
+        self.path_generation
+    }
+
+    /// Sets how many threads the model may use, and how to seed it
+    pub fn set_threading(&mut self, threading: Threading) {
+        self.threading = threading;
+    }
+
+    pub fn threading(&self) -> Threading {
+        self.threading
+    }
+
+    /// Sets whether the model should use importance sampling, shifting the
+    /// drifts of the underlyings towards the levels that matter to the
+    /// payoffs. Models that do not support it may ignore this.
+    pub fn set_importance_sampling(&mut self, importance_sampling: bool) {
+        self.importance_sampling = importance_sampling;
+    }
+
+    /// If importance sampling is on, the level that matters most for each
+    /// underlying that has one, with the observation it matters at
+    pub fn importance_levels(&self)
+        -> Option<&HashMap<RcInstrument, (DateDayFraction, f64)>> {
+        assert!(self.collated);
+        if self.importance_sampling {
+            Some(&self.importance_levels)
+        } else {
+            None
+        }
+    }
+
+    /// The observations of an underlying registered so far, in the order
+    /// they were registered
+    pub fn registered_observations(&self, instrument: &RcInstrument)
+        -> &[DateDayFraction] {
+        self.observations.get(instrument).map_or(&[][..], |obs| &obs[..])
+    }
+
+    /// The number of observations registered so far for each underlying,
+    /// and the number of flows. Comparing these before and after an
+    /// instrument registers its dependencies finds the columns of the paths
+    /// and the flows that belong to it.
+    pub fn registered(&self) -> (HashMap<RcInstrument, usize>, usize) {
+        let observations = self.observations.iter()
+            .map(|(instrument, obs)| (instrument.clone(), obs.len())).collect();
+        (observations, self.flows.len())
+    }
+
     pub fn collate(&mut self) -> Result<(), qm::Error> {
 
         // Sort each of the observations vectors by date/day-fraction and
@@ -132,6 +834,13 @@ impl MonteCarloTimeline {
         assert!(self.collated);
         &self.flows
     }
+
+    /// The underlyings whose paths must be quanto-adjusted, with the id of
+    /// the FX rate that defines the adjustment
+    pub fn quantos(&self) -> &HashMap<RcInstrument, String> {
+        assert!(self.collated);
+        &self.quantos
+    }
 }
 
 impl MonteCarloDependencies for MonteCarloTimeline {
@@ -151,4 +860,142 @@ impl MonteCarloDependencies for MonteCarloTimeline {
         // the client later relies on this order
         self.flows.push(instrument.clone());
     }
+
+    fn quanto(&mut self, instrument: &RcInstrument, fx_id: &str) {
+
+        // An underlying can only be evolved in one measure, so the last
+        // quanto specified for any underlying wins
+        self.quantos.insert(instrument.clone(), fx_id.to_string());
+    }
+
+    fn importance_level(&mut self, instrument: &RcInstrument,
+        date_time: DateDayFraction, level: f64) {
+
+        // Likewise, an underlying can only be shifted one way, so the last
+        // level specified wins
+        self.importance_levels.insert(instrument.clone(), (date_time, level));
+    }
 } 
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::Priceable;
+    use instruments::DependencyContext;
+    use instruments::bonds::ZeroCoupon;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::Pricer;
+    use risk::cache::PricingContextPrefetch;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use serde_json;
+    use std::sync::Arc;
+
+    /// What a closed form needs to price a European on the sample
+    /// underlying the way a Monte-Carlo model sees it: the forward and vol
+    /// time to expiry, the at the money variance, and the discount factor
+    /// to the pay date.
+    pub struct EuropeanTerms {
+        pub forward: f64,
+        pub vol_time: f64,
+        pub sqrt_variance: f64,
+        pub df: f64
+    }
+
+    pub fn sample_european_terms(market_data: &MarketData, expiry: DateTime)
+        -> EuropeanTerms {
+
+        let equity = sample_underlying();
+        let forward_curve = market_data.forward_curve(&*equity, expiry.date()).unwrap();
+        let forward = forward_curve.forward(expiry.date()).unwrap();
+        let vol = market_data.vol_surface(&*equity, expiry.date(),
+            &|| Ok(forward_curve.clone())).unwrap();
+        let obs = equity.time_to_day_fraction(expiry).unwrap();
+        let vol_time = vol.vol_time(obs).unwrap();
+        let sqrt_variance = vol.variance(obs, forward).unwrap().sqrt();
+        let pay_date = sample_settlement(2).apply(expiry.date());
+        let bond = ZeroCoupon::new("SampleBond", "OPT",
+            RcCurrency::new(Arc::new(sample_currency(2))), expiry,
+            pay_date, sample_settlement(2));
+        let df = bond.price(market_data, sample_val_date()).unwrap();
+        EuropeanTerms { forward: forward, vol_time: vol_time,
+            sqrt_variance: sqrt_variance, df: df }
+    }
+
+    /// Prices each European on the sample underlying with the given model,
+    /// and checks it is within tolerance of the closed form.
+    pub fn check_monte_carlo_europeans(model_factory: RcMonteCarloModelFactory,
+        market_data: &MarketData, expiry: DateTime, options: &[(f64, PutOrCall)],
+        tolerance: f64, closed_form: &Fn(&EuropeanTerms, f64, PutOrCall) -> f64) {
+
+        let terms = sample_european_terms(market_data, expiry);
+        for &(strike, put_or_call) in options.iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), expiry, strike, put_or_call,
+                OptionSettlement::Cash).unwrap();
+            let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(european))))];
+            let pricer = MonteCarloPricer::with_settings(instruments,
+                model_factory.clone(), MonteCarloSettings {
+                    threading: Threading::new(1, Some(42)),
+                    ..MonteCarloSettings::default() }, market_data).unwrap();
+            let price = pricer.price().unwrap();
+            let expected = closed_form(&terms, strike, put_or_call);
+            assert!(approx_eq(price, expected, tolerance),
+                "strike={} price={} expected={}", strike, price, expected);
+        }
+    }
+
+    /// Collects the timeline and prefetched market data needed to build a
+    /// model for an instrument directly, rather than through a pricer, so a
+    /// test can get at the model itself. The market data is fetched for the
+    /// needs of the underlying, which for an option on a bond must be the
+    /// bond, as the option would also ask for a vol surface.
+    pub fn sample_model_inputs(market_data: &MarketData, instrument: &RcInstrument,
+        underlying: &RcInstrument) -> (MonteCarloTimeline, Box<BumpablePricingContext>) {
+
+        let spot_date = market_data.spot_date();
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        timeline.set_threading(Threading::new(1, Some(42)));
+        instrument.as_mc_priceable().unwrap().mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(underlying);
+        let context = PricingContextPrefetch::new(market_data,
+            Arc::new(dependencies)).unwrap();
+        (timeline, Box::new(context))
+    }
+
+    /// Applies a bump that should not change the paths of the model, and
+    /// checks that they are untouched. Paths are saved before they are
+    /// regenerated, so finding none saved shows they were not regenerated.
+    pub fn check_bump_leaves_paths<M: RegeneratedPaths>(model: &mut M, bump: &Bump)
+        where M::Paths: PartialEq {
+
+        let paths = model.copy_paths();
+        let mut save = model.new_saveable();
+        assert!(model.bump(bump, Some(&mut *save)).unwrap());
+        assert!(save.as_any().downcast_ref::<SavedPaths<M::Paths>>().unwrap()
+            .paths.is_none());
+        assert!(model.copy_paths() == paths);
+        model.restore(&*save).unwrap();
+    }
+
+    /// Serializes and deserializes a value, checking that it writes out the
+    /// same way again, and returns the copy.
+    pub fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        let serialized = serde_json::to_string(value).unwrap();
+        let deserialized: T = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(serde_json::to_string(&deserialized).unwrap(), serialized);
+        deserialized
+    }
+}
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use statrs::distribution::Normal;
+use statrs::distribution::Univariate;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use math::optionpricing::Black76;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::vol_times;
+use models::calculate_substepping;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Number of steps in vol time used to build the distribution of the time
+/// spent in the stressed regime, for semi-analytic pricing
+const OCCUPATION_STEPS: usize = 400;
+
+/// The two volatility regimes of a regime-switching model
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Regime {
+    Calm,
+    Stressed
+}
+
+/// The parameters of a two-regime Markov-switching Black model for one
+/// underlying. The underlying is log-normal with the vol of the current
+/// regime, and the regime is a continuous-time Markov chain that switches
+/// from calm to stressed with the given intensity, and back again with
+/// another. The regime is independent of the underlying.
+///
+/// Given the time spent in the stressed regime, the underlying is
+/// log-normal with the combined variance of the two regimes, so Europeans
+/// are priced semi-analytically by integrating the Black price over the
+/// distribution of the time spent stressed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RegimeSwitchingParameters {
+    calm_vol: f64,
+    stressed_vol: f64,
+    calm_to_stressed: f64,
+    stressed_to_calm: f64,
+    initial_regime: Regime
+}
+
+impl RegimeSwitchingParameters {
+    pub fn new(calm_vol: f64, stressed_vol: f64, calm_to_stressed: f64,
+        stressed_to_calm: f64, initial_regime: Regime)
+        -> Result<RegimeSwitchingParameters, qm::Error> {
+
+        if calm_vol < 0.0 || stressed_vol < 0.0 {
+            return Err(qm::Error::new("Regime-switching vols must be non-negative"))
+        }
+        if calm_to_stressed < 0.0 || stressed_to_calm < 0.0 {
+            return Err(qm::Error::new(
+                "Regime-switching intensities must be non-negative"))
+        }
+
+        Ok(RegimeSwitchingParameters { calm_vol: calm_vol,
+            stressed_vol: stressed_vol, calm_to_stressed: calm_to_stressed,
+            stressed_to_calm: stressed_to_calm, initial_regime: initial_regime })
+    }
+
+    pub fn calm_vol(&self) -> f64 { self.calm_vol }
+    pub fn stressed_vol(&self) -> f64 { self.stressed_vol }
+    pub fn calm_to_stressed(&self) -> f64 { self.calm_to_stressed }
+    pub fn stressed_to_calm(&self) -> f64 { self.stressed_to_calm }
+    pub fn initial_regime(&self) -> Regime { self.initial_regime }
+
+    /// The vol in the given regime
+    pub fn vol(&self, regime: Regime) -> f64 {
+        match regime {
+            Regime::Calm => self.calm_vol,
+            Regime::Stressed => self.stressed_vol
+        }
+    }
+
+    /// The probability that the chain is in the other regime after the
+    /// given time, starting in the given regime. This is exact, whatever
+    /// switching happens in between.
+    pub fn switch_probability(&self, regime: Regime, dt: f64) -> f64 {
+        let total = self.calm_to_stressed + self.stressed_to_calm;
+        if total <= 0.0 || dt <= 0.0 {
+            return 0.0
+        }
+        let intensity = match regime {
+            Regime::Calm => self.calm_to_stressed,
+            Regime::Stressed => self.stressed_to_calm
+        };
+        intensity / total * (1.0 - (-total * dt).exp())
+    }
+
+    /// The expected total variance to the given vol time, from the expected
+    /// time spent in the stressed regime
+    pub fn expected_variance(&self, t: f64) -> f64 {
+        let total = self.calm_to_stressed + self.stressed_to_calm;
+        let initial = if self.initial_regime == Regime::Stressed { 1.0 } else { 0.0 };
+        let stressed_time = if total <= 0.0 {
+            initial * t
+        } else {
+            let stationary = self.calm_to_stressed / total;
+            stationary * t + (initial - stationary) * (1.0 - (-total * t).exp()) / total
+        };
+        let calm_variance = self.calm_vol * self.calm_vol;
+        let stressed_variance = self.stressed_vol * self.stressed_vol;
+        calm_variance * t + (stressed_variance - calm_variance) * stressed_time
+    }
+
+    /// Semi-analytic price of a European call
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        let black76 = Black76::new()?;
+        self.integrate(t, &|variance| black76.call_price(df, forward, strike,
+            variance.sqrt()))
+    }
+
+    /// Semi-analytic price of a European put
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        let black76 = Black76::new()?;
+        self.integrate(t, &|variance| black76.put_price(df, forward, strike,
+            variance.sqrt()))
+    }
+
+    /// Integrates a function of the total variance to the given vol time
+    /// over the distribution of the time spent stressed. The distribution
+    /// comes from the chain observed at evenly spaced steps, with the
+    /// regime at the start of each step applying over the step, as in the
+    /// Monte-Carlo model.
+    fn integrate(&self, t: f64, f: &Fn(f64) -> f64) -> Result<f64, qm::Error> {
+        if t <= 0.0 {
+            return Ok(f(0.0))
+        }
+
+        // probabilities of being calm or stressed, indexed by the number of
+        // steps so far spent stressed
+        let n = OCCUPATION_STEPS;
+        let h = t / n as f64;
+        let calm_stay = 1.0 - self.switch_probability(Regime::Calm, h);
+        let stressed_stay = 1.0 - self.switch_probability(Regime::Stressed, h);
+        let mut calm = vec![0.0; n + 1];
+        let mut stressed = vec![0.0; n + 1];
+        match self.initial_regime {
+            Regime::Calm => calm[0] = 1.0,
+            Regime::Stressed => stressed[0] = 1.0
+        }
+        for step in 0..n {
+            let mut next_calm = vec![0.0; n + 1];
+            let mut next_stressed = vec![0.0; n + 1];
+            for k in 0..(step + 1) {
+                next_calm[k] += calm[k] * calm_stay;
+                next_stressed[k] += calm[k] * (1.0 - calm_stay);
+                next_calm[k + 1] += stressed[k] * (1.0 - stressed_stay);
+                next_stressed[k + 1] += stressed[k] * stressed_stay;
+            }
+            calm = next_calm;
+            stressed = next_stressed;
+        }
+
+        let calm_variance = self.calm_vol * self.calm_vol * h;
+        let stressed_variance = self.stressed_vol * self.stressed_vol * h;
+        let mut result = 0.0;
+        for k in 0..(n + 1) {
+            let probability = calm[k] + stressed[k];
+            if probability > 0.0 {
+                let variance = stressed_variance * k as f64
+                    + calm_variance * (n - k) as f64;
+                result += probability * f(variance);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// The RegimeSwitchingFactory creates a regime-switching Black model, given
+/// the timeline of the product(s) to value and the market data. The
+/// parameters for each underlying are held by the factory, keyed by the id
+/// of the underlying, as the market data only contains implied vols. The
+/// factory also holds the maximum time step and the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RegimeSwitchingFactory {
+    parameters: HashMap<String, RegimeSwitchingParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl RegimeSwitchingFactory {
+    pub fn new(parameters: HashMap<String, RegimeSwitchingParameters>,
+        time_step: f64, number_of_paths: usize) -> RegimeSwitchingFactory {
+
+        RegimeSwitchingFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RegimeSwitchingFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for RegimeSwitchingFactory {
+    fn type_id(&self) -> &'static str { "RegimeSwitchingFactory" }
+}
+
+impl MonteCarloModelFactory for RegimeSwitchingFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = RegimeSwitching::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A regime-switching model evolves each underlying as a log-normal
+/// diffusion whose vol switches between a calm and a stressed level, at
+/// random times given by its own Markov chain. Within each step the vol is
+/// that of the regime at the start of the step, and the regime at the end
+/// of the step is sampled exactly from the chain. The underlyings are
+/// correlated with each other via the correlations in the market data, but
+/// their regimes are independent.
+///
+/// The drift comes from the forward curve in the market data, as for
+/// BlackDiffusion. The vol surface only supplies the measure of time, so
+/// vol bumps have no effect on the price. Quanto underlyings and displaced
+/// vol surfaces are not supported.
+#[derive(Clone)]
+pub struct RegimeSwitching {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<RegimeSwitchingParameters>,
+    substepping: Vec<usize>,
+    spot_gaussians: Array3<f64>,
+    regime_gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl RegimeSwitching {
+
+    /// Creates a new regime-switching model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the parameters by underlying id, the maximum step in vol time and the
+    /// number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, RegimeSwitchingParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<RegimeSwitching, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("Regime-switching time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new(
+                "Regime-switching does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "regime-switching")?;
+
+        let substepping = calculate_substepping(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let regime_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &spot_gaussians,
+            &regime_gaussians, context.as_pricing_context(), &instruments,
+            &asset_parameters, &substepping)?;
+
+        Ok(RegimeSwitching {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            substepping: substepping,
+            spot_gaussians: spot_gaussians,
+            regime_gaussians: regime_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.spot_gaussians,
+            &self.regime_gaussians, self.context.as_pricing_context(),
+            &self.instruments, &self.parameters, &self.substepping)?;
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    spot_gaussians: &Array3<f64>,
+    regime_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[RegimeSwitchingParameters],
+    substepping: &[usize]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the underlyings with each other, but not their regimes
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, spot_gaussians)?;
+
+    for ((((instrument, p), spot), regime), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(regime_gaussians.axis_iter(Axis(2)))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, context, observations, spot, regime,
+            substepping, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &RegimeSwitchingParameters,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, regime_gaussians: ArrayView2<f64>,
+    substepping: &[usize], mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new(
+                "Regime-switching does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for (time, substep) in times.iter().zip(substepping.iter()) {
+        steps.push((time - previous) / (*substep as f64));
+        previous = *time;
+    }
+
+    // the switching probabilities only depend on the step size and regime
+    let switches: Vec<(f64, f64)> = steps.iter().map(|dt|
+        (parameters.switch_probability(Regime::Calm, *dt),
+        parameters.switch_probability(Regime::Stressed, *dt))).collect();
+
+    let normal = match Normal::new(0.0, 1.0) {
+        Ok(normal) => normal,
+        Err(e) => return Err(qm::Error::new(&format!("RSStat error: {}", e)))
+    };
+    for ((z_x, z_r), mut one_path) in spot_gaussians.outer_iter()
+        .zip(regime_gaussians.outer_iter()).zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0;
+        let mut regime = parameters.initial_regime;
+        let mut g = 0;
+        for i in 0..observations.len() {
+            let (calm_switch, stressed_switch) = switches[i];
+            for _ in 0..substepping[i] {
+                let vol = parameters.vol(regime);
+                let variance = vol * vol * steps[i];
+                log_x += variance.sqrt() * z_x[g] - 0.5 * variance;
+
+                let switch = match regime {
+                    Regime::Calm => calm_switch,
+                    Regime::Stressed => stressed_switch
+                };
+                if normal.cdf(z_r[g]) < switch {
+                    regime = match regime {
+                        Regime::Calm => Regime::Stressed,
+                        Regime::Stressed => Regime::Calm
+                    };
+                }
+                g += 1;
+            }
+            one_path[i] = forwards[i] * log_x.exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for RegimeSwitching {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The vols of the calm and stressed regimes are parameters of the model,
+    /// so bumps to vol levels leave the paths unchanged. Bumps to forwards,
+    /// rates or correlations change them.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for RegimeSwitching {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("RegimeSwitching does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for RegimeSwitching {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for RegimeSwitching {
+
+    /// Bumps the market data, then regenerates all the paths with the same
+    /// random numbers and regime switches, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_expiry;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use risk::marketdata::tests::sample_market_data;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    fn switching() -> RegimeSwitchingParameters {
+        RegimeSwitchingParameters::new(0.15, 0.45, 1.0, 3.0, Regime::Calm).unwrap()
+    }
+
+    #[test]
+    fn no_switching_is_black() {
+        let black76 = Black76::new().unwrap();
+        for &(regime, vol) in [(Regime::Calm, 0.2), (Regime::Stressed, 0.5)].iter() {
+            let p = RegimeSwitchingParameters::new(0.2, 0.5, 0.0, 0.0, regime).unwrap();
+            for &strike in [70.0, 100.0, 130.0].iter() {
+                let expected = black76.call_price(0.95, 100.0, strike, vol * 2.0_f64.sqrt());
+                let call = p.call_price(0.95, 100.0, strike, 2.0).unwrap();
+                assert!(approx_eq(call, expected, 1e-10),
+                    "strike={} call={} expected={}", strike, call, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn occupation_matches_expected_variance() {
+        // integrating the variance itself gives its expectation, up to the
+        // discretization of the chain
+        let p = switching();
+        let t = 1.5;
+        let expected = p.expected_variance(t);
+        let variance = p.integrate(t, &|variance| variance).unwrap();
+        assert!(approx_eq(variance, expected, 1e-3 * expected),
+            "variance={} expected={}", variance, expected);
+
+        // switching to stressed raises the price above calm Black
+        let black76 = Black76::new().unwrap();
+        let calm = black76.call_price(1.0, 100.0, 100.0, 0.15 * t.sqrt());
+        let call = p.call_price(1.0, 100.0, 100.0, t).unwrap();
+        assert!(call > calm, "call={} calm={}", call, calm);
+    }
+
+    #[test]
+    fn monte_carlo_matches_semi_analytic() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), switching());
+        let factory = round_trip(&RegimeSwitchingFactory::new(parameters,
+            1.0 / 52.0, 20000));
+
+        // Seeded paths, so the result does not change from run to run.
+        // Regime switches add to the noise, with a standard error of about
+        // 0.16 for the at the money call.
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), sample_expiry(),
+            &[(70.0, PutOrCall::Put), (100.0, PutOrCall::Call), (130.0, PutOrCall::Call)],
+            0.5, &|terms, strike, put_or_call| match put_or_call {
+                PutOrCall::Call => switching().call_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap(),
+                PutOrCall::Put => switching().put_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap()
+            });
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            switching());
+        let mut model = RegimeSwitching::new(&timeline, context,
+            &parameters, 1.0 / 52.0, 100).unwrap();
+
+        // the regime vols are parameters of the model
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,711 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::vol_times;
+use models::calculate_substepping;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The parameters of the rough Bergomi model of Bayer, Friz and Gatheral
+/// (2016) for one underlying:
+///
+///  dS/S = mu(t) dt + sqrt(V) dZ
+///  V(t) = xi0 exp(eta Y(t) - eta^2 t^(2H) / 2)
+///  Y(t) = sqrt(2H) integral of (t - s)^(H - 1/2) dW(s) from 0 to t
+///  dZ dW = rho dt
+///
+/// where xi0 is the forward variance, which is flat, eta the vol of
+/// variance and H the Hurst exponent of the variance, which is rough for
+/// H less than a half. Rough vol gives the power-law term structure of the
+/// at-the-money skew seen in equity markets with very few parameters.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RoughBergomiParameters {
+    xi0: f64,
+    eta: f64,
+    hurst: f64,
+    rho: f64
+}
+
+impl RoughBergomiParameters {
+    pub fn new(xi0: f64, eta: f64, hurst: f64, rho: f64)
+        -> Result<RoughBergomiParameters, qm::Error> {
+
+        if xi0 <= 0.0 {
+            return Err(qm::Error::new("Rough Bergomi forward variance must be positive"))
+        }
+        if eta < 0.0 {
+            return Err(qm::Error::new("Rough Bergomi vol of variance must not be negative"))
+        }
+        if hurst <= 0.0 || hurst >= 0.5 {
+            return Err(qm::Error::new("Rough Bergomi Hurst exponent must be strictly \
+                between zero and a half"))
+        }
+        if rho < -1.0 || rho > 1.0 {
+            return Err(qm::Error::new("Rough Bergomi correlation must be between -1 and 1"))
+        }
+
+        Ok(RoughBergomiParameters { xi0: xi0, eta: eta, hurst: hurst, rho: rho })
+    }
+
+    pub fn xi0(&self) -> f64 { self.xi0 }
+    pub fn eta(&self) -> f64 { self.eta }
+    pub fn hurst(&self) -> f64 { self.hurst }
+    pub fn rho(&self) -> f64 { self.rho }
+}
+
+/// The discretization of the Volterra process Y on a grid of time steps,
+/// by the hybrid scheme of Bennedsen, Lunde and Pakkanen (2017) with one
+/// exact near-field term. Within the step ending at each grid point, the
+/// Brownian increment and the integral of the kernel are simulated exactly
+/// as a correlated pair of gaussians. Further back, each Brownian increment
+/// is weighted by the average of the kernel over its step, which allows a
+/// non-uniform grid, so observations need not be evenly spaced.
+///
+/// The kernel only depends on the grid and the Hurst exponent, so it is
+/// built once per model and reused every time the paths are refetched.
+#[derive(Clone, Debug)]
+pub struct VolterraKernel {
+    hurst: f64,
+    steps: Vec<f64>,
+    root_steps: Array1<f64>,
+    near_correlated: Array1<f64>,
+    near_independent: Array1<f64>,
+    near_steps: Vec<Option<usize>>,
+    weights: Array2<f64>,
+    variances: Array1<f64>
+}
+
+impl VolterraKernel {
+
+    /// Builds the kernel for the given step lengths and Hurst exponent
+    pub fn new(steps: &[f64], hurst: f64) -> VolterraKernel {
+
+        let n = steps.len();
+        let alpha = hurst - 0.5;
+        let scale = 2.0 * hurst;
+        let mut times = Vec::with_capacity(n + 1);
+        times.push(0.0);
+        for dt in steps.iter() {
+            let last = *times.last().unwrap();
+            times.push(last + dt.max(0.0));
+        }
+
+        // The near-field integral over each step is c z1 + d z2, where
+        // z1 also drives the Brownian increment, sqrt(dt) z1
+        let mut root_steps = Array1::<f64>::zeros(n);
+        let mut near_correlated = Array1::<f64>::zeros(n);
+        let mut near_independent = Array1::<f64>::zeros(n);
+        let mut near_variances = vec![0.0; n];
+        for (j, dt) in steps.iter().enumerate() {
+            if *dt <= 0.0 {
+                continue;
+            }
+            let covariance = dt.powf(alpha + 1.0) / (alpha + 1.0);
+            let variance = dt.powf(2.0 * alpha + 1.0) / (2.0 * alpha + 1.0);
+            let c = covariance / dt.sqrt();
+            root_steps[j] = dt.sqrt();
+            near_correlated[j] = c;
+            near_independent[j] = (variance - c * c).max(0.0).sqrt();
+            near_variances[j] = variance;
+        }
+
+        // The near field of each grid point is the last step of non-zero
+        // length before it. The far-field weight of the increment over step
+        // j, as seen from grid point i, is stored at [j, i] so that the far
+        // field for all paths is a single matrix product. The variance of
+        // the scheme at each grid point is accumulated as we go.
+        let mut near_steps = vec![None; n];
+        let mut weights = Array2::<f64>::zeros((n, n));
+        let mut variances = Array1::<f64>::zeros(n);
+        for i in 1..n {
+            near_steps[i] = if steps[i - 1] > 0.0 { Some(i - 1) } else { near_steps[i - 1] };
+            let near_step = match near_steps[i] {
+                Some(near_step) => near_step,
+                None => continue
+            };
+            let mut variance = near_variances[near_step];
+            for j in 0..near_step {
+                if steps[j] <= 0.0 {
+                    continue;
+                }
+                let w = ((times[i] - times[j]).powf(alpha + 1.0)
+                    - (times[i] - times[j + 1]).powf(alpha + 1.0))
+                    / ((alpha + 1.0) * steps[j]);
+                weights[[j, i]] = w;
+                variance += w * w * steps[j];
+            }
+            variances[i] = scale * variance;
+        }
+
+        VolterraKernel {
+            hurst: hurst,
+            steps: steps.to_vec(),
+            root_steps: root_steps,
+            near_correlated: near_correlated,
+            near_independent: near_independent,
+            near_steps: near_steps,
+            weights: weights,
+            variances: variances }
+    }
+
+    /// Whether this kernel was built for the given steps and Hurst exponent
+    pub fn matches(&self, steps: &[f64], hurst: f64) -> bool {
+        self.hurst == hurst && self.steps == steps
+    }
+
+    /// The variance of the discretized Y at the start of each step. This
+    /// is close to the exact t^(2H), and is used in place of it so that the
+    /// expected variance is exactly the forward variance.
+    pub fn variances(&self) -> &Array1<f64> { &self.variances }
+
+    /// Given gaussians z1 and z2, each by path and step, returns the
+    /// Brownian increments over each step, and the Volterra process Y at
+    /// the start of each step, both by path and step.
+    pub fn simulate(&self, z1: ArrayView2<f64>, z2: ArrayView2<f64>)
+        -> (Array2<f64>, Array2<f64>) {
+
+        let increments = &z1 * &self.root_steps;
+        let near = &(&z1 * &self.near_correlated) + &(&z2 * &self.near_independent);
+        let mut volterra = increments.dot(&self.weights);
+
+        // Y at the start of each step includes the near field
+        let scale = (2.0 * self.hurst).sqrt();
+        for (i, near_step) in self.near_steps.iter().enumerate() {
+            let mut column = volterra.subview_mut(Axis(1), i);
+            if let Some(near_step) = *near_step {
+                column += &near.subview(Axis(1), near_step);
+                column *= scale;
+            } else {
+                column.fill(0.0);
+            }
+        }
+
+        (increments, volterra)
+    }
+}
+
+/// The RoughBergomiFactory creates a rough Bergomi model, given the
+/// timeline of the product(s) to value and the market data. The parameters
+/// for each underlying are held by the factory, keyed by the id of the
+/// underlying, as the market data only contains implied vols. The factory
+/// also holds the maximum time step and the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoughBergomiFactory {
+    parameters: HashMap<String, RoughBergomiParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl RoughBergomiFactory {
+    pub fn new(parameters: HashMap<String, RoughBergomiParameters>,
+        time_step: f64, number_of_paths: usize) -> RoughBergomiFactory {
+
+        RoughBergomiFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RoughBergomiFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for RoughBergomiFactory {
+    fn type_id(&self) -> &'static str { "RoughBergomiFactory" }
+}
+
+impl MonteCarloModelFactory for RoughBergomiFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = RoughBergomi::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A rough Bergomi model evolves each underlying with its own rough
+/// variance, given by the hybrid scheme. The paths for all Monte-Carlo
+/// paths are built together, a step at a time, using array operations,
+/// with the far field of the Volterra process as a single matrix product,
+/// which is much faster than convolving path by path. The kernels are
+/// cached, and only rebuilt if a bump changes the vol times.
+///
+/// The spot of each underlying is log-normal within each step, with the
+/// variance at the start of the step, so relative to its forward it is a
+/// martingale. The underlyings are correlated with each other via the
+/// correlations in the market data, and each with its own variance via
+/// rho, but the variances are independent of each other.
+///
+/// As for Heston, the drift comes from the forward curve and the vol
+/// surface only supplies the business-day vol time. Vol bumps have no
+/// effect on the price. Quanto underlyings and displaced vol surfaces are
+/// not supported.
+#[derive(Clone)]
+pub struct RoughBergomi {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<RoughBergomiParameters>,
+    substepping: Vec<usize>,
+    kernels: Vec<VolterraKernel>,
+    variance_gaussians: Array3<f64>,
+    near_gaussians: Array3<f64>,
+    spot_gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl RoughBergomi {
+
+    /// Creates a new rough Bergomi model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the parameters by underlying id, the maximum step in vol time and
+    /// the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, RoughBergomiParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<RoughBergomi, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("Rough Bergomi time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("Rough Bergomi does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "rough Bergomi")?;
+
+        let substepping = calculate_substepping(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+        let mut kernels = Vec::with_capacity(instruments.len());
+        for (instrument, p) in instruments.iter().zip(asset_parameters.iter()) {
+            let steps = grid_steps(instrument.deref(), context.as_pricing_context(),
+                &observations, &substepping)?;
+            kernels.push(VolterraKernel::new(&steps, p.hurst));
+        }
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let variance_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let near_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &variance_gaussians,
+            &near_gaussians, &spot_gaussians, context.as_pricing_context(),
+            &instruments, &asset_parameters, &kernels, &substepping)?;
+
+        Ok(RoughBergomi {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            substepping: substepping,
+            kernels: kernels,
+            variance_gaussians: variance_gaussians,
+            near_gaussians: near_gaussians,
+            spot_gaussians: spot_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers,
+    /// and the same kernels unless the vol times have changed
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        for ((instrument, p), kernel) in self.instruments.iter()
+            .zip(self.parameters.iter()).zip(self.kernels.iter_mut()) {
+
+            let steps = grid_steps(instrument.deref(),
+                self.context.as_pricing_context(), &self.observations,
+                &self.substepping)?;
+            if !kernel.matches(&steps, p.hurst) {
+                *kernel = VolterraKernel::new(&steps, p.hurst);
+            }
+        }
+
+        self.paths = fetch_paths(&self.observations, &self.variance_gaussians,
+            &self.near_gaussians, &self.spot_gaussians,
+            self.context.as_pricing_context(), &self.instruments,
+            &self.parameters, &self.kernels, &self.substepping)?;
+        Ok(())
+    }
+}
+
+/// The length in vol time of every step of the simulation for one asset
+fn grid_steps(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction], substepping: &[usize])
+    -> Result<Vec<f64>, qm::Error> {
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::new();
+    let mut previous = 0.0;
+    for (time, substep) in times.iter().zip(substepping.iter()) {
+        let dt = ((time - previous) / (*substep as f64)).max(0.0);
+        for _ in 0..*substep {
+            steps.push(dt);
+        }
+        previous = *time;
+    }
+    Ok(steps)
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    variance_gaussians: &Array3<f64>,
+    near_gaussians: &Array3<f64>,
+    spot_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[RoughBergomiParameters],
+    kernels: &[VolterraKernel],
+    substepping: &[usize]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the underlyings with each other, but not their variances
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, spot_gaussians)?;
+
+    for ((((((instrument, p), kernel), z1), z2), spot), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(kernels.iter())
+        .zip(variance_gaussians.axis_iter(Axis(2)))
+        .zip(near_gaussians.axis_iter(Axis(2)))
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, kernel, context, observations,
+            z1, z2, spot, substepping, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &RoughBergomiParameters,
+    kernel: &VolterraKernel, context: &PricingContext,
+    observations: &[DateDayFraction], variance_gaussians: ArrayView2<f64>,
+    near_gaussians: ArrayView2<f64>, spot_gaussians: ArrayView2<f64>,
+    substepping: &[usize], mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("Rough Bergomi does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+
+    let (increments, volterra) = kernel.simulate(variance_gaussians, near_gaussians);
+
+    // the variance at the start of each step, for all paths
+    let eta = parameters.eta;
+    let mut variance = volterra * eta;
+    variance -= &(kernel.variances() * (0.5 * eta * eta));
+    variance.mapv_inplace(|x| parameters.xi0 * x.exp());
+
+    // the log step of the spot within each step, for all paths
+    let orthogonal = (1.0 - parameters.rho * parameters.rho).sqrt();
+    let mut drivers = increments * parameters.rho;
+    drivers += &(&(&spot_gaussians * &kernel.root_steps) * orthogonal);
+    let dt = Array1::from_vec(kernel.steps.clone());
+    let mut log_steps = &variance.mapv(f64::sqrt) * &drivers;
+    log_steps -= &(&(&variance * &dt) * 0.5);
+
+    // accumulate the log steps, recording the spot at each observation
+    let mut log_x = Array1::<f64>::zeros(path.shape()[0]);
+    let mut step = 0;
+    for (i, substeps) in substepping.iter().enumerate() {
+        for _ in 0..*substeps {
+            log_x += &log_steps.subview(Axis(1), step);
+            step += 1;
+        }
+        let forward = forwards[i];
+        path.subview_mut(Axis(1), i).assign(&log_x.mapv(|x| forward * x.exp()));
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for RoughBergomi {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The forward variance xi0 is a parameter of the model, so bumps to vol
+    /// levels leave the rough Bergomi paths unchanged. Bumps to forwards,
+    /// rates or correlations change them.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for RoughBergomi {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Rough Bergomi does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for RoughBergomi {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for RoughBergomi {
+
+    /// Bumps the market data, then regenerates the paths from the same
+    /// Volterra drivers, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::blackdiffusion::fetch_gaussians;
+    use math::numerics::approx_eq;
+    use math::optionpricing::Black76;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::Pricer;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::EuropeanTerms;
+    use models::tests::sample_european_terms;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+
+    #[test]
+    fn kernel_variance_is_close_to_exact() {
+        // an uneven grid, as given by observations at irregular times
+        let mut steps = vec![0.02; 30];
+        steps.extend(vec![0.05; 10]);
+        steps.push(0.0);
+        steps.push(0.1);
+        let hurst = 0.1;
+        let kernel = VolterraKernel::new(&steps, hurst);
+        let mut t = 0.0_f64;
+        for (i, dt) in steps.iter().enumerate() {
+            let expected = t.powf(2.0 * hurst);
+            let variance = kernel.variances()[i];
+            assert!(approx_eq(variance, expected, 0.02 * expected + 1e-12),
+                "i={} t={} variance={} expected={}", i, t, variance, expected);
+            t += dt;
+        }
+        assert!(kernel.matches(&steps, hurst));
+        assert!(!kernel.matches(&steps, 0.2));
+    }
+
+    #[test]
+    fn kernel_simulates_volterra_variance() {
+        let steps = vec![0.05; 20];
+        let kernel = VolterraKernel::new(&steps, 0.15);
+        let n_paths = 20000;
+        let z1 = fetch_gaussians(&steps.iter().map(|_| 1).collect::<Vec<usize>>(), 1, n_paths);
+        let z2 = fetch_gaussians(&steps.iter().map(|_| 1).collect::<Vec<usize>>(), 1, n_paths);
+        let (increments, volterra) = kernel.simulate(z1.subview(Axis(2), 0),
+            z2.subview(Axis(2), 0));
+
+        // the sample variances match the scheme, within sampling error
+        let last = volterra.subview(Axis(1), 19);
+        let variance = last.iter().map(|y| y * y).sum::<f64>() / n_paths as f64;
+        let expected = kernel.variances()[19];
+        assert!(approx_eq(variance, expected, 0.05 * expected),
+            "variance={} expected={}", variance, expected);
+        let first = increments.subview(Axis(1), 0);
+        let variance = first.iter().map(|w| w * w).sum::<f64>() / n_paths as f64;
+        assert!(approx_eq(variance, 0.05, 0.05 * 0.05), "variance={}", variance);
+    }
+
+    fn sample_european(strike: f64, put_or_call: PutOrCall) -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", sample_underlying(), sample_settlement(2),
+            expiry(), strike, put_or_call, OptionSettlement::Cash).unwrap())))
+    }
+
+    fn mc_price(parameters: RoughBergomiParameters, strike: f64,
+        put_or_call: PutOrCall) -> f64 {
+
+        let market_data = sample_market_data();
+        let mut map = HashMap::new();
+        map.insert("BP.L".to_string(), parameters);
+        let factory = RoughBergomiFactory::new(map, 1.0 / 52.0, 20000);
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+        let instruments = vec![(1.0, sample_european(strike, put_or_call))];
+        let pricer = MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    fn expiry() -> DateTime {
+        DateTime::new(Date::from_ymd(2017, 07, 03), TimeOfDay::Close)
+    }
+
+    fn black_price(terms: &EuropeanTerms, vol: f64, strike: f64,
+        put_or_call: PutOrCall) -> f64 {
+
+        let black76 = Black76::new().unwrap();
+        let sqrt_variance = vol * terms.vol_time.sqrt();
+        match put_or_call {
+            PutOrCall::Call => black76.call_price(terms.df, terms.forward, strike,
+                sqrt_variance),
+            PutOrCall::Put => black76.put_price(terms.df, terms.forward, strike,
+                sqrt_variance)
+        }
+    }
+
+    #[test]
+    fn without_vol_of_variance_is_black() {
+        let p = RoughBergomiParameters::new(0.09, 0.0, 0.1, -0.9).unwrap();
+        let mut map = HashMap::new();
+        map.insert("BP.L".to_string(), p);
+        let factory = round_trip(&RoughBergomiFactory::new(map, 1.0 / 52.0, 20000));
+
+        // The paths are seeded, so this is repeatable. With zero vol of
+        // variance the only error is noise, with standard errors up to
+        // about 0.11 at the call
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), expiry(),
+            &[(85.0, PutOrCall::Put), (100.0, PutOrCall::Call), (115.0, PutOrCall::Call)],
+            0.4, &|terms, strike, put_or_call|
+                black_price(terms, 0.3, strike, put_or_call));
+    }
+
+    #[test]
+    fn negative_correlation_gives_skew() {
+        // compared with the flat-vol price at the forward variance, low
+        // strike puts are dearer and high strike calls cheaper
+        let p = RoughBergomiParameters::new(0.09, 1.9, 0.1, -0.9).unwrap();
+        let terms = sample_european_terms(&sample_market_data(), expiry());
+        let put = mc_price(p, 80.0, PutOrCall::Put);
+        let flat_put = black_price(&terms, 0.3, 80.0, PutOrCall::Put);
+        assert!(put > flat_put + 0.4, "put={} flat={}", put, flat_put);
+        let call = mc_price(p, 120.0, PutOrCall::Call);
+        let flat_call = black_price(&terms, 0.3, 120.0, PutOrCall::Call);
+        assert!(call < flat_call - 0.4, "call={} flat={}", call, flat_call);
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = sample_european(100.0, PutOrCall::Call);
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            RoughBergomiParameters::new(0.09, 1.9, 0.1, -0.9).unwrap());
+        let mut model = RoughBergomi::new(&timeline, context,
+            &parameters, 1.0 / 52.0, 100).unwrap();
+
+        // the forward variance is xi0, not the vol surface
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use math::sabr::SabrParameters;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::vol_times;
+use models::calculate_substepping;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Cap on the instantaneous log-normal vol of a path. When beta is less
+/// than one, the vol grows without limit as the underlying approaches zero,
+/// where the exact process would be absorbed. The cap stops such paths
+/// overflowing, while still leaving them stuck near zero.
+const MAX_VOL: f64 = 10.0;
+
+/// The SabrFactory creates a SABR model, given the timeline of the product(s)
+/// to value and the market data. The SABR parameters for each underlying
+/// are held by the factory, keyed by the id of the underlying, as the
+/// market data only contains implied vols. The factory also holds the
+/// maximum time step and the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SabrFactory {
+    parameters: HashMap<String, SabrParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl SabrFactory {
+    pub fn new(parameters: HashMap<String, SabrParameters>, time_step: f64,
+        number_of_paths: usize) -> SabrFactory {
+
+        SabrFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SabrFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for SabrFactory {
+    fn type_id(&self) -> &'static str { "SabrFactory" }
+}
+
+impl MonteCarloModelFactory for SabrFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Sabr::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A SABR model evolves each underlying with its own stochastic vol. SABR
+/// describes a forward to a single expiry, so each underlying is evolved
+/// as the forward to the observation at the end of each period, scaled
+/// along the forward curve. For a single observation this is exactly SABR,
+/// and matches the Hagan implied vols of the parameters, subject to the
+/// accuracy of the expansion. The underlyings are correlated with each
+/// other via the correlations in the market data, and each with its own
+/// vol via rho. The vol processes are independent of each other.
+///
+/// As for Heston, the parameters are in the business-day vol time of the
+/// vol surface, whose implied vols are otherwise not used, so vol bumps
+/// have no effect on the price. Quanto underlyings and displaced vol
+/// surfaces are not supported.
+#[derive(Clone)]
+pub struct Sabr {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<SabrParameters>,
+    substepping: Vec<usize>,
+    spot_gaussians: Array3<f64>,
+    vol_gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl Sabr {
+
+    /// Creates a new SABR model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the SABR parameters by underlying id, the maximum step in vol time
+    /// and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, SabrParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<Sabr, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("SABR time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("SABR does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "SABR")?;
+
+        let substepping = calculate_substepping(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let vol_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &spot_gaussians,
+            &vol_gaussians, context.as_pricing_context(), &instruments,
+            &asset_parameters, &substepping)?;
+
+        Ok(Sabr {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            substepping: substepping,
+            spot_gaussians: spot_gaussians,
+            vol_gaussians: vol_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.spot_gaussians,
+            &self.vol_gaussians, self.context.as_pricing_context(),
+            &self.instruments, &self.parameters, &self.substepping)?;
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    spot_gaussians: &Array3<f64>,
+    vol_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[SabrParameters],
+    substepping: &[usize]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the underlyings with each other, but not their vols
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, spot_gaussians)?;
+
+    for ((((instrument, p), spot), vol), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(vol_gaussians.axis_iter(Axis(2)))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, context, observations, spot,
+            vol, substepping, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &SabrParameters,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, vol_gaussians: ArrayView2<f64>,
+    substepping: &[usize], mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("SABR does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for (time, substep) in times.iter().zip(substepping.iter()) {
+        steps.push((time - previous) / (*substep as f64));
+        previous = *time;
+    }
+
+    // Each step of the underlying is log-normal, with the vol at the start
+    // of the step, so the underlying relative to its forward is a
+    // martingale. The steps of the vol are exact.
+    let beta = parameters.beta();
+    let rho = parameters.rho();
+    let nu = parameters.nu();
+    let orthogonal = (1.0 - rho * rho).sqrt();
+    for ((z_x, z_v), mut one_path) in spot_gaussians.outer_iter()
+        .zip(vol_gaussians.outer_iter()).zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0_f64;
+        let mut alpha = parameters.alpha();
+        let mut g = 0;
+        for i in 0..observations.len() {
+            let dt = steps[i];
+            let root_dt = dt.sqrt();
+            for _ in 0..substepping[i] {
+                let level = forwards[i] * log_x.exp();
+                let vol = (alpha * level.powf(beta - 1.0)).min(MAX_VOL);
+                let z = rho * z_v[g] + orthogonal * z_x[g];
+                log_x += vol * root_dt * z - 0.5 * vol * vol * dt;
+                alpha *= (nu * root_dt * z_v[g] - 0.5 * nu * nu * dt).exp();
+                g += 1;
+            }
+            one_path[i] = forwards[i] * log_x.exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for Sabr {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// Alpha comes from the parameters, and the vol surfaces only give the
+    /// vol times, so bumps to vol levels leave the SABR paths unchanged.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for Sabr {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("SABR does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for Sabr {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for Sabr {
+
+    /// Bumps the market data, then regenerates all the paths with the same
+    /// random numbers, unless the bump leaves them unchanged. Any bump to
+    /// forwards, rates or correlations changes the paths.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::optionpricing::Black76;
+    use instruments::options::PutOrCall;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use risk::marketdata::tests::sample_market_data;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    #[test]
+    fn monte_carlo_matches_hagan() {
+        let sabr = SabrParameters::new(3.0, 0.5, -0.4, 0.4).unwrap();
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), sabr);
+        let factory = round_trip(&SabrFactory::new(parameters, 1.0 / 52.0, 20000));
+        let black76 = Black76::new().unwrap();
+
+        // The paths are seeded, so the price is repeatable. Allow about
+        // three standard errors, which are up to 0.14, as the Hagan
+        // expansion is itself only approximate.
+        let expiry = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), expiry,
+            &[(75.0, PutOrCall::Put), (100.0, PutOrCall::Call), (125.0, PutOrCall::Call)],
+            0.4, &|terms, strike, put_or_call| {
+                let t = terms.vol_time;
+                let vol = sabr.implied_vol(terms.forward, strike, t).unwrap();
+                let sqrt_var = vol * t.sqrt();
+                match put_or_call {
+                    PutOrCall::Call => black76.call_price(terms.df, terms.forward,
+                        strike, sqrt_var),
+                    PutOrCall::Put => black76.put_price(terms.df, terms.forward,
+                        strike, sqrt_var)
+                }
+            });
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            SabrParameters::new(3.0, 0.5, -0.4, 0.4).unwrap());
+        let mut model = Sabr::new(&timeline, context,
+            &parameters, 1.0 / 52.0, 100).unwrap();
+
+        // the vol surface only gives the vol times
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::heston::HestonParameters;
+use models::localvol::LocalVolGrid;
+use models::localvol::calculate_steps;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Number of bins across the spot dimension when estimating the expected
+/// variance conditional on spot. Each bin holds the same number of paths.
+const LEVERAGE_BINS: usize = 40;
+
+/// Smallest allowed conditional expectation of the variance, which stops
+/// the leverage blowing up where the variance of all paths in a bin has
+/// been absorbed at zero.
+const MIN_CONDITIONAL_VARIANCE: f64 = 1e-8;
+
+/// The leverage function L(t, x) of a stochastic local vol model, on the
+/// steps of a local vol grid. The model is a Heston model where the
+/// instantaneous variance is scaled by the square of the leverage:
+///
+///  dX/X = L(t, X) sqrt(v) dW1
+///
+/// By Gyongy's theorem, it reprices the Europeans of the implied vol
+/// surface if L(t, x)^2 E[v | X = x] is the Dupire local variance. The
+/// conditional expectation is not known in closed form, so the leverage is
+/// calibrated by the particle method of Guyon and Henry-Labordere (2012):
+/// all paths are stepped forward together, and at the start of each step
+/// the conditional expectation is estimated by sorting the paths into bins
+/// by spot and averaging the variance in each bin.
+///
+/// As for local vol, the spot dimension is the log of the (displaced)
+/// underlying relative to its forward.
+#[derive(Clone, Debug)]
+pub struct LeverageFunction {
+    centres: Vec<Vec<f64>>,
+    leverages: Vec<Vec<f64>>
+}
+
+impl LeverageFunction {
+
+    /// Calibrates the leverage function to the given local vol grid, by
+    /// stepping the given Heston dynamics forward for all paths at once.
+    /// The gaussians are indexed by path and step, and drive the part of
+    /// the underlying that is independent of the variance and the variance
+    /// respectively. Using the same gaussians in the simulation gives the
+    /// same paths as the calibration.
+    pub fn calibrate(grid: &LocalVolGrid, parameters: &HestonParameters,
+        spot_gaussians: ArrayView2<f64>, variance_gaussians: ArrayView2<f64>)
+        -> Result<LeverageFunction, qm::Error> {
+
+        let n_paths = spot_gaussians.shape()[0];
+        let n_steps = grid.times().len();
+        if n_paths == 0 {
+            return Err(qm::Error::new("Leverage calibration needs at least one path"))
+        }
+        if spot_gaussians.shape()[1] != n_steps
+            || variance_gaussians.shape() != spot_gaussians.shape() {
+            return Err(qm::Error::new("Leverage calibration gaussians do not \
+                match the local vol grid"))
+        }
+
+        let n_bins = LEVERAGE_BINS.min(n_paths);
+        let mut log_x = vec![0.0; n_paths];
+        let mut v = vec![parameters.v0(); n_paths];
+        let mut order: Vec<usize> = (0..n_paths).collect();
+        let mut centres = Vec::with_capacity(n_steps);
+        let mut leverages = Vec::with_capacity(n_steps);
+        let mut previous = 0.0;
+        for (g, time) in grid.times().iter().enumerate() {
+            let dt = time - previous;
+            previous = *time;
+
+            // Estimate E[v | x] in bins of equal numbers of paths. Bins
+            // whose centres do not increase, such as at the first step where
+            // all paths start at the forward, are merged with the bin before.
+            order.sort_by(|a, b| log_x[*a].partial_cmp(&log_x[*b])
+                .unwrap_or(::std::cmp::Ordering::Equal));
+            let mut bins: Vec<(f64, f64, usize)> = Vec::with_capacity(n_bins);
+            for bin in 0..n_bins {
+                let start = (bin * n_paths) / n_bins;
+                let end = ((bin + 1) * n_paths) / n_bins;
+                let mut sum_x = 0.0;
+                let mut sum_v = 0.0;
+                for &p in order[start..end].iter() {
+                    sum_x += log_x[p];
+                    sum_v += v[p].max(0.0);
+                }
+                let count = end - start;
+                let merge = match bins.last() {
+                    Some(&(x, _, n)) => sum_x / count as f64 <= x / n as f64,
+                    None => false
+                };
+                if merge {
+                    let last = bins.last_mut().unwrap();
+                    last.0 += sum_x;
+                    last.1 += sum_v;
+                    last.2 += count;
+                } else {
+                    bins.push((sum_x, sum_v, count));
+                }
+            }
+
+            let mut step_centres = Vec::with_capacity(bins.len());
+            let mut step_leverages = Vec::with_capacity(bins.len());
+            for &(sum_x, sum_v, count) in bins.iter() {
+                let centre = sum_x / count as f64;
+                let conditional = (sum_v / count as f64).max(MIN_CONDITIONAL_VARIANCE);
+                let leverage = (grid.local_variance(g, centre) / conditional).sqrt();
+                if !leverage.is_finite() {
+                    return Err(qm::Error::new("Leverage is not finite"))
+                }
+                step_centres.push(centre);
+                step_leverages.push(leverage);
+            }
+            centres.push(step_centres);
+            leverages.push(step_leverages);
+
+            // step all the paths forward with the new leverage
+            for p in 0..n_paths {
+                let leverage = interpolate(&centres[g], &leverages[g], log_x[p]);
+                let (next_x, next_v) = slv_step(parameters, leverage, log_x[p],
+                    v[p], dt, spot_gaussians[[p, g]], variance_gaussians[[p, g]]);
+                log_x[p] = next_x;
+                v[p] = next_v;
+            }
+        }
+
+        Ok(LeverageFunction { centres: centres, leverages: leverages })
+    }
+
+    /// The leverage in the step with the given index, where the log of the
+    /// underlying relative to its forward at the start of the step is as
+    /// given. Interpolation is linear between the bins, and flat beyond
+    /// them.
+    pub fn leverage(&self, step: usize, log_moneyness: f64) -> f64 {
+        interpolate(&self.centres[step], &self.leverages[step], log_moneyness)
+    }
+}
+
+/// Linear interpolation in increasing abscissae, flat outside them
+fn interpolate(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    let n = xs.len();
+    if n == 1 || x <= xs[0] {
+        return ys[0]
+    }
+    if x >= xs[n - 1] {
+        return ys[n - 1]
+    }
+
+    let mut lower = 0;
+    let mut upper = n - 1;
+    while upper - lower > 1 {
+        let middle = (lower + upper) / 2;
+        if xs[middle] <= x {
+            lower = middle;
+        } else {
+            upper = middle;
+        }
+    }
+    let fraction = (x - xs[lower]) / (xs[upper] - xs[lower]);
+    ys[lower] * (1.0 - fraction) + ys[upper] * fraction
+}
+
+/// One step of the SLV dynamics. The variance uses a full-truncation Euler
+/// step, and the underlying a log-Euler step with the leverage and variance
+/// at the start of the step, so it stays a martingale relative to its
+/// forward however large the step.
+fn slv_step(parameters: &HestonParameters, leverage: f64, log_x: f64, v: f64,
+    dt: f64, z_x: f64, z_v: f64) -> (f64, f64) {
+
+    if dt <= 0.0 {
+        return (log_x, v)
+    }
+
+    let rho = parameters.rho();
+    let v_plus = v.max(0.0);
+    let root = (v_plus * dt).sqrt();
+    let z = rho * z_v + (1.0 - rho * rho).sqrt() * z_x;
+    let scaled = leverage * leverage * v_plus * dt;
+    let log_x_next = log_x - 0.5 * scaled + leverage * root * z;
+    let v_next = v + parameters.kappa() * (parameters.theta() - v_plus) * dt
+        + parameters.xi() * root * z_v;
+    (log_x_next, v_next)
+}
+
+/// The SlvFactory creates a stochastic local vol model, given the timeline
+/// of the product(s) to value and the market data. The Heston parameters
+/// for each underlying are held by the factory, keyed by the id of the
+/// underlying, as for HestonFactory. The factory also holds the maximum time
+/// step and the number of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SlvFactory {
+    parameters: HashMap<String, HestonParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl SlvFactory {
+    pub fn new(parameters: HashMap<String, HestonParameters>, time_step: f64,
+        number_of_paths: usize) -> SlvFactory {
+
+        SlvFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SlvFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for SlvFactory {
+    fn type_id(&self) -> &'static str { "SlvFactory" }
+}
+
+impl MonteCarloModelFactory for SlvFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Slv::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A stochastic local vol model evolves each underlying with Heston
+/// stochastic variance, scaled by a leverage function calibrated so that
+/// the model reprices all the Europeans on the implied vol surface, subject
+/// to discretization, binning and Monte-Carlo error. The Heston parameters
+/// control the forward smile and the dynamics of the vol, which matter for
+/// exotics such as barriers and forward starts, while the leverage takes
+/// care of the vanillas.
+///
+/// The underlyings are correlated with each other via the correlations in
+/// the market data, and each with its own variance via rho. The variance
+/// processes are independent of each other. The drift, the time grid and
+/// the handling of fixed cash dividends are as for LocalVol, and the same
+/// vol surfaces are supported. Quanto underlyings are not supported.
+///
+/// The leverage functions are recalibrated on any bump, using the same
+/// random numbers, so vol bumps change the paths.
+#[derive(Clone)]
+pub struct Slv {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<HestonParameters>,
+    step_dates: Vec<DateDayFraction>,
+    substepping: Vec<usize>,
+    spot_gaussians: Array3<f64>,
+    variance_gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl Slv {
+
+    /// Creates a new stochastic local vol model, given a timeline to define
+    /// the instrument(s) we want to price, a context to define the market
+    /// data, the Heston parameters by underlying id, the maximum step in vol
+    /// time and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, HestonParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<Slv, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("SLV time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("SLV does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "Heston")?;
+
+        let (step_dates, substepping) = calculate_steps(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let variance_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &step_dates, &substepping,
+            &spot_gaussians, &variance_gaussians, context.as_pricing_context(),
+            &instruments, &asset_parameters)?;
+
+        Ok(Slv {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            step_dates: step_dates,
+            substepping: substepping,
+            spot_gaussians: spot_gaussians,
+            variance_gaussians: variance_gaussians,
+            paths: paths })
+    }
+
+    /// Recalibrates the leverage functions and refetches all paths for all
+    /// assets, using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.step_dates,
+            &self.substepping, &self.spot_gaussians, &self.variance_gaussians,
+            self.context.as_pricing_context(), &self.instruments,
+            &self.parameters)?;
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    step_dates: &[DateDayFraction],
+    substepping: &[usize],
+    spot_gaussians: &Array3<f64>,
+    variance_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[HestonParameters]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the underlyings with each other, but not their variances
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, spot_gaussians)?;
+
+    for ((((instrument, p), spot), variance), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(variance_gaussians.axis_iter(Axis(2)))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, context, observations, step_dates,
+            substepping, spot, variance, path)?;
+    }
+    Ok(paths)
+}
+
+/// Calibrates the leverage function and fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &HestonParameters,
+    context: &PricingContext, observations: &[DateDayFraction],
+    step_dates: &[DateDayFraction], substepping: &[usize],
+    spot_gaussians: ArrayView2<f64>, variance_gaussians: ArrayView2<f64>,
+    mut path: ArrayViewMut2<f64>) -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let grid = LocalVolGrid::new(&*vol_surface, &*forward_curve, step_dates)?;
+    let leverage = LeverageFunction::calibrate(&grid, parameters,
+        spot_gaussians, variance_gaussians)?;
+
+    // the forwards of the displaced underlying, and the displacements
+    let mut forwards = Vec::with_capacity(observations.len());
+    let mut displacements = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        let displacement = vol_surface.displacement(obs.date())?;
+        forwards.push(forward_curve.forward(obs.date())? - displacement);
+        displacements.push(displacement);
+    }
+
+    let mut steps = Vec::with_capacity(step_dates.len());
+    let mut previous = 0.0;
+    for time in grid.times().iter() {
+        steps.push(time - previous);
+        previous = *time;
+    }
+
+    // exactly the same steps as the calibration, so the paths reproduce
+    // the particles the leverage was calibrated to
+    for ((z_x, z_v), mut one_path) in spot_gaussians.outer_iter()
+        .zip(variance_gaussians.outer_iter()).zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0;
+        let mut v = parameters.v0();
+        let mut g = 0;
+        for i in 0..observations.len() {
+            for _ in 0..substepping[i] {
+                let (next_x, next_v) = slv_step(parameters,
+                    leverage.leverage(g, log_x), log_x, v, steps[g], z_x[g], z_v[g]);
+                log_x = next_x;
+                v = next_v;
+                g += 1;
+            }
+            one_path[i] = forwards[i] * log_x.exp() + displacements[i];
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for Slv {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The leverage functions are calibrated to the vol surfaces, so bumps
+    /// to the vols of the underlyings change the paths, as do bumps to
+    /// forwards, rates or correlations.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump_with_vols(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for Slv {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Slv does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for Slv {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for Slv {
+
+    /// Bumps the market data, then recalibrates the leverage functions and
+    /// regenerates all the paths with the same random numbers, if the bump
+    /// changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::blackdiffusion::fetch_gaussians;
+    use math::numerics::approx_eq;
+    use data::forward::DriftlessForward;
+    use data::volsurface::FlatVolSurface;
+    use data::volsurface::DivAssumptions;
+    use dates::Date;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::round_trip;
+    use models::localvol::tests::assert_reprices_europeans;
+    use models::localvol::tests::skewed_market_data;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_market_data;
+
+    #[test]
+    fn flat_vol_with_constant_variance_gives_unit_leverage() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base_date = Date::from_ymd(2017, 01, 02);
+        let surface = FlatVolSurface::new(0.3, calendar,
+            DateDayFraction::new(base_date, 0.0));
+        let forward = DriftlessForward::new(100.0);
+        let dates: Vec<DateDayFraction> = (1..13).map(|month|
+            DateDayFraction::new(base_date + 30 * month, 0.7)).collect();
+        let grid = LocalVolGrid::new(&surface, &forward, &dates).unwrap();
+
+        // the variance barely moves from the square of the implied vol
+        let parameters = HestonParameters::new(0.09, 1.0, 0.09, 1e-4, -0.5).unwrap();
+        let substepping = vec![dates.len()];
+        let spot = fetch_gaussians(&substepping, 1, 1000);
+        let variance = fetch_gaussians(&substepping, 1, 1000);
+        let leverage = LeverageFunction::calibrate(&grid, &parameters,
+            spot.subview(Axis(2), 0), variance.subview(Axis(2), 0)).unwrap();
+
+        for step in 0..dates.len() {
+            for &y in [-1.0, -0.2, 0.0, 0.1, 1.0].iter() {
+                let l = leverage.leverage(step, y);
+                assert!(approx_eq(l, 1.0, 1e-3), "step={} y={} leverage={}", step, y, l);
+            }
+        }
+    }
+
+    fn slv_factory() -> RcMonteCarloModelFactory {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap());
+        let factory = round_trip(&SlvFactory::new(parameters, 1.0 / 52.0, 20000));
+        RcMonteCarloModelFactory::new(Arc::new(factory))
+    }
+
+    #[test]
+    fn slv_reprices_europeans() {
+        assert_reprices_europeans(slv_factory(),
+            &skewed_market_data(DivAssumptions::NoCashDivs));
+    }
+
+    #[test]
+    fn slv_reprices_europeans_with_fixed_dividends() {
+        assert_reprices_europeans(slv_factory(),
+            &skewed_market_data(DivAssumptions::FixedDivs));
+    }
+
+    #[test]
+    fn bump_to_other_vol_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap());
+        let mut model = Slv::new(&timeline, context,
+            &parameters, 1.0 / 52.0, 100).unwrap();
+
+        // only BP.L is calibrated, so its paths do not see the vols of GSK.L
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
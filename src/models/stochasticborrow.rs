@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings_with;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use models::hullwhite::year_fraction;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The parameters of the stochastic part of the borrow cost of one
+/// underlying. The borrow cost is the one implied by the forward curve,
+/// plus a spread y that starts at zero and mean-reverts to it:
+///
+///  dy = -mean_reversion y dt + vol dW2
+///  dW1 dW2 = correlation dt
+///
+/// where W1 drives the spot. Hard-to-borrow names typically have a borrow
+/// cost that rises when the spot falls, which is a negative correlation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BorrowParameters {
+    mean_reversion: f64,
+    vol: f64,
+    correlation: f64
+}
+
+impl BorrowParameters {
+    /// Creates the parameters. The mean reversion must be positive, the
+    /// vol must not be negative, and the correlation must be between minus
+    /// one and one.
+    pub fn new(mean_reversion: f64, vol: f64, correlation: f64)
+        -> Result<BorrowParameters, qm::Error> {
+
+        if mean_reversion <= 0.0 {
+            return Err(qm::Error::new("Borrow mean reversion must be positive"))
+        }
+        if vol < 0.0 {
+            return Err(qm::Error::new("Borrow vol must not be negative"))
+        }
+        if correlation < -1.0 || correlation > 1.0 {
+            return Err(qm::Error::new(
+                "Borrow correlation must be between minus one and one"))
+        }
+
+        Ok(BorrowParameters { mean_reversion: mean_reversion, vol: vol,
+            correlation: correlation })
+    }
+
+    pub fn mean_reversion(&self) -> f64 { self.mean_reversion }
+    pub fn vol(&self) -> f64 { self.vol }
+    pub fn correlation(&self) -> f64 { self.correlation }
+}
+
+/// The StochasticBorrowFactory creates a model where the borrow cost of
+/// each underlying is uncertain, given the timeline of the product(s) to
+/// value and the market data. The factory holds the borrow parameters
+/// keyed by the id of the underlying, the maximum time step in years and
+/// the number of paths. Underlyings without parameters have the
+/// deterministic borrow cost of their forward curves.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StochasticBorrowFactory {
+    parameters: HashMap<String, BorrowParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl StochasticBorrowFactory {
+    pub fn new(parameters: HashMap<String, BorrowParameters>, time_step: f64,
+        number_of_paths: usize) -> StochasticBorrowFactory {
+
+        StochasticBorrowFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(StochasticBorrowFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for StochasticBorrowFactory {
+    fn type_id(&self) -> &'static str { "StochasticBorrowFactory" }
+}
+
+impl MonteCarloModelFactory for StochasticBorrowFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = StochasticBorrow::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A model where the drift of each underlying is perturbed by a stochastic
+/// borrow spread. Without the spread, the underlying is log-normal about
+/// its forward, with the at the money variance from the vol surface, flat
+/// between observations. The spread is an Ornstein-Uhlenbeck process,
+/// simulated exactly, and its integral is accumulated with the trapezium
+/// rule. Each underlying is scaled by the exponential of minus the
+/// integral, with a convexity correction that makes the scaling driftless
+/// when the spread is independent of the spot.
+///
+/// Uncorrelated borrow uncertainty therefore leaves the forwards unchanged
+/// but adds to the variance of long-dated underlyings. Correlation between
+/// the spread and the spot moves the forwards too: a borrow cost that rises
+/// as the spot falls raises them.
+///
+/// The underlyings are correlated with each other via the correlations in
+/// the market data. The spreads of different underlyings are independent.
+/// Quanto underlyings and displaced vol surfaces are not supported.
+#[derive(Clone)]
+pub struct StochasticBorrow {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<Option<BorrowParameters>>,
+    substepping: Vec<usize>,
+    spot_gaussians: Array3<f64>,
+    borrow_gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl StochasticBorrow {
+
+    /// Creates a new stochastic borrow model, given a timeline to define
+    /// the instrument(s) we want to price, a context to define the market
+    /// data, the borrow parameters by underlying id, the maximum time step
+    /// in years and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, BorrowParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<StochasticBorrow, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("Stochastic borrow time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new(
+                "Stochastic borrow model does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings_with(timeline, |id| Ok(parameters.get(id).cloned()))?;
+        if observations.is_empty() {
+            return Err(qm::Error::new("No observations"))
+        }
+
+        // the spreads evolve in calendar time, so the steps are too
+        let spot_date = context.as_pricing_context().spot_date();
+        let mut substepping = Vec::with_capacity(observations.len());
+        let mut previous = 0.0;
+        for obs in observations.iter() {
+            let t = year_fraction(spot_date, obs.date());
+            substepping.push((((t - previous) / time_step).ceil() as usize).max(1));
+            previous = t;
+        }
+
+        // as for StochasticDividends, the gaussians are kept uncorrelated,
+        // so that paths can be refetched with the same random numbers after
+        // any bump. The borrow gaussians are the parts of the borrow
+        // brownian motions that are independent of the spots.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let borrow_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &spot_gaussians,
+            &borrow_gaussians, context.as_pricing_context(), &instruments,
+            &asset_parameters, &substepping)?;
+
+        Ok(StochasticBorrow {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            substepping: substepping,
+            spot_gaussians: spot_gaussians,
+            borrow_gaussians: borrow_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.spot_gaussians,
+            &self.borrow_gaussians, self.context.as_pricing_context(),
+            &self.instruments, &self.parameters, &self.substepping)?;
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    spot_gaussians: &Array3<f64>,
+    borrow_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[Option<BorrowParameters>],
+    substepping: &[usize]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the underlyings with each other, but not their spreads
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, spot_gaussians)?;
+
+    for (((instrument, p), (spot, borrow)), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated.axis_iter(Axis(2)).zip(borrow_gaussians.axis_iter(Axis(2))))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, context, observations, spot,
+            borrow, substepping, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &Option<BorrowParameters>,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, borrow_gaussians: ArrayView2<f64>,
+    substepping: &[usize], mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let spot_date = context.spot_date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+
+    // the forwards, and the standard deviation and length in years of the
+    // substeps to each observation
+    let mut forwards = Vec::with_capacity(observations.len());
+    let mut std_devs = Vec::with_capacity(observations.len());
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous_variance = 0.0;
+    let mut previous_time = 0.0;
+    for (obs, substeps) in observations.iter().zip(substepping.iter()) {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("Stochastic borrow model does not \
+                support displaced vol surfaces"))
+        }
+        let forward = forward_curve.forward(obs.date())?;
+        let variance = vol_surface.variance(*obs, forward)?;
+        if variance < previous_variance {
+            return Err(qm::Error::new("Negative forward variance"))
+        }
+        let time = year_fraction(spot_date, obs.date());
+        let n = *substeps as f64;
+        forwards.push(forward);
+        std_devs.push(((variance - previous_variance) / n).sqrt());
+        steps.push((time - previous_time) / n);
+        previous_variance = variance;
+        previous_time = time;
+    }
+
+    // without a spread, this is Black with a flat vol between observations
+    let p = match *parameters {
+        Some(p) => p,
+        None => BorrowParameters::new(1.0, 0.0, 0.0)?
+    };
+    let a = p.mean_reversion;
+    let rho = p.correlation;
+    let rho_perp = (1.0 - rho * rho).sqrt();
+
+    // The exact decay and standard deviation of the spread over each
+    // substep, and the variance of the integrated spread at each
+    // observation. The variance follows the same recursion as the
+    // simulation, so the convexity correction is exact for the scheme.
+    let mut decays = Vec::with_capacity(observations.len());
+    let mut spread_std_devs = Vec::with_capacity(observations.len());
+    let mut integral_variances = Vec::with_capacity(observations.len());
+    let mut var_y = 0.0;
+    let mut cov = 0.0;
+    let mut var_integral = 0.0;
+    for (dt, substeps) in steps.iter().zip(substepping.iter()) {
+        let decay = (-a * dt).exp();
+        let s2 = p.vol * p.vol * (1.0 - decay * decay) / (2.0 * a);
+        let h = 0.5 * dt;
+        for _ in 0..*substeps {
+            var_integral += h * h * (1.0 + decay).powi(2) * var_y + h * h * s2
+                + 2.0 * h * (1.0 + decay) * cov;
+            cov = decay * cov + h * (1.0 + decay) * decay * var_y + h * s2;
+            var_y = decay * decay * var_y + s2;
+        }
+        decays.push(decay);
+        spread_std_devs.push(s2.sqrt());
+        integral_variances.push(var_integral);
+    }
+
+    for ((z_s, z_b), mut one_path) in spot_gaussians.outer_iter()
+        .zip(borrow_gaussians.outer_iter()).zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0;
+        let mut y = 0.0;
+        let mut integral = 0.0;
+        let mut g = 0;
+        for i in 0..observations.len() {
+            let std_dev = std_devs[i];
+            for _ in 0..substepping[i] {
+                let spot_draw = z_s[g];
+                log_x += std_dev * spot_draw - 0.5 * std_dev * std_dev;
+                let y_next = decays[i] * y + spread_std_devs[i]
+                    * (rho * spot_draw + rho_perp * z_b[g]);
+                integral += 0.5 * (y + y_next) * steps[i];
+                y = y_next;
+                g += 1;
+            }
+            one_path[i] = forwards[i]
+                * (log_x - integral - 0.5 * integral_variances[i]).exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for StochasticBorrow {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The spots diffuse with the variances of their vol surfaces, so bumps
+    /// to the vols of the underlyings change the paths, as do bumps to
+    /// forwards, rates or correlations.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump_with_vols(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for StochasticBorrow {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Stochastic borrow model does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for StochasticBorrow {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for StochasticBorrow {
+
+    /// Bumps the market data, then regenerates all the paths with the same
+    /// random numbers, if the bump changes them. The time grid is unchanged
+    /// by bumps.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::DependencyContext;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::cache::PricingContextPrefetch;
+    use risk::Pricer;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::round_trip;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use dates::Date;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+
+    fn sample_factory(parameters: BorrowParameters) -> StochasticBorrowFactory {
+        let mut map = HashMap::new();
+        map.insert("BP.L".to_string(), parameters);
+        StochasticBorrowFactory::new(map, 1.0 / 52.0, 20000)
+    }
+
+    fn sample_european(equity: RcInstrument) -> RcInstrument {
+        let expiry = sample_expiry();
+        RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", equity, sample_settlement(2), expiry,
+            100.0, PutOrCall::Call, OptionSettlement::Cash).unwrap())))
+    }
+
+    fn sample_model(parameters: BorrowParameters, european: &RcInstrument)
+        -> Box<MonteCarloModel> {
+
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        european.as_mc_priceable().unwrap().mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(european);
+        let context = PricingContextPrefetch::new(&market_data,
+            Arc::new(dependencies)).unwrap();
+        sample_factory(parameters).factory(&timeline, Box::new(context)).unwrap()
+    }
+
+    #[test]
+    fn stochastic_borrow_without_vol_is_black() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let european = sample_european(equity);
+        let val_date = sample_val_date();
+        let expected = european.as_priceable().unwrap().price(&market_data, val_date)
+            .unwrap();
+
+        let parameters = BorrowParameters::new(0.5, 0.0, -0.5).unwrap();
+        let factory = round_trip(&sample_factory(parameters));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+
+        let pricer = MonteCarloPricer::with_settings(vec![(1.0, european)], model_factory,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let price = pricer.price().unwrap();
+
+        // The paths are seeded. The standard error is about 0.19, and without
+        // borrow vol there is no discretisation bias, so allow about three
+        // standard errors.
+        assert!(approx_eq(price, expected, 0.6),
+            "price={} expected={}", price, expected);
+    }
+
+    #[test]
+    fn uncorrelated_borrow_adds_variance_about_forward() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let european = sample_european(equity.clone());
+        let (a, vol) = (0.5, 0.2);
+        let model = sample_model(BorrowParameters::new(a, vol, 0.0).unwrap(),
+            &european);
+
+        // the underlying is centred on its forward
+        let expiry = sample_expiry();
+        let forward_curve = market_data.forward_curve(&*equity, expiry.date()).unwrap();
+        let forward = forward_curve.forward(expiry.date()).unwrap();
+        let paths = model.paths(&equity).unwrap();
+        let n_paths = paths.shape()[0] as f64;
+        let mean = paths.scalar_sum() / n_paths;
+        assert!(approx_eq(mean, forward, 1.5), "mean={} forward={}", mean, forward);
+
+        // the log variance is that of the spot plus that of the integrated
+        // spread, which is the same as for the Hull-White short rate
+        let vol_surface = market_data.vol_surface(&*equity, expiry.date(),
+            &|| Ok(forward_curve.clone())).unwrap();
+        let obs = equity.time_to_day_fraction(expiry).unwrap();
+        let spot_variance = vol_surface.variance(obs, forward).unwrap();
+        let t = year_fraction(Date::from_ymd(2017, 01, 02), expiry.date());
+        let decay = (-a * t).exp();
+        let spread_variance = vol * vol / (a * a) * (t - 2.0 * (1.0 - decay) / a
+            + (1.0 - decay * decay) / (2.0 * a));
+        let logs = paths.mapv(|s| s.ln());
+        let log_mean = logs.scalar_sum() / n_paths;
+        let variance = logs.mapv(|x| (x - log_mean).powi(2)).scalar_sum() / n_paths;
+        assert!(approx_eq(variance, spot_variance + spread_variance, 0.008),
+            "variance={} spot={} spread={}", variance, spot_variance, spread_variance);
+        assert!(spread_variance > 0.02, "spread={}", spread_variance);
+    }
+
+    #[test]
+    fn borrow_rising_as_spot_falls_raises_forward() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let european = sample_european(equity.clone());
+        let model = sample_model(BorrowParameters::new(0.5, 0.2, -0.7).unwrap(),
+            &european);
+
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let forward = market_data.forward_curve(&*equity, expiry).unwrap()
+            .forward(expiry).unwrap();
+        let paths = model.paths(&equity).unwrap();
+        let mean = paths.scalar_sum() / (paths.shape()[0] as f64);
+        assert!(mean > forward + 1.5, "mean={} forward={}", mean, forward);
+    }
+
+    #[test]
+    fn bump_to_other_vol_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = sample_european(sample_underlying());
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            BorrowParameters::new(0.5, 0.2, -0.7).unwrap());
+        let mut model = StochasticBorrow::new(&timeline, context,
+            &parameters, 1.0 / 52.0, 100).unwrap();
+
+        // the paths of BP.L do not see the vols of GSK.L
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
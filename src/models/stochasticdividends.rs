@@ -0,0 +1,613 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::Axis;
+use core::qm;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings_with;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use models::hullwhite::year_fraction;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The volatility of the discrete dividends of an underlying, and the
+/// correlation of the dividends with its spot.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct DividendVolParameters {
+    vol: f64,
+    correlation: f64
+}
+
+impl DividendVolParameters {
+    /// Creates the parameters. The vol must not be negative, and the
+    /// correlation must be between minus one and one.
+    pub fn new(vol: f64, correlation: f64)
+        -> Result<DividendVolParameters, qm::Error> {
+        if vol < 0.0 {
+            return Err(qm::Error::new("Dividend vol must not be negative"))
+        }
+        if correlation < -1.0 || correlation > 1.0 {
+            return Err(qm::Error::new(
+                "Dividend correlation must be between minus one and one"))
+        }
+        Ok(DividendVolParameters { vol: vol, correlation: correlation })
+    }
+
+    pub fn vol(&self) -> f64 { self.vol }
+    pub fn correlation(&self) -> f64 { self.correlation }
+}
+
+/// The StochasticDividendFactory creates a model where the discrete
+/// dividends of each underlying are uncertain, given the timeline of the
+/// product(s) to value and the market data. The factory holds the dividend
+/// vol parameters keyed by the id of the underlying, and the number of
+/// paths. Underlyings without parameters have deterministic dividends.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StochasticDividendFactory {
+    parameters: HashMap<String, DividendVolParameters>,
+    number_of_paths: usize
+}
+
+impl StochasticDividendFactory {
+    pub fn new(parameters: HashMap<String, DividendVolParameters>,
+        number_of_paths: usize) -> StochasticDividendFactory {
+
+        StochasticDividendFactory { parameters: parameters,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(StochasticDividendFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for StochasticDividendFactory {
+    fn type_id(&self) -> &'static str { "StochasticDividendFactory" }
+}
+
+impl MonteCarloModelFactory for StochasticDividendFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = StochasticDividends::new(timeline, context,
+            &self.parameters, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A model where each discrete dividend is the amount expected by the
+/// forward curve, scaled by a log-normal factor. The factor is driftless,
+/// with a flat vol in calendar time, and its brownian motion is correlated
+/// with that of the spot, so that dividends tend to be cut when the spot
+/// falls. As the dividends on a path are simulated, they are available to
+/// dividend swaps and similar instruments via MonteCarloContext::dividends.
+///
+/// Without the dividend uncertainty, the spot is log-normal about its
+/// forward, with the at the money variance from the vol surface. Each
+/// dividend that is higher or lower than expected then lowers or raises
+/// the spot on and after its ex date by the difference, grown at the rate
+/// of the underlying's yield curve. The uncertainty in the dividends
+/// therefore adds to the variance of long-dated forwards, but leaves the
+/// forwards themselves unchanged. Very large dividend vols can make the
+/// spot negative on some paths.
+///
+/// The underlyings are correlated with each other via the correlations in
+/// the market data. The dividend factors of different underlyings are
+/// independent. Quanto underlyings and displaced vol surfaces are not
+/// supported.
+#[derive(Clone)]
+pub struct StochasticDividends {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<DividendVolParameters>,
+    timeline: DividendTimeline,
+    spot_gaussians: Array3<f64>,
+    dividend_gaussians: Array3<f64>,
+    paths: Array3<f64>,
+    dividends: Vec<Array2<f64>>
+}
+
+impl StochasticDividends {
+
+    /// Creates a new stochastic dividend model, given a timeline to define
+    /// the instrument(s) we want to price, a context to define the market
+    /// data, the dividend vol parameters by underlying id and the number
+    /// of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, DividendVolParameters>,
+        n_paths: usize)
+        -> Result<StochasticDividends, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new(
+                "Stochastic dividend model does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings_with(timeline, |id| match parameters.get(id) {
+                Some(p) => Ok(*p),
+                None => DividendVolParameters::new(0.0, 0.0)
+            })?;
+
+        let dividend_timeline = DividendTimeline::new(context.as_pricing_context(),
+            &instruments, &observations)?;
+
+        // The gaussians are kept uncorrelated, so that paths can be
+        // refetched with the same random numbers after any bump, including
+        // a correlation bump. The dividend gaussians are the parts of the
+        // dividend brownian motions that are independent of the spots.
+        let steps = vec![1; dividend_timeline.grid.len()];
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&steps, instruments.len(), n_paths)?;
+        let dividend_gaussians = source.fetch(&steps, instruments.len(), n_paths)?;
+
+        let mut model = StochasticDividends {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            timeline: dividend_timeline,
+            spot_gaussians: spot_gaussians,
+            dividend_gaussians: dividend_gaussians,
+            paths: Array3::zeros((0, 0, 0)),
+            dividends: Vec::new() };
+        model.refetch_all()?;
+        Ok(model)
+    }
+
+    /// Refetch all paths and dividends for all assets, using the same
+    /// random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        let (paths, dividends) = fetch_paths(self.context.as_pricing_context(),
+            &self.instruments, &self.parameters, &self.observations,
+            &self.timeline, &self.spot_gaussians, &self.dividend_gaussians)?;
+        self.paths = paths;
+        self.dividends = dividends;
+        Ok(())
+    }
+
+    fn asset(&self, instrument: &RcInstrument) -> Result<usize, qm::Error> {
+        let id = instrument.id().to_string();
+        self.key.get(&id).cloned().ok_or_else(|| qm::Error::new(
+            &format!("Stochastic dividend model does not know about '{}'", id)))
+    }
+}
+
+/// The points in time where the model is evolved. These are the
+/// observations, plus the open on the ex date of each dividend up to the
+/// last observation, for all the underlyings.
+#[derive(Clone)]
+struct DividendTimeline {
+    grid: Vec<DateDayFraction>,
+    observation_indices: Vec<usize>,
+    ex_dates: Vec<Vec<Date>>,
+    ex_indices: Vec<Vec<usize>>
+}
+
+impl DividendTimeline {
+    fn new(context: &PricingContext, instruments: &[RcInstrument],
+        observations: &[DateDayFraction]) -> Result<DividendTimeline, qm::Error> {
+
+        // find the ex dates by looking for the days when the forward curve
+        // expects a dividend
+        let spot_date = context.spot_date();
+        let hwm = observations.last().unwrap().date();
+        let mut grid = observations.to_vec();
+        let mut ex_dates = Vec::with_capacity(instruments.len());
+        let mut ex_points = Vec::with_capacity(instruments.len());
+        for instrument in instruments.iter() {
+            let forward_curve = context.forward_curve(instrument.deref(), hwm)?;
+            let mut dates = Vec::new();
+            let mut points = Vec::new();
+            let mut date = spot_date + 1;
+            while date <= hwm {
+                if forward_curve.undiscounted_divs(date - 1, date)? != 0.0 {
+                    let point = instrument.time_to_day_fraction(
+                        DateTime::new(date, TimeOfDay::Open))?;
+                    dates.push(date);
+                    points.push(point);
+                    grid.push(point);
+                }
+                date += 1;
+            }
+            ex_dates.push(dates);
+            ex_points.push(points);
+        }
+        grid.sort();
+        grid.dedup();
+
+        let (observation_indices, ex_indices) = {
+            let index = |point: &DateDayFraction|
+                grid.iter().position(|g| g == point).unwrap();
+            (observations.iter().map(&index).collect(),
+                ex_points.iter().map(|points| points.iter().map(&index).collect())
+                    .collect())
+        };
+
+        Ok(DividendTimeline {
+            grid: grid,
+            observation_indices: observation_indices,
+            ex_dates: ex_dates,
+            ex_indices: ex_indices })
+    }
+}
+
+fn fetch_paths(
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[DividendVolParameters],
+    observations: &[DateDayFraction],
+    timeline: &DividendTimeline,
+    spot_gaussians: &Array3<f64>,
+    dividend_gaussians: &Array3<f64>)
+    -> Result<(Array3<f64>, Vec<Array2<f64>>), qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_steps = timeline.grid.len();
+    let spot_date = context.spot_date();
+    let hwm = observations.last().unwrap().date();
+    let times: Vec<f64> = timeline.grid.iter()
+        .map(|g| year_fraction(spot_date, g.date())).collect();
+
+    let correlated = correlate_gaussians(context, instruments,
+        &timeline.grid, &vec![1; n_steps], spot_gaussians)?;
+
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(),
+        instruments.len()));
+    let mut dividends = Vec::with_capacity(instruments.len());
+    for (i, instrument) in instruments.iter().enumerate() {
+        let forward_curve = context.forward_curve(instrument.deref(), hwm)?;
+        let vol_surface = context.vol_surface(instrument.deref(), hwm,
+            &|| Ok(forward_curve.clone()))?;
+        let yield_curve = context.yield_curve(instrument.credit_id(), hwm)?;
+
+        // the standard deviations of the spot over each step, from the at
+        // the money variances
+        let mut std_devs = Vec::with_capacity(n_steps);
+        let mut previous = 0.0;
+        for point in timeline.grid.iter() {
+            if vol_surface.displacement(point.date())? != 0.0 {
+                return Err(qm::Error::new("Stochastic dividend model does \
+                    not support displaced vol surfaces"))
+            }
+            let forward = forward_curve.forward(point.date())?;
+            let variance = vol_surface.variance(*point, forward)?;
+            if variance < previous {
+                return Err(qm::Error::new("Negative forward variance"))
+            }
+            std_devs.push((variance - previous).sqrt());
+            previous = variance;
+        }
+
+        let mut forwards = Vec::with_capacity(observations.len());
+        for obs in observations.iter() {
+            forwards.push(forward_curve.forward(obs.date())?);
+        }
+
+        // the expected dividends, and the growth of any surprise in each
+        // dividend from its ex date to each observation on or after it
+        let ex_dates = &timeline.ex_dates[i];
+        let mut expected = Vec::with_capacity(ex_dates.len());
+        let mut growth = Array2::<f64>::zeros((ex_dates.len(), observations.len()));
+        for (j, ex_date) in ex_dates.iter().enumerate() {
+            expected.push(forward_curve.undiscounted_divs(*ex_date - 1, *ex_date)?);
+            for (k, obs) in observations.iter().enumerate() {
+                if obs.date() >= *ex_date {
+                    growth[(j, k)] = yield_curve.df(*ex_date, obs.date())?;
+                }
+            }
+        }
+
+        let vol = parameters[i].vol();
+        let rho = parameters[i].correlation();
+        let rho_perp = (1.0 - rho * rho).sqrt();
+        let mut asset_dividends = Array2::<f64>::zeros((n_paths, ex_dates.len()));
+        let mut log_spots = vec![0.0; n_steps];
+        let mut dividend_factors = vec![0.0; n_steps];
+        for (((z, z_perp), mut path), mut divs) in correlated.outer_iter()
+            .zip(dividend_gaussians.outer_iter())
+            .zip(paths.subview_mut(Axis(2), i).outer_iter_mut())
+            .zip(asset_dividends.outer_iter_mut()) {
+
+            // walk the spot and the brownian motion of the dividends together
+            let mut log_spot = 0.0;
+            let mut w = 0.0;
+            let mut previous_time = 0.0;
+            for g in 0..n_steps {
+                let std_dev = std_devs[g];
+                let spot_draw = z[[g, i]];
+                log_spot += std_dev * spot_draw - 0.5 * std_dev * std_dev;
+                w += (times[g] - previous_time).sqrt()
+                    * (rho * spot_draw + rho_perp * z_perp[[g, i]]);
+                previous_time = times[g];
+                log_spots[g] = log_spot;
+                dividend_factors[g] = (vol * w - 0.5 * vol * vol * times[g]).exp();
+            }
+
+            for (j, dividend) in divs.iter_mut().enumerate() {
+                *dividend = expected[j]
+                    * dividend_factors[timeline.ex_indices[i][j]];
+            }
+
+            for (k, value) in path.iter_mut().enumerate() {
+                let mut spot = forwards[k]
+                    * log_spots[timeline.observation_indices[k]].exp();
+                for (j, dividend) in divs.iter().enumerate() {
+                    spot -= (dividend - expected[j]) * growth[(j, k)];
+                }
+                *value = spot;
+            }
+        }
+        dividends.push(asset_dividends);
+    }
+
+    Ok((paths, dividends))
+}
+
+impl MonteCarloModel for StochasticDividends {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The spots diffuse with the variances of their vol surfaces, and the
+    /// dividends are scaled to the forwards, so bumps to either change the
+    /// paths, as do bumps to rates or correlations.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump_with_vols(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for StochasticDividends {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+        let asset = self.asset(instrument)?;
+        Ok(self.paths.subview(Axis(2), asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+
+    fn dividends(&self, instrument: &RcInstrument)
+        -> Result<Option<(&[Date], ArrayView2<f64>)>, qm::Error> {
+        let asset = self.asset(instrument)?;
+        Ok(Some((&self.timeline.ex_dates[asset], self.dividends[asset].view())))
+    }
+}
+
+impl RegeneratedPaths for StochasticDividends {
+    type Paths = (Array3<f64>, Vec<Array2<f64>>);
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> (Array3<f64>, Vec<Array2<f64>>) {
+        (self.paths.clone(), self.dividends.clone())
+    }
+
+    fn set_paths(&mut self, paths: &(Array3<f64>, Vec<Array2<f64>>)) {
+        let &(ref spots, ref dividends) = paths;
+        self.paths.assign(spots);
+        self.dividends = dividends.clone();
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for StochasticDividends {
+
+    /// Bumps the market data, then regenerates all the paths and dividends
+    /// with the same random numbers, if the bump changes them. The ex dates
+    /// are unchanged by bumps.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::dividends::tests::sample_dividend_swap;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_val_date;
+    use risk::cache::PricingContextPrefetch;
+    use instruments::DependencyContext;
+    use risk::Pricer;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::round_trip;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    fn sample_factory(vol: f64, correlation: f64) -> StochasticDividendFactory {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            DividendVolParameters::new(vol, correlation).unwrap());
+        StochasticDividendFactory::new(parameters, 20000)
+    }
+
+    #[test]
+    fn dividends_are_lognormal_about_forward() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", equity.clone(), sample_settlement(2), expiry,
+            100.0, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        european.as_mc_priceable().unwrap().mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&european);
+        let context = PricingContextPrefetch::new(&market_data,
+            Arc::new(dependencies)).unwrap();
+        let vol = 0.4;
+        let model = sample_factory(vol, 0.5).factory(&timeline, Box::new(context))
+            .unwrap();
+
+        // the three dividends up to expiry are log-normal about the amounts
+        // expected by the forward curve, with the dividend vol
+        let forward_curve = market_data.forward_curve(&*equity, expiry.date()).unwrap();
+        let (ex_dates, dividends) = model.dividends(&equity).unwrap().unwrap();
+        assert_eq!(ex_dates, &[Date::from_ymd(2017, 01, 30),
+            Date::from_ymd(2017, 07, 31), Date::from_ymd(2018, 01, 29)]);
+        let n_paths = dividends.shape()[0] as f64;
+        for (ex_date, amounts) in ex_dates.iter().zip(dividends.axis_iter(Axis(1))) {
+            let expected = forward_curve.undiscounted_divs(*ex_date - 1, *ex_date)
+                .unwrap();
+            let mean = amounts.scalar_sum() / n_paths;
+            assert!(approx_eq(mean, expected, 0.02 * expected),
+                "ex_date={} mean={} expected={}", ex_date, mean, expected);
+
+            let logs = amounts.mapv(|d| (d / expected).ln());
+            let log_mean = logs.scalar_sum() / n_paths;
+            let log_variance = logs.mapv(|x| (x - log_mean).powi(2)).scalar_sum()
+                / n_paths;
+            let std_dev = vol * year_fraction(spot_date, *ex_date).sqrt();
+            assert!(approx_eq(log_variance.sqrt(), std_dev, 0.03 * std_dev + 0.002),
+                "ex_date={} std_dev={} expected={}", ex_date,
+                log_variance.sqrt(), std_dev);
+        }
+
+        // the surprises in the dividends leave the forward unchanged
+        let paths = model.paths(&equity).unwrap();
+        let mean = paths.scalar_sum() / n_paths;
+        let forward = forward_curve.forward(expiry.date()).unwrap();
+        assert!(approx_eq(mean, forward, 1.0), "mean={} forward={}", mean, forward);
+    }
+
+    #[test]
+    fn dividend_swap_and_european_prices() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let val_date = sample_val_date();
+        let factory = round_trip(&sample_factory(0.5, 0.8));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+
+        // the dividend swap is worth the same as with deterministic
+        // dividends, as the expected dividends are unchanged. The swap has
+        // a notional of 1000, so the noise is much larger than for options:
+        // the seeded paths give a standard error of about 3.
+        let swap = sample_dividend_swap(2.0);
+        let expected = swap.price(&market_data, val_date).unwrap();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(swap))))];
+        let pricer = MonteCarloPricer::with_settings(instruments, model_factory.clone(),
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let price = pricer.price().unwrap();
+        assert!(approx_eq(price, expected, 10.0),
+            "price={} expected={}", price, expected);
+
+        // with no dividend vol, the model is log-normal with the at the
+        // money vol, so a European matches its Black price
+        let deterministic = RcMonteCarloModelFactory::new(Arc::new(
+            sample_factory(0.0, 0.0)));
+        let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+            equity.clone(), sample_settlement(2), expiry, 100.0, PutOrCall::Call,
+            OptionSettlement::Cash).unwrap();
+        let black = european.price(&market_data, val_date).unwrap();
+        let instruments = vec![(1.0, RcInstrument::new(Qrc::new(Arc::new(european))))];
+        let pricer = MonteCarloPricer::with_settings(instruments, deterministic,
+            MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let price = pricer.price().unwrap();
+
+        // The paths are seeded, so this is repeatable. The standard error of
+        // the call is about 0.19, so allow three of those.
+        assert!(approx_eq(price, black, 0.6), "price={} black={}", price, black);
+    }
+
+    #[test]
+    fn bump_to_other_vol_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            DividendVolParameters::new(0.2, 0.5).unwrap());
+        let mut model = StochasticDividends::new(&timeline, context,
+            &parameters, 100).unwrap();
+
+        // the paths of BP.L do not see the vols of GSK.L
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use statrs::distribution::Normal;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::vol_times;
+use models::calculate_substepping;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::correlate_gaussians;
+use models::heston::QeVariance;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The parameters of the 3/2 stochastic volatility model for one
+/// underlying:
+///
+///  dS/S = mu(t) dt + sqrt(v) dW1
+///  dv = kappa v (theta - v) dt + epsilon v^(3/2) dW2
+///  dW1 dW2 = rho dt
+///
+/// where v0 is the initial variance, theta the long-term variance, kappa
+/// the speed of mean reversion per unit of variance and epsilon the
+/// volatility of volatility. Compared with Heston, the vol of vol grows
+/// with the level of the variance and so does the speed of mean reversion,
+/// so spikes in variance are larger but short-lived, which fits the
+/// dynamics of VIX options better.
+///
+/// The reciprocal of the variance, x = 1/v, is a CIR process:
+///
+///  dx = kappa theta (theta_x - x) dt - epsilon sqrt(x) dW2
+///
+/// with long-term level theta_x = (kappa + epsilon^2) / (kappa theta), so
+/// it can be simulated with the same QE scheme as the Heston variance.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ThreeHalvesParameters {
+    v0: f64,
+    kappa: f64,
+    theta: f64,
+    epsilon: f64,
+    rho: f64
+}
+
+impl ThreeHalvesParameters {
+    pub fn new(v0: f64, kappa: f64, theta: f64, epsilon: f64, rho: f64)
+        -> Result<ThreeHalvesParameters, qm::Error> {
+
+        if v0 <= 0.0 || theta <= 0.0 {
+            return Err(qm::Error::new("3/2 initial and long-term variances \
+                must be positive"))
+        }
+        if kappa <= 0.0 || epsilon <= 0.0 {
+            return Err(qm::Error::new("3/2 mean reversion and vol of vol \
+                must be positive"))
+        }
+        if rho < -1.0 || rho > 1.0 {
+            return Err(qm::Error::new("3/2 correlation must be between -1 and 1"))
+        }
+
+        // Otherwise the underlying is a strict local martingale, so that
+        // calls are not worth the forward less puts and strikes.
+        if kappa < rho * epsilon - 0.5 * epsilon * epsilon {
+            return Err(qm::Error::new("3/2 correlation is too high for the \
+                underlying to be a martingale"))
+        }
+
+        Ok(ThreeHalvesParameters { v0: v0, kappa: kappa, theta: theta,
+            epsilon: epsilon, rho: rho })
+    }
+
+    pub fn v0(&self) -> f64 { self.v0 }
+    pub fn kappa(&self) -> f64 { self.kappa }
+    pub fn theta(&self) -> f64 { self.theta }
+    pub fn epsilon(&self) -> f64 { self.epsilon }
+    pub fn rho(&self) -> f64 { self.rho }
+
+    /// Takes one step of the dynamics. The reciprocal of the variance takes
+    /// a QE step, and the underlying a log step with the variance centrally
+    /// weighted in time. Its correlated part comes from the change in the
+    /// log of the variance, as in the Heston QE scheme, so that it is
+    /// consistent with the sampled variance. Takes and returns the log of
+    /// the underlying relative to its forward, and the variance. The two
+    /// gaussians drive the variance and the part of the underlying that is
+    /// independent of it.
+    ///
+    /// There is no closed form for the martingale correction, so the
+    /// forward is only matched to within the discretization error, which
+    /// is small for time steps of a week or so.
+    pub fn step(&self, log_x: f64, v: f64, dt: f64, z_v: f64, z_x: f64,
+        normal: &Normal) -> (f64, f64) {
+
+        if dt <= 0.0 {
+            return (log_x, v)
+        }
+
+        // The QE scheme may put mass at zero, which the reciprocal of the
+        // variance never reaches. Such samples take the expected value.
+        let kappa_x = self.kappa * self.theta;
+        let theta_x = (self.kappa + self.epsilon * self.epsilon) / kappa_x;
+        let qe = QeVariance::new(kappa_x, theta_x, self.epsilon, 1.0 / v, dt);
+        let x_next = qe.sample(z_v, normal);
+        let v_next = if x_next > 0.0 { 1.0 / x_next } else { 1.0 / qe.mean() };
+
+        // integral of sqrt(v) dW2, from Ito's lemma applied to log(v)
+        let integrated = 0.5 * (v + v_next) * dt;
+        let w = ((v_next / v).ln() - kappa_x * dt
+            + (self.kappa + 0.5 * self.epsilon * self.epsilon) * integrated)
+            / self.epsilon;
+
+        let log_x_next = log_x - 0.5 * integrated + self.rho * w
+            + ((1.0 - self.rho * self.rho) * integrated).sqrt() * z_x;
+        (log_x_next, v_next)
+    }
+}
+
+/// The ThreeHalvesFactory creates a 3/2 model, given the timeline of the
+/// product(s) to value and the market data. As for HestonFactory, the
+/// parameters for each underlying are held by the factory, keyed by the id
+/// of the underlying, together with the maximum time step and the number
+/// of paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThreeHalvesFactory {
+    parameters: HashMap<String, ThreeHalvesParameters>,
+    time_step: f64,
+    number_of_paths: usize
+}
+
+impl ThreeHalvesFactory {
+    pub fn new(parameters: HashMap<String, ThreeHalvesParameters>, time_step: f64,
+        number_of_paths: usize) -> ThreeHalvesFactory {
+
+        ThreeHalvesFactory { parameters: parameters, time_step: time_step,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ThreeHalvesFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for ThreeHalvesFactory {
+    fn type_id(&self) -> &'static str { "ThreeHalvesFactory" }
+}
+
+impl MonteCarloModelFactory for ThreeHalvesFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = ThreeHalves::new(timeline, context, &self.parameters,
+            self.time_step, self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A 3/2 model evolves each underlying with its own stochastic variance.
+/// As for Heston, the underlyings are correlated with each other via the
+/// correlations in the market data, and each with its own variance via
+/// rho, while the variance processes are independent of each other.
+///
+/// The drift comes from the forward curve in the market data, and the vol
+/// surface only supplies the measure of time, so vol bumps have no effect
+/// on the price.
+///
+/// Quanto underlyings and displaced vol surfaces are not supported.
+#[derive(Clone)]
+pub struct ThreeHalves {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<ThreeHalvesParameters>,
+    substepping: Vec<usize>,
+    spot_gaussians: Array3<f64>,
+    variance_gaussians: Array3<f64>,
+    paths: Array3<f64>
+}
+
+impl ThreeHalves {
+
+    /// Creates a new 3/2 model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the 3/2 parameters by underlying id, the maximum step in vol time
+    /// and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, ThreeHalvesParameters>,
+        time_step: f64,
+        n_paths: usize)
+        -> Result<ThreeHalves, qm::Error> {
+
+        if time_step <= 0.0 {
+            return Err(qm::Error::new("3/2 time step must be positive"))
+        }
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("3/2 does not support quanto underlyings"))
+        }
+
+        let mut observations = Vec::new();
+        let mut key = HashMap::new();
+        let mut instruments = Vec::new();
+        let mut asset_parameters = Vec::new();
+        for (asset, obs) in timeline.observations().iter() {
+            if observations.is_empty() {
+                observations = obs.to_vec();
+            }
+            let id = asset.id().to_string();
+            let p = parameters.get(&id).ok_or_else(|| qm::Error::new(
+                &format!("No 3/2 parameters for '{}'", id)))?;
+            key.insert(id, instruments.len());
+            instruments.push(asset.clone());
+            asset_parameters.push(*p);
+        }
+
+        let substepping = calculate_substepping(&observations,
+            context.as_pricing_context(), &instruments, time_step)?;
+
+        // as for Heston, the gaussians are kept uncorrelated so that paths
+        // can be refetched with the same random numbers after any bump
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let variance_gaussians = source.fetch(&substepping, n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &spot_gaussians,
+            &variance_gaussians, context.as_pricing_context(), &instruments,
+            &asset_parameters, &substepping)?;
+
+        Ok(ThreeHalves {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            substepping: substepping,
+            spot_gaussians: spot_gaussians,
+            variance_gaussians: variance_gaussians,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        self.paths = fetch_paths(&self.observations, &self.spot_gaussians,
+            &self.variance_gaussians, self.context.as_pricing_context(),
+            &self.instruments, &self.parameters, &self.substepping)?;
+        Ok(())
+    }
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    spot_gaussians: &Array3<f64>,
+    variance_gaussians: &Array3<f64>,
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[ThreeHalvesParameters],
+    substepping: &[usize]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the underlyings with each other, but not their variances
+    let correlated = correlate_gaussians(context, instruments, observations,
+        substepping, spot_gaussians)?;
+
+    for (((instrument, p), (spot, variance)), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated.axis_iter(Axis(2)).zip(variance_gaussians.axis_iter(Axis(2))))
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, context, observations, spot,
+            variance, substepping, path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &ThreeHalvesParameters,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, variance_gaussians: ArrayView2<f64>,
+    substepping: &[usize], mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("3/2 does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for (time, substep) in times.iter().zip(substepping.iter()) {
+        steps.push((time - previous) / (*substep as f64));
+        previous = *time;
+    }
+
+    let normal = match Normal::new(0.0, 1.0) {
+        Ok(normal) => normal,
+        Err(e) => return Err(qm::Error::new(&format!("RSStat error: {}", e)))
+    };
+    for ((z_x, z_v), mut one_path) in spot_gaussians.outer_iter()
+        .zip(variance_gaussians.outer_iter()).zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0;
+        let mut v = parameters.v0;
+        let mut g = 0;
+        for i in 0..observations.len() {
+            for _ in 0..substepping[i] {
+                let (next_x, next_v) = parameters.step(log_x, v, steps[i],
+                    z_v[g], z_x[g], &normal);
+                log_x = next_x;
+                v = next_v;
+                g += 1;
+            }
+            one_path[i] = forwards[i] * log_x.exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for ThreeHalves {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// The variance starts at v0 and reverts to theta, both parameters of
+    /// the model, so bumps to vol levels leave the 3/2 paths unchanged.
+    /// Bumps to forwards, rates or correlations change them.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for ThreeHalves {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("3/2 does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for ThreeHalves {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for ThreeHalves {
+
+    /// Bumps the market data, then regenerates the spots and variances
+    /// with the same random numbers, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::optionpricing::Black76;
+    use instruments::DependencyContext;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::cache::PricingContextPrefetch;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::round_trip;
+    use models::tests::check_monte_carlo_europeans;
+    use dates::Date;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    fn sample_factory(parameters: ThreeHalvesParameters) -> ThreeHalvesFactory {
+        let mut map = HashMap::new();
+        map.insert("BP.L".to_string(), parameters);
+        ThreeHalvesFactory::new(map, 1.0 / 52.0, 20000)
+    }
+
+    #[test]
+    fn parameters_must_give_a_martingale() {
+        assert!(ThreeHalvesParameters::new(0.09, 2.0, 0.09, 4.0, -0.7).is_ok());
+        assert!(ThreeHalvesParameters::new(0.09, 0.2, 0.09, 1.0, 0.9).is_err());
+        assert!(ThreeHalvesParameters::new(0.0, 2.0, 0.09, 4.0, -0.7).is_err());
+    }
+
+    #[test]
+    fn three_halves_without_vol_of_vol_is_black() {
+        let parameters = ThreeHalvesParameters::new(0.09, 20.0, 0.09, 1e-4, -0.7)
+            .unwrap();
+        let factory = round_trip(&sample_factory(parameters));
+        let black76 = Black76::new().unwrap();
+
+        // With the variance starting at its long-term level, and no vol of
+        // vol, the variance stays where it is. The paths are seeded, so this
+        // is deterministic. Even without vol of vol the variance steps are
+        // noisy, and the standard errors are up to 0.19.
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), sample_expiry(),
+            &[(80.0, PutOrCall::Put), (100.0, PutOrCall::Call), (120.0, PutOrCall::Call)],
+            0.6, &|terms, strike, put_or_call| {
+                let sqrt_variance = (0.09 * terms.vol_time).sqrt();
+                match put_or_call {
+                    PutOrCall::Call => black76.call_price(terms.df, terms.forward,
+                        strike, sqrt_variance),
+                    PutOrCall::Put => black76.put_price(terms.df, terms.forward,
+                        strike, sqrt_variance)
+                }
+            });
+    }
+
+    #[test]
+    fn negative_correlation_gives_skew_about_forward() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", equity.clone(), sample_settlement(2), expiry,
+            100.0, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        european.as_mc_priceable().unwrap().mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&european);
+        let context = PricingContextPrefetch::new(&market_data,
+            Arc::new(dependencies)).unwrap();
+        let parameters = ThreeHalvesParameters::new(0.09, 20.0, 0.08, 4.0, -0.7)
+            .unwrap();
+        let model = sample_factory(parameters).factory(&timeline, Box::new(context))
+            .unwrap();
+
+        // the underlying is centred on its forward
+        let forward_curve = market_data.forward_curve(&*equity, expiry.date()).unwrap();
+        let forward = forward_curve.forward(expiry.date()).unwrap();
+        let paths = model.paths(&equity).unwrap();
+        let n_paths = paths.shape()[0] as f64;
+        let mean = paths.scalar_sum() / n_paths;
+        assert!(approx_eq(mean, forward, 1.0), "mean={} forward={}", mean, forward);
+
+        // and the negative correlation skews the log returns to the left
+        let logs = paths.mapv(|s| (s / forward).ln());
+        let log_mean = logs.scalar_sum() / n_paths;
+        let variance = logs.mapv(|x| (x - log_mean).powi(2)).scalar_sum() / n_paths;
+        let skewness = logs.mapv(|x| (x - log_mean).powi(3)).scalar_sum() / n_paths
+            / variance.powf(1.5);
+        assert!(skewness < -0.3, "skewness={}", skewness);
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            ThreeHalvesParameters::new(0.09, 2.0, 0.09, 4.0, -0.7).unwrap());
+        let mut model = ThreeHalves::new(&timeline, context,
+            &parameters, 1.0 / 52.0, 100).unwrap();
+
+        // the variance process runs from its own parameters
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
@@ -0,0 +1,690 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use rand;
+use rand::StdRng;
+use rand::distributions::Gamma;
+use rand::distributions::IndependentSample;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use math::complex::Complex;
+use math::fourier::CharacteristicFunction;
+use math::fourier::LevyProcess;
+use math::fourier;
+use math::neldermead::nelder_mead;
+use math::optionpricing::Black76;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::evaluate_flows;
+use models::collect_underlyings;
+use models::vol_times;
+use models::Threading;
+use models::RegeneratedPaths;
+use models::BumpedPaths;
+use models::bump_paths;
+use models::new_saved_paths;
+use models::restore_paths;
+use models::blackdiffusion::GaussianSource;
+use models::blackdiffusion::seeded_generator;
+use models::blackdiffusion::correlate_gaussians;
+use dates::datetime::DateDayFraction;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Relative tolerance of the calibration, in terms of the sum of squared
+/// errors in the prices
+const CALIBRATION_TOLERANCE: f64 = 1e-12;
+
+/// Maximum number of iterations of the calibration
+const CALIBRATION_MAX_ITER: u32 = 5000;
+
+/// The parameters of the variance gamma model of Madan, Carr and Chang
+/// (1998) for one underlying. The log of the underlying relative to its
+/// forward is a Brownian motion with drift, running in a gamma-distributed
+/// business time G:
+///
+///  ln(S/F) = omega t + theta G(t) + sigma W(G(t))
+///
+/// where G has mean t and variance nu t, so nu controls the kurtosis and
+/// theta the skew, and omega is the drift that makes S/F a martingale.
+/// It is a pure jump process, with many small jumps, which gives the steep
+/// short-dated smiles that diffusions cannot match.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct VarianceGammaParameters {
+    sigma: f64,
+    nu: f64,
+    theta: f64
+}
+
+impl VarianceGammaParameters {
+    pub fn new(sigma: f64, nu: f64, theta: f64)
+        -> Result<VarianceGammaParameters, qm::Error> {
+
+        if sigma <= 0.0 || nu <= 0.0 {
+            return Err(qm::Error::new("Variance gamma sigma and nu must be positive"))
+        }
+        if 1.0 - theta * nu - 0.5 * sigma * sigma * nu <= 0.0 {
+            return Err(qm::Error::new("Variance gamma parameters give an infinite \
+                forward, as theta or sigma are too large for the given nu"))
+        }
+
+        Ok(VarianceGammaParameters { sigma: sigma, nu: nu, theta: theta })
+    }
+
+    pub fn sigma(&self) -> f64 { self.sigma }
+    pub fn nu(&self) -> f64 { self.nu }
+    pub fn theta(&self) -> f64 { self.theta }
+
+    /// The drift per unit time that makes the underlying relative to its
+    /// forward a martingale
+    pub fn omega(&self) -> f64 {
+        (1.0 - self.theta * self.nu - 0.5 * self.sigma * self.sigma * self.nu).ln()
+            / self.nu
+    }
+
+    /// Semi-analytic price of a European call. This uses the COS method,
+    /// which copes better than the Lewis integral with the slow decay of
+    /// the characteristic function at short expiries.
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::cos_call_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic price of a European put
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64, t: f64)
+        -> Result<f64, qm::Error> {
+        fourier::cos_put_price(self, df, forward, strike, t)
+    }
+
+    /// Semi-analytic prices of a strip of European calls, from a single FFT
+    pub fn call_prices(&self, df: f64, forward: f64, strikes: &[f64], t: f64)
+        -> Result<Vec<f64>, qm::Error> {
+        fourier::fft_call_prices(self, df, forward, strikes, t)
+    }
+
+    /// Semi-analytic prices of a strip of European puts, from a single FFT
+    pub fn put_prices(&self, df: f64, forward: f64, strikes: &[f64], t: f64)
+        -> Result<Vec<f64>, qm::Error> {
+        fourier::fft_put_prices(self, df, forward, strikes, t)
+    }
+
+    /// Calibrates sigma, nu and theta to a market smile of (strike, vol)
+    /// pairs, for the given forward and time to expiry. This minimizes the
+    /// sum of squared errors in the undiscounted prices of out-of-the-money
+    /// options, relative to the forward, rather than in the vols, as it
+    /// saves solving for the implied vol of each model price.
+    pub fn calibrate(forward: f64, t: f64, smile: &[(f64, f64)])
+        -> Result<VarianceGammaParameters, qm::Error> {
+
+        if smile.len() < 3 {
+            return Err(qm::Error::new("Variance gamma calibration needs at least \
+                three points on the smile"))
+        }
+        if t <= 0.0 || forward <= 0.0 {
+            return Err(qm::Error::new("Variance gamma calibration needs a positive \
+                time and forward"))
+        }
+
+        let black76 = Black76::new()?;
+        let mut quotes = Vec::with_capacity(smile.len());
+        let mut mean_vol = 0.0;
+        for &(strike, vol) in smile.iter() {
+            let sqrt_var = vol * t.sqrt();
+            let price = if strike < forward {
+                black76.put_price(1.0, forward, strike, sqrt_var)
+            } else {
+                black76.call_price(1.0, forward, strike, sqrt_var)
+            };
+            quotes.push((strike, price / forward));
+            mean_vol += vol / smile.len() as f64;
+        }
+
+        // Search in terms of the logs of sigma and nu, and theta itself.
+        // Parameters that are invalid, or fail to price, are heavily
+        // penalized.
+        let parameters = |x: &[f64]| VarianceGammaParameters::new(x[0].exp(),
+            x[1].exp(), x[2]);
+        let mut objective = |x: &[f64]| -> Result<f64, qm::Error> {
+            let p = match parameters(x) {
+                Ok(p) => p,
+                Err(_) => return Ok(1.0)
+            };
+            let mut sum = 0.0;
+            for &(strike, price) in quotes.iter() {
+                let model = if strike < forward {
+                    p.put_price(1.0, 1.0, strike / forward, t)
+                } else {
+                    p.call_price(1.0, 1.0, strike / forward, t)
+                };
+                sum += match model {
+                    Ok(model) => (model - price) * (model - price),
+                    Err(_) => 1.0
+                };
+            }
+            Ok(sum)
+        };
+
+        // start from a symmetric smile at the average vol, and restart
+        // from the best point, as the simplex can collapse early
+        let mut start = vec![mean_vol.ln(), (0.2_f64).ln(), 0.0];
+        for _ in 0..2 {
+            let (best, _) = nelder_mead(&start, &[0.2, 0.5, 0.1],
+                CALIBRATION_TOLERANCE, CALIBRATION_MAX_ITER, &mut objective)?;
+            start = best;
+        }
+        parameters(&start)
+    }
+}
+
+impl CharacteristicFunction for VarianceGammaParameters {
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+        let iu = Complex::i() * u;
+        let base = Complex::from_real(1.0) - iu * (self.theta * self.nu)
+            + u * u * (0.5 * self.sigma * self.sigma * self.nu);
+        (iu * (self.omega() * t) - base.ln() * (t / self.nu)).exp()
+    }
+}
+
+impl LevyProcess for VarianceGammaParameters {
+    fn as_characteristic_function(&self) -> &CharacteristicFunction { self }
+}
+
+/// The VarianceGammaFactory creates a variance gamma model, given the
+/// timeline of the product(s) to value and the market data. The parameters
+/// for each underlying are held by the factory, keyed by the id of the
+/// underlying, as the market data only contains implied vols. The factory
+/// also holds the number of paths. There is no time step, as each period
+/// between observations is simulated exactly.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VarianceGammaFactory {
+    parameters: HashMap<String, VarianceGammaParameters>,
+    number_of_paths: usize
+}
+
+impl VarianceGammaFactory {
+    pub fn new(parameters: HashMap<String, VarianceGammaParameters>,
+        number_of_paths: usize) -> VarianceGammaFactory {
+
+        VarianceGammaFactory { parameters: parameters,
+            number_of_paths: number_of_paths }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(VarianceGammaFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for VarianceGammaFactory {
+    fn type_id(&self) -> &'static str { "VarianceGammaFactory" }
+}
+
+impl MonteCarloModelFactory for VarianceGammaFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = VarianceGamma::new(timeline, context, &self.parameters,
+            self.number_of_paths)?;
+        Ok(Box::new(model))
+    }
+}
+
+/// A variance gamma model evolves each underlying by a Brownian motion
+/// with drift, time-changed by its own gamma process. Each period between
+/// observations is simulated exactly, by drawing the gamma time elapsed in
+/// the period, then a gaussian scaled by its square root. The gaussians
+/// are correlated between underlyings via the correlations in the market
+/// data, but the gamma times are independent.
+///
+/// As for Heston, the drift comes from the forward curve and the vol
+/// surface only supplies the business-day vol time. Vol bumps have no
+/// effect on the price. The gamma times depend only on the vol times, so
+/// they are reused after bumps, unless the bump changes the vol times, as
+/// a spot date bump may, in which case they are redrawn. Quanto underlyings
+/// and displaced vol surfaces are not supported.
+#[derive(Clone)]
+pub struct VarianceGamma {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    parameters: Vec<VarianceGammaParameters>,
+    spot_gaussians: Array3<f64>,
+    steps: Vec<Vec<f64>>,
+    gamma_times: Vec<Array2<f64>>,
+    threading: Threading,
+    paths: Array3<f64>
+}
+
+impl VarianceGamma {
+
+    /// Creates a new variance gamma model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// the parameters by underlying id and the number of paths.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        parameters: &HashMap<String, VarianceGammaParameters>,
+        n_paths: usize)
+        -> Result<VarianceGamma, qm::Error> {
+
+        if !timeline.quantos().is_empty() {
+            return Err(qm::Error::new("Variance gamma does not support quanto underlyings"))
+        }
+
+        let (observations, key, instruments, asset_parameters) =
+            collect_underlyings(timeline, parameters, "variance gamma")?;
+
+        let threading = timeline.threading().for_overlay("gamma");
+        let mut steps = Vec::with_capacity(instruments.len());
+        let mut gamma_times = Vec::with_capacity(instruments.len());
+        for (instrument, p) in instruments.iter().zip(asset_parameters.iter()) {
+            let asset_steps = vol_steps(instrument.deref(),
+                context.as_pricing_context(), &observations)?;
+            let mut rand = gamma_generator(&threading, instrument.id());
+            gamma_times.push(fetch_gamma_times(&asset_steps, p, n_paths, &mut rand)?);
+            steps.push(asset_steps);
+        }
+
+        // One gaussian per observation. They are kept uncorrelated, so
+        // that paths can be refetched with the same random numbers after
+        // any bump, including a correlation bump.
+        let n_assets = instruments.len();
+        let mut source = GaussianSource::from_timeline(timeline);
+        let spot_gaussians = source.fetch(&vec![1; observations.len()],
+            n_assets, n_paths)?;
+        let paths = fetch_paths(&observations, &spot_gaussians, &steps,
+            &gamma_times, context.as_pricing_context(), &instruments,
+            &asset_parameters)?;
+
+        Ok(VarianceGamma {
+            observations: observations,
+            flows: timeline.flows().to_vec(),
+            context: context,
+            key: key,
+            instruments: instruments,
+            parameters: asset_parameters,
+            spot_gaussians: spot_gaussians,
+            steps: steps,
+            gamma_times: gamma_times,
+            threading: threading,
+            paths: paths })
+    }
+
+    /// Refetch all paths for all assets, using the same random numbers
+    /// unless the vol times have changed
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        let n_paths = self.spot_gaussians.shape()[0];
+        for (asset, (instrument, p)) in self.instruments.iter()
+            .zip(self.parameters.iter()).enumerate() {
+
+            let asset_steps = vol_steps(instrument.deref(),
+                self.context.as_pricing_context(), &self.observations)?;
+            if asset_steps != self.steps[asset] {
+                let mut rand = gamma_generator(&self.threading, instrument.id());
+                self.gamma_times[asset] = fetch_gamma_times(&asset_steps, p,
+                    n_paths, &mut rand)?;
+                self.steps[asset] = asset_steps;
+            }
+        }
+
+        self.paths = fetch_paths(&self.observations, &self.spot_gaussians,
+            &self.steps, &self.gamma_times, self.context.as_pricing_context(),
+            &self.instruments, &self.parameters)?;
+        Ok(())
+    }
+}
+
+/// The vol time elapsed in each period up to an observation
+fn vol_steps(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction]) -> Result<Vec<f64>, qm::Error> {
+
+    let times = vol_times(instrument, context, observations)?;
+    let mut steps = Vec::with_capacity(observations.len());
+    let mut previous = 0.0;
+    for time in times.iter() {
+        steps.push((time - previous).max(0.0));
+        previous = *time;
+    }
+    Ok(steps)
+}
+
+/// The generator for the gamma times of an underlying. With a seed, each
+/// underlying has a stream of its own, keyed by its id and the threading of
+/// the gamma times, so it draws the same times whenever it is refetched.
+fn gamma_generator(threading: &Threading, id: &str) -> StdRng {
+    match threading.seed() {
+        Some(seed) => seeded_generator(seed, threading.for_overlay(id).stream(), 0, 0),
+        // as for fetch_gaussians, use the standard library random number
+        // generator for now
+        None => rand::StdRng::new().unwrap()
+    }
+}
+
+/// Draws the gamma time elapsed in each period, by path. The gamma time in
+/// a period of length dt has shape dt / nu and scale nu. (This uses the
+/// gamma distribution from rand rather than statrs, as the statrs sampler
+/// has the wrong sign in its rejection test, which biases the draws low.)
+fn fetch_gamma_times(steps: &[f64], parameters: &VarianceGammaParameters,
+    n_paths: usize, rand: &mut StdRng) -> Result<Array2<f64>, qm::Error> {
+
+    // rand panics rather than failing on a bad distribution, and the
+    // parameters may have been deserialized without validation
+    if !(parameters.nu > 0.0) {
+        return Err(qm::Error::new("Variance gamma nu must be positive"))
+    }
+
+    let mut result = Array2::<f64>::zeros((n_paths, steps.len()));
+
+    for (i, &dt) in steps.iter().enumerate() {
+        if dt <= 0.0 {
+            continue;
+        }
+        let gamma = Gamma::new(dt / parameters.nu, parameters.nu);
+        for draw in result.subview_mut(Axis(1), i).iter_mut() {
+            *draw = gamma.ind_sample(rand);
+        }
+    }
+
+    Ok(result)
+}
+
+fn fetch_paths(
+    observations: &[DateDayFraction],
+    spot_gaussians: &Array3<f64>,
+    steps: &[Vec<f64>],
+    gamma_times: &[Array2<f64>],
+    context: &PricingContext,
+    instruments: &[RcInstrument],
+    parameters: &[VarianceGammaParameters]) -> Result<Array3<f64>, qm::Error> {
+
+    let n_paths = spot_gaussians.shape()[0];
+    let n_assets = instruments.len();
+    let mut paths = Array3::<f64>::zeros((n_paths, observations.len(), n_assets));
+
+    // correlate the gaussians with each other, but not the gamma times
+    let correlated = correlate_gaussians(context, instruments, observations,
+        &vec![1; observations.len()], spot_gaussians)?;
+
+    for (((((instrument, p), spot), asset_steps), gammas), path) in instruments.iter()
+        .zip(parameters.iter())
+        .zip(correlated.axis_iter(Axis(2)))
+        .zip(steps.iter())
+        .zip(gamma_times.iter())
+        .zip(paths.axis_iter_mut(Axis(2))) {
+
+        fetch_path(instrument.deref(), p, context, observations, spot,
+            asset_steps, gammas.view(), path)?;
+    }
+    Ok(paths)
+}
+
+/// Fetches the paths for a single asset
+fn fetch_path(instrument: &Instrument, parameters: &VarianceGammaParameters,
+    context: &PricingContext, observations: &[DateDayFraction],
+    spot_gaussians: ArrayView2<f64>, steps: &[f64],
+    gamma_times: ArrayView2<f64>, mut path: ArrayViewMut2<f64>)
+    -> Result<(), qm::Error> {
+
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+    let mut forwards = Vec::with_capacity(observations.len());
+    for obs in observations.iter() {
+        if vol_surface.displacement(obs.date())? != 0.0 {
+            return Err(qm::Error::new("Variance gamma does not support displaced vol surfaces"))
+        }
+        forwards.push(forward_curve.forward(obs.date())?);
+    }
+
+    let omega = parameters.omega();
+    for ((z, g), mut one_path) in spot_gaussians.outer_iter()
+        .zip(gamma_times.outer_iter()).zip(path.outer_iter_mut()) {
+
+        let mut log_x = 0.0;
+        for (i, dt) in steps.iter().enumerate() {
+            log_x += omega * dt + parameters.theta * g[i]
+                + parameters.sigma * g[i].sqrt() * z[i];
+            one_path[i] = forwards[i] * log_x.exp();
+        }
+    }
+
+    Ok(())
+}
+
+impl MonteCarloModel for VarianceGamma {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+
+    /// Sigma comes from the parameters, so bumps to vol levels leave the
+    /// variance gamma paths unchanged. Bumps to forwards, rates or
+    /// correlations change them, as the paths are scaled to the forwards.
+    fn bumped_paths(&self, bump: &Bump) -> Result<BumpedPaths, qm::Error> {
+        Ok(BumpedPaths::of_bump(bump, self.context.dependencies()?))
+    }
+}
+
+impl MonteCarloContext for VarianceGamma {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Variance gamma does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+        evaluate_flows(self.context.as_pricing_context(), &self.flows,
+            quantities)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
+
+impl RegeneratedPaths for VarianceGamma {
+    type Paths = Array3<f64>;
+
+    fn bumpable_data(&self) -> &Bumpable { self.context.as_bumpable() }
+    fn bumpable_data_mut(&mut self) -> &mut Bumpable { self.context.as_mut_bumpable() }
+
+    fn copy_paths(&self) -> Array3<f64> {
+        self.paths.clone()
+    }
+
+    fn set_paths(&mut self, paths: &Array3<f64>) {
+        self.paths.assign(paths);
+    }
+
+    fn regenerate_paths(&mut self, _changes: &BumpedPaths) -> Result<(), qm::Error> {
+        self.refetch_all()
+    }
+}
+
+impl Bumpable for VarianceGamma {
+
+    /// Bumps the market data, then regenerates the paths with the same
+    /// random numbers and gamma times, if the bump changes them.
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        bump_paths(self, bump, any_saved)
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        new_saved_paths(self)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+        restore_paths(self, any_saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use math::brent::zbrent;
+    use instruments::options::PutOrCall;
+    use models::RcMonteCarloModelFactory;
+    use models::tests::check_monte_carlo_europeans;
+    use models::tests::round_trip;
+    use risk::marketdata::tests::sample_market_data;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use models::tests::sample_model_inputs;
+    use models::tests::check_bump_leaves_paths;
+    use data::bumpvol::BumpVol;
+    use risk::marketdata::tests::sample_european;
+
+    fn skewed() -> VarianceGammaParameters {
+        VarianceGammaParameters::new(0.25, 0.2, -0.15).unwrap()
+    }
+
+    #[test]
+    fn characteristic_function_is_martingale() {
+        let u = Complex::new(0.0, -1.0);
+        let phi = skewed().characteristic_function(u, 0.5);
+        assert!(approx_eq(phi.re, 1.0, 1e-12) && approx_eq(phi.im, 0.0, 1e-12),
+            "phi={:?}", phi);
+    }
+
+    #[test]
+    fn gamma_times_have_the_right_moments() {
+        // the gamma time in each period has mean dt and variance nu dt,
+        // over short periods, where shape dt / nu is below one, and long
+        let p = skewed();
+        let steps = [0.05, 0.5, 1.5];
+        let n_paths = 100000;
+        let mut rand = seeded_generator(42, 0, 0, 0);
+        let times = fetch_gamma_times(&steps, &p, n_paths, &mut rand).unwrap();
+        for (i, &dt) in steps.iter().enumerate() {
+            let draws = times.subview(Axis(1), i);
+            let mean = draws.scalar_sum() / n_paths as f64;
+            let variance = draws.iter().map(|g| (g - mean) * (g - mean))
+                .sum::<f64>() / n_paths as f64;
+            assert!(approx_eq(mean, dt, 0.01 * dt), "dt={} mean={}", dt, mean);
+            assert!(approx_eq(variance, p.nu * dt, 0.03 * p.nu * dt),
+                "dt={} variance={} expected={}", dt, variance, p.nu * dt);
+        }
+    }
+
+    #[test]
+    fn cos_matches_lewis() {
+        let p = skewed();
+        for &(strike, t) in [(80.0, 0.5_f64), (100.0, 1.0), (120.0, 2.0)].iter() {
+            let call = p.call_price(0.9, 100.0, strike, t).unwrap();
+            let expected = fourier::call_price(&p, 0.9, 100.0, strike, t).unwrap();
+            assert!(approx_eq(call, expected, 1e-4),
+                "strike={} t={} call={} expected={}", strike, t, call, expected);
+        }
+    }
+
+    #[test]
+    fn fft_matches_cos() {
+        // a whole strip from one transform, against pricing each strike
+        let p = skewed();
+        let strikes = [60.0, 80.0, 95.0, 100.0, 105.0, 120.0, 150.0];
+        for &t in [0.5, 1.0_f64, 2.0].iter() {
+            let calls = p.call_prices(0.9, 100.0, &strikes, t).unwrap();
+            let puts = p.put_prices(0.9, 100.0, &strikes, t).unwrap();
+            for (i, &strike) in strikes.iter().enumerate() {
+                let expected = p.call_price(0.9, 100.0, strike, t).unwrap();
+                assert!(approx_eq(calls[i], expected, 1e-5),
+                    "strike={} t={} call={} expected={}", strike, t, calls[i], expected);
+                let expected = p.put_price(0.9, 100.0, strike, t).unwrap();
+                assert!(approx_eq(puts[i], expected, 1e-5),
+                    "strike={} t={} put={} expected={}", strike, t, puts[i], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn calibration_recovers_parameters() {
+        let (forward, t) = (100.0, 0.5);
+        let p = skewed();
+        let black76 = Black76::new().unwrap();
+        let smile: Vec<(f64, f64)> = [70.0, 80.0, 90.0, 100.0, 110.0, 120.0, 135.0]
+            .iter().map(|&strike| {
+                let price = p.call_price(1.0, forward, strike, t).unwrap();
+                let vol = zbrent(0.01, 2.0, 1e-12, 100, &mut |vol| Ok(black76.call_price(
+                    1.0, forward, strike, vol * t.sqrt()) - price)).unwrap();
+                (strike, vol)
+            }).collect();
+
+        let fitted = VarianceGammaParameters::calibrate(forward, t, &smile).unwrap();
+        assert!(approx_eq(fitted.sigma(), 0.25, 1e-3), "fitted={:?}", fitted);
+        assert!(approx_eq(fitted.nu(), 0.2, 1e-3), "fitted={:?}", fitted);
+        assert!(approx_eq(fitted.theta(), -0.15, 1e-3), "fitted={:?}", fitted);
+
+        assert!(VarianceGammaParameters::calibrate(forward, t, &smile[0..2]).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_matches_semi_analytic() {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(), skewed());
+        let factory = round_trip(&VarianceGammaFactory::new(parameters, 20000));
+
+        // The paths are seeded, so the price is repeatable. The standard
+        // errors are at most 0.085, so this allows three of them.
+        let expiry = DateTime::new(Date::from_ymd(2017, 07, 03), TimeOfDay::Close);
+        check_monte_carlo_europeans(RcMonteCarloModelFactory::new(Arc::new(factory)),
+            &sample_market_data(), expiry,
+            &[(85.0, PutOrCall::Put), (100.0, PutOrCall::Call), (115.0, PutOrCall::Call)],
+            0.25, &|terms, strike, put_or_call| match put_or_call {
+                PutOrCall::Call => skewed().call_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap(),
+                PutOrCall::Put => skewed().put_price(terms.df, terms.forward,
+                    strike, terms.vol_time).unwrap()
+            });
+    }
+
+    #[test]
+    fn vol_bump_leaves_paths_untouched() {
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let (timeline, context) = sample_model_inputs(&market_data, &european, &european);
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            skewed());
+        let mut model = VarianceGamma::new(&timeline, context, &parameters, 100).unwrap();
+
+        // sigma is a parameter, so the gamma times and paths stay as they were
+        check_bump_leaves_paths(&mut model,
+            &Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)));
+    }
+}
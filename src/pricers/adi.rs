@@ -0,0 +1,438 @@
+use core::qm;
+use std::collections::HashMap;
+use math::tridiagonal::solve_tridiagonal;
+use models::heston::HestonParameters;
+use pricers::pde::GridDate;
+use pricers::pde::SpaceGrid;
+use pricers::pde::apply_events;
+use pricers::pde::exercise_point;
+use pricers::pde::ExercisePoint;
+
+/// The variance grid extends to this multiple of the larger of the initial
+/// and long-term variances
+const VARIANCE_MULTIPLE: f64 = 10.0;
+
+/// The nodes of the variance grid are concentrated near zero, where the
+/// values curve most, with a spacing there of this fraction of the top of
+/// the grid
+const VARIANCE_CONCENTRATION: f64 = 0.002;
+
+/// The alternating direction implicit schemes for stepping a PDE with a
+/// mixed derivative. Each splits a step into implicit solves along each
+/// direction in turn, which are tridiagonal, while the mixed derivative is
+/// explicit. All are second order in time and unconditionally stable for
+/// the Heston PDE.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AdiScheme {
+    /// The Craig-Sneyd scheme, with theta of one half
+    CraigSneyd,
+    /// The modified Craig-Sneyd scheme of In 't Hout and Welfert, with theta
+    /// of one third, which damps better than Craig-Sneyd
+    ModifiedCraigSneyd,
+    /// The Hundsdorfer-Verwer scheme, with theta of one half plus the root
+    /// of three over six
+    HundsdorferVerwer
+}
+
+/// Configures the PdePricer to roll back under the Heston model, on a
+/// two-dimensional grid in the log of the underlying and its variance. The
+/// parameters are keyed by the id of the underlying, as for the Heston
+/// Monte-Carlo model, and time is measured in the vol time of the vol
+/// surface, so the levels of the implied vols are not used.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HestonPdeModel {
+    parameters: HashMap<String, HestonParameters>,
+    variance_steps: usize,
+    scheme: AdiScheme
+}
+
+impl HestonPdeModel {
+    pub fn new(parameters: HashMap<String, HestonParameters>,
+        variance_steps: usize, scheme: AdiScheme)
+        -> Result<HestonPdeModel, qm::Error> {
+
+        if variance_steps < 3 {
+            return Err(qm::Error::new("Heston PDE needs at least three \
+                variance steps"))
+        }
+        Ok(HestonPdeModel { parameters: parameters, variance_steps: variance_steps,
+            scheme: scheme })
+    }
+
+    pub fn parameters(&self, id: &str) -> Result<&HestonParameters, qm::Error> {
+        self.parameters.get(id).ok_or_else(|| qm::Error::new(&format!(
+            "No Heston parameters for '{}'", id)))
+    }
+
+    pub fn variance_steps(&self) -> usize { self.variance_steps }
+    pub fn scheme(&self) -> AdiScheme { self.scheme }
+}
+
+/// The mean variance over the given vol time, for sizing the space grid
+pub fn mean_variance(parameters: &HestonParameters, t: f64) -> f64 {
+    let kt = parameters.kappa() * t;
+    if kt < 1e-8 {
+        return parameters.v0()
+    }
+    parameters.theta() + (parameters.v0() - parameters.theta()) * (1.0 - (-kt).exp()) / kt
+}
+
+/// Rolls back the payoff from the last grid date to the first under the
+/// Heston model, returning the value at the spot and initial variance, in
+/// the same discounted units as the payoff, and the exercise boundary at
+/// the variance node nearest the initial variance. The times are the vol times of
+/// the grid dates, and the drifts come from the forwards. Barrier
+/// observations and dividends are applied at each variance as for the
+/// one-dimensional grid, and early exercise by taking the larger of the
+/// value and the payoff after each step.
+pub fn roll_back_heston(grid: &[GridDate], forwards: &[f64], times: &[f64],
+    space: &SpaceGrid, payoff: &Fn(usize) -> Vec<f64>,
+    parameters: &HestonParameters, model: &HestonPdeModel)
+    -> Result<(f64, Vec<ExercisePoint>), qm::Error> {
+
+    let variances = variance_grid(parameters, model.variance_steps);
+    let nx = space.spots.len();
+    let nv = variances.len();
+    let v0 = parameters.v0();
+    let nearest = (0..nv).min_by(|a, b| (variances[*a] - v0).abs()
+        .partial_cmp(&(variances[*b] - v0).abs()).unwrap()).unwrap();
+    let row = |values: &[f64]| -> Vec<f64> {
+        values[nearest * nx..(nearest + 1) * nx].to_vec()
+    };
+    let expand = |row: Vec<f64>| -> Vec<f64> {
+        let mut values = Vec::with_capacity(nx * nv);
+        for _ in 0..nv {
+            values.extend(row.iter());
+        }
+        values
+    };
+
+    let last = grid.len() - 1;
+    let mut values = expand(payoff(last));
+    let mut points = Vec::new();
+    if grid[last].exercise {
+        let exercise = payoff(last);
+        points.extend(exercise_point(grid[last].date, &exercise, &exercise, space));
+    }
+    apply_heston_events(&grid[last], space, nx, &mut values);
+    let mut smooth = true;
+    for i in (0..last).rev() {
+        let dt = (times[i + 1] - times[i]).max(0.0);
+        let mu = (forwards[i + 1] / forwards[i]).ln() - grid[i + 1].dividend_ratio.ln();
+        values = if smooth {
+            // two fully implicit half steps after a discontinuity, as for
+            // the one-dimensional grid
+            let operator = HestonOperator::new(parameters, &variances, space,
+                0.5 * dt, 0.5 * mu);
+            let half = operator.douglas(&values, 1.0)?;
+            operator.douglas(&half, 1.0)?
+        } else {
+            let operator = HestonOperator::new(parameters, &variances, space, dt, mu);
+            match model.scheme {
+                AdiScheme::CraigSneyd => operator.craig_sneyd(&values, 0.5, 0.5)?,
+                AdiScheme::ModifiedCraigSneyd =>
+                    operator.craig_sneyd(&values, 1.0 / 3.0, 1.0 / 3.0)?,
+                AdiScheme::HundsdorferVerwer => operator.hundsdorfer_verwer(&values,
+                    0.5 + 3.0_f64.sqrt() / 6.0)?
+            }
+        };
+        if grid[i].exercise {
+            let exercise = payoff(i);
+            for (value, exercise) in values.iter_mut().zip(exercise.iter().cycle()) {
+                *value = value.max(*exercise);
+            }
+            points.extend(exercise_point(grid[i].date, &row(&values), &exercise, space));
+        }
+        smooth = apply_heston_events(&grid[i], space, nx, &mut values);
+    }
+
+    // interpolate quadratically in the variance at the spot
+    let j = match variances.iter().position(|v| *v >= v0) {
+        Some(j) => j.max(1).min(nv - 2),
+        None => nv - 2
+    };
+    let mut value = 0.0;
+    for a in j - 1..j + 2 {
+        let mut weight = 1.0;
+        for b in j - 1..j + 2 {
+            if a != b {
+                weight *= (v0 - variances[b]) / (variances[a] - variances[b]);
+            }
+        }
+        value += weight * values[a * nx + space.below];
+    }
+    points.reverse();
+    Ok((value, points))
+}
+
+/// Applies the events of a grid date to each row of constant variance,
+/// returning true if any made the values discontinuous
+fn apply_heston_events(date: &GridDate, space: &SpaceGrid, nx: usize,
+    values: &mut Vec<f64>) -> bool {
+
+    let mut discontinuous = false;
+    let mut row = Vec::with_capacity(nx);
+    for chunk in values.chunks_mut(nx) {
+        row.clear();
+        row.extend(chunk.iter());
+        discontinuous |= apply_events(date, &space.hit, space.h, &mut row);
+        chunk.copy_from_slice(&row);
+    }
+    discontinuous
+}
+
+/// The nodes in variance, from zero, stretched by a sinh so that they are
+/// closest together near zero
+fn variance_grid(parameters: &HestonParameters, steps: usize) -> Vec<f64> {
+    let top = VARIANCE_MULTIPLE * parameters.v0().max(parameters.theta());
+    let scale = VARIANCE_CONCENTRATION * top;
+    let end = (top / scale).asinh();
+    (0..steps + 1).map(|j| scale * (end * j as f64 / steps as f64).sinh()).collect()
+}
+
+/// The finite difference operator of the Heston PDE over one step, in the
+/// log of the underlying x and the variance v,
+///
+///  A = A0 + A1 + A2
+///  A0 = rho xi v dt d2/dxdv
+///  A1 = 0.5 v dt d2/dx2 + (mu - 0.5 v dt) d/dx
+///  A2 = 0.5 xi^2 v dt d2/dv2 + kappa (theta - v) dt d/dv
+///
+/// where dt is the vol time of the step and mu the drift of x over it, so
+/// each step has unit length. At the ends of the x grid, the value is taken
+/// to be linear in the underlying, so only the drift acts. At zero variance
+/// the diffusion vanishes, leaving the drift of the variance, and at the
+/// top of the grid the value is taken to be flat in the variance.
+struct HestonOperator {
+    nx: usize,
+    nv: usize,
+    x_lower: Vec<f64>,
+    x_diag: Vec<f64>,
+    x_upper: Vec<f64>,
+    v_lower: Vec<f64>,
+    v_diag: Vec<f64>,
+    v_upper: Vec<f64>,
+    mixed: Vec<[f64; 3]>
+}
+
+impl HestonOperator {
+    fn new(parameters: &HestonParameters, variances: &[f64], space: &SpaceGrid,
+        dt: f64, mu: f64) -> HestonOperator {
+
+        let nx = space.spots.len();
+        let nv = variances.len();
+        let h = space.h;
+        let (kappa, theta, xi, rho) = (parameters.kappa(), parameters.theta(),
+            parameters.xi(), parameters.rho());
+
+        // the x direction, row by row
+        let mut x_lower = vec![0.0; nx * nv];
+        let mut x_diag = vec![0.0; nx * nv];
+        let mut x_upper = vec![0.0; nx * nv];
+        for (j, v) in variances.iter().enumerate() {
+            let convection = 0.5 * (mu - 0.5 * v * dt) / h;
+            let diffusion = 0.5 * v * dt / (h * h);
+            for i in 0..nx {
+                let k = j * nx + i;
+                if i == 0 {
+                    x_diag[k] = -mu / h;
+                    x_upper[k] = mu / h;
+                } else if i == nx - 1 {
+                    x_lower[k] = -mu / h;
+                    x_diag[k] = mu / h;
+                } else {
+                    x_lower[k] = diffusion - convection;
+                    x_diag[k] = -2.0 * diffusion;
+                    x_upper[k] = diffusion + convection;
+                }
+            }
+        }
+
+        // the v direction, which is the same for every interior x, with
+        // the weights of the central differences on the uneven grid
+        let mut v_lower = vec![0.0; nv];
+        let mut v_diag = vec![0.0; nv];
+        let mut v_upper = vec![0.0; nv];
+        let mut mixed = vec![[0.0; 3]; nv];
+        v_diag[0] = -kappa * theta * dt / variances[1];
+        v_upper[0] = kappa * theta * dt / variances[1];
+        for j in 1..nv - 1 {
+            let v = variances[j];
+            let below = v - variances[j - 1];
+            let above = variances[j + 1] - v;
+            let first = [-above / (below * (below + above)),
+                (above - below) / (below * above), below / (above * (below + above))];
+            let second = [2.0 / (below * (below + above)), -2.0 / (below * above),
+                2.0 / (above * (below + above))];
+            let diffusion = 0.5 * xi * xi * v * dt;
+            let drift = kappa * (theta - v) * dt;
+            v_lower[j] = diffusion * second[0] + drift * first[0];
+            v_diag[j] = diffusion * second[1] + drift * first[1];
+            v_upper[j] = diffusion * second[2] + drift * first[2];
+            let c = rho * xi * v * dt / (2.0 * h);
+            mixed[j] = [c * first[0], c * first[1], c * first[2]];
+        }
+        let below = variances[nv - 1] - variances[nv - 2];
+        let diffusion = xi * xi * variances[nv - 1] * dt / (below * below);
+        v_lower[nv - 1] = diffusion;
+        v_diag[nv - 1] = -diffusion;
+
+        HestonOperator { nx: nx, nv: nv, x_lower: x_lower, x_diag: x_diag,
+            x_upper: x_upper, v_lower: v_lower, v_diag: v_diag, v_upper: v_upper,
+            mixed: mixed }
+    }
+
+    fn apply_mixed(&self, u: &[f64]) -> Vec<f64> {
+        let (nx, nv) = (self.nx, self.nv);
+        let mut result = vec![0.0; nx * nv];
+        for j in 1..nv - 1 {
+            for i in 1..nx - 1 {
+                let mut total = 0.0;
+                for (q, weight) in self.mixed[j].iter().enumerate() {
+                    let row = (j + q - 1) * nx;
+                    total += weight * (u[row + i + 1] - u[row + i - 1]);
+                }
+                result[j * nx + i] = total;
+            }
+        }
+        result
+    }
+
+    fn apply_x(&self, u: &[f64]) -> Vec<f64> {
+        let nx = self.nx;
+        let mut result = vec![0.0; u.len()];
+        for k in 0..u.len() {
+            let i = k % nx;
+            let mut total = self.x_diag[k] * u[k];
+            if i > 0 {
+                total += self.x_lower[k] * u[k - 1];
+            }
+            if i < nx - 1 {
+                total += self.x_upper[k] * u[k + 1];
+            }
+            result[k] = total;
+        }
+        result
+    }
+
+    fn apply_v(&self, u: &[f64]) -> Vec<f64> {
+        let (nx, nv) = (self.nx, self.nv);
+        let mut result = vec![0.0; u.len()];
+        for j in 0..nv {
+            for i in 1..nx - 1 {
+                let k = j * nx + i;
+                let mut total = self.v_diag[j] * u[k];
+                if j > 0 {
+                    total += self.v_lower[j] * u[k - nx];
+                }
+                if j < nv - 1 {
+                    total += self.v_upper[j] * u[k + nx];
+                }
+                result[k] = total;
+            }
+        }
+        result
+    }
+
+    /// Solves (I - theta A1) y = rhs, row by row
+    fn solve_x(&self, rhs: &[f64], theta: f64) -> Result<Vec<f64>, qm::Error> {
+        let nx = self.nx;
+        let mut result = Vec::with_capacity(rhs.len());
+        for (j, row) in rhs.chunks(nx).enumerate() {
+            let range = j * nx..(j + 1) * nx;
+            let lower: Vec<f64> = self.x_lower[range.clone()].iter().map(|a| -theta * a).collect();
+            let diag: Vec<f64> = self.x_diag[range.clone()].iter().map(|a| 1.0 - theta * a).collect();
+            let upper: Vec<f64> = self.x_upper[range].iter().map(|a| -theta * a).collect();
+            result.extend(solve_tridiagonal(&lower, &diag, &upper, row)?);
+        }
+        Ok(result)
+    }
+
+    /// Solves (I - theta A2) y = rhs, column by column. A2 is zero at the
+    /// ends of the x grid, so those columns are unchanged.
+    fn solve_v(&self, rhs: &[f64], theta: f64) -> Result<Vec<f64>, qm::Error> {
+        let (nx, nv) = (self.nx, self.nv);
+        let lower: Vec<f64> = self.v_lower.iter().map(|a| -theta * a).collect();
+        let diag: Vec<f64> = self.v_diag.iter().map(|a| 1.0 - theta * a).collect();
+        let upper: Vec<f64> = self.v_upper.iter().map(|a| -theta * a).collect();
+        let mut result = rhs.to_vec();
+        let mut column = vec![0.0; nv];
+        for i in 1..nx - 1 {
+            for j in 0..nv {
+                column[j] = rhs[j * nx + i];
+            }
+            let solved = solve_tridiagonal(&lower, &diag, &upper, &column)?;
+            for j in 0..nv {
+                result[j * nx + i] = solved[j];
+            }
+        }
+        Ok(result)
+    }
+
+    /// The whole operator, applied to the values
+    fn apply(&self, u: &[f64]) -> Vec<f64> {
+        let mut result = self.apply_mixed(u);
+        for (r, (a, b)) in result.iter_mut().zip(self.apply_x(u).iter()
+            .zip(self.apply_v(u).iter())) {
+            *r += a + b;
+        }
+        result
+    }
+
+    /// The implicit corrections in each direction in turn, starting from
+    /// the explicit estimate y0 and correcting against the values u
+    fn corrections(&self, y0: Vec<f64>, u: &[f64], theta: f64)
+        -> Result<Vec<f64>, qm::Error> {
+
+        let mut rhs = y0;
+        for (r, a) in rhs.iter_mut().zip(self.apply_x(u).iter()) {
+            *r -= theta * a;
+        }
+        let y1 = self.solve_x(&rhs, theta)?;
+        let mut rhs = y1;
+        for (r, a) in rhs.iter_mut().zip(self.apply_v(u).iter()) {
+            *r -= theta * a;
+        }
+        self.solve_v(&rhs, theta)
+    }
+
+    /// The Douglas scheme, which is first order unless theta is one half,
+    /// and with theta of one damps discontinuities
+    fn douglas(&self, u: &[f64], theta: f64) -> Result<Vec<f64>, qm::Error> {
+        self.corrections(add(u, &self.apply(u), 1.0), u, theta)
+    }
+
+    /// The Craig-Sneyd scheme, which corrects the explicit mixed term by
+    /// sigma, and the modified scheme, which also corrects the whole
+    /// operator by one half less theta
+    fn craig_sneyd(&self, u: &[f64], theta: f64, sigma: f64)
+        -> Result<Vec<f64>, qm::Error> {
+
+        let y0 = add(u, &self.apply(u), 1.0);
+        let y2 = self.corrections(y0.clone(), u, theta)?;
+        let mixed_change = sub(&self.apply_mixed(&y2), &self.apply_mixed(u));
+        let mut corrected = add(&y0, &mixed_change, sigma);
+        if theta != 0.5 {
+            let change = sub(&self.apply(&y2), &self.apply(u));
+            corrected = add(&corrected, &change, 0.5 - theta);
+        }
+        self.corrections(corrected, u, theta)
+    }
+
+    /// The Hundsdorfer-Verwer scheme
+    fn hundsdorfer_verwer(&self, u: &[f64], theta: f64) -> Result<Vec<f64>, qm::Error> {
+        let y0 = add(u, &self.apply(u), 1.0);
+        let y2 = self.corrections(y0.clone(), u, theta)?;
+        let change = sub(&self.apply(&y2), &self.apply(u));
+        let corrected = add(&y0, &change, 0.5);
+        self.corrections(corrected, &y2, theta)
+    }
+}
+
+fn add(a: &[f64], b: &[f64], scale: f64) -> Vec<f64> {
+    a.iter().zip(b.iter()).map(|(a, b)| a + scale * b).collect()
+}
+
+fn sub(a: &[f64], b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b.iter()).map(|(a, b)| a - b).collect()
+}
@@ -0,0 +1,288 @@
+use core::qm;
+use std::sync::Arc;
+use std::collections::HashMap;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use risk::cache::PricingContextPrefetch;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::dependencies::DependencyCollector;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::BumpablePricingContext;
+use pricers::PricerFactory;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use risk::marketdata::MarketData;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The models under which instruments may have closed-form prices.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AnalyticModel {
+    /// Log-normal underlyings, using the vol surfaces of the market data at
+    /// the strike of each option, so this is Black76, or Black-Scholes in
+    /// terms of the forward. Barriers, digitals and quantos all have closed
+    /// forms under this model.
+    Black,
+
+    /// Normal underlyings, with a constant normal vol for each underlying,
+    /// keyed by its id, as the vol surfaces in the market data are
+    /// log-normal. Time is measured in the vol time of the underlying, as
+    /// it is by the Bachelier Monte-Carlo model. Only vanillas have closed
+    /// forms under this model.
+    Bachelier(HashMap<String, f64>)
+}
+
+impl AnalyticModel {
+    /// The normal vol of the given underlying, for the Bachelier model
+    pub fn normal_vol(&self, id: &str) -> Result<f64, qm::Error> {
+        match *self {
+            AnalyticModel::Black => Err(qm::Error::new(
+                "The Black model has no normal vols")),
+            AnalyticModel::Bachelier(ref vols) => vols.get(id).cloned()
+                .ok_or_else(|| qm::Error::new(&format!(
+                    "No normal vol supplied for {}", id)))
+        }
+    }
+}
+
+/// The AnalyticPricer values instruments by their closed forms under a
+/// given model, using the AnalyticPriceable interface of each instrument.
+/// Like the SelfPricer, it exposes this as a Pricer, allowing bumping for
+/// risk calculation. It is an error to price an instrument that has no
+/// closed form under the model.
+#[derive(Clone)]
+pub struct AnalyticPricer {
+    model: AnalyticModel,
+    instruments: Vec<(f64, RcInstrument)>,
+    context: PricingContextPrefetch
+}
+
+/// The AnalyticPricerFactory is used to construct AnalyticPricer pricers.
+/// It holds the model to value the instruments under.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnalyticPricerFactory {
+    model: AnalyticModel
+}
+
+impl AnalyticPricerFactory {
+    pub fn new(model: AnalyticModel) -> AnalyticPricerFactory {
+        AnalyticPricerFactory { model: model }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(AnalyticPricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for AnalyticPricerFactory {
+    fn type_id(&self) -> &'static str { "AnalyticPricerFactory" }
+}
+
+impl PricerFactory for AnalyticPricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // Apply the fixings to the instrument. (This is the last time we need
+        // the fixings.)
+        let instruments = match instrument.fix(&*fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        let pricer = AnalyticPricer::new(instruments, self.model.clone(),
+            &*market_data)?;
+        Ok(Box::new(pricer))
+    }
+}
+
+impl AnalyticPricer {
+    pub fn new(instruments: Vec<(f64, RcInstrument)>, model: AnalyticModel,
+        market_data: &MarketData) -> Result<AnalyticPricer, qm::Error> {
+
+        // Find the dependencies of the resulting vector of instruments
+        // also validate that all instruments are analytically priceable
+        let mut dependencies = DependencyCollector::new(
+            market_data.spot_date());
+        for &(_, ref instr) in instruments.iter() {
+            dependencies.spot(instr);
+            if instr.as_analytic().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} has no \
+                    closed-form price", instr.id())))
+            }
+        }
+
+        // Create a cached pricing context, prefetching the data to price them
+        let context = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        Ok(AnalyticPricer { model: model, instruments: instruments,
+            context: context })
+    }
+}
+
+impl Pricer for AnalyticPricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        // value as of the spot date at the open, as the SelfPricer does
+        let context = self.context.as_pricing_context();
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+
+        // Note that we have already verified that all components are
+        // analytic priceable, so here we simply skip any that are not.
+        let mut total = 0.0;
+        for &(weight, ref instrument) in self.instruments.iter() {
+            if let Some(analytic) = instrument.as_analytic() {
+                let price = analytic.analytic_price(context, &self.model, val_date)?
+                    .ok_or_else(|| qm::Error::new(&format!("Instrument {} has \
+                        no closed-form price under {:?}", instrument.id(), self.model)))?;
+                total += weight * price;
+            }
+        }
+        Ok(total)
+    }
+}
+
+impl PricerClone for AnalyticPricer {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for AnalyticPricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        self.context.bump(bump, save)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        self.context.new_saveable()
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        self.context.restore(saved)
+    }
+}
+
+impl TimeBumpable for AnalyticPricer {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        if bump.apply(&mut self.instruments, &mut self.context)? {
+            // if the instruments have changed, we need to rebuild the pricer
+            *self = AnalyticPricer::new(self.instruments.clone(), self.model.clone(),
+                self.context.raw_market_data())?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use dates::Date;
+    use math::numerics::approx_eq;
+    use data::bumpspot::BumpSpot;
+    use data::fixings::FixingTable;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use models::RcMonteCarloModelFactory;
+    use models::bachelier::BachelierFactory;
+    use models::PathGeneration;
+    use pricers::RcPricerFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use core::factories::Qrc;
+    use serde_json;
+
+    fn sample_fixings() -> RcFixingTable {
+        RcFixingTable::new(Arc::new(FixingTable::new(Date::from_ymd(2017, 01, 02))))
+    }
+
+    fn normal_vols() -> HashMap<String, f64> {
+        let mut vols = HashMap::new();
+        vols.insert("BP.L".to_string(), 30.0);
+        vols
+    }
+
+    #[test]
+    fn black_european_matches_self_price() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let factory = AnalyticPricerFactory::new(AnalyticModel::Black);
+        let mut pricer = factory.new(instrument, sample_fixings(), market_data).unwrap();
+        assert_approx(pricer.price().unwrap(), 16.710717400832973, 1e-12);
+
+        // bumps go through the prefetched context, as for the self pricer
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert_approx(pricer.price().unwrap(), 17.343905306334765, 1e-12);
+    }
+
+    #[test]
+    fn bachelier_european_matches_monte_carlo() {
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model = AnalyticModel::Bachelier(normal_vols());
+        let pricer = AnalyticPricer::new(vec![(1.0, instrument.clone())],
+            model, &market_data).unwrap();
+        let analytic = pricer.price().unwrap();
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BachelierFactory::new(normal_vols(), 65535)));
+        let mc_pricer = MonteCarloPricer::with_settings(vec![(1.0, instrument)],
+            model_factory, MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let mc = mc_pricer.price().unwrap();
+        assert!(analytic > 10.0);
+        assert_approx(analytic, mc, 0.05);
+    }
+
+    #[test]
+    fn bachelier_needs_normal_vol() {
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model = AnalyticModel::Bachelier(HashMap::new());
+        let pricer = AnalyticPricer::new(vec![(1.0, instrument)], model,
+            &market_data).unwrap();
+        assert!(pricer.price().is_err());
+    }
+
+    #[test]
+    fn analytic_pricer_tagged_serde() {
+        let factory = RcPricerFactory::new(Arc::new(
+            AnalyticPricerFactory::new(AnalyticModel::Bachelier(normal_vols()))));
+        let serialized = serde_json::to_string_pretty(&factory).unwrap();
+        let deserialized: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let expected = factory.new(instrument.clone(), sample_fixings(),
+            market_data.clone()).unwrap().price().unwrap();
+        let price = deserialized.new(instrument, sample_fixings(),
+            market_data).unwrap().price().unwrap();
+        assert_approx(price, expected, 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
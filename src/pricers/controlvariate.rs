@@ -0,0 +1,555 @@
+use core::qm;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use instruments::RcInstrument;
+use instruments::MonteCarloContext;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::PricingContext;
+use models::MonteCarloTimeline;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use dates::datetime::DateDayFraction;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::ArrayView2;
+use ndarray::Axis;
+use ndarray::Slice;
+
+/// The columns of the paths and of the flows that belong to one of several
+/// instruments valued on the same timeline. Each instrument appends its
+/// observations and flows to the timeline in turn, so its columns are a
+/// contiguous range of each.
+#[derive(Clone, Debug)]
+pub struct Columns {
+    observations: HashMap<RcInstrument, (usize, usize)>,
+    flows: (usize, usize),
+    shared: bool
+}
+
+impl Columns {
+    /// Registers the dependencies of an instrument by the given function,
+    /// and records the columns they were given.
+    pub fn register<F>(timeline: &mut MonteCarloTimeline, register: F)
+        -> Result<Columns, qm::Error>
+        where F: FnOnce(&mut MonteCarloTimeline) -> Result<(), qm::Error> {
+
+        let (before, flows_before) = timeline.registered();
+        register(timeline)?;
+        let (after, flows_after) = timeline.registered();
+
+        let observations = after.into_iter().filter_map(|(instrument, end)| {
+            let start = before.get(&instrument).cloned().unwrap_or(0);
+            if end > start { Some((instrument, (start, end))) } else { None }
+        }).collect();
+        Ok(Columns { observations: observations, flows: (flows_before, flows_after),
+            shared: false })
+    }
+
+    /// Registers the dependencies of an instrument, as register, except that
+    /// an underlying the instrument observes no later than it is already
+    /// observed is not observed again. The instrument shares the columns
+    /// already on the timeline, which must hold all of its observations of
+    /// the underlying, as a contiguous run in the same order. This lets an
+    /// instrument be valued on the paths of another whose dates interleave
+    /// with its own, such as the digitals that replicate the coupons of an
+    /// autocallable. The flows are always registered afresh.
+    pub fn share<F>(timeline: &mut MonteCarloTimeline, register: F)
+        -> Result<Columns, qm::Error>
+        where F: FnOnce(&mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        let mut recorded = RecordedDependencies::new();
+        register(&mut recorded)?;
+
+        // group the observations by underlying, keeping their order
+        let mut underlyings: Vec<(RcInstrument, Vec<DateDayFraction>)> = Vec::new();
+        for &(ref instrument, date_time) in recorded.observations.iter() {
+            match underlyings.iter().position(|u| u.0 == *instrument) {
+                Some(i) => underlyings[i].1.push(date_time),
+                None => underlyings.push((instrument.clone(), vec![date_time]))
+            }
+        }
+
+        let mut observations = HashMap::new();
+        let mut shared = false;
+        for (instrument, dates) in underlyings.into_iter() {
+            let existing = timeline.registered_observations(&instrument);
+            let start = existing.len();
+            if existing.last().map_or(true, |last| *last < dates[0]) {
+                for date_time in dates.iter() {
+                    timeline.observation(&instrument, *date_time);
+                }
+                observations.insert(instrument, (start, start + dates.len()));
+                continue;
+            }
+
+            let first = existing.iter().position(|obs| *obs == dates[0]);
+            let columns = match first {
+                Some(first) if existing[first..].starts_with(&dates) =>
+                    (first, first + dates.len()),
+                _ => return Err(qm::Error::new(&format!("The observations of \
+                    '{}' cannot share the paths already on the timeline",
+                    instrument.id())))
+            };
+            observations.insert(instrument, columns);
+            shared = true;
+        }
+
+        let flows_before = timeline.registered().1;
+        for flow in recorded.flows.iter() {
+            timeline.flow(flow);
+        }
+        for &(ref instrument, ref fx_id) in recorded.quantos.iter() {
+            timeline.quanto(instrument, fx_id);
+        }
+        for &(ref instrument, date_time, level) in recorded.importance_levels.iter() {
+            timeline.importance_level(instrument, date_time, level);
+        }
+        let flows_after = timeline.registered().1;
+        Ok(Columns { observations: observations, flows: (flows_before, flows_after),
+            shared: shared })
+    }
+
+    /// Whether any of the columns of the paths are shared with another
+    /// instrument
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// The range of columns of the paths of an underlying
+    pub fn observations(&self, instrument: &RcInstrument) -> Option<(usize, usize)> {
+        self.observations.get(instrument).cloned()
+    }
+
+    /// The range of columns of the flows
+    pub fn flows(&self) -> (usize, usize) {
+        self.flows
+    }
+}
+
+/// The dependencies of an instrument, recorded in the order they are given,
+/// so they can be checked before they are registered on a timeline
+struct RecordedDependencies {
+    observations: Vec<(RcInstrument, DateDayFraction)>,
+    flows: Vec<RcInstrument>,
+    quantos: Vec<(RcInstrument, String)>,
+    importance_levels: Vec<(RcInstrument, DateDayFraction, f64)>
+}
+
+impl RecordedDependencies {
+    fn new() -> RecordedDependencies {
+        RecordedDependencies { observations: Vec::new(), flows: Vec::new(),
+            quantos: Vec::new(), importance_levels: Vec::new() }
+    }
+}
+
+impl MonteCarloDependencies for RecordedDependencies {
+    fn observation(&mut self, instrument: &RcInstrument, date_time: DateDayFraction) {
+        self.observations.push((instrument.clone(), date_time));
+    }
+
+    fn flow(&mut self, instrument: &RcInstrument) {
+        self.flows.push(instrument.clone());
+    }
+
+    fn quanto(&mut self, instrument: &RcInstrument, fx_id: &str) {
+        self.quantos.push((instrument.clone(), fx_id.to_string()));
+    }
+
+    fn importance_level(&mut self, instrument: &RcInstrument,
+        date_time: DateDayFraction, level: f64) {
+        self.importance_levels.push((instrument.clone(), date_time, level));
+    }
+}
+
+/// A view of a Monte-Carlo context that presents one instrument with only
+/// its own columns of the paths and flows, so that several instruments can
+/// share the paths of one model. The view also records the value of each
+/// path, whenever the instrument evaluates its flows.
+pub struct ColumnView<'a> {
+    context: &'a MonteCarloContext,
+    columns: &'a Columns,
+    n_flows: usize,
+    values: RefCell<Option<Array1<f64>>>
+}
+
+impl<'a> ColumnView<'a> {
+    /// Creates a view of the given columns of a context with the given
+    /// total number of flows.
+    pub fn new(context: &'a MonteCarloContext, columns: &'a Columns,
+        n_flows: usize) -> ColumnView<'a> {
+        ColumnView { context: context, columns: columns, n_flows: n_flows,
+            values: RefCell::new(None) }
+    }
+
+    /// The value of each path, summed over every evaluation of the flows
+    /// since the last call, or None if the flows were not evaluated
+    pub fn take_values(&self) -> Option<Array1<f64>> {
+        self.values.borrow_mut().take()
+    }
+}
+
+impl<'a> MonteCarloContext for ColumnView<'a> {
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let (start, end) = self.columns.observations(instrument)
+            .ok_or_else(|| qm::Error::new(&format!("There are no observations \
+            of '{}' for this instrument", instrument.id())))?;
+        let mut paths = self.context.paths(instrument)?;
+        paths.slice_axis_inplace(Axis(1), Slice::from(start..end));
+        Ok(paths)
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+
+        let values = self.evaluate_path_flows(quantities)?;
+        let value = values.scalar_sum() / values.len() as f64;
+        let mut recorded = self.values.borrow_mut();
+        match *recorded {
+            Some(ref mut total) => *total += &values,
+            None => *recorded = Some(values)
+        }
+        Ok(value)
+    }
+
+    fn evaluate_path_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<Array1<f64>, qm::Error> {
+
+        let (start, end) = self.columns.flows;
+        let n_paths = quantities.shape()[0];
+        if quantities.shape()[1] != end - start {
+            return Err(qm::Error::new(&format!("Instrument registered {} flows \
+                but evaluated {}", end - start, quantities.shape()[1])))
+        }
+        let mut all = Array2::<f64>::zeros((n_paths, self.n_flows));
+        all.slice_axis_mut(Axis(1), Slice::from(start..end)).assign(&quantities);
+        self.context.evaluate_path_flows(all.view())
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        self.context.pricing_context()
+    }
+
+    fn threads(&self) -> usize {
+        self.context.threads()
+    }
+
+    /// The default steps count observations from the start of the paths,
+    /// so they are only meaningful to the instrument whose observations
+    /// come first.
+    fn default_steps(&self, instrument: &RcInstrument)
+        -> Result<Option<&[usize]>, qm::Error> {
+
+        let steps = self.context.default_steps(instrument)?;
+        match self.columns.observations.get(instrument) {
+            Some(&(start, _)) if start > 0 && steps.is_some() =>
+                Err(qm::Error::new(&format!("Defaults of '{}' cannot be \
+                    seen by a second instrument on the same paths", instrument.id()))),
+            _ => Ok(steps)
+        }
+    }
+
+    fn dividends(&self, instrument: &RcInstrument)
+        -> Result<Option<(&[Date], ArrayView2<f64>)>, qm::Error> {
+        self.context.dividends(instrument)
+    }
+}
+
+/// The result of valuing an instrument with a control variate. The
+/// controlled price is the simulated price of the instrument, less the
+/// coefficient times the error in the simulated price of the proxy. The
+/// coefficient minimises the variance of the controlled price, which is
+/// the variance of the uncontrolled price times one minus the square of
+/// the correlation.
+#[derive(Clone, Debug)]
+pub struct ControlVariateEstimate {
+    instrument_id: String,
+    coefficient: f64,
+    correlation: f64,
+    uncontrolled: f64,
+    proxy_simulated: f64,
+    proxy_analytic: f64
+}
+
+impl ControlVariateEstimate {
+    pub fn instrument_id(&self) -> &str { &self.instrument_id }
+    pub fn coefficient(&self) -> f64 { self.coefficient }
+    pub fn correlation(&self) -> f64 { self.correlation }
+    pub fn uncontrolled(&self) -> f64 { self.uncontrolled }
+    pub fn proxy_simulated(&self) -> f64 { self.proxy_simulated }
+    pub fn proxy_analytic(&self) -> f64 { self.proxy_analytic }
+
+    pub fn controlled(&self) -> f64 {
+        self.uncontrolled - self.coefficient
+            * (self.proxy_simulated - self.proxy_analytic)
+    }
+}
+
+/// The proxy of an instrument, valued on the same paths as a control
+/// variate. The coefficient may be frozen, so that bumped valuations use
+/// the coefficient of the unbumped valuation, rather than adding the noise
+/// of a fresh regression to the risks.
+#[derive(Clone)]
+pub struct ControlVariate {
+    proxy: RcInstrument,
+    columns: Columns,
+    frozen: Option<f64>
+}
+
+impl ControlVariate {
+    /// Finds the proxy of an instrument and registers its dependencies.
+    /// Returns None if the instrument has no proxy.
+    pub fn new(instrument: &MonteCarloPriceable, timeline: &mut MonteCarloTimeline)
+        -> Result<Option<ControlVariate>, qm::Error> {
+
+        let proxy = match instrument.control_variate()? {
+            Some(proxy) => proxy,
+            None => return Ok(None)
+        };
+        if proxy.as_priceable().is_none() {
+            return Err(qm::Error::new(&format!("Control variate {} is not \
+                priceable analytically", proxy.id())))
+        }
+        let columns = {
+            let mc = proxy.as_mc_priceable().ok_or_else(|| qm::Error::new(
+                &format!("Control variate {} is not priceable by MonteCarlo",
+                proxy.id())))?;
+            Columns::register(timeline, |t| mc.mc_dependencies(&[], t))?
+        };
+        Ok(Some(ControlVariate { proxy: proxy, columns: columns, frozen: None }))
+    }
+
+    pub fn proxy(&self) -> &RcInstrument { &self.proxy }
+
+    /// Values the instrument through the given view, and the proxy on the
+    /// same paths, and combines them.
+    pub fn estimate(&self, instrument: &MonteCarloPriceable, view: &ColumnView,
+        context: &MonteCarloContext, n_flows: usize)
+        -> Result<ControlVariateEstimate, qm::Error> {
+        Ok(self.estimate_paths(instrument, view, context, n_flows)?.0)
+    }
+
+    /// As estimate, but also returns the controlled value of each path,
+    /// whose mean is the controlled price
+    pub fn estimate_paths(&self, instrument: &MonteCarloPriceable, view: &ColumnView,
+        context: &MonteCarloContext, n_flows: usize)
+        -> Result<(ControlVariateEstimate, Array1<f64>), qm::Error> {
+
+        let id = instrument.as_instrument().id();
+        view.take_values();
+        instrument.mc_price(view)?;
+        let y = view.take_values().ok_or_else(|| qm::Error::new(&format!(
+            "Instrument {} did not value its flows, so has no path values \
+            to control", id)))?;
+
+        let proxy_view = ColumnView::new(context, &self.columns, n_flows);
+        let mc = self.proxy.as_mc_priceable().unwrap();
+        mc.mc_price(&proxy_view)?;
+        let x = proxy_view.take_values().ok_or_else(|| qm::Error::new(&format!(
+            "Control variate {} did not value its flows", self.proxy.id())))?;
+
+        if x.len() != y.len() || x.len() < 2 {
+            return Err(qm::Error::new("Control variates need at least two paths"))
+        }
+
+        // value analytically at the same time as the flows
+        let val_date = DateTime::new(context.pricing_context().spot_date(),
+            TimeOfDay::Open);
+        let proxy_analytic = self.proxy.as_priceable().unwrap()
+            .price(context.pricing_context(), val_date)?;
+
+        let n = x.len() as f64;
+        let mean_x = x.scalar_sum() / n;
+        let mean_y = y.scalar_sum() / n;
+        let mut covariance = 0.0;
+        let mut variance_x = 0.0;
+        let mut variance_y = 0.0;
+        for (xi, yi) in x.iter().zip(y.iter()) {
+            covariance += (xi - mean_x) * (yi - mean_y);
+            variance_x += (xi - mean_x) * (xi - mean_x);
+            variance_y += (yi - mean_y) * (yi - mean_y);
+        }
+        let coefficient = match self.frozen {
+            Some(coefficient) => coefficient,
+            None => if variance_x > 0.0 { covariance / variance_x } else { 0.0 }
+        };
+        let correlation = if variance_x > 0.0 && variance_y > 0.0 {
+            covariance / (variance_x * variance_y).sqrt()
+        } else {
+            0.0
+        };
+
+        let mut controlled = y;
+        controlled.scaled_add(-coefficient, &x);
+        controlled += coefficient * proxy_analytic;
+
+        Ok((ControlVariateEstimate { instrument_id: id.to_string(),
+            coefficient: coefficient, correlation: correlation,
+            uncontrolled: mean_y, proxy_simulated: mean_x,
+            proxy_analytic: proxy_analytic }, controlled))
+    }
+
+    /// Freezes the coefficient of the given estimate, or leaves it alone if
+    /// it is already frozen.
+    pub fn freeze(&mut self, estimate: &ControlVariateEstimate) {
+        if self.frozen.is_none() {
+            self.frozen = Some(estimate.coefficient());
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool { self.frozen.is_some() }
+
+    /// Forgets any frozen coefficient
+    pub fn unfreeze(&mut self) {
+        self.frozen = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use math::numerics::approx_eq;
+    use instruments::asians::AsianOption;
+    use instruments::asians::AveragingSchedule;
+    use instruments::options::PutOrCall;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::Pricer;
+    use risk::Bumpable;
+    use data::bump::Bump;
+    use data::bumpspot::BumpSpot;
+    use data::fixings::RcFixingTable;
+    use data::fixings::FixingTable;
+    use risk::marketdata::RcMarketData;
+    use models::RcMonteCarloModelFactory;
+    use models::Threading;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::PricerFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloPricerFactory;
+    use pricers::montecarlo::MonteCarloSettings;
+    use core::factories::Qrc;
+    use serde_json;
+
+    fn sample_asian(dates: &[DateTime]) -> RcInstrument {
+        let equity = sample_underlying();
+        let average_out = AveragingSchedule::new(dates).unwrap();
+        RcInstrument::new(Qrc::new(Arc::new(AsianOption::new("SampleAsian", "OPT",
+            equity, sample_settlement(2), 100.0, PutOrCall::Call, None,
+            average_out, *dates.last().unwrap()).unwrap())))
+    }
+
+    fn monthly_dates() -> Vec<DateTime> {
+        let mut dates: Vec<DateTime> = (0..6).map(|i| DateTime::new(
+            Date::from_ymd(2017, 12, 04) + 30 * i, TimeOfDay::Close)).collect();
+        dates.push(sample_expiry());
+        dates
+    }
+
+    fn sample_pricer(instrument: RcInstrument, n_paths: usize,
+        control_variates: bool) -> MonteCarloPricer {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, n_paths)));
+        MonteCarloPricer::with_settings(vec![(1.0, instrument)], model_factory,
+            MonteCarloSettings { control_variates: control_variates,
+                threading: Threading::new(1, Some(42)), ..MonteCarloSettings::default() },
+            &sample_market_data()).unwrap()
+    }
+
+    #[test]
+    fn single_date_asian_is_controlled_exactly() {
+        // averaging only at expiry, the Asian is its own proxy, so the
+        // coefficient is one and the price is the analytic European
+        let expiry = sample_expiry();
+        let pricer = sample_pricer(sample_asian(&[expiry]), 1000, true);
+        let estimates = pricer.control_variate_estimates().unwrap();
+        assert_eq!(estimates.len(), 1);
+        assert_eq!(estimates[0].instrument_id(), "SampleAsian");
+        assert!(approx_eq(estimates[0].coefficient(), 1.0, 1e-9));
+        assert!(approx_eq(estimates[0].correlation(), 1.0, 1e-9));
+        assert!(approx_eq(estimates[0].proxy_analytic(), 16.710717400832973, 1e-9));
+        assert!(approx_eq(pricer.price().unwrap(), 16.710717400832973, 1e-9));
+    }
+
+    #[test]
+    fn asian_control_variate_reduces_noise() {
+        let asian = sample_asian(&monthly_dates());
+        let reference = sample_pricer(asian.clone(), 200000, false).price().unwrap();
+
+        // the European explains most of the variance of the average, so a
+        // tenth of the paths are about as good as the reference. The
+        // analytic proxy also takes out part of the Euler discretisation
+        // bias, so the two differ by a few hundredths even without noise.
+        let pricer = sample_pricer(asian.clone(), 20000, true);
+        let estimate = pricer.control_variate_estimates().unwrap()[0].clone();
+        assert!(estimate.correlation() > 0.8, "correlation={}", estimate.correlation());
+        assert!(estimate.coefficient() > 0.3 && estimate.coefficient() < 1.0,
+            "coefficient={}", estimate.coefficient());
+        let price = pricer.price().unwrap();
+        assert!(approx_eq(price, estimate.controlled(), 1e-12));
+
+        // with the same seed and number of paths, the control variate cuts
+        // the standard error by the factor sqrt(1 - rho^2) that the
+        // correlation predicts
+        let uncontrolled = sample_pricer(asian, 20000, false);
+        let controlled_error = pricer.price_with_statistics(1).unwrap().unwrap()
+            .standard_error();
+        let uncontrolled_error = uncontrolled.price_with_statistics(1).unwrap().unwrap()
+            .standard_error();
+        let predicted = uncontrolled_error
+            * (1.0 - estimate.correlation() * estimate.correlation()).sqrt();
+        assert!(controlled_error < 0.5 * uncontrolled_error,
+            "controlled={} uncontrolled={}", controlled_error, uncontrolled_error);
+        assert!(approx_eq(controlled_error, predicted, 0.1 * predicted),
+            "controlled={} predicted={}", controlled_error, predicted);
+
+        // the standard errors of the reference and the controlled price are
+        // both about 0.05
+        assert!(approx_eq(price, reference, 0.2),
+            "price={} reference={} uncontrolled={}", price, reference,
+            estimate.uncontrolled());
+    }
+
+    #[test]
+    fn coefficient_is_frozen_when_bumped() {
+        let mut pricer = sample_pricer(sample_asian(&monthly_dates()), 5000, true);
+        let unbumped = pricer.price().unwrap();
+        let coefficient = pricer.control_variate_estimates().unwrap()[0].coefficient();
+
+        let mut save = pricer.new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.control_variate_estimates().unwrap()[0].clone();
+        assert_eq!(bumped.coefficient(), coefficient);
+        let delta = pricer.price().unwrap() - unbumped;
+        assert!(delta > 0.4 && delta < 0.8, "delta={}", delta);
+
+        pricer.restore(&*save).unwrap();
+        assert!(approx_eq(pricer.price().unwrap(), unbumped, 1e-12));
+    }
+
+    #[test]
+    fn control_variates_are_configurable() {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 1000)));
+        let factory = MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings { control_variates: true,
+                ..MonteCarloSettings::default() });
+        let serialized = serde_json::to_string(&factory).unwrap();
+        assert!(serialized.contains("\"control_variates\":true"));
+        let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized).unwrap();
+
+        let expiry = sample_expiry();
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            market_data.spot_date())));
+        let pricer = factory.new(sample_asian(&[expiry]), fixings, market_data).unwrap();
+        assert!(approx_eq(pricer.price().unwrap(), 16.710717400832973, 1e-9));
+    }
+}
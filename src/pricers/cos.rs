@@ -0,0 +1,580 @@
+use core::qm;
+use std::sync::Arc;
+use std::ops::Deref;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use instruments::RcInstrument;
+use instruments::Exercisable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use risk::cache::PricingContextPrefetch;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::dependencies::DependencyCollector;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::BumpablePricingContext;
+use pricers::PricerFactory;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use risk::marketdata::MarketData;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use math::complex::Complex;
+use math::fourier::CharacteristicFunction;
+use math::fourier::LevyProcess;
+use math::fourier::cos_range;
+use models::heston::HestonParameters;
+use models::bates::BatesParameters;
+use models::variancegamma::VarianceGammaParameters;
+use models::merton::MertonParameters;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The model of a single underlying in the COS pricer, given by its
+/// parameters. Only the characteristic function of the model is used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CosModel {
+    Heston(HestonParameters),
+    Bates(BatesParameters),
+    VarianceGamma(VarianceGammaParameters),
+    Merton(MertonParameters)
+}
+
+impl CosModel {
+    fn characteristic_function(&self) -> &CharacteristicFunction {
+        match *self {
+            CosModel::Heston(ref p) => p,
+            CosModel::Bates(ref p) => p,
+            CosModel::VarianceGamma(ref p) => p,
+            CosModel::Merton(ref p) => p
+        }
+    }
+
+    /// The model as a Levy process, if it is one, which is needed to roll
+    /// back between exercise dates
+    fn levy_process(&self) -> Option<&LevyProcess> {
+        match *self {
+            CosModel::VarianceGamma(ref p) => Some(p),
+            CosModel::Merton(ref p) => Some(p),
+            CosModel::Heston(_) | CosModel::Bates(_) => None
+        }
+    }
+}
+
+/// The CosPricer values European and Bermudan options on a single
+/// underlying by the COS method of Fang and Oosterlee (2008, 2009). The
+/// value at each exercise date is expanded as a cosine series in the log of
+/// the underlying, and rolled back to the previous date by multiplying the
+/// terms by the characteristic function of the increment. It converges
+/// fast in the number of terms, and the price is smooth in the inputs,
+/// so it is a good alternative to Monte-Carlo for calibration and for
+/// validating the other pricers.
+///
+/// Underlyings with parameters in the factory use the characteristic
+/// function of those parameters, with time measured in the vol time of the
+/// underlying. Other underlyings use a log-normal model, whose variance to
+/// expiry matches the vol surface at the forward, so the smile is ignored,
+/// and which handles displacement in the same way as the lattice pricer.
+/// Bermudans need a model whose increments are independent, so they cannot
+/// be valued under Heston or Bates, and options that can be exercised at
+/// any time are not supported.
+#[derive(Clone)]
+pub struct CosPricer {
+    factory: CosPricerFactory,
+    instruments: Vec<(f64, RcInstrument)>,
+    context: PricingContextPrefetch
+}
+
+/// The CosPricerFactory is used to construct CosPricer pricers. It holds
+/// the model parameters for each underlying, keyed by the id of the
+/// underlying, and the number of terms in the cosine expansion.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CosPricerFactory {
+    models: HashMap<String, CosModel>,
+    terms: usize
+}
+
+impl CosPricerFactory {
+    pub fn new(models: HashMap<String, CosModel>, terms: usize) -> CosPricerFactory {
+        CosPricerFactory { models: models, terms: terms }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CosPricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for CosPricerFactory {
+    fn type_id(&self) -> &'static str { "CosPricerFactory" }
+}
+
+impl PricerFactory for CosPricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // Apply the fixings to the instrument. (This is the last time we need
+        // the fixings.)
+        let instruments = match instrument.fix(&*fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        let pricer = CosPricer::new(instruments, self.clone(), &*market_data)?;
+        Ok(Box::new(pricer))
+    }
+}
+
+impl CosPricer {
+    pub fn new(instruments: Vec<(f64, RcInstrument)>,
+        factory: CosPricerFactory, market_data: &MarketData)
+        -> Result<CosPricer, qm::Error> {
+
+        if factory.terms == 0 {
+            return Err(qm::Error::new("COS pricer needs at least one term"))
+        }
+
+        // Find the dependencies of the resulting vector of instruments
+        // also validate that all instruments are exercisable
+        let mut dependencies = DependencyCollector::new(
+            market_data.spot_date());
+        for &(_, ref instr) in instruments.iter() {
+            dependencies.spot(instr);
+            if instr.as_exercisable().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} is not \
+                    priceable by the COS method", instr.id())))
+            }
+        }
+
+        // Create a cached pricing context, prefetching the data to price them
+        let context = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        Ok(CosPricer { factory: factory, instruments: instruments,
+            context: context })
+    }
+}
+
+impl Pricer for CosPricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        // Note that we have already verified that all components are
+        // exercisable, so here we simply skip any that are not.
+        let context = self.context.as_pricing_context();
+        let mut total = 0.0;
+        for &(weight, ref instrument) in self.instruments.iter() {
+            if let Some(exercisable) = instrument.as_exercisable() {
+                total += weight * roll_back(exercisable, context, &self.factory)?;
+            }
+        }
+        Ok(total)
+    }
+}
+
+impl PricerClone for CosPricer {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for CosPricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        self.context.bump(bump, save)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        self.context.new_saveable()
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        self.context.restore(saved)
+    }
+}
+
+impl TimeBumpable for CosPricer {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        if bump.apply(&mut self.instruments, &mut self.context)? {
+            // if the instruments have changed, we need to rebuild the pricer
+            *self = CosPricer::new(self.instruments.clone(), self.factory.clone(),
+                self.context.raw_market_data())?
+        }
+        Ok(())
+    }
+}
+
+/// The log-normal model used for underlyings with no parameters in the
+/// factory
+struct LogNormal {
+    vol: f64
+}
+
+impl CharacteristicFunction for LogNormal {
+    fn characteristic_function(&self, u: Complex, t: f64) -> Complex {
+        let v = self.vol * self.vol * t;
+        let iu = Complex::i() * u;
+        (iu * (-0.5 * v) + iu * iu * (0.5 * v)).exp()
+    }
+}
+
+impl LevyProcess for LogNormal {
+    fn as_characteristic_function(&self) -> &CharacteristicFunction { self }
+}
+
+/// Number of grid points per term of the cosine expansion. The values at
+/// exercise have kinks, such as at the strike, which the grid resolves to
+/// second order in its spacing, so it is much finer than the expansion.
+const POINTS_PER_TERM: usize = 8;
+
+/// A regular grid of points in the log of the underlying relative to its
+/// forward, at the midpoints of equal intervals across the range of the
+/// expansion. Cosine coefficients are found from the values at the points
+/// by the midpoint rule, which is exact for the terms of the series, so
+/// going between values and coefficients loses nothing but the kinks.
+struct CosGrid {
+    a: f64,
+    width: f64,
+    terms: usize,
+    points: usize,
+    // cos and sin of 2 pi j / (4 * points), as the angle of term k at
+    // point i is 2 pi k (2 i + 1) / (4 * points)
+    cos: Vec<f64>,
+    sin: Vec<f64>
+}
+
+impl CosGrid {
+    fn new(a: f64, b: f64, terms: usize) -> CosGrid {
+        let points = POINTS_PER_TERM * terms;
+        let period = 4 * points;
+        let angle = |j: usize| 2.0 * PI * j as f64 / period as f64;
+        CosGrid { a: a, width: b - a, terms: terms, points: points,
+            cos: (0..period).map(|j| angle(j).cos()).collect(),
+            sin: (0..period).map(|j| angle(j).sin()).collect() }
+    }
+
+    /// The log of the underlying relative to its forward at a point
+    fn point(&self, i: usize) -> f64 {
+        self.a + (i as f64 + 0.5) * self.width / self.points as f64
+    }
+
+    /// The Fourier variable of a term
+    fn frequency(&self, k: usize) -> f64 {
+        k as f64 * PI / self.width
+    }
+
+    fn index(&self, k: usize, i: usize) -> usize {
+        (k * (2 * i + 1)) % self.cos.len()
+    }
+
+    /// The cosine coefficients of the values at the points
+    fn coefficients(&self, values: &[f64]) -> Vec<f64> {
+        let scale = 2.0 / self.points as f64;
+        (0..self.terms).map(|k| scale * values.iter().enumerate()
+            .map(|(i, v)| v * self.cos[self.index(k, i)]).sum::<f64>()).collect()
+    }
+
+    /// The expected values at the points, given the cosine coefficients of
+    /// the values at the end of a period, and the characteristic function
+    /// of the increment over the period at the frequency of each term
+    fn expectations(&self, coefficients: &[f64], increments: &[Complex]) -> Vec<f64> {
+        (0..self.points).map(|i| coefficients.iter().zip(increments.iter())
+            .enumerate().map(|(k, (v, phi))| {
+                let j = self.index(k, i);
+                let term = v * (phi.re * self.cos[j] - phi.im * self.sin[j]);
+                if k == 0 { 0.5 * term } else { term } })
+            .sum()).collect()
+    }
+
+    /// The expected value where the log of the underlying relative to its
+    /// forward is zero
+    fn expectation_at_forward(&self, coefficients: &[f64], increments: &[Complex]) -> f64 {
+        coefficients.iter().zip(increments.iter()).enumerate().map(|(k, (v, phi))| {
+            let shift = (Complex::i() * (-self.frequency(k) * self.a)).exp();
+            let term = v * (*phi * shift).re;
+            if k == 0 { 0.5 * term } else { term } }).sum()
+    }
+}
+
+/// Values the option as of the open on the spot date, discounted to the
+/// settlement date of the spot date.
+fn roll_back(exercisable: &Exercisable, context: &PricingContext,
+    factory: &CosPricerFactory) -> Result<f64, qm::Error> {
+
+    let spot_date = context.spot_date();
+    let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+    let expiry = exercisable.expiry();
+    if expiry < val_date {
+        return Ok(0.0)
+    }
+
+    let instrument = exercisable.as_instrument();
+    let underlying = exercisable.underlying().deref();
+    let expiry_date = expiry.date();
+    let forward_curve = context.forward_curve(underlying, expiry_date)?;
+    let vol = context.vol_surface(underlying, expiry_date,
+        &|| Ok(forward_curve.clone()))?;
+    let settlement = instrument.settlement();
+    let yc = context.yield_curve(instrument.credit_id(),
+        settlement.apply(expiry_date))?;
+
+    // the exercise dates after the val date, which end at expiry
+    let mut exercise_now = false;
+    let mut dates = vec![val_date];
+    if !exercisable.early_exercise() {
+        dates.push(expiry);
+    } else if let Some(schedule) = exercisable.exercise_schedule() {
+        exercise_now = schedule.contains(val_date);
+        dates.extend_from_slice(schedule.after(val_date));
+    } else {
+        return Err(qm::Error::new("The COS pricer cannot value options that \
+            may be exercised at any time"))
+    }
+
+    // forwards of the log-normal part of the underlying, the discount
+    // factor from the base date of the yield curve to the settlement date of
+    // exercise, and the vol time since the val date, at each date
+    let start = vol.vol_time(underlying.time_to_day_fraction(val_date)?)?;
+    let mut forwards = Vec::with_capacity(dates.len());
+    let mut displacements = Vec::with_capacity(dates.len());
+    let mut dfs = Vec::with_capacity(dates.len());
+    let mut times = Vec::with_capacity(dates.len());
+    let mut previous = 0.0_f64;
+    for date_time in dates.iter() {
+        let date = date_time.date();
+        let displacement = vol.displacement(date)?;
+        let forward = forward_curve.forward(date)? - displacement;
+        if forward < 0.0 {
+            return Err(qm::Error::new("Negative forward"))
+        }
+        forwards.push(forward);
+        displacements.push(displacement);
+        dfs.push((-yc.rt(settlement.apply(date))?).exp());
+        let time = (vol.vol_time(underlying.time_to_day_fraction(*date_time)?)?
+            - start).max(previous);
+        times.push(time);
+        previous = time;
+    }
+    let last = dates.len() - 1;
+
+    // the discounted value of exercising at the given date
+    let exercise = |m: usize, y: f64| dfs[m] * exercisable.exercise_value(
+        forwards[m] * y.exp() + displacements[m]);
+
+    if last == 0 || times[last] <= 0.0 {
+        // nothing left to roll back through, so value at the forward
+        let value = if last == 0 { exercise(0, 0.0) } else { exercise(last, 0.0) };
+        return Ok(value / dfs[0])
+    }
+
+    let log_normal;
+    let (model, levy): (&CharacteristicFunction, Option<&LevyProcess>)
+        = match factory.models.get(underlying.id()) {
+        Some(model) => {
+            if displacements.iter().any(|d| *d != 0.0) {
+                return Err(qm::Error::new("The COS pricer does not support \
+                    displaced vol surfaces with model parameters"))
+            }
+            (model.characteristic_function(), model.levy_process())
+        },
+        None => {
+            let variance = vol.forward_variance(
+                underlying.time_to_day_fraction(val_date)?,
+                underlying.time_to_day_fraction(expiry)?,
+                forwards[last] + displacements[last])?;
+            if variance < 0.0 {
+                return Err(qm::Error::new("Negative variance"))
+            }
+            log_normal = LogNormal { vol: (variance / times[last]).sqrt() };
+            (&log_normal, Some(&log_normal))
+        }
+    };
+    if last > 1 && levy.is_none() {
+        return Err(qm::Error::new("The COS pricer can only value Bermudans \
+            under models whose increments are independent"))
+    }
+
+    let (a, b) = cos_range(model, times[last])?;
+    let grid = CosGrid::new(a, b, factory.terms);
+    let increments = |from: usize, to: usize| -> Vec<Complex> {
+        let t = times[to] - times[from];
+        (0..grid.terms).map(|k| {
+            let u = Complex::from_real(grid.frequency(k));
+            match levy {
+                Some(levy) => levy.characteristic_function(u, t),
+                None => model.characteristic_function(u, t)
+            } }).collect()
+    };
+
+    // roll back through the exercise dates, working in values discounted to
+    // the base date of the yield curve
+    let mut values: Vec<f64> = (0..grid.points)
+        .map(|i| exercise(last, grid.point(i))).collect();
+    for m in (1..last).rev() {
+        let continuation = grid.expectations(&grid.coefficients(&values),
+            &increments(m, m + 1));
+        values = continuation.iter().enumerate()
+            .map(|(i, value)| value.max(exercise(m, grid.point(i)))).collect();
+    }
+    let mut value = grid.expectation_at_forward(&grid.coefficients(&values),
+        &increments(0, 1));
+    if exercise_now {
+        value = value.max(exercise(0, 0.0));
+    }
+    if !value.is_finite() {
+        return Err(qm::Error::new("COS pricing failed to converge"))
+    }
+
+    // discount to the settlement date of the val date
+    Ok(value / dfs[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::SpotStartingBermudan;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::exercise::ExerciseSchedule;
+    use models::merton::LogNormalJumps;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use pricers::RcPricerFactory;
+    use pricers::pde::PdePricer;
+    use pricers::pde::PdePricerFactory;
+    use pricers::pde::ExerciseMethod;
+    use data::fixings::FixingTable;
+    use dates::Date;
+    use serde_json;
+
+    fn sample_bermudan(strike: f64) -> RcInstrument {
+        let exercise = [DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 12, 01), TimeOfDay::Close),
+            sample_expiry()];
+        let schedule = ExerciseSchedule::new(&exercise).unwrap();
+        RcInstrument::new(Qrc::new(Arc::new(
+            SpotStartingBermudan::new("SampleBermudan", "OPT", sample_underlying(),
+            sample_settlement(2), schedule, strike, PutOrCall::Put,
+            OptionSettlement::Cash).unwrap())))
+    }
+
+    fn cos_price(instrument: RcInstrument, models: HashMap<String, CosModel>)
+        -> Result<f64, qm::Error> {
+        let market_data = sample_market_data();
+        let pricer = CosPricer::new(vec![(1.0, instrument)],
+            CosPricerFactory::new(models, 256), &market_data)?;
+        pricer.price()
+    }
+
+    /// Parameters that make each model log-normal with the vol of the
+    /// sample market data
+    fn log_normal_models() -> Vec<CosModel> {
+        let no_jumps = LogNormalJumps::new(0.0, -0.1, 0.15).unwrap();
+        vec![CosModel::Heston(HestonParameters::new(0.09, 1.0, 0.09, 1e-4, 0.0).unwrap()),
+            CosModel::Merton(MertonParameters::new(0.3, no_jumps).unwrap()),
+            CosModel::VarianceGamma(VarianceGammaParameters::new(0.3, 1e-6, 0.0).unwrap())]
+    }
+
+    fn models_for(model: CosModel) -> HashMap<String, CosModel> {
+        let mut models = HashMap::new();
+        models.insert("BP.L".to_string(), model);
+        models
+    }
+
+    #[test]
+    fn cos_european_matches_black() {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        for &(strike, put_or_call) in [(80.0, PutOrCall::Put), (100.0, PutOrCall::Call),
+            (120.0, PutOrCall::Call)].iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap();
+            let expected = european.price(&market_data, val_date).unwrap();
+            let european = RcInstrument::new(Qrc::new(Arc::new(european)));
+
+            let price = cos_price(european.clone(), HashMap::new()).unwrap();
+            assert!(approx_eq(price, expected, 1e-4),
+                "strike={} price={} expected={}", strike, price, expected);
+
+            // the parametric models agree when they reduce to log-normal,
+            // which checks that they use the same measure of time
+            for model in log_normal_models() {
+                let price = cos_price(european.clone(), models_for(model.clone())).unwrap();
+                assert!(approx_eq(price, expected, 1e-3),
+                    "model={:?} strike={} price={} expected={}",
+                    model, strike, price, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn cos_bermudan_matches_pde() {
+        let bermudan = sample_bermudan(110.0);
+        let market_data = sample_market_data();
+        let pricer = PdePricer::new(vec![(1.0, bermudan.clone())],
+            PdePricerFactory::new(400, 200, ExerciseMethod::Penalty),
+            &market_data).unwrap();
+        let expected = pricer.price().unwrap();
+
+        let price = cos_price(bermudan.clone(), HashMap::new()).unwrap();
+        assert!(approx_eq(price, expected, 0.02),
+            "price={} expected={}", price, expected);
+
+        let no_jumps = LogNormalJumps::new(0.0, -0.1, 0.15).unwrap();
+        let merton = CosModel::Merton(MertonParameters::new(0.3, no_jumps).unwrap());
+        let price = cos_price(bermudan, models_for(merton)).unwrap();
+        assert!(approx_eq(price, expected, 0.02),
+            "price={} expected={}", price, expected);
+    }
+
+    #[test]
+    fn cos_bermudan_needs_independent_increments() {
+        let heston = CosModel::Heston(HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap());
+        assert!(cos_price(sample_bermudan(110.0), models_for(heston)).is_err());
+
+        let american = RcInstrument::new(Qrc::new(Arc::new(
+            SpotStartingEuropean::new("SampleAmerican", "OPT",
+            sample_underlying(), sample_settlement(2), sample_expiry(),
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap()
+            .with_early_exercise(true))));
+        assert!(cos_price(american, HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn cos_pricer_tagged_serde() {
+        let variance_gamma = CosModel::VarianceGamma(
+            VarianceGammaParameters::new(0.25, 0.2, -0.15).unwrap());
+        let factory: RcPricerFactory = Qrc::new(Arc::new(
+            CosPricerFactory::new(models_for(variance_gamma.clone()), 256)));
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let factory: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(factory.type_id(), "CosPricerFactory");
+
+        let bermudan = sample_bermudan(110.0);
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        let pricer = factory.new(bermudan.clone(), fixings, market_data).unwrap();
+        let price = cos_price(bermudan, models_for(variance_gamma)).unwrap();
+        assert!(approx_eq(pricer.price().unwrap(), price, 1e-9));
+    }
+}
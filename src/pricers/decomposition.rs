@@ -0,0 +1,374 @@
+use core::qm;
+use std::sync::Arc;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use pricers::PricerFactory;
+use pricers::montecarlo::MonteCarloPricer;
+use pricers::montecarlo::MonteCarloPricerFactory;
+use pricers::selfpricer::SelfPricer;
+use pricers::statistics::MonteCarloResult;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::dependencies::DependencyCollector;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use data::bump::Bump;
+use data::fixings::RcFixingTable;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The DecompositionPricer splits each instrument into its static
+/// replication (see MonteCarloPriceable::static_replication), which it values
+/// analytically, and a residual, which is the instrument less the
+/// replication, valued by Monte-Carlo. For example, the coupons and put of
+/// an autocallable are digitals and a European put, which leaves only the
+/// effect of the early calls to simulate. The replication is simulated on
+/// the same paths as the instrument, so that much of the noise of both
+/// cancels in the residual, in the price and in every bumped price.
+///
+/// The price is unbiased whatever the replication, as the replication is
+/// added back at its analytic price. Its error is that of the residual,
+/// which is small when the replication is close to the instrument. The
+/// replication of an autocallable is that of a note that is never called,
+/// so it takes out less of the noise the more likely the calls are.
+///
+/// The replication is valued on the columns of the paths of the instrument
+/// (see Columns::share), so it must observe its underlyings on dates that
+/// the instrument observes.
+#[derive(Clone)]
+pub struct DecompositionPricer {
+    replication: SelfPricer,
+    residual: MonteCarloPricer
+}
+
+/// The DecompositionPricerFactory builds a DecompositionPricer for any
+/// instrument with a static replication, and a plain Monte-Carlo pricer, with
+/// the same settings, for any other.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DecompositionPricerFactory {
+    monte_carlo: MonteCarloPricerFactory
+}
+
+impl DecompositionPricerFactory {
+    /// Creates a factory that values the residuals with the given
+    /// Monte-Carlo settings
+    pub fn new(monte_carlo: MonteCarloPricerFactory) -> DecompositionPricerFactory {
+        DecompositionPricerFactory { monte_carlo: monte_carlo }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DecompositionPricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for DecompositionPricerFactory {
+    fn type_id(&self) -> &'static str { "DecompositionPricerFactory" }
+}
+
+impl PricerFactory for DecompositionPricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // streams are keyed by the instrument before fixing, as they are for
+        // the Monte-Carlo pricer
+        let id = instrument.id().to_string();
+        let instruments = match instrument.fix(&*fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        let (residual, replication) = decompose(instruments)?;
+        let residual = self.monte_carlo.pricer(&id, residual, &*market_data)?;
+        if replication.is_empty() {
+            return Ok(Box::new(residual))
+        }
+        let replication = SelfPricer::new(replication, &*market_data)?;
+        Ok(Box::new(DecompositionPricer::new(replication, residual)))
+    }
+}
+
+/// Splits weighted instruments into the residual, which holds every
+/// instrument less its static replication, and the replication. Either of
+/// them sums back to the instruments with the other.
+pub fn decompose(instruments: Vec<(f64, RcInstrument)>)
+    -> Result<(Vec<(f64, RcInstrument)>, Vec<(f64, RcInstrument)>), qm::Error> {
+
+    let mut residual = Vec::new();
+    let mut replication = Vec::new();
+    for (weight, instrument) in instruments.into_iter() {
+        let parts = match instrument.as_mc_priceable() {
+            Some(mc) => mc.static_replication()?,
+            None => None
+        };
+        residual.push((weight, instrument.clone()));
+        for (part_weight, part) in parts.into_iter().flat_map(|p| p.into_iter()) {
+            if part.as_priceable().is_none() || part.as_mc_priceable().is_none() {
+                return Err(qm::Error::new(&format!("Replication {} of {} must \
+                    be priceable both analytically and by Monte-Carlo",
+                    part.id(), instrument.id())))
+            }
+            residual.push((-weight * part_weight, part.clone()));
+            replication.push((weight * part_weight, part));
+        }
+    }
+    Ok((residual, replication))
+}
+
+impl DecompositionPricer {
+    /// Creates a pricer from the analytic pricer of the replication and the
+    /// Monte-Carlo pricer of the residual, as found by decompose
+    pub fn new(replication: SelfPricer, residual: MonteCarloPricer)
+        -> DecompositionPricer {
+        DecompositionPricer { replication: replication, residual: residual }
+    }
+
+    /// The analytic price of the static replication
+    pub fn replication_price(&self) -> Result<f64, qm::Error> {
+        self.replication.price()
+    }
+
+    /// The Monte-Carlo price of the residual
+    pub fn residual_price(&self) -> Result<f64, qm::Error> {
+        self.residual.price()
+    }
+}
+
+impl Pricer for DecompositionPricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        Ok(self.replication.price()? + self.residual.price()?)
+    }
+
+    /// The statistics are those of the residual, as the replication is
+    /// valued without error
+    fn price_with_statistics(&self, batches: usize)
+        -> Result<Option<MonteCarloResult>, qm::Error> {
+        let replication = self.replication.price()?;
+        Ok(self.residual.price_with_statistics(batches)?
+            .map(|result| result.shifted(replication)))
+    }
+}
+
+impl PricerClone for DecompositionPricer {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for DecompositionPricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+
+        let saved = match save {
+            Some(save) => Some(save.as_mut_any().downcast_mut::<SavedDecomposition>()
+                .ok_or_else(|| qm::Error::new("Mismatching save space for decomposition"))?),
+            None => None
+        };
+        Ok(match saved {
+            Some(saved) => {
+                let replication = self.replication.bump(bump,
+                    Some(&mut *saved.replication))?;
+                self.residual.bump(bump, Some(&mut *saved.residual))? || replication
+            },
+            None => {
+                let replication = self.replication.bump(bump, None)?;
+                self.residual.bump(bump, None)? || replication
+            }
+        })
+    }
+
+    /// The residual holds the replication as well as the instruments, so
+    /// its dependencies are those of the whole pricer
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.residual.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.residual.context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        Box::new(SavedDecomposition {
+            replication: self.replication.new_saveable(),
+            residual: self.residual.new_saveable() })
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        let saved = saved.as_any().downcast_ref::<SavedDecomposition>()
+            .ok_or_else(|| qm::Error::new("Mismatching save space for decomposition"))?;
+        self.replication.restore(&*saved.replication)?;
+        self.residual.restore(&*saved.residual)
+    }
+}
+
+/// The saved state of the replication and of the residual
+struct SavedDecomposition {
+    replication: Box<Saveable>,
+    residual: Box<Saveable>
+}
+
+impl Saveable for SavedDecomposition {
+    fn as_any(&self) -> &::std::any::Any { self }
+    fn as_mut_any(&mut self) -> &mut ::std::any::Any { self }
+
+    fn clear(&mut self) {
+        self.replication.clear();
+        self.residual.clear();
+    }
+}
+
+impl TimeBumpable for DecompositionPricer {
+    /// The replication and the residual fix in the same way, so they still
+    /// sum to the instruments after the bump
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        self.replication.bump_time(bump)?;
+        self.residual.bump_time(bump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::autocallables::Autocallable;
+    use instruments::autocallables::AutocallObservation;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::fixings::FixingTable;
+    use models::RcMonteCarloModelFactory;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use pricers::RcPricerFactory;
+    use serde_json;
+
+    fn sample_autocallable(autocall_barrier: f64) -> RcInstrument {
+        let equity = sample_underlying();
+        let observations: Vec<AutocallObservation> = [(2017, 07, 03),
+            (2018, 01, 02), (2018, 07, 02), (2019, 01, 02)].iter()
+            .map(|&(y, m, d)| AutocallObservation::new(DateTime::new(
+            Date::from_ymd(y, m, d), TimeOfDay::Close), Some(autocall_barrier), 0.8, 0.03))
+            .collect();
+        RcInstrument::new(Qrc::new(Arc::new(Autocallable::new("SampleAutocall",
+            "OPT", equity, sample_settlement(2), 1000.0, 100.0, &observations,
+            false, 0.7, 1.0, 0.0).unwrap())))
+    }
+
+    fn sample_fixings() -> RcFixingTable {
+        RcFixingTable::new(Arc::new(FixingTable::new(Date::from_ymd(2017, 01, 02))))
+    }
+
+    fn monte_carlo(n_paths: usize, seed: u64) -> MonteCarloPricerFactory {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, n_paths)));
+        MonteCarloPricerFactory::with_settings(model_factory, MonteCarloSettings {
+            threading: Threading::new(1, Some(seed)), ..MonteCarloSettings::default() })
+    }
+
+    #[test]
+    fn decomposition_reduces_noise_of_autocallable() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let autocall = sample_autocallable(1.1);
+        let reference = monte_carlo(200000, 1).new(autocall.clone(),
+            sample_fixings(), market_data.clone()).unwrap().price().unwrap();
+
+        let plain = monte_carlo(5000, 2).new(autocall.clone(), sample_fixings(),
+            market_data.clone()).unwrap();
+        let factory: RcPricerFactory = Qrc::new(Arc::new(
+            DecompositionPricerFactory::new(monte_carlo(5000, 2))));
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let factory: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+        let mut pricer = factory.new(autocall, sample_fixings(), market_data).unwrap();
+
+        // on the same paths, the decomposition has a fraction of the error
+        let plain_result = plain.price_with_statistics(1).unwrap().unwrap();
+        let result = pricer.price_with_statistics(1).unwrap().unwrap();
+        let price = pricer.price().unwrap();
+        assert!(approx_eq(result.price(), price, 1e-9));
+        assert!(result.standard_error() < 0.5 * plain_result.standard_error(),
+            "error={} plain={}", result.standard_error(), plain_result.standard_error());
+        assert!(approx_eq(price, reference, 4.0 * result.standard_error()),
+            "price={} reference={} error={}", price, reference, result.standard_error());
+
+        // restoring a bump gives back the price exactly
+        let mut save = pricer.new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.bump(&bump, Some(&mut *save)).unwrap());
+        assert!(pricer.price().unwrap() != price);
+        pricer.restore(&*save).unwrap();
+        assert_eq!(pricer.price().unwrap(), price);
+    }
+
+    #[test]
+    fn decomposition_reduces_noise_of_greeks() {
+        // The scatter of bumped greeks over many seeds. The replication
+        // takes the noise of the coupons and the put out of the paths that
+        // are not called, so it helps most when calls are rare, as they
+        // are with a high call barrier.
+        let scatter = |decomposed: bool, bump: &Bump| {
+            let greeks: Vec<f64> = (10..30).map(|seed| {
+                let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+                let factory = monte_carlo(2000, seed);
+                let mut pricer = if decomposed {
+                    DecompositionPricerFactory::new(factory).new(
+                        sample_autocallable(1.3), sample_fixings(), market_data).unwrap()
+                } else {
+                    factory.new(sample_autocallable(1.3), sample_fixings(),
+                        market_data).unwrap()
+                };
+                let price = pricer.price().unwrap();
+                let mut save = pricer.new_saveable();
+                assert!(pricer.bump(bump, Some(&mut *save)).unwrap());
+                pricer.price().unwrap() - price
+            }).collect();
+            let n = greeks.len() as f64;
+            let mean = greeks.iter().sum::<f64>() / n;
+            (greeks.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        };
+        for bump in [Bump::new_spot("BP.L", BumpSpot::new_relative(0.01)),
+            Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01))].iter() {
+            let plain = scatter(false, bump);
+            let decomposed = scatter(true, bump);
+            assert!(decomposed < 0.6 * plain, "decomposed={} plain={}",
+                decomposed, plain);
+        }
+    }
+
+    #[test]
+    fn replication_of_autocallable() {
+        let (residual, replication) = decompose(vec![(2.0, sample_autocallable(1.1))])
+            .unwrap();
+
+        // a digital per coupon, then the put struck at the barrier and the
+        // digital for the rest of the way to the put strike
+        let ids: Vec<&str> = replication.iter().map(|r| r.1.id()).collect();
+        assert_eq!(ids, vec!["SampleAutocall:coupon:2017-07-03",
+            "SampleAutocall:coupon:2018-01-02", "SampleAutocall:coupon:2018-07-02",
+            "SampleAutocall:coupon:2019-01-02", "SampleAutocall:put",
+            "SampleAutocall:put:digital"]);
+        assert!(approx_eq(replication[0].0, 60.0, 1e-12));
+        assert!(approx_eq(replication[4].0, -20.0, 1e-12));
+        assert!(approx_eq(replication[5].0, -600.0, 1e-12));
+
+        // the residual is the note less the replication
+        assert_eq!(residual.len(), 7);
+        assert_eq!(residual[0].1.id(), "SampleAutocall");
+        assert!(approx_eq(residual[0].0, 2.0, 1e-12));
+        for (r, p) in residual[1..].iter().zip(replication.iter()) {
+            assert_eq!(r.1.id(), p.1.id());
+            assert_eq!(r.0, -p.0);
+        }
+    }
+}
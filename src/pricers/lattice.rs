@@ -0,0 +1,649 @@
+use core::qm;
+use std::sync::Arc;
+use std::ops::Deref;
+use instruments::RcInstrument;
+use instruments::Exercisable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use risk::cache::PricingContextPrefetch;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::dependencies::DependencyCollector;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::BumpablePricingContext;
+use pricers::PricerFactory;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use risk::marketdata::MarketData;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The shape of the recombining lattice
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LatticeType {
+    /// The Cox-Ross-Rubinstein binomial tree, where the underlying moves up
+    /// or down by the same factor at each step
+    Binomial,
+    /// A trinomial tree, where the underlying may also stay where it is.
+    /// The log spacing is the square root of three times the variance per
+    /// step, which keeps all three probabilities positive.
+    Trinomial
+}
+
+/// The LatticePricer values options on a single underlying, which may be
+/// European, American or Bermudan, by rolling back through a recombining
+/// binomial or trinomial tree. It is simple and fast, so it makes a good
+/// second opinion for the PDE pricer and for the trees that the options use
+/// to price themselves.
+///
+/// Steps are evenly spaced in calendar time between the open on the spot
+/// date and expiry, and the total variance to expiry is evenly spread
+/// across them, so the smile is ignored. The nodes at each step are scaled
+/// to the forward of the underlying at that step, which makes the lattice
+/// exactly reprice the forwards. Discrete dividends therefore appear as a
+/// proportional drop in the nodes at the first step on or after each ex
+/// date. Bermudan exercise dates are rounded to the nearest step.
+/// Displacement is handled in the same way as for the Black76 valuation of
+/// a European.
+///
+/// Tree prices oscillate as the number of steps changes, because the kinks
+/// of the payoff, such as the strike, fall at different places between the
+/// nodes. If the factory asks for smoothing, the payoff at each node at
+/// expiry is averaged over the cell of the log of the underlying that the
+/// node stands for, split at the kinks that the option reports, which makes
+/// the convergence smooth, though averaging adds a little to the variance.
+/// The error is then close to proportional to one over the number of
+/// steps, so if the factory asks for Richardson
+/// extrapolation, the price is extrapolated from the lattice with the given
+/// steps and one with half as many, cancelling the leading error. The same
+/// applies to greeks by bumping, which are then smooth too.
+#[derive(Clone)]
+pub struct LatticePricer {
+    factory: LatticePricerFactory,
+    instruments: Vec<(f64, RcInstrument)>,
+    context: PricingContextPrefetch
+}
+
+/// The LatticePricerFactory is used to construct LatticePricer pricers. It
+/// holds the type of lattice and the number of steps to expiry, and whether
+/// to smooth the payoff and extrapolate in the number of steps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LatticePricerFactory {
+    lattice: LatticeType,
+    steps: usize,
+    #[serde(default)]
+    smoothing: bool,
+    #[serde(default)]
+    richardson: bool
+}
+
+impl LatticePricerFactory {
+    pub fn new(lattice: LatticeType, steps: usize) -> LatticePricerFactory {
+        LatticePricerFactory::with_convergence(lattice, steps, false, false)
+    }
+
+    /// Creates a factory that smooths the payoff at expiry, and that
+    /// extrapolates from half the number of steps if richardson is set.
+    /// See LatticePricer.
+    pub fn with_convergence(lattice: LatticeType, steps: usize, smoothing: bool,
+        richardson: bool) -> LatticePricerFactory {
+        LatticePricerFactory { lattice: lattice, steps: steps,
+            smoothing: smoothing, richardson: richardson }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(LatticePricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for LatticePricerFactory {
+    fn type_id(&self) -> &'static str { "LatticePricerFactory" }
+}
+
+impl PricerFactory for LatticePricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // Apply the fixings to the instrument. (This is the last time we need
+        // the fixings.)
+        let instruments = match instrument.fix(&*fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        let pricer = LatticePricer::new(instruments, self.clone(), &*market_data)?;
+        Ok(Box::new(pricer))
+    }
+}
+
+impl LatticePricer {
+    pub fn new(instruments: Vec<(f64, RcInstrument)>,
+        factory: LatticePricerFactory, market_data: &MarketData)
+        -> Result<LatticePricer, qm::Error> {
+
+        if factory.steps == 0 {
+            return Err(qm::Error::new("Lattice pricer needs at least one step"))
+        }
+        if factory.richardson && factory.steps < 2 {
+            return Err(qm::Error::new("Richardson extrapolation needs at least \
+                two steps"))
+        }
+
+        // Find the dependencies of the resulting vector of instruments
+        // also validate that all instruments are exercisable
+        let mut dependencies = DependencyCollector::new(
+            market_data.spot_date());
+        for &(_, ref instr) in instruments.iter() {
+            dependencies.spot(instr);
+            if instr.as_exercisable().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} is not \
+                    priceable on a lattice", instr.id())))
+            }
+        }
+
+        // Create a cached pricing context, prefetching the data to price them
+        let context = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        Ok(LatticePricer { factory: factory, instruments: instruments,
+            context: context })
+    }
+}
+
+impl Pricer for LatticePricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        // Note that we have already verified that all components are
+        // exercisable, so here we simply skip any that are not.
+        let context = self.context.as_pricing_context();
+        let mut total = 0.0;
+        for &(weight, ref instrument) in self.instruments.iter() {
+            if let Some(exercisable) = instrument.as_exercisable() {
+                total += weight * roll_back(exercisable, context, &self.factory)?;
+            }
+        }
+        Ok(total)
+    }
+}
+
+impl PricerClone for LatticePricer {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for LatticePricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        self.context.bump(bump, save)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        self.context.new_saveable()
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        self.context.restore(saved)
+    }
+}
+
+impl TimeBumpable for LatticePricer {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        if bump.apply(&mut self.instruments, &mut self.context)? {
+            // if the instruments have changed, we need to rebuild the pricer
+            *self = LatticePricer::new(self.instruments.clone(), self.factory.clone(),
+                self.context.raw_market_data())?
+        }
+        Ok(())
+    }
+}
+
+/// The branching of one step of the lattice. Node j of step i branches to
+/// nodes j to j + n - 1 of step i + 1, with the given probabilities, where
+/// n is the number of branches. The log of the underlying at node j of step
+/// i, relative to the forward, is spacing * (j - i * (n - 1) / 2), less a
+/// drift of i times the log of the normalisation, which makes each step a
+/// martingale.
+struct Branching {
+    probabilities: Vec<f64>,
+    spacing: f64,
+    log_normalisation: f64
+}
+
+impl Branching {
+    fn new(lattice: LatticeType, variance: f64) -> Branching {
+        let (probabilities, spacing) = if variance == 0.0 {
+            // all the nodes coincide, so the probabilities do not matter
+            match lattice {
+                LatticeType::Binomial => (vec![0.5, 0.5], 0.0),
+                LatticeType::Trinomial => (vec![1.0 / 6.0, 2.0 / 3.0, 1.0 / 6.0], 0.0)
+            }
+        } else {
+            match lattice {
+                LatticeType::Binomial => {
+                    let up = variance.sqrt().exp();
+                    let down = 1.0 / up;
+                    let p = (1.0 - down) / (up - down);
+                    (vec![1.0 - p, p], 2.0 * variance.sqrt())
+                },
+                LatticeType::Trinomial => {
+                    // match the mean and variance of the log of the underlying
+                    let dx = (3.0 * variance).sqrt();
+                    let mean = -0.5 * variance;
+                    let second = (variance + mean * mean) / (dx * dx);
+                    let first = mean / dx;
+                    let up = 0.5 * (second + first);
+                    let down = 0.5 * (second - first);
+                    (vec![down, 1.0 - up - down, up], dx)
+                }
+            }
+        };
+
+        let n = probabilities.len();
+        let normalisation: f64 = probabilities.iter().enumerate().map(|(k, p)|
+            p * (spacing * (k as f64 - 0.5 * (n - 1) as f64)).exp()).sum();
+        Branching { probabilities: probabilities, spacing: spacing,
+            log_normalisation: normalisation.ln() }
+    }
+
+    fn branches(&self) -> usize { self.probabilities.len() }
+
+    /// The number of nodes at the given step
+    fn nodes(&self, step: usize) -> usize { step * (self.branches() - 1) + 1 }
+
+    /// The log of the underlying at the given node, relative to the forward
+    /// at its step
+    fn log_node(&self, step: usize, j: usize) -> f64 {
+        let centre = 0.5 * (step * (self.branches() - 1)) as f64;
+        self.spacing * (j as f64 - centre) - step as f64 * self.log_normalisation
+    }
+
+    /// The underlying at the given node, given the forward at its step
+    fn node(&self, forward: f64, step: usize, j: usize) -> f64 {
+        forward * self.log_node(step, j).exp()
+    }
+
+    /// The expected value at the previous step, given the values at the
+    /// nodes of a step
+    fn expectation(&self, values: &[f64], j: usize) -> f64 {
+        self.probabilities.iter().enumerate().map(|(k, p)| p * values[j + k]).sum()
+    }
+}
+
+/// Values the option as of the open on the spot date, discounted to the
+/// settlement date of the spot date, extrapolating in the number of steps
+/// if the factory says so.
+fn roll_back(exercisable: &Exercisable, context: &PricingContext,
+    factory: &LatticePricerFactory) -> Result<f64, qm::Error> {
+
+    let fine = roll_back_steps(exercisable, context, factory.lattice,
+        factory.steps, factory.smoothing)?;
+    if !factory.richardson {
+        return Ok(fine)
+    }
+
+    // with an error of c / n for n steps, this combination cancels c
+    let steps = factory.steps;
+    let half = steps / 2;
+    let coarse = roll_back_steps(exercisable, context, factory.lattice, half,
+        factory.smoothing)?;
+    Ok((steps as f64 * fine - half as f64 * coarse) / (steps - half) as f64)
+}
+
+/// Values the option on a lattice with the given number of steps, as
+/// roll_back does.
+fn roll_back_steps(exercisable: &Exercisable, context: &PricingContext,
+    lattice: LatticeType, steps: usize, smoothing: bool) -> Result<f64, qm::Error> {
+
+    let spot_date = context.spot_date();
+    let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+    let expiry = exercisable.expiry();
+    if expiry < val_date {
+        return Ok(0.0)
+    }
+
+    let instrument = exercisable.as_instrument();
+    let underlying = exercisable.underlying().deref();
+    let expiry_date = expiry.date();
+    let forward_curve = context.forward_curve(underlying, expiry_date)?;
+    let vol = context.vol_surface(underlying, expiry_date,
+        &|| Ok(forward_curve.clone()))?;
+    let settlement = instrument.settlement();
+    let yc = context.yield_curve(instrument.credit_id(),
+        settlement.apply(expiry_date))?;
+
+    // lay out the steps of the lattice between the val date and expiry
+    let days = (expiry_date - spot_date) as f64;
+    let mut step_dates = Vec::with_capacity(steps + 1);
+    step_dates.push(val_date);
+    for i in 1..steps {
+        let offset = (days * i as f64 / steps as f64).round() as i32;
+        step_dates.push(DateTime::new(spot_date + offset, expiry.time_of_day())
+            .max(val_date));
+    }
+    step_dates.push(expiry);
+
+    // forwards of the log-normal part of the underlying, plus the discount
+    // factor from the base date of the yield curve to the settlement date of
+    // exercise at each step
+    let mut forwards = Vec::with_capacity(steps + 1);
+    let mut displacements = Vec::with_capacity(steps + 1);
+    let mut dfs = Vec::with_capacity(steps + 1);
+    for step_date in step_dates.iter() {
+        let date = step_date.date();
+        let displacement = vol.displacement(date)?;
+        let forward = forward_curve.forward(date)? - displacement;
+        if forward < 0.0 {
+            return Err(qm::Error::new("Negative forward"))
+        }
+        forwards.push(forward);
+        displacements.push(displacement);
+        dfs.push((-yc.rt(settlement.apply(date))?).exp());
+    }
+
+    let expiry_time = underlying.time_to_day_fraction(expiry)?;
+    let variance = vol.forward_variance(underlying.time_to_day_fraction(val_date)?,
+        expiry_time, forwards[steps] + displacements[steps])?;
+    if variance < 0.0 {
+        return Err(qm::Error::new("Negative variance"))
+    }
+    let branching = Branching::new(lattice, variance / steps as f64);
+
+    let exercisable_steps = if !exercisable.early_exercise() {
+        let mut at_expiry = vec![false; steps + 1];
+        at_expiry[steps] = true;
+        at_expiry
+    } else if let Some(schedule) = exercisable.exercise_schedule() {
+        let step_of = |exercise_date: Date| if days > 0.0 {
+            ((exercise_date - spot_date) as f64 * steps as f64 / days).round() as usize
+        } else {
+            0
+        };
+        let mut on_schedule = schedule.steps(val_date, steps, &step_of);
+        on_schedule[steps] = true;
+        on_schedule
+    } else {
+        vec![true; steps + 1]
+    };
+
+    // the discounted value of exercising at node j of step i
+    let exercise = |i: usize, j: usize| dfs[i] * exercisable.exercise_value(
+        branching.node(forwards[i], i, j) + displacements[i]);
+
+    // roll back through the lattice, working in values discounted to the
+    // base date of the yield curve
+    let smoothing = smoothing && branching.spacing > 0.0;
+    let log_kinks: Vec<f64> = exercisable.kinks().iter()
+        .map(|kink| kink - displacements[steps])
+        .filter(|kink| *kink > 0.0)
+        .map(|kink| (kink / forwards[steps]).ln()).collect();
+    let mut values: Vec<f64> = (0..branching.nodes(steps)).map(|j| if smoothing {
+        dfs[steps] * cell_average(&|x| exercisable.exercise_value(
+            forwards[steps] * x.exp() + displacements[steps]),
+            branching.log_node(steps, j), branching.spacing, &log_kinks)
+    } else {
+        exercise(steps, j)
+    }).collect();
+    for i in (0..steps).rev() {
+        values = (0..branching.nodes(i)).map(|j| {
+            let value = branching.expectation(&values, j);
+            if exercisable_steps[i] {
+                value.max(exercise(i, j))
+            } else {
+                value
+            }
+        }).collect();
+    }
+
+    // discount to the settlement date of the val date
+    Ok(values[0] / dfs[0])
+}
+
+/// The average of a function of the log of the underlying over the cell of
+/// the given width centred on x. The cell is split at the kinks, and each
+/// piece integrated by Simpson's rule, which is accurate for the smooth
+/// pieces of a payoff.
+fn cell_average(f: &Fn(f64) -> f64, x: f64, width: f64, kinks: &[f64]) -> f64 {
+    let (low, high) = (x - 0.5 * width, x + 0.5 * width);
+    let mut edges = vec![low];
+    let mut inside: Vec<f64> = kinks.iter().filter(|k| **k > low && **k < high)
+        .cloned().collect();
+    inside.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    edges.extend(inside);
+    edges.push(high);
+
+    const INTERVALS: usize = 4;
+    let mut total = 0.0;
+    for piece in edges.windows(2) {
+        let h = (piece[1] - piece[0]) / INTERVALS as f64;
+        if h <= 0.0 {
+            continue;
+        }
+
+        // evaluate just inside the ends, so a jump at a kink is taken from
+        // the side of the piece
+        let inner = |k: usize| {
+            let y = piece[0] + k as f64 * h;
+            if k == 0 {
+                f(y + 1e-12 * h)
+            } else if k == INTERVALS {
+                f(y - 1e-12 * h)
+            } else {
+                f(y)
+            }
+        };
+        let mut sum = inner(0) + inner(INTERVALS);
+        for k in 1..INTERVALS {
+            sum += if k % 2 == 1 { 4.0 } else { 2.0 } * inner(k);
+        }
+        total += sum * h / 3.0;
+    }
+    total / width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::SpotStartingBermudan;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::exercise::ExerciseSchedule;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use pricers::RcPricerFactory;
+    use pricers::pde::PdePricer;
+    use pricers::pde::PdePricerFactory;
+    use pricers::pde::ExerciseMethod;
+    use data::fixings::FixingTable;
+    use data::bumpspot::BumpSpot;
+    use serde_json;
+
+    fn lattice_price(instrument: RcInstrument, lattice: LatticeType) -> f64 {
+        let market_data = sample_market_data();
+        let pricer = LatticePricer::new(vec![(1.0, instrument)],
+            LatticePricerFactory::new(lattice, 500), &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    fn pde_price(instrument: RcInstrument) -> f64 {
+        let market_data = sample_market_data();
+        let pricer = PdePricer::new(vec![(1.0, instrument)],
+            PdePricerFactory::new(400, 200, ExerciseMethod::Penalty),
+            &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn lattice_european_matches_black() {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        for &(strike, put_or_call) in [(80.0, PutOrCall::Put), (100.0, PutOrCall::Call),
+            (120.0, PutOrCall::Call)].iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap();
+            let expected = european.price(&market_data, val_date).unwrap();
+            let european = RcInstrument::new(Qrc::new(Arc::new(european)));
+            for &lattice in [LatticeType::Binomial, LatticeType::Trinomial].iter() {
+                let price = lattice_price(european.clone(), lattice);
+                assert!(approx_eq(price, expected, 0.03),
+                    "lattice={:?} strike={} price={} expected={}",
+                    lattice, strike, price, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn lattice_american_matches_pde() {
+        for &(strike, put_or_call) in [(110.0, PutOrCall::Put), (90.0, PutOrCall::Call)]
+            .iter() {
+            let american = RcInstrument::new(Qrc::new(Arc::new(
+                SpotStartingEuropean::new("SampleAmerican", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap()
+                .with_early_exercise(true))));
+            let expected = pde_price(american.clone());
+            for &lattice in [LatticeType::Binomial, LatticeType::Trinomial].iter() {
+                let price = lattice_price(american.clone(), lattice);
+                assert!(approx_eq(price, expected, 0.05),
+                    "lattice={:?} strike={} price={} expected={}",
+                    lattice, strike, price, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn lattice_bermudan_matches_pde() {
+        let exercise = [DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 12, 01), TimeOfDay::Close),
+            sample_expiry()];
+        let schedule = ExerciseSchedule::new(&exercise).unwrap();
+        let bermudan = RcInstrument::new(Qrc::new(Arc::new(
+            SpotStartingBermudan::new("SampleBermudan", "OPT", sample_underlying(),
+            sample_settlement(2), schedule, 110.0, PutOrCall::Put,
+            OptionSettlement::Cash).unwrap())));
+        let expected = pde_price(bermudan.clone());
+        let price = lattice_price(bermudan, LatticeType::Trinomial);
+        assert!(approx_eq(price, expected, 0.05),
+            "price={} expected={}", price, expected);
+    }
+
+    #[test]
+    fn lattice_pricer_tagged_serde() {
+        let factory: RcPricerFactory = Qrc::new(Arc::new(
+            LatticePricerFactory::new(LatticeType::Trinomial, 500)));
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let factory: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(factory.type_id(), "LatticePricerFactory");
+
+        let american = SpotStartingEuropean::new("SampleAmerican", "OPT",
+            sample_underlying(), sample_settlement(2), sample_expiry(),
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap()
+            .with_early_exercise(true);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(american)));
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        let pricer = factory.new(instrument.clone(), fixings, market_data).unwrap();
+        let price = lattice_price(instrument, LatticeType::Trinomial);
+        assert!(approx_eq(pricer.price().unwrap(), price, 1e-9));
+
+        // factories from before smoothing do neither
+        let factory: LatticePricerFactory = serde_json::from_str(
+            r#"{"lattice":"Binomial","steps":100}"#).unwrap();
+        assert!(!factory.smoothing && !factory.richardson);
+    }
+
+    fn accelerated_price(instrument: RcInstrument, lattice: LatticeType, steps: usize,
+        market_data: &MarketData) -> f64 {
+        let pricer = LatticePricer::new(vec![(1.0, instrument)],
+            LatticePricerFactory::with_convergence(lattice, steps, true, true),
+            market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn lattice_smoothing_and_richardson_converge() {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let mut bumped_market_data = market_data.clone();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        bumped_market_data.bump(&bump, None).unwrap();
+        for &(strike, put_or_call) in [(100.0, PutOrCall::Call), (110.0, PutOrCall::Put)]
+            .iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap();
+            let expected = european.price(&market_data, val_date).unwrap();
+            let expected_delta = european.price(&bumped_market_data, val_date).unwrap()
+                - expected;
+            let european = RcInstrument::new(Qrc::new(Arc::new(european)));
+            for &lattice in [LatticeType::Binomial, LatticeType::Trinomial].iter() {
+
+                // smoothed prices move steadily with the number of steps,
+                // where unsmoothed binomial prices jump by several cents
+                let smoothed: Vec<f64> = (40..47).map(|steps| LatticePricer::new(
+                    vec![(1.0, european.clone())], LatticePricerFactory::with_convergence(
+                    lattice, steps, true, false), &market_data).unwrap().price().unwrap())
+                    .collect();
+                assert!(smoothed.windows(3).all(|w| (w[2] - 2.0 * w[1] + w[0]).abs() < 0.005),
+                    "lattice={:?} smoothed={:?}", lattice, smoothed);
+
+                // and extrapolation takes out most of the error
+                let mut pricer = LatticePricer::new(vec![(1.0, european.clone())],
+                    LatticePricerFactory::with_convergence(lattice, 100, true, true),
+                    &market_data).unwrap();
+                let price = pricer.price().unwrap();
+                assert!(approx_eq(price, expected, 0.002),
+                    "lattice={:?} strike={} price={} expected={}",
+                    lattice, strike, price, expected);
+                assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+                let delta = pricer.price().unwrap() - price;
+                assert!(approx_eq(delta, expected_delta, 0.002),
+                    "lattice={:?} strike={} delta={} expected={}",
+                    lattice, strike, delta, expected_delta);
+            }
+        }
+
+        // early exercise of a call, where the strike is the kink
+        let american = RcInstrument::new(Qrc::new(Arc::new(
+            SpotStartingEuropean::new("SampleAmerican", "OPT",
+            sample_underlying(), sample_settlement(2), sample_expiry(),
+            90.0, PutOrCall::Call, OptionSettlement::Cash).unwrap()
+            .with_early_exercise(true))));
+        let expected = accelerated_price(american.clone(), LatticeType::Trinomial, 2000,
+            &market_data);
+        for &lattice in [LatticeType::Binomial, LatticeType::Trinomial].iter() {
+            let price = accelerated_price(american.clone(), lattice, 100, &market_data);
+            assert!(approx_eq(price, expected, 0.005),
+                "lattice={:?} price={} expected={}", lattice, price, expected);
+        }
+
+        assert!(LatticePricer::new(vec![(1.0, american)],
+            LatticePricerFactory::with_convergence(LatticeType::Binomial, 1, true, true),
+            &market_data).is_err());
+    }
+}
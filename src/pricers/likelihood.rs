@@ -0,0 +1,162 @@
+use core::qm;
+use std::collections::HashMap;
+use instruments::RcInstrument;
+use models::MonteCarloModel;
+use pricers::adjoint::MonteCarloGreeks;
+use pricers::controlvariate::Columns;
+use pricers::controlvariate::ColumnView;
+use ndarray::Array1;
+
+/// Values weighted instruments and their greeks on the paths of a model by
+/// the likelihood ratio method, where each instrument sees the given columns
+/// of the paths and flows. Each greek is the mean over the paths of the
+/// value of the path times its score from the model, so the payoffs are
+/// never differentiated, and may be discontinuous, as for digitals. The
+/// estimates are unbiased, but noisier than pathwise greeks for payoffs
+/// that are continuous.
+pub fn likelihood_ratio_greeks(instruments: &[(f64, RcInstrument)],
+    columns: &[Columns], n_flows: usize, model: &MonteCarloModel)
+    -> Result<MonteCarloGreeks, qm::Error> {
+
+    assert_eq!(instruments.len(), columns.len());
+    let likelihood = model.as_likelihood_ratio().ok_or_else(|| qm::Error::new(
+        "The model does not support likelihood ratio greeks"))?;
+    let (underlyings, spot_scores, vol_scores) = likelihood.likelihood_scores()?;
+
+    // the weighted value of each path, summed over the instruments
+    let context = model.as_mc_context();
+    let n_paths = spot_scores.shape()[0];
+    let mut values = Array1::<f64>::zeros(n_paths);
+    for (&(weight, ref instrument), columns) in instruments.iter().zip(columns.iter()) {
+        let mc = instrument.as_mc_priceable().ok_or_else(|| qm::Error::new(
+            &format!("Instrument {} is not priceable by MonteCarlo", instrument.id())))?;
+        let view = ColumnView::new(context, columns, n_flows);
+        mc.mc_price(&view)?;
+        let path_values = view.take_values().ok_or_else(|| qm::Error::new(
+            &format!("Instrument {} did not evaluate its flows", instrument.id())))?;
+        values.scaled_add(weight, &path_values);
+    }
+
+    let scale = 1.0 / n_paths as f64;
+    let mut deltas = HashMap::with_capacity(underlyings.len());
+    let mut vegas = HashMap::with_capacity(underlyings.len());
+    for (i, underlying) in underlyings.iter().enumerate() {
+        let delta = values.dot(&spot_scores.column(i)) * scale;
+        let vega = values.dot(&vol_scores.column(i)) * scale;
+        deltas.insert(underlying.id().to_string(), delta);
+        vegas.insert(underlying.id().to_string(), vega);
+    }
+    Ok(MonteCarloGreeks::new(values.scalar_sum() * scale, deltas, vegas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use math::numerics::approx_eq;
+    use data::bump::Bump;
+    use data::bumpspot::BumpSpot;
+    use risk::Pricer;
+    use risk::Bumpable;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use instruments::options::PutOrCall;
+    use instruments::digitals::DigitalPayout;
+    use instruments::digitals::tests::sample_digital;
+    use risk::marketdata::tests::sample_expiry;
+    use models::RcMonteCarloModelFactory;
+    use models::PathGeneration;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::bachelier::BachelierFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use pricers::selfpricer::SelfPricer;
+    use core::factories::Qrc;
+
+    fn sample_pricer(instrument: RcInstrument, n_paths: usize) -> MonteCarloPricer {
+        let market_data = sample_market_data();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, n_paths)));
+        MonteCarloPricer::with_settings(vec![(1.0, instrument)], model_factory,
+            MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                ..MonteCarloSettings::default() }, &market_data).unwrap()
+    }
+
+    /// Central differences of the closed-form self price
+    fn self_greek(instrument: RcInstrument, up: &Bump, down: &Bump, size: f64) -> f64 {
+        let market_data = sample_market_data();
+        let mut pricer = SelfPricer::new(vec![(1.0, instrument)], &market_data).unwrap();
+        let mut save = pricer.new_saveable();
+        pricer.bump(up, Some(&mut *save)).unwrap();
+        let up_price = pricer.price().unwrap();
+        pricer.restore(&*save).unwrap();
+        save.clear();
+        pricer.bump(down, Some(&mut *save)).unwrap();
+        let down_price = pricer.price().unwrap();
+        pricer.restore(&*save).unwrap();
+        (up_price - down_price) / (2.0 * size)
+    }
+
+    fn spot_bumps(h: f64) -> (Bump, Bump) {
+        (Bump::new_spot("BP.L", BumpSpot::new_relative(h)),
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-h)))
+    }
+
+    #[test]
+    fn likelihood_ratio_matches_pathwise_greeks() {
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let pricer = sample_pricer(instrument.clone(), 65535);
+        let greeks = pricer.likelihood_ratio_greeks().unwrap();
+        assert_approx(greeks.price(), pricer.price().unwrap(), 1e-10);
+
+        // the same paths differentiated pathwise give much less noisy
+        // greeks for a vanilla
+        let pathwise = pricer.adjoint_greeks().unwrap();
+        assert_approx(greeks.deltas()["BP.L"], pathwise.deltas()["BP.L"], 0.005);
+        assert_approx(greeks.vegas()["BP.L"], pathwise.vegas()["BP.L"], 1.5);
+    }
+
+    #[test]
+    fn likelihood_ratio_differentiates_exact_digitals() {
+        // bumping an exact digital on the same paths gives mostly zero,
+        // and it has no pathwise delta at all
+        let digital = |spread_width| RcInstrument::new(Qrc::new(Arc::new(
+            sample_digital(100.0, sample_expiry(), PutOrCall::Call,
+            DigitalPayout::CashOrNothing(100.0), spread_width))));
+        let pricer = sample_pricer(digital(0.0), 65535);
+        assert!(pricer.adjoint_greeks().is_err());
+        let greeks = pricer.likelihood_ratio_greeks().unwrap();
+
+        // compare with a narrow spread, differentiated pathwise, allowing
+        // for the noise of the likelihood ratio vega
+        let spread = sample_pricer(digital(1.0), 65535).adjoint_greeks().unwrap();
+        assert_approx(greeks.deltas()["BP.L"], spread.deltas()["BP.L"], 0.02);
+        assert_approx(greeks.vegas()["BP.L"], spread.vegas()["BP.L"], 5.0);
+
+        // and with the closed form, allowing for the skew that the model
+        // does not see
+        let h = 1e-4;
+        let (up, down) = spot_bumps(h);
+        let delta = self_greek(digital(0.0), &up, &down, 100.0 * h);
+        assert!(delta > 0.9);
+        assert_approx(greeks.deltas()["BP.L"], delta, 0.1);
+    }
+
+    #[test]
+    fn likelihood_ratio_needs_supporting_model() {
+        let market_data = sample_market_data();
+        let mut normal_vols = HashMap::new();
+        normal_vols.insert("BP.L".to_string(), 30.0);
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BachelierFactory::new(normal_vols, 100)));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let pricer = MonteCarloPricer::new(vec![(1.0, instrument)], model_factory,
+            &market_data).unwrap();
+        assert!(pricer.likelihood_ratio_greeks().is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,515 @@
+use core::qm;
+use std::sync::Arc;
+use instruments::RcInstrument;
+use instruments::Exercisable;
+use instruments::MonteCarloContext;
+use instruments::MonteCarloDependencies;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::factories::Qrc;
+use nalgebra::base::DMatrix;
+use nalgebra::base::DVector;
+use nalgebra::linalg::Cholesky;
+use ndarray::Array2;
+
+/// The functions of the underlying that the continuation value is
+/// regressed onto. The underlying is first divided by its average across
+/// the regression paths on the exercise date, so that the regression is
+/// well conditioned whatever the level of the underlying.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RegressionBasis {
+    /// One, x, x squared and so on
+    Monomial,
+    /// The weighted Laguerre polynomials exp(-x/2) L_n(x) used in the
+    /// original paper of Longstaff and Schwartz (2001)
+    Laguerre
+}
+
+/// Configures the Longstaff-Schwartz least squares Monte-Carlo valuation of
+/// options with early exercise. At each exercise date, working backwards,
+/// the discounted value of continuing is regressed onto the basis
+/// functions of the underlying, over the paths that are in the money. The
+/// option is exercised on each path at the first date where exercising is
+/// worth more than the regressed continuation value.
+///
+/// Regressing and valuing on the same paths gives a small upward bias, as
+/// the exercise decisions peek at the future of those paths. If the
+/// in-sample fraction is less than one, the regression uses only that
+/// fraction of the paths, and the option is valued on the rest, which
+/// gives a low-biased estimate instead.
+///
+/// An American option is exercisable on the given number of dates evenly
+/// spaced between the spot date and expiry. A Bermudan option is
+/// exercisable on its schedule.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LongstaffSchwartz {
+    basis: RegressionBasis,
+    degree: usize,
+    exercise_steps: usize,
+    in_sample_fraction: f64
+}
+
+impl LongstaffSchwartz {
+    /// Creates a configuration regressing onto the basis functions up to
+    /// and including the given degree.
+    pub fn new(basis: RegressionBasis, degree: usize, exercise_steps: usize,
+        in_sample_fraction: f64) -> Result<LongstaffSchwartz, qm::Error> {
+
+        if exercise_steps == 0 {
+            return Err(qm::Error::new("Least squares Monte-Carlo needs at \
+                least one exercise step"))
+        }
+        if !(in_sample_fraction > 0.0 && in_sample_fraction <= 1.0) {
+            return Err(qm::Error::new("In-sample fraction must be greater \
+                than zero and no more than one"))
+        }
+        Ok(LongstaffSchwartz { basis: basis, degree: degree,
+            exercise_steps: exercise_steps,
+            in_sample_fraction: in_sample_fraction })
+    }
+
+    /// The values of the basis functions at the given scaled underlying
+    fn basis_functions(&self, x: f64, out: &mut [f64]) {
+        assert_eq!(out.len(), self.degree + 1);
+        self.basis.values(x, out);
+    }
+
+    /// The number of paths used for regression, out of the given total
+    fn in_sample_paths(&self, n_paths: usize) -> usize {
+        ((self.in_sample_fraction * n_paths as f64).round() as usize)
+            .max(1).min(n_paths)
+    }
+}
+
+impl RegressionBasis {
+    /// The values of as many of the basis functions as there is space for
+    /// in the output, from degree zero upwards, at the given scaled value
+    pub fn values(&self, x: f64, out: &mut [f64]) {
+        match *self {
+            RegressionBasis::Monomial => {
+                let mut power = 1.0;
+                for value in out.iter_mut() {
+                    *value = power;
+                    power *= x;
+                }
+            },
+            RegressionBasis::Laguerre => {
+                let weight = (-0.5 * x).exp();
+                let mut previous = 0.0;
+                let mut current = 1.0;
+                for (n, value) in out.iter_mut().enumerate() {
+                    *value = weight * current;
+                    let n = n as f64;
+                    let next = ((2.0 * n + 1.0 - x) * current - n * previous)
+                        / (n + 1.0);
+                    previous = current;
+                    current = next;
+                }
+            }
+        }
+    }
+}
+
+/// The exercise decision at each exercise date but the last, as the scaling
+/// of the underlying and the regression coefficients. Where there were too
+/// few paths in the money to regress, there are no coefficients, and the
+/// option is never exercised.
+#[derive(Clone, Debug)]
+pub struct ExerciseRule {
+    scales: Vec<f64>,
+    coefficients: Vec<Option<Vec<f64>>>
+}
+
+/// An option with early exercise, as seen by least squares Monte-Carlo. It
+/// observes the underlying on each exercise date, and has one flow per
+/// exercise date, paying at the settlement date of that exercise. The
+/// exercise rule may be frozen, so that bumped valuations reuse the
+/// exercise decisions of the unbumped valuation, rather than adding the
+/// noise of a fresh regression to the risks.
+#[derive(Clone)]
+pub struct LeastSquaresOption {
+    instrument: RcInstrument,
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    frozen: Option<Arc<ExerciseRule>>
+}
+
+impl LeastSquaresOption {
+    /// Lays out the exercise dates of an exercisable instrument that are
+    /// after the open on the given date. Returns an error if the
+    /// instrument is not exercisable or has no exercise dates left.
+    pub fn new(instrument: &RcInstrument, config: &LongstaffSchwartz,
+        val_date: DateTime) -> Result<LeastSquaresOption, qm::Error> {
+
+        let exercisable = instrument.as_exercisable().ok_or_else(||
+            qm::Error::new(&format!("Instrument {} is not exercisable",
+            instrument.id())))?;
+        let expiry = exercisable.expiry();
+
+        let mut dates = if !exercisable.early_exercise() {
+            vec![expiry]
+        } else if let Some(schedule) = exercisable.exercise_schedule() {
+            schedule.after(val_date).to_vec()
+        } else {
+            let spot_date = val_date.date();
+            let days = (expiry.date() - spot_date) as f64;
+            let steps = config.exercise_steps;
+            (1..(steps + 1)).map(|i| {
+                let offset = (days * i as f64 / steps as f64).round() as i32;
+                DateTime::new(spot_date + offset, expiry.time_of_day())
+            }).collect()
+        };
+        dates.retain(|d| *d > val_date && *d <= expiry);
+        dates.dedup();
+        if dates.is_empty() {
+            return Err(qm::Error::new(&format!("Instrument {} has no exercise \
+                dates left", instrument.id())))
+        }
+
+        let instr = exercisable.as_instrument();
+        let underlying = exercisable.underlying();
+        let currency = RcCurrency::new(Arc::new(instr.payoff_currency().clone()));
+        let mut observations = Vec::with_capacity(dates.len());
+        let mut flows = Vec::with_capacity(dates.len());
+        for date in dates.iter() {
+            observations.push(underlying.time_to_day_fraction(*date)?);
+            let pay_date = instr.settlement().apply(date.date());
+            flows.push(RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                &format!("{}:Exercise:{}", instr.id(), date), instr.credit_id(),
+                currency.clone(), *date, pay_date, instr.settlement().clone())))));
+        }
+
+        Ok(LeastSquaresOption { instrument: instrument.clone(),
+            observations: observations, flows: flows, frozen: None })
+    }
+
+    /// Registers the observations of the underlying on each exercise date,
+    /// and the flows paying on exercise.
+    pub fn mc_dependencies(&self, output: &mut MonteCarloDependencies)
+        -> Result<(), qm::Error> {
+
+        let underlying = self.exercisable()?.underlying();
+        for observation in self.observations.iter() {
+            output.observation(underlying, *observation);
+        }
+        for flow in self.flows.iter() {
+            output.flow(flow);
+        }
+        Ok(())
+    }
+
+    /// Freezes the exercise decisions of the given model, or leaves them
+    /// alone if they are already frozen.
+    pub fn freeze(&mut self, context: &MonteCarloContext,
+        config: &LongstaffSchwartz) -> Result<(), qm::Error> {
+
+        if self.frozen.is_none() {
+            self.frozen = Some(Arc::new(self.regress(context, config)?));
+        }
+        Ok(())
+    }
+
+    /// Forgets any frozen exercise decisions
+    pub fn unfreeze(&mut self) {
+        self.frozen = None;
+    }
+
+    /// Values the option, using the frozen exercise decisions if there are
+    /// any, or regressing on the paths of the given model if not.
+    pub fn mc_price(&self, context: &MonteCarloContext, config: &LongstaffSchwartz)
+        -> Result<f64, qm::Error> {
+
+        let regressed;
+        let rule = match self.frozen {
+            Some(ref rule) => rule.as_ref(),
+            None => {
+                regressed = self.regress(context, config)?;
+                &regressed
+            }
+        };
+
+        let exercisable = self.exercisable()?;
+        let paths = context.paths(exercisable.underlying())?;
+        let n_paths = paths.shape()[0];
+        let n_dates = self.observations.len();
+        assert_eq!(paths.shape()[1], n_dates);
+        let dfs = self.discount_factors(context)?;
+
+        // value on the paths that were not used for regression, or all of
+        // them if they all were
+        let in_sample = config.in_sample_paths(n_paths);
+        let first = if in_sample < n_paths { in_sample } else { 0 };
+        let mut quantities = Array2::zeros((n_paths, n_dates));
+        let mut basis = vec![0.0; config.degree + 1];
+        for path in first..n_paths {
+            for k in 0..n_dates {
+                let spot = paths[[path, k]];
+                let exercise = exercisable.exercise_value(spot);
+                if exercise <= 0.0 {
+                    continue;
+                }
+                let exercised = if k == n_dates - 1 {
+                    true
+                } else if let Some(ref beta) = rule.coefficients[k] {
+                    config.basis_functions(spot / rule.scales[k], &mut basis);
+                    dfs[k] * exercise >= dot(beta, &basis)
+                } else {
+                    false
+                };
+                if exercised {
+                    quantities[[path, k]] = exercise;
+                    break;
+                }
+            }
+        }
+
+        // evaluate_flows averages over all the paths, including any that
+        // were only used for regression and so have no flows
+        let value = context.evaluate_flows(quantities.view())?;
+        Ok(value * n_paths as f64 / (n_paths - first) as f64)
+    }
+
+    /// Works backwards through the exercise dates, regressing the
+    /// discounted value of continuing onto the basis functions of the
+    /// underlying over the in-sample paths that are in the money.
+    fn regress(&self, context: &MonteCarloContext, config: &LongstaffSchwartz)
+        -> Result<ExerciseRule, qm::Error> {
+
+        let exercisable = self.exercisable()?;
+        let paths = context.paths(exercisable.underlying())?;
+        let n_paths = config.in_sample_paths(paths.shape()[0]);
+        let n_dates = self.observations.len();
+        let n_basis = config.degree + 1;
+        let dfs = self.discount_factors(context)?;
+
+        // discounted value of each path if held from the date being regressed
+        let last = n_dates - 1;
+        let mut values: Vec<f64> = (0..n_paths).map(|path|
+            dfs[last] * exercisable.exercise_value(paths[[path, last]])).collect();
+
+        let mut scales = vec![1.0; last];
+        let mut coefficients = vec![None; last];
+        let mut basis = vec![0.0; n_basis];
+        for k in (0..last).rev() {
+            let scale = (0..n_paths).map(|path| paths[[path, k]]).sum::<f64>()
+                / n_paths as f64;
+            if !(scale > 0.0) {
+                return Err(qm::Error::new("Least squares Monte-Carlo needs a \
+                    positive underlying"))
+            }
+            scales[k] = scale;
+
+            // accumulate the normal equations over the in the money paths
+            let mut lhs = DMatrix::<f64>::zeros(n_basis, n_basis);
+            let mut rhs = DVector::<f64>::zeros(n_basis);
+            let mut in_the_money = 0;
+            for path in 0..n_paths {
+                if exercisable.exercise_value(paths[[path, k]]) > 0.0 {
+                    in_the_money += 1;
+                    config.basis_functions(paths[[path, k]] / scale, &mut basis);
+                    for i in 0..n_basis {
+                        rhs[i] += basis[i] * values[path];
+                        for j in 0..n_basis {
+                            lhs[(i, j)] += basis[i] * basis[j];
+                        }
+                    }
+                }
+            }
+            if in_the_money <= n_basis {
+                continue;
+            }
+            let beta = match Cholesky::new(lhs) {
+                Some(cholesky) => cholesky.solve(&rhs),
+                None => return Err(qm::Error::new("Least squares Monte-Carlo \
+                    regression is singular"))
+            };
+            let beta: Vec<f64> = beta.iter().cloned().collect();
+
+            // exercise where it beats the regressed continuation value
+            for path in 0..n_paths {
+                let spot = paths[[path, k]];
+                let exercise = dfs[k] * exercisable.exercise_value(spot);
+                if exercise > 0.0 {
+                    config.basis_functions(spot / scale, &mut basis);
+                    if exercise >= dot(&beta, &basis) {
+                        values[path] = exercise;
+                    }
+                }
+            }
+            coefficients[k] = Some(beta);
+        }
+
+        Ok(ExerciseRule { scales: scales, coefficients: coefficients })
+    }
+
+    /// The value of receiving one on exercise at each exercise date, which
+    /// puts the exercise and continuation values on the same footing. This
+    /// uses the deterministic curves, even if the model discounts
+    /// stochastically, which only affects the exercise decisions.
+    fn discount_factors(&self, context: &MonteCarloContext)
+        -> Result<Vec<f64>, qm::Error> {
+
+        let pricing_context = context.pricing_context();
+        let val_date = DateTime::new(pricing_context.spot_date(), TimeOfDay::Open);
+        let mut dfs = Vec::with_capacity(self.flows.len());
+        for flow in self.flows.iter() {
+            let priceable = flow.as_priceable().ok_or_else(|| qm::Error::new(
+                "Exercise flows must be priceable"))?;
+            dfs.push(priceable.price(pricing_context, val_date)?);
+        }
+        Ok(dfs)
+    }
+
+    fn exercisable(&self) -> Result<&Exercisable, qm::Error> {
+        self.instrument.as_exercisable().ok_or_else(|| qm::Error::new(
+            "Least squares Monte-Carlo instrument is not exercisable"))
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::SpotStartingBermudan;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::exercise::ExerciseSchedule;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use risk::Pricer;
+    use risk::Bumpable;
+    use data::bump::Bump;
+    use data::bumpspot::BumpSpot;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::Threading;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloPricerFactory;
+    use pricers::montecarlo::MonteCarloSettings;
+    use pricers::pde::PdePricer;
+    use pricers::pde::PdePricerFactory;
+    use pricers::pde::ExerciseMethod;
+    use dates::Date;
+    use serde_json;
+
+    fn sample_american() -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleAmerican", "OPT", sample_underlying(), sample_settlement(2),
+            sample_expiry(), 110.0, PutOrCall::Put, OptionSettlement::Cash)
+            .unwrap().with_early_exercise(true))))
+    }
+
+    fn lsmc_pricer(instrument: RcInstrument, config: LongstaffSchwartz)
+        -> MonteCarloPricer {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(1, 0.01, 20000)));
+        MonteCarloPricer::with_settings(vec![(1.0, instrument)], model_factory,
+            MonteCarloSettings { early_exercise: Some(config),
+                threading: Threading::new(1, Some(42)), ..MonteCarloSettings::default() },
+            &sample_market_data())
+            .unwrap()
+    }
+
+    fn pde_pricer(instrument: RcInstrument) -> PdePricer {
+        PdePricer::new(vec![(1.0, instrument)],
+            PdePricerFactory::new(400, 200, ExerciseMethod::Penalty),
+            &sample_market_data()).unwrap()
+    }
+
+    #[test]
+    fn lsmc_american_put_matches_pde() {
+        let expected = pde_pricer(sample_american()).price().unwrap();
+        for &(basis, in_sample_fraction) in [(RegressionBasis::Laguerre, 0.5),
+            (RegressionBasis::Monomial, 1.0)].iter() {
+            let config = LongstaffSchwartz::new(basis, 3, 50, in_sample_fraction)
+                .unwrap();
+            let price = lsmc_pricer(sample_american(), config).price().unwrap();
+
+            // lsmc_pricer seeds its paths. The standard error is about 0.09,
+            // and the bias of exercising on discrete dates with an
+            // approximate rule is smaller than that, so allow 0.3
+            assert!(approx_eq(price, expected, 0.3),
+                "basis={:?} price={} expected={}", basis, price, expected);
+        }
+    }
+
+    #[test]
+    fn lsmc_bermudan_matches_pde() {
+        let exercise = [DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 12, 01), TimeOfDay::Close),
+            sample_expiry()];
+        let schedule = ExerciseSchedule::new(&exercise).unwrap();
+        let bermudan = RcInstrument::new(Qrc::new(Arc::new(
+            SpotStartingBermudan::new("SampleBermudan", "OPT", sample_underlying(),
+            sample_settlement(2), schedule, 110.0, PutOrCall::Put,
+            OptionSettlement::Cash).unwrap())));
+        let expected = pde_pricer(bermudan.clone()).price().unwrap();
+        let config = LongstaffSchwartz::new(RegressionBasis::Monomial, 2, 1, 0.5)
+            .unwrap();
+        let price = lsmc_pricer(bermudan, config).price().unwrap();
+
+        // the standard error is about 0.1, but over many seeds the price
+        // also sits about 0.1 above the PDE, so allow for both
+        assert!(approx_eq(price, expected, 0.45),
+            "price={} expected={}", price, expected);
+    }
+
+    #[test]
+    fn lsmc_delta_reuses_exercise_decisions() {
+        let config = LongstaffSchwartz::new(RegressionBasis::Laguerre, 3, 50, 1.0)
+            .unwrap();
+        let mut pricer = lsmc_pricer(sample_american(), config);
+        let mut pde = pde_pricer(sample_american());
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.new_saveable();
+        assert!(pricer.bump(&bump, Some(&mut *save)).unwrap());
+        let delta = pricer.price().unwrap() - unbumped;
+
+        let pde_unbumped = pde.price().unwrap();
+        assert!(pde.bump(&bump, None).unwrap());
+        let expected = pde.price().unwrap() - pde_unbumped;
+
+        // with the exercise decisions frozen, the delta has no regression
+        // noise, but paths that cross the exercise boundary still make it
+        // noisier than the delta of a European. From seed to seed it moves
+        // by about 0.01.
+        assert!(approx_eq(delta, expected, 0.05),
+            "delta={} expected={}", delta, expected);
+
+        // restoring gives back the unbumped price, even though the
+        // exercise decisions are now frozen
+        pricer.restore(&*save).unwrap();
+        assert!(approx_eq(pricer.price().unwrap(), unbumped, 1e-12));
+    }
+
+    #[test]
+    fn lsmc_config_tagged_serde() {
+        assert!(LongstaffSchwartz::new(RegressionBasis::Laguerre, 3, 0, 0.5).is_err());
+        assert!(LongstaffSchwartz::new(RegressionBasis::Laguerre, 3, 50, 0.0).is_err());
+        assert!(LongstaffSchwartz::new(RegressionBasis::Laguerre, 3, 50, 1.5).is_err());
+
+        let config = LongstaffSchwartz::new(RegressionBasis::Laguerre, 3, 50, 0.5)
+            .unwrap();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(1, 0.01, 20000)));
+        let factory = MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings { early_exercise: Some(config),
+                ..MonteCarloSettings::default() });
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let deserialized: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+            .unwrap();
+        assert_eq!(serde_json::to_string(&deserialized).unwrap(), serialized);
+    }
+}
@@ -1,8 +1,27 @@
+pub mod adi;
+pub mod adjoint;
+pub mod analytic;
+pub mod controlvariate;
+pub mod cos;
+pub mod decomposition;
+pub mod lattice;
+pub mod likelihood;
+pub mod lsmc;
 pub mod montecarlo;
+pub mod nested;
+pub mod pde;
+pub mod selecting;
 pub mod selfpricer;
+pub mod statistics;
 
 use pricers::montecarlo::MonteCarloPricerFactory;
 use pricers::selfpricer::SelfPricerFactory;
+use pricers::pde::PdePricerFactory;
+use pricers::lattice::LatticePricerFactory;
+use pricers::cos::CosPricerFactory;
+use pricers::analytic::AnalyticPricerFactory;
+use pricers::selecting::SelectingPricerFactory;
+use pricers::decomposition::DecompositionPricerFactory;
 use core::qm;
 use core::factories::{TypeId, Qrc, Registry};
 use instruments::RcInstrument;
@@ -47,6 +66,12 @@ pub fn get_registry() -> &'static TypeRegistry {
             let mut reg = TypeRegistry::new();
             reg.insert("MonteCarloPricerFactory", BoxFnSeed::new(MonteCarloPricerFactory::from_serial));
             reg.insert("SelfPricerFactory", BoxFnSeed::new(SelfPricerFactory::from_serial));
+            reg.insert("PdePricerFactory", BoxFnSeed::new(PdePricerFactory::from_serial));
+            reg.insert("LatticePricerFactory", BoxFnSeed::new(LatticePricerFactory::from_serial));
+            reg.insert("CosPricerFactory", BoxFnSeed::new(CosPricerFactory::from_serial));
+            reg.insert("AnalyticPricerFactory", BoxFnSeed::new(AnalyticPricerFactory::from_serial));
+            reg.insert("SelectingPricerFactory", BoxFnSeed::new(SelectingPricerFactory::from_serial));
+            reg.insert("DecompositionPricerFactory", BoxFnSeed::new(DecompositionPricerFactory::from_serial));
             reg
         };
     }
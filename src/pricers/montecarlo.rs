@@ -1,5 +1,6 @@
 use core::qm;
 use std::sync::Arc;
+use std::collections::HashMap;
 use instruments::RcInstrument;
 use instruments::PricingContext;
 use instruments::DependencyContext;
@@ -10,6 +11,7 @@ use risk::dependencies::DependencyCollector;
 use risk::Bumpable;
 use risk::TimeBumpable;
 use risk::Saveable;
+use risk::GreekMethod;
 use pricers::PricerFactory;
 use data::fixings::RcFixingTable;
 use data::bump::Bump;
@@ -19,6 +21,26 @@ use risk::marketdata::MarketData;
 use models::MonteCarloModel;
 use models::RcMonteCarloModelFactory;
 use models::MonteCarloTimeline;
+use models::PathGeneration;
+use models::Threading;
+use models::discounting::StochasticDiscounting;
+use models::discounting::StochasticallyDiscounted;
+use pricers::lsmc::LongstaffSchwartz;
+use pricers::analytic::AnalyticModel;
+use pricers::adjoint::MonteCarloGreeks;
+use pricers::adjoint::adjoint_greeks;
+use pricers::likelihood::likelihood_ratio_greeks;
+use pricers::statistics::MonteCarloResult;
+use pricers::statistics::AdaptivePaths;
+use ndarray::Array1;
+use pricers::lsmc::LeastSquaresOption;
+use pricers::controlvariate::Columns;
+use pricers::controlvariate::ColumnView;
+use pricers::controlvariate::ControlVariate;
+use pricers::controlvariate::ControlVariateEstimate;
+use instruments::MonteCarloContext;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
 use core::factories::TypeId;
 use core::factories::Qrc;
 use serde::Deserialize;
@@ -27,11 +49,93 @@ use erased_serde as esd;
 /// The MonteCarlo calculator uses the MonteCarloPriceable interface of an
 /// instrument to evaluate the instrument . It then exposes this
 /// interface as a Pricer, allowing bumping for risk calculation.
+///
+/// If the pricer is configured for early exercise, instruments that are
+/// exercisable but not MonteCarloPriceable, such as American and Bermudan
+/// options, are valued by least squares Monte-Carlo. The exercise
+/// decisions are frozen when the pricer is first bumped, so that bumped
+/// prices differ from the unbumped price only through the paths.
+///
+/// If the pricer is configured for control variates, instruments that
+/// supply an analytically priceable proxy are valued with the proxy on the
+/// same paths, and corrected by its error. The coefficients are frozen in
+/// the same way as exercise decisions.
+///
+/// If the pricer is configured for importance sampling, models that support
+/// it shift the drifts of the underlyings towards the levels the payoffs
+/// care about, such as the strike of a far out of the money digital, and
+/// weight the paths to compensate. The weights are fixed when the model is
+/// built, so bumped prices reuse them. Importance sampling cannot be
+/// combined with stochastic discounting or early exercise, which value the
+/// paths without the weights.
+///
+/// The pricer may be configured to spread the simulation over several
+/// threads, with a seed that makes its prices repeatable whatever the
+/// number of threads, and with the streams of random numbers shared by all
+/// pricers or chosen per instrument. The seed and stream are fixed when the
+/// pricer is built, so all its bumps see common random numbers, even when
+/// a bump of time rebuilds it. See models::Threading.
+///
+/// If the pricer is configured for adaptive path counts, it runs further
+/// batches of paths, each from a model of its own on its own stream, until
+/// the standard error of the price is within tolerance, or it reaches the
+/// maximum number of batches. The batches are chosen when the pricer is
+/// built, and bumps apply to all of them, so the risks are on the same
+/// paths as the price. This cannot be combined with early exercise.
+///
+/// If the pricer is configured to prefer closed forms, instruments that
+/// have a closed-form price under the model, such as Europeans under
+/// BlackDiffusion, are valued by it rather than from the paths. This is
+/// not possible with stochastic discounting, which changes the model.
 #[derive(Clone)]
 pub struct MonteCarloPricer {
     model_factory: RcMonteCarloModelFactory,
     instruments: Vec<(f64, RcInstrument)>,
-    model: Box<MonteCarloModel>
+    settings: MonteCarloSettings,
+    analytic_model: Option<AnalyticModel>,
+    least_squares: Vec<Option<LeastSquaresOption>>,
+    instrument_columns: Vec<Columns>,
+    columns: Vec<Columns>,
+    proxies: Vec<Option<ControlVariate>>,
+    n_flows: usize,
+    model: Box<MonteCarloModel>,
+    batches: Vec<Box<MonteCarloModel>>
+}
+
+/// How a MonteCarloPricer values its instruments, other than the model.
+/// The default is a single batch of pseudo-random paths on one thread,
+/// randomly seeded, discounted with the deterministic curves, and with
+/// none of the options described for the pricer. Most uses set only the
+/// fields that differ, and take the rest from the default.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MonteCarloSettings {
+    /// Discount the flows on one yield curve along simulated short rate
+    /// paths, rather than with the deterministic curve
+    #[serde(default)]
+    pub discounting: Option<StochasticDiscounting>,
+    /// Value options with early exercise by least squares Monte-Carlo
+    #[serde(default)]
+    pub early_exercise: Option<LongstaffSchwartz>,
+    /// How the model generates its paths, for example from a Sobol sequence
+    #[serde(default)]
+    pub path_generation: PathGeneration,
+    /// Value instruments with analytic proxies using control variates
+    #[serde(default)]
+    pub control_variates: bool,
+    /// Shift the paths towards the levels that matter to far out of the
+    /// money payoffs
+    #[serde(default)]
+    pub importance_sampling: bool,
+    /// How many threads to run the simulation on, optionally seeded
+    #[serde(default)]
+    pub threading: Threading,
+    /// Value instruments by their closed forms under the model where they can
+    #[serde(default)]
+    pub analytic: bool,
+    /// Run batches of paths until the price is within a tolerance, rather
+    /// than a fixed number of paths
+    #[serde(default)]
+    pub adaptive_paths: Option<AdaptivePaths>
 }
 
 /// The MonteCarloPricerFactory is used to construct MonteCarloPricer pricers.
@@ -39,7 +143,9 @@ pub struct MonteCarloPricer {
 /// what sort of pricer it is.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MonteCarloPricerFactory {
-    model_factory: RcMonteCarloModelFactory
+    model_factory: RcMonteCarloModelFactory,
+    #[serde(flatten)]
+    settings: MonteCarloSettings
 }
 
 impl MonteCarloPricerFactory {
@@ -53,7 +159,16 @@ impl MonteCarloPricerFactory {
     pub fn new(model_factory: RcMonteCarloModelFactory)
         -> MonteCarloPricerFactory {
 
-        MonteCarloPricerFactory { model_factory: model_factory }
+        MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings::default())
+    }
+
+    /// Constructs a factory for pricers with the given settings.
+    pub fn with_settings(model_factory: RcMonteCarloModelFactory,
+        settings: MonteCarloSettings) -> MonteCarloPricerFactory {
+
+        MonteCarloPricerFactory { model_factory: model_factory,
+            settings: settings }
     }
 
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
@@ -69,6 +184,10 @@ impl PricerFactory for MonteCarloPricerFactory {
     fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable, 
         market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
 
+        // Streams per instrument are keyed by the instrument before fixing,
+        // so they do not change as its fixings come in
+        let id = instrument.id().to_string();
+
         // Apply the fixings to the instrument. (This is the last time we need
         // the fixings.)
         let instruments = match instrument.fix(&*fixing_table)? {
@@ -76,16 +195,67 @@ impl PricerFactory for MonteCarloPricerFactory {
             None => vec!((1.0, instrument))
         };
 
-        let pricer = MonteCarloPricer::new(instruments, self.model_factory.clone(), &*market_data)?;
+        let pricer = self.pricer(&id, instruments, &*market_data)?;
         Ok(Box::new(pricer))
     }
 }
 
+impl MonteCarloPricerFactory {
+    /// Builds a pricer with the settings of this factory for instruments
+    /// that are already fixed, whose streams are keyed by the id of the
+    /// instrument they were fixed from
+    pub fn pricer(&self, id: &str, instruments: Vec<(f64, RcInstrument)>,
+        market_data: &MarketData) -> Result<MonteCarloPricer, qm::Error> {
+
+        let settings = MonteCarloSettings {
+            threading: self.settings.threading.resolve(&[id]),
+            ..self.settings.clone() };
+        MonteCarloPricer::with_settings(instruments,
+            self.model_factory.clone(), settings, market_data)
+    }
+}
+
 impl MonteCarloPricer {
     pub fn new(instruments:  Vec<(f64, RcInstrument)>,
         model_factory: RcMonteCarloModelFactory, market_data: &MarketData)
         -> Result<MonteCarloPricer, qm::Error> {
 
+        MonteCarloPricer::with_settings(instruments, model_factory,
+            MonteCarloSettings::default(), market_data)
+    }
+
+    /// Constructs a pricer with the given settings. With adaptive path
+    /// counts, each batch is a model of its own, with its own stream of
+    /// random numbers, and every batch is bumped for risks.
+    pub fn with_settings(instruments: Vec<(f64, RcInstrument)>,
+        model_factory: RcMonteCarloModelFactory,
+        settings: MonteCarloSettings, market_data: &MarketData)
+        -> Result<MonteCarloPricer, qm::Error> {
+
+        MonteCarloPricer::build(instruments, model_factory, settings, None,
+            market_data)
+    }
+
+    /// Builds the pricer. If the number of batches is given, it is used
+    /// rather than running batches until the price is within tolerance.
+    fn build(instruments: Vec<(f64, RcInstrument)>,
+        model_factory: RcMonteCarloModelFactory,
+        settings: MonteCarloSettings, n_batches: Option<usize>,
+        market_data: &MarketData)
+        -> Result<MonteCarloPricer, qm::Error> {
+
+        if settings.importance_sampling && settings.discounting.is_some() {
+            return Err(qm::Error::new("Importance sampling cannot be combined \
+                with stochastic discounting"))
+        }
+
+        // fix the seed and stream, unless they already are
+        let threading = {
+            let ids: Vec<&str> = instruments.iter().map(|&(_, ref i)| i.id()).collect();
+            settings.threading.resolve(&ids)
+        };
+        let settings = MonteCarloSettings { threading: threading, ..settings };
+
         // Find the dependencies of the resulting vector of instruments,
         // also validate that all instruments are priceable by Monte-Carlo
         // and fetch the timeline.
@@ -94,51 +264,376 @@ impl MonteCarloPricer {
         let mut timeline: MonteCarloTimeline 
             = MonteCarloTimeline::new(spot_date);
         let dates_to_value = Vec::new();
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let mut least_squares = Vec::with_capacity(instruments.len());
+        let mut all_columns = Vec::with_capacity(instruments.len());
+        let mut columns = Vec::new();
+        let mut proxies = Vec::new();
         for &(_, ref instr) in instruments.iter() {
             dependencies.spot(instr);
-            if let Some(mc) = instr.as_mc_priceable() {
-               mc.mc_dependencies(&dates_to_value, &mut timeline)?;
-            } else {
-                return Err(qm::Error::new(&format!("Instrument {} is not \
-                    priceable by MonteCarlo", instr.id())))
-            } 
+            let mut option = None;
+            let instrument_columns = Columns::share(&mut timeline, |timeline| {
+                if let Some(mc) = instr.as_mc_priceable() {
+                    mc.mc_dependencies(&dates_to_value, timeline)
+                } else if let (Some(config), Some(_)) = (settings.early_exercise.as_ref(),
+                    instr.as_exercisable()) {
+                    let lsmc = LeastSquaresOption::new(instr, config, val_date)?;
+                    lsmc.mc_dependencies(timeline)?;
+                    option = Some(lsmc);
+                    Ok(())
+                } else {
+                    Err(qm::Error::new(&format!("Instrument {} is not \
+                        priceable by MonteCarlo", instr.id())))
+                }
+            })?;
+            least_squares.push(option);
+
+            // with control variates, the proxies share the paths, so every
+            // instrument must see only its own columns of them
+            if settings.control_variates {
+                let proxy = match instr.as_mc_priceable() {
+                    Some(mc) => ControlVariate::new(mc, &mut timeline)?,
+                    None => None
+                };
+                if let Some(ref proxy) = proxy {
+                    dependencies.spot(proxy.proxy());
+                }
+                proxies.push(proxy);
+                columns.push(instrument_columns.clone());
+            }
+            all_columns.push(instrument_columns);
+        }
+
+        // instruments that share their columns of the paths, such as the
+        // replication of another, must each see only their own columns
+        if columns.is_empty() && all_columns.iter().any(|c| c.is_shared()) {
+            columns = all_columns.clone();
+        }
+        if settings.importance_sampling && least_squares.iter().any(|o| o.is_some()) {
+            return Err(qm::Error::new("Importance sampling cannot be combined \
+                with early exercise"))
+        }
+
+        // the exercise decisions are frozen path by path, so they cannot be
+        // shared between batches
+        if settings.adaptive_paths.is_some()
+            && least_squares.iter().any(|o| o.is_some()) {
+            return Err(qm::Error::new("Adaptive path counts cannot be combined \
+                with early exercise"))
         }
         timeline.collate()?;
+        let n_flows = timeline.flows().len();
+        timeline.set_path_generation(settings.path_generation);
+        timeline.set_importance_sampling(settings.importance_sampling);
+        timeline.set_threading(threading);
 
-        // Create a cached pricing context, prefetching the data to price them
-        let context = Box::new(PricingContextPrefetch::new(market_data,
-            Arc::new(dependencies))?);
+        // Create a Monte-Carlo model, with a cached pricing context,
+        // prefetching the data to price them
+        let dependencies = Arc::new(dependencies);
+        let model = build_model(&timeline, &model_factory,
+            settings.discounting.as_ref(),
+            &dependencies, market_data)?;
+
+        // the closed forms are of the model without stochastic discounting
+        let analytic_model = if settings.analytic && settings.discounting.is_none() {
+            model_factory.analytic_model()
+        } else {
+            None
+        };
 
-        // Create a Monte-Carlo model
-        let model = model_factory.factory(&timeline, context)?;
+        let mut pricer = MonteCarloPricer {
+            model_factory: model_factory, instruments: instruments,
+            settings: settings, analytic_model: analytic_model,
+            least_squares: least_squares,
+            instrument_columns: all_columns, columns: columns, proxies: proxies, n_flows: n_flows,
+            model: model, batches: Vec::new() };
 
-        Ok(MonteCarloPricer {
-            model_factory: model_factory, instruments: instruments, model: model })
+        // run further batches, each on its own stream, until the error is
+        // within tolerance, accumulating the values of the paths so far
+        if let Some(adaptive) = pricer.settings.adaptive_paths {
+            let paired = pricer.settings.path_generation == PathGeneration::Antithetic;
+            let mut values = Vec::new();
+            loop {
+                let count = pricer.batches.len() + 1;
+                let done = match n_batches {
+                    Some(n) => count >= n,
+                    None => {
+                        let last = pricer.batches.last().map_or(&*pricer.model, |m| &**m);
+                        match pricer.value_model(last, true)?.1 {
+                            Some(paths) => {
+                                values.extend(paths.iter().cloned());
+                                let values = Array1::from_vec(values.clone());
+                                let price = values.scalar_sum() / values.len() as f64;
+                                count >= adaptive.max_batches() || adaptive.is_converged(
+                                    &MonteCarloResult::new(price, values.view(), paired, 1)?)
+                            },
+                            // all the instruments have closed forms, so there
+                            // are no errors to reduce
+                            None => true
+                        }
+                    }
+                };
+                if done {
+                    break;
+                }
+                timeline.set_threading(threading.for_overlay(&format!("batch {}", count)));
+                let model = build_model(&timeline, &pricer.model_factory,
+                    pricer.settings.discounting.as_ref(), &dependencies, market_data)?;
+                pricer.batches.push(model);
+            }
+        }
+        Ok(pricer)
     }
-}
 
-impl Pricer for MonteCarloPricer {
-    fn as_bumpable(&self) -> &Bumpable { self }
-    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
-    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+    /// The number of batches of paths, which is one unless the pricer is
+    /// configured for adaptive path counts
+    pub fn n_batches(&self) -> usize {
+        self.batches.len() + 1
+    }
 
-    fn price(&self) -> Result<f64, qm::Error> {
+    /// The models of every batch of paths
+    fn models(&self) -> Vec<&MonteCarloModel> {
+        let mut models = vec![&*self.model];
+        models.extend(self.batches.iter().map(|model| &**model));
+        models
+    }
+
+    /// The threading of the pricer, with the seed and stream it resolved.
+    /// Another pricer built with this threading sees the same paths.
+    pub fn threading(&self) -> Threading {
+        self.settings.threading
+    }
+
+    /// The price and its first-order greeks by adjoint differentiation of
+    /// the paths, from a single pass. The model must support this, as
+    /// BlackDiffusion does, and so must every instrument, as vanillas do.
+    /// Stochastic discounting is not supported, as the model then has no
+    /// adjoint, and neither is early exercise. See pricers::adjoint.
+    pub fn adjoint_greeks(&self) -> Result<MonteCarloGreeks, qm::Error> {
+        if self.least_squares.iter().any(|o| o.is_some()) {
+            return Err(qm::Error::new("Adjoint greeks cannot be combined \
+                with early exercise"))
+        }
+        let mut greeks = Vec::with_capacity(self.n_batches());
+        for model in self.models() {
+            greeks.push(adjoint_greeks(&self.instruments, &self.instrument_columns,
+                self.n_flows, model)?);
+        }
+        Ok(mean_greeks(greeks))
+    }
+
+    /// The price and its first-order greeks by the likelihood ratio method,
+    /// from a single pass. The model must give the scores of its paths, as
+    /// BlackDiffusion does for a single underlying, but the instruments
+    /// need only be priceable by Monte-Carlo, so digitals and barriers are
+    /// differentiated without smoothing. Early exercise is not supported.
+    /// See pricers::likelihood.
+    pub fn likelihood_ratio_greeks(&self) -> Result<MonteCarloGreeks, qm::Error> {
+        if self.least_squares.iter().any(|o| o.is_some()) {
+            return Err(qm::Error::new("Likelihood ratio greeks cannot be \
+                combined with early exercise"))
+        }
+        let mut greeks = Vec::with_capacity(self.n_batches());
+        for model in self.models() {
+            greeks.push(likelihood_ratio_greeks(&self.instruments,
+                &self.instrument_columns, self.n_flows, model)?);
+        }
+        Ok(mean_greeks(greeks))
+    }
+
+    /// Runs a Monte-Carlo simulation to value the instruments, returning
+    /// the weighted sum of their prices, averaged over the batches. If
+    /// record is set, the weighted sum of the values of each path of every
+    /// batch is also returned, unless no instrument was valued on the paths.
+    fn value(&self, record: bool) -> Result<(f64, Option<Array1<f64>>), qm::Error> {
+        if self.batches.is_empty() {
+            return self.value_model(&*self.model, record)
+        }
+
+        // the batches have the same number of paths, so the price is the
+        // mean of their prices
+        let models = self.models();
+        let mut total = 0.0;
+        let mut values = Vec::new();
+        let mut recorded = false;
+        for model in models.iter() {
+            let (price, paths) = self.value_model(*model, record)?;
+            total += price;
+            if let Some(paths) = paths {
+                values.extend(paths.iter().cloned());
+                recorded = true;
+            }
+        }
+        let values = if recorded { Some(Array1::from_vec(values)) } else { None };
+        Ok((total / models.len() as f64, values))
+    }
+
+    /// Values the instruments on the paths of one model, as value does
+    fn value_model(&self, model: &MonteCarloModel, record: bool)
+        -> Result<(f64, Option<Array1<f64>>), qm::Error> {
 
-        // Run a Monte-Carlo simulation to generate a matrix of cashflows
-        // per path. Note that we have already verified that the instruments
-        // are all mc priceable, so just skip them if they aren't
+        // Note that we have already verified that the instruments are all
+        // mc priceable, so just skip them if they aren't
         let mut total = 0.0;
-        for &(weight, ref instrument) in self.instruments.iter() {
+        let mut closed_form = 0.0;
+        let mut values: Option<Array1<f64>> = None;
+        let context = model.as_mc_context();
+        let pricing_context = model.as_bumpable().context();
+        let val_date = DateTime::new(pricing_context.spot_date(), TimeOfDay::Open);
+        for (i, (&(weight, ref instrument), least_squares)) in self.instruments.iter()
+            .zip(self.least_squares.iter()).enumerate() {
+
+            // prefer the closed form, if there is one under the model
+            if let (Some(model), Some(analytic)) = (self.analytic_model.as_ref(),
+                instrument.as_analytic()) {
+                if let Some(price) = analytic.analytic_price(pricing_context,
+                    model, val_date)? {
+                    total += weight * price;
+                    closed_form += weight * price;
+                    continue;
+                }
+            }
+
+            // the columns view records the value of each path, so use one
+            // of every instrument if we are recording
+            let columns = match self.columns.get(i) {
+                Some(columns) => Some(columns),
+                None if record => Some(&self.instrument_columns[i]),
+                None => None
+            };
+            let view = columns.map(|columns|
+                ColumnView::new(context, columns, self.n_flows));
+            let instrument_context: &MonteCarloContext = match view {
+                Some(ref view) => view,
+                None => context
+            };
+            let mut controlled = None;
             if let Some(mc) = instrument.as_mc_priceable() {
-               let context = self.model.as_mc_context();
-               total += weight * mc.mc_price(context)?;
+                total += weight * match (self.proxies.get(i), view.as_ref()) {
+                    (Some(&Some(ref proxy)), Some(view)) => {
+                        let (estimate, paths) = proxy.estimate_paths(mc,
+                            view, context, self.n_flows)?;
+                        controlled = Some(paths);
+                        estimate.controlled()
+                    },
+                    _ => mc.mc_price(instrument_context)?
+                };
+            } else if let (Some(config), &Some(ref option)) = (
+                self.settings.early_exercise.as_ref(), least_squares) {
+                total += weight * option.mc_price(instrument_context, config)?;
+            } else {
+                continue;
+            }
+
+            if record {
+                let paths = match controlled {
+                    Some(paths) => Some(paths),
+                    None => view.as_ref().and_then(|view| view.take_values())
+                };
+                if let Some(paths) = paths {
+                    match values {
+                        Some(ref mut values) => values.scaled_add(weight, &paths),
+                        None => values = Some(paths * weight)
+                    }
+                }
             }
         }
 
-        // Return a weighted sum of the individual prices. (TODO consider
+        // The closed forms are the same on every path. (TODO consider
         // returning some data structure that shows the components as well as
         // the weighted sum.)
-        Ok(total)
+        if let Some(ref mut values) = values {
+            *values += closed_form;
+        }
+        Ok((total, values))
+    }
+
+    /// The control variate estimates of the instruments that have proxies,
+    /// including the regression coefficients, from the first batch of
+    /// paths. This is empty unless the pricer is configured for control
+    /// variates.
+    pub fn control_variate_estimates(&self)
+        -> Result<Vec<ControlVariateEstimate>, qm::Error> {
+
+        let context = self.model.as_mc_context();
+        let mut estimates = Vec::new();
+        for ((&(_, ref instrument), columns), proxy) in self.instruments.iter()
+            .zip(self.columns.iter()).zip(self.proxies.iter()) {
+            if let (Some(mc), &Some(ref proxy)) = (instrument.as_mc_priceable(), proxy) {
+                let view = ColumnView::new(context, columns, self.n_flows);
+                estimates.push(proxy.estimate(mc, &view, context, self.n_flows)?);
+            }
+        }
+        Ok(estimates)
+    }
+}
+
+/// The mean of the greeks of batches with the same number of paths
+fn mean_greeks(greeks: Vec<MonteCarloGreeks>) -> MonteCarloGreeks {
+    if greeks.len() == 1 {
+        return greeks.into_iter().next().unwrap()
+    }
+    let scale = 1.0 / greeks.len() as f64;
+    let mut price = 0.0;
+    let mut deltas = HashMap::new();
+    let mut vegas = HashMap::new();
+    for batch in greeks.iter() {
+        price += batch.price() * scale;
+        for (id, delta) in batch.deltas().iter() {
+            *deltas.entry(id.clone()).or_insert(0.0) += delta * scale;
+        }
+        for (id, vega) in batch.vegas().iter() {
+            *vegas.entry(id.clone()).or_insert(0.0) += vega * scale;
+        }
+    }
+    MonteCarloGreeks::new(price, deltas, vegas)
+}
+
+/// Creates a Monte-Carlo model, with a cached pricing context, prefetching
+/// the data to price the instruments whose dependencies are given
+fn build_model(timeline: &MonteCarloTimeline, model_factory: &RcMonteCarloModelFactory,
+    discounting: Option<&StochasticDiscounting>,
+    dependencies: &Arc<DependencyCollector>, market_data: &MarketData)
+    -> Result<Box<MonteCarloModel>, qm::Error> {
+
+    let context = Box::new(PricingContextPrefetch::new(market_data,
+        dependencies.clone())?);
+    let mut model = model_factory.factory(timeline, context)?;
+    if let Some(discounting) = discounting {
+        model = Box::new(StochasticallyDiscounted::new(timeline, model,
+            discounting)?);
+    }
+    Ok(model)
+}
+
+impl Pricer for MonteCarloPricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        Ok(self.value(false)?.0)
+    }
+
+    /// The price is the same as that of price(), including any control
+    /// variates and closed forms, which contribute no error
+    fn price_with_statistics(&self, batches: usize)
+        -> Result<Option<MonteCarloResult>, qm::Error> {
+
+        let (price, values) = self.value(true)?;
+        let values = values.ok_or_else(|| qm::Error::new(
+            "No instrument was valued on the paths, so there are no statistics"))?;
+        let paired = self.settings.path_generation == PathGeneration::Antithetic;
+        Ok(Some(MonteCarloResult::new(price, values.view(), paired, batches)?))
+    }
+
+    fn path_greeks(&self, method: GreekMethod)
+        -> Result<Option<MonteCarloGreeks>, qm::Error> {
+        match method {
+            GreekMethod::Bumped => Ok(None),
+            GreekMethod::Pathwise => Ok(Some(self.adjoint_greeks()?)),
+            GreekMethod::LikelihoodRatio => Ok(Some(self.likelihood_ratio_greeks()?))
+        }
     }
 }
 
@@ -149,7 +644,49 @@ impl PricerClone for MonteCarloPricer {
 impl Bumpable for MonteCarloPricer {
     fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
         -> Result<bool, qm::Error> {
-        self.model.bump(bump, save)
+
+        // freeze the exercise decisions of the unbumped model, so that the
+        // regression noise does not pollute the risks
+        if let Some(ref config) = self.settings.early_exercise {
+            let context = self.model.as_mc_context();
+            for (i, option) in self.least_squares.iter_mut().enumerate() {
+                if let Some(ref mut option) = *option {
+                    match self.columns.get(i) {
+                        Some(columns) => option.freeze(&ColumnView::new(
+                            context, columns, self.n_flows), config)?,
+                        None => option.freeze(context, config)?
+                    }
+                }
+            }
+        }
+
+        // likewise the control variate coefficients
+        if self.proxies.iter().any(|p| p.as_ref().map_or(false, |p| !p.is_frozen())) {
+            let estimates = self.control_variate_estimates()?;
+            let mut estimates = estimates.iter();
+            for proxy in self.proxies.iter_mut() {
+                if let Some(ref mut proxy) = *proxy {
+                    proxy.freeze(estimates.next().unwrap());
+                }
+            }
+        }
+        if self.batches.is_empty() {
+            return self.model.bump(bump, save)
+        }
+
+        // every batch is bumped, saving into its own part of the saveable
+        let mut saved = match save {
+            Some(save) => Some(save.as_mut_any().downcast_mut::<SavedBatches>()
+                .ok_or_else(|| qm::Error::new("Mismatching save space for batches"))?),
+            None => None
+        };
+        let mut bumped = false;
+        for (i, model) in Some(&mut self.model).into_iter()
+            .chain(self.batches.iter_mut()).enumerate() {
+            let save = saved.as_mut().map(|saved| &mut *saved.batches[i]);
+            bumped |= model.bump(bump, save)?;
+        }
+        Ok(bumped)
     }
 
     fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
@@ -161,20 +698,67 @@ impl Bumpable for MonteCarloPricer {
     }
 
     fn new_saveable(&self) -> Box<Saveable> {
-        self.model.new_saveable()
+        if self.batches.is_empty() {
+            return self.model.new_saveable()
+        }
+        Box::new(SavedBatches { batches: self.models().iter()
+            .map(|model| model.new_saveable()).collect() })
     }
 
     fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
-        self.model.restore(saved)
+        if self.batches.is_empty() {
+            return self.model.restore(saved)
+        }
+        let saved = saved.as_any().downcast_ref::<SavedBatches>()
+            .ok_or_else(|| qm::Error::new("Mismatching save space for batches"))?;
+        for (model, saved) in Some(&mut self.model).into_iter()
+            .chain(self.batches.iter_mut()).zip(saved.batches.iter()) {
+            model.restore(&**saved)?;
+        }
+        Ok(())
+    }
+}
+
+/// The saved state of every batch of paths of a pricer
+struct SavedBatches {
+    batches: Vec<Box<Saveable>>
+}
+
+impl Saveable for SavedBatches {
+    fn as_any(&self) -> &::std::any::Any { self }
+    fn as_mut_any(&mut self) -> &mut ::std::any::Any { self }
+
+    fn clear(&mut self) {
+        for saved in self.batches.iter_mut() {
+            saved.clear();
+        }
     }
 }
 
 impl TimeBumpable for MonteCarloPricer {
     fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
-        if bump.apply(&mut self.instruments, self.model.as_mut_bumpable())? {
+        // the other batches would need bumping too, so rebuild them all,
+        // keeping the number of batches
+        if bump.apply(&mut self.instruments, self.model.as_mut_bumpable())?
+            || !self.batches.is_empty() {
             // if the instruments have changed, we need to rebuild the pricer
-            *self = MonteCarloPricer::new(self.instruments.clone(), self.model_factory.clone(),
+            *self = MonteCarloPricer::build(
+                self.instruments.clone(), self.model_factory.clone(),
+                self.settings.clone(), Some(self.n_batches()),
                 self.model.raw_market_data())?
+        } else {
+            // the exercise decisions and coefficients were for the old
+            // spot date
+            for option in self.least_squares.iter_mut() {
+                if let Some(ref mut option) = *option {
+                    option.unfreeze();
+                }
+            }
+            for proxy in self.proxies.iter_mut() {
+                if let Some(ref mut proxy) = *proxy {
+                    proxy.unfreeze();
+                }
+            }
         }
         Ok(())
     }
@@ -198,7 +782,40 @@ mod tests {
     use risk::marketdata::tests::sample_european;
     use risk::marketdata::tests::sample_forward_european;
     use models::blackdiffusion::BlackDiffusionFactory;
+    use models::blackdiffusion::BlackDiffusion;
+    use models::StreamAssignment;
+    use pricers::statistics::ErrorTolerance;
+    use models::MonteCarloModelFactory;
+    use risk::BumpablePricingContext;
     use core::factories::Qrc;
+    use serde_tagged::de::BoxFnSeed;
+    use serde_json;
+
+    /// A model factory that is not built into the models registry, as if it
+    /// were defined in a downstream crate
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct DownstreamFactory {
+        number_of_paths: usize
+    }
+
+    impl DownstreamFactory {
+        pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcMonteCarloModelFactory, esd::Error> {
+            Ok(Qrc::new(Arc::new(DownstreamFactory::deserialize(de)?)))
+        }
+    }
+
+    impl TypeId for DownstreamFactory {
+        fn type_id(&self) -> &'static str { "DownstreamFactory" }
+    }
+
+    impl MonteCarloModelFactory for DownstreamFactory {
+        fn factory(&self, timeline: &MonteCarloTimeline,
+            context: Box<BumpablePricingContext>)
+            -> Result<Box<MonteCarloModel>, qm::Error> {
+            Ok(Box::new(BlackDiffusion::new(timeline, context, 20, 0.01,
+                self.number_of_paths)?))
+        }
+    }
 
     fn sample_fixings() -> FixingTable {
         let today = Date::from_ymd(2017, 01, 02);
@@ -301,6 +918,375 @@ mod tests {
         assert_approx(price, unbumped_price, 1e-12);
     }
 
+    #[test]
+    fn monte_carlo_price_with_registered_model() {
+
+        // once registered, the downstream model can be configured by name
+        let config = r###"{ "DownstreamFactory": { "number_of_paths": 100000 } }"###;
+        assert!(serde_json::from_str::<RcMonteCarloModelFactory>(config).is_err());
+        ::models::get_registry().register("DownstreamFactory",
+            BoxFnSeed::new(DownstreamFactory::from_serial)).unwrap();
+        let model_factory: RcMonteCarloModelFactory = serde_json::from_str(config).unwrap();
+        assert_eq!(model_factory.type_id(), "DownstreamFactory");
+
+        // the baseline is the same as for the built-in BlackDiffusion
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let pricer = factory.new(instrument, fixings, market_data).unwrap();
+        assert_approx(pricer.price().unwrap(), 16.710717400832973, 0.3);
+    }
+
+    #[test]
+    fn monte_carlo_price_european_antithetic() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        // antithetic pairs need an even number of paths
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 99999)));
+        let factory = MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings { path_generation: PathGeneration::Antithetic,
+                ..MonteCarloSettings::default() });
+        assert!(factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).is_err());
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 100000)));
+        let factory = MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings { path_generation: PathGeneration::Antithetic,
+                ..MonteCarloSettings::default() });
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+            .unwrap();
+        let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let unbumped_price = pricer.price().unwrap();
+        assert_approx(unbumped_price, 16.710717400832973, 0.3);
+
+        // the bumped revaluations reuse the same pairs, so the risks are as
+        // accurate as with plain pseudo-random numbers
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped_price = pricer.price().unwrap();
+        assert_approx(bumped_price - unbumped_price, 0.633187905501792, 0.02);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert_approx(pricer.price().unwrap(), unbumped_price, 1e-12);
+
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped_price = pricer.price().unwrap();
+        assert_approx(bumped_price - unbumped_price, 0.429105019892687, 0.02);
+    }
+
+    #[test]
+    fn monte_carlo_price_european_sobol() {
+
+        // The Euler substeps of the Black diffusion bias the price by about
+        // 0.09 above the analytic price, which we only see once the noise
+        // has gone, so we compare with the price of the discretised model,
+        // found with a quarter of a million Brownian bridge paths. With
+        // this many paths, pseudo-random numbers give a standard error of
+        // about 0.17.
+        let discretised = 16.80074;
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        for &(path_generation, tolerance) in [(PathGeneration::Sobol, 0.05),
+            (PathGeneration::SobolBrownianBridge, 0.02)].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 16383)));
+            let factory = MonteCarloPricerFactory::with_settings(model_factory,
+                MonteCarloSettings { path_generation: path_generation,
+                    ..MonteCarloSettings::default() });
+
+            // the configuration round-trips, and the paths do not depend on
+            // a random seed
+            let serialized = serde_json::to_string(&factory).unwrap();
+            let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+                .unwrap();
+            let pricer = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            let price = pricer.price().unwrap();
+            let repeat = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            assert_eq!(repeat.price().unwrap(), price);
+            assert!(approx_eq(price, discretised, tolerance),
+                "path_generation={:?} price={}", path_generation, price);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_price_european_stratified() {
+
+        // Compare the scatter of the price over many seeds with that of
+        // pseudo-random paths. The vanilla depends only on the end of the
+        // path, so stratifying it removes most of the noise, leaving an
+        // error of about a tenth of that of pseudo-random paths.
+        let discretised = 16.80074;
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let mut errors = Vec::new();
+        for &path_generation in [PathGeneration::PseudoRandom,
+            PathGeneration::Stratified, PathGeneration::LatinHypercube].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 1000)));
+            let mut sum_squares = 0.0;
+            for seed in 0..20 {
+                let factory = MonteCarloPricerFactory::with_settings(
+                    model_factory.clone(), MonteCarloSettings { path_generation: path_generation,
+                        threading: Threading::new(1, Some(seed)),
+                        ..MonteCarloSettings::default() });
+                let serialized = serde_json::to_string(&factory).unwrap();
+                let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+                    .unwrap();
+                let price = factory.new(instrument.clone(), fixings.clone(),
+                    market_data.clone()).unwrap().price().unwrap();
+                sum_squares += (price - discretised).powi(2);
+            }
+            errors.push((sum_squares / 20.0).sqrt());
+        }
+
+        // a Latin hypercube stratifies each step, but not their sum, so it
+        // helps less
+        assert!(errors[1] < 0.2 * errors[0], "errors={:?}", errors);
+        assert!(errors[2] < 0.75 * errors[0], "errors={:?}", errors);
+    }
+
+    #[test]
+    fn monte_carlo_importance_sampling_configuration() {
+
+        // a vanilla does not ask for importance sampling, so its price is
+        // unchanged when it is turned on
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 4095)));
+        let plain = MonteCarloPricerFactory::with_settings(model_factory.clone(),
+            MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                ..MonteCarloSettings::default() });
+        let factory = MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                importance_sampling: true, ..MonteCarloSettings::default() });
+        let serialized = serde_json::to_string(&factory).unwrap();
+        assert!(serialized.contains("\"importance_sampling\":true"));
+        let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+            .unwrap();
+        let price = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap().price().unwrap();
+        let expected = plain.new(instrument, fixings, market_data).unwrap()
+            .price().unwrap();
+        assert_eq!(price, expected);
+    }
+
+    #[test]
+    fn monte_carlo_threads_do_not_change_prices() {
+
+        // with a seed, the prices and risks are the same on any number of
+        // threads, and the blocks of paths do not divide the paths exactly
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let mut results = Vec::new();
+        for &(threads, seed) in [(1, Some(42)), (4, Some(42)), (1, Some(43)),
+            (4, Some(43))].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 10000)));
+            let factory = MonteCarloPricerFactory::with_settings(model_factory,
+                MonteCarloSettings { path_generation: PathGeneration::Antithetic,
+                    threading: Threading::new(threads, seed),
+                    ..MonteCarloSettings::default() });
+            let serialized = serde_json::to_string(&factory).unwrap();
+            let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+                .unwrap();
+            let mut pricer = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            let price = pricer.price().unwrap();
+
+            assert!(approx_eq(price, 16.710717400832973, 0.3),
+                "threads={} seed={:?} price={}", threads, seed, price);
+            let mut save = pricer.new_saveable();
+            assert!(pricer.bump(&bump, Some(&mut *save)).unwrap());
+            results.push((price, pricer.price().unwrap()));
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[2], results[3]);
+        assert!(results[2].0 != results[0].0);
+    }
+
+    #[test]
+    fn monte_carlo_streams_are_reproducible() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let forward = RcInstrument::new(Qrc::new(sample_forward_european()));
+        let pricer = |instrument: &RcInstrument, threading: Threading| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 1000)));
+            let factory = MonteCarloPricerFactory::with_settings(model_factory,
+                MonteCarloSettings { threading: threading,
+                    ..MonteCarloSettings::default() });
+            let serialized = serde_json::to_string(&factory).unwrap();
+            let factory: MonteCarloPricerFactory = serde_json::from_str(&serialized)
+                .unwrap();
+            factory.new(instrument.clone(), fixings.clone(), market_data.clone())
+                .unwrap()
+        };
+        let price = |instrument: &RcInstrument, threading: Threading|
+            pricer(instrument, threading).price().unwrap();
+
+        // the same seed and stream always give the same price, and another
+        // stream of the seed gives another
+        let shared = Threading::new(1, Some(42));
+        let stream = Threading::with_streams(1, Some(42), StreamAssignment::Stream(3));
+        assert_eq!(price(&european, shared), price(&european, shared));
+        assert_eq!(price(&european, stream), price(&european, stream));
+        assert!(price(&european, stream) != price(&european, shared));
+
+        // streams per instrument are keyed by the id of the instrument
+        let per_instrument = Threading::with_streams(1, Some(42),
+            StreamAssignment::PerInstrument);
+        assert_eq!(price(&european, per_instrument), price(&european, per_instrument));
+        assert!(price(&european, per_instrument) != price(&european, shared));
+        assert!(per_instrument.resolve(&[european.id()]).stream()
+            != per_instrument.resolve(&[forward.id()]).stream());
+
+        // an unseeded pricer picks a seed once, and a pricer built with its
+        // resolved threading sees the same paths
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 1000)));
+        let unseeded = MonteCarloPricer::with_settings(vec![(1.0, european.clone())],
+            model_factory, MonteCarloSettings::default(), &*market_data).unwrap();
+        let threading = unseeded.threading();
+        assert!(threading.seed().is_some());
+        assert_eq!(threading.resolve(&["ignored"]), threading);
+        assert_eq!(price(&european, threading), unseeded.price().unwrap());
+    }
+
+    #[test]
+    fn monte_carlo_adaptive_paths() {
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let pricer = |max_batches| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 1000)));
+            let adaptive = AdaptivePaths::new(ErrorTolerance::Absolute(0.3),
+                max_batches).unwrap();
+            MonteCarloPricer::with_settings(vec![(1.0, instrument.clone())],
+                model_factory, MonteCarloSettings {
+                    threading: Threading::new(1, Some(42)),
+                    adaptive_paths: Some(adaptive), ..MonteCarloSettings::default() },
+                &market_data).unwrap()
+        };
+
+        // a thousand paths give an error of about 0.8, so it takes several
+        // batches to reach the tolerance
+        let mut adaptive = pricer(50);
+        let n_batches = adaptive.n_batches();
+        assert!(n_batches > 3 && n_batches < 50, "n_batches={}", n_batches);
+        let price = adaptive.price().unwrap();
+        let result = adaptive.price_with_statistics(1).unwrap().unwrap();
+        assert_eq!(result.n_samples(), 1000 * n_batches);
+        assert!(result.standard_error() <= 0.3);
+        assert_approx(result.price(), price, 1e-10);
+        assert_approx(price, 16.710717400832973, 1.0);
+
+        // the cap stops it short of the tolerance
+        let capped = pricer(2);
+        assert_eq!(capped.n_batches(), 2);
+        let result = capped.price_with_statistics(1).unwrap().unwrap();
+        assert!(result.standard_error() > 0.3);
+
+        // every batch is bumped, and restored
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let mut save = adaptive.new_saveable();
+        assert!(adaptive.bump(&bump, Some(&mut *save)).unwrap());
+        let delta = (adaptive.price().unwrap() - price) / 1.0;
+        assert_approx(delta, 0.6, 0.1);
+        adaptive.restore(&*save).unwrap();
+        assert_eq!(adaptive.price().unwrap(), price);
+
+        // a bump of time keeps the batches
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let time_bump = BumpTime::new(spot_date + 1, spot_date, SpotDynamics::StickyForward);
+        adaptive.bump_time(&time_bump).unwrap();
+        assert_eq!(adaptive.n_batches(), n_batches);
+        assert_approx(adaptive.price().unwrap(), price, 0.1);
+    }
+
+    #[test]
+    fn monte_carlo_standard_error() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let mut errors = Vec::new();
+        for &path_generation in [PathGeneration::PseudoRandom,
+            PathGeneration::Antithetic].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 10000)));
+            let factory = MonteCarloPricerFactory::with_settings(model_factory,
+                MonteCarloSettings { path_generation: path_generation,
+                    threading: Threading::new(1, Some(42)),
+                    ..MonteCarloSettings::default() });
+            let mut pricer = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            let price = pricer.price().unwrap();
+            let result = pricer.price_with_statistics(4).unwrap().unwrap();
+            assert_approx(result.price(), price, 1e-10);
+
+            // the analytic price is within the interval, allowing for the
+            // bias of the Euler substeps
+            let (low, high) = result.confidence_interval(0.999).unwrap();
+            assert!(low < 16.8 && high > 16.71, "low={} high={}", low, high);
+
+            let history = result.history();
+            assert_eq!(history.len(), 4);
+            assert_eq!(history[3].n_samples(), result.n_samples());
+            assert_approx(history[3].price(), price, 1e-9);
+            assert!(history[0].standard_error() > result.standard_error());
+            errors.push(result.standard_error());
+
+            // bumped prices have statistics too
+            let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+            assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+            let bumped = pricer.price_with_statistics(1).unwrap().unwrap();
+            assert_approx(bumped.price(), pricer.price().unwrap(), 1e-10);
+            assert!(bumped.history().is_empty());
+        }
+
+        // a vanilla call has a standard deviation of about 25, and the
+        // antithetic pairs cancel some of it, even counting each pair as
+        // only one sample
+        assert!(errors[0] > 0.2 && errors[0] < 0.3, "error={}", errors[0]);
+        assert!(errors[1] < 0.9 * errors[0], "errors={:?}", errors);
+    }
+
+    #[test]
+    fn monte_carlo_prefers_closed_form() {
+
+        // With so few paths, only the closed form could match the self
+        // pricer this closely, including after a bump
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 100)));
+        let factory = MonteCarloPricerFactory::with_settings(model_factory,
+            MonteCarloSettings { analytic: true, ..MonteCarloSettings::default() });
+        let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
+        assert_approx(pricer.price().unwrap(), 16.710717400832973, 1e-12);
+
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert_approx(pricer.price().unwrap(), 17.343905306334765, 1e-12);
+    }
+
     #[test]
     fn monte_carlo_price_forward_european_time_bumped() {
 
@@ -0,0 +1,442 @@
+use core::qm;
+use std::sync::Arc;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::MonteCarloDependencies;
+use instruments::PricingContext;
+use models::MonteCarloTimeline;
+use models::RcMonteCarloModelFactory;
+use models::PathGeneration;
+use models::Threading;
+use pricers::RcPricerFactory;
+use pricers::lsmc::RegressionBasis;
+use risk::Pricer;
+use risk::cache::PricingContextPrefetch;
+use risk::dependencies::DependencyCollector;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use data::bumpspotdate::SpotDynamics;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use nalgebra::base::DMatrix;
+use nalgebra::base::DVector;
+use nalgebra::linalg::Cholesky;
+
+/// How the instrument is valued in each outer scenario
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum InnerRepricing {
+    /// Reprice with the inner pricer in every outer scenario
+    Full,
+    /// Reprice with the inner pricer in the given number of outer scenarios
+    /// on each date, regress the values onto the basis functions up to the
+    /// given degree of each underlying, divided by its mean across the
+    /// scenarios, and value every scenario by the regressed proxy. This
+    /// smooths out the noise of the inner prices, and needs far fewer of
+    /// them.
+    Regression { basis: RegressionBasis, degree: usize, training_scenarios: usize }
+}
+
+/// Configures a nested Monte-Carlo simulation of the future values of an
+/// instrument, as needed for exposures and valuation adjustments such as
+/// CVA. The outer model simulates scenarios of the spots of the underlyings
+/// on each exposure date. In each scenario, the instrument is repriced by
+/// the inner pricer, rolled forward to the exposure date and with the spots
+/// of the scenario. The inner path budget is that of the inner pricer
+/// factory, for example the number of paths of its Monte-Carlo model, so
+/// the cost of the simulation is that times the number of scenarios that
+/// are repriced.
+///
+/// Time is rolled forward with sticky spot dynamics and then the spots are
+/// replaced, so the forwards in each scenario follow from its spots. Any
+/// fixings between the spot date and an exposure date are taken from the
+/// spot, not from the scenario, so path-dependent instruments see only the
+/// spots on the exposure date.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NestedMonteCarlo {
+    outer_model: RcMonteCarloModelFactory,
+    inner_pricer: RcPricerFactory,
+    exposure_dates: Vec<Date>,
+    repricing: InnerRepricing,
+    #[serde(default)]
+    path_generation: PathGeneration,
+    #[serde(default)]
+    threading: Threading
+}
+
+impl NestedMonteCarlo {
+    pub fn new(outer_model: RcMonteCarloModelFactory, inner_pricer: RcPricerFactory,
+        exposure_dates: Vec<Date>, repricing: InnerRepricing,
+        path_generation: PathGeneration, threading: Threading)
+        -> Result<NestedMonteCarlo, qm::Error> {
+
+        if exposure_dates.is_empty() {
+            return Err(qm::Error::new("Nested Monte-Carlo needs at least one \
+                exposure date"))
+        }
+        if exposure_dates.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(qm::Error::new("Exposure dates must be in increasing order"))
+        }
+        if let InnerRepricing::Regression { training_scenarios, .. } = repricing {
+            if training_scenarios == 0 {
+                return Err(qm::Error::new("Regression proxies need at least one \
+                    training scenario"))
+            }
+        }
+        Ok(NestedMonteCarlo { outer_model: outer_model, inner_pricer: inner_pricer,
+            exposure_dates: exposure_dates, repricing: repricing,
+            path_generation: path_generation, threading: threading })
+    }
+
+    pub fn exposure_dates(&self) -> &[Date] { &self.exposure_dates }
+    pub fn repricing(&self) -> InnerRepricing { self.repricing }
+
+    /// Simulates the values of the instrument on each exposure date
+    pub fn exposures(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<ExposureProfile, qm::Error> {
+
+        let spot_date = market_data.spot_date();
+        if self.exposure_dates[0] <= spot_date {
+            return Err(qm::Error::new("Exposure dates must be after the spot date"))
+        }
+        let threading = self.threading.resolve(&[instrument.id()]);
+        let pricer = self.inner_pricer.new(instrument.clone(), fixing_table,
+            market_data.clone())?;
+        let price = pricer.price()?;
+
+        // the outer scenarios observe every underlying with a forward curve
+        // at the open of each exposure date
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&instrument);
+        let mut underlyings: Vec<RcInstrument> = dependencies.forward_curves().keys()
+            .filter(|u| u.as_exchange_rate().is_none()).cloned().collect();
+        underlyings.sort_by(|a, b| a.id().cmp(b.id()));
+        if underlyings.is_empty() {
+            return Err(qm::Error::new(&format!("Instrument {} has no underlyings \
+                to simulate", instrument.id())))
+        }
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        timeline.set_path_generation(self.path_generation);
+        timeline.set_threading(threading);
+        for date in self.exposure_dates.iter() {
+            let date_time = DateTime::new(*date, TimeOfDay::Open);
+            for underlying in underlyings.iter() {
+                timeline.observation(underlying, underlying.time_to_day_fraction(date_time)?);
+            }
+        }
+        timeline.collate()?;
+        let context = PricingContextPrefetch::new(&*market_data, Arc::new(dependencies))?;
+        let model = self.outer_model.factory(&timeline, Box::new(context))?;
+        let mc_context = model.as_mc_context();
+        let mut paths = Vec::with_capacity(underlyings.len());
+        for underlying in underlyings.iter() {
+            paths.push(mc_context.paths(underlying)?.to_owned());
+        }
+        let n_scenarios = paths[0].shape()[0];
+
+        let mut values = Vec::with_capacity(self.exposure_dates.len());
+        for (k, date) in self.exposure_dates.iter().enumerate() {
+            let mut rolled = pricer.clone_box();
+            rolled.bump_time(&BumpTime::new(*date, *date, SpotDynamics::StickySpot))?;
+            let spots = |scenario: usize| -> Vec<f64> {
+                paths.iter().map(|p| p[[scenario, k]]).collect()
+            };
+            let n_repriced = match self.repricing {
+                InnerRepricing::Full => n_scenarios,
+                InnerRepricing::Regression { training_scenarios, .. } =>
+                    training_scenarios.min(n_scenarios)
+            };
+            let mut repriced = Vec::with_capacity(n_repriced);
+            for scenario in 0..n_repriced {
+                repriced.push(reprice(&*rolled, &underlyings, &spots(scenario))?);
+            }
+            values.push(match self.repricing {
+                InnerRepricing::Full => repriced,
+                InnerRepricing::Regression { basis, degree, .. } => {
+                    let scenarios: Vec<Vec<f64>> = (0..n_scenarios).map(|s| spots(s))
+                        .collect();
+                    regress_proxy(&scenarios, &repriced, basis, degree)?
+                }
+            });
+        }
+
+        Ok(ExposureProfile { price: price, dates: self.exposure_dates.clone(),
+            values: values })
+    }
+}
+
+/// Prices a copy of the pricer with the spots of the scenario
+fn reprice(pricer: &Pricer, underlyings: &[RcInstrument], spots: &[f64])
+    -> Result<f64, qm::Error> {
+
+    let mut scenario = pricer.clone_box();
+    for (underlying, spot) in underlyings.iter().zip(spots.iter()) {
+        let bump = Bump::new_spot(underlying.id(), BumpSpot::new_replace(*spot));
+        scenario.as_mut_bumpable().bump(&bump, None)?;
+    }
+    scenario.price()
+}
+
+/// Fits the values of the training scenarios, which are the first, by
+/// least squares, and returns the fitted values of all the scenarios
+fn regress_proxy(scenarios: &[Vec<f64>], values: &[f64], basis: RegressionBasis,
+    degree: usize) -> Result<Vec<f64>, qm::Error> {
+
+    let n_underlyings = scenarios[0].len();
+    let mut scales = vec![0.0; n_underlyings];
+    for scenario in scenarios.iter() {
+        for (scale, spot) in scales.iter_mut().zip(scenario.iter()) {
+            *scale += spot / scenarios.len() as f64;
+        }
+    }
+
+    // a constant, then the functions of each underlying, leaving out the
+    // monomials of degree zero, which are constant too
+    let first = match basis {
+        RegressionBasis::Monomial => 1,
+        RegressionBasis::Laguerre => 0
+    };
+    let n_basis = 1 + n_underlyings * (degree + 1 - first);
+    let mut buffer = vec![0.0; degree + 1];
+    let mut functions = |scenario: &[f64], out: &mut Vec<f64>| {
+        out.clear();
+        out.push(1.0);
+        for (spot, scale) in scenario.iter().zip(scales.iter()) {
+            basis.values(spot / scale, &mut buffer);
+            out.extend(buffer[first..].iter());
+        }
+    };
+
+    if values.len() < n_basis {
+        return Err(qm::Error::new(&format!("Regression proxies need at least \
+            {} training scenarios", n_basis)))
+    }
+    let mut lhs = DMatrix::<f64>::zeros(n_basis, n_basis);
+    let mut rhs = DVector::<f64>::zeros(n_basis);
+    let mut row = Vec::with_capacity(n_basis);
+    for (scenario, value) in scenarios.iter().zip(values.iter()) {
+        functions(scenario, &mut row);
+        for i in 0..n_basis {
+            rhs[i] += row[i] * value;
+            for j in 0..n_basis {
+                lhs[(i, j)] += row[i] * row[j];
+            }
+        }
+    }
+    let beta = match Cholesky::new(lhs) {
+        Some(cholesky) => cholesky.solve(&rhs),
+        None => return Err(qm::Error::new("Regression proxy is singular"))
+    };
+
+    Ok(scenarios.iter().map(|scenario| {
+        functions(scenario, &mut row);
+        row.iter().zip(beta.iter()).map(|(f, b)| f * b).sum()
+    }).collect())
+}
+
+/// The simulated values of an instrument on each exposure date, one per
+/// outer scenario, as of the open on that date and discounted to its
+/// settlement date, together with the price today. Positive values are
+/// owed to the holder, so they are the exposure to the default of the
+/// counterparty.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExposureProfile {
+    price: f64,
+    dates: Vec<Date>,
+    values: Vec<Vec<f64>>
+}
+
+impl ExposureProfile {
+    pub fn price(&self) -> f64 { self.price }
+    pub fn dates(&self) -> &[Date] { &self.dates }
+
+    /// The values in each scenario on the exposure date with the given index
+    pub fn values(&self, date: usize) -> &[f64] { &self.values[date] }
+
+    /// The mean value on the exposure date
+    pub fn mean_value(&self, date: usize) -> f64 {
+        mean(self.values[date].iter().cloned())
+    }
+
+    /// The expected exposure, which is the mean of the positive part of the
+    /// values on the exposure date
+    pub fn expected_exposure(&self, date: usize) -> f64 {
+        mean(self.values[date].iter().map(|v| v.max(0.0)))
+    }
+
+    /// The expected negative exposure, which is the mean of the negative
+    /// part of the values, and is the exposure of the counterparty to us
+    pub fn expected_negative_exposure(&self, date: usize) -> f64 {
+        mean(self.values[date].iter().map(|v| v.min(0.0)))
+    }
+
+    /// The potential future exposure at the given confidence level, such as
+    /// 0.95, which is that quantile of the positive part of the values,
+    /// interpolated linearly between scenarios
+    pub fn potential_future_exposure(&self, date: usize, level: f64)
+        -> Result<f64, qm::Error> {
+
+        if !(level >= 0.0 && level <= 1.0) {
+            return Err(qm::Error::new("The confidence level must be between \
+                zero and one"))
+        }
+        let mut exposures: Vec<f64> = self.values[date].iter().map(|v| v.max(0.0))
+            .collect();
+        exposures.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let position = level * (exposures.len() - 1) as f64;
+        let below = position.floor() as usize;
+        let above = (below + 1).min(exposures.len() - 1);
+        let w = position - below as f64;
+        Ok((1.0 - w) * exposures[below] + w * exposures[above])
+    }
+
+    /// The expected positive exposure, which is the average of the expected
+    /// exposures over time from the spot date to the last exposure date,
+    /// each weighted by the days since the date before
+    pub fn expected_positive_exposure(&self, spot_date: Date) -> f64 {
+        let mut previous = spot_date;
+        let mut total = 0.0;
+        for (k, date) in self.dates.iter().enumerate() {
+            total += self.expected_exposure(k) * (*date - previous) as f64;
+            previous = *date;
+        }
+        total / (previous - spot_date) as f64
+    }
+}
+
+fn mean<I: Iterator<Item = f64>>(values: I) -> f64 {
+    let (total, count) = values.fold((0.0, 0), |(t, c), v| (t + v, c + 1));
+    total / count as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::Priceable;
+    use instruments::bonds::ZeroCoupon;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::selfpricer::SelfPricerFactory;
+    use data::fixings::FixingTable;
+    use core::factories::Qrc;
+    use serde_json;
+
+    fn sample_european() -> RcInstrument {
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new("SampleEuropean",
+            "OPT", equity, sample_settlement(2), expiry, 100.0, PutOrCall::Call,
+            OptionSettlement::Cash).unwrap())))
+    }
+
+    fn sample_dates() -> Vec<Date> {
+        vec![Date::from_ymd(2017, 04, 03), Date::from_ymd(2017, 10, 02),
+            Date::from_ymd(2018, 03, 01)]
+    }
+
+    fn sample_nested(repricing: InnerRepricing, n_paths: usize) -> NestedMonteCarlo {
+        let outer_model = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(1, 0.01, n_paths)));
+        let inner_pricer: RcPricerFactory = Qrc::new(Arc::new(SelfPricerFactory::new()));
+        NestedMonteCarlo::new(outer_model, inner_pricer, sample_dates(), repricing,
+            PathGeneration::Sobol, Threading::default()).unwrap()
+    }
+
+    fn exposures(nested: &NestedMonteCarlo) -> ExposureProfile {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        nested.exposures(sample_european(), fixings, market_data).unwrap()
+    }
+
+    #[test]
+    fn nested_values_are_martingales() {
+        let profile = exposures(&sample_nested(InnerRepricing::Full, 8191));
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+
+        // a long call is always an exposure, and the mean of its future
+        // values, discounted back from the settlement of each date, is its
+        // price today
+        for (k, date) in profile.dates().iter().enumerate() {
+            let bond = ZeroCoupon::new("SampleBond", "OPT",
+                RcCurrency::new(Arc::new(sample_currency(2))),
+                DateTime::new(*date, TimeOfDay::Open),
+                sample_settlement(2).apply(*date), sample_settlement(2));
+            let df = bond.price(&market_data, val_date).unwrap();
+            let discounted = profile.mean_value(k) * df;
+            assert!(approx_eq(discounted, profile.price(), 0.05),
+                "date={} discounted mean={} price={}", date, discounted, profile.price());
+            assert_eq!(profile.expected_exposure(k), profile.mean_value(k));
+            assert_eq!(profile.expected_negative_exposure(k), 0.0);
+            let pfe = profile.potential_future_exposure(k, 0.95).unwrap();
+            assert!(pfe > profile.expected_exposure(k));
+        }
+
+        // the spread of values widens with time, and so does the
+        // potential future exposure
+        let pfe: Vec<f64> = (0..3).map(|k| profile.potential_future_exposure(k, 0.95)
+            .unwrap()).collect();
+        assert!(pfe[0] < pfe[1] && pfe[1] < pfe[2], "pfe={:?}", pfe);
+        let epe = profile.expected_positive_exposure(market_data.spot_date());
+        assert!(epe > profile.expected_exposure(0) && epe < profile.expected_exposure(2));
+    }
+
+    #[test]
+    fn nested_regression_proxies_match_full_repricing() {
+        let full = exposures(&sample_nested(InnerRepricing::Full, 4095));
+        let proxy = exposures(&sample_nested(InnerRepricing::Regression {
+            basis: RegressionBasis::Monomial, degree: 4, training_scenarios: 256 }, 4095));
+
+        // a polynomial cannot follow the kink of the payoff close to expiry,
+        // so the proxy is within a couple of percent
+        for k in 0..3 {
+            let tolerance = 0.02 * full.expected_exposure(k);
+            assert!(approx_eq(proxy.expected_exposure(k), full.expected_exposure(k), tolerance),
+                "date={} proxy={} full={}", k, proxy.expected_exposure(k),
+                full.expected_exposure(k));
+            let (proxy_pfe, full_pfe) = (proxy.potential_future_exposure(k, 0.95).unwrap(),
+                full.potential_future_exposure(k, 0.95).unwrap());
+            assert!(approx_eq(proxy_pfe, full_pfe, 0.02 * full_pfe), "date={} proxy={} full={}",
+                k, proxy_pfe, full_pfe);
+        }
+
+        // too few training scenarios to fit the basis
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        let sparse = sample_nested(InnerRepricing::Regression {
+            basis: RegressionBasis::Laguerre, degree: 4, training_scenarios: 3 }, 511);
+        assert!(sparse.exposures(sample_european(), fixings, market_data).is_err());
+    }
+
+    #[test]
+    fn nested_config_serde() {
+        let nested = sample_nested(InnerRepricing::Regression {
+            basis: RegressionBasis::Laguerre, degree: 3, training_scenarios: 100 }, 511);
+        let serialized = serde_json::to_string_pretty(&nested).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: NestedMonteCarlo = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.exposure_dates(), nested.exposure_dates());
+        assert_eq!(deserialized.repricing(), nested.repricing());
+
+        let outer_model = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(1, 0.01, 100)));
+        let inner_pricer: RcPricerFactory = Qrc::new(Arc::new(SelfPricerFactory::new()));
+        assert!(NestedMonteCarlo::new(outer_model, inner_pricer,
+            vec![Date::from_ymd(2017, 10, 02), Date::from_ymd(2017, 04, 03)],
+            InnerRepricing::Full, PathGeneration::Sobol, Threading::default()).is_err());
+    }
+}
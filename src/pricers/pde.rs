@@ -0,0 +1,1208 @@
+use core::qm;
+use std::sync::Arc;
+use std::ops::Deref;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::barriers::BarrierType;
+use math::tridiagonal::solve_tridiagonal;
+use pricers::adi::HestonPdeModel;
+use pricers::adi::mean_variance;
+use pricers::adi::roll_back_heston;
+use risk::cache::PricingContextPrefetch;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::dependencies::DependencyCollector;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::BumpablePricingContext;
+use pricers::PricerFactory;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use risk::marketdata::MarketData;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The grid covers this many standard deviations of the log of the
+/// underlying either side of the spot, plus the drift to expiry
+const NUMBER_OF_STD_DEVS: f64 = 5.0;
+
+/// The smallest half-width of the grid in the log of the underlying, for
+/// options that are very close to expiry
+const MIN_HALF_WIDTH: f64 = 0.05;
+
+/// Convergence tolerance and iteration limits for the solvers that impose
+/// the early exercise constraint
+const PSOR_TOLERANCE: f64 = 1e-10;
+const PSOR_MAX_ITERATIONS: usize = 10000;
+const PENALTY: f64 = 1e8;
+const PENALTY_TOLERANCE: f64 = 1e-8;
+const PENALTY_MAX_ITERATIONS: usize = 100;
+
+/// How the early exercise constraint of an American option is imposed at
+/// each time step. Both methods solve the same linear complementarity
+/// problem, so they give the same price to within their tolerances.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ExerciseMethod {
+    /// Projected successive over-relaxation, with the given relaxation
+    /// factor, which must be between zero and two
+    Psor { relaxation: f64 },
+    /// Adds a large penalty wherever the value is below the exercise value,
+    /// and iterates until the set of exercised nodes stops changing
+    Penalty
+}
+
+/// The PdePricer values options on a single underlying by rolling back
+/// through a one-dimensional finite difference grid in the log of the
+/// underlying, using the Crank-Nicolson scheme. It handles European,
+/// American and Bermudan options, via the Exercisable interface, and
+/// barrier options. For the smooth, noise-free values and risks of these
+/// products, it is much faster than Monte-Carlo.
+///
+/// The underlying is log-normal with the at the money variance from the
+/// vol surface, so the smile is ignored. The drift between grid dates comes
+/// from the forward curve, and each discrete dividend is applied as a jump
+/// at the open of its ex date, as the proportion of the underlying that it
+/// removes from the forward. Cash dividend assumptions, which need a
+/// displaced vol surface, are not supported.
+///
+/// Under the Heston model, the grid has a second dimension in the variance
+/// of the underlying, so the skew of the model is seen by barriers and early
+/// exercise. Time is then the vol time of the vol surface, and the Heston
+/// parameters come from the factory rather than the market data.
+///
+/// The grid dates include the ex dates, exercise dates and barrier
+/// observations, and the space grid has a node on the spot, with the
+/// barrier midway between two nodes. The first step after the payoff and
+/// after each barrier observation is taken as two fully implicit half
+/// steps, to damp the oscillations that Crank-Nicolson gives near
+/// discontinuities.
+#[derive(Clone)]
+pub struct PdePricer {
+    factory: PdePricerFactory,
+    instruments: Vec<(f64, RcInstrument)>,
+    context: PricingContextPrefetch
+}
+
+/// The model of the underlying on the grid. The Heston model adds a second
+/// dimension, the variance, and is rolled back by an alternating direction
+/// implicit scheme, which imposes early exercise directly after each step
+/// rather than by the exercise method.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PdeModel {
+    Black,
+    Heston(HestonPdeModel)
+}
+
+impl Default for PdeModel {
+    fn default() -> PdeModel { PdeModel::Black }
+}
+
+/// The PdePricerFactory is used to construct PdePricer pricers. It holds
+/// the number of steps in the log of the underlying and in time, how
+/// early exercise is imposed, and the model of the underlying.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PdePricerFactory {
+    space_steps: usize,
+    time_steps: usize,
+    exercise_method: ExerciseMethod,
+    #[serde(default)]
+    model: PdeModel
+}
+
+impl PdePricerFactory {
+    pub fn new(space_steps: usize, time_steps: usize,
+        exercise_method: ExerciseMethod) -> PdePricerFactory {
+
+        PdePricerFactory::with_model(space_steps, time_steps, exercise_method,
+            PdeModel::Black)
+    }
+
+    pub fn with_model(space_steps: usize, time_steps: usize,
+        exercise_method: ExerciseMethod, model: PdeModel) -> PdePricerFactory {
+
+        PdePricerFactory { space_steps: space_steps, time_steps: time_steps,
+            exercise_method: exercise_method, model: model }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(PdePricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for PdePricerFactory {
+    fn type_id(&self) -> &'static str { "PdePricerFactory" }
+}
+
+impl PricerFactory for PdePricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // Apply the fixings to the instrument. (This is the last time we need
+        // the fixings.)
+        let instruments = match instrument.fix(&*fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        let pricer = PdePricer::new(instruments, self.clone(), &*market_data)?;
+        Ok(Box::new(pricer))
+    }
+}
+
+impl PdePricer {
+    pub fn new(instruments: Vec<(f64, RcInstrument)>,
+        factory: PdePricerFactory, market_data: &MarketData)
+        -> Result<PdePricer, qm::Error> {
+
+        if factory.space_steps < 3 || factory.time_steps == 0 {
+            return Err(qm::Error::new("PDE pricer needs at least three space \
+                steps and one time step"))
+        }
+        if let ExerciseMethod::Psor { relaxation } = factory.exercise_method {
+            if relaxation <= 0.0 || relaxation >= 2.0 {
+                return Err(qm::Error::new("PSOR relaxation must be between \
+                    zero and two"))
+            }
+        }
+
+        // Find the dependencies of the resulting vector of instruments
+        // also validate that all instruments are priceable on a grid
+        let mut dependencies = DependencyCollector::new(
+            market_data.spot_date());
+        for &(_, ref instr) in instruments.iter() {
+            dependencies.spot(instr);
+            if instr.as_exercisable().is_none() && instr.as_barrier().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} is not \
+                    priceable by PDE", instr.id())))
+            }
+        }
+
+        // Create a cached pricing context, prefetching the data to price them
+        let context = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        Ok(PdePricer { factory: factory, instruments: instruments,
+            context: context })
+    }
+}
+
+impl Pricer for PdePricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        Ok(self.value()?.0)
+    }
+
+    fn exercise_boundaries(&self) -> Result<Option<Vec<ExerciseBoundary>>, qm::Error> {
+        Ok(Some(self.value()?.1))
+    }
+}
+
+impl PdePricer {
+    /// The price, and the exercise boundary of each instrument that may be
+    /// exercised early
+    fn value(&self) -> Result<(f64, Vec<ExerciseBoundary>), qm::Error> {
+        // Note that we have already verified that all components are
+        // priceable on a grid, so here we simply skip any that are not.
+        let context = self.context.as_pricing_context();
+        let mut total = 0.0;
+        let mut boundaries = Vec::new();
+        for &(weight, ref instrument) in self.instruments.iter() {
+            if let Some(exercisable) = instrument.as_exercisable() {
+                let exercise = if !exercisable.early_exercise() {
+                    GridExercise::Expiry
+                } else if let Some(schedule) = exercisable.exercise_schedule() {
+                    GridExercise::Dates(schedule.dates())
+                } else {
+                    GridExercise::Any
+                };
+                let instr = exercisable.as_instrument();
+                let terms = GridTerms {
+                    underlying: exercisable.underlying(),
+                    credit_id: instr.credit_id(),
+                    settlement: instr.settlement(),
+                    expiry: exercisable.expiry(),
+                    payoff: &|s| exercisable.exercise_value(s),
+                    exercise: exercise,
+                    barrier: None };
+                let (value, points) = roll_back(&terms, context, &self.factory)?;
+                total += weight * value;
+                if exercisable.early_exercise() {
+                    boundaries.push(ExerciseBoundary { id: instrument.id().to_string(),
+                        points: points });
+                }
+
+            } else if let Some(option) = instrument.as_barrier() {
+                let instr = option.as_instrument();
+                let mut terms = GridTerms {
+                    underlying: option.underlying(),
+                    credit_id: instr.credit_id(),
+                    settlement: instr.settlement(),
+                    expiry: option.expiry(),
+                    payoff: &|s| option.payoff(s),
+                    exercise: GridExercise::Expiry,
+                    barrier: Some((option.barrier(), option.barrier_type(),
+                        option.observations())) };
+                let knock_out = roll_back(&terms, context, &self.factory)?.0;
+
+                // a knock-in is a European less the matching knock-out
+                total += weight * if option.barrier_type().is_knock_in() {
+                    terms.barrier = None;
+                    roll_back(&terms, context, &self.factory)?.0 - knock_out
+                } else {
+                    knock_out
+                };
+            }
+        }
+        Ok((total, boundaries))
+    }
+}
+
+impl PricerClone for PdePricer {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for PdePricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        self.context.bump(bump, save)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        self.context.new_saveable()
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        self.context.restore(saved)
+    }
+}
+
+impl TimeBumpable for PdePricer {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        if bump.apply(&mut self.instruments, &mut self.context)? {
+            // if the instruments have changed, we need to rebuild the pricer
+            *self = PdePricer::new(self.instruments.clone(), self.factory.clone(),
+                self.context.raw_market_data())?
+        }
+        Ok(())
+    }
+}
+
+/// When the holder may exercise, other than at expiry
+enum GridExercise<'a> {
+    Expiry,
+    Any,
+    Dates(&'a [DateTime])
+}
+
+/// The terms of a product as seen by the grid. The payoff is the value
+/// received on exercise given the underlying, paid at the settlement date
+/// of the exercise date. The barrier, if any, is a knock-out with the given
+/// level, direction and observations.
+struct GridTerms<'a> {
+    underlying: &'a RcInstrument,
+    credit_id: &'a str,
+    settlement: &'a RcDateRule,
+    expiry: DateTime,
+    payoff: &'a Fn(f64) -> f64,
+    exercise: GridExercise<'a>,
+    barrier: Option<(f64, BarrierType, &'a [DateTime])>
+}
+
+/// What happens at each date of the grid
+pub struct GridDate {
+    pub date: DateTime,
+    pub exercise: bool,
+    pub observation: bool,
+    pub dividend_ratio: f64
+}
+
+/// The nodes of the grid in the underlying, which are evenly spaced in its
+/// log with spacing h, and the index of the node on the spot. The nodes
+/// that are hit are those on or beyond the barrier.
+pub struct SpaceGrid {
+    pub spots: Vec<f64>,
+    pub h: f64,
+    pub below: usize,
+    pub hit: Vec<bool>
+}
+
+/// The early-exercise boundary of an option, as found on the grid. At each
+/// grid date when the option may be exercised, and it is optimal to
+/// exercise at some level of the underlying, the boundary is the level at
+/// the edge of the exercise region, which is below it for a put and above
+/// it for a call. The levels are those of nodes of the grid, so they are
+/// only as fine as its spacing. Under the Heston model, the boundary also
+/// depends on the variance, and is that at the node nearest the initial
+/// variance.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExerciseBoundary {
+    id: String,
+    points: Vec<ExercisePoint>
+}
+
+impl ExerciseBoundary {
+    /// The id of the instrument
+    pub fn id(&self) -> &str { &self.id }
+
+    /// The points of the boundary, in order of date
+    pub fn points(&self) -> &[ExercisePoint] { &self.points }
+}
+
+/// The level of the underlying at the edge of the exercise region on a date
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ExercisePoint {
+    date: DateTime,
+    level: f64
+}
+
+impl ExercisePoint {
+    pub fn date(&self) -> DateTime { self.date }
+    pub fn level(&self) -> f64 { self.level }
+}
+
+/// Finds the edge of the exercise region, where the values have been
+/// floored at a positive exercise value, nearest the spot node. Returns
+/// None if no node is exercised.
+pub fn exercise_point(date: DateTime, values: &[f64], exercise: &[f64],
+    space: &SpaceGrid) -> Option<ExercisePoint> {
+
+    let exercised = |k: usize| exercise[k] > 0.0 && values[k] <= exercise[k];
+    let n = values.len();
+    let distance = |k: usize| (k as i64 - space.below as i64).abs();
+    let nearest = (0..n).filter(|k| exercised(*k)).min_by_key(|k| distance(*k))?;
+
+    // the region around the nearest node, whose edges at the ends of the
+    // grid are not boundaries
+    let mut first = nearest;
+    while first > 0 && exercised(first - 1) {
+        first -= 1;
+    }
+    let mut last = nearest;
+    while last < n - 1 && exercised(last + 1) {
+        last += 1;
+    }
+    let edge = match (first > 0, last < n - 1) {
+        (true, true) => if distance(first) <= distance(last) { first } else { last },
+        (true, false) => first,
+        (false, true) => last,
+        (false, false) => nearest
+    };
+    Some(ExercisePoint { date: date, level: space.spots[edge] })
+}
+
+/// Values the product as of the open on the spot date, discounted to the
+/// settlement date of the spot date, and finds the exercise boundary.
+fn roll_back(terms: &GridTerms, context: &PricingContext,
+    factory: &PdePricerFactory) -> Result<(f64, Vec<ExercisePoint>), qm::Error> {
+
+    let spot_date = context.spot_date();
+    let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+    if terms.expiry < val_date {
+        return Ok((0.0, Vec::new()))
+    }
+
+    let expiry_date = terms.expiry.date();
+    let underlying = terms.underlying.deref();
+    let forward_curve = context.forward_curve(underlying, expiry_date)?;
+    let vol = context.vol_surface(underlying, expiry_date,
+        &|| Ok(forward_curve.clone()))?;
+    let yc = context.yield_curve(terms.credit_id,
+        terms.settlement.apply(expiry_date))?;
+
+    // lay out the dates of the grid, evenly spaced in calendar time between
+    // the val date and expiry, plus the ex dates and any dates when
+    // something happens
+    let mut dates = vec![val_date, terms.expiry];
+    let days = (expiry_date - spot_date) as f64;
+    for i in 1..factory.time_steps {
+        let offset = (days * i as f64 / factory.time_steps as f64).round() as i32;
+        dates.push(DateTime::new(spot_date + offset, terms.expiry.time_of_day())
+            .max(val_date));
+    }
+    let mut ex_dates = Vec::new();
+    let mut date = spot_date + 1;
+    while date <= expiry_date {
+        if forward_curve.undiscounted_divs(date - 1, date)? != 0.0 {
+            ex_dates.push(DateTime::new(date, TimeOfDay::Open));
+        }
+        date += 1;
+    }
+    dates.extend(ex_dates.iter());
+    let exercise_dates: &[DateTime] = match terms.exercise {
+        GridExercise::Dates(exercise_dates) => exercise_dates,
+        _ => &[]
+    };
+    let observations: &[DateTime] = match terms.barrier {
+        Some((_, _, observations)) => observations,
+        None => &[]
+    };
+    dates.extend(exercise_dates.iter().chain(observations.iter())
+        .filter(|d| **d >= val_date && **d <= terms.expiry));
+    dates.sort();
+    dates.dedup();
+
+    let any_exercise = match terms.exercise {
+        GridExercise::Any => true,
+        _ => false
+    };
+    let mut grid = Vec::with_capacity(dates.len());
+    for date in dates.iter() {
+        let dividend_ratio = if ex_dates.contains(date) {
+            let forward = forward_curve.forward(date.date())?;
+            let dividend = forward_curve.undiscounted_divs(date.date() - 1, date.date())?;
+            forward / (forward + dividend)
+        } else {
+            1.0
+        };
+        grid.push(GridDate {
+            date: *date,
+            exercise: any_exercise || exercise_dates.contains(date),
+            observation: observations.contains(date),
+            dividend_ratio: dividend_ratio });
+    }
+
+    // the forwards, variances and discount factors from the base date of the
+    // yield curve to the settlement date of exercise, at each grid date. For
+    // the Heston model, the variances are the vol times instead.
+    let heston = match factory.model {
+        PdeModel::Black => None,
+        PdeModel::Heston(ref model) => Some((model, model.parameters(underlying.id())?))
+    };
+    let n_dates = grid.len();
+    let mut forwards = Vec::with_capacity(n_dates);
+    let mut variances = Vec::with_capacity(n_dates);
+    let mut dfs = Vec::with_capacity(n_dates);
+    let mut previous = ::std::f64::NEG_INFINITY;
+    for g in grid.iter() {
+        let date = g.date.date();
+        if vol.displacement(date)? != 0.0 {
+            return Err(qm::Error::new("PDE pricer does not support displaced \
+                vol surfaces"))
+        }
+        let forward = forward_curve.forward(date)?;
+        if forward <= 0.0 {
+            return Err(qm::Error::new("PDE pricer needs positive forwards"))
+        }
+        let time = underlying.time_to_day_fraction(g.date)?;
+        let variance = match heston {
+            None => vol.variance(time, forward)?,
+            Some(_) => vol.vol_time(time)?
+        }.max(previous);
+        forwards.push(forward);
+        variances.push(variance);
+        dfs.push((-yc.rt(terms.settlement.apply(date))?).exp());
+        previous = variance;
+    }
+    let total_variance = match heston {
+        None => variances[n_dates - 1] - variances[0],
+        Some((_, parameters)) => {
+            let t = variances[n_dates - 1] - variances[0];
+            mean_variance(parameters, t) * t
+        }
+    };
+
+    // lay out the space grid in x = log(S), with a node on the spot and,
+    // if it is within the grid, the barrier midway between two nodes. With
+    // a node on the barrier, zeroing it moves the barrier by half a step,
+    // which gives a first order bias in the knock-out value.
+    let x0 = forwards[0].ln();
+    let drift = (forwards[n_dates - 1] / forwards[0]).ln();
+    let half_width = (NUMBER_OF_STD_DEVS * total_variance.sqrt()).max(MIN_HALF_WIDTH);
+    let lower = x0 + drift.min(0.0) - half_width;
+    let upper = x0 + drift.max(0.0) + half_width;
+    let mut h = (upper - lower) / factory.space_steps as f64;
+    let barrier_offset = match terms.barrier {
+        Some((barrier, _, _)) => Some(barrier.ln() - x0),
+        None => None
+    };
+    if let Some(offset) = barrier_offset {
+        if offset != 0.0 && x0 + offset > lower && x0 + offset < upper {
+            h = offset.abs() / ((offset.abs() / h - 0.5).round().max(0.0) + 0.5);
+        }
+    }
+    let below = ((x0 - lower) / h).ceil() as usize;
+    let above = ((upper - x0) / h).ceil() as usize;
+    let n = below + above + 1;
+    let spots: Vec<f64> = (0..n).map(|k| (x0 + (k as f64 - below as f64) * h).exp())
+        .collect();
+
+    // the nodes that hit the barrier
+    let hit: Vec<bool> = match (terms.barrier, barrier_offset) {
+        (Some((_, barrier_type, _)), Some(offset)) => {
+            let index = below as f64 + offset / h;
+            (0..n).map(|k| match barrier_type {
+                BarrierType::UpAndOut | BarrierType::UpAndIn => k as f64 >= index,
+                BarrierType::DownAndOut | BarrierType::DownAndIn => k as f64 <= index
+            }).collect()
+        },
+        _ => vec![false; n]
+    };
+
+    // roll back, working in values discounted to the base date of the
+    // yield curve, so the scheme has no discounting term
+    let payoff = |i: usize| -> Vec<f64> {
+        spots.iter().map(|s| dfs[i] * (terms.payoff)(*s)).collect()
+    };
+    let space = SpaceGrid { spots: spots.clone(), h: h, below: below, hit: hit };
+    if let Some((model, parameters)) = heston {
+        let (value, points) = roll_back_heston(&grid, &forwards, &variances, &space,
+            &payoff, parameters, model)?;
+        return Ok((value / dfs[0], points))
+    }
+    let last = n_dates - 1;
+    let mut values = payoff(last);
+    let mut points = Vec::new();
+    if grid[last].exercise {
+        points.extend(exercise_point(grid[last].date, &values, &values, &space));
+    }
+    apply_events(&grid[last], &space.hit, h, &mut values);
+    let mut smooth = true;
+    for i in (0..last).rev() {
+        let variance = variances[i + 1] - variances[i];
+        let mu = (forwards[i + 1] / forwards[i]).ln()
+            - grid[i + 1].dividend_ratio.ln();
+        let floor = if grid[i].exercise && any_exercise {
+            Some(payoff(i))
+        } else {
+            None
+        };
+        let floor_ref = floor.as_ref().map(|f| &f[..]);
+        values = if smooth {
+            let half = step(&values, 0.5 * variance, 0.5 * mu, h, 1.0,
+                floor_ref, factory.exercise_method)?;
+            step(&half, 0.5 * variance, 0.5 * mu, h, 1.0, floor_ref,
+                factory.exercise_method)?
+        } else {
+            step(&values, variance, mu, h, 0.5, floor_ref, factory.exercise_method)?
+        };
+        if grid[i].exercise {
+            let exercise = match floor {
+                Some(floor) => floor,
+                None => payoff(i)
+            };
+            if !any_exercise {
+                for (value, exercise) in values.iter_mut().zip(exercise.iter()) {
+                    *value = value.max(*exercise);
+                }
+            }
+            points.extend(exercise_point(grid[i].date, &values, &exercise, &space));
+        }
+        smooth = apply_events(&grid[i], &space.hit, h, &mut values);
+    }
+
+    // discount to the settlement date of the val date
+    points.reverse();
+    Ok((values[below] / dfs[0], points))
+}
+
+/// Applies the barrier and the dividend at a grid date, in reverse order
+/// of time, as we are rolling back. Returns true if the values have been
+/// made discontinuous, so the next step should be smoothed.
+pub fn apply_events(date: &GridDate, hit: &[bool], h: f64, values: &mut Vec<f64>)
+    -> bool {
+
+    let mut discontinuous = false;
+    if date.observation {
+        for (value, is_hit) in values.iter_mut().zip(hit.iter()) {
+            if *is_hit {
+                *value = 0.0;
+                discontinuous = true;
+            }
+        }
+    }
+
+    // just before the dividend, the value at S is the value just after at
+    // S times the ratio. Interpolate linearly in log(S), extrapolating at
+    // the ends of the grid.
+    if date.dividend_ratio != 1.0 {
+        let shift = date.dividend_ratio.ln() / h;
+        let n = values.len();
+        let shifted: Vec<f64> = (0..n).map(|k| {
+            let p = k as f64 + shift;
+            let i = (p.floor().max(0.0) as usize).min(n - 2);
+            let w = p - i as f64;
+            (1.0 - w) * values[i] + w * values[i + 1]
+        }).collect();
+        *values = shifted;
+    }
+    discontinuous
+}
+
+/// Takes one step back in time with the theta scheme, given the variance
+/// and the drift in the log of the underlying over the step. Theta is one
+/// half for Crank-Nicolson and one for fully implicit. At the ends of the
+/// grid, the value is assumed to be linear in the underlying. If there is
+/// a floor, the values are constrained to be at least the floor.
+fn step(values: &[f64], variance: f64, mu: f64, h: f64, theta: f64,
+    floor: Option<&[f64]>, method: ExerciseMethod) -> Result<Vec<f64>, qm::Error> {
+
+    // the operator, which is tridiagonal
+    let n = values.len();
+    let convection = 0.5 * (mu - 0.5 * variance) / h;
+    let diffusion = 0.5 * variance / (h * h);
+    let mut lower = vec![diffusion - convection; n];
+    let mut diag = vec![-2.0 * diffusion; n];
+    let mut upper = vec![diffusion + convection; n];
+    lower[0] = 0.0;
+    diag[0] = -mu / h;
+    upper[0] = mu / h;
+    lower[n - 1] = -mu / h;
+    diag[n - 1] = mu / h;
+    upper[n - 1] = 0.0;
+
+    // the explicit part
+    let explicit = 1.0 - theta;
+    let rhs: Vec<f64> = (0..n).map(|k| {
+        let mut applied = diag[k] * values[k];
+        if k > 0 {
+            applied += lower[k] * values[k - 1];
+        }
+        if k < n - 1 {
+            applied += upper[k] * values[k + 1];
+        }
+        values[k] + explicit * applied
+    }).collect();
+
+    // the implicit part
+    for k in 0..n {
+        lower[k] *= -theta;
+        diag[k] = 1.0 - theta * diag[k];
+        upper[k] *= -theta;
+    }
+
+    match floor {
+        None => solve_tridiagonal(&lower, &diag, &upper, &rhs),
+        Some(floor) => match method {
+            ExerciseMethod::Psor { relaxation } =>
+                solve_psor(&lower, &diag, &upper, &rhs, floor, relaxation),
+            ExerciseMethod::Penalty =>
+                solve_penalty(&lower, &diag, &upper, &rhs, floor)
+        }
+    }
+}
+
+/// Solves the tridiagonal system subject to the values being at least the
+/// floor, by projected successive over-relaxation, starting from the
+/// unconstrained solution.
+fn solve_psor(lower: &[f64], diag: &[f64], upper: &[f64], rhs: &[f64],
+    floor: &[f64], relaxation: f64) -> Result<Vec<f64>, qm::Error> {
+
+    let n = diag.len();
+    let mut values = solve_tridiagonal(lower, diag, upper, rhs)?;
+    for (value, f) in values.iter_mut().zip(floor.iter()) {
+        *value = value.max(*f);
+    }
+
+    for _ in 0..PSOR_MAX_ITERATIONS {
+        let mut change = 0.0_f64;
+        for k in 0..n {
+            let mut residual = rhs[k];
+            if k > 0 {
+                residual -= lower[k] * values[k - 1];
+            }
+            if k < n - 1 {
+                residual -= upper[k] * values[k + 1];
+            }
+            let gauss_seidel = residual / diag[k];
+            let value = (values[k] + relaxation * (gauss_seidel - values[k]))
+                .max(floor[k]);
+            change = change.max((value - values[k]).abs());
+            values[k] = value;
+        }
+        if change < PSOR_TOLERANCE {
+            return Ok(values)
+        }
+    }
+    Err(qm::Error::new("PSOR did not converge"))
+}
+
+/// Solves the tridiagonal system subject to the values being at least the
+/// floor, by the penalty method, starting from the unconstrained solution.
+fn solve_penalty(lower: &[f64], diag: &[f64], upper: &[f64], rhs: &[f64],
+    floor: &[f64]) -> Result<Vec<f64>, qm::Error> {
+
+    let mut values = solve_tridiagonal(lower, diag, upper, rhs)?;
+    for _ in 0..PENALTY_MAX_ITERATIONS {
+        // penalise the nodes that are currently below their floor
+        let mut penalised = diag.to_vec();
+        let mut penalised_rhs = rhs.to_vec();
+        for k in 0..diag.len() {
+            if values[k] < floor[k] {
+                penalised[k] += PENALTY;
+                penalised_rhs[k] += PENALTY * floor[k];
+            }
+        }
+        let next = solve_tridiagonal(lower, &penalised, upper, &penalised_rhs)?;
+        let change = next.iter().zip(values.iter())
+            .fold(0.0_f64, |acc, (n, v)| acc.max((n - v).abs() / n.abs().max(1.0)));
+        values = next;
+
+        if change < PENALTY_TOLERANCE {
+            // the penalty leaves the exercised nodes a tiny amount below
+            // their floor, so snap them onto it
+            for (value, f) in values.iter_mut().zip(floor.iter()) {
+                *value = value.max(*f);
+            }
+            return Ok(values)
+        }
+    }
+    Err(qm::Error::new("Penalty method did not converge"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::Priceable;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::SpotStartingBermudan;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::exercise::ExerciseSchedule;
+    use instruments::barriers::BarrierOption;
+    use instruments::barriers::BarrierMonitoring;
+    use risk::marketdata::tests::sample_market_data;
+    use models::tests::sample_european_terms;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::RcPricerFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::Threading;
+    use models::heston::HestonParameters;
+    use models::heston::HestonFactory;
+    use pricers::adi::AdiScheme;
+    use data::fixings::FixingTable;
+    use dates::Date;
+    use std::collections::HashMap;
+    use serde_json;
+
+    fn sample_factory(exercise_method: ExerciseMethod) -> PdePricerFactory {
+        PdePricerFactory::new(400, 200, exercise_method)
+    }
+
+    fn pde_price(instrument: RcInstrument, exercise_method: ExerciseMethod) -> f64 {
+        let market_data = sample_market_data();
+        let pricer = PdePricer::new(vec![(1.0, instrument)],
+            sample_factory(exercise_method), &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn pde_european_matches_black() {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        for &(strike, put_or_call) in [(80.0, PutOrCall::Put), (100.0, PutOrCall::Call),
+            (100.0, PutOrCall::Put), (120.0, PutOrCall::Call)].iter() {
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap();
+            let expected = european.price(&market_data, val_date).unwrap();
+            let price = pde_price(RcInstrument::new(Qrc::new(Arc::new(european))),
+                ExerciseMethod::Penalty);
+            assert!(approx_eq(price, expected, 0.01),
+                "strike={} price={} expected={}", strike, price, expected);
+        }
+    }
+
+    #[test]
+    fn pde_american_matches_tree() {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        for &(strike, put_or_call) in [(110.0, PutOrCall::Put), (90.0, PutOrCall::Call)]
+            .iter() {
+            let american = SpotStartingEuropean::new("SampleAmerican", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap()
+                .with_early_exercise(true);
+            let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+                sample_underlying(), sample_settlement(2), sample_expiry(),
+                strike, put_or_call, OptionSettlement::Cash).unwrap();
+            let tree = american.price(&market_data, val_date).unwrap();
+            let european = european.price(&market_data, val_date).unwrap();
+            let american = RcInstrument::new(Qrc::new(Arc::new(american)));
+
+            // both ways of imposing early exercise give the same price
+            let psor = pde_price(american.clone(),
+                ExerciseMethod::Psor { relaxation: 1.2 });
+            let penalty = pde_price(american, ExerciseMethod::Penalty);
+            assert!(approx_eq(psor, penalty, 1e-6),
+                "strike={} psor={} penalty={}", strike, psor, penalty);
+
+            // the tree has fewer steps and rounds the ex dates, so allow
+            // for its discretization error
+            assert!(approx_eq(psor, tree, 0.05),
+                "strike={} pde={} tree={}", strike, psor, tree);
+
+            // the call is only worth exercising early just before the
+            // dividends, so its early exercise premium is small
+            assert!(psor > european,
+                "strike={} pde={} european={}", strike, psor, european);
+        }
+    }
+
+    #[test]
+    fn pde_bermudan_between_european_and_american() {
+        let expiry = sample_expiry();
+        let exercise = [DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 12, 01), TimeOfDay::Close), expiry];
+        let schedule = ExerciseSchedule::new(&exercise).unwrap();
+        let bermudan = SpotStartingBermudan::new("SampleBermudan", "OPT",
+            sample_underlying(), sample_settlement(2), schedule, 110.0,
+            PutOrCall::Put, OptionSettlement::Cash).unwrap();
+        let american = SpotStartingEuropean::new("SampleAmerican", "OPT",
+            sample_underlying(), sample_settlement(2), expiry, 110.0,
+            PutOrCall::Put, OptionSettlement::Cash).unwrap().with_early_exercise(true);
+        let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+            sample_underlying(), sample_settlement(2), expiry, 110.0,
+            PutOrCall::Put, OptionSettlement::Cash).unwrap();
+
+        let method = ExerciseMethod::Psor { relaxation: 1.2 };
+        let bermudan = pde_price(RcInstrument::new(Qrc::new(Arc::new(bermudan))), method);
+        let american = pde_price(RcInstrument::new(Qrc::new(Arc::new(american))), method);
+        let european = pde_price(RcInstrument::new(Qrc::new(Arc::new(european))), method);
+        assert!(bermudan > european + 0.01 && bermudan < american - 0.01,
+            "bermudan={} european={} american={}", bermudan, european, american);
+    }
+
+    #[test]
+    fn pde_barriers_match_monte_carlo() {
+        let market_data = sample_market_data();
+        let observations = vec![
+            DateTime::new(Date::from_ymd(2017, 04, 03), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 07, 03), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 10, 02), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close),
+            sample_expiry()];
+
+        // The Black diffusion steps the spot rather than its log, so its
+        // paths are only log-normal in the limit of small substeps. With the
+        // usual substeps of 0.01 in variance, the knock-in is about 0.04
+        // cheaper than on exactly log-normal paths, so take finer ones.
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(1, 0.001, 20000)));
+
+        let mut total = 0.0;
+        for &barrier_type in [BarrierType::DownAndOut, BarrierType::DownAndIn].iter() {
+            let option = RcInstrument::new(Qrc::new(Arc::new(BarrierOption::new(
+                "SampleBarrier", "OPT", sample_underlying(), sample_settlement(2),
+                sample_expiry(), 100.0, PutOrCall::Call, OptionSettlement::Cash,
+                85.0, barrier_type, BarrierMonitoring::Discrete(observations.clone()))
+                .unwrap())));
+            let price = pde_price(option.clone(), ExerciseMethod::Penalty);
+            let pricer = MonteCarloPricer::with_settings(vec![(1.0, option)],
+                model_factory.clone(), MonteCarloSettings {
+                    threading: Threading::new(1, Some(42)),
+                    ..MonteCarloSettings::default() }, &market_data).unwrap();
+            let result = pricer.price_with_statistics(1).unwrap().unwrap();
+            let expected = result.price();
+
+            // the paths are seeded, and the PDE is converged to within a
+            // few cents, so allow three standard errors
+            assert!(approx_eq(price, expected, 3.0 * result.standard_error()),
+                "barrier={:?} price={} expected={} standard_error={}",
+                barrier_type, price, expected, result.standard_error());
+            total += price;
+        }
+
+        // knock-in plus knock-out is the European
+        let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+            sample_underlying(), sample_settlement(2), sample_expiry(),
+            100.0, PutOrCall::Call, OptionSettlement::Cash).unwrap();
+        let european = pde_price(RcInstrument::new(Qrc::new(Arc::new(european))),
+            ExerciseMethod::Penalty);
+        assert!(approx_eq(total, european, 1e-9), "total={} european={}",
+            total, european);
+    }
+
+    #[test]
+    fn pde_discrete_barrier_converges() {
+        let observations = vec![
+            DateTime::new(Date::from_ymd(2017, 04, 03), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 07, 03), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 10, 02), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close),
+            sample_expiry()];
+        let option = RcInstrument::new(Qrc::new(Arc::new(BarrierOption::new(
+            "SampleBarrier", "OPT", sample_underlying(), sample_settlement(2),
+            sample_expiry(), 100.0, PutOrCall::Call, OptionSettlement::Cash,
+            85.0, BarrierType::DownAndOut, BarrierMonitoring::Discrete(observations))
+            .unwrap())));
+        let price = |space_steps: usize, time_steps: usize| -> f64 {
+            PdePricer::new(vec![(1.0, option.clone())],
+                PdePricerFactory::new(space_steps, time_steps, ExerciseMethod::Penalty),
+                &sample_market_data()).unwrap().price().unwrap()
+        };
+
+        // with the barrier midway between nodes, the error is second
+        // order, so the usual grid is within a few tenths of a cent of one
+        // four times finer
+        let coarse = price(400, 200);
+        let fine = price(1600, 800);
+        assert!(approx_eq(coarse, fine, 0.005), "coarse={} fine={}", coarse, fine);
+    }
+
+    #[test]
+    fn pde_pricer_tagged_serde() {
+        let factory: RcPricerFactory = Qrc::new(Arc::new(sample_factory(
+            ExerciseMethod::Psor { relaxation: 1.2 })));
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let factory: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(factory.type_id(), "PdePricerFactory");
+
+        let american = SpotStartingEuropean::new("SampleAmerican", "OPT",
+            sample_underlying(), sample_settlement(2), sample_expiry(),
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap()
+            .with_early_exercise(true);
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(american)));
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        let pricer = factory.new(instrument.clone(), fixings, market_data).unwrap();
+        let price = pde_price(instrument, ExerciseMethod::Penalty);
+        assert!(approx_eq(pricer.price().unwrap(), price, 1e-6));
+    }
+
+    #[test]
+    fn pde_american_exercise_boundary() {
+        let market_data = sample_market_data();
+        let expiry = sample_expiry();
+        let american = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleAmerican", "OPT", sample_underlying(), sample_settlement(2), expiry,
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap()
+            .with_early_exercise(true))));
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", sample_underlying(), sample_settlement(2), expiry,
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap())));
+        let boundaries = |method| {
+            let pricer = PdePricer::new(vec![(1.0, american.clone()),
+                (1.0, european.clone())], sample_factory(method), &market_data).unwrap();
+            pricer.exercise_boundaries().unwrap().unwrap()
+        };
+
+        // only the american has a boundary, and both ways of imposing early
+        // exercise agree on it
+        let penalty = boundaries(ExerciseMethod::Penalty);
+        let psor = boundaries(ExerciseMethod::Psor { relaxation: 1.2 });
+        assert_eq!(penalty.len(), 1);
+        assert_eq!(penalty[0].id(), "SampleAmerican");
+        assert_eq!(penalty[0].points(), psor[0].points());
+
+        // the put is exercised below the strike, and not at the spot, as
+        // it is worth more than its intrinsic value. At expiry, it is
+        // exercised whenever it is in the money.
+        let points = penalty[0].points();
+        assert!(points.len() > 100);
+        assert!(points.windows(2).all(|w| w[0].date() < w[1].date()));
+        assert!(points.iter().all(|p| p.level() < 110.0), "{:?}", points);
+        assert!(points[0].level() < 100.0, "{:?}", points[0]);
+        let last = points.last().unwrap();
+        assert_eq!(last.date(), expiry);
+        assert!(last.level() > 109.0, "{:?}", last);
+
+        // well before expiry, the boundary is well below the strike
+        assert!(points[0].level() < 90.0, "{:?}", points[0]);
+
+        // other pricers do not find boundaries
+        let market_data = sample_market_data();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(1, 0.01, 100)));
+        let pricer = MonteCarloPricer::new(vec![(1.0, european)], model_factory,
+            &market_data).unwrap();
+        assert!(pricer.exercise_boundaries().unwrap().is_none());
+    }
+
+    #[test]
+    fn pde_bermudan_and_heston_exercise_boundaries() {
+        let market_data = sample_market_data();
+        let expiry = sample_expiry();
+        let exercise = [DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 12, 01), TimeOfDay::Close), expiry];
+        let schedule = ExerciseSchedule::new(&exercise).unwrap();
+        let bermudan = RcInstrument::new(Qrc::new(Arc::new(SpotStartingBermudan::new(
+            "SampleBermudan", "OPT", sample_underlying(), sample_settlement(2), schedule,
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap())));
+
+        // the bermudan has a point on each exercise date
+        let method = ExerciseMethod::Psor { relaxation: 1.2 };
+        let pricer = PdePricer::new(vec![(1.0, bermudan)], sample_factory(method),
+            &market_data).unwrap();
+        let boundaries = pricer.exercise_boundaries().unwrap().unwrap();
+        let points = boundaries[0].points();
+        let dates: Vec<DateTime> = points.iter().map(|p| p.date()).collect();
+        assert_eq!(&dates[..], &exercise[..]);
+        assert!(points[0].level() < points[1].level()
+            && points[1].level() < points[2].level(), "{:?}", points);
+
+        // the serialized boundary round-trips
+        let serialized = serde_json::to_string(&boundaries[0]).unwrap();
+        let deserialized: ExerciseBoundary = serde_json::from_str(&serialized).unwrap();
+        for (p, q) in deserialized.points().iter().zip(points.iter()) {
+            assert_eq!(p.date(), q.date());
+            assert!(approx_eq(p.level(), q.level(), 1e-12));
+        }
+
+        // under Heston, the boundary of the american is also below the
+        // strike and reaches it at expiry
+        let american = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleAmerican", "OPT", sample_underlying(), sample_settlement(2), expiry,
+            110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap()
+            .with_early_exercise(true))));
+        let pricer = PdePricer::new(vec![(1.0, american)],
+            heston_factory(AdiScheme::HundsdorferVerwer), &market_data).unwrap();
+        let boundaries = pricer.exercise_boundaries().unwrap().unwrap();
+        let points = boundaries[0].points();
+        assert!(points.iter().all(|p| p.level() < 110.0), "{:?}", points);
+        assert!(points[0].level() < 100.0, "{:?}", points[0]);
+        assert!(points.last().unwrap().level() > 109.0, "{:?}", points.last());
+    }
+
+    fn heston_factory(scheme: AdiScheme) -> PdePricerFactory {
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap());
+        let model = HestonPdeModel::new(parameters, 50, scheme).unwrap();
+        PdePricerFactory::with_model(400, 100, ExerciseMethod::Penalty,
+            PdeModel::Heston(model))
+    }
+
+    fn heston_price(instrument: RcInstrument, scheme: AdiScheme) -> f64 {
+        let market_data = sample_market_data();
+        let pricer = PdePricer::new(vec![(1.0, instrument)],
+            heston_factory(scheme), &market_data).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn pde_heston_european_matches_semi_analytic() {
+        let expiry = sample_expiry();
+        let underlying = sample_underlying();
+        let parameters = HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap();
+
+        // the semi-analytic price needs the forward and vol time that the
+        // grid uses, and the discount factor to the pay date
+        let terms = sample_european_terms(&sample_market_data(), expiry);
+        let (df, forward, t) = (terms.df, terms.forward, terms.vol_time);
+
+        for &(strike, put_or_call) in [(80.0, PutOrCall::Put), (100.0, PutOrCall::Call),
+            (120.0, PutOrCall::Call)].iter() {
+            let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+                "SampleEuropean", "OPT", underlying.clone(), sample_settlement(2),
+                expiry, strike, put_or_call, OptionSettlement::Cash).unwrap())));
+            let expected = match put_or_call {
+                PutOrCall::Call => parameters.call_price(df, forward, strike, t).unwrap(),
+                PutOrCall::Put => parameters.put_price(df, forward, strike, t).unwrap()
+            };
+            for &scheme in [AdiScheme::CraigSneyd, AdiScheme::ModifiedCraigSneyd,
+                AdiScheme::HundsdorferVerwer].iter() {
+                let price = heston_price(european.clone(), scheme);
+                assert!(approx_eq(price, expected, 0.01),
+                    "strike={} scheme={:?} price={} expected={}",
+                    strike, scheme, price, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn pde_heston_american_and_barrier() {
+        let expiry = sample_expiry();
+        let scheme = AdiScheme::HundsdorferVerwer;
+        let option = |american: bool| -> RcInstrument {
+            if american {
+                RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+                    "SampleAmerican", "OPT", sample_underlying(), sample_settlement(2),
+                    expiry, 110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap()
+                    .with_early_exercise(true))))
+            } else {
+                RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+                    "SampleEuropean", "OPT", sample_underlying(), sample_settlement(2),
+                    expiry, 110.0, PutOrCall::Put, OptionSettlement::Cash).unwrap())))
+            }
+        };
+        let american = heston_price(option(true), scheme);
+        let european = heston_price(option(false), scheme);
+        assert!(american > european + 0.01, "american={} european={}",
+            american, european);
+
+        // a discretely monitored down-and-out call, against Monte-Carlo on
+        // seeded paths of the same model
+        let market_data = sample_market_data();
+        let observations = vec![
+            DateTime::new(Date::from_ymd(2017, 04, 03), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 07, 03), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 10, 02), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close),
+            expiry];
+        let mut parameters = HashMap::new();
+        parameters.insert("BP.L".to_string(),
+            HestonParameters::new(0.09, 2.0, 0.08, 0.6, -0.7).unwrap());
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            HestonFactory::new(parameters, 1.0 / 52.0, 20000)));
+        let mut total = 0.0;
+        for &barrier_type in [BarrierType::DownAndOut, BarrierType::DownAndIn].iter() {
+            let option = RcInstrument::new(Qrc::new(Arc::new(BarrierOption::new(
+                "SampleBarrier", "OPT", sample_underlying(), sample_settlement(2),
+                expiry, 100.0, PutOrCall::Call, OptionSettlement::Cash,
+                85.0, barrier_type, BarrierMonitoring::Discrete(observations.clone()))
+                .unwrap())));
+            let price = heston_price(option.clone(), scheme);
+            let pricer = MonteCarloPricer::with_settings(vec![(1.0, option)],
+                model_factory.clone(), MonteCarloSettings {
+                    threading: Threading::new(1, Some(42)),
+                    ..MonteCarloSettings::default() }, &market_data).unwrap();
+            let result = pricer.price_with_statistics(1).unwrap().unwrap();
+            let expected = result.price();
+
+            // the standard errors are about 0.14 for the knock-out and 0.035
+            // for the knock-in, which dominate the grid and QE step errors
+            assert!(approx_eq(price, expected, 3.0 * result.standard_error()),
+                "barrier={:?} price={} expected={} standard_error={}",
+                barrier_type, price, expected, result.standard_error());
+            total += price;
+        }
+
+        // knock-in plus knock-out is the European
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropean", "OPT", sample_underlying(), sample_settlement(2),
+            expiry, 100.0, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+        let european = heston_price(european, scheme);
+        assert!(approx_eq(total, european, 1e-9), "total={} european={}",
+            total, european);
+    }
+
+    #[test]
+    fn pde_heston_serde() {
+        let factory = heston_factory(AdiScheme::ModifiedCraigSneyd);
+        let serialized = serde_json::to_string(&factory).unwrap();
+        let deserialized: PdePricerFactory = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.model, factory.model);
+
+        // factories serialized before the model was added are Black
+        let old = r#"{"space_steps":400,"time_steps":200,"exercise_method":"Penalty"}"#;
+        let deserialized: PdePricerFactory = serde_json::from_str(old).unwrap();
+        assert_eq!(deserialized.model, PdeModel::Black);
+    }
+}
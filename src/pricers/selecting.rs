@@ -0,0 +1,149 @@
+use core::qm;
+use std::sync::Arc;
+use instruments::RcInstrument;
+use pricers::PricerFactory;
+use pricers::RcPricerFactory;
+use risk::Pricer;
+use data::fixings::RcFixingTable;
+use risk::marketdata::RcMarketData;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Which instruments a rule of the SelectingPricerFactory applies to
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum InstrumentMatch {
+    /// Instruments with the given type id, such as "SpotStartingEuropean"
+    TypeId(String),
+    /// Instruments that may be exercised before expiry
+    EarlyExercise,
+    /// Barrier options
+    Barrier
+}
+
+impl InstrumentMatch {
+    pub fn matches(&self, instrument: &RcInstrument) -> bool {
+        match *self {
+            InstrumentMatch::TypeId(ref id) => instrument.type_id() == id,
+            InstrumentMatch::EarlyExercise => instrument.as_exercisable()
+                .map_or(false, |e| e.early_exercise()),
+            InstrumentMatch::Barrier => instrument.as_barrier().is_some()
+        }
+    }
+}
+
+/// The SelectingPricerFactory chooses the pricer for each instrument from
+/// a list of rules, each giving a pricer factory with all its numerical
+/// settings, such as the paths, steps or grid sizes. The first rule that
+/// matches the instrument is used, or the default if none do. As the rules
+/// and the factories they hold are serialized with it, one configuration
+/// describes fully how every instrument is priced.
+///
+/// Instruments are matched before their fixings are applied, so an
+/// instrument is priced the same way throughout its life.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SelectingPricerFactory {
+    rules: Vec<(InstrumentMatch, RcPricerFactory)>,
+    default: RcPricerFactory
+}
+
+impl SelectingPricerFactory {
+    pub fn new(rules: Vec<(InstrumentMatch, RcPricerFactory)>,
+        default: RcPricerFactory) -> SelectingPricerFactory {
+        SelectingPricerFactory { rules: rules, default: default }
+    }
+
+    /// The factory that prices the given instrument
+    pub fn select(&self, instrument: &RcInstrument) -> &RcPricerFactory {
+        self.rules.iter().find(|rule| rule.0.matches(instrument))
+            .map_or(&self.default, |rule| &rule.1)
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SelectingPricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for SelectingPricerFactory {
+    fn type_id(&self) -> &'static str { "SelectingPricerFactory" }
+}
+
+impl PricerFactory for SelectingPricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        let factory = self.select(&instrument).clone();
+        factory.new(instrument, fixing_table, market_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use pricers::pde::PdePricerFactory;
+    use pricers::pde::ExerciseMethod;
+    use pricers::lattice::LatticePricerFactory;
+    use pricers::lattice::LatticeType;
+    use pricers::selfpricer::SelfPricerFactory;
+    use data::fixings::FixingTable;
+    use dates::Date;
+    use serde_json;
+
+    fn sample_options() -> (RcInstrument, RcInstrument) {
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let american = SpotStartingEuropean::new("SampleAmerican", "OPT",
+            equity.clone(), sample_settlement(2), expiry, 110.0, PutOrCall::Put,
+            OptionSettlement::Cash).unwrap().with_early_exercise(true);
+        let european = SpotStartingEuropean::new("SampleEuropean", "OPT",
+            equity, sample_settlement(2), expiry, 110.0, PutOrCall::Put,
+            OptionSettlement::Cash).unwrap();
+        (RcInstrument::new(Qrc::new(Arc::new(american))),
+            RcInstrument::new(Qrc::new(Arc::new(european))))
+    }
+
+    fn price(factory: &RcPricerFactory, instrument: RcInstrument) -> f64 {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        factory.new(instrument, fixings, market_data).unwrap().price().unwrap()
+    }
+
+    #[test]
+    fn selecting_pricer_follows_rules_in_order() {
+        let (american, european) = sample_options();
+        let pde: RcPricerFactory = Qrc::new(Arc::new(PdePricerFactory::new(
+            200, 100, ExerciseMethod::Penalty)));
+        let lattice: RcPricerFactory = Qrc::new(Arc::new(
+            LatticePricerFactory::new(LatticeType::Binomial, 100)));
+        let default: RcPricerFactory = Qrc::new(Arc::new(SelfPricerFactory::new()));
+        let factory = SelectingPricerFactory::new(vec![
+            (InstrumentMatch::EarlyExercise, pde.clone()),
+            (InstrumentMatch::TypeId("SpotStartingEuropean".to_string()), lattice.clone()),
+            (InstrumentMatch::Barrier, pde.clone())], default);
+
+        // the American also matches the second rule, but the first wins
+        assert_eq!(factory.select(&american).type_id(), "PdePricerFactory");
+        assert_eq!(factory.select(&european).type_id(), "LatticePricerFactory");
+
+        // the whole configuration round-trips through serialization
+        let factory: RcPricerFactory = Qrc::new(Arc::new(factory));
+        let serialized = serde_json::to_string_pretty(&factory).unwrap();
+        print!("serialized: {}\n", serialized);
+        let factory: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(factory.type_id(), "SelectingPricerFactory");
+        assert_eq!(serde_json::to_string_pretty(&factory).unwrap(), serialized);
+
+        assert!(approx_eq(price(&factory, american.clone()), price(&pde, american), 1e-12));
+        assert!(approx_eq(price(&factory, european.clone()), price(&lattice, european), 1e-12));
+    }
+}
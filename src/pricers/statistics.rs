@@ -0,0 +1,247 @@
+use core::qm;
+use statrs::function::erf::erf_inv;
+use ndarray::ArrayView1;
+
+/// A Monte-Carlo price with the statistics needed to judge whether enough
+/// paths were used. The standard error is the standard deviation of the
+/// values of the paths over the square root of their number. Antithetic
+/// pairs of paths are not independent, so each pair counts as one sample.
+/// Low-discrepancy and stratified paths are not independent either, and
+/// for them the standard error is that of independent paths, which
+/// overstates the error of smooth payoffs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MonteCarloResult {
+    price: f64,
+    standard_error: f64,
+    n_samples: usize,
+    history: Vec<ConvergencePoint>
+}
+
+/// The estimate of a price from the samples up to the end of a batch
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ConvergencePoint {
+    n_samples: usize,
+    price: f64,
+    standard_error: f64
+}
+
+impl ConvergencePoint {
+    pub fn n_samples(&self) -> usize { self.n_samples }
+    pub fn price(&self) -> f64 { self.price }
+    pub fn standard_error(&self) -> f64 { self.standard_error }
+}
+
+impl MonteCarloResult {
+    /// Creates a result from a price and the value of each path, whose
+    /// mean should be the price. If the paths come in antithetic pairs,
+    /// each pair is averaged into one sample. If batches is more than one,
+    /// the samples are split into that many batches of equal size, apart
+    /// from the last, and the history shows the estimate after each batch.
+    pub fn new(price: f64, values: ArrayView1<f64>, paired: bool, batches: usize)
+        -> Result<MonteCarloResult, qm::Error> {
+
+        let samples: Vec<f64> = if paired {
+            if values.len() % 2 != 0 {
+                return Err(qm::Error::new("Antithetic paths must come in pairs"))
+            }
+            (0..values.len() / 2).map(|i| 0.5 * (values[2 * i] + values[2 * i + 1]))
+                .collect()
+        } else {
+            values.iter().cloned().collect()
+        };
+        let n = samples.len();
+        if n < 2 {
+            return Err(qm::Error::new("The standard error needs at least two samples"))
+        }
+
+        // accumulate about the first sample, to avoid cancellation when
+        // the standard error is small compared with the price
+        let offset = samples[0];
+        let mut history = Vec::new();
+        let batches = batches.max(1).min(n);
+        let batch_size = n / batches;
+        let (mut sum, mut sum_squares) = (0.0, 0.0);
+        for (i, sample) in samples.iter().enumerate() {
+            let x = sample - offset;
+            sum += x;
+            sum_squares += x * x;
+            let count = i + 1;
+            if batches > 1 && (count % batch_size == 0 && count / batch_size < batches
+                || count == n) {
+                let (mean, error) = mean_and_error(sum, sum_squares, count);
+                history.push(ConvergencePoint { n_samples: count,
+                    price: mean + offset, standard_error: error });
+            }
+        }
+        let (_, standard_error) = mean_and_error(sum, sum_squares, n);
+
+        Ok(MonteCarloResult { price: price, standard_error: standard_error,
+            n_samples: n, history: history })
+    }
+
+    pub fn price(&self) -> f64 { self.price }
+    pub fn standard_error(&self) -> f64 { self.standard_error }
+    pub fn n_samples(&self) -> usize { self.n_samples }
+
+    /// The result with a value known without error, such as an analytic
+    /// price, added to the price and to every estimate in the history
+    pub fn shifted(&self, amount: f64) -> MonteCarloResult {
+        let history = self.history.iter().map(|point| ConvergencePoint {
+            price: point.price + amount, ..*point }).collect();
+        MonteCarloResult { price: self.price + amount, history: history, ..*self }
+    }
+
+    /// The estimates after each batch, or empty if there was one batch
+    pub fn history(&self) -> &[ConvergencePoint] { &self.history }
+
+    /// The standard error as a fraction of the price
+    pub fn relative_error(&self) -> f64 {
+        self.standard_error / self.price.abs()
+    }
+
+    /// The two-sided confidence interval about the price at the given
+    /// level, such as 0.95, assuming the error is normally distributed,
+    /// which it is for many paths by the central limit theorem.
+    pub fn confidence_interval(&self, level: f64) -> Result<(f64, f64), qm::Error> {
+        if !(level > 0.0 && level < 1.0) {
+            return Err(qm::Error::new("The confidence level must be between \
+                zero and one"))
+        }
+        let half_width = 2.0_f64.sqrt() * erf_inv(level) * self.standard_error;
+        Ok((self.price - half_width, self.price + half_width))
+    }
+}
+
+/// The standard error that a Monte-Carlo price should reach, either as an
+/// absolute amount or as a fraction of the price
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorTolerance {
+    Absolute(f64),
+    Relative(f64)
+}
+
+/// Configures a Monte-Carlo pricer to run batches of paths until the
+/// standard error of its price is within the tolerance, rather than a fixed
+/// number of paths. Each batch has the number of paths of the model, so the
+/// maximum number of batches caps the paths at that many times as many. The
+/// number of batches is chosen when the pricer is built, and then fixed, so
+/// that bumped prices are on the same paths.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AdaptivePaths {
+    tolerance: ErrorTolerance,
+    max_batches: usize
+}
+
+impl AdaptivePaths {
+    pub fn new(tolerance: ErrorTolerance, max_batches: usize)
+        -> Result<AdaptivePaths, qm::Error> {
+
+        let target = match tolerance {
+            ErrorTolerance::Absolute(target) => target,
+            ErrorTolerance::Relative(target) => target
+        };
+        if !(target > 0.0) {
+            return Err(qm::Error::new("The error tolerance must be positive"))
+        }
+        if max_batches == 0 {
+            return Err(qm::Error::new("There must be at least one batch of paths"))
+        }
+        Ok(AdaptivePaths { tolerance: tolerance, max_batches: max_batches })
+    }
+
+    pub fn tolerance(&self) -> ErrorTolerance { self.tolerance }
+    pub fn max_batches(&self) -> usize { self.max_batches }
+
+    /// Whether the standard error of the result is within the tolerance
+    pub fn is_converged(&self, result: &MonteCarloResult) -> bool {
+        match self.tolerance {
+            ErrorTolerance::Absolute(target) => result.standard_error() <= target,
+            ErrorTolerance::Relative(target) => result.relative_error() <= target
+        }
+    }
+}
+
+/// The mean of the samples and its standard error, from their sum and sum
+/// of squares, using the unbiased estimate of the variance
+fn mean_and_error(sum: f64, sum_squares: f64, n: usize) -> (f64, f64) {
+    let count = n as f64;
+    let mean = sum / count;
+    if n < 2 {
+        return (mean, 0.0)
+    }
+    let variance = ((sum_squares - sum * mean) / (count - 1.0)).max(0.0);
+    (mean, (variance / count).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use ndarray::Array1;
+
+    #[test]
+    fn standard_error_and_interval() {
+        let values = Array1::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        let result = MonteCarloResult::new(4.5, values.view(), false, 1).unwrap();
+        assert_eq!(result.n_samples(), 8);
+        assert!(result.history().is_empty());
+
+        // the sample variance is 6, so the error is the root of 6 / 8
+        assert_approx(result.standard_error(), 0.75_f64.sqrt(), 1e-14);
+        let (low, high) = result.confidence_interval(0.95).unwrap();
+        assert_approx(high - 4.5, 1.959963984540054 * 0.75_f64.sqrt(), 1e-12);
+        assert_approx(4.5 - low, high - 4.5, 1e-14);
+        assert!(result.confidence_interval(1.0).is_err());
+    }
+
+    #[test]
+    fn antithetic_pairs_are_one_sample() {
+        let values = Array1::from_vec(vec![1.0, 3.0, 2.0, 6.0]);
+        let result = MonteCarloResult::new(3.0, values.view(), true, 1).unwrap();
+        assert_eq!(result.n_samples(), 2);
+        assert_approx(result.standard_error(), 1.0, 1e-14);
+
+        let odd = Array1::from_vec(vec![1.0, 3.0, 2.0]);
+        assert!(MonteCarloResult::new(2.0, odd.view(), true, 1).is_err());
+    }
+
+    #[test]
+    fn history_shows_each_batch() {
+        let values = Array1::from_vec((0..10).map(|i| i as f64).collect());
+        let result = MonteCarloResult::new(4.5, values.view(), false, 3).unwrap();
+        let history = result.history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].n_samples(), 3);
+        assert_approx(history[0].price(), 1.0, 1e-14);
+        assert_approx(history[0].standard_error(), (1.0_f64 / 3.0).sqrt(), 1e-14);
+        assert_eq!(history[1].n_samples(), 6);
+        assert_approx(history[1].price(), 2.5, 1e-14);
+
+        // the last batch takes the remainder, and ends at the full estimate
+        assert_eq!(history[2].n_samples(), 10);
+        assert_approx(history[2].price(), 4.5, 1e-14);
+        assert_approx(history[2].standard_error(), result.standard_error(), 1e-14);
+    }
+
+    #[test]
+    fn adaptive_paths_tolerances() {
+        let values = Array1::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        let result = MonteCarloResult::new(4.5, values.view(), false, 1).unwrap();
+
+        // the standard error is about 0.866, or 19% of the price
+        let absolute = |t| AdaptivePaths::new(ErrorTolerance::Absolute(t), 10).unwrap();
+        let relative = |t| AdaptivePaths::new(ErrorTolerance::Relative(t), 10).unwrap();
+        assert!(absolute(0.9).is_converged(&result));
+        assert!(!absolute(0.8).is_converged(&result));
+        assert!(relative(0.2).is_converged(&result));
+        assert!(!relative(0.19).is_converged(&result));
+
+        assert!(AdaptivePaths::new(ErrorTolerance::Relative(0.0), 10).is_err());
+        assert!(AdaptivePaths::new(ErrorTolerance::Absolute(0.1), 0).is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
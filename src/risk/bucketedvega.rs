@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::ReportTolerances;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use data::bump::Bump;
+use data::bumpvol::BumpVol;
+use data::bumpvol::VolBucket;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Bucketed vega is the vega to the vols near each pillar of a grid of
+/// expiries and strikes, rather than to the whole vol surface, so that it
+/// can be mapped to hedge instruments. This report shows a matrix of vegas
+/// for each of the underlyings that affect the price, indexed by expiry then
+/// strike. The buckets are weighted as described in data::bumpvol::VolBucket,
+/// so the vegas of all the buckets of an underlying add up, to first order,
+/// to its vega for a parallel bump.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BucketedVegaReport {
+    bumpsize: f64,
+    expiries: Vec<Date>,
+    strikes: Vec<f64>,
+    results: HashMap<String, Vec<Vec<f64>>>
+}
+
+impl Report for BucketedVegaReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for BucketedVegaReport {
+    fn type_id(&self) -> &'static str { "BucketedVegaReport" }
+}
+
+impl BucketedVegaReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(BucketedVegaReport::deserialize(de)?)))
+    }
+
+    pub fn expiries(&self) -> &[Date] { &self.expiries }
+    pub fn strikes(&self) -> &[f64] { &self.strikes }
+    pub fn results(&self) -> &HashMap<String, Vec<Vec<f64>>> { &self.results }
+
+    /// The total vega of an underlying across all the buckets
+    pub fn total(&self, id: &str) -> Option<f64> {
+        self.results.get(id).map(|matrix| matrix.iter()
+            .map(|row| row.iter().sum::<f64>()).sum())
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v BucketedVegaReport> for &'v BucketedVegaReport {
+    fn validate(self, other: &'v BucketedVegaReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "BucketedVegaReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // As for vega, use the currency risk tolerance scaled by one over
+        // bumpsize
+        let tolerance = tol.currency_risk() / self.bumpsize;
+        for (id, matrix) in &self.results {
+            if let Some(other_matrix) = other.results.get(id) {
+                if matrix.len() != other_matrix.len() {
+                    writeln!(diffs, "BucketedVegaReport: {} expiries {} != {}", id, matrix.len(), other_matrix.len())?;
+                }
+                for (i, (row, other_row)) in matrix.iter().zip(other_matrix.iter()).enumerate() {
+                    for (j, (vega, other_vega)) in row.iter().zip(other_row.iter()).enumerate() {
+                        if !approx_eq(*vega, *other_vega, tolerance) {
+                            writeln!(diffs, "BucketedVegaReport: {} bucket ({}, {}) vega {} != {} tol={}",
+                                id, i, j, vega, other_vega, tolerance)?;
+                        }
+                    }
+                }
+            } else {
+                write!(diffs, "BucketedVegaReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for BucketedVegaReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<BucketedVegaReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "BucketedVegaReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for bucketed vega by bumping. Each bucket of the grid of
+/// expiries and strikes is bumped up and down by an additive bump of the
+/// given size, and the vega is the central difference. If there are no
+/// strikes, the buckets are by expiry only, and similarly if there are no
+/// expiries. Vol cubes are bucketed by expiry and strike, but not by
+/// tenor.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BucketedVegaReportGenerator {
+    bumpsize: f64,
+    expiries: Vec<Date>,
+    strikes: Vec<f64>
+}
+
+impl BucketedVegaReportGenerator {
+    pub fn new(bumpsize: f64, expiries: Vec<Date>, strikes: Vec<f64>)
+        -> Result<BucketedVegaReportGenerator, qm::Error> {
+
+        // validate the grid once, rather than for every bucket
+        VolBucket::new(expiries.clone(), strikes.clone(), 0, 0)?;
+        Ok(BucketedVegaReportGenerator { bumpsize: bumpsize, expiries: expiries,
+            strikes: strikes })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BucketedVegaReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn bumpsize(&self) -> f64 { self.bumpsize }
+    pub fn expiries(&self) -> &[Date] { &self.expiries }
+    pub fn strikes(&self) -> &[f64] { &self.strikes }
+
+    /// The prices with each bucket of the vols of an underlying bumped up
+    /// and down, indexed by expiry then strike. The pricer is restored
+    /// after each bucket, so it may already have other bumps applied, such
+    /// as a spot bump saved elsewhere.
+    pub fn bumped_prices(&self, id: &str, is_cube: bool, pricer: &mut Pricer,
+        saveable: &mut Saveable, unbumped: f64)
+        -> Result<Vec<Vec<(f64, f64)>>, qm::Error> {
+
+        let new_bump = |bump: BumpVol| if is_cube {
+            Bump::new_vol_cube(id, bump)
+        } else {
+            Bump::new_vol(id, bump)
+        };
+
+        let n_expiries = self.expiries.len().max(1);
+        let n_strikes = self.strikes.len().max(1);
+        let mut matrix = Vec::with_capacity(n_expiries);
+        for i in 0..n_expiries {
+            let mut row = Vec::with_capacity(n_strikes);
+            for j in 0..n_strikes {
+                let bucket = VolBucket::new(self.expiries.clone(),
+                    self.strikes.clone(), i, j)?;
+
+                // bump up and reprice, then bump down by twice as much on
+                // top of the up bump (do not save the result from this)
+                let up = new_bump(BumpVol::new_bucketed(self.bumpsize, bucket.clone()));
+                let upbumped = bumped_price(&up, pricer, Some(saveable), unbumped)?;
+                let down = new_bump(BumpVol::new_bucketed(-2.0 * self.bumpsize, bucket));
+                let downbumped = bumped_price(&down, pricer, None, unbumped)?;
+
+                pricer.as_mut_bumpable().restore(saveable)?;
+                saveable.clear();
+
+                row.push((upbumped, downbumped));
+            }
+            matrix.push(row);
+        }
+        Ok(matrix)
+    }
+
+    /// The bucketed vegas of an underlying, as central differences of the
+    /// bumped prices
+    pub fn bucketed_vegas(&self, id: &str, is_cube: bool, pricer: &mut Pricer,
+        saveable: &mut Saveable, unbumped: f64) -> Result<Vec<Vec<f64>>, qm::Error> {
+
+        let prices = self.bumped_prices(id, is_cube, pricer, saveable, unbumped)?;
+        Ok(prices.iter().map(|row| row.iter()
+            .map(|&(up, down)| (up - down) / (2.0 * self.bumpsize)).collect())
+            .collect())
+    }
+}
+
+impl TypeId for BucketedVegaReportGenerator {
+    fn type_id(&self) -> &'static str { "BucketedVegaReportGenerator" }
+}
+
+impl ReportGenerator for BucketedVegaReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the underlyings we should have vega to, as for parallel vega
+        let (instruments, vol_cubes) = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let vol_cubes: Vec<String> = dependencies.vol_cubes().keys()
+                .map(|id| id.to_string()).collect();
+            (dependencies.instruments_clone(), vol_cubes)
+        };
+
+        let mut results = HashMap::new();
+        for id in instruments.iter() {
+            let vegas = self.bucketed_vegas(id, false, pricer, saveable, unbumped)?;
+            results.insert(id.to_string(), vegas);
+        }
+        for id in vol_cubes.iter() {
+            let vegas = self.bucketed_vegas(id, true, pricer, saveable, unbumped)?;
+            results.insert(id.to_string(), vegas);
+        }
+
+        Ok(Qbox::new(Box::new(BucketedVegaReport { bumpsize: self.bumpsize,
+            expiries: self.expiries.clone(), strikes: self.strikes.clone(),
+            results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::RcReportGenerator;
+    use risk::BoxReport;
+    use risk::deltagamma::tests::sample_pricer;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn sample_generator() -> BucketedVegaReportGenerator {
+        BucketedVegaReportGenerator::new(0.01,
+            vec![Date::from_ymd(2017, 06, 01), Date::from_ymd(2018, 01, 01),
+                Date::from_ymd(2019, 01, 01)],
+            vec![80.0, 100.0, 120.0]).unwrap()
+    }
+
+    #[test]
+    fn bucketed_vega_european() {
+        // an at the money call, expiring on 2018-06-01
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = sample_generator().generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<BucketedVegaReport>().unwrap();
+        let vegas = &results.results()["BP.L"];
+        assert_eq!(vegas.len(), 3);
+
+        // the vegas add up to the parallel vega, apart from second order
+        // effects as each bucket is bumped by less
+        let parallel = |size: f64| {
+            let mut pricer = sample_pricer();
+            pricer.as_mut_bumpable().bump(&Bump::new_vol("BP.L",
+                BumpVol::new_flat_additive(size)), None).unwrap();
+            pricer.price().unwrap()
+        };
+        let vega = (parallel(0.01) - parallel(-0.01)) / 0.02;
+        assert_approx(results.total("BP.L").unwrap(), vega, 1e-4 * vega);
+
+        // The vega is all at the strike of the option, and split between
+        // the expiries either side of it, in proportion to the days from
+        // each. Other buckets have no vega.
+        let later = 151.0 / 365.0;
+        assert_approx(vegas[1][1], vega * (1.0 - later), 1e-3 * vega);
+        assert_approx(vegas[2][1], vega * later, 1e-3 * vega);
+        for i in 0..3 {
+            for j in 0..3 {
+                if j != 1 || i == 0 {
+                    assert_eq!(vegas[i][j], 0.0, "bucket ({}, {})", i, j);
+                }
+            }
+        }
+
+        // the pricer is left as it was
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn bucketed_vega_by_expiry_only() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let generator = BucketedVegaReportGenerator::new(0.01,
+            vec![Date::from_ymd(2018, 01, 01), Date::from_ymd(2019, 01, 01)],
+            Vec::new()).unwrap();
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<BucketedVegaReport>().unwrap();
+        let vegas = &results.results()["BP.L"];
+        assert_eq!(vegas.len(), 2);
+        assert_eq!(vegas[0].len(), 1);
+        assert!(vegas[0][0] > vegas[1][0] && vegas[1][0] > 0.0);
+
+        assert!(BucketedVegaReportGenerator::new(0.01, Vec::new(),
+            vec![100.0, 100.0]).is_err());
+    }
+
+    #[test]
+    fn serde_bucketed_vega_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(sample_generator()));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let serialized = serde_json::to_string_pretty(&report).unwrap();
+        let deserialized: BoxReport = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&report, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
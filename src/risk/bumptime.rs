@@ -1,6 +1,7 @@
 use risk::Bumpable;
 use dates::Date;
 use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
 use core::qm;
 use std::collections::HashMap;
 use instruments::Instrument;
@@ -8,6 +9,7 @@ use instruments::RcInstrument;
 use instruments::fix_all;
 use instruments::PricingContext;
 use data::fixings::FixingTable;
+use data::divstream::dividend_fixing_id;
 use data::bumpspotdate::BumpSpotDate;
 use data::bumpspotdate::SpotDynamics;
 use data::bump::Bump;
@@ -67,24 +69,100 @@ impl BumpTime {
         // by the list of instruments.
         let mut fixing_map = HashMap::new();
         for (id, instrument) in dependencies.instruments_iter() {
-            for fixing in dependencies.fixings(id).iter() {
-                let date = fixing.date();
+
+            // Daily fixings are held as ranges, so expand just the part of
+            // each range that time is moving over. Several instruments may
+            // depend on the same fixing, so only add each one once.
+            let mut fixings: Vec<DateTime> = dependencies.fixings(id).iter()
+                .filter(|fixing| fixing.date() >= old_spot_date
+                    && fixing.date() < new_spot_date).cloned().collect();
+            for range in dependencies.fixing_ranges(id).iter() {
+                fixings.append(&mut range.dates_between(old_spot_date, new_spot_date));
+            }
+            fixings.sort();
+            fixings.dedup();
+
+            if fixings.is_empty() {
+                continue;
+            }
+
+            // Commodities have no spot, so their fixings are always taken
+            // from their forward curves
+            let sticky_spot = self.spot_date_bump.spot_dynamics() == SpotDynamics::StickySpot
+                && !instrument.is_forward_quoted();
+            let values = if sticky_spot {
+                let spot = context.spot(id)?;
+                fixings.iter().map(|fixing| (*fixing, spot)).collect()
+            } else {
+                let inst: &Instrument = &*instrument.clone();
+                let curve = context.forward_curve(inst, new_spot_date)?;
+                let mut values = Vec::with_capacity(fixings.len());
+                for fixing in fixings.iter() {
+                    values.push((*fixing, curve.forward(fixing.date())?));
+                }
+                values
+            };
+            fixing_map.insert(id.to_string(), values);
+        }
+
+        // Floating rate indices such as Libor are not instruments, so their
+        // fixings are projected from the forecast curve instead. Rates have
+        // no spot, so this is the same for both spot dynamics. Several
+        // instruments may depend on the same fixing, so only add it once.
+        for (id, fixings) in dependencies.rate_fixings().iter() {
+            for fixing in fixings.iter() {
+                let date = fixing.date().date();
                 if date >= old_spot_date && date < new_spot_date {
-                    let value = match self.spot_date_bump.spot_dynamics() {
-                        SpotDynamics::StickyForward => {
-                            // it looks inefficient to keep fetching the curves each time round
-                            // the loop, but by far the most common case has at most one fixing
-                            let inst: &Instrument = &*instrument.clone();
-                            let curve = context.forward_curve(inst, new_spot_date)?;
-                            curve.forward(date)? },
-                        SpotDynamics::StickySpot => {
-                            context.spot(id)? }
-                    };
+                    let entry = fixing_map.entry(id.to_string())
+                        .or_insert(Vec::<(DateTime, f64)>::new());
+                    if entry.iter().all(|&(existing, _)| existing != fixing.date()) {
+                        let forecast = context.yield_curve(fixing.forecast_id(), fixing.end())?;
+                        entry.push((fixing.date(), fixing.forward(&*forecast)?));
+                    }
+                }
+            }
+        }
 
+        // Price indices are not instruments either, so their values for each
+        // reference month are projected from the inflation curve
+        for (id, months) in dependencies.inflation_fixings().iter() {
+            for month in months.iter() {
+                if *month >= old_spot_date && *month < new_spot_date {
+                    let curve = context.inflation_curve(id, *month)?;
                     fixing_map.entry(id.to_string()).or_insert(Vec::<(DateTime, f64)>::new())
-                        .push((*fixing, value));
-                }           
+                        .push((DateTime::new(*month, TimeOfDay::Close),
+                            curve.forward_index(*month)?));
+                }
+            }
+        }
+
+        // Dividends that go ex as time moves past are recorded as fixings,
+        // taken from the forward curve. The curve is sticky however spot
+        // moves, so this is the same for both spot dynamics. An entry is
+        // added even if no dividends go ex, to show that none did.
+        for (id, high_water_mark) in dependencies.dividends().iter() {
+            if *high_water_mark < old_spot_date || new_spot_date <= old_spot_date {
+                continue;
+            }
+            let instrument = match dependencies.instrument_by_id(id) {
+                Some(instrument) => instrument.clone(),
+                None => return Err(qm::Error::new(&format!(
+                    "Dividend dependency on unknown instrument \"{}\"", id)))
+            };
+            let inst: &Instrument = &*instrument;
+            let curve = context.forward_curve(inst, *high_water_mark)?;
+            let last = if new_spot_date - 1 < *high_water_mark {
+                new_spot_date - 1 } else { *high_water_mark };
+            let mut dividends = Vec::<(DateTime, f64)>::new();
+            let mut date = old_spot_date;
+            while date <= last {
+                let amount = curve.undiscounted_divs(date - 1, date)?;
+                if amount != 0.0 {
+                    dividends.push((DateTime::new(date, TimeOfDay::Open), amount));
+                }
+                date += 1;
             }
+            fixing_map.insert(dividend_fixing_id(id), dividends);
         }
 
         // Apply the fixings to each of the instruments, and build up a new vector of them
@@ -99,6 +177,97 @@ impl BumpTime {
             }
         }
 
+        // Instruments with embedded exercise, such as callable bonds, make
+        // their exercise decisions as time moves past them, in date order.
+        // Only instruments in the top-level list are asked.
+        let mut any_exercised = false;
+        let mut replacement = Vec::with_capacity(instruments.len());
+        for &(weight, ref instrument) in instruments.iter() {
+            let mut dates: Vec<DateTime> = dependencies.exercises(instrument.id())
+                .iter().filter(|date| date.date() >= old_spot_date
+                    && date.date() < new_spot_date).cloned().collect();
+            dates.sort();
+
+            let mut current = vec![(weight, instrument.clone())];
+            for date in dates.iter() {
+                let mut next = Vec::with_capacity(current.len());
+                for &(weight, ref instrument) in current.iter() {
+                    if let Some(decomposition) = instrument.exercise(context, *date)? {
+                        any_exercised = true;
+                        for &(weight2, ref instrument) in decomposition.iter() {
+                            next.push((weight * weight2, instrument.clone()));
+                        }
+                    } else {
+                        next.push((weight, instrument.clone()));
+                    }
+                }
+                current = next;
+            }
+            replacement.append(&mut current);
+        }
+
+        if any_exercised {
+            *instruments = replacement;
+            any_changes = true;
+        }
+
         Ok(any_changes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use core::factories::Qrc;
+    use dates::datetime::TimeOfDay;
+    use instruments::Priceable;
+    use instruments::DependencyContext;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_forward_european;
+    use risk::marketdata::tests::sample_expiry;
+
+    #[test]
+    fn strike_date_fixing_turns_forward_start_into_vanilla() {
+        let market_data = sample_market_data();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let forward_start = sample_forward_european();
+        let instrument = RcInstrument::new(Qrc::new(forward_start.clone()));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&instrument);
+        let mut instruments = vec![(1.0, instrument)];
+
+        // the strike is set at the close today, so a bump to today leaves
+        // the option forward starting
+        let bump = BumpTime::new(spot_date, spot_date, SpotDynamics::StickyForward);
+        assert!(!bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments[0].1.type_id(), "ForwardStartingEuropean");
+
+        // once the strike date passes, the option becomes a vanilla with a
+        // strike of 95% of the fixing, which is the forward on the strike date
+        let bump = BumpTime::new(spot_date + 1, spot_date, SpotDynamics::StickyForward);
+        assert!(bump.update_instruments(&mut instruments, &market_data,
+            &dependencies).unwrap());
+        assert_eq!(instruments.len(), 1);
+        let (weight, ref vanilla) = instruments[0];
+        assert_eq!(weight, 1.0);
+        assert_eq!(vanilla.type_id(), "SpotStartingEuropean");
+
+        let underlying = dependencies.instrument_by_id("BP.L").unwrap().clone();
+        let fixing = market_data.forward_curve(&*underlying, spot_date + 1)
+            .unwrap().forward(spot_date).unwrap();
+        let expected = SpotStartingEuropean::new("Expected", "OPT", underlying,
+            forward_start.settlement().clone(),
+            sample_expiry(),
+            0.95 * fixing, PutOrCall::Call, OptionSettlement::Cash).unwrap();
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let price = vanilla.as_priceable().unwrap().price(&market_data, val_date).unwrap();
+        let expected_price = expected.price(&market_data, val_date).unwrap();
+        assert!(approx_eq(price, expected_price, 1e-12),
+            "price={} expected={}", price, expected_price);
+    }
+}
@@ -3,11 +3,15 @@ use std::collections::HashMap;
 use std::any::Any;
 use std::ops::Deref;
 use data::volsurface::RcVolSurface;
+use data::volcube::RcVolCube;
+use data::inflation::RcInflationCurve;
+use data::commodity::RcCommodityCurve;
 use data::forward::Forward;
 use data::curves::RcRateCurve;
 use data::bump::Bump;
 use dates::Date;
 use instruments::Instrument;
+use instruments::ExchangeRate;
 use instruments::PricingContext;
 use risk::dependencies::DependencyCollector;
 use risk::marketdata::MarketData;
@@ -129,10 +133,11 @@ impl PricingContextPrefetch {
             } else {
                 return Err(qm::Error::new("Cannot find instrument"))
             }
-        } else {
-            return Err(qm::Error::new("Cannot find prefetched forward"))
         }
 
+        // If nothing was prefetched for this id, there is nothing to refetch,
+        // though the pricer may still use the bumped data directly, such as
+        // a spot, so report it as bumped.
         Ok(true)
     }
 }
@@ -203,6 +208,53 @@ impl PricingContext for PricingContextPrefetch {
         -> Result<f64, qm::Error> {
         self.context.correlation(first, second)
     }
+
+    fn average_correlation(&self, first: &Instrument, second: &Instrument,
+        from: Date, to: Date) -> Result<f64, qm::Error> {
+        self.context.average_correlation(first, second, from, to)
+    }
+
+    fn fx_forward_curve(&self, pair: &ExchangeRate, _high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+        // FX forwards are prefetched along with the other forwards
+        find_cached_data(pair.id(), &self.forward_curves, "FX forward")
+    }
+
+    fn fx_vol_surface(&self, fx_id: &str, high_water_mark: Date)
+        -> Result<RcVolSurface, qm::Error> {
+        // FX vols are used by few instruments, so we do not cache them
+        self.context.fx_vol_surface(fx_id, high_water_mark)
+    }
+
+    fn vol_cube(&self, id: &str, high_water_mark: Date)
+        -> Result<RcVolCube, qm::Error> {
+        // as with FX vols, vol cubes are used by few instruments, so we do
+        // not cache them
+        self.context.vol_cube(id, high_water_mark)
+    }
+
+    fn hazard_curve(&self, credit_id: &str, high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        // hazard curves are also not cached
+        self.context.hazard_curve(credit_id, high_water_mark)
+    }
+
+    fn inflation_curve(&self, index_id: &str, high_water_mark: Date)
+        -> Result<RcInflationCurve, qm::Error> {
+        // nor are inflation curves
+        self.context.inflation_curve(index_id, high_water_mark)
+    }
+
+    fn commodity_curve(&self, id: &str, high_water_mark: Date)
+        -> Result<RcCommodityCurve, qm::Error> {
+        // commodity curves are fetched via the prefetched forwards
+        self.context.commodity_curve(id, high_water_mark)
+    }
+
+    fn correlation_by_id(&self, first: &str, second: &str)
+        -> Result<f64, qm::Error> {
+        self.context.correlation_by_id(first, second)
+    }
 }
 
 /// Look for market-data-derived objects in the cache. If they are not there,
@@ -254,7 +306,19 @@ impl Bumpable for PricingContextPrefetch {
         match bump {
             &Bump::Spot(ref id, _) => self.refetch(&id, bumped, false, saved_forward_curves, saved_vol_surfaces),
             &Bump::Divs(ref id, _) => self.refetch(&id, bumped, false, saved_forward_curves, saved_vol_surfaces),
+            &Bump::FxSpot(ref fx_id, _) => {
+                // only currency pairs have prefetched forwards. The rate may
+                // also be used directly, for example by quantos.
+                if self.forward_curves.contains_key(fx_id) {
+                    self.refetch(&fx_id, bumped, false, saved_forward_curves, saved_vol_surfaces)
+                } else {
+                    Ok(bumped)
+                }
+            },
             &Bump::Vol(ref id, _) => self.refetch(&id, false, bumped, saved_forward_curves, saved_vol_surfaces),
+            &Bump::VolCube(_, _) => Ok(bumped),
+            &Bump::Hazard(_, _) => Ok(bumped),
+            &Bump::Correlation(_, _, _) => Ok(bumped),
             &Bump::Borrow(ref id, _) => self.refetch(&id, bumped, false, saved_forward_curves, saved_vol_surfaces),
             &Bump::Yield(ref credit_id, _) => {
                 // we have to copy these ids to avoid a tangle with borrowing
@@ -0,0 +1,308 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpcorrelation::BumpCorrelation;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The sensitivity of the price to the correlation between two factors
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CorrelationSensitivity {
+    first: String,
+    second: String,
+    sensitivity: f64
+}
+
+impl CorrelationSensitivity {
+    pub fn first(&self) -> &str { &self.first }
+    pub fn second(&self) -> &str { &self.second }
+    pub fn sensitivity(&self) -> f64 { self.sensitivity }
+}
+
+/// This report shows the sensitivity of the price to the correlation
+/// between each pair of the underlyings that affect the price, where a
+/// correlation is supplied, ordered by the ids of the pair. It also shows
+/// the sensitivity to a parallel move in all of those correlations. The
+/// sensitivities are derivatives with respect to the correlation, so a
+/// move of 0.01 changes the price by a hundredth of them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CorrelationReport {
+    bumpsize: f64,
+    parallel: f64,
+    pairs: Vec<CorrelationSensitivity>
+}
+
+impl Report for CorrelationReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for CorrelationReport {
+    fn type_id(&self) -> &'static str { "CorrelationReport" }
+}
+
+impl CorrelationReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(CorrelationReport::deserialize(de)?)))
+    }
+
+    pub fn parallel(&self) -> f64 { self.parallel }
+    pub fn pairs(&self) -> &[CorrelationSensitivity] { &self.pairs }
+
+    /// Finds the sensitivity to the correlation between two factors, in
+    /// either order, if it is in the report
+    pub fn get(&self, first: &str, second: &str) -> Option<f64> {
+        self.pairs.iter().find(|pair| pair.first == first && pair.second == second
+            || pair.first == second && pair.second == first)
+            .map(|pair| pair.sensitivity)
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v CorrelationReport> for &'v CorrelationReport {
+    fn validate(self, other: &'v CorrelationReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.pairs.len() != other.pairs.len() {
+            writeln!(diffs, "CorrelationReport: number of pairs {} != {}", self.pairs.len(), other.pairs.len())?;
+        }
+
+        // Correlation sensitivity is a difference of prices divided by the
+        // bumpsize, as for vega
+        let tolerance = tol.currency_risk() / self.bumpsize;
+        if !approx_eq(self.parallel, other.parallel, tolerance) {
+            writeln!(diffs, "CorrelationReport: parallel {} != {} tol={}", self.parallel, other.parallel, tolerance)?;
+        }
+        for pair in self.pairs.iter() {
+            if let Some(other_sensitivity) = other.get(&pair.first, &pair.second) {
+                if !approx_eq(pair.sensitivity, other_sensitivity, tolerance) {
+                    writeln!(diffs, "CorrelationReport: {} {} {} != {} tol={}", pair.first,
+                        pair.second, pair.sensitivity, other_sensitivity, tolerance)?;
+                }
+            } else {
+                writeln!(diffs, "CorrelationReport: {} {} is missing", pair.first, pair.second)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for CorrelationReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<CorrelationReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "CorrelationReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for correlation sensitivities by bumping. The bump size is
+/// additive in correlation. Each pair is bumped up and down separately from
+/// the unbumped state, so that correlations, including any term structure,
+/// are restored exactly between bumps. Bumped correlations are clamped to
+/// between minus one and one, so for correlations within the bump size of
+/// these limits, the sensitivities are understated.
+///
+/// The underlyings are the instruments and FX rates that affect the price.
+/// Pairs with no supplied correlation are ignored, as bumping them does
+/// nothing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CorrelationReportGenerator {
+    bumpsize: f64
+}
+
+impl CorrelationReportGenerator {
+    pub fn new(bumpsize: f64) -> CorrelationReportGenerator {
+        CorrelationReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CorrelationReportGenerator::deserialize(de)?)))
+    }
+
+    /// Applies the bump to each of the pairs, returning the bumped price
+    /// and leaving the bumps in place, or None if none of them are supplied
+    fn bumped_price(&self, pairs: &[(String, String)], bump: f64,
+        pricer: &mut Pricer, saveable: &mut Saveable) -> Result<Option<f64>, qm::Error> {
+
+        let mut bumped = false;
+        for &(ref first, ref second) in pairs.iter() {
+            let bump = Bump::new_correlation(first, second, BumpCorrelation::new_additive(bump));
+            if pricer.as_mut_bumpable().bump(&bump, Some(saveable))? {
+                bumped = true;
+            }
+        }
+        if bumped {
+            Ok(Some(pricer.price()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The sensitivity to a move in all the given pairs, or None if none
+    /// of them are supplied
+    fn sensitivity(&self, pairs: &[(String, String)], pricer: &mut Pricer,
+        saveable: &mut Saveable) -> Result<Option<f64>, qm::Error> {
+
+        let upbumped = self.bumped_price(pairs, self.bumpsize, pricer, saveable)?;
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        let downbumped = self.bumped_price(pairs, -self.bumpsize, pricer, saveable)?;
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        Ok(match (upbumped, downbumped) {
+            (Some(up), Some(down)) => Some((up - down) / (2.0 * self.bumpsize)),
+            _ => None
+        })
+    }
+}
+
+impl TypeId for CorrelationReportGenerator {
+    fn type_id(&self) -> &'static str { "CorrelationReportGenerator" }
+}
+
+impl ReportGenerator for CorrelationReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, _unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the underlyings, including FX rates, which may be correlated
+        // with the instruments for example in quantos. Sort them so the
+        // pairs have a well-defined order.
+        let underlyings = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let mut underlyings = dependencies.instruments_clone();
+            for id in dependencies.fx_rates().keys() {
+                if !underlyings.contains(id) {
+                    underlyings.push(id.to_string());
+                }
+            }
+            underlyings.sort();
+            underlyings
+        };
+
+        let mut pairs = Vec::new();
+        let mut supplied = Vec::new();
+        for (i, first) in underlyings.iter().enumerate() {
+            for second in underlyings[i + 1..].iter() {
+                let pair = vec![(first.to_string(), second.to_string())];
+                if let Some(sensitivity) = self.sensitivity(&pair, pricer, saveable)? {
+                    pairs.push(CorrelationSensitivity { first: first.to_string(),
+                        second: second.to_string(), sensitivity: sensitivity });
+                    supplied.extend(pair.into_iter());
+                }
+            }
+        }
+
+        let parallel = self.sensitivity(&supplied, pricer, saveable)?.unwrap_or(0.0);
+
+        Ok(Qbox::new(Box::new(CorrelationReport { bumpsize: self.bumpsize,
+            parallel: parallel, pairs: pairs })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::selfpricer::SelfPricer;
+    use risk::RcReportGenerator;
+    use risk::BoxReport;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_margrabe;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn margrabe_price(correlation: f64) -> f64 {
+        SelfPricer::new(vec![(1.0, sample_margrabe())],
+            &sample_correlated_market_data(correlation)).unwrap().price().unwrap()
+    }
+
+    #[test]
+    fn correlation_exchange_option() {
+        let mut market_data = sample_correlated_market_data(0.5);
+
+        // a correlation with something the price does not depend on is
+        // not reported
+        market_data.set_correlation("GSK.L", "VOD.L", 0.3).unwrap();
+        let mut pricer = SelfPricer::new(vec![(1.0, sample_margrabe())],
+            &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = CorrelationReportGenerator::new(0.01);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<CorrelationReport>().unwrap();
+        assert_eq!(results.pairs().len(), 1);
+        assert_eq!(results.pairs()[0].first(), "BP.L");
+        assert_eq!(results.pairs()[0].second(), "GSK.L");
+
+        // Higher correlation lowers the vol of the spread, and so the
+        // price of the exchange option. The sensitivity matches repricing
+        // at bumped correlations.
+        let sensitivity = results.get("GSK.L", "BP.L").unwrap();
+        let expected = (margrabe_price(0.51) - margrabe_price(0.49)) / 0.02;
+        assert!(sensitivity < 0.0, "sensitivity={}", sensitivity);
+        assert_approx(sensitivity, expected, 1e-9);
+        assert!(results.get("GSK.L", "VOD.L").is_none());
+
+        // with only one pair, the parallel sensitivity is the same
+        assert_approx(results.parallel(), sensitivity, 1e-12);
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn correlation_single_underlying() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let generator = CorrelationReportGenerator::new(0.01);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<CorrelationReport>().unwrap();
+        assert!(results.pairs().is_empty());
+        assert_eq!(results.parallel(), 0.0);
+    }
+
+    #[test]
+    fn serde_correlation_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(CorrelationReportGenerator::new(0.01)));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+
+        let mut pricer = SelfPricer::new(vec![(1.0, sample_margrabe())],
+            &sample_correlated_market_data(0.5)).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let serialized = serde_json::to_string_pretty(&report).unwrap();
+        let deserialized: BoxReport = serde_json::from_str(&serialized).unwrap();
+        let deserialized = deserialized.as_any().downcast_ref::<CorrelationReport>().unwrap();
+        assert_eq!(deserialized.pairs().len(), 1);
+        assert_approx(deserialized.get("BP.L", "GSK.L").unwrap(),
+            report.as_any().downcast_ref::<CorrelationReport>().unwrap()
+                .get("BP.L", "GSK.L").unwrap(), 1e-12);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,366 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Cross gamma is the second derivative of price with respect to the spot
+/// values of two underlyings. This report shows the full matrix of second
+/// derivatives between the underlyings that affect the price, including
+/// FX rates. The diagonal is the gamma of each underlying, and the matrix
+/// is symmetric. The ids are sorted, and give the order of the rows and
+/// columns.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CrossGammaReport {
+    bumpsize: f64,
+    ids: Vec<String>,
+    matrix: Vec<Vec<f64>>
+}
+
+impl Report for CrossGammaReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for CrossGammaReport {
+    fn type_id(&self) -> &'static str { "CrossGammaReport" }
+}
+
+impl CrossGammaReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(CrossGammaReport::deserialize(de)?)))
+    }
+
+    pub fn ids(&self) -> &[String] { &self.ids }
+    pub fn matrix(&self) -> &[Vec<f64>] { &self.matrix }
+
+    /// The cross gamma between two underlyings, in either order, or None if
+    /// the price does not depend on both of them
+    pub fn cross_gamma(&self, first: &str, second: &str) -> Option<f64> {
+        let i = self.ids.iter().position(|id| id == first)?;
+        let j = self.ids.iter().position(|id| id == second)?;
+        Some(self.matrix[i][j])
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v CrossGammaReport> for &'v CrossGammaReport {
+    fn validate(self, other: &'v CrossGammaReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.ids != other.ids {
+            writeln!(diffs, "CrossGammaReport: underlyings {:?} != {:?}", self.ids, other.ids)?;
+            return Ok(())
+        }
+
+        // Like gamma, cross gamma is based on diffs, so use the unit_risk
+        // tolerance scaled by one over bumpsize squared.
+        let tolerance = tol.unit_risk() / self.bumpsize.powi(2);
+        for (i, (row, other_row)) in self.matrix.iter().zip(other.matrix.iter()).enumerate() {
+            for (j, (value, other_value)) in row.iter().zip(other_row.iter()).enumerate() {
+                if !approx_eq(*value, *other_value, tolerance) {
+                    writeln!(diffs, "CrossGammaReport: {} {} cross gamma {} != {} tol={}",
+                        self.ids[i], self.ids[j], value, other_value, tolerance)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for CrossGammaReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<CrossGammaReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "CrossGammaReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for cross gamma by bumping. The bump size is specified as a
+/// fraction of the current spot.
+///
+/// Each underlying is bumped up and down, as for delta and gamma. Then for
+/// each pair, the first underlying is bumped up and left bumped while each
+/// of the later ones is bumped up and restored in turn, and the same again
+/// for the down bumps. The cross gamma is the average of the estimates from
+/// the up and the down bumps, which cancels the third order errors of each.
+/// Together with the single bumps, this needs n(n + 1) revaluations for n
+/// underlyings, rather than the 2n(n - 1) + 2n of bumping each pair
+/// separately in all four directions.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CrossGammaReportGenerator {
+    bumpsize: f64
+}
+
+impl CrossGammaReportGenerator {
+    pub fn new(bumpsize: f64) -> CrossGammaReportGenerator {
+        CrossGammaReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CrossGammaReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for CrossGammaReportGenerator {
+    fn type_id(&self) -> &'static str { "CrossGammaReportGenerator" }
+}
+
+impl ReportGenerator for CrossGammaReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // As with delta, the down bump is applied on top of the up bump,
+        // cancelling it out without a restore. The pairs are bumped down
+        // from the unbumped state, as they are restored between bumps.
+        let up = BumpSpot::new_relative(self.bumpsize);
+        let down_from_up = BumpSpot::new_relative(
+            (1.0 - self.bumpsize) / (1.0 + self.bumpsize) - 1.0);
+        let down = BumpSpot::new_relative(-self.bumpsize);
+
+        // Find the underlyings, as for delta, but sorted so the matrix has
+        // a well-defined order
+        let underlyings = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let fx_ids: Vec<String> = dependencies.fx_rates().keys()
+                .map(|id| id.to_string()).collect();
+            let mut instruments = dependencies.instruments_clone();
+            instruments.retain(|id| !fx_ids.contains(id));
+            let mut underlyings: Vec<(String, bool)> = instruments.into_iter()
+                .map(|id| (id, false))
+                .chain(fx_ids.into_iter().map(|id| (id, true))).collect();
+            underlyings.sort();
+            underlyings
+        };
+
+        let new_bump = |i: usize, bump: &BumpSpot| {
+            let (ref id, is_fx) = underlyings[i];
+            if is_fx {
+                Bump::new_fx_spot(id, bump.clone())
+            } else {
+                Bump::new_spot(id, bump.clone())
+            }
+        };
+
+        let n = underlyings.len();
+        let mut bumpsizes = Vec::with_capacity(n);
+        for &(ref id, is_fx) in underlyings.iter() {
+            let spot = if is_fx {
+                pricer.as_bumpable().context().fx_spot(id)?
+            } else {
+                pricer.as_bumpable().context().spot(id)?
+            };
+            bumpsizes.push(self.bumpsize * spot);
+        }
+
+        // the single bumps give the diagonal
+        let mut upbumped = Vec::with_capacity(n);
+        let mut downbumped = Vec::with_capacity(n);
+        for i in 0..n {
+            upbumped.push(bumped_price(&new_bump(i, &up), pricer,
+                Some(saveable), unbumped)?);
+            downbumped.push(bumped_price(&new_bump(i, &down_from_up), pricer,
+                None, unbumped)?);
+            pricer.as_mut_bumpable().restore(saveable)?;
+            saveable.clear();
+        }
+
+        let mut matrix = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            matrix[i][i] = (upbumped[i] + downbumped[i] - 2.0 * unbumped)
+                / bumpsizes[i].powi(2);
+        }
+
+        // each pair is bumped with the first underlying left bumped, using
+        // a second save area for the later ones
+        if n > 1 {
+            let mut inner = pricer.as_bumpable().new_saveable();
+            for i in 0..(n - 1) {
+                let mut both_up = Vec::with_capacity(n - i - 1);
+                let mut both_down = Vec::with_capacity(n - i - 1);
+
+                bumped_price(&new_bump(i, &up), pricer, Some(saveable), unbumped)?;
+                for j in (i + 1)..n {
+                    both_up.push(bumped_price(&new_bump(j, &up), pricer,
+                        Some(&mut *inner), upbumped[i])?);
+                    pricer.as_mut_bumpable().restore(&*inner)?;
+                    inner.clear();
+                }
+
+                bumped_price(&new_bump(i, &down_from_up), pricer, None, unbumped)?;
+                for j in (i + 1)..n {
+                    both_down.push(bumped_price(&new_bump(j, &down), pricer,
+                        Some(&mut *inner), downbumped[i])?);
+                    pricer.as_mut_bumpable().restore(&*inner)?;
+                    inner.clear();
+                }
+
+                pricer.as_mut_bumpable().restore(saveable)?;
+                saveable.clear();
+
+                for (k, j) in ((i + 1)..n).enumerate() {
+                    let cross = (both_up[k] - upbumped[i] - upbumped[j]
+                        + both_down[k] - downbumped[i] - downbumped[j]
+                        + 2.0 * unbumped) / (2.0 * bumpsizes[i] * bumpsizes[j]);
+                    matrix[i][j] = cross;
+                    matrix[j][i] = cross;
+                }
+            }
+        }
+
+        let ids = underlyings.into_iter().map(|(id, _)| id).collect();
+        Ok(Qbox::new(Box::new(CrossGammaReport { bumpsize: self.bumpsize,
+            ids: ids, matrix: matrix })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::selfpricer::SelfPricer;
+    use risk::RcReportGenerator;
+    use risk::BoxReport;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::deltagamma::DeltaGammaReport;
+    use risk::deltagamma::tests::sample_pricer;
+    use instruments::PricingContext;
+    use risk::Bumpable;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::RcMonteCarloModelFactory;
+    use models::Threading;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_margrabe;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    #[test]
+    fn cross_gamma_exchange_option() {
+        let market_data = sample_correlated_market_data(0.5);
+        let mut pricer = SelfPricer::new(vec![(1.0, sample_margrabe())],
+            &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = CrossGammaReportGenerator::new(0.01);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<CrossGammaReport>().unwrap();
+        assert_eq!(results.ids(), &["BP.L".to_string(), "GSK.L".to_string()]);
+        let bp_bp = results.cross_gamma("BP.L", "BP.L").unwrap();
+        let bp_gsk = results.cross_gamma("BP.L", "GSK.L").unwrap();
+        let gsk_gsk = results.cross_gamma("GSK.L", "GSK.L").unwrap();
+        assert_eq!(bp_gsk, results.cross_gamma("GSK.L", "BP.L").unwrap());
+        assert!(results.cross_gamma("BP.L", "VOD.L").is_none());
+
+        // the diagonal is the same as the gamma
+        let generator = DeltaGammaReportGenerator::new(0.01);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let gammas = report.as_any().downcast_ref::<DeltaGammaReport>().unwrap().results();
+        assert_approx(bp_bp, gammas["BP.L"].gamma(), 1e-12);
+        assert_approx(gsk_gsk, gammas["GSK.L"].gamma(), 1e-12);
+
+        // the cross gamma matches bumping the pair in all four directions
+        let bp = market_data.spot("BP.L").unwrap();
+        let gsk = market_data.spot("GSK.L").unwrap();
+        let bumped = |bp_bump: f64, gsk_bump: f64| {
+            let mut pricer = SelfPricer::new(vec![(1.0, sample_margrabe())],
+                &market_data).unwrap();
+            pricer.bump(&Bump::new_spot("BP.L", BumpSpot::new_relative(bp_bump)), None).unwrap();
+            pricer.bump(&Bump::new_spot("GSK.L", BumpSpot::new_relative(gsk_bump)), None).unwrap();
+            pricer.price().unwrap()
+        };
+        let four_way = (bumped(0.01, 0.01) - bumped(0.01, -0.01)
+            - bumped(-0.01, 0.01) + bumped(-0.01, -0.01)) / (4.0 * 0.01 * bp * 0.01 * gsk);
+        assert_approx(bp_gsk, four_way, 1e-3 * four_way.abs());
+
+        // delivering more GSK.L is worth less the higher BP.L is, and as
+        // the payoff is nearly homogeneous in the spots, the deltas hardly
+        // change if both spots move together
+        assert!(bp_gsk < 0.0 && bp_bp > 0.0 && gsk_gsk > 0.0);
+        assert_approx(bp * bp_bp + gsk * bp_gsk, 0.0, 0.02 * bp * bp_bp);
+
+        // and the pricer is left as it was
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn cross_gamma_monte_carlo() {
+        let market_data = sample_correlated_market_data(0.5);
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 10000)));
+        let mut pricer = MonteCarloPricer::with_settings(vec![(1.0, sample_margrabe())],
+            factory, MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let generator = CrossGammaReportGenerator::new(0.05);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<CrossGammaReport>().unwrap();
+
+        // the same paths are used for every bump, so the cross gamma is
+        // close to the analytic one, and the nested bumps are all restored
+        let mut analytic = SelfPricer::new(vec![(1.0, sample_margrabe())],
+            &market_data).unwrap();
+        let analytic_unbumped = analytic.price().unwrap();
+        let mut save = analytic.as_bumpable().new_saveable();
+        let report = generator.generate(&mut analytic, &mut *save, analytic_unbumped).unwrap();
+        let expected = report.as_any().downcast_ref::<CrossGammaReport>().unwrap()
+            .cross_gamma("BP.L", "GSK.L").unwrap();
+        assert_approx(results.cross_gamma("BP.L", "GSK.L").unwrap(), expected,
+            0.2 * expected.abs());
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn cross_gamma_single_underlying() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let generator = CrossGammaReportGenerator::new(0.01);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<CrossGammaReport>().unwrap();
+        assert_eq!(results.matrix().len(), 1);
+        let generator = DeltaGammaReportGenerator::new(0.01);
+        let gammas = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let gammas = gammas.as_any().downcast_ref::<DeltaGammaReport>().unwrap().results();
+        assert_approx(results.cross_gamma("BP.L", "BP.L").unwrap(),
+            gammas["BP.L"].gamma(), 1e-12);
+
+        // round trip the report via JSON
+        let serialized = serde_json::to_string_pretty(&report).unwrap();
+        let deserialized: BoxReport = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&report, &deserialized);
+    }
+
+    #[test]
+    fn serde_cross_gamma_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(CrossGammaReportGenerator::new(0.01)));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpyield::BumpYield;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// CS01 is the change in price for a one basis point rise in the hazard
+/// rate of an entity that may default. This report shows the CS01 with
+/// respect to each of the hazard curves that affect the price, keyed by
+/// credit id.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cs01Report {
+    bumpsize: f64,
+    results: HashMap<String, f64>
+}
+
+impl Report for Cs01Report {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for Cs01Report {
+    fn type_id(&self) -> &'static str { "Cs01Report" }
+}
+
+impl Cs01Report {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(Cs01Report::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &HashMap<String, f64> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v Cs01Report> for &'v Cs01Report {
+    fn validate(self, other: &'v Cs01Report, tol: &ReportTolerances, 
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "Cs01Report: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // DV01 is a difference of prices scaled to one basis point, so the
+        // currency risk tolerance is scaled in the same way
+        let tolerance = tol.currency_risk() * BASIS_POINT / self.bumpsize;
+        for (id, cs01) in &self.results {
+            if let Some(other_cs01) = other.results.get(id) {
+                if !approx_eq(*cs01, *other_cs01, tolerance) {
+                    writeln!(diffs, "Cs01Report: {} cs01 {} != {} tol={}", id, cs01, other_cs01, tolerance)?;
+                }
+            } else {
+                write!(diffs, "Cs01Report: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for Cs01Report {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<Cs01Report>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "Cs01Report: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+const BASIS_POINT: f64 = 0.0001;
+
+/// Calculator for CS01 by bumping. The bump size is a flat annualised
+/// shift in the hazard rate, so 0.0001 is one basis point. Whatever the bump size,
+/// the results are scaled to one basis point, and are calculated from
+/// symmetric up and down bumps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Cs01ReportGenerator {
+    bumpsize: f64
+}
+
+impl Cs01ReportGenerator {
+    pub fn new(bumpsize: f64) -> Cs01ReportGenerator {
+        Cs01ReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Cs01ReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for Cs01ReportGenerator {
+    fn type_id(&self) -> &'static str { "Cs01ReportGenerator" }
+}
+
+impl ReportGenerator for Cs01ReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Annualised bumps are additive in one plus the rate, so bumping
+        // down by twice the bumpsize cancels out the original up bump
+        let up = self.bumpsize;
+        let down = -2.0 * self.bumpsize;
+
+        // Find the hazard curves we should have risk to. Note that we need to
+        // clone the list of credit ids, to avoid borrowing problems.
+        let credit_ids: Vec<String> = pricer.as_bumpable().dependencies()?
+            .hazard_curves().keys().map(|id| id.to_string()).collect();
+
+        let mut results = HashMap::new();
+        for id in credit_ids.iter() {
+
+            // bump up and reprice
+            let bump = Bump::new_hazard(id, BumpYield::new_flat_annualised(up));
+            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+
+            // bump down and reprice (do not save the result from this)
+            let bump = Bump::new_hazard(id, BumpYield::new_flat_annualised(down));
+            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
+
+            pricer.as_mut_bumpable().restore(saveable)?;
+            saveable.clear();
+
+            let cs01 = (upbumped - downbumped) / (2.0 * self.bumpsize) * BASIS_POINT;
+            results.insert(id.to_string(), cs01);
+        }
+
+        Ok(Qbox::new(Box::new(Cs01Report { bumpsize: self.bumpsize, results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::RcInstrument;
+    use instruments::swaps::PayOrReceive;
+    use instruments::credit::tests::sample_cds;
+    use instruments::credit::tests::sample_cds_market_data;
+    use pricers::selfpricer::SelfPricer;
+    use risk::RcReportGenerator;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    #[test]
+    fn cs01_credit_default_swap() {
+        let market_data = sample_cds_market_data(0.02);
+        let cds = RcInstrument::new(Qrc::new(Arc::new(
+            sample_cds(0.01, PayOrReceive::Pay))));
+        let mut pricer = SelfPricer::new(vec![(1.0, cds)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = Cs01ReportGenerator::new(0.0001);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<Cs01Report>().unwrap().results().clone();
+
+        // the only credit risk is to the reference entity, and the buyer of
+        // protection gains as its credit worsens. For a five year swap on
+        // a notional of a million, a basis point is worth a few hundred.
+        assert_eq!(results.len(), 1);
+        let cs01 = *results.get("BP.ISSUER").unwrap();
+        assert!(cs01 > 200.0 && cs01 < 500.0, "cs01={}", cs01);
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-9);
+    }
+
+    #[test]
+    fn serde_cs01_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(Cs01ReportGenerator::new(0.0001)));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
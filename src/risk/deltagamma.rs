@@ -9,10 +9,14 @@ use risk::ReportGenerator;
 use risk::Pricer;
 use risk::Saveable;
 use risk::bumped_price;
+use risk::bumped_path_greeks;
+use risk::path_greeks;
+use risk::GreekMethod;
 use risk::ApproxEqReport;
 use risk::ReportTolerances;
 use data::bump::Bump;
 use data::bumpspot::BumpSpot;
+use pricers::adjoint::MonteCarloGreeks;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::{Qrc, Qbox};
@@ -114,14 +118,25 @@ impl<'v> ApproxEq<DeltaGammaTolerances, &'v DeltaGamma> for &'v DeltaGamma {
 
 /// Calculator for delta and gamma by bumping. The bump size is specified as
 /// a fraction of the current spot.
+///
+/// Alternatively, the deltas may be estimated directly from the paths of a
+/// Monte-Carlo pricer, in which case the gammas are central differences of
+/// the estimated deltas, with the same bumps. Underlyings that the
+/// estimator does not cover, such as FX rates, are still bumped.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DeltaGammaReportGenerator {
-    bumpsize: f64
+    bumpsize: f64,
+    #[serde(default)]
+    method: GreekMethod
 }
 
 impl DeltaGammaReportGenerator {
     pub fn new(bumpsize: f64) -> DeltaGammaReportGenerator {
-        DeltaGammaReportGenerator { bumpsize: bumpsize }
+        DeltaGammaReportGenerator::with_method(bumpsize, GreekMethod::Bumped)
+    }
+
+    pub fn with_method(bumpsize: f64, method: GreekMethod) -> DeltaGammaReportGenerator {
+        DeltaGammaReportGenerator { bumpsize: bumpsize, method: method }
     }
 
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
@@ -145,19 +160,65 @@ impl ReportGenerator for DeltaGammaReportGenerator {
         let down = BumpSpot::new_relative(down_bump);
 
         // Find the underlyings we should have delta to. Note that we need to
-        // clone the list of instruments, to avoid borrowing problems.
-        let instruments = pricer.as_bumpable().dependencies()?.instruments_clone();
+        // clone the list of instruments, to avoid borrowing problems. FX
+        // rates are included whether or not they are instruments, so we
+        // have delta to the rates used by quantos, for example.
+        let (instruments, fx_ids) = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let fx_ids: Vec<String> = dependencies.fx_rates().keys()
+                .map(|id| id.to_string()).collect();
+            let mut instruments = dependencies.instruments_clone();
+            instruments.retain(|id| !fx_ids.contains(id));
+            (instruments, fx_ids)
+        };
+
+        let greeks = match self.method {
+            GreekMethod::Bumped => None,
+            method => Some(path_greeks(pricer, method)?)
+        };
+
+        let underlyings = instruments.iter().map(|id| (id, false))
+            .chain(fx_ids.iter().map(|id| (id, true)));
         let mut results = HashMap::new();
-        for id in instruments.iter() {
+        for (id, is_fx) in underlyings {
 
-            let spot = pricer.as_bumpable().context().spot(id)?;
+            let spot = if is_fx {
+                pricer.as_bumpable().context().fx_spot(id)?
+            } else {
+                pricer.as_bumpable().context().spot(id)?
+            };
+            let new_bump = |bump: &BumpSpot| if is_fx {
+                Bump::new_fx_spot(id, bump.clone())
+            } else {
+                Bump::new_spot(id, bump.clone())
+            };
+
+            if let (Some(greeks), false) = (greeks.as_ref(), is_fx) {
+                if let Some(&delta) = greeks.deltas().get(id) {
+                    let bump = new_bump(&up);
+                    let upbumped = bumped_path_greeks(&bump, pricer,
+                        Some(saveable), self.method, greeks)?;
+                    let bump = new_bump(&down);
+                    let downbumped = bumped_path_greeks(&bump, pricer, None,
+                        self.method, greeks)?;
+
+                    pricer.as_mut_bumpable().restore(saveable)?;
+                    saveable.clear();
+
+                    let bumpsize = self.bumpsize * spot;
+                    let gamma = (path_delta(&upbumped, id)?
+                        - path_delta(&downbumped, id)?) / (2.0 * bumpsize);
+                    results.insert(id.to_string(), DeltaGamma {delta, gamma});
+                    continue;
+                }
+            }
 
             // bump up and reprice
-            let bump = Bump::new_spot(id, up.clone());
+            let bump = new_bump(&up);
             let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
             
             // bump down and reprice (do not save the result from this)
-            let bump = Bump::new_spot(id, down.clone());
+            let bump = new_bump(&down);
             let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
 
             pricer.as_mut_bumpable().restore(saveable)?;
@@ -174,6 +235,11 @@ impl ReportGenerator for DeltaGammaReportGenerator {
     }
 }
 
+fn path_delta(greeks: &MonteCarloGreeks, id: &str) -> Result<f64, qm::Error> {
+    greeks.deltas().get(id).cloned().ok_or_else(|| qm::Error::new(&format!(
+        "No delta estimated for {} after bumping", id)))
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -195,6 +261,11 @@ pub mod tests {
     use dates::datetime::TimeOfDay;
     use core::factories::Qrc;
     use core::factories::tests::assert_debug_eq;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::PathGeneration;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
     use serde_json;
 
     // a sample pricer that evaluates european options
@@ -295,6 +366,53 @@ pub mod tests {
         assert_approx(delta_gamma.gamma(), 0.01017907258926698, 1e-12);
     }
 
+    #[test]
+    fn delta_gamma_from_paths() {
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 16383)));
+        let mut pricer = MonteCarloPricer::with_settings(vec![(1.0, instrument)],
+            model_factory, MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.new_saveable();
+
+        let bumped = DeltaGammaReportGenerator::new(0.01);
+        let report = bumped.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let bumped = report.as_any().downcast_ref::<DeltaGammaReport>().unwrap()
+            .results()["BP.L"].delta();
+
+        // on the same paths, the pathwise and bumped greeks are close
+        let generator = DeltaGammaReportGenerator::with_method(0.01, GreekMethod::Pathwise);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<DeltaGammaReport>().unwrap().results();
+        assert_approx(results["BP.L"].delta(), bumped, 2e-3);
+        assert_approx(results["BP.L"].gamma(), 0.0102, 2e-3);
+
+        // and the pricer is left as it was
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // other pricers cannot estimate greeks from paths
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(generator.generate(&mut *pricer, &mut *save, unbumped).is_err());
+    }
+
+    #[test]
+    fn serde_delta_gamma_generator_defaults_to_bumping() {
+        let generator: DeltaGammaReportGenerator = serde_json::from_str(
+            r#"{ "bumpsize": 0.01 }"#).unwrap();
+        assert_eq!(generator.method, GreekMethod::Bumped);
+
+        let generator = RcReportGenerator::new(Arc::new(
+            DeltaGammaReportGenerator::with_method(0.01, GreekMethod::LikelihoodRatio)));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
     #[test]
     fn serde_delta_gamma_generator_roundtrip() {
 
@@ -2,8 +2,11 @@ use std::collections::hash_map::Iter;
 use instruments::DependencyContext;
 use dates::Date;
 use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
 use instruments::RcInstrument;
 use instruments::SpotRequirement;
+use data::fixings::RateFixing;
+use data::fixings::FixingRange;
 use std::collections::HashSet;
 use std::collections::HashMap;
 
@@ -17,8 +20,18 @@ pub struct DependencyCollector {
     instruments: HashMap<String, RcInstrument>,
     forward_id_from_credit_id: HashMap<String, Vec<String>>,
     fixings: HashMap<String, Vec<DateTime>>,
+    fixing_ranges: HashMap<String, Vec<FixingRange>>,
+    rate_fixings: HashMap<String, Vec<RateFixing>>,
+    fx_rates: HashMap<String, Date>,
+    vol_cubes: HashMap<String, Date>,
+    exercises: HashMap<String, Vec<DateTime>>,
+    hazard_curves: HashMap<String, Date>,
+    inflation_curves: HashMap<String, Date>,
+    inflation_fixings: HashMap<String, Vec<Date>>,
+    dividends: HashMap<String, Date>,
     empty: Vec<String>,
-    empty_fixings: Vec<DateTime>
+    empty_fixings: Vec<DateTime>,
+    empty_fixing_ranges: Vec<FixingRange>
 }
 
 impl DependencyCollector {
@@ -32,8 +45,18 @@ impl DependencyCollector {
             instruments: HashMap::new(),
             forward_id_from_credit_id: HashMap::new(),
             fixings: HashMap::new(),
+            fixing_ranges: HashMap::new(),
+            rate_fixings: HashMap::new(),
+            fx_rates: HashMap::new(),
+            vol_cubes: HashMap::new(),
+            exercises: HashMap::new(),
+            hazard_curves: HashMap::new(),
+            inflation_curves: HashMap::new(),
+            inflation_fixings: HashMap::new(),
+            dividends: HashMap::new(),
             empty: Vec::<String>::new(),
-            empty_fixings: Vec::<DateTime>::new()
+            empty_fixings: Vec::<DateTime>::new(),
+            empty_fixing_ranges: Vec::<FixingRange>::new()
         }
     }
 
@@ -46,6 +69,10 @@ impl DependencyCollector {
         get_hwm_by_str(&self.yield_curves, credit_id)
     }
 
+    pub fn yield_curves(&self) -> &HashMap<String, Date> {
+        &self.yield_curves
+    }
+
     pub fn forward_curve_hwm(&self, instrument: &RcInstrument)
         -> Option<Date> {
         get_hwm(&self.forward_curves, instrument)
@@ -89,11 +116,82 @@ impl DependencyCollector {
         }
     }
 
+    /// The runs of daily fixings of the given underlier. Overlapping runs
+    /// on the same calendar have been merged.
+    pub fn fixing_ranges(&self, id: &str) -> &[FixingRange] {
+        if let Some(ranges) = self.fixing_ranges.get(&id.to_string()) {
+            &ranges
+        } else {
+            &self.empty_fixing_ranges
+        }
+    }
+
+    /// The fixings of floating rate indices, keyed by the id of the index
+    pub fn rate_fixings(&self) -> &HashMap<String, Vec<RateFixing>> {
+        &self.rate_fixings
+    }
+
+    pub fn fx_rate_hwm(&self, fx_id: &str) -> Option<Date> {
+        get_hwm_by_str(&self.fx_rates, fx_id)
+    }
+
+    pub fn fx_rates(&self) -> &HashMap<String, Date> {
+        &self.fx_rates
+    }
+
+    pub fn vol_cube_hwm(&self, id: &str) -> Option<Date> {
+        get_hwm_by_str(&self.vol_cubes, id)
+    }
+
+    pub fn vol_cubes(&self) -> &HashMap<String, Date> {
+        &self.vol_cubes
+    }
+
+    pub fn hazard_curve_hwm(&self, credit_id: &str) -> Option<Date> {
+        get_hwm_by_str(&self.hazard_curves, credit_id)
+    }
+
+    pub fn hazard_curves(&self) -> &HashMap<String, Date> {
+        &self.hazard_curves
+    }
+
+    pub fn inflation_curve_hwm(&self, index_id: &str) -> Option<Date> {
+        get_hwm_by_str(&self.inflation_curves, index_id)
+    }
+
+    pub fn inflation_fixings(&self) -> &HashMap<String, Vec<Date>> {
+        &self.inflation_fixings
+    }
+
+    /// The ids of instruments whose dividends are recorded as fixings,
+    /// with the last ex date of interest
+    pub fn dividends(&self) -> &HashMap<String, Date> {
+        &self.dividends
+    }
+
+    /// The dates of exercise decisions made by the instrument with the
+    /// given id
+    pub fn exercises(&self, id: &str) -> &[DateTime] {
+        if let Some(exercises) = self.exercises.get(&id.to_string()) {
+            &exercises
+        } else {
+            &self.empty_fixings
+        }
+    }
+
     fn add_instrument(&mut self, instrument: &RcInstrument) {
         self.instruments.insert(
             instrument.id().to_string(), instrument.clone());
     }
 
+    fn forward_yield_curve(&mut self, instrument: &RcInstrument,
+        credit_id: &str, high_water_mark: Date) {
+        set_hwm_by_str(credit_id, high_water_mark, &mut self.yield_curves);
+        let forward_ids = self.forward_id_from_credit_id.entry(
+            credit_id.to_string()).or_insert(Vec::<String>::new());
+        forward_ids.push(instrument.id().to_string());
+    }
+
     pub fn instruments_clone(&self) -> Vec<String> {
         // this rather unpleasant syntax forces the ids to be owned
         // by the resulting vector rather than the original hashmap
@@ -146,15 +244,18 @@ impl DependencyContext for DependencyCollector {
 
         // also set the high water mark on the associated yield curve
         let credit_id = instrument.credit_id();
-        set_hwm_by_str(credit_id, high_water_mark, &mut self.yield_curves);
-        {
-            // this brace to avoid multiple mutable borrow
-            let forward_ids = self.forward_id_from_credit_id.entry(
-                credit_id.to_string()).or_insert(Vec::<String>::new());
-            forward_ids.push(instrument.id().to_string());
+        self.forward_yield_curve(instrument, credit_id, high_water_mark);
+
+        // the forward of a currency pair also depends on the yield curve of
+        // its foreign currency
+        if let Some(pair) = instrument.as_exchange_rate() {
+            self.forward_yield_curve(instrument, pair.foreign_credit_id(),
+                high_water_mark);
         }
 
-        // and add a dependency on this spot (this adds the instrument)
+        // and add a dependency on this spot. The instrument is added even
+        // if it has no spot, such as a commodity, so it can be found by id.
+        self.add_instrument(instrument);
         self.spot(instrument);
     }
 
@@ -169,6 +270,66 @@ impl DependencyContext for DependencyCollector {
             .push(date)
     }
 
+    fn fixing_range(&mut self, id: &str, range: FixingRange) {
+        // Merge the new range with any it overlaps. Merging may make it
+        // overlap ranges that it did not before, so repeat until stable.
+        let ranges = self.fixing_ranges.entry(id.to_string()).or_insert(Vec::new());
+        let mut merged = range;
+        loop {
+            let count = ranges.len();
+            ranges.retain(|existing| !merged.merge(existing));
+            if ranges.len() == count {
+                break;
+            }
+        }
+        ranges.push(merged);
+    }
+
+    fn rate_fixing(&mut self, index_id: &str, fixing: RateFixing) {
+        // rate fixings are also listed with the other fixings, so that
+        // all the fixing dates can be found by id
+        self.fixing(index_id, fixing.date());
+        self.rate_fixings.entry(index_id.to_string()).or_insert(Vec::new())
+            .push(fixing)
+    }
+
+    fn fx_rate(&mut self, fx_id: &str, high_water_mark: Date) {
+        set_hwm_by_str(fx_id, high_water_mark, &mut self.fx_rates);
+    }
+
+    fn vol_cube(&mut self, id: &str, high_water_mark: Date) {
+        set_hwm_by_str(id, high_water_mark, &mut self.vol_cubes);
+    }
+
+    fn exercise(&mut self, id: &str, date: DateTime) {
+        let exercises = self.exercises.entry(id.to_string()).or_insert(Vec::new());
+        if !exercises.contains(&date) {
+            exercises.push(date);
+        }
+    }
+
+    fn hazard_curve(&mut self, credit_id: &str, high_water_mark: Date) {
+        set_hwm_by_str(credit_id, high_water_mark, &mut self.hazard_curves);
+    }
+
+    fn inflation_curve(&mut self, index_id: &str, high_water_mark: Date) {
+        set_hwm_by_str(index_id, high_water_mark, &mut self.inflation_curves);
+    }
+
+    fn inflation_fixing(&mut self, index_id: &str, month: Date) {
+        // like rate fixings, these are also listed with the other fixings
+        self.fixing(index_id, DateTime::new(month, TimeOfDay::Close));
+        let months = self.inflation_fixings.entry(index_id.to_string())
+            .or_insert(Vec::new());
+        if !months.contains(&month) {
+            months.push(month);
+        }
+    }
+
+    fn dividends(&mut self, instrument: &RcInstrument, high_water_mark: Date) {
+        set_hwm_by_str(instrument.id(), high_water_mark, &mut self.dividends);
+        self.add_instrument(instrument);
+    }
 }
 
 pub fn set_hwm_by_str(id: &str, high_water_mark: Date,
@@ -264,4 +425,27 @@ mod tests {
         assert_eq!(c.yield_curve_hwm("OPT"), Some(d+212));
         assert_eq!(c.yield_curve_hwm("LSE"), Some(d+210));
     }
+
+    #[test]
+    fn fixing_ranges_are_merged() {
+        let d = Date::from_ymd(2018, 01, 01);
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let range = |first: i32, last: i32, time_of_day: TimeOfDay|
+            FixingRange::new(d + first, d + last, time_of_day, calendar.clone());
+
+        let mut c = DependencyCollector::new(d);
+        c.fixing_range("BP.L", range(0, 30, TimeOfDay::Close));
+        c.fixing_range("BP.L", range(60, 90, TimeOfDay::Close));
+        c.fixing_range("BP.L", range(0, 90, TimeOfDay::Open));
+        assert_eq!(c.fixing_ranges("BP.L").len(), 3);
+
+        // a range that abuts one and overlaps another joins all three
+        c.fixing_range("BP.L", range(31, 70, TimeOfDay::Close));
+        let ranges = c.fixing_ranges("BP.L");
+        assert_eq!(ranges.len(), 2);
+        let close = ranges.iter().find(|r| r.time_of_day() == TimeOfDay::Close).unwrap();
+        assert_eq!(close.first(), d);
+        assert_eq!(close.last(), d + 90);
+        assert!(c.fixing_ranges("BT.L").is_empty());
+    }
 }
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpyield::BumpYield;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// DV01 is the change in price for a one basis point rise in a yield curve.
+/// This report shows the DV01 with respect to each of the yield curves that
+/// affect the price, keyed by credit id. Bumping a yield curve also moves
+/// the forwards of any equities whose dividends or borrow are discounted
+/// on it, so these effects are included.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Dv01Report {
+    bumpsize: f64,
+    results: HashMap<String, f64>
+}
+
+impl Report for Dv01Report {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for Dv01Report {
+    fn type_id(&self) -> &'static str { "Dv01Report" }
+}
+
+impl Dv01Report {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(Dv01Report::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &HashMap<String, f64> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v Dv01Report> for &'v Dv01Report {
+    fn validate(self, other: &'v Dv01Report, tol: &ReportTolerances, 
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "Dv01Report: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // DV01 is a difference of prices scaled to one basis point, so the
+        // currency risk tolerance is scaled in the same way
+        let tolerance = tol.currency_risk() * BASIS_POINT / self.bumpsize;
+        for (id, dv01) in &self.results {
+            if let Some(other_dv01) = other.results.get(id) {
+                if !approx_eq(*dv01, *other_dv01, tolerance) {
+                    writeln!(diffs, "Dv01Report: {} dv01 {} != {} tol={}", id, dv01, other_dv01, tolerance)?;
+                }
+            } else {
+                write!(diffs, "Dv01Report: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for Dv01Report {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<Dv01Report>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "Dv01Report: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// The size of one basis point, which DV01 is scaled to
+pub const BASIS_POINT: f64 = 0.0001;
+
+/// Calculator for DV01 by bumping. The bump size is a flat annualised
+/// shift in the yield, so 0.0001 is one basis point. Whatever the bump size,
+/// the results are scaled to one basis point, and are calculated from
+/// symmetric up and down bumps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Dv01ReportGenerator {
+    bumpsize: f64
+}
+
+impl Dv01ReportGenerator {
+    pub fn new(bumpsize: f64) -> Dv01ReportGenerator {
+        Dv01ReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Dv01ReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for Dv01ReportGenerator {
+    fn type_id(&self) -> &'static str { "Dv01ReportGenerator" }
+}
+
+impl ReportGenerator for Dv01ReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Annualised bumps are additive in one plus the yield, so bumping
+        // down by twice the bumpsize cancels out the original up bump
+        let up = self.bumpsize;
+        let down = -2.0 * self.bumpsize;
+
+        // Find the yield curves we should have risk to. Note that we need to
+        // clone the list of credit ids, to avoid borrowing problems.
+        let credit_ids: Vec<String> = pricer.as_bumpable().dependencies()?
+            .yield_curves().keys().map(|id| id.to_string()).collect();
+
+        let mut results = HashMap::new();
+        for id in credit_ids.iter() {
+
+            // bump up and reprice
+            let bump = Bump::new_yield(id, BumpYield::new_flat_annualised(up));
+            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+
+            // bump down and reprice (do not save the result from this)
+            let bump = Bump::new_yield(id, BumpYield::new_flat_annualised(down));
+            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
+
+            pricer.as_mut_bumpable().restore(saveable)?;
+            saveable.clear();
+
+            let dv01 = (upbumped - downbumped) / (2.0 * self.bumpsize) * BASIS_POINT;
+            results.insert(id.to_string(), dv01);
+        }
+
+        Ok(Qbox::new(Box::new(Dv01Report { bumpsize: self.bumpsize, results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::RcReportGenerator;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    #[test]
+    fn dv01_european() {
+
+        // create a pricer for a european at the money call
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        // the result for a basis point bump should be very close to the
+        // result from a one percent bump, scaled down
+        let generator = Dv01ReportGenerator::new(0.0001);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<Dv01Report>().unwrap().results().clone();
+
+        let generator = Dv01ReportGenerator::new(0.01);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let coarse = report.as_any().downcast_ref::<Dv01Report>().unwrap().results();
+
+        assert!(results.len() > 0);
+        assert_eq!(results.len(), coarse.len());
+        for (id, dv01) in results.iter() {
+            assert_approx(*dv01, *coarse.get(id).unwrap(), 1e-4);
+        }
+
+        // raising the rate underlying the equity raises the forward, so the
+        // call gains value
+        assert!(*results.get("LSE").unwrap() > 0.0);
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn serde_dv01_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(Dv01ReportGenerator::new(0.0001)));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use risk::dv01::BASIS_POINT;
+use data::bump::Bump;
+use data::bumpyield::BumpYield;
+use data::bumpyield::RateBucket;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Key rate DV01 is the DV01 bucketed by the pillars of a yield curve. This
+/// report shows the key rate DV01s of each of the yield curves that affect
+/// the price, keyed by credit id, with one entry for each pillar. To first
+/// order, they add up to the DV01 of the whole curve.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyRateDv01Report {
+    bumpsize: f64,
+    pillars: Vec<Date>,
+    results: HashMap<String, Vec<f64>>
+}
+
+impl Report for KeyRateDv01Report {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for KeyRateDv01Report {
+    fn type_id(&self) -> &'static str { "KeyRateDv01Report" }
+}
+
+impl KeyRateDv01Report {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(KeyRateDv01Report::deserialize(de)?)))
+    }
+
+    pub fn pillars(&self) -> &[Date] { &self.pillars }
+    pub fn results(&self) -> &HashMap<String, Vec<f64>> { &self.results }
+
+    /// The sum of the key rate DV01s of a curve, if it is in the report
+    pub fn total(&self, credit_id: &str) -> Option<f64> {
+        self.results.get(credit_id).map(|dv01s| dv01s.iter().sum())
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v KeyRateDv01Report> for &'v KeyRateDv01Report {
+    fn validate(self, other: &'v KeyRateDv01Report, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "KeyRateDv01Report: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // as for DV01
+        let tolerance = tol.currency_risk() * BASIS_POINT / self.bumpsize;
+        for (id, dv01s) in &self.results {
+            if let Some(other_dv01s) = other.results.get(id) {
+                if dv01s.len() != other_dv01s.len() {
+                    writeln!(diffs, "KeyRateDv01Report: {} pillars {} != {}", id, dv01s.len(), other_dv01s.len())?;
+                }
+                for (i, (dv01, other_dv01)) in dv01s.iter().zip(other_dv01s.iter()).enumerate() {
+                    if !approx_eq(*dv01, *other_dv01, tolerance) {
+                        writeln!(diffs, "KeyRateDv01Report: {} pillar {} dv01 {} != {} tol={}",
+                            id, i, dv01, other_dv01, tolerance)?;
+                    }
+                }
+            } else {
+                write!(diffs, "KeyRateDv01Report: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for KeyRateDv01Report {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<KeyRateDv01Report>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "KeyRateDv01Report: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for key rate DV01 by bumping. Each pillar of each yield curve
+/// is bumped in turn by an annualised shift of the bump size, weighted so
+/// the bump falls to zero at the neighbouring pillars, as defined by
+/// RateBucket. The pillars are normally the maturities of the futures and
+/// swaps used to hedge. As with DV01, the results are scaled to one basis
+/// point and calculated from symmetric up and down bumps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyRateDv01ReportGenerator {
+    bumpsize: f64,
+    pillars: Vec<Date>
+}
+
+impl KeyRateDv01ReportGenerator {
+    /// Creates a generator for the given pillars, which must be strictly
+    /// increasing.
+    pub fn new(bumpsize: f64, pillars: Vec<Date>)
+        -> Result<KeyRateDv01ReportGenerator, qm::Error> {
+        if pillars.is_empty() {
+            return Err(qm::Error::new("Key rate DV01 needs at least one pillar"))
+        }
+        RateBucket::new(pillars.clone(), 0)?;
+        Ok(KeyRateDv01ReportGenerator { bumpsize: bumpsize, pillars: pillars })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(KeyRateDv01ReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn pillars(&self) -> &[Date] { &self.pillars }
+}
+
+impl TypeId for KeyRateDv01ReportGenerator {
+    fn type_id(&self) -> &'static str { "KeyRateDv01ReportGenerator" }
+}
+
+impl ReportGenerator for KeyRateDv01ReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the yield curves we should have risk to. Note that we need to
+        // clone the list of credit ids, to avoid borrowing problems.
+        let credit_ids: Vec<String> = pricer.as_bumpable().dependencies()?
+            .yield_curves().keys().map(|id| id.to_string()).collect();
+
+        let mut results = HashMap::new();
+        for id in credit_ids.iter() {
+            let mut dv01s = Vec::with_capacity(self.pillars.len());
+            for pillar in 0..self.pillars.len() {
+                let bucket = RateBucket::new(self.pillars.clone(), pillar)?;
+
+                // bump up and reprice
+                let bump = Bump::new_yield(id, BumpYield::new_key_rate(
+                    self.bumpsize, bucket.clone()));
+                let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+
+                // Bump down and reprice (do not save the result from this).
+                // As for DV01, bumping down by twice the bumpsize cancels
+                // out the original up bump.
+                let bump = Bump::new_yield(id, BumpYield::new_key_rate(
+                    -2.0 * self.bumpsize, bucket));
+                let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
+
+                pricer.as_mut_bumpable().restore(saveable)?;
+                saveable.clear();
+
+                dv01s.push((upbumped - downbumped) / (2.0 * self.bumpsize) * BASIS_POINT);
+            }
+            results.insert(id.to_string(), dv01s);
+        }
+
+        Ok(Qbox::new(Box::new(KeyRateDv01Report { bumpsize: self.bumpsize,
+            pillars: self.pillars.clone(), results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::dv01::{Dv01ReportGenerator, Dv01Report};
+    use risk::RcReportGenerator;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn sample_pillars() -> Vec<Date> {
+        vec![Date::from_ymd(2017, 07, 01), Date::from_ymd(2018, 01, 01),
+            Date::from_ymd(2019, 01, 01), Date::from_ymd(2020, 01, 01)]
+    }
+
+    #[test]
+    fn key_rate_dv01_european() {
+
+        // create a pricer for a european at the money call, expiring on
+        // 2018-06-01
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = KeyRateDv01ReportGenerator::new(0.0001, sample_pillars()).unwrap();
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<KeyRateDv01Report>().unwrap();
+        assert_eq!(results.pillars(), &sample_pillars()[..]);
+
+        let generator = Dv01ReportGenerator::new(0.0001);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let parallel = report.as_any().downcast_ref::<Dv01Report>().unwrap().results();
+        assert_eq!(results.results().len(), parallel.len());
+
+        // the key rate DV01s add up to the parallel DV01
+        let dv01s = &results.results()["LSE"];
+        let dv01 = parallel["LSE"];
+        assert_eq!(dv01s.len(), 4);
+        assert_approx(results.total("LSE").unwrap(), dv01, 1e-4 * dv01.abs());
+        assert!(results.total("missing").is_none());
+
+        // The option is only sensitive to rates up to its expiry, which are
+        // mostly in the pillars either side of it. Nothing is beyond them.
+        assert!(dv01s[1] > 0.0 && dv01s[2] > 0.0);
+        assert!(dv01s[2].abs() > dv01s[0].abs());
+        assert_eq!(dv01s[3], 0.0);
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn key_rate_dv01_bad_pillars() {
+        assert!(KeyRateDv01ReportGenerator::new(0.0001, Vec::new()).is_err());
+        let mut pillars = sample_pillars();
+        pillars.reverse();
+        assert!(KeyRateDv01ReportGenerator::new(0.0001, pillars).is_err());
+    }
+
+    #[test]
+    fn serde_key_rate_dv01_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(
+            KeyRateDv01ReportGenerator::new(0.0001, sample_pillars()).unwrap()));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
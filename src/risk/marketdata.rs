@@ -2,13 +2,22 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::any::Any;
 use std::ops::Deref;
+use ndarray::Array2;
 use core::qm;
 use dates::Date;
 use data::curves::RcRateCurve;
 use data::divstream::RcDividendStream;
 use data::volsurface::RcVolSurface;
+use data::volsurface::VolTimeDynamics;
+use data::volcube::RcVolCube;
+use data::inflation::RcInflationCurve;
+use data::commodity::RcCommodityCurve;
+use data::commodity::CommodityCurveForward;
+use data::correlations::Correlations;
+use data::correlations::CorrelationTermStructure;
 use data::forward::Forward;
 use data::forward::EquityForward;
+use data::forward::InterestParityForward;
 use data::bump::Bump;
 use data::bumpspot::BumpSpot;
 use data::bumpdivs::BumpDivs;
@@ -18,6 +27,7 @@ use data::bumpspotdate::BumpSpotDate;
 use data::bumpspotdate::SpotDynamics;
 use data::bump::Bumper;
 use instruments::Instrument;
+use instruments::ExchangeRate;
 use instruments::PricingContext;
 use risk::Bumpable;
 use risk::Saveable;
@@ -44,7 +54,19 @@ pub struct MarketData {
     yield_curves: HashMap<String, RcRateCurve>,
     borrow_curves: HashMap<String, RcRateCurve>,
     dividends: HashMap<String, RcDividendStream>,
-    vol_surfaces: HashMap<String, RcVolSurface>
+    vol_surfaces: HashMap<String, RcVolSurface>,
+    #[serde(default)]
+    fx_vol_surfaces: HashMap<String, RcVolSurface>,
+    #[serde(default)]
+    correlations: Correlations,
+    #[serde(default)]
+    vol_cubes: HashMap<String, RcVolCube>,
+    #[serde(default)]
+    hazard_curves: HashMap<String, RcRateCurve>,
+    #[serde(default)]
+    inflation_curves: HashMap<String, RcInflationCurve>,
+    #[serde(default)]
+    commodity_curves: HashMap<String, RcCommodityCurve>
 }
 
 impl MarketData {
@@ -65,7 +87,7 @@ impl MarketData {
     /// * 'dividends'      - Dividend streams, keyed by the id of the equity
     /// * 'vol_surfaces'   - Vol surfaces, keyed by the id of the instrument
     ///                      such as an equity. Vol cubes for interest rates
-    ///                      will be supplied as a separate entry.
+    ///                      are supplied separately, via add_vol_cube.
     pub fn new(
         spot_date: Date, 
         spots: HashMap<String, f64>,
@@ -80,7 +102,80 @@ impl MarketData {
             yield_curves: yield_curves,
             borrow_curves: borrow_curves,
             dividends: dividends,
-            vol_surfaces: vol_surfaces }
+            vol_surfaces: vol_surfaces,
+            fx_vol_surfaces: HashMap::new(),
+            correlations: Correlations::new(),
+            vol_cubes: HashMap::new(),
+            hazard_curves: HashMap::new(),
+            inflation_curves: HashMap::new(),
+            commodity_curves: HashMap::new() }
+    }
+
+    /// Sets a spot value, such as the spot of an FX rate, replacing any
+    /// existing value for the same id
+    pub fn set_spot(&mut self, id: &str, spot: f64) {
+        self.spots.insert(id.to_string(), spot);
+    }
+
+    /// Adds a vol surface for an instrument such as an equity or commodity,
+    /// keyed by the id of the instrument, replacing any existing surface
+    pub fn add_vol_surface(&mut self, id: &str, surface: RcVolSurface) {
+        self.vol_surfaces.insert(id.to_string(), surface);
+    }
+
+    /// Adds a vol surface for an FX rate, keyed by the id of the rate, such
+    /// as "GBPUSD". FX rates are not instruments, so these are kept separate
+    /// from the vol surfaces for equities. The spot for the rate is supplied
+    /// in the spots, under the same id.
+    pub fn add_fx_vol_surface(&mut self, fx_id: &str, surface: RcVolSurface) {
+        self.fx_vol_surfaces.insert(fx_id.to_string(), surface);
+    }
+
+    /// Sets the instantaneous correlation between two factors, identified
+    /// by id. These may be instruments such as equities, or FX rates.
+    pub fn set_correlation(&mut self, first: &str, second: &str,
+        correlation: f64) -> Result<(), qm::Error> {
+        self.correlations.set(first, second, correlation)
+    }
+
+    /// Sets a term structure of instantaneous correlations between two
+    /// factors, identified by id. Models that step through time use this in
+    /// preference to any constant correlation for the same pair.
+    pub fn set_correlation_term_structure(&mut self, first: &str, second: &str,
+        term_structure: CorrelationTermStructure) -> Result<(), qm::Error> {
+        self.correlations.set_term_structure(first, second, term_structure)
+    }
+
+    /// Sets the instantaneous correlations between all pairs of the given
+    /// factors, from a full matrix in the order of the ids
+    pub fn set_correlation_matrix(&mut self, ids: &[&str], matrix: &Array2<f64>)
+        -> Result<(), qm::Error> {
+        self.correlations.set_matrix(ids, matrix)
+    }
+
+    /// Adds a vol cube for options on swap rates, keyed by id, normally
+    /// that of the floating rate index of the underlying swaps
+    pub fn add_vol_cube(&mut self, id: &str, cube: RcVolCube) {
+        self.vol_cubes.insert(id.to_string(), cube);
+    }
+
+    /// Adds a hazard rate curve for an entity that may default, keyed by its
+    /// credit id. The discount factors of the curve are survival probabilities.
+    pub fn add_hazard_curve(&mut self, credit_id: &str, curve: RcRateCurve) {
+        self.hazard_curves.insert(credit_id.to_string(), curve);
+    }
+
+    /// Adds an inflation curve for a price index, keyed by the id of the
+    /// index. Historic values of the index are supplied as fixings.
+    pub fn add_inflation_curve(&mut self, index_id: &str, curve: RcInflationCurve) {
+        self.inflation_curves.insert(index_id.to_string(), curve);
+    }
+
+    /// Adds a curve of forward prices for a commodity, keyed by the id of
+    /// the commodity. Commodities have no spot, so there is no spot to
+    /// supply alongside the curve.
+    pub fn add_commodity_curve(&mut self, id: &str, curve: RcCommodityCurve) {
+        self.commodity_curves.insert(id.to_string(), curve);
     }
 
     /// Bumps the spot date, for example during a Theta calculation
@@ -188,9 +283,21 @@ impl PricingContext for MarketData {
     fn forward_curve(&self, instrument: &Instrument, high_water_mark: Date)
         -> Result<Arc<Forward>, qm::Error> {
 
-        // This assumes the instrument is an equity. Need handling for other
-        // types of underlying that may not have dividends or borrow, or may
-        // be driftless
+        // Currency pairs have no dividends or borrow, and drift with the
+        // difference in rates between their currencies
+        if let Some(pair) = instrument.as_exchange_rate() {
+            return self.fx_forward_curve(pair, high_water_mark)
+        }
+
+        // Commodities take their forwards directly from their curves
+        if instrument.is_forward_quoted() {
+            let curve = self.commodity_curve(instrument.id(), high_water_mark)?;
+            return Ok(Arc::new(CommodityCurveForward::new(curve)))
+        }
+
+        // Otherwise, this assumes the instrument is an equity. Need handling
+        // for other types of underlying that may not have dividends or
+        // borrow, or may be driftless
         let id = instrument.id();
         let spot = find_market_data(id, &self.spots, "Spot")?;
         let divs = find_market_data(id, &self.dividends, "Dividends")?;
@@ -225,9 +332,64 @@ impl PricingContext for MarketData {
         Ok(vol)
     }
 
-    fn correlation(&self, _first: &Instrument, _second: &Instrument)
+    fn fx_vol_surface(&self, fx_id: &str, _high_water_mark: Date)
+        -> Result<RcVolSurface, qm::Error> {
+
+        let mut vol = find_market_data(fx_id, &self.fx_vol_surfaces,
+            "FX vol surface")?;
+
+        // FX rates have no stepwise dividends, so there are no forward
+        // dynamics to apply, and expiry dates stay fixed when time moves on
+        VolTimeDynamics::ConstantExpiry.modify(&mut vol, self.spot_date)?;
+        Ok(vol)
+    }
+
+    fn fx_forward_curve(&self, pair: &ExchangeRate, _high_water_mark: Date)
+        -> Result<Arc<Forward>, qm::Error> {
+
+        let spot = find_market_data(pair.id(), &self.spots, "FX spot")?;
+        let domestic = find_market_data(pair.domestic_credit_id(),
+            &self.yield_curves, "Yield curve for FX forward")?;
+        let foreign = find_market_data(pair.foreign_credit_id(),
+            &self.yield_curves, "Yield curve for FX forward")?;
+        let forward = InterestParityForward::new(self.spot_date, spot,
+            pair.settlement().clone(), domestic, foreign)?;
+        Ok(Arc::new(forward))
+    }
+
+    fn correlation(&self, first: &Instrument, second: &Instrument)
         -> Result<f64, qm::Error> {
-        Err(qm::Error::new("Correlation not implemented"))
+        self.correlations.get(first.id(), second.id())
+    }
+
+    fn average_correlation(&self, first: &Instrument, second: &Instrument,
+        from: Date, to: Date) -> Result<f64, qm::Error> {
+        self.correlations.get_average(first.id(), second.id(), from, to)
+    }
+
+    fn correlation_by_id(&self, first: &str, second: &str)
+        -> Result<f64, qm::Error> {
+        self.correlations.get(first, second)
+    }
+
+    fn vol_cube(&self, id: &str, _high_water_mark: Date)
+        -> Result<RcVolCube, qm::Error> {
+        find_market_data(id, &self.vol_cubes, "Vol cube")
+    }
+
+    fn hazard_curve(&self, credit_id: &str, _high_water_mark: Date)
+        -> Result<RcRateCurve, qm::Error> {
+        find_market_data(credit_id, &self.hazard_curves, "Hazard curve")
+    }
+
+    fn inflation_curve(&self, index_id: &str, _high_water_mark: Date)
+        -> Result<RcInflationCurve, qm::Error> {
+        find_market_data(index_id, &self.inflation_curves, "Inflation curve")
+    }
+
+    fn commodity_curve(&self, id: &str, _high_water_mark: Date)
+        -> Result<RcCommodityCurve, qm::Error> {
+        find_market_data(id, &self.commodity_curves, "Commodity curve")
     }
 }
 
@@ -253,6 +415,8 @@ impl Bumpable for MarketData {
         match bump {
             &Bump::Spot(ref id, ref bump) => apply_bump(&id, bump as &BumpSpot,
                 &mut self.spots, saved.map_or(None, |s| Some(&mut s.spots))),
+            &Bump::FxSpot(ref fx_id, ref bump) => apply_bump(&fx_id, bump as &BumpSpot,
+                &mut self.spots, saved.map_or(None, |s| Some(&mut s.spots))),
             &Bump::Divs(ref id, ref bump) => apply_bump(&id, bump as &BumpDivs,
                 &mut self.dividends, saved.map_or(None, |s| Some(&mut s.dividends))),
             &Bump::Borrow(ref id, ref bump) => apply_bump(&id,
@@ -260,9 +424,22 @@ impl Bumpable for MarketData {
                 saved.map_or(None, |s| Some(&mut s.borrow_curves))),
             &Bump::Vol(ref id, ref bump) => apply_bump(&id, bump as &BumpVol,
                 &mut self.vol_surfaces, saved.map_or(None, |s| Some(&mut s.vol_surfaces))),
+            &Bump::VolCube(ref id, ref bump) => apply_bump(&id, bump as &BumpVol,
+                &mut self.vol_cubes, saved.map_or(None, |s| Some(&mut s.vol_cubes))),
             &Bump::Yield(ref credit_id, ref bump) => apply_bump(&credit_id,
                 bump as &BumpYield, &mut self.yield_curves, 
                 saved.map_or(None, |s| Some(&mut s.yield_curves))),
+            &Bump::Hazard(ref credit_id, ref bump) => apply_bump(&credit_id,
+                bump as &BumpYield, &mut self.hazard_curves,
+                saved.map_or(None, |s| Some(&mut s.hazard_curves))),
+            &Bump::Correlation(ref first, ref second, ref bump) => {
+                if let Some(s) = saved {
+                    if s.correlations.is_none() {
+                        s.correlations = Some(self.correlations.clone());
+                    }
+                }
+                self.correlations.bump(&first, &second, bump)
+            },
              &Bump::SpotDate(_) => Err(qm::Error::new("MarketData does not have \
                 enough information to handle spot date bumping on its own. It needs \
                 to be handled by a containing PricingContextPrefetch."))
@@ -289,6 +466,11 @@ impl Bumpable for MarketData {
             copy_from_saved(&mut self.borrow_curves, &saved.borrow_curves);
             copy_from_saved(&mut self.dividends, &saved.dividends);
             copy_from_saved(&mut self.vol_surfaces, &saved.vol_surfaces);
+            copy_from_saved(&mut self.vol_cubes, &saved.vol_cubes);
+            copy_from_saved(&mut self.hazard_curves, &saved.hazard_curves);
+            if let Some(ref correlations) = saved.correlations {
+                self.correlations = correlations.clone();
+            }
             Ok(())
 
         } else {
@@ -353,7 +535,10 @@ pub struct SavedData {
     yield_curves: HashMap<String, RcRateCurve>,
     borrow_curves: HashMap<String, RcRateCurve>,
     dividends: HashMap<String, RcDividendStream>,
-    vol_surfaces: HashMap<String, RcVolSurface>
+    vol_surfaces: HashMap<String, RcVolSurface>,
+    vol_cubes: HashMap<String, RcVolCube>,
+    hazard_curves: HashMap<String, RcRateCurve>,
+    correlations: Option<Correlations>
 }
 
 impl SavedData {
@@ -366,7 +551,10 @@ impl SavedData {
             yield_curves: HashMap::new(),
             borrow_curves: HashMap::new(),
             dividends: HashMap::new(),
-            vol_surfaces: HashMap::new() }
+            vol_surfaces: HashMap::new(),
+            vol_cubes: HashMap::new(),
+            hazard_curves: HashMap::new(),
+            correlations: None }
     }
 }
 
@@ -380,6 +568,9 @@ impl Saveable for SavedData {
         self.borrow_curves.clear();
         self.dividends.clear();
         self.vol_surfaces.clear();
+        self.vol_cubes.clear();
+        self.hazard_curves.clear();
+        self.correlations = None;
     }
 }
 
@@ -399,6 +590,7 @@ pub mod tests {
     use instruments::options::ForwardStartingEuropean;
     use instruments::options::PutOrCall;
     use instruments::options::OptionSettlement;
+    use instruments::exchange::ExchangeOption;
     use instruments::Priceable;
     use instruments::RcInstrument;
     use data::divstream::DividendStream;
@@ -406,6 +598,9 @@ pub mod tests {
     use data::curves::RateCurveAct365;
     use data::volsurface::RcVolSurface;
     use data::volsurface::FlatVolSurface;
+    use data::volsurface::VolByProbabilityFlatSmile;
+    use data::volsurface::DivAssumptions;
+    use data::volsmile::FlatSmile;
     use data::bumpspot::BumpSpot;
     use data::bumpdivs::BumpDivs;
     use data::bumpvol::BumpVol;
@@ -414,6 +609,7 @@ pub mod tests {
     use dates::calendar::RcCalendar;
     use math::numerics::approx_eq;
     use math::interpolation::Extrap;
+    use math::interpolation::Linear;
     use core::factories::Qrc;
     use serde_json;
 
@@ -433,6 +629,19 @@ pub mod tests {
         Equity::new("BP.L", "LSE", currency, settlement)
     }
 
+    pub fn sample_underlying() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))))
+    }
+
+    pub fn sample_expiry() -> DateTime {
+        DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close)
+    }
+
+    pub fn sample_val_date() -> DateTime {
+        DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open)
+    }
+
     pub fn sample_european() -> Arc<SpotStartingEuropean> {
 
         let strike = 100.0;
@@ -509,6 +718,22 @@ pub mod tests {
         RcVolSurface::new(Arc::new(FlatVolSurface::new(0.3, calendar, base)))
     }
 
+    /// A flat 30% vol surface that treats the dividends as fixed, so the
+    /// log-normal process applies to the spot less the given displacement.
+    pub fn create_sample_displaced_vol(displacement: f64) -> RcVolSurface {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base_date = Date::from_ymd(2016, 12, 30);
+        let base = DateDayFraction::new(base_date, 0.2);
+        let smiles = [(DateDayFraction::new(Date::from_ymd(2018, 12, 31), 0.7),
+            FlatSmile::new(0.3).unwrap())];
+        let forward = Linear::new(&[(base_date, 100.0)],
+            Extrap::Flat, Extrap::Flat).unwrap();
+        let divs = Linear::new(&[(base_date, displacement)],
+            Extrap::Flat, Extrap::Flat).unwrap();
+        RcVolSurface::new(Arc::new(VolByProbabilityFlatSmile::new(&smiles,
+            calendar, base, forward, divs, DivAssumptions::FixedDivs).unwrap()))
+    }
+
     pub fn sample_market_data() -> MarketData {
     
         let spot_date = Date::from_ymd(2017, 01, 02);
@@ -536,6 +761,30 @@ pub mod tests {
             borrow_curves, dividends, vol_surfaces)
     }
 
+    /// The sample market data, but with a displaced vol surface for BP.L.
+    pub fn sample_displaced_market_data(displacement: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        market_data.add_vol_surface("BP.L", create_sample_displaced_vol(displacement));
+        market_data
+    }
+
+    /// The sample market data, with BP.L and GSK.L correlated.
+    pub fn sample_correlated_market_data(correlation: f64) -> MarketData {
+        let mut market_data = sample_market_data();
+        market_data.set_correlation("BP.L", "GSK.L", correlation).unwrap();
+        market_data
+    }
+
+    /// Receive two BP.L for one GSK.L, which starts at twice the level
+    pub fn sample_margrabe() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = |id| RcInstrument::new(Qrc::new(Arc::new(
+            Equity::new(id, "LSE", currency.clone(), sample_settlement(2)))));
+        RcInstrument::new(Qrc::new(Arc::new(ExchangeOption::new("SampleExchange",
+            "OPT", vec![(2.0, equity("BP.L"))], vec![(1.0, equity("GSK.L"))],
+            sample_settlement(2), sample_expiry()).unwrap())))
+    }
+
     #[test]
     fn european_unbumped_price() {
 
@@ -5,10 +5,35 @@ pub mod bumptime;
 pub mod deltagamma;
 pub mod timebumped;
 pub mod vegavolga;
+pub mod dv01;
+pub mod cs01;
+pub mod mu;
+pub mod scenarios;
+pub mod crossgamma;
+pub mod bucketedvega;
+pub mod vannavolga;
+pub mod theta;
+pub mod keyrate;
+pub mod correlation;
+pub mod stress;
+pub mod var;
 
 use risk::timebumped::{TimeBumpedReportGenerator, TimeBumpedReport};
 use risk::deltagamma::{DeltaGammaReportGenerator, DeltaGammaReport};
 use risk::vegavolga::{VegaVolgaReportGenerator, VegaVolgaReport};
+use risk::dv01::{Dv01ReportGenerator, Dv01Report};
+use risk::cs01::{Cs01ReportGenerator, Cs01Report};
+use risk::mu::{MuReportGenerator, MuReport};
+use risk::mu::{DividendMuReportGenerator, DividendMuReport};
+use risk::scenarios::{ScenarioReportGenerator, ScenarioReport};
+use risk::crossgamma::{CrossGammaReportGenerator, CrossGammaReport};
+use risk::bucketedvega::{BucketedVegaReportGenerator, BucketedVegaReport};
+use risk::vannavolga::{SurfaceVannaVolgaReportGenerator, SurfaceVannaVolgaReport};
+use risk::theta::{ThetaReportGenerator, ThetaReport};
+use risk::keyrate::{KeyRateDv01ReportGenerator, KeyRateDv01Report};
+use risk::correlation::{CorrelationReportGenerator, CorrelationReport};
+use risk::stress::StressReportGenerator;
+use risk::var::{HistoricalVarReportGenerator, VarReport};
 use core::qm;
 use core::factories::{Qrc, Qbox, TypeId, Registry};
 use data::bump::Bump;
@@ -25,6 +50,9 @@ use std::any::Any;
 use std::fmt;
 use std::ops::Deref;
 use math::numerics::ApproxEq;
+use pricers::adjoint::MonteCarloGreeks;
+use pricers::statistics::MonteCarloResult;
+use pricers::pde::ExerciseBoundary;
 
 /// Interface that defines all bumps of simple underlying market data. This
 /// defines most risks that the analytics outputs. Most methods take a save
@@ -107,6 +135,59 @@ pub trait Pricer : Bumpable + TimeBumpable + PricerClone {
     /// 
     /// Discount date is currently disabled.
     fn price(&self /*, discount_date: Option<Date>*/) -> Result<f64, qm::Error>;
+
+    /// Returns the price with its standard error, confidence intervals and
+    /// optionally the history of its convergence over the given number of
+    /// batches, or None if the pricer has no error, which is the default.
+    /// Monte-Carlo pricers override this.
+    fn price_with_statistics(&self, _batches: usize)
+        -> Result<Option<MonteCarloResult>, qm::Error> {
+        Ok(None)
+    }
+
+    /// Returns the price and its first-order greeks from a single pricing
+    /// pass by the given method, or None if the pricer does not support
+    /// it. Only Monte-Carlo pricers support any method other than bumping,
+    /// which report generators do for themselves, so that is the default.
+    fn path_greeks(&self, _method: GreekMethod)
+        -> Result<Option<MonteCarloGreeks>, qm::Error> {
+        Ok(None)
+    }
+
+    /// Returns the early-exercise boundary of each instrument that may be
+    /// exercised early, as found while pricing, or None if the pricer does
+    /// not find boundaries, which is the default. PDE pricers override this.
+    fn exercise_boundaries(&self)
+        -> Result<Option<Vec<ExerciseBoundary>>, qm::Error> {
+        Ok(None)
+    }
+}
+
+/// How report generators calculate deltas and vegas. Bumping and
+/// repricing works with any pricer, but is noisy for Monte-Carlo prices of
+/// discontinuous payoffs such as digitals, where few paths cross the
+/// discontinuity. The other methods estimate the greeks directly from the
+/// paths of a Monte-Carlo pricer, which is also quicker, as it needs only
+/// one pass whatever the number of underlyings.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GreekMethod {
+    /// Central differences of bumped prices
+    Bumped,
+
+    /// Derivatives of the payoff of each path, by adjoint differentiation
+    /// (see pricers::adjoint). This is the least noisy method, but needs
+    /// payoffs that are continuous, such as vanillas or digitals valued as
+    /// spreads.
+    Pathwise,
+
+    /// The value of each path times the derivative of the log of its
+    /// density (see pricers::likelihood). This works for any payoff,
+    /// including exact digitals, but is noisier than pathwise greeks.
+    LikelihoodRatio
+}
+
+impl Default for GreekMethod {
+    fn default() -> GreekMethod { GreekMethod::Bumped }
 }
 
 /// For some reason that I do not understand, the rust compiler runs into an
@@ -201,7 +282,20 @@ pub fn get_generator_registry() -> &'static GeneratorTypeRegistry {
             let mut reg = GeneratorTypeRegistry::new();
             reg.insert("DeltaGammaReportGenerator", BoxFnSeed::new(DeltaGammaReportGenerator::from_serial));
             reg.insert("VegaVolgaReportGenerator", BoxFnSeed::new(VegaVolgaReportGenerator::from_serial));
+            reg.insert("Dv01ReportGenerator", BoxFnSeed::new(Dv01ReportGenerator::from_serial));
+            reg.insert("Cs01ReportGenerator", BoxFnSeed::new(Cs01ReportGenerator::from_serial));
+            reg.insert("MuReportGenerator", BoxFnSeed::new(MuReportGenerator::from_serial));
+            reg.insert("DividendMuReportGenerator", BoxFnSeed::new(DividendMuReportGenerator::from_serial));
             reg.insert("TimeBumpedReportGenerator", BoxFnSeed::new(TimeBumpedReportGenerator::from_serial));
+            reg.insert("ScenarioReportGenerator", BoxFnSeed::new(ScenarioReportGenerator::from_serial));
+            reg.insert("CrossGammaReportGenerator", BoxFnSeed::new(CrossGammaReportGenerator::from_serial));
+            reg.insert("BucketedVegaReportGenerator", BoxFnSeed::new(BucketedVegaReportGenerator::from_serial));
+            reg.insert("SurfaceVannaVolgaReportGenerator", BoxFnSeed::new(SurfaceVannaVolgaReportGenerator::from_serial));
+            reg.insert("ThetaReportGenerator", BoxFnSeed::new(ThetaReportGenerator::from_serial));
+            reg.insert("KeyRateDv01ReportGenerator", BoxFnSeed::new(KeyRateDv01ReportGenerator::from_serial));
+            reg.insert("CorrelationReportGenerator", BoxFnSeed::new(CorrelationReportGenerator::from_serial));
+            reg.insert("StressReportGenerator", BoxFnSeed::new(StressReportGenerator::from_serial));
+            reg.insert("HistoricalVarReportGenerator", BoxFnSeed::new(HistoricalVarReportGenerator::from_serial));
             reg
         };
     }
@@ -256,7 +350,19 @@ pub fn get_report_registry() -> &'static ReportTypeRegistry {
             let mut reg = ReportTypeRegistry::new();
             reg.insert("DeltaGammaReport", BoxFnSeed::new(DeltaGammaReport::from_serial));
             reg.insert("VegaVolgaReport", BoxFnSeed::new(VegaVolgaReport::from_serial));
+            reg.insert("Dv01Report", BoxFnSeed::new(Dv01Report::from_serial));
+            reg.insert("Cs01Report", BoxFnSeed::new(Cs01Report::from_serial));
+            reg.insert("MuReport", BoxFnSeed::new(MuReport::from_serial));
+            reg.insert("DividendMuReport", BoxFnSeed::new(DividendMuReport::from_serial));
             reg.insert("TimeBumpedReport", BoxFnSeed::new(TimeBumpedReport::from_serial));
+            reg.insert("ScenarioReport", BoxFnSeed::new(ScenarioReport::from_serial));
+            reg.insert("CrossGammaReport", BoxFnSeed::new(CrossGammaReport::from_serial));
+            reg.insert("BucketedVegaReport", BoxFnSeed::new(BucketedVegaReport::from_serial));
+            reg.insert("SurfaceVannaVolgaReport", BoxFnSeed::new(SurfaceVannaVolgaReport::from_serial));
+            reg.insert("ThetaReport", BoxFnSeed::new(ThetaReport::from_serial));
+            reg.insert("KeyRateDv01Report", BoxFnSeed::new(KeyRateDv01Report::from_serial));
+            reg.insert("CorrelationReport", BoxFnSeed::new(CorrelationReport::from_serial));
+            reg.insert("VarReport", BoxFnSeed::new(VarReport::from_serial));
             reg
         };
     }
@@ -273,4 +379,25 @@ pub fn bumped_price(bump: &Bump, pricer: &mut Pricer, saveable: Option<&mut Save
     } else {
         Ok(unbumped)
     }
+}
+
+/// Like bumped_price, but returns the greeks of the bumped pricer by the
+/// given method, for the second-order greeks of report generators.
+pub fn bumped_path_greeks(bump: &Bump, pricer: &mut Pricer, saveable: Option<&mut Saveable>,
+    method: GreekMethod, unbumped: &MonteCarloGreeks) -> Result<MonteCarloGreeks, qm::Error> {
+
+    if pricer.as_mut_bumpable().bump(bump, saveable)? {
+        path_greeks(pricer, method)
+    } else {
+        Ok(unbumped.clone())
+    }
+}
+
+/// The greeks of a pricer by the given method, which is an error if the
+/// pricer does not support it
+pub fn path_greeks(pricer: &Pricer, method: GreekMethod)
+    -> Result<MonteCarloGreeks, qm::Error> {
+
+    pricer.path_greeks(method)?.ok_or_else(|| qm::Error::new(&format!(
+        "The pricer does not support {:?} greeks", method)))
 }
\ No newline at end of file
@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpdivs::BumpDivs;
+use data::bumpyield::RateBucket;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Mu is the change in price for a one percent relative rise in the
+/// dividends of an underlier. This report shows the mu with respect to each
+/// of the underliers whose forwards affect the price, keyed by id.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MuReport {
+    bumpsize: f64,
+    results: HashMap<String, f64>
+}
+
+impl Report for MuReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for MuReport {
+    fn type_id(&self) -> &'static str { "MuReport" }
+}
+
+impl MuReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(MuReport::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &HashMap<String, f64> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v MuReport> for &'v MuReport {
+    fn validate(self, other: &'v MuReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "MuReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // Mu is a difference of prices scaled to one percent, so the
+        // currency risk tolerance is scaled in the same way
+        let tolerance = tol.currency_risk() * PERCENT / self.bumpsize;
+        for (id, mu) in &self.results {
+            if let Some(other_mu) = other.results.get(id) {
+                if !approx_eq(*mu, *other_mu, tolerance) {
+                    writeln!(diffs, "MuReport: {} mu {} != {} tol={}", id, mu, other_mu, tolerance)?;
+                }
+            } else {
+                write!(diffs, "MuReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for MuReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<MuReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "MuReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+const PERCENT: f64 = 0.01;
+
+/// Calculator for mu by bumping. The bump size is a relative change in all
+/// the dividends of an underlier, cash, relative and yield, so 0.01 is a one
+/// percent rise. Whatever the bump size, the results are scaled to one
+/// percent, and are calculated from symmetric up and down bumps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MuReportGenerator {
+    bumpsize: f64
+}
+
+impl MuReportGenerator {
+    pub fn new(bumpsize: f64) -> MuReportGenerator {
+        MuReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(MuReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for MuReportGenerator {
+    fn type_id(&self) -> &'static str { "MuReportGenerator" }
+}
+
+impl ReportGenerator for MuReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // As with delta, we bump up by 1 + bumpsize, then down by
+        // (1 - bumpsize) / (1 + bumpsize) to cancel out the up bump.
+        let up = self.bumpsize;
+        let down = (1.0 - self.bumpsize) / (1.0 + self.bumpsize) - 1.0;
+
+        // Find the underliers whose dividends we should have risk to. Currency
+        // pairs and commodities have forwards but no dividends. Note that we
+        // need to clone the list of ids, to avoid borrowing problems.
+        let ids: Vec<String> = pricer.as_bumpable().dependencies()?
+            .forward_curves().keys().filter(|inst| inst.as_exchange_rate().is_none()
+                && !inst.is_forward_quoted())
+            .map(|inst| inst.id().to_string()).collect();
+
+        let mut results = HashMap::new();
+        for id in ids.iter() {
+
+            // bump up and reprice
+            let bump = Bump::new_divs(id, BumpDivs::new_all_relative(up));
+            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+
+            // bump down and reprice (do not save the result from this)
+            let bump = Bump::new_divs(id, BumpDivs::new_all_relative(down));
+            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
+
+            pricer.as_mut_bumpable().restore(saveable)?;
+            saveable.clear();
+
+            let mu = (upbumped - downbumped) / (2.0 * self.bumpsize) * PERCENT;
+            results.insert(id.to_string(), mu);
+        }
+
+        Ok(Qbox::new(Box::new(MuReport { bumpsize: self.bumpsize, results: results })))
+    }
+}
+
+/// The mu of one underlier, split into the part from the cash amounts of
+/// its dividends and the part from the relative amounts and the dividend
+/// yield. Each is also bucketed by ex date, and to first order the buckets
+/// add up to the parallel figure.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DividendMu {
+    cash: f64,
+    dividend_yield: f64,
+    cash_buckets: Vec<f64>,
+    dividend_yield_buckets: Vec<f64>
+}
+
+impl DividendMu {
+    pub fn cash(&self) -> f64 { self.cash }
+    pub fn dividend_yield(&self) -> f64 { self.dividend_yield }
+    pub fn cash_buckets(&self) -> &[f64] { &self.cash_buckets }
+    pub fn dividend_yield_buckets(&self) -> &[f64] { &self.dividend_yield_buckets }
+}
+
+/// This report shows the mu of each of the underliers whose forwards affect
+/// the price, split into cash and yield components, each in parallel and
+/// bucketed by the pillars, keyed by id. As for mu, the results are scaled
+/// to a one percent rise.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DividendMuReport {
+    bumpsize: f64,
+    pillars: Vec<Date>,
+    results: HashMap<String, DividendMu>
+}
+
+impl Report for DividendMuReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for DividendMuReport {
+    fn type_id(&self) -> &'static str { "DividendMuReport" }
+}
+
+impl DividendMuReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(DividendMuReport::deserialize(de)?)))
+    }
+
+    pub fn pillars(&self) -> &[Date] { &self.pillars }
+    pub fn results(&self) -> &HashMap<String, DividendMu> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v DividendMuReport> for &'v DividendMuReport {
+    fn validate(self, other: &'v DividendMuReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "DividendMuReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // as for mu
+        let tolerance = tol.currency_risk() * PERCENT / self.bumpsize;
+        for (id, mu) in &self.results {
+            if let Some(other_mu) = other.results.get(id) {
+                if !approx_eq(mu.cash, other_mu.cash, tolerance) {
+                    writeln!(diffs, "DividendMuReport: {} cash mu {} != {} tol={}", id, mu.cash, other_mu.cash, tolerance)?;
+                }
+                if !approx_eq(mu.dividend_yield, other_mu.dividend_yield, tolerance) {
+                    writeln!(diffs, "DividendMuReport: {} yield mu {} != {} tol={}", id, mu.dividend_yield, other_mu.dividend_yield, tolerance)?;
+                }
+                for &(name, buckets, other_buckets) in [
+                    ("cash", &mu.cash_buckets, &other_mu.cash_buckets),
+                    ("yield", &mu.dividend_yield_buckets, &other_mu.dividend_yield_buckets)].iter() {
+                    if buckets.len() != other_buckets.len() {
+                        writeln!(diffs, "DividendMuReport: {} {} buckets {} != {}", id, name, buckets.len(), other_buckets.len())?;
+                    }
+                    for (i, (bucket, other_bucket)) in buckets.iter().zip(other_buckets.iter()).enumerate() {
+                        if !approx_eq(*bucket, *other_bucket, tolerance) {
+                            writeln!(diffs, "DividendMuReport: {} {} bucket {} mu {} != {} tol={}",
+                                id, name, i, bucket, other_bucket, tolerance)?;
+                        }
+                    }
+                }
+            } else {
+                write!(diffs, "DividendMuReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for DividendMuReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<DividendMuReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "DividendMuReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for mu split into cash and yield components, in parallel and
+/// bucketed by ex date. The bump size is a relative change, as for mu. The
+/// pillars define the buckets as for RateBucket. If there are no pillars,
+/// only the parallel figures are calculated.
+///
+/// Bucketed relative bumps do not exactly cancel, so unlike mu, each up and
+/// each down bump is applied separately to the unbumped state.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DividendMuReportGenerator {
+    bumpsize: f64,
+    pillars: Vec<Date>
+}
+
+impl DividendMuReportGenerator {
+    /// Creates a generator for the given pillars, which must be strictly
+    /// increasing.
+    pub fn new(bumpsize: f64, pillars: Vec<Date>)
+        -> Result<DividendMuReportGenerator, qm::Error> {
+        if !pillars.is_empty() {
+            RateBucket::new(pillars.clone(), 0)?;
+        }
+        Ok(DividendMuReportGenerator { bumpsize: bumpsize, pillars: pillars })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DividendMuReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn pillars(&self) -> &[Date] { &self.pillars }
+
+    fn mu(&self, id: &str, up: BumpDivs, down: BumpDivs, pricer: &mut Pricer,
+        saveable: &mut Saveable, unbumped: f64) -> Result<f64, qm::Error> {
+
+        let upbumped = bumped_price(&Bump::new_divs(id, up), pricer, Some(saveable), unbumped)?;
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        let downbumped = bumped_price(&Bump::new_divs(id, down), pricer, Some(saveable), unbumped)?;
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        Ok((upbumped - downbumped) / (2.0 * self.bumpsize) * PERCENT)
+    }
+}
+
+impl TypeId for DividendMuReportGenerator {
+    fn type_id(&self) -> &'static str { "DividendMuReportGenerator" }
+}
+
+impl ReportGenerator for DividendMuReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        let size = self.bumpsize;
+
+        // as for mu
+        let ids: Vec<String> = pricer.as_bumpable().dependencies()?
+            .forward_curves().keys().filter(|inst| inst.as_exchange_rate().is_none()
+                && !inst.is_forward_quoted())
+            .map(|inst| inst.id().to_string()).collect();
+
+        let mut results = HashMap::new();
+        for id in ids.iter() {
+            let cash = self.mu(id, BumpDivs::new_cash_relative(size, None),
+                BumpDivs::new_cash_relative(-size, None), pricer, saveable, unbumped)?;
+            let dividend_yield = self.mu(id, BumpDivs::new_yield_relative(size, None),
+                BumpDivs::new_yield_relative(-size, None), pricer, saveable, unbumped)?;
+
+            let mut cash_buckets = Vec::with_capacity(self.pillars.len());
+            let mut dividend_yield_buckets = Vec::with_capacity(self.pillars.len());
+            for pillar in 0..self.pillars.len() {
+                let bucket = RateBucket::new(self.pillars.clone(), pillar)?;
+                cash_buckets.push(self.mu(id,
+                    BumpDivs::new_cash_relative(size, Some(bucket.clone())),
+                    BumpDivs::new_cash_relative(-size, Some(bucket.clone())),
+                    pricer, saveable, unbumped)?);
+                dividend_yield_buckets.push(self.mu(id,
+                    BumpDivs::new_yield_relative(size, Some(bucket.clone())),
+                    BumpDivs::new_yield_relative(-size, Some(bucket)),
+                    pricer, saveable, unbumped)?);
+            }
+
+            results.insert(id.to_string(), DividendMu { cash: cash,
+                dividend_yield: dividend_yield, cash_buckets: cash_buckets,
+                dividend_yield_buckets: dividend_yield_buckets });
+        }
+
+        Ok(Qbox::new(Box::new(DividendMuReport { bumpsize: self.bumpsize,
+            pillars: self.pillars.clone(), results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use instruments::RcInstrument;
+    use instruments::dividends::tests::sample_dividend_future;
+    use pricers::selfpricer::SelfPricer;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::RcReportGenerator;
+    use risk::deltagamma::tests::sample_pricer;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    #[test]
+    fn mu_dividend_future() {
+        let market_data = sample_market_data();
+        let future = sample_dividend_future(2.0);
+        let expected = future.futures_price(&market_data).unwrap();
+        let future = RcInstrument::new(Qrc::new(Arc::new(future)));
+        let mut pricer = SelfPricer::new(vec![(1.0, future)], &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = MuReportGenerator::new(0.01);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<MuReport>().unwrap().results().clone();
+
+        // a future on ten times the dividends gains about a tenth of the
+        // expected dividends for a one percent rise in them
+        assert_eq!(results.len(), 1);
+        let mu = *results.get("BP.L").unwrap();
+        assert!(mu > 0.09 * expected && mu <= 0.1 * expected,
+            "mu={} expected={}", mu, expected);
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-9);
+    }
+
+    /// The ex dates of the sample dividends
+    fn sample_pillars() -> Vec<Date> {
+        let d = Date::from_ymd(2017, 01, 02);
+        vec![d + 28, d + 210, d + 392, d + 574]
+    }
+
+    #[test]
+    fn dividend_mu_european() {
+        // create a pricer for a european at the money call, expiring on
+        // 2018-06-01, after the first three of the sample dividends
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = DividendMuReportGenerator::new(0.01, sample_pillars()).unwrap();
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let report = report.as_any().downcast_ref::<DividendMuReport>().unwrap();
+        assert_eq!(report.pillars(), &sample_pillars()[..]);
+        let results = &report.results()["BP.L"];
+
+        let generator = MuReportGenerator::new(0.01);
+        let mu = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let mu = mu.as_any().downcast_ref::<MuReport>().unwrap().results()["BP.L"];
+
+        // raising the dividends lowers the forward and so the call, and
+        // the components add up to the mu of all the dividends
+        assert!(results.cash() < 0.0 && results.dividend_yield() < 0.0);
+        assert_approx(results.cash() + results.dividend_yield(), mu, 1e-3 * mu.abs());
+
+        // Each dividend is on its own pillar, so the buckets show the risk
+        // to each dividend. The first is all cash and the last goes ex
+        // after the option expires.
+        let cash = results.cash_buckets();
+        let dividend_yield = results.dividend_yield_buckets();
+        assert_eq!(cash.len(), 4);
+        assert_eq!(dividend_yield.len(), 4);
+        assert!(cash[0] < 0.0 && cash[1] < 0.0 && cash[2] < 0.0);
+        assert!(dividend_yield[1] < 0.0 && dividend_yield[2] < 0.0);
+        assert_eq!(dividend_yield[0], 0.0);
+        assert_eq!(cash[3], 0.0);
+        assert_eq!(dividend_yield[3], 0.0);
+        assert_approx(cash.iter().sum(), results.cash(), 1e-3 * results.cash().abs());
+        assert_approx(dividend_yield.iter().sum(), results.dividend_yield(),
+            1e-3 * results.dividend_yield().abs());
+
+        // after all the bumps, the price is restored
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn dividend_mu_without_buckets() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = DividendMuReportGenerator::new(0.01, Vec::new()).unwrap();
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let report = report.as_any().downcast_ref::<DividendMuReport>().unwrap();
+        let results = &report.results()["BP.L"];
+        assert!(results.cash() < 0.0);
+        assert!(results.cash_buckets().is_empty());
+        assert!(results.dividend_yield_buckets().is_empty());
+
+        let mut pillars = sample_pillars();
+        pillars.reverse();
+        assert!(DividendMuReportGenerator::new(0.01, pillars).is_err());
+    }
+
+    #[test]
+    fn serde_dividend_mu_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(
+            DividendMuReportGenerator::new(0.01, sample_pillars()).unwrap()));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    #[test]
+    fn serde_mu_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(MuReportGenerator::new(0.01)));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
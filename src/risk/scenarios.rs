@@ -0,0 +1,479 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use risk::bumptime::BumpTime;
+use data::bump::Bump;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// One step of a scenario. Either a bump of market data, such as a spot,
+/// vol, yield or dividend bump, or a move of the spot date.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ScenarioStep {
+    Bump(Bump),
+    Time(BumpTime)
+}
+
+/// A named scenario, which is an ordered list of steps. The steps are
+/// applied in order, so a replacement of the spot followed by a relative
+/// bump is not the same as the relative bump followed by the replacement.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Scenario {
+    name: String,
+    steps: Vec<ScenarioStep>
+}
+
+impl Scenario {
+    pub fn new(name: &str, steps: Vec<ScenarioStep>) -> Scenario {
+        Scenario { name: name.to_string(), steps: steps }
+    }
+
+    /// Creates a scenario that only bumps market data
+    pub fn from_bumps(name: &str, bumps: Vec<Bump>) -> Scenario {
+        Scenario::new(name, bumps.into_iter().map(ScenarioStep::Bump).collect())
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn steps(&self) -> &[ScenarioStep] { &self.steps }
+
+    /// Composes two scenarios, giving one that applies the steps of this
+    /// one followed by those of the other.
+    pub fn then(&self, name: &str, other: &Scenario) -> Scenario {
+        let mut steps = self.steps.clone();
+        steps.extend(other.steps.iter().cloned());
+        Scenario::new(name, steps)
+    }
+
+    /// Returns the price under this scenario, leaving the pricer as it
+    /// was. Market data bumps are applied and restored through the save
+    /// and restore facility of the pricer. Moves of the spot date cannot
+    /// be restored, so if there are any, the steps are applied to a clone
+    /// of the pricer instead. If no step changes anything, the unbumped
+    /// price is returned, avoiding a repricing.
+    pub fn price(&self, pricer: &mut Pricer, unbumped: f64)
+        -> Result<f64, qm::Error> {
+
+        let time_bumped = self.steps.iter().any(|step| match *step {
+            ScenarioStep::Time(_) => true,
+            ScenarioStep::Bump(_) => false });
+        if time_bumped {
+            let mut pricer_clone = pricer.clone_box();
+            for step in self.steps.iter() {
+                match *step {
+                    ScenarioStep::Bump(ref bump) => {
+                        pricer_clone.as_mut_bumpable().bump(bump, None)?;
+                    },
+                    ScenarioStep::Time(ref bump) =>
+                        pricer_clone.as_mut_time_bumpable().bump_time(bump)?
+                }
+            }
+            return pricer_clone.price()
+        }
+
+        // Each bump has a save area of its own, as a later bump of the same
+        // data would overwrite what an earlier one saved. They are restored
+        // in reverse order, whether or not the scenario succeeds. A failed
+        // restore does not stop the others, so the pricer is left as
+        // unbumped as we can make it, and we report the first error.
+        let mut saved = Vec::new();
+        let mut result = self.bump_and_price(pricer, unbumped, &mut saved);
+        while let Some(save) = saved.pop() {
+            let restored = pricer.as_mut_bumpable().restore(&*save);
+            if let Err(e) = restored {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    fn bump_and_price(&self, pricer: &mut Pricer, unbumped: f64,
+        saved: &mut Vec<Box<Saveable>>) -> Result<f64, qm::Error> {
+
+        let mut bumped = false;
+        for step in self.steps.iter() {
+            if let ScenarioStep::Bump(ref bump) = *step {
+                // save before checking for errors, as a failed bump may
+                // have bumped some of the data
+                let mut save = pricer.as_bumpable().new_saveable();
+                let result = pricer.as_mut_bumpable().bump(bump, Some(&mut *save));
+                saved.push(save);
+                bumped |= result?;
+            }
+        }
+
+        if bumped {
+            pricer.price()
+        } else {
+            Ok(unbumped)
+        }
+    }
+}
+
+/// The price of a portfolio under a named scenario, and its change from
+/// the unbumped price
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScenarioResult {
+    name: String,
+    price: f64,
+    change: f64
+}
+
+impl ScenarioResult {
+    pub fn new(name: &str, price: f64, change: f64) -> ScenarioResult {
+        ScenarioResult { name: name.to_string(), price: price, change: change }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn price(&self) -> f64 { self.price }
+    pub fn change(&self) -> f64 { self.change }
+}
+
+/// A report of the prices under each of a list of scenarios, in the order
+/// the scenarios were given
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScenarioReport {
+    results: Vec<ScenarioResult>
+}
+
+impl Report for ScenarioReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for ScenarioReport {
+    fn type_id(&self) -> &'static str { "ScenarioReport" }
+}
+
+impl ScenarioReport {
+    pub fn new(results: Vec<ScenarioResult>) -> ScenarioReport {
+        ScenarioReport { results: results }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(ScenarioReport::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &[ScenarioResult] { &self.results }
+
+    /// The result of the scenario with the given name, if there is one
+    pub fn get(&self, name: &str) -> Option<&ScenarioResult> {
+        self.results.iter().find(|result| result.name == name)
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v ScenarioReport> for &'v ScenarioReport {
+    fn validate(self, other: &'v ScenarioReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        // Scenario prices may be from different random numbers than the
+        // unbumped price, so use the price tolerance for the changes too
+        let tolerance = tol.price();
+
+        if self.results.len() != other.results.len() {
+            writeln!(diffs, "ScenarioReport: number of scenarios {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        for (result, other_result) in self.results.iter().zip(other.results.iter()) {
+            if result.name != other_result.name {
+                writeln!(diffs, "ScenarioReport: scenario {} != {}", result.name, other_result.name)?;
+            }
+            if !approx_eq(result.price, other_result.price, tolerance) {
+                writeln!(diffs, "ScenarioReport: {} price {} != {} tol={}", result.name, result.price, other_result.price, tolerance)?;
+            }
+            if !approx_eq(result.change, other_result.change, tolerance) {
+                writeln!(diffs, "ScenarioReport: {} change {} != {} tol={}", result.name, result.change, other_result.change, tolerance)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for ScenarioReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<ScenarioReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "ScenarioReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for the prices of a pricer, normally of a portfolio, under
+/// each of a list of named scenarios. The pricer is left as it was. Each
+/// bump in a scenario is given a save area of its own, so the saveable
+/// passed in is not used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScenarioReportGenerator {
+    scenarios: Vec<Scenario>
+}
+
+impl ScenarioReportGenerator {
+    pub fn new(scenarios: Vec<Scenario>) -> ScenarioReportGenerator {
+        ScenarioReportGenerator { scenarios: scenarios }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ScenarioReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn scenarios(&self) -> &[Scenario] { &self.scenarios }
+}
+
+impl TypeId for ScenarioReportGenerator {
+    fn type_id(&self) -> &'static str { "ScenarioReportGenerator" }
+}
+
+impl ReportGenerator for ScenarioReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, _saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        let mut results = Vec::with_capacity(self.scenarios.len());
+        for scenario in self.scenarios.iter() {
+            let price = scenario.price(pricer, unbumped)?;
+            results.push(ScenarioResult { name: scenario.name.clone(),
+                price: price, change: price - unbumped });
+        }
+
+        Ok(Qbox::new(Box::new(ScenarioReport { results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instruments::RcInstrument;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use pricers::selfpricer::SelfPricer;
+    use risk::RcReportGenerator;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use models::RcMonteCarloModelFactory;
+    use models::Threading;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_underlying;
+    use risk::marketdata::tests::sample_expiry;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::bumpyield::BumpYield;
+    use data::bumpdivs::BumpDivs;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::Date;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn crash() -> Scenario {
+        Scenario::from_bumps("Crash", vec![
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-0.2)),
+            Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.1))])
+    }
+
+    /// The price after applying the given bumps one by one
+    fn manually_bumped(bumps: &[Bump]) -> f64 {
+        let mut pricer = sample_pricer();
+        for bump in bumps.iter() {
+            assert!(pricer.as_mut_bumpable().bump(bump, None).unwrap());
+        }
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn scenario_matches_manual_bumps() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+
+        let price = crash().price(&mut *pricer, unbumped).unwrap();
+        let expected = manually_bumped(&[
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-0.2)),
+            Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.1))]);
+        assert_approx(price, expected, 1e-12);
+        assert!(price < unbumped - 5.0);
+
+        // the pricer is restored after the scenario
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // a scenario that bumps nothing the pricer depends on leaves the
+        // price unchanged
+        let elsewhere = Scenario::from_bumps("Elsewhere", vec![
+            Bump::new_spot("GSK.L", BumpSpot::new_relative(0.5)),
+            Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.1))]);
+        assert_approx(elsewhere.price(&mut *pricer, unbumped).unwrap(), unbumped, 1e-12);
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn repeated_bumps_are_restored() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+
+        // bumping the same data twice must still restore the original
+        let scenario = Scenario::from_bumps("Twice", vec![
+            Bump::new_spot("BP.L", BumpSpot::new_relative(0.1)),
+            Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.5)),
+            Bump::new_yield("OPT", BumpYield::new_flat_continuously_compounded(0.01)),
+            Bump::new_spot("BP.L", BumpSpot::new_relative(0.1))]);
+        let price = scenario.price(&mut *pricer, unbumped).unwrap();
+        assert!(price > unbumped + 5.0);
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn monte_carlo_scenario_is_restored() {
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 1000)));
+        let mut pricer = MonteCarloPricer::with_settings(vec![(1.0, instrument)],
+            model_factory, MonteCarloSettings { threading: Threading::new(1, Some(42)),
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        // the cached paths are bumped twice, and must be restored to the
+        // original ones, not those after the first bump
+        let scenario = crash().then("CrashAgain", &crash());
+        let price = scenario.price(&mut pricer, unbumped).unwrap();
+        assert!(price < unbumped - 10.0);
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn composed_scenarios_apply_in_order() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+
+        let replace = Scenario::from_bumps("Replace", vec![
+            Bump::new_spot("BP.L", BumpSpot::new_replace(120.0))]);
+        let rally = Scenario::from_bumps("Rally", vec![
+            Bump::new_spot("BP.L", BumpSpot::new_relative(0.1))]);
+
+        let replace_then_rally = replace.then("ReplaceThenRally", &rally);
+        assert_eq!(replace_then_rally.name(), "ReplaceThenRally");
+        assert_eq!(replace_then_rally.steps().len(), 2);
+        let price = replace_then_rally.price(&mut *pricer, unbumped).unwrap();
+        assert_approx(price, manually_bumped(&[
+            Bump::new_spot("BP.L", BumpSpot::new_replace(132.0))]), 1e-12);
+
+        let rally_then_replace = rally.then("RallyThenReplace", &replace);
+        let price = rally_then_replace.price(&mut *pricer, unbumped).unwrap();
+        assert_approx(price, manually_bumped(&[
+            Bump::new_spot("BP.L", BumpSpot::new_replace(120.0))]), 1e-12);
+
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn scenario_with_time_step() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+
+        // moving forward one day on its own gives the theta bumped price
+        let theta_date = pricer.as_bumpable().context().spot_date() + 1;
+        let bump = BumpTime::new(theta_date, theta_date, SpotDynamics::StickyForward);
+        let tomorrow = Scenario::new("Tomorrow", vec![ScenarioStep::Time(bump)]);
+        let price = tomorrow.price(&mut *pricer, unbumped).unwrap();
+        assert_approx(price, 16.696665883860128, 1e-12);
+
+        // a crash tomorrow is the same as a crash followed by a day's decay
+        let crash_tomorrow = crash().then("CrashTomorrow", &tomorrow);
+        let price = crash_tomorrow.price(&mut *pricer, unbumped).unwrap();
+        let mut expected = sample_pricer();
+        for step in crash_tomorrow.steps() {
+            match *step {
+                ScenarioStep::Bump(ref bump) => {
+                    expected.as_mut_bumpable().bump(bump, None).unwrap();
+                },
+                ScenarioStep::Time(ref bump) =>
+                    expected.as_mut_time_bumpable().bump_time(bump).unwrap()
+            }
+        }
+        assert_approx(price, expected.price().unwrap(), 1e-12);
+        assert!(price < crash().price(&mut *pricer, unbumped).unwrap());
+
+        // the original pricer is unchanged
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-14);
+    }
+
+    #[test]
+    fn scenario_report_for_portfolio() {
+        let market_data = sample_market_data();
+        let equity = sample_underlying();
+        let expiry = sample_expiry();
+        let option = |id: &str, put_or_call| RcInstrument::new(Qrc::new(Arc::new(
+            SpotStartingEuropean::new(id, "OPT", equity.clone(), sample_settlement(2),
+            expiry, 100.0, put_or_call, OptionSettlement::Cash).unwrap())));
+        let call = option("Call", PutOrCall::Call);
+        let put = option("Put", PutOrCall::Put);
+        let positions = vec![(2.0, call.clone()), (-1.0, put.clone())];
+        let mut pricer = SelfPricer::new(positions, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        let rally = Scenario::from_bumps("Rally", vec![
+            Bump::new_spot("BP.L", BumpSpot::new_relative(0.1))]);
+        let generator = ScenarioReportGenerator::new(vec![crash(), rally.clone()]);
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<ScenarioReport>().unwrap();
+        assert_eq!(results.results().len(), 2);
+        assert_eq!(results.results()[0].name(), "Crash");
+
+        // the portfolio moves by the weighted moves of its positions
+        for scenario in [crash(), rally].iter() {
+            let mut expected = 0.0;
+            for &(weight, ref instrument) in [(2.0, call.clone()), (-1.0, put.clone())].iter() {
+                let mut single = SelfPricer::new(vec![(1.0, instrument.clone())],
+                    &market_data).unwrap();
+                let single_unbumped = single.price().unwrap();
+                expected += weight * (scenario.price(&mut single, single_unbumped).unwrap()
+                    - single_unbumped);
+            }
+            let result = results.get(scenario.name()).unwrap();
+            assert_approx(result.change(), expected, 1e-10);
+            assert_approx(result.price(), unbumped + expected, 1e-10);
+        }
+        assert!(results.get("Rally").unwrap().change() > 0.0);
+        assert!(results.get("Crash").unwrap().change() < 0.0);
+        assert!(results.get("Missing").is_none());
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn serde_scenario_generator_roundtrip() {
+        let theta_date = Date::from_ymd(2017, 01, 03);
+        let tomorrow = Scenario::new("Tomorrow", vec![ScenarioStep::Time(
+            BumpTime::new(theta_date, theta_date, SpotDynamics::StickyForward))]);
+        let rates = Scenario::from_bumps("Rates", vec![
+            Bump::new_yield("OPT", BumpYield::new_flat_annualised(0.01)),
+            Bump::new_divs("BP.L", BumpDivs::new_all_relative(-0.5))]);
+        let generator = RcReportGenerator::new(Arc::new(ScenarioReportGenerator::new(
+            vec![crash().then("CrashTomorrow", &tomorrow), rates])));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::dependencies::DependencyCollector;
+use risk::scenarios::{Scenario, ScenarioResult, ScenarioReport};
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use data::bumpvol::BumpVol;
+use data::bumpyield::BumpYield;
+use data::bumpyield::RateBucket;
+use data::bumpdivs::BumpDivs;
+use data::bumpcorrelation::BumpCorrelation;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// One shock of a stress. Most shocks apply the same bump to all the market
+/// data of one kind that the portfolio depends on, such as all the spots
+/// or all the vol surfaces, so a stress can be defined without knowing what
+/// is in the portfolio. A shock may also be a single bump of specific data.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Shock {
+    /// Bumps the spots of all the underlyings except FX rates
+    Spots(BumpSpot),
+    /// Bumps all the FX rates
+    FxSpots(BumpSpot),
+    /// Bumps all the vol surfaces
+    Vols(BumpVol),
+    /// Bumps all the yield curves
+    Yields(BumpYield),
+    /// Bumps the dividends of all the underlyings that have them
+    Divs(BumpDivs),
+    /// Bumps the borrow curves of all the underlyings that have them
+    Borrows(BumpYield),
+    /// Bumps the correlations between all pairs of underlyings, where they
+    /// are supplied
+    Correlations(BumpCorrelation),
+    /// A single bump of specific market data
+    Bump(Bump)
+}
+
+/// A named stress, which is a list of shocks. This is turned into a
+/// scenario for a particular portfolio, by expanding each shock into bumps
+/// of the market data the portfolio depends on. As with a scenario, the
+/// shocks are applied in order.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Stress {
+    name: String,
+    shocks: Vec<Shock>
+}
+
+impl Stress {
+    pub fn new(name: &str, shocks: Vec<Shock>) -> Stress {
+        Stress { name: name.to_string(), shocks: shocks }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn shocks(&self) -> &[Shock] { &self.shocks }
+
+    /// Creates the scenario for a portfolio with the given dependencies.
+    /// The ids are sorted, so the bumps are in a well-defined order.
+    pub fn scenario(&self, dependencies: &DependencyCollector) -> Scenario {
+
+        // as for delta and mu
+        let mut fx_ids: Vec<String> = dependencies.fx_rates().keys()
+            .map(|id| id.to_string()).collect();
+        fx_ids.sort();
+        let mut spot_ids = dependencies.instruments_clone();
+        spot_ids.retain(|id| !fx_ids.contains(id));
+        spot_ids.sort();
+        let mut forward_ids: Vec<String> = dependencies.forward_curves().keys()
+            .filter(|inst| inst.as_exchange_rate().is_none() && !inst.is_forward_quoted())
+            .map(|inst| inst.id().to_string()).collect();
+        forward_ids.sort();
+        let mut vol_ids: Vec<String> = dependencies.vol_surfaces().keys()
+            .map(|inst| inst.id().to_string()).collect();
+        vol_ids.sort();
+        let mut credit_ids: Vec<String> = dependencies.yield_curves().keys()
+            .map(|id| id.to_string()).collect();
+        credit_ids.sort();
+
+        let mut bumps = Vec::new();
+        for shock in self.shocks.iter() {
+            match *shock {
+                Shock::Spots(ref bump) => bumps.extend(spot_ids.iter()
+                    .map(|id| Bump::new_spot(id, bump.clone()))),
+                Shock::FxSpots(ref bump) => bumps.extend(fx_ids.iter()
+                    .map(|id| Bump::new_fx_spot(id, bump.clone()))),
+                Shock::Vols(ref bump) => bumps.extend(vol_ids.iter()
+                    .map(|id| Bump::new_vol(id, bump.clone()))),
+                Shock::Yields(ref bump) => bumps.extend(credit_ids.iter()
+                    .map(|id| Bump::new_yield(id, bump.clone()))),
+                Shock::Divs(ref bump) => bumps.extend(forward_ids.iter()
+                    .map(|id| Bump::new_divs(id, bump.clone()))),
+                Shock::Borrows(ref bump) => bumps.extend(forward_ids.iter()
+                    .map(|id| Bump::new_borrow(id, bump.clone()))),
+                Shock::Correlations(ref bump) => {
+                    let mut ids: Vec<&String> = spot_ids.iter().chain(fx_ids.iter()).collect();
+                    ids.sort();
+                    for (i, first) in ids.iter().enumerate() {
+                        for second in ids[i + 1..].iter() {
+                            bumps.push(Bump::new_correlation(first, second, bump.clone()));
+                        }
+                    }
+                },
+                Shock::Bump(ref bump) => bumps.push(bump.clone())
+            }
+        }
+
+        Scenario::from_bumps(&self.name, bumps)
+    }
+}
+
+/// A library of stresses, which are stylised versions of large historical
+/// market moves. The yield curve stresses pivot between pillars one and ten
+/// years after the spot date, so the library depends on the spot date.
+pub fn historical_stresses(spot_date: Date) -> Result<Vec<Stress>, qm::Error> {
+
+    let pillars = vec![spot_date + 365, spot_date + 3650];
+    let twist = |short: f64, long: f64| -> Result<Vec<Shock>, qm::Error> {
+        Ok(vec![
+            Shock::Yields(BumpYield::new_key_rate(short, RateBucket::new(pillars.clone(), 0)?)),
+            Shock::Yields(BumpYield::new_key_rate(long, RateBucket::new(pillars.clone(), 1)?))])
+    };
+
+    Ok(vec![
+        Stress::new("Equity crash", vec![
+            Shock::Spots(BumpSpot::new_relative(-0.3)),
+            Shock::Vols(BumpVol::new_flat_additive(0.15)),
+            Shock::Correlations(BumpCorrelation::new_additive(0.2))]),
+        Stress::new("Equity rally", vec![
+            Shock::Spots(BumpSpot::new_relative(0.15)),
+            Shock::Vols(BumpVol::new_flat_additive(-0.05))]),
+        Stress::new("Rates up 100bp", vec![
+            Shock::Yields(BumpYield::new_flat_annualised(0.01))]),
+        Stress::new("Rates down 100bp", vec![
+            Shock::Yields(BumpYield::new_flat_annualised(-0.01))]),
+        Stress::new("Curve steepening", twist(-0.005, 0.005)?),
+        Stress::new("Curve flattening", twist(0.005, -0.005)?),
+        Stress::new("Dividend cut", vec![
+            Shock::Divs(BumpDivs::new_all_relative(-0.5))]),
+        Stress::new("FX devaluation", vec![
+            Shock::FxSpots(BumpSpot::new_relative(-0.2))])])
+}
+
+/// Calculator for the prices of a pricer, normally of a portfolio, under
+/// each of a list of named stresses. The results are in a scenario report
+/// keyed by the name of each stress. The pricer is left as it was and, as
+/// for scenarios, the saveable passed in is not used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StressReportGenerator {
+    stresses: Vec<Stress>
+}
+
+impl StressReportGenerator {
+    pub fn new(stresses: Vec<Stress>) -> StressReportGenerator {
+        StressReportGenerator { stresses: stresses }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(StressReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn stresses(&self) -> &[Stress] { &self.stresses }
+}
+
+impl TypeId for StressReportGenerator {
+    fn type_id(&self) -> &'static str { "StressReportGenerator" }
+}
+
+impl ReportGenerator for StressReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, _saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        let scenarios: Vec<Scenario> = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            self.stresses.iter().map(|stress| stress.scenario(dependencies)).collect()
+        };
+
+        let mut results = Vec::with_capacity(scenarios.len());
+        for scenario in scenarios.iter() {
+            let price = scenario.price(pricer, unbumped)?;
+            results.push(ScenarioResult::new(scenario.name(), price, price - unbumped));
+        }
+
+        Ok(Qbox::new(Box::new(ScenarioReport::new(results))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use pricers::selfpricer::SelfPricer;
+    use risk::RcReportGenerator;
+    use risk::scenarios::ScenarioStep;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::marketdata::tests::sample_correlated_market_data;
+    use risk::marketdata::tests::sample_margrabe;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    /// The price after applying the given bumps one by one
+    fn manually_bumped(bumps: &[Bump]) -> f64 {
+        let mut pricer = sample_pricer();
+        for bump in bumps.iter() {
+            pricer.as_mut_bumpable().bump(bump, None).unwrap();
+        }
+        pricer.price().unwrap()
+    }
+
+    fn spot_date() -> Date {
+        Date::from_ymd(2017, 01, 02)
+    }
+
+    #[test]
+    fn historical_stresses_european() {
+        // create a pricer for a european at the money call
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let stresses = historical_stresses(spot_date()).unwrap();
+        let generator = StressReportGenerator::new(stresses.clone());
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<ScenarioReport>().unwrap();
+        assert_eq!(results.results().len(), stresses.len());
+
+        // the crash shocks the spot and vol, and there is nothing to
+        // correlate with
+        let crash = results.get("Equity crash").unwrap();
+        let expected = manually_bumped(&[
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-0.3)),
+            Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.15))]);
+        assert_approx(crash.price(), expected, 1e-12);
+        assert_approx(crash.change(), expected - unbumped, 1e-12);
+        assert!(crash.change() < 0.0);
+        assert!(results.get("Equity rally").unwrap().change() > 0.0);
+
+        // The call gains from higher rates. Steepening raises the long
+        // rates and lowers the short ones, with the option in between.
+        let pillars = vec![spot_date() + 365, spot_date() + 3650];
+        let expected = manually_bumped(&[
+            Bump::new_yield("LSE", BumpYield::new_key_rate(-0.005,
+                RateBucket::new(pillars.clone(), 0).unwrap())),
+            Bump::new_yield("LSE", BumpYield::new_key_rate(0.005,
+                RateBucket::new(pillars.clone(), 1).unwrap()))]);
+        assert_approx(results.get("Curve steepening").unwrap().price(), expected, 1e-12);
+        assert!(results.get("Rates up 100bp").unwrap().change() > 0.0);
+        assert!(results.get("Rates down 100bp").unwrap().change() < 0.0);
+
+        // cutting dividends raises the forward
+        assert!(results.get("Dividend cut").unwrap().change() > 0.0);
+
+        // there are no FX rates to devalue
+        assert_eq!(results.get("FX devaluation").unwrap().change(), 0.0);
+
+        // the pricer is left as it was
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn stress_is_portfolio_wide() {
+        let market_data = sample_correlated_market_data(0.5);
+        let pricer = SelfPricer::new(vec![(1.0, sample_margrabe())], &market_data).unwrap();
+
+        let stress = Stress::new("Crash", vec![
+            Shock::Spots(BumpSpot::new_relative(-0.3)),
+            Shock::Correlations(BumpCorrelation::new_additive(0.2))]);
+        let scenario = stress.scenario(pricer.as_bumpable().dependencies().unwrap());
+        assert_eq!(scenario.name(), "Crash");
+        let bumps: Vec<String> = scenario.steps().iter().map(|step| match *step {
+            ScenarioStep::Bump(ref bump) => format!("{:?}", bump),
+            ScenarioStep::Time(_) => panic!("unexpected time step") }).collect();
+        assert_eq!(bumps.len(), 3);
+        assert!(bumps[0].contains("BP.L") && bumps[1].contains("GSK.L"));
+        assert!(bumps[2].contains("Correlation"));
+    }
+
+    #[test]
+    fn serde_stress_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(StressReportGenerator::new(
+            historical_stresses(spot_date()).unwrap())));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
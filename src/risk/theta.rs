@@ -0,0 +1,300 @@
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::ApproxEqReport;
+use risk::bumptime::BumpTime;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpspotdate::BumpSpotDate;
+use data::bumpspotdate::SpotDynamics;
+use dates::Date;
+use dates::calendar::RcCalendar;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// How far forward a theta moves the spot date.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ThetaDay {
+    /// The next calendar day
+    Calendar,
+    /// The next business day in the given calendar, so theta on a Friday
+    /// normally covers the weekend
+    Business(RcCalendar)
+}
+
+/// The conventions of one theta calculation. As well as the day to move
+/// to, this defines what happens to spot, and whether any fixings between
+/// the old and new spot dates are rolled into the instruments. Sticky spot
+/// dynamics leave the spots where they are, so the vols at each strike are
+/// also unchanged, which gives a sticky strike theta. Sticky forward
+/// dynamics move the spots up their forwards.
+///
+/// A theta that does not roll fixings only moves the spot date of the
+/// market data. This isolates the pure time decay from the effect of
+/// fixings, but it fails for instruments that need a fixing that falls
+/// between the two dates.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThetaConvention {
+    name: String,
+    day: ThetaDay,
+    spot_dynamics: SpotDynamics,
+    roll_fixings: bool
+}
+
+impl ThetaConvention {
+    pub fn new(name: &str, day: ThetaDay, spot_dynamics: SpotDynamics,
+        roll_fixings: bool) -> ThetaConvention {
+        ThetaConvention { name: name.to_string(), day: day,
+            spot_dynamics: spot_dynamics, roll_fixings: roll_fixings }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn day(&self) -> &ThetaDay { &self.day }
+    pub fn spot_dynamics(&self) -> SpotDynamics { self.spot_dynamics }
+    pub fn roll_fixings(&self) -> bool { self.roll_fixings }
+
+    /// The date a theta from the given spot date moves to
+    pub fn theta_date(&self, spot_date: Date) -> Date {
+        match self.day {
+            ThetaDay::Calendar => spot_date + 1,
+            ThetaDay::Business(ref calendar) => calendar.step(spot_date, 1, true)
+        }
+    }
+
+    /// Returns the theta date and the price as of that date. The time bump
+    /// irreversibly modifies the pricer, so it is applied to a clone.
+    pub fn price(&self, pricer: &Pricer) -> Result<(Date, f64), qm::Error> {
+        let date = self.theta_date(pricer.as_bumpable().context().spot_date());
+        let mut pricer_clone = pricer.clone_box();
+        if self.roll_fixings {
+            pricer_clone.bump_time(&BumpTime::new(date, date, self.spot_dynamics))?;
+        } else {
+            let bump = Bump::new_spot_date(BumpSpotDate::new(date, self.spot_dynamics));
+            pricer_clone.as_mut_bumpable().bump(&bump, None)?;
+        }
+        Ok((date, pricer_clone.price()?))
+    }
+}
+
+/// The theta calculated under one named set of conventions
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThetaResult {
+    name: String,
+    date: Date,
+    price: f64,
+    theta: f64
+}
+
+impl ThetaResult {
+    pub fn name(&self) -> &str { &self.name }
+    pub fn date(&self) -> Date { self.date }
+    pub fn price(&self) -> f64 { self.price }
+    pub fn theta(&self) -> f64 { self.theta }
+}
+
+/// A report of theta under each of the requested conventions, in the order
+/// they were requested. Each is reported separately, so that a P&L explain
+/// can pick the one that matches how it moves the market.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThetaReport {
+    results: Vec<ThetaResult>
+}
+
+impl Report for ThetaReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for ThetaReport {
+    fn type_id(&self) -> &'static str { "ThetaReport" }
+}
+
+impl ThetaReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(ThetaReport::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &[ThetaResult] { &self.results }
+
+    /// Finds the theta with the given convention name
+    pub fn get(&self, name: &str) -> Option<&ThetaResult> {
+        self.results.iter().find(|result| result.name == name)
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v ThetaReport> for &'v ThetaReport {
+    fn validate(self, other: &'v ThetaReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        // Use the price tolerance for theta as well as bumped price, as Monte-Carlo may not use the
+        // same random numbers for bumped and unbumped in this case.
+        let tolerance = tol.price();
+
+        if self.results.len() != other.results.len() {
+            writeln!(diffs, "ThetaReport: number of results {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        for (result, other_result) in self.results.iter().zip(other.results.iter()) {
+            if result.name != other_result.name || result.date != other_result.date {
+                writeln!(diffs, "ThetaReport: {} on {} != {} on {}", result.name, result.date,
+                    other_result.name, other_result.date)?;
+            }
+            if !approx_eq(result.price, other_result.price, tolerance) {
+                writeln!(diffs, "ThetaReport: {} price {} != {} tol={}", result.name,
+                    result.price, other_result.price, tolerance)?;
+            }
+            if !approx_eq(result.theta, other_result.theta, tolerance) {
+                writeln!(diffs, "ThetaReport: {} theta {} != {} tol={}", result.name,
+                    result.theta, other_result.theta, tolerance)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for ThetaReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<ThetaReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "ThetaReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for theta under one or more sets of conventions. Unlike the
+/// time bumped report, which moves to any date and may calculate risks
+/// there, this always moves one day forward.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThetaReportGenerator {
+    conventions: Vec<ThetaConvention>
+}
+
+impl ThetaReportGenerator {
+    pub fn new(conventions: Vec<ThetaConvention>) -> ThetaReportGenerator {
+        ThetaReportGenerator { conventions: conventions }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(ThetaReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn conventions(&self) -> &[ThetaConvention] { &self.conventions }
+}
+
+impl TypeId for ThetaReportGenerator {
+    fn type_id(&self) -> &'static str { "ThetaReportGenerator" }
+}
+
+impl ReportGenerator for ThetaReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, _saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        let mut results = Vec::with_capacity(self.conventions.len());
+        for convention in self.conventions.iter() {
+            let (date, price) = convention.price(pricer)?;
+            results.push(ThetaResult { name: convention.name.clone(), date: date,
+                price: price, theta: price - unbumped });
+        }
+
+        Ok(Qbox::new(Box::new(ThetaReport { results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::RcReportGenerator;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::timebumped::{TimeBumpedReportGenerator, TimeBumpedReport};
+    use dates::calendar::WeekdayCalendar;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn business() -> ThetaDay {
+        ThetaDay::Business(RcCalendar::new(Arc::new(WeekdayCalendar::new())))
+    }
+
+    fn sample_generator() -> ThetaReportGenerator {
+        ThetaReportGenerator::new(vec![
+            ThetaConvention::new("calendar", ThetaDay::Calendar, SpotDynamics::StickyForward, true),
+            ThetaConvention::new("business", business(), SpotDynamics::StickyForward, true),
+            ThetaConvention::new("sticky strike", ThetaDay::Calendar, SpotDynamics::StickySpot, true),
+            ThetaConvention::new("unrolled", ThetaDay::Calendar, SpotDynamics::StickyForward, false)])
+    }
+
+    #[test]
+    fn theta_conventions_european_call() {
+        // create a pricer for a european at the money call, and move it to
+        // a Friday
+        let mut pricer = sample_pricer();
+        let friday = Date::from_ymd(2017, 01, 06);
+        pricer.bump_time(&BumpTime::new(friday, friday, SpotDynamics::StickyForward)).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = sample_generator().generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<ThetaReport>().unwrap();
+        assert_eq!(results.results().len(), 4);
+
+        // calendar theta matches the time bumped report
+        let time_bumped = |date: Date, dynamics: SpotDynamics, pricer: &mut Pricer| {
+            let generator = TimeBumpedReportGenerator::new(BumpTime::new(date, date, dynamics));
+            let mut save = pricer.as_bumpable().new_saveable();
+            let report = generator.generate(pricer, &mut *save, unbumped).unwrap();
+            report.as_any().downcast_ref::<TimeBumpedReport>().unwrap().theta()
+        };
+        let calendar = results.get("calendar").unwrap();
+        assert_eq!(calendar.date(), friday + 1);
+        assert_approx(calendar.price() - unbumped, calendar.theta(), 1e-14);
+        assert_approx(calendar.theta(), time_bumped(friday + 1,
+            SpotDynamics::StickyForward, &mut *pricer), 1e-12);
+
+        // business day theta covers the weekend
+        let business = results.get("business").unwrap();
+        assert_eq!(business.date(), Date::from_ymd(2017, 01, 09));
+        assert_approx(business.theta(), time_bumped(business.date(),
+            SpotDynamics::StickyForward, &mut *pricer), 1e-12);
+
+        // sticky strike theta keeps the spot where it is, so it does
+        // not pick up the drift of the forward
+        let sticky_strike = results.get("sticky strike").unwrap();
+        assert_approx(sticky_strike.theta(), time_bumped(friday + 1,
+            SpotDynamics::StickySpot, &mut *pricer), 1e-12);
+        assert!(sticky_strike.theta() != calendar.theta());
+
+        // no fixings fall between the dates, so rolling them makes no
+        // difference to a european
+        let unrolled = results.get("unrolled").unwrap();
+        assert_approx(unrolled.theta(), calendar.theta(), 1e-12);
+        assert!(results.get("missing").is_none());
+
+        // the pricer is left as it was
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-14);
+    }
+
+    #[test]
+    fn serde_theta_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(sample_generator()));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
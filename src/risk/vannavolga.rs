@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::ReportTolerances;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::ApproxEqReport;
+use risk::bucketedvega::BucketedVegaReportGenerator;
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The second order vol risks of an underlying, bucketed across its vol
+/// surface in the same way as bucketed vega. Each is a matrix indexed by
+/// expiry then strike. Vanna is the derivative of the vega of each bucket
+/// with respect to the spot, and volga its derivative with respect to the
+/// vols of the same bucket. The ladder gives the bucketed vegas with the
+/// spot moved by each of the relative shifts of the spot ladder, which
+/// shows the cross terms between spot and vol beyond the first order.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SurfaceVannaVolga {
+    vega: Vec<Vec<f64>>,
+    vanna: Vec<Vec<f64>>,
+    volga: Vec<Vec<f64>>,
+    ladder: Vec<Vec<Vec<f64>>>
+}
+
+impl SurfaceVannaVolga {
+    pub fn vega(&self) -> &[Vec<f64>] { &self.vega }
+    pub fn vanna(&self) -> &[Vec<f64>] { &self.vanna }
+    pub fn volga(&self) -> &[Vec<f64>] { &self.volga }
+    pub fn ladder(&self) -> &[Vec<Vec<f64>>] { &self.ladder }
+}
+
+/// This report shows the bucketed vega, vanna and volga of each of the
+/// underlyings that affect the price, and the bucketed vegas across a
+/// ladder of spot shifts.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SurfaceVannaVolgaReport {
+    vol_bumpsize: f64,
+    spot_bumpsize: f64,
+    expiries: Vec<Date>,
+    strikes: Vec<f64>,
+    spot_ladder: Vec<f64>,
+    results: HashMap<String, SurfaceVannaVolga>
+}
+
+impl Report for SurfaceVannaVolgaReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for SurfaceVannaVolgaReport {
+    fn type_id(&self) -> &'static str { "SurfaceVannaVolgaReport" }
+}
+
+impl SurfaceVannaVolgaReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(SurfaceVannaVolgaReport::deserialize(de)?)))
+    }
+
+    pub fn expiries(&self) -> &[Date] { &self.expiries }
+    pub fn strikes(&self) -> &[f64] { &self.strikes }
+    pub fn spot_ladder(&self) -> &[f64] { &self.spot_ladder }
+    pub fn results(&self) -> &HashMap<String, SurfaceVannaVolga> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v SurfaceVannaVolgaReport> for &'v SurfaceVannaVolgaReport {
+    fn validate(self, other: &'v SurfaceVannaVolgaReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "SurfaceVannaVolgaReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // All the risks are based on diffs, so use the currency risk
+        // tolerance, scaled by one over the bump sizes as for vega and volga
+        let vega = tol.currency_risk() / self.vol_bumpsize;
+        let vanna = vega / self.spot_bumpsize;
+        let volga = vega / self.vol_bumpsize;
+
+        for (id, results) in &self.results {
+            if let Some(other_results) = other.results.get(id) {
+                validate_matrix(&results.vega, &other_results.vega, vega, id, "vega", diffs)?;
+                validate_matrix(&results.vanna, &other_results.vanna, vanna, id, "vanna", diffs)?;
+                validate_matrix(&results.volga, &other_results.volga, volga, id, "volga", diffs)?;
+                if results.ladder.len() != other_results.ladder.len() {
+                    writeln!(diffs, "SurfaceVannaVolgaReport: {} ladder length {} != {}", id, results.ladder.len(), other_results.ladder.len())?;
+                }
+                for (rung, other_rung) in results.ladder.iter().zip(other_results.ladder.iter()) {
+                    validate_matrix(rung, other_rung, vega, id, "ladder vega", diffs)?;
+                }
+            } else {
+                write!(diffs, "SurfaceVannaVolgaReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_matrix(matrix: &[Vec<f64>], other: &[Vec<f64>], tolerance: f64,
+    id: &str, name: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+    if matrix.len() != other.len() {
+        writeln!(diffs, "SurfaceVannaVolgaReport: {} {} expiries {} != {}", id, name, matrix.len(), other.len())?;
+    }
+    for (i, (row, other_row)) in matrix.iter().zip(other.iter()).enumerate() {
+        for (j, (value, other_value)) in row.iter().zip(other_row.iter()).enumerate() {
+            if !approx_eq(*value, *other_value, tolerance) {
+                writeln!(diffs, "SurfaceVannaVolgaReport: {} bucket ({}, {}) {} {} != {} tol={}",
+                    id, i, j, name, value, other_value, tolerance)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl ApproxEqReport for SurfaceVannaVolgaReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<SurfaceVannaVolgaReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "SurfaceVannaVolgaReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for bucketed vanna and volga by bumping. The vols are bumped
+/// bucket by bucket as for bucketed vega. The spot bump size and the shifts
+/// of the spot ladder are fractions of the current spot.
+///
+/// The spot bumps are applied once for each underlying and left in place
+/// while every bucket of its vols is bumped and restored, using a second
+/// save area, so vanna needs four revaluations per bucket, two of which
+/// also give the vega and volga, and each rung of the ladder needs two.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SurfaceVannaVolgaReportGenerator {
+    buckets: BucketedVegaReportGenerator,
+    spot_bumpsize: f64,
+    spot_ladder: Vec<f64>
+}
+
+impl SurfaceVannaVolgaReportGenerator {
+    pub fn new(buckets: BucketedVegaReportGenerator, spot_bumpsize: f64,
+        spot_ladder: Vec<f64>) -> SurfaceVannaVolgaReportGenerator {
+        SurfaceVannaVolgaReportGenerator { buckets: buckets,
+            spot_bumpsize: spot_bumpsize, spot_ladder: spot_ladder }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SurfaceVannaVolgaReportGenerator::deserialize(de)?)))
+    }
+
+    fn vanna_volga(&self, id: &str, pricer: &mut Pricer, saveable: &mut Saveable,
+        inner: &mut Saveable, unbumped: f64) -> Result<SurfaceVannaVolga, qm::Error> {
+
+        let vol_bumpsize = self.buckets.bumpsize();
+        let vegas = |prices: &[Vec<(f64, f64)>]| -> Vec<Vec<f64>> {
+            prices.iter().map(|row| row.iter()
+                .map(|&(up, down)| (up - down) / (2.0 * vol_bumpsize)).collect())
+                .collect()
+        };
+
+        // vega and volga at the current spot
+        let prices = self.buckets.bumped_prices(id, false, pricer, inner, unbumped)?;
+        let vega = vegas(&prices);
+        let volga = prices.iter().map(|row| row.iter()
+            .map(|&(up, down)| (up + down - 2.0 * unbumped) / vol_bumpsize.powi(2))
+            .collect()).collect();
+
+        // vanna from the vegas with the spot bumped up and down, where the
+        // down bump is applied on top of the up bump, as for delta
+        let spot = pricer.as_bumpable().context().spot(id)?;
+        let up = Bump::new_spot(id, BumpSpot::new_relative(self.spot_bumpsize));
+        let down = Bump::new_spot(id, BumpSpot::new_relative(
+            (1.0 - self.spot_bumpsize) / (1.0 + self.spot_bumpsize) - 1.0));
+        let upbumped = bumped_price(&up, pricer, Some(saveable), unbumped)?;
+        let up_vegas = vegas(&self.buckets.bumped_prices(id, false, pricer, inner, upbumped)?);
+        let downbumped = bumped_price(&down, pricer, None, unbumped)?;
+        let down_vegas = vegas(&self.buckets.bumped_prices(id, false, pricer, inner, downbumped)?);
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        let spot_bumpsize = self.spot_bumpsize * spot;
+        let vanna = up_vegas.iter().zip(down_vegas.iter()).map(|(up_row, down_row)|
+            up_row.iter().zip(down_row.iter())
+                .map(|(up, down)| (up - down) / (2.0 * spot_bumpsize)).collect())
+            .collect();
+
+        // the bucketed vegas at each rung of the ladder
+        let mut ladder = Vec::with_capacity(self.spot_ladder.len());
+        for &shift in self.spot_ladder.iter() {
+            if shift == 0.0 {
+                ladder.push(vega.clone());
+                continue;
+            }
+            let bump = Bump::new_spot(id, BumpSpot::new_relative(shift));
+            let bumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+            ladder.push(self.buckets.bucketed_vegas(id, false, pricer, inner, bumped)?);
+            pricer.as_mut_bumpable().restore(saveable)?;
+            saveable.clear();
+        }
+
+        Ok(SurfaceVannaVolga { vega: vega, vanna: vanna, volga: volga, ladder: ladder })
+    }
+}
+
+impl TypeId for SurfaceVannaVolgaReportGenerator {
+    fn type_id(&self) -> &'static str { "SurfaceVannaVolgaReportGenerator" }
+}
+
+impl ReportGenerator for SurfaceVannaVolgaReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the underlyings that have both a spot and a vol surface. FX
+        // rates are excluded, as their vols are not bumped as instruments.
+        let instruments = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let mut instruments = dependencies.instruments_clone();
+            instruments.retain(|id| !dependencies.fx_rates().contains_key(id));
+            instruments
+        };
+
+        let mut inner = pricer.as_bumpable().new_saveable();
+        let mut results = HashMap::new();
+        for id in instruments.iter() {
+            let vanna_volga = self.vanna_volga(id, pricer, saveable, &mut *inner, unbumped)?;
+            results.insert(id.to_string(), vanna_volga);
+        }
+
+        Ok(Qbox::new(Box::new(SurfaceVannaVolgaReport {
+            vol_bumpsize: self.buckets.bumpsize(), spot_bumpsize: self.spot_bumpsize,
+            expiries: self.buckets.expiries().to_vec(),
+            strikes: self.buckets.strikes().to_vec(),
+            spot_ladder: self.spot_ladder.clone(), results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::RcReportGenerator;
+    use risk::BoxReport;
+    use risk::bucketedvega::BucketedVegaReport;
+    use risk::deltagamma::tests::sample_pricer;
+    use data::bumpvol::BumpVol;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn sample_generator() -> SurfaceVannaVolgaReportGenerator {
+        let buckets = BucketedVegaReportGenerator::new(0.01,
+            vec![Date::from_ymd(2017, 06, 01), Date::from_ymd(2018, 01, 01),
+                Date::from_ymd(2019, 01, 01)],
+            vec![80.0, 100.0, 120.0]).unwrap();
+        SurfaceVannaVolgaReportGenerator::new(buckets, 0.01, vec![-0.1, 0.0, 0.1])
+    }
+
+    /// The price with the given parallel bumps of spot and vol
+    fn bumped(spot: f64, vol: f64) -> f64 {
+        let mut pricer = sample_pricer();
+        pricer.as_mut_bumpable().bump(&Bump::new_spot("BP.L",
+            BumpSpot::new_relative(spot)), None).unwrap();
+        pricer.as_mut_bumpable().bump(&Bump::new_vol("BP.L",
+            BumpVol::new_flat_additive(vol)), None).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn surface_vanna_volga_european() {
+        // an at the money call, expiring on 2018-06-01
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = sample_generator().generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<SurfaceVannaVolgaReport>().unwrap();
+        let results = &results.results()["BP.L"];
+
+        // The option only sees the vols at its strike and expiry, which
+        // are split between the two buckets either side with weights w.
+        // Vanna is linear in the weights and volga quadratic.
+        let later = 151.0 / 365.0;
+        let weights = [1.0 - later, later];
+        let spot = 100.0;
+        let vanna = (bumped(0.01, 0.01) - bumped(0.01, -0.01)
+            - bumped(-0.01, 0.01) + bumped(-0.01, -0.01)) / (4.0 * 0.01 * 0.01 * spot);
+        let volga = (bumped(0.0, 0.01) + bumped(0.0, -0.01) - 2.0 * unbumped) / 0.01f64.powi(2);
+        assert!(vanna.abs() > 1e-3 && volga.abs() > 0.1, "vanna={} volga={}", vanna, volga);
+        for (k, &w) in weights.iter().enumerate() {
+            assert_approx(results.vanna()[k + 1][1], w * vanna, 0.01 * vanna.abs());
+            assert_approx(results.volga()[k + 1][1], w * w * volga, 0.01 * volga.abs());
+        }
+        for i in 0..3 {
+            for j in 0..3 {
+                if j != 1 || i == 0 {
+                    assert_eq!(results.vanna()[i][j], 0.0, "bucket ({}, {})", i, j);
+                    assert_eq!(results.volga()[i][j], 0.0, "bucket ({}, {})", i, j);
+                }
+            }
+        }
+
+        // the vegas match the bucketed vega report, and so does every rung
+        // of the ladder, with the spot moved
+        let buckets = sample_generator().buckets;
+        let vega = buckets.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let vega = &vega.as_any().downcast_ref::<BucketedVegaReport>().unwrap()
+            .results()["BP.L"];
+        assert_eq!(results.vega(), &vega[..]);
+        assert_eq!(results.ladder().len(), 3);
+        assert_eq!(results.ladder()[1], *vega);
+        for &(rung, shift) in [(0, -0.1), (2, 0.1)].iter() {
+            let mut shifted = sample_pricer();
+            shifted.as_mut_bumpable().bump(&Bump::new_spot("BP.L",
+                BumpSpot::new_relative(shift)), None).unwrap();
+            let shifted_unbumped = shifted.price().unwrap();
+            let mut shifted_save = shifted.as_bumpable().new_saveable();
+            let expected = buckets.generate(&mut *shifted, &mut *shifted_save,
+                shifted_unbumped).unwrap();
+            let expected = &expected.as_any().downcast_ref::<BucketedVegaReport>().unwrap()
+                .results()["BP.L"];
+            for (row, expected_row) in results.ladder()[rung].iter().zip(expected.iter()) {
+                for (value, expected_value) in row.iter().zip(expected_row.iter()) {
+                    assert_approx(*value, *expected_value, 1e-9);
+                }
+            }
+        }
+
+        // an at the money call has less vega when the spot moves away
+        let total = |matrix: &[Vec<f64>]| matrix.iter()
+            .map(|row| row.iter().sum::<f64>()).sum::<f64>();
+        assert!(total(&results.ladder()[0]) < total(&results.ladder()[1]));
+
+        // the pricer is left as it was
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn serde_surface_vanna_volga_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(sample_generator()));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let serialized = serde_json::to_string_pretty(&report).unwrap();
+        let deserialized: BoxReport = serde_json::from_str(&serialized).unwrap();
+
+        // json does not always round trip the last bit of a float, so
+        // compare the values approximately
+        let report = report.as_any().downcast_ref::<SurfaceVannaVolgaReport>().unwrap();
+        let deserialized = deserialized.as_any().downcast_ref::<SurfaceVannaVolgaReport>().unwrap();
+        assert_eq!(report.expiries(), deserialized.expiries());
+        assert_eq!(report.strikes(), deserialized.strikes());
+        assert_eq!(report.spot_ladder(), deserialized.spot_ladder());
+        let results = &report.results()["BP.L"];
+        let other = &deserialized.results()["BP.L"];
+        let matrices = |r: &SurfaceVannaVolga| -> Vec<Vec<Vec<f64>>> {
+            let mut matrices = vec![r.vega().to_vec(), r.vanna().to_vec(), r.volga().to_vec()];
+            matrices.extend(r.ladder().iter().cloned());
+            matrices
+        };
+        for (matrix, other_matrix) in matrices(results).iter().zip(matrices(other).iter()) {
+            for (row, other_row) in matrix.iter().zip(other_matrix.iter()) {
+                for (value, other_value) in row.iter().zip(other_row.iter()) {
+                    assert_approx(*value, *other_value, 1e-12);
+                }
+            }
+        }
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
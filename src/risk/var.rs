@@ -0,0 +1,525 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use std::cmp::Ordering;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use risk::scenarios::Scenario;
+use risk::marketdata::RcMarketData;
+use instruments::Composite;
+use pricers::PricerFactory;
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use data::bumpvol::BumpVol;
+use data::bumpyield::BumpYield;
+use data::bumpdivs::BumpDivs;
+use data::bumpcorrelation::BumpCorrelation;
+use data::fixings::RcFixingTable;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// A risk factor whose history drives a historical simulation. The change
+/// in the factor on each day is applied to today's market data as a bump,
+/// so its meaning depends on the kind of factor: a relative return for
+/// spots and dividends, an absolute change in vol for vol surfaces, an
+/// annualised change in rate for yield, borrow and hazard curves, and an
+/// absolute change for correlations.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RiskFactor {
+    Spot(String),
+    FxSpot(String),
+    Vol(String),
+    Yield(String),
+    Hazard(String),
+    Divs(String),
+    Borrow(String),
+    Correlation(String, String)
+}
+
+impl RiskFactor {
+    /// The bump that applies the given change in this factor
+    pub fn bump(&self, change: f64) -> Bump {
+        match *self {
+            RiskFactor::Spot(ref id) => Bump::new_spot(id, BumpSpot::new_relative(change)),
+            RiskFactor::FxSpot(ref id) => Bump::new_fx_spot(id, BumpSpot::new_relative(change)),
+            RiskFactor::Vol(ref id) => Bump::new_vol(id, BumpVol::new_flat_additive(change)),
+            RiskFactor::Yield(ref id) => Bump::new_yield(id, BumpYield::new_flat_annualised(change)),
+            RiskFactor::Hazard(ref id) => Bump::new_hazard(id, BumpYield::new_flat_annualised(change)),
+            RiskFactor::Divs(ref id) => Bump::new_divs(id, BumpDivs::new_all_relative(change)),
+            RiskFactor::Borrow(ref id) => Bump::new_borrow(id, BumpYield::new_flat_annualised(change)),
+            RiskFactor::Correlation(ref first, ref second) =>
+                Bump::new_correlation(first, second, BumpCorrelation::new_additive(change))
+        }
+    }
+}
+
+/// A history of daily changes in a set of risk factors. There is one row
+/// of changes for each date, with one change for each factor, in the same
+/// order as the factors. Factors that the portfolio does not depend on
+/// are harmless, so one history can be shared by many portfolios.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketHistory {
+    factors: Vec<RiskFactor>,
+    dates: Vec<Date>,
+    changes: Vec<Vec<f64>>
+}
+
+impl MarketHistory {
+    pub fn new(factors: Vec<RiskFactor>, dates: Vec<Date>, changes: Vec<Vec<f64>>)
+        -> Result<MarketHistory, qm::Error> {
+
+        if dates.is_empty() {
+            return Err(qm::Error::new("Market history must have at least one date"))
+        }
+        if dates.len() != changes.len() {
+            return Err(qm::Error::new(&format!("Market history has {} dates \
+                but {} days of changes", dates.len(), changes.len())))
+        }
+        for (date, row) in dates.iter().zip(changes.iter()) {
+            if row.len() != factors.len() {
+                return Err(qm::Error::new(&format!("Market history on {} has \
+                    {} changes for {} factors", date, row.len(), factors.len())))
+            }
+        }
+
+        Ok(MarketHistory { factors: factors, dates: dates, changes: changes })
+    }
+
+    pub fn factors(&self) -> &[RiskFactor] { &self.factors }
+    pub fn dates(&self) -> &[Date] { &self.dates }
+    pub fn changes(&self) -> &[Vec<f64>] { &self.changes }
+
+    /// The scenario that applies the changes of the given day, named by its
+    /// date. Factors that did not move are left out.
+    pub fn scenario(&self, day: usize) -> Scenario {
+        let bumps = self.factors.iter().zip(self.changes[day].iter())
+            .filter(|&(_, &change)| change != 0.0)
+            .map(|(factor, &change)| factor.bump(change))
+            .collect();
+        Scenario::from_bumps(&self.dates[day].to_string(), bumps)
+    }
+
+    /// The P&L of the pricer under the changes of each day, leaving the
+    /// pricer as it was
+    pub fn pnls(&self, pricer: &mut Pricer, unbumped: f64) -> Result<Vec<f64>, qm::Error> {
+        let mut pnls = Vec::with_capacity(self.dates.len());
+        for day in 0..self.dates.len() {
+            pnls.push(self.scenario(day).price(pricer, unbumped)? - unbumped);
+        }
+        Ok(pnls)
+    }
+}
+
+/// One position of a portfolio, with its own pricer, so that its share of
+/// the P&L can be found
+pub struct VarPosition {
+    id: String,
+    weight: f64,
+    pricer: Box<Pricer>
+}
+
+impl VarPosition {
+    pub fn new(id: &str, weight: f64, pricer: Box<Pricer>) -> VarPosition {
+        VarPosition { id: id.to_string(), weight: weight, pricer: pricer }
+    }
+
+    /// Creates a position for each trade in the portfolio and its
+    /// sub-portfolios, with the weights multiplied through the tree
+    pub fn from_portfolio(portfolio: &Composite, factory: &PricerFactory,
+        fixings: RcFixingTable, market_data: RcMarketData)
+        -> Result<Vec<VarPosition>, qm::Error> {
+
+        let mut positions = Vec::new();
+        for (weight, trade) in portfolio.positions() {
+            let id = trade.id().to_string();
+            let pricer = factory.new(trade, fixings.clone(), market_data.clone())?;
+            positions.push(VarPosition::new(&id, weight, pricer));
+        }
+        Ok(positions)
+    }
+
+    pub fn id(&self) -> &str { &self.id }
+    pub fn weight(&self) -> f64 { self.weight }
+    pub fn pricer(&self) -> &Pricer { &*self.pricer }
+}
+
+/// The P&L of one position on the day that sets the VaR, including its
+/// weight. The contributions of all the positions add up to minus the VaR.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VarContribution {
+    id: String,
+    pnl: f64
+}
+
+impl VarContribution {
+    pub fn id(&self) -> &str { &self.id }
+    pub fn pnl(&self) -> f64 { self.pnl }
+}
+
+/// The results of a historical simulation. This contains the P&L for each
+/// day of the history, which is the distribution the VaR is taken from, and
+/// the VaR itself, which is reported as a positive loss. If the simulation
+/// was by position, it also shows how each position contributed to the
+/// loss on the day that sets the VaR.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VarReport {
+    confidence: f64,
+    dates: Vec<Date>,
+    pnls: Vec<f64>,
+    var: f64,
+    var_date: Date,
+    contributions: Vec<VarContribution>
+}
+
+impl Report for VarReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for VarReport {
+    fn type_id(&self) -> &'static str { "VarReport" }
+}
+
+impl VarReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(VarReport::deserialize(de)?)))
+    }
+
+    pub fn confidence(&self) -> f64 { self.confidence }
+    pub fn dates(&self) -> &[Date] { &self.dates }
+    pub fn pnls(&self) -> &[f64] { &self.pnls }
+    pub fn var(&self) -> f64 { self.var }
+    pub fn var_date(&self) -> Date { self.var_date }
+    pub fn contributions(&self) -> &[VarContribution] { &self.contributions }
+
+    /// Finds the contribution of the position with the given id. If more
+    /// than one position has that id, this is the first of them.
+    pub fn contribution(&self, id: &str) -> Option<&VarContribution> {
+        self.contributions.iter().find(|contribution| contribution.id == id)
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v VarReport> for &'v VarReport {
+    fn validate(self, other: &'v VarReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        // as for scenarios
+        let tolerance = tol.price();
+
+        if self.confidence != other.confidence {
+            writeln!(diffs, "VarReport: confidence {} != {}", self.confidence, other.confidence)?;
+        }
+        if self.dates != other.dates {
+            writeln!(diffs, "VarReport: dates differ")?;
+        }
+        for (i, (pnl, other_pnl)) in self.pnls.iter().zip(other.pnls.iter()).enumerate() {
+            if !approx_eq(*pnl, *other_pnl, tolerance) {
+                writeln!(diffs, "VarReport: day {} pnl {} != {} tol={}",
+                    i, pnl, other_pnl, tolerance)?;
+            }
+        }
+        if !approx_eq(self.var, other.var, tolerance) || self.var_date != other.var_date {
+            writeln!(diffs, "VarReport: var {} on {} != {} on {} tol={}", self.var,
+                self.var_date, other.var, other.var_date, tolerance)?;
+        }
+
+        if self.contributions.len() != other.contributions.len() {
+            writeln!(diffs, "VarReport: number of contributions {} != {}",
+                self.contributions.len(), other.contributions.len())?;
+        }
+        for (contribution, other_contribution) in self.contributions.iter()
+            .zip(other.contributions.iter()) {
+            if contribution.id != other_contribution.id
+                || !approx_eq(contribution.pnl, other_contribution.pnl, tolerance) {
+                writeln!(diffs, "VarReport: contribution {} {} != {} {} tol={}",
+                    contribution.id, contribution.pnl, other_contribution.id,
+                    other_contribution.pnl, tolerance)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for VarReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<VarReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "VarReport: mismatching report {} != {}", TypeId::type_id(self), TypeId::type_id(other))?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for value at risk by historical simulation. The changes of
+/// each day of the history are applied to today's market data as a
+/// scenario, and the portfolio is repriced, giving a distribution of P&L.
+/// The VaR at a confidence level such as 0.99 is the loss that is exceeded
+/// on less than one percent of the days. It is taken from the day of the
+/// history with that loss, rather than interpolated, so the contributions
+/// of the positions on that day add up to it exactly.
+///
+/// As a report generator, this works on the portfolio as a whole, so it
+/// cannot say which positions contribute. To break the VaR down by
+/// position, use generate_by_position with a pricer for each position.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoricalVarReportGenerator {
+    history: MarketHistory,
+    confidence: f64
+}
+
+impl HistoricalVarReportGenerator {
+    /// Creates a generator with a confidence level, which must be strictly
+    /// between zero and one
+    pub fn new(history: MarketHistory, confidence: f64)
+        -> Result<HistoricalVarReportGenerator, qm::Error> {
+        if !(confidence > 0.0 && confidence < 1.0) {
+            return Err(qm::Error::new(&format!("VaR confidence {} must be \
+                strictly between zero and one", confidence)))
+        }
+        Ok(HistoricalVarReportGenerator { history: history, confidence: confidence })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(HistoricalVarReportGenerator::deserialize(de)?)))
+    }
+
+    pub fn history(&self) -> &MarketHistory { &self.history }
+    pub fn confidence(&self) -> f64 { self.confidence }
+
+    /// The day of the history that sets the VaR, given the P&L of each day.
+    /// With n days, this is the day with the k'th largest loss, where k is
+    /// n times one minus the confidence, rounded up. A P&L that is not
+    /// finite, for example from a pricer that failed under a scenario, has
+    /// no place in the ordering, so it is an error.
+    pub fn var_day(&self, pnls: &[f64]) -> Result<usize, qm::Error> {
+        if let Some(day) = pnls.iter().position(|pnl| !pnl.is_finite()) {
+            return Err(qm::Error::new(&format!("VaR P&L {} on day {} is not \
+                finite", pnls[day], day)))
+        }
+
+        // the P&Ls are all finite, so they are totally ordered
+        let mut days: Vec<usize> = (0..pnls.len()).collect();
+        days.sort_by(|a, b| pnls[*a].partial_cmp(&pnls[*b])
+            .unwrap_or(Ordering::Equal));
+
+        // allow for rounding, so that 100 days at 0.95 gives the fifth loss
+        let tail = (1.0 - self.confidence) * pnls.len() as f64;
+        let k = (tail - 1e-9).ceil().max(1.0) as usize;
+        Ok(days[k.min(pnls.len()) - 1])
+    }
+
+    /// Runs the simulation for each position separately, so the report
+    /// shows how they contribute to the VaR. The pricers are left as they
+    /// were.
+    pub fn generate_by_position(&self, positions: &mut [VarPosition])
+        -> Result<VarReport, qm::Error> {
+
+        let mut total = vec![0.0; self.history.dates().len()];
+        let mut position_pnls = Vec::with_capacity(positions.len());
+        for position in positions.iter_mut() {
+            let unbumped = position.pricer.price()?;
+            let pnls = self.history.pnls(&mut *position.pricer, unbumped)?;
+            for (sum, pnl) in total.iter_mut().zip(pnls.iter()) {
+                *sum += position.weight * pnl;
+            }
+            position_pnls.push(pnls);
+        }
+
+        let day = self.var_day(&total)?;
+        let mut report = self.report(total, day);
+        report.contributions = positions.iter().zip(position_pnls.iter())
+            .map(|(position, pnls)| VarContribution { id: position.id.clone(),
+                pnl: position.weight * pnls[day] }).collect();
+        Ok(report)
+    }
+
+    fn report(&self, pnls: Vec<f64>, day: usize) -> VarReport {
+        VarReport { confidence: self.confidence, dates: self.history.dates().to_vec(),
+            var: -pnls[day], var_date: self.history.dates()[day], pnls: pnls,
+            contributions: Vec::new() }
+    }
+}
+
+impl TypeId for HistoricalVarReportGenerator {
+    fn type_id(&self) -> &'static str { "HistoricalVarReportGenerator" }
+}
+
+impl ReportGenerator for HistoricalVarReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, _saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+        let pnls = self.history.pnls(pricer, unbumped)?;
+        let day = self.var_day(&pnls)?;
+        Ok(Qbox::new(Box::new(self.report(pnls, day))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instruments::options::PutOrCall;
+    use instruments::portfolio::Portfolio;
+    use instruments::portfolio::PortfolioTags;
+    use instruments::portfolio::tests::sample_option;
+    use instruments::assets::RcCurrency;
+    use pricers::selfpricer::SelfPricerFactory;
+    use risk::RcReportGenerator;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use data::fixings::FixingTable;
+    use core::factories::tests::assert_debug_eq;
+    use std::f64::{NAN, NEG_INFINITY};
+    use serde_json;
+
+    /// Ten days of moves in BP.L and its vol, and in an underlying that
+    /// none of the samples depend on
+    fn sample_history() -> MarketHistory {
+        let factors = vec![RiskFactor::Spot("BP.L".to_string()),
+            RiskFactor::Vol("BP.L".to_string()), RiskFactor::Spot("XXX.L".to_string())];
+        let spots = [0.01, -0.02, 0.005, -0.05, 0.0, 0.03, -0.01, 0.02, -0.03, 0.015];
+        let vols = [0.0, 0.01, 0.0, 0.03, 0.0, -0.01, 0.005, 0.0, 0.02, -0.005];
+        let dates = (0..10).map(|i| Date::from_ymd(2016, 12, 1) + i).collect();
+        let changes = spots.iter().zip(vols.iter())
+            .map(|(&spot, &vol)| vec![spot, vol, 0.1]).collect();
+        MarketHistory::new(factors, dates, changes).unwrap()
+    }
+
+    /// Long a call, and short twice as many puts
+    fn sample_positions() -> Vec<VarPosition> {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let portfolio = Portfolio::new("Book", "OPT", currency, sample_settlement(2),
+            PortfolioTags::default(), vec![
+                (1.0, sample_option("Call", 100.0, PutOrCall::Call)),
+                (-2.0, sample_option("Put", 90.0, PutOrCall::Put))]).unwrap();
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(
+            Date::from_ymd(2017, 01, 02))));
+        VarPosition::from_portfolio(&portfolio, &SelfPricerFactory::new(),
+            fixings, market_data).unwrap()
+    }
+
+    #[test]
+    fn historical_var_by_position() {
+        let mut positions = sample_positions();
+        let unbumped: Vec<f64> = positions.iter()
+            .map(|position| position.pricer().price().unwrap()).collect();
+
+        let generator = HistoricalVarReportGenerator::new(sample_history(), 0.8).unwrap();
+        let report = generator.generate_by_position(&mut positions).unwrap();
+        assert_eq!(report.pnls().len(), 10);
+
+        // each day's P&L is the weighted sum of the P&Ls of the positions,
+        // each repriced under that day's changes
+        let mut call = sample_positions().remove(0);
+        let expected = generator.history().scenario(3).price(
+            &mut *call.pricer, unbumped[0]).unwrap() - unbumped[0];
+        let mut put = sample_positions().remove(1);
+        let expected = expected - 2.0 * (generator.history().scenario(3).price(
+            &mut *put.pricer, unbumped[1]).unwrap() - unbumped[1]);
+        assert_approx(report.pnls()[3], expected, 1e-12);
+
+        // With ten days at 80%, the VaR is the second largest loss, so
+        // exactly one day is worse. The worst days are the falls in spot.
+        let worse = report.pnls().iter().filter(|&&pnl| pnl < -report.var()).count();
+        assert_eq!(worse, 1);
+        assert_eq!(report.var_date(), Date::from_ymd(2016, 12, 9));
+        assert!(report.var() > 0.0);
+
+        // the contributions add up to the loss on that day, and the short
+        // puts lose as well as the long call
+        let call = report.contribution("Call").unwrap().pnl();
+        let put = report.contribution("Put").unwrap().pnl();
+        assert_eq!(report.contributions().len(), 2);
+        assert_approx(call + put, -report.var(), 1e-12);
+        assert!(call < 0.0 && put < 0.0);
+        assert!(report.contribution("missing").is_none());
+
+        // the pricers are left as they were
+        for (position, price) in positions.iter().zip(unbumped.iter()) {
+            assert_approx(position.pricer().price().unwrap(), *price, 1e-12);
+        }
+    }
+
+    #[test]
+    fn historical_var_as_report_generator() {
+        let mut positions = sample_positions();
+        let generator = HistoricalVarReportGenerator::new(sample_history(), 0.8).unwrap();
+        let by_position = generator.generate_by_position(&mut positions[0..1]).unwrap();
+
+        // the whole portfolio gives the same distribution, but no breakdown
+        let pricer = &mut positions[0].pricer;
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(&mut **pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VarReport>().unwrap();
+        assert_eq!(results.dates(), by_position.dates());
+        for (pnl, expected) in results.pnls().iter().zip(by_position.pnls().iter()) {
+            assert_approx(*pnl, *expected, 1e-12);
+        }
+        assert_approx(results.var(), by_position.var(), 1e-12);
+        assert_eq!(results.var_date(), by_position.var_date());
+        assert!(results.contributions().is_empty());
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+    }
+
+    #[test]
+    fn var_day_rounds_tail() {
+        let history = sample_history();
+        let pnls: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        let var_day = |confidence| HistoricalVarReportGenerator::new(
+            history.clone(), confidence).unwrap().var_day(&pnls).unwrap();
+        assert_eq!(var_day(0.95), 4);
+        assert_eq!(var_day(0.99), 0);
+        assert_eq!(var_day(0.999), 0);
+        assert_eq!(var_day(0.975), 2);
+
+        // a P&L that is not finite cannot be ordered
+        let generator = HistoricalVarReportGenerator::new(history, 0.95).unwrap();
+        assert!(generator.var_day(&[1.0, NAN, -1.0]).is_err());
+        assert!(generator.var_day(&[1.0, NEG_INFINITY]).is_err());
+    }
+
+    #[test]
+    fn bad_history_and_confidence() {
+        let factors = vec![RiskFactor::Spot("BP.L".to_string())];
+        let dates = vec![Date::from_ymd(2016, 12, 1), Date::from_ymd(2016, 12, 2)];
+        assert!(MarketHistory::new(factors.clone(), dates.clone(),
+            vec![vec![0.01]]).is_err());
+        assert!(MarketHistory::new(factors.clone(), dates.clone(),
+            vec![vec![0.01], vec![0.01, 0.02]]).is_err());
+        assert!(MarketHistory::new(factors.clone(), Vec::new(), Vec::new()).is_err());
+
+        let history = MarketHistory::new(factors, dates, vec![vec![0.01], vec![0.0]]).unwrap();
+        assert_eq!(history.scenario(0).steps().len(), 1);
+        assert_eq!(history.scenario(1).steps().len(), 0);
+        assert_eq!(history.scenario(1).name(), "2016-12-02");
+        assert!(HistoricalVarReportGenerator::new(history.clone(), 1.0).is_err());
+        assert!(HistoricalVarReportGenerator::new(history, 0.0).is_err());
+    }
+
+    #[test]
+    fn serde_historical_var_generator_roundtrip() {
+        let generator = RcReportGenerator::new(Arc::new(
+            HistoricalVarReportGenerator::new(sample_history(), 0.99).unwrap()));
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
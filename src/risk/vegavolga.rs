@@ -10,9 +10,13 @@ use risk::ReportTolerances;
 use risk::Pricer;
 use risk::Saveable;
 use risk::bumped_price;
+use risk::bumped_path_greeks;
+use risk::path_greeks;
+use risk::GreekMethod;
 use risk::ApproxEqReport;
 use data::bump::Bump;
 use data::bumpvol::BumpVol;
+use pricers::adjoint::MonteCarloGreeks;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::{Qrc, Qbox};
@@ -123,14 +127,26 @@ impl<'v> ApproxEq<VegaVolgaTolerances, &'v VegaVolga> for &'v VegaVolga {
 
 /// Calculator for vega and volga by bumping. The bump size is specified as
 /// a fraction of the current spot.
+///
+/// Alternatively, the vegas may be estimated directly from the paths of a
+/// Monte-Carlo pricer, in which case the volgas are central differences of
+/// the estimated vegas, with the same bumps. The estimated vegas are to a
+/// parallel additive shift of vol, so match flat additive bumps. Vol cubes
+/// and any underlyings the estimator does not cover are still bumped.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VegaVolgaReportGenerator {
-    bump: BumpVol
+    bump: BumpVol,
+    #[serde(default)]
+    method: GreekMethod
 }
 
 impl VegaVolgaReportGenerator {
     pub fn new(bump: BumpVol) -> VegaVolgaReportGenerator {
-        VegaVolgaReportGenerator { bump: bump }
+        VegaVolgaReportGenerator::with_method(bump, GreekMethod::Bumped)
+    }
+
+    pub fn with_method(bump: BumpVol, method: GreekMethod) -> VegaVolgaReportGenerator {
+        VegaVolgaReportGenerator { bump: bump, method: method }
     }
 
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
@@ -147,40 +163,103 @@ impl ReportGenerator for VegaVolgaReportGenerator {
         -> Result<BoxReport, qm::Error> {
 
         let bumpsize = self.bump.bumpsize();
-        let bumpsize_2 = bumpsize.powi(2);
 
         // Find the underlyings we should have vega to. Note that we need to
         // clone the list of instruments, to avoid borrowing problems.
-        let instruments = pricer.as_bumpable().dependencies()?.instruments_clone();
+        let (instruments, vol_cubes) = {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let vol_cubes: Vec<String> = dependencies.vol_cubes().keys()
+                .map(|id| id.to_string()).collect();
+            (dependencies.instruments_clone(), vol_cubes)
+        };
+
+        let greeks = match self.method {
+            GreekMethod::Bumped => None,
+            method => Some(path_greeks(pricer, method)?)
+        };
+
         let mut results = HashMap::new();
         for id in instruments.iter() {
+            let up = Bump::new_vol(id, self.bump.clone());
+            let down = Bump::new_vol(id, self.bump.opposite());
+            let vega_volga = match greeks {
+                Some(ref greeks) if greeks.vegas().contains_key(id) =>
+                    self.path_vega_volga(id, &up, &down, pricer, saveable, greeks)?,
+                _ => self.vega_volga(&up, &down, pricer, saveable, unbumped)?
+            };
+            results.insert(id.to_string(), vega_volga);
+        }
 
-            // bump up and reprice
-            let bump = Bump::new_vol(id, self.bump.clone());
-            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
-            
-            // bump down and reprice (do not save the result from this)
-            let bump = Bump::new_vol(id, self.bump.opposite());
-            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
-
-            pricer.as_mut_bumpable().restore(saveable)?;
-            saveable.clear();
-
-            // vega and volga calculations
-            let vega = (upbumped - downbumped) / (2.0 * bumpsize);
-            let volga = (upbumped + downbumped - 2.0 * unbumped) / bumpsize_2;
-            results.insert(id.to_string(), VegaVolga {vega, volga});
+        // Rates vols are held in vol cubes rather than on instruments, so
+        // they are reported against the id of the cube
+        for id in vol_cubes.iter() {
+            let up = Bump::new_vol_cube(id, self.bump.clone());
+            let down = Bump::new_vol_cube(id, self.bump.opposite());
+            let vega_volga = self.vega_volga(&up, &down, pricer, saveable, unbumped)?;
+            results.insert(id.to_string(), vega_volga);
         }
 
         Ok(Qbox::new(Box::new(VegaVolgaReport { bumpsize, results })))
     }
 }
 
+impl VegaVolgaReportGenerator {
+    fn vega_volga(&self, up: &Bump, down: &Bump, pricer: &mut Pricer,
+        saveable: &mut Saveable, unbumped: f64) -> Result<VegaVolga, qm::Error> {
+
+        let bumpsize = self.bump.bumpsize();
+        let bumpsize_2 = bumpsize.powi(2);
+
+        // bump up and reprice
+        let upbumped = bumped_price(up, pricer, Some(saveable), unbumped)?;
+
+        // bump down and reprice (do not save the result from this)
+        let downbumped = bumped_price(down, pricer, None, unbumped)?;
+
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        // vega and volga calculations
+        let vega = (upbumped - downbumped) / (2.0 * bumpsize);
+        let volga = (upbumped + downbumped - 2.0 * unbumped) / bumpsize_2;
+        Ok(VegaVolga {vega, volga})
+    }
+
+    /// Takes the vega from the estimated greeks, and the volga from the
+    /// estimated vegas when bumped up and down
+    fn path_vega_volga(&self, id: &str, up: &Bump, down: &Bump, pricer: &mut Pricer,
+        saveable: &mut Saveable, greeks: &MonteCarloGreeks) -> Result<VegaVolga, qm::Error> {
+
+        let upbumped = bumped_path_greeks(up, pricer, Some(saveable), self.method, greeks)?;
+        let downbumped = bumped_path_greeks(down, pricer, None, self.method, greeks)?;
+
+        pricer.as_mut_bumpable().restore(saveable)?;
+        saveable.clear();
+
+        let vega_of = |greeks: &MonteCarloGreeks| greeks.vegas().get(id).cloned()
+            .ok_or_else(|| qm::Error::new(&format!("No vega estimated for {}", id)));
+        let vega = vega_of(greeks)?;
+        let volga = (vega_of(&upbumped)? - vega_of(&downbumped)?)
+            / (2.0 * self.bump.bumpsize());
+        Ok(VegaVolga {vega, volga})
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use math::numerics::approx_eq;
     use risk::deltagamma::tests::sample_pricer;
+    use risk::Bumpable;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use instruments::RcInstrument;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::PathGeneration;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::montecarlo::MonteCarloSettings;
+    use core::factories::Qrc;
 
     #[test]
     fn vega_volga_european() {
@@ -211,6 +290,50 @@ mod tests {
         assert_approx(vega_volga.volga(), 86.34909534066537, 1e-12);
     }
 
+    #[test]
+    fn vega_volga_from_paths() {
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 65535)));
+        let mut pricer = MonteCarloPricer::with_settings(vec![(1.0, instrument)],
+            model_factory, MonteCarloSettings { path_generation: PathGeneration::Sobol,
+                ..MonteCarloSettings::default() }, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.new_saveable();
+
+        let bump = BumpVol::new_flat_additive(0.01);
+        let bumped = VegaVolgaReportGenerator::new(bump.clone());
+        let report = bumped.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let bumped = report.as_any().downcast_ref::<VegaVolgaReport>().unwrap()
+            .results()["BP.L"].vega();
+
+        let generator = VegaVolgaReportGenerator::with_method(bump, GreekMethod::Pathwise);
+        let report = generator.generate(&mut pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaVolgaReport>().unwrap().results();
+        assert_approx(results["BP.L"].vega(), bumped, 0.5);
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // the volga is the difference of the estimated vegas, so compare
+        // it with a difference of vegas bumped on the same paths. (The
+        // model sees only the at the money vol, so its volga differs from
+        // that of the closed form.)
+        let h = 0.01;
+        let mut vega = |shift: f64| {
+            let mut vega = 0.0;
+            for &size in [1e-4, -1e-4].iter() {
+                let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(shift + size));
+                pricer.bump(&bump, Some(&mut *save)).unwrap();
+                vega += pricer.price().unwrap() / (2.0 * size);
+                pricer.restore(&*save).unwrap();
+                save.clear();
+            }
+            vega
+        };
+        let volga = (vega(h) - vega(-h)) / (2.0 * h);
+        assert_approx(results["BP.L"].volga(), volga, 0.5);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={}", value, expected);